@@ -540,6 +540,56 @@ impl Aml for IO {
     }
 }
 
+/// Generic register descriptor, pointing at a register of an address space.
+/// A zeroed system memory register stands for a register that isn't
+/// implemented.
+pub struct Register {
+    space: OpRegionSpace,
+    bit_width: u8,
+    bit_offset: u8,
+    address: u64,
+    access_size: u8,
+}
+
+impl Register {
+    pub fn new(
+        space: OpRegionSpace,
+        bit_width: u8,
+        bit_offset: u8,
+        address: u64,
+        access_size: u8,
+    ) -> Self {
+        Register {
+            space,
+            bit_width,
+            bit_offset,
+            address,
+            access_size,
+        }
+    }
+
+    pub fn null() -> Self {
+        Register::new(OpRegionSpace::SystemMemory, 0, 0, 0, 0)
+    }
+}
+
+impl Aml for Register {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        bytes.push(0x82); /* Generic Register Descriptor */
+        bytes.append(&mut 12u16.to_le_bytes().to_vec());
+
+        // 12 bytes of payload
+        bytes.push(self.space as u8);
+        bytes.push(self.bit_width);
+        bytes.push(self.bit_offset);
+        bytes.push(self.access_size);
+        bytes.append(&mut self.address.to_le_bytes().to_vec());
+        bytes
+    }
+}
+
 pub struct Interrupt {
     consumer: bool,
     edge_triggered: bool,
@@ -645,6 +695,37 @@ impl<'a> Scope<'a> {
     }
 }
 
+pub struct ThermalZone<'a> {
+    path: Path,
+    children: Vec<&'a dyn Aml>,
+}
+
+impl<'a> Aml for ThermalZone<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.append(&mut self.path.to_aml_bytes());
+        for child in &self.children {
+            bytes.append(&mut child.to_aml_bytes());
+        }
+
+        let mut pkg_length = create_pkg_length(&bytes);
+        pkg_length.reverse();
+        for byte in pkg_length {
+            bytes.insert(0, byte);
+        }
+
+        bytes.insert(0, 0x85); /* ThermalZoneOp */
+        bytes.insert(0, 0x5b); /* ExtOpPrefix */
+        bytes
+    }
+}
+
+impl<'a> ThermalZone<'a> {
+    pub fn new(path: Path, children: Vec<&'a dyn Aml>) -> Self {
+        ThermalZone { path, children }
+    }
+}
+
 pub struct Method<'a> {
     path: Path,
     children: Vec<&'a dyn Aml>,
@@ -703,6 +784,394 @@ impl<'a> Aml for Return<'a> {
     }
 }
 
+#[derive(Clone, Copy)]
+pub enum OpRegionSpace {
+    SystemMemory,
+    SystemIO,
+    PCIConfig,
+    EmbeddedControl,
+    SMBus,
+    SystemCMOS,
+    PCIBarTarget,
+}
+
+pub struct OpRegion {
+    path: Path,
+    space: OpRegionSpace,
+    offset: usize,
+    length: usize,
+}
+
+impl OpRegion {
+    pub fn new(path: Path, space: OpRegionSpace, offset: usize, length: usize) -> Self {
+        OpRegion {
+            path,
+            space,
+            offset,
+            length,
+        }
+    }
+}
+
+impl Aml for OpRegion {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x5b); /* ExtOpPrefix */
+        bytes.push(0x80); /* OpRegionOp */
+        bytes.append(&mut self.path.to_aml_bytes());
+        bytes.push(self.space as u8);
+        bytes.append(&mut self.offset.to_aml_bytes()); /* RegionOffset */
+        bytes.append(&mut self.length.to_aml_bytes()); /* RegionLen */
+        bytes
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum FieldAccessType {
+    Any,
+    Byte,
+    Word,
+    DWord,
+    QWord,
+    Buffer,
+}
+
+#[derive(Clone, Copy)]
+pub enum FieldUpdateRule {
+    Preserve,
+    WriteAsOnes,
+    WriteAsZeroes,
+}
+
+pub enum FieldEntry {
+    Named([u8; 4], usize),
+    Reserved(usize),
+}
+
+pub struct Field {
+    path: Path,
+    fields: Vec<FieldEntry>,
+    access_type: FieldAccessType,
+    update_rule: FieldUpdateRule,
+}
+
+impl Field {
+    pub fn new(
+        path: Path,
+        access_type: FieldAccessType,
+        update_rule: FieldUpdateRule,
+        fields: Vec<FieldEntry>,
+    ) -> Self {
+        Field {
+            path,
+            fields,
+            access_type,
+            update_rule,
+        }
+    }
+}
+
+/* Field bit lengths use the PkgLength encoding but, unlike PkgLength, do not
+include the length bytes themselves. */
+fn create_field_length(length: usize) -> Vec<u8> {
+    assert!(length < 2usize.pow(12));
+
+    if length < 2usize.pow(6) {
+        vec![length as u8]
+    } else {
+        vec![(1u8 << 6) | (length & 0xf) as u8, (length >> 4) as u8]
+    }
+}
+
+impl Aml for Field {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.append(&mut self.path.to_aml_bytes());
+
+        let flags: u8 = self.access_type as u8 | (self.update_rule as u8) << 5;
+        bytes.push(flags);
+
+        for field in self.fields.iter() {
+            match field {
+                FieldEntry::Named(name, length) => {
+                    bytes.extend_from_slice(name);
+                    bytes.append(&mut create_field_length(*length));
+                }
+                FieldEntry::Reserved(length) => {
+                    bytes.push(0x0); /* ReservedField */
+                    bytes.append(&mut create_field_length(*length));
+                }
+            }
+        }
+
+        let mut pkg_length = create_pkg_length(&bytes);
+        pkg_length.reverse();
+        for byte in pkg_length {
+            bytes.insert(0, byte);
+        }
+
+        bytes.insert(0, 0x81); /* FieldOp */
+        bytes.insert(0, 0x5b); /* ExtOpPrefix */
+        bytes
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Local(pub u8);
+
+impl Aml for Local {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        assert!(self.0 < 8);
+        vec![0x60 + self.0] /* Local0Op */
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Arg(pub u8);
+
+impl Aml for Arg {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        assert!(self.0 < 7);
+        vec![0x68 + self.0] /* Arg0Op */
+    }
+}
+
+pub struct Store<'a> {
+    name: &'a dyn Aml,
+    value: &'a dyn Aml,
+}
+
+impl<'a> Store<'a> {
+    pub fn new(name: &'a dyn Aml, value: &'a dyn Aml) -> Self {
+        Store { name, value }
+    }
+}
+
+impl<'a> Aml for Store<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x70); /* StoreOp */
+        bytes.append(&mut self.value.to_aml_bytes());
+        bytes.append(&mut self.name.to_aml_bytes());
+        bytes
+    }
+}
+
+pub struct Index<'a> {
+    source: &'a dyn Aml,
+    index: &'a dyn Aml,
+}
+
+impl<'a> Index<'a> {
+    pub fn new(source: &'a dyn Aml, index: &'a dyn Aml) -> Self {
+        Index { source, index }
+    }
+}
+
+impl<'a> Aml for Index<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x88); /* IndexOp */
+        bytes.append(&mut self.source.to_aml_bytes());
+        bytes.append(&mut self.index.to_aml_bytes());
+        bytes.push(0x00); /* NullName */
+        bytes
+    }
+}
+
+pub struct DerefOf<'a> {
+    reference: &'a dyn Aml,
+}
+
+impl<'a> DerefOf<'a> {
+    pub fn new(reference: &'a dyn Aml) -> Self {
+        DerefOf { reference }
+    }
+}
+
+impl<'a> Aml for DerefOf<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x83); /* DerefOfOp */
+        bytes.append(&mut self.reference.to_aml_bytes());
+        bytes
+    }
+}
+
+pub struct SizeOf<'a> {
+    object: &'a dyn Aml,
+}
+
+impl<'a> SizeOf<'a> {
+    pub fn new(object: &'a dyn Aml) -> Self {
+        SizeOf { object }
+    }
+}
+
+impl<'a> Aml for SizeOf<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x87); /* SizeOfOp */
+        bytes.append(&mut self.object.to_aml_bytes());
+        bytes
+    }
+}
+
+pub struct Mid<'a> {
+    target: &'a dyn Aml,
+    source: &'a dyn Aml,
+    index: &'a dyn Aml,
+    length: &'a dyn Aml,
+}
+
+impl<'a> Mid<'a> {
+    pub fn new(
+        target: &'a dyn Aml,
+        source: &'a dyn Aml,
+        index: &'a dyn Aml,
+        length: &'a dyn Aml,
+    ) -> Self {
+        Mid {
+            target,
+            source,
+            index,
+            length,
+        }
+    }
+}
+
+impl<'a> Aml for Mid<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x9e); /* MidOp */
+        bytes.append(&mut self.source.to_aml_bytes());
+        bytes.append(&mut self.index.to_aml_bytes());
+        bytes.append(&mut self.length.to_aml_bytes());
+        bytes.append(&mut self.target.to_aml_bytes());
+        bytes
+    }
+}
+
+pub struct MethodCall<'a> {
+    path: Path,
+    args: Vec<&'a dyn Aml>,
+}
+
+impl<'a> MethodCall<'a> {
+    pub fn new(path: Path, args: Vec<&'a dyn Aml>) -> Self {
+        MethodCall { path, args }
+    }
+}
+
+impl<'a> Aml for MethodCall<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.append(&mut self.path.to_aml_bytes());
+        for arg in self.args.iter() {
+            bytes.append(&mut arg.to_aml_bytes());
+        }
+        bytes
+    }
+}
+
+pub struct And<'a> {
+    target: &'a dyn Aml,
+    a: &'a dyn Aml,
+    b: &'a dyn Aml,
+}
+
+impl<'a> And<'a> {
+    pub fn new(target: &'a dyn Aml, a: &'a dyn Aml, b: &'a dyn Aml) -> Self {
+        And { target, a, b }
+    }
+}
+
+impl<'a> Aml for And<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x7b); /* AndOp */
+        bytes.append(&mut self.a.to_aml_bytes());
+        bytes.append(&mut self.b.to_aml_bytes());
+        bytes.append(&mut self.target.to_aml_bytes());
+        bytes
+    }
+}
+
+pub struct Equal<'a> {
+    left: &'a dyn Aml,
+    right: &'a dyn Aml,
+}
+
+impl<'a> Equal<'a> {
+    pub fn new(left: &'a dyn Aml, right: &'a dyn Aml) -> Self {
+        Equal { left, right }
+    }
+}
+
+impl<'a> Aml for Equal<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x93); /* LEqualOp */
+        bytes.append(&mut self.left.to_aml_bytes());
+        bytes.append(&mut self.right.to_aml_bytes());
+        bytes
+    }
+}
+
+pub struct If<'a> {
+    predicate: &'a dyn Aml,
+    if_children: Vec<&'a dyn Aml>,
+}
+
+impl<'a> If<'a> {
+    pub fn new(predicate: &'a dyn Aml, if_children: Vec<&'a dyn Aml>) -> Self {
+        If {
+            predicate,
+            if_children,
+        }
+    }
+}
+
+impl<'a> Aml for If<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.append(&mut self.predicate.to_aml_bytes());
+        for child in self.if_children.iter() {
+            bytes.append(&mut child.to_aml_bytes());
+        }
+
+        let mut pkg_length = create_pkg_length(&bytes);
+        pkg_length.reverse();
+        for byte in pkg_length {
+            bytes.insert(0, byte);
+        }
+
+        bytes.insert(0, 0xa0); /* IfOp */
+        bytes
+    }
+}
+
+pub struct Notify<'a> {
+    object: &'a dyn Aml,
+    value: &'a dyn Aml,
+}
+
+impl<'a> Notify<'a> {
+    pub fn new(object: &'a dyn Aml, value: &'a dyn Aml) -> Self {
+        Notify { object, value }
+    }
+}
+
+impl<'a> Aml for Notify<'a> {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(0x86); /* NotifyOp */
+        bytes.append(&mut self.object.to_aml_bytes());
+        bytes.append(&mut self.value.to_aml_bytes());
+        bytes
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -987,6 +1456,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_register() {
+        /*
+        ResourceTemplate ()
+        {
+            Register (SystemIO,
+                0x08,               // Bit Width
+                0x00,               // Bit Offset
+                0x00000000000003C8, // Address
+                0x01,               // Access Size
+                )
+        }
+        */
+        let register_data = [
+            0x11, 0x14, 0x0A, 0x11, 0x82, 0x0C, 0x00, 0x01, 0x08, 0x00, 0x01, 0xC8, 0x03, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x79, 0x00,
+        ];
+
+        assert_eq!(
+            ResourceTemplate::new(vec![&Register::new(
+                OpRegionSpace::SystemIO,
+                8,
+                0,
+                0x3c8,
+                1
+            )])
+            .to_aml_bytes(),
+            register_data
+        );
+    }
+
     #[test]
     fn test_pkg_length() {
         assert_eq!(create_pkg_length(&[0u8; 62].to_vec()), vec![63]);
@@ -1093,4 +1593,142 @@ mod tests {
             [0x14, 0x09, 0x5F, 0x53, 0x54, 0x41, 0x00, 0xA4, 0x0A, 0x0F]
         );
     }
+
+    #[test]
+    fn test_op_region() {
+        /*
+        OperationRegion (GDST, SystemIO, 0x03C4, 0x08)
+        */
+        assert_eq!(
+            OpRegion::new("GDST".into(), OpRegionSpace::SystemIO, 0x3c4, 8).to_aml_bytes(),
+            [0x5B, 0x80, 0x47, 0x44, 0x53, 0x54, 0x01, 0x0B, 0xC4, 0x03, 0x0A, 0x08]
+        );
+    }
+
+    #[test]
+    fn test_field() {
+        /*
+        Field (GDST, ByteAcc, NoLock, WriteAsZeros)
+        {
+            GDAT,   8,
+                ,   8,
+            GFLG,   16
+        }
+        */
+        assert_eq!(
+            Field::new(
+                "GDST".into(),
+                FieldAccessType::Byte,
+                FieldUpdateRule::WriteAsZeroes,
+                vec![
+                    FieldEntry::Named(*b"GDAT", 8),
+                    FieldEntry::Reserved(8),
+                    FieldEntry::Named(*b"GFLG", 16)
+                ]
+            )
+            .to_aml_bytes(),
+            [
+                0x5B, 0x81, 0x12, 0x47, 0x44, 0x53, 0x54, 0x41, 0x47, 0x44, 0x41, 0x54, 0x08, 0x00,
+                0x08, 0x47, 0x46, 0x4C, 0x47, 0x10
+            ]
+        );
+    }
+
+    #[test]
+    fn test_store() {
+        /*
+        Store (GDAT, Local0)
+        */
+        assert_eq!(
+            Store::new(&Local(0), &Path::new("GDAT")).to_aml_bytes(),
+            [0x70, 0x47, 0x44, 0x41, 0x54, 0x60]
+        );
+    }
+
+    #[test]
+    fn test_index() {
+        /*
+        Store (BSTA, Index (BSTP, One))
+        */
+        assert_eq!(
+            Store::new(&Index::new(&Path::new("BSTP"), &ONE), &Path::new("BSTA")).to_aml_bytes(),
+            [0x70, 0x42, 0x53, 0x54, 0x41, 0x88, 0x42, 0x53, 0x54, 0x50, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_thermal_zone() {
+        /*
+        ThermalZone (TZ00)
+        {
+            Name (_CRT, 0x0E94)  // _CRT: Critical Temperature
+        }
+        */
+        assert_eq!(
+            ThermalZone::new("TZ00".into(), vec![&Name::new("_CRT".into(), &3732u16)])
+                .to_aml_bytes(),
+            [
+                0x5B, 0x85, 0x0D, 0x54, 0x5A, 0x30, 0x30, 0x08, 0x5F, 0x43, 0x52, 0x54, 0x0B, 0x94,
+                0x0E
+            ]
+        );
+    }
+
+    #[test]
+    fn test_if_notify() {
+        /*
+        If (((Local0 & 0x01) == 0x01))
+        {
+            Notify (\_SB.PWRB, 0x80) // Status Change
+        }
+        */
+        assert_eq!(
+            If::new(
+                &Equal::new(&And::new(&Local(1), &Local(0), &1u8), &1u8),
+                vec![&Notify::new(&Path::new("\\_SB_.PWRB"), &0x80u8)]
+            )
+            .to_aml_bytes(),
+            [
+                0xA0, 0x16, 0x93, 0x7B, 0x60, 0x0A, 0x01, 0x61, 0x0A, 0x01, 0x86, 0x5C, 0x2E, 0x5F,
+                0x53, 0x42, 0x5F, 0x50, 0x57, 0x52, 0x42, 0x0A, 0x80
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dsm_input() {
+        /*
+        If ((SizeOf (Arg3) == One))
+        {
+            Local0 = DerefOf (Arg3 [Zero])
+        }
+        */
+        assert_eq!(
+            If::new(
+                &Equal::new(&SizeOf::new(&Arg(3)), &ONE),
+                vec![&Store::new(
+                    &Local(0),
+                    &DerefOf::new(&Index::new(&Arg(3), &ZERO))
+                )]
+            )
+            .to_aml_bytes(),
+            [0xA0, 0x0C, 0x93, 0x87, 0x6B, 0x01, 0x70, 0x83, 0x88, 0x6B, 0x00, 0x00, 0x60]
+        );
+    }
+
+    #[test]
+    fn test_mid_method_call() {
+        /*
+        Mid (ODAT, Zero, OLEN, Local0)
+        NCAL (Arg0, 0x01)
+        */
+        assert_eq!(
+            Mid::new(&Local(0), &Path::new("ODAT"), &ZERO, &Path::new("OLEN")).to_aml_bytes(),
+            [0x9E, 0x4F, 0x44, 0x41, 0x54, 0x00, 0x4F, 0x4C, 0x45, 0x4E, 0x60]
+        );
+        assert_eq!(
+            MethodCall::new("NCAL".into(), vec![&Arg(0), &1u8]).to_aml_bytes(),
+            [0x4E, 0x43, 0x41, 0x4C, 0x68, 0x0A, 0x01]
+        );
+    }
 }
@@ -0,0 +1,559 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::DiskFile;
+use byteorder::{ByteOrder, LittleEndian};
+use std::cmp;
+use std::io::{self, Seek, SeekFrom, Write};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+const SECTOR_SHIFT: u8 = 9;
+const SECTOR_SIZE: u64 = 1 << SECTOR_SHIFT;
+
+// Status register bits.
+pub const ATA_STATUS_ERR: u8 = 1 << 0;
+pub const ATA_STATUS_DRQ: u8 = 1 << 3;
+pub const ATA_STATUS_DSC: u8 = 1 << 4;
+pub const ATA_STATUS_DRDY: u8 = 1 << 6;
+pub const ATA_STATUS_BSY: u8 = 1 << 7;
+
+// Error register bits.
+const ATA_ERROR_ABRT: u8 = 1 << 2;
+const ATA_ERROR_IDNF: u8 = 1 << 4;
+const ATA_ERROR_UNC: u8 = 1 << 6;
+
+// The device register selects the LBA addressing, instead of CHS.
+const ATA_DEVICE_LBA: u8 = 1 << 6;
+
+// Commands, as described by the ATA/ATAPI Command Set (ATA8-ACS).
+const ATA_CMD_READ_SECTORS: u8 = 0x20;
+const ATA_CMD_READ_SECTORS_EXT: u8 = 0x24;
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_READ_MULTIPLE_EXT: u8 = 0x29;
+const ATA_CMD_WRITE_SECTORS: u8 = 0x30;
+const ATA_CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_WRITE_MULTIPLE_EXT: u8 = 0x39;
+const ATA_CMD_READ_VERIFY: u8 = 0x40;
+const ATA_CMD_READ_VERIFY_EXT: u8 = 0x42;
+const ATA_CMD_SEEK: u8 = 0x70;
+const ATA_CMD_INIT_DEV_PARAMS: u8 = 0x91;
+const ATA_CMD_READ_MULTIPLE: u8 = 0xc4;
+const ATA_CMD_WRITE_MULTIPLE: u8 = 0xc5;
+const ATA_CMD_SET_MULTIPLE: u8 = 0xc6;
+const ATA_CMD_READ_DMA: u8 = 0xc8;
+const ATA_CMD_WRITE_DMA: u8 = 0xca;
+const ATA_CMD_STANDBY_IMMEDIATE: u8 = 0xe0;
+const ATA_CMD_IDLE_IMMEDIATE: u8 = 0xe1;
+const ATA_CMD_STANDBY: u8 = 0xe2;
+const ATA_CMD_IDLE: u8 = 0xe3;
+const ATA_CMD_CHECK_POWER_MODE: u8 = 0xe5;
+const ATA_CMD_FLUSH_CACHE: u8 = 0xe7;
+const ATA_CMD_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_CMD_IDENTIFY: u8 = 0xec;
+const ATA_CMD_SET_FEATURES: u8 = 0xef;
+
+// SET FEATURES subcommands.
+const ATA_FEATURE_ENABLE_WCACHE: u8 = 0x02;
+const ATA_FEATURE_XFER_MODE: u8 = 0x03;
+const ATA_FEATURE_DISABLE_RLA: u8 = 0x55;
+const ATA_FEATURE_DISABLE_WCACHE: u8 = 0x82;
+const ATA_FEATURE_ENABLE_RLA: u8 = 0xaa;
+
+// Reported by CHECK POWER MODE.
+const ATA_POWER_MODE_ACTIVE: u16 = 0xff;
+
+const IDENTIFY_WORDS: usize = 256;
+const IDENTIFY_SERIAL: usize = 10;
+const IDENTIFY_FIRMWARE: usize = 23;
+const IDENTIFY_MODEL: usize = 27;
+const IDENTIFY_MULTIPLE: usize = 59;
+const IDENTIFY_MWDMA: usize = 63;
+const IDENTIFY_FEATURES_ENABLED: usize = 85;
+const IDENTIFY_UDMA: usize = 88;
+const IDENTIFY_WCACHE: u16 = 1 << 5;
+
+const FIRMWARE_REVISION: &[u8] = b"0.1";
+const MODEL: &[u8] = b"Cloud Hypervisor SATA disk";
+// Sectors transferred per DRQ block by READ/WRITE MULTIPLE.
+const MAX_MULTIPLE_SECTORS: u16 = 16;
+
+/// Registers of an ATA command, taken from a Register Host to Device FIS.
+pub struct TaskFile {
+    pub command: u8,
+    pub features: u16,
+    pub lba: u64,
+    pub count: u16,
+    pub device: u8,
+}
+
+impl TaskFile {
+    pub fn from_fis(fis: &[u8]) -> Self {
+        TaskFile {
+            command: fis[2],
+            features: u16::from(fis[3]) | u16::from(fis[11]) << 8,
+            lba: u64::from(fis[4])
+                | u64::from(fis[5]) << 8
+                | u64::from(fis[6]) << 16
+                | u64::from(fis[8]) << 24
+                | u64::from(fis[9]) << 32
+                | u64::from(fis[10]) << 40,
+            count: LittleEndian::read_u16(&fis[12..14]),
+            device: fis[7],
+        }
+    }
+
+    // Address and number of sectors of a 28 bits command.
+    fn lba28(&self) -> Result<(u64, u64), u8> {
+        // CHS addressing is not supported.
+        if self.device & ATA_DEVICE_LBA == 0 {
+            return Err(ATA_ERROR_ABRT);
+        }
+
+        let lba = self.lba & 0xff_ffff | u64::from(self.device & 0xf) << 24;
+        let count = match self.count & 0xff {
+            0 => 256,
+            count => u64::from(count),
+        };
+        Ok((lba, count))
+    }
+
+    // Address and number of sectors of a 48 bits command.
+    fn lba48(&self) -> (u64, u64) {
+        let count = match self.count {
+            0 => 65536,
+            count => u64::from(count),
+        };
+        (self.lba, count)
+    }
+}
+
+/// Outcome of an ATA command.
+pub struct Completion {
+    pub status: u8,
+    pub error: u8,
+    pub count: u16,
+    /// Number of bytes transferred from or to the guest memory.
+    pub bytes: u32,
+    /// The data was read through PIO, and the final status has to be
+    /// reported through a PIO Setup FIS.
+    pub pio_in: bool,
+}
+
+fn is_pio_in(command: u8) -> bool {
+    match command {
+        ATA_CMD_IDENTIFY
+        | ATA_CMD_READ_SECTORS
+        | ATA_CMD_READ_SECTORS_EXT
+        | ATA_CMD_READ_MULTIPLE
+        | ATA_CMD_READ_MULTIPLE_EXT => true,
+        _ => false,
+    }
+}
+
+// ATA strings hold two characters per word, the first one in the high byte,
+// and are padded with spaces.
+fn put_string(words: &mut [u16], s: &[u8]) {
+    let c = |i: usize| match s.get(i) {
+        Some(&c) if c != 0 => c,
+        _ => b' ',
+    };
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = u16::from(c(2 * i)) << 8 | u16::from(c(2 * i + 1));
+    }
+}
+
+fn identify_data(sectors: u64, serial: &[u8]) -> [u16; IDENTIFY_WORDS] {
+    let mut id = [0u16; IDENTIFY_WORDS];
+
+    // The obsolete CHS geometry is capped to what it can describe.
+    let cylinders = cmp::min(sectors / (16 * 63), 16383) as u16;
+    let chs_sectors = u32::from(cylinders) * 16 * 63;
+    let lba28_sectors = cmp::min(sectors, 0x0fff_ffff) as u32;
+
+    // Fixed, non removable device.
+    id[0] = 0x0040;
+    id[1] = cylinders;
+    id[3] = 16;
+    id[6] = 63;
+    put_string(&mut id[IDENTIFY_SERIAL..IDENTIFY_SERIAL + 10], serial);
+    put_string(
+        &mut id[IDENTIFY_FIRMWARE..IDENTIFY_FIRMWARE + 4],
+        FIRMWARE_REVISION,
+    );
+    put_string(&mut id[IDENTIFY_MODEL..IDENTIFY_MODEL + 20], MODEL);
+    id[47] = 0x8000 | MAX_MULTIPLE_SECTORS;
+    // LBA and DMA supported.
+    id[49] = 0x0300;
+    id[50] = 0x4000;
+    // Words 54-58, 64-70 and 88 are valid.
+    id[53] = 0x0007;
+    id[54] = cylinders;
+    id[55] = 16;
+    id[56] = 63;
+    id[57] = chs_sectors as u16;
+    id[58] = (chs_sectors >> 16) as u16;
+    id[IDENTIFY_MULTIPLE] = 0x0100 | MAX_MULTIPLE_SECTORS;
+    id[60] = lba28_sectors as u16;
+    id[61] = (lba28_sectors >> 16) as u16;
+    // Multiword DMA modes 0-2 supported, mode 2 selected.
+    id[IDENTIFY_MWDMA] = 0x0407;
+    // PIO modes 3 and 4 supported.
+    id[64] = 0x0003;
+    for word in id[65..69].iter_mut() {
+        *word = 120;
+    }
+    // SATA Gen1, Gen2 and Gen3 speeds.
+    id[76] = 0x000e;
+    // ATA-4 to ATA8-ACS.
+    id[80] = 0x01f0;
+    // Write cache, 48 bits addressing, FLUSH CACHE and FLUSH CACHE EXT.
+    id[82] = IDENTIFY_WCACHE;
+    id[83] = 0x7400;
+    id[84] = 0x4000;
+    id[IDENTIFY_FEATURES_ENABLED] = IDENTIFY_WCACHE;
+    id[86] = 0x3400;
+    id[87] = 0x4000;
+    // Ultra DMA modes 0-6 supported, mode 5 selected.
+    id[IDENTIFY_UDMA] = 0x207f;
+    for (i, word) in id[100..104].iter_mut().enumerate() {
+        *word = (sectors >> (16 * i)) as u16;
+    }
+    // One logical sector per physical sector.
+    id[106] = 0x4000;
+    // Non rotating media.
+    id[217] = 0x0001;
+
+    id
+}
+
+/// ATA hard drive, backed by a disk image.
+pub struct Drive {
+    disk: Box<dyn DiskFile>,
+    sectors: u64,
+    identify: [u16; IDENTIFY_WORDS],
+    write_cache: bool,
+}
+
+impl Drive {
+    pub fn new(mut disk: Box<dyn DiskFile>, serial: &[u8]) -> io::Result<Self> {
+        let disk_size = disk.seek(SeekFrom::End(0))?;
+        if disk_size % SECTOR_SIZE != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; \
+                 the remainder will not be visible to the guest.",
+                disk_size, SECTOR_SIZE
+            );
+        }
+        let sectors = disk_size >> SECTOR_SHIFT;
+
+        Ok(Drive {
+            disk,
+            sectors,
+            identify: identify_data(sectors, serial),
+            write_cache: true,
+        })
+    }
+
+    // The IDENTIFY DEVICE data, with its integrity word.
+    fn identify_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; IDENTIFY_WORDS * 2];
+        LittleEndian::write_u16_into(&self.identify, &mut data);
+
+        data[510] = 0xa5;
+        let sum = data[..511]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        data[511] = sum.wrapping_neg();
+
+        data
+    }
+
+    pub fn execute(
+        &mut self,
+        tf: &TaskFile,
+        mem: &GuestMemoryMmap,
+        sg: &[(GuestAddress, usize)],
+    ) -> Completion {
+        let mut count = 0;
+
+        let result = match tf.command {
+            ATA_CMD_IDENTIFY => Ok(copy_to_guest(mem, sg, &self.identify_bytes())),
+            ATA_CMD_READ_DMA | ATA_CMD_READ_SECTORS | ATA_CMD_READ_MULTIPLE => tf
+                .lba28()
+                .and_then(|(lba, sectors)| self.read(mem, sg, lba, sectors)),
+            ATA_CMD_READ_DMA_EXT | ATA_CMD_READ_SECTORS_EXT | ATA_CMD_READ_MULTIPLE_EXT => {
+                let (lba, sectors) = tf.lba48();
+                self.read(mem, sg, lba, sectors)
+            }
+            ATA_CMD_WRITE_DMA | ATA_CMD_WRITE_SECTORS | ATA_CMD_WRITE_MULTIPLE => tf
+                .lba28()
+                .and_then(|(lba, sectors)| self.write(mem, sg, lba, sectors)),
+            ATA_CMD_WRITE_DMA_EXT | ATA_CMD_WRITE_SECTORS_EXT | ATA_CMD_WRITE_MULTIPLE_EXT => {
+                let (lba, sectors) = tf.lba48();
+                self.write(mem, sg, lba, sectors)
+            }
+            ATA_CMD_READ_VERIFY => tf
+                .lba28()
+                .and_then(|(lba, sectors)| self.check_range(lba, sectors))
+                .map(|_| 0),
+            ATA_CMD_READ_VERIFY_EXT => {
+                let (lba, sectors) = tf.lba48();
+                self.check_range(lba, sectors).map(|_| 0)
+            }
+            ATA_CMD_FLUSH_CACHE | ATA_CMD_FLUSH_CACHE_EXT => self.flush().map(|_| 0),
+            ATA_CMD_SET_FEATURES => self.set_features(tf).map(|_| 0),
+            ATA_CMD_SET_MULTIPLE => self.set_multiple(tf.count & 0xff).map(|_| 0),
+            ATA_CMD_CHECK_POWER_MODE => {
+                count = ATA_POWER_MODE_ACTIVE;
+                Ok(0)
+            }
+            ATA_CMD_SEEK
+            | ATA_CMD_INIT_DEV_PARAMS
+            | ATA_CMD_STANDBY_IMMEDIATE
+            | ATA_CMD_IDLE_IMMEDIATE
+            | ATA_CMD_STANDBY
+            | ATA_CMD_IDLE => Ok(0),
+            _ => {
+                debug!("Unsupported ATA command 0x{:x}", tf.command);
+                Err(ATA_ERROR_ABRT)
+            }
+        };
+
+        match result {
+            Ok(bytes) => Completion {
+                status: ATA_STATUS_DRDY | ATA_STATUS_DSC,
+                error: 0,
+                count,
+                bytes,
+                pio_in: is_pio_in(tf.command),
+            },
+            Err(error) => Completion {
+                status: ATA_STATUS_DRDY | ATA_STATUS_DSC | ATA_STATUS_ERR,
+                error,
+                count: tf.count,
+                bytes: 0,
+                pio_in: false,
+            },
+        }
+    }
+
+    fn check_range(&self, lba: u64, sectors: u64) -> Result<(), u8> {
+        match lba.checked_add(sectors) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(ATA_ERROR_IDNF),
+        }
+    }
+
+    // Move to the given sector, and return the length of the transfer.
+    fn seek(&mut self, lba: u64, sectors: u64) -> Result<usize, u8> {
+        self.check_range(lba, sectors)?;
+        self.disk
+            .seek(SeekFrom::Start(lba << SECTOR_SHIFT))
+            .map_err(|e| {
+                error!("Failed to seek disk image: {}", e);
+                ATA_ERROR_ABRT
+            })?;
+
+        Ok((sectors << SECTOR_SHIFT) as usize)
+    }
+
+    fn read(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        sg: &[(GuestAddress, usize)],
+        lba: u64,
+        sectors: u64,
+    ) -> Result<u32, u8> {
+        let len = self.seek(lba, sectors)?;
+        let mut done = 0;
+
+        for &(addr, size) in sg {
+            let size = cmp::min(size, len - done);
+            if size == 0 {
+                break;
+            }
+            mem.read_exact_from(addr, &mut self.disk, size)
+                .map_err(|e| {
+                    error!("Failed to read disk image: {:?}", e);
+                    ATA_ERROR_UNC
+                })?;
+            done += size;
+        }
+
+        Ok(done as u32)
+    }
+
+    fn write(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        sg: &[(GuestAddress, usize)],
+        lba: u64,
+        sectors: u64,
+    ) -> Result<u32, u8> {
+        let len = self.seek(lba, sectors)?;
+        let mut done = 0;
+
+        for &(addr, size) in sg {
+            let size = cmp::min(size, len - done);
+            if size == 0 {
+                break;
+            }
+            mem.write_all_to(addr, &mut self.disk, size).map_err(|e| {
+                error!("Failed to write disk image: {:?}", e);
+                ATA_ERROR_ABRT
+            })?;
+            done += size;
+        }
+
+        if !self.write_cache {
+            self.flush()?;
+        }
+
+        Ok(done as u32)
+    }
+
+    fn flush(&mut self) -> Result<(), u8> {
+        self.disk.flush().map_err(|e| {
+            error!("Failed to flush disk image: {}", e);
+            ATA_ERROR_ABRT
+        })
+    }
+
+    fn set_features(&mut self, tf: &TaskFile) -> Result<(), u8> {
+        match tf.features as u8 {
+            ATA_FEATURE_ENABLE_WCACHE => self.set_write_cache(true),
+            ATA_FEATURE_DISABLE_WCACHE => self.set_write_cache(false),
+            ATA_FEATURE_XFER_MODE => return self.set_transfer_mode(tf.count as u8),
+            ATA_FEATURE_ENABLE_RLA | ATA_FEATURE_DISABLE_RLA => {}
+            feature => {
+                debug!("Unsupported ATA feature 0x{:x}", feature);
+                return Err(ATA_ERROR_ABRT);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_write_cache(&mut self, enabled: bool) {
+        self.write_cache = enabled;
+        if enabled {
+            self.identify[IDENTIFY_FEATURES_ENABLED] |= IDENTIFY_WCACHE;
+        } else {
+            self.identify[IDENTIFY_FEATURES_ENABLED] &= !IDENTIFY_WCACHE;
+        }
+    }
+
+    // The transfer modes don't make any difference to the emulation, but are
+    // reported back to the guest through IDENTIFY DEVICE.
+    fn set_transfer_mode(&mut self, mode: u8) -> Result<(), u8> {
+        let selected = 1u16 << (mode & 0x7) << 8;
+        let (mwdma, udma) = match mode >> 3 {
+            // PIO modes.
+            0 | 1 => (0, 0),
+            4 if mode & 0x7 <= 2 => (selected, 0),
+            8 if mode & 0x7 <= 6 => (0, selected),
+            _ => return Err(ATA_ERROR_ABRT),
+        };
+
+        self.identify[IDENTIFY_MWDMA] = self.identify[IDENTIFY_MWDMA] & 0xff | mwdma;
+        self.identify[IDENTIFY_UDMA] = self.identify[IDENTIFY_UDMA] & 0xff | udma;
+
+        Ok(())
+    }
+
+    fn set_multiple(&mut self, sectors: u16) -> Result<(), u8> {
+        if sectors > MAX_MULTIPLE_SECTORS || !sectors.is_power_of_two() {
+            return Err(ATA_ERROR_ABRT);
+        }
+
+        self.identify[IDENTIFY_MULTIPLE] = 0x0100 | sectors;
+
+        Ok(())
+    }
+}
+
+// Copy data to the guest memory described by the PRDT, and return the
+// number of bytes actually copied.
+fn copy_to_guest(mem: &GuestMemoryMmap, sg: &[(GuestAddress, usize)], data: &[u8]) -> u32 {
+    let mut done = 0;
+
+    for &(addr, size) in sg {
+        let size = cmp::min(size, data.len() - done);
+        if size == 0 {
+            break;
+        }
+        if let Err(e) = mem.write_slice(&data[done..done + size], addr) {
+            error!("Failed to write guest memory: {:?}", e);
+            break;
+        }
+        done += size;
+    }
+
+    done as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn drive(sectors: u64) -> Drive {
+        let disk = Cursor::new(vec![0u8; (sectors << SECTOR_SHIFT) as usize]);
+        Drive::new(Box::new(disk), b"123456").unwrap()
+    }
+
+    fn command(command: u8, lba: u64, count: u16) -> TaskFile {
+        TaskFile {
+            command,
+            features: 0,
+            lba,
+            count,
+            device: ATA_DEVICE_LBA,
+        }
+    }
+
+    #[test]
+    fn test_identify() {
+        let drive = drive(0x1_0000_0000);
+        let data = drive.identify_bytes();
+
+        assert_eq!(data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+        assert_eq!(&data[20..28], b"214365  ");
+        assert_eq!(&data[28..40], b"            ");
+        assert_eq!(LittleEndian::read_u32(&data[120..124]), 0x0fff_ffff);
+        assert_eq!(LittleEndian::read_u64(&data[200..208]), 0x1_0000_0000);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mem = GuestMemoryMmap::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let sg = [(GuestAddress(0x1000), 0x200), (GuestAddress(0x3000), 0x600)];
+        let mut drive = drive(16);
+
+        mem.write_slice(&[0x5a; 0x200], GuestAddress(0x1000))
+            .unwrap();
+        mem.write_slice(&[0xa5; 0x600], GuestAddress(0x3000))
+            .unwrap();
+        let c = drive.execute(&command(ATA_CMD_WRITE_DMA_EXT, 8, 4), &mem, &sg);
+        assert_eq!(c.status & ATA_STATUS_ERR, 0);
+        assert_eq!(c.bytes, 0x800);
+
+        let sg = [(GuestAddress(0x8000), 0x1000)];
+        let c = drive.execute(&command(ATA_CMD_READ_DMA, 7, 5), &mem, &sg);
+        assert_eq!(c.status & ATA_STATUS_ERR, 0);
+        assert_eq!(c.bytes, 0xa00);
+        let mut data = [0u8; 0xa00];
+        mem.read_slice(&mut data, GuestAddress(0x8000)).unwrap();
+        assert!(data[..0x200].iter().all(|b| *b == 0));
+        assert!(data[0x200..0x400].iter().all(|b| *b == 0x5a));
+        assert!(data[0x400..].iter().all(|b| *b == 0xa5));
+
+        // Past the end of the disk.
+        let c = drive.execute(&command(ATA_CMD_READ_DMA_EXT, 15, 2), &mem, &sg);
+        assert_eq!(c.status & ATA_STATUS_ERR, ATA_STATUS_ERR);
+        assert_eq!(c.error, ATA_ERROR_IDNF);
+    }
+}
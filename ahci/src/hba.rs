@@ -0,0 +1,711 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::ata::{
+    Completion, Drive, TaskFile, ATA_STATUS_BSY, ATA_STATUS_DRDY, ATA_STATUS_DRQ, ATA_STATUS_DSC,
+    ATA_STATUS_ERR,
+};
+use crate::{DiskFile, Error, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use devices::BusDevice;
+use pci::{
+    BarReprogrammingParams, InterruptDelivery, InterruptParameters, PciBarConfiguration,
+    PciBarRegionType, PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciInterruptPin, PciMassStorageSubclass, PciProgrammingInterface,
+};
+use std::any::Any;
+use std::cmp;
+use std::sync::{Arc, RwLock};
+use vm_allocator::SystemAllocator;
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap, GuestUsize};
+
+const AHCI_VENDOR_ID: u16 = 0x8086;
+// Intel ICH9 AHCI controller, which most guests come with a driver for.
+const AHCI_DEV_ID: u16 = 0x2922;
+
+// The registers are exposed through the AHCI Base Address Register.
+const ABAR_INDEX: usize = 5;
+const ABAR_SIZE: u64 = 0x2000;
+
+/// Maximum number of ports, hence of disks, of a controller.
+pub const MAX_PORTS: usize = 32;
+const CMD_SLOTS: usize = 32;
+
+// Generic host control registers, as described by the Serial ATA AHCI 1.3.1
+// specification.
+const CAP: u64 = 0x00;
+const GHC: u64 = 0x04;
+const IS: u64 = 0x08;
+const PI: u64 = 0x0c;
+const VS: u64 = 0x10;
+const PORT_BASE: u64 = 0x100;
+const PORT_SIZE: u64 = 0x80;
+
+const CAP_NCS_SHIFT: u32 = 8;
+const CAP_SAM: u32 = 1 << 18;
+const CAP_ISS_GEN3: u32 = 3 << 20;
+const CAP_SCLO: u32 = 1 << 24;
+const CAP_S64A: u32 = 1 << 31;
+
+const GHC_HR: u32 = 1 << 0;
+const GHC_IE: u32 = 1 << 1;
+const GHC_AE: u32 = 1 << 31;
+
+const AHCI_VERSION: u32 = 0x0001_0300;
+
+// Port registers.
+const PX_CLB: u64 = 0x00;
+const PX_CLBU: u64 = 0x04;
+const PX_FB: u64 = 0x08;
+const PX_FBU: u64 = 0x0c;
+const PX_IS: u64 = 0x10;
+const PX_IE: u64 = 0x14;
+const PX_CMD: u64 = 0x18;
+const PX_TFD: u64 = 0x20;
+const PX_SIG: u64 = 0x24;
+const PX_SSTS: u64 = 0x28;
+const PX_SCTL: u64 = 0x2c;
+const PX_SERR: u64 = 0x30;
+const PX_CI: u64 = 0x38;
+
+const PX_IS_DHRS: u32 = 1 << 0;
+const PX_IS_PSS: u32 = 1 << 1;
+const PX_IS_HBFS: u32 = 1 << 29;
+const PX_IS_TFES: u32 = 1 << 30;
+
+const PX_CMD_ST: u32 = 1 << 0;
+const PX_CMD_SUD: u32 = 1 << 1;
+const PX_CMD_POD: u32 = 1 << 2;
+const PX_CMD_CLO: u32 = 1 << 3;
+const PX_CMD_FRE: u32 = 1 << 4;
+const PX_CMD_CCS_SHIFT: u32 = 8;
+const PX_CMD_CCS_MASK: u32 = 0x1f << PX_CMD_CCS_SHIFT;
+const PX_CMD_FR: u32 = 1 << 14;
+const PX_CMD_CR: u32 = 1 << 15;
+
+// Signature of an ATA device.
+const SIG_ATA: u32 = 0x0000_0101;
+
+// Device present and communication established at Gen3 speed.
+const SSTS_ACTIVE: u32 = 0x0000_0133;
+// Device present, but no communication established.
+const SSTS_PRESENT: u32 = 0x0000_0001;
+const SCTL_DET_MASK: u32 = 0xf;
+const SCTL_DET_COMRESET: u32 = 0x1;
+
+// Frame Information Structures.
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_TYPE_REG_D2H: u8 = 0x34;
+const FIS_TYPE_PIO_SETUP: u8 = 0x5f;
+const FIS_H2D_COMMAND: u8 = 1 << 7;
+const FIS_INTERRUPT: u8 = 1 << 6;
+const FIS_DEVICE_TO_HOST: u8 = 1 << 5;
+const FIS_LEN: usize = 20;
+const FIS_CONTROL: usize = 15;
+
+// Offsets of the received FISes, within the FIS receive area.
+const RX_FIS_PIO_SETUP: u64 = 0x20;
+const RX_FIS_D2H: u64 = 0x40;
+
+// Device control register.
+const ATA_CONTROL_SRST: u8 = 1 << 2;
+
+// Command list and command tables.
+const CMD_HEADER_SIZE: u64 = 0x20;
+const CMD_HEADER_PRDBC: u64 = 0x4;
+const CMD_HEADER_PRDTL_SHIFT: u32 = 16;
+const CMD_TABLE_PRDT: u64 = 0x80;
+const PRD_SIZE: u64 = 0x10;
+const PRD_DBC_MASK: u32 = 0x3f_ffff;
+
+type ScatterGather = Vec<(GuestAddress, usize)>;
+
+#[derive(Copy, Clone)]
+enum SataProgrammingInterface {
+    Ahci = 0x01,
+}
+
+impl PciProgrammingInterface for SataProgrammingInterface {
+    fn get_register_value(&self) -> u8 {
+        *self as u8
+    }
+}
+
+// Register Device to Host and PIO Setup FISes share the same layout for
+// their first 16 bytes.
+fn device_fis(
+    fis_type: u8,
+    flags: u8,
+    status: u8,
+    error: u8,
+    lba: u64,
+    device: u8,
+    count: u16,
+) -> [u8; FIS_LEN] {
+    let mut fis = [0u8; FIS_LEN];
+    fis[0] = fis_type;
+    fis[1] = flags;
+    fis[2] = status;
+    fis[3] = error;
+    fis[4] = lba as u8;
+    fis[5] = (lba >> 8) as u8;
+    fis[6] = (lba >> 16) as u8;
+    fis[7] = device;
+    fis[8] = (lba >> 24) as u8;
+    fis[9] = (lba >> 32) as u8;
+    fis[10] = (lba >> 40) as u8;
+    LittleEndian::write_u16(&mut fis[12..14], count);
+    fis
+}
+
+fn read_command(
+    mem: &GuestMemoryMmap,
+    header_addr: u64,
+) -> std::result::Result<([u8; FIS_LEN], ScatterGather), GuestMemoryError> {
+    let mut header = [0u8; CMD_HEADER_SIZE as usize];
+    mem.read_slice(&mut header, GuestAddress(header_addr))?;
+    let prdtl = LittleEndian::read_u32(&header[0..4]) >> CMD_HEADER_PRDTL_SHIFT;
+    let ctba = LittleEndian::read_u64(&header[8..16]) & !0x7f;
+
+    let mut fis = [0u8; FIS_LEN];
+    mem.read_slice(&mut fis, GuestAddress(ctba))?;
+
+    let mut sg = Vec::with_capacity(prdtl as usize);
+    for i in 0..u64::from(prdtl) {
+        let mut prd = [0u8; PRD_SIZE as usize];
+        let prd_addr = ctba.wrapping_add(CMD_TABLE_PRDT + i * PRD_SIZE);
+        mem.read_slice(&mut prd, GuestAddress(prd_addr))?;
+
+        let addr = LittleEndian::read_u64(&prd[0..8]) & !1;
+        let len = (LittleEndian::read_u32(&prd[12..16]) & PRD_DBC_MASK) as usize + 1;
+        sg.push((GuestAddress(addr), len));
+    }
+
+    Ok((fis, sg))
+}
+
+struct Port {
+    clb: u32,
+    clbu: u32,
+    fb: u32,
+    fbu: u32,
+    is: u32,
+    ie: u32,
+    cmd: u32,
+    tfd: u32,
+    sig: u32,
+    sctl: u32,
+    serr: u32,
+    ci: u32,
+    // A command failed, and nothing gets processed until the guest restarts
+    // the port.
+    halted: bool,
+    // Software reset asserted through a control FIS.
+    srst: bool,
+    // The device signature was posted after the last reset.
+    signature_posted: bool,
+    // An enabled interrupt condition was raised since the last check.
+    irq_pending: bool,
+    drive: Drive,
+}
+
+impl Port {
+    fn new(drive: Drive) -> Self {
+        let mut port = Port {
+            clb: 0,
+            clbu: 0,
+            fb: 0,
+            fbu: 0,
+            is: 0,
+            ie: 0,
+            cmd: 0,
+            tfd: 0,
+            sig: 0,
+            sctl: 0,
+            serr: 0,
+            ci: 0,
+            halted: false,
+            srst: false,
+            signature_posted: false,
+            irq_pending: false,
+            drive,
+        };
+        port.reset();
+        port
+    }
+
+    // Port reset, as done by an HBA reset.
+    fn reset(&mut self) {
+        self.clb = 0;
+        self.clbu = 0;
+        self.fb = 0;
+        self.fbu = 0;
+        self.is = 0;
+        self.ie = 0;
+        // Staggered spin-up and cold presence detection are not supported,
+        // the device is always spun up and powered on.
+        self.cmd = PX_CMD_SUD | PX_CMD_POD;
+        self.sctl = 0;
+        self.serr = 0;
+        self.ci = 0;
+        self.halted = false;
+        self.srst = false;
+        self.irq_pending = false;
+        self.reset_device();
+    }
+
+    // Device reset, as done by a COMRESET or a software reset.
+    fn reset_device(&mut self) {
+        // The error register reports the diagnostics passed.
+        self.tfd = 1 << 8 | u32::from(ATA_STATUS_DRDY | ATA_STATUS_DSC);
+        self.sig = SIG_ATA;
+        self.signature_posted = false;
+    }
+
+    fn raise(&mut self, cause: u32) {
+        self.is |= cause;
+        if cause & self.ie != 0 {
+            self.irq_pending = true;
+        }
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        match offset {
+            PX_CLB => self.clb,
+            PX_CLBU => self.clbu,
+            PX_FB => self.fb,
+            PX_FBU => self.fbu,
+            PX_IS => self.is,
+            PX_IE => self.ie,
+            PX_CMD => self.cmd,
+            PX_TFD => self.tfd,
+            PX_SIG => self.sig,
+            PX_SSTS if self.sctl & SCTL_DET_MASK == SCTL_DET_COMRESET => SSTS_PRESENT,
+            PX_SSTS => SSTS_ACTIVE,
+            PX_SCTL => self.sctl,
+            PX_SERR => self.serr,
+            PX_CI => self.ci,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, value: u32, mem: &GuestMemoryMmap) {
+        match offset {
+            PX_CLB => self.clb = value & !0x3ff,
+            PX_CLBU => self.clbu = value,
+            PX_FB => self.fb = value & !0xff,
+            PX_FBU => self.fbu = value,
+            PX_IS => self.is &= !value,
+            PX_IE => {
+                self.ie = value;
+                if self.is & self.ie != 0 {
+                    self.irq_pending = true;
+                }
+            }
+            PX_CMD => self.write_cmd(value, mem),
+            PX_SCTL => {
+                let det = self.sctl & SCTL_DET_MASK;
+                self.sctl = value;
+                // The COMRESET is over once the guest stops requesting it.
+                if det == SCTL_DET_COMRESET && value & SCTL_DET_MASK != SCTL_DET_COMRESET {
+                    self.reset_device();
+                    self.post_signature(mem);
+                }
+            }
+            PX_SERR => self.serr &= !value,
+            PX_CI => {
+                if self.cmd & PX_CMD_ST != 0 {
+                    self.ci |= value;
+                    self.process_commands(mem);
+                }
+            }
+            _ => debug!("Ignored AHCI port register write at 0x{:x}", offset),
+        }
+    }
+
+    fn write_cmd(&mut self, value: u32, mem: &GuestMemoryMmap) {
+        self.cmd = self.cmd & !(PX_CMD_ST | PX_CMD_FRE) | value & (PX_CMD_ST | PX_CMD_FRE);
+
+        // Command list override clears the busy state of the device.
+        if value & PX_CMD_CLO != 0 {
+            self.tfd &= !u32::from(ATA_STATUS_BSY | ATA_STATUS_DRQ);
+        }
+
+        if self.cmd & PX_CMD_FRE != 0 {
+            self.cmd |= PX_CMD_FR;
+            self.post_signature(mem);
+        } else {
+            self.cmd &= !PX_CMD_FR;
+        }
+
+        if self.cmd & PX_CMD_ST != 0 {
+            self.cmd |= PX_CMD_CR;
+        } else {
+            self.cmd &= !(PX_CMD_CR | PX_CMD_CCS_MASK);
+            self.ci = 0;
+            self.halted = false;
+        }
+    }
+
+    fn post_fis(&self, mem: &GuestMemoryMmap, offset: u64, fis: &[u8]) {
+        if self.cmd & PX_CMD_FRE == 0 {
+            return;
+        }
+
+        let fis_base = u64::from(self.fbu) << 32 | u64::from(self.fb);
+        if let Err(e) = mem.write_slice(fis, GuestAddress(fis_base + offset)) {
+            error!("Failed to post AHCI FIS: {:?}", e);
+        }
+    }
+
+    // The device sends its signature once ready, after a reset.
+    fn post_signature(&mut self, mem: &GuestMemoryMmap) {
+        if self.signature_posted || self.cmd & PX_CMD_FRE == 0 {
+            return;
+        }
+
+        let fis = device_fis(
+            FIS_TYPE_REG_D2H,
+            0,
+            self.tfd as u8,
+            (self.tfd >> 8) as u8,
+            u64::from(self.sig >> 8),
+            0,
+            self.sig as u16 & 0xff,
+        );
+        self.post_fis(mem, RX_FIS_D2H, &fis);
+        self.signature_posted = true;
+    }
+
+    fn process_commands(&mut self, mem: &GuestMemoryMmap) {
+        for slot in 0..CMD_SLOTS {
+            if self.halted {
+                break;
+            }
+            if self.ci & (1 << slot) != 0 {
+                self.process_command(mem, slot);
+            }
+        }
+    }
+
+    fn process_command(&mut self, mem: &GuestMemoryMmap, slot: usize) {
+        self.cmd = self.cmd & !PX_CMD_CCS_MASK | (slot as u32) << PX_CMD_CCS_SHIFT;
+
+        let command_list = u64::from(self.clbu) << 32 | u64::from(self.clb);
+        let header_addr = command_list + slot as u64 * CMD_HEADER_SIZE;
+        let (fis, sg) = match read_command(mem, header_addr) {
+            Ok(command) => command,
+            Err(e) => {
+                error!("Failed to read AHCI command: {:?}", e);
+                self.halted = true;
+                self.raise(PX_IS_HBFS);
+                return;
+            }
+        };
+
+        if fis[0] != FIS_TYPE_REG_H2D {
+            warn!("Unsupported AHCI command FIS type 0x{:x}", fis[0]);
+            self.ci &= !(1 << slot);
+            return;
+        }
+
+        // Device control register update, only meant for software resets.
+        if fis[1] & FIS_H2D_COMMAND == 0 {
+            if fis[FIS_CONTROL] & ATA_CONTROL_SRST != 0 {
+                self.srst = true;
+            } else if self.srst {
+                self.srst = false;
+                self.reset_device();
+                self.post_signature(mem);
+            }
+            self.ci &= !(1 << slot);
+            return;
+        }
+
+        let tf = TaskFile::from_fis(&fis);
+        let completion = self.drive.execute(&tf, mem, &sg);
+        self.complete(mem, header_addr, slot, &tf, &completion);
+    }
+
+    fn complete(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        header_addr: u64,
+        slot: usize,
+        tf: &TaskFile,
+        c: &Completion,
+    ) {
+        let prdbc = GuestAddress(header_addr + CMD_HEADER_PRDBC);
+        if let Err(e) = mem.write_obj(c.bytes, prdbc) {
+            error!("Failed to update AHCI command header: {:?}", e);
+        }
+
+        self.tfd = u32::from(c.error) << 8 | u32::from(c.status);
+
+        if c.pio_in {
+            let mut fis = device_fis(
+                FIS_TYPE_PIO_SETUP,
+                FIS_INTERRUPT | FIS_DEVICE_TO_HOST,
+                c.status | ATA_STATUS_DRQ,
+                c.error,
+                tf.lba,
+                tf.device,
+                c.count,
+            );
+            // Final status, and number of bytes transferred.
+            fis[15] = c.status;
+            LittleEndian::write_u16(&mut fis[16..18], cmp::min(c.bytes, 0xffff) as u16);
+            self.post_fis(mem, RX_FIS_PIO_SETUP, &fis);
+            self.raise(PX_IS_PSS);
+        } else {
+            let fis = device_fis(
+                FIS_TYPE_REG_D2H,
+                FIS_INTERRUPT,
+                c.status,
+                c.error,
+                tf.lba,
+                tf.device,
+                c.count,
+            );
+            self.post_fis(mem, RX_FIS_D2H, &fis);
+            self.raise(PX_IS_DHRS);
+        }
+
+        if c.status & ATA_STATUS_ERR != 0 {
+            // The failed command is left in the command list, for the guest
+            // to find it out through PxCMD.CCS.
+            self.halted = true;
+            self.raise(PX_IS_TFES);
+        } else {
+            self.ci &= !(1 << slot);
+        }
+    }
+}
+
+/// Emulated AHCI PCI controller, with one SATA drive per port.
+pub struct Ahci {
+    configuration: PciConfiguration,
+    memory: Arc<RwLock<GuestMemoryMmap>>,
+    interrupt_cb: Option<Arc<InterruptDelivery>>,
+    ghc: u32,
+    is: u32,
+    ports: Vec<Port>,
+}
+
+impl Ahci {
+    /// Create a new AHCI controller, with a drive for each disk image. The
+    /// serial number of each drive is given along with its disk image.
+    pub fn new(
+        disks: Vec<(Box<dyn DiskFile>, Vec<u8>)>,
+        memory: Arc<RwLock<GuestMemoryMmap>>,
+    ) -> Result<Self> {
+        if disks.len() > MAX_PORTS {
+            return Err(Error::TooManyDisks(disks.len()));
+        }
+
+        let mut ports = Vec::with_capacity(disks.len());
+        for (disk, serial) in disks {
+            let drive = Drive::new(disk, &serial).map_err(Error::GetDiskSize)?;
+            ports.push(Port::new(drive));
+        }
+
+        let configuration = PciConfiguration::new(
+            AHCI_VENDOR_ID,
+            AHCI_DEV_ID,
+            PciClassCode::MassStorage,
+            &PciMassStorageSubclass::SATAController,
+            Some(&SataProgrammingInterface::Ahci),
+            PciHeaderType::Device,
+            AHCI_VENDOR_ID,
+            AHCI_DEV_ID,
+            None,
+        );
+
+        Ok(Ahci {
+            configuration,
+            memory,
+            interrupt_cb: None,
+            ghc: GHC_AE,
+            is: 0,
+            ports,
+        })
+    }
+
+    fn reset(&mut self) {
+        self.ghc = GHC_AE;
+        self.is = 0;
+        for port in self.ports.iter_mut() {
+            port.reset();
+        }
+    }
+
+    fn signal_interrupt(&self) {
+        if let Some(cb) = &self.interrupt_cb {
+            if let Err(e) = (cb)(InterruptParameters { msix: None }) {
+                error!("Failed to signal AHCI interrupt: {:?}", e);
+            }
+        }
+    }
+
+    fn update_interrupt(&mut self, index: usize) {
+        let port = &mut self.ports[index];
+        let irq_pending = port.irq_pending;
+        port.irq_pending = false;
+        if port.is & port.ie != 0 {
+            self.is |= 1 << index;
+        }
+
+        if irq_pending && self.ghc & GHC_IE != 0 {
+            self.signal_interrupt();
+        }
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        match offset {
+            CAP => {
+                CAP_S64A
+                    | CAP_SCLO
+                    | CAP_ISS_GEN3
+                    | CAP_SAM
+                    | (CMD_SLOTS as u32 - 1) << CAP_NCS_SHIFT
+                    | (self.ports.len() as u32).saturating_sub(1)
+            }
+            GHC => self.ghc,
+            IS => self.is,
+            PI => ((1u64 << self.ports.len()) - 1) as u32,
+            VS => AHCI_VERSION,
+            o if o >= PORT_BASE => {
+                let index = ((o - PORT_BASE) / PORT_SIZE) as usize;
+                self.ports
+                    .get(index)
+                    .map_or(0, |port| port.read((o - PORT_BASE) % PORT_SIZE))
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_reg(&mut self, offset: u64, value: u32) {
+        match offset {
+            GHC => {
+                if value & GHC_HR != 0 {
+                    self.reset();
+                    return;
+                }
+
+                self.ghc = GHC_AE | value & GHC_IE;
+                if self.ghc & GHC_IE != 0 && self.is != 0 {
+                    self.signal_interrupt();
+                }
+            }
+            IS => {
+                self.is &= !value;
+                // Ports with an interrupt condition still set keep their bit.
+                for (index, port) in self.ports.iter().enumerate() {
+                    if port.is & port.ie != 0 {
+                        self.is |= 1 << index;
+                    }
+                }
+            }
+            o if o >= PORT_BASE => {
+                let index = ((o - PORT_BASE) / PORT_SIZE) as usize;
+                if index >= self.ports.len() {
+                    return;
+                }
+
+                let mem = self.memory.read().unwrap();
+                self.ports[index].write((o - PORT_BASE) % PORT_SIZE, value, &mem);
+                drop(mem);
+                self.update_interrupt(index);
+            }
+            _ => debug!("Ignored AHCI register write at 0x{:x}", offset),
+        }
+    }
+}
+
+impl BusDevice for Ahci {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for Ahci {
+    fn assign_pin_irq(
+        &mut self,
+        irq_cb: Arc<InterruptDelivery>,
+        irq_num: u32,
+        irq_pin: PciInterruptPin,
+    ) {
+        self.configuration.set_irq(irq_num as u8, irq_pin);
+        self.interrupt_cb = Some(irq_cb);
+    }
+
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        // The registers live below 4GiB, for the sake of 32 bits guests.
+        let abar_addr = allocator
+            .allocate_mmio_hole_addresses(None, ABAR_SIZE, Some(ABAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(ABAR_SIZE))?;
+        let config = PciBarConfiguration::default()
+            .set_register_index(ABAR_INDEX)
+            .set_address(abar_addr.raw_value())
+            .set_size(ABAR_SIZE)
+            .set_region_type(PciBarRegionType::Memory32BitRegion);
+        self.configuration
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(abar_addr.raw_value(), e))?;
+
+        Ok(vec![(
+            abar_addr,
+            ABAR_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+        )])
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.len() != 4 || offset % 4 != 0 {
+            warn!("Invalid AHCI register read at 0x{:x}", offset);
+            return;
+        }
+
+        LittleEndian::write_u32(data, self.read_reg(offset));
+    }
+
+    fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if data.len() != 4 || offset % 4 != 0 {
+            warn!("Invalid AHCI register write at 0x{:x}", offset);
+            return;
+        }
+
+        self.write_reg(offset, LittleEndian::read_u32(data));
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
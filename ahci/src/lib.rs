@@ -0,0 +1,55 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulated AHCI (SATA) host bus adapter.
+//!
+//! This is meant for guests coming without any virtio-blk driver, so that
+//! they can at least boot from a SATA hard drive and have the virtio drivers
+//! installed afterwards. Each disk is attached to its own port, and the
+//! commands are handled synchronously from the vCPU thread issuing them.
+extern crate byteorder;
+extern crate devices;
+#[macro_use]
+extern crate log;
+extern crate pci;
+extern crate vm_allocator;
+extern crate vm_memory;
+
+mod ata;
+mod hba;
+
+use std::fmt::{self, Display};
+use std::io::{self, Read, Seek, Write};
+
+pub use hba::{Ahci, MAX_PORTS};
+
+/// Backing file of an emulated drive.
+pub trait DiskFile: Read + Seek + Write + Send {}
+impl<D: Read + Seek + Write + Send> DiskFile for D {}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot get the size of a disk image.
+    GetDiskSize(io::Error),
+    /// More disks than ports on the controller.
+    TooManyDisks(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            GetDiskSize(e) => write!(f, "failed to get disk image size: {}", e),
+            TooManyDisks(n) => write!(
+                f,
+                "{} disks requested, but only {} ports are available",
+                n, MAX_PORTS
+            ),
+        }
+    }
+}
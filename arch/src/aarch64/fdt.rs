@@ -0,0 +1,567 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt::{self, Display};
+use std::result;
+
+use super::layout;
+use super::regs::mpidr;
+use crate::InitramfsConfig;
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestUsize};
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+// Header, followed by the single, terminating, memory reservation entry.
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_RSVMAP_SIZE: usize = 16;
+
+// Interrupt specifier cells, as defined by the GIC bindings.
+const GIC_FDT_IRQ_TYPE_SPI: u32 = 0;
+const GIC_FDT_IRQ_TYPE_PPI: u32 = 1;
+const IRQ_TYPE_EDGE_RISING: u32 = 1;
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+// Private peripheral interrupts used by the virtual timers and the GIC
+// maintenance interrupt.
+const ARCH_TIMER_VIRT_PPI: u32 = 11;
+const ARCH_TIMER_PHYS_SECURE_PPI: u32 = 13;
+const ARCH_TIMER_PHYS_NONSECURE_PPI: u32 = 14;
+const ARCH_TIMER_HYP_PPI: u32 = 10;
+const GIC_MAINTENANCE_PPI: u32 = 9;
+
+const GIC_PHANDLE: u32 = 1;
+const ITS_PHANDLE: u32 = 2;
+const CLOCK_PHANDLE: u32 = 3;
+
+// Clock feeding the PL011, used by the guest to compute the baud rate.
+const APB_PCLK_FREQUENCY: u32 = 24_000_000;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// A node was ended while none was open.
+    UnbalancedNode,
+    /// The tree was finished with nodes left open.
+    UnclosedNode,
+    /// A node or property name contains a NUL byte.
+    InvalidString,
+    /// The device tree doesn't fit the space reserved for it.
+    TooLarge(usize),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            UnbalancedNode => write!(f, "device tree node ended while none was open"),
+            UnclosedNode => write!(f, "device tree finished with unclosed nodes"),
+            InvalidString => write!(f, "device tree string contains a NUL byte"),
+            TooLarge(size) => write!(f, "device tree of {} bytes is too large", size),
+        }
+    }
+}
+
+/// Writes a flattened device tree blob, as described by the devicetree
+/// specification.
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        FdtWriter {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn append_u32(&mut self, val: u32) {
+        self.structure.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.structure.len() & 0x3 != 0 {
+            self.structure.push(0);
+        }
+    }
+
+    fn string_offset(&mut self, name: &str) -> Result<u32> {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return Ok(*offset);
+        }
+
+        let offset = self.strings.len() as u32;
+        let name_cstr = CString::new(name).map_err(|_| Error::InvalidString)?;
+        self.strings
+            .extend_from_slice(name_cstr.as_bytes_with_nul());
+        self.string_offsets.insert(name.to_string(), offset);
+        Ok(offset)
+    }
+
+    pub fn begin_node(&mut self, name: &str) -> Result<()> {
+        let name_cstr = CString::new(name).map_err(|_| Error::InvalidString)?;
+        self.append_u32(FDT_BEGIN_NODE);
+        self.structure
+            .extend_from_slice(name_cstr.as_bytes_with_nul());
+        self.align();
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn end_node(&mut self) -> Result<()> {
+        if self.depth == 0 {
+            return Err(Error::UnbalancedNode);
+        }
+        self.append_u32(FDT_END_NODE);
+        self.depth -= 1;
+        Ok(())
+    }
+
+    pub fn property(&mut self, name: &str, val: &[u8]) -> Result<()> {
+        let name_offset = self.string_offset(name)?;
+        self.append_u32(FDT_PROP);
+        self.append_u32(val.len() as u32);
+        self.append_u32(name_offset);
+        self.structure.extend_from_slice(val);
+        self.align();
+        Ok(())
+    }
+
+    pub fn property_null(&mut self, name: &str) -> Result<()> {
+        self.property(name, &[])
+    }
+
+    pub fn property_string(&mut self, name: &str, val: &str) -> Result<()> {
+        let val_cstr = CString::new(val).map_err(|_| Error::InvalidString)?;
+        self.property(name, val_cstr.as_bytes_with_nul())
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) -> Result<()> {
+        self.property(name, &val.to_be_bytes())
+    }
+
+    pub fn property_u64(&mut self, name: &str, val: u64) -> Result<()> {
+        self.property(name, &val.to_be_bytes())
+    }
+
+    pub fn property_array_u32(&mut self, name: &str, cells: &[u32]) -> Result<()> {
+        let val: Vec<u8> = cells
+            .iter()
+            .flat_map(|c| c.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &val)
+    }
+
+    pub fn property_array_u64(&mut self, name: &str, cells: &[u64]) -> Result<()> {
+        let val: Vec<u8> = cells
+            .iter()
+            .flat_map(|c| c.to_be_bytes().to_vec())
+            .collect();
+        self.property(name, &val)
+    }
+
+    /// Returns the device tree blob, with the boot CPU set to `boot_cpuid`.
+    pub fn finish(mut self, boot_cpuid: u32) -> Result<Vec<u8>> {
+        if self.depth != 0 {
+            return Err(Error::UnclosedNode);
+        }
+        self.append_u32(FDT_END);
+
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + FDT_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let totalsize = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            totalsize as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            boot_cpuid,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob = Vec::with_capacity(totalsize);
+        for field in header.iter() {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0u8; FDT_RSVMAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+
+        Ok(blob)
+    }
+}
+
+impl Default for FdtWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Creates the device tree describing the platform to the guest.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `cmdline` - The kernel command line.
+/// * `initramfs` - Guest memory the initramfs was loaded in, if any.
+/// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `pci_enabled` - Whether the PCI host bridge should be described.
+pub fn create_fdt(
+    guest_mem: &GuestMemoryMmap,
+    cmdline: &str,
+    initramfs: &Option<InitramfsConfig>,
+    num_cpus: u8,
+    pci_enabled: bool,
+    virtio_mmio_devices: &[(GuestAddress, GuestUsize, u32)],
+) -> Result<Vec<u8>> {
+    let mut fdt = FdtWriter::new();
+
+    fdt.begin_node("")?;
+    fdt.property_string("compatible", "linux,dummy-virt")?;
+    // All the addresses and sizes are 64 bits wide.
+    fdt.property_u32("#address-cells", 0x2)?;
+    fdt.property_u32("#size-cells", 0x2)?;
+    fdt.property_u32("interrupt-parent", GIC_PHANDLE)?;
+
+    create_cpu_nodes(&mut fdt, num_cpus)?;
+    create_memory_node(&mut fdt, guest_mem)?;
+    create_chosen_node(&mut fdt, cmdline, initramfs)?;
+    create_gic_node(&mut fdt, num_cpus)?;
+    create_timer_node(&mut fdt)?;
+    create_clock_node(&mut fdt)?;
+    create_serial_node(&mut fdt)?;
+    create_psci_node(&mut fdt)?;
+    if pci_enabled {
+        create_pci_node(&mut fdt)?;
+    }
+    for (addr, size, irq) in virtio_mmio_devices {
+        create_virtio_mmio_node(&mut fdt, *addr, *size, *irq)?;
+    }
+
+    fdt.end_node()?;
+
+    let blob = fdt.finish(0)?;
+    if blob.len() as GuestUsize > layout::FDT_MAX_SIZE {
+        return Err(Error::TooLarge(blob.len()));
+    }
+
+    Ok(blob)
+}
+
+fn create_cpu_nodes(fdt: &mut FdtWriter, num_cpus: u8) -> Result<()> {
+    fdt.begin_node("cpus")?;
+    // The cpu nodes are addressed by their MPIDR affinity bits.
+    fdt.property_u32("#address-cells", 0x1)?;
+    fdt.property_u32("#size-cells", 0x0)?;
+
+    for cpu_id in 0..num_cpus {
+        let reg = mpidr(cpu_id) as u32;
+        fdt.begin_node(&format!("cpu@{:x}", reg))?;
+        fdt.property_string("device_type", "cpu")?;
+        fdt.property_string("compatible", "arm,arm-v8")?;
+        if num_cpus > 1 {
+            fdt.property_string("enable-method", "psci")?;
+        }
+        fdt.property_u32("reg", reg)?;
+        fdt.end_node()?;
+    }
+
+    fdt.end_node()
+}
+
+fn create_memory_node(fdt: &mut FdtWriter, guest_mem: &GuestMemoryMmap) -> Result<()> {
+    let mem_size = guest_mem
+        .end_addr()
+        .unchecked_offset_from(layout::RAM_64BIT_START)
+        + 1;
+
+    fdt.begin_node("memory")?;
+    fdt.property_string("device_type", "memory")?;
+    fdt.property_array_u64("reg", &[layout::RAM_64BIT_START.raw_value(), mem_size])?;
+    fdt.end_node()
+}
+
+fn create_chosen_node(
+    fdt: &mut FdtWriter,
+    cmdline: &str,
+    initramfs: &Option<InitramfsConfig>,
+) -> Result<()> {
+    fdt.begin_node("chosen")?;
+    fdt.property_string("bootargs", cmdline)?;
+    if let Some(initramfs) = initramfs {
+        let start = initramfs.address.raw_value();
+        fdt.property_u64("linux,initrd-start", start)?;
+        fdt.property_u64("linux,initrd-end", start + initramfs.size as u64)?;
+    }
+    fdt.property_string(
+        "stdout-path",
+        &format!(
+            "/pl011@{:x}",
+            layout::LEGACY_SERIAL_MAPPED_IO_START.raw_value()
+        ),
+    )?;
+    fdt.end_node()
+}
+
+fn create_gic_node(fdt: &mut FdtWriter, num_cpus: u8) -> Result<()> {
+    let redists_size = layout::GIC_V3_REDIST_SIZE * GuestUsize::from(num_cpus);
+
+    fdt.begin_node(&format!("intc@{:x}", layout::GIC_V3_DIST_START.raw_value()))?;
+    fdt.property_string("compatible", "arm,gic-v3")?;
+    fdt.property_null("interrupt-controller")?;
+    fdt.property_u32("#interrupt-cells", 3)?;
+    fdt.property_u32("#address-cells", 2)?;
+    fdt.property_u32("#size-cells", 2)?;
+    fdt.property_null("ranges")?;
+    fdt.property_array_u64(
+        "reg",
+        &[
+            layout::GIC_V3_DIST_START.raw_value(),
+            layout::GIC_V3_DIST_SIZE,
+            layout::GIC_V3_REDIST_START.raw_value(),
+            redists_size,
+        ],
+    )?;
+    fdt.property_u32("phandle", GIC_PHANDLE)?;
+    fdt.property_array_u32(
+        "interrupts",
+        &[
+            GIC_FDT_IRQ_TYPE_PPI,
+            GIC_MAINTENANCE_PPI,
+            IRQ_TYPE_LEVEL_HIGH,
+        ],
+    )?;
+
+    // The ITS translates the MSIs of the PCI devices.
+    fdt.begin_node(&format!("msic@{:x}", layout::GIC_V3_ITS_START.raw_value()))?;
+    fdt.property_string("compatible", "arm,gic-v3-its")?;
+    fdt.property_null("msi-controller")?;
+    fdt.property_u32("phandle", ITS_PHANDLE)?;
+    fdt.property_array_u64(
+        "reg",
+        &[
+            layout::GIC_V3_ITS_START.raw_value(),
+            layout::GIC_V3_ITS_SIZE,
+        ],
+    )?;
+    fdt.end_node()?;
+
+    fdt.end_node()
+}
+
+fn create_timer_node(fdt: &mut FdtWriter) -> Result<()> {
+    let mut interrupts = Vec::new();
+    for ppi in [
+        ARCH_TIMER_PHYS_SECURE_PPI,
+        ARCH_TIMER_PHYS_NONSECURE_PPI,
+        ARCH_TIMER_VIRT_PPI,
+        ARCH_TIMER_HYP_PPI,
+    ]
+    .iter()
+    {
+        interrupts.extend_from_slice(&[GIC_FDT_IRQ_TYPE_PPI, *ppi, IRQ_TYPE_LEVEL_HIGH]);
+    }
+
+    fdt.begin_node("timer")?;
+    fdt.property_string("compatible", "arm,armv8-timer")?;
+    fdt.property_null("always-on")?;
+    fdt.property_array_u32("interrupts", &interrupts)?;
+    fdt.end_node()
+}
+
+fn create_clock_node(fdt: &mut FdtWriter) -> Result<()> {
+    fdt.begin_node("apb-pclk")?;
+    fdt.property_string("compatible", "fixed-clock")?;
+    fdt.property_u32("#clock-cells", 0x0)?;
+    fdt.property_u32("clock-frequency", APB_PCLK_FREQUENCY)?;
+    fdt.property_string("clock-output-names", "clk24mhz")?;
+    fdt.property_u32("phandle", CLOCK_PHANDLE)?;
+    fdt.end_node()
+}
+
+fn create_serial_node(fdt: &mut FdtWriter) -> Result<()> {
+    fdt.begin_node(&format!(
+        "pl011@{:x}",
+        layout::LEGACY_SERIAL_MAPPED_IO_START.raw_value()
+    ))?;
+    // Both strings are matched on, so they are written as a string list.
+    fdt.property("compatible", b"arm,pl011\0arm,primecell\0")?;
+    fdt.property_array_u64(
+        "reg",
+        &[
+            layout::LEGACY_SERIAL_MAPPED_IO_START.raw_value(),
+            layout::LEGACY_SERIAL_SIZE,
+        ],
+    )?;
+    fdt.property_u32("clocks", CLOCK_PHANDLE)?;
+    fdt.property_string("clock-names", "apb_pclk")?;
+    fdt.property_array_u32(
+        "interrupts",
+        &[
+            GIC_FDT_IRQ_TYPE_SPI,
+            layout::LEGACY_SERIAL_IRQ,
+            IRQ_TYPE_EDGE_RISING,
+        ],
+    )?;
+    fdt.end_node()
+}
+
+fn create_psci_node(fdt: &mut FdtWriter) -> Result<()> {
+    // KVM implements PSCI, through hypervisor calls, to power the vCPUs on
+    // and off.
+    fdt.begin_node("psci")?;
+    fdt.property_string("compatible", "arm,psci-0.2")?;
+    fdt.property_string("method", "hvc")?;
+    fdt.end_node()
+}
+
+fn create_pci_node(fdt: &mut FdtWriter) -> Result<()> {
+    // Bus number 0 to the last one the MMCONFIG area has room for.
+    let last_bus = (layout::PCI_MMCONFIG_SIZE >> 20) as u32 - 1;
+    // 32-bit non-prefetchable memory space, mapped 1:1.
+    const PCI_RANGE_MMIO32: u32 = 0x0200_0000;
+
+    fdt.begin_node(&format!("pci@{:x}", layout::PCI_MMCONFIG_START.raw_value()))?;
+    fdt.property_string("compatible", "pci-host-ecam-generic")?;
+    fdt.property_string("device_type", "pci")?;
+    fdt.property_array_u32("bus-range", &[0, last_bus])?;
+    fdt.property_u32("linux,pci-domain", 0)?;
+    fdt.property_u32("#address-cells", 3)?;
+    fdt.property_u32("#size-cells", 2)?;
+    fdt.property_array_u64(
+        "reg",
+        &[
+            layout::PCI_MMCONFIG_START.raw_value(),
+            layout::PCI_MMCONFIG_SIZE,
+        ],
+    )?;
+    let mmio_start = layout::MEM_32BIT_DEVICES_START.raw_value();
+    let mmio_size = layout::MEM_32BIT_DEVICES_SIZE;
+    fdt.property_array_u32(
+        "ranges",
+        &[
+            PCI_RANGE_MMIO32,
+            (mmio_start >> 32) as u32,
+            mmio_start as u32,
+            (mmio_start >> 32) as u32,
+            mmio_start as u32,
+            (mmio_size >> 32) as u32,
+            mmio_size as u32,
+        ],
+    )?;
+    fdt.property_null("dma-coherent")?;
+    fdt.property_u32("msi-parent", ITS_PHANDLE)?;
+    fdt.end_node()
+}
+
+fn create_virtio_mmio_node(
+    fdt: &mut FdtWriter,
+    addr: GuestAddress,
+    size: GuestUsize,
+    irq: u32,
+) -> Result<()> {
+    fdt.begin_node(&format!("virtio_mmio@{:x}", addr.raw_value()))?;
+    fdt.property_string("compatible", "virtio,mmio")?;
+    fdt.property_array_u64("reg", &[addr.raw_value(), size])?;
+    fdt.property_array_u32(
+        "interrupts",
+        &[GIC_FDT_IRQ_TYPE_SPI, irq, IRQ_TYPE_EDGE_RISING],
+    )?;
+    fdt.property_null("dma-coherent")?;
+    fdt.end_node()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(blob: &[u8], offset: usize) -> u32 {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&blob[offset..offset + 4]);
+        u32::from_be_bytes(bytes)
+    }
+
+    #[test]
+    fn test_fdt_writer() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("").unwrap();
+        fdt.property_u32("a", 0x1234_5678).unwrap();
+        fdt.property_string("b", "xy").unwrap();
+        fdt.begin_node("c@0").unwrap();
+        fdt.property_null("a").unwrap();
+        fdt.end_node().unwrap();
+        fdt.end_node().unwrap();
+        let blob = fdt.finish(0).unwrap();
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        assert_eq!(be32(&blob, 20), FDT_VERSION);
+
+        let off_dt_struct = be32(&blob, 8) as usize;
+        let off_dt_strings = be32(&blob, 12) as usize;
+        assert_eq!(off_dt_struct, FDT_HEADER_SIZE + FDT_RSVMAP_SIZE);
+        // The property names are only stored once.
+        assert_eq!(&blob[off_dt_strings..], b"a\0b\0");
+
+        let expected: Vec<u32> = vec![
+            FDT_BEGIN_NODE,
+            0,
+            FDT_PROP,
+            4,
+            0,
+            0x1234_5678,
+            FDT_PROP,
+            3,
+            2,
+            u32::from_be_bytes(*b"xy\0\0"),
+            FDT_BEGIN_NODE,
+            u32::from_be_bytes(*b"c@0\0"),
+            FDT_PROP,
+            0,
+            0,
+            FDT_END_NODE,
+            FDT_END_NODE,
+            FDT_END,
+        ];
+        let structure: Vec<u32> = (off_dt_struct..off_dt_strings)
+            .step_by(4)
+            .map(|offset| be32(&blob, offset))
+            .collect();
+        assert_eq!(structure, expected);
+    }
+
+    #[test]
+    fn test_fdt_writer_unbalanced() {
+        let mut fdt = FdtWriter::new();
+        assert_eq!(fdt.end_node(), Err(Error::UnbalancedNode));
+        fdt.begin_node("").unwrap();
+        assert_eq!(fdt.finish(0), Err(Error::UnclosedNode));
+
+        let mut fdt = FdtWriter::new();
+        assert_eq!(fdt.begin_node("a\0b"), Err(Error::InvalidString));
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::sync::Arc;
+use std::{io, result};
+
+use super::layout;
+use hypervisor::aarch64::{
+    GicDevice, KVM_DEV_ARM_VGIC_CTRL_INIT, KVM_DEV_ARM_VGIC_GRP_ADDR, KVM_DEV_ARM_VGIC_GRP_CTRL,
+    KVM_DEV_ARM_VGIC_GRP_NR_IRQS, KVM_VGIC_ITS_ADDR_TYPE, KVM_VGIC_V3_ADDR_TYPE_DIST,
+    KVM_VGIC_V3_ADDR_TYPE_REDIST,
+};
+use hypervisor::{Device, DeviceAttr};
+use vm_memory::Address;
+
+/// Number of interrupts the distributor handles: the 32 private ones,
+/// followed by the shared ones the devices are given.
+pub const GIC_NR_IRQS: u32 = 128;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The redistributors range has no room for that many vCPUs.
+    TooManyVcpus(u8),
+    /// Failed to create the interrupt controller device.
+    CreateDevice(io::Error),
+    /// Failed to set an attribute of the interrupt controller device.
+    SetDeviceAttr(io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// The emulated GICv3 and its Interrupt Translation Service.
+pub struct Gic {
+    gic: Arc<dyn Device>,
+    its: Arc<dyn Device>,
+}
+
+fn set_attr(device: &Arc<dyn Device>, group: u32, attr: u64, addr: u64) -> Result<()> {
+    let attr = DeviceAttr {
+        flags: 0,
+        group,
+        attr,
+        addr,
+    };
+    device.set_device_attr(&attr).map_err(Error::SetDeviceAttr)
+}
+
+// Points the attribute at the address value, which KVM reads it from.
+fn set_addr_attr(device: &Arc<dyn Device>, group: u32, attr: u32, value: &u64) -> Result<()> {
+    set_attr(device, group, u64::from(attr), value as *const u64 as u64)
+}
+
+impl Gic {
+    /// Creates the interrupt controller of a VM with `vcpu_count` vCPUs, at
+    /// its fixed place in the memory layout. The vCPUs have to be created
+    /// before the controller is finalized.
+    pub fn new(vm: &Arc<dyn hypervisor::Vm>, vcpu_count: u8) -> Result<Self> {
+        if u64::from(vcpu_count) > layout::GIC_V3_REDIST_MAX {
+            return Err(Error::TooManyVcpus(vcpu_count));
+        }
+
+        let gic = vm
+            .create_gic_device(GicDevice::V3)
+            .map_err(Error::CreateDevice)?;
+        set_addr_attr(
+            &gic,
+            KVM_DEV_ARM_VGIC_GRP_ADDR,
+            KVM_VGIC_V3_ADDR_TYPE_DIST,
+            &layout::GIC_V3_DIST_START.raw_value(),
+        )?;
+        set_addr_attr(
+            &gic,
+            KVM_DEV_ARM_VGIC_GRP_ADDR,
+            KVM_VGIC_V3_ADDR_TYPE_REDIST,
+            &layout::GIC_V3_REDIST_START.raw_value(),
+        )?;
+        let nr_irqs = GIC_NR_IRQS;
+        set_attr(
+            &gic,
+            KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+            0,
+            &nr_irqs as *const u32 as u64,
+        )?;
+
+        let its = vm
+            .create_gic_device(GicDevice::Its)
+            .map_err(Error::CreateDevice)?;
+        set_addr_attr(
+            &its,
+            KVM_DEV_ARM_VGIC_GRP_ADDR,
+            KVM_VGIC_ITS_ADDR_TYPE,
+            &layout::GIC_V3_ITS_START.raw_value(),
+        )?;
+        set_attr(
+            &its,
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+            0,
+        )?;
+
+        Ok(Gic { gic, its })
+    }
+
+    /// Finalizes the interrupt controller, once all the vCPUs exist.
+    pub fn finalize(&self) -> Result<()> {
+        set_attr(
+            &self.gic,
+            KVM_DEV_ARM_VGIC_GRP_CTRL,
+            u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+            0,
+        )
+    }
+
+    /// Returns the Interrupt Translation Service device.
+    pub fn its(&self) -> &Arc<dyn Device> {
+        &self.its
+    }
+}
@@ -1,7 +1,71 @@
 // Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-/// Kernel command line start address.
-pub const CMDLINE_START: usize = 0x0;
-/// Kernel command line start address maximum size.
-pub const CMDLINE_MAX_SIZE: usize = 0x0;
+use vm_memory::{GuestAddress, GuestUsize};
+
+/*
+
+Memory layout documentation and constants
+~~~~~~ ~~~~~~ ~~~~~~~~~~~~~ ~~~ ~~~~~~~~~
+
+Constants are in order and grouped by range. Take care to update all references
+when making changes and keep them in order.
+
+*/
+
+// ** Platform devices (start: 0, length: 256MiB) **
+pub const PLATFORM_DEVICES_START: GuestAddress = GuestAddress(0x0);
+pub const PLATFORM_DEVICES_SIZE: GuestUsize = (256 << 20);
+
+// == Fixed addresses within the "Platform devices" range: ==
+
+// GICv3 distributor (start: 128MiB, length: 64KiB)
+pub const GIC_V3_DIST_START: GuestAddress = GuestAddress(0x0800_0000);
+pub const GIC_V3_DIST_SIZE: GuestUsize = 0x1_0000;
+
+// GICv3 Interrupt Translation Service (start: 128MiB + 512KiB, length: 128KiB)
+pub const GIC_V3_ITS_START: GuestAddress = GuestAddress(0x0808_0000);
+pub const GIC_V3_ITS_SIZE: GuestUsize = 0x2_0000;
+
+// GICv3 redistributors, one per vCPU (start: 128MiB + 640KiB, up to 144MiB)
+pub const GIC_V3_REDIST_START: GuestAddress = GuestAddress(0x080a_0000);
+pub const GIC_V3_REDIST_SIZE: GuestUsize = 0x2_0000;
+/// Most vCPUs the redistributors range has room for.
+pub const GIC_V3_REDIST_MAX: u64 = (0x0900_0000 - 0x080a_0000) / GIC_V3_REDIST_SIZE;
+
+// PL011 UART (start: 144MiB, length: 4KiB)
+pub const LEGACY_SERIAL_MAPPED_IO_START: GuestAddress = GuestAddress(0x0900_0000);
+pub const LEGACY_SERIAL_SIZE: GuestUsize = 0x1000;
+
+// == End of "Platform devices" range. ==
+
+// ** 32-bit devices (start: 256MiB, length: 512MiB) **
+pub const MEM_32BIT_DEVICES_START: GuestAddress = GuestAddress(0x1000_0000);
+pub const MEM_32BIT_DEVICES_SIZE: GuestUsize = (512 << 20);
+
+// ** PCI MMCONFIG space (start: 768MiB, length: 256MiB) **
+pub const PCI_MMCONFIG_START: GuestAddress = GuestAddress(0x3000_0000);
+pub const PCI_MMCONFIG_SIZE: GuestUsize = (256 << 20);
+
+// ** RAM (start: 1GiB, length: varies) **
+pub const RAM_64BIT_START: GuestAddress = GuestAddress(0x4000_0000);
+
+// == Fixed constants within the "RAM" range ==
+
+/// The device tree is written at the end of RAM, in a 2MiB aligned area.
+pub const FDT_MAX_SIZE: GuestUsize = 0x20_0000;
+
+// == End of "RAM" range. ==
+
+/// Kernel command line maximum size. The command line is passed through the
+/// device tree, and is not written anywhere else in guest memory.
+pub const CMDLINE_MAX_SIZE: usize = 2048;
+
+// ** Interrupts (shared peripheral interrupt numbers) **
+
+/// PL011 UART interrupt.
+pub const LEGACY_SERIAL_IRQ: u32 = 1;
+/// First interrupt handed out to the other devices.
+pub const IRQ_BASE: u32 = 32;
+/// Number of interrupts handed out to the other devices.
+pub const IRQ_NUM: u32 = 64;
@@ -1,27 +1,298 @@
 // Copyright 2019 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+pub mod fdt;
+pub mod gic;
 pub mod layout;
+pub mod regs;
 
-use memory_model::{GuestAddress, GuestMemory};
+use crate::{InitramfsConfig, RegionType};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::ffi::CStr;
+use std::io::{Read, Seek, SeekFrom};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestUsize};
 
-/// Stub function that needs to be implemented when aarch64 functionality is added.
-pub fn arch_memory_regions(size: usize) -> Vec<(GuestAddress, usize, RegionType)> {
-    vec![(GuestAddress(0), size, RegionType::Ram)]
+// arm64 Image header, as documented in the kernel's
+// Documentation/arm64/booting.txt.
+const IMAGE_HEADER_SIZE: usize = 64;
+const IMAGE_MAGIC: u32 = 0x644d_5241; // "ARM\x64"
+const IMAGE_TEXT_OFFSET: u64 = 8;
+const IMAGE_SIZE: u64 = 16;
+const IMAGE_MAGIC_OFFSET: u64 = 56;
+// Kernels older than 3.17 don't set the image size, nor the text offset,
+// which they expect to be this one.
+const IMAGE_DEFAULT_TEXT_OFFSET: u64 = 0x8_0000;
+
+// Alignment of the initramfs in guest memory.
+const INITRAMFS_ALIGNMENT: u64 = 0x1000;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Failed to create the device tree.
+    CreateFdt(fdt::Error),
+    /// Failed to write the device tree to guest memory.
+    WriteFdt,
+    /// The kernel image isn't an arm64 Image.
+    InvalidKernelImage,
+    /// Failed to read the kernel image.
+    ReadKernelImage,
+    /// The kernel image doesn't fit in guest memory.
+    KernelPastRamEnd,
+}
+
+impl From<Error> for super::Error {
+    fn from(e: Error) -> super::Error {
+        super::Error::AArch64Setup(e)
+    }
+}
+
+/// Returns a Vec of the valid memory addresses.
+/// These should be used to configure the GuestMemory structure for the platform.
+/// For aarch64, the RAM starts after the devices, at 1GiB.
+pub fn arch_memory_regions(size: GuestUsize) -> Vec<(GuestAddress, usize, RegionType)> {
+    vec![
+        (
+            layout::PLATFORM_DEVICES_START,
+            layout::PLATFORM_DEVICES_SIZE as usize,
+            RegionType::Reserved,
+        ),
+        (
+            layout::MEM_32BIT_DEVICES_START,
+            layout::MEM_32BIT_DEVICES_SIZE as usize,
+            RegionType::SubRegion,
+        ),
+        (
+            layout::PCI_MMCONFIG_START,
+            layout::PCI_MMCONFIG_SIZE as usize,
+            RegionType::Reserved,
+        ),
+        (layout::RAM_64BIT_START, size as usize, RegionType::Ram),
+    ]
+}
+
+/// Returns the address the device tree is written at: the last 2MiB aligned
+/// area of RAM big enough for it.
+pub fn get_fdt_addr(guest_mem: &GuestMemoryMmap) -> GuestAddress {
+    let fdt_start = (guest_mem.end_addr().raw_value() + 1).saturating_sub(layout::FDT_MAX_SIZE)
+        & !(layout::FDT_MAX_SIZE - 1);
+
+    GuestAddress(std::cmp::max(
+        fdt_start,
+        layout::RAM_64BIT_START.raw_value(),
+    ))
 }
 
-/// Stub function that needs to be implemented when aarch64 functionality is added.
+/// Configures the system and should be called once per vm before starting vcpu threads.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `cmdline` - The kernel command line.
+/// * `initramfs` - Guest memory the initramfs was loaded in, if any.
+/// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `pci_enabled` - Whether the guest has a PCI host bridge.
+/// * `virtio_mmio_devices` - Base address, size and interrupt of each virtio-mmio device.
 pub fn configure_system(
-    _guest_mem: &GuestMemory,
-    _cmdline_addr: GuestAddress,
-    _cmdline_size: usize,
-    _num_cpus: u8,
-    _rsdp_addr: Option<GuestAddress>,
+    guest_mem: &GuestMemoryMmap,
+    cmdline: &CStr,
+    initramfs: &Option<InitramfsConfig>,
+    num_cpus: u8,
+    pci_enabled: bool,
+    virtio_mmio_devices: &[(GuestAddress, GuestUsize, u32)],
 ) -> super::Result<()> {
+    let cmdline = cmdline
+        .to_str()
+        .map_err(|_| Error::CreateFdt(fdt::Error::InvalidString))?;
+    let fdt = fdt::create_fdt(
+        guest_mem,
+        cmdline,
+        initramfs,
+        num_cpus,
+        pci_enabled,
+        virtio_mmio_devices,
+    )
+    .map_err(Error::CreateFdt)?;
+
+    guest_mem
+        .write_slice(&fdt, get_fdt_addr(guest_mem))
+        .map_err(|_| Error::WriteFdt)?;
+
     Ok(())
 }
 
-/// Stub function that needs to be implemented when aarch64 functionality is added.
-pub fn get_reserved_mem_addr() -> usize {
-    0
+/// Returns the address the initramfs is loaded at: right below the device
+/// tree, aligned down to a page, leaving the start of the RAM to the kernel.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `initramfs_size` - Size of the initramfs in bytes.
+pub fn initramfs_load_addr(
+    guest_mem: &GuestMemoryMmap,
+    initramfs_size: usize,
+) -> super::Result<GuestAddress> {
+    let addr = get_fdt_addr(guest_mem)
+        .raw_value()
+        .checked_sub(initramfs_size as u64)
+        .ok_or(super::Error::InitramfsAddress)?
+        & !(INITRAMFS_ALIGNMENT - 1);
+    if addr < layout::RAM_64BIT_START.raw_value() {
+        return Err(super::Error::InitramfsAddress);
+    }
+
+    Ok(GuestAddress(addr))
+}
+
+/// Loads an arm64 Image kernel to its place in RAM, and returns the address
+/// the boot CPU has to jump to.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `kernel_image` - The kernel Image file.
+pub fn load_kernel<F>(
+    guest_mem: &GuestMemoryMmap,
+    kernel_image: &mut F,
+) -> super::Result<GuestAddress>
+where
+    F: Read + Seek,
+{
+    let mut header = [0u8; IMAGE_HEADER_SIZE];
+    kernel_image
+        .seek(SeekFrom::Start(0))
+        .map_err(|_| Error::ReadKernelImage)?;
+    kernel_image
+        .read_exact(&mut header)
+        .map_err(|_| Error::ReadKernelImage)?;
+
+    let field = |offset: u64| {
+        let mut bytes = &header[offset as usize..];
+        bytes.read_u64::<LittleEndian>()
+    };
+    let magic = (&header[IMAGE_MAGIC_OFFSET as usize..])
+        .read_u32::<LittleEndian>()
+        .map_err(|_| Error::InvalidKernelImage)?;
+    if magic != IMAGE_MAGIC {
+        return Err(Error::InvalidKernelImage.into());
+    }
+
+    let image_size = field(IMAGE_SIZE).map_err(|_| Error::InvalidKernelImage)?;
+    let text_offset = if image_size == 0 {
+        IMAGE_DEFAULT_TEXT_OFFSET
+    } else {
+        field(IMAGE_TEXT_OFFSET).map_err(|_| Error::InvalidKernelImage)?
+    };
+
+    let kernel_size = kernel_image
+        .seek(SeekFrom::End(0))
+        .map_err(|_| Error::ReadKernelImage)?;
+    let kernel_addr = layout::RAM_64BIT_START
+        .checked_add(text_offset)
+        .ok_or(Error::KernelPastRamEnd)?;
+    // The kernel must not overlap the device tree, at the end of RAM.
+    if kernel_addr.raw_value() + std::cmp::max(kernel_size, image_size)
+        > get_fdt_addr(guest_mem).raw_value()
+    {
+        return Err(Error::KernelPastRamEnd.into());
+    }
+
+    kernel_image
+        .seek(SeekFrom::Start(0))
+        .map_err(|_| Error::ReadKernelImage)?;
+    guest_mem
+        .read_exact_from(kernel_addr, kernel_image, kernel_size as usize)
+        .map_err(|_| Error::ReadKernelImage)?;
+
+    Ok(kernel_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn guest_mem(size: GuestUsize) -> GuestMemoryMmap {
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_memory_regions(size)
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        GuestMemoryMmap::new(&ram_regions).unwrap()
+    }
+
+    fn kernel_image(text_offset: u64, image_size: u64) -> Vec<u8> {
+        let mut image = vec![0u8; 0x1000];
+        image[8..16].copy_from_slice(&text_offset.to_le_bytes());
+        image[16..24].copy_from_slice(&image_size.to_le_bytes());
+        image[56..60].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn test_regions() {
+        let regions = arch_memory_regions(1 << 29);
+        assert_eq!(4, regions.len());
+        assert_eq!(layout::RAM_64BIT_START, regions[3].0);
+        assert_eq!(1usize << 29, regions[3].1);
+    }
+
+    #[test]
+    fn test_fdt_addr() {
+        let gm = guest_mem(128 << 20);
+        assert_eq!(
+            get_fdt_addr(&gm),
+            layout::RAM_64BIT_START.unchecked_add((128 << 20) - layout::FDT_MAX_SIZE)
+        );
+
+        let gm = guest_mem(0x30_0000);
+        assert_eq!(get_fdt_addr(&gm), layout::RAM_64BIT_START);
+    }
+
+    #[test]
+    fn test_load_kernel() {
+        let gm = guest_mem(128 << 20);
+
+        let mut image = Cursor::new(kernel_image(0x20_0000, 0x1000));
+        assert_eq!(
+            load_kernel(&gm, &mut image).unwrap(),
+            layout::RAM_64BIT_START.unchecked_add(0x20_0000)
+        );
+
+        // Old kernels are loaded at the default text offset.
+        let mut image = Cursor::new(kernel_image(0x20_0000, 0));
+        assert_eq!(
+            load_kernel(&gm, &mut image).unwrap(),
+            layout::RAM_64BIT_START.unchecked_add(IMAGE_DEFAULT_TEXT_OFFSET)
+        );
+
+        let mut image = Cursor::new(vec![0u8; 0x1000]);
+        assert_eq!(
+            load_kernel(&gm, &mut image),
+            Err(super::super::Error::AArch64Setup(Error::InvalidKernelImage))
+        );
+    }
+
+    #[test]
+    fn test_system_configuration() {
+        let gm = guest_mem(128 << 20);
+        let cmdline = std::ffi::CString::new("console=ttyAMA0").unwrap();
+        let virtio_mmio_devices = [(layout::MEM_32BIT_DEVICES_START, 0x1000, layout::IRQ_BASE)];
+        configure_system(&gm, &cmdline, &None, 4, true, &virtio_mmio_devices).unwrap();
+
+        let magic: u32 = gm.read_obj(get_fdt_addr(&gm)).unwrap();
+        assert_eq!(u32::from_be(magic), 0xd00d_feed);
+    }
+
+    #[test]
+    fn test_initramfs_load_addr() {
+        let gm = guest_mem(128 << 20);
+        assert_eq!(
+            initramfs_load_addr(&gm, 0x1800).unwrap(),
+            GuestAddress(get_fdt_addr(&gm).raw_value() - 0x2000)
+        );
+        assert_eq!(
+            initramfs_load_addr(&gm, 128 << 20),
+            Err(super::super::Error::InitramfsAddress)
+        );
+    }
 }
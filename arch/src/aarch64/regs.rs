@@ -0,0 +1,126 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::sync::Arc;
+use std::{io, result};
+
+use hypervisor::aarch64::{
+    VcpuInit, KVM_ARM_VCPU_POWER_OFF, KVM_ARM_VCPU_PSCI_0_2, KVM_REG_ARM64, KVM_REG_ARM_CORE,
+    KVM_REG_SIZE_U64,
+};
+
+// PSTATE: EL1h, with the debug, SError, IRQ and FIQ exceptions masked, as
+// the Linux arm64 boot protocol requires.
+const PSTATE_FAULT_BITS_64: u64 = 0x3c5;
+
+// Offsets of the core registers in the kernel's `struct kvm_regs`.
+const REGS_OFFSET: u64 = 0;
+const SP_OFFSET: u64 = 31 * 8;
+const PC_OFFSET: u64 = 32 * 8;
+const PSTATE_OFFSET: u64 = 33 * 8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to get the vCPU target preferred by the host.
+    GetPreferredTarget(io::Error),
+    /// Failed to initialize the vCPU.
+    VcpuInit(io::Error),
+    /// Failed to set a core register.
+    SetCoreRegister(io::Error),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Identifier of the core register at `offset` in `struct kvm_regs`.
+fn core_reg_id(offset: u64) -> u64 {
+    KVM_REG_ARM64 as u64 | KVM_REG_SIZE_U64 as u64 | u64::from(KVM_REG_ARM_CORE) | (offset / 4)
+}
+
+/// Returns the affinity bits of the MPIDR register KVM gives the vCPU
+/// `cpu_id`, which the device tree refers to the CPUs with.
+pub fn mpidr(cpu_id: u8) -> u64 {
+    let cpu_id = u64::from(cpu_id);
+    ((cpu_id >> 4) & 0xff) << 8 | (cpu_id & 0xf)
+}
+
+/// Initializes the vCPU for the host CPU target. All the vCPUs but the boot
+/// one start powered off, until the guest turns them on through PSCI.
+///
+/// # Arguments
+///
+/// * `vm` - The VM the vCPU belongs to.
+/// * `vcpu` - The VCPU to initialize.
+/// * `cpu_id` - Index of the VCPU.
+pub fn setup_vcpu_init(
+    vm: &Arc<dyn hypervisor::Vm>,
+    vcpu: &Arc<dyn hypervisor::Vcpu>,
+    cpu_id: u8,
+) -> Result<()> {
+    let mut kvi = VcpuInit::default();
+    vm.get_preferred_target(&mut kvi)
+        .map_err(Error::GetPreferredTarget)?;
+
+    kvi.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
+    if cpu_id > 0 {
+        kvi.features[0] |= 1 << KVM_ARM_VCPU_POWER_OFF;
+    }
+
+    vcpu.vcpu_init(&kvi).map_err(Error::VcpuInit)
+}
+
+/// Configures the core registers of the boot CPU, which jumps to the kernel
+/// with the device tree address in X0.
+///
+/// # Arguments
+///
+/// * `vcpu` - The VCPU to configure.
+/// * `boot_ip` - Starting instruction pointer.
+/// * `fdt_addr` - Address of the device tree in guest memory.
+pub fn setup_regs(vcpu: &Arc<dyn hypervisor::Vcpu>, boot_ip: u64, fdt_addr: u64) -> Result<()> {
+    vcpu.set_reg(core_reg_id(PSTATE_OFFSET), PSTATE_FAULT_BITS_64)
+        .map_err(Error::SetCoreRegister)?;
+    vcpu.set_reg(core_reg_id(PC_OFFSET), boot_ip)
+        .map_err(Error::SetCoreRegister)?;
+    // X1 to X3 are reserved and must be zero, which they are after reset.
+    vcpu.set_reg(core_reg_id(REGS_OFFSET), fdt_addr)
+        .map_err(Error::SetCoreRegister)
+}
+
+/// Returns the core registers of the vCPU, by name.
+pub fn core_registers(vcpu: &Arc<dyn hypervisor::Vcpu>) -> io::Result<Vec<(String, u64)>> {
+    let mut registers = Vec::new();
+    for i in 0..31 {
+        let value = vcpu.get_reg(core_reg_id(REGS_OFFSET + i * 8))?;
+        registers.push((format!("x{}", i), value));
+    }
+    registers.push(("sp".to_string(), vcpu.get_reg(core_reg_id(SP_OFFSET))?));
+    registers.push(("pc".to_string(), vcpu.get_reg(core_reg_id(PC_OFFSET))?));
+    registers.push((
+        "pstate".to_string(),
+        vcpu.get_reg(core_reg_id(PSTATE_OFFSET))?,
+    ));
+
+    Ok(registers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mpidr() {
+        assert_eq!(mpidr(0), 0);
+        assert_eq!(mpidr(15), 0xf);
+        assert_eq!(mpidr(16), 0x100);
+        assert_eq!(mpidr(37), 0x205);
+    }
+
+    #[test]
+    fn test_core_reg_id() {
+        // Values of the KVM_REG_ARM_CORE_REG() macro.
+        assert_eq!(core_reg_id(PC_OFFSET), 0x6030_0000_0010_0040);
+        assert_eq!(core_reg_id(PSTATE_OFFSET), 0x6030_0000_0010_0042);
+    }
+}
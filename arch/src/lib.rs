@@ -10,20 +10,23 @@
 )]
 
 extern crate byteorder;
-extern crate kvm_bindings;
+extern crate hypervisor;
 extern crate libc;
 
 #[cfg(feature = "acpi")]
 extern crate acpi_tables;
 extern crate arch_gen;
-extern crate kvm_ioctls;
 extern crate linux_loader;
 extern crate vm_memory;
 
 use std::result;
+use vm_memory::GuestAddress;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
+    #[cfg(target_arch = "aarch64")]
+    /// AArch64 specific error triggered during system configuration.
+    AArch64Setup(aarch64::Error),
     #[cfg(target_arch = "x86_64")]
     /// X86_64 specific error triggered during system configuration.
     X86_64Setup(x86_64::Error),
@@ -31,6 +34,8 @@ pub enum Error {
     ZeroPagePastRamEnd,
     /// Error writing the zero page of guest memory.
     ZeroPageSetup,
+    /// The initramfs doesn't fit in the guest memory.
+    InitramfsAddress,
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -52,13 +57,37 @@ pub enum RegionType {
     Reserved,
 }
 
+/// Protocol the kernel is booted with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootProtocol {
+    /// Linux 64-bit boot protocol, entered in long mode.
+    LinuxBoot,
+    /// PVH boot protocol, entered in 32-bit protected mode.
+    PvhBoot,
+}
+
+/// Entry point of the kernel, and the protocol to boot it with.
+#[derive(Clone, Copy, Debug)]
+pub struct EntryPoint {
+    /// Address of the first instruction of the kernel.
+    pub entry_addr: GuestAddress,
+    /// Protocol the kernel is booted with.
+    pub protocol: BootProtocol,
+}
+
+/// Guest memory the initramfs is loaded in.
+#[derive(Clone, Copy, Debug)]
+pub struct InitramfsConfig {
+    pub address: GuestAddress,
+    pub size: usize,
+}
+
 #[cfg(target_arch = "aarch64")]
 pub mod aarch64;
 
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::{
-    arch_memory_regions, configure_system, get_reserved_mem_addr, layout::CMDLINE_MAX_SIZE,
-    layout::CMDLINE_START,
+    arch_memory_regions, configure_system, initramfs_load_addr, layout, layout::CMDLINE_MAX_SIZE,
 };
 
 #[cfg(target_arch = "x86_64")]
@@ -66,5 +95,6 @@ pub mod x86_64;
 
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::{
-    arch_memory_regions, configure_system, layout, layout::CMDLINE_MAX_SIZE, layout::CMDLINE_START,
+    arch_memory_regions, configure_system, initramfs_load_addr, layout, layout::CMDLINE_MAX_SIZE,
+    layout::CMDLINE_START,
 };
@@ -0,0 +1,802 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Guests booted from an IGVM (Independent Guest Virtual Machine) image.
+//!
+//! Instead of a kernel the VMM loads, an IGVM image is a list of directives
+//! for the VMM: the pages the guest starts with, the areas the VMM fills
+//! with parameters such as the memory map or the command line, and the
+//! state of the boot vCPU. An image can carry directives for several
+//! platforms, only the ones of the platform the guest runs on are applied.
+
+use byteorder::{ByteOrder, LittleEndian};
+use hypervisor::x86_64::{DescriptorTable, SegmentRegister, StandardRegisters};
+use std::result;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The image doesn't start with an IGVM header.
+    NoHeader,
+    /// The IGVM header, or a directive, is malformed.
+    InvalidHeader,
+    /// The checksum of the headers doesn't match.
+    InvalidChecksum,
+    /// The IGVM format version isn't supported.
+    UnsupportedVersion(u32),
+    /// The image has no directives for the platform of the guest.
+    UnsupportedPlatform,
+    /// The directive, of this type, isn't supported.
+    UnsupportedDirective(u32),
+    /// A directive refers to a parameter area that isn't declared.
+    InvalidParameterArea(u32),
+    /// The boot vCPU has more than one context, or a context is set for
+    /// another vCPU.
+    InvalidVpContext,
+}
+pub type Result<T> = result::Result<T, Error>;
+
+const IGVM_MAGIC: &[u8; 4] = b"IGVM";
+const IGVM_FIXED_HEADER_SIZE: usize = 24;
+const IGVM_VARIABLE_HEADER_ALIGN: usize = 8;
+// The checksum field, zeroed while the checksum is computed.
+const IGVM_CHECKSUM_OFFSET: usize = 20;
+
+// Variable header types.
+const IGVM_VHT_SUPPORTED_PLATFORM: u32 = 0x1;
+const IGVM_VHT_GUEST_POLICY: u32 = 0x101;
+const IGVM_VHT_PARAMETER_AREA: u32 = 0x301;
+const IGVM_VHT_PAGE_DATA: u32 = 0x302;
+const IGVM_VHT_PARAMETER_INSERT: u32 = 0x303;
+const IGVM_VHT_VP_CONTEXT: u32 = 0x304;
+const IGVM_VHT_REQUIRED_MEMORY: u32 = 0x305;
+const IGVM_VHT_VP_COUNT_PARAMETER: u32 = 0x307;
+const IGVM_VHT_MEMORY_MAP: u32 = 0x30c;
+const IGVM_VHT_COMMAND_LINE: u32 = 0x30e;
+// Directives the VMM may skip if it doesn't know them.
+const IGVM_VHF_TYPE_IGNORABLE: u32 = 1 << 31;
+
+const IGVM_PLATFORM_TYPE_NATIVE: u8 = 0x0;
+const IGVM_PLATFORM_TYPE_SEV_SNP: u8 = 0x2;
+
+const IGVM_PAGE_SIZE_4K: u64 = 0x1000;
+const IGVM_PAGE_SIZE_2M: u64 = 0x20_0000;
+const IGVM_PAGE_FLAG_2MB: u32 = 1 << 0;
+const IGVM_PAGE_FLAG_UNMEASURED: u32 = 1 << 1;
+const IGVM_PAGE_DATA_TYPE_NORMAL: u16 = 0x0;
+const IGVM_PAGE_DATA_TYPE_SECRETS: u16 = 0x1;
+const IGVM_PAGE_DATA_TYPE_CPUID: u16 = 0x2;
+
+/// Size of a memory map entry, the page number and the count of pages of a
+/// range of RAM, followed by its type and flags.
+pub const IGVM_MEMORY_MAP_ENTRY_SIZE: usize = 24;
+
+// Layout of the context of a vCPU on the native platform.
+const NATIVE_VP_CONTEXT_SIZE: usize = 256;
+// Layout of the VMSA of a SEV-SNP vCPU, for the registers it starts with.
+const VMSA_SIZE: usize = 0x1000;
+const VMSA_SEGMENTS: [(usize, Segment); 7] = [
+    (0x00, Segment::Es),
+    (0x10, Segment::Cs),
+    (0x20, Segment::Ss),
+    (0x30, Segment::Ds),
+    (0x40, Segment::Fs),
+    (0x50, Segment::Gs),
+    (0x90, Segment::Tr),
+];
+
+/// Platforms the directives of an image are for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IgvmPlatform {
+    /// Guests without memory encryption.
+    Native,
+    /// AMD SEV-SNP guests.
+    SevSnp,
+}
+
+/// What the content of a page is, for the confidential platforms.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IgvmPageType {
+    Normal,
+    /// The secrets page of the guest.
+    Secrets,
+    /// The CPUID table, the VMM fills.
+    Cpuid,
+}
+
+/// A page the guest starts with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IgvmPage {
+    pub address: u64,
+    pub size: u64,
+    /// Content of the page, zeroes if `None`.
+    pub data: Option<Vec<u8>>,
+    pub type_: IgvmPageType,
+    /// Whether the page is part of the launch measurement.
+    pub measured: bool,
+}
+
+/// A parameter the VMM writes at an offset of a parameter area.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IgvmParameter {
+    /// Number of vCPUs, as a 32-bit integer.
+    VpCount,
+    /// Memory map entries, terminated by an empty one.
+    MemoryMap,
+    /// The null terminated command line.
+    CommandLine,
+}
+
+/// An area the VMM fills with parameters, inserted in the guest memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IgvmParameterArea {
+    pub address: u64,
+    /// Initial content of the area, parameters are written over it.
+    pub data: Vec<u8>,
+    pub parameters: Vec<(IgvmParameter, usize)>,
+}
+
+/// Registers the boot vCPU starts with.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IgvmVpContext {
+    pub regs: StandardRegisters,
+    pub cs: SegmentRegister,
+    pub ds: SegmentRegister,
+    pub es: SegmentRegister,
+    pub fs: SegmentRegister,
+    pub gs: SegmentRegister,
+    pub ss: SegmentRegister,
+    pub tr: SegmentRegister,
+    pub gdt: DescriptorTable,
+    pub idt: DescriptorTable,
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+/// The directives of an image applying to one platform.
+#[derive(Clone, Debug, Default)]
+pub struct IgvmImage {
+    pub pages: Vec<IgvmPage>,
+    pub parameter_areas: Vec<IgvmParameterArea>,
+    /// Ranges of RAM the guest needs.
+    pub required_memory: Vec<(u64, u64)>,
+    pub vp_context: Option<IgvmVpContext>,
+}
+
+#[derive(Clone, Copy)]
+enum Segment {
+    Cs,
+    Ds,
+    Es,
+    Fs,
+    Gs,
+    Ss,
+    Tr,
+}
+
+/// Whether the image starts with an IGVM header.
+pub fn is_igvm(image: &[u8]) -> bool {
+    image.get(..4) == Some(&IGVM_MAGIC[..])
+}
+
+/// Returns the directives of an IGVM image applying to `platform`, for a
+/// guest of `ram_size` bytes of RAM.
+pub fn igvm_image(image: &[u8], platform: IgvmPlatform, ram_size: u64) -> Result<IgvmImage> {
+    if !is_igvm(image) {
+        return Err(Error::NoHeader);
+    }
+    let header = image
+        .get(..IGVM_FIXED_HEADER_SIZE)
+        .ok_or(Error::InvalidHeader)?;
+    let version = LittleEndian::read_u32(&header[4..]);
+    if version != 1 && version != 2 {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    let offset = LittleEndian::read_u32(&header[8..]) as usize;
+    let size = LittleEndian::read_u32(&header[12..]) as usize;
+    if offset < IGVM_FIXED_HEADER_SIZE
+        || LittleEndian::read_u32(&header[16..]) as usize != image.len()
+    {
+        return Err(Error::InvalidHeader);
+    }
+    let headers = image
+        .get(offset..offset + size)
+        .ok_or(Error::InvalidHeader)?;
+
+    let mut checked = image[..offset].to_vec();
+    checked[IGVM_CHECKSUM_OFFSET..IGVM_CHECKSUM_OFFSET + 4].copy_from_slice(&[0; 4]);
+    checked.extend_from_slice(headers);
+    if crc32(&checked) != LittleEndian::read_u32(&header[IGVM_CHECKSUM_OFFSET..]) {
+        return Err(Error::InvalidChecksum);
+    }
+
+    let mut directives = Vec::new();
+    let mut remaining = headers;
+    while !remaining.is_empty() {
+        if remaining.len() < 8 {
+            return Err(Error::InvalidHeader);
+        }
+        let type_ = LittleEndian::read_u32(&remaining[0..]);
+        let length = LittleEndian::read_u32(&remaining[4..]) as usize;
+        let data = remaining.get(8..8 + length).ok_or(Error::InvalidHeader)?;
+        directives.push((type_, data));
+        let aligned =
+            (8 + length + IGVM_VARIABLE_HEADER_ALIGN - 1) & !(IGVM_VARIABLE_HEADER_ALIGN - 1);
+        remaining = remaining.get(aligned..).unwrap_or(&[]);
+    }
+
+    // The supported platforms come first, each one of them having a bit of
+    // the compatibility mask of the following directives.
+    let platform_type = match platform {
+        IgvmPlatform::Native => IGVM_PLATFORM_TYPE_NATIVE,
+        IgvmPlatform::SevSnp => IGVM_PLATFORM_TYPE_SEV_SNP,
+    };
+    let mask = directives
+        .iter()
+        .filter(|(type_, data)| *type_ == IGVM_VHT_SUPPORTED_PLATFORM && data.len() >= 16)
+        .find(|(_, data)| data[5] == platform_type)
+        .map(|(_, data)| LittleEndian::read_u32(&data[0..]))
+        .ok_or(Error::UnsupportedPlatform)?;
+    let applies = |data: &[u8]| LittleEndian::read_u32(&data[8..]) & mask != 0;
+
+    let mut igvm = IgvmImage::default();
+    // Parameter areas by index, until they are inserted.
+    let mut areas: Vec<(u32, IgvmParameterArea)> = Vec::new();
+    for (type_, data) in directives {
+        let expected_size = match type_ {
+            IGVM_VHT_SUPPORTED_PLATFORM | IGVM_VHT_PARAMETER_AREA | IGVM_VHT_PARAMETER_INSERT => 16,
+            IGVM_VHT_PAGE_DATA | IGVM_VHT_VP_CONTEXT | IGVM_VHT_REQUIRED_MEMORY => 24,
+            IGVM_VHT_VP_COUNT_PARAMETER | IGVM_VHT_MEMORY_MAP | IGVM_VHT_COMMAND_LINE => 8,
+            _ => 0,
+        };
+        if data.len() < expected_size {
+            return Err(Error::InvalidHeader);
+        }
+
+        match type_ {
+            IGVM_VHT_SUPPORTED_PLATFORM => {}
+            // The VMM launches the guest with its own policy.
+            IGVM_VHT_GUEST_POLICY => {}
+            IGVM_VHT_PARAMETER_AREA => {
+                let size = LittleEndian::read_u64(&data[0..]);
+                let index = LittleEndian::read_u32(&data[8..]);
+                let file_offset = LittleEndian::read_u32(&data[12..]) as usize;
+                // The area is made of pages of the guest RAM, and allocated
+                // before it is checked against it.
+                if size % IGVM_PAGE_SIZE_4K != 0 || size > ram_size {
+                    return Err(Error::InvalidHeader);
+                }
+                let size = size as usize;
+                let area = if file_offset == 0 {
+                    vec![0u8; size]
+                } else {
+                    let end = file_offset.checked_add(size).ok_or(Error::InvalidHeader)?;
+                    image
+                        .get(file_offset..end)
+                        .ok_or(Error::InvalidHeader)?
+                        .to_vec()
+                };
+                areas.push((
+                    index,
+                    IgvmParameterArea {
+                        address: 0,
+                        data: area,
+                        parameters: Vec::new(),
+                    },
+                ));
+            }
+            IGVM_VHT_VP_COUNT_PARAMETER | IGVM_VHT_MEMORY_MAP | IGVM_VHT_COMMAND_LINE => {
+                let index = LittleEndian::read_u32(&data[0..]);
+                let offset = LittleEndian::read_u32(&data[4..]) as usize;
+                let parameter = match type_ {
+                    IGVM_VHT_VP_COUNT_PARAMETER => IgvmParameter::VpCount,
+                    IGVM_VHT_MEMORY_MAP => IgvmParameter::MemoryMap,
+                    _ => IgvmParameter::CommandLine,
+                };
+                let (_, area) = areas
+                    .iter_mut()
+                    .find(|(i, _)| *i == index)
+                    .ok_or(Error::InvalidParameterArea(index))?;
+                if offset >= area.data.len() {
+                    return Err(Error::InvalidHeader);
+                }
+                area.parameters.push((parameter, offset));
+            }
+            IGVM_VHT_PARAMETER_INSERT => {
+                if !applies(data) {
+                    continue;
+                }
+                let index = LittleEndian::read_u32(&data[12..]);
+                let position = areas
+                    .iter()
+                    .position(|(i, _)| *i == index)
+                    .ok_or(Error::InvalidParameterArea(index))?;
+                let (_, mut area) = areas.remove(position);
+                area.address = LittleEndian::read_u64(&data[0..]);
+                igvm.parameter_areas.push(area);
+            }
+            IGVM_VHT_PAGE_DATA => {
+                if !applies(data) {
+                    continue;
+                }
+                let flags = LittleEndian::read_u32(&data[16..]);
+                let size = if flags & IGVM_PAGE_FLAG_2MB != 0 {
+                    IGVM_PAGE_SIZE_2M
+                } else {
+                    IGVM_PAGE_SIZE_4K
+                };
+                let file_offset = LittleEndian::read_u32(&data[12..]) as usize;
+                let content = if file_offset == 0 {
+                    None
+                } else {
+                    Some(
+                        image
+                            .get(file_offset..file_offset + size as usize)
+                            .ok_or(Error::InvalidHeader)?
+                            .to_vec(),
+                    )
+                };
+                let page_type = match LittleEndian::read_u16(&data[20..]) {
+                    IGVM_PAGE_DATA_TYPE_NORMAL => IgvmPageType::Normal,
+                    IGVM_PAGE_DATA_TYPE_SECRETS => IgvmPageType::Secrets,
+                    IGVM_PAGE_DATA_TYPE_CPUID => IgvmPageType::Cpuid,
+                    _ => return Err(Error::UnsupportedDirective(type_)),
+                };
+                igvm.pages.push(IgvmPage {
+                    address: LittleEndian::read_u64(&data[0..]),
+                    size,
+                    data: content,
+                    type_: page_type,
+                    measured: flags & IGVM_PAGE_FLAG_UNMEASURED == 0,
+                });
+            }
+            IGVM_VHT_VP_CONTEXT => {
+                if !applies(data) {
+                    continue;
+                }
+                if LittleEndian::read_u16(&data[16..]) != 0 || igvm.vp_context.is_some() {
+                    return Err(Error::InvalidVpContext);
+                }
+                let file_offset = LittleEndian::read_u32(&data[12..]) as usize;
+                let context_size = match platform {
+                    IgvmPlatform::Native => NATIVE_VP_CONTEXT_SIZE,
+                    IgvmPlatform::SevSnp => VMSA_SIZE,
+                };
+                let end = file_offset
+                    .checked_add(context_size)
+                    .ok_or(Error::InvalidHeader)?;
+                let context = image.get(file_offset..end).ok_or(Error::InvalidHeader)?;
+                igvm.vp_context = Some(match platform {
+                    IgvmPlatform::Native => native_vp_context(context),
+                    IgvmPlatform::SevSnp => vmsa_vp_context(context),
+                });
+            }
+            IGVM_VHT_REQUIRED_MEMORY => {
+                if !applies(data) {
+                    continue;
+                }
+                igvm.required_memory.push((
+                    LittleEndian::read_u64(&data[0..]),
+                    u64::from(LittleEndian::read_u32(&data[12..])),
+                ));
+            }
+            _ if type_ & IGVM_VHF_TYPE_IGNORABLE != 0 => {}
+            _ => return Err(Error::UnsupportedDirective(type_)),
+        }
+    }
+
+    Ok(igvm)
+}
+
+/// Builds the memory map of the guest, from its ranges of RAM, for the
+/// parameter areas asking for it.
+pub fn memory_map(ram: &[(u64, u64)]) -> Vec<u8> {
+    let mut map = vec![0u8; (ram.len() + 1) * IGVM_MEMORY_MAP_ENTRY_SIZE];
+    for (i, &(start, size)) in ram.iter().enumerate() {
+        let entry = &mut map[i * IGVM_MEMORY_MAP_ENTRY_SIZE..];
+        LittleEndian::write_u64(&mut entry[0..], start / IGVM_PAGE_SIZE_4K);
+        LittleEndian::write_u64(&mut entry[8..], size / IGVM_PAGE_SIZE_4K);
+        // Plain memory, without flags.
+    }
+    map
+}
+
+// Segments are described by their VMCB attributes, the format both the
+// native and the SEV-SNP contexts use.
+fn segment(selector: u16, attributes: u16, limit: u32, base: u64) -> SegmentRegister {
+    let bit = |shift: u16| ((attributes >> shift) & 1) as u8;
+    SegmentRegister {
+        base,
+        limit,
+        selector,
+        type_: (attributes & 0xf) as u8,
+        s: bit(4),
+        dpl: ((attributes >> 5) & 0x3) as u8,
+        present: bit(7),
+        avl: bit(8),
+        l: bit(9),
+        db: bit(10),
+        g: bit(11),
+        unusable: (bit(7) == 0) as u8,
+        ..Default::default()
+    }
+}
+
+fn set_segment(context: &mut IgvmVpContext, which: Segment, value: SegmentRegister) {
+    match which {
+        Segment::Cs => context.cs = value,
+        Segment::Ds => context.ds = value,
+        Segment::Es => context.es = value,
+        Segment::Fs => context.fs = value,
+        Segment::Gs => context.gs = value,
+        Segment::Ss => context.ss = value,
+        Segment::Tr => context.tr = value,
+    }
+}
+
+// The general purpose registers, from RAX to R15, then the instruction
+// pointer and the flags, the descriptor tables, a code and a data segment,
+// the FS and GS bases, and the control registers.
+fn native_vp_context(raw: &[u8]) -> IgvmVpContext {
+    let gpr = |i: usize| LittleEndian::read_u64(&raw[i * 8..]);
+    let mut context = IgvmVpContext {
+        regs: StandardRegisters {
+            rax: gpr(0),
+            rcx: gpr(1),
+            rdx: gpr(2),
+            rbx: gpr(3),
+            rsp: gpr(4),
+            rbp: gpr(5),
+            rsi: gpr(6),
+            rdi: gpr(7),
+            r8: gpr(8),
+            r9: gpr(9),
+            r10: gpr(10),
+            r11: gpr(11),
+            r12: gpr(12),
+            r13: gpr(13),
+            r14: gpr(14),
+            r15: gpr(15),
+            rip: gpr(16),
+            rflags: gpr(17),
+        },
+        idt: DescriptorTable {
+            base: LittleEndian::read_u64(&raw[144..]),
+            limit: LittleEndian::read_u16(&raw[152..]),
+            ..Default::default()
+        },
+        gdt: DescriptorTable {
+            base: LittleEndian::read_u64(&raw[160..]),
+            limit: LittleEndian::read_u16(&raw[158..]),
+            ..Default::default()
+        },
+        cs: segment(
+            LittleEndian::read_u16(&raw[168..]),
+            LittleEndian::read_u16(&raw[170..]),
+            LittleEndian::read_u32(&raw[184..]),
+            LittleEndian::read_u64(&raw[176..]),
+        ),
+        cr0: LittleEndian::read_u64(&raw[224..]),
+        cr3: LittleEndian::read_u64(&raw[232..]),
+        cr4: LittleEndian::read_u64(&raw[240..]),
+        efer: LittleEndian::read_u64(&raw[248..]),
+        ..Default::default()
+    };
+    let data = segment(
+        LittleEndian::read_u16(&raw[188..]),
+        LittleEndian::read_u16(&raw[190..]),
+        LittleEndian::read_u32(&raw[200..]),
+        LittleEndian::read_u64(&raw[192..]),
+    );
+    context.ds = data;
+    context.es = data;
+    context.ss = data;
+    context.fs = SegmentRegister {
+        base: LittleEndian::read_u64(&raw[208..]),
+        ..data
+    };
+    context.gs = SegmentRegister {
+        base: LittleEndian::read_u64(&raw[216..]),
+        ..data
+    };
+    // A busy 64-bit TSS, as the vCPU can't enter protected mode without one.
+    context.tr = segment(0, 0x8b, 0xffff, 0);
+    context
+}
+
+fn vmsa_vp_context(raw: &[u8]) -> IgvmVpContext {
+    let read_segment = |offset: usize| {
+        segment(
+            LittleEndian::read_u16(&raw[offset..]),
+            LittleEndian::read_u16(&raw[offset + 2..]),
+            LittleEndian::read_u32(&raw[offset + 4..]),
+            LittleEndian::read_u64(&raw[offset + 8..]),
+        )
+    };
+    let read_table = |offset: usize| DescriptorTable {
+        base: LittleEndian::read_u64(&raw[offset + 8..]),
+        limit: LittleEndian::read_u32(&raw[offset + 4..]) as u16,
+        ..Default::default()
+    };
+    let reg = |offset: usize| LittleEndian::read_u64(&raw[offset..]);
+
+    let mut context = IgvmVpContext {
+        regs: StandardRegisters {
+            rax: reg(0x1f8),
+            rcx: reg(0x308),
+            rdx: reg(0x310),
+            rbx: reg(0x318),
+            rsp: reg(0x1d8),
+            rbp: reg(0x328),
+            rsi: reg(0x330),
+            rdi: reg(0x338),
+            r8: reg(0x340),
+            r9: reg(0x348),
+            r10: reg(0x350),
+            r11: reg(0x358),
+            r12: reg(0x360),
+            r13: reg(0x368),
+            r14: reg(0x370),
+            r15: reg(0x378),
+            rip: reg(0x178),
+            rflags: reg(0x170),
+        },
+        gdt: read_table(0x60),
+        idt: read_table(0x80),
+        efer: reg(0xd0),
+        cr4: reg(0x148),
+        cr3: reg(0x150),
+        cr0: reg(0x158),
+        ..Default::default()
+    };
+    for &(offset, which) in VMSA_SEGMENTS.iter() {
+        set_segment(&mut context, which, read_segment(offset));
+    }
+    context
+}
+
+// The CRC-32 of the IEEE 802.3 standard, the one of the IGVM checksum.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RAM of the guests the images are loaded into.
+    const RAM_SIZE: u64 = 0x100_0000;
+
+    fn directive(type_: u32, fields: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0u8; 8];
+        LittleEndian::write_u32(&mut raw[0..], type_);
+        LittleEndian::write_u32(&mut raw[4..], fields.len() as u32);
+        raw.extend_from_slice(fields);
+        raw.resize((raw.len() + 7) & !7, 0);
+        raw
+    }
+
+    fn supported_platform(mask: u32, platform_type: u8) -> Vec<u8> {
+        let mut fields = [0u8; 16];
+        LittleEndian::write_u32(&mut fields[0..], mask);
+        fields[5] = platform_type;
+        directive(IGVM_VHT_SUPPORTED_PLATFORM, &fields)
+    }
+
+    fn page_data(address: u64, mask: u32, file_offset: u32) -> Vec<u8> {
+        let mut fields = [0u8; 24];
+        LittleEndian::write_u64(&mut fields[0..], address);
+        LittleEndian::write_u32(&mut fields[8..], mask);
+        LittleEndian::write_u32(&mut fields[12..], file_offset);
+        directive(IGVM_VHT_PAGE_DATA, &fields)
+    }
+
+    // An image made of its headers, followed by the data they refer to from
+    // `data_offset`.
+    fn igvm_file(directives: &[Vec<u8>], data: &[u8]) -> (Vec<u8>, u32) {
+        let headers: Vec<u8> = directives.concat();
+        let mut image = vec![0u8; IGVM_FIXED_HEADER_SIZE];
+        image[..4].copy_from_slice(IGVM_MAGIC);
+        LittleEndian::write_u32(&mut image[4..], 1);
+        LittleEndian::write_u32(&mut image[8..], IGVM_FIXED_HEADER_SIZE as u32);
+        LittleEndian::write_u32(&mut image[12..], headers.len() as u32);
+        image.extend_from_slice(&headers);
+        let data_offset = image.len() as u32;
+        image.extend_from_slice(data);
+        let total = image.len() as u32;
+        LittleEndian::write_u32(&mut image[16..], total);
+        let checksum = crc32(&image[..data_offset as usize]);
+        LittleEndian::write_u32(&mut image[IGVM_CHECKSUM_OFFSET..], checksum);
+        (image, data_offset)
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_igvm_pages() {
+        // The data offset only depends on the size of the headers.
+        let headers = |offset| {
+            vec![
+                supported_platform(0x1, IGVM_PLATFORM_TYPE_NATIVE),
+                supported_platform(0x2, IGVM_PLATFORM_TYPE_SEV_SNP),
+                page_data(0x10_0000, 0x1, offset),
+                page_data(0x20_0000, 0x2, 0),
+                page_data(0x10_1000, 0x3, 0),
+            ]
+        };
+        let (_, data_offset) = igvm_file(&headers(0), &[]);
+        let (image, _) = igvm_file(&headers(data_offset), &[0xaa; 0x1000]);
+
+        let native = igvm_image(&image, IgvmPlatform::Native, RAM_SIZE).unwrap();
+        assert_eq!(
+            native.pages,
+            vec![
+                IgvmPage {
+                    address: 0x10_0000,
+                    size: 0x1000,
+                    data: Some(vec![0xaa; 0x1000]),
+                    type_: IgvmPageType::Normal,
+                    measured: true,
+                },
+                IgvmPage {
+                    address: 0x10_1000,
+                    size: 0x1000,
+                    data: None,
+                    type_: IgvmPageType::Normal,
+                    measured: true,
+                },
+            ]
+        );
+        let sev_snp = igvm_image(&image, IgvmPlatform::SevSnp, RAM_SIZE).unwrap();
+        assert_eq!(sev_snp.pages.len(), 2);
+        assert_eq!(sev_snp.pages[0].address, 0x20_0000);
+
+        let mut corrupted = image.clone();
+        corrupted[IGVM_FIXED_HEADER_SIZE + 8] ^= 1;
+        assert_eq!(
+            igvm_image(&corrupted, IgvmPlatform::Native, RAM_SIZE).unwrap_err(),
+            Error::InvalidChecksum
+        );
+        assert_eq!(
+            igvm_image(&[0u8; 0x100], IgvmPlatform::Native, RAM_SIZE).unwrap_err(),
+            Error::NoHeader
+        );
+        let (image, _) = igvm_file(&[supported_platform(0x1, IGVM_PLATFORM_TYPE_NATIVE)], &[]);
+        assert_eq!(
+            igvm_image(&image, IgvmPlatform::SevSnp, RAM_SIZE).unwrap_err(),
+            Error::UnsupportedPlatform
+        );
+    }
+
+    #[test]
+    fn test_igvm_parameters() {
+        let mut area = [0u8; 16];
+        LittleEndian::write_u64(&mut area[0..], 0x1000);
+        LittleEndian::write_u32(&mut area[8..], 3);
+        let mut memory_map = [0u8; 8];
+        LittleEndian::write_u32(&mut memory_map[0..], 3);
+        let mut cmdline = [0u8; 8];
+        LittleEndian::write_u32(&mut cmdline[0..], 3);
+        LittleEndian::write_u32(&mut cmdline[4..], 0x800);
+        let mut insert = [0u8; 16];
+        LittleEndian::write_u64(&mut insert[0..], 0x8000);
+        LittleEndian::write_u32(&mut insert[8..], 0x1);
+        LittleEndian::write_u32(&mut insert[12..], 3);
+        let (image, _) = igvm_file(
+            &[
+                supported_platform(0x1, IGVM_PLATFORM_TYPE_NATIVE),
+                directive(IGVM_VHT_PARAMETER_AREA, &area),
+                directive(IGVM_VHT_MEMORY_MAP, &memory_map),
+                directive(IGVM_VHT_COMMAND_LINE, &cmdline),
+                directive(IGVM_VHT_PARAMETER_INSERT, &insert),
+            ],
+            &[],
+        );
+
+        let igvm = igvm_image(&image, IgvmPlatform::Native, RAM_SIZE).unwrap();
+        assert_eq!(
+            igvm.parameter_areas,
+            vec![IgvmParameterArea {
+                address: 0x8000,
+                data: vec![0u8; 0x1000],
+                parameters: vec![
+                    (IgvmParameter::MemoryMap, 0),
+                    (IgvmParameter::CommandLine, 0x800)
+                ],
+            }]
+        );
+
+        // Areas which aren't made of whole pages of the guest RAM.
+        for &size in [0x800, RAM_SIZE + 0x1000].iter() {
+            LittleEndian::write_u64(&mut area[0..], size);
+            let (image, _) = igvm_file(
+                &[
+                    supported_platform(0x1, IGVM_PLATFORM_TYPE_NATIVE),
+                    directive(IGVM_VHT_PARAMETER_AREA, &area),
+                ],
+                &[],
+            );
+            assert_eq!(
+                igvm_image(&image, IgvmPlatform::Native, RAM_SIZE).unwrap_err(),
+                Error::InvalidHeader
+            );
+        }
+
+        LittleEndian::write_u32(&mut insert[12..], 4);
+        let (image, _) = igvm_file(
+            &[
+                supported_platform(0x1, IGVM_PLATFORM_TYPE_NATIVE),
+                directive(IGVM_VHT_PARAMETER_INSERT, &insert),
+            ],
+            &[],
+        );
+        assert_eq!(
+            igvm_image(&image, IgvmPlatform::Native, RAM_SIZE).unwrap_err(),
+            Error::InvalidParameterArea(4)
+        );
+    }
+
+    #[test]
+    fn test_igvm_native_vp_context() {
+        let mut context = [0u8; NATIVE_VP_CONTEXT_SIZE];
+        // RSI, RIP and RFLAGS.
+        LittleEndian::write_u64(&mut context[6 * 8..], 0x7000);
+        LittleEndian::write_u64(&mut context[16 * 8..], 0x10_0000);
+        LittleEndian::write_u64(&mut context[17 * 8..], 0x2);
+        // A 64-bit code segment.
+        LittleEndian::write_u16(&mut context[168..], 0x8);
+        LittleEndian::write_u16(&mut context[170..], 0xa9b);
+        LittleEndian::write_u64(&mut context[224..], 0x8000_0011);
+
+        let headers = |offset| {
+            let mut fields = [0u8; 24];
+            LittleEndian::write_u32(&mut fields[8..], 0x1);
+            LittleEndian::write_u32(&mut fields[12..], offset);
+            vec![
+                supported_platform(0x1, IGVM_PLATFORM_TYPE_NATIVE),
+                directive(IGVM_VHT_VP_CONTEXT, &fields),
+            ]
+        };
+        let (_, data_offset) = igvm_file(&headers(0), &[]);
+        let (image, _) = igvm_file(&headers(data_offset), &context);
+
+        let vp_context = igvm_image(&image, IgvmPlatform::Native, RAM_SIZE)
+            .unwrap()
+            .vp_context
+            .unwrap();
+        assert_eq!(vp_context.regs.rsi, 0x7000);
+        assert_eq!(vp_context.regs.rip, 0x10_0000);
+        assert_eq!(vp_context.regs.rflags, 0x2);
+        assert_eq!(vp_context.cs.selector, 0x8);
+        assert_eq!(vp_context.cs.type_, 0xb);
+        assert_eq!(vp_context.cs.present, 1);
+        assert_eq!(vp_context.cs.l, 1);
+        assert_eq!(vp_context.cs.g, 1);
+        assert_eq!(vp_context.cr0, 0x8000_0011);
+    }
+
+    #[test]
+    fn test_memory_map() {
+        let map = memory_map(&[(0, 0xa_0000), (0x10_0000, 0x7ff0_0000)]);
+
+        assert_eq!(map.len(), 3 * IGVM_MEMORY_MAP_ENTRY_SIZE);
+        assert_eq!(LittleEndian::read_u64(&map[8..]), 0xa0);
+        assert_eq!(LittleEndian::read_u64(&map[24..]), 0x100);
+        assert_eq!(LittleEndian::read_u64(&map[32..]), 0x7ff00);
+        assert_eq!(&map[48..], &[0u8; IGVM_MEMORY_MAP_ENTRY_SIZE][..]);
+    }
+}
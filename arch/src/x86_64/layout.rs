@@ -26,6 +26,9 @@ pub const LOW_RAM_START: GuestAddress = GuestAddress(0x0);
 pub const BOOT_GDT_START: GuestAddress = GuestAddress(0x500);
 pub const BOOT_IDT_START: GuestAddress = GuestAddress(0x520);
 
+/// PVH start info, followed by the memory map, for the firmware.
+pub const PVH_INFO_START: GuestAddress = GuestAddress(0x6000);
+
 /// The 'zero page', a.k.a linux kernel bootparams.
 pub const ZERO_PAGE_START: GuestAddress = GuestAddress(0x7000);
 
@@ -78,15 +81,33 @@ pub const PCI_MMCONFIG_START: GuestAddress =
     GuestAddress(MEM_32BIT_DEVICES_START.0 + MEM_32BIT_DEVICES_SIZE);
 pub const PCI_MMCONFIG_SIZE: GuestUsize = (256 << 20);
 
+// MMCONFIG space of the PCI segments other than the segment 0, one bus each
+// (start: after the PCI MMCONFIG space, length: 1MiB per segment)
+pub const PCI_SEGMENTS_MMCONFIG_START: GuestAddress =
+    GuestAddress(PCI_MMCONFIG_START.0 + PCI_MMCONFIG_SIZE);
+pub const PCI_SEGMENT_MMCONFIG_SIZE: GuestUsize = (1 << 20);
+
 // IOAPIC
 pub const IOAPIC_START: GuestAddress = GuestAddress(0xfec0_0000);
 pub const IOAPIC_SIZE: GuestUsize = 0x20;
 
+// TPM CRB interface, locality 0
+pub const TPM_START: GuestAddress = GuestAddress(0xfed4_0000);
+pub const TPM_SIZE: GuestUsize = 0x1000;
+
+// Mailbox of the _DSM methods of the ACPI NVDIMMs
+pub const NVDIMM_DSM_START: GuestAddress = GuestAddress(0xfed5_0000);
+pub const NVDIMM_DSM_SIZE: GuestUsize = 0x1000;
+
 // APIC
 pub const APIC_START: GuestAddress = GuestAddress(0xfee0_0000);
 
 /// Address for the TSS setup.
-pub const KVM_TSS_ADDRESS: GuestAddress = GuestAddress(0xfffb_d000);
+pub const KVM_TSS_ADDRESS: GuestAddress = GuestAddress(0xfeff_d000);
+
+// Firmware image, ending at 4GiB where the reset vector is (length: 4MiB)
+pub const FIRMWARE_START: GuestAddress = GuestAddress(0xffc0_0000);
+pub const FIRMWARE_SIZE: GuestUsize = (4 << 20);
 
 // == End of "32-bit reserved" range. ==
 
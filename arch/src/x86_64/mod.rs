@@ -6,12 +6,16 @@
 // found in the LICENSE-BSD-3-Clause file.
 
 mod gdt;
+pub mod igvm;
 pub mod interrupts;
 pub mod layout;
 mod mptable;
+mod ovmf;
 pub mod regs;
+pub mod sev;
+pub mod tdx;
 
-use crate::RegionType;
+use crate::{InitramfsConfig, RegionType};
 use linux_loader::loader::bootparam::{boot_params, setup_header};
 use std::mem;
 use vm_memory::{
@@ -32,12 +36,67 @@ struct BootParamsWrapper(boot_params);
 // It is safe to initialize BootParamsWrap which is a wrapper over `boot_params` (a series of ints).
 unsafe impl ByteValued for BootParamsWrapper {}
 
+// From xen/include/public/arch-x86/hvm/start_info.h
+const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+// PVH boot protocol start info, through which the firmware finds the
+// memory map and the ACPI tables.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct HvmStartInfo {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+// It is safe to initialize HvmStartInfo which is a series of ints.
+unsafe impl ByteValued for HvmStartInfo {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct HvmMemmapTableEntry {
+    addr: u64,
+    size: u64,
+    type_: u32,
+    reserved: u32,
+}
+
+// It is safe to initialize HvmMemmapTableEntry which is a series of ints.
+unsafe impl ByteValued for HvmMemmapTableEntry {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct HvmModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+// It is safe to initialize HvmModlistEntry which is a series of ints.
+unsafe impl ByteValued for HvmModlistEntry {}
+
+// Alignment of the initramfs in guest memory.
+const INITRAMFS_ALIGNMENT: u64 = 0x1000;
+
 #[derive(Debug, PartialEq)]
 pub enum Error {
     /// Invalid e820 setup params.
     E820Configuration,
     /// Error writing MP table to memory.
     MpTableSetup(mptable::Error),
+    /// Error writing the PVH start info to memory.
+    PvhInfoSetup,
+    /// The bzImage doesn't support the 64-bit boot protocol.
+    BzImageNot64Bit,
 }
 
 impl From<Error> for super::Error {
@@ -100,28 +159,49 @@ pub fn arch_memory_regions(size: GuestUsize) -> Vec<(GuestAddress, usize, Region
 /// * `guest_mem` - The memory to be used by the guest.
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
+/// * `initramfs` - Guest memory the initramfs was loaded in, if any.
 /// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `setup_hdr` - The bzImage setup header, if the kernel is a bzImage.
+/// * `rsdp_addr` - Address of the ACPI RSDP, if the guest has ACPI tables.
+/// * `sgx_epc_region` - Guest address range of the SGX EPC sections, if any.
+///
+/// The memory map, the initramfs and the RSDP are given both through the
+/// zero page, for the kernel, and through the PVH start info, for the
+/// firmware and the PVH kernels.
 #[allow(clippy::too_many_arguments)]
 pub fn configure_system(
     guest_mem: &GuestMemoryMmap,
     cmdline_addr: GuestAddress,
     cmdline_size: usize,
+    initramfs: &Option<InitramfsConfig>,
     num_cpus: u8,
     setup_hdr: Option<setup_header>,
     rsdp_addr: Option<GuestAddress>,
+    sgx_epc_region: Option<(GuestAddress, GuestUsize)>,
 ) -> super::Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x53726448;
     const KERNEL_LOADER_OTHER: u8 = 0xff;
     const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x1000000; // Must be non-zero.
 
+    // The 64-bit entry point is there from the boot protocol 2.12.
+    const KERNEL_64BIT_BOOT_PROTOCOL: u16 = 0x020c;
+    const XLF_KERNEL_64: u16 = 0x1;
+
     // Note that this puts the mptable at the last 1k of Linux's 640k base RAM
     mptable::setup_mptable(guest_mem, num_cpus).map_err(Error::MpTableSetup)?;
 
     let mut params: BootParamsWrapper = BootParamsWrapper(boot_params::default());
 
     if let Some(hdr) = setup_hdr {
+        if hdr.version < KERNEL_64BIT_BOOT_PROTOCOL || hdr.xloadflags & XLF_KERNEL_64 == 0 {
+            return Err(Error::BzImageNot64Bit.into());
+        }
+
+        // The setup header comes from the bzImage, the fields the boot
+        // loader sets are overwritten.
         params.0.hdr = hdr;
+        params.0.hdr.type_of_loader = KERNEL_LOADER_OTHER;
         params.0.hdr.cmd_line_ptr = cmdline_addr.raw_value() as u32;
         params.0.hdr.cmdline_size = cmdline_size as u32;
     } else {
@@ -133,51 +213,159 @@ pub fn configure_system(
         params.0.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
     };
 
-    add_e820_entry(&mut params.0, 0, layout::EBDA_START.raw_value(), E820_RAM)?;
+    if let Some(initramfs) = initramfs {
+        params.0.hdr.ramdisk_image = initramfs.address.raw_value() as u32;
+        params.0.hdr.ramdisk_size = initramfs.size as u32;
+    }
+
+    let memory_map = memory_map(guest_mem, sgx_epc_region);
+    for &(addr, size, mem_type) in memory_map.iter() {
+        add_e820_entry(&mut params.0, addr, size, mem_type)?;
+    }
+
+    if let Some(rsdp_addr) = rsdp_addr {
+        params.0.acpi_rsdp_addr = rsdp_addr.0;
+    }
+
+    configure_pvh(guest_mem, cmdline_addr, initramfs, rsdp_addr, &memory_map)?;
+
+    let zero_page_addr = layout::ZERO_PAGE_START;
+    guest_mem
+        .checked_offset(zero_page_addr, mem::size_of::<boot_params>())
+        .ok_or(super::Error::ZeroPagePastRamEnd)?;
+    guest_mem
+        .write_obj(params, zero_page_addr)
+        .map_err(|_| super::Error::ZeroPageSetup)?;
+
+    Ok(())
+}
+
+// The guest memory map, as (address, size, e820 type) entries. The SGX EPC
+// is not RAM the kernel can use, the guest finds its sections through CPUID.
+fn memory_map(
+    guest_mem: &GuestMemoryMmap,
+    sgx_epc_region: Option<(GuestAddress, GuestUsize)>,
+) -> Vec<(u64, u64, u32)> {
+    let mut memory_map = vec![(0, layout::EBDA_START.raw_value(), E820_RAM)];
 
     let mem_end = guest_mem.end_addr();
     if mem_end < layout::MEM_32BIT_RESERVED_START {
-        add_e820_entry(
-            &mut params.0,
+        memory_map.push((
             layout::HIGH_RAM_START.raw_value(),
             mem_end.unchecked_offset_from(layout::HIGH_RAM_START) + 1,
             E820_RAM,
-        )?;
+        ));
     } else {
-        add_e820_entry(
-            &mut params.0,
+        memory_map.push((
             layout::HIGH_RAM_START.raw_value(),
             layout::MEM_32BIT_RESERVED_START.unchecked_offset_from(layout::HIGH_RAM_START),
             E820_RAM,
-        )?;
+        ));
         if mem_end > layout::RAM_64BIT_START {
-            add_e820_entry(
-                &mut params.0,
+            memory_map.push((
                 layout::RAM_64BIT_START.raw_value(),
                 mem_end.unchecked_offset_from(layout::RAM_64BIT_START) + 1,
                 E820_RAM,
-            )?;
+            ));
         }
     }
 
-    add_e820_entry(
-        &mut params.0,
+    memory_map.push((
         layout::PCI_MMCONFIG_START.0,
         layout::PCI_MMCONFIG_SIZE,
         E820_RESERVED,
-    )?;
+    ));
 
-    if let Some(rsdp_addr) = rsdp_addr {
-        params.0.acpi_rsdp_addr = rsdp_addr.0;
+    if let Some((start, size)) = sgx_epc_region {
+        memory_map.push((start.raw_value(), size, E820_RESERVED));
     }
 
-    let zero_page_addr = layout::ZERO_PAGE_START;
-    guest_mem
-        .checked_offset(zero_page_addr, mem::size_of::<boot_params>())
-        .ok_or(super::Error::ZeroPagePastRamEnd)?;
+    memory_map
+}
+
+/// Returns the address the initramfs is loaded at: the end of the guest RAM
+/// below the 32-bit memory hole, aligned down to a page, leaving the start of
+/// it to the kernel.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `initramfs_size` - Size of the initramfs in bytes.
+pub fn initramfs_load_addr(
+    guest_mem: &GuestMemoryMmap,
+    initramfs_size: usize,
+) -> super::Result<GuestAddress> {
+    let lowmem_end = std::cmp::min(
+        guest_mem.end_addr().raw_value() + 1,
+        layout::MEM_32BIT_RESERVED_START.raw_value(),
+    );
+    let addr = lowmem_end
+        .checked_sub(initramfs_size as u64)
+        .ok_or(super::Error::InitramfsAddress)?
+        & !(INITRAMFS_ALIGNMENT - 1);
+    if addr < layout::HIGH_RAM_START.raw_value() {
+        return Err(super::Error::InitramfsAddress);
+    }
+
+    Ok(GuestAddress(addr))
+}
+
+// Writes the PVH start info, followed by the memory map and the module list
+// it points to.
+fn configure_pvh(
+    guest_mem: &GuestMemoryMmap,
+    cmdline_addr: GuestAddress,
+    initramfs: &Option<InitramfsConfig>,
+    rsdp_addr: Option<GuestAddress>,
+    memory_map: &[(u64, u64, u32)],
+) -> Result<(), Error> {
+    let memmap_addr = layout::PVH_INFO_START.unchecked_add(mem::size_of::<HvmStartInfo>() as u64);
+    let modlist_addr = memmap_addr
+        .unchecked_add((memory_map.len() * mem::size_of::<HvmMemmapTableEntry>()) as u64);
+    let start_info = HvmStartInfo {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        nr_modules: if initramfs.is_some() { 1 } else { 0 },
+        modlist_paddr: if initramfs.is_some() {
+            modlist_addr.raw_value()
+        } else {
+            0
+        },
+        cmdline_paddr: cmdline_addr.raw_value(),
+        rsdp_paddr: rsdp_addr.map(|addr| addr.raw_value()).unwrap_or(0),
+        memmap_paddr: memmap_addr.raw_value(),
+        memmap_entries: memory_map.len() as u32,
+        ..Default::default()
+    };
     guest_mem
-        .write_obj(params, zero_page_addr)
-        .map_err(|_| super::Error::ZeroPageSetup)?;
+        .write_obj(start_info, layout::PVH_INFO_START)
+        .map_err(|_| Error::PvhInfoSetup)?;
+
+    for (i, &(addr, size, type_)) in memory_map.iter().enumerate() {
+        let entry = HvmMemmapTableEntry {
+            addr,
+            size,
+            type_,
+            reserved: 0,
+        };
+        let entry_addr =
+            memmap_addr.unchecked_add((i * mem::size_of::<HvmMemmapTableEntry>()) as u64);
+        guest_mem
+            .write_obj(entry, entry_addr)
+            .map_err(|_| Error::PvhInfoSetup)?;
+    }
+
+    // The initramfs is the only module.
+    if let Some(initramfs) = initramfs {
+        let module = HvmModlistEntry {
+            paddr: initramfs.address.raw_value(),
+            size: initramfs.size as u64,
+            ..Default::default()
+        };
+        guest_mem
+            .write_obj(module, modlist_addr)
+            .map_err(|_| Error::PvhInfoSetup)?;
+    }
 
     Ok(())
 }
@@ -227,7 +415,7 @@ mod tests {
     fn test_system_configuration() {
         let no_vcpus = 4;
         let gm = GuestMemoryMmap::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
-        let config_err = configure_system(&gm, GuestAddress(0), 0, 1, None, None);
+        let config_err = configure_system(&gm, GuestAddress(0), 0, &None, 1, None, None, None);
         assert!(config_err.is_err());
         assert_eq!(
             config_err.unwrap_err(),
@@ -245,7 +433,7 @@ mod tests {
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::new(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus, None, None, None).unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
@@ -256,7 +444,7 @@ mod tests {
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::new(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus, None, None, None).unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
@@ -267,7 +455,61 @@ mod tests {
             .map(|r| (r.0, r.1))
             .collect();
         let gm = GuestMemoryMmap::new(&ram_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus, None, None).unwrap();
+        configure_system(&gm, GuestAddress(0), 0, &None, no_vcpus, None, None, None).unwrap();
+    }
+
+    #[test]
+    fn test_bzimage_configuration() {
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_memory_regions(128 << 20)
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gm = GuestMemoryMmap::new(&ram_regions).unwrap();
+
+        let mut hdr = setup_header {
+            version: 0x020f,
+            xloadflags: 0x1,
+            ..Default::default()
+        };
+        configure_system(
+            &gm,
+            layout::CMDLINE_START,
+            16,
+            &None,
+            1,
+            Some(hdr),
+            None,
+            None,
+        )
+        .unwrap();
+        let params: BootParamsWrapper = gm.read_obj(layout::ZERO_PAGE_START).unwrap();
+        // The setup header is packed, its fields are copied out of it.
+        let hdr_read = params.0.hdr;
+        assert_eq!({ hdr_read.type_of_loader }, 0xff);
+        assert_eq!(
+            { hdr_read.cmd_line_ptr },
+            layout::CMDLINE_START.raw_value() as u32
+        );
+        assert_eq!({ hdr_read.cmdline_size }, 16);
+        assert_eq!({ hdr_read.version }, 0x020f);
+
+        // Kernels without the 64-bit entry point can't be booted.
+        hdr.xloadflags = 0;
+        assert_eq!(
+            configure_system(
+                &gm,
+                layout::CMDLINE_START,
+                16,
+                &None,
+                1,
+                Some(hdr),
+                None,
+                None
+            )
+            .unwrap_err(),
+            super::super::Error::X86_64Setup(super::Error::BzImageNot64Bit)
+        );
     }
 
     #[test]
@@ -309,4 +551,103 @@ mod tests {
         )
         .is_err());
     }
+
+    #[test]
+    fn test_pvh_start_info() {
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_memory_regions(128 << 20)
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gm = GuestMemoryMmap::new(&ram_regions).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &None,
+            1,
+            None,
+            Some(layout::RSDP_POINTER),
+            None,
+        )
+        .unwrap();
+
+        let start_info: HvmStartInfo = gm.read_obj(layout::PVH_INFO_START).unwrap();
+        assert_eq!(start_info.magic, XEN_HVM_START_MAGIC_VALUE);
+        assert_eq!(start_info.rsdp_paddr, layout::RSDP_POINTER.raw_value());
+        assert_eq!(start_info.memmap_entries, 3);
+
+        let entry: HvmMemmapTableEntry =
+            gm.read_obj(GuestAddress(start_info.memmap_paddr)).unwrap();
+        assert_eq!(entry.addr, 0);
+        assert_eq!(entry.size, layout::EBDA_START.raw_value());
+        assert_eq!(entry.type_, E820_RAM);
+        assert_eq!(start_info.nr_modules, 0);
+    }
+
+    #[test]
+    fn test_sgx_epc_memory_map() {
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_memory_regions(128 << 20)
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gm = GuestMemoryMmap::new(&ram_regions).unwrap();
+        let epc_start = layout::RAM_64BIT_START;
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &None,
+            1,
+            None,
+            None,
+            Some((epc_start, 64 << 20)),
+        )
+        .unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(layout::ZERO_PAGE_START).unwrap();
+        assert_eq!(params.0.e820_entries, 4);
+        let entry = params.0.e820_table[3];
+        assert_eq!({ entry.addr }, epc_start.raw_value());
+        assert_eq!({ entry.size }, 64 << 20);
+        assert_eq!({ entry.type_ }, E820_RESERVED);
+
+        let start_info: HvmStartInfo = gm.read_obj(layout::PVH_INFO_START).unwrap();
+        assert_eq!(start_info.memmap_entries, 4);
+    }
+
+    #[test]
+    fn test_initramfs() {
+        let ram_regions: Vec<(GuestAddress, usize)> = arch_memory_regions(128 << 20)
+            .iter()
+            .filter(|r| r.2 == RegionType::Ram)
+            .map(|r| (r.0, r.1))
+            .collect();
+        let gm = GuestMemoryMmap::new(&ram_regions).unwrap();
+
+        let address = initramfs_load_addr(&gm, 0x1800).unwrap();
+        assert_eq!(address, GuestAddress((128 << 20) - 0x2000));
+        assert_eq!(
+            initramfs_load_addr(&gm, 128 << 20).unwrap_err(),
+            super::super::Error::InitramfsAddress
+        );
+
+        let initramfs = Some(InitramfsConfig {
+            address,
+            size: 0x1800,
+        });
+        configure_system(&gm, GuestAddress(0), 0, &initramfs, 1, None, None, None).unwrap();
+
+        let params: BootParamsWrapper = gm.read_obj(layout::ZERO_PAGE_START).unwrap();
+        let hdr = params.0.hdr;
+        assert_eq!({ hdr.ramdisk_image }, address.raw_value() as u32);
+        assert_eq!({ hdr.ramdisk_size }, 0x1800);
+
+        let start_info: HvmStartInfo = gm.read_obj(layout::PVH_INFO_START).unwrap();
+        assert_eq!(start_info.nr_modules, 1);
+        let module: HvmModlistEntry = gm.read_obj(GuestAddress(start_info.modlist_paddr)).unwrap();
+        assert_eq!(module.paddr, address.raw_value());
+        assert_eq!(module.size, 0x1800);
+    }
 }
@@ -0,0 +1,98 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! GUIDed table OVMF based firmwares describe themselves with, right below
+//! the reset vector at the end of their image.
+//!
+//! Each entry of the table is its data, followed by the u16 size of the
+//! entry and by its GUID, the entries being walked from the end. The table
+//! ends with a footer entry, whose size is the one of the whole table.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// A GUID, as stored in the firmware images.
+pub type Guid = [u8; 16];
+
+// 96b582de-1fb2-45f7-baea-a366c55a082d
+const TABLE_FOOTER_GUID: Guid = [
+    0xde, 0x82, 0xb5, 0x96, 0xb2, 0x1f, 0xf7, 0x45, 0xba, 0xea, 0xa3, 0x66, 0xc5, 0x5a, 0x08, 0x2d,
+];
+// Distance from the end of the image to the end of the footer GUID.
+const TABLE_FOOTER_OFFSET: usize = 0x20;
+const ENTRY_HEADER_SIZE: usize = 2 + 16;
+
+/// Returns the data of the table entry with the given GUID, if the image
+/// has a table, and the table has such an entry.
+pub fn table_entry<'a>(image: &'a [u8], guid: &Guid) -> Option<&'a [u8]> {
+    let footer_end = image.len().checked_sub(TABLE_FOOTER_OFFSET)?;
+    let footer_start = footer_end.checked_sub(ENTRY_HEADER_SIZE)?;
+    if image[footer_end - 16..footer_end] != TABLE_FOOTER_GUID {
+        return None;
+    }
+    let table_size = LittleEndian::read_u16(&image[footer_start..]) as usize;
+    let table_start = footer_end.checked_sub(table_size)?;
+
+    let mut entry_end = footer_start;
+    while entry_end >= table_start + ENTRY_HEADER_SIZE {
+        let entry_guid = &image[entry_end - 16..entry_end];
+        let entry_size = LittleEndian::read_u16(&image[entry_end - ENTRY_HEADER_SIZE..]) as usize;
+        if entry_size < ENTRY_HEADER_SIZE || entry_end < table_start + entry_size {
+            return None;
+        }
+
+        let entry_start = entry_end - entry_size;
+        if entry_guid == guid {
+            return Some(&image[entry_start..entry_end - ENTRY_HEADER_SIZE]);
+        }
+        entry_end = entry_start;
+    }
+
+    None
+}
+
+/// Builds an image ending with a table of the given entries, followed by a
+/// fake reset vector, for the tests of the firmware metadata.
+#[cfg(test)]
+pub fn image_with_table(entries: &[(Guid, &[u8])]) -> Vec<u8> {
+    let mut table = Vec::new();
+    for (guid, data) in entries {
+        table.extend_from_slice(data);
+        let mut size = [0u8; 2];
+        LittleEndian::write_u16(&mut size, (data.len() + ENTRY_HEADER_SIZE) as u16);
+        table.extend_from_slice(&size);
+        table.extend_from_slice(guid);
+    }
+    let mut size = [0u8; 2];
+    LittleEndian::write_u16(&mut size, (table.len() + ENTRY_HEADER_SIZE) as u16);
+    table.extend_from_slice(&size);
+    table.extend_from_slice(&TABLE_FOOTER_GUID);
+
+    let mut image = vec![0u8; 0x1000];
+    image.extend_from_slice(&table);
+    image.extend_from_slice(&[0xf4; TABLE_FOOTER_OFFSET]);
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_entry() {
+        let first = [1u8; 16];
+        let second = [2u8; 16];
+        let image = image_with_table(&[(first, &[0xaa; 4][..]), (second, &[0xbb; 6][..])]);
+
+        assert_eq!(table_entry(&image, &first), Some(&[0xaa; 4][..]));
+        assert_eq!(table_entry(&image, &second), Some(&[0xbb; 6][..]));
+        assert_eq!(table_entry(&image, &[3u8; 16]), None);
+    }
+
+    #[test]
+    fn test_no_table() {
+        assert_eq!(table_entry(&[0u8; 0x1000], &[1u8; 16]), None);
+        assert_eq!(table_entry(&[0u8; 8], &[1u8; 16]), None);
+    }
+}
@@ -5,14 +5,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use std::sync::Arc;
 use std::{io, mem, result};
 
-use super::gdt::{gdt_entry, kvm_segment_from_gdt};
+use super::gdt::{gdt_entry, segment_from_gdt};
+use super::igvm::IgvmVpContext;
 use arch_gen::x86::msr_index;
-use kvm_bindings::{kvm_fpu, kvm_msr_entry, kvm_msrs, kvm_regs, kvm_sregs};
-use kvm_ioctls::VcpuFd;
+use hypervisor::x86_64::{FpuState, MsrEntry, SpecialRegisters, StandardRegisters};
 use layout::{BOOT_GDT_START, BOOT_IDT_START, PDE_START, PDPTE_START, PML4_START};
 use vm_memory::{Address, Bytes, GuestMemory, GuestMemoryMmap};
+use BootProtocol;
 
 // MTRR constants
 const MTRR_ENABLE: u64 = 0x800; // IA32_MTRR_DEF_TYPE MSR: E (MTRRs enabled) flag, bit 11
@@ -48,9 +50,9 @@ pub type Result<T> = result::Result<T, Error>;
 ///
 /// # Arguments
 ///
-/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
-pub fn setup_fpu(vcpu: &VcpuFd) -> Result<()> {
-    let fpu: kvm_fpu = kvm_fpu {
+/// * `vcpu` - The VCPU to configure.
+pub fn setup_fpu(vcpu: &Arc<dyn hypervisor::Vcpu>) -> Result<()> {
+    let fpu: FpuState = FpuState {
         fcw: 0x37f,
         mxcsr: 0x1f80,
         ..Default::default()
@@ -63,42 +65,46 @@ pub fn setup_fpu(vcpu: &VcpuFd) -> Result<()> {
 ///
 /// # Arguments
 ///
-/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
-pub fn setup_msrs(vcpu: &VcpuFd) -> Result<()> {
-    let entry_vec = create_msr_entries();
-    let vec_size_bytes =
-        mem::size_of::<kvm_msrs>() + (entry_vec.len() * mem::size_of::<kvm_msr_entry>());
-    let vec: Vec<u8> = Vec::with_capacity(vec_size_bytes);
-    let msrs: &mut kvm_msrs = unsafe {
-        // Converting the vector's memory to a struct is unsafe.  Carefully using the read-only
-        // vector to size and set the members ensures no out-of-bounds errors below.
-        &mut *(vec.as_ptr() as *mut kvm_msrs)
-    };
-
-    unsafe {
-        // Mapping the unsized array to a slice is unsafe because the length isn't known.
-        // Providing the length used to create the struct guarantees the entire slice is valid.
-        let entries: &mut [kvm_msr_entry] = msrs.entries.as_mut_slice(entry_vec.len());
-        entries.copy_from_slice(&entry_vec);
-    }
-    msrs.nmsrs = entry_vec.len() as u32;
-
-    vcpu.set_msrs(msrs)
-        .map_err(Error::SetModelSpecificRegisters)?;
+/// * `vcpu` - The VCPU to configure.
+pub fn setup_msrs(vcpu: &Arc<dyn hypervisor::Vcpu>) -> Result<()> {
+    vcpu.set_msrs(&create_msr_entries())
+        .map_err(Error::SetModelSpecificRegisters)
+}
 
-    Ok(())
+/// Enable VMX outside of SMX on a given CPU, and lock the feature control
+/// MSR, as the firmware of a host running a hypervisor does.
+///
+/// # Arguments
+///
+/// * `vcpu` - The VCPU to configure.
+pub fn setup_vmx_msrs(vcpu: &Arc<dyn hypervisor::Vcpu>) -> Result<()> {
+    let entries = vec![MsrEntry {
+        index: msr_index::MSR_IA32_FEATURE_CONTROL,
+        data: u64::from(
+            msr_index::FEATURE_CONTROL_LOCKED
+                | msr_index::FEATURE_CONTROL_VMXON_ENABLED_OUTSIDE_SMX,
+        ),
+        ..Default::default()
+    }];
+    vcpu.set_msrs(&entries)
+        .map_err(Error::SetModelSpecificRegisters)
 }
 
 /// Configure base registers for a given CPU.
 ///
 /// # Arguments
 ///
-/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `vcpu` - The VCPU to configure.
 /// * `boot_ip` - Starting instruction pointer.
 /// * `boot_sp` - Starting stack pointer.
 /// * `boot_si` - Must point to zero page address per Linux ABI.
-pub fn setup_regs(vcpu: &VcpuFd, boot_ip: u64, boot_sp: u64, boot_si: u64) -> Result<()> {
-    let regs: kvm_regs = kvm_regs {
+pub fn setup_regs(
+    vcpu: &Arc<dyn hypervisor::Vcpu>,
+    boot_ip: u64,
+    boot_sp: u64,
+    boot_si: u64,
+) -> Result<()> {
+    let regs: StandardRegisters = StandardRegisters {
         rflags: 0x0000000000000002u64,
         rip: boot_ip,
         rsp: boot_sp,
@@ -110,18 +116,78 @@ pub fn setup_regs(vcpu: &VcpuFd, boot_ip: u64, boot_sp: u64, boot_si: u64) -> Re
     vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters)
 }
 
+/// Configure base registers for a given CPU booted through the PVH boot
+/// protocol.
+///
+/// # Arguments
+///
+/// * `vcpu` - The VCPU to configure.
+/// * `boot_ip` - Starting instruction pointer.
+/// * `start_info` - Must point to the PVH start info per PVH ABI.
+pub fn setup_pvh_regs(
+    vcpu: &Arc<dyn hypervisor::Vcpu>,
+    boot_ip: u64,
+    start_info: u64,
+) -> Result<()> {
+    let regs: StandardRegisters = StandardRegisters {
+        rflags: 0x0000000000000002u64,
+        rip: boot_ip,
+        rbx: start_info,
+        ..Default::default()
+    };
+
+    vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters)
+}
+
 /// Configures the segment registers and system page tables for a given CPU.
 ///
 /// # Arguments
 ///
 /// * `mem` - The memory that will be passed to the guest.
-/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
-pub fn setup_sregs(mem: &GuestMemoryMmap, vcpu: &VcpuFd) -> Result<()> {
-    let mut sregs: kvm_sregs = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
+/// * `vcpu` - The VCPU to configure.
+/// * `boot_prot` - The protocol the kernel is booted with.
+pub fn setup_sregs(
+    mem: &GuestMemoryMmap,
+    vcpu: &Arc<dyn hypervisor::Vcpu>,
+    boot_prot: BootProtocol,
+) -> Result<()> {
+    let mut sregs: SpecialRegisters = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
+
+    configure_segments_and_sregs(mem, &mut sregs, boot_prot)?;
+    // The PVH entry point runs with paging disabled.
+    if boot_prot == BootProtocol::LinuxBoot {
+        setup_page_tables(mem, &mut sregs)?; // TODO(dgreid) - Can this be done once per system instead?
+    }
 
-    configure_segments_and_sregs(mem, &mut sregs)?;
-    setup_page_tables(mem, &mut sregs)?; // TODO(dgreid) - Can this be done once per system instead?
+    vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)
+}
 
+/// Configures the registers of a CPU from the context an IGVM image gives
+/// it. The image sets its own descriptor tables and page tables up.
+///
+/// # Arguments
+///
+/// * `vcpu` - The VCPU to configure.
+/// * `context` - The registers the VCPU starts with.
+pub fn setup_igvm_regs(vcpu: &Arc<dyn hypervisor::Vcpu>, context: &IgvmVpContext) -> Result<()> {
+    vcpu.set_regs(&context.regs)
+        .map_err(Error::SetBaseRegisters)?;
+    setup_fpu(vcpu)?;
+
+    let mut sregs: SpecialRegisters = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
+    sregs.cs = context.cs;
+    sregs.ds = context.ds;
+    sregs.es = context.es;
+    sregs.fs = context.fs;
+    sregs.gs = context.gs;
+    sregs.ss = context.ss;
+    sregs.tr = context.tr;
+    sregs.gdt = context.gdt;
+    sregs.idt = context.idt;
+    sregs.cr0 = context.cr0;
+    sregs.cr3 = context.cr3;
+    sregs.cr4 = context.cr4;
+    sregs.efer = context.efer;
     vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)
 }
 
@@ -154,17 +220,30 @@ fn write_idt_value(val: u64, guest_mem: &GuestMemoryMmap) -> Result<()> {
         .map_err(|_| Error::WriteIDT)
 }
 
-fn configure_segments_and_sregs(mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Result<()> {
-    let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
-        gdt_entry(0, 0, 0),            // NULL
-        gdt_entry(0xa09b, 0, 0xfffff), // CODE
-        gdt_entry(0xc093, 0, 0xfffff), // DATA
-        gdt_entry(0x808b, 0, 0xfffff), // TSS
-    ];
+fn configure_segments_and_sregs(
+    mem: &GuestMemoryMmap,
+    sregs: &mut SpecialRegisters,
+    boot_prot: BootProtocol,
+) -> Result<()> {
+    let gdt_table: [u64; BOOT_GDT_MAX as usize] = match boot_prot {
+        BootProtocol::LinuxBoot => [
+            gdt_entry(0, 0, 0),            // NULL
+            gdt_entry(0xa09b, 0, 0xfffff), // CODE
+            gdt_entry(0xc093, 0, 0xfffff), // DATA
+            gdt_entry(0x808b, 0, 0xfffff), // TSS
+        ],
+        // Flat 32-bit segments, and a TSS of the minimal size.
+        BootProtocol::PvhBoot => [
+            gdt_entry(0, 0, 0),            // NULL
+            gdt_entry(0xc09b, 0, 0xfffff), // CODE
+            gdt_entry(0xc093, 0, 0xfffff), // DATA
+            gdt_entry(0x008b, 0, 0x67),    // TSS
+        ],
+    };
 
-    let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
-    let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
-    let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
+    let code_seg = segment_from_gdt(gdt_table[1], 1);
+    let data_seg = segment_from_gdt(gdt_table[2], 2);
+    let tss_seg = segment_from_gdt(gdt_table[3], 3);
 
     // Write segments
     write_gdt_table(&gdt_table[..], mem)?;
@@ -183,14 +262,24 @@ fn configure_segments_and_sregs(mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) ->
     sregs.ss = data_seg;
     sregs.tr = tss_seg;
 
-    /* 64-bit protected mode */
-    sregs.cr0 |= X86_CR0_PE;
-    sregs.efer |= EFER_LME | EFER_LMA;
+    match boot_prot {
+        BootProtocol::LinuxBoot => {
+            /* 64-bit protected mode */
+            sregs.cr0 |= X86_CR0_PE;
+            sregs.efer |= EFER_LME | EFER_LMA;
+        }
+        BootProtocol::PvhBoot => {
+            /* 32-bit protected mode, without paging */
+            sregs.cr0 = X86_CR0_PE;
+            sregs.cr4 = 0;
+            sregs.efer = 0;
+        }
+    }
 
     Ok(())
 }
 
-fn setup_page_tables(mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Result<()> {
+fn setup_page_tables(mem: &GuestMemoryMmap, sregs: &mut SpecialRegisters) -> Result<()> {
     // Puts PML4 right after zero page but aligned to 4k.
 
     // Entry covering VA [0..512GB)
@@ -213,62 +302,62 @@ fn setup_page_tables(mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Result<()>
     Ok(())
 }
 
-fn create_msr_entries() -> Vec<kvm_msr_entry> {
-    let mut entries = Vec::<kvm_msr_entry>::new();
+fn create_msr_entries() -> Vec<MsrEntry> {
+    let mut entries = Vec::<MsrEntry>::new();
 
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_IA32_SYSENTER_CS,
         data: 0x0,
         ..Default::default()
     });
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_IA32_SYSENTER_ESP,
         data: 0x0,
         ..Default::default()
     });
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_IA32_SYSENTER_EIP,
         data: 0x0,
         ..Default::default()
     });
     // x86_64 specific msrs, we only run on x86_64 not x86.
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_STAR,
         data: 0x0,
         ..Default::default()
     });
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_CSTAR,
         data: 0x0,
         ..Default::default()
     });
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_KERNEL_GS_BASE,
         data: 0x0,
         ..Default::default()
     });
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_SYSCALL_MASK,
         data: 0x0,
         ..Default::default()
     });
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_LSTAR,
         data: 0x0,
         ..Default::default()
     });
     // end of x86_64 specific code
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_IA32_TSC,
         data: 0x0,
         ..Default::default()
     });
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_IA32_MISC_ENABLE,
         data: msr_index::MSR_IA32_MISC_ENABLE_FAST_STRING as u64,
         ..Default::default()
     });
-    entries.push(kvm_msr_entry {
+    entries.push(MsrEntry {
         index: msr_index::MSR_MTRRdefType,
         data: MTRR_ENABLE | MTRR_MEM_TYPE_WB,
         ..Default::default()
@@ -279,26 +368,30 @@ fn create_msr_entries() -> Vec<kvm_msr_entry> {
 
 #[cfg(test)]
 mod tests {
-    extern crate kvm_ioctls;
     extern crate vm_memory;
 
     use super::*;
-    use kvm_ioctls::Kvm;
     use vm_memory::{GuestAddress, GuestMemoryMmap};
 
     fn create_guest_mem() -> GuestMemoryMmap {
         GuestMemoryMmap::new(&vec![(GuestAddress(0), 0x10000)]).unwrap()
     }
 
+    fn create_vcpu() -> Arc<dyn hypervisor::Vcpu> {
+        let hv = hypervisor::new().unwrap();
+        let vm = hv.create_vm().unwrap();
+        vm.create_vcpu(0, None).unwrap()
+    }
+
     fn read_u64(gm: &GuestMemoryMmap, offset: GuestAddress) -> u64 {
         gm.read_obj(offset).unwrap()
     }
 
     #[test]
     fn segments_and_sregs() {
-        let mut sregs: kvm_sregs = Default::default();
+        let mut sregs: SpecialRegisters = Default::default();
         let gm = create_guest_mem();
-        configure_segments_and_sregs(&gm, &mut sregs).unwrap();
+        configure_segments_and_sregs(&gm, &mut sregs, BootProtocol::LinuxBoot).unwrap();
 
         assert_eq!(0x0, read_u64(&gm, BOOT_GDT_START));
         assert_eq!(
@@ -328,9 +421,39 @@ mod tests {
         assert_eq!(EFER_LME | EFER_LMA, sregs.efer);
     }
 
+    #[test]
+    fn pvh_segments_and_sregs() {
+        let mut sregs: SpecialRegisters = Default::default();
+        let gm = create_guest_mem();
+        configure_segments_and_sregs(&gm, &mut sregs, BootProtocol::PvhBoot).unwrap();
+
+        assert_eq!(0x0, read_u64(&gm, BOOT_GDT_START));
+        assert_eq!(
+            0xcf9b000000ffff,
+            read_u64(&gm, BOOT_GDT_START.unchecked_add(8))
+        );
+        assert_eq!(
+            0xcf93000000ffff,
+            read_u64(&gm, BOOT_GDT_START.unchecked_add(16))
+        );
+        assert_eq!(
+            0x8b0000000067,
+            read_u64(&gm, BOOT_GDT_START.unchecked_add(24))
+        );
+
+        assert_eq!(0, sregs.cs.base);
+        assert_eq!(1, sregs.cs.db);
+        assert_eq!(0, sregs.cs.l);
+        assert_eq!(0x10, sregs.ds.selector);
+        assert_eq!(0x67, sregs.tr.limit);
+        assert_eq!(X86_CR0_PE, sregs.cr0);
+        assert_eq!(0, sregs.cr4);
+        assert_eq!(0, sregs.efer);
+    }
+
     #[test]
     fn page_tables() {
-        let mut sregs: kvm_sregs = Default::default();
+        let mut sregs: SpecialRegisters = Default::default();
         let gm = create_guest_mem();
         setup_page_tables(&gm, &mut sregs).unwrap();
 
@@ -350,17 +473,15 @@ mod tests {
 
     #[test]
     fn test_setup_fpu() {
-        let kvm = Kvm::new().unwrap();
-        let vm = kvm.create_vm().unwrap();
-        let vcpu = vm.create_vcpu(0).unwrap();
+        let vcpu = create_vcpu();
         setup_fpu(&vcpu).unwrap();
 
-        let expected_fpu: kvm_fpu = kvm_fpu {
+        let expected_fpu: FpuState = FpuState {
             fcw: 0x37f,
             mxcsr: 0x1f80,
             ..Default::default()
         };
-        let actual_fpu: kvm_fpu = vcpu.get_fpu().unwrap();
+        let actual_fpu: FpuState = vcpu.get_fpu().unwrap();
         // TODO: auto-generate kvm related structures with PartialEq on.
         assert_eq!(expected_fpu.fcw, actual_fpu.fcw);
         // Setting the mxcsr register from kvm_fpu inside setup_fpu does not influence anything.
@@ -372,31 +493,16 @@ mod tests {
 
     #[test]
     fn test_setup_msrs() {
-        let kvm = Kvm::new().unwrap();
-        let vm = kvm.create_vm().unwrap();
-        let vcpu = vm.create_vcpu(0).unwrap();
+        let vcpu = create_vcpu();
         setup_msrs(&vcpu).unwrap();
 
         // This test will check against the last MSR entry configured (the tenth one).
         // See create_msr_entries for details.
-        let test_kvm_msrs_entry = [kvm_msr_entry {
+        let mut msrs = [MsrEntry {
             index: msr_index::MSR_IA32_MISC_ENABLE,
             ..Default::default()
         }];
-        let vec_size_bytes = mem::size_of::<kvm_msrs>() + mem::size_of::<kvm_msr_entry>();
-        let vec: Vec<u8> = Vec::with_capacity(vec_size_bytes);
-        let mut msrs: &mut kvm_msrs = unsafe {
-            // Converting the vector's memory to a struct is unsafe.  Carefully using the read-only
-            // vector to size and set the members ensures no out-of-bounds errors below.
-            &mut *(vec.as_ptr() as *mut kvm_msrs)
-        };
 
-        unsafe {
-            let entries: &mut [kvm_msr_entry] = msrs.entries.as_mut_slice(1);
-            entries.copy_from_slice(&test_kvm_msrs_entry);
-        }
-
-        msrs.nmsrs = 1;
         // get_msrs returns the number of msrs that it succeed in reading. We only want to read 1
         // in this test case scenario.
         let read_msrs = vcpu.get_msrs(&mut msrs).unwrap();
@@ -406,18 +512,14 @@ mod tests {
         // tenth one (i.e the one with index msr_index::MSR_IA32_MISC_ENABLE has the data we
         // expect.
         let entry_vec = create_msr_entries();
-        unsafe {
-            assert_eq!(entry_vec[9], msrs.entries.as_slice(1)[0]);
-        }
+        assert_eq!(entry_vec[9], msrs[0]);
     }
 
     #[test]
     fn test_setup_regs() {
-        let kvm = Kvm::new().unwrap();
-        let vm = kvm.create_vm().unwrap();
-        let vcpu = vm.create_vcpu(0).unwrap();
+        let vcpu = create_vcpu();
 
-        let expected_regs: kvm_regs = kvm_regs {
+        let expected_regs: StandardRegisters = StandardRegisters {
             rflags: 0x0000000000000002u64,
             rip: 1,
             rsp: 2,
@@ -434,23 +536,21 @@ mod tests {
         )
         .unwrap();
 
-        let actual_regs: kvm_regs = vcpu.get_regs().unwrap();
+        let actual_regs: StandardRegisters = vcpu.get_regs().unwrap();
         assert_eq!(actual_regs, expected_regs);
     }
 
     #[test]
     fn test_setup_sregs() {
-        let kvm = Kvm::new().unwrap();
-        let vm = kvm.create_vm().unwrap();
-        let vcpu = vm.create_vcpu(0).unwrap();
+        let vcpu = create_vcpu();
 
-        let mut expected_sregs: kvm_sregs = vcpu.get_sregs().unwrap();
+        let mut expected_sregs: SpecialRegisters = vcpu.get_sregs().unwrap();
         let gm = create_guest_mem();
-        configure_segments_and_sregs(&gm, &mut expected_sregs).unwrap();
+        configure_segments_and_sregs(&gm, &mut expected_sregs, BootProtocol::LinuxBoot).unwrap();
         setup_page_tables(&gm, &mut expected_sregs).unwrap();
 
-        setup_sregs(&gm, &vcpu).unwrap();
-        let actual_sregs: kvm_sregs = vcpu.get_sregs().unwrap();
+        setup_sregs(&gm, &vcpu, BootProtocol::LinuxBoot).unwrap();
+        let actual_sregs: SpecialRegisters = vcpu.get_sregs().unwrap();
         assert_eq!(expected_sregs, actual_sregs);
     }
 }
@@ -0,0 +1,228 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! AMD SEV-SNP guests, booted from an OVMF firmware.
+//!
+//! Besides the firmware itself, the SEV metadata of the image tells which
+//! guest pages the VMM adds to the guest before it runs: the memory the
+//! firmware runs from until it validates the RAM, the secrets page of the
+//! guest, and the CPUID table the AMD secure processor checks.
+
+use super::ovmf::{self, Guid};
+use byteorder::{ByteOrder, LittleEndian};
+use hypervisor::x86_64::CpuId;
+use std::result;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The firmware image has no SEV metadata.
+    NoMetadata,
+    /// The SEV metadata is malformed.
+    InvalidMetadata,
+    /// There are more CPUID entries than the CPUID page has room for.
+    CpuidTableSize(usize),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+// dc886566-984a-4798-a75e-5585a7bf67cc
+const SEV_METADATA_GUID: Guid = [
+    0x66, 0x65, 0x88, 0xdc, 0x4a, 0x98, 0x98, 0x47, 0xa7, 0x5e, 0x55, 0x85, 0xa7, 0xbf, 0x67, 0xcc,
+];
+const SEV_SIGNATURE: &[u8; 4] = b"ASEV";
+const SEV_HEADER_SIZE: usize = 16;
+const SEV_SECTION_SIZE: usize = 12;
+
+/// Size of the CPUID page, and its layout from the SEV-SNP firmware ABI.
+pub const CPUID_PAGE_SIZE: usize = 0x1000;
+const CPUID_PAGE_HEADER_SIZE: usize = 16;
+const CPUID_PAGE_ENTRY_SIZE: usize = 48;
+const CPUID_PAGE_MAX_ENTRIES: usize = 64;
+// The XSAVE leaf sub-leaves depend on the enabled state components, the
+// table only has the size of the legacy and header areas, for x87 only.
+const XSAVE_CPUID_LEAF: u32 = 0xd;
+const XSAVE_LEGACY_SIZE: u32 = 0x240;
+const XCR0_X87: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SevSectionType {
+    /// Memory the firmware runs from, before it validates the RAM.
+    SnpSecMem,
+    /// The secrets page of the guest.
+    SnpSecrets,
+    /// The CPUID table.
+    Cpuid,
+    /// Calling area of the secure VM service module.
+    SvsmCaa,
+    /// Hashes of the kernel, the initramfs and the command line.
+    KernelHashes,
+}
+
+/// A range the firmware expects to be added to the guest before it runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SevSection {
+    pub address: u64,
+    pub size: u64,
+    pub type_: SevSectionType,
+}
+
+/// Returns the sections of the SEV metadata of an OVMF image.
+pub fn sev_sections(image: &[u8]) -> Result<Vec<SevSection>> {
+    let entry = ovmf::table_entry(image, &SEV_METADATA_GUID).ok_or(Error::NoMetadata)?;
+    if entry.len() < 4 {
+        return Err(Error::InvalidMetadata);
+    }
+    // The metadata is found from the end of the image.
+    let offset = image
+        .len()
+        .checked_sub(LittleEndian::read_u32(entry) as usize)
+        .ok_or(Error::InvalidMetadata)?;
+    let header = image
+        .get(offset..offset + SEV_HEADER_SIZE)
+        .ok_or(Error::InvalidMetadata)?;
+    if &header[..4] != SEV_SIGNATURE {
+        return Err(Error::InvalidMetadata);
+    }
+    let table_size = (LittleEndian::read_u32(&header[12..]) as usize)
+        .checked_mul(SEV_SECTION_SIZE)
+        .ok_or(Error::InvalidMetadata)?;
+    let table_start = offset + SEV_HEADER_SIZE;
+    let table = image
+        .get(table_start..table_start + table_size)
+        .ok_or(Error::InvalidMetadata)?;
+
+    table
+        .chunks(SEV_SECTION_SIZE)
+        .map(|raw| {
+            let type_ = match LittleEndian::read_u32(&raw[8..]) {
+                1 => SevSectionType::SnpSecMem,
+                2 => SevSectionType::SnpSecrets,
+                3 => SevSectionType::Cpuid,
+                4 => SevSectionType::SvsmCaa,
+                0x10 => SevSectionType::KernelHashes,
+                _ => return Err(Error::InvalidMetadata),
+            };
+            Ok(SevSection {
+                address: u64::from(LittleEndian::read_u32(&raw[0..])),
+                size: u64::from(LittleEndian::read_u32(&raw[4..])),
+                type_,
+            })
+        })
+        .collect()
+}
+
+/// Builds the CPUID page of the guest, from the CPUID entries of its vCPUs.
+pub fn cpuid_page(cpuid: &CpuId) -> Result<Vec<u8>> {
+    let entries = cpuid.as_slice();
+    if entries.len() > CPUID_PAGE_MAX_ENTRIES {
+        return Err(Error::CpuidTableSize(entries.len()));
+    }
+
+    let mut page = vec![0u8; CPUID_PAGE_SIZE];
+    LittleEndian::write_u32(&mut page[0..], entries.len() as u32);
+    for (i, entry) in entries.iter().enumerate() {
+        let raw = &mut page[CPUID_PAGE_HEADER_SIZE + i * CPUID_PAGE_ENTRY_SIZE..];
+        let (ebx, xcr0) = if entry.function == XSAVE_CPUID_LEAF && entry.index <= 1 {
+            (XSAVE_LEGACY_SIZE, XCR0_X87)
+        } else {
+            (entry.ebx, 0)
+        };
+        LittleEndian::write_u32(&mut raw[0..], entry.function);
+        LittleEndian::write_u32(&mut raw[4..], entry.index);
+        LittleEndian::write_u64(&mut raw[8..], xcr0);
+        // No supervisor state components.
+        LittleEndian::write_u64(&mut raw[16..], 0);
+        LittleEndian::write_u32(&mut raw[24..], entry.eax);
+        LittleEndian::write_u32(&mut raw[28..], ebx);
+        LittleEndian::write_u32(&mut raw[32..], entry.ecx);
+        LittleEndian::write_u32(&mut raw[36..], entry.edx);
+    }
+
+    Ok(page)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hypervisor::x86_64::CpuIdEntry;
+
+    fn sev_image(sections: &[(u32, u32, u32)]) -> Vec<u8> {
+        let size = SEV_HEADER_SIZE + sections.len() * SEV_SECTION_SIZE;
+        let mut metadata = vec![0u8; size];
+        metadata[..4].copy_from_slice(SEV_SIGNATURE);
+        LittleEndian::write_u32(&mut metadata[4..], size as u32);
+        LittleEndian::write_u32(&mut metadata[8..], 1);
+        LittleEndian::write_u32(&mut metadata[12..], sections.len() as u32);
+        for (i, &(base, size, type_)) in sections.iter().enumerate() {
+            let raw = &mut metadata[SEV_HEADER_SIZE + i * SEV_SECTION_SIZE..];
+            LittleEndian::write_u32(&mut raw[0..], base);
+            LittleEndian::write_u32(&mut raw[4..], size);
+            LittleEndian::write_u32(&mut raw[8..], type_);
+        }
+
+        // The metadata goes at the start of the image, whose size is known
+        // once the table is added.
+        let mut offset = [0u8; 4];
+        let image = ovmf::image_with_table(&[(SEV_METADATA_GUID, &offset[..])]);
+        LittleEndian::write_u32(&mut offset, image.len() as u32);
+        let mut image = ovmf::image_with_table(&[(SEV_METADATA_GUID, &offset[..])]);
+        image[..metadata.len()].copy_from_slice(&metadata);
+        image
+    }
+
+    #[test]
+    fn test_sev_sections() {
+        let image = sev_image(&[(0x80_0000, 0x9000, 1), (0x80_9000, 0x1000, 3)]);
+
+        assert_eq!(
+            sev_sections(&image).unwrap(),
+            vec![
+                SevSection {
+                    address: 0x80_0000,
+                    size: 0x9000,
+                    type_: SevSectionType::SnpSecMem,
+                },
+                SevSection {
+                    address: 0x80_9000,
+                    size: 0x1000,
+                    type_: SevSectionType::Cpuid,
+                },
+            ]
+        );
+
+        assert_eq!(sev_sections(&[0u8; 0x1000]), Err(Error::NoMetadata));
+        let image = sev_image(&[(0x80_0000, 0x1000, 5)]);
+        assert_eq!(sev_sections(&image), Err(Error::InvalidMetadata));
+    }
+
+    #[test]
+    fn test_cpuid_page() {
+        let entries = [
+            CpuIdEntry {
+                function: 1,
+                eax: 0x00a0_0f11,
+                ebx: 0x0800,
+                ..Default::default()
+            },
+            CpuIdEntry {
+                function: XSAVE_CPUID_LEAF,
+                ebx: 0x988,
+                ..Default::default()
+            },
+        ];
+        let mut cpuid = CpuId::new(entries.len());
+        cpuid.as_mut_slice().copy_from_slice(&entries);
+        let page = cpuid_page(&cpuid).unwrap();
+
+        assert_eq!(page.len(), CPUID_PAGE_SIZE);
+        assert_eq!(LittleEndian::read_u32(&page[0..]), 2);
+        let first = &page[CPUID_PAGE_HEADER_SIZE..];
+        assert_eq!(LittleEndian::read_u32(&first[0..]), 1);
+        assert_eq!(LittleEndian::read_u32(&first[24..]), 0x00a0_0f11);
+        assert_eq!(LittleEndian::read_u32(&first[28..]), 0x0800);
+        let second = &page[CPUID_PAGE_HEADER_SIZE + CPUID_PAGE_ENTRY_SIZE..];
+        assert_eq!(LittleEndian::read_u64(&second[8..]), XCR0_X87);
+        assert_eq!(LittleEndian::read_u32(&second[28..]), XSAVE_LEGACY_SIZE);
+    }
+}
@@ -0,0 +1,434 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Intel TDX guests, booted from a TDX virtual firmware (TDVF).
+//!
+//! The TDVF metadata tells where the sections of the firmware go in the
+//! guest memory, and which of them the VMM adds to the TD before it runs.
+//! One of them is the TD hand-off block (HOB), describing the guest memory
+//! to the firmware, and carrying the ACPI tables, which the firmware can't
+//! find in the guest memory as the other guests do.
+
+use super::ovmf::{self, Guid};
+use byteorder::{ByteOrder, LittleEndian};
+use std::result;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The firmware image has no TDVF metadata.
+    NoMetadata,
+    /// The TDVF metadata is malformed.
+    InvalidMetadata,
+    /// An ACPI table is too large for a HOB.
+    AcpiTableSize(usize),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+// e47a6535-984a-4798-865e-4685a7bf8ec2
+const TDVF_METADATA_GUID: Guid = [
+    0x35, 0x65, 0x7a, 0xe4, 0x4a, 0x98, 0x98, 0x47, 0x86, 0x5e, 0x46, 0x85, 0xa7, 0xbf, 0x8e, 0xc2,
+];
+const TDVF_SIGNATURE: &[u8; 4] = b"TDVF";
+const TDVF_HEADER_SIZE: usize = 16;
+const TDVF_SECTION_SIZE: usize = 32;
+const TDVF_ATTRIBUTE_MR_EXTEND: u32 = 1;
+const PAGE_SIZE: u64 = 0x1000;
+
+// From the UEFI Platform Initialization specification.
+const HOB_TYPE_HANDOFF: u16 = 1;
+const HOB_TYPE_RESOURCE_DESCRIPTOR: u16 = 3;
+const HOB_TYPE_GUID_EXTENSION: u16 = 4;
+const HOB_TYPE_END_OF_HOB_LIST: u16 = 0xffff;
+const HOB_HANDOFF_TABLE_VERSION: u32 = 9;
+const HOB_HEADER_SIZE: usize = 8;
+const HOB_HANDOFF_SIZE: usize = 56;
+const HOB_HANDOFF_END_OF_HOB_LIST: usize = 48;
+const HOB_RESOURCE_DESCRIPTOR_SIZE: usize = 48;
+const RESOURCE_SYSTEM_MEMORY: u32 = 0;
+const RESOURCE_MEMORY_MAPPED_IO: u32 = 1;
+const RESOURCE_MEMORY_UNACCEPTED: u32 = 7;
+// Present, initialized and tested.
+const RESOURCE_ATTRIBUTES_MEMORY: u32 = 0x7;
+// Present, initialized and uncacheable.
+const RESOURCE_ATTRIBUTES_MMIO: u32 = 0x403;
+// 6a0c5870-d4ed-44f4-a135-dd238b6f0c8d
+const ACPI_TABLE_HOB_GUID: Guid = [
+    0x70, 0x58, 0x0c, 0x6a, 0xed, 0xd4, 0xf4, 0x44, 0xa1, 0x35, 0xdd, 0x23, 0x8b, 0x6f, 0x0c, 0x8d,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TdvfSectionType {
+    /// Boot firmware volume, the firmware code.
+    Bfv,
+    /// Configuration firmware volume, the firmware variables.
+    Cfv,
+    /// Where the TD HOB goes.
+    TdHob,
+    /// Memory the firmware runs from, before it accepts the RAM.
+    TempMem,
+    /// Memory the firmware keeps once the guest runs.
+    PermMem,
+    /// Where a kernel the firmware boots goes.
+    Payload,
+    /// Where the parameters of the kernel go.
+    PayloadParam,
+}
+
+/// A section of the firmware, in the guest memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TdvfSection {
+    /// Offset in the image of the section content, if it has any.
+    pub data_offset: u32,
+    /// Size of the section content, zero if it has none.
+    pub data_size: u32,
+    /// Guest address of the section.
+    pub address: u64,
+    /// Size of the section in the guest memory.
+    pub size: u64,
+    pub type_: TdvfSectionType,
+    /// Whether the content of the section extends the TD measurement.
+    pub measure: bool,
+}
+
+impl TdvfSection {
+    /// Whether the VMM adds the section to the TD before it runs, the
+    /// other ones being left to the guest to accept.
+    pub fn is_initial(&self) -> bool {
+        match self.type_ {
+            TdvfSectionType::Bfv
+            | TdvfSectionType::Cfv
+            | TdvfSectionType::TdHob
+            | TdvfSectionType::TempMem => true,
+            _ => false,
+        }
+    }
+}
+
+/// Returns the sections of a TDVF image.
+pub fn tdvf_sections(image: &[u8]) -> Result<Vec<TdvfSection>> {
+    let entry = ovmf::table_entry(image, &TDVF_METADATA_GUID).ok_or(Error::NoMetadata)?;
+    if entry.len() < 4 {
+        return Err(Error::InvalidMetadata);
+    }
+    // The metadata is found from the end of the image.
+    let offset = image
+        .len()
+        .checked_sub(LittleEndian::read_u32(entry) as usize)
+        .ok_or(Error::InvalidMetadata)?;
+    let header = image
+        .get(offset..offset + TDVF_HEADER_SIZE)
+        .ok_or(Error::InvalidMetadata)?;
+    if &header[..4] != TDVF_SIGNATURE {
+        return Err(Error::InvalidMetadata);
+    }
+    let table_size = (LittleEndian::read_u32(&header[12..]) as usize)
+        .checked_mul(TDVF_SECTION_SIZE)
+        .ok_or(Error::InvalidMetadata)?;
+    let table_start = offset + TDVF_HEADER_SIZE;
+    let table = image
+        .get(table_start..table_start + table_size)
+        .ok_or(Error::InvalidMetadata)?;
+
+    let mut sections = Vec::new();
+    for raw in table.chunks(TDVF_SECTION_SIZE) {
+        let type_ = match LittleEndian::read_u32(&raw[24..]) {
+            0 => TdvfSectionType::Bfv,
+            1 => TdvfSectionType::Cfv,
+            2 => TdvfSectionType::TdHob,
+            3 => TdvfSectionType::TempMem,
+            4 => TdvfSectionType::PermMem,
+            5 => TdvfSectionType::Payload,
+            6 => TdvfSectionType::PayloadParam,
+            _ => return Err(Error::InvalidMetadata),
+        };
+        let section = TdvfSection {
+            data_offset: LittleEndian::read_u32(&raw[0..]),
+            data_size: LittleEndian::read_u32(&raw[4..]),
+            address: LittleEndian::read_u64(&raw[8..]),
+            size: LittleEndian::read_u64(&raw[16..]),
+            type_,
+            measure: LittleEndian::read_u32(&raw[28..]) & TDVF_ATTRIBUTE_MR_EXTEND != 0,
+        };
+
+        // The content has to be in the image, and to fit in the section,
+        // which is made of whole pages.
+        if section.data_offset as usize + section.data_size as usize > image.len()
+            || u64::from(section.data_size) > section.size
+            || section.address % PAGE_SIZE != 0
+            || section.size % PAGE_SIZE != 0
+        {
+            return Err(Error::InvalidMetadata);
+        }
+        sections.push(section);
+    }
+
+    Ok(sections)
+}
+
+/// Splits the RAM ranges into the ones the firmware finds accepted, and the
+/// ones it has to accept, as (start, size, accepted).
+pub fn memory_resources(ram: &[(u64, u64)], accepted: &[(u64, u64)]) -> Vec<(u64, u64, bool)> {
+    let mut accepted = accepted.to_vec();
+    accepted.sort();
+
+    let mut resources = Vec::new();
+    for &(start, size) in ram.iter() {
+        let end = start + size;
+        let mut cursor = start;
+        for &(accepted_start, accepted_size) in accepted.iter() {
+            let range_start = accepted_start.max(cursor);
+            let range_end = (accepted_start + accepted_size).min(end);
+            if range_start >= range_end {
+                continue;
+            }
+            if range_start > cursor {
+                resources.push((cursor, range_start - cursor, false));
+            }
+            resources.push((range_start, range_end - range_start, true));
+            cursor = range_end;
+        }
+        if cursor < end {
+            resources.push((cursor, end - cursor, false));
+        }
+    }
+
+    resources
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    let mut bytes = [0u8; 2];
+    LittleEndian::write_u16(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    let mut bytes = [0u8; 4];
+    LittleEndian::write_u32(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    let mut bytes = [0u8; 8];
+    LittleEndian::write_u64(&mut bytes, value);
+    buf.extend_from_slice(&bytes);
+}
+
+/// TD hand-off block, the list of HOBs the firmware finds the guest memory
+/// map and the ACPI tables in.
+pub struct TdHob {
+    address: u64,
+    hob: Vec<u8>,
+}
+
+impl TdHob {
+    /// Starts a HOB list, to be written at `address` in the guest memory.
+    pub fn new(address: u64) -> Self {
+        let mut hob = TdHob {
+            address,
+            hob: Vec::new(),
+        };
+        hob.push_header(HOB_TYPE_HANDOFF, HOB_HANDOFF_SIZE);
+        push_u32(&mut hob.hob, HOB_HANDOFF_TABLE_VERSION);
+        // Boot with full configuration.
+        push_u32(&mut hob.hob, 0);
+        // The memory the firmware allocates from is found from the resource
+        // descriptors, and the end of the list is set by finish().
+        for _ in 0..5 {
+            push_u64(&mut hob.hob, 0);
+        }
+
+        hob
+    }
+
+    fn push_header(&mut self, type_: u16, size: usize) {
+        push_u16(&mut self.hob, type_);
+        push_u16(&mut self.hob, size as u16);
+        push_u32(&mut self.hob, 0);
+    }
+
+    fn push_resource(&mut self, type_: u32, attributes: u32, start: u64, size: u64) {
+        self.push_header(HOB_TYPE_RESOURCE_DESCRIPTOR, HOB_RESOURCE_DESCRIPTOR_SIZE);
+        // No owner.
+        self.hob.extend_from_slice(&[0u8; 16]);
+        push_u32(&mut self.hob, type_);
+        push_u32(&mut self.hob, attributes);
+        push_u64(&mut self.hob, start);
+        push_u64(&mut self.hob, size);
+    }
+
+    /// Describes a RAM range, either already accepted or left to the
+    /// firmware to accept.
+    pub fn add_memory(&mut self, start: u64, size: u64, accepted: bool) {
+        let type_ = if accepted {
+            RESOURCE_SYSTEM_MEMORY
+        } else {
+            RESOURCE_MEMORY_UNACCEPTED
+        };
+        self.push_resource(type_, RESOURCE_ATTRIBUTES_MEMORY, start, size);
+    }
+
+    /// Describes an MMIO range of the devices.
+    pub fn add_mmio(&mut self, start: u64, size: u64) {
+        self.push_resource(
+            RESOURCE_MEMORY_MAPPED_IO,
+            RESOURCE_ATTRIBUTES_MMIO,
+            start,
+            size,
+        );
+    }
+
+    /// Hands an ACPI table over to the firmware, which installs it.
+    pub fn add_acpi_table(&mut self, table: &[u8]) -> Result<()> {
+        let size = HOB_HEADER_SIZE + ACPI_TABLE_HOB_GUID.len() + table.len();
+        // The HOBs are 8 bytes aligned.
+        let aligned_size = (size + 7) & !7;
+        if aligned_size > usize::from(u16::max_value()) {
+            return Err(Error::AcpiTableSize(table.len()));
+        }
+
+        self.push_header(HOB_TYPE_GUID_EXTENSION, aligned_size);
+        self.hob.extend_from_slice(&ACPI_TABLE_HOB_GUID);
+        self.hob.extend_from_slice(table);
+        self.hob.resize(self.hob.len() + aligned_size - size, 0);
+
+        Ok(())
+    }
+
+    /// Ends the HOB list, returning its content.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.push_header(HOB_TYPE_END_OF_HOB_LIST, HOB_HEADER_SIZE);
+        let end = self.address + self.hob.len() as u64;
+        LittleEndian::write_u64(&mut self.hob[HOB_HANDOFF_END_OF_HOB_LIST..], end);
+
+        self.hob
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tdvf_image(sections: &[(u32, u32, u64, u64, u32, u32)]) -> Vec<u8> {
+        let mut metadata = TDVF_SIGNATURE.to_vec();
+        push_u32(
+            &mut metadata,
+            (TDVF_HEADER_SIZE + sections.len() * TDVF_SECTION_SIZE) as u32,
+        );
+        push_u32(&mut metadata, 1);
+        push_u32(&mut metadata, sections.len() as u32);
+        for &(data_offset, data_size, address, size, type_, attributes) in sections.iter() {
+            push_u32(&mut metadata, data_offset);
+            push_u32(&mut metadata, data_size);
+            push_u64(&mut metadata, address);
+            push_u64(&mut metadata, size);
+            push_u32(&mut metadata, type_);
+            push_u32(&mut metadata, attributes);
+        }
+
+        // The metadata goes at the start of the image, whose size is known
+        // once the table is added.
+        let mut offset = [0u8; 4];
+        let image = ovmf::image_with_table(&[(TDVF_METADATA_GUID, &offset[..])]);
+        LittleEndian::write_u32(&mut offset, image.len() as u32);
+        let mut image = ovmf::image_with_table(&[(TDVF_METADATA_GUID, &offset[..])]);
+        image[..metadata.len()].copy_from_slice(&metadata);
+        image
+    }
+
+    #[test]
+    fn test_tdvf_sections() {
+        let image = tdvf_image(&[
+            (0x100, 0x200, 0xffff_f000, 0x1000, 0, 1),
+            (0, 0, 0x80_0000, 0x2000, 2, 0),
+        ]);
+        let sections = tdvf_sections(&image).unwrap();
+
+        assert_eq!(
+            sections,
+            vec![
+                TdvfSection {
+                    data_offset: 0x100,
+                    data_size: 0x200,
+                    address: 0xffff_f000,
+                    size: 0x1000,
+                    type_: TdvfSectionType::Bfv,
+                    measure: true,
+                },
+                TdvfSection {
+                    data_offset: 0,
+                    data_size: 0,
+                    address: 0x80_0000,
+                    size: 0x2000,
+                    type_: TdvfSectionType::TdHob,
+                    measure: false,
+                },
+            ]
+        );
+        assert!(sections[0].is_initial());
+    }
+
+    #[test]
+    fn test_invalid_tdvf_sections() {
+        assert_eq!(tdvf_sections(&[0u8; 0x1000]), Err(Error::NoMetadata));
+        // Unknown section type.
+        let image = tdvf_image(&[(0, 0, 0x80_0000, 0x1000, 7, 0)]);
+        assert_eq!(tdvf_sections(&image), Err(Error::InvalidMetadata));
+        // Content larger than the section.
+        let image = tdvf_image(&[(0, 0x2000, 0x80_0000, 0x1000, 0, 0)]);
+        assert_eq!(tdvf_sections(&image), Err(Error::InvalidMetadata));
+    }
+
+    #[test]
+    fn test_memory_resources() {
+        let ram = [(0, 0x8000_0000), (0x1_0000_0000, 0x1000_0000)];
+        let accepted = [(0x81_0000, 0x1000), (0x80_0000, 0x1000)];
+
+        assert_eq!(
+            memory_resources(&ram, &accepted),
+            vec![
+                (0, 0x80_0000, false),
+                (0x80_0000, 0x1000, true),
+                (0x80_1000, 0xf000, false),
+                (0x81_0000, 0x1000, true),
+                (0x81_1000, 0x8000_0000 - 0x81_1000, false),
+                (0x1_0000_0000, 0x1000_0000, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_td_hob() {
+        let mut hob = TdHob::new(0x80_0000);
+        hob.add_memory(0, 0x8000_0000, false);
+        hob.add_mmio(0xc000_0000, 0x1000_0000);
+        hob.add_acpi_table(&[0xaa; 5]).unwrap();
+        let hob = hob.finish();
+
+        let acpi_hob_size = (HOB_HEADER_SIZE + 16 + 5 + 7) & !7;
+        let size = HOB_HANDOFF_SIZE + 2 * HOB_RESOURCE_DESCRIPTOR_SIZE + acpi_hob_size + 8;
+        assert_eq!(hob.len(), size);
+        assert_eq!(LittleEndian::read_u16(&hob[0..]), HOB_TYPE_HANDOFF);
+        assert_eq!(
+            LittleEndian::read_u64(&hob[HOB_HANDOFF_END_OF_HOB_LIST..]),
+            0x80_0000 + size as u64
+        );
+        let acpi_hob = HOB_HANDOFF_SIZE + 2 * HOB_RESOURCE_DESCRIPTOR_SIZE;
+        assert_eq!(
+            LittleEndian::read_u16(&hob[acpi_hob + 2..]) as usize,
+            acpi_hob_size
+        );
+        assert_eq!(&hob[acpi_hob + 8..acpi_hob + 24], &ACPI_TABLE_HOB_GUID[..]);
+        assert_eq!(
+            LittleEndian::read_u16(&hob[size - 8..]),
+            HOB_TYPE_END_OF_HOB_LIST
+        );
+
+        let mut hob = TdHob::new(0);
+        assert_eq!(
+            hob.add_acpi_table(&[0u8; 0x10000]),
+            Err(Error::AcpiTableSize(0x10000))
+        );
+    }
+}
@@ -3,8 +3,10 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use byteorder::{ByteOrder, LittleEndian};
 use vmm_sys_util::eventfd::EventFd;
 use BusDevice;
+use Interrupt;
 
 /// A device for handling ACPI shutdown and reboot
 pub struct AcpiShutdownDevice {
@@ -50,3 +52,192 @@ impl BusDevice for AcpiShutdownDevice {
         }
     }
 }
+
+/// Generic Event Device notification for an ACPI power button press.
+pub const GED_POWER_BUTTON: u8 = 1 << 0;
+/// Generic Event Device notification for a battery status change.
+pub const GED_BATTERY: u8 = 1 << 1;
+/// Generic Event Device notification for a thermal zone temperature change.
+pub const GED_THERMAL_ZONE: u8 = 1 << 2;
+/// Generic Event Device notification for PCI devices being added or removed.
+pub const GED_PCI_HOTPLUG: u8 = 1 << 3;
+
+/// A device for notifying the guest about ACPI events such as the power
+/// button being pressed. The pending notifications are reported through a
+/// single byte register which is cleared when the guest reads it.
+pub struct AcpiGEDDevice {
+    interrupt: Box<dyn Interrupt>,
+    notification_type: u8,
+}
+
+impl AcpiGEDDevice {
+    pub fn new(interrupt: Box<dyn Interrupt>) -> AcpiGEDDevice {
+        AcpiGEDDevice {
+            interrupt,
+            notification_type: 0,
+        }
+    }
+
+    /// Records the notification and interrupts the guest so that its _EVT
+    /// method handles it.
+    pub fn notify(&mut self, notification_type: u8) -> Result<(), std::io::Error> {
+        self.notification_type |= notification_type;
+        self.interrupt.deliver()
+    }
+}
+
+impl BusDevice for AcpiGEDDevice {
+    fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        data[0] = self.notification_type;
+        self.notification_type = 0;
+    }
+
+    fn write(&mut self, _base: u64, _offset: u64, _data: &[u8]) {}
+}
+
+/// A device through which the guest ACPI methods find out about the PCI
+/// devices being added to or removed from the bus 0, and eject them. Its
+/// three 32 bits registers have a bit per device number: the devices added,
+/// and the ones to be removed, both cleared when the guest reads them, and
+/// the ones the guest ejected, which it writes.
+pub struct AcpiPciHotplugDevice {
+    added: u32,
+    removing: u32,
+    ejected: u32,
+    eject_evt: EventFd,
+}
+
+impl AcpiPciHotplugDevice {
+    /// Constructs a device that will signal the given event when the guest
+    /// ejects a device.
+    pub fn new(eject_evt: EventFd) -> AcpiPciHotplugDevice {
+        AcpiPciHotplugDevice {
+            added: 0,
+            removing: 0,
+            ejected: 0,
+            eject_evt,
+        }
+    }
+
+    /// Records the device added, for the guest to enumerate it once it is
+    /// notified.
+    pub fn add_device(&mut self, device_id: u32) {
+        self.added |= 1 << device_id;
+    }
+
+    /// Records the device to remove, for the guest to eject it once it is
+    /// notified.
+    pub fn remove_device(&mut self, device_id: u32) {
+        self.removing |= 1 << device_id;
+    }
+
+    /// The devices the guest ejected since the last call.
+    pub fn take_ejected(&mut self) -> u32 {
+        let ejected = self.ejected;
+        self.ejected = 0;
+        ejected
+    }
+}
+
+impl BusDevice for AcpiPciHotplugDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.len() != 4 {
+            for i in data.iter_mut() {
+                *i = 0;
+            }
+            return;
+        }
+
+        let value = match offset {
+            0 => std::mem::replace(&mut self.added, 0),
+            4 => std::mem::replace(&mut self.removing, 0),
+            _ => 0,
+        };
+        LittleEndian::write_u32(data, value);
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if data.len() != 4 || offset != 8 {
+            return;
+        }
+
+        self.ejected |= LittleEndian::read_u32(data);
+        if let Err(e) = self.eject_evt.write(1) {
+            error!("Error triggering the PCI device eject event: {}", e);
+        }
+    }
+}
+
+/// Design capacity of the emulated battery, in mWh.
+pub const BATTERY_DESIGN_CAPACITY: u32 = 50_000;
+
+// Battery state bits, as returned by the ACPI _BST method.
+const BATTERY_DISCHARGING: u32 = 1 << 0;
+const BATTERY_CHARGING: u32 = 1 << 1;
+
+// 0 degrees Celsius, in tenths of Kelvin which is the ACPI temperature unit.
+const ZERO_CELSIUS_DECI_KELVIN: u32 = 2732;
+
+/// A device exposing synthetic battery and thermal zone readings to the
+/// guest ACPI methods, through four read-only 32 bits registers: the battery
+/// state, its present rate and remaining capacity, and the temperature.
+pub struct AcpiSensorsDevice {
+    registers: [u32; 4],
+}
+
+impl AcpiSensorsDevice {
+    /// Constructs a device reporting a full battery, at 25 degrees Celsius.
+    pub fn new() -> AcpiSensorsDevice {
+        AcpiSensorsDevice {
+            registers: [
+                0,
+                0,
+                BATTERY_DESIGN_CAPACITY,
+                ZERO_CELSIUS_DECI_KELVIN + 250,
+            ],
+        }
+    }
+
+    /// Updates the battery readings, `level` being the remaining capacity in
+    /// percent and `rate` the charging or discharging rate in mW.
+    pub fn set_battery(&mut self, charging: bool, level: u8, rate: u32) {
+        let level = u32::from(std::cmp::min(level, 100));
+
+        self.registers[0] = if !charging {
+            BATTERY_DISCHARGING
+        } else if level < 100 {
+            BATTERY_CHARGING
+        } else {
+            0
+        };
+        self.registers[1] = rate;
+        self.registers[2] = BATTERY_DESIGN_CAPACITY / 100 * level;
+    }
+
+    /// Updates the thermal zone temperature, in degrees Celsius.
+    pub fn set_temperature(&mut self, celsius: u32) {
+        self.registers[3] = ZERO_CELSIUS_DECI_KELVIN + celsius * 10;
+    }
+}
+
+impl Default for AcpiSensorsDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusDevice for AcpiSensorsDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let reg = offset as usize / 4;
+        if data.len() != 4 || offset % 4 != 0 || reg >= self.registers.len() {
+            for i in data.iter_mut() {
+                *i = 0;
+            }
+            return;
+        }
+
+        LittleEndian::write_u32(data, self.registers[reg]);
+    }
+
+    fn write(&mut self, _base: u64, _offset: u64, _data: &[u8]) {}
+}
@@ -11,8 +11,7 @@
 
 use crate::BusDevice;
 use byteorder::{ByteOrder, LittleEndian};
-use kvm_bindings::kvm_msi;
-use kvm_ioctls::VmFd;
+use hypervisor::MsiMessage;
 use std::sync::Arc;
 use std::{io, result};
 use vm_memory::GuestAddress;
@@ -156,7 +155,7 @@ pub struct Ioapic {
     id: u32,
     reg_sel: u32,
     reg_entries: [RedirectionTableEntry; NUM_IOAPIC_PINS],
-    vm_fd: Arc<VmFd>,
+    vm: Arc<dyn hypervisor::Vm>,
     apic_address: GuestAddress,
 }
 
@@ -196,12 +195,12 @@ impl BusDevice for Ioapic {
 }
 
 impl Ioapic {
-    pub fn new(vm_fd: Arc<VmFd>, apic_address: GuestAddress) -> Ioapic {
+    pub fn new(vm: Arc<dyn hypervisor::Vm>, apic_address: GuestAddress) -> Ioapic {
         Ioapic {
             id: 0,
             reg_sel: 0,
             reg_entries: [0; NUM_IOAPIC_PINS],
-            vm_fd,
+            vm,
             apic_address,
         }
     }
@@ -274,7 +273,7 @@ impl Ioapic {
             | u32::from(delivery_mode) << 8
             | u32::from(vector(*entry));
 
-        let msi = kvm_msi {
+        let msi = MsiMessage {
             address_lo,
             address_hi: 0x0,
             data,
@@ -283,9 +282,9 @@ impl Ioapic {
             pad: [0u8; 12],
         };
 
-        match self.vm_fd.signal_msi(msi) {
-            Ok(ret) => {
-                if ret > 0 {
+        match self.vm.signal_msi(msi) {
+            Ok(delivered) => {
+                if delivered {
                     debug!("MSI message successfully delivered");
                     // If trigger mode is level sensitive, set the Remote IRR bit.
                     // It will be cleared when the EOI is received.
@@ -13,6 +13,17 @@ const INDEX_OFFSET: u64 = 0x0;
 const DATA_OFFSET: u64 = 0x1;
 const DATA_LEN: usize = 128;
 
+// Status registers: the default divider, the 24-hour BCD mode, and the valid
+// RAM and time bit, which Windows checks before trusting the clock.
+const STATUS_REG_A: usize = 0x0a;
+const STATUS_REG_B: usize = 0x0b;
+const STATUS_REG_D: usize = 0x0d;
+const STATUS_A_DIVIDER: u8 = 0x26;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOURS_PM: u8 = 0x80;
+const STATUS_D_VRT: u8 = 0x80;
+
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
 pub struct Cmos {
     index: u8,
@@ -26,6 +37,10 @@ impl Cmos {
     pub fn new(mem_below_4g: u64, mem_above_4g: u64) -> Cmos {
         let mut data = [0u8; DATA_LEN];
 
+        data[STATUS_REG_A] = STATUS_A_DIVIDER;
+        data[STATUS_REG_B] = STATUS_B_24_HOUR;
+        data[STATUS_REG_D] = STATUS_D_VRT;
+
         // Extended memory from 16 MB to 4 GB in units of 64 KB
         let ext_mem = min(
             0xFFFF,
@@ -94,15 +109,30 @@ impl BusDevice for Cmos {
                     month = tm.tm_mon + 1;
                     year = tm.tm_year;
                 };
+                // The guest picks the format of the time through the status
+                // register B.
+                let status_b = self.data[STATUS_REG_B];
+                let encode = |v: u8| {
+                    if status_b & STATUS_B_BINARY != 0 {
+                        v
+                    } else {
+                        to_bcd(v)
+                    }
+                };
+                let hours = hours as u8;
                 match self.index {
-                    0x00 => to_bcd(seconds as u8),
-                    0x02 => to_bcd(minutes as u8),
-                    0x04 => to_bcd(hours as u8),
-                    0x06 => to_bcd(week_day as u8),
-                    0x07 => to_bcd(day as u8),
-                    0x08 => to_bcd(month as u8),
-                    0x09 => to_bcd((year % 100) as u8),
-                    0x32 => to_bcd(((year + 1900) / 100) as u8),
+                    0x00 => encode(seconds as u8),
+                    0x02 => encode(minutes as u8),
+                    0x04 if status_b & STATUS_B_24_HOUR != 0 => encode(hours),
+                    0x04 => {
+                        let pm = if hours >= 12 { HOURS_PM } else { 0 };
+                        encode((hours + 11) % 12 + 1) | pm
+                    }
+                    0x06 => encode(week_day as u8),
+                    0x07 => encode(day as u8),
+                    0x08 => encode(month as u8),
+                    0x09 => encode((year % 100) as u8),
+                    0x32 => encode(((year + 1900) / 100) as u8),
                     _ => {
                         // self.index is always guaranteed to be in range via INDEX_MASK.
                         self.data[(self.index & INDEX_MASK) as usize]
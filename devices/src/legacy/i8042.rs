@@ -33,7 +33,10 @@ impl BusDevice for I8042Device {
     }
 
     fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
-        if data.len() == 1 && data[0] == 0xfe && offset == 3 {
+        // The pulse output line commands, 0xf0 to 0xff, pulse the lines
+        // whose bit is cleared, the reset line being the first one. 0xfe is
+        // the usual reset command.
+        if data.len() == 1 && data[0] & 0xf1 == 0xf0 && offset == 3 {
             debug!("i8042 reset signalled");
             if let Err(e) = self.reset_evt.write(1) {
                 error!("Error triggering i8042 reset event: {}", e);
@@ -8,9 +8,13 @@
 #[cfg(feature = "cmos")]
 mod cmos;
 mod i8042;
+mod pl011;
+mod pvpanic;
 mod serial;
 
 #[cfg(feature = "cmos")]
 pub use self::cmos::Cmos;
 pub use self::i8042::I8042Device;
+pub use self::pl011::Pl011;
+pub use self::pvpanic::{Pvpanic, PVPANIC_CRASH_LOADED, PVPANIC_PANICKED};
 pub use self::serial::Serial;
@@ -0,0 +1,317 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::{BusDevice, Interrupt};
+use std::collections::VecDeque;
+use std::{io, result};
+use vmm_sys_util::errno::Result;
+
+// Register offsets.
+const UARTDR: u64 = 0x000;
+const UARTRSR_UARTECR: u64 = 0x004;
+const UARTFR: u64 = 0x018;
+const UARTILPR: u64 = 0x020;
+const UARTIBRD: u64 = 0x024;
+const UARTFBRD: u64 = 0x028;
+const UARTLCR_H: u64 = 0x02c;
+const UARTCR: u64 = 0x030;
+const UARTIFLS: u64 = 0x034;
+const UARTIMSC: u64 = 0x038;
+const UARTRIS: u64 = 0x03c;
+const UARTMIS: u64 = 0x040;
+const UARTICR: u64 = 0x044;
+const UARTDMACR: u64 = 0x048;
+const UARTPERIPHID0: u64 = 0xfe0;
+const UARTPCELLID3: u64 = 0xffc;
+
+// Peripheral and PrimeCell identification registers, the OS drivers match
+// the device on.
+const PL011_ID: [u32; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+// Flag register bits.
+const FR_RXFE: u32 = 0x10;
+const FR_TXFE: u32 = 0x80;
+
+// Interrupt bits, shared by the mask, raw and masked status registers.
+const INT_RX: u32 = 0x10;
+const INT_TX: u32 = 0x20;
+
+const DEFAULT_FLAGS: u32 = FR_TXFE | FR_RXFE; // both FIFOs empty
+const DEFAULT_CONTROL: u32 = 0x300; // transmit and receive enabled
+const DEFAULT_FIFO_LEVEL: u32 = 0x12; // FIFOs half full
+const DEFAULT_BAUD_DIVISOR: u32 = 13; // 115200 bps, from a 24MHz clock
+
+/// Emulates the ARM PL011 UART, the serial port of the aarch64 platforms.
+///
+/// This can optionally write the guest's output to a Write trait object. To send input to the
+/// guest, use `queue_input_bytes`.
+pub struct Pl011 {
+    flags: u32,
+    line_control: u32,
+    control: u32,
+    fifo_level: u32,
+    int_enabled: u32,
+    int_level: u32,
+    integer_baud_divisor: u32,
+    fractional_baud_divisor: u32,
+    irda_low_power: u32,
+    dma_control: u32,
+    interrupt: Box<dyn Interrupt>,
+    in_buffer: VecDeque<u8>,
+    out: Option<Box<dyn io::Write + Send>>,
+}
+
+impl Pl011 {
+    pub fn new(interrupt: Box<dyn Interrupt>, out: Option<Box<dyn io::Write + Send>>) -> Pl011 {
+        Pl011 {
+            flags: DEFAULT_FLAGS,
+            line_control: 0,
+            control: DEFAULT_CONTROL,
+            fifo_level: DEFAULT_FIFO_LEVEL,
+            int_enabled: 0,
+            int_level: 0,
+            integer_baud_divisor: DEFAULT_BAUD_DIVISOR,
+            fractional_baud_divisor: 0,
+            irda_low_power: 0,
+            dma_control: 0,
+            interrupt,
+            in_buffer: VecDeque::new(),
+            out,
+        }
+    }
+
+    /// Queues raw bytes for the guest to read and signals the interrupt if enabled.
+    pub fn queue_input_bytes(&mut self, c: &[u8]) -> Result<()> {
+        if c.is_empty() {
+            return Ok(());
+        }
+
+        self.in_buffer.extend(c);
+        self.flags &= !FR_RXFE;
+        self.int_level |= INT_RX;
+        self.update_interrupt()?;
+        Ok(())
+    }
+
+    // Delivers the interrupt when any of the raised ones is enabled.
+    fn update_interrupt(&mut self) -> result::Result<(), io::Error> {
+        if self.int_level & self.int_enabled != 0 {
+            self.interrupt.deliver()?;
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self) -> u32 {
+        let c = self.in_buffer.pop_front().unwrap_or_default();
+        if self.in_buffer.is_empty() {
+            self.flags |= FR_RXFE;
+            self.int_level &= !INT_RX;
+        }
+        u32::from(c)
+    }
+
+    fn handle_read(&mut self, offset: u64) -> u32 {
+        match offset {
+            UARTDR => self.read_data(),
+            UARTRSR_UARTECR => 0,
+            UARTFR => self.flags,
+            UARTILPR => self.irda_low_power,
+            UARTIBRD => self.integer_baud_divisor,
+            UARTFBRD => self.fractional_baud_divisor,
+            UARTLCR_H => self.line_control,
+            UARTCR => self.control,
+            UARTIFLS => self.fifo_level,
+            UARTIMSC => self.int_enabled,
+            UARTRIS => self.int_level,
+            UARTMIS => self.int_level & self.int_enabled,
+            UARTDMACR => self.dma_control,
+            UARTPERIPHID0..=UARTPCELLID3 if offset & 0x3 == 0 => {
+                PL011_ID[((offset - UARTPERIPHID0) / 4) as usize]
+            }
+            _ => 0,
+        }
+    }
+
+    fn handle_write(&mut self, offset: u64, v: u32) -> Result<()> {
+        match offset {
+            UARTDR => {
+                if let Some(out) = self.out.as_mut() {
+                    out.write_all(&[v as u8])?;
+                    out.flush()?;
+                }
+                // The byte is sent right away, leaving the transmit FIFO
+                // empty again.
+                self.int_level |= INT_TX;
+                self.update_interrupt()?;
+            }
+            UARTRSR_UARTECR => {}
+            UARTILPR => self.irda_low_power = v & 0xff,
+            UARTIBRD => self.integer_baud_divisor = v & 0xffff,
+            UARTFBRD => self.fractional_baud_divisor = v & 0x3f,
+            UARTLCR_H => self.line_control = v & 0xff,
+            UARTCR => self.control = v & 0xffff,
+            UARTIFLS => self.fifo_level = v & 0x3f,
+            UARTIMSC => {
+                self.int_enabled = v & 0x7ff;
+                self.update_interrupt()?;
+            }
+            UARTICR => {
+                self.int_level &= !v;
+                self.update_interrupt()?;
+            }
+            UARTDMACR => self.dma_control = v & 0x7,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl BusDevice for Pl011 {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.is_empty() || data.len() > 4 {
+            return;
+        }
+
+        let v = self.handle_read(offset);
+        data.copy_from_slice(&v.to_le_bytes()[..data.len()]);
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if data.is_empty() || data.len() > 4 {
+            return;
+        }
+
+        let mut bytes = [0u8; 4];
+        bytes[..data.len()].copy_from_slice(data);
+        if let Err(_e) = self.handle_write(offset, u32::from_le_bytes(bytes)) {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use vmm_sys_util::eventfd::EventFd;
+
+    struct TestInterrupt {
+        event_fd: EventFd,
+    }
+
+    impl Interrupt for TestInterrupt {
+        fn deliver(&self) -> result::Result<(), std::io::Error> {
+            self.event_fd.write(1)
+        }
+    }
+
+    impl TestInterrupt {
+        fn new(event_fd: EventFd) -> Self {
+            TestInterrupt { event_fd }
+        }
+    }
+
+    #[derive(Clone)]
+    struct SharedBuffer {
+        buf: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl SharedBuffer {
+        fn new() -> SharedBuffer {
+            SharedBuffer {
+                buf: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+    }
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.buf.lock().unwrap().flush()
+        }
+    }
+
+    fn read_reg(pl011: &mut Pl011, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        pl011.read(0, offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    #[test]
+    fn pl011_output() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let pl011_out = SharedBuffer::new();
+        let mut pl011 = Pl011::new(
+            Box::new(TestInterrupt::new(intr_evt.try_clone().unwrap())),
+            Some(Box::new(pl011_out.clone())),
+        );
+
+        // Both byte and word accesses are handled.
+        pl011.write(0, UARTDR, b"a");
+        pl011.write(0, UARTDR, &[b'b', 0, 0, 0]);
+        pl011.write(0, UARTDR, &[b'c', 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(pl011_out.buf.lock().unwrap().as_slice(), b"ab");
+        assert_eq!(read_reg(&mut pl011, UARTRIS) & INT_TX, INT_TX);
+    }
+
+    #[test]
+    fn pl011_input() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut pl011 = Pl011::new(
+            Box::new(TestInterrupt::new(intr_evt.try_clone().unwrap())),
+            None,
+        );
+
+        // write 1 to the interrupt event fd, so that read doesn't block in case the event fd
+        // counter doesn't change (for 0 it blocks)
+        assert!(intr_evt.write(1).is_ok());
+        pl011.write(0, UARTIMSC, &INT_RX.to_le_bytes());
+        pl011.queue_input_bytes(b"ab").unwrap();
+        assert_eq!(intr_evt.read().unwrap(), 2);
+
+        assert_eq!(read_reg(&mut pl011, UARTFR) & FR_RXFE, 0);
+        assert_eq!(read_reg(&mut pl011, UARTMIS), INT_RX);
+        assert_eq!(read_reg(&mut pl011, UARTDR), u32::from(b'a'));
+        assert_eq!(read_reg(&mut pl011, UARTDR), u32::from(b'b'));
+        assert_eq!(read_reg(&mut pl011, UARTFR) & FR_RXFE, FR_RXFE);
+        assert_eq!(read_reg(&mut pl011, UARTRIS), 0);
+    }
+
+    #[test]
+    fn pl011_interrupt_clear() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut pl011 = Pl011::new(
+            Box::new(TestInterrupt::new(intr_evt.try_clone().unwrap())),
+            None,
+        );
+
+        assert!(intr_evt.write(1).is_ok());
+        pl011.write(0, UARTDR, b"a");
+        // The transmit interrupt is raised, but masked.
+        assert_eq!(intr_evt.read().unwrap(), 1);
+        assert_eq!(read_reg(&mut pl011, UARTMIS), 0);
+
+        // Unmasking delivers the pending interrupt.
+        assert!(intr_evt.write(1).is_ok());
+        pl011.write(0, UARTIMSC, &INT_TX.to_le_bytes());
+        assert_eq!(intr_evt.read().unwrap(), 2);
+
+        pl011.write(0, UARTICR, &INT_TX.to_le_bytes());
+        assert_eq!(read_reg(&mut pl011, UARTRIS), 0);
+    }
+
+    #[test]
+    fn pl011_id() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut pl011 = Pl011::new(Box::new(TestInterrupt::new(intr_evt)), None);
+
+        let id: Vec<u32> = (0..8)
+            .map(|i| read_reg(&mut pl011, UARTPERIPHID0 + i * 4))
+            .collect();
+        assert_eq!(id, PL011_ID);
+        assert_eq!(read_reg(&mut pl011, UARTPERIPHID0 + 1), 0);
+    }
+}
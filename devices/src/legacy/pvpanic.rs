@@ -0,0 +1,92 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use vmm_sys_util::eventfd::EventFd;
+
+use BusDevice;
+
+/// The guest panicked.
+pub const PVPANIC_PANICKED: u8 = 1 << 0;
+/// The guest panicked, and is about to run the crash kernel it loaded.
+pub const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+/// A pvpanic device, as the ISA one of QEMU on I/O port 0x505, through which
+/// the guest reports its panics. Reading the register gives the events the
+/// device supports, and the guest writes the one it reports.
+pub struct Pvpanic {
+    panic_evt: EventFd,
+    events: u8,
+}
+
+impl Pvpanic {
+    /// Constructs a pvpanic device that will signal the given event when the
+    /// guest reports a panic.
+    pub fn new(panic_evt: EventFd) -> Pvpanic {
+        Pvpanic {
+            panic_evt,
+            events: 0,
+        }
+    }
+
+    /// The events the guest reported since the last call.
+    pub fn take_events(&mut self) -> u8 {
+        let events = self.events;
+        self.events = 0;
+        events
+    }
+}
+
+impl BusDevice for Pvpanic {
+    fn read(&mut self, _base: u64, _offset: u64, data: &mut [u8]) {
+        if data.len() == 1 {
+            data[0] = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+        }
+    }
+
+    fn write(&mut self, _base: u64, _offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            return;
+        }
+
+        let events = data[0] & (PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+        if events == 0 {
+            return;
+        }
+
+        debug!("Guest panic reported: {:#x}", events);
+        self.events |= events;
+        if let Err(e) = self.panic_evt.write(1) {
+            error!("Error triggering the guest panic event: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pvpanic_events() {
+        let panic_evt = EventFd::new(0).unwrap();
+        let mut pvpanic = Pvpanic::new(panic_evt.try_clone().unwrap());
+
+        let mut data = [0u8];
+        pvpanic.read(0, 0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+
+        // Unknown events are ignored.
+        pvpanic.write(0, 0, &[1 << 4]);
+        assert_eq!(pvpanic.take_events(), 0);
+
+        pvpanic.write(0, 0, &[PVPANIC_PANICKED]);
+        pvpanic.write(0, 0, &[PVPANIC_CRASH_LOADED]);
+        assert_eq!(panic_evt.read().unwrap(), 2);
+        assert_eq!(
+            pvpanic.take_events(),
+            PVPANIC_PANICKED | PVPANIC_CRASH_LOADED
+        );
+        assert_eq!(pvpanic.take_events(), 0);
+    }
+}
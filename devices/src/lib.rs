@@ -8,11 +8,11 @@
 //! Emulates virtual and hardware devices.
 extern crate byteorder;
 extern crate epoll;
-extern crate kvm_bindings;
-extern crate kvm_ioctls;
+extern crate hypervisor;
 extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate vhost_rs;
 extern crate vm_memory;
 extern crate vmm_sys_util;
 
@@ -24,9 +24,14 @@ mod acpi;
 mod bus;
 pub mod ioapic;
 pub mod legacy;
+pub mod nvdimm;
+pub mod tpm;
 
 #[cfg(feature = "acpi")]
-pub use self::acpi::AcpiShutdownDevice;
+pub use self::acpi::{
+    AcpiGEDDevice, AcpiPciHotplugDevice, AcpiSensorsDevice, AcpiShutdownDevice,
+    BATTERY_DESIGN_CAPACITY, GED_BATTERY, GED_PCI_HOTPLUG, GED_POWER_BUTTON, GED_THERMAL_ZONE,
+};
 pub use self::bus::{Bus, BusDevice, Error as BusError};
 
 pub type DeviceEventT = u16;
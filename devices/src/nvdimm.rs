@@ -0,0 +1,290 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Mailbox the _DSM (Device Specific Method) methods of the ACPI NVDIMMs
+//! reach the VMM through.
+//!
+//! The _DSM method of an NVDIMM writes the handle of the NVDIMM, the UUID of
+//! the function family, the revision and the input buffer of the function to
+//! the mailbox, and the function index last, which runs the function right
+//! away. It then reads the length and the content of the output buffer back.
+//! Only the namespace label functions of the Intel family are implemented,
+//! for the guest to manage the labels of the NVDIMMs which have a label area.
+
+use crate::BusDevice;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+// Register offsets, which the AML of the _DSM methods lays its fields out
+// from.
+pub const DSM_HANDLE: usize = 0x00;
+pub const DSM_REVISION: usize = 0x04;
+pub const DSM_FUNCTION: usize = 0x08;
+pub const DSM_UUID: usize = 0x10;
+pub const DSM_INPUT: usize = 0x20;
+pub const DSM_OUTPUT_LENGTH: usize = 0x200;
+pub const DSM_OUTPUT: usize = 0x204;
+
+/// Size of the MMIO region of the mailbox.
+pub const DSM_REGION_SIZE: u64 = 0x1000;
+/// Most bytes of label data a single function transfers.
+pub const DSM_MAX_TRANSFER: usize = 256;
+/// Size of the input buffer: the offset and length of the label data, and
+/// the data of a write.
+pub const DSM_INPUT_SIZE: usize = 8 + DSM_MAX_TRANSFER;
+/// Size of the output buffer: the status, and the data of a read.
+pub const DSM_OUTPUT_SIZE: usize = 4 + DSM_MAX_TRANSFER;
+
+/// UUID of the Intel NVDIMM DSM family, 4309AC30-0D11-11E4-9191-0800200C9A66,
+/// as laid out in the _DSM argument.
+pub const INTEL_DSM_UUID: [u8; 16] = [
+    0x30, 0xac, 0x09, 0x43, 0x11, 0x0d, 0xe4, 0x11, 0x91, 0x91, 0x08, 0x00, 0x20, 0x0c, 0x9a, 0x66,
+];
+
+// Function indexes of the Intel family.
+const DSM_FN_QUERY: u32 = 0;
+const DSM_FN_GET_LABEL_SIZE: u32 = 4;
+const DSM_FN_GET_LABEL_DATA: u32 = 5;
+const DSM_FN_SET_LABEL_DATA: u32 = 6;
+
+// Status codes of the Intel family.
+const DSM_STATUS_SUCCESS: u32 = 0;
+const DSM_STATUS_NOT_SUPPORTED: u32 = 1;
+const DSM_STATUS_NO_DEVICE: u32 = 2;
+const DSM_STATUS_INVALID_INPUT: u32 = 3;
+const DSM_STATUS_HW_ERROR: u32 = 4;
+
+/// Area of the backing file of an NVDIMM holding its namespace labels,
+/// which the guest doesn't map.
+pub struct LabelArea {
+    pub file: File,
+    pub offset: u64,
+    pub size: u64,
+}
+
+pub struct NvdimmDsm {
+    // The label areas of the NVDIMMs, by handle less one, their size being
+    // zero for the ones without labels.
+    labels: Vec<LabelArea>,
+    regs: Vec<u8>,
+}
+
+impl NvdimmDsm {
+    pub fn new(labels: Vec<LabelArea>) -> Self {
+        NvdimmDsm {
+            labels,
+            regs: vec![0; DSM_REGION_SIZE as usize],
+        }
+    }
+
+    fn reg_u32(&self, offset: usize) -> u32 {
+        LittleEndian::read_u32(&self.regs[offset..offset + 4])
+    }
+
+    fn run(&mut self) {
+        let handle = self.reg_u32(DSM_HANDLE);
+        let function = self.reg_u32(DSM_FUNCTION);
+        debug!(
+            "NVDIMM DSM function {} of handle {}, revision {}",
+            function,
+            handle,
+            self.reg_u32(DSM_REVISION)
+        );
+
+        let intel = self.regs[DSM_UUID..DSM_UUID + 16] == INTEL_DSM_UUID;
+        let label_area = (handle as usize)
+            .checked_sub(1)
+            .and_then(|index| self.labels.get(index));
+        let input = &self.regs[DSM_INPUT..DSM_INPUT + DSM_INPUT_SIZE];
+        let output = match (intel, label_area) {
+            // No function of other families, nor of unknown NVDIMMs.
+            (false, _) | (true, None) if function == DSM_FN_QUERY => vec![0],
+            (false, _) => status(DSM_STATUS_NOT_SUPPORTED),
+            (true, None) => status(DSM_STATUS_NO_DEVICE),
+            (true, Some(label_area)) => label_function(label_area, function, input),
+        };
+
+        let len = output.len();
+        LittleEndian::write_u32(
+            &mut self.regs[DSM_OUTPUT_LENGTH..DSM_OUTPUT_LENGTH + 4],
+            len as u32,
+        );
+        self.regs[DSM_OUTPUT..DSM_OUTPUT + DSM_OUTPUT_SIZE]
+            .iter_mut()
+            .for_each(|b| *b = 0);
+        self.regs[DSM_OUTPUT..DSM_OUTPUT + len].copy_from_slice(&output);
+    }
+}
+
+fn status(status: u32) -> Vec<u8> {
+    let mut output = vec![0; 4];
+    LittleEndian::write_u32(&mut output, status);
+    output
+}
+
+// The output of a function of the Intel family, on an NVDIMM.
+fn label_function(label_area: &LabelArea, function: u32, input: &[u8]) -> Vec<u8> {
+    if label_area.size == 0 {
+        return match function {
+            DSM_FN_QUERY => vec![0],
+            _ => status(DSM_STATUS_NOT_SUPPORTED),
+        };
+    }
+
+    let offset = u64::from(LittleEndian::read_u32(&input[0..4]));
+    let len = LittleEndian::read_u32(&input[4..8]) as usize;
+    let in_range = len <= DSM_MAX_TRANSFER && offset + len as u64 <= label_area.size;
+    match function {
+        DSM_FN_QUERY => vec![
+            1 << DSM_FN_QUERY
+                | 1 << DSM_FN_GET_LABEL_SIZE
+                | 1 << DSM_FN_GET_LABEL_DATA
+                | 1 << DSM_FN_SET_LABEL_DATA,
+        ],
+        DSM_FN_GET_LABEL_SIZE => {
+            let mut output = status(DSM_STATUS_SUCCESS);
+            output.resize(12, 0);
+            LittleEndian::write_u32(&mut output[4..8], label_area.size as u32);
+            LittleEndian::write_u32(&mut output[8..12], DSM_MAX_TRANSFER as u32);
+            output
+        }
+        DSM_FN_GET_LABEL_DATA if in_range => {
+            let mut output = status(DSM_STATUS_SUCCESS);
+            output.resize(4 + len, 0);
+            match label_area
+                .file
+                .read_exact_at(&mut output[4..], label_area.offset + offset)
+            {
+                Ok(()) => output,
+                Err(e) => label_error("read", e),
+            }
+        }
+        DSM_FN_SET_LABEL_DATA if in_range => {
+            match label_area
+                .file
+                .write_all_at(&input[8..8 + len], label_area.offset + offset)
+            {
+                Ok(()) => status(DSM_STATUS_SUCCESS),
+                Err(e) => label_error("write", e),
+            }
+        }
+        DSM_FN_GET_LABEL_DATA | DSM_FN_SET_LABEL_DATA => status(DSM_STATUS_INVALID_INPUT),
+        _ => status(DSM_STATUS_NOT_SUPPORTED),
+    }
+}
+
+fn label_error(operation: &str, e: io::Error) -> Vec<u8> {
+    error!("Cannot {} the NVDIMM namespace labels: {}", operation, e);
+    status(DSM_STATUS_HW_ERROR)
+}
+
+impl BusDevice for NvdimmDsm {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let offset = offset as usize;
+        if offset + data.len() <= self.regs.len() {
+            data.copy_from_slice(&self.regs[offset..offset + data.len()]);
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        let offset = offset as usize;
+        if offset + data.len() > self.regs.len() {
+            return;
+        }
+
+        self.regs[offset..offset + data.len()].copy_from_slice(data);
+        if offset == DSM_FUNCTION {
+            self.run();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+
+    fn call(
+        dsm: &mut NvdimmDsm,
+        uuid: &[u8; 16],
+        handle: u32,
+        function: u32,
+        input: &[u8],
+    ) -> Vec<u8> {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, handle);
+        dsm.write(0, DSM_HANDLE as u64, &bytes);
+        dsm.write(0, DSM_UUID as u64, uuid);
+        dsm.write(0, DSM_INPUT as u64, input);
+        LittleEndian::write_u32(&mut bytes, function);
+        dsm.write(0, DSM_FUNCTION as u64, &bytes);
+
+        dsm.read(0, DSM_OUTPUT_LENGTH as u64, &mut bytes);
+        let mut output = vec![0u8; LittleEndian::read_u32(&bytes) as usize];
+        dsm.read(0, DSM_OUTPUT as u64, &mut output);
+        output
+    }
+
+    fn label_input(offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut input = vec![0u8; 8];
+        LittleEndian::write_u32(&mut input[0..4], offset);
+        LittleEndian::write_u32(&mut input[4..8], data.len() as u32);
+        input.extend_from_slice(data);
+        input
+    }
+
+    #[test]
+    fn test_dsm_labels() {
+        let file = tempfile().unwrap();
+        file.set_len(0x3000).unwrap();
+        let mut dsm = NvdimmDsm::new(vec![
+            LabelArea {
+                file: file.try_clone().unwrap(),
+                offset: 0x1000,
+                size: 0x2000,
+            },
+            LabelArea {
+                file: file.try_clone().unwrap(),
+                offset: 0,
+                size: 0,
+            },
+        ]);
+
+        assert_eq!(call(&mut dsm, &INTEL_DSM_UUID, 1, 0, &[]), [0x71]);
+        assert_eq!(
+            call(&mut dsm, &INTEL_DSM_UUID, 1, 4, &[]),
+            [0, 0, 0, 0, 0, 0x20, 0, 0, 0, 1, 0, 0]
+        );
+
+        let input = label_input(0x10, b"label");
+        assert_eq!(call(&mut dsm, &INTEL_DSM_UUID, 1, 6, &input), status(0));
+        let mut data = [0u8; 5];
+        file.read_exact_at(&mut data, 0x1010).unwrap();
+        assert_eq!(&data, b"label");
+
+        let input = label_input(0x10, &[0; 5]);
+        assert_eq!(
+            call(&mut dsm, &INTEL_DSM_UUID, 1, 5, &input[..8]),
+            [0, 0, 0, 0, b'l', b'a', b'b', b'e', b'l']
+        );
+
+        // Past the end of the label area.
+        let input = label_input(0x1ffc, &[0; 5]);
+        assert_eq!(
+            call(&mut dsm, &INTEL_DSM_UUID, 1, 5, &input[..8]),
+            status(3)
+        );
+
+        // An NVDIMM without labels, an unknown one, and another family.
+        assert_eq!(call(&mut dsm, &INTEL_DSM_UUID, 2, 0, &[]), [0]);
+        assert_eq!(call(&mut dsm, &INTEL_DSM_UUID, 2, 4, &[]), status(1));
+        assert_eq!(call(&mut dsm, &INTEL_DSM_UUID, 3, 4, &[]), status(2));
+        assert_eq!(call(&mut dsm, &[0; 16], 1, 0, &[]), [0]);
+    }
+}
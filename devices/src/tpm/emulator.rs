@@ -0,0 +1,150 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Client of the swtpm TPM emulator, through its control channel socket.
+//!
+//! The control channel carries the commands of swtpm's ioctl interface,
+//! big endian, each one answered with its result code. The TPM commands
+//! themselves go through a data channel, a socket pair whose other end is
+//! handed to swtpm with CMD_SET_DATAFD.
+
+use super::TpmBackend;
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::result;
+use vhost_rs::vhost_user::sock_ctrl_msg::ScmSocket;
+
+// From swtpm's tpm_ioctl.h.
+const CMD_GET_CAPABILITY: u32 = 1;
+const CMD_INIT: u32 = 2;
+const CMD_SET_DATAFD: u32 = 16;
+const CMD_SET_BUFFERSIZE: u32 = 17;
+
+const PTM_CAP_INIT: u64 = 1;
+const PTM_CAP_SET_DATAFD: u64 = 1 << 12;
+const PTM_CAP_SET_BUFFERSIZE: u64 = 1 << 13;
+const REQUIRED_CAPABILITIES: u64 = PTM_CAP_INIT | PTM_CAP_SET_DATAFD | PTM_CAP_SET_BUFFERSIZE;
+
+// Every TPM response starts with its tag, its size and its response code.
+const TPM_RESPONSE_HEADER_SIZE: usize = 10;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot connect to the swtpm control socket.
+    Connect(io::Error),
+    /// Cannot talk to swtpm.
+    Io(io::Error),
+    /// swtpm lacks some of the control commands, of these capabilities.
+    MissingCapabilities(u64),
+    /// A control command failed, with this TPM result code.
+    Command(u32, u32),
+    /// swtpm can't use buffers large enough for the commands.
+    BufferSize(u32),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+// Sends a control command along with a file descriptor.
+fn send_with_fd(socket: &UnixStream, data: &[u8], fd: RawFd) -> io::Result<()> {
+    let sent = socket
+        .send_with_fd(data, fd)
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+    if sent != data.len() {
+        return Err(io::Error::from(io::ErrorKind::WriteZero));
+    }
+
+    Ok(())
+}
+
+/// A TPM emulated by swtpm, started with its control channel on a UNIX
+/// socket, as `swtpm socket --tpm2 --ctrl type=unixio,path=<socket>`.
+pub struct Emulator {
+    control: UnixStream,
+    data: UnixStream,
+}
+
+impl Emulator {
+    /// Connects to the swtpm control socket, and starts the TPM with
+    /// command buffers of `buffer_size` bytes.
+    pub fn new(socket: &Path, buffer_size: u32) -> Result<Self> {
+        let control = UnixStream::connect(socket).map_err(Error::Connect)?;
+        let (data, swtpm_data) = UnixStream::pair().map_err(Error::Io)?;
+        let mut emulator = Emulator { control, data };
+
+        let capabilities = emulator.control_command(CMD_GET_CAPABILITY, &[], 8)?;
+        let missing = REQUIRED_CAPABILITIES & !BigEndian::read_u64(&capabilities);
+        if missing != 0 {
+            return Err(Error::MissingCapabilities(missing));
+        }
+
+        // swtpm owns its end of the data channel once it gets it.
+        let sent = send_with_fd(
+            &emulator.control,
+            &CMD_SET_DATAFD.to_be_bytes(),
+            swtpm_data.as_raw_fd(),
+        );
+        drop(swtpm_data);
+        sent.map_err(Error::Io)?;
+        emulator.check_result(CMD_SET_DATAFD)?;
+
+        // The buffer size can only be set while the TPM is stopped.
+        let sizes = emulator.control_command(CMD_SET_BUFFERSIZE, &buffer_size.to_be_bytes(), 12)?;
+        let size = BigEndian::read_u32(&sizes);
+        if size < buffer_size {
+            return Err(Error::BufferSize(size));
+        }
+
+        // No init flags. The TPM restarts from the state swtpm keeps, when
+        // connected to again as the VM reboots.
+        emulator.control_command(CMD_INIT, &0u32.to_be_bytes(), 0)?;
+
+        Ok(emulator)
+    }
+
+    fn check_result(&mut self, cmd: u32) -> Result<()> {
+        let mut result = [0u8; 4];
+        self.control.read_exact(&mut result).map_err(Error::Io)?;
+        match BigEndian::read_u32(&result) {
+            0 => Ok(()),
+            result => Err(Error::Command(cmd, result)),
+        }
+    }
+
+    // Sends a control command, and returns the output following its result
+    // code, which CMD_GET_CAPABILITY has none of.
+    fn control_command(&mut self, cmd: u32, input: &[u8], output_size: usize) -> Result<Vec<u8>> {
+        let mut request = cmd.to_be_bytes().to_vec();
+        request.extend_from_slice(input);
+        self.control.write_all(&request).map_err(Error::Io)?;
+
+        if cmd != CMD_GET_CAPABILITY {
+            self.check_result(cmd)?;
+        }
+        let mut output = vec![0u8; output_size];
+        self.control.read_exact(&mut output).map_err(Error::Io)?;
+
+        Ok(output)
+    }
+}
+
+impl TpmBackend for Emulator {
+    fn execute(&mut self, command: &[u8]) -> io::Result<Vec<u8>> {
+        self.data.write_all(command)?;
+
+        let mut response = vec![0u8; TPM_RESPONSE_HEADER_SIZE];
+        self.data.read_exact(&mut response)?;
+        let size = BigEndian::read_u32(&response[2..]) as usize;
+        if size < TPM_RESPONSE_HEADER_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        response.resize(size, 0);
+        self.data
+            .read_exact(&mut response[TPM_RESPONSE_HEADER_SIZE..])?;
+
+        Ok(response)
+    }
+}
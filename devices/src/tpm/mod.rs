@@ -0,0 +1,329 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! TPM 2.0 exposed through the Command Response Buffer (CRB) interface of
+//! the TCG PC Client Platform TPM Profile specification, locality 0 only.
+//!
+//! The guest writes a command into the data buffer, and sets the start
+//! register. The command is handed to the backend right away, and the
+//! start register cleared once the response is in the data buffer, the
+//! guest polling for it.
+
+pub mod emulator;
+
+use crate::BusDevice;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::cmp;
+use std::io;
+
+/// Runs the TPM commands.
+pub trait TpmBackend: Send {
+    /// Runs a TPM command, and returns its response.
+    fn execute(&mut self, command: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+// Register offsets.
+const CRB_LOC_STATE: u64 = 0x00;
+const CRB_LOC_CTRL: u64 = 0x08;
+const CRB_LOC_STS: u64 = 0x0c;
+const CRB_INTF_ID: u64 = 0x30;
+const CRB_CTRL_EXT: u64 = 0x38;
+const CRB_CTRL_REQ: u64 = 0x40;
+const CRB_CTRL_STS: u64 = 0x44;
+const CRB_CTRL_CANCEL: u64 = 0x48;
+const CRB_CTRL_START: u64 = 0x4c;
+const CRB_INT_ENABLE: u64 = 0x50;
+const CRB_INT_STS: u64 = 0x54;
+const CRB_CTRL_CMD_SIZE: u64 = 0x58;
+const CRB_CTRL_CMD_LADDR: u64 = 0x5c;
+const CRB_CTRL_CMD_HADDR: u64 = 0x60;
+const CRB_CTRL_RSP_SIZE: u64 = 0x64;
+const CRB_CTRL_RSP_ADDR: u64 = 0x68;
+const CRB_DATA_BUFFER: u64 = 0x80;
+
+/// Offset of the control area, given to the guest by the TPM2 ACPI table.
+pub const CRB_CONTROL_AREA_OFFSET: u64 = CRB_CTRL_REQ;
+/// Size of the command and response buffer, the rest of the 4 KiB region.
+pub const CRB_DATA_BUFFER_SIZE: usize = 0xf80;
+
+// LOC_STATE bits.
+const LOC_STATE_ESTABLISHED: u32 = 1;
+const LOC_STATE_ASSIGNED: u32 = 1 << 1;
+const LOC_STATE_REG_VALID: u32 = 1 << 7;
+// LOC_CTRL bits.
+const LOC_CTRL_REQUEST_ACCESS: u32 = 1;
+const LOC_CTRL_RELINQUISH: u32 = 1 << 1;
+// LOC_STS bits.
+const LOC_STS_GRANTED: u32 = 1;
+// CTRL_REQ bits.
+const CTRL_REQ_CMD_READY: u32 = 1;
+const CTRL_REQ_GO_IDLE: u32 = 1 << 1;
+// CTRL_STS bits.
+const CTRL_STS_ERROR: u32 = 1;
+const CTRL_STS_IDLE: u32 = 1 << 1;
+// CTRL_START bits.
+const CTRL_START_CMD: u32 = 1;
+
+// A CRB interface, version 1, transferring up to 64 bytes at once, with
+// the CRB interface selected. The IBM vendor ID, as QEMU and swtpm use.
+const INTF_ID: u64 = 0x0001_1014_0002_5811;
+
+// Every TPM command starts with its tag and its size.
+const TPM_HEADER_SIZE: usize = 10;
+
+pub struct TpmCrb {
+    backend: Box<dyn TpmBackend>,
+    base: u64,
+    loc_assigned: bool,
+    ctrl_sts: u32,
+    int_enable: u32,
+    int_sts: u32,
+    data: Vec<u8>,
+}
+
+impl TpmCrb {
+    /// Constructs a TPM CRB interface at `base`, running its commands on
+    /// `backend`.
+    pub fn new(backend: Box<dyn TpmBackend>, base: u64) -> Self {
+        TpmCrb {
+            backend,
+            base,
+            loc_assigned: false,
+            ctrl_sts: CTRL_STS_IDLE,
+            int_enable: 0,
+            int_sts: 0,
+            data: vec![0; CRB_DATA_BUFFER_SIZE],
+        }
+    }
+
+    // The registers, as the guest reads them. The start register always
+    // reads as 0, the commands being done once started.
+    fn registers(&self) -> [u8; CRB_DATA_BUFFER as usize] {
+        let mut regs = [0u8; CRB_DATA_BUFFER as usize];
+        let mut loc_state = LOC_STATE_ESTABLISHED | LOC_STATE_REG_VALID;
+        let mut loc_sts = 0;
+        if self.loc_assigned {
+            loc_state |= LOC_STATE_ASSIGNED;
+            loc_sts |= LOC_STS_GRANTED;
+        }
+        let buffer = self.base + CRB_DATA_BUFFER;
+
+        LittleEndian::write_u32(&mut regs[CRB_LOC_STATE as usize..], loc_state);
+        LittleEndian::write_u32(&mut regs[CRB_LOC_STS as usize..], loc_sts);
+        LittleEndian::write_u64(&mut regs[CRB_INTF_ID as usize..], INTF_ID);
+        LittleEndian::write_u64(&mut regs[CRB_CTRL_EXT as usize..], 0);
+        LittleEndian::write_u32(&mut regs[CRB_CTRL_STS as usize..], self.ctrl_sts);
+        LittleEndian::write_u32(&mut regs[CRB_INT_ENABLE as usize..], self.int_enable);
+        LittleEndian::write_u32(&mut regs[CRB_INT_STS as usize..], self.int_sts);
+        LittleEndian::write_u32(
+            &mut regs[CRB_CTRL_CMD_SIZE as usize..],
+            CRB_DATA_BUFFER_SIZE as u32,
+        );
+        LittleEndian::write_u32(&mut regs[CRB_CTRL_CMD_LADDR as usize..], buffer as u32);
+        LittleEndian::write_u32(
+            &mut regs[CRB_CTRL_CMD_HADDR as usize..],
+            (buffer >> 32) as u32,
+        );
+        LittleEndian::write_u32(
+            &mut regs[CRB_CTRL_RSP_SIZE as usize..],
+            CRB_DATA_BUFFER_SIZE as u32,
+        );
+        LittleEndian::write_u64(&mut regs[CRB_CTRL_RSP_ADDR as usize..], buffer);
+
+        regs
+    }
+
+    fn execute(&mut self) {
+        let size = BigEndian::read_u32(&self.data[2..]) as usize;
+        if !(TPM_HEADER_SIZE..=CRB_DATA_BUFFER_SIZE).contains(&size) {
+            error!("TPM: invalid command size {}", size);
+            self.ctrl_sts |= CTRL_STS_ERROR;
+            return;
+        }
+
+        match self.backend.execute(&self.data[..size]) {
+            Ok(ref response) if response.len() <= CRB_DATA_BUFFER_SIZE => {
+                self.data[..response.len()].copy_from_slice(response);
+            }
+            Ok(response) => {
+                error!("TPM: response of {} bytes is too large", response.len());
+                self.ctrl_sts |= CTRL_STS_ERROR;
+            }
+            Err(e) => {
+                error!("TPM: failed running a command: {}", e);
+                self.ctrl_sts |= CTRL_STS_ERROR;
+            }
+        }
+    }
+}
+
+impl BusDevice for TpmCrb {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        let offset = offset as usize;
+        let end = offset + data.len();
+
+        if offset >= CRB_DATA_BUFFER as usize {
+            let start = offset - CRB_DATA_BUFFER as usize;
+            let len = cmp::min(data.len(), self.data.len().saturating_sub(start));
+            data[..len].copy_from_slice(&self.data[start..start + len]);
+        } else if end <= CRB_DATA_BUFFER as usize {
+            data.copy_from_slice(&self.registers()[offset..end]);
+        } else {
+            error!(
+                "TPM: invalid read of {} bytes at 0x{:x}",
+                data.len(),
+                offset
+            );
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if offset >= CRB_DATA_BUFFER {
+            let start = (offset - CRB_DATA_BUFFER) as usize;
+            let len = cmp::min(data.len(), self.data.len().saturating_sub(start));
+            self.data[start..start + len].copy_from_slice(&data[..len]);
+            return;
+        }
+
+        if data.len() != 4 {
+            error!(
+                "TPM: invalid write of {} bytes at 0x{:x}",
+                data.len(),
+                offset
+            );
+            return;
+        }
+        let value = LittleEndian::read_u32(data);
+
+        match offset {
+            CRB_LOC_CTRL => {
+                if value & LOC_CTRL_RELINQUISH != 0 {
+                    self.loc_assigned = false;
+                }
+                if value & LOC_CTRL_REQUEST_ACCESS != 0 {
+                    self.loc_assigned = true;
+                }
+            }
+            CRB_CTRL_REQ => {
+                if value & CTRL_REQ_CMD_READY != 0 {
+                    self.ctrl_sts &= !CTRL_STS_IDLE;
+                }
+                if value & CTRL_REQ_GO_IDLE != 0 {
+                    self.ctrl_sts |= CTRL_STS_IDLE;
+                }
+            }
+            // Commands are done by the time the guest could cancel them.
+            CRB_CTRL_CANCEL => {}
+            CRB_CTRL_START => {
+                if value & CTRL_START_CMD != 0
+                    && self.loc_assigned
+                    && self.ctrl_sts & CTRL_STS_IDLE == 0
+                {
+                    self.execute();
+                }
+            }
+            CRB_INT_ENABLE => self.int_enable = value,
+            // Write 1 to clear.
+            CRB_INT_STS => self.int_sts &= !value,
+            _ => debug!("TPM: ignored write at 0x{:x}", offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    const BASE: u64 = 0xfed4_0000;
+
+    // Answers every command with a header carrying its size, and keeps the
+    // commands it gets.
+    struct TestBackend {
+        commands: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl TpmBackend for TestBackend {
+        fn execute(&mut self, command: &[u8]) -> io::Result<Vec<u8>> {
+            self.commands.lock().unwrap().push(command.to_vec());
+            let mut response = vec![0x80, 0x01, 0, 0, 0, 12, 0, 0, 0, 0, 0xab, 0xcd];
+            response[11] = command.len() as u8;
+            Ok(response)
+        }
+    }
+
+    fn read_u32(crb: &mut TpmCrb, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        crb.read(BASE, offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write_u32(crb: &mut TpmCrb, offset: u64, value: u32) {
+        let mut data = [0u8; 4];
+        LittleEndian::write_u32(&mut data, value);
+        crb.write(BASE, offset, &data);
+    }
+
+    #[test]
+    fn test_crb_registers() {
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let mut crb = TpmCrb::new(Box::new(TestBackend { commands }), BASE);
+
+        assert_eq!(read_u32(&mut crb, CRB_INTF_ID) & 0xf, 1);
+        assert_eq!(read_u32(&mut crb, CRB_CTRL_STS), CTRL_STS_IDLE);
+        assert_eq!(
+            read_u32(&mut crb, CRB_CTRL_CMD_LADDR),
+            (BASE + CRB_DATA_BUFFER) as u32
+        );
+        let mut rsp_addr = [0u8; 8];
+        crb.read(BASE, CRB_CTRL_RSP_ADDR, &mut rsp_addr);
+        assert_eq!(LittleEndian::read_u64(&rsp_addr), BASE + CRB_DATA_BUFFER);
+
+        assert_eq!(read_u32(&mut crb, CRB_LOC_STS), 0);
+        write_u32(&mut crb, CRB_LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+        assert_eq!(read_u32(&mut crb, CRB_LOC_STS), LOC_STS_GRANTED);
+        assert_ne!(read_u32(&mut crb, CRB_LOC_STATE) & LOC_STATE_ASSIGNED, 0);
+        write_u32(&mut crb, CRB_LOC_CTRL, LOC_CTRL_RELINQUISH);
+        assert_eq!(read_u32(&mut crb, CRB_LOC_STS), 0);
+
+        write_u32(&mut crb, CRB_CTRL_REQ, CTRL_REQ_CMD_READY);
+        assert_eq!(read_u32(&mut crb, CRB_CTRL_STS), 0);
+        write_u32(&mut crb, CRB_CTRL_REQ, CTRL_REQ_GO_IDLE);
+        assert_eq!(read_u32(&mut crb, CRB_CTRL_STS), CTRL_STS_IDLE);
+    }
+
+    #[test]
+    fn test_crb_command() {
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let backend = TestBackend {
+            commands: commands.clone(),
+        };
+        let mut crb = TpmCrb::new(Box::new(backend), BASE);
+        let command = [0x80, 0x01, 0, 0, 0, 11, 0, 0, 0x01, 0x44, 0x00];
+
+        // Not started without the locality, nor while idle.
+        crb.write(BASE, CRB_DATA_BUFFER, &command);
+        write_u32(&mut crb, CRB_CTRL_START, CTRL_START_CMD);
+        write_u32(&mut crb, CRB_LOC_CTRL, LOC_CTRL_REQUEST_ACCESS);
+        write_u32(&mut crb, CRB_CTRL_START, CTRL_START_CMD);
+        assert!(commands.lock().unwrap().is_empty());
+
+        write_u32(&mut crb, CRB_CTRL_REQ, CTRL_REQ_CMD_READY);
+        write_u32(&mut crb, CRB_CTRL_START, CTRL_START_CMD);
+        assert_eq!(*commands.lock().unwrap(), vec![command.to_vec()]);
+        assert_eq!(read_u32(&mut crb, CRB_CTRL_START), 0);
+        assert_eq!(read_u32(&mut crb, CRB_CTRL_STS), 0);
+
+        let mut response = [0u8; 12];
+        crb.read(BASE, CRB_DATA_BUFFER, &mut response);
+        assert_eq!(response, [0x80, 0x01, 0, 0, 0, 12, 0, 0, 0, 0, 0xab, 11]);
+
+        // A command larger than the buffer is an error.
+        crb.write(BASE, CRB_DATA_BUFFER + 2, &[0, 0, 0x10, 0]);
+        write_u32(&mut crb, CRB_CTRL_START, CTRL_START_CMD);
+        assert_eq!(commands.lock().unwrap().len(), 1);
+        assert_eq!(read_u32(&mut crb, CRB_CTRL_STS), CTRL_STS_ERROR);
+    }
+}
@@ -0,0 +1,1152 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::eeprom::{Eeprom, E1000_DEV_ID, E1000_VENDOR_ID};
+use crate::{Error, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use devices::BusDevice;
+use libc::EFD_NONBLOCK;
+use net_util::{MacAddr, Tap};
+use pci::{
+    BarReprogrammingParams, InterruptDelivery, InterruptParameters, PciBarConfiguration,
+    PciBarRegionType, PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
+    PciInterruptPin, PciNetworkControllerSubclass,
+};
+use std::any::Any;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use vm_allocator::SystemAllocator;
+use vm_device::{Pausable, PauseControl, PauseWorker};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap, GuestUsize};
+use vmm_sys_util::eventfd::EventFd;
+
+// Registers, as described by the PCI/PCI-X Family of Gigabit Ethernet
+// Controllers Software Developer's Manual.
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EECD: usize = 0x0010;
+const EERD: usize = 0x0014;
+const MDIC: usize = 0x0020;
+const VET: usize = 0x0038;
+const ICR: usize = 0x00c0;
+const ICS: usize = 0x00c8;
+const IMS: usize = 0x00d0;
+const IMC: usize = 0x00d8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const LEDCTL: usize = 0x0e00;
+const PBA: usize = 0x1000;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+const STATS_START: usize = 0x4000;
+const STATS_END: usize = 0x4100;
+const GPRC: usize = 0x4074;
+const GPTC: usize = 0x4080;
+const TPR: usize = 0x40d0;
+const TPT: usize = 0x40d4;
+const MTA: usize = 0x5200;
+const RA: usize = 0x5400;
+const RA_ENTRIES: usize = 16;
+
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_SPD_1000: u32 = 1 << 9;
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_VME: u32 = 1 << 30;
+
+const STATUS_FD: u32 = 1 << 0;
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED_1000: u32 = 1 << 7;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+const MDIC_DATA_MASK: u32 = 0xffff;
+const MDIC_OP_WRITE: u32 = 1 << 26;
+const MDIC_OP_READ: u32 = 2 << 26;
+const MDIC_READY: u32 = 1 << 28;
+const MDIC_INT_EN: u32 = 1 << 29;
+const MDIC_ERROR: u32 = 1 << 30;
+
+const ICR_TXDW: u32 = 1 << 0;
+const ICR_TXQE: u32 = 1 << 1;
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXT0: u32 = 1 << 7;
+const ICR_MDAC: u32 = 1 << 9;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_UPE: u32 = 1 << 3;
+const RCTL_MPE: u32 = 1 << 4;
+const RCTL_MO_SHIFT: u32 = 12;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_BSIZE_SHIFT: u32 = 16;
+const RCTL_BSEX: u32 = 1 << 25;
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+
+const RAH_AV: u32 = 1 << 31;
+
+// Transmit descriptors. The command byte is at the same offset for the
+// legacy, context and data descriptors.
+const DESC_SIZE: u64 = 16;
+const TXD_CMD_EOP: u8 = 1 << 0;
+const TXD_CMD_IC: u8 = 1 << 2;
+const TXD_CMD_TSE: u8 = 1 << 2;
+const TXD_CMD_RS: u8 = 1 << 3;
+const TXD_CMD_DEXT: u8 = 1 << 5;
+const TXD_CMD_VLE: u8 = 1 << 6;
+const TXD_DTYP_CONTEXT: u8 = 0;
+const TXD_STA_DD: u8 = 1 << 0;
+const TXD_POPTS_IXSM: u8 = 1 << 0;
+const TXD_POPTS_TXSM: u8 = 1 << 1;
+const TXD_TUCMD_TCP: u8 = 1 << 0;
+const TXD_TUCMD_IP: u8 = 1 << 1;
+
+// Legacy receive descriptors.
+const RXD_STA_DD: u8 = 1 << 0;
+const RXD_STA_EOP: u8 = 1 << 1;
+const RXD_STA_IXSM: u8 = 1 << 2;
+const RXD_STA_VP: u8 = 1 << 3;
+
+const PHY_ADDR: u32 = 1;
+const PHY_REGS: usize = 32;
+const PHY_CTRL: usize = 0;
+const PHY_CTRL_RESTART_AUTONEG: u16 = 1 << 9;
+const PHY_CTRL_RESET: u16 = 1 << 15;
+// Registers 1 to 3 are the status and the PHY identifier.
+const PHY_STATUS: usize = 1;
+const PHY_ID2: usize = 3;
+
+const MMIO_BAR_SIZE: u64 = 0x20000;
+const IO_BAR_SIZE: u64 = 0x40;
+// The IO BAR gives an indirect access to the registers.
+const IOADDR: u64 = 0;
+const IODATA: u64 = 4;
+
+// The frames are exchanged with the TAP interface along with a virtio net
+// header, which lets the host take care of the checksum and segmentation
+// offloads.
+const VNET_HDR_LEN: usize = 10;
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const MAX_BUFFER_SIZE: usize = 65562;
+
+const ETH_ALEN: usize = 6;
+const ETH_TYPE_OFFSET: usize = 12;
+const VLAN_HLEN: usize = 4;
+const MIN_FRAME_LEN: usize = 60;
+const FCS_LEN: usize = 4;
+
+// A frame is available for reading from the tap device.
+const RX_TAP_EVENT: u64 = 0;
+// The guest has made some receive descriptors available.
+const RX_QUEUE_EVENT: u64 = 1;
+// The device has been dropped.
+const KILL_EVENT: u64 = 2;
+// The device is paused.
+const PAUSE_EVENT: u64 = 3;
+
+fn phy_defaults() -> [u16; PHY_REGS] {
+    let mut phy = [0u16; PHY_REGS];
+    // Auto-negotiation enabled, full duplex, 1000 Mb/s.
+    phy[PHY_CTRL] = 0x1140;
+    // Link up and auto-negotiation complete.
+    phy[PHY_STATUS] = 0x796d;
+    // Marvell 88E1011.
+    phy[2] = 0x0141;
+    phy[PHY_ID2] = 0x0c20;
+    phy[4] = 0x0de1;
+    phy[5] = 0x41e0;
+    phy[9] = 0x0e00;
+    phy[10] = 0x3c00;
+    phy[15] = 0x3000;
+    phy[16] = 0x0360;
+    // 1000 Mb/s, full duplex, link up.
+    phy[17] = 0xac00;
+    phy[20] = 0x0d60;
+    phy
+}
+
+// Internet checksum, folded but not complemented.
+fn checksum(data: &[u8]) -> u16 {
+    let sum = data.chunks(2).fold(0u32, |sum, c| {
+        sum + (u32::from(c[0]) << 8 | u32::from(*c.get(1).unwrap_or(&0)))
+    });
+    fold(sum)
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+// Offsets and segmentation parameters set by the last context descriptor.
+#[derive(Default)]
+struct TxContext {
+    ipcss: usize,
+    ipcso: usize,
+    ipcse: usize,
+    tucss: usize,
+    tucso: usize,
+    tucmd: u8,
+    hdr_len: usize,
+    mss: u16,
+}
+
+impl TxContext {
+    fn from_desc(desc: &[u8]) -> Self {
+        TxContext {
+            ipcss: usize::from(desc[0]),
+            ipcso: usize::from(desc[1]),
+            ipcse: usize::from(LittleEndian::read_u16(&desc[2..4])),
+            tucss: usize::from(desc[4]),
+            tucso: usize::from(desc[5]),
+            tucmd: desc[11],
+            hdr_len: usize::from(desc[13]),
+            mss: LittleEndian::read_u16(&desc[14..16]),
+        }
+    }
+}
+
+// Offloads requested by the first descriptor of a frame.
+#[derive(Clone, Copy)]
+enum TxOffload {
+    None,
+    Legacy { css: usize, cso: usize },
+    Extended { popts: u8, tse: bool },
+}
+
+struct Tx {
+    context: TxContext,
+    offload: TxOffload,
+    vlan: Option<u16>,
+    // The frame being gathered, after some room for the vnet header.
+    frame: Vec<u8>,
+    // The frame is too large, and will be dropped.
+    oversized: bool,
+}
+
+impl Tx {
+    fn new() -> Self {
+        Tx {
+            context: TxContext::default(),
+            offload: TxOffload::None,
+            vlan: None,
+            frame: vec![0u8; VNET_HDR_LEN],
+            oversized: false,
+        }
+    }
+
+    fn reset_frame(&mut self) {
+        self.frame.truncate(0);
+        self.frame.resize(VNET_HDR_LEN, 0);
+        self.oversized = false;
+    }
+}
+
+struct E1000State {
+    regs: Vec<u32>,
+    phy: [u16; PHY_REGS],
+    eeprom: Eeprom,
+    mac: MacAddr,
+    tap: Tap,
+    memory: Arc<RwLock<GuestMemoryMmap>>,
+    interrupt_cb: Option<Arc<InterruptDelivery>>,
+    // Wakes the receive thread up.
+    rx_evt: EventFd,
+    tx: Tx,
+}
+
+impl E1000State {
+    fn reset(&mut self) {
+        for reg in self.regs.iter_mut() {
+            *reg = 0;
+        }
+        self.phy = phy_defaults();
+        self.tx = Tx::new();
+
+        self.regs[CTRL / 4] = CTRL_SLU | CTRL_SPD_1000;
+        self.regs[STATUS / 4] = STATUS_FD | STATUS_LU | STATUS_SPEED_1000;
+        self.regs[VET / 4] = 0x8100;
+        self.regs[LEDCTL / 4] = 0x0000_0602;
+        self.regs[PBA / 4] = 0x0010_0030;
+
+        let mac = self.mac.get_bytes();
+        self.regs[RA / 4] = LittleEndian::read_u32(&mac[0..4]);
+        self.regs[RA / 4 + 1] = u32::from(LittleEndian::read_u16(&mac[4..6])) | RAH_AV;
+    }
+
+    fn signal_interrupt(&self) {
+        if let Some(cb) = &self.interrupt_cb {
+            if let Err(e) = (cb)(InterruptParameters { msix: None }) {
+                error!("Failed to signal e1000 interrupt: {:?}", e);
+            }
+        }
+    }
+
+    fn update_interrupt(&self) {
+        if self.regs[ICR / 4] & self.regs[IMS / 4] != 0 {
+            self.signal_interrupt();
+        }
+    }
+
+    fn raise_interrupt(&mut self, cause: u32) {
+        self.regs[ICR / 4] |= cause;
+        if cause & self.regs[IMS / 4] != 0 {
+            self.signal_interrupt();
+        }
+    }
+
+    fn kick_rx(&self) {
+        if let Err(e) = self.rx_evt.write(1) {
+            error!("Failed to kick e1000 receive thread: {}", e);
+        }
+    }
+
+    fn read_reg(&mut self, offset: usize) -> u32 {
+        let index = offset / 4;
+        match offset {
+            ICR => {
+                let icr = self.regs[index];
+                self.regs[index] = 0;
+                icr
+            }
+            EECD => self.eeprom.eecd(),
+            ICS | IMC => 0,
+            // Statistics are cleared when read.
+            o if (STATS_START..STATS_END).contains(&o) => {
+                let value = self.regs[index];
+                self.regs[index] = 0;
+                value
+            }
+            _ => self.regs.get(index).cloned().unwrap_or(0),
+        }
+    }
+
+    fn write_reg(&mut self, offset: usize, value: u32) {
+        let index = offset / 4;
+        match offset {
+            CTRL => {
+                if value & CTRL_RST != 0 {
+                    self.reset();
+                } else {
+                    self.regs[index] = value;
+                }
+            }
+            STATUS => {}
+            EECD => self.eeprom.set_eecd(value),
+            EERD => {
+                if value & EERD_START != 0 {
+                    let addr = (value >> 8) & 0xff;
+                    self.regs[index] =
+                        u32::from(self.eeprom.read(addr as usize)) << 16 | addr << 8 | EERD_DONE;
+                }
+            }
+            MDIC => self.write_mdic(value),
+            ICR => self.regs[index] &= !value,
+            ICS => self.raise_interrupt(value),
+            IMS => {
+                self.regs[IMS / 4] |= value;
+                self.update_interrupt();
+            }
+            IMC => self.regs[IMS / 4] &= !value,
+            RCTL => {
+                self.regs[index] = value;
+                if value & RCTL_EN != 0 {
+                    self.kick_rx();
+                }
+            }
+            RDH | RDT => {
+                self.regs[index] = value & 0xffff;
+                self.kick_rx();
+            }
+            TDH => self.regs[index] = value & 0xffff,
+            TCTL => {
+                self.regs[index] = value;
+                self.process_tx();
+            }
+            TDT => {
+                self.regs[index] = value & 0xffff;
+                self.process_tx();
+            }
+            _ => {
+                if let Some(reg) = self.regs.get_mut(index) {
+                    *reg = value;
+                }
+            }
+        }
+    }
+
+    fn write_mdic(&mut self, value: u32) {
+        let reg = ((value >> 16) & 0x1f) as usize;
+        let phy_addr = (value >> 21) & 0x1f;
+        let mut mdic = value & !(MDIC_READY | MDIC_ERROR);
+
+        if phy_addr != PHY_ADDR {
+            mdic |= MDIC_ERROR;
+        } else if value & MDIC_OP_READ == MDIC_OP_READ {
+            mdic = (mdic & !MDIC_DATA_MASK) | u32::from(self.phy[reg]);
+        } else if value & MDIC_OP_WRITE == MDIC_OP_WRITE {
+            let data = (value & MDIC_DATA_MASK) as u16;
+            match reg {
+                // The link is always up, there is nothing to negotiate.
+                PHY_CTRL => {
+                    self.phy[reg] = data & !(PHY_CTRL_RESET | PHY_CTRL_RESTART_AUTONEG);
+                    self.raise_interrupt(ICR_LSC);
+                }
+                PHY_STATUS..=PHY_ID2 => {}
+                _ => self.phy[reg] = data,
+            }
+        }
+
+        self.regs[MDIC / 4] = mdic | MDIC_READY;
+        if value & MDIC_INT_EN != 0 {
+            self.raise_interrupt(ICR_MDAC);
+        }
+    }
+
+    fn ring(&self, bal: usize, bah: usize, len: usize) -> (u64, u64) {
+        let base = u64::from(self.regs[bah / 4]) << 32 | u64::from(self.regs[bal / 4]);
+        (base, u64::from(self.regs[len / 4]) / DESC_SIZE)
+    }
+
+    fn process_tx(&mut self) {
+        if self.regs[TCTL / 4] & TCTL_EN == 0 {
+            return;
+        }
+
+        let (base, count) = self.ring(TDBAL, TDBAH, TDLEN);
+        let mut head = u64::from(self.regs[TDH / 4]);
+        let tail = u64::from(self.regs[TDT / 4]);
+        if head >= count || tail >= count {
+            return;
+        }
+
+        let memory = self.memory.clone();
+        let mem = memory.read().unwrap();
+        while head != tail {
+            let desc_addr = GuestAddress(base + head * DESC_SIZE);
+            let mut desc = [0u8; DESC_SIZE as usize];
+            if let Err(e) = mem.read_slice(&mut desc, desc_addr) {
+                error!("Failed to read e1000 transmit descriptor: {:?}", e);
+                break;
+            }
+
+            self.process_tx_desc(&mem, &desc);
+
+            if desc[11] & TXD_CMD_RS != 0 {
+                if let Err(e) = mem.write_obj(desc[12] | TXD_STA_DD, desc_addr.unchecked_add(12)) {
+                    error!("Failed to write e1000 transmit descriptor: {:?}", e);
+                }
+            }
+
+            head = (head + 1) % count;
+        }
+
+        self.regs[TDH / 4] = head as u32;
+        self.raise_interrupt(ICR_TXDW | ICR_TXQE);
+    }
+
+    fn process_tx_desc(&mut self, mem: &GuestMemoryMmap, desc: &[u8]) {
+        let cmd = desc[11];
+        let extended = cmd & TXD_CMD_DEXT != 0;
+        if extended && desc[10] >> 4 == TXD_DTYP_CONTEXT {
+            self.tx.context = TxContext::from_desc(desc);
+            return;
+        }
+
+        let len = if extended {
+            (LittleEndian::read_u32(&desc[8..12]) & 0xf_ffff) as usize
+        } else {
+            usize::from(LittleEndian::read_u16(&desc[8..10]))
+        };
+
+        // The offloads are given by the first descriptor of the frame.
+        if self.tx.frame.len() == VNET_HDR_LEN {
+            self.tx.offload = if extended {
+                TxOffload::Extended {
+                    popts: desc[13],
+                    tse: cmd & TXD_CMD_TSE != 0,
+                }
+            } else if cmd & TXD_CMD_IC != 0 {
+                TxOffload::Legacy {
+                    css: usize::from(desc[13]),
+                    cso: usize::from(desc[10]),
+                }
+            } else {
+                TxOffload::None
+            };
+        }
+        if cmd & TXD_CMD_VLE != 0 && self.regs[CTRL / 4] & CTRL_VME != 0 {
+            self.tx.vlan = Some(LittleEndian::read_u16(&desc[14..16]));
+        }
+
+        let start = self.tx.frame.len();
+        if start + len > MAX_BUFFER_SIZE {
+            self.tx.oversized = true;
+        } else if len > 0 {
+            let addr = GuestAddress(LittleEndian::read_u64(&desc[0..8]));
+            self.tx.frame.resize(start + len, 0);
+            if let Err(e) = mem.read_slice(&mut self.tx.frame[start..], addr) {
+                error!("Failed to read e1000 transmit buffer: {:?}", e);
+                self.tx.oversized = true;
+            }
+        }
+
+        if cmd & TXD_CMD_EOP != 0 {
+            if self.tx.oversized {
+                warn!("Dropping oversized e1000 frame");
+            } else {
+                self.send_frame();
+            }
+            self.tx.reset_frame();
+            self.tx.vlan = None;
+        }
+    }
+
+    fn send_frame(&mut self) {
+        let Tx {
+            context,
+            offload,
+            vlan,
+            frame,
+            ..
+        } = &mut self.tx;
+
+        let len = frame.len() - VNET_HDR_LEN;
+        let data = &mut frame[VNET_HDR_LEN..];
+        // Checksum start and offset, and segmentation type, header length
+        // and size.
+        let mut csum: Option<(usize, usize)> = None;
+        let mut gso: Option<(u8, usize, u16)> = None;
+
+        match *offload {
+            TxOffload::None => {}
+            TxOffload::Legacy { css, cso } => {
+                if cso > css {
+                    csum = Some((css, cso - css));
+                }
+            }
+            TxOffload::Extended { popts, tse } => {
+                let ipv4 = context.tucmd & TXD_TUCMD_IP != 0;
+
+                // The driver leaves the IP total length to the device, as it
+                // is different for each segment.
+                if tse && ipv4 && context.ipcss + 4 <= len {
+                    let tot_len = (len - context.ipcss) as u16;
+                    BigEndian::write_u16(&mut data[context.ipcss + 2..], tot_len);
+                }
+
+                if (popts & TXD_POPTS_IXSM != 0 || (tse && ipv4)) && context.ipcso + 2 <= len {
+                    let end = if context.ipcse == 0 {
+                        len
+                    } else {
+                        cmp::min(context.ipcse + 1, len)
+                    };
+                    if context.ipcss < end {
+                        BigEndian::write_u16(&mut data[context.ipcso..], 0);
+                        let sum = !checksum(&data[context.ipcss..end]);
+                        BigEndian::write_u16(&mut data[context.ipcso..], sum);
+                    }
+                }
+
+                if context.tucso > context.tucss && context.tucso + 2 <= len {
+                    if tse && context.tucmd & TXD_TUCMD_TCP != 0 {
+                        // Unlike the hardware, the host expects the pseudo
+                        // header checksum to include the TCP length.
+                        let sum = BigEndian::read_u16(&data[context.tucso..]);
+                        let sum = fold(u32::from(sum) + (len - context.tucss) as u32);
+                        BigEndian::write_u16(&mut data[context.tucso..], sum);
+
+                        let gso_type = if ipv4 {
+                            VIRTIO_NET_HDR_GSO_TCPV4
+                        } else {
+                            VIRTIO_NET_HDR_GSO_TCPV6
+                        };
+                        gso = Some((gso_type, context.hdr_len, context.mss));
+                    }
+
+                    if tse || popts & TXD_POPTS_TXSM != 0 {
+                        csum = Some((context.tucss, context.tucso - context.tucss));
+                    }
+                }
+            }
+        }
+
+        if let Some((start, offset)) = csum {
+            if start + offset + 2 > len {
+                csum = None;
+            }
+        }
+
+        // The offsets given by the driver don't account for the VLAN tag.
+        if let Some(tci) = vlan.take() {
+            if len >= ETH_TYPE_OFFSET {
+                let mut tag = [0u8; VLAN_HLEN];
+                BigEndian::write_u16(&mut tag[0..2], self.regs[VET / 4] as u16);
+                BigEndian::write_u16(&mut tag[2..4], tci);
+                let at = VNET_HDR_LEN + ETH_TYPE_OFFSET;
+                frame.splice(at..at, tag.iter().cloned());
+
+                csum = csum.map(|(start, offset)| (start + VLAN_HLEN, offset));
+                gso = gso.map(|(gso_type, hdr_len, size)| (gso_type, hdr_len + VLAN_HLEN, size));
+            }
+        }
+
+        let hdr = &mut frame[..VNET_HDR_LEN];
+        for b in hdr.iter_mut() {
+            *b = 0;
+        }
+        if let Some((start, offset)) = csum {
+            hdr[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+            LittleEndian::write_u16(&mut hdr[6..8], start as u16);
+            LittleEndian::write_u16(&mut hdr[8..10], offset as u16);
+        }
+        if let Some((gso_type, hdr_len, size)) = gso {
+            hdr[1] = gso_type;
+            LittleEndian::write_u16(&mut hdr[2..4], hdr_len as u16);
+            LittleEndian::write_u16(&mut hdr[4..6], size);
+        }
+
+        if let Err(e) = self.tap.write(frame.as_slice()) {
+            warn!("Failed to write e1000 frame to tap: {}", e);
+            return;
+        }
+
+        self.regs[GPTC / 4] = self.regs[GPTC / 4].wrapping_add(1);
+        self.regs[TPT / 4] = self.regs[TPT / 4].wrapping_add(1);
+    }
+
+    fn accept(&self, frame: &[u8]) -> bool {
+        if frame.len() < ETH_TYPE_OFFSET {
+            return false;
+        }
+
+        let rctl = self.regs[RCTL / 4];
+        let dst = &frame[..ETH_ALEN];
+        if dst[0] & 1 == 0 {
+            if rctl & RCTL_UPE != 0 {
+                return true;
+            }
+            return (0..RA_ENTRIES).any(|i| {
+                let ral = self.regs[RA / 4 + 2 * i];
+                let rah = self.regs[RA / 4 + 2 * i + 1];
+                rah & RAH_AV != 0
+                    && LittleEndian::read_u32(&dst[0..4]) == ral
+                    && u32::from(LittleEndian::read_u16(&dst[4..6])) == rah & 0xffff
+            });
+        }
+
+        if dst.iter().all(|b| *b == 0xff) {
+            return rctl & RCTL_BAM != 0;
+        }
+        if rctl & RCTL_MPE != 0 {
+            return true;
+        }
+
+        // The multicast filter is indexed by 12 bits of the address,
+        // selected by the multicast offset.
+        let shift = [4, 3, 2, 0][((rctl >> RCTL_MO_SHIFT) & 0x3) as usize];
+        let hash = (u16::from(dst[4]) >> shift | u16::from(dst[5]) << (8 - shift)) & 0xfff;
+        let mta = self.regs[MTA / 4 + (hash >> 5) as usize];
+        mta & (1 << (hash & 0x1f)) != 0
+    }
+
+    fn rx_buffer_size(&self) -> usize {
+        let rctl = self.regs[RCTL / 4];
+        let bsize = (rctl >> RCTL_BSIZE_SHIFT) & 0x3;
+        if rctl & RCTL_BSEX != 0 && bsize != 0 {
+            32768 >> bsize
+        } else {
+            2048 >> bsize
+        }
+    }
+
+    // Returns false if the guest did not provide enough receive descriptors,
+    // in which case the frame should be retried later.
+    fn receive(&mut self, frame: &[u8]) -> bool {
+        if self.regs[RCTL / 4] & RCTL_EN == 0 || !self.accept(frame) {
+            return true;
+        }
+
+        let mut status = 0;
+        let mut special = 0;
+        let mut buf = Vec::with_capacity(frame.len() + FCS_LEN);
+        if self.regs[CTRL / 4] & CTRL_VME != 0
+            && frame.len() >= ETH_TYPE_OFFSET + VLAN_HLEN
+            && u32::from(BigEndian::read_u16(&frame[ETH_TYPE_OFFSET..])) == self.regs[VET / 4]
+        {
+            special = BigEndian::read_u16(&frame[ETH_TYPE_OFFSET + 2..]);
+            status |= RXD_STA_VP;
+            buf.extend_from_slice(&frame[..ETH_TYPE_OFFSET]);
+            buf.extend_from_slice(&frame[ETH_TYPE_OFFSET + VLAN_HLEN..]);
+        } else {
+            buf.extend_from_slice(frame);
+        }
+        if buf.len() < MIN_FRAME_LEN {
+            buf.resize(MIN_FRAME_LEN, 0);
+        }
+        // The driver expects the frame check sequence unless stripped.
+        if self.regs[RCTL / 4] & RCTL_SECRC == 0 {
+            buf.resize(buf.len() + FCS_LEN, 0);
+        }
+
+        let (base, count) = self.ring(RDBAL, RDBAH, RDLEN);
+        let mut head = u64::from(self.regs[RDH / 4]);
+        let tail = u64::from(self.regs[RDT / 4]);
+        if head >= count || tail >= count {
+            return false;
+        }
+
+        let available = if tail >= head {
+            tail - head
+        } else {
+            count - head + tail
+        };
+        let buffer_size = self.rx_buffer_size();
+        let chunks = buf.chunks(buffer_size);
+        if chunks.len() as u64 > available {
+            return false;
+        }
+
+        let memory = self.memory.clone();
+        let mem = memory.read().unwrap();
+        let last = chunks.len() - 1;
+        for (i, chunk) in chunks.enumerate() {
+            let desc_addr = GuestAddress(base + head * DESC_SIZE);
+            let mut desc = [0u8; DESC_SIZE as usize];
+            if let Err(e) = mem.read_slice(&mut desc[..8], desc_addr) {
+                error!("Failed to read e1000 receive descriptor: {:?}", e);
+                return true;
+            }
+
+            let addr = GuestAddress(LittleEndian::read_u64(&desc[0..8]));
+            if let Err(e) = mem.write_slice(chunk, addr) {
+                error!("Failed to write e1000 receive buffer: {:?}", e);
+            }
+
+            LittleEndian::write_u16(&mut desc[8..10], chunk.len() as u16);
+            desc[12] = RXD_STA_DD | RXD_STA_IXSM;
+            if i == last {
+                desc[12] |= RXD_STA_EOP | status;
+                LittleEndian::write_u16(&mut desc[14..16], special);
+            }
+            if let Err(e) = mem.write_slice(&desc[8..], desc_addr.unchecked_add(8)) {
+                error!("Failed to write e1000 receive descriptor: {:?}", e);
+            }
+
+            head = (head + 1) % count;
+        }
+
+        self.regs[RDH / 4] = head as u32;
+        self.regs[GPRC / 4] = self.regs[GPRC / 4].wrapping_add(1);
+        self.regs[TPR / 4] = self.regs[TPR / 4].wrapping_add(1);
+        self.raise_interrupt(ICR_RXT0);
+
+        true
+    }
+}
+
+// Moves the frames from the tap interface to the guest.
+struct RxHandler {
+    state: Arc<Mutex<E1000State>>,
+    tap_fd: RawFd,
+    rx_evt: EventFd,
+    kill_evt: EventFd,
+    pause: PauseWorker,
+    epoll_fd: RawFd,
+    tap_listening: bool,
+    frame_buf: Vec<u8>,
+    frame_len: usize,
+    // A frame has been read from the tap, but not received by the guest.
+    deferred_frame: bool,
+}
+
+impl RxHandler {
+    fn process_rx(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if !self.deferred_frame {
+                match state.tap.read(&mut self.frame_buf) {
+                    Ok(len) => {
+                        self.frame_len = len;
+                        self.deferred_frame = true;
+                    }
+                    Err(e) => {
+                        if e.kind() != io::ErrorKind::WouldBlock {
+                            error!("Failed to read e1000 frame from tap: {}", e);
+                        }
+                        break;
+                    }
+                }
+            }
+
+            if self.frame_len < VNET_HDR_LEN
+                || state.receive(&self.frame_buf[VNET_HDR_LEN..self.frame_len])
+            {
+                self.deferred_frame = false;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn set_tap_listening(&mut self, listening: bool) -> io::Result<()> {
+        if listening == self.tap_listening {
+            return Ok(());
+        }
+
+        let op = if listening {
+            epoll::ControlOptions::EPOLL_CTL_ADD
+        } else {
+            epoll::ControlOptions::EPOLL_CTL_DEL
+        };
+        epoll::ctl(
+            self.epoll_fd,
+            op,
+            self.tap_fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, RX_TAP_EVENT),
+        )?;
+        self.tap_listening = listening;
+
+        Ok(())
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        for (fd, event) in &[
+            (self.rx_evt.as_raw_fd(), RX_QUEUE_EVENT),
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+            (self.pause.evt().as_raw_fd(), PAUSE_EVENT),
+        ] {
+            epoll::ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                *fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, *event),
+            )?;
+        }
+        self.set_tap_listening(true)?;
+
+        const EPOLL_EVENTS_LEN: usize = 8;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        loop {
+            let num_events = match epoll::wait(self.epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                match event.data {
+                    RX_TAP_EVENT => self.process_rx(),
+                    RX_QUEUE_EVENT => {
+                        self.rx_evt.read()?;
+                        self.process_rx();
+                    }
+                    KILL_EVENT => return Ok(()),
+                    // The tap is left alone, its frames received once
+                    // resumed.
+                    PAUSE_EVENT => self.pause.wait_resumed(),
+                    _ => error!("Unknown event for e1000"),
+                }
+            }
+
+            // Stop polling the tap until the guest provides some receive
+            // descriptors for the pending frame.
+            let listening = !self.deferred_frame;
+            self.set_tap_listening(listening)?;
+        }
+    }
+}
+
+/// Emulated e1000 PCI device, backed by a TAP interface.
+pub struct E1000 {
+    configuration: PciConfiguration,
+    state: Arc<Mutex<E1000State>>,
+    mmio_bar_addr: u64,
+    io_bar_addr: u64,
+    // Register offset accessed through the IO BAR.
+    io_addr: u32,
+    kill_evt: EventFd,
+    pause: PauseControl,
+}
+
+impl E1000 {
+    /// Create a new e1000 device with the given TAP interface.
+    pub fn new_with_tap(
+        tap: Tap,
+        mac: &MacAddr,
+        memory: Arc<RwLock<GuestMemoryMmap>>,
+    ) -> Result<Self> {
+        // The frames are fully checksummed by the host.
+        tap.set_offload(0).map_err(Error::TapSetOffload)?;
+        tap.set_vnet_hdr_size(VNET_HDR_LEN as i32)
+            .map_err(Error::TapSetVnetHdrSize)?;
+
+        let configuration = PciConfiguration::new(
+            E1000_VENDOR_ID,
+            E1000_DEV_ID,
+            PciClassCode::NetworkController,
+            &PciNetworkControllerSubclass::EthernetController,
+            None,
+            PciHeaderType::Device,
+            E1000_VENDOR_ID,
+            E1000_DEV_ID,
+            None,
+        );
+
+        let rx_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let kill_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFd)?;
+        let pause = PauseControl::new().map_err(Error::EventFd)?;
+        let tap_fd = tap.as_raw_fd();
+
+        let mut state = E1000State {
+            regs: vec![0u32; MMIO_BAR_SIZE as usize / 4],
+            phy: phy_defaults(),
+            eeprom: Eeprom::new(mac.get_bytes()),
+            mac: mac.clone(),
+            tap,
+            memory,
+            interrupt_cb: None,
+            rx_evt: rx_evt.try_clone().map_err(Error::EventFd)?,
+            tx: Tx::new(),
+        };
+        state.reset();
+        let state = Arc::new(Mutex::new(state));
+
+        let mut handler = RxHandler {
+            state: state.clone(),
+            tap_fd,
+            rx_evt,
+            kill_evt: kill_evt.try_clone().map_err(Error::EventFd)?,
+            pause: pause.worker(),
+            epoll_fd: epoll::create(true).map_err(Error::EpollCreateFd)?,
+            tap_listening: false,
+            frame_buf: vec![0u8; MAX_BUFFER_SIZE],
+            frame_len: 0,
+            deferred_frame: false,
+        };
+        thread::Builder::new()
+            .name("e1000".to_string())
+            .spawn(move || {
+                if let Err(e) = handler.run() {
+                    error!("e1000 receive thread failed: {}", e);
+                }
+            })
+            .map_err(Error::ThreadSpawn)?;
+
+        Ok(E1000 {
+            configuration,
+            state,
+            mmio_bar_addr: 0,
+            io_bar_addr: 0,
+            io_addr: 0,
+            kill_evt,
+            pause,
+        })
+    }
+
+    /// Create a new e1000 device with the given IP address and netmask, and
+    /// the given IPv6 address and prefix length, if any.
+    pub fn new(
+        ip_addr: Ipv4Addr,
+        netmask: Ipv4Addr,
+        ipv6: Option<(Ipv6Addr, u8)>,
+        mac: &MacAddr,
+        memory: Arc<RwLock<GuestMemoryMmap>>,
+    ) -> Result<Self> {
+        let tap = Tap::new().map_err(Error::TapOpen)?;
+        tap.set_ip_addr(ip_addr).map_err(Error::TapSetIp)?;
+        tap.set_netmask(netmask).map_err(Error::TapSetNetmask)?;
+        if let Some((ip6_addr, prefix_len)) = ipv6 {
+            tap.set_ipv6_addr(ip6_addr, prefix_len)
+                .map_err(Error::TapSetIpv6)?;
+        }
+        tap.enable().map_err(Error::TapEnable)?;
+
+        Self::new_with_tap(tap, mac, memory)
+    }
+
+    fn read_mmio(&mut self, offset: u64, data: &mut [u8]) {
+        if data.len() != 4 || offset % 4 != 0 {
+            warn!("Invalid e1000 register read at 0x{:x}", offset);
+            return;
+        }
+
+        let value = self.state.lock().unwrap().read_reg(offset as usize);
+        LittleEndian::write_u32(data, value);
+    }
+
+    fn write_mmio(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 4 || offset % 4 != 0 {
+            warn!("Invalid e1000 register write at 0x{:x}", offset);
+            return;
+        }
+
+        let value = LittleEndian::read_u32(data);
+        self.state.lock().unwrap().write_reg(offset as usize, value);
+    }
+}
+
+impl Drop for E1000 {
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.kill_evt.write(1);
+    }
+}
+
+// The transmit ring is processed by the vCPU writing the tail register,
+// only the receive thread is paused.
+impl Pausable for E1000 {
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
+impl BusDevice for E1000 {
+    fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        self.read_bar(base, offset, data)
+    }
+
+    fn write(&mut self, base: u64, offset: u64, data: &[u8]) {
+        self.write_bar(base, offset, data)
+    }
+}
+
+impl PciDevice for E1000 {
+    fn assign_pin_irq(
+        &mut self,
+        irq_cb: Arc<InterruptDelivery>,
+        irq_num: u32,
+        irq_pin: PciInterruptPin,
+    ) {
+        self.configuration.set_irq(irq_num as u8, irq_pin);
+        self.state.lock().unwrap().interrupt_cb = Some(irq_cb);
+    }
+
+    fn allocate_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> std::result::Result<Vec<(GuestAddress, GuestUsize, PciBarRegionType)>, PciDeviceError>
+    {
+        let mut ranges = Vec::new();
+
+        // The registers live below 4GiB, for the sake of 32 bits guests.
+        let mmio_addr = allocator
+            .allocate_mmio_hole_addresses(None, MMIO_BAR_SIZE, Some(MMIO_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(MMIO_BAR_SIZE))?;
+        let config = PciBarConfiguration::default()
+            .set_register_index(0)
+            .set_address(mmio_addr.raw_value())
+            .set_size(MMIO_BAR_SIZE)
+            .set_region_type(PciBarRegionType::Memory32BitRegion);
+        self.configuration
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(mmio_addr.raw_value(), e))?;
+        ranges.push((
+            mmio_addr,
+            MMIO_BAR_SIZE,
+            PciBarRegionType::Memory32BitRegion,
+        ));
+        self.mmio_bar_addr = mmio_addr.raw_value();
+
+        let io_addr = allocator
+            .allocate_io_addresses(None, IO_BAR_SIZE, Some(IO_BAR_SIZE))
+            .ok_or(PciDeviceError::IoAllocationFailed(IO_BAR_SIZE))?;
+        let config = PciBarConfiguration::default()
+            .set_register_index(1)
+            .set_address(io_addr.raw_value())
+            .set_size(IO_BAR_SIZE)
+            .set_region_type(PciBarRegionType::IORegion);
+        self.configuration
+            .add_pci_bar(&config)
+            .map_err(|e| PciDeviceError::IoRegistrationFailed(io_addr.raw_value(), e))?;
+        ranges.push((io_addr, IO_BAR_SIZE, PciBarRegionType::IORegion));
+        self.io_bar_addr = io_addr.raw_value();
+
+        Ok(ranges)
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.configuration
+            .write_config_register(reg_idx, offset, data);
+    }
+
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.configuration.read_reg(reg_idx)
+    }
+
+    fn detect_bar_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        data: &[u8],
+    ) -> Option<BarReprogrammingParams> {
+        self.configuration.detect_bar_reprogramming(reg_idx, data)
+    }
+
+    fn read_bar(&mut self, base: u64, offset: u64, data: &mut [u8]) {
+        if base != self.io_bar_addr {
+            self.read_mmio(offset, data);
+        } else {
+            match offset {
+                IOADDR if data.len() == 4 => LittleEndian::write_u32(data, self.io_addr),
+                IODATA => self.read_mmio(u64::from(self.io_addr), data),
+                _ => warn!("Invalid e1000 IO read at 0x{:x}", offset),
+            }
+        }
+    }
+
+    fn write_bar(&mut self, base: u64, offset: u64, data: &[u8]) {
+        if base != self.io_bar_addr {
+            self.write_mmio(offset, data);
+        } else {
+            match offset {
+                IOADDR if data.len() == 4 => {
+                    self.io_addr = LittleEndian::read_u32(data) % MMIO_BAR_SIZE as u32
+                }
+                IODATA => self.write_mmio(u64::from(self.io_addr), data),
+                _ => warn!("Invalid e1000 IO write at 0x{:x}", offset),
+            }
+        }
+    }
+
+    fn move_bar(&mut self, old_base: u64, new_base: u64) -> std::result::Result<(), io::Error> {
+        if old_base == self.io_bar_addr {
+            self.io_bar_addr = new_base;
+        } else if old_base == self.mmio_bar_addr {
+            self.mmio_bar_addr = new_base;
+        }
+
+        Ok(())
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
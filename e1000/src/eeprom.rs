@@ -0,0 +1,170 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+// EECD register bits.
+const EECD_SK: u32 = 1 << 0;
+const EECD_CS: u32 = 1 << 1;
+const EECD_DI: u32 = 1 << 2;
+const EECD_DO: u32 = 1 << 3;
+const EECD_FWE_MASK: u32 = 3 << 4;
+const EECD_REQ: u32 = 1 << 6;
+const EECD_GNT: u32 = 1 << 7;
+const EECD_PRES: u32 = 1 << 8;
+
+// The driver sends a start bit, a 2 bits opcode and a 6 bits address before
+// reading the 16 bits word back.
+const MICROWIRE_READ_OPCODE: u32 = 0x6;
+const MICROWIRE_CMD_BITS: u16 = 9;
+
+const EEPROM_WORDS: usize = 64;
+const EEPROM_CHECKSUM_REG: usize = 0x3f;
+const EEPROM_SUM: u16 = 0xbaba;
+
+pub const E1000_DEV_ID: u16 = 0x100e;
+pub const E1000_VENDOR_ID: u16 = 0x8086;
+
+// Default content of an 82540EM EEPROM. The first three words hold the MAC
+// address, and the last one the checksum.
+#[rustfmt::skip]
+const EEPROM_TEMPLATE: [u16; EEPROM_WORDS] = [
+    0x0000, 0x0000, 0x0000, 0x0000, 0xffff, 0x0000, 0x0000, 0x0000,
+    0x3000, 0x1000, 0x6403, E1000_DEV_ID, E1000_VENDOR_ID, E1000_DEV_ID, E1000_VENDOR_ID, 0x3040,
+    0x0008, 0x2000, 0x7e14, 0x0048, 0x1000, 0x00d8, 0x0000, 0x2700,
+    0x6cc9, 0x3150, 0x0722, 0x040b, 0x0984, 0x0000, 0xc000, 0x0706,
+    0x1008, 0x0000, 0x0f04, 0x7fff, 0x4d01, 0xffff, 0xffff, 0xffff,
+    0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff,
+    0x0100, 0x4000, 0x121c, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff,
+    0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0x0000,
+];
+
+/// Microwire EEPROM, bit banged by the driver through the EECD register, or
+/// read one word at a time through EERD.
+pub struct Eeprom {
+    data: [u16; EEPROM_WORDS],
+    // Last value written to EECD.
+    eecd: u32,
+    cmd: u32,
+    bits_in: u16,
+    // Index of the bit being shifted out, across the whole EEPROM.
+    bit_out: u16,
+    reading: bool,
+}
+
+impl Eeprom {
+    pub fn new(mac: &[u8]) -> Self {
+        let mut data = EEPROM_TEMPLATE;
+        for (i, word) in data.iter_mut().take(3).enumerate() {
+            *word = u16::from(mac[2 * i]) | u16::from(mac[2 * i + 1]) << 8;
+        }
+
+        let sum = data[..EEPROM_CHECKSUM_REG]
+            .iter()
+            .fold(0u16, |sum, word| sum.wrapping_add(*word));
+        data[EEPROM_CHECKSUM_REG] = EEPROM_SUM.wrapping_sub(sum);
+
+        Eeprom {
+            data,
+            eecd: 0,
+            cmd: 0,
+            bits_in: 0,
+            bit_out: 0,
+            reading: false,
+        }
+    }
+
+    pub fn read(&self, addr: usize) -> u16 {
+        self.data.get(addr).cloned().unwrap_or(0)
+    }
+
+    pub fn eecd(&self) -> u32 {
+        let mut eecd = self.eecd | EECD_PRES | EECD_GNT;
+
+        let word = self.data[(self.bit_out as usize >> 4) % EEPROM_WORDS];
+        if !self.reading || (word >> (15 - (self.bit_out & 0xf))) & 1 != 0 {
+            eecd |= EECD_DO;
+        }
+
+        eecd
+    }
+
+    pub fn set_eecd(&mut self, eecd: u32) {
+        let old_eecd = self.eecd;
+        self.eecd = eecd & (EECD_SK | EECD_CS | EECD_DI | EECD_FWE_MASK | EECD_REQ);
+
+        if eecd & EECD_CS == 0 {
+            return;
+        }
+
+        // Selecting the chip starts a new command.
+        if old_eecd & EECD_CS == 0 {
+            self.cmd = 0;
+            self.bits_in = 0;
+            self.bit_out = 0;
+            self.reading = false;
+        }
+
+        if (eecd ^ old_eecd) & EECD_SK == 0 {
+            return;
+        }
+
+        // The data is shifted out on the falling edge of the clock, and
+        // shifted in on its rising edge.
+        if eecd & EECD_SK == 0 {
+            self.bit_out = self.bit_out.wrapping_add(1);
+            return;
+        }
+
+        self.cmd = self.cmd << 1 | (eecd & EECD_DI != 0) as u32;
+        self.bits_in += 1;
+        if self.bits_in == MICROWIRE_CMD_BITS && !self.reading {
+            self.reading = (self.cmd >> 6) & 0x7 == MICROWIRE_READ_OPCODE;
+            self.bit_out = (((self.cmd & 0x3f) as u16) << 4).wrapping_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn clock(eeprom: &mut Eeprom, di: bool) -> bool {
+        let di = if di { EECD_DI } else { 0 };
+        eeprom.set_eecd(EECD_CS | di);
+        eeprom.set_eecd(EECD_CS | di | EECD_SK);
+        let bit = eeprom.eecd() & EECD_DO != 0;
+        eeprom.set_eecd(EECD_CS | di);
+        bit
+    }
+
+    #[test]
+    fn test_checksum() {
+        let eeprom = Eeprom::new(&MAC);
+        let sum = (0..EEPROM_WORDS).fold(0u16, |sum, i| sum.wrapping_add(eeprom.read(i)));
+        assert_eq!(sum, EEPROM_SUM);
+        assert_eq!(eeprom.read(0), 0x5452);
+        assert_eq!(eeprom.read(2), 0x5634);
+    }
+
+    #[test]
+    fn test_microwire_read() {
+        let mut eeprom = Eeprom::new(&MAC);
+
+        for addr in 0..EEPROM_WORDS {
+            eeprom.set_eecd(0);
+            let cmd = (MICROWIRE_READ_OPCODE << 6) | addr as u32;
+            for i in (0..MICROWIRE_CMD_BITS).rev() {
+                clock(&mut eeprom, (cmd >> i) & 1 != 0);
+            }
+
+            let mut word = 0u16;
+            for _ in 0..16 {
+                word = word << 1 | clock(&mut eeprom, false) as u16;
+            }
+            assert_eq!(word, eeprom.read(addr));
+        }
+    }
+}
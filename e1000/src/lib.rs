@@ -0,0 +1,77 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulated Intel 82540EM (e1000) network controller.
+//!
+//! This is a slow but widely supported NIC, meant for guests coming without
+//! any virtio driver, such as OS installers. The frames are exchanged with the
+//! host through a TAP interface.
+extern crate byteorder;
+extern crate devices;
+extern crate epoll;
+#[macro_use]
+extern crate log;
+extern crate net_util;
+extern crate pci;
+extern crate vm_allocator;
+extern crate vm_memory;
+extern crate vmm_sys_util;
+
+mod device;
+mod eeprom;
+
+use net_util::TapError;
+use std::fmt::{self, Display};
+use std::io;
+
+pub use device::E1000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Open tap device failed.
+    TapOpen(TapError),
+    /// Setting tap IP failed.
+    TapSetIp(TapError),
+    /// Setting tap netmask failed.
+    TapSetNetmask(TapError),
+    /// Setting tap IPv6 address failed.
+    TapSetIpv6(TapError),
+    /// Setting tap interface offload flags failed.
+    TapSetOffload(TapError),
+    /// Setting vnet header size failed.
+    TapSetVnetHdrSize(TapError),
+    /// Enabling tap interface failed.
+    TapEnable(TapError),
+    /// Cannot create an EventFd.
+    EventFd(io::Error),
+    /// Cannot create the epoll file descriptor.
+    EpollCreateFd(io::Error),
+    /// Cannot add a file descriptor to the epoll set.
+    EpollCtl(io::Error),
+    /// Cannot spawn the receive thread.
+    ThreadSpawn(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            TapOpen(e) => write!(f, "failed to open tap device: {:?}", e),
+            TapSetIp(e) => write!(f, "failed to set tap IP address: {:?}", e),
+            TapSetNetmask(e) => write!(f, "failed to set tap netmask: {:?}", e),
+            TapSetIpv6(e) => write!(f, "failed to set tap IPv6 address: {:?}", e),
+            TapSetOffload(e) => write!(f, "failed to set tap offload flags: {:?}", e),
+            TapSetVnetHdrSize(e) => write!(f, "failed to set tap vnet header size: {:?}", e),
+            TapEnable(e) => write!(f, "failed to enable tap device: {:?}", e),
+            EventFd(e) => write!(f, "failed to create eventfd: {}", e),
+            EpollCreateFd(e) => write!(f, "failed to create epoll fd: {}", e),
+            EpollCtl(e) => write!(f, "failed to add fd to epoll: {}", e),
+            ThreadSpawn(e) => write!(f, "failed to spawn receive thread: {}", e),
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Each byte is an operation on the API, the sequence failing on any response
+// or VM state the model of the VMM doesn't expect.
+fuzz_target!(|data: &[u8]| {
+    vmm::api::harness::run(data);
+});
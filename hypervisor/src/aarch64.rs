@@ -0,0 +1,39 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! aarch64 vCPU and interrupt controller state.
+
+/// Target and features a vCPU is initialized with.
+pub use kvm_bindings::kvm_vcpu_init as VcpuInit;
+
+/// vCPU features.
+pub use kvm_bindings::{KVM_ARM_VCPU_POWER_OFF, KVM_ARM_VCPU_PSCI_0_2};
+
+/// Register identifiers, for `Vcpu::set_reg()` and `Vcpu::get_reg()`.
+pub use kvm_bindings::{
+    KVM_REG_ARM64, KVM_REG_ARM64_SYSREG, KVM_REG_ARM64_SYSREG_CRM_SHIFT,
+    KVM_REG_ARM64_SYSREG_CRN_SHIFT, KVM_REG_ARM64_SYSREG_OP0_SHIFT, KVM_REG_ARM64_SYSREG_OP1_SHIFT,
+    KVM_REG_ARM64_SYSREG_OP2_SHIFT, KVM_REG_ARM_CORE, KVM_REG_SIZE_U64,
+};
+
+/// Interrupt controller device attributes.
+pub use kvm_bindings::{
+    KVM_DEV_ARM_VGIC_CTRL_INIT, KVM_DEV_ARM_VGIC_GRP_ADDR, KVM_DEV_ARM_VGIC_GRP_CTRL,
+    KVM_DEV_ARM_VGIC_GRP_NR_IRQS, KVM_VGIC_ITS_ADDR_TYPE, KVM_VGIC_V3_ADDR_TYPE_DIST,
+    KVM_VGIC_V3_ADDR_TYPE_REDIST,
+};
+
+/// MSI flag telling the interrupt translation service which device the
+/// message comes from.
+pub use kvm_bindings::KVM_MSI_VALID_DEVID;
+
+/// Interrupt controller devices the hypervisor can emulate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GicDevice {
+    /// GICv3 distributor and redistributors.
+    V3,
+    /// GICv3 Interrupt Translation Service, turning MSIs into LPIs.
+    Its,
+}
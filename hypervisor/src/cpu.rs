@@ -0,0 +1,129 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io;
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::VcpuInit;
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::{CpuId, FpuState, LapicState, MsrEntry, SpecialRegisters, StandardRegisters};
+
+/// Reasons for a vCPU to return from `Vcpu::run()`.
+#[derive(Debug)]
+pub enum VmExit {
+    /// The exit was handled by the hypervisor, or through the `VmmOps`.
+    Ignore,
+    /// The guest signaled the end of the interrupt with the given vector,
+    /// which the userspace IOAPIC has to be told about.
+    IoapicEoi(u8),
+    /// The guest can't run anymore, after a triple fault, or it asked for
+    /// the system to be turned off or reset.
+    Shutdown,
+    /// The vCPU hit a breakpoint, or ran the instruction it was stepping.
+    Debug,
+    /// The exit reason could not be handled, for the given reason.
+    Unhandled(String),
+}
+
+/// A vCPU created by a VM.
+pub trait Vcpu: Send + Sync {
+    /// Runs the vCPU until it exits, returning the reason.
+    fn run(&self) -> io::Result<VmExit>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the general purpose registers.
+    fn get_regs(&self) -> io::Result<StandardRegisters>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the general purpose registers.
+    fn set_regs(&self, regs: &StandardRegisters) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the segment, control and descriptor table registers.
+    fn get_sregs(&self) -> io::Result<SpecialRegisters>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the segment, control and descriptor table registers.
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the floating point state.
+    fn get_fpu(&self) -> io::Result<FpuState>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the floating point state.
+    fn set_fpu(&self, fpu: &FpuState) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the local APIC registers.
+    fn get_lapic(&self) -> io::Result<LapicState>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the local APIC registers.
+    fn set_lapic(&self, lapic: &LapicState) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Reads the MSRs of the given entries, returning how many were read.
+    fn get_msrs(&self, msrs: &mut [MsrEntry]) -> io::Result<usize>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Writes the MSRs of the given entries.
+    fn set_msrs(&self, msrs: &[MsrEntry]) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the CPUID entries the guest sees.
+    fn set_cpuid2(&self, cpuid: &CpuId) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Enables the Hyper-V synthetic interrupt controller.
+    fn enable_hyperv_synic(&self) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Tells the guest, through its kvmclock, that the vCPU was stopped by
+    /// the host, for its watchdogs not to take the time it was stopped for
+    /// a lockup. Fails if the guest doesn't use the kvmclock.
+    fn kvmclock_paused(&self) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Initializes the vCPU of a TDX VM, once its CPUID is set, with the
+    /// address of the hand-off block describing the guest to the firmware.
+    fn tdx_init(&self, hob_address: u64) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Adds the pages of a private memory range to a TDX VM, copying their
+    /// content from `host_address`, and extending the measurement with it
+    /// if `measure` is set.
+    fn tdx_init_memory_region(
+        &self,
+        host_address: u64,
+        guest_address: u64,
+        size: u64,
+        measure: bool,
+    ) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the hardware breakpoints of the vCPU, up to 4 of them, and
+    /// whether it runs a single instruction at a time, exiting with
+    /// `VmExit::Debug` when it hits one of them or steps.
+    fn set_guest_debug(&self, breakpoints: &[u64], single_step: bool) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Translates a guest virtual address through the current page tables
+    /// of the vCPU, returning none if it isn't mapped.
+    fn translate_gva(&self, gva: u64) -> io::Result<Option<u64>>;
+
+    #[cfg(target_arch = "aarch64")]
+    /// Initializes the vCPU with the given target and features, which has
+    /// to be done before anything else.
+    fn vcpu_init(&self, kvi: &VcpuInit) -> io::Result<()>;
+
+    #[cfg(target_arch = "aarch64")]
+    /// Sets the register `reg_id`.
+    fn set_reg(&self, reg_id: u64, data: u64) -> io::Result<()>;
+
+    #[cfg(target_arch = "aarch64")]
+    /// Returns the register `reg_id`.
+    fn get_reg(&self, reg_id: u64) -> io::Result<u64>;
+}
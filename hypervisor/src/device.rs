@@ -0,0 +1,15 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io;
+
+/// Attribute of a hypervisor emulated device.
+pub use kvm_bindings::kvm_device_attr as DeviceAttr;
+
+/// A device emulated by the hypervisor.
+pub trait Device: Send + Sync {
+    /// Sets an attribute of the device.
+    fn set_device_attr(&self, attr: &DeviceAttr) -> io::Result<()>;
+}
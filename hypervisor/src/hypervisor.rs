@@ -0,0 +1,52 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io;
+use std::sync::Arc;
+
+use crate::vm::Vm;
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::{ConfidentialVm, CpuId};
+
+/// Optional hypervisor features the VMM adapts to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capability {
+    /// MSIs can be injected into the guest.
+    SignalMsi,
+    #[cfg(target_arch = "x86_64")]
+    /// The IOAPIC can be left to userspace, the local APICs being emulated
+    /// by the hypervisor.
+    SplitIrqchip,
+    #[cfg(target_arch = "x86_64")]
+    /// The local APIC timers support the TSC deadline mode.
+    TscDeadlineTimer,
+}
+
+/// A hypervisor VMs can be created from.
+pub trait Hypervisor: Send + Sync {
+    /// Creates a VM, with neither guest memory nor vCPUs.
+    fn create_vm(&self) -> io::Result<Arc<dyn Vm>>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Creates a confidential VM of the given kind, whose memory is private
+    /// to the guest.
+    fn create_confidential_vm(&self, kind: ConfidentialVm) -> io::Result<Arc<dyn Vm>>;
+
+    /// Returns how many guest memory regions a VM can have.
+    fn get_max_memory_slots(&self) -> u32;
+
+    /// Checks whether the hypervisor supports the given capability.
+    fn check_capability(&self, cap: Capability) -> bool;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Returns the CPUID entries the hypervisor can expose to its guests.
+    fn get_cpuid(&self) -> io::Result<CpuId>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Allows the guests to use the dynamically enabled AMX tile data
+    /// state. Needed before any vCPU gets created, and before the CPUID
+    /// entries the hypervisor supports are read for them to include AMX.
+    fn enable_amx_state_components(&self) -> io::Result<()>;
+}
@@ -0,0 +1,676 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! TDX and SEV-SNP VMs, through the KVM interfaces to the TDX module and to
+//! the AMD secure processor, and through the guest private memory.
+//!
+//! The guest memory regions are backed by guest_memfd files, which the host
+//! can't access, and by the VMM memory for the pages the guest shares with
+//! the host. The guest converts its pages between both, which KVM hands over
+//! to the VMM as memory attribute changes to make.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::raw::c_ulong;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::ptr::{null_mut, read_volatile, write_volatile};
+use std::sync::{Arc, Mutex};
+
+use kvm_bindings::{kvm_cpuid2, kvm_cpuid_entry2, kvm_enable_cap, KVMIO};
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
+
+use crate::cpu::VmExit;
+use crate::vec_with_array_field;
+use crate::vm::UserMemoryRegion;
+use crate::x86_64::{ConfidentialVm, CpuId, SevSnpPageType};
+
+// From <linux/kvm.h>
+const KVM_CAP_EXIT_HYPERCALL: u32 = 201;
+const KVM_CAP_VM_TYPES: c_ulong = 235;
+const KVM_X86_SNP_VM: u64 = 4;
+const KVM_X86_TDX_VM: u64 = 5;
+const KVM_MEM_GUEST_MEMFD: u32 = 1 << 2;
+const KVM_MEMORY_ATTRIBUTE_PRIVATE: u64 = 1 << 3;
+const KVM_EXIT_MEMORY_FAULT: u32 = 39;
+const KVM_MEMORY_EXIT_FLAG_PRIVATE: u64 = 1 << 3;
+// From <asm/kvm_para.h>
+const KVM_HC_MAP_GPA_RANGE: u64 = 12;
+const KVM_MAP_GPA_RANGE_ENCRYPTED: u64 = 1 << 4;
+
+// Offsets in the kvm_run structure of the exit reason, and of the hypercall
+// and memory fault exit information.
+const KVM_RUN_EXIT_REASON: usize = 8;
+const KVM_RUN_HYPERCALL_NR: usize = 32;
+const KVM_RUN_HYPERCALL_ARGS: usize = 40;
+const KVM_RUN_HYPERCALL_RET: usize = 88;
+const KVM_RUN_MEMORY_FAULT_FLAGS: usize = 32;
+const KVM_RUN_MEMORY_FAULT_GPA: usize = 40;
+const KVM_RUN_MEMORY_FAULT_SIZE: usize = 48;
+
+const PAGE_SIZE: u64 = 4096;
+
+// TDX commands, and the TD attributes and CPUID entries they go with.
+const KVM_TDX_CAPABILITIES: u32 = 0;
+const KVM_TDX_INIT_VM: u32 = 1;
+const KVM_TDX_INIT_VCPU: u32 = 2;
+const KVM_TDX_INIT_MEM_REGION: u32 = 3;
+const KVM_TDX_FINALIZE_VM: u32 = 4;
+const KVM_TDX_MEASURE_MEMORY_REGION: u32 = 1;
+// The guest gets a #VE on its accesses to unaccepted memory, instead of the
+// TD exiting.
+const TDX_ATTRIBUTE_SEPT_VE_DISABLE: u64 = 1 << 28;
+// Size in u64 of the kvm_tdx_capabilities fields before its CPUID entries.
+const TDX_CAPABILITIES_HEADER: usize = 256;
+const TDX_MAX_CPUID_CONFIGS: usize = 256;
+const XSAVE_CPUID_LEAF: u32 = 0xd;
+
+// SEV commands, and the SEV-SNP page types of the launch updates.
+const KVM_SEV_INIT2: u32 = 22;
+const KVM_SEV_SNP_LAUNCH_START: u32 = 100;
+const KVM_SEV_SNP_LAUNCH_UPDATE: u32 = 101;
+const KVM_SEV_SNP_LAUNCH_FINISH: u32 = 102;
+const KVM_SEV_SNP_PAGE_TYPE_NORMAL: u8 = 1;
+const KVM_SEV_SNP_PAGE_TYPE_ZERO: u8 = 3;
+const KVM_SEV_SNP_PAGE_TYPE_UNMEASURED: u8 = 4;
+const KVM_SEV_SNP_PAGE_TYPE_SECRETS: u8 = 5;
+const KVM_SEV_SNP_PAGE_TYPE_CPUID: u8 = 6;
+const SEV_SNP_GHCB_VERSION: u16 = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct CreateGuestMemfd {
+    size: u64,
+    flags: u64,
+    reserved: [u64; 6],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UserMemoryRegion2 {
+    slot: u32,
+    flags: u32,
+    guest_phys_addr: u64,
+    memory_size: u64,
+    userspace_addr: u64,
+    guest_memfd_offset: u64,
+    guest_memfd: u32,
+    pad1: u32,
+    pad2: [u64; 14],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MemoryAttributes {
+    address: u64,
+    size: u64,
+    attributes: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct TdxCmd {
+    id: u32,
+    flags: u32,
+    data: u64,
+    hw_error: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct TdxInitVm {
+    attributes: u64,
+    xfam: u64,
+    mrconfigid: [u64; 6],
+    mrowner: [u64; 6],
+    mrownerconfig: [u64; 6],
+    reserved: [u64; 12],
+    cpuid: kvm_cpuid2,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct TdxInitMemRegion {
+    source_addr: u64,
+    gpa: u64,
+    nr_pages: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SevCmd {
+    id: u32,
+    pad0: u32,
+    data: u64,
+    error: u32,
+    sev_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SevInit2 {
+    vmsa_features: u64,
+    flags: u32,
+    ghcb_version: u16,
+    pad1: u16,
+    pad2: [u32; 8],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SnpLaunchStart {
+    policy: u64,
+    gosvw: [u8; 16],
+    flags: u16,
+    pad0: [u8; 6],
+    pad1: [u64; 4],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SnpLaunchUpdate {
+    gfn_start: u64,
+    uaddr: u64,
+    len: u64,
+    type_: u8,
+    pad0: u8,
+    flags: u16,
+    pad1: u32,
+    pad2: [u64; 4],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SnpLaunchFinish {
+    id_block_uaddr: u64,
+    id_auth_uaddr: u64,
+    id_block_en: u8,
+    auth_key_en: u8,
+    vcek_disabled: u8,
+    host_data: [u8; 32],
+    pad0: [u8; 3],
+    flags: u16,
+    pad1: [u64; 4],
+}
+
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_iow_nr!(KVM_SET_USER_MEMORY_REGION2, KVMIO, 0x49, UserMemoryRegion2);
+ioctl_iowr_nr!(KVM_MEMORY_ENCRYPT_OP, KVMIO, 0xba, c_ulong);
+ioctl_iow_nr!(KVM_SET_MEMORY_ATTRIBUTES, KVMIO, 0xd2, MemoryAttributes);
+ioctl_iowr_nr!(KVM_CREATE_GUEST_MEMFD, KVMIO, 0xd4, CreateGuestMemfd);
+
+fn check_ret(ret: i32) -> io::Result<i32> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ret)
+}
+
+fn set_memory_attributes(vm: &VmFd, address: u64, size: u64, private: bool) -> io::Result<()> {
+    let attributes = MemoryAttributes {
+        address,
+        size,
+        attributes: if private {
+            KVM_MEMORY_ATTRIBUTE_PRIVATE
+        } else {
+            0
+        },
+        flags: 0,
+    };
+    // Safe because the kernel only reads the attributes structure.
+    check_ret(unsafe { ioctl_with_ref(vm, KVM_SET_MEMORY_ATTRIBUTES(), &attributes) })?;
+
+    Ok(())
+}
+
+// Runs a TDX command on the VM or on one of its vCPUs, `data` pointing to
+// the command structure, if any.
+fn tdx_command<F: AsRawFd>(fd: &F, id: u32, flags: u32, data: u64) -> io::Result<()> {
+    let mut cmd = TdxCmd {
+        id,
+        flags,
+        data,
+        hw_error: 0,
+    };
+    // Safe because the kernel only accesses the command and the structure
+    // its data points to, which the callers keep alive.
+    check_ret(unsafe { ioctl_with_mut_ref(fd, KVM_MEMORY_ENCRYPT_OP(), &mut cmd) })?;
+
+    Ok(())
+}
+
+// Restricts the CPUID entries of a TD to the ones the TDX module lets the
+// VMM configure, and their registers to the configurable bits. Returns the
+// TD attributes and extended features the TDX module supports, along with
+// the entries.
+fn tdx_configurable_cpuid(
+    vm: &VmFd,
+    cpuid: &CpuId,
+) -> io::Result<(u64, u64, Vec<kvm_cpuid_entry2>)> {
+    let entry_words = std::mem::size_of::<kvm_cpuid_entry2>() / std::mem::size_of::<u64>();
+    let mut caps = vec![0u64; TDX_CAPABILITIES_HEADER + 1 + TDX_MAX_CPUID_CONFIGS * entry_words];
+    // The CPUID entries count, followed by a padding word.
+    caps[TDX_CAPABILITIES_HEADER] = TDX_MAX_CPUID_CONFIGS as u64;
+    tdx_command(vm, KVM_TDX_CAPABILITIES, 0, caps.as_mut_ptr() as u64)?;
+
+    let count = (caps[TDX_CAPABILITIES_HEADER] as u32) as usize;
+    // Safe because the vector was allocated with room for this many entries,
+    // which the kernel filled.
+    let configs = unsafe {
+        std::slice::from_raw_parts(
+            caps[TDX_CAPABILITIES_HEADER + 1..].as_ptr() as *const kvm_cpuid_entry2,
+            count.min(TDX_MAX_CPUID_CONFIGS),
+        )
+    };
+
+    let entries = cpuid
+        .as_slice()
+        .iter()
+        .filter_map(|entry| {
+            let config = configs.iter().find(|config| {
+                config.function == entry.function
+                    && (config.index == entry.index || config.index == u32::max_value())
+            })?;
+            let mut entry = *entry;
+            entry.eax &= config.eax;
+            entry.ebx &= config.ebx;
+            entry.ecx &= config.ecx;
+            entry.edx &= config.edx;
+            Some(entry)
+        })
+        .collect();
+
+    Ok((caps[0], caps[1], entries))
+}
+
+/// Private memory and launch state of a confidential VM.
+pub struct ConfidentialState {
+    kind: ConfidentialVm,
+    // Private memory of the guest, by memory slot.
+    guest_memfds: Mutex<HashMap<u32, File>>,
+    // The AMD secure processor, the SEV commands are sent to.
+    sev: Option<File>,
+}
+
+impl ConfidentialState {
+    /// Creates a confidential VM of the given kind, with KVM handing the
+    /// memory conversions of the guest over to the VMM.
+    pub fn create_vm(kvm: &Kvm, kind: ConfidentialVm) -> io::Result<(VmFd, Self)> {
+        let vm_type = match kind {
+            ConfidentialVm::Tdx => KVM_X86_TDX_VM,
+            ConfidentialVm::SevSnp => KVM_X86_SNP_VM,
+        };
+        // Safe because the kernel doesn't access any memory for this ioctl.
+        let vm_types =
+            check_ret(unsafe { ioctl_with_val(kvm, KVM_CHECK_EXTENSION(), KVM_CAP_VM_TYPES) })?;
+        if vm_types as u64 & (1 << vm_type) == 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+
+        let sev = match kind {
+            ConfidentialVm::SevSnp => {
+                Some(OpenOptions::new().read(true).write(true).open("/dev/sev")?)
+            }
+            ConfidentialVm::Tdx => None,
+        };
+
+        let fd = kvm.create_vm_with_type(vm_type)?;
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_EXIT_HYPERCALL,
+            ..Default::default()
+        };
+        cap.args[0] = 1 << KVM_HC_MAP_GPA_RANGE;
+        fd.enable_cap(&cap)?;
+
+        Ok((
+            fd,
+            ConfidentialState {
+                kind,
+                guest_memfds: Mutex::new(HashMap::new()),
+                sev,
+            },
+        ))
+    }
+
+    /// Sets or removes a guest memory region, backed by a guest_memfd its
+    /// pages start private in.
+    ///
+    /// # Safety
+    ///
+    /// Same as `Vm::set_user_memory_region()`.
+    pub unsafe fn set_private_memory_region(
+        &self,
+        vm: &VmFd,
+        region: UserMemoryRegion,
+    ) -> io::Result<()> {
+        let mut guest_memfds = self.guest_memfds.lock().unwrap();
+        let mut region2 = UserMemoryRegion2 {
+            slot: region.slot,
+            flags: region.flags,
+            guest_phys_addr: region.guest_phys_addr,
+            memory_size: region.memory_size,
+            userspace_addr: region.userspace_addr,
+            ..Default::default()
+        };
+
+        if region.memory_size == 0 {
+            check_ret(ioctl_with_ref(vm, KVM_SET_USER_MEMORY_REGION2(), &region2))?;
+            guest_memfds.remove(&region.slot);
+            return Ok(());
+        }
+
+        let create = CreateGuestMemfd {
+            size: region.memory_size,
+            ..Default::default()
+        };
+        let guest_memfd = File::from_raw_fd(check_ret(ioctl_with_ref(
+            vm,
+            KVM_CREATE_GUEST_MEMFD(),
+            &create,
+        ))?);
+
+        region2.flags |= KVM_MEM_GUEST_MEMFD;
+        region2.guest_memfd = guest_memfd.as_raw_fd() as u32;
+        check_ret(ioctl_with_ref(vm, KVM_SET_USER_MEMORY_REGION2(), &region2))?;
+        set_memory_attributes(vm, region.guest_phys_addr, region.memory_size, true)?;
+        guest_memfds.insert(region.slot, guest_memfd);
+
+        Ok(())
+    }
+
+    fn check_kind(&self, kind: ConfidentialVm) -> io::Result<()> {
+        if self.kind != kind {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        Ok(())
+    }
+
+    /// Initializes a TD, with the CPUID entries the TDX module lets the VMM
+    /// configure.
+    pub fn tdx_init(&self, vm: &VmFd, cpuid: &CpuId) -> io::Result<()> {
+        self.check_kind(ConfidentialVm::Tdx)?;
+
+        let (supported_attributes, supported_xfam, entries) = tdx_configurable_cpuid(vm, cpuid)?;
+        // The extended features are the user and supervisor state
+        // components XSAVE supports.
+        let xsave = |index| {
+            cpuid
+                .as_slice()
+                .iter()
+                .find(|entry| entry.function == XSAVE_CPUID_LEAF && entry.index == index)
+                .cloned()
+                .unwrap_or_default()
+        };
+        let (xcr0, xss) = (xsave(0), xsave(1));
+        let xfam = (u64::from(xcr0.eax) | u64::from(xcr0.edx) << 32)
+            | (u64::from(xss.ecx) | u64::from(xss.edx) << 32);
+
+        let mut init_vm = vec_with_array_field::<TdxInitVm, kvm_cpuid_entry2>(entries.len());
+        init_vm[0].attributes = TDX_ATTRIBUTE_SEPT_VE_DISABLE & supported_attributes;
+        init_vm[0].xfam = xfam & supported_xfam;
+        init_vm[0].cpuid.nent = entries.len() as u32;
+        // Safe because the vector was allocated with room for all entries.
+        unsafe {
+            init_vm[0]
+                .cpuid
+                .entries
+                .as_mut_slice(entries.len())
+                .copy_from_slice(&entries);
+        }
+
+        tdx_command(
+            vm,
+            KVM_TDX_INIT_VM,
+            0,
+            &init_vm[0] as *const TdxInitVm as u64,
+        )
+    }
+
+    /// Finalizes the measurement of a TD.
+    pub fn tdx_finalize(&self, vm: &VmFd) -> io::Result<()> {
+        self.check_kind(ConfidentialVm::Tdx)?;
+        tdx_command(vm, KVM_TDX_FINALIZE_VM, 0, 0)
+    }
+
+    // Runs a SEV command, `data` pointing to the command structure.
+    fn sev_command(&self, vm: &VmFd, id: u32, data: u64) -> io::Result<()> {
+        self.check_kind(ConfidentialVm::SevSnp)?;
+        // Safe to unwrap because SEV-SNP VMs are created with the file.
+        let sev = self.sev.as_ref().unwrap();
+        let mut cmd = SevCmd {
+            id,
+            data,
+            sev_fd: sev.as_raw_fd() as u32,
+            ..Default::default()
+        };
+        // Safe because the kernel only accesses the command and the structure
+        // its data points to, which the callers keep alive.
+        check_ret(unsafe { ioctl_with_mut_ref(vm, KVM_MEMORY_ENCRYPT_OP(), &mut cmd) })?;
+
+        Ok(())
+    }
+
+    /// Initializes a SEV-SNP VM and starts its launch.
+    pub fn sev_snp_init(&self, vm: &VmFd, policy: u64) -> io::Result<()> {
+        let init = SevInit2 {
+            ghcb_version: SEV_SNP_GHCB_VERSION,
+            ..Default::default()
+        };
+        self.sev_command(vm, KVM_SEV_INIT2, &init as *const SevInit2 as u64)?;
+
+        let start = SnpLaunchStart {
+            policy,
+            ..Default::default()
+        };
+        self.sev_command(vm, KVM_SEV_SNP_LAUNCH_START, &start as *const _ as u64)
+    }
+
+    /// Adds private pages to a SEV-SNP VM being launched.
+    pub fn sev_snp_launch_update(
+        &self,
+        vm: &VmFd,
+        host_address: u64,
+        guest_address: u64,
+        size: u64,
+        page_type: SevSnpPageType,
+    ) -> io::Result<()> {
+        let mut update = SnpLaunchUpdate {
+            gfn_start: guest_address / PAGE_SIZE,
+            uaddr: host_address,
+            len: size,
+            type_: match page_type {
+                SevSnpPageType::Normal => KVM_SEV_SNP_PAGE_TYPE_NORMAL,
+                SevSnpPageType::Zero => KVM_SEV_SNP_PAGE_TYPE_ZERO,
+                SevSnpPageType::Unmeasured => KVM_SEV_SNP_PAGE_TYPE_UNMEASURED,
+                SevSnpPageType::Secrets => KVM_SEV_SNP_PAGE_TYPE_SECRETS,
+                SevSnpPageType::Cpuid => KVM_SEV_SNP_PAGE_TYPE_CPUID,
+            },
+            ..Default::default()
+        };
+
+        // The kernel moves the range forward as it adds the pages, and may
+        // stop before the end of it.
+        while update.len > 0 {
+            match self.sev_command(
+                vm,
+                KVM_SEV_SNP_LAUNCH_UPDATE,
+                &mut update as *mut SnpLaunchUpdate as u64,
+            ) {
+                Ok(()) => {}
+                Err(e) => match e.raw_os_error() {
+                    Some(libc::EAGAIN) | Some(libc::EINTR) => {}
+                    _ => return Err(e),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finishes the launch of a SEV-SNP VM.
+    pub fn sev_snp_launch_finish(&self, vm: &VmFd) -> io::Result<()> {
+        let finish = SnpLaunchFinish::default();
+        self.sev_command(vm, KVM_SEV_SNP_LAUNCH_FINISH, &finish as *const _ as u64)
+    }
+}
+
+/// Initializes a vCPU of a TD, with the address of its hand-off block.
+pub fn tdx_init_vcpu(vcpu: &VcpuFd, hob_address: u64) -> io::Result<()> {
+    tdx_command(vcpu, KVM_TDX_INIT_VCPU, 0, hob_address)
+}
+
+/// Adds private pages to a TD, through one of its vCPUs.
+pub fn tdx_init_memory_region(
+    vcpu: &VcpuFd,
+    host_address: u64,
+    guest_address: u64,
+    size: u64,
+    measure: bool,
+) -> io::Result<()> {
+    let mut region = TdxInitMemRegion {
+        source_addr: host_address,
+        gpa: guest_address,
+        nr_pages: size / PAGE_SIZE,
+    };
+    let flags = if measure {
+        KVM_TDX_MEASURE_MEMORY_REGION
+    } else {
+        0
+    };
+
+    // The kernel moves the range forward as it adds the pages, and may stop
+    // before the end of it.
+    while region.nr_pages > 0 {
+        match tdx_command(
+            vcpu,
+            KVM_TDX_INIT_MEM_REGION,
+            flags,
+            &mut region as *mut TdxInitMemRegion as u64,
+        ) {
+            Ok(()) => {}
+            Err(e) => match e.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EINTR) => {}
+                _ => return Err(e),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+// The kvm_run structure of a vCPU, mapped again to read the exit
+// information kvm-ioctls doesn't decode.
+struct KvmRun {
+    addr: *mut u8,
+}
+
+// Safe because the mapping is only accessed from the thread running the
+// vCPU, while it's out of the guest.
+unsafe impl Send for KvmRun {}
+unsafe impl Sync for KvmRun {}
+
+impl KvmRun {
+    fn new(vcpu: &VcpuFd) -> io::Result<Self> {
+        // Safe because the kernel provides the mapping, checked below, and
+        // the exit information all fits in its first page.
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                PAGE_SIZE as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vcpu.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(KvmRun {
+            addr: addr as *mut u8,
+        })
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        // Safe because the offsets are within the mapped page.
+        unsafe { read_volatile(self.addr.add(offset) as *const T) }
+    }
+
+    fn write<T: Copy>(&self, offset: usize, value: T) {
+        // Safe because the offsets are within the mapped page.
+        unsafe { write_volatile(self.addr.add(offset) as *mut T, value) }
+    }
+}
+
+impl Drop for KvmRun {
+    fn drop(&mut self) {
+        // Safe because the page was mapped by new().
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, PAGE_SIZE as usize);
+        }
+    }
+}
+
+/// A vCPU of a confidential VM, converting the guest memory as the guest
+/// asks for.
+pub struct ConfidentialVcpu {
+    vm: Arc<VmFd>,
+    run: KvmRun,
+}
+
+impl ConfidentialVcpu {
+    pub fn new(vm: Arc<VmFd>, vcpu: &VcpuFd) -> io::Result<Self> {
+        Ok(ConfidentialVcpu {
+            vm,
+            run: KvmRun::new(vcpu)?,
+        })
+    }
+
+    /// Handles a hypercall exit, the guest asking for some of its memory to
+    /// be made private or shared.
+    pub fn hypercall(&self) -> io::Result<VmExit> {
+        let nr: u64 = self.run.read(KVM_RUN_HYPERCALL_NR);
+        if nr != KVM_HC_MAP_GPA_RANGE {
+            return Ok(VmExit::Unhandled(format!("Unexpected hypercall {}", nr)));
+        }
+
+        let arg = |i| -> u64 { self.run.read(KVM_RUN_HYPERCALL_ARGS + i * 8) };
+        let (address, pages, attributes) = (arg(0), arg(1), arg(2));
+        set_memory_attributes(
+            &self.vm,
+            address,
+            pages * PAGE_SIZE,
+            attributes & KVM_MAP_GPA_RANGE_ENCRYPTED != 0,
+        )?;
+        self.run.write(KVM_RUN_HYPERCALL_RET, 0u64);
+
+        Ok(VmExit::Ignore)
+    }
+
+    /// Handles a memory fault, the guest accessing some of its memory as
+    /// private while it's shared, or the other way around. Returns whether
+    /// the vCPU failed for this reason.
+    pub fn memory_fault(&self, error: &io::Error) -> io::Result<bool> {
+        let exit_reason: u32 = self.run.read(KVM_RUN_EXIT_REASON);
+        if error.raw_os_error() != Some(libc::EFAULT) || exit_reason != KVM_EXIT_MEMORY_FAULT {
+            return Ok(false);
+        }
+
+        let flags: u64 = self.run.read(KVM_RUN_MEMORY_FAULT_FLAGS);
+        set_memory_attributes(
+            &self.vm,
+            self.run.read(KVM_RUN_MEMORY_FAULT_GPA),
+            self.run.read(KVM_RUN_MEMORY_FAULT_SIZE),
+            flags & KVM_MEMORY_EXIT_FLAG_PRIVATE != 0,
+        )?;
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,746 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! KVM backend.
+
+#[cfg(target_arch = "x86_64")]
+mod confidential;
+
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    kvm_clock_data, kvm_guest_debug, kvm_msr_entry, kvm_msrs, kvm_pit_config, kvm_translation,
+    KVM_CAP_HYPERV_SYNIC, KVM_CAP_SPLIT_IRQCHIP, KVM_PIT_SPEAKER_DUMMY,
+};
+use kvm_bindings::{
+    kvm_create_device, kvm_device_type_KVM_DEV_TYPE_VFIO, kvm_dirty_log, kvm_enable_cap,
+    kvm_irq_routing, kvm_irq_routing_entry, KVMIO,
+};
+#[cfg(target_arch = "aarch64")]
+use kvm_bindings::{
+    kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+};
+#[cfg(target_arch = "x86_64")]
+use kvm_ioctls::MAX_KVM_CPUID_ENTRIES;
+use kvm_ioctls::{
+    Cap, DeviceFd, IoEventAddress as KvmIoEventAddress, Kvm, NoDatamatch, VcpuExit, VcpuFd, VmFd,
+};
+use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::ioctl::ioctl_with_ref;
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref};
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::{GicDevice, VcpuInit};
+use crate::cpu::{Vcpu, VmExit};
+use crate::device::{Device, DeviceAttr};
+use crate::hypervisor::{Capability, Hypervisor};
+use crate::vec_with_array_field;
+use crate::vm::{
+    DataMatch, HaltPollStats, IoEventAddress, IrqRoutingEntry, MsiMessage, UserMemoryRegion, Vm,
+    VmmOps,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::{
+    ConfidentialVm, CpuId, FpuState, LapicState, MsrEntry, SevSnpPageType, SpecialRegisters,
+    StandardRegisters,
+};
+#[cfg(target_arch = "x86_64")]
+use confidential::{ConfidentialState, ConfidentialVcpu};
+
+// kvm-ioctls can only enable capabilities on the VM file descriptor of x86
+// hosts, while the SynIC is enabled per vCPU, and the halt polling on all
+// the architectures.
+ioctl_iow_nr!(KVM_ENABLE_CAP, KVMIO, 0xa3, kvm_enable_cap);
+// Nor does it read the dirty log of a memory slot.
+ioctl_iow_nr!(KVM_GET_DIRTY_LOG, KVMIO, 0x42, kvm_dirty_log);
+// Nor does it set the guest debug state, translate addresses, read the
+// kvmclock nor tell the guest its vCPUs were stopped.
+#[cfg(target_arch = "x86_64")]
+ioctl_iow_nr!(KVM_SET_GUEST_DEBUG, KVMIO, 0x9b, kvm_guest_debug);
+#[cfg(target_arch = "x86_64")]
+ioctl_iowr_nr!(KVM_TRANSLATE, KVMIO, 0x85, kvm_translation);
+#[cfg(target_arch = "x86_64")]
+ioctl_ior_nr!(KVM_GET_CLOCK, KVMIO, 0x7c, kvm_clock_data);
+#[cfg(target_arch = "x86_64")]
+ioctl_io_nr!(KVM_KVMCLOCK_CTRL, KVMIO, 0xad);
+
+// From <linux/kvm.h> and <asm/kvm.h>
+#[cfg(target_arch = "x86_64")]
+const KVM_GUESTDBG_ENABLE: u32 = 0x1;
+#[cfg(target_arch = "x86_64")]
+const KVM_GUESTDBG_SINGLESTEP: u32 = 0x2;
+#[cfg(target_arch = "x86_64")]
+const KVM_GUESTDBG_USE_HW_BP: u32 = 0x2_0000;
+// Hardware breakpoints the debug registers hold.
+#[cfg(target_arch = "x86_64")]
+const MAX_HW_BREAKPOINTS: usize = 4;
+// From <linux/kvm.h>, the guest instructions that no longer exit.
+#[cfg(target_arch = "x86_64")]
+const KVM_CAP_X86_DISABLE_EXITS: u32 = 143;
+#[cfg(target_arch = "x86_64")]
+const KVM_X86_DISABLE_EXITS_HLT: u64 = 1 << 1;
+#[cfg(target_arch = "x86_64")]
+const KVM_X86_DISABLE_EXITS_PAUSE: u64 = 1 << 2;
+// From <asm/prctl.h> and <asm/fpu/types.h>, the permission of the guests of
+// the process to use dynamically enabled XSAVE state components.
+#[cfg(target_arch = "x86_64")]
+const ARCH_GET_XCOMP_GUEST_PERM: libc::c_int = 0x1024;
+#[cfg(target_arch = "x86_64")]
+const ARCH_REQ_XCOMP_GUEST_PERM: libc::c_int = 0x1025;
+#[cfg(target_arch = "x86_64")]
+const XFEATURE_XTILEDATA: u64 = 18;
+// From <linux/kvm.h>, the per VM halt polling from Linux 5.9.
+const KVM_CAP_HALT_POLL: u32 = 182;
+// Statistics KVM keeps for each VM, in directories named after the process
+// and the VM file descriptor.
+const KVM_DEBUGFS_PATH: &str = "/sys/kernel/debug/kvm";
+
+// The kvm_dirty_log structure, the bitmap address being a union member
+// of it.
+#[repr(C)]
+struct KvmDirtyLog {
+    slot: u32,
+    padding: u32,
+    dirty_bitmap: u64,
+}
+
+/// KVM, through /dev/kvm.
+pub struct KvmHypervisor {
+    kvm: Kvm,
+}
+
+impl KvmHypervisor {
+    pub fn new() -> io::Result<Self> {
+        Ok(KvmHypervisor { kvm: Kvm::new()? })
+    }
+
+    /// Checks whether the host runs KVM.
+    pub fn is_available() -> bool {
+        Path::new("/dev/kvm").exists()
+    }
+}
+
+impl Hypervisor for KvmHypervisor {
+    fn create_vm(&self) -> io::Result<Arc<dyn Vm>> {
+        let fd = self.kvm.create_vm()?;
+        Ok(Arc::new(KvmVm {
+            fd: Arc::new(fd),
+            #[cfg(target_arch = "x86_64")]
+            confidential: None,
+        }))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn create_confidential_vm(&self, kind: ConfidentialVm) -> io::Result<Arc<dyn Vm>> {
+        let (fd, confidential) = ConfidentialState::create_vm(&self.kvm, kind)?;
+        Ok(Arc::new(KvmVm {
+            fd: Arc::new(fd),
+            confidential: Some(confidential),
+        }))
+    }
+
+    fn get_max_memory_slots(&self) -> u32 {
+        self.kvm.get_nr_memslots() as u32
+    }
+
+    fn check_capability(&self, cap: Capability) -> bool {
+        let cap = match cap {
+            Capability::SignalMsi => Cap::SignalMsi,
+            #[cfg(target_arch = "x86_64")]
+            Capability::SplitIrqchip => Cap::SplitIrqchip,
+            #[cfg(target_arch = "x86_64")]
+            Capability::TscDeadlineTimer => Cap::TscDeadlineTimer,
+        };
+        self.kvm.check_extension(cap)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_cpuid(&self) -> io::Result<CpuId> {
+        self.kvm.get_supported_cpuid(MAX_KVM_CPUID_ENTRIES)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn enable_amx_state_components(&self) -> io::Result<()> {
+        // The permission is given to the whole process, for the guest state
+        // of its vCPUs.
+        // Safe because the syscall only reads its arguments.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_arch_prctl,
+                ARCH_REQ_XCOMP_GUEST_PERM,
+                XFEATURE_XTILEDATA,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut permitted: u64 = 0;
+        // Safe because the kernel writes the permitted state components to
+        // a valid u64.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_arch_prctl,
+                ARCH_GET_XCOMP_GUEST_PERM,
+                &mut permitted as *mut u64,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if permitted & (1 << XFEATURE_XTILEDATA) == 0 {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        Ok(())
+    }
+}
+
+/// A KVM VM.
+pub struct KvmVm {
+    // Shared with the vCPUs of confidential VMs, which convert the guest
+    // memory from their threads.
+    fd: Arc<VmFd>,
+    #[cfg(target_arch = "x86_64")]
+    confidential: Option<ConfidentialState>,
+}
+
+impl KvmVm {
+    fn create_device(&self, type_: u32) -> io::Result<Arc<dyn Device>> {
+        let mut device = kvm_create_device {
+            type_,
+            fd: 0,
+            flags: 0,
+        };
+
+        let fd = self.fd.create_device(&mut device)?;
+        Ok(Arc::new(KvmDevice { fd }))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn confidential(&self) -> io::Result<&ConfidentialState> {
+        self.confidential
+            .as_ref()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))
+    }
+}
+
+impl Vm for KvmVm {
+    #[cfg(target_arch = "x86_64")]
+    fn set_tss_address(&self, offset: usize) -> io::Result<()> {
+        self.fd.set_tss_address(offset)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn create_irq_chip(&self) -> io::Result<()> {
+        self.fd.create_irq_chip()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn enable_split_irq(&self, ioapic_pins: u32) -> io::Result<()> {
+        let mut cap: kvm_enable_cap = Default::default();
+        cap.cap = KVM_CAP_SPLIT_IRQCHIP;
+        cap.args[0] = u64::from(ioapic_pins);
+        self.fd.enable_cap(&cap)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn create_pit(&self) -> io::Result<()> {
+        let mut pit_config = kvm_pit_config::default();
+        // We need to enable the emulation of a dummy speaker port stub so that writing to port 0x61
+        // (i.e. KVM_SPEAKER_BASE_ADDRESS) does not trigger an exit to user space.
+        pit_config.flags = KVM_PIT_SPEAKER_DUMMY;
+        self.fd.create_pit2(pit_config)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn disable_idle_exits(&self) -> io::Result<()> {
+        let mut cap: kvm_enable_cap = Default::default();
+        cap.cap = KVM_CAP_X86_DISABLE_EXITS;
+        cap.args[0] = KVM_X86_DISABLE_EXITS_HLT | KVM_X86_DISABLE_EXITS_PAUSE;
+        self.fd.enable_cap(&cap)
+    }
+
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> io::Result<()> {
+        self.fd.register_irqfd(fd, gsi)
+    }
+
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> io::Result<()> {
+        self.fd.unregister_irqfd(fd, gsi)
+    }
+
+    fn set_gsi_routing(&self, entries: &[IrqRoutingEntry]) -> io::Result<()> {
+        let mut irq_routing =
+            vec_with_array_field::<kvm_irq_routing, kvm_irq_routing_entry>(entries.len());
+        irq_routing[0].nr = entries.len() as u32;
+        irq_routing[0].flags = 0;
+
+        // Safe because the vector was allocated with room for all entries.
+        unsafe {
+            let routing_entries: &mut [kvm_irq_routing_entry] =
+                irq_routing[0].entries.as_mut_slice(entries.len());
+            routing_entries.copy_from_slice(entries);
+        }
+
+        self.fd.set_gsi_routing(&irq_routing[0])
+    }
+
+    fn signal_msi(&self, msi: MsiMessage) -> io::Result<bool> {
+        self.fd.signal_msi(msi).map(|ret| ret > 0)
+    }
+
+    fn register_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Option<DataMatch>,
+    ) -> io::Result<()> {
+        let addr = kvm_ioevent_address(addr);
+        match datamatch {
+            Some(DataMatch::DataMatch32(value)) => self.fd.register_ioevent(fd, &addr, value),
+            Some(DataMatch::DataMatch64(value)) => self.fd.register_ioevent(fd, &addr, value),
+            None => self.fd.register_ioevent(fd, &addr, NoDatamatch),
+        }
+    }
+
+    fn unregister_ioevent(&self, fd: &EventFd, addr: &IoEventAddress) -> io::Result<()> {
+        self.fd.unregister_ioevent(fd, &kvm_ioevent_address(addr))
+    }
+
+    unsafe fn set_user_memory_region(&self, region: UserMemoryRegion) -> io::Result<()> {
+        self.fd.set_user_memory_region(region)
+    }
+
+    fn get_dirty_log(&self, slot: u32, size: u64) -> io::Result<Vec<u64>> {
+        // A bit for each host page, in 64-bit words.
+        // Safe because sysconf() only reads a system constant.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let pages = (size + page_size - 1) / page_size;
+        let mut bitmap = vec![0u64; ((pages + 63) / 64) as usize];
+        let dirty_log = KvmDirtyLog {
+            slot,
+            padding: 0,
+            dirty_bitmap: bitmap.as_mut_ptr() as u64,
+        };
+        // Safe because the VM file descriptor is valid, and the kernel only
+        // writes the bitmap, sized for the pages of the slot.
+        let ret = unsafe { ioctl_with_ref(&*self.fd, KVM_GET_DIRTY_LOG(), &dirty_log) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(bitmap)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn set_private_memory_region(&self, region: UserMemoryRegion) -> io::Result<()> {
+        self.confidential()?
+            .set_private_memory_region(&self.fd, region)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn tdx_init(&self, cpuid: &CpuId) -> io::Result<()> {
+        self.confidential()?.tdx_init(&self.fd, cpuid)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn tdx_finalize(&self) -> io::Result<()> {
+        self.confidential()?.tdx_finalize(&self.fd)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn sev_snp_init(&self, policy: u64) -> io::Result<()> {
+        self.confidential()?.sev_snp_init(&self.fd, policy)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn sev_snp_launch_update(
+        &self,
+        host_address: u64,
+        guest_address: u64,
+        size: u64,
+        page_type: SevSnpPageType,
+    ) -> io::Result<()> {
+        self.confidential()?.sev_snp_launch_update(
+            &self.fd,
+            host_address,
+            guest_address,
+            size,
+            page_type,
+        )
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn sev_snp_launch_finish(&self) -> io::Result<()> {
+        self.confidential()?.sev_snp_launch_finish(&self.fd)
+    }
+
+    fn create_vcpu(&self, id: u8, vmm_ops: Option<Arc<dyn VmmOps>>) -> io::Result<Arc<dyn Vcpu>> {
+        let fd = self.fd.create_vcpu(id)?;
+        #[cfg(target_arch = "x86_64")]
+        let confidential = match self.confidential {
+            Some(_) => Some(ConfidentialVcpu::new(self.fd.clone(), &fd)?),
+            None => None,
+        };
+        Ok(Arc::new(KvmVcpu {
+            fd,
+            vmm_ops,
+            #[cfg(target_arch = "x86_64")]
+            confidential,
+        }))
+    }
+
+    fn create_passthrough_device(&self) -> io::Result<Arc<dyn Device>> {
+        self.create_device(kvm_device_type_KVM_DEV_TYPE_VFIO)
+    }
+
+    fn set_halt_poll_ns(&self, ns: u32) -> io::Result<()> {
+        let mut cap: kvm_enable_cap = Default::default();
+        cap.cap = KVM_CAP_HALT_POLL;
+        cap.args[0] = u64::from(ns);
+        // Safe because the VM file descriptor is valid, and the kernel only
+        // reads the capability structure.
+        let ret = unsafe { ioctl_with_ref(&*self.fd, KVM_ENABLE_CAP(), &cap) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn halt_poll_stats(&self) -> io::Result<HaltPollStats> {
+        let dir = format!(
+            "{}/{}-{}",
+            KVM_DEBUGFS_PATH,
+            process::id(),
+            self.fd.as_raw_fd()
+        );
+        let read_stat = |name: &str| -> io::Result<u64> {
+            fs::read_to_string(format!("{}/{}", dir, name))?
+                .trim()
+                .parse()
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+        };
+
+        Ok(HaltPollStats {
+            attempted: read_stat("halt_attempted_poll")?,
+            successful: read_stat("halt_successful_poll")?,
+        })
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_clock(&self) -> io::Result<u64> {
+        let mut clock: kvm_clock_data = Default::default();
+        // Safe because the VM file descriptor is valid, and the kernel only
+        // writes the clock structure.
+        let ret = unsafe { ioctl_with_mut_ref(&*self.fd, KVM_GET_CLOCK(), &mut clock) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(clock.clock)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn get_preferred_target(&self, kvi: &mut VcpuInit) -> io::Result<()> {
+        self.fd.get_preferred_target(kvi)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn create_gic_device(&self, device: GicDevice) -> io::Result<Arc<dyn Device>> {
+        self.create_device(match device {
+            GicDevice::V3 => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+            GicDevice::Its => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+        })
+    }
+}
+
+fn kvm_ioevent_address(addr: &IoEventAddress) -> KvmIoEventAddress {
+    match *addr {
+        IoEventAddress::Pio(addr) => KvmIoEventAddress::Pio(addr),
+        IoEventAddress::Mmio(addr) => KvmIoEventAddress::Mmio(addr),
+    }
+}
+
+/// A KVM vCPU.
+pub struct KvmVcpu {
+    fd: VcpuFd,
+    vmm_ops: Option<Arc<dyn VmmOps>>,
+    #[cfg(target_arch = "x86_64")]
+    confidential: Option<ConfidentialVcpu>,
+}
+
+impl Vcpu for KvmVcpu {
+    fn run(&self) -> io::Result<VmExit> {
+        let exit = match self.fd.run() {
+            Ok(exit) => exit,
+            // Guest accesses to memory it didn't convert fail the run.
+            #[cfg(target_arch = "x86_64")]
+            Err(e) => match &self.confidential {
+                Some(confidential) if confidential.memory_fault(&e)? => return Ok(VmExit::Ignore),
+                _ => return Err(e),
+            },
+            #[cfg(target_arch = "aarch64")]
+            Err(e) => return Err(e),
+        };
+
+        match exit {
+            VcpuExit::IoIn(addr, data) => {
+                if let Some(vmm_ops) = &self.vmm_ops {
+                    vmm_ops.pio_read(u64::from(addr), data);
+                }
+                Ok(VmExit::Ignore)
+            }
+            VcpuExit::IoOut(addr, data) => {
+                if let Some(vmm_ops) = &self.vmm_ops {
+                    vmm_ops.pio_write(u64::from(addr), data);
+                }
+                Ok(VmExit::Ignore)
+            }
+            VcpuExit::MmioRead(addr, data) => {
+                if let Some(vmm_ops) = &self.vmm_ops {
+                    vmm_ops.mmio_read(addr, data);
+                }
+                Ok(VmExit::Ignore)
+            }
+            VcpuExit::MmioWrite(addr, data) => {
+                if let Some(vmm_ops) = &self.vmm_ops {
+                    vmm_ops.mmio_write(addr, data);
+                }
+                Ok(VmExit::Ignore)
+            }
+            VcpuExit::IoapicEoi(vector) => Ok(VmExit::IoapicEoi(vector)),
+            VcpuExit::Shutdown => Ok(VmExit::Shutdown),
+            VcpuExit::Debug => Ok(VmExit::Debug),
+            #[cfg(target_arch = "x86_64")]
+            VcpuExit::Hypercall if self.confidential.is_some() => {
+                // Safe to unwrap because of the match guard.
+                self.confidential.as_ref().unwrap().hypercall()
+            }
+            // PSCI SYSTEM_OFF and SYSTEM_RESET calls, which kvm-ioctls does
+            // not tell apart. Both reset the VM, as a triple fault does.
+            #[cfg(target_arch = "aarch64")]
+            VcpuExit::SystemEvent => Ok(VmExit::Shutdown),
+            r => Ok(VmExit::Unhandled(format!(
+                "Unexpected exit reason on vcpu run: {:?}",
+                r
+            ))),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_regs(&self) -> io::Result<StandardRegisters> {
+        self.fd.get_regs()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_regs(&self, regs: &StandardRegisters) -> io::Result<()> {
+        self.fd.set_regs(regs)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_sregs(&self) -> io::Result<SpecialRegisters> {
+        self.fd.get_sregs()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> io::Result<()> {
+        self.fd.set_sregs(sregs)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_fpu(&self) -> io::Result<FpuState> {
+        self.fd.get_fpu()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_fpu(&self, fpu: &FpuState) -> io::Result<()> {
+        self.fd.set_fpu(fpu)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_lapic(&self) -> io::Result<LapicState> {
+        self.fd.get_lapic()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_lapic(&self, lapic: &LapicState) -> io::Result<()> {
+        self.fd.set_lapic(lapic)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn get_msrs(&self, msrs: &mut [MsrEntry]) -> io::Result<usize> {
+        let mut kvm_msrs = vec_with_array_field::<kvm_msrs, kvm_msr_entry>(msrs.len());
+        kvm_msrs[0].nmsrs = msrs.len() as u32;
+
+        // Safe because the vector was allocated with room for all entries.
+        unsafe {
+            kvm_msrs[0]
+                .entries
+                .as_mut_slice(msrs.len())
+                .copy_from_slice(msrs);
+        }
+
+        let count = self.fd.get_msrs(&mut kvm_msrs[0])? as usize;
+
+        // Safe for the same reason.
+        unsafe {
+            msrs.copy_from_slice(kvm_msrs[0].entries.as_slice(msrs.len()));
+        }
+
+        Ok(count)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_msrs(&self, msrs: &[MsrEntry]) -> io::Result<()> {
+        let mut kvm_msrs = vec_with_array_field::<kvm_msrs, kvm_msr_entry>(msrs.len());
+        kvm_msrs[0].nmsrs = msrs.len() as u32;
+
+        // Safe because the vector was allocated with room for all entries.
+        unsafe {
+            kvm_msrs[0]
+                .entries
+                .as_mut_slice(msrs.len())
+                .copy_from_slice(msrs);
+        }
+
+        self.fd.set_msrs(&kvm_msrs[0])?;
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_cpuid2(&self, cpuid: &CpuId) -> io::Result<()> {
+        self.fd.set_cpuid2(cpuid)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn enable_hyperv_synic(&self) -> io::Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_HYPERV_SYNIC,
+            ..Default::default()
+        };
+        // Safe because the vCPU file descriptor is valid, and the kernel
+        // only reads the capability structure.
+        let ret = unsafe { ioctl_with_ref(&self.fd, KVM_ENABLE_CAP(), &cap) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn kvmclock_paused(&self) -> io::Result<()> {
+        // Safe because the vCPU file descriptor is valid, and the ioctl
+        // takes no argument.
+        let ret = unsafe { ioctl(&self.fd, KVM_KVMCLOCK_CTRL()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn tdx_init(&self, hob_address: u64) -> io::Result<()> {
+        confidential::tdx_init_vcpu(&self.fd, hob_address)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn set_guest_debug(&self, breakpoints: &[u64], single_step: bool) -> io::Result<()> {
+        if breakpoints.len() > MAX_HW_BREAKPOINTS {
+            return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+        }
+
+        let mut debug = kvm_guest_debug::default();
+        if !breakpoints.is_empty() || single_step {
+            debug.control = KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_HW_BP;
+        }
+        if single_step {
+            debug.control |= KVM_GUESTDBG_SINGLESTEP;
+        }
+        // DR7 enables the execution breakpoints of DR0 to DR3 globally, its
+        // bit 10 being always set, and GE (bit 9) recommended.
+        let mut dr7 = 0x600;
+        for (i, &breakpoint) in breakpoints.iter().enumerate() {
+            debug.arch.debugreg[i] = breakpoint;
+            dr7 |= 1 << (i * 2 + 1);
+        }
+        debug.arch.debugreg[7] = dr7;
+
+        // Safe because the vCPU file descriptor is valid, and the kernel
+        // only reads the debug structure.
+        let ret = unsafe { ioctl_with_ref(&self.fd, KVM_SET_GUEST_DEBUG(), &debug) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn translate_gva(&self, gva: u64) -> io::Result<Option<u64>> {
+        let mut translation = kvm_translation {
+            linear_address: gva,
+            ..Default::default()
+        };
+        // Safe because the vCPU file descriptor is valid, and the kernel
+        // fills the translation structure it is given.
+        let ret = unsafe { ioctl_with_mut_ref(&self.fd, KVM_TRANSLATE(), &mut translation) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if translation.valid == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(translation.physical_address))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn tdx_init_memory_region(
+        &self,
+        host_address: u64,
+        guest_address: u64,
+        size: u64,
+        measure: bool,
+    ) -> io::Result<()> {
+        confidential::tdx_init_memory_region(&self.fd, host_address, guest_address, size, measure)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn vcpu_init(&self, kvi: &VcpuInit) -> io::Result<()> {
+        self.fd.vcpu_init(kvi)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn set_reg(&self, reg_id: u64, data: u64) -> io::Result<()> {
+        self.fd.set_one_reg(reg_id, data)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn get_reg(&self, reg_id: u64) -> io::Result<u64> {
+        self.fd.get_one_reg(reg_id)
+    }
+}
+
+/// A KVM emulated device.
+pub struct KvmDevice {
+    fd: DeviceFd,
+}
+
+impl Device for KvmDevice {
+    fn set_device_attr(&self, attr: &DeviceAttr) -> io::Result<()> {
+        self.fd.set_device_attr(attr)
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Hypervisor abstraction.
+//!
+//! The VMM drives its VMs and vCPUs through the `Hypervisor`, `Vm` and
+//! `Vcpu` traits, instead of calling into a given hypervisor directly. Those
+//! are implemented on top of KVM, and of the Microsoft Hypervisor (MSHV).
+//! Both backends can be built in, the one to use being picked at runtime
+//! depending on the host. MSHV is only supported on x86_64.
+//!
+//! The interfaces reuse the KVM structure layouts, which the other backends
+//! convert from and to.
+
+#[macro_use]
+extern crate vmm_sys_util;
+
+#[cfg(target_arch = "aarch64")]
+pub mod aarch64;
+mod cpu;
+mod device;
+mod hypervisor;
+#[cfg(feature = "kvm")]
+pub mod kvm;
+#[cfg(all(feature = "mshv", target_arch = "x86_64"))]
+pub mod mshv;
+mod vm;
+#[cfg(target_arch = "x86_64")]
+pub mod x86_64;
+
+use std::io;
+use std::mem::size_of;
+use std::sync::Arc;
+
+pub use crate::cpu::{Vcpu, VmExit};
+pub use crate::device::{Device, DeviceAttr};
+pub use crate::hypervisor::{Capability, Hypervisor};
+pub use crate::vm::{
+    DataMatch, HaltPollStats, IoEventAddress, IrqRoutingEntry, MsiMessage, UserMemoryRegion, Vm,
+    VmmOps, KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI, KVM_MEM_LOG_DIRTY_PAGES,
+};
+
+/// Creates the hypervisor to run VMs with, KVM being preferred over MSHV
+/// when both are built in and available on the host.
+pub fn new() -> io::Result<Arc<dyn Hypervisor>> {
+    #[cfg(feature = "kvm")]
+    {
+        if kvm::KvmHypervisor::is_available() {
+            return Ok(Arc::new(kvm::KvmHypervisor::new()?));
+        }
+    }
+
+    #[cfg(all(feature = "mshv", target_arch = "x86_64"))]
+    {
+        if mshv::MshvHypervisor::is_available() {
+            return Ok(Arc::new(mshv::MshvHypervisor::new()?));
+        }
+    }
+
+    Err(io::Error::from_raw_os_error(libc::ENODEV))
+}
+
+// Returns a `Vec<T>` with a size in bytes at least as large as `size_in_bytes`.
+fn vec_with_size_in_bytes<T: Default>(size_in_bytes: usize) -> Vec<T> {
+    let rounded_size = (size_in_bytes + size_of::<T>() - 1) / size_of::<T>();
+    let mut v = Vec::with_capacity(rounded_size);
+    for _ in 0..rounded_size {
+        v.push(T::default())
+    }
+    v
+}
+
+// Makes a `Vec<T>` large enough for a `T` followed by `count` trailing
+// entries of type `F`, for the hypervisor structures ending with a flexible
+// array member. Only the first element of the returned vector is meant to
+// be used, as a `T`.
+fn vec_with_array_field<T: Default, F>(count: usize) -> Vec<T> {
+    let element_space = count * size_of::<F>();
+    let vec_size_bytes = size_of::<T>() + element_space;
+    vec_with_size_in_bytes(vec_size_bytes)
+}
@@ -0,0 +1,306 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Emulation of the guest instructions accessing MMIO regions.
+//!
+//! MSHV reports such accesses with the faulting instruction bytes, instead
+//! of decoding them itself. Only the `mov` family of instructions compilers
+//! emit for MMIO accessors is supported.
+
+use std::fmt::{self, Display};
+use std::result;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The instruction bytes end in the middle of an instruction.
+    Truncated,
+    /// The instruction does not access memory through a `mov`.
+    UnsupportedOpcode(u8),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            Truncated => write!(f, "truncated MMIO instruction"),
+            UnsupportedOpcode(opcode) => {
+                write!(f, "unsupported MMIO instruction opcode 0x{:x}", opcode)
+            }
+        }
+    }
+}
+
+// General purpose registers, in their instruction encoding order.
+pub const RAX: usize = 0;
+pub const RCX: usize = 1;
+pub const RDX: usize = 2;
+pub const RBX: usize = 3;
+pub const RSP: usize = 4;
+pub const RBP: usize = 5;
+pub const RSI: usize = 6;
+pub const RDI: usize = 7;
+
+const OPERAND_SIZE_PREFIX: u8 = 0x66;
+const REX_W: u8 = 0x8;
+const REX_R: u8 = 0x4;
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn peek(&self) -> Result<u8> {
+        self.bytes.get(self.pos).cloned().ok_or(Error::Truncated)
+    }
+
+    fn next(&mut self) -> Result<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn immediate(&mut self, size: usize) -> Result<u64> {
+        if self.pos + size > self.bytes.len() {
+            return Err(Error::Truncated);
+        }
+        let mut value = 0;
+        for (i, byte) in self.bytes[self.pos..self.pos + size].iter().enumerate() {
+            value |= u64::from(*byte) << (i * 8);
+        }
+        self.pos += size;
+        Ok(value)
+    }
+
+    // Skips the memory operand, returning the `reg` field of the ModR/M byte.
+    fn modrm(&mut self) -> Result<usize> {
+        let modrm = self.next()?;
+        let mode = modrm >> 6;
+        let rm = modrm & 0x7;
+
+        let mut displacement = match mode {
+            1 => 1,
+            2 => 4,
+            _ => 0,
+        };
+        if rm == 4 && mode != 3 {
+            let sib = self.next()?;
+            if sib & 0x7 == 5 && mode == 0 {
+                displacement = 4;
+            }
+        } else if rm == 5 && mode == 0 {
+            // RIP relative
+            displacement = 4;
+        }
+        self.immediate(displacement)?;
+
+        Ok(usize::from((modrm >> 3) & 0x7))
+    }
+}
+
+fn mask(size: usize) -> u64 {
+    if size >= 8 {
+        !0
+    } else {
+        (1 << (size * 8)) - 1
+    }
+}
+
+fn read_reg(gprs: &[u64; 16], reg: usize, size: usize, rex: bool) -> u64 {
+    // Without a REX prefix, the byte registers 4 to 7 are AH, CH, DH and BH.
+    if size == 1 && !rex && (RSP..=RDI).contains(&reg) {
+        (gprs[reg - RSP] >> 8) & 0xff
+    } else {
+        gprs[reg] & mask(size)
+    }
+}
+
+fn write_reg(gprs: &mut [u64; 16], reg: usize, size: usize, rex: bool, value: u64) {
+    if size == 1 && !rex && (RSP..=RDI).contains(&reg) {
+        let gpr = &mut gprs[reg - RSP];
+        *gpr = (*gpr & !0xff00) | (value & 0xff) << 8;
+    } else if size == 4 {
+        // 32-bit writes clear the upper half of the register.
+        gprs[reg] = value & mask(4);
+    } else {
+        gprs[reg] = (gprs[reg] & !mask(size)) | (value & mask(size));
+    }
+}
+
+/// Emulates the MMIO access of the instruction starting `bytes`, through
+/// `read` and `write`, and updates the general purpose registers `gprs`
+/// accordingly. Returns the instruction length, for the caller to move the
+/// instruction pointer past it.
+pub fn emulate<R, W>(bytes: &[u8], gprs: &mut [u64; 16], read: R, write: W) -> Result<usize>
+where
+    R: FnOnce(&mut [u8]),
+    W: FnOnce(&[u8]),
+{
+    let mut decoder = Decoder { bytes, pos: 0 };
+
+    let mut operand_size = 4;
+    loop {
+        match decoder.peek()? {
+            OPERAND_SIZE_PREFIX => operand_size = 2,
+            // Address size, segment override, lock and repeat prefixes,
+            // which don't matter for a single memory access.
+            0x67 | 0x2e | 0x36 | 0x3e | 0x26 | 0x64 | 0x65 | 0xf0 | 0xf2 | 0xf3 => {}
+            _ => break,
+        }
+        decoder.next()?;
+    }
+
+    let mut rex = None;
+    if decoder.peek()? & 0xf0 == 0x40 {
+        let prefix = decoder.next()?;
+        if prefix & REX_W != 0 {
+            operand_size = 8;
+        }
+        rex = Some(prefix);
+    }
+    let rex_r = rex.map_or(0, |prefix| if prefix & REX_R != 0 { 8 } else { 0 });
+
+    let opcode = decoder.next()?;
+    match opcode {
+        // mov r/m, r
+        0x88 | 0x89 => {
+            let size = if opcode == 0x88 { 1 } else { operand_size };
+            let reg = decoder.modrm()? + rex_r;
+            let value = read_reg(gprs, reg, size, rex.is_some());
+            write(&value.to_le_bytes()[..size]);
+        }
+        // mov r, r/m
+        0x8a | 0x8b => {
+            let size = if opcode == 0x8a { 1 } else { operand_size };
+            let reg = decoder.modrm()? + rex_r;
+            let mut data = [0u8; 8];
+            read(&mut data[..size]);
+            write_reg(gprs, reg, size, rex.is_some(), u64::from_le_bytes(data));
+        }
+        // mov r/m, imm
+        0xc6 | 0xc7 => {
+            let size = if opcode == 0xc6 { 1 } else { operand_size };
+            decoder.modrm()?;
+            // 64-bit stores take a sign extended 32-bit immediate.
+            let mut value = decoder.immediate(std::cmp::min(size, 4))?;
+            if size == 8 {
+                value = value as i32 as i64 as u64;
+            }
+            write(&value.to_le_bytes()[..size]);
+        }
+        // movzx r, r/m8 and movzx r, r/m16
+        0x0f => {
+            let opcode = decoder.next()?;
+            let size = match opcode {
+                0xb6 => 1,
+                0xb7 => 2,
+                _ => return Err(Error::UnsupportedOpcode(opcode)),
+            };
+            let reg = decoder.modrm()? + rex_r;
+            let mut data = [0u8; 8];
+            read(&mut data[..size]);
+            write_reg(
+                gprs,
+                reg,
+                operand_size,
+                rex.is_some(),
+                u64::from_le_bytes(data),
+            );
+        }
+        _ => return Err(Error::UnsupportedOpcode(opcode)),
+    }
+
+    Ok(decoder.pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(bytes: &[u8], gprs: &mut [u64; 16], mmio: &mut [u8; 8]) -> Result<usize> {
+        let loaded = *mmio;
+        let mut stored = None;
+        let len = emulate(
+            bytes,
+            gprs,
+            |data| data.copy_from_slice(&loaded[..data.len()]),
+            |data| stored = Some(data.to_vec()),
+        )?;
+        if let Some(stored) = stored {
+            mmio[..stored.len()].copy_from_slice(&stored);
+        }
+        Ok(len)
+    }
+
+    #[test]
+    fn test_mov_store() {
+        let mut gprs = [0u64; 16];
+        let mut mmio = [0u8; 8];
+
+        // mov %eax,(%rbx)
+        gprs[RAX] = 0x1122_3344_5566_7788;
+        assert_eq!(run(&[0x89, 0x03], &mut gprs, &mut mmio), Ok(2));
+        assert_eq!(mmio, [0x88, 0x77, 0x66, 0x55, 0, 0, 0, 0]);
+
+        // mov %ah,0x10(%rbx)
+        assert_eq!(run(&[0x88, 0x63, 0x10], &mut gprs, &mut mmio), Ok(3));
+        assert_eq!(mmio[0], 0x77);
+
+        // mov %r9,0x12345678(%rip)
+        gprs[9] = 0x0102_0304_0506_0708;
+        let bytes = [0x4c, 0x89, 0x0d, 0x78, 0x56, 0x34, 0x12];
+        assert_eq!(run(&bytes, &mut gprs, &mut mmio), Ok(7));
+        assert_eq!(mmio, [8, 7, 6, 5, 4, 3, 2, 1]);
+
+        // movw $0xbeef,(%rax,%rcx,4)
+        let bytes = [0x66, 0xc7, 0x04, 0x88, 0xef, 0xbe];
+        assert_eq!(run(&bytes, &mut gprs, &mut mmio), Ok(6));
+        assert_eq!(&mmio[..2], &[0xef, 0xbe]);
+
+        // movq $-1,(%rdi)
+        let bytes = [0x48, 0xc7, 0x07, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(run(&bytes, &mut gprs, &mut mmio), Ok(7));
+        assert_eq!(mmio, [0xff; 8]);
+    }
+
+    #[test]
+    fn test_mov_load() {
+        let mut gprs = [!0u64; 16];
+        let mut mmio = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+
+        // mov (%rsi),%edx zero extends to 64 bits
+        assert_eq!(run(&[0x8b, 0x16], &mut gprs, &mut mmio), Ok(2));
+        assert_eq!(gprs[RDX], 0x4433_2211);
+
+        // mov (%rsi),%cl only updates the low byte
+        assert_eq!(run(&[0x8a, 0x0e], &mut gprs, &mut mmio), Ok(2));
+        assert_eq!(gprs[RCX], 0xffff_ffff_ffff_ff11);
+
+        // mov 0x8(%rbp),%r10
+        assert_eq!(run(&[0x4c, 0x8b, 0x55, 0x08], &mut gprs, &mut mmio), Ok(4));
+        assert_eq!(gprs[10], 0x8877_6655_4433_2211);
+
+        // movzwl (%rbx),%eax
+        gprs[RAX] = !0;
+        assert_eq!(run(&[0x0f, 0xb7, 0x03], &mut gprs, &mut mmio), Ok(3));
+        assert_eq!(gprs[RAX], 0x2211);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut gprs = [0u64; 16];
+        let mut mmio = [0u8; 8];
+
+        // add %eax,(%rbx)
+        assert_eq!(
+            run(&[0x01, 0x03], &mut gprs, &mut mmio),
+            Err(Error::UnsupportedOpcode(0x01))
+        );
+        assert_eq!(run(&[0x89], &mut gprs, &mut mmio), Err(Error::Truncated));
+    }
+}
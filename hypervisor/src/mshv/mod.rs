@@ -0,0 +1,703 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Microsoft Hypervisor (MSHV) backend, for Linux running as the root
+//! partition of Hyper-V.
+//!
+//! MSHV always emulates the local APICs, leaving the IOAPIC to the VMM, and
+//! does not emulate any PIC nor PIT. Interrupts are injected as MSIs.
+
+mod emulator;
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use kvm_bindings::{KVM_IRQ_ROUTING_MSI, KVM_MEM_READONLY};
+use mshv_bindings::*;
+use mshv_ioctls::{IoEventAddress as MshvIoEventAddress, Mshv, NoDatamatch, VcpuFd, VmFd};
+use vmm_sys_util::errno;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::cpu::{Vcpu, VmExit};
+use crate::device::Device;
+use crate::hypervisor::{Capability, Hypervisor};
+use crate::vec_with_array_field;
+use crate::vm::{
+    DataMatch, HaltPollStats, IoEventAddress, IrqRoutingEntry, MsiMessage, UserMemoryRegion, Vm,
+    VmmOps,
+};
+use crate::x86_64::{
+    ConfidentialVm, CpuId, DescriptorTable, FpuState, LapicState, MsrEntry, SegmentRegister,
+    SevSnpPageType, SpecialRegisters, StandardRegisters,
+};
+
+// MSHV does not bound the number of guest memory regions, which are only
+// numbered to mimic the KVM memory slots.
+const MAX_MEMORY_SLOTS: u32 = 512;
+
+const PAGE_SHIFT: u64 = 12;
+
+// Intercept access types
+const HV_INTERCEPT_ACCESS_WRITE: u8 = 1;
+
+fn io_error(e: errno::Error) -> io::Error {
+    io::Error::from_raw_os_error(e.errno())
+}
+
+fn unsupported() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOTSUP)
+}
+
+/// MSHV, through /dev/mshv.
+pub struct MshvHypervisor {
+    mshv: Mshv,
+}
+
+impl MshvHypervisor {
+    pub fn new() -> io::Result<Self> {
+        Ok(MshvHypervisor {
+            mshv: Mshv::new().map_err(io_error)?,
+        })
+    }
+
+    /// Checks whether the host runs as the root partition of MSHV.
+    pub fn is_available() -> bool {
+        Path::new("/dev/mshv").exists()
+    }
+}
+
+impl Hypervisor for MshvHypervisor {
+    fn create_vm(&self) -> io::Result<Arc<dyn Vm>> {
+        let fd = loop {
+            match self.mshv.create_vm() {
+                Ok(fd) => break fd,
+                // The partition creation can be interrupted, and should
+                // then be retried.
+                Err(e) if e.errno() == libc::EINTR => continue,
+                Err(e) => return Err(io_error(e)),
+            }
+        };
+
+        Ok(Arc::new(MshvVm {
+            fd: Arc::new(fd),
+            regions: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    fn create_confidential_vm(&self, _kind: ConfidentialVm) -> io::Result<Arc<dyn Vm>> {
+        // Isolated partitions aren't supported.
+        Err(unsupported())
+    }
+
+    fn get_max_memory_slots(&self) -> u32 {
+        MAX_MEMORY_SLOTS
+    }
+
+    fn check_capability(&self, cap: Capability) -> bool {
+        match cap {
+            Capability::SignalMsi | Capability::SplitIrqchip | Capability::TscDeadlineTimer => true,
+        }
+    }
+
+    fn get_cpuid(&self) -> io::Result<CpuId> {
+        // The guest CPUID is set by the hypervisor, the VMM has no say in it.
+        Ok(CpuId::new(0))
+    }
+
+    fn enable_amx_state_components(&self) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+/// An MSHV partition.
+pub struct MshvVm {
+    fd: Arc<VmFd>,
+    // Regions mapped in the guest, by slot, since MSHV unmaps memory by
+    // region instead of by slot.
+    regions: Mutex<HashMap<u32, mshv_user_mem_region>>,
+}
+
+impl Vm for MshvVm {
+    fn set_tss_address(&self, _offset: usize) -> io::Result<()> {
+        // There is no real mode TSS to set aside.
+        Ok(())
+    }
+
+    fn create_irq_chip(&self) -> io::Result<()> {
+        // The IOAPIC can't be emulated by the hypervisor.
+        Err(unsupported())
+    }
+
+    fn enable_split_irq(&self, _ioapic_pins: u32) -> io::Result<()> {
+        // The local APICs are always emulated by the hypervisor, and the
+        // IOAPIC never is.
+        Ok(())
+    }
+
+    fn create_pit(&self) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn disable_idle_exits(&self) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> io::Result<()> {
+        self.fd.register_irqfd(fd, gsi).map_err(io_error)
+    }
+
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> io::Result<()> {
+        self.fd.unregister_irqfd(fd, gsi).map_err(io_error)
+    }
+
+    fn set_gsi_routing(&self, entries: &[IrqRoutingEntry]) -> io::Result<()> {
+        let msi_entries: Vec<mshv_msi_routing_entry> = entries
+            .iter()
+            .filter(|entry| entry.type_ == KVM_IRQ_ROUTING_MSI)
+            .map(|entry| {
+                // Safe because the entry is an MSI one.
+                let msi = unsafe { entry.u.msi };
+                mshv_msi_routing_entry {
+                    gsi: entry.gsi,
+                    address_lo: msi.address_lo,
+                    address_hi: msi.address_hi,
+                    data: msi.data,
+                }
+            })
+            .collect();
+
+        let mut msi_routing =
+            vec_with_array_field::<mshv_msi_routing, mshv_msi_routing_entry>(msi_entries.len());
+        msi_routing[0].nr = msi_entries.len() as u32;
+
+        // Safe because the vector was allocated with room for all entries.
+        unsafe {
+            let routing_entries: &mut [mshv_msi_routing_entry] =
+                msi_routing[0].entries.as_mut_slice(msi_entries.len());
+            routing_entries.copy_from_slice(&msi_entries);
+        }
+
+        self.fd.set_msi_routing(&msi_routing[0]).map_err(io_error)
+    }
+
+    fn signal_msi(&self, msi: MsiMessage) -> io::Result<bool> {
+        // Decode the x86 MSI address and data, as in the IOAPIC
+        // redirection table entries.
+        let request = InterruptRequest {
+            interrupt_type: (msi.data >> 8) & 0x7,
+            apic_id: u64::from((msi.address_lo >> 12) & 0xff),
+            vector: msi.data & 0xff,
+            level_triggered: (msi.data >> 15) & 0x1 == 1,
+            logical_destination_mode: (msi.address_lo >> 2) & 0x1 == 1,
+            long_mode: false,
+        };
+
+        self.fd
+            .request_virtual_interrupt(&request)
+            .map_err(io_error)?;
+        Ok(true)
+    }
+
+    fn register_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Option<DataMatch>,
+    ) -> io::Result<()> {
+        let addr = mshv_ioevent_address(addr);
+        match datamatch {
+            Some(DataMatch::DataMatch32(value)) => self.fd.register_ioevent(fd, &addr, value),
+            Some(DataMatch::DataMatch64(value)) => self.fd.register_ioevent(fd, &addr, value),
+            None => self.fd.register_ioevent(fd, &addr, NoDatamatch),
+        }
+        .map_err(io_error)
+    }
+
+    fn unregister_ioevent(&self, fd: &EventFd, addr: &IoEventAddress) -> io::Result<()> {
+        self.fd
+            .unregister_ioevent(fd, &mshv_ioevent_address(addr), NoDatamatch)
+            .map_err(io_error)
+    }
+
+    unsafe fn set_user_memory_region(&self, region: UserMemoryRegion) -> io::Result<()> {
+        let mut regions = self.regions.lock().unwrap();
+
+        // Like with KVM, setting a slot again replaces its region.
+        if let Some(old_region) = regions.remove(&region.slot) {
+            self.fd.unmap_user_memory(old_region).map_err(io_error)?;
+        }
+
+        if region.memory_size == 0 {
+            return Ok(());
+        }
+
+        let mut flags = HV_MAP_GPA_READABLE | HV_MAP_GPA_EXECUTABLE;
+        if region.flags & KVM_MEM_READONLY == 0 {
+            flags |= HV_MAP_GPA_WRITABLE;
+        }
+        let mshv_region = mshv_user_mem_region {
+            flags,
+            guest_pfn: region.guest_phys_addr >> PAGE_SHIFT,
+            size: region.memory_size,
+            userspace_addr: region.userspace_addr,
+        };
+
+        self.fd.map_user_memory(mshv_region).map_err(io_error)?;
+        regions.insert(region.slot, mshv_region);
+
+        Ok(())
+    }
+
+    fn get_dirty_log(&self, _slot: u32, _size: u64) -> io::Result<Vec<u64>> {
+        Err(unsupported())
+    }
+
+    unsafe fn set_private_memory_region(&self, _region: UserMemoryRegion) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn tdx_init(&self, _cpuid: &CpuId) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn tdx_finalize(&self) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn sev_snp_init(&self, _policy: u64) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn sev_snp_launch_update(
+        &self,
+        _host_address: u64,
+        _guest_address: u64,
+        _size: u64,
+        _page_type: SevSnpPageType,
+    ) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn sev_snp_launch_finish(&self) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn create_vcpu(&self, id: u8, vmm_ops: Option<Arc<dyn VmmOps>>) -> io::Result<Arc<dyn Vcpu>> {
+        let fd = self.fd.create_vcpu(id).map_err(io_error)?;
+        Ok(Arc::new(MshvVcpu { fd, vmm_ops }))
+    }
+
+    fn create_passthrough_device(&self) -> io::Result<Arc<dyn Device>> {
+        Err(unsupported())
+    }
+
+    fn set_halt_poll_ns(&self, _ns: u32) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn halt_poll_stats(&self) -> io::Result<HaltPollStats> {
+        Err(unsupported())
+    }
+
+    fn get_clock(&self) -> io::Result<u64> {
+        Err(unsupported())
+    }
+}
+
+fn mshv_ioevent_address(addr: &IoEventAddress) -> MshvIoEventAddress {
+    match *addr {
+        IoEventAddress::Pio(addr) => MshvIoEventAddress::Pio(addr),
+        IoEventAddress::Mmio(addr) => MshvIoEventAddress::Mmio(addr),
+    }
+}
+
+/// An MSHV virtual processor.
+pub struct MshvVcpu {
+    fd: VcpuFd,
+    vmm_ops: Option<Arc<dyn VmmOps>>,
+}
+
+impl MshvVcpu {
+    fn handle_io_port_intercept(&self, msg: &hv_message) -> io::Result<VmExit> {
+        let info = msg.to_ioport_info().map_err(io_error)?;
+        // Safe because the access info is always a bitfield.
+        let (access_size, string_op) = unsafe {
+            (
+                info.access_info.__bindgen_anon_1.access_size() as usize,
+                info.access_info.__bindgen_anon_1.string_op(),
+            )
+        };
+        if string_op != 0 {
+            return Ok(VmExit::Unhandled(format!(
+                "Unsupported string I/O on port 0x{:x}",
+                info.port_number
+            )));
+        }
+
+        let mut regs = self.get_regs()?;
+        let port = u64::from(info.port_number);
+        let mut data = [0u8; 4];
+        if info.header.intercept_access_type == HV_INTERCEPT_ACCESS_WRITE {
+            data.copy_from_slice(&(info.rax as u32).to_le_bytes());
+            if let Some(vmm_ops) = &self.vmm_ops {
+                vmm_ops.pio_write(port, &data[..access_size]);
+            }
+        } else {
+            if let Some(vmm_ops) = &self.vmm_ops {
+                vmm_ops.pio_read(port, &mut data[..access_size]);
+            }
+            let mask = 0xffff_ffff >> (32 - access_size * 8);
+            let value = u64::from(u32::from_le_bytes(data) & mask);
+            regs.rax = (info.rax & !u64::from(mask)) | value;
+        }
+
+        regs.rip = info.header.rip + u64::from(info.header.instruction_length());
+        self.set_regs(&regs)?;
+
+        Ok(VmExit::Ignore)
+    }
+
+    fn handle_unmapped_gpa(&self, msg: &hv_message) -> io::Result<VmExit> {
+        let info = msg.to_memory_info().map_err(io_error)?;
+        let gpa = info.guest_physical_address;
+        let bytes = &info.instruction_bytes[..info.instruction_byte_count as usize];
+
+        let mut regs = self.get_regs()?;
+        let mut gprs = [
+            regs.rax, regs.rcx, regs.rdx, regs.rbx, regs.rsp, regs.rbp, regs.rsi, regs.rdi,
+            regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+        ];
+
+        let len = emulator::emulate(
+            bytes,
+            &mut gprs,
+            |data| {
+                if let Some(vmm_ops) = &self.vmm_ops {
+                    vmm_ops.mmio_read(gpa, data);
+                }
+            },
+            |data| {
+                if let Some(vmm_ops) = &self.vmm_ops {
+                    vmm_ops.mmio_write(gpa, data);
+                }
+            },
+        );
+        let len = match len {
+            Ok(len) => len,
+            Err(e) => {
+                return Ok(VmExit::Unhandled(format!(
+                    "Cannot emulate MMIO access at 0x{:x}: {}",
+                    gpa, e
+                )));
+            }
+        };
+
+        regs.rax = gprs[emulator::RAX];
+        regs.rcx = gprs[emulator::RCX];
+        regs.rdx = gprs[emulator::RDX];
+        regs.rbx = gprs[emulator::RBX];
+        regs.rsp = gprs[emulator::RSP];
+        regs.rbp = gprs[emulator::RBP];
+        regs.rsi = gprs[emulator::RSI];
+        regs.rdi = gprs[emulator::RDI];
+        regs.r8 = gprs[8];
+        regs.r9 = gprs[9];
+        regs.r10 = gprs[10];
+        regs.r11 = gprs[11];
+        regs.r12 = gprs[12];
+        regs.r13 = gprs[13];
+        regs.r14 = gprs[14];
+        regs.r15 = gprs[15];
+        regs.rip += len as u64;
+        self.set_regs(&regs)?;
+
+        Ok(VmExit::Ignore)
+    }
+}
+
+impl Vcpu for MshvVcpu {
+    fn run(&self) -> io::Result<VmExit> {
+        let msg = self.fd.run(hv_message::default()).map_err(io_error)?;
+
+        match msg.header.message_type {
+            hv_message_type_HVMSG_X64_IO_PORT_INTERCEPT => self.handle_io_port_intercept(&msg),
+            hv_message_type_HVMSG_UNMAPPED_GPA => self.handle_unmapped_gpa(&msg),
+            hv_message_type_HVMSG_X64_APIC_EOI => {
+                let info = msg.to_apic_eoi_info().map_err(io_error)?;
+                Ok(VmExit::IoapicEoi(info.interrupt_vector as u8))
+            }
+            // The processor halted with interrupts disabled, or hit a
+            // triple fault.
+            hv_message_type_HVMSG_X64_HALT | hv_message_type_HVMSG_UNRECOVERABLE_EXCEPTION => {
+                Ok(VmExit::Shutdown)
+            }
+            message_type => Ok(VmExit::Unhandled(format!(
+                "Unexpected exit reason on vcpu run: 0x{:x}",
+                message_type
+            ))),
+        }
+    }
+
+    fn get_regs(&self) -> io::Result<StandardRegisters> {
+        let regs = self.fd.get_regs().map_err(io_error)?;
+        Ok(StandardRegisters {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rsp: regs.rsp,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: regs.rip,
+            rflags: regs.rflags,
+        })
+    }
+
+    fn set_regs(&self, regs: &StandardRegisters) -> io::Result<()> {
+        let regs = mshv_bindings::StandardRegisters {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rsp: regs.rsp,
+            rbp: regs.rbp,
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: regs.rip,
+            rflags: regs.rflags,
+        };
+        self.fd.set_regs(&regs).map_err(io_error)
+    }
+
+    fn get_sregs(&self) -> io::Result<SpecialRegisters> {
+        let sregs = self.fd.get_sregs().map_err(io_error)?;
+        Ok(SpecialRegisters {
+            cs: segment_from_mshv(&sregs.cs),
+            ds: segment_from_mshv(&sregs.ds),
+            es: segment_from_mshv(&sregs.es),
+            fs: segment_from_mshv(&sregs.fs),
+            gs: segment_from_mshv(&sregs.gs),
+            ss: segment_from_mshv(&sregs.ss),
+            tr: segment_from_mshv(&sregs.tr),
+            ldt: segment_from_mshv(&sregs.ldt),
+            gdt: DescriptorTable {
+                base: sregs.gdt.base,
+                limit: sregs.gdt.limit,
+                ..Default::default()
+            },
+            idt: DescriptorTable {
+                base: sregs.idt.base,
+                limit: sregs.idt.limit,
+                ..Default::default()
+            },
+            cr0: sregs.cr0,
+            cr2: sregs.cr2,
+            cr3: sregs.cr3,
+            cr4: sregs.cr4,
+            cr8: sregs.cr8,
+            efer: sregs.efer,
+            apic_base: sregs.apic_base,
+            interrupt_bitmap: sregs.interrupt_bitmap,
+        })
+    }
+
+    fn set_sregs(&self, sregs: &SpecialRegisters) -> io::Result<()> {
+        let sregs = mshv_bindings::SpecialRegisters {
+            cs: segment_to_mshv(&sregs.cs),
+            ds: segment_to_mshv(&sregs.ds),
+            es: segment_to_mshv(&sregs.es),
+            fs: segment_to_mshv(&sregs.fs),
+            gs: segment_to_mshv(&sregs.gs),
+            ss: segment_to_mshv(&sregs.ss),
+            tr: segment_to_mshv(&sregs.tr),
+            ldt: segment_to_mshv(&sregs.ldt),
+            gdt: TableRegister {
+                base: sregs.gdt.base,
+                limit: sregs.gdt.limit,
+            },
+            idt: TableRegister {
+                base: sregs.idt.base,
+                limit: sregs.idt.limit,
+            },
+            cr0: sregs.cr0,
+            cr2: sregs.cr2,
+            cr3: sregs.cr3,
+            cr4: sregs.cr4,
+            cr8: sregs.cr8,
+            efer: sregs.efer,
+            apic_base: sregs.apic_base,
+            interrupt_bitmap: sregs.interrupt_bitmap,
+        };
+        self.fd.set_sregs(&sregs).map_err(io_error)
+    }
+
+    fn get_fpu(&self) -> io::Result<FpuState> {
+        let fpu = self.fd.get_fpu().map_err(io_error)?;
+        Ok(FpuState {
+            fpr: fpu.fpr,
+            fcw: fpu.fcw,
+            fsw: fpu.fsw,
+            ftwx: fpu.ftwx,
+            last_opcode: fpu.last_opcode,
+            last_ip: fpu.last_ip,
+            last_dp: fpu.last_dp,
+            xmm: fpu.xmm,
+            mxcsr: fpu.mxcsr,
+            ..Default::default()
+        })
+    }
+
+    fn set_fpu(&self, fpu: &FpuState) -> io::Result<()> {
+        let fpu = FloatingPointUnit {
+            fpr: fpu.fpr,
+            fcw: fpu.fcw,
+            fsw: fpu.fsw,
+            ftwx: fpu.ftwx,
+            last_opcode: fpu.last_opcode,
+            last_ip: fpu.last_ip,
+            last_dp: fpu.last_dp,
+            xmm: fpu.xmm,
+            mxcsr: fpu.mxcsr,
+            ..Default::default()
+        };
+        self.fd.set_fpu(&fpu).map_err(io_error)
+    }
+
+    fn get_lapic(&self) -> io::Result<LapicState> {
+        let lapic = self.fd.get_lapic().map_err(io_error)?;
+        Ok(LapicState { regs: lapic.regs })
+    }
+
+    fn set_lapic(&self, lapic: &LapicState) -> io::Result<()> {
+        let lapic = mshv_bindings::LapicState { regs: lapic.regs };
+        self.fd.set_lapic(&lapic).map_err(io_error)
+    }
+
+    fn get_msrs(&self, msrs: &mut [MsrEntry]) -> io::Result<usize> {
+        let entries: Vec<msr_entry> = msrs
+            .iter()
+            .map(|msr| msr_entry {
+                index: msr.index,
+                data: msr.data,
+                ..Default::default()
+            })
+            .collect();
+        let mut mshv_msrs = Msrs::from_entries(&entries);
+
+        let count = self.fd.get_msrs(&mut mshv_msrs).map_err(io_error)?;
+        for (msr, entry) in msrs.iter_mut().zip(mshv_msrs.as_slice()) {
+            msr.data = entry.data;
+        }
+
+        Ok(count)
+    }
+
+    fn set_msrs(&self, msrs: &[MsrEntry]) -> io::Result<()> {
+        let entries: Vec<msr_entry> = msrs
+            .iter()
+            .map(|msr| msr_entry {
+                index: msr.index,
+                data: msr.data,
+                ..Default::default()
+            })
+            .collect();
+
+        self.fd
+            .set_msrs(&Msrs::from_entries(&entries))
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    fn set_cpuid2(&self, _cpuid: &CpuId) -> io::Result<()> {
+        // The guest CPUID is set by the hypervisor.
+        Ok(())
+    }
+
+    fn enable_hyperv_synic(&self) -> io::Result<()> {
+        // The guest is provided with the Hyper-V enlightenments as is.
+        Ok(())
+    }
+
+    fn kvmclock_paused(&self) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn tdx_init(&self, _hob_address: u64) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn tdx_init_memory_region(
+        &self,
+        _host_address: u64,
+        _guest_address: u64,
+        _size: u64,
+        _measure: bool,
+    ) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn set_guest_debug(&self, _breakpoints: &[u64], _single_step: bool) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    fn translate_gva(&self, _gva: u64) -> io::Result<Option<u64>> {
+        Err(unsupported())
+    }
+}
+
+fn segment_from_mshv(segment: &mshv_bindings::SegmentRegister) -> SegmentRegister {
+    SegmentRegister {
+        base: segment.base,
+        limit: segment.limit,
+        selector: segment.selector,
+        type_: segment.type_,
+        present: segment.present,
+        dpl: segment.dpl,
+        db: segment.db,
+        s: segment.s,
+        l: segment.l,
+        g: segment.g,
+        avl: segment.avl,
+        unusable: segment.unusable,
+        ..Default::default()
+    }
+}
+
+fn segment_to_mshv(segment: &SegmentRegister) -> mshv_bindings::SegmentRegister {
+    mshv_bindings::SegmentRegister {
+        base: segment.base,
+        limit: segment.limit,
+        selector: segment.selector,
+        type_: segment.type_,
+        present: segment.present,
+        dpl: segment.dpl,
+        db: segment.db,
+        s: segment.s,
+        l: segment.l,
+        g: segment.g,
+        avl: segment.avl,
+        unusable: segment.unusable,
+        ..Default::default()
+    }
+}
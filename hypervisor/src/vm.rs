@@ -0,0 +1,204 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::io;
+use std::sync::Arc;
+
+use vmm_sys_util::eventfd::EventFd;
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::{GicDevice, VcpuInit};
+use crate::cpu::Vcpu;
+use crate::device::Device;
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::{CpuId, SevSnpPageType};
+
+/// A guest memory region, backed by some memory of the VMM. A region with a
+/// zero size removes the one previously set at the same slot.
+pub use kvm_bindings::kvm_userspace_memory_region as UserMemoryRegion;
+
+/// An MSI message to inject into the guest.
+pub use kvm_bindings::kvm_msi as MsiMessage;
+
+/// A GSI routing entry, mapping the interrupts signaled through an irqfd.
+pub use kvm_bindings::kvm_irq_routing_entry as IrqRoutingEntry;
+
+/// Types of GSI routing entries, to a pin of an in-kernel interrupt
+/// controller or to an MSI message.
+pub use kvm_bindings::{KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI};
+
+/// Flag of the guest memory regions whose pages the hypervisor logs the
+/// guest writes to.
+pub use kvm_bindings::KVM_MEM_LOG_DIRTY_PAGES;
+
+/// Guest address an ioeventfd is triggered by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoEventAddress {
+    /// I/O port address.
+    Pio(u64),
+    /// MMIO address.
+    Mmio(u64),
+}
+
+/// Value the guest has to write for an ioeventfd to be triggered, the size
+/// of the write having to match too.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataMatch {
+    DataMatch32(u32),
+    DataMatch64(u64),
+}
+
+/// Counts of the halts of the vCPUs of a VM, since it was created.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HaltPollStats {
+    /// Halts the hypervisor polled for a wakeup on, before blocking.
+    pub attempted: u64,
+    /// Halts a wakeup came for while the hypervisor was polling.
+    pub successful: u64,
+}
+
+/// Callbacks into the VMM, for the guest accesses the hypervisor leaves to
+/// the device emulation.
+pub trait VmmOps: Send + Sync {
+    fn pio_read(&self, port: u64, data: &mut [u8]);
+    fn pio_write(&self, port: u64, data: &[u8]);
+    fn mmio_read(&self, addr: u64, data: &mut [u8]);
+    fn mmio_write(&self, addr: u64, data: &[u8]);
+}
+
+/// A VM created by a hypervisor.
+pub trait Vm: Send + Sync {
+    #[cfg(target_arch = "x86_64")]
+    /// Sets the guest address of the region the hypervisor may use for the
+    /// TSS of real mode guests.
+    fn set_tss_address(&self, offset: usize) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Emulates the local APICs, the PICs and the IOAPIC in the hypervisor.
+    fn create_irq_chip(&self) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Only emulates the local APICs in the hypervisor, leaving the IOAPIC
+    /// and its `ioapic_pins` pins to the VMM.
+    fn enable_split_irq(&self, ioapic_pins: u32) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Emulates the PIT in the hypervisor.
+    fn create_pit(&self) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Lets the vCPUs run HLT and PAUSE without exiting to the hypervisor,
+    /// for the ones having host CPUs of their own. To be called before the
+    /// vCPUs are created.
+    fn disable_idle_exits(&self) -> io::Result<()>;
+
+    /// Triggers the `gsi` interrupt whenever `fd` is written to.
+    fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> io::Result<()>;
+
+    /// Stops triggering the `gsi` interrupt when `fd` is written to.
+    fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> io::Result<()>;
+
+    /// Sets the routes of the interrupts signaled through irqfds.
+    fn set_gsi_routing(&self, entries: &[IrqRoutingEntry]) -> io::Result<()>;
+
+    /// Injects an MSI, returning whether the guest accepted it.
+    fn signal_msi(&self, msi: MsiMessage) -> io::Result<bool>;
+
+    /// Writes to `fd` whenever the guest writes to `addr`, instead of
+    /// exiting to the VMM.
+    fn register_ioevent(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Option<DataMatch>,
+    ) -> io::Result<()>;
+
+    /// Removes an ioeventfd set through `register_ioevent()`.
+    fn unregister_ioevent(&self, fd: &EventFd, addr: &IoEventAddress) -> io::Result<()>;
+
+    /// Sets, updates or removes a guest memory region.
+    ///
+    /// # Safety
+    ///
+    /// The memory backing the region must stay mapped as long as the guest
+    /// can access it, and the region must not overlap with other ones.
+    unsafe fn set_user_memory_region(&self, region: UserMemoryRegion) -> io::Result<()>;
+
+    /// Reads and clears the bitmap of the pages of the memory slot the guest
+    /// wrote to since the last call, a bit for each page of the `size`
+    /// bytes of its region, which has to be set with dirty logging.
+    fn get_dirty_log(&self, slot: u32, size: u64) -> io::Result<Vec<u64>>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Sets, updates or removes a guest memory region of a confidential VM,
+    /// private to the guest. The VMM memory backing it is only used for the
+    /// pages the guest shares with the host, and for the initial content of
+    /// the private pages.
+    ///
+    /// # Safety
+    ///
+    /// Same as `set_user_memory_region()`.
+    unsafe fn set_private_memory_region(&self, region: UserMemoryRegion) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Initializes a TDX VM with the CPUID entries of its vCPUs, before any
+    /// of them is created.
+    fn tdx_init(&self, cpuid: &CpuId) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Finalizes the measurement of a TDX VM, once its initial memory is
+    /// added and its vCPUs initialized, so that it can run.
+    fn tdx_finalize(&self) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Initializes a SEV-SNP VM and starts its launch with the guest
+    /// `policy`, before any vCPU is created.
+    fn sev_snp_init(&self, policy: u64) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Adds the pages of a private memory range to a SEV-SNP VM being
+    /// launched, their content coming from `host_address` for the page
+    /// types that have one.
+    fn sev_snp_launch_update(
+        &self,
+        host_address: u64,
+        guest_address: u64,
+        size: u64,
+        page_type: SevSnpPageType,
+    ) -> io::Result<()>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Finishes the launch of a SEV-SNP VM, encrypting the state of its
+    /// vCPUs, so that it can run.
+    fn sev_snp_launch_finish(&self) -> io::Result<()>;
+
+    /// Creates the vCPU `id`, handing the guest accesses to emulate over to
+    /// `vmm_ops`.
+    fn create_vcpu(&self, id: u8, vmm_ops: Option<Arc<dyn VmmOps>>) -> io::Result<Arc<dyn Vcpu>>;
+
+    /// Creates the hypervisor device VFIO groups get attached to, for
+    /// device passthrough.
+    fn create_passthrough_device(&self) -> io::Result<Arc<dyn Device>>;
+
+    /// Sets how long the vCPUs poll for a wakeup at most when the guest
+    /// halts them, before blocking, in nanoseconds. 0 disables the polling.
+    fn set_halt_poll_ns(&self, ns: u32) -> io::Result<()>;
+
+    /// Reads how often polling for a wakeup paid off for the halted vCPUs.
+    fn halt_poll_stats(&self) -> io::Result<HaltPollStats>;
+
+    #[cfg(target_arch = "x86_64")]
+    /// Reads the paravirtual clock of the guest, in nanoseconds.
+    fn get_clock(&self) -> io::Result<u64>;
+
+    #[cfg(target_arch = "aarch64")]
+    /// Fills `kvi` with the vCPU target matching the host CPU.
+    fn get_preferred_target(&self, kvi: &mut VcpuInit) -> io::Result<()>;
+
+    #[cfg(target_arch = "aarch64")]
+    /// Creates an interrupt controller device, to be configured through its
+    /// attributes.
+    fn create_gic_device(&self, device: GicDevice) -> io::Result<Arc<dyn Device>>;
+}
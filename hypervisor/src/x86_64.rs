@@ -0,0 +1,45 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! x86_64 vCPU state.
+
+pub use kvm_bindings::kvm_cpuid_entry2 as CpuIdEntry;
+pub use kvm_bindings::kvm_dtable as DescriptorTable;
+pub use kvm_bindings::kvm_fpu as FpuState;
+pub use kvm_bindings::kvm_lapic_state as LapicState;
+pub use kvm_bindings::kvm_msr_entry as MsrEntry;
+pub use kvm_bindings::kvm_regs as StandardRegisters;
+pub use kvm_bindings::kvm_segment as SegmentRegister;
+pub use kvm_bindings::kvm_sregs as SpecialRegisters;
+pub use kvm_ioctls::CpuId;
+
+/// The in-kernel interrupt controllers, the GSI routing entries of their
+/// pins refer to.
+pub use kvm_bindings::{KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE};
+
+/// Kinds of confidential VMs, whose memory and vCPU state are encrypted and
+/// out of reach of the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfidentialVm {
+    /// Intel Trust Domain Extensions.
+    Tdx,
+    /// AMD Secure Encrypted Virtualization with Secure Nested Paging.
+    SevSnp,
+}
+
+/// How the SEV-SNP firmware adds a page to the guest, before it runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SevSnpPageType {
+    /// Page copied from the VMM memory, and measured.
+    Normal,
+    /// Measured page of zeroes.
+    Zero,
+    /// Page copied from the VMM memory, but not measured.
+    Unmeasured,
+    /// Page the firmware fills with the guest secrets.
+    Secrets,
+    /// CPUID table, checked by the firmware against the host CPUID.
+    Cpuid,
+}
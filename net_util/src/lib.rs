@@ -52,8 +52,17 @@ fn create_sockaddr(ip_addr: net::Ipv4Addr) -> net_gen::sockaddr {
 }
 
 fn create_socket() -> Result<net::UdpSocket> {
+    create_socket_of_family(libc::AF_INET)
+}
+
+// The IPv6 addresses of an interface are set through an IPv6 socket.
+fn create_inet6_socket() -> Result<net::UdpSocket> {
+    create_socket_of_family(libc::AF_INET6)
+}
+
+fn create_socket_of_family(family: libc::c_int) -> Result<net::UdpSocket> {
     // This is safe since we check the return value.
-    let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    let sock = unsafe { libc::socket(family, libc::SOCK_DGRAM, 0) };
     if sock < 0 {
         return Err(Error::CreateSocket(IoError::last_os_error()));
     }
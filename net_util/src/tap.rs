@@ -5,13 +5,19 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Error as IoError, Read, Result as IoResult, Write};
 use std::net;
 use std::os::raw::*;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::str;
 
-use super::{create_sockaddr, create_socket, Error as NetUtilError};
+use super::{
+    create_inet6_socket, create_sockaddr, create_socket, Error as NetUtilError, MacAddr,
+    MAC_ADDR_LEN,
+};
 use libc;
 use net_gen;
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
@@ -27,10 +33,26 @@ pub enum Error {
     /// Failed to create a socket.
     NetUtil(NetUtilError),
     InvalidIfname,
+    /// The file is not a tap interface with a virtio-net header.
+    InvalidTapFile,
+    /// Couldn't make the tap interface non blocking.
+    SetNonBlocking(IoError),
+    /// The IPv6 prefix length is over 128.
+    InvalidIpv6PrefixLen(u8),
+    /// Couldn't find or open the character device of a macvtap interface.
+    OpenMacvtap(IoError),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+// struct in6_ifreq of the kernel, which SIOCSIFADDR takes on an IPv6 socket.
+#[repr(C)]
+struct In6Ifreq {
+    ifr6_addr: libc::in6_addr,
+    ifr6_prefixlen: u32,
+    ifr6_ifindex: c_int,
+}
+
 /// Handle for a network tap interface.
 ///
 /// For now, this simply wraps the file descriptor for the tap device so methods
@@ -124,6 +146,60 @@ impl Tap {
         })
     }
 
+    /// Wraps a tap interface opened by another process, such as a more
+    /// privileged one handing it over through a UNIX socket.
+    pub fn from_tap_file(tap_file: File) -> Result<Tap> {
+        let mut ifreq: net_gen::ifreq = Default::default();
+
+        // ioctl is safe since we call it with a valid fd and check the return
+        // value.
+        let ret = unsafe { ioctl_with_mut_ref(&tap_file, net_gen::TUNGETIFF(), &mut ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        // Safe since only the flags are accessed, and they're copied out.
+        let flags = u32::from(unsafe { *ifreq.ifr_ifru.ifru_flags.as_ref() } as u16);
+        let required_flags = net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR;
+        if flags & required_flags != required_flags {
+            return Err(Error::InvalidTapFile);
+        }
+
+        // The fcntl calls are safe since the fd is valid, and we check the
+        // return values.
+        let fd = tap_file.as_raw_fd();
+        let fd_flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if fd_flags < 0
+            || unsafe { libc::fcntl(fd, libc::F_SETFL, fd_flags | libc::O_NONBLOCK) } < 0
+        {
+            return Err(Error::SetNonBlocking(IoError::last_os_error()));
+        }
+
+        // Safe since only the name is accessed, and it's cloned out.
+        Ok(Tap {
+            tap_file,
+            if_name: unsafe { *ifreq.ifr_ifrn.ifrn_name.as_ref() },
+        })
+    }
+
+    /// Opens a queue of the macvtap interface `if_name`, through its
+    /// character device, `/dev/tap<ifindex>`. The frames go straight to and
+    /// from the link the interface sits on.
+    pub fn open_macvtap(if_name: &str) -> Result<Tap> {
+        build_terminated_if_name(if_name)?;
+
+        let ifindex = fs::read_to_string(format!("/sys/class/net/{}/ifindex", if_name))
+            .map_err(Error::OpenMacvtap)?;
+        let tap_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(format!("/dev/tap{}", ifindex.trim()))
+            .map_err(Error::OpenMacvtap)?;
+
+        Self::from_tap_file(tap_file)
+    }
+
     /// Create a new tap interface.
     pub fn new() -> Result<Tap> {
         Self::open_named("vmtap%d")
@@ -153,6 +229,47 @@ impl Tap {
         Ok(())
     }
 
+    /// Add a host-side IPv6 address to the tap interface, along with the
+    /// prefix length of its subnet. The interface keeps its IPv4 address,
+    /// if any.
+    pub fn set_ipv6_addr(&self, ip_addr: net::Ipv6Addr, prefix_len: u8) -> Result<()> {
+        if prefix_len > 128 {
+            return Err(Error::InvalidIpv6PrefixLen(prefix_len));
+        }
+
+        let sock = create_inet6_socket().map_err(Error::NetUtil)?;
+        let mut ifreq = self.get_ifreq();
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe {
+            ioctl_with_mut_ref(&sock, net_gen::sockios::SIOCGIFINDEX as c_ulong, &mut ifreq)
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        let in6_ifreq = In6Ifreq {
+            ifr6_addr: libc::in6_addr {
+                s6_addr: ip_addr.octets(),
+            },
+            ifr6_prefixlen: u32::from(prefix_len),
+            // We only access one field of the ifru union, the one the kernel
+            // wrote the index to, hence this is safe.
+            ifr6_ifindex: unsafe { *ifreq.ifr_ifru.ifru_ivalue.as_ref() },
+        };
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret =
+            unsafe { ioctl_with_ref(&sock, net_gen::sockios::SIOCSIFADDR as c_ulong, &in6_ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     /// Set the netmask for the subnet that the tap interface will exist on.
     pub fn set_netmask(&self, netmask: net::Ipv4Addr) -> Result<()> {
         let sock = create_socket().map_err(Error::NetUtil)?;
@@ -190,6 +307,19 @@ impl Tap {
         Ok(())
     }
 
+    /// Set whether the tap interface outlives its last file descriptor. A
+    /// persistent interface no longer set persistent goes away once closed.
+    pub fn set_persist(&self, persist: bool) -> Result<()> {
+        // ioctl is safe. Called with a valid tap fd, and we check the return.
+        let ret =
+            unsafe { ioctl_with_val(&self.tap_file, net_gen::TUNSETPERSIST(), persist as c_ulong) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
     /// Enable the tap interface.
     pub fn enable(&self) -> Result<()> {
         let sock = create_socket().map_err(Error::NetUtil)?;
@@ -214,6 +344,47 @@ impl Tap {
         Ok(())
     }
 
+    /// Whether the interface is a macvtap one rather than a tap one.
+    pub fn is_macvtap(&self) -> bool {
+        Path::new(&format!("/sys/class/net/{}/macvtap", self.if_name_as_str())).exists()
+    }
+
+    /// Get the MAC address of the interface. The one of a macvtap interface
+    /// is the address its guest is reached at on the link.
+    pub fn mac_addr(&self) -> Result<MacAddr> {
+        let sock = create_socket().map_err(Error::NetUtil)?;
+        let mut ifreq = self.get_ifreq();
+
+        // ioctl is safe. Called with a valid sock fd, and we check the return.
+        #[allow(clippy::cast_lossless)]
+        let ret = unsafe {
+            ioctl_with_mut_ref(
+                &sock,
+                net_gen::sockios::SIOCGIFHWADDR as c_ulong,
+                &mut ifreq,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        // We only access one field of the ifru union, the one the kernel
+        // wrote the address to, hence this is safe.
+        let sa_data = unsafe { ifreq.ifr_ifru.ifru_hwaddr.as_ref() }.sa_data;
+        let bytes: Vec<u8> = sa_data[..MAC_ADDR_LEN].iter().map(|b| *b as u8).collect();
+
+        Ok(MacAddr::from_bytes_unchecked(&bytes))
+    }
+
+    fn if_name_as_str(&self) -> &str {
+        let len = self
+            .if_name
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or_else(|| self.if_name.len());
+        str::from_utf8(&self.if_name[..len]).unwrap_or("")
+    }
+
     /// Set the size of the vnet hdr.
     pub fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
         // ioctl is safe. Called with a valid tap fd, and we check the return.
@@ -445,12 +616,33 @@ mod tests {
         assert!(ret.is_ok());
     }
 
+    #[test]
+    fn test_tap_configure_ipv6() {
+        // Unlike the IPv4 one, an IPv6 address can be on several interfaces.
+        let tap = Tap::new().unwrap();
+        let ip_addr: net::Ipv6Addr = "fd00:241::1".parse().unwrap();
+
+        assert!(tap.set_ipv6_addr(ip_addr, 64).is_ok());
+        assert!(tap.set_ipv6_addr(ip_addr, 129).is_err());
+    }
+
     #[test]
     fn test_set_options() {
         // This line will fail to provide an initialized FD if the test is not run as root.
         let tap = Tap::new().unwrap();
         tap.set_vnet_hdr_size(16).unwrap();
         tap.set_offload(0).unwrap();
+        tap.set_persist(false).unwrap();
+    }
+
+    #[test]
+    fn test_tap_mac_addr() {
+        let tap = Tap::new().unwrap();
+        let (mac, _, _) = pnet_get_mac_tx_rx(tap_name_to_string(&tap));
+
+        assert_eq!(tap.mac_addr().unwrap().to_string(), mac.to_string());
+        assert!(!tap.is_macvtap());
+        assert!(Tap::open_macvtap(&tap_name_to_string(&tap)).is_err());
     }
 
     #[test]
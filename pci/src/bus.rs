@@ -10,6 +10,7 @@ use byteorder::{ByteOrder, LittleEndian};
 use devices::BusDevice;
 use std;
 use std::any::Any;
+use std::collections::BTreeMap;
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex, Weak};
 use vm_memory::{Address, GuestAddress, GuestUsize};
@@ -17,6 +18,17 @@ use vm_memory::{Address, GuestAddress, GuestUsize};
 const VENDOR_ID_INTEL: u16 = 0x8086;
 const DEVICE_ID_INTEL_VIRT_PCIE_HOST: u16 = 0x0d57;
 
+const NUM_DEVICE_IDS: u32 = 32;
+const NUM_FUNCTION_IDS: u32 = 8;
+// Registers of the PCI configuration space, the PCI Express extended one
+// not being emulated.
+const NUM_CONFIGURATION_REGISTERS: usize = 64;
+
+// Header type register, and the bit telling the device has more functions
+// than its function 0.
+const HEADER_TYPE_REG: usize = 3;
+const HEADER_TYPE_MULTIFUNCTION: u32 = 0x0080_0000;
+
 /// Errors for device manager.
 #[derive(Debug)]
 pub enum PciRootError {
@@ -28,6 +40,8 @@ pub enum PciRootError {
     PioInsert(devices::BusError),
     /// Could not add a device to the mmio bus.
     MmioInsert(devices::BusError),
+    /// All the device numbers of the bus are in use.
+    NoPciDeviceSlotAvailable,
 }
 pub type Result<T> = std::result::Result<T, PciRootError>;
 
@@ -77,20 +91,23 @@ impl PciDevice for PciRoot {
 }
 
 pub struct PciBus {
-    /// Devices attached to this bus.
+    /// Devices attached to this bus, by their device and function numbers.
     /// Device 0 is host bridge.
-    devices: Vec<Arc<Mutex<dyn PciDevice>>>,
+    devices: BTreeMap<u32, Arc<Mutex<dyn PciDevice>>>,
+    /// Device the functions added with `add_function()` are packed onto.
+    multifunction_device: Option<u32>,
     device_reloc: Weak<dyn DeviceRelocation>,
 }
 
 impl PciBus {
     pub fn new(pci_root: PciRoot, device_reloc: Weak<dyn DeviceRelocation>) -> Self {
-        let mut devices: Vec<Arc<Mutex<dyn PciDevice>>> = Vec::new();
+        let mut devices: BTreeMap<u32, Arc<Mutex<dyn PciDevice>>> = BTreeMap::new();
 
-        devices.push(Arc::new(Mutex::new(pci_root)));
+        devices.insert(0, Arc::new(Mutex::new(pci_root)));
 
         PciBus {
             devices,
+            multifunction_device: None,
             device_reloc,
         }
     }
@@ -119,13 +136,118 @@ impl PciBus {
         Ok(())
     }
 
+    /// Add a single function device, with the device number returned by
+    /// `next_device_id()`.
     pub fn add_device(&mut self, device: Arc<Mutex<dyn PciDevice>>) -> Result<()> {
-        self.devices.push(device);
+        let device_id = self.next_device_id();
+        if device_id >= NUM_DEVICE_IDS {
+            return Err(PciRootError::NoPciDeviceSlotAvailable);
+        }
+
+        self.devices.insert(device_id << 3, device);
+        Ok(())
+    }
+
+    /// Add a device as the next function of a multifunction device, with the
+    /// device and function numbers returned by `next_function_id()`.
+    pub fn add_function(&mut self, device: Arc<Mutex<dyn PciDevice>>) -> Result<()> {
+        let devfn = self.next_function_id();
+        if devfn >> 3 >= NUM_DEVICE_IDS {
+            return Err(PciRootError::NoPciDeviceSlotAvailable);
+        }
+
+        if devfn & 0x7 == 0 {
+            self.multifunction_device = Some(devfn >> 3);
+        }
+        self.devices.insert(devfn, device);
         Ok(())
     }
 
+    /// Lowest device number without any function on the bus, the ones of
+    /// the removed devices being reused, or `NUM_DEVICE_IDS` once they are
+    /// all in use.
     pub fn next_device_id(&self) -> u32 {
-        self.devices.len() as u32
+        (0..NUM_DEVICE_IDS)
+            .find(|&device_id| {
+                self.devices
+                    .range(device_id << 3..(device_id + 1) << 3)
+                    .next()
+                    .is_none()
+            })
+            .unwrap_or(NUM_DEVICE_IDS)
+    }
+
+    /// Whether a single function device can still be added.
+    pub fn has_free_device_id(&self) -> bool {
+        self.next_device_id() < NUM_DEVICE_IDS
+    }
+
+    /// Remove a single function device from the bus, returning it. None is
+    /// returned when there is no such device, or when the device has more
+    /// functions than its function 0.
+    pub fn remove_device(&mut self, device_id: u32) -> Option<Arc<Mutex<dyn PciDevice>>> {
+        let devfn = device_id << 3;
+        // The host bridge stays.
+        if device_id == 0
+            || device_id >= NUM_DEVICE_IDS
+            || self
+                .devices
+                .range(devfn + 1..devfn + NUM_FUNCTION_IDS)
+                .next()
+                .is_some()
+        {
+            return None;
+        }
+
+        if self.multifunction_device == Some(device_id) {
+            self.multifunction_device = None;
+        }
+        self.devices.remove(&devfn)
+    }
+
+    /// Device and function numbers, as the device number shifted by 3 bits
+    /// ORed with the function number, of the next function `add_function()`
+    /// adds: the next function of the current multifunction device, or the
+    /// function 0 of a new one once it has 8 functions.
+    pub fn next_function_id(&self) -> u32 {
+        if let Some(device_id) = self.multifunction_device {
+            let functions = self
+                .devices
+                .range(device_id << 3..(device_id + 1) << 3)
+                .count() as u32;
+            if functions < NUM_FUNCTION_IDS {
+                return device_id << 3 | functions;
+            }
+        }
+
+        self.next_device_id() << 3
+    }
+
+    fn read_config_register(&self, device: usize, function: usize, register: usize) -> u32 {
+        let devfn = (device << 3 | function) as u32;
+        let value = match self.devices.get(&devfn) {
+            Some(d) => d.lock().unwrap().read_config_register(register),
+            None => return 0xffff_ffff,
+        };
+
+        // The guest only looks for the other functions of a device whose
+        // function 0 is flagged as multifunction.
+        if function == 0
+            && register == HEADER_TYPE_REG
+            && self
+                .devices
+                .range(devfn + 1..devfn + NUM_FUNCTION_IDS)
+                .next()
+                .is_some()
+        {
+            return value | HEADER_TYPE_MULTIFUNCTION;
+        }
+
+        value
+    }
+
+    fn get_device(&self, device: usize, function: usize) -> Option<&Arc<Mutex<dyn PciDevice>>> {
+        self.devices.get(&((device << 3 | function) as u32))
     }
 }
 
@@ -157,19 +279,10 @@ impl PciConfigIo {
             return 0xffff_ffff;
         }
 
-        // Don't support multi-function devices.
-        if function > 0 {
-            return 0xffff_ffff;
-        }
-
         self.pci_bus
             .lock()
             .unwrap()
-            .devices
-            .get(device)
-            .map_or(0xffff_ffff, |d| {
-                d.lock().unwrap().read_config_register(register)
-            })
+            .read_config_register(device, function, register)
     }
 
     pub fn config_space_write(&mut self, offset: u64, data: &[u8]) {
@@ -182,7 +295,7 @@ impl PciConfigIo {
             return;
         }
 
-        let (bus, device, _function, register) =
+        let (bus, device, function, register) =
             parse_config_address(self.config_address & !0x8000_0000);
 
         // Only support one bus.
@@ -191,7 +304,7 @@ impl PciConfigIo {
         }
 
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(device) {
+        if let Some(d) = pci_bus.get_device(device, function) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -277,21 +390,17 @@ impl PciConfigMmio {
     }
 
     fn config_space_read(&self, config_address: u32) -> u32 {
-        let (bus, device, _function, register) = parse_config_address(config_address);
+        let (bus, device, function, register) = parse_mmio_config_address(config_address);
 
-        // Only support one bus.
-        if bus != 0 {
+        // Only support one bus, and no extended configuration space.
+        if bus != 0 || register >= NUM_CONFIGURATION_REGISTERS {
             return 0xffff_ffff;
         }
 
         self.pci_bus
             .lock()
             .unwrap()
-            .devices
-            .get(device)
-            .map_or(0xffff_ffff, |d| {
-                d.lock().unwrap().read_config_register(register)
-            })
+            .read_config_register(device, function, register)
     }
 
     fn config_space_write(&mut self, config_address: u32, offset: u64, data: &[u8]) {
@@ -299,15 +408,15 @@ impl PciConfigMmio {
             return;
         }
 
-        let (bus, device, _function, register) = parse_config_address(config_address);
+        let (bus, device, function, register) = parse_mmio_config_address(config_address);
 
-        // Only support one bus.
-        if bus != 0 {
+        // Only support one bus, and no extended configuration space.
+        if bus != 0 || register >= NUM_CONFIGURATION_REGISTERS {
             return;
         }
 
         let pci_bus = self.pci_bus.lock().unwrap();
-        if let Some(d) = pci_bus.devices.get(device) {
+        if let Some(d) = pci_bus.get_device(device, function) {
             let mut device = d.lock().unwrap();
 
             // Find out if one of the device's BAR is being reprogrammed, and
@@ -376,3 +485,129 @@ fn parse_config_address(config_address: u32) -> (usize, usize, usize, usize) {
 
     (bus_number, device_number, function_number, register_number)
 }
+
+// Parse the offset of an access to the MMCONFIG area, laid out as PCI
+// Express ECAM, to a (bus, device, function, register) tuple.
+fn parse_mmio_config_address(config_address: u32) -> (usize, usize, usize, usize) {
+    const BUS_NUMBER_OFFSET: usize = 20;
+    const BUS_NUMBER_MASK: u32 = 0x00ff;
+    const DEVICE_NUMBER_OFFSET: usize = 15;
+    const DEVICE_NUMBER_MASK: u32 = 0x1f;
+    const FUNCTION_NUMBER_OFFSET: usize = 12;
+    const FUNCTION_NUMBER_MASK: u32 = 0x07;
+    const REGISTER_NUMBER_OFFSET: usize = 2;
+    const REGISTER_NUMBER_MASK: u32 = 0x3ff;
+
+    let bus_number = ((config_address >> BUS_NUMBER_OFFSET) & BUS_NUMBER_MASK) as usize;
+    let device_number = ((config_address >> DEVICE_NUMBER_OFFSET) & DEVICE_NUMBER_MASK) as usize;
+    let function_number =
+        ((config_address >> FUNCTION_NUMBER_OFFSET) & FUNCTION_NUMBER_MASK) as usize;
+    let register_number =
+        ((config_address >> REGISTER_NUMBER_OFFSET) & REGISTER_NUMBER_MASK) as usize;
+
+    (bus_number, device_number, function_number, register_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    struct NoRelocation;
+
+    impl DeviceRelocation for NoRelocation {
+        fn move_bar(
+            &self,
+            _old_base: u64,
+            _new_base: u64,
+            _len: u64,
+            _pci_dev: &mut dyn PciDevice,
+            _region_type: PciBarRegionType,
+        ) -> std::result::Result<(), io::Error> {
+            Ok(())
+        }
+    }
+
+    fn new_device() -> Arc<Mutex<dyn PciDevice>> {
+        Arc::new(Mutex::new(PciRoot::new(None)))
+    }
+
+    #[test]
+    fn multifunction_devices() {
+        let device_reloc: Weak<dyn DeviceRelocation> = Weak::<NoRelocation>::new();
+        let mut bus = PciBus::new(PciRoot::new(None), device_reloc);
+
+        bus.add_device(new_device()).unwrap();
+        for _ in 0..9 {
+            bus.add_function(new_device()).unwrap();
+        }
+        assert_eq!(bus.next_function_id(), 3 << 3 | 1);
+        bus.add_device(new_device()).unwrap();
+        assert_eq!(bus.next_device_id(), 5);
+
+        // Only the function 0 of the packed devices tells about the other
+        // functions.
+        let header_type = |device, function| {
+            bus.read_config_register(device, function, HEADER_TYPE_REG) & HEADER_TYPE_MULTIFUNCTION
+        };
+        assert_eq!(header_type(1, 0), 0);
+        assert_eq!(header_type(2, 0), HEADER_TYPE_MULTIFUNCTION);
+        assert_eq!(header_type(2, 7), 0);
+        assert_eq!(header_type(3, 0), 0);
+        assert_eq!(header_type(4, 0), 0);
+        assert_eq!(bus.read_config_register(3, 1, 0), 0xffff_ffff);
+        assert!(bus.get_device(2, 7).is_some());
+    }
+
+    #[test]
+    fn mmio_config_address() {
+        // Register 0x10 of the function 2 of the device 3, on the bus 1.
+        assert_eq!(
+            parse_mmio_config_address(1 << 20 | 3 << 15 | 2 << 12 | 0x40),
+            (1, 3, 2, 0x10)
+        );
+        // Extended configuration space.
+        assert_eq!(parse_mmio_config_address(0x100), (0, 0, 0, 0x40));
+        assert_eq!(
+            parse_config_address(1 << 16 | 3 << 11 | 2 << 8 | 0x40),
+            (1, 3, 2, 0x10)
+        );
+    }
+
+    #[test]
+    fn no_device_slot_available() {
+        let device_reloc: Weak<dyn DeviceRelocation> = Weak::<NoRelocation>::new();
+        let mut bus = PciBus::new(PciRoot::new(None), device_reloc);
+
+        for _ in 1..NUM_DEVICE_IDS {
+            bus.add_device(new_device()).unwrap();
+        }
+        assert!(bus.add_device(new_device()).is_err());
+        assert!(bus.add_function(new_device()).is_err());
+    }
+
+    #[test]
+    fn remove_device() {
+        let device_reloc: Weak<dyn DeviceRelocation> = Weak::<NoRelocation>::new();
+        let mut bus = PciBus::new(PciRoot::new(None), device_reloc);
+
+        for _ in 0..3 {
+            bus.add_device(new_device()).unwrap();
+        }
+        bus.add_function(new_device()).unwrap();
+        bus.add_function(new_device()).unwrap();
+
+        // Neither the host bridge, nor a device with several functions, nor
+        // a missing one.
+        assert!(bus.remove_device(0).is_none());
+        assert!(bus.remove_device(4).is_none());
+        assert!(bus.remove_device(5).is_none());
+
+        // The device number of the removed device is handed out again.
+        assert!(bus.remove_device(2).is_some());
+        assert!(bus.get_device(2, 0).is_none());
+        assert_eq!(bus.next_device_id(), 2);
+        bus.add_device(new_device()).unwrap();
+        assert_eq!(bus.next_device_id(), 5);
+    }
+}
@@ -676,12 +676,21 @@ impl PciConfiguration {
         let mask = self.writable_bits[reg_idx];
         if reg_idx >= BAR0_REG && reg_idx < BAR0_REG + NUM_BAR_REGS {
             let bar_idx = reg_idx - 4;
+            if bar_idx > 0
+                && self.bar_type[bar_idx].is_none()
+                && self.bar_type[bar_idx - 1] == Some(PciBarRegionType::Memory64BitRegion)
+            {
+                return self.detect_bar64_reprogramming(reg_idx, value);
+            }
+
             if (value & mask) != (self.bar_addr[bar_idx] & mask) {
                 // Handle special case where the address being written is
                 // different from the address initially provided. This is a
                 // BAR reprogramming case which needs to be properly caught.
                 if let Some(bar_type) = self.bar_type[bar_idx] {
                     match bar_type {
+                        // The upper half of the BAR, written last, tells
+                        // where it is moved to.
                         PciBarRegionType::Memory64BitRegion => {}
                         _ => {
                             debug!(
@@ -703,31 +712,6 @@ impl PciConfiguration {
                             });
                         }
                     }
-                } else if (reg_idx > BAR0_REG)
-                    && (self.registers[reg_idx - 1] & self.writable_bits[reg_idx - 1])
-                        != (self.bar_addr[bar_idx - 1] & self.writable_bits[reg_idx - 1])
-                {
-                    debug!(
-                        "DETECT BAR REPROG: current 0x{:x}, new 0x{:x}",
-                        self.registers[reg_idx], value
-                    );
-                    let old_base = u64::from(self.bar_addr[bar_idx] & mask) << 32
-                        | u64::from(self.bar_addr[bar_idx - 1] & self.writable_bits[reg_idx - 1]);
-                    let new_base = u64::from(value & mask) << 32
-                        | u64::from(self.registers[reg_idx - 1] & self.writable_bits[reg_idx - 1]);
-                    let len = u64::from(self.bar_size[bar_idx]) << 32
-                        | u64::from(self.bar_size[bar_idx - 1]);
-                    let region_type = PciBarRegionType::Memory64BitRegion;
-
-                    self.bar_addr[bar_idx] = value;
-                    self.bar_addr[bar_idx - 1] = self.registers[reg_idx - 1];
-
-                    return Some(BarReprogrammingParams {
-                        old_base,
-                        new_base,
-                        len,
-                        region_type,
-                    });
                 }
             }
         } else if reg_idx == ROM_BAR_REG && (value & mask) != (self.rom_bar_addr & mask) {
@@ -752,6 +736,43 @@ impl PciConfiguration {
 
         None
     }
+
+    // The upper half of a 64 bits BAR is written after its lower half, which
+    // the register already holds: the BAR is moved if either of them
+    // changed.
+    fn detect_bar64_reprogramming(
+        &mut self,
+        reg_idx: usize,
+        value: u32,
+    ) -> Option<BarReprogrammingParams> {
+        let bar_idx = reg_idx - 4;
+        let mask = self.writable_bits[reg_idx];
+        let lsb_mask = self.writable_bits[reg_idx - 1];
+
+        let old_base = u64::from(self.bar_addr[bar_idx] & mask) << 32
+            | u64::from(self.bar_addr[bar_idx - 1] & lsb_mask);
+        let new_base =
+            u64::from(value & mask) << 32 | u64::from(self.registers[reg_idx - 1] & lsb_mask);
+        if new_base == old_base {
+            return None;
+        }
+
+        debug!(
+            "DETECT BAR REPROG: current 0x{:x}, new 0x{:x}",
+            old_base, new_base
+        );
+        let len = u64::from(self.bar_size[bar_idx]) << 32 | u64::from(self.bar_size[bar_idx - 1]);
+
+        self.bar_addr[bar_idx] = value;
+        self.bar_addr[bar_idx - 1] = self.registers[reg_idx - 1];
+
+        Some(BarReprogrammingParams {
+            old_base,
+            new_base,
+            len,
+            region_type: PciBarRegionType::Memory64BitRegion,
+        })
+    }
 }
 
 impl Default for PciBarConfiguration {
@@ -911,4 +932,65 @@ mod tests {
         assert_eq!(subclass, 0x01);
         assert_eq!(prog_if, 0x5a);
     }
+
+    #[test]
+    fn move_bar64() {
+        let mut cfg = PciConfiguration::new(
+            0x1234,
+            0x5678,
+            PciClassCode::MultimediaController,
+            &PciMultimediaSubclass::AudioController,
+            None,
+            PciHeaderType::Device,
+            0xABCD,
+            0x2468,
+            None,
+        );
+
+        // 16GiB BAR, larger than its lower half can describe, and 1GiB
+        // BAR.
+        let bar = PciBarConfiguration::default()
+            .set_register_index(0)
+            .set_address(0x40_0000_0000)
+            .set_size(0x4_0000_0000)
+            .set_region_type(PciBarRegionType::Memory64BitRegion);
+        cfg.add_pci_bar(&bar).unwrap();
+        let bar = PciBarConfiguration::default()
+            .set_register_index(2)
+            .set_address(0x44_0000_0000)
+            .set_size(0x4000_0000)
+            .set_region_type(PciBarRegionType::Memory64BitRegion);
+        cfg.add_pci_bar(&bar).unwrap();
+
+        // The guest sizing the BAR gets its size back, without moving it.
+        let mut size = Vec::new();
+        for reg in BAR0_REG..BAR0_REG + 2 {
+            let addr = cfg.read_reg(reg);
+            let ones = 0xffff_ffffu32.to_le_bytes();
+            assert!(cfg.detect_bar_reprogramming(reg, &ones).is_none());
+            cfg.write_reg(reg, 0xffff_ffff);
+            size.push(cfg.read_reg(reg));
+            assert!(cfg
+                .detect_bar_reprogramming(reg, &addr.to_le_bytes())
+                .is_none());
+            cfg.write_reg(reg, addr);
+        }
+        assert_eq!(size[0] & BAR_MEM_ADDR_MASK, 0);
+        assert_eq!(size[1], 0x4);
+
+        // Moving the second BAR within the same 4GiB is caught on the write
+        // of its upper half.
+        let lsb = cfg.read_reg(BAR0_REG + 2) | 0x4000_0000;
+        assert!(cfg
+            .detect_bar_reprogramming(BAR0_REG + 2, &lsb.to_le_bytes())
+            .is_none());
+        cfg.write_reg(BAR0_REG + 2, lsb);
+        let msb = cfg.read_reg(BAR0_REG + 3);
+        let params = cfg
+            .detect_bar_reprogramming(BAR0_REG + 3, &msb.to_le_bytes())
+            .unwrap();
+        assert_eq!(params.old_base, 0x44_0000_0000);
+        assert_eq!(params.new_base, 0x44_4000_0000);
+        assert_eq!(params.len, 0x4000_0000);
+    }
 }
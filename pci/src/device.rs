@@ -11,6 +11,7 @@ use std::fmt::{self, Display};
 use std::sync::Arc;
 use std::{self, io, result};
 use vm_allocator::SystemAllocator;
+use vm_device::InterruptSourceGroup;
 use vm_memory::{GuestAddress, GuestUsize};
 
 pub struct InterruptParameters<'a> {
@@ -66,8 +67,9 @@ pub trait PciDevice: BusDevice {
     ) {
     }
 
-    /// Assign MSI-X to this device.
-    fn assign_msix(&mut self, _msi_cb: Arc<InterruptDelivery>) {}
+    /// Assign MSI-X to this device, each vector of its table raising the
+    /// interrupt of the group with the same index.
+    fn assign_msix(&mut self, _interrupt_group: Arc<dyn InterruptSourceGroup>) {}
 
     /// Allocates the needed PCI BARs space using the `allocate` function which takes a size and
     /// returns an address. Returns a Vec of (GuestAddress, GuestUsize) tuples.
@@ -78,6 +80,16 @@ pub trait PciDevice: BusDevice {
         Ok(Vec::new())
     }
 
+    /// Frees the PCI BARs space `allocate_bars()` allocated, from where the
+    /// guest last moved the BARs to, before the device is removed. Returns
+    /// the freed ranges, for their mappings to be removed as well.
+    fn free_bars(
+        &mut self,
+        _allocator: &mut SystemAllocator,
+    ) -> Vec<(GuestAddress, GuestUsize, PciBarRegionType)> {
+        Vec::new()
+    }
+
     /// Sets a register in the configuration space.
     /// * `reg_idx` - The index of the config register to modify.
     /// * `offset` - Offset in to the register.
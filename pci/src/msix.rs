@@ -8,9 +8,9 @@ extern crate vm_memory;
 
 use std::sync::Arc;
 
-use crate::device::InterruptParameters;
-use crate::{InterruptDelivery, PciCapability, PciCapabilityID};
+use crate::{PciCapability, PciCapabilityID};
 use byteorder::{ByteOrder, LittleEndian};
+use vm_device::{InterruptIndex, InterruptSourceGroup, MsiIrqSourceConfig};
 use vm_memory::ByteValued;
 
 const MAX_MSIX_VECTORS_PER_DEVICE: u16 = 2048;
@@ -51,7 +51,8 @@ impl Default for MsixTableEntry {
 pub struct MsixConfig {
     pub table_entries: Vec<MsixTableEntry>,
     pub pba_entries: Vec<u64>,
-    interrupt_cb: Option<Arc<InterruptDelivery>>,
+    // Interrupts of the vectors, routed as the guest programs the table.
+    interrupt_source_group: Option<Arc<dyn InterruptSourceGroup>>,
     masked: bool,
     enabled: bool,
 }
@@ -69,14 +70,37 @@ impl MsixConfig {
         MsixConfig {
             table_entries,
             pba_entries,
-            interrupt_cb: None,
+            interrupt_source_group: None,
             masked: false,
             enabled: false,
         }
     }
 
-    pub fn register_interrupt_cb(&mut self, cb: Arc<InterruptDelivery>) {
-        self.interrupt_cb = Some(cb);
+    pub fn register_interrupt_source_group(&mut self, group: Arc<dyn InterruptSourceGroup>) {
+        self.interrupt_source_group = Some(group);
+        for index in 0..self.table_entries.len() {
+            self.update_route(index);
+        }
+    }
+
+    // Routes the vector to its message, unless it is masked, or MSI-X is
+    // masked or disabled for the whole device.
+    fn update_route(&self, index: usize) {
+        if let Some(group) = &self.interrupt_source_group {
+            let entry = &self.table_entries[index];
+            let config = MsiIrqSourceConfig {
+                low_addr: entry.msg_addr_lo,
+                high_addr: entry.msg_addr_hi,
+                data: entry.msg_data,
+            };
+            let masked = !self.enabled || self.masked || entry.masked();
+            if let Err(e) = group.update(index as InterruptIndex, config, masked) {
+                error!(
+                    "failed to update the route of MSI-X vector {}: {}",
+                    index, e
+                );
+            }
+        }
     }
 
     pub fn masked(&self) -> bool {
@@ -89,10 +113,17 @@ impl MsixConfig {
 
     pub fn set_msg_ctl(&mut self, reg: u16) {
         let old_masked = self.masked;
+        let old_enabled = self.enabled;
 
         self.masked = ((reg >> FUNCTION_MASK_BIT) & 1u16) == 1u16;
         self.enabled = ((reg >> MSIX_ENABLE_BIT) & 1u16) == 1u16;
 
+        if old_masked != self.masked || old_enabled != self.enabled {
+            for index in 0..self.table_entries.len() {
+                self.update_route(index);
+            }
+        }
+
         // If the Function Mask bit was set, and has just been cleared, it's
         // important to go through the entire PBA to check if there was any
         // pending MSI-X message to inject, given that the vector is not
@@ -198,6 +229,8 @@ impl MsixConfig {
             _ => error!("invalid data length"),
         };
 
+        self.update_route(index);
+
         // After the MSI-X table entry has been updated, it is necessary to
         // check if the vector control masking bit has changed. In case the
         // bit has been flipped from 1 to 0, we need to inject a MSI message
@@ -285,10 +318,8 @@ impl MsixConfig {
 
     fn inject_msix_and_clear_pba(&mut self, vector: usize) {
         // Inject the MSI message
-        if let Some(cb) = &self.interrupt_cb {
-            match (cb)(InterruptParameters {
-                msix: Some(&self.table_entries[vector]),
-            }) {
+        if let Some(group) = &self.interrupt_source_group {
+            match group.trigger(vector as InterruptIndex) {
                 Ok(_) => debug!("MSI-X injected on vector control flip"),
                 Err(e) => error!("failed to inject MSI-X: {}", e),
             };
@@ -385,3 +416,85 @@ impl MsixCap {
         (self.msg_ctl & 0x7ff) + 1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Mutex;
+    use vmm_sys_util::eventfd::EventFd;
+
+    // Records the routes of the vectors, and the interrupts raised.
+    #[derive(Default)]
+    struct TestGroup {
+        routes: Mutex<Vec<Option<MsiIrqSourceConfig>>>,
+        triggered: Mutex<Vec<InterruptIndex>>,
+    }
+
+    impl InterruptSourceGroup for TestGroup {
+        fn trigger(&self, index: InterruptIndex) -> io::Result<()> {
+            self.triggered.lock().unwrap().push(index);
+            Ok(())
+        }
+
+        fn notifier(&self, _index: InterruptIndex) -> Option<&EventFd> {
+            None
+        }
+
+        fn update(
+            &self,
+            index: InterruptIndex,
+            config: MsiIrqSourceConfig,
+            masked: bool,
+        ) -> io::Result<()> {
+            let mut routes = self.routes.lock().unwrap();
+            routes.resize(routes.len().max(index as usize + 1), None);
+            routes[index as usize] = if masked { None } else { Some(config) };
+            Ok(())
+        }
+
+        fn masked(&self, index: InterruptIndex) -> bool {
+            self.routes.lock().unwrap()[index as usize].is_none()
+        }
+
+        fn free(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn vector_routes() {
+        let group = Arc::new(TestGroup::default());
+        let mut msix = MsixConfig::new(2);
+        msix.register_interrupt_source_group(group.clone());
+        assert!(group.masked(0) && group.masked(1));
+
+        // The entries are programmed before MSI-X is enabled.
+        msix.write_table(0x0, &0xfee0_0000u32.to_le_bytes());
+        msix.write_table(0x8, &0x41u32.to_le_bytes());
+        msix.write_table(0x1c, &1u32.to_le_bytes());
+        assert!(group.masked(0));
+
+        msix.set_msg_ctl(MSIX_ENABLE_MASK);
+        assert_eq!(
+            group.routes.lock().unwrap()[0],
+            Some(MsiIrqSourceConfig {
+                low_addr: 0xfee0_0000,
+                high_addr: 0,
+                data: 0x41,
+            })
+        );
+        assert!(group.masked(1));
+
+        // A pending interrupt is raised once its vector is unmasked.
+        msix.set_pba_bit(1, false);
+        msix.write_table(0x1c, &0u32.to_le_bytes());
+        assert!(!group.masked(1));
+        assert_eq!(*group.triggered.lock().unwrap(), vec![1]);
+        assert_eq!(msix.get_pba_bit(1), 0);
+
+        // Masking the function stops routing all the vectors.
+        msix.set_msg_ctl(MSIX_ENABLE_MASK | FUNCTION_MASK_MASK);
+        assert!(group.masked(0) && group.masked(1));
+    }
+}
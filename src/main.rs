@@ -83,7 +83,7 @@ fn main() {
     let default_memory = format! {"size={}M", config::DEFAULT_MEMORY_MB};
     let default_rng = format! {"src={}", config::DEFAULT_RNG_SOURCE};
 
-    let cmd_arguments = App::new("cloud-hypervisor")
+    let app = App::new("cloud-hypervisor")
         .version(crate_version!())
         .author(crate_authors!())
         .about("Launch a cloud-hypervisor VMM.")
@@ -92,7 +92,15 @@ fn main() {
         .arg(
             Arg::with_name("cpus")
                 .long("cpus")
-                .help("Number of virtual CPUs")
+                .help(
+                    "Number of virtual CPUs, with an optional topology, host CPU affinity, \
+                     hidden and enabled CPU features, Hyper-V enlightenments and reported \
+                     frequency \"<boot_vcpus>,topology=threads:<threads_per_core>,\
+                     cores_per_die:<cores_per_die>,dies:<dies_per_package>,sockets:<packages>,\
+                     affinity=[<vcpu>@[<host_cpu>,...],...],\
+                     disable_features=[<feature>,...],features=vmx|svm|amx,kvm_hyperv=on|off,\
+                     frequency=<frequency_mhz>\"",
+                )
                 .default_value(&default_vcpus)
                 .group("vm-config"),
         )
@@ -101,22 +109,55 @@ fn main() {
                 .long("memory")
                 .help(
                     "Memory parameters \"size=<guest_memory_size>,\
-                     file=<backing_file_path>\"",
+                     file=<backing_file_path>,hugepages=on|off,\
+                     hugepage_size=<huge_page_size>,\
+                     hugepages_fallback=none|thp|small,prefault=on|off,\
+                     mergeable=on|off,thp=on|off\"",
                 )
                 .default_value(&default_memory)
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("memory-zone")
+                .long("memory-zone")
+                .help(
+                    "Guest memory zone parameters, overriding the memory size \
+                     \"size=<zone_size>,file=<backing_file_path>,shared=on|off,\
+                     hugepages=on|off,hugepage_size=<huge_page_size>,\
+                     hugepages_fallback=none|thp|small\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help(
+                    "Path to a JSON or TOML file holding the VM configuration, \
+                     the other options overriding its fields",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("kernel")
                 .long("kernel")
-                .help("Path to kernel image (vmlinux)")
+                .help("Path to kernel image (vmlinux, bzImage), IGVM image or firmware")
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("initramfs")
+                .long("initramfs")
+                .help("Path to initramfs image")
                 .takes_value(true)
                 .group("vm-config"),
         )
         .arg(
             Arg::with_name("cmdline")
                 .long("cmdline")
-                .help("Kernel command line")
+                .help("Kernel command line, with {variable} placeholders")
                 .takes_value(true)
                 .group("vm-config"),
         )
@@ -125,7 +166,25 @@ fn main() {
                 .long("disk")
                 .help(
                     "Disk parameters \"path=<disk_image_path>,\
-                     iommu=on|off\"",
+                     iommu=on|off,bw_size=<bytes>,bw_one_time_burst=<bytes>,\
+                     bw_refill_time=<ms>,ops_size=<io_ops>,\
+                     ops_one_time_burst=<io_ops>,ops_refill_time=<ms>,\
+                     model=virtio|ahci,queue_size=<size_of_the_queue>,\
+                     group=<disk_group_id>,weight=<weight_in_group>,\
+                     readonly=on|off,removable=on|off,\
+                     cache=on|off|writeback|none|unsafe,\
+                     direct=on|off,id=<device_id>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("disk-group")
+                .long("disk-group")
+                .help(
+                    "Bandwidth shared by the disks of the group, in proportion \
+                     to their weights \"id=<disk_group_id>,bandwidth=<bytes_per_second>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -135,9 +194,15 @@ fn main() {
             Arg::with_name("net")
                 .long("net")
                 .help(
-                    "Network parameters \"tap=<if_name>,\
-                     ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,\
-                     iommu=on|off\"",
+                    "Network parameters \"tap=<if_name>,macvtap=<if_name>,\
+                     ip=<ip_addr>,mask=<net_mask>,ip6=<ipv6_addr>,\
+                     ip6_prefix_len=<ipv6_prefix_len>,mac=<mac_addr>,\
+                     iommu=on|off,bw_size=<bytes>,bw_one_time_burst=<bytes>,\
+                     bw_refill_time=<ms>,ops_size=<frames>,\
+                     ops_one_time_burst=<frames>,ops_refill_time=<ms>,\
+                     model=virtio|e1000,queue_size=<size_of_each_queue>,\
+                     fds=[<tap_fd>,...],offload_csum=on|off,offload_tso=on|off,\
+                     offload_ufo=on|off,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -160,7 +225,7 @@ fn main() {
                     "virtio-fs parameters \"tag=<tag_name>,\
                      sock=<socket_path>,num_queues=<number_of_queues>,\
                      queue_size=<size_of_each_queue>,dax=on|off,\
-                     cache_size=<DAX cache size: default 8Gib>\"",
+                     cache_size=<DAX cache size: default 8Gib>,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -171,7 +236,18 @@ fn main() {
                 .long("pmem")
                 .help(
                     "Persistent memory parameters \"file=<backing_file_path>,\
-                     size=<persistent_memory_size>,iommu=on|off\"",
+                     size=<persistent_memory_size>,iommu=on|off,id=<device_id>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("nvdimm")
+                .long("nvdimm")
+                .help(
+                    "ACPI NVDIMM parameters \"file=<backing_file_path>,\
+                     size=<nvdimm_size>,label_size=<label_area_size>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -180,27 +256,90 @@ fn main() {
         .arg(
             Arg::with_name("serial")
                 .long("serial")
-                .help("Control serial port: off|null|tty|file=/path/to/a/file")
+                .help(
+                    "Control serial port: \"off|null|pty|tty|file=/path/to/a/file|\
+                     socket=/path/to/a/socket\"",
+                )
                 .default_value("null")
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("uart")
+                .long("uart")
+                .help(
+                    "Additional 16550 UARTs, COM2 to COM4 by default: \
+                     \"null|pty|file=/path/to/a/file|socket=/path/to/a/socket,\
+                     port=<io_port>,irq=3|4\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("console")
                 .long("console")
                 .help(
-                    "Control (virtio) console: \"off|null|tty|file=/path/to/a/file,\
-                     iommu=on|off\"",
+                    "Control (virtio) console: \"off|null|pty|tty|file=/path/to/a/file|\
+                     socket=/path/to/a/socket,iommu=on|off,queue_size=<size_of_each_queue>\"",
                 )
                 .default_value("tty")
                 .group("vm-config"),
         )
+        .arg(
+            Arg::with_name("console-port")
+                .long("console-port")
+                .help(
+                    "Additional virtio-console ports: \"name=<port_name>,\
+                     socket=/path/to/a/socket\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
         .arg(
             Arg::with_name("device")
                 .long("device")
                 .help("Direct device assignment parameter")
                 .help(
                     "Direct device assignment parameters \
-                     \"path=<device_path>,iommu=on|off\"",
+                     \"path=<device_path>|mdev=<mdev_uuid>,iommu=on|off,\
+                     pci_segment=<segment_id>,romfile=<rom_path>,mac=<vf_mac>,\
+                     id=<device_id>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("user-device")
+                .long("user-device")
+                .help(
+                    "Device emulated by a vfio-user server \
+                     \"socket=<vfio_user_socket>,iommu=on|off,\
+                     pci_segment=<segment_id>,id=<device_id>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("plugin")
+                .long("plugin")
+                .help(
+                    "Device type emulated by a plugin executable \
+                     \"name=<device_type>,path=<plugin_executable>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("plugin-device")
+                .long("plugin-device")
+                .help(
+                    "Device of a plugin device type \
+                     \"plugin=<device_type>,config=<plugin_config>,iommu=on|off,\
+                     pci_segment=<segment_id>,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -212,7 +351,7 @@ fn main() {
                 .help(
                     "Network parameters \"mac=<mac_addr>,\
                      sock=<socket_path>, num_queues=<number_of_queues>,\
-                     queue_size=<size_of_each_queue>\"",
+                     queue_size=<size_of_each_queue>,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -223,7 +362,316 @@ fn main() {
                 .long("vsock")
                 .help(
                     "Virtio VSOCK parameters \"cid=<context_id>,\
-                     sock=<socket_path>,iommu=on|off\"",
+                     sock=<socket_path>,iommu=on|off,\
+                     queue_size=<size_of_each_queue>,id=<device_id>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("numa")
+                .long("numa")
+                .help(
+                    "Guest NUMA node parameters \"id=<node_id>,size=<memory_size>,\
+                     cpus=<first>-<last>:<cpu>,distances=<node_id>@<distance>:...,\
+                     host_node=<host_node_id>,pci_segments=<segment_id>:...\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .help(
+                    "VM profile: \"default|unikernel|windows\". The unikernel \
+                     profile boots an ELF kernel on a single vCPU, without ACPI nor \
+                     PCI (virtio-mmio only), serial and console being off by default. \
+                     The windows profile enables the Hyper-V enlightenments and the \
+                     WAET ACPI table",
+                )
+                .takes_value(true)
+                .default_value("default")
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("confidential-guest")
+                .long("confidential-guest")
+                .help(
+                    "Guest memory is encrypted (SEV-ES/SNP). Virtio devices \
+                     then require the guest to use bounce buffers for DMA",
+                )
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("pvpanic")
+                .long("pvpanic")
+                .help(
+                    "pvpanic device, through which the guest reports its panics \
+                     to the event monitor, and what is done with the VM then \
+                     \"action=none|pause|reboot|shutdown\"",
+                )
+                .takes_value(true)
+                .min_values(0)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("platform")
+                .long("platform")
+                .help(
+                    "Platform the guest runs on: \"default|tdx|sev-snp\". TDX and \
+                     SEV-SNP guests have their memory and vCPUs state encrypted, \
+                     and boot from a firmware for that platform",
+                )
+                .takes_value(true)
+                .default_value("default")
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("latency-profile")
+                .long("latency-profile")
+                .help(
+                    "Latency profile: \"default|realtime\". The realtime vCPUs run \
+                     with SCHED_FIFO on isolated host CPUs of their own, without \
+                     exiting on HLT and PAUSE, and the VMM memory is locked",
+                )
+                .takes_value(true)
+                .default_value("default")
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("sensors")
+                .long("sensors")
+                .help(
+                    "Synthetic ACPI sensors, whose readings are set through the \
+                     vm.sensors API \"battery=on|off,thermal_zone=on|off\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("idle")
+                .long("idle")
+                .help(
+                    "Guest idleness detection, after all vCPUs are halted for \
+                     the timeout, parking of the vCPU threads of an idle \
+                     guest, and paging out of its memory after it is idle for \
+                     long \"timeout=<seconds>,park=on|off,hibernate=<seconds>\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("halt-poll")
+                .long("halt-poll")
+                .help(
+                    "Longest the halted vCPUs poll for a wakeup, and whether the \
+                     VMM adapts it to the guest wakeups \"max_ns=<nanoseconds>,\
+                     adaptive=on|off\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("sgx-epc")
+                .long("sgx-epc")
+                .help(
+                    "SGX EPC section exposed to the guest \
+                     \"size=<epc_section_size>,prefault=on|off\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("acpi-table")
+                .long("acpi-table")
+                .help(
+                    "ACPI table added as is to the guest ACPI tables, such as \
+                     an SSDT \"path=<table_file>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("acpi-oem-table")
+                .long("acpi-oem-table")
+                .help(
+                    "ACPI OEM table of key-value pairs \
+                     \"signature=<table_signature>,<key>=<value>,...\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("hostname")
+                .long("hostname")
+                .help("Hostname of the guest")
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("resolvers")
+                .long("resolvers")
+                .help("DNS resolvers of the guest \"<ip>,<ip>,...\"")
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("tpm")
+                .long("tpm")
+                .help("TPM 2.0 emulated by swtpm \"socket=<swtpm_control_socket>\"")
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("pci")
+                .long("pci")
+                .help(
+                    "PCI bus layout, packing the virtio devices 8 per PCI \
+                     device as multifunction devices, and count of PCI \
+                     segments \"multifunction=on|off,num_pci_segments=<count>\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("security")
+                .long("security")
+                .help(
+                    "Mandatory access control labels of the VMM and of the files of the VM \
+                     \"selinux_process_label=<context>,selinux_file_label=<context>,\
+                     apparmor_profile=<profile>\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("landlock")
+                .long("landlock")
+                .help(
+                    "Confine the VMM with Landlock to the files of the VM once they are opened, \
+                     and to the ones of the rules \"path=<path>,access=ro|rw\"",
+                )
+                .takes_value(true)
+                .min_values(0)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .help(
+                    "Drop the privileges of the VMM to the user and groups once the files and \
+                     devices of the VM are opened \"uid=<uid>,gid=<gid>,groups=<gid>:<gid>\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("clock-drift")
+                .long("clock-drift")
+                .help(
+                    "Report the drift of the guest clock from the host clock past a threshold \
+                     \"threshold=<drift_ms>,period=<check_period_s>\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("ptp")
+                .long("ptp")
+                .help(
+                    "The guest reads the host clock through the KVM PTP clock, \
+                     failing the VM creation if the host doesn't provide it",
+                )
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("cgroup")
+                .long("cgroup")
+                .help(
+                    "cgroup v2 the VMM is moved into, with the vCPU and device \
+                     threads in sub-groups of their own \
+                     \"path=<cgroup_path>,io_weight=<weight>,vcpu_weight=<weight>,\
+                     vcpu_quota=<percent>,vcpu_cpus=[<host_cpu>,...],\
+                     device_weight=<weight>,device_quota=<percent>,\
+                     device_cpus=[<host_cpu>,...]\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("gpu")
+                .long("gpu")
+                .help(
+                    "virtio-gpu device, whose display is read through the API \
+                     \"width=<width>,height=<height>,iommu=on|off\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("input")
+                .long("input")
+                .help(
+                    "virtio-input keyboard and tablet, fed through a UNIX socket \
+                     \"socket=<socket_path>,iommu=on|off\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("gdb")
+                .long("gdb")
+                .help("GDB remote serial protocol stub \"path=<gdb_socket_path>\"")
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("diagnostics")
+                .long("diagnostics")
+                .help(
+                    "Directory the diagnostic bundles are written to when the \
+                     guest panics or triple faults, and audit of the guest drivers \
+                     of the virtio devices \"path=<bundles_directory>,audit=on|off\"",
+                )
+                .takes_value(true)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("guest-os")
+                .long("guest-os")
+                .help(
+                    "Probe the guest OS, from its boot messages and from the \
+                     report of a guest agent on vsock \"port=<vsock_port>\"",
+                )
+                .takes_value(true)
+                .min_values(0)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("hook")
+                .long("hook")
+                .help(
+                    "Host executable the guest has run by requesting its name \
+                     on a vsock port \"name=<hook_name>,command=<host_executable>,\
+                     port=<vsock_port>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vm-config"),
+        )
+        .arg(
+            Arg::with_name("pci-segment")
+                .long("pci-segment")
+                .help(
+                    "Sizes of the MMIO apertures of a PCI segment \
+                     \"pci_segment=<segment_id>,\
+                     mmio32_aperture_size=<aperture_size>,\
+                     mmio64_aperture_size=<aperture_size>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -236,11 +684,100 @@ fn main() {
                     "Vhost user Block parameters \"sock=<socket_path>,\
                      num_queues=<number_of_queues>,\
                      queue_size=<size_of_each_queue>, \
-                     wce=<true|false, default true>\"",
+                     wce=<true|false, default true>,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1),
         )
+        .arg(
+            Arg::with_name("event-monitor")
+                .long("event-monitor")
+                .help(
+                    "File to report the VM lifecycle events to, as JSON objects \
+                     \"path=<event_file>\" or \"fd=<file_descriptor>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("api-audit-log")
+                .long("api-audit-log")
+                .help(
+                    "File to log the HTTP API requests to, as JSON objects \
+                     \"path=<log_file>\" or \"fd=<file_descriptor>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("host-resources")
+                .long("host-resources")
+                .help(
+                    "Reserve the TAP interfaces, VFIO devices and huge pages of the VM in a \
+                     registry shared with the other VMM processes of the host, by default \
+                     /run/cloud-hypervisor/resources",
+                )
+                .takes_value(true)
+                .min_values(0)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("cleanup-stale")
+                .long("cleanup-stale")
+                .help(
+                    "Reclaim the TAP interfaces, files, sockets and reservations the dead VMM \
+                     processes left behind in the host resources registry, before starting",
+                )
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("vm-pool")
+                .long("vm-pool")
+                .help(
+                    "Boot a pool of VMs from the VM configuration, paused until they are \
+                     claimed through the vm.claim API, their guest agent waiting for its \
+                     claim on vsock \"size=<number_of_vms>,port=<vsock_port>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("block-cache")
+                .long("block-cache")
+                .help(
+                    "Cache of the read-only disk images with cache=on, shared by the VMs \
+                     of the process \"size=<cache_size>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("tracing")
+                .long("tracing")
+                .help(
+                    "Trace the phases of the VMM, written once the VM booted \
+                     \"path=<trace_file>,format=json|chrome\"",
+                )
+                .takes_value(true)
+                .min_values(0)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("multi-tenant")
+                .long("multi-tenant")
+                .help(
+                    "Own the VMs by the Bearer token of the API client creating them, the \
+                     requests about the process needing the admin token of the file \
+                     \"admin_token=<admin_token_file>,tenant_dir=<tenant_files_directory>\"",
+                )
+                .takes_value(true)
+                .min_values(0)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -265,23 +802,86 @@ fn main() {
                 .default_value(&api_server_path)
                 .group("vmm-config"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("fd-socket")
+                .long("fd-socket")
+                .help(
+                    "UNIX domain socket the API clients pass the files of the VM through, \
+                     as SCM_RIGHTS ancillary data",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        );
+
+    #[cfg(feature = "dbus_api")]
+    let app = app
+        .arg(
+            Arg::with_name("dbus-service-name")
+                .long("dbus-service-name")
+                .help("D-Bus service name the API is served under, on the session bus by default")
+                .takes_value(true)
+                .min_values(0)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("dbus-object-path")
+                .long("dbus-object-path")
+                .help("D-Bus object path the API is served at")
+                .takes_value(true)
+                .default_value(vmm::api::dbus::DEFAULT_DBUS_OBJECT_PATH)
+                .group("vmm-config"),
+        )
+        .arg(
+            Arg::with_name("dbus-system-bus")
+                .long("dbus-system-bus")
+                .help("Serve the D-Bus API on the system bus")
+                .group("vmm-config"),
+        );
+
+    let cmd_arguments = app.get_matches();
 
     // These .unwrap()s cannot fail as there is a default value defined
     let cpus = cmd_arguments.value_of("cpus").unwrap();
     let memory = cmd_arguments.value_of("memory").unwrap();
     let rng = cmd_arguments.value_of("rng").unwrap();
-    let serial = cmd_arguments.value_of("serial").unwrap();
+    let profile = cmd_arguments.value_of("profile").unwrap();
+
+    // The unikernel profile does not poll the serial port nor the console
+    // unless they have been explicitly requested.
+    let unikernel = profile == "unikernel";
+    let serial = if unikernel && cmd_arguments.occurrences_of("serial") == 0 {
+        "off"
+    } else {
+        cmd_arguments.value_of("serial").unwrap()
+    };
 
     let kernel = cmd_arguments.value_of("kernel");
+    let initramfs = cmd_arguments.value_of("initramfs");
     let cmdline = cmd_arguments.value_of("cmdline");
 
     let disks: Option<Vec<&str>> = cmd_arguments.values_of("disk").map(|x| x.collect());
+    let disk_groups: Option<Vec<&str>> = cmd_arguments.values_of("disk-group").map(|x| x.collect());
     let net: Option<Vec<&str>> = cmd_arguments.values_of("net").map(|x| x.collect());
-    let console = cmd_arguments.value_of("console").unwrap();
+    let console = if unikernel && cmd_arguments.occurrences_of("console") == 0 {
+        "off"
+    } else {
+        cmd_arguments.value_of("console").unwrap()
+    };
     let fs: Option<Vec<&str>> = cmd_arguments.values_of("fs").map(|x| x.collect());
     let pmem: Option<Vec<&str>> = cmd_arguments.values_of("pmem").map(|x| x.collect());
+    let nvdimms: Option<Vec<&str>> = cmd_arguments.values_of("nvdimm").map(|x| x.collect());
     let devices: Option<Vec<&str>> = cmd_arguments.values_of("device").map(|x| x.collect());
+    let user_devices: Option<Vec<&str>> =
+        cmd_arguments.values_of("user-device").map(|x| x.collect());
+    let plugins: Option<Vec<&str>> = cmd_arguments.values_of("plugin").map(|x| x.collect());
+    let plugin_devices: Option<Vec<&str>> = cmd_arguments
+        .values_of("plugin-device")
+        .map(|x| x.collect());
+    let uarts: Option<Vec<&str>> = cmd_arguments.values_of("uart").map(|x| x.collect());
+    let console_ports: Option<Vec<&str>> = cmd_arguments
+        .values_of("console-port")
+        .map(|x| x.collect());
     let vhost_user_net: Option<Vec<&str>> = cmd_arguments
         .values_of("vhost-user-net")
         .map(|x| x.collect());
@@ -289,6 +889,17 @@ fn main() {
         .values_of("vhost-user-blk")
         .map(|x| x.collect());
     let vsock: Option<Vec<&str>> = cmd_arguments.values_of("vsock").map(|x| x.collect());
+    let memory_zones: Option<Vec<&str>> =
+        cmd_arguments.values_of("memory-zone").map(|x| x.collect());
+    let numa: Option<Vec<&str>> = cmd_arguments.values_of("numa").map(|x| x.collect());
+    let sgx_epc: Option<Vec<&str>> = cmd_arguments.values_of("sgx-epc").map(|x| x.collect());
+    let pci_segments: Option<Vec<&str>> =
+        cmd_arguments.values_of("pci-segment").map(|x| x.collect());
+    let acpi_tables: Option<Vec<&str>> = cmd_arguments.values_of("acpi-table").map(|x| x.collect());
+    let acpi_oem_tables: Option<Vec<&str>> = cmd_arguments
+        .values_of("acpi-oem-table")
+        .map(|x| x.collect());
+    let hooks: Option<Vec<&str>> = cmd_arguments.values_of("hook").map(|x| x.collect());
 
     let log_level = match cmd_arguments.occurrences_of("v") {
         0 => LevelFilter::Error,
@@ -314,23 +925,87 @@ fn main() {
     .map(|()| log::set_max_level(log_level))
     .expect("Expected to be able to setup logger");
 
-    let vm_config = match config::VmConfig::parse(config::VmParams {
+    let vm_params = config::VmParams {
         cpus,
         memory,
+        memory_zones,
         kernel,
+        initramfs,
         cmdline,
         disks,
+        disk_groups,
         net,
         rng,
         fs,
         pmem,
+        nvdimms,
         serial,
         console,
         devices,
+        user_devices,
+        plugins,
+        plugin_devices,
+        uarts,
+        console_ports,
         vhost_user_net,
         vhost_user_blk,
         vsock,
-    }) {
+        numa,
+        profile,
+        confidential_guest: cmd_arguments.is_present("confidential-guest"),
+        platform: cmd_arguments.value_of("platform").unwrap(),
+        latency_profile: cmd_arguments.value_of("latency-profile").unwrap(),
+        sensors: cmd_arguments.value_of("sensors"),
+        idle: cmd_arguments.value_of("idle"),
+        halt_poll: cmd_arguments.value_of("halt-poll"),
+        acpi_tables,
+        acpi_oem_tables,
+        sgx_epc,
+        hostname: cmd_arguments.value_of("hostname"),
+        resolvers: cmd_arguments.value_of("resolvers"),
+        tpm: cmd_arguments.value_of("tpm"),
+        pci: cmd_arguments.value_of("pci"),
+        pci_segments,
+        security: cmd_arguments.value_of("security"),
+        gdb: cmd_arguments.value_of("gdb"),
+        pvpanic: if cmd_arguments.is_present("pvpanic") {
+            Some(cmd_arguments.value_of("pvpanic").unwrap_or(""))
+        } else {
+            None
+        },
+        hooks,
+        diagnostics: cmd_arguments.value_of("diagnostics"),
+        guest_os: if cmd_arguments.is_present("guest-os") {
+            Some(cmd_arguments.value_of("guest-os").unwrap_or(""))
+        } else {
+            None
+        },
+        landlock: if cmd_arguments.is_present("landlock") {
+            Some(
+                cmd_arguments
+                    .values_of("landlock")
+                    .map(|x| x.collect())
+                    .unwrap_or_default(),
+            )
+        } else {
+            None
+        },
+        user: cmd_arguments.value_of("user"),
+        clock_drift: cmd_arguments.value_of("clock-drift"),
+        ptp: cmd_arguments.is_present("ptp"),
+        cgroup: cmd_arguments.value_of("cgroup"),
+        gpu: cmd_arguments.value_of("gpu"),
+        input: cmd_arguments.value_of("input"),
+    };
+    let vm_config = match cmd_arguments.value_of("config") {
+        Some(path) => config::VmConfig::parse_with_file(
+            vm_params,
+            std::path::Path::new(path),
+            &|option| cmd_arguments.occurrences_of(option) > 0,
+        ),
+        None => config::VmConfig::parse(vm_params),
+    };
+    let vm_config = match vm_config {
         Ok(config) => config,
         Err(e) => {
             println!("Failed parsing parameters {:?}", e);
@@ -338,6 +1013,51 @@ fn main() {
         }
     };
 
+    let pool = match cmd_arguments.value_of("vm-pool").map(config::PoolConfig::parse) {
+        Some(Ok(pool)) => Some(pool),
+        Some(Err(e)) => {
+            println!("Failed parsing parameters {:?}", e);
+            process::exit(1);
+        }
+        None => None,
+    };
+
+    let block_cache = match cmd_arguments
+        .value_of("block-cache")
+        .map(config::BlockCacheConfig::parse)
+    {
+        Some(Ok(block_cache)) => block_cache.size,
+        Some(Err(e)) => {
+            println!("Failed parsing parameters {:?}", e);
+            process::exit(1);
+        }
+        None => config::DEFAULT_BLOCK_CACHE_SIZE,
+    };
+
+    if cmd_arguments.is_present("tracing") {
+        match config::TracingConfig::parse(cmd_arguments.value_of("tracing").unwrap_or("")) {
+            Ok(tracing) => vmm::tracer::start(tracing),
+            Err(e) => {
+                println!("Failed parsing parameters {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let tenancy = if cmd_arguments.is_present("multi-tenant") {
+        let tenancy_config =
+            config::TenancyConfig::parse(cmd_arguments.value_of("multi-tenant").unwrap_or(""));
+        match vmm::tenancy::Tenancy::new(&tenancy_config) {
+            Ok(tenancy) => Some(tenancy),
+            Err(e) => {
+                println!("Failed reading the admin token {:?}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
     let api_socket_path = cmd_arguments
         .value_of("api-socket")
         .expect("Missing argument: api-socket");
@@ -356,12 +1076,66 @@ fn main() {
     let (api_request_sender, api_request_receiver) = channel();
     let api_evt = EventFd::new(EFD_NONBLOCK).expect("Cannot create API EventFd");
 
+    let event_monitor = cmd_arguments
+        .value_of("event-monitor")
+        .map(|value| vmm::event_monitor::open_event_file(value).expect("Error opening event file"));
+
+    let api_audit_log = cmd_arguments.value_of("api-audit-log").map(|value| {
+        vmm::api::audit::open_audit_log(value).expect("Error opening API access log")
+    });
+
+    let host_resources = if cmd_arguments.is_present("host-resources") {
+        Some(
+            cmd_arguments
+                .value_of("host-resources")
+                .unwrap_or(vmm::host_resources::DEFAULT_HOST_RESOURCES_PATH)
+                .into(),
+        )
+    } else {
+        None
+    };
+
+    if cmd_arguments.is_present("cleanup-stale") {
+        let path = cmd_arguments
+            .value_of("host-resources")
+            .unwrap_or(vmm::host_resources::DEFAULT_HOST_RESOURCES_PATH);
+        match vmm::host_resources::cleanup_stale(std::path::Path::new(path)) {
+            Ok(reclaimed) => {
+                for (owner, leftover) in reclaimed {
+                    println!("Reclaimed {:?} of the VMM process {}", leftover, owner);
+                }
+            }
+            Err(e) => {
+                println!("Failed reclaiming the stale host resources {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    // The events are sent to the D-Bus API, for it to signal them.
+    #[cfg(not(feature = "dbus_api"))]
+    let event_sender = None;
+    #[cfg(feature = "dbus_api")]
+    let (event_sender, dbus_events) = if cmd_arguments.is_present("dbus-service-name") {
+        let (sender, receiver) = channel();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+
     let http_sender = api_request_sender.clone();
     let vmm_thread = match vmm::start_vmm_thread(
         api_socket_path,
         api_evt.try_clone().unwrap(),
         http_sender,
         api_request_receiver,
+        event_monitor,
+        event_sender,
+        host_resources,
+        cmd_arguments.value_of("fd-socket"),
+        block_cache,
+        tenancy,
+        api_audit_log,
     ) {
         Ok(t) => t,
         Err(e) => {
@@ -370,12 +1144,48 @@ fn main() {
         }
     };
 
-    if cmd_arguments.is_present("vm-config") && vm_config.valid() {
+    #[cfg(feature = "dbus_api")]
+    {
+        if let Some(events) = dbus_events {
+            let options = vmm::api::DBusApiOptions {
+                service_name: cmd_arguments
+                    .value_of("dbus-service-name")
+                    .unwrap_or(vmm::api::dbus::DEFAULT_DBUS_SERVICE_NAME)
+                    .to_string(),
+                object_path: cmd_arguments
+                    .value_of("dbus-object-path")
+                    .unwrap()
+                    .to_string(),
+                system_bus: cmd_arguments.is_present("dbus-system-bus"),
+            };
+            if let Err(e) = vmm::api::start_dbus_thread(
+                options,
+                api_evt.try_clone().unwrap(),
+                vmm::api::ApiSender::new(api_request_sender.clone()),
+                events,
+            ) {
+                println!("Failed starting the D-Bus API {:?}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(pool) = pool {
+        // The VM config is the template of the VMs of the pool, the default
+        // VM being left out.
+        let sender = vmm::api::ApiSender::new(api_request_sender);
+        let data = vmm::api::VmmPoolData {
+            config: vm_config,
+            pool,
+        };
+        vmm::api::vmm_pool(api_evt.try_clone().unwrap(), sender, Arc::new(data))
+            .expect("Could not create the VM pool");
+    } else if cmd_arguments.is_present("vm-config") && vm_config.valid() {
         // Create and boot the VM based off the VM config we just built.
-        let sender = api_request_sender.clone();
+        let sender = vmm::api::ApiSender::new(api_request_sender);
         vmm::api::vm_create(
             api_evt.try_clone().unwrap(),
-            api_request_sender,
+            sender.clone(),
             Arc::new(vm_config),
         )
         .expect("Could not create the VM");
@@ -7,12 +7,13 @@
 //! Virtual Function I/O (VFIO) API
 extern crate byteorder;
 extern crate devices;
+extern crate hypervisor;
 extern crate kvm_bindings;
-extern crate kvm_ioctls;
 #[macro_use]
 extern crate log;
 extern crate pci;
 extern crate vfio_bindings;
+extern crate vhost_rs;
 extern crate vm_allocator;
 extern crate vm_device;
 extern crate vm_memory;
@@ -22,11 +23,16 @@ extern crate vmm_sys_util;
 mod vfio_device;
 mod vfio_ioctls;
 mod vfio_pci;
+mod vfio_user;
 
 use std::mem::size_of;
 
-pub use vfio_device::{VfioContainer, VfioDevice, VfioDmaMapping, VfioError};
-pub use vfio_pci::{VfioPciDevice, VfioPciError};
+pub use vfio_device::{
+    VfioContainer, VfioDevice, VfioDmaMapping, VfioError, VfioMigrationState, VFIO_MIGRATION_P2P,
+    VFIO_MIGRATION_PRE_COPY, VFIO_MIGRATION_STOP_COPY,
+};
+pub use vfio_pci::{VfioOps, VfioPciDevice, VfioPciError};
+pub use vfio_user::{VfioUserDevice, VfioUserDmaMapping, VfioUserError};
 
 // Returns a `Vec<T>` with a size in bytes at least as large as `size_in_bytes`.
 fn vec_with_size_in_bytes<T: Default>(size_in_bytes: usize) -> Vec<T> {
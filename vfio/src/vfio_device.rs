@@ -3,13 +3,14 @@
 // SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
 //
 use crate::vec_with_array_field;
+use crate::vfio_pci::VfioOps;
 use byteorder::{ByteOrder, LittleEndian};
-use kvm_ioctls::*;
+use hypervisor::{Device, DeviceAttr};
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io;
+use std::io::{self, Read, Write};
 use std::mem;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::prelude::FileExt;
@@ -19,8 +20,8 @@ use std::sync::{Arc, RwLock};
 use std::u32;
 use vfio_bindings::bindings::vfio::*;
 use vfio_ioctls::*;
-use vm_device::ExternalDmaMapping;
-use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vm_device::{ExternalDmaMapping, MemoryListener};
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::ioctl::*;
 
@@ -40,12 +41,17 @@ pub enum VfioError {
     GroupGetDeviceFD,
     KvmSetDeviceAttr(io::Error),
     VfioDeviceGetInfo,
+    VfioDeviceNotPci,
     VfioDeviceGetRegionInfo,
     InvalidPath,
     IommuDmaMap,
     IommuDmaUnmap,
     VfioDeviceGetIrqInfo,
     VfioDeviceSetIrq,
+    IommuDirtyLog(io::Error),
+    VfioMigrationUnsupported,
+    VfioMigrationSetState(io::Error),
+    VfioMigrationData(io::Error),
 }
 pub type Result<T> = std::result::Result<T, VfioError>;
 
@@ -84,6 +90,7 @@ impl fmt::Display for VfioError {
             VfioError::VfioDeviceGetInfo => {
                 write!(f, "failed to get vfio device's info or info doesn't match")
             }
+            VfioError::VfioDeviceNotPci => write!(f, "vfio device isn't a PCI device"),
             VfioError::VfioDeviceGetRegionInfo => {
                 write!(f, "failed to get vfio device's region info")
             }
@@ -94,6 +101,16 @@ impl fmt::Display for VfioError {
             }
             VfioError::VfioDeviceGetIrqInfo => write!(f, "failed to get vfio device irq info"),
             VfioError::VfioDeviceSetIrq => write!(f, "failed to set vfio deviece irq"),
+            VfioError::IommuDirtyLog(e) => write!(f, "failed to track iommu dirty pages: {}", e),
+            VfioError::VfioMigrationUnsupported => {
+                write!(f, "vfio device doesn't support migration")
+            }
+            VfioError::VfioMigrationSetState(e) => {
+                write!(f, "failed to set vfio device migration state: {}", e)
+            }
+            VfioError::VfioMigrationData(e) => {
+                write!(f, "failed to transfer vfio device migration data: {}", e)
+            }
         }
     }
 }
@@ -105,6 +122,73 @@ struct vfio_region_info_with_cap {
     cap_info: __IncompleteArrayField<u8>,
 }
 
+// The device features, the migration v2 uAPI and the type1 IOMMU dirty
+// pages tracking are not part of vfio-bindings yet, see <linux/vfio.h>.
+const VFIO_DEVICE_FEATURE_GET: u32 = 1 << 16;
+const VFIO_DEVICE_FEATURE_SET: u32 = 1 << 17;
+const VFIO_DEVICE_FEATURE_MIGRATION: u32 = 1;
+const VFIO_DEVICE_FEATURE_MIG_DEVICE_STATE: u32 = 2;
+
+/// The device state can be saved once stopped.
+pub const VFIO_MIGRATION_STOP_COPY: u64 = 1 << 0;
+/// The device supports the peer to peer quiescent states.
+pub const VFIO_MIGRATION_P2P: u64 = 1 << 1;
+/// The device state can be saved iteratively while running.
+pub const VFIO_MIGRATION_PRE_COPY: u64 = 1 << 2;
+
+const VFIO_IOMMU_DIRTY_PAGES_FLAG_START: u32 = 1 << 0;
+const VFIO_IOMMU_DIRTY_PAGES_FLAG_STOP: u32 = 1 << 1;
+const VFIO_IOMMU_DIRTY_PAGES_FLAG_GET_BITMAP: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct vfio_device_feature_migration {
+    argsz: u32,
+    flags: u32,
+    migration_flags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct vfio_device_feature_mig_state {
+    argsz: u32,
+    flags: u32,
+    device_state: u32,
+    data_fd: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct vfio_iommu_type1_dirty_bitmap {
+    argsz: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct vfio_iommu_type1_dirty_bitmap_get {
+    argsz: u32,
+    flags: u32,
+    iova: u64,
+    size: u64,
+    pgsize: u64,
+    bitmap_size: u64,
+    bitmap_data: u64,
+}
+
+/// VFIO migration v2 device states.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VfioMigrationState {
+    Error = 0,
+    Stop = 1,
+    Running = 2,
+    StopCopy = 3,
+    Resuming = 4,
+    RunningP2p = 5,
+    PreCopy = 6,
+    PreCopyP2p = 7,
+}
+
 pub struct VfioContainer {
     container: File,
 }
@@ -189,6 +273,61 @@ impl VfioContainer {
 
         Ok(())
     }
+
+    fn set_dirty_log(&self, flags: u32) -> Result<()> {
+        let dirty_bitmap = vfio_iommu_type1_dirty_bitmap {
+            argsz: mem::size_of::<vfio_iommu_type1_dirty_bitmap>() as u32,
+            flags,
+        };
+
+        // Safe as file is vfio container, dirty_bitmap is constructed by us,
+        // and we check the return value
+        let ret = unsafe { ioctl_with_ref(self, VFIO_IOMMU_DIRTY_PAGES(), &dirty_bitmap) };
+        if ret != 0 {
+            return Err(VfioError::IommuDirtyLog(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Start tracking the pages the devices of the container write to
+    /// through DMA.
+    pub fn start_dirty_log(&self) -> Result<()> {
+        self.set_dirty_log(VFIO_IOMMU_DIRTY_PAGES_FLAG_START)
+    }
+
+    /// Stop tracking the pages written through DMA.
+    pub fn stop_dirty_log(&self) -> Result<()> {
+        self.set_dirty_log(VFIO_IOMMU_DIRTY_PAGES_FLAG_STOP)
+    }
+
+    /// Retrieve and clear the dirty pages bitmap of a DMA mapped IOVA range,
+    /// one bit per page of pgsize bytes. The range must match a range
+    /// previously mapped through vfio_dma_map(), and pgsize the smallest
+    /// page size supported by the IOMMU.
+    pub fn get_dirty_log(&self, iova: u64, size: u64, pgsize: u64) -> Result<Vec<u64>> {
+        let pages = (size + pgsize - 1) / pgsize;
+        let mut bitmap = vec![0u64; ((pages + 63) / 64) as usize];
+        let dirty_bitmap = vfio_iommu_type1_dirty_bitmap_get {
+            argsz: mem::size_of::<vfio_iommu_type1_dirty_bitmap_get>() as u32,
+            flags: VFIO_IOMMU_DIRTY_PAGES_FLAG_GET_BITMAP,
+            iova,
+            size,
+            pgsize,
+            bitmap_size: (bitmap.len() * mem::size_of::<u64>()) as u64,
+            bitmap_data: bitmap.as_mut_ptr() as u64,
+        };
+
+        // Safe as file is vfio container, dirty_bitmap is constructed by us
+        // and points to a bitmap large enough for the whole range, and we
+        // check the return value
+        let ret = unsafe { ioctl_with_ref(self, VFIO_IOMMU_DIRTY_PAGES(), &dirty_bitmap) };
+        if ret != 0 {
+            return Err(VfioError::IommuDirtyLog(io::Error::last_os_error()));
+        }
+
+        Ok(bitmap)
+    }
 }
 
 impl AsRawFd for VfioContainer {
@@ -199,12 +338,12 @@ impl AsRawFd for VfioContainer {
 
 struct VfioGroup {
     group: File,
-    device: Arc<DeviceFd>,
+    device: Arc<dyn Device>,
     container: Arc<VfioContainer>,
 }
 
 impl VfioGroup {
-    fn new(id: u32, device: Arc<DeviceFd>) -> Result<Self> {
+    fn new(id: u32, device: Arc<dyn Device>) -> Result<Self> {
         let group_path = Path::new("/dev/vfio").join(id.to_string());
         let group = OpenOptions::new()
             .read(true)
@@ -253,17 +392,17 @@ impl VfioGroup {
         })
     }
 
-    fn kvm_device_add_group(device_fd: &Arc<DeviceFd>, group: &File) -> Result<()> {
+    fn kvm_device_add_group(device: &Arc<dyn Device>, group: &File) -> Result<()> {
         let group_fd = group.as_raw_fd();
         let group_fd_ptr = &group_fd as *const i32;
-        let dev_attr = kvm_bindings::kvm_device_attr {
+        let dev_attr = DeviceAttr {
             flags: 0,
             group: kvm_bindings::KVM_DEV_VFIO_GROUP,
             attr: u64::from(kvm_bindings::KVM_DEV_VFIO_GROUP_ADD),
             addr: group_fd_ptr as u64,
         };
 
-        device_fd
+        device
             .set_device_attr(&dev_attr)
             .map_err(VfioError::KvmSetDeviceAttr)
     }
@@ -271,7 +410,7 @@ impl VfioGroup {
     fn kvm_device_del_group(&self) -> std::result::Result<(), io::Error> {
         let group_fd = self.as_raw_fd();
         let group_fd_ptr = &group_fd as *const i32;
-        let dev_attr = kvm_bindings::kvm_device_attr {
+        let dev_attr = DeviceAttr {
             flags: 0,
             group: kvm_bindings::KVM_DEV_VFIO_GROUP,
             attr: u64::from(kvm_bindings::KVM_DEV_VFIO_GROUP_DEL),
@@ -316,12 +455,19 @@ impl VfioGroup {
         };
         // Safe as we are the owner of dev and dev_info which are valid value,
         // and we verify the return value.
+        // Mediated devices do not necessarily expose all the PCI interrupt
+        // types, so the number of IRQ indexes is not checked.
         let ret = unsafe { ioctl_with_mut_ref(&device, VFIO_DEVICE_GET_INFO(), &mut dev_info) };
-        if ret < 0
-            || (dev_info.flags & VFIO_DEVICE_FLAGS_PCI) == 0
-            || dev_info.num_regions < VFIO_PCI_CONFIG_REGION_INDEX + 1
-            || dev_info.num_irqs < VFIO_PCI_MSIX_IRQ_INDEX + 1
-        {
+        if ret < 0 {
+            return Err(VfioError::VfioDeviceGetInfo);
+        }
+
+        // Only devices exposed through the PCI layout can be assigned, not
+        // e.g. the vfio-ccw or vfio-ap mediated devices.
+        if (dev_info.flags & VFIO_DEVICE_FLAGS_PCI) == 0 {
+            return Err(VfioError::VfioDeviceNotPci);
+        }
+        if dev_info.num_regions < VFIO_PCI_CONFIG_REGION_INDEX + 1 {
             return Err(VfioError::VfioDeviceGetInfo);
         }
 
@@ -435,10 +581,18 @@ impl VfioDeviceInfo {
                 ioctl_with_mut_ref(&self.device, VFIO_DEVICE_GET_REGION_INFO(), &mut reg_info)
             };
             if ret < 0 {
+                // Keep an empty region, the regions are looked up by index.
                 error!("Could not get region #{} info", i);
+                regions.push(VfioRegion {
+                    flags: 0,
+                    size: 0,
+                    offset: 0,
+                    mmap: (0, 0),
+                });
                 continue;
             }
 
+            let mut flags = reg_info.flags;
             let mut mmap_size: u64 = reg_info.size;
             let mut mmap_offset: u64 = 0;
             if reg_info.flags & VFIO_REGION_INFO_FLAG_CAPS != 0 && reg_info.argsz > argsz {
@@ -462,35 +616,73 @@ impl VfioDeviceInfo {
                 };
                 if ret < 0 {
                     error!("Could not get region #{} info", i);
+                    regions.push(VfioRegion {
+                        flags: 0,
+                        size: 0,
+                        offset: 0,
+                        mmap: (0, 0),
+                    });
                     continue;
                 }
-                // region_with_cap[0].cap_info may contain vfio_region_info_cap_sparse_mmap
-                // struct or vfio_region_info_cap_type struct. Both of them begin with
-                // vfio_info_cap_header.
-                // so safe to convert cap_info into vfio_info_cap_header pointer first, and
-                // safe to access its elments through this poiner.
-                #[allow(clippy::cast_ptr_alignment)]
-                let cap_header =
-                    unsafe { region_with_cap[0].cap_info.as_ptr() as *const vfio_info_cap_header };
-                if unsafe { u32::from((*cap_header).id) } == VFIO_REGION_INFO_CAP_SPARSE_MMAP {
-                    // cap_info is vfio_region_sparse_mmap here
-                    // so safe to convert cap_info into vfio_info_region_sparse_mmap pointer, and
-                    // safe to access its elements through this pointer.
-                    #[allow(clippy::cast_ptr_alignment)]
-                    let sparse_mmap = unsafe {
-                        region_with_cap[0].cap_info.as_ptr()
-                            as *const vfio_region_info_cap_sparse_mmap
-                    };
-                    let mmap_area = unsafe {
-                        (*sparse_mmap).areas.as_ptr() as *const vfio_region_sparse_mmap_area
+
+                // The capabilities are chained, each vfio_info_cap_header
+                // giving the offset of the next one from the beginning of
+                // the vfio_region_info structure. Mediated devices commonly
+                // report a region type along with a sparse mmap capability.
+                let mut cap_offset = region_with_cap[0].region_info.cap_offset;
+                while cap_offset >= argsz && cap_offset < reg_info.argsz {
+                    // cap_info may contain vfio_region_info_cap_sparse_mmap
+                    // struct or vfio_region_info_cap_type struct. Both of them
+                    // begin with vfio_info_cap_header, and the offset has been
+                    // checked against the buffer size, so it is safe to access
+                    // the header through this pointer.
+                    let cap_ptr = unsafe {
+                        region_with_cap[0]
+                            .cap_info
+                            .as_ptr()
+                            .add((cap_offset - argsz) as usize)
                     };
-                    mmap_size = unsafe { (*mmap_area).size };
-                    mmap_offset = unsafe { (*mmap_area).offset };
+                    #[allow(clippy::cast_ptr_alignment)]
+                    let cap_header = cap_ptr as *const vfio_info_cap_header;
+                    match unsafe { u32::from((*cap_header).id) } {
+                        VFIO_REGION_INFO_CAP_SPARSE_MMAP => {
+                            // cap_info is vfio_region_sparse_mmap here
+                            // so safe to convert cap_info into vfio_info_region_sparse_mmap
+                            // pointer, and safe to access its elements through this pointer.
+                            #[allow(clippy::cast_ptr_alignment)]
+                            let sparse_mmap = cap_ptr as *const vfio_region_info_cap_sparse_mmap;
+                            if unsafe { (*sparse_mmap).nr_areas } == 0 {
+                                // No part of the region can be mapped.
+                                flags &= !VFIO_REGION_INFO_FLAG_MMAP;
+                            } else {
+                                let mmap_area = unsafe {
+                                    (*sparse_mmap).areas.as_ptr()
+                                        as *const vfio_region_sparse_mmap_area
+                                };
+                                mmap_size = unsafe { (*mmap_area).size };
+                                mmap_offset = unsafe { (*mmap_area).offset };
+                            }
+                        }
+                        VFIO_REGION_INFO_CAP_TYPE => {
+                            // Safe for the same reasons as above.
+                            #[allow(clippy::cast_ptr_alignment)]
+                            let cap_type = cap_ptr as *const vfio_region_info_cap_type;
+                            debug!(
+                                "Region #{} type 0x{:x}, subtype 0x{:x}",
+                                i,
+                                unsafe { (*cap_type).type_ },
+                                unsafe { (*cap_type).subtype }
+                            );
+                        }
+                        id => debug!("Region #{} unknown capability {}", i, id),
+                    }
+
+                    cap_offset = unsafe { (*cap_header).next };
                 }
             }
 
             let region = VfioRegion {
-                flags: reg_info.flags,
+                flags,
                 size: reg_info.size,
                 offset: reg_info.offset,
                 mmap: (mmap_offset, mmap_size),
@@ -511,6 +703,8 @@ impl VfioDeviceInfo {
 /// This structure implements the ExternalDmaMapping trait. It is meant to
 /// be used when the caller tries to provide a way to update the mappings
 /// associated with a specific VFIO container.
+/// It also implements the MemoryListener trait, for the VFIO containers that
+/// are not attached to the virtual IOMMU and identity map the guest RAM.
 pub struct VfioDmaMapping {
     container: Arc<VfioContainer>,
     memory: Arc<RwLock<GuestMemoryMmap>>,
@@ -570,27 +764,42 @@ impl ExternalDmaMapping for VfioDmaMapping {
     }
 }
 
+impl MemoryListener for VfioDmaMapping {
+    fn region_added(&self, gpa: u64, size: u64, host_addr: u64) -> result::Result<(), io::Error> {
+        self.container
+            .vfio_dma_map(gpa, size, host_addr)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "failed to identity map memory for VFIO container, \
+                         gpa 0x{:x}, size 0x{:x}: {:?}",
+                        gpa, size, e
+                    ),
+                )
+            })
+    }
+
+    fn region_removed(&self, gpa: u64, size: u64) -> result::Result<(), io::Error> {
+        self.unmap(gpa, size)
+    }
+}
+
 /// Vfio device for exposing regions which could be read/write to kernel vfio device.
 pub struct VfioDevice {
     device: File,
     flags: u32,
+    mdev: bool,
     group: VfioGroup,
     regions: Vec<VfioRegion>,
     irqs: HashMap<u32, VfioIrq>,
-    mem: Arc<RwLock<GuestMemoryMmap>>,
-    iommu_attached: bool,
 }
 
 impl VfioDevice {
     /// Create a new vfio device, then guest read/write on this device could be
     /// transfered into kernel vfio.
     /// sysfspath specify the vfio device path in sys file system.
-    pub fn new(
-        sysfspath: &Path,
-        device_fd: Arc<DeviceFd>,
-        mem: Arc<RwLock<GuestMemoryMmap>>,
-        iommu_attached: bool,
-    ) -> Result<Self> {
+    pub fn new(sysfspath: &Path, device: Arc<dyn Device>) -> Result<Self> {
         let uuid_path: PathBuf = [sysfspath, Path::new("iommu_group")].iter().collect();
         let group_path = uuid_path.read_link().map_err(|_| VfioError::InvalidPath)?;
         let group_osstr = group_path.file_name().ok_or(VfioError::InvalidPath)?;
@@ -599,7 +808,11 @@ impl VfioDevice {
             .parse::<u32>()
             .map_err(|_| VfioError::InvalidPath)?;
 
-        let group = VfioGroup::new(group_id, device_fd)?;
+        // Mediated devices are children of their parent device, with a
+        // link to their mdev type.
+        let mdev = sysfspath.join("mdev_type").exists();
+
+        let group = VfioGroup::new(group_id, device)?;
         let device_info = group.get_device(sysfspath)?;
         let regions = device_info.get_regions()?;
         let irqs = device_info.get_irqs()?;
@@ -607,14 +820,26 @@ impl VfioDevice {
         Ok(VfioDevice {
             device: device_info.device,
             flags: device_info.flags,
+            mdev,
             group,
             regions,
             irqs,
-            mem,
-            iommu_attached,
         })
     }
 
+    /// Whether the device is a mediated device, e.g. a vGPU slice of a
+    /// physical GPU, rather than a full physical device.
+    pub fn is_mdev(&self) -> bool {
+        self.mdev
+    }
+
+    /// Whether the device can raise interrupts of the given type (INTX, MSI
+    /// or MSI-X). Mediated devices may lack a type their configuration space
+    /// still advertises a capability for.
+    pub fn has_irq(&self, irq_index: u32) -> bool {
+        self.irqs.get(&irq_index).map_or(false, |irq| irq.count > 0)
+    }
+
     /// VFIO device reset.
     /// Only if the device supports being reset.
     pub fn reset(&self) {
@@ -833,40 +1058,6 @@ impl VfioDevice {
         self.group.container.clone()
     }
 
-    fn vfio_dma_map(&self, iova: u64, size: u64, user_addr: u64) -> Result<()> {
-        self.group.container.vfio_dma_map(iova, size, user_addr)
-    }
-
-    fn vfio_dma_unmap(&self, iova: u64, size: u64) -> Result<()> {
-        self.group.container.vfio_dma_unmap(iova, size)
-    }
-
-    /// Add all guest memory regions into vfio container's iommu table,
-    /// then vfio kernel driver could access guest memory from gfn
-    pub fn setup_dma_map(&self) -> Result<()> {
-        if !self.iommu_attached {
-            self.mem.read().unwrap().with_regions(|_index, region| {
-                self.vfio_dma_map(
-                    region.start_addr().raw_value(),
-                    region.len() as u64,
-                    region.as_ptr() as u64,
-                )
-            })?;
-        }
-        Ok(())
-    }
-
-    /// remove all guest memory regions from vfio containers iommu table
-    /// then vfio kernel driver couldn't access this guest memory
-    pub fn unset_dma_map(&self) -> Result<()> {
-        if !self.iommu_attached {
-            self.mem.read().unwrap().with_regions(|_index, region| {
-                self.vfio_dma_unmap(region.start_addr().raw_value(), region.len() as u64)
-            })?;
-        }
-        Ok(())
-    }
-
     /// Return the maximum numner of interrupts a VFIO device can request.
     /// This is used for pre-allocating the VFIO PCI routes.
     pub fn max_interrupts(&self) -> u32 {
@@ -887,6 +1078,143 @@ impl VfioDevice {
 
         max_interrupts
     }
+
+    /// Return the VFIO_MIGRATION_* flags the device supports, or None if
+    /// the device cannot be migrated.
+    pub fn migration_flags(&self) -> Option<u64> {
+        let mut feature = vfio_device_feature_migration {
+            argsz: mem::size_of::<vfio_device_feature_migration>() as u32,
+            flags: VFIO_DEVICE_FEATURE_GET | VFIO_DEVICE_FEATURE_MIGRATION,
+            migration_flags: 0,
+        };
+
+        // Safe as we are the owner of self and feature which are valid value
+        let ret = unsafe { ioctl_with_mut_ref(self, VFIO_DEVICE_FEATURE(), &mut feature) };
+        if ret != 0 {
+            return None;
+        }
+
+        Some(feature.migration_flags)
+    }
+
+    /// Move the device to a new migration state. The kernel goes through
+    /// the intermediate states on its own. The returned file carries the
+    /// device state, it is read from in the StopCopy and PreCopy states,
+    /// and written to in the Resuming state.
+    pub fn set_migration_state(&self, state: VfioMigrationState) -> Result<Option<File>> {
+        let mut feature = vfio_device_feature_mig_state {
+            argsz: mem::size_of::<vfio_device_feature_mig_state>() as u32,
+            flags: VFIO_DEVICE_FEATURE_SET | VFIO_DEVICE_FEATURE_MIG_DEVICE_STATE,
+            device_state: state as u32,
+            data_fd: -1,
+        };
+
+        // Safe as we are the owner of self and feature which are valid value
+        let ret = unsafe { ioctl_with_mut_ref(self, VFIO_DEVICE_FEATURE(), &mut feature) };
+        if ret != 0 {
+            return Err(VfioError::VfioMigrationSetState(io::Error::last_os_error()));
+        }
+
+        if feature.data_fd < 0 {
+            return Ok(None);
+        }
+
+        // Safe as the kernel just handed us this fd
+        Ok(Some(unsafe { File::from_raw_fd(feature.data_fd) }))
+    }
+
+    /// Stop the device and read its whole state out. The device is left
+    /// stopped, until the migration is either cancelled by moving it back
+    /// to the Running state, or completed by releasing it.
+    pub fn save_state(&self) -> Result<Vec<u8>> {
+        if self.migration_flags().unwrap_or(0) & VFIO_MIGRATION_STOP_COPY == 0 {
+            return Err(VfioError::VfioMigrationUnsupported);
+        }
+
+        let mut data_file = self
+            .set_migration_state(VfioMigrationState::StopCopy)?
+            .ok_or(VfioError::VfioMigrationUnsupported)?;
+        let mut state = Vec::new();
+        data_file
+            .read_to_end(&mut state)
+            .map_err(VfioError::VfioMigrationData)?;
+
+        self.set_migration_state(VfioMigrationState::Stop)?;
+
+        Ok(state)
+    }
+
+    /// Load a state previously saved with save_state() into the device. The
+    /// device is left stopped, until moved to the Running state.
+    pub fn restore_state(&self, state: &[u8]) -> Result<()> {
+        let mut data_file = self
+            .set_migration_state(VfioMigrationState::Resuming)?
+            .ok_or(VfioError::VfioMigrationUnsupported)?;
+        data_file
+            .write_all(state)
+            .map_err(VfioError::VfioMigrationData)?;
+        // The device state is loaded once the file is closed.
+        drop(data_file);
+
+        self.set_migration_state(VfioMigrationState::Stop)?;
+
+        Ok(())
+    }
+}
+
+impl VfioOps for VfioDevice {
+    fn reset(&self) {
+        VfioDevice::reset(self)
+    }
+
+    fn has_irq(&self, irq_index: u32) -> bool {
+        VfioDevice::has_irq(self, irq_index)
+    }
+
+    fn max_interrupts(&self) -> u32 {
+        VfioDevice::max_interrupts(self)
+    }
+
+    fn enable_msi(&self, fds: Vec<&EventFd>) -> Result<()> {
+        VfioDevice::enable_msi(self, fds)
+    }
+
+    fn disable_msi(&self) -> Result<()> {
+        VfioDevice::disable_msi(self)
+    }
+
+    fn enable_msix(&self, fds: Vec<&EventFd>) -> Result<()> {
+        VfioDevice::enable_msix(self, fds)
+    }
+
+    fn disable_msix(&self) -> Result<()> {
+        VfioDevice::disable_msix(self)
+    }
+
+    fn get_region_flags(&self, index: u32) -> u32 {
+        VfioDevice::get_region_flags(self, index)
+    }
+
+    fn get_region_offset(&self, index: u32) -> u64 {
+        VfioDevice::get_region_offset(self, index)
+    }
+
+    fn get_region_mmap(&self, index: u32) -> (u64, u64) {
+        VfioDevice::get_region_mmap(self, index)
+    }
+
+    // All the regions are mapped from the device file.
+    fn get_region_fd(&self, _index: u32) -> Option<RawFd> {
+        Some(self.device.as_raw_fd())
+    }
+
+    fn region_read(&self, index: u32, buf: &mut [u8], addr: u64) {
+        VfioDevice::region_read(self, index, buf, addr)
+    }
+
+    fn region_write(&self, index: u32, buf: &[u8], addr: u64) {
+        VfioDevice::region_write(self, index, buf, addr)
+    }
 }
 
 impl AsRawFd for VfioDevice {
@@ -7,55 +7,107 @@ extern crate devices;
 extern crate pci;
 extern crate vm_allocator;
 
-use crate::vec_with_array_field;
-use crate::vfio_device::VfioDevice;
+use crate::vfio_device::Result as VfioResult;
 use byteorder::{ByteOrder, LittleEndian};
 use devices::BusDevice;
-use kvm_bindings::{
-    kvm_irq_routing, kvm_irq_routing_entry, kvm_userspace_memory_region, KVM_IRQ_ROUTING_MSI,
-};
-use kvm_ioctls::*;
+use hypervisor::UserMemoryRegion;
 use pci::{
     BarReprogrammingParams, MsiCap, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
     PciCapabilityID, PciClassCode, PciConfiguration, PciDevice, PciDeviceError, PciHeaderType,
     PciSubclass, MSIX_TABLE_ENTRY_SIZE,
 };
 use std::any::Any;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
 use std::ptr::null_mut;
 use std::sync::Arc;
-use std::{fmt, io, result};
+use std::{cmp, fmt, io, result};
 use vfio_bindings::bindings::vfio::*;
 use vm_allocator::SystemAllocator;
+use vm_device::{InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqSourceConfig};
 use vm_memory::{Address, GuestAddress, GuestUsize};
 use vmm_sys_util::eventfd::EventFd;
 
 #[derive(Debug)]
 pub enum VfioPciError {
-    AllocateGsi,
-    EventFd(io::Error),
-    IrqFd(io::Error),
+    CreateInterruptGroup(io::Error),
+    FreeInterruptGroup(io::Error),
     NewVfioPciDevice,
     MapRegionGuest(io::Error),
+    UnmapRegionGuest(io::Error),
+    AllocateMemSlot,
     SetGsiRouting(io::Error),
+    InvalidRom,
 }
 pub type Result<T> = std::result::Result<T, VfioPciError>;
 
 impl fmt::Display for VfioPciError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            VfioPciError::AllocateGsi => write!(f, "failed to allocate GSI"),
-            VfioPciError::EventFd(e) => write!(f, "failed to create eventfd: {}", e),
-            VfioPciError::IrqFd(e) => write!(f, "failed to register irqfd: {}", e),
+            VfioPciError::CreateInterruptGroup(e) => {
+                write!(f, "failed to create the interrupts: {}", e)
+            }
+            VfioPciError::FreeInterruptGroup(e) => {
+                write!(f, "failed to free the interrupts: {}", e)
+            }
             VfioPciError::NewVfioPciDevice => write!(f, "failed to create VFIO PCI device"),
             VfioPciError::MapRegionGuest(e) => {
                 write!(f, "failed to map VFIO PCI region into guest: {}", e)
             }
+            VfioPciError::UnmapRegionGuest(e) => {
+                write!(f, "failed to unmap VFIO PCI region from guest: {}", e)
+            }
+            VfioPciError::AllocateMemSlot => write!(f, "failed to allocate a KVM memory slot"),
             VfioPciError::SetGsiRouting(e) => write!(f, "failed to set GSI routes for KVM: {}", e),
+            VfioPciError::InvalidRom => write!(f, "option ROM without the 0x55AA signature"),
         }
     }
 }
 
+/// The operations of a VFIO device a VfioPciDevice relies on, to expose it
+/// to the guest. The device is either handled by the kernel VFIO driver, or
+/// emulated by another process and reached through vfio-user.
+pub trait VfioOps: Send + Sync {
+    /// Reset the device, if it supports being reset.
+    fn reset(&self);
+
+    /// Whether the device can raise interrupts of the given type (INTX, MSI
+    /// or MSI-X).
+    fn has_irq(&self, irq_index: u32) -> bool;
+
+    /// The maximum number of interrupts the device can request.
+    fn max_interrupts(&self) -> u32;
+
+    /// Have the device trigger the EventFds for its MSI vectors.
+    fn enable_msi(&self, fds: Vec<&EventFd>) -> VfioResult<()>;
+
+    /// Stop the device triggering its MSI vectors.
+    fn disable_msi(&self) -> VfioResult<()>;
+
+    /// Have the device trigger the EventFds for its MSI-X vectors.
+    fn enable_msix(&self, fds: Vec<&EventFd>) -> VfioResult<()>;
+
+    /// Stop the device triggering its MSI-X vectors.
+    fn disable_msix(&self) -> VfioResult<()>;
+
+    /// The VFIO_REGION_INFO_FLAG_* flags of a region.
+    fn get_region_flags(&self, index: u32) -> u32;
+
+    /// The offset of a region in the file it can be mapped from.
+    fn get_region_offset(&self, index: u32) -> u64;
+
+    /// The offset and size of the part of a region which can be mapped.
+    fn get_region_mmap(&self, index: u32) -> (u64, u64);
+
+    /// The file a region can be mapped from, if any.
+    fn get_region_fd(&self, index: u32) -> Option<RawFd>;
+
+    /// Read from a region, at the given offset in it.
+    fn region_read(&self, index: u32, buf: &mut [u8], addr: u64);
+
+    /// Write to a region, at the given offset in it.
+    fn region_write(&self, index: u32, buf: &[u8], addr: u64);
+}
+
 #[derive(Copy, Clone)]
 enum PciVfioSubclass {
     VfioSubclass = 0xff,
@@ -229,43 +281,22 @@ struct MsiVector {
     masked: bool,
 }
 
-struct InterruptRoute {
-    gsi: u32,
-    irq_fd: EventFd,
-    msi_vector: MsiVector,
-}
-
-impl InterruptRoute {
-    fn new(vm: &Arc<VmFd>, allocator: &mut SystemAllocator, msi_vector: MsiVector) -> Result<Self> {
-        let irq_fd = EventFd::new(libc::EFD_NONBLOCK).map_err(VfioPciError::EventFd)?;
-        let gsi = allocator.allocate_gsi().ok_or(VfioPciError::AllocateGsi)?;
-
-        vm.register_irqfd(&irq_fd, gsi)
-            .map_err(VfioPciError::IrqFd)?;
-
-        Ok(InterruptRoute {
-            gsi,
-            irq_fd,
-            msi_vector,
-        })
-    }
-}
-
 #[derive(Copy, Clone)]
 struct MmioRegion {
     start: GuestAddress,
     length: GuestUsize,
+    region_type: PciBarRegionType,
     index: u32,
     mem_slot: Option<u32>,
     host_addr: Option<u64>,
 }
 
 struct VfioPciConfig {
-    device: Arc<VfioDevice>,
+    device: Arc<dyn VfioOps>,
 }
 
 impl VfioPciConfig {
-    fn new(device: Arc<VfioDevice>) -> Self {
+    fn new(device: Arc<dyn VfioOps>) -> Self {
         VfioPciConfig { device }
     }
 
@@ -303,27 +334,41 @@ impl VfioPciConfig {
 /// VfioPciDevice represents a VFIO PCI device.
 /// This structure implements the BusDevice and PciDevice traits.
 ///
-/// A VfioPciDevice is bound to a VfioDevice and is also a PCI device.
-/// The VMM creates a VfioDevice, then assigns it to a VfioPciDevice,
-/// which then gets added to the PCI bus.
+/// A VfioPciDevice is bound to a VfioDevice, or to a VfioUserDevice, and is
+/// also a PCI device. The VMM creates the device, then assigns it to a
+/// VfioPciDevice, which then gets added to the PCI bus.
 pub struct VfioPciDevice {
-    vm_fd: Arc<VmFd>,
-    device: Arc<VfioDevice>,
+    vm: Arc<dyn hypervisor::Vm>,
+    device: Arc<dyn VfioOps>,
     vfio_pci_configuration: VfioPciConfig,
     configuration: PciConfiguration,
     mmio_regions: Vec<MmioRegion>,
     interrupt: Interrupt,
-    interrupt_routes: Vec<InterruptRoute>,
+    // The interrupts the device raises, along with the messages the guest
+    // programmed them with, through the MSI capability or the MSI-X table.
+    interrupt_group: Arc<dyn InterruptSourceGroup>,
+    interrupt_routes: Vec<MsiVector>,
+    // The option ROM the guest reads through the ROM BAR, given to the VMM
+    // or read from the device once, the reads otherwise going to the
+    // device.
+    rom: Option<Vec<u8>>,
 }
 
 impl VfioPciDevice {
-    /// Constructs a new Vfio Pci device for the given Vfio device
+    /// Constructs a new Vfio Pci device for the given Vfio device, whose
+    /// option ROM is replaced by `rom`, if given.
     pub fn new(
-        vm_fd: &Arc<VmFd>,
-        allocator: &mut SystemAllocator,
-        device: VfioDevice,
+        vm: &Arc<dyn hypervisor::Vm>,
+        interrupt_manager: &Arc<dyn InterruptManager>,
+        device: Arc<dyn VfioOps>,
+        rom: Option<Vec<u8>>,
     ) -> Result<Self> {
-        let device = Arc::new(device);
+        if let Some(rom) = &rom {
+            if !rom.starts_with(&PCI_ROM_SIGNATURE) {
+                return Err(VfioPciError::InvalidRom);
+            }
+        }
+
         device.reset();
 
         let configuration = PciConfiguration::new(
@@ -340,8 +385,14 @@ impl VfioPciDevice {
 
         let vfio_pci_configuration = VfioPciConfig::new(Arc::clone(&device));
 
+        // The vectors are routed once the guest driver programs the device.
+        let max_interrupts = device.max_interrupts();
+        let interrupt_group = interrupt_manager
+            .create_group(None, max_interrupts)
+            .map_err(VfioPciError::CreateInterruptGroup)?;
+
         let mut vfio_pci_device = VfioPciDevice {
-            vm_fd: vm_fd.clone(),
+            vm: vm.clone(),
             device,
             configuration,
             vfio_pci_configuration,
@@ -350,19 +401,13 @@ impl VfioPciDevice {
                 msi: None,
                 msix: None,
             },
-            interrupt_routes: Vec::new(),
+            interrupt_group,
+            interrupt_routes: vec![MsiVector::default(); max_interrupts as usize],
+            rom,
         };
 
         vfio_pci_device.parse_capabilities();
-
-        // Allocate temporary interrupt routes for now.
-        // The MSI vectors will be filled when the guest driver programs the device.
-        let max_interrupts = vfio_pci_device.device.max_interrupts();
-        for _ in 0..max_interrupts {
-            let msi_vector: MsiVector = Default::default();
-            let route = InterruptRoute::new(vm_fd, allocator, msi_vector)?;
-            vfio_pci_device.interrupt_routes.push(route);
-        }
+        vfio_pci_device.parse_extended_capabilities();
 
         Ok(vfio_pci_device)
     }
@@ -370,48 +415,29 @@ impl VfioPciDevice {
     fn irq_fds(&self) -> Result<Vec<&EventFd>> {
         let mut irq_fds: Vec<&EventFd> = Vec::new();
 
-        for r in &self.interrupt_routes {
-            irq_fds.push(&r.irq_fd);
+        for index in 0..self.interrupt_routes.len() {
+            if let Some(irq_fd) = self.interrupt_group.notifier(index as InterruptIndex) {
+                irq_fds.push(irq_fd);
+            }
         }
 
         Ok(irq_fds)
     }
 
-    fn set_kvm_routes(&self) -> Result<()> {
-        let mut entry_vec: Vec<kvm_irq_routing_entry> = Vec::new();
-        for route in self.interrupt_routes.iter() {
-            // Do not add masked vectors to the GSI mapping
-            if route.msi_vector.masked {
-                continue;
-            }
-
-            let mut entry = kvm_irq_routing_entry {
-                gsi: route.gsi,
-                type_: KVM_IRQ_ROUTING_MSI,
-                ..Default::default()
+    fn set_gsi_routes(&self) -> Result<()> {
+        for (index, route) in self.interrupt_routes.iter().enumerate() {
+            // The masked vectors are not routed.
+            let config = MsiIrqSourceConfig {
+                low_addr: route.msg_addr_lo,
+                high_addr: route.msg_addr_hi,
+                data: route.msg_data,
             };
-
-            entry.u.msi.address_lo = route.msi_vector.msg_addr_lo;
-            entry.u.msi.address_hi = route.msi_vector.msg_addr_hi;
-            entry.u.msi.data = route.msi_vector.msg_data;
-
-            entry_vec.push(entry);
-        }
-
-        let mut irq_routing =
-            vec_with_array_field::<kvm_irq_routing, kvm_irq_routing_entry>(entry_vec.len());
-        irq_routing[0].nr = entry_vec.len() as u32;
-        irq_routing[0].flags = 0;
-
-        unsafe {
-            let entries: &mut [kvm_irq_routing_entry] =
-                irq_routing[0].entries.as_mut_slice(entry_vec.len());
-            entries.copy_from_slice(&entry_vec);
+            self.interrupt_group
+                .update(index as InterruptIndex, config, route.masked)
+                .map_err(VfioPciError::SetGsiRouting)?;
         }
 
-        self.vm_fd
-            .set_gsi_routing(&irq_routing[0])
-            .map_err(VfioPciError::SetGsiRouting)
+        Ok(())
     }
 
     fn parse_msix_capabilities(&mut self, cap: u8) {
@@ -465,12 +491,22 @@ impl VfioPciDevice {
                 .vfio_pci_configuration
                 .read_config_byte(cap_next.into());
 
+            // The capabilities of interrupt types the device can't raise,
+            // as happens with mediated devices, are left to the device.
             match PciCapabilityID::from(cap_id) {
                 PciCapabilityID::MessageSignalledInterrupts => {
-                    self.parse_msi_capabilities(cap_next);
+                    if self.device.has_irq(VFIO_PCI_MSI_IRQ_INDEX) {
+                        self.parse_msi_capabilities(cap_next);
+                    } else {
+                        warn!("Ignoring MSI capability, the device has no MSI IRQ");
+                    }
                 }
                 PciCapabilityID::MSIX => {
-                    self.parse_msix_capabilities(cap_next);
+                    if self.device.has_irq(VFIO_PCI_MSIX_IRQ_INDEX) {
+                        self.parse_msix_capabilities(cap_next);
+                    } else {
+                        warn!("Ignoring MSI-X capability, the device has no MSI-X IRQ");
+                    }
                 }
                 _ => {}
             };
@@ -481,6 +517,62 @@ impl VfioPciDevice {
         }
     }
 
+    // The PCI Express extended capabilities are not exposed to the guest,
+    // which therefore can't resize the BARs: they keep the size the host
+    // gave them. The ones the device could make larger are reported, the
+    // host kernel resizing them through the resource<N>_resize sysfs files
+    // before the device is bound to VFIO.
+    fn parse_extended_capabilities(&mut self) {
+        let mut cap_next = PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET;
+
+        // Each capability takes at least one register, bounding the walk
+        // through a broken list.
+        for _ in 0..PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET / 4 {
+            let header = self.vfio_pci_configuration.read_config_dword(cap_next);
+            if header == 0 || header == 0xffff_ffff {
+                break;
+            }
+
+            if header & 0xffff == PCI_EXT_CAP_ID_REBAR {
+                self.parse_resizable_bar_capability(cap_next);
+            }
+
+            cap_next = header >> 20;
+            if cap_next < PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET {
+                break;
+            }
+        }
+    }
+
+    fn parse_resizable_bar_capability(&self, cap: u32) {
+        let num_bars = (self.vfio_pci_configuration.read_config_dword(cap + 8) >> 5) & 0x7;
+        for index in 0..num_bars {
+            // Sizes the BAR supports, from 1MiB, and the current one.
+            let sizes = self
+                .vfio_pci_configuration
+                .read_config_dword(cap + 4 + index * 8)
+                >> 4;
+            let control = self
+                .vfio_pci_configuration
+                .read_config_dword(cap + 8 + index * 8);
+            if sizes == 0 {
+                continue;
+            }
+
+            let bar_id = control & 0x7;
+            let current_size = 1u64 << (20 + ((control >> 8) & 0x3f));
+            let max_size = 1u64 << (20 + 31 - sizes.leading_zeros());
+            if max_size > current_size {
+                warn!(
+                    "BAR {} is resizable up to {} MiB, its current size being {} MiB",
+                    bar_id,
+                    max_size >> 20,
+                    current_size >> 20
+                );
+            }
+        }
+    }
+
     fn update_msi_interrupt_routes(&mut self, msi: &VfioMsi) -> Result<()> {
         let num_vectors = msi.cap.num_enabled_vectors();
         for (idx, route) in self.interrupt_routes.iter_mut().enumerate() {
@@ -489,20 +581,20 @@ impl VfioPciDevice {
             // to "Multiple Message Capable" and "Multiple Message Enable"
             // fields from the "Message Control" register.
             if idx >= num_vectors {
-                route.msi_vector.masked = true;
+                route.masked = true;
                 continue;
             }
 
-            route.msi_vector.msg_addr_lo = msi.cap.msg_addr_lo;
-            route.msi_vector.msg_addr_hi = msi.cap.msg_addr_hi;
-            route.msi_vector.msg_data = u32::from(msi.cap.msg_data) | (idx as u32);
-            route.msi_vector.masked = msi.cap.vector_masked(idx);
+            route.msg_addr_lo = msi.cap.msg_addr_lo;
+            route.msg_addr_hi = msi.cap.msg_addr_hi;
+            route.msg_data = u32::from(msi.cap.msg_data) | (idx as u32);
+            route.masked = msi.cap.vector_masked(idx);
         }
 
         // Check if we need to update KVM GSI mapping, based on the status of
         // the "MSI Enable" bit.
         if msi.cap.enabled() {
-            return self.set_kvm_routes();
+            return self.set_gsi_routes();
         }
 
         Ok(())
@@ -519,14 +611,14 @@ impl VfioPciDevice {
             // Fill tables
             if let Some(msix) = &self.interrupt.msix {
                 for (idx, entry) in msix.bar.table_entries.iter().enumerate() {
-                    self.interrupt_routes[idx].msi_vector.msg_addr_lo = entry.msg_addr_lo;
-                    self.interrupt_routes[idx].msi_vector.msg_addr_hi = entry.msg_addr_hi;
-                    self.interrupt_routes[idx].msi_vector.msg_data = entry.msg_data;
-                    self.interrupt_routes[idx].msi_vector.masked = entry.masked();
+                    self.interrupt_routes[idx].msg_addr_lo = entry.msg_addr_lo;
+                    self.interrupt_routes[idx].msg_addr_hi = entry.msg_addr_hi;
+                    self.interrupt_routes[idx].msg_data = entry.msg_data;
+                    self.interrupt_routes[idx].masked = entry.masked();
                 }
             }
 
-            return self.set_kvm_routes();
+            return self.set_gsi_routes();
         }
 
         Ok(())
@@ -596,17 +688,21 @@ impl VfioPciDevice {
     ///
     /// # Arguments
     ///
-    /// * `vm` - The KVM VM file descriptor. It is used to set the VFIO MMIO regions
-    ///          as KVM user memory regions.
-    /// * `mem_slot` - The KVM memory slot to set the user memopry regions.
-    /// # Return value
-    ///
-    /// This function returns the updated KVM memory slot id.
-    pub fn map_mmio_regions(&mut self, vm: &Arc<VmFd>, mem_slot: u32) -> Result<u32> {
-        let fd = self.device.as_raw_fd();
-        let mut new_mem_slot = mem_slot;
-
+    /// * `vm` - The VM the VFIO MMIO regions are set as user memory regions
+    ///          of.
+    /// * `mem_slot` - Closure returning a free KVM memory slot, called for each
+    ///                user memory region to set. It returns None when no slot
+    ///                is left.
+    pub fn map_mmio_regions<F>(&mut self, vm: &Arc<dyn hypervisor::Vm>, mem_slot: F) -> Result<()>
+    where
+        F: Fn() -> Option<u32>,
+    {
         for region in self.mmio_regions.iter_mut() {
+            // The option ROM kept by the VMM is read from its copy.
+            if region.index == VFIO_PCI_ROM_REGION_INDEX && self.rom.is_some() {
+                continue;
+            }
+
             // We want to skip the mapping of the BAR containing the MSI-X
             // table even if it is mappable. The reason is we need to trap
             // any access to the MSI-X table and update the GSI routing
@@ -619,6 +715,10 @@ impl VfioPciDevice {
 
             let region_flags = self.device.get_region_flags(region.index);
             if region_flags & VFIO_REGION_INFO_FLAG_MMAP != 0 {
+                let fd = match self.device.get_region_fd(region.index) {
+                    Some(fd) => fd,
+                    None => continue,
+                };
                 let mut prot = 0;
                 if region_flags & VFIO_REGION_INFO_FLAG_READ != 0 {
                     prot |= libc::PROT_READ;
@@ -648,8 +748,15 @@ impl VfioPciDevice {
                     continue;
                 }
 
-                let mem_region = kvm_userspace_memory_region {
-                    slot: new_mem_slot as u32,
+                let new_mem_slot = match mem_slot() {
+                    Some(slot) => slot,
+                    None => {
+                        unsafe { libc::munmap(host_addr, mmap_size as usize) };
+                        return Err(VfioPciError::AllocateMemSlot);
+                    }
+                };
+                let mem_region = UserMemoryRegion {
+                    slot: new_mem_slot,
                     guest_phys_addr: region.start.raw_value() + mmap_offset,
                     memory_size: mmap_size as u64,
                     userspace_addr: host_addr as u64,
@@ -665,12 +772,58 @@ impl VfioPciDevice {
                 // Update the region with memory mapped info.
                 region.mem_slot = Some(new_mem_slot);
                 region.host_addr = Some(host_addr as u64);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmap the MMIO regions `map_mmio_regions()` mapped into the guest,
+    /// before the device is removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `free_slot` - Closure the KVM memory slots of the regions are given
+    ///                 back to.
+    pub fn unmap_mmio_regions<F>(&mut self, free_slot: F) -> Result<()>
+    where
+        F: Fn(u32),
+    {
+        for region in self.mmio_regions.iter_mut() {
+            if let (Some(mem_slot), Some(host_addr)) = (region.mem_slot, region.host_addr) {
+                let (mmap_offset, mmap_size) = self.device.get_region_mmap(region.index);
+
+                let mem_region = UserMemoryRegion {
+                    slot: mem_slot,
+                    guest_phys_addr: region.start.raw_value() + mmap_offset,
+                    memory_size: 0,
+                    userspace_addr: host_addr,
+                    flags: 0,
+                };
+                // Safe because the region is removed, not added.
+                unsafe {
+                    self.vm
+                        .set_user_memory_region(mem_region)
+                        .map_err(VfioPciError::UnmapRegionGuest)?;
+                }
+                // Safe because the guest doesn't reach the mapping anymore.
+                unsafe { libc::munmap(host_addr as *mut libc::c_void, mmap_size as usize) };
 
-                new_mem_slot += 1;
+                free_slot(mem_slot);
+                region.mem_slot = None;
+                region.host_addr = None;
             }
         }
 
-        Ok(new_mem_slot)
+        Ok(())
+    }
+
+    /// Stop the interrupt routes of the device triggering their GSI, and
+    /// give the GSIs back, before the device is removed.
+    pub fn free_interrupt_routes(&mut self) -> Result<()> {
+        self.interrupt_group
+            .free()
+            .map_err(VfioPciError::FreeInterruptGroup)
     }
 }
 
@@ -683,10 +836,6 @@ impl Drop for VfioPciDevice {
         if self.interrupt.msix.is_some() && self.device.disable_msix().is_err() {
             error!("Could not disable MSI-X");
         }
-
-        if self.device.unset_dma_map().is_err() {
-            error!("failed to remove all guest memory regions from iommu table");
-        }
     }
 }
 
@@ -704,6 +853,10 @@ impl BusDevice for VfioPciDevice {
 const PCI_CONFIG_BAR_OFFSET: u32 = 0x10;
 // Capability register offset in the PCI config space.
 const PCI_CONFIG_CAPABILITY_OFFSET: u32 = 0x34;
+// First PCI Express extended capability offset in the config space.
+const PCI_CONFIG_EXTENDED_CAPABILITY_OFFSET: u32 = 0x100;
+// Resizable BAR extended capability ID.
+const PCI_EXT_CAP_ID_REBAR: u32 = 0x15;
 // IO BAR when first BAR bit is 1.
 const PCI_CONFIG_IO_BAR: u32 = 0x1;
 // Memory BAR flags (lower 4 bits).
@@ -722,6 +875,10 @@ const PCI_CONFIG_BAR0_INDEX: usize = 4;
 const PCI_ROM_EXP_BAR_INDEX: usize = 12;
 // PCI interrupt pin and line register index
 const PCI_INTX_REG_INDEX: usize = 15;
+// First bytes of a PCI option ROM image.
+const PCI_ROM_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+// Smallest ROM BAR, the address decoder of the ROM BAR decoding from bit 11.
+const PCI_ROM_MIN_SIZE: u64 = 0x800;
 
 impl PciDevice for VfioPciDevice {
     fn allocate_bars(
@@ -731,12 +888,20 @@ impl PciDevice for VfioPciDevice {
     {
         let mut ranges = Vec::new();
         let mut bar_id = VFIO_PCI_BAR0_REGION_INDEX as u32;
+        let given_rom_len = self.rom.as_ref().map(|rom| rom.len());
 
         // Going through all regular regions to compute the BAR size.
         // We're not saving the BAR address to restore it, because we
         // are going to allocate a guest address for each BAR and write
         // that new address back.
         while bar_id < VFIO_PCI_CONFIG_REGION_INDEX {
+            // The option ROM given to the VMM takes the place of the one of
+            // the device.
+            if bar_id == VFIO_PCI_ROM_REGION_INDEX && given_rom_len.is_some() {
+                bar_id += 1;
+                continue;
+            }
+
             let mut lsb_size: u32 = 0xffff_ffff;
             let mut msb_size = 0;
             let mut region_size: u64;
@@ -795,9 +960,10 @@ impl PciDevice for VfioPciDevice {
                 let first_bit = lsb_size.trailing_zeros();
                 region_size = 2u64.pow(first_bit);
                 // We need to allocate a guest PIO address range for that BAR.
-                // The address needs to be 4 bytes aligned.
+                // The address needs to be naturally aligned, and at least 4
+                // bytes aligned.
                 bar_addr = allocator
-                    .allocate_io_addresses(None, region_size, Some(0x4))
+                    .allocate_io_addresses(None, region_size, Some(cmp::max(region_size, 0x4)))
                     .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?;
             } else {
                 if is_64bit_bar {
@@ -808,7 +974,7 @@ impl PciDevice for VfioPciDevice {
                     let msb_bar_offset: u32 = PCI_CONFIG_BAR_OFFSET + (bar_id + 1) * 4;
 
                     self.vfio_pci_configuration
-                        .write_config_dword(msb_bar_offset, msb_size);
+                        .write_config_dword(msb_size, msb_bar_offset);
 
                     msb_size = self
                         .vfio_pci_configuration
@@ -830,14 +996,16 @@ impl PciDevice for VfioPciDevice {
                 // In case the BAR is mappable directly, this means it might be
                 // set as KVM user memory region, which expects to deal with 4K
                 // pages. Therefore, the aligment has to be set accordingly.
+                // BARs are also naturally aligned, the guest otherwise
+                // reassigning them.
                 let bar_alignment = if (bar_id == VFIO_PCI_ROM_REGION_INDEX)
                     || (self.device.get_region_flags(bar_id) & VFIO_REGION_INFO_FLAG_MMAP != 0)
                 {
-                    // 4K alignment
-                    0x1000
+                    // At least 4K alignment
+                    cmp::max(region_size, 0x1000)
                 } else {
-                    // Default 16 bytes alignment
-                    0x10
+                    // At least 16 bytes alignment
+                    cmp::max(region_size, 0x10)
                 };
                 if is_64bit_bar {
                     bar_addr = allocator
@@ -877,24 +1045,91 @@ impl PciDevice for VfioPciDevice {
             self.mmio_regions.push(MmioRegion {
                 start: bar_addr,
                 length: region_size,
+                region_type,
                 index: bar_id as u32,
                 mem_slot: None,
                 host_addr: None,
             });
 
+            // The option ROM of the device is read out once, for the guest
+            // firmware not to go through the device for each of its reads.
+            if bar_id == VFIO_PCI_ROM_REGION_INDEX {
+                let mut rom = vec![0u8; region_size as usize];
+                self.device
+                    .region_read(VFIO_PCI_ROM_REGION_INDEX, &mut rom, 0);
+                if rom.starts_with(&PCI_ROM_SIGNATURE) {
+                    self.rom = Some(rom);
+                } else {
+                    warn!(
+                        "The option ROM of the VFIO device has no valid signature, \
+                         the guest firmware ignoring it"
+                    );
+                }
+            }
+
             bar_id += 1;
             if is_64bit_bar {
                 bar_id += 1;
             }
         }
 
-        if self.device.setup_dma_map().is_err() {
-            error!("failed to add all guest memory regions into iommu table");
+        // The ROM BAR of the option ROM given to the VMM, disabled until the
+        // guest enables it.
+        if let Some(rom_len) = given_rom_len {
+            let region_size = cmp::max(rom_len as u64, PCI_ROM_MIN_SIZE).next_power_of_two();
+            let bar_addr = allocator
+                .allocate_mmio_hole_addresses(
+                    None,
+                    region_size,
+                    Some(cmp::max(region_size, 0x1000)),
+                )
+                .ok_or_else(|| PciDeviceError::IoAllocationFailed(region_size))?;
+            let config = PciBarConfiguration::default()
+                .set_register_index(PCI_ROM_EXP_BAR_INDEX)
+                .set_address(bar_addr.raw_value())
+                .set_size(region_size)
+                .set_region_type(PciBarRegionType::Memory32BitRegion);
+            self.configuration
+                .add_pci_rom_bar(&config, 0)
+                .map_err(|e| PciDeviceError::IoRegistrationFailed(bar_addr.raw_value(), e))?;
+
+            ranges.push((bar_addr, region_size, PciBarRegionType::Memory32BitRegion));
+            self.mmio_regions.push(MmioRegion {
+                start: bar_addr,
+                length: region_size,
+                region_type: PciBarRegionType::Memory32BitRegion,
+                index: VFIO_PCI_ROM_REGION_INDEX,
+                mem_slot: None,
+                host_addr: None,
+            });
         }
 
         Ok(ranges)
     }
 
+    fn free_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> Vec<(GuestAddress, GuestUsize, PciBarRegionType)> {
+        let mut ranges = Vec::new();
+        for region in self.mmio_regions.drain(..) {
+            match region.region_type {
+                PciBarRegionType::IORegion => {
+                    allocator.free_io_addresses(region.start, region.length)
+                }
+                PciBarRegionType::Memory32BitRegion => {
+                    allocator.free_mmio_hole_addresses(region.start, region.length)
+                }
+                PciBarRegionType::Memory64BitRegion => {
+                    allocator.free_mmio_addresses(region.start, region.length)
+                }
+            }
+            ranges.push((region.start, region.length, region.region_type));
+        }
+
+        ranges
+    }
+
     fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
         // When the guest wants to write to a BAR, we trap it into
         // our local configuration space. We're not reprogramming
@@ -981,7 +1216,12 @@ impl PciDevice for VfioPciDevice {
         if let Some(region) = self.find_region(addr) {
             let offset = addr - region.start.raw_value();
 
-            if self.interrupt.msix_table_accessed(region.index, offset) {
+            if let (VFIO_PCI_ROM_REGION_INDEX, Some(rom)) = (region.index, &self.rom) {
+                // The BAR is larger than the ROM, its end reading as zeros.
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte = rom.get(offset as usize + i).cloned().unwrap_or(0);
+                }
+            } else if self.interrupt.msix_table_accessed(region.index, offset) {
                 self.read_msix_table(offset, data);
             } else {
                 self.device.region_read(region.index, data, offset);
@@ -994,6 +1234,11 @@ impl PciDevice for VfioPciDevice {
         if let Some(region) = self.find_region(addr) {
             let offset = addr - region.start.raw_value();
 
+            // The option ROM is read-only.
+            if region.index == VFIO_PCI_ROM_REGION_INDEX && self.rom.is_some() {
+                return;
+            }
+
             // If the MSI-X table is written to, we need to update our cache.
             if self.interrupt.msix_table_accessed(region.index, offset) {
                 if let Err(e) = self.update_msix_table(offset, data) {
@@ -1015,7 +1260,7 @@ impl PciDevice for VfioPciDevice {
                         let (mmap_offset, mmap_size) = self.device.get_region_mmap(region.index);
 
                         // Remove old region from KVM
-                        let old_mem_region = kvm_userspace_memory_region {
+                        let old_mem_region = UserMemoryRegion {
                             slot: mem_slot,
                             guest_phys_addr: old_base + mmap_offset,
                             memory_size: 0,
@@ -1024,11 +1269,11 @@ impl PciDevice for VfioPciDevice {
                         };
                         // Safe because the guest regions are guaranteed not to overlap.
                         unsafe {
-                            self.vm_fd.set_user_memory_region(old_mem_region)?;
+                            self.vm.set_user_memory_region(old_mem_region)?;
                         }
 
                         // Insert new region to KVM
-                        let new_mem_region = kvm_userspace_memory_region {
+                        let new_mem_region = UserMemoryRegion {
                             slot: mem_slot,
                             guest_phys_addr: new_base + mmap_offset,
                             memory_size: mmap_size as u64,
@@ -1037,7 +1282,7 @@ impl PciDevice for VfioPciDevice {
                         };
                         // Safe because the guest regions are guaranteed not to overlap.
                         unsafe {
-                            self.vm_fd.set_user_memory_region(new_mem_region)?;
+                            self.vm.set_user_memory_region(new_mem_region)?;
                         }
                     }
                 }
@@ -0,0 +1,685 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0 OR BSD-3-Clause
+//
+
+//! Client of the vfio-user protocol, for PCI devices emulated by another
+//! process, such as an SPDK NVMe/vfio-user target or the libvfio-user
+//! samples, listening on a UNIX socket.
+//!
+//! Each message mirrors one of the VFIO ioctls, the file descriptors it
+//! carries, the guest RAM for DMA_MAP or the interrupt EventFds for
+//! SET_IRQS, going along as SCM_RIGHTS control messages. The device maps the
+//! guest RAM it is given, so that the guest RAM has to be backed by shared
+//! files.
+
+use crate::vfio_device::{Result as VfioResult, VfioError};
+use crate::vfio_pci::VfioOps;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::{cmp, fmt, result};
+use vfio_bindings::bindings::vfio::*;
+use vhost_rs::vhost_user::sock_ctrl_msg::ScmSocket;
+use vm_device::{ExternalDmaMapping, MemoryListener};
+use vm_memory::{Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+use vmm_sys_util::eventfd::EventFd;
+
+// Commands, from the vfio-user specification.
+const VFIO_USER_VERSION: u16 = 1;
+const VFIO_USER_DMA_MAP: u16 = 2;
+const VFIO_USER_DMA_UNMAP: u16 = 3;
+const VFIO_USER_DEVICE_GET_INFO: u16 = 4;
+const VFIO_USER_DEVICE_GET_REGION_INFO: u16 = 5;
+const VFIO_USER_DEVICE_GET_IRQ_INFO: u16 = 7;
+const VFIO_USER_DEVICE_SET_IRQS: u16 = 8;
+const VFIO_USER_REGION_READ: u16 = 9;
+const VFIO_USER_REGION_WRITE: u16 = 10;
+const VFIO_USER_DEVICE_RESET: u16 = 13;
+
+const VFIO_USER_MAJOR_VERSION: u16 = 0;
+const VFIO_USER_MINOR_VERSION: u16 = 1;
+
+// Message ID, command, message size, flags and error number.
+const HEADER_SIZE: usize = 16;
+const FLAGS_TYPE_MASK: u32 = 0xf;
+const FLAGS_TYPE_REPLY: u32 = 1;
+const FLAGS_ERROR: u32 = 1 << 5;
+
+const DMA_MAP_FLAG_READ: u32 = 1 << 0;
+const DMA_MAP_FLAG_WRITE: u32 = 1 << 1;
+
+// Size of the fixed part of the vfio_region_info structure.
+const REGION_INFO_SIZE: usize = 32;
+
+// Most file descriptors a message may carry. The server tells how many it
+// accepts while negotiating the version, a single one if it doesn't.
+const MAX_MSG_FDS: usize = 8;
+
+#[derive(Debug)]
+pub enum VfioUserError {
+    /// Cannot connect to the server socket.
+    Connect(io::Error),
+    /// Cannot talk to the server.
+    Io(io::Error),
+    /// The server sent a malformed reply to the command.
+    InvalidReply(u16),
+    /// The server failed the command, with this errno.
+    Command(u16, u32),
+    /// The server speaks another major version of the protocol.
+    Version(u16, u16),
+    /// The device isn't a PCI device.
+    NotPci,
+}
+pub type Result<T> = std::result::Result<T, VfioUserError>;
+
+impl fmt::Display for VfioUserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VfioUserError::Connect(e) => write!(f, "failed to connect to vfio-user socket: {}", e),
+            VfioUserError::Io(e) => write!(f, "failed to talk to vfio-user server: {}", e),
+            VfioUserError::InvalidReply(cmd) => {
+                write!(f, "invalid vfio-user reply to command {}", cmd)
+            }
+            VfioUserError::Command(cmd, errno) => {
+                write!(f, "vfio-user command {} failed: errno {}", cmd, errno)
+            }
+            VfioUserError::Version(major, minor) => {
+                write!(f, "unsupported vfio-user version {}.{}", major, minor)
+            }
+            VfioUserError::NotPci => write!(f, "vfio-user device isn't a PCI device"),
+        }
+    }
+}
+
+// Sends a message, along with the file descriptors it carries.
+fn send_with_fds(socket: &mut UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    if fds.is_empty() {
+        return socket.write_all(data);
+    }
+
+    let sent = ScmSocket::send_with_fds(&*socket, &[data], fds)
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+
+    // The file descriptors went along with the first bytes.
+    socket.write_all(&data[sent..])
+}
+
+// Fills the buffer from the socket, and returns the file descriptors which
+// came along with its beginning.
+fn recv_with_fds(socket: &mut UnixStream, buf: &mut [u8]) -> io::Result<Vec<File>> {
+    let (received, files) = socket
+        .recv_with_files(buf, MAX_MSG_FDS)
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+    if received == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    socket.read_exact(&mut buf[received..])?;
+    Ok(files)
+}
+
+// Reads the number of file descriptors the server accepts from the JSON
+// capabilities of its version reply, e.g. {"capabilities":{"max_msg_fds":8}}.
+fn parse_max_msg_fds(capabilities: &str) -> usize {
+    capabilities
+        .find("\"max_msg_fds\"")
+        .map(|start| &capabilities[start + "\"max_msg_fds\"".len()..])
+        .map(|s| s.trim_start())
+        .filter(|s| s.starts_with(':'))
+        .map(|s| {
+            s[1..]
+                .trim_start()
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+        })
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .map_or(1, |max| cmp::min(cmp::max(max, 1), MAX_MSG_FDS))
+}
+
+struct Client {
+    socket: UnixStream,
+    next_id: u16,
+    max_msg_fds: usize,
+}
+
+impl Client {
+    // Sends a command, and returns the payload of its reply, along with the
+    // file descriptors it carries.
+    fn request(
+        &mut self,
+        command: u16,
+        payload: &[u8],
+        fds: &[RawFd],
+    ) -> Result<(Vec<u8>, Vec<File>)> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut message = vec![0u8; HEADER_SIZE];
+        LittleEndian::write_u16(&mut message[0..2], id);
+        LittleEndian::write_u16(&mut message[2..4], command);
+        LittleEndian::write_u32(&mut message[4..8], (HEADER_SIZE + payload.len()) as u32);
+        message.extend_from_slice(payload);
+        send_with_fds(&mut self.socket, &message, fds).map_err(VfioUserError::Io)?;
+
+        let mut header = [0u8; HEADER_SIZE];
+        let files = recv_with_fds(&mut self.socket, &mut header).map_err(VfioUserError::Io)?;
+        let size = LittleEndian::read_u32(&header[4..8]) as usize;
+        let flags = LittleEndian::read_u32(&header[8..12]);
+        if LittleEndian::read_u16(&header[0..2]) != id
+            || LittleEndian::read_u16(&header[2..4]) != command
+            || flags & FLAGS_TYPE_MASK != FLAGS_TYPE_REPLY
+            || size < HEADER_SIZE
+        {
+            return Err(VfioUserError::InvalidReply(command));
+        }
+
+        let mut reply = vec![0u8; size - HEADER_SIZE];
+        self.socket
+            .read_exact(&mut reply)
+            .map_err(VfioUserError::Io)?;
+        if flags & FLAGS_ERROR != 0 {
+            let errno = LittleEndian::read_u32(&header[12..16]);
+            return Err(VfioUserError::Command(command, errno));
+        }
+
+        Ok((reply, files))
+    }
+
+    fn negotiate_version(&mut self) -> Result<()> {
+        let mut payload = vec![0u8; 4];
+        LittleEndian::write_u16(&mut payload[0..2], VFIO_USER_MAJOR_VERSION);
+        LittleEndian::write_u16(&mut payload[2..4], VFIO_USER_MINOR_VERSION);
+        payload.extend_from_slice(
+            format!("{{\"capabilities\":{{\"max_msg_fds\":{}}}}}\0", MAX_MSG_FDS).as_bytes(),
+        );
+
+        let (reply, _) = self.request(VFIO_USER_VERSION, &payload, &[])?;
+        if reply.len() < 4 {
+            return Err(VfioUserError::InvalidReply(VFIO_USER_VERSION));
+        }
+        let major = LittleEndian::read_u16(&reply[0..2]);
+        let minor = LittleEndian::read_u16(&reply[2..4]);
+        if major != VFIO_USER_MAJOR_VERSION {
+            return Err(VfioUserError::Version(major, minor));
+        }
+
+        let capabilities = String::from_utf8_lossy(&reply[4..]);
+        self.max_msg_fds = parse_max_msg_fds(&capabilities);
+
+        Ok(())
+    }
+}
+
+struct VfioUserRegion {
+    flags: u32,
+    size: u64,
+    offset: u64,
+    mmap: (u64, u64),
+    file: Option<File>,
+}
+
+impl VfioUserRegion {
+    fn empty() -> Self {
+        VfioUserRegion {
+            flags: 0,
+            size: 0,
+            offset: 0,
+            mmap: (0, 0),
+            file: None,
+        }
+    }
+}
+
+/// A PCI device emulated by another process, and reached through the
+/// vfio-user protocol.
+pub struct VfioUserDevice {
+    client: Mutex<Client>,
+    flags: u32,
+    regions: Vec<VfioUserRegion>,
+    // Number of interrupts of each type, by IRQ index.
+    irqs: Vec<u32>,
+}
+
+impl VfioUserDevice {
+    /// Connect to the vfio-user server listening on `socket`, and get the
+    /// regions and interrupts of its device.
+    pub fn new(socket: &Path) -> Result<Self> {
+        let socket = UnixStream::connect(socket).map_err(VfioUserError::Connect)?;
+        VfioUserDevice::from_stream(socket)
+    }
+
+    /// Same as `new`, with the vfio-user server at the other end of an
+    /// already connected `socket`.
+    pub fn from_stream(socket: UnixStream) -> Result<Self> {
+        let mut client = Client {
+            socket,
+            next_id: 0,
+            max_msg_fds: 1,
+        };
+        client.negotiate_version()?;
+
+        let mut info = [0u8; 16];
+        LittleEndian::write_u32(&mut info[0..4], info.len() as u32);
+        let (reply, _) = client.request(VFIO_USER_DEVICE_GET_INFO, &info, &[])?;
+        if reply.len() < info.len() {
+            return Err(VfioUserError::InvalidReply(VFIO_USER_DEVICE_GET_INFO));
+        }
+        let flags = LittleEndian::read_u32(&reply[4..8]);
+        let num_regions = LittleEndian::read_u32(&reply[8..12]);
+        let num_irqs = LittleEndian::read_u32(&reply[12..16]);
+        if flags & VFIO_DEVICE_FLAGS_PCI == 0 {
+            return Err(VfioUserError::NotPci);
+        }
+
+        let mut regions = Vec::new();
+        for index in 0..num_regions {
+            // Keep an empty region, the regions are looked up by index.
+            let region = VfioUserDevice::get_region(&mut client, index).unwrap_or_else(|e| {
+                error!("Could not get region #{} info: {}", index, e);
+                VfioUserRegion::empty()
+            });
+
+            debug!("Region #{}", index);
+            debug!("\tflag 0x{:x}", region.flags);
+            debug!("\tsize 0x{:x}", region.size);
+            debug!("\toffset 0x{:x}", region.offset);
+
+            regions.push(region);
+        }
+
+        let mut irqs = Vec::new();
+        for index in 0..num_irqs {
+            let mut irq_info = [0u8; 16];
+            LittleEndian::write_u32(&mut irq_info[0..4], irq_info.len() as u32);
+            LittleEndian::write_u32(&mut irq_info[8..12], index);
+            let count = match client.request(VFIO_USER_DEVICE_GET_IRQ_INFO, &irq_info, &[]) {
+                Ok((reply, _)) if reply.len() >= irq_info.len() => {
+                    LittleEndian::read_u32(&reply[12..16])
+                }
+                _ => {
+                    warn!("Could not get vfio-user IRQ info for index {:}", index);
+                    0
+                }
+            };
+
+            debug!("IRQ #{}", index);
+            debug!("\tcount {}", count);
+
+            irqs.push(count);
+        }
+
+        Ok(VfioUserDevice {
+            client: Mutex::new(client),
+            flags,
+            regions,
+            irqs,
+        })
+    }
+
+    fn get_region(client: &mut Client, index: u32) -> Result<VfioUserRegion> {
+        let mut argsz = REGION_INFO_SIZE;
+        // The server tells the size needed for the capabilities, when they
+        // don't fit.
+        let (reply, mut files) = loop {
+            let mut region_info = vec![0u8; REGION_INFO_SIZE];
+            LittleEndian::write_u32(&mut region_info[0..4], argsz as u32);
+            LittleEndian::write_u32(&mut region_info[8..12], index);
+            let (reply, files) =
+                client.request(VFIO_USER_DEVICE_GET_REGION_INFO, &region_info, &[])?;
+            if reply.len() < REGION_INFO_SIZE {
+                return Err(VfioUserError::InvalidReply(
+                    VFIO_USER_DEVICE_GET_REGION_INFO,
+                ));
+            }
+            let needed = LittleEndian::read_u32(&reply[0..4]) as usize;
+            if needed <= argsz {
+                break (reply, files);
+            }
+            argsz = needed;
+        };
+
+        let mut flags = LittleEndian::read_u32(&reply[4..8]);
+        let size = LittleEndian::read_u64(&reply[16..24]);
+        let offset = LittleEndian::read_u64(&reply[24..32]);
+        let mut mmap = (0, size);
+
+        // The capabilities are chained, each header giving the offset of
+        // the next one from the beginning of the region info.
+        let mut cap_offset = LittleEndian::read_u32(&reply[12..16]) as usize;
+        while flags & VFIO_REGION_INFO_FLAG_CAPS != 0
+            && cap_offset >= REGION_INFO_SIZE
+            && cap_offset + 8 <= reply.len()
+        {
+            let cap = &reply[cap_offset..];
+            match u32::from(LittleEndian::read_u16(&cap[0..2])) {
+                VFIO_REGION_INFO_CAP_SPARSE_MMAP if cap.len() >= 16 => {
+                    let nr_areas = LittleEndian::read_u32(&cap[8..12]);
+                    if nr_areas == 0 {
+                        // No part of the region can be mapped.
+                        flags &= !VFIO_REGION_INFO_FLAG_MMAP;
+                    } else if cap.len() >= 32 {
+                        mmap = (
+                            LittleEndian::read_u64(&cap[16..24]),
+                            LittleEndian::read_u64(&cap[24..32]),
+                        );
+                    }
+                }
+                VFIO_REGION_INFO_CAP_TYPE if cap.len() >= 16 => debug!(
+                    "Region #{} type 0x{:x}, subtype 0x{:x}",
+                    index,
+                    LittleEndian::read_u32(&cap[8..12]),
+                    LittleEndian::read_u32(&cap[12..16])
+                ),
+                id => debug!("Region #{} unknown capability {}", index, id),
+            }
+
+            cap_offset = LittleEndian::read_u32(&cap[4..8]) as usize;
+        }
+
+        // A region can only be mapped from a file the server hands over.
+        let file = files.pop();
+        if file.is_none() {
+            flags &= !VFIO_REGION_INFO_FLAG_MMAP;
+        }
+
+        Ok(VfioUserRegion {
+            flags,
+            size,
+            offset,
+            mmap,
+            file,
+        })
+    }
+
+    fn request(&self, command: u16, payload: &[u8], fds: &[RawFd]) -> Result<Vec<u8>> {
+        self.client
+            .lock()
+            .unwrap()
+            .request(command, payload, fds)
+            .map(|(reply, _)| reply)
+    }
+
+    /// Let the device access `size` bytes of the guest memory at `iova`,
+    /// mapping them from `fd`, at `offset`.
+    pub fn dma_map(&self, iova: u64, size: u64, fd: RawFd, offset: u64) -> Result<()> {
+        let mut dma_map = [0u8; 32];
+        LittleEndian::write_u32(&mut dma_map[0..4], dma_map.len() as u32);
+        LittleEndian::write_u32(&mut dma_map[4..8], DMA_MAP_FLAG_READ | DMA_MAP_FLAG_WRITE);
+        LittleEndian::write_u64(&mut dma_map[8..16], offset);
+        LittleEndian::write_u64(&mut dma_map[16..24], iova);
+        LittleEndian::write_u64(&mut dma_map[24..32], size);
+
+        self.request(VFIO_USER_DMA_MAP, &dma_map, &[fd]).map(|_| ())
+    }
+
+    /// Stop the device accessing `size` bytes of the guest memory at `iova`.
+    pub fn dma_unmap(&self, iova: u64, size: u64) -> Result<()> {
+        let mut dma_unmap = [0u8; 24];
+        LittleEndian::write_u32(&mut dma_unmap[0..4], dma_unmap.len() as u32);
+        LittleEndian::write_u64(&mut dma_unmap[8..16], iova);
+        LittleEndian::write_u64(&mut dma_unmap[16..24], size);
+
+        self.request(VFIO_USER_DMA_UNMAP, &dma_unmap, &[])
+            .map(|_| ())
+    }
+
+    fn irq_count(&self, irq_index: u32) -> u32 {
+        self.irqs.get(irq_index as usize).cloned().unwrap_or(0)
+    }
+
+    fn enable_irq(&self, irq_index: u32, event_fds: Vec<&EventFd>) -> VfioResult<()> {
+        let count = cmp::min(self.irq_count(irq_index) as usize, event_fds.len());
+        if count == 0 {
+            return Err(VfioError::VfioDeviceSetIrq);
+        }
+
+        let fds: Vec<RawFd> = event_fds[..count].iter().map(|fd| fd.as_raw_fd()).collect();
+        let max_msg_fds = self.client.lock().unwrap().max_msg_fds;
+        for (i, chunk) in fds.chunks(max_msg_fds).enumerate() {
+            let mut irq_set = [0u8; 20];
+            LittleEndian::write_u32(&mut irq_set[0..4], irq_set.len() as u32);
+            LittleEndian::write_u32(
+                &mut irq_set[4..8],
+                VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+            );
+            LittleEndian::write_u32(&mut irq_set[8..12], irq_index);
+            LittleEndian::write_u32(&mut irq_set[12..16], (i * max_msg_fds) as u32);
+            LittleEndian::write_u32(&mut irq_set[16..20], chunk.len() as u32);
+
+            if let Err(e) = self.request(VFIO_USER_DEVICE_SET_IRQS, &irq_set, chunk) {
+                error!("Could not set vfio-user IRQs: {}", e);
+                return Err(VfioError::VfioDeviceSetIrq);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn disable_irq(&self, irq_index: u32) -> VfioResult<()> {
+        if self.irq_count(irq_index) == 0 {
+            return Err(VfioError::VfioDeviceSetIrq);
+        }
+
+        // No data and a count of zero disable all the interrupts of the type.
+        let mut irq_set = [0u8; 20];
+        LittleEndian::write_u32(&mut irq_set[0..4], irq_set.len() as u32);
+        LittleEndian::write_u32(
+            &mut irq_set[4..8],
+            VFIO_IRQ_SET_DATA_NONE | VFIO_IRQ_SET_ACTION_TRIGGER,
+        );
+        LittleEndian::write_u32(&mut irq_set[8..12], irq_index);
+
+        self.request(VFIO_USER_DEVICE_SET_IRQS, &irq_set, &[])
+            .map(|_| ())
+            .map_err(|e| {
+                error!("Could not disable vfio-user IRQs: {}", e);
+                VfioError::VfioDeviceSetIrq
+            })
+    }
+
+    // Checks an access fits in a region, and returns the region.
+    fn region_access(&self, index: u32, addr: u64, size: u64) -> Option<&VfioUserRegion> {
+        match self.regions.get(index as usize) {
+            Some(region) if size <= region.size && addr <= region.size - size => Some(region),
+            _ => {
+                warn!(
+                    "vfio-user region access with invalid parameter, index: {}, addr: {}, size: {}",
+                    index, addr, size
+                );
+                None
+            }
+        }
+    }
+}
+
+impl VfioOps for VfioUserDevice {
+    fn reset(&self) {
+        if self.flags & VFIO_DEVICE_FLAGS_RESET != 0 {
+            if let Err(e) = self.request(VFIO_USER_DEVICE_RESET, &[], &[]) {
+                error!("Could not reset vfio-user device: {}", e);
+            }
+        }
+    }
+
+    fn has_irq(&self, irq_index: u32) -> bool {
+        self.irq_count(irq_index) > 0
+    }
+
+    fn max_interrupts(&self) -> u32 {
+        [
+            VFIO_PCI_INTX_IRQ_INDEX,
+            VFIO_PCI_MSI_IRQ_INDEX,
+            VFIO_PCI_MSIX_IRQ_INDEX,
+        ]
+        .iter()
+        .map(|&index| self.irq_count(index))
+        .max()
+        .unwrap_or(0)
+    }
+
+    fn enable_msi(&self, fds: Vec<&EventFd>) -> VfioResult<()> {
+        self.enable_irq(VFIO_PCI_MSI_IRQ_INDEX, fds)
+    }
+
+    fn disable_msi(&self) -> VfioResult<()> {
+        self.disable_irq(VFIO_PCI_MSI_IRQ_INDEX)
+    }
+
+    fn enable_msix(&self, fds: Vec<&EventFd>) -> VfioResult<()> {
+        self.enable_irq(VFIO_PCI_MSIX_IRQ_INDEX, fds)
+    }
+
+    fn disable_msix(&self) -> VfioResult<()> {
+        self.disable_irq(VFIO_PCI_MSIX_IRQ_INDEX)
+    }
+
+    fn get_region_flags(&self, index: u32) -> u32 {
+        self.regions.get(index as usize).map_or(0, |r| r.flags)
+    }
+
+    fn get_region_offset(&self, index: u32) -> u64 {
+        self.regions.get(index as usize).map_or(0, |r| r.offset)
+    }
+
+    fn get_region_mmap(&self, index: u32) -> (u64, u64) {
+        self.regions.get(index as usize).map_or((0, 0), |r| r.mmap)
+    }
+
+    fn get_region_fd(&self, index: u32) -> Option<RawFd> {
+        self.regions
+            .get(index as usize)
+            .and_then(|r| r.file.as_ref())
+            .map(|file| file.as_raw_fd())
+    }
+
+    fn region_read(&self, index: u32, buf: &mut [u8], addr: u64) {
+        if self.region_access(index, addr, buf.len() as u64).is_none() {
+            return;
+        }
+
+        let mut region_access = [0u8; 16];
+        LittleEndian::write_u64(&mut region_access[0..8], addr);
+        LittleEndian::write_u32(&mut region_access[8..12], index);
+        LittleEndian::write_u32(&mut region_access[12..16], buf.len() as u32);
+
+        // The reply repeats the access, followed by the data read.
+        match self.request(VFIO_USER_REGION_READ, &region_access, &[]) {
+            Ok(reply) if reply.len() >= region_access.len() + buf.len() => {
+                buf.copy_from_slice(&reply[region_access.len()..region_access.len() + buf.len()])
+            }
+            Ok(_) => warn!(
+                "Short vfio-user region read in index: {}, addr: {}",
+                index, addr
+            ),
+            Err(e) => warn!(
+                "Failed to read vfio-user region in index: {}, addr: {}, error: {}",
+                index, addr, e
+            ),
+        }
+    }
+
+    fn region_write(&self, index: u32, buf: &[u8], addr: u64) {
+        match self.region_access(index, addr, buf.len() as u64) {
+            Some(region) if region.flags & VFIO_REGION_INFO_FLAG_WRITE != 0 => {}
+            _ => return,
+        }
+
+        let mut region_access = vec![0u8; 16];
+        LittleEndian::write_u64(&mut region_access[0..8], addr);
+        LittleEndian::write_u32(&mut region_access[8..12], index);
+        LittleEndian::write_u32(&mut region_access[12..16], buf.len() as u32);
+        region_access.extend_from_slice(buf);
+
+        if let Err(e) = self.request(VFIO_USER_REGION_WRITE, &region_access, &[]) {
+            warn!(
+                "Failed to write vfio-user region in index: {}, addr: {}, error: {}",
+                index, addr, e
+            );
+        }
+    }
+}
+
+/// This structure implements the ExternalDmaMapping trait, for a vfio-user
+/// device attached to the virtual IOMMU, and the MemoryListener trait, for
+/// a device identity mapping the guest RAM. The device maps the guest
+/// memory from the files backing it.
+pub struct VfioUserDmaMapping {
+    device: Arc<VfioUserDevice>,
+    memory: Arc<RwLock<GuestMemoryMmap>>,
+}
+
+impl VfioUserDmaMapping {
+    pub fn new(device: Arc<VfioUserDevice>, memory: Arc<RwLock<GuestMemoryMmap>>) -> Self {
+        VfioUserDmaMapping { device, memory }
+    }
+}
+
+impl ExternalDmaMapping for VfioUserDmaMapping {
+    fn map(&self, iova: u64, gpa: u64, size: u64) -> result::Result<(), io::Error> {
+        let memory = self.memory.read().unwrap();
+        let (fd, offset) = match memory.find_region(GuestAddress(gpa)) {
+            Some(region) => match region.file_offset() {
+                Some(file_offset) => (
+                    file_offset.file().as_raw_fd(),
+                    file_offset.start() + (gpa - region.start_addr().raw_value()),
+                ),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "guest address 0x{:x} isn't backed by a file the \
+                             vfio-user device can map",
+                            gpa
+                        ),
+                    ))
+                }
+            },
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("guest address 0x{:x} isn't guest RAM", gpa),
+                ))
+            }
+        };
+
+        self.device.dma_map(iova, size, fd, offset).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "failed to map memory for vfio-user device, \
+                     iova 0x{:x}, gpa 0x{:x}, size 0x{:x}: {}",
+                    iova, gpa, size, e
+                ),
+            )
+        })
+    }
+
+    fn unmap(&self, iova: u64, size: u64) -> result::Result<(), io::Error> {
+        self.device.dma_unmap(iova, size).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "failed to unmap memory for vfio-user device, \
+                     iova 0x{:x}, size 0x{:x}: {}",
+                    iova, size, e
+                ),
+            )
+        })
+    }
+}
+
+impl MemoryListener for VfioUserDmaMapping {
+    fn region_added(&self, gpa: u64, size: u64, _host_addr: u64) -> result::Result<(), io::Error> {
+        self.map(gpa, gpa, size)
+    }
+
+    fn region_removed(&self, gpa: u64, size: u64) -> result::Result<(), io::Error> {
+        self.unmap(gpa, size)
+    }
+}
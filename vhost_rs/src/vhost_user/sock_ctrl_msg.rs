@@ -14,7 +14,8 @@ use std::os::unix::net::{UnixDatagram, UnixStream};
 use std::ptr::{copy_nonoverlapping, null_mut, write_unaligned};
 
 use libc::{
-    c_long, c_void, cmsghdr, iovec, msghdr, recvmsg, sendmsg, MSG_NOSIGNAL, SCM_RIGHTS, SOL_SOCKET,
+    c_int, c_long, c_void, cmsghdr, iovec, msghdr, recvmsg, sendmsg, MSG_CMSG_CLOEXEC,
+    MSG_NOSIGNAL, SCM_RIGHTS, SOL_SOCKET,
 };
 use vmm_sys_util::errno::{Error, Result};
 
@@ -156,7 +157,12 @@ fn raw_sendmsg<D: IntoIovec>(fd: RawFd, out_data: &[D], out_fds: &[RawFd]) -> Re
     }
 }
 
-fn raw_recvmsg(fd: RawFd, iovecs: &mut [iovec], in_fds: &mut [RawFd]) -> Result<(usize, usize)> {
+fn raw_recvmsg(
+    fd: RawFd,
+    iovecs: &mut [iovec],
+    in_fds: &mut [RawFd],
+    flags: c_int,
+) -> Result<(usize, usize)> {
     let cmsg_capacity = CMSG_SPACE!(size_of::<RawFd>() * in_fds.len());
     let mut cmsg_buffer = CmsgBuffer::with_capacity(cmsg_capacity);
     let mut msg = msghdr {
@@ -176,7 +182,7 @@ fn raw_recvmsg(fd: RawFd, iovecs: &mut [iovec], in_fds: &mut [RawFd]) -> Result<
 
     // Safe because the msghdr was properly constructed from valid (or null) pointers of the
     // indicated length and we check the return value.
-    let total_read = unsafe { recvmsg(fd, &mut msg, libc::MSG_WAITALL) };
+    let total_read = unsafe { recvmsg(fd, &mut msg, flags) };
 
     if total_read == -1 {
         return Err(Error::last());
@@ -280,7 +286,33 @@ pub trait ScmSocket {
     ///           closed on drop like a `File`-like type would be. It is recommended that each valid
     ///           file descriptor gets wrapped in a drop type that closes it after this returns.
     fn recv_with_fds(&self, iovecs: &mut [iovec], fds: &mut [RawFd]) -> Result<(usize, usize)> {
-        raw_recvmsg(self.socket_fd(), iovecs, fds)
+        raw_recvmsg(self.socket_fd(), iovecs, fds, libc::MSG_WAITALL)
+    }
+
+    /// Receives data and the files whose descriptors come along with it. Unlike `recv_with_fds`,
+    /// returns as soon as some data is there, and the descriptors are received close-on-exec.
+    ///
+    /// On success, returns the number of bytes and the files received.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - A buffer to receive data from the socket.
+    /// * `max_fds` - The most file descriptors to receive.
+    fn recv_with_files(&self, buf: &mut [u8], max_fds: usize) -> Result<(usize, Vec<File>)> {
+        let mut fds = vec![0; max_fds];
+        let mut iovecs = [iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        }];
+
+        let (read_count, fd_count) =
+            raw_recvmsg(self.socket_fd(), &mut iovecs, &mut fds, MSG_CMSG_CLOEXEC)?;
+        let files = fds[..fd_count]
+            .iter()
+            // Safe because the first fd_count fds from raw_recvmsg are owned by us and valid.
+            .map(|fd| unsafe { File::from_raw_fd(*fd) })
+            .collect();
+        Ok((read_count, files))
     }
 }
 
@@ -81,6 +81,11 @@ impl SystemAllocator {
         self.gsi_allocator.allocate_gsi().ok()
     }
 
+    /// Gives back a GSI reserved with `allocate_gsi()`.
+    pub fn free_gsi(&mut self, gsi: u32) {
+        self.gsi_allocator.free_gsi(gsi)
+    }
+
     /// Reserves a section of `size` bytes of IO address space.
     pub fn allocate_io_addresses(
         &mut self,
@@ -131,4 +136,10 @@ impl SystemAllocator {
     pub fn free_mmio_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
         self.mmio_address_space.free(address, size)
     }
+
+    /// Free an MMIO hole address range.
+    /// We can only free a range if it matches exactly an already allocated range.
+    pub fn free_mmio_hole_addresses(&mut self, address: GuestAddress, size: GuestUsize) {
+        self.mmio_hole_address_space.free(address, size)
+    }
 }
@@ -0,0 +1,63 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Message signalled interrupts of the devices, each vector being routed to
+//! the guest by the hypervisor and raised through an EventFd.
+//!
+//! The guest programs the message of each vector, and masks it, through the
+//! MSI capability or the MSI-X table of the device, which then updates the
+//! route of the vector. Raising an interrupt only takes writing its EventFd,
+//! from the device threads, or from the kernel for the devices it handles.
+
+use std::io;
+use std::sync::Arc;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Index of an interrupt in its group.
+pub type InterruptIndex = u32;
+
+/// Message the guest programmed an MSI or MSI-X vector with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MsiIrqSourceConfig {
+    pub low_addr: u32,
+    pub high_addr: u32,
+    pub data: u32,
+}
+
+/// The interrupts of a device, the vectors of its MSI capability or of its
+/// MSI-X table.
+pub trait InterruptSourceGroup: Send + Sync {
+    /// Raises the interrupt, which the hypervisor drops when it is masked.
+    fn trigger(&self, index: InterruptIndex) -> io::Result<()>;
+
+    /// The EventFd raising the interrupt.
+    fn notifier(&self, index: InterruptIndex) -> Option<&EventFd>;
+
+    /// Routes the interrupt to the guest with the message, or stops routing
+    /// it while it is masked.
+    fn update(
+        &self,
+        index: InterruptIndex,
+        config: MsiIrqSourceConfig,
+        masked: bool,
+    ) -> io::Result<()>;
+
+    /// Whether the interrupt is masked, writing its EventFd raising nothing.
+    fn masked(&self, index: InterruptIndex) -> bool;
+
+    /// Stops routing the interrupts, and gives their resources back, before
+    /// the device is removed.
+    fn free(&self) -> io::Result<()>;
+}
+
+/// Creates the interrupt groups of the devices.
+pub trait InterruptManager: Send + Sync {
+    /// Creates a group of `count` interrupts, all masked. The hypervisors
+    /// telling the devices apart by their requester ID are given `devid`.
+    fn create_group(
+        &self,
+        devid: Option<u32>,
+        count: InterruptIndex,
+    ) -> io::Result<Arc<dyn InterruptSourceGroup>>;
+}
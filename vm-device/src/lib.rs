@@ -1,3 +1,9 @@
+mod interrupt;
+mod pause;
+
+pub use interrupt::{InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqSourceConfig};
+pub use pause::{PauseControl, PauseWorker, PAUSE_TIMEOUT};
+
 /// Trait meant for triggering the DMA mapping update related to an external
 /// device not managed fully through virtio. It is dedicated to virtio-iommu
 /// in order to trigger the map update anytime the mapping is updated from the
@@ -9,3 +15,37 @@ pub trait ExternalDmaMapping: Send + Sync {
     /// Unmap a memory range
     fn unmap(&self, iova: u64, size: u64) -> std::result::Result<(), std::io::Error>;
 }
+
+/// Trait meant for the devices and backends which need to keep their view of
+/// the guest RAM in sync with the actual guest memory layout, such as VFIO
+/// containers identity mapping the guest RAM, vhost-user backends or the
+/// virtio-iommu. A region is reported as removed before it gets unplugged, so
+/// that no stale mapping can be used once the memory is gone.
+pub trait MemoryListener: Send + Sync {
+    /// A RAM region has been added to the guest memory.
+    fn region_added(
+        &self,
+        gpa: u64,
+        size: u64,
+        host_addr: u64,
+    ) -> std::result::Result<(), std::io::Error>;
+
+    /// A RAM region is about to be removed from the guest memory.
+    fn region_removed(&self, gpa: u64, size: u64) -> std::result::Result<(), std::io::Error>;
+}
+
+/// Trait for the devices running worker threads, which go on handling the
+/// requests of the guest drivers while the vCPUs are paused unless they are
+/// paused along with them.
+pub trait Pausable {
+    /// Pauses the worker threads of the device, once they completed the
+    /// requests they were handling, and flushed them to the backend.
+    fn pause(&mut self) -> std::result::Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Resumes the worker threads of the paused device.
+    fn resume(&mut self) -> std::result::Result<(), std::io::Error> {
+        Ok(())
+    }
+}
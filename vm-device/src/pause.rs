@@ -0,0 +1,200 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Pausing of the worker threads of a device, which poll the pause event
+//! alongside their other events.
+//!
+//! The event stays readable for as long as the device is paused, each
+//! worker thread waiting for the device to be resumed once it completed the
+//! requests it was handling. The device is paused once all its worker
+//! threads are, the ones created while it is paused pausing right away.
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+/// How long pausing a device waits for its worker threads to stop.
+pub const PAUSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct PauseState {
+    paused: bool,
+    workers: usize,
+    paused_workers: usize,
+}
+
+struct PauseShared {
+    evt: EventFd,
+    state: Mutex<PauseState>,
+    cond: Condvar,
+}
+
+/// Pauses the worker threads of a device, the device keeping it.
+pub struct PauseControl {
+    shared: Arc<PauseShared>,
+}
+
+impl PauseControl {
+    pub fn new() -> io::Result<Self> {
+        Ok(PauseControl {
+            shared: Arc::new(PauseShared {
+                evt: EventFd::new(EFD_NONBLOCK)?,
+                state: Mutex::new(PauseState::default()),
+                cond: Condvar::new(),
+            }),
+        })
+    }
+
+    /// The handle of a new worker thread, paused along with the device.
+    pub fn worker(&self) -> PauseWorker {
+        self.shared.state.lock().unwrap().workers += 1;
+
+        PauseWorker {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Pauses the worker threads, waiting up to `PAUSE_TIMEOUT` for them to
+    /// stop. A TimedOut error is returned for the threads which didn't, their
+    /// pause taking effect once they're done with their current requests.
+    pub fn pause(&self) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.paused {
+            state.paused = true;
+            self.shared.evt.write(1)?;
+        }
+
+        let deadline = Instant::now() + PAUSE_TIMEOUT;
+        while state.paused_workers < state.workers {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "{} of {} worker threads paused",
+                        state.paused_workers, state.workers
+                    ),
+                ));
+            }
+            state = self
+                .shared
+                .cond
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+
+        Ok(())
+    }
+
+    /// Resumes the paused worker threads.
+    pub fn resume(&self) -> io::Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.paused {
+            state.paused = false;
+            match self.shared.evt.read() {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            self.shared.cond.notify_all();
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PauseControl {
+    // The worker threads of a device dropped while paused go on, for them
+    // to see they are to stop.
+    fn drop(&mut self) {
+        // Ignore the result because there is nothing we can do about it.
+        let _ = self.resume();
+    }
+}
+
+/// The handle a worker thread pauses through.
+pub struct PauseWorker {
+    shared: Arc<PauseShared>,
+}
+
+impl PauseWorker {
+    /// The event to poll, readable while the device is paused.
+    pub fn evt(&self) -> &EventFd {
+        &self.shared.evt
+    }
+
+    /// Waits for the device to be resumed, once the worker stopped handling
+    /// its requests. Returns right away if the device isn't paused.
+    pub fn wait_resumed(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.paused {
+            return;
+        }
+
+        state.paused_workers += 1;
+        self.shared.cond.notify_all();
+        while state.paused {
+            state = self.shared.cond.wait(state).unwrap();
+        }
+        state.paused_workers -= 1;
+    }
+}
+
+impl Drop for PauseWorker {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().workers -= 1;
+        self.shared.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_pause_workers() {
+        let control = PauseControl::new().unwrap();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let worker = control.worker();
+        let worker_running = running.clone();
+        let thread = thread::spawn(move || {
+            while worker_running.load(Ordering::SeqCst) {
+                worker.wait_resumed();
+                thread::yield_now();
+            }
+        });
+
+        for _ in 0..2 {
+            control.pause().unwrap();
+            // The event stays readable while the device is paused.
+            assert!(worker_evt_readable(&control));
+            control.resume().unwrap();
+            assert!(!worker_evt_readable(&control));
+        }
+
+        running.store(false, Ordering::SeqCst);
+        thread.join().unwrap();
+
+        // Without worker threads left, the device pauses right away.
+        control.pause().unwrap();
+        control.resume().unwrap();
+    }
+
+    fn worker_evt_readable(control: &PauseControl) -> bool {
+        let worker = control.worker();
+        let mut pollfd = libc::pollfd {
+            fd: worker.evt().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because the kernel only writes the pollfd structure.
+        unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+    }
+}
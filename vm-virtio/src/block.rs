@@ -17,32 +17,53 @@ use std::os::linux::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
 use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
-    VirtioDeviceType, VirtioInterruptType,
+    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, RateLimiter, TokenType,
+    VirtioDevice, VirtioDeviceType, VirtioInterruptType,
 };
 use crate::VirtioInterrupt;
+use qcow::QcowFile;
 use virtio_bindings::bindings::virtio_blk::*;
-use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+use vm_device::{Pausable, PauseControl, PauseWorker};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
-
-const CONFIG_SPACE_SIZE: usize = 8;
+use vmm_sys_util::write_zeroes::PunchHole;
+
+// The configuration space goes up to the write zeroes fields, the fields
+// in between are left zeroed since their features aren't offered.
+const CONFIG_SPACE_SIZE: usize = 60;
+const CONFIG_WRITEBACK_OFFSET: usize = 32;
+const CONFIG_DISCARD_OFFSET: usize = 36;
+const CONFIG_WRITE_ZEROES_OFFSET: usize = 48;
+// Segments of a discard or write zeroes request, and the alignment of the
+// discarded ranges, in sectors.
+const MAX_DISCARD_SEG: u32 = 32;
+const DISCARD_SECTOR_ALIGNMENT: u32 = 8;
+// Size of a discard or write zeroes segment: sector, number of sectors
+// and flags.
+const DISCARD_SEGMENT_SIZE: u32 = 16;
+// Size of the buffer of zeroes written when the filesystem can't zero a
+// range by itself.
+const ZEROES_BUFFER_SIZE: u64 = 1 << 20;
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
-const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 1;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
 // The device has been dropped.
 pub const KILL_EVENT: DeviceEventT = 1;
+// The rate limiter refill timer expired.
+const RATE_LIMITER_EVENT: DeviceEventT = 2;
+// The device is paused.
+const PAUSE_EVENT: DeviceEventT = 3;
 // Number of DeviceEventT events supported by this implementation.
-pub const BLOCK_EVENTS_COUNT: usize = 2;
+pub const BLOCK_EVENTS_COUNT: usize = 4;
 
 #[derive(Debug)]
 pub enum Error {
@@ -62,6 +83,9 @@ pub enum Error {
     GetFileMetadata,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
+    /// The segments of a discard or write zeroes request don't fit in its
+    /// data descriptor, or are too many.
+    InvalidSegments,
 }
 
 #[derive(Debug)]
@@ -71,6 +95,9 @@ pub enum ExecuteError {
     Read(GuestMemoryError),
     Seek(io::Error),
     Write(GuestMemoryError),
+    Discard(io::Error),
+    // The guest wrote to a read-only disk.
+    ReadOnly,
     Unsupported(u32),
 }
 
@@ -82,13 +109,39 @@ impl ExecuteError {
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::ReadOnly => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
+
+    /// Whether the disk image ran out of space, as thin-provisioned storage
+    /// does until space is freed, rather than failing.
+    pub fn out_of_space(&self) -> bool {
+        let error = match self {
+            ExecuteError::Flush(e) => e,
+            ExecuteError::Write(GuestMemoryError::IOError(e)) => e,
+            ExecuteError::Discard(e) => e,
+            _ => return false,
+        };
+
+        error.raw_os_error() == Some(libc::ENOSPC)
+    }
 }
 
-pub trait DiskFile: Read + Seek + Write + Clone {}
-impl<D: Read + Seek + Write + Clone> DiskFile for D {}
+/// Ranges of a disk image the guest discards or zeroes.
+pub trait DiskDiscard {
+    /// Deallocates the storage of the range, which may read back as
+    /// anything afterwards.
+    fn discard_range(&mut self, offset: u64, length: u64) -> io::Result<()>;
+
+    /// Zeroes the range, deallocating its storage when `unmap` is set, or
+    /// keeping it allocated otherwise where the image can.
+    fn zero_range(&mut self, offset: u64, length: u64, unmap: bool) -> io::Result<()>;
+}
+
+pub trait DiskFile: Read + Seek + Write + DiskDiscard + Clone {}
+impl<D: Read + Seek + Write + DiskDiscard + Clone> DiskFile for D {}
 
 pub struct RawFile {
     file: File,
@@ -98,6 +151,77 @@ impl RawFile {
     pub fn new(file: File) -> Self {
         RawFile { file }
     }
+
+    fn fallocate(&self, mode: libc::c_int, offset: u64, length: u64) -> io::Result<()> {
+        // Safe because the file descriptor is valid, and we check the
+        // return value.
+        let ret = unsafe {
+            libc::fallocate64(
+                self.file.as_raw_fd(),
+                mode,
+                offset as libc::off64_t,
+                length as libc::off64_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn write_zeroes(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        let zeroes = vec![0u8; cmp::min(length, ZEROES_BUFFER_SIZE) as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut remaining = length;
+        while remaining > 0 {
+            let len = cmp::min(remaining, zeroes.len() as u64) as usize;
+            self.file.write_all(&zeroes[..len])?;
+            remaining -= len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl DiskDiscard for RawFile {
+    fn discard_range(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        match self.fallocate(
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset,
+            length,
+        ) {
+            // A discard is only a hint, which the filesystems without
+            // holes can't take.
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn zero_range(&mut self, offset: u64, length: u64, unmap: bool) -> io::Result<()> {
+        let mode = if unmap {
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE
+        } else {
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE
+        };
+        match self.fallocate(mode, offset, length) {
+            // Block devices and some filesystems can't zero ranges.
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                self.write_zeroes(offset, length)
+            }
+            result => result,
+        }
+    }
+}
+
+// The clusters of the discarded and zeroed ranges are deallocated, reading
+// back as zeroes, and only the parts of clusters are written with zeroes.
+impl DiskDiscard for QcowFile {
+    fn discard_range(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        self.punch_hole(offset, length)
+    }
+
+    fn zero_range(&mut self, offset: u64, length: u64, _unmap: bool) -> io::Result<()> {
+        self.punch_hole(offset, length)
+    }
 }
 
 impl Read for RawFile {
@@ -118,7 +242,8 @@ impl Write for RawFile {
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.file.flush()
+        // The writes are only on the disk once the file data is synced.
+        self.file.sync_data()
     }
 }
 
@@ -136,9 +261,21 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
+impl RequestType {
+    /// The request changes the content of the disk.
+    pub fn writes(self) -> bool {
+        match self {
+            RequestType::Out | RequestType::Discard | RequestType::WriteZeroes => true,
+            _ => false,
+        }
+    }
+}
+
 pub fn request_type(
     mem: &GuestMemoryMmap,
     desc_addr: GuestAddress,
@@ -149,6 +286,8 @@ pub fn request_type(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -240,7 +379,7 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+            if data_desc.is_write_only() && req.request_type.writes() {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.request_type == RequestType::In {
@@ -313,10 +452,70 @@ impl Request {
                 mem.write_slice(&disk_id.as_slice(), self.data_addr)
                     .map_err(ExecuteError::Write)?;
             }
+            // The disk has to take discards, see execute_discard().
+            RequestType::Discard => return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD)),
+            RequestType::WriteZeroes => {
+                return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES))
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
     }
+
+    /// Executes a discard or write zeroes request, whose data descriptor
+    /// holds the segments of the disk to discard or zero.
+    pub fn execute_discard<T: DiskDiscard>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ExecuteError> {
+        let request_type = match self.request_type {
+            RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+            RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
+            _ => return Err(ExecuteError::BadRequest(Error::InvalidSegments)),
+        };
+        let num_segments = self.data_len / DISCARD_SEGMENT_SIZE;
+        if self.data_len % DISCARD_SEGMENT_SIZE != 0 || num_segments > MAX_DISCARD_SEG {
+            return Err(ExecuteError::BadRequest(Error::InvalidSegments));
+        }
+
+        for index in 0..num_segments {
+            let segment_addr = self
+                .data_addr
+                .checked_add(u64::from(index * DISCARD_SEGMENT_SIZE))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidSegments))?;
+            let sector: u64 = mem.read_obj(segment_addr).map_err(ExecuteError::Read)?;
+            let num_sectors: u32 = mem
+                .read_obj(segment_addr.unchecked_add(8))
+                .map_err(ExecuteError::Read)?;
+            let flags: u32 = mem
+                .read_obj(segment_addr.unchecked_add(12))
+                .map_err(ExecuteError::Read)?;
+
+            let top = sector
+                .checked_add(u64::from(num_sectors))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            if top > disk_nsectors {
+                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+            }
+            let offset = sector << SECTOR_SHIFT;
+            let length = u64::from(num_sectors) << SECTOR_SHIFT;
+
+            // The discards take no flag, and the write zeroes only the one
+            // letting the device deallocate the range.
+            match self.request_type {
+                RequestType::Discard if flags == 0 => disk.discard_range(offset, length),
+                RequestType::WriteZeroes if flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP == 0 => {
+                    let unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+                    disk.zero_range(offset, length, unmap)
+                }
+                _ => return Err(ExecuteError::Unsupported(request_type)),
+            }
+            .map_err(ExecuteError::Discard)?;
+        }
+        Ok(0)
+    }
 }
 
 struct BlockEpollHandler<T: DiskFile> {
@@ -326,29 +525,93 @@ struct BlockEpollHandler<T: DiskFile> {
     disk_nsectors: u64,
     interrupt_cb: Arc<VirtioInterrupt>,
     disk_image_id: Vec<u8>,
+    rate_limiter: Option<RateLimiter>,
+    out_of_space: Arc<AtomicBool>,
+    out_of_space_evt: Option<EventFd>,
+    // The disk image ran out of space, the requests are left on the queue
+    // until the device is reset.
+    stalled: bool,
+    writeback: Arc<AtomicBool>,
+    ignore_flush: bool,
+    read_only: bool,
+    pause: PauseWorker,
 }
 
 impl<T: DiskFile> BlockEpollHandler<T> {
     fn process_queue(&mut self, queue_index: usize) -> bool {
+        if self.stalled {
+            return false;
+        }
+
         let queue = &mut self.queues[queue_index];
 
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_desc_heads = vec![(0, 0); queue.actual_size() as usize];
         let mut used_count = 0;
+        let mut stalled = false;
         let mem = self.mem.read().unwrap();
-        for avail_desc in queue.iter(&mem) {
+        while let Some(avail_desc) = queue.iter(&mem).next() {
             let len;
             match Request::parse(&avail_desc, &mem) {
                 Ok(request) => {
-                    let status = match request.execute(
-                        &mut self.disk_image,
-                        self.disk_nsectors,
-                        &mem,
-                        &self.disk_image_id,
-                    ) {
+                    if let Some(rate_limiter) = &mut self.rate_limiter {
+                        // If consume() fails, there is no budget left. Put the
+                        // descriptor back and wait for the limiter to refill.
+                        if !rate_limiter.consume(1, TokenType::Ops) {
+                            queue.go_to_previous_position();
+                            break;
+                        }
+                        let bytes = match request.request_type {
+                            RequestType::In | RequestType::Out => u64::from(request.data_len),
+                            _ => 0,
+                        };
+                        if !rate_limiter.consume(bytes, TokenType::Bytes) {
+                            // Give back the operation token taken above.
+                            rate_limiter.manual_replenish(1, TokenType::Ops);
+                            queue.go_to_previous_position();
+                            break;
+                        }
+                    }
+
+                    let mut result = if request.request_type.writes() && self.read_only {
+                        // The disk image isn't written even if it could be.
+                        Err(ExecuteError::ReadOnly)
+                    } else if request.request_type == RequestType::Flush && self.ignore_flush {
+                        Ok(0)
+                    } else if request.request_type == RequestType::Discard
+                        || request.request_type == RequestType::WriteZeroes
+                    {
+                        request.execute_discard(&mut self.disk_image, self.disk_nsectors, &mem)
+                    } else {
+                        request.execute(
+                            &mut self.disk_image,
+                            self.disk_nsectors,
+                            &mem,
+                            &self.disk_image_id,
+                        )
+                    };
+                    // Without a write cache, the writes are flushed before
+                    // they complete.
+                    if result.is_ok()
+                        && request.request_type.writes()
+                        && !self.ignore_flush
+                        && !self.writeback.load(Ordering::Acquire)
+                    {
+                        if let Err(e) = self.disk_image.flush() {
+                            result = Err(ExecuteError::Flush(e));
+                        }
+                    }
+                    let status = match result {
                         Ok(l) => {
                             len = l;
                             VIRTIO_BLK_S_OK
                         }
+                        // The request is taken again once the device is
+                        // reset, rather than the guest getting an error.
+                        Err(ref e) if e.out_of_space() => {
+                            queue.go_to_previous_position();
+                            stalled = true;
+                            break;
+                        }
                         Err(e) => {
                             error!("Failed to execute request: {:?}", e);
                             len = 1; // We need at least 1 byte for the status.
@@ -371,6 +634,18 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         for &(desc_index, len) in &used_desc_heads[..used_count] {
             queue.add_used(&mem, desc_index, len);
         }
+
+        if stalled {
+            warn!("Disk image out of space, the device waits for a reset to retry");
+            self.stalled = true;
+            self.out_of_space.store(true, Ordering::SeqCst);
+            if let Some(out_of_space_evt) = &self.out_of_space_evt {
+                if let Err(e) = out_of_space_evt.write(1) {
+                    error!("Failed to signal the disk image is out of space: {:?}", e);
+                }
+            }
+        }
+
         used_count > 0
     }
 
@@ -418,6 +693,22 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause.evt().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                rate_limiter.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(RATE_LIMITER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
 
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -455,10 +746,34 @@ impl<T: DiskFile> BlockEpollHandler<T> {
                             }
                         }
                     }
+                    RATE_LIMITER_EVENT => {
+                        if let Some(rate_limiter) = &mut self.rate_limiter {
+                            if let Err(e) = rate_limiter.event_handler() {
+                                error!("Failed to handle rate limiter event: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                        // Resume processing the requests left on the queue.
+                        if self.process_queue(0) {
+                            if let Err(e) = self.signal_used_queue(0) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
                         break 'epoll;
                     }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing epoll loop");
+                        // The requests are completed already, their writes
+                        // are flushed before the image is left alone.
+                        if let Err(e) = self.disk_image.flush() {
+                            error!("Failed to flush the disk image: {:?}", e);
+                        }
+                        self.pause.wait_resumed();
+                    }
                     _ => {
                         error!("Unknown event for virtio-block");
                     }
@@ -479,12 +794,21 @@ pub struct Block<T: DiskFile> {
     avail_features: u64,
     acked_features: u64,
     config_space: Vec<u8>,
+    queue_sizes: Vec<u16>,
     queue_evt: Option<EventFd>,
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    rate_limiter: Option<RateLimiter>,
+    out_of_space: Arc<AtomicBool>,
+    out_of_space_evt: Option<EventFd>,
+    writeback: Arc<AtomicBool>,
+    ignore_flush: bool,
+    read_only: bool,
+    pause: PauseControl,
 }
 
 pub fn build_config_space(disk_size: u64) -> Vec<u8> {
-    // We only support disk size, which uses the first two words of the configuration space.
+    // We support disk size, which uses the first two words of the configuration space,
+    // and the writeback field telling the guest the write cache is enabled.
     // If the image is not a multiple of the sector size, the tail bits are not exposed.
     // The config space is little endian.
     let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
@@ -492,6 +816,20 @@ pub fn build_config_space(disk_size: u64) -> Vec<u8> {
     for i in 0..8 {
         config.push((num_sectors >> (8 * i)) as u8);
     }
+    config.resize(CONFIG_SPACE_SIZE, 0);
+    config[CONFIG_WRITEBACK_OFFSET] = 1;
+    // The discards and write zeroes are as large as the guest wants, the
+    // write zeroes being allowed to deallocate their ranges.
+    let discard_fields = [std::u32::MAX, MAX_DISCARD_SEG, DISCARD_SECTOR_ALIGNMENT];
+    let write_zeroes_fields = [std::u32::MAX, MAX_DISCARD_SEG, 1];
+    for (i, field) in discard_fields.iter().enumerate() {
+        let offset = CONFIG_DISCARD_OFFSET + 4 * i;
+        config[offset..offset + 4].copy_from_slice(&field.to_le_bytes());
+    }
+    for (i, field) in write_zeroes_fields.iter().enumerate() {
+        let offset = CONFIG_WRITE_ZEROES_OFFSET + 4 * i;
+        config[offset..offset + 4].copy_from_slice(&field.to_le_bytes());
+    }
     config
 }
 
@@ -504,6 +842,8 @@ impl<T: DiskFile> Block<T> {
         disk_path: PathBuf,
         is_disk_read_only: bool,
         iommu: bool,
+        rate_limiter: Option<RateLimiter>,
+        queue_size: u16,
     ) -> io::Result<Block<T>> {
         let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
         if disk_size % SECTOR_SIZE != 0 {
@@ -514,7 +854,9 @@ impl<T: DiskFile> Block<T> {
             );
         }
 
-        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_BLK_F_FLUSH)
+            | (1u64 << VIRTIO_BLK_F_CONFIG_WCE);
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -522,6 +864,8 @@ impl<T: DiskFile> Block<T> {
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
         };
 
         Ok(Block {
@@ -532,10 +876,37 @@ impl<T: DiskFile> Block<T> {
             avail_features,
             acked_features: 0u64,
             config_space: build_config_space(disk_size),
+            queue_sizes: vec![queue_size; NUM_QUEUES],
             queue_evt: None,
             interrupt_cb: None,
+            rate_limiter,
+            out_of_space: Arc::new(AtomicBool::new(false)),
+            out_of_space_evt: None,
+            writeback: Arc::new(AtomicBool::new(true)),
+            ignore_flush: false,
+            read_only: is_disk_read_only,
+            pause: PauseControl::new()?,
         })
     }
+
+    /// Completes the flush requests of the guest without syncing the disk
+    /// image, nor flushing the writes when the guest disables the write
+    /// cache: the writes the host didn't sync yet are lost if it crashes.
+    pub fn set_ignore_flush(&mut self, ignore_flush: bool) {
+        self.ignore_flush = ignore_flush;
+    }
+
+    /// Sets the event written when the disk image runs out of space, the
+    /// device then waiting for a reset to retry the request it was handling.
+    pub fn set_out_of_space_evt(&mut self, out_of_space_evt: EventFd) {
+        self.out_of_space_evt = Some(out_of_space_evt);
+    }
+
+    /// Flag raised along with the out of space event, the VMM lowering it
+    /// once it handled the event.
+    pub fn out_of_space(&self) -> Arc<AtomicBool> {
+        self.out_of_space.clone()
+    }
 }
 
 impl<T: DiskFile> Drop for Block<T> {
@@ -547,13 +918,23 @@ impl<T: DiskFile> Drop for Block<T> {
     }
 }
 
+impl<T: DiskFile> Pausable for Block<T> {
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
 impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
     fn device_type(&self) -> u32 {
         VirtioDeviceType::TYPE_BLOCK as u32
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn features(&self, page: u32) -> u32 {
@@ -604,14 +985,15 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        let data_len = data.len() as u64;
-        let config_len = self.config_space.len() as u64;
-        if offset + data_len > config_len {
+        // The writeback field is the only one the guest can write, to
+        // switch between the writeback and writethrough modes of the cache.
+        if offset != CONFIG_WRITEBACK_OFFSET as u64 || data.len() != 1 {
             error!("Failed to write config space");
             return;
         }
-        let (_, right) = self.config_space.split_at_mut(offset as usize);
-        right.copy_from_slice(&data[..]);
+        let writeback = data[0] != 0;
+        self.config_space[CONFIG_WRITEBACK_OFFSET] = writeback as u8;
+        self.writeback.store(writeback, Ordering::Release);
     }
 
     fn activate(
@@ -656,6 +1038,22 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
                 ActivateError::BadActivate
             })?;
 
+            let rate_limiter = match &self.rate_limiter {
+                Some(rate_limiter) => Some(rate_limiter.try_clone().map_err(|e| {
+                    error!("failed to clone rate limiter: {}", e);
+                    ActivateError::BadActivate
+                })?),
+                None => None,
+            };
+
+            let out_of_space_evt = match &self.out_of_space_evt {
+                Some(out_of_space_evt) => Some(out_of_space_evt.try_clone().map_err(|e| {
+                    error!("failed to clone out of space EventFd: {}", e);
+                    ActivateError::BadActivate
+                })?),
+                None => None,
+            };
+
             let mut handler = BlockEpollHandler {
                 queues,
                 mem,
@@ -663,6 +1061,14 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
                 disk_nsectors: self.disk_nsectors,
                 interrupt_cb,
                 disk_image_id,
+                rate_limiter,
+                out_of_space: self.out_of_space.clone(),
+                out_of_space_evt,
+                stalled: false,
+                writeback: self.writeback.clone(),
+                ignore_flush: self.ignore_flush,
+                read_only: self.read_only,
+                pause: self.pause.worker(),
             };
 
             let worker_result = thread::Builder::new()
@@ -685,6 +1091,10 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             let _ = kill_evt.write(1);
         }
 
+        // The write cache is enabled again, as the guest first finds it.
+        self.config_space[CONFIG_WRITEBACK_OFFSET] = 1;
+        self.writeback.store(true, Ordering::Release);
+
         // Return the interrupt and queue EventFDs
         Some((
             self.interrupt_cb.take().unwrap(),
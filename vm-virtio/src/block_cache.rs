@@ -0,0 +1,362 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Host side read cache of the read-only disk images, shared by the VMs of
+//! the process.
+//!
+//! The blocks are keyed by the digest of the content of their image, rather
+//! than by its path, so that identical images, such as the copies of a base
+//! image on network storage, share their blocks. The digest is computed the
+//! first time the process opens an image, and remembered for as long as the
+//! image file isn't changed. The least recently used blocks are evicted once
+//! the cache holds its capacity.
+
+use crate::DiskDiscard;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::linux::fs::MetadataExt;
+use std::sync::{Arc, Mutex};
+
+/// Size of the blocks the images are cached by.
+pub const CACHE_BLOCK_SIZE: u64 = 64 << 10;
+
+// Size of the reads the digest of an image is computed from.
+const DIGEST_CHUNK_SIZE: usize = 1 << 20;
+
+/// The file of a disk image, as long as it isn't changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImageIdentity {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl ImageIdentity {
+    pub fn of(file: &File) -> io::Result<Self> {
+        let metadata = file.metadata()?;
+        Ok(ImageIdentity {
+            dev: metadata.st_dev(),
+            ino: metadata.st_ino(),
+            size: metadata.st_size(),
+            mtime: metadata.st_mtime(),
+            mtime_nsec: metadata.st_mtime_nsec(),
+        })
+    }
+}
+
+// A block of an image, by the digest of the image and the index of the
+// block in it.
+type BlockKey = (u64, u64);
+
+#[derive(Default)]
+struct CacheState {
+    // The blocks, along with the tick they were last used at.
+    blocks: HashMap<BlockKey, (Arc<Vec<u8>>, u64)>,
+    // The blocks by the tick they were last used at, the least recently used
+    // first.
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+    bytes: u64,
+    digests: HashMap<ImageIdentity, u64>,
+    hits: u64,
+    misses: u64,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: BlockKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (block, last_use) = self.blocks.get_mut(&key)?;
+        self.lru.remove(&*last_use);
+        *last_use = tick;
+        self.lru.insert(tick, key);
+
+        Some(block.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, block: Arc<Vec<u8>>, capacity: u64) {
+        if self.blocks.contains_key(&key) || block.len() as u64 > capacity {
+            return;
+        }
+        while self.bytes + block.len() as u64 > capacity {
+            let (tick, evicted) = match self.lru.iter().next() {
+                Some((&tick, &evicted)) => (tick, evicted),
+                None => break,
+            };
+            self.lru.remove(&tick);
+            if let Some((evicted, _)) = self.blocks.remove(&evicted) {
+                self.bytes -= evicted.len() as u64;
+            }
+        }
+
+        self.tick += 1;
+        self.bytes += block.len() as u64;
+        self.lru.insert(self.tick, key);
+        self.blocks.insert(key, (block, self.tick));
+    }
+}
+
+/// Blocks of the cached disk images, up to `capacity` bytes of them.
+pub struct BlockCache {
+    capacity: u64,
+    state: Mutex<CacheState>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: u64) -> Arc<Self> {
+        Arc::new(BlockCache {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        })
+    }
+
+    /// Number of the reads the cache served, and of the ones it didn't.
+    pub fn stats(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.hits, state.misses)
+    }
+
+    // The digest of the image, read in full unless the image was opened
+    // before. Two images opened at once may both be read.
+    fn digest<T: Read + Seek>(&self, identity: ImageIdentity, image: &mut T) -> io::Result<u64> {
+        if let Some(&digest) = self.state.lock().unwrap().digests.get(&identity) {
+            return Ok(digest);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        let mut chunk = vec![0u8; DIGEST_CHUNK_SIZE];
+        let mut size = 0u64;
+        image.seek(SeekFrom::Start(0))?;
+        loop {
+            let len = image.read(&mut chunk)?;
+            if len == 0 {
+                break;
+            }
+            hasher.write(&chunk[..len]);
+            size += len as u64;
+        }
+        hasher.write_u64(size);
+        let digest = hasher.finish();
+        debug!("Digest of the cached disk image: {:016x}", digest);
+
+        self.state.lock().unwrap().digests.insert(identity, digest);
+
+        Ok(digest)
+    }
+
+    fn get(&self, key: BlockKey) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        let block = state.touch(key);
+        if block.is_some() {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+
+        block
+    }
+
+    fn insert(&self, key: BlockKey, block: Arc<Vec<u8>>) {
+        self.state.lock().unwrap().insert(key, block, self.capacity);
+    }
+}
+
+/// A read-only disk image, read through the block cache. Writes fail, the
+/// device exposing the disk as read-only.
+pub struct CachedDisk<T> {
+    image: T,
+    cache: Arc<BlockCache>,
+    digest: u64,
+    size: u64,
+    position: u64,
+}
+
+impl<T: Read + Seek> CachedDisk<T> {
+    pub fn new(mut image: T, cache: Arc<BlockCache>, identity: ImageIdentity) -> io::Result<Self> {
+        let size = image.seek(SeekFrom::End(0))?;
+        let digest = cache.digest(identity, &mut image)?;
+
+        Ok(CachedDisk {
+            image,
+            cache,
+            digest,
+            size,
+            position: 0,
+        })
+    }
+
+    fn block(&mut self, index: u64) -> io::Result<Arc<Vec<u8>>> {
+        let key = (self.digest, index);
+        if let Some(block) = self.cache.get(key) {
+            return Ok(block);
+        }
+
+        let start = index * CACHE_BLOCK_SIZE;
+        let len = std::cmp::min(CACHE_BLOCK_SIZE, self.size - start);
+        let mut block = vec![0u8; len as usize];
+        self.image.seek(SeekFrom::Start(start))?;
+        self.image.read_exact(&mut block)?;
+        let block = Arc::new(block);
+        self.cache.insert(key, block.clone());
+
+        Ok(block)
+    }
+}
+
+impl<T: Read + Seek> Read for CachedDisk<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block = self.block(self.position / CACHE_BLOCK_SIZE)?;
+        let offset = (self.position % CACHE_BLOCK_SIZE) as usize;
+        let len = std::cmp::min(buf.len(), block.len() - offset);
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<T> Seek for CachedDisk<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => checked_offset(self.size, offset),
+            SeekFrom::Current(offset) => checked_offset(self.position, offset),
+        };
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        }
+    }
+}
+
+fn checked_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
+
+impl<T> Write for CachedDisk<T> {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::PermissionDenied))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T> DiskDiscard for CachedDisk<T> {
+    fn discard_range(&mut self, _offset: u64, _length: u64) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::PermissionDenied))
+    }
+
+    fn zero_range(&mut self, _offset: u64, _length: u64, _unmap: bool) -> io::Result<()> {
+        Err(io::Error::from(io::ErrorKind::PermissionDenied))
+    }
+}
+
+impl<T: Clone> Clone for CachedDisk<T> {
+    fn clone(&self) -> Self {
+        CachedDisk {
+            image: self.image.clone(),
+            cache: self.cache.clone(),
+            digest: self.digest,
+            size: self.size,
+            position: self.position,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn identity(ino: u64) -> ImageIdentity {
+        ImageIdentity {
+            dev: 1,
+            ino,
+            size: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+        }
+    }
+
+    fn image(len: usize) -> Cursor<Vec<u8>> {
+        Cursor::new((0..len).map(|i| (i % 251) as u8).collect())
+    }
+
+    #[test]
+    fn test_cached_disk_read() {
+        let len = 3 * CACHE_BLOCK_SIZE as usize + 100;
+        let cache = BlockCache::new(1 << 20);
+        let mut disk = CachedDisk::new(image(len), cache, identity(1)).unwrap();
+
+        // A read across blocks, up to the end of the image.
+        let start = CACHE_BLOCK_SIZE as usize - 10;
+        disk.seek(SeekFrom::Start(start as u64)).unwrap();
+        let mut buf = Vec::new();
+        disk.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, image(len).into_inner()[start..].to_vec());
+
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), len as u64);
+        assert!(disk.write(&[0]).is_err());
+    }
+
+    #[test]
+    fn test_identical_images_share_blocks() {
+        let len = 2 * CACHE_BLOCK_SIZE as usize;
+        let cache = BlockCache::new(1 << 20);
+        let mut first = CachedDisk::new(image(len), cache.clone(), identity(1)).unwrap();
+        let mut second = CachedDisk::new(image(len), cache.clone(), identity(2)).unwrap();
+        let mut other = image(len).into_inner();
+        other[0] = 0xff;
+        let mut third = CachedDisk::new(Cursor::new(other), cache.clone(), identity(3)).unwrap();
+        assert_eq!(first.digest, second.digest);
+        assert_ne!(first.digest, third.digest);
+
+        let mut buf = vec![0u8; 16];
+        first.read_exact(&mut buf).unwrap();
+        second.read_exact(&mut buf).unwrap();
+        assert_eq!(cache.stats(), (1, 1));
+        third.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[0], 0xff);
+        assert_eq!(cache.stats(), (1, 2));
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let len = 3 * CACHE_BLOCK_SIZE as usize;
+        let cache = BlockCache::new(2 * CACHE_BLOCK_SIZE);
+        let mut disk = CachedDisk::new(image(len), cache.clone(), identity(1)).unwrap();
+
+        let mut buf = vec![0u8; 1];
+        for &index in [0, 1, 0, 2].iter() {
+            disk.seek(SeekFrom::Start(index * CACHE_BLOCK_SIZE))
+                .unwrap();
+            disk.read_exact(&mut buf).unwrap();
+        }
+        // The block 1 was the least recently used one when the block 2 came
+        // in.
+        let state = cache.state.lock().unwrap();
+        assert_eq!(state.bytes, 2 * CACHE_BLOCK_SIZE);
+        assert!(state.blocks.contains_key(&(disk.digest, 0)));
+        assert!(!state.blocks.contains_key(&(disk.digest, 1)));
+        assert!(state.blocks.contains_key(&(disk.digest, 2)));
+    }
+}
@@ -11,6 +11,7 @@ use std::io::Write;
 use std::ops::DerefMut;
 use std::os::unix::io::AsRawFd;
 use std::result;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 
@@ -20,26 +21,51 @@ use super::{
     VirtioInterruptType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::VirtioInterrupt;
-use std::sync::atomic::{AtomicU64, Ordering};
+use vm_device::{Pausable, PauseControl, PauseWorker};
 use vm_memory::{ByteValued, Bytes, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
-const QUEUE_SIZE: u16 = 256;
-const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
-
-// New descriptors are pending on the virtio queue.
-const INPUT_QUEUE_EVENT: DeviceEventT = 0;
-const OUTPUT_QUEUE_EVENT: DeviceEventT = 1;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 0;
+// The device is paused.
+const PAUSE_EVENT: DeviceEventT = 1;
 // Some input from the VMM is ready to be injected into the VM.
 const INPUT_EVENT: DeviceEventT = 2;
-// The device has been dropped.
-const KILL_EVENT: DeviceEventT = 3;
 // Console configuration change event is triggered.
-const CONFIG_EVENT: DeviceEventT = 4;
+const CONFIG_EVENT: DeviceEventT = 3;
+// Some control messages from the VMM are ready to be sent to the driver.
+const CONTROL_EVENT: DeviceEventT = 4;
+// New descriptors are pending on a virtio queue, the event of the queue being
+// this plus its index.
+const QUEUE_EVENT_BASE: DeviceEventT = 5;
 
 //Console size feature bit
 const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+// Multiple ports feature bit
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
+
+// Queues of the control messages, from the device to the driver and from
+// the driver to the device, which come after the queues of the first port.
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
+// Control messages.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_RESIZE: u16 = 5;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// Receive queue of a port, its transmit queue being the next one.
+fn rx_queue(port: usize) -> usize {
+    if port == 0 {
+        0
+    } else {
+        2 + 2 * port
+    }
+}
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
@@ -53,17 +79,52 @@ pub struct VirtioConsoleConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl ByteValued for VirtioConsoleConfig {}
 
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioConsoleControl {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioConsoleControl {}
+
+// A control message, along with the data following it.
+fn control_message(id: usize, event: u16, value: u16, data: &[u8]) -> Vec<u8> {
+    let control = VirtioConsoleControl {
+        id: id as u32,
+        event,
+        value,
+    };
+    let mut message = control.as_slice().to_vec();
+    message.extend_from_slice(data);
+
+    message
+}
+
+/// Additional port of the console, a named channel to an agent of the guest
+/// rather than a terminal.
+pub struct ConsolePort {
+    pub name: String,
+    pub out: Box<dyn io::Write + Send + Sync + 'static>,
+}
+
+struct PortOutput {
+    name: Option<String>,
+    out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
+}
+
 struct ConsoleEpollHandler {
     queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
     mem: Arc<RwLock<GuestMemoryMmap>>,
     interrupt_cb: Arc<VirtioInterrupt>,
-    in_buffer: Arc<Mutex<VecDeque<u8>>>,
-    out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
-    input_queue_evt: EventFd,
-    output_queue_evt: EventFd,
-    input_evt: EventFd,
-    config_evt: EventFd,
+    input: Arc<ConsoleInput>,
+    ports: Vec<PortOutput>,
+    multiport: bool,
     kill_evt: EventFd,
+    pause: PauseWorker,
 }
 
 impl ConsoleEpollHandler {
@@ -73,47 +134,33 @@ impl ConsoleEpollHandler {
      * dirver in the receive queue for incoming data. Here,
      * we place the input data to these empty buffers.
      */
-    fn process_input_queue(&mut self) -> bool {
-        let mut in_buffer = self.in_buffer.lock().unwrap();
-        let count = in_buffer.len();
-        let recv_queue = &mut self.queues[0]; //receiveq
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
-        let mut used_count = 0;
-        let mut write_count = 0;
+    fn process_input_queue(&mut self, port: usize) -> bool {
+        let mut in_buffer = self.input.ports[port].in_buffer.lock().unwrap();
+        let recv_queue = &mut self.queues[rx_queue(port)]; //receiveq
+        let mut used_desc_heads = Vec::new();
 
         let mem = self.mem.read().unwrap();
+        if in_buffer.is_empty() {
+            return false;
+        }
         for avail_desc in recv_queue.iter(&mem) {
-            let len;
-
-            let limit = cmp::min(write_count + avail_desc.len as u32, count as u32);
-            let source_slice = in_buffer
-                .drain(write_count as usize..limit as usize)
-                .collect::<Vec<u8>>();
-            let write_result = mem.write_slice(&source_slice[..], avail_desc.addr);
-
-            match write_result {
-                Ok(_) => {
-                    len = limit - write_count; //avail_desc.len;
-                    write_count = limit;
-                }
-                Err(e) => {
-                    error!("Failed to write slice: {:?}", e);
-                    break;
-                }
+            let count = cmp::min(avail_desc.len as usize, in_buffer.len());
+            let source_slice = in_buffer.drain(..count).collect::<Vec<u8>>();
+            if let Err(e) = mem.write_slice(&source_slice[..], avail_desc.addr) {
+                error!("Failed to write slice: {:?}", e);
+                break;
             }
 
-            used_desc_heads[used_count] = (avail_desc.index, len);
-            used_count += 1;
-
-            if write_count >= count as u32 {
+            used_desc_heads.push((avail_desc.index, count as u32));
+            if in_buffer.is_empty() {
                 break;
             }
         }
 
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
+        for &(desc_index, len) in &used_desc_heads {
             recv_queue.add_used(&mem, desc_index, len);
         }
-        used_count > 0
+        !used_desc_heads.is_empty()
     }
 
     /*
@@ -123,15 +170,13 @@ impl ConsoleEpollHandler {
      * we read data from the transmit queue and flush them
      * to the referenced address.
      */
-    fn process_output_queue(&mut self) -> bool {
-        let trans_queue = &mut self.queues[1]; //transmitq
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
-        let mut used_count = 0;
+    fn process_output_queue(&mut self, port: usize) -> bool {
+        let trans_queue = &mut self.queues[rx_queue(port) + 1]; //transmitq
+        let mut used_desc_heads = Vec::new();
 
         let mem = self.mem.read().unwrap();
         for avail_desc in trans_queue.iter(&mem) {
-            let len;
-            let mut out = self.out.lock().unwrap();
+            let mut out = self.ports[port].out.lock().unwrap();
             let _ = mem.write_to(
                 avail_desc.addr,
                 &mut out.deref_mut(),
@@ -139,63 +184,218 @@ impl ConsoleEpollHandler {
             );
             let _ = out.flush();
 
-            len = avail_desc.len;
-            used_desc_heads[used_count] = (avail_desc.index, len);
-            used_count += 1;
+            used_desc_heads.push((avail_desc.index, avail_desc.len));
+        }
+
+        for &(desc_index, len) in &used_desc_heads {
+            trans_queue.add_used(&mem, desc_index, len);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    // Sends the pending control messages to the driver, as long as it gave
+    // buffers for them.
+    fn process_control_rx_queue(&mut self) -> bool {
+        let mut control = self.input.control.lock().unwrap();
+        let recv_queue = &mut self.queues[CONTROL_RX_QUEUE];
+        let mut used_desc_heads = Vec::new();
+
+        let mem = self.mem.read().unwrap();
+        if control.is_empty() {
+            return false;
+        }
+        for avail_desc in recv_queue.iter(&mem) {
+            let message = match control.pop_front() {
+                Some(message) => message,
+                None => break,
+            };
+            let len = cmp::min(avail_desc.len as usize, message.len());
+            if let Err(e) = mem.write_slice(&message[..len], avail_desc.addr) {
+                error!("Failed to write control message: {:?}", e);
+                break;
+            }
+
+            used_desc_heads.push((avail_desc.index, len as u32));
+            if control.is_empty() {
+                break;
+            }
+        }
+
+        for &(desc_index, len) in &used_desc_heads {
+            recv_queue.add_used(&mem, desc_index, len);
         }
+        !used_desc_heads.is_empty()
+    }
 
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
+    // Answers the control messages of the driver, which sets the ports up.
+    fn process_control_tx_queue(&mut self) -> bool {
+        let trans_queue = &mut self.queues[CONTROL_TX_QUEUE];
+        let mut used_desc_heads = Vec::new();
+        let mut messages = Vec::new();
+
+        let mem = self.mem.read().unwrap();
+        for avail_desc in trans_queue.iter(&mem) {
+            if avail_desc.len as usize >= std::mem::size_of::<VirtioConsoleControl>() {
+                match mem.read_obj::<VirtioConsoleControl>(avail_desc.addr) {
+                    Ok(control) => messages.push(control),
+                    Err(e) => error!("Failed to read control message: {:?}", e),
+                }
+            }
+            used_desc_heads.push((avail_desc.index, avail_desc.len));
+        }
+
+        for &(desc_index, len) in &used_desc_heads {
             trans_queue.add_used(&mem, desc_index, len);
         }
-        used_count > 0
+        drop(mem);
+
+        for control in messages {
+            self.handle_control_message(control);
+        }
+        !used_desc_heads.is_empty()
     }
 
-    fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
-        (self.interrupt_cb)(&VirtioInterruptType::Queue, Some(&self.queues[0])).map_err(|e| {
+    fn handle_control_message(&self, control: VirtioConsoleControl) {
+        let port = control.id as usize;
+        match control.event {
+            VIRTIO_CONSOLE_DEVICE_READY if control.value == 1 => {
+                for port in 0..self.ports.len() {
+                    self.input.send_control(control_message(
+                        port,
+                        VIRTIO_CONSOLE_DEVICE_ADD,
+                        1,
+                        &[],
+                    ));
+                }
+            }
+            VIRTIO_CONSOLE_DEVICE_READY => error!("The console driver failed to set up"),
+            VIRTIO_CONSOLE_PORT_READY if port < self.ports.len() && control.value == 1 => {
+                match &self.ports[port].name {
+                    Some(name) => self.input.send_control(control_message(
+                        port,
+                        VIRTIO_CONSOLE_PORT_NAME,
+                        1,
+                        name.as_bytes(),
+                    )),
+                    None => {
+                        self.input.send_control(control_message(
+                            port,
+                            VIRTIO_CONSOLE_CONSOLE_PORT,
+                            1,
+                            &[],
+                        ));
+                        // The terminal size is only sent once the port is a
+                        // console.
+                        self.input.send_resize();
+                    }
+                }
+                let connected = self.input.ports[port].connected.load(Ordering::SeqCst);
+                self.input.send_control(control_message(
+                    port,
+                    VIRTIO_CONSOLE_PORT_OPEN,
+                    connected as u16,
+                    &[],
+                ));
+            }
+            VIRTIO_CONSOLE_PORT_READY => {
+                error!("The console driver failed to set port {} up", port)
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                debug!("Guest opened console port {}: {}", port, control.value)
+            }
+            event => warn!("Unknown console control message {}", event),
+        }
+    }
+
+    fn signal_used_queue(&self, index: usize) -> result::Result<(), DeviceError> {
+        (self.interrupt_cb)(&VirtioInterruptType::Queue, Some(&self.queues[index])).map_err(|e| {
             error!("Failed to signal used queue: {:?}", e);
             DeviceError::FailedSignalingUsedQueue(e)
         })
     }
 
+    // Handles the notification of the queue, or the input or control
+    // messages pending for it.
+    fn process_queue(&mut self, index: usize) -> result::Result<(), DeviceError> {
+        let used = match index {
+            CONTROL_RX_QUEUE if self.multiport => self.process_control_rx_queue(),
+            CONTROL_TX_QUEUE if self.multiport => {
+                // The answers go out right away, if the driver gave buffers
+                // for them.
+                let used = self.process_control_tx_queue();
+                if self.process_control_rx_queue() {
+                    self.signal_used_queue(CONTROL_RX_QUEUE)?;
+                }
+                used
+            }
+            _ => {
+                let port = if index < 2 { 0 } else { index / 2 - 1 };
+                if index % 2 == 0 {
+                    self.process_input_queue(port)
+                } else {
+                    self.process_output_queue(port)
+                }
+            }
+        };
+
+        if used {
+            self.signal_used_queue(index)?;
+        }
+
+        Ok(())
+    }
+
     fn run(&mut self) -> result::Result<(), DeviceError> {
         // Create the epoll file descriptor
         let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
 
         // Add events
+        for (index, queue_evt) in self.queue_evts.iter().enumerate() {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                queue_evt.as_raw_fd(),
+                epoll::Event::new(
+                    epoll::Events::EPOLLIN,
+                    u64::from(QUEUE_EVENT_BASE) + index as u64,
+                ),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
         epoll::ctl(
             epoll_fd,
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.input_queue_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(INPUT_QUEUE_EVENT)),
+            self.input.input_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(INPUT_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
         epoll::ctl(
             epoll_fd,
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.output_queue_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(OUTPUT_QUEUE_EVENT)),
+            self.input.config_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CONFIG_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
         epoll::ctl(
             epoll_fd,
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.input_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(INPUT_EVENT)),
+            self.input.control_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CONTROL_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+
         epoll::ctl(
             epoll_fd,
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.config_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CONFIG_EVENT)),
+            self.kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
-
         epoll::ctl(
             epoll_fd,
             epoll::ControlOptions::EPOLL_CTL_ADD,
-            self.kill_evt.as_raw_fd(),
-            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
+            self.pause.evt().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
 
@@ -224,33 +424,22 @@ impl ConsoleEpollHandler {
                 let ev_type = event.data as u16;
 
                 match ev_type {
-                    INPUT_QUEUE_EVENT => {
-                        if let Err(e) = self.input_queue_evt.read() {
-                            error!("Failed to get queue event: {:?}", e);
-                            break 'epoll;
-                        }
-                    }
-                    OUTPUT_QUEUE_EVENT => {
-                        if let Err(e) = self.output_queue_evt.read() {
-                            error!("Failed to get queue event: {:?}", e);
-                            break 'epoll;
-                        } else {
-                            self.process_output_queue();
-                        }
-                    }
                     INPUT_EVENT => {
-                        if let Err(e) = self.input_evt.read() {
+                        if let Err(e) = self.input.input_evt.read() {
                             error!("Failed to get input event: {:?}", e);
                             break 'epoll;
-                        } else if self.process_input_queue() {
-                            if let Err(e) = self.signal_used_queue() {
-                                error!("Failed to signal used queue: {:?}", e);
-                                break 'epoll;
+                        }
+                        for port in 0..self.ports.len() {
+                            if self.process_input_queue(port) {
+                                if let Err(e) = self.signal_used_queue(rx_queue(port)) {
+                                    error!("Failed to signal used queue: {:?}", e);
+                                    break 'epoll;
+                                }
                             }
                         }
                     }
                     CONFIG_EVENT => {
-                        if let Err(e) = self.config_evt.read() {
+                        if let Err(e) = self.input.config_evt.read() {
                             error!("Failed to get config event: {:?}", e);
                             break 'epoll;
                         } else if let Err(e) =
@@ -259,11 +448,41 @@ impl ConsoleEpollHandler {
                             error!("Failed to signal console driver: {:?}", e);
                         }
                     }
+                    CONTROL_EVENT => {
+                        if let Err(e) = self.input.control_evt.read() {
+                            error!("Failed to get control event: {:?}", e);
+                            break 'epoll;
+                        }
+                        if !self.multiport {
+                            continue;
+                        }
+                        if let Err(e) = self.process_queue(CONTROL_RX_QUEUE) {
+                            error!("Failed to send control messages: {:?}", e);
+                            break 'epoll;
+                        }
+                    }
 
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
                         break 'epoll;
                     }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing epoll loop");
+                        self.pause.wait_resumed();
+                    }
+                    _ if ev_type >= QUEUE_EVENT_BASE
+                        && ((ev_type - QUEUE_EVENT_BASE) as usize) < self.queues.len() =>
+                    {
+                        let index = (ev_type - QUEUE_EVENT_BASE) as usize;
+                        if let Err(e) = self.queue_evts[index].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        }
+                        if let Err(e) = self.process_queue(index) {
+                            error!("Failed to process queue {}: {:?}", index, e);
+                            break 'epoll;
+                        }
+                    }
                     _ => {
                         error!("Unknown event for virtio-console");
                     }
@@ -275,41 +494,92 @@ impl ConsoleEpollHandler {
     }
 }
 
+struct PortInput {
+    in_buffer: Mutex<VecDeque<u8>>,
+    // A client is connected to the host side of the port.
+    connected: AtomicBool,
+}
+
 /// Input device.
 pub struct ConsoleInput {
     input_evt: EventFd,
     config_evt: EventFd,
-    in_buffer: Arc<Mutex<VecDeque<u8>>>,
+    control_evt: EventFd,
+    ports: Vec<PortInput>,
+    // Control messages pending for the driver.
+    control: Mutex<VecDeque<Vec<u8>>>,
     config: Arc<Mutex<VirtioConsoleConfig>>,
     acked_features: AtomicU64,
 }
 
 impl ConsoleInput {
+    /// Queues input for the console, the first port.
     pub fn queue_input_bytes(&self, input: &[u8]) {
-        let mut in_buffer = self.in_buffer.lock().unwrap();
-        in_buffer.extend(input);
-        let _ = self.input_evt.write(1);
+        self.queue_port_input_bytes(0, input)
+    }
+
+    /// Queues input for a port, the additional ones starting from 1.
+    pub fn queue_port_input_bytes(&self, port: usize, input: &[u8]) {
+        if let Some(port) = self.ports.get(port) {
+            port.in_buffer.lock().unwrap().extend(input);
+            let _ = self.input_evt.write(1);
+        }
+    }
+
+    /// Tells the driver whether a client is connected to the host side of
+    /// the port, the driver reporting it to the guest applications.
+    pub fn set_port_connected(&self, port: usize, connected: bool) {
+        if let Some(input) = self.ports.get(port) {
+            if input.connected.swap(connected, Ordering::SeqCst) != connected && self.multiport() {
+                self.send_control(control_message(
+                    port,
+                    VIRTIO_CONSOLE_PORT_OPEN,
+                    connected as u16,
+                    &[],
+                ));
+            }
+        }
     }
 
     pub fn update_console_size(&self, cols: u16, rows: u16) {
-        if self
-            .acked_features
-            .fetch_and(1u64 << VIRTIO_CONSOLE_F_SIZE, Ordering::SeqCst)
-            != 0
+        self.config.lock().unwrap().update_console_size(cols, rows);
+        // With multiple ports, the size of a console port is sent along
+        // with the port, through the control queue.
+        if self.multiport() {
+            self.send_resize();
+        } else if self.acked_features.load(Ordering::SeqCst) & (1u64 << VIRTIO_CONSOLE_F_SIZE) != 0
         {
-            self.config.lock().unwrap().update_console_size(cols, rows);
             //Send the interrupt to the driver
             let _ = self.config_evt.write(1);
         }
     }
+
+    fn multiport(&self) -> bool {
+        self.acked_features.load(Ordering::SeqCst) & (1u64 << VIRTIO_CONSOLE_F_MULTIPORT) != 0
+    }
+
+    fn send_control(&self, message: Vec<u8>) {
+        self.control.lock().unwrap().push_back(message);
+        let _ = self.control_evt.write(1);
+    }
+
+    // Sends the size of the console to the driver, rows first, once known.
+    fn send_resize(&self) {
+        let config = *self.config.lock().unwrap();
+        if config.cols != 0 && config.rows != 0 {
+            let mut size = config.rows.to_le_bytes().to_vec();
+            size.extend_from_slice(&config.cols.to_le_bytes());
+            self.send_control(control_message(0, VIRTIO_CONSOLE_RESIZE, 0, &size));
+        }
+    }
 }
 
 impl VirtioConsoleConfig {
-    pub fn new(cols: u16, rows: u16) -> Self {
+    pub fn new(cols: u16, rows: u16, max_nr_ports: u32) -> Self {
         VirtioConsoleConfig {
             cols,
             rows,
-            max_nr_ports: 1u32,
+            max_nr_ports,
             emerg_wr: 0u32,
         }
     }
@@ -327,18 +597,24 @@ pub struct Console {
     acked_features: u64,
     config: Arc<Mutex<VirtioConsoleConfig>>,
     input: Arc<ConsoleInput>,
-    out: Arc<Mutex<Box<dyn io::Write + Send + Sync + 'static>>>,
+    ports: Vec<PortOutput>,
+    queue_sizes: Vec<u16>,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    pause: PauseControl,
 }
 
 impl Console {
-    /// Create a new virtio console device that gets random data from /dev/urandom.
+    /// Create a new virtio console device, whose console writes to `out`.
+    /// The additional `ports` make it a multiport device, which the driver
+    /// has to support.
     pub fn new(
         out: Box<dyn io::Write + Send + Sync + 'static>,
+        ports: Vec<ConsolePort>,
         cols: u16,
         rows: u16,
         iommu: bool,
+        queue_size: u16,
     ) -> io::Result<(Console, Arc<ConsoleInput>)> {
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_CONSOLE_F_SIZE;
 
@@ -346,13 +622,42 @@ impl Console {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
-        let input_evt = EventFd::new(EFD_NONBLOCK).unwrap();
-        let config_evt = EventFd::new(EFD_NONBLOCK).unwrap();
-        let console_config = Arc::new(Mutex::new(VirtioConsoleConfig::new(cols, rows)));
+        // The queues of the ports, and of the control messages with several
+        // of them.
+        let num_queues = if ports.is_empty() {
+            2
+        } else {
+            avail_features |= 1u64 << VIRTIO_CONSOLE_F_MULTIPORT;
+            4 + 2 * ports.len()
+        };
+
+        let mut port_outputs = vec![PortOutput {
+            name: None,
+            out: Arc::new(Mutex::new(out)),
+        }];
+        port_outputs.extend(ports.into_iter().map(|port| PortOutput {
+            name: Some(port.name),
+            out: Arc::new(Mutex::new(port.out)),
+        }));
+
+        let console_config = Arc::new(Mutex::new(VirtioConsoleConfig::new(
+            cols,
+            rows,
+            port_outputs.len() as u32,
+        )));
         let console_input = Arc::new(ConsoleInput {
-            input_evt,
-            config_evt,
-            in_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            input_evt: EventFd::new(EFD_NONBLOCK)?,
+            config_evt: EventFd::new(EFD_NONBLOCK)?,
+            control_evt: EventFd::new(EFD_NONBLOCK)?,
+            ports: port_outputs
+                .iter()
+                .map(|port| PortInput {
+                    in_buffer: Mutex::new(VecDeque::new()),
+                    // The console is always there for the guest.
+                    connected: AtomicBool::new(port.name.is_none()),
+                })
+                .collect(),
+            control: Mutex::new(VecDeque::new()),
             config: console_config.clone(),
             acked_features: AtomicU64::new(0),
         });
@@ -364,9 +669,11 @@ impl Console {
                 acked_features: 0u64,
                 config: console_config,
                 input: console_input.clone(),
-                out: Arc::new(Mutex::new(out)),
+                ports: port_outputs,
+                queue_sizes: vec![queue_size; num_queues],
                 queue_evts: None,
                 interrupt_cb: None,
+                pause: PauseControl::new()?,
             },
             console_input,
         ))
@@ -382,13 +689,23 @@ impl Drop for Console {
     }
 }
 
+impl Pausable for Console {
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
 impl VirtioDevice for Console {
     fn device_type(&self) -> u32 {
         VirtioDeviceType::TYPE_CONSOLE as u32
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn features(&self, page: u32) -> u32 {
@@ -450,12 +767,12 @@ impl VirtioDevice for Console {
         mem: Arc<RwLock<GuestMemoryMmap>>,
         interrupt_cb: Arc<VirtioInterrupt>,
         queues: Vec<Queue>,
-        mut queue_evts: Vec<EventFd>,
+        queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        if queues.len() != self.queue_sizes.len() || queue_evts.len() != self.queue_sizes.len() {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                self.queue_sizes.len(),
                 queues.len()
             );
             return Err(ActivateError::BadActivate);
@@ -500,13 +817,19 @@ impl VirtioDevice for Console {
             queues,
             mem,
             interrupt_cb,
-            in_buffer: self.input.in_buffer.clone(),
-            out: self.out.clone(),
-            input_queue_evt: queue_evts.remove(0),
-            output_queue_evt: queue_evts.remove(0),
-            input_evt: self.input.input_evt.try_clone().unwrap(),
-            config_evt: self.input.config_evt.try_clone().unwrap(),
+            queue_evts,
+            input: self.input.clone(),
+            ports: self
+                .ports
+                .iter()
+                .map(|port| PortOutput {
+                    name: port.name.clone(),
+                    out: port.out.clone(),
+                })
+                .collect(),
+            multiport: (self.acked_features & (1u64 << VIRTIO_CONSOLE_F_MULTIPORT)) != 0,
             kill_evt,
+            pause: self.pause.worker(),
         };
 
         let worker_result = thread::Builder::new()
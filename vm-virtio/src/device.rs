@@ -8,6 +8,7 @@
 
 use super::*;
 use std::sync::{Arc, RwLock};
+use vm_device::{MemoryListener, Pausable};
 use vm_memory::{GuestAddress, GuestMemoryMmap, GuestUsize};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -44,8 +45,9 @@ pub struct VirtioSharedMemoryList {
 /// device. Once the guest driver has configured the device, `VirtioDevice::activate` will be called
 /// and all the events, memory, and queues for device operation will be moved into the device.
 /// Optionally, a virtio device can implement device reset in which it returns said resources and
-/// resets its internal.
-pub trait VirtioDevice: Send {
+/// resets its internal. The worker threads of an activated device are paused along with the
+/// vCPUs of a paused VM, through `Pausable`.
+pub trait VirtioDevice: Pausable + Send {
     /// The virtio device type.
     fn device_type(&self) -> u32;
 
@@ -90,6 +92,15 @@ pub trait VirtioDevice: Send {
     fn iommu_translate(&self, addr: u64) -> u64 {
         addr
     }
+
+    /// Returns the listener to notify of the guest RAM changes, for the
+    /// devices keeping their own mappings of the guest memory.
+    fn memory_listener(
+        &self,
+        _mem: Arc<RwLock<GuestMemoryMmap>>,
+    ) -> Option<Arc<dyn MemoryListener>> {
+        None
+    }
 }
 
 /// Trait providing address translation the same way a physical DMA remapping
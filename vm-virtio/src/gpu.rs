@@ -0,0 +1,1204 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! virtio-gpu device, 2D only. The guest draws into the resources it
+//! creates, backed by its own memory, has them copied to the host, and
+//! flushes the one it sets on the single scanout to the display. The display
+//! holds the image of the scanout as of its last flush, which the VMM reads.
+
+use epoll;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
+    VirtioDeviceType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use vm_device::{Pausable, PauseControl, PauseWorker};
+use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+// New descriptors are pending on the control queue.
+const CONTROL_QUEUE_EVENT: DeviceEventT = 0;
+// New descriptors are pending on the cursor queue.
+const CURSOR_QUEUE_EVENT: DeviceEventT = 1;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 2;
+// The device is paused.
+const PAUSE_EVENT: DeviceEventT = 3;
+
+// Commands and responses, from linux/virtio_gpu.h.
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
+const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
+const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+const VIRTIO_GPU_RESP_ERR_UNSPEC: u32 = 0x1200;
+const VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY: u32 = 0x1201;
+const VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID: u32 = 0x1202;
+const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
+const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
+
+// The response of a fenced command is fenced as well.
+const VIRTIO_GPU_FLAG_FENCE: u32 = 1;
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+
+// Pixel formats, 4 bytes per pixel, named after the order of their bytes
+// in memory.
+const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+const VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM: u32 = 3;
+const VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM: u32 = 4;
+const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 67;
+const VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM: u32 = 68;
+const VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM: u32 = 121;
+const VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM: u32 = 134;
+const BYTES_PER_PIXEL: u32 = 4;
+
+// Host memory the images of the resources of the guest can take.
+const MAX_HOSTMEM: u64 = 256 << 20;
+// Guest memory regions backing a resource, at most.
+const MAX_BACKING_ENTRIES: u32 = 16384;
+// Largest request read from the guest, a resource backing with the most
+// entries.
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuCtrlHdr {
+    type_: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    padding: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuCtrlHdr {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuRect {}
+
+impl VirtioGpuRect {
+    // Whether the rectangle is within an image of this size.
+    fn within(&self, width: u32, height: u32) -> bool {
+        let fits = |start: u32, size: u32, limit: u32| {
+            start.checked_add(size).map_or(false, |end| end <= limit)
+        };
+        fits(self.x, self.width, width) && fits(self.y, self.height, height)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuDisplayOne {
+    r: VirtioGpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuRespDisplayInfo {
+    hdr: VirtioGpuCtrlHdr,
+    pmodes: [VirtioGpuDisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuRespDisplayInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuResourceCreate2d {
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuResourceCreate2d {}
+
+// Body of the commands on a resource without parameters: the unref and the
+// backing detach ones.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuResource {
+    resource_id: u32,
+    padding: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuResource {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuSetScanout {
+    r: VirtioGpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuSetScanout {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuResourceFlush {
+    r: VirtioGpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuResourceFlush {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuTransferToHost2d {
+    r: VirtioGpuRect,
+    offset: u64,
+    resource_id: u32,
+    padding: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuTransferToHost2d {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuResourceAttachBacking {
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuResourceAttachBacking {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuMemEntry {
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuMemEntry {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioGpuConfig {
+    events_read: u32,
+    events_clear: u32,
+    num_scanouts: u32,
+    num_capsets: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioGpuConfig {}
+
+// Reads a structure of the request at the offset, if the request is long
+// enough. The request buffer isn't aligned for the structures.
+fn read_struct<T: ByteValued + Default>(request: &[u8], offset: usize) -> Option<T> {
+    let mut value = T::default();
+    let size = size_of::<T>();
+    value
+        .as_mut_slice()
+        .copy_from_slice(request.get(offset..offset.checked_add(size)?)?);
+    Some(value)
+}
+
+// Offsets of the red, green and blue bytes of a pixel of the format.
+fn rgb_offsets(format: u32) -> Option<[usize; 3]> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => Some([2, 1, 0]),
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => Some([1, 2, 3]),
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => Some([0, 1, 2]),
+        VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM => Some([3, 2, 1]),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    /// Guest gave us bad memory addresses.
+    GuestMemory(GuestMemoryError),
+    /// The request is longer than MAX_REQUEST_SIZE.
+    RequestTooLarge,
+    /// The request has no writable descriptor for the response.
+    NoResponseDescriptor,
+    /// The backing of a resource is smaller than the transfer.
+    BackingTooSmall,
+}
+
+/// Image of the display, as the guest last flushed it.
+#[derive(Clone, Debug)]
+pub struct GpuFramebuffer {
+    pub width: u32,
+    pub height: u32,
+    /// The pixels, row after row, as red, green and blue bytes.
+    pub rgb: Vec<u8>,
+}
+
+impl GpuFramebuffer {
+    /// Writes the image as a binary PPM.
+    pub fn write_ppm(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        writer.write_all(&self.rgb)
+    }
+}
+
+/// The display of a virtio-gpu device, shared with the VMM.
+#[derive(Clone, Default)]
+pub struct GpuDisplay {
+    framebuffer: Arc<Mutex<Option<GpuFramebuffer>>>,
+}
+
+impl GpuDisplay {
+    /// The image of the display, unless the guest didn't set it up, or
+    /// disabled it.
+    pub fn framebuffer(&self) -> Option<GpuFramebuffer> {
+        self.framebuffer.lock().unwrap().clone()
+    }
+
+    fn set_framebuffer(&self, framebuffer: Option<GpuFramebuffer>) {
+        *self.framebuffer.lock().unwrap() = framebuffer;
+    }
+}
+
+struct Resource {
+    width: u32,
+    height: u32,
+    format: u32,
+    image: Vec<u8>,
+    // Guest memory regions the resource is copied from, as a contiguous
+    // buffer.
+    backing: Vec<(GuestAddress, u32)>,
+}
+
+impl Resource {
+    // Reads the bytes at the offset of the backing into the buffer.
+    fn read_backing(
+        &self,
+        mem: &GuestMemoryMmap,
+        mut offset: u64,
+        mut buf: &mut [u8],
+    ) -> result::Result<(), Error> {
+        for &(addr, length) in self.backing.iter() {
+            if buf.is_empty() {
+                break;
+            }
+            let length = u64::from(length);
+            if offset >= length {
+                offset -= length;
+                continue;
+            }
+
+            let count = cmp::min(length - offset, buf.len() as u64) as usize;
+            let addr = addr.checked_add(offset).ok_or(Error::BackingTooSmall)?;
+            mem.read_slice(&mut buf[..count], addr)
+                .map_err(Error::GuestMemory)?;
+            buf = &mut buf[count..];
+            offset = 0;
+        }
+
+        if buf.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::BackingTooSmall)
+        }
+    }
+}
+
+// The resource on the scanout, and the rectangle of it displayed.
+struct Scanout {
+    resource_id: u32,
+    r: VirtioGpuRect,
+}
+
+// The resources of the guest, and the scanout.
+struct GpuState {
+    width: u32,
+    height: u32,
+    resources: BTreeMap<u32, Resource>,
+    hostmem: u64,
+    scanout: Option<Scanout>,
+    display: GpuDisplay,
+}
+
+impl GpuState {
+    fn new(width: u32, height: u32, display: GpuDisplay) -> Self {
+        display.set_framebuffer(None);
+        GpuState {
+            width,
+            height,
+            resources: BTreeMap::new(),
+            hostmem: 0,
+            scanout: None,
+            display,
+        }
+    }
+
+    // Runs a command, returning its response.
+    fn command(&mut self, mem: &GuestMemoryMmap, request: &[u8]) -> Vec<u8> {
+        let hdr: VirtioGpuCtrlHdr = match read_struct(request, 0) {
+            Some(hdr) => hdr,
+            None => return Vec::new(),
+        };
+        let body = size_of::<VirtioGpuCtrlHdr>();
+
+        let result = match hdr.type_ {
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO => {
+                let mut info = VirtioGpuRespDisplayInfo::default();
+                info.hdr = response_hdr(&hdr, VIRTIO_GPU_RESP_OK_DISPLAY_INFO);
+                info.pmodes[0] = VirtioGpuDisplayOne {
+                    r: VirtioGpuRect {
+                        x: 0,
+                        y: 0,
+                        width: self.width,
+                        height: self.height,
+                    },
+                    enabled: 1,
+                    flags: 0,
+                };
+                return info.as_slice().to_vec();
+            }
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => {
+                read_struct(request, body).map(|create| self.resource_create_2d(create))
+            }
+            VIRTIO_GPU_CMD_RESOURCE_UNREF => read_struct(request, body)
+                .map(|resource: VirtioGpuResource| self.resource_unref(resource.resource_id)),
+            VIRTIO_GPU_CMD_SET_SCANOUT => {
+                read_struct(request, body).map(|set_scanout| self.set_scanout(set_scanout))
+            }
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH => read_struct(request, body)
+                .map(|flush: VirtioGpuResourceFlush| self.resource_flush(flush.resource_id)),
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => {
+                read_struct(request, body).map(|transfer| self.transfer_to_host_2d(mem, transfer))
+            }
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => read_struct(request, body).map(|attach| {
+                let entries = body + size_of::<VirtioGpuResourceAttachBacking>();
+                self.resource_attach_backing(attach, &request[entries..])
+            }),
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING => {
+                read_struct(request, body).map(|resource: VirtioGpuResource| {
+                    self.resource_detach_backing(resource.resource_id)
+                })
+            }
+            _ => {
+                debug!("Unsupported virtio-gpu command 0x{:x}", hdr.type_);
+                Some(Err(VIRTIO_GPU_RESP_ERR_UNSPEC))
+            }
+        };
+
+        let type_ = match result {
+            Some(Ok(())) => VIRTIO_GPU_RESP_OK_NODATA,
+            Some(Err(e)) => e,
+            None => VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER,
+        };
+        response_hdr(&hdr, type_).as_slice().to_vec()
+    }
+
+    fn resource_create_2d(&mut self, create: VirtioGpuResourceCreate2d) -> result::Result<(), u32> {
+        if create.resource_id == 0 || self.resources.contains_key(&create.resource_id) {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID);
+        }
+        if rgb_offsets(create.format).is_none() || create.width == 0 || create.height == 0 {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+        let size = u64::from(create.width) * u64::from(create.height) * u64::from(BYTES_PER_PIXEL);
+        if self.hostmem + size > MAX_HOSTMEM {
+            return Err(VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY);
+        }
+
+        self.hostmem += size;
+        self.resources.insert(
+            create.resource_id,
+            Resource {
+                width: create.width,
+                height: create.height,
+                format: create.format,
+                image: vec![0; size as usize],
+                backing: Vec::new(),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn resource_unref(&mut self, resource_id: u32) -> result::Result<(), u32> {
+        let resource = self
+            .resources
+            .remove(&resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        self.hostmem -= resource.image.len() as u64;
+        if self.scanout.as_ref().map(|scanout| scanout.resource_id) == Some(resource_id) {
+            self.scanout = None;
+            self.display.set_framebuffer(None);
+        }
+
+        Ok(())
+    }
+
+    fn set_scanout(&mut self, set_scanout: VirtioGpuSetScanout) -> result::Result<(), u32> {
+        if set_scanout.scanout_id != 0 {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID);
+        }
+        // The resource 0 disables the scanout.
+        if set_scanout.resource_id == 0 {
+            self.scanout = None;
+            self.display.set_framebuffer(None);
+            return Ok(());
+        }
+
+        let resource = self
+            .resources
+            .get(&set_scanout.resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        let r = set_scanout.r;
+        if r.width == 0 || r.height == 0 || !r.within(resource.width, resource.height) {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+        self.scanout = Some(Scanout {
+            resource_id: set_scanout.resource_id,
+            r,
+        });
+
+        Ok(())
+    }
+
+    // The display is updated with the whole scanout rectangle, whichever
+    // part of the resource is flushed.
+    fn resource_flush(&mut self, resource_id: u32) -> result::Result<(), u32> {
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        let r = match &self.scanout {
+            Some(scanout) if scanout.resource_id == resource_id => scanout.r,
+            _ => return Ok(()),
+        };
+
+        let offsets = rgb_offsets(resource.format).unwrap();
+        let mut rgb = Vec::with_capacity(r.width as usize * r.height as usize * 3);
+        for y in r.y..r.y + r.height {
+            let start = ((y * resource.width + r.x) * BYTES_PER_PIXEL) as usize;
+            let row = &resource.image[start..start + (r.width * BYTES_PER_PIXEL) as usize];
+            for pixel in row.chunks(BYTES_PER_PIXEL as usize) {
+                rgb.extend(offsets.iter().map(|&offset| pixel[offset]));
+            }
+        }
+        self.display.set_framebuffer(Some(GpuFramebuffer {
+            width: r.width,
+            height: r.height,
+            rgb,
+        }));
+
+        Ok(())
+    }
+
+    // The rows of the rectangle are copied from the backing, the offset
+    // being the one of the first pixel of the rectangle, and the backing
+    // having the stride of the resource.
+    fn transfer_to_host_2d(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        transfer: VirtioGpuTransferToHost2d,
+    ) -> result::Result<(), u32> {
+        let resource = self
+            .resources
+            .get_mut(&transfer.resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        let r = transfer.r;
+        if resource.backing.is_empty() || !r.within(resource.width, resource.height) {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+
+        let stride = resource.width * BYTES_PER_PIXEL;
+        let len = (r.width * BYTES_PER_PIXEL) as usize;
+        let mut row = vec![0u8; len];
+        for h in 0..r.height {
+            let src = transfer
+                .offset
+                .checked_add(u64::from(stride) * u64::from(h))
+                .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)?;
+            if let Err(e) = resource.read_backing(mem, src, &mut row) {
+                error!("Cannot read the virtio-gpu resource backing: {:?}", e);
+                return Err(VIRTIO_GPU_RESP_ERR_UNSPEC);
+            }
+            let dst = ((r.y + h) * stride + r.x * BYTES_PER_PIXEL) as usize;
+            resource.image[dst..dst + len].copy_from_slice(&row);
+        }
+
+        Ok(())
+    }
+
+    fn resource_attach_backing(
+        &mut self,
+        attach: VirtioGpuResourceAttachBacking,
+        entries: &[u8],
+    ) -> result::Result<(), u32> {
+        let resource = self
+            .resources
+            .get_mut(&attach.resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        if attach.nr_entries == 0 || attach.nr_entries > MAX_BACKING_ENTRIES {
+            return Err(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER);
+        }
+
+        let mut backing = Vec::with_capacity(attach.nr_entries as usize);
+        for i in 0..attach.nr_entries as usize {
+            let entry: VirtioGpuMemEntry = read_struct(entries, i * size_of::<VirtioGpuMemEntry>())
+                .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER)?;
+            backing.push((GuestAddress(entry.addr), entry.length));
+        }
+        resource.backing = backing;
+
+        Ok(())
+    }
+
+    fn resource_detach_backing(&mut self, resource_id: u32) -> result::Result<(), u32> {
+        let resource = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID)?;
+        resource.backing.clear();
+
+        Ok(())
+    }
+}
+
+// The header of the response to a command, fenced if the command is.
+fn response_hdr(hdr: &VirtioGpuCtrlHdr, type_: u32) -> VirtioGpuCtrlHdr {
+    let mut response = VirtioGpuCtrlHdr {
+        type_,
+        ..Default::default()
+    };
+    if hdr.flags & VIRTIO_GPU_FLAG_FENCE != 0 {
+        response.flags = VIRTIO_GPU_FLAG_FENCE;
+        response.fence_id = hdr.fence_id;
+        response.ctx_id = hdr.ctx_id;
+    }
+
+    response
+}
+
+// The readable descriptors of a chain, as a single request, and the first
+// writable one, the response going there.
+fn read_request(
+    head: &DescriptorChain,
+    mem: &GuestMemoryMmap,
+) -> result::Result<(Vec<u8>, Option<(GuestAddress, u32)>), Error> {
+    let mut request = Vec::new();
+    let mut response = None;
+    let mut read_desc = |d: &DescriptorChain| -> result::Result<(), Error> {
+        if d.is_write_only() {
+            if response.is_none() {
+                response = Some((d.addr, d.len));
+            }
+        } else {
+            let start = request.len();
+            if start + d.len as usize > MAX_REQUEST_SIZE {
+                return Err(Error::RequestTooLarge);
+            }
+            request.resize(start + d.len as usize, 0);
+            mem.read_slice(&mut request[start..], d.addr)
+                .map_err(Error::GuestMemory)?;
+        }
+        Ok(())
+    };
+
+    read_desc(head)?;
+    let mut desc = head.next_descriptor();
+    while let Some(d) = desc {
+        read_desc(&d)?;
+        desc = d.next_descriptor();
+    }
+
+    Ok((request, response))
+}
+
+// Runs the command of a chain, returning the length of its response.
+fn process_command(
+    head: &DescriptorChain,
+    mem: &GuestMemoryMmap,
+    state: &mut GpuState,
+) -> result::Result<u32, Error> {
+    let (request, response) = read_request(head, mem)?;
+    let (addr, size) = response.ok_or(Error::NoResponseDescriptor)?;
+    let reply = state.command(mem, &request);
+    let len = cmp::min(reply.len(), size as usize);
+    mem.write_slice(&reply[..len], addr)
+        .map_err(Error::GuestMemory)?;
+
+    Ok(len as u32)
+}
+
+struct GpuEpollHandler {
+    queues: Vec<Queue>,
+    mem: Arc<RwLock<GuestMemoryMmap>>,
+    state: GpuState,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    kill_evt: EventFd,
+    pause: PauseWorker,
+}
+
+impl GpuEpollHandler {
+    fn control_queue(&mut self) -> bool {
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.read().unwrap();
+        for avail_desc in self.queues[0].iter(&mem) {
+            let len = match process_command(&avail_desc, &mem, &mut self.state) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("Failed to process virtio-gpu command: {:?}", e);
+                    0
+                }
+            };
+
+            used_desc_heads[used_count] = (avail_desc.index, len);
+            used_count += 1;
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            self.queues[0].add_used(&mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    // There is no cursor on the display, the cursor commands are only
+    // consumed.
+    fn cursor_queue(&mut self) -> bool {
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.read().unwrap();
+        for avail_desc in self.queues[1].iter(&mem) {
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            self.queues[1].add_used(&mem, desc_index, 0);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self, queue: &Queue) -> result::Result<(), DeviceError> {
+        (self.interrupt_cb)(&VirtioInterruptType::Queue, Some(queue)).map_err(|e| {
+            error!("Failed to signal used queue: {:?}", e);
+            DeviceError::FailedSignalingUsedQueue(e)
+        })
+    }
+
+    fn run(&mut self) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.queue_evts[0].as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CONTROL_QUEUE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.queue_evts[1].as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CURSOR_QUEUE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.kill_evt.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause.evt().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                match ev_type {
+                    CONTROL_QUEUE_EVENT => {
+                        if let Err(e) = self.queue_evts[0].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if self.control_queue() {
+                            if let Err(e) = self.signal_used_queue(&self.queues[0]) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    CURSOR_QUEUE_EVENT => {
+                        if let Err(e) = self.queue_evts[1].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if self.cursor_queue() {
+                            if let Err(e) = self.signal_used_queue(&self.queues[1]) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing epoll loop");
+                        self.pause.wait_resumed();
+                    }
+                    _ => {
+                        error!("Unknown event for virtio-gpu");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio device for displaying the 2D graphics of the guest.
+pub struct Gpu {
+    kill_evt: Option<EventFd>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioGpuConfig,
+    width: u32,
+    height: u32,
+    display: GpuDisplay,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    pause: PauseControl,
+}
+
+impl Gpu {
+    /// Create a new virtio-gpu device with a single display of the given
+    /// size, along with the display.
+    pub fn new(width: u32, height: u32, iommu: bool) -> io::Result<(Gpu, GpuDisplay)> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        let display = GpuDisplay::default();
+        Ok((
+            Gpu {
+                kill_evt: None,
+                avail_features,
+                acked_features: 0u64,
+                config: VirtioGpuConfig {
+                    num_scanouts: 1,
+                    ..Default::default()
+                },
+                width,
+                height,
+                display: display.clone(),
+                queue_evts: None,
+                interrupt_cb: None,
+                pause: PauseControl::new()?,
+            },
+            display,
+        ))
+    }
+}
+
+impl Drop for Gpu {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl Pausable for Gpu {
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
+impl VirtioDevice for Gpu {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_GPU as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            // Get the lower 32-bits of the features bitfield.
+            0 => self.avail_features as u32,
+            // Get the upper 32-bits of the features bitfield.
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!("Received request for unknown features page.");
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => {
+                warn!("Cannot acknowledge unknown features page.");
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    // Only events_clear is writable, and the device raises no event.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if offset != 4 || data.len() != 4 {
+            warn!("virtio-gpu device configuration is read-only");
+        }
+    }
+
+    fn activate(
+        &mut self,
+        mem: Arc<RwLock<GuestMemoryMmap>>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) =
+            match EventFd::new(EFD_NONBLOCK).and_then(|e| Ok((e.try_clone()?, e))) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed creating kill EventFd pair: {}", e);
+                    return Err(ActivateError::BadActivate);
+                }
+            };
+        self.kill_evt = Some(self_kill_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // The resources of the guest are gone with the previous activation.
+        let mut handler = GpuEpollHandler {
+            queues,
+            mem,
+            state: GpuState::new(self.width, self.height, self.display.clone()),
+            interrupt_cb,
+            queue_evts,
+            kill_evt,
+            pause: self.pause.worker(),
+        };
+
+        let worker_result = thread::Builder::new()
+            .name("virtio_gpu".to_string())
+            .spawn(move || handler.run());
+
+        if let Err(e) = worker_result {
+            error!("failed to spawn virtio_gpu worker: {}", e);
+            return Err(ActivateError::BadActivate);
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<VirtioInterrupt>, Vec<EventFd>)> {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command<T: ByteValued>(
+        state: &mut GpuState,
+        mem: &GuestMemoryMmap,
+        type_: u32,
+        body: T,
+    ) -> u32 {
+        let hdr = VirtioGpuCtrlHdr {
+            type_,
+            ..Default::default()
+        };
+        let mut request = hdr.as_slice().to_vec();
+        request.extend_from_slice(body.as_slice());
+        let response = state.command(mem, &request);
+        read_struct::<VirtioGpuCtrlHdr>(&response, 0).unwrap().type_
+    }
+
+    #[test]
+    fn test_transfer_and_flush() {
+        let mem = GuestMemoryMmap::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut state = GpuState::new(2, 2, GpuDisplay::default());
+
+        // A 2x2 B8G8R8X8 image, its rows split between two backing entries.
+        let pixels: [u8; 16] = [
+            0x01, 0x02, 0x03, 0, 0x04, 0x05, 0x06, 0, 0x07, 0x08, 0x09, 0, 0x0a, 0x0b, 0x0c, 0,
+        ];
+        mem.write_slice(&pixels[..8], GuestAddress(0x1000)).unwrap();
+        mem.write_slice(&pixels[8..], GuestAddress(0x3000)).unwrap();
+
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 1,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            width: 2,
+            height: 2,
+        };
+        assert_eq!(
+            command(&mut state, &mem, VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let hdr = VirtioGpuCtrlHdr {
+            type_: VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+            ..Default::default()
+        };
+        let mut request = hdr.as_slice().to_vec();
+        let attach = VirtioGpuResourceAttachBacking {
+            resource_id: 1,
+            nr_entries: 2,
+        };
+        request.extend_from_slice(attach.as_slice());
+        for &addr in [0x1000, 0x3000].iter() {
+            let entry = VirtioGpuMemEntry {
+                addr,
+                length: 8,
+                padding: 0,
+            };
+            request.extend_from_slice(entry.as_slice());
+        }
+        let response = state.command(&mem, &request);
+        assert_eq!(
+            read_struct::<VirtioGpuCtrlHdr>(&response, 0).unwrap().type_,
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+
+        let r = VirtioGpuRect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        let transfer = VirtioGpuTransferToHost2d {
+            r,
+            offset: 0,
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            command(
+                &mut state,
+                &mem,
+                VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+                transfer
+            ),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        let set_scanout = VirtioGpuSetScanout {
+            r,
+            scanout_id: 0,
+            resource_id: 1,
+        };
+        assert_eq!(
+            command(&mut state, &mem, VIRTIO_GPU_CMD_SET_SCANOUT, set_scanout),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert!(state.display.framebuffer().is_none());
+
+        let flush = VirtioGpuResourceFlush {
+            r,
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            command(&mut state, &mem, VIRTIO_GPU_CMD_RESOURCE_FLUSH, flush),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        let framebuffer = state.display.framebuffer().unwrap();
+        assert_eq!((framebuffer.width, framebuffer.height), (2, 2));
+        assert_eq!(
+            framebuffer.rgb,
+            vec![0x03, 0x02, 0x01, 0x06, 0x05, 0x04, 0x09, 0x08, 0x07, 0x0c, 0x0b, 0x0a]
+        );
+
+        let unref = VirtioGpuResource {
+            resource_id: 1,
+            padding: 0,
+        };
+        assert_eq!(
+            command(&mut state, &mem, VIRTIO_GPU_CMD_RESOURCE_UNREF, unref),
+            VIRTIO_GPU_RESP_OK_NODATA
+        );
+        assert!(state.display.framebuffer().is_none());
+        assert_eq!(state.hostmem, 0);
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let mem = GuestMemoryMmap::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut state = GpuState::new(1024, 768, GpuDisplay::default());
+
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 1,
+            format: 0,
+            width: 16,
+            height: 16,
+        };
+        assert_eq!(
+            command(&mut state, &mem, VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create),
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER
+        );
+        let create = VirtioGpuResourceCreate2d {
+            resource_id: 1,
+            format: VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM,
+            width: 16384,
+            height: 16384,
+        };
+        assert_eq!(
+            command(&mut state, &mem, VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, create),
+            VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY
+        );
+
+        let set_scanout = VirtioGpuSetScanout {
+            r: VirtioGpuRect::default(),
+            scanout_id: 1,
+            resource_id: 0,
+        };
+        assert_eq!(
+            command(&mut state, &mem, VIRTIO_GPU_CMD_SET_SCANOUT, set_scanout),
+            VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID
+        );
+        let flush = VirtioGpuResourceFlush {
+            r: VirtioGpuRect::default(),
+            resource_id: 2,
+            padding: 0,
+        };
+        assert_eq!(
+            command(&mut state, &mem, VIRTIO_GPU_CMD_RESOURCE_FLUSH, flush),
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID
+        );
+    }
+}
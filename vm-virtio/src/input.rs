@@ -0,0 +1,700 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! virtio-input devices: a keyboard, and a tablet, an absolute pointer with
+//! three buttons and a wheel. The VMM queues the events of the host for the
+//! guest, as evdev events, which the device hands over to the driver as it
+//! provides buffers for them.
+
+use epoll;
+use libc::EFD_NONBLOCK;
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::result;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use super::Error as DeviceError;
+use super::{
+    ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
+    VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use crate::{VirtioInterrupt, VirtioInterruptType};
+use vm_device::{Pausable, PauseControl, PauseWorker};
+use vm_memory::{ByteValued, Bytes, GuestMemoryMmap};
+use vmm_sys_util::eventfd::EventFd;
+
+const QUEUE_SIZE: u16 = 64;
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+// New descriptors are pending on the event queue.
+const EVENT_QUEUE_EVENT: DeviceEventT = 0;
+// New descriptors are pending on the status queue.
+const STATUS_QUEUE_EVENT: DeviceEventT = 1;
+// New events of the host are pending for the guest.
+const INPUT_EVENT: DeviceEventT = 2;
+// The device has been dropped.
+const KILL_EVENT: DeviceEventT = 3;
+// The device is paused.
+const PAUSE_EVENT: DeviceEventT = 4;
+
+// Events the driver didn't take yet, at most. The events past them are
+// dropped.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// Configuration selectors, from linux/virtio_input.h.
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+const VIRTIO_INPUT_CFG_ABS_INFO: u8 = 0x12;
+
+// Event types and codes, from linux/input-event-codes.h.
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+const EV_REP: u16 = 0x14;
+const KEY_ESC: u16 = 1;
+const KEY_MICMUTE: u16 = 248;
+/// First and last codes of the buttons, rather than keys, of EV_KEY.
+pub const BTN_MISC: u16 = 0x100;
+pub const BTN_TRIGGER_HAPPY40: u16 = 0x2e7;
+const BTN_LEFT: u16 = 0x110;
+const BTN_MIDDLE: u16 = 0x112;
+const REL_WHEEL: u16 = 0x08;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+
+const BUS_VIRTUAL: u16 = 0x06;
+const INPUT_VENDOR: u16 = 0x0627;
+const INPUT_VERSION: u16 = 0x0001;
+/// Largest absolute position of the tablet, on both axes.
+pub const TABLET_MAX: u32 = 0x7fff;
+
+/// Event of the host for the guest, as the evdev ones.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for InputEvent {}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct VirtioInputConfig {
+    select: u8,
+    subsel: u8,
+    size: u8,
+    reserved: [u8; 5],
+    u: [u8; 128],
+}
+
+impl Default for VirtioInputConfig {
+    fn default() -> Self {
+        VirtioInputConfig {
+            select: 0,
+            subsel: 0,
+            size: 0,
+            reserved: [0; 5],
+            u: [0; 128],
+        }
+    }
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioInputConfig {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioInputAbsInfo {
+    min: u32,
+    max: u32,
+    fuzz: u32,
+    flat: u32,
+    res: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioInputAbsInfo {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioInputDevIds {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl ByteValued for VirtioInputDevIds {}
+
+/// The kind of input device, and of the events it reports.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InputKind {
+    Keyboard,
+    Tablet,
+}
+
+impl InputKind {
+    fn name(self) -> &'static str {
+        match self {
+            InputKind::Keyboard => "Cloud Hypervisor Keyboard",
+            InputKind::Tablet => "Cloud Hypervisor Tablet",
+        }
+    }
+
+    fn product(self) -> u16 {
+        match self {
+            InputKind::Keyboard => 0x0001,
+            InputKind::Tablet => 0x0003,
+        }
+    }
+
+    // The codes of the event type the device reports, as a bitmap.
+    fn event_bits(self, type_: u16) -> Vec<u8> {
+        let codes: Vec<u16> = match (self, type_) {
+            (InputKind::Keyboard, EV_KEY) => (KEY_ESC..=KEY_MICMUTE).collect(),
+            // The guest repeats the keys held down.
+            (InputKind::Keyboard, EV_REP) => return vec![0],
+            (InputKind::Tablet, EV_KEY) => (BTN_LEFT..=BTN_MIDDLE).collect(),
+            (InputKind::Tablet, EV_REL) => vec![REL_WHEEL],
+            (InputKind::Tablet, EV_ABS) => vec![ABS_X, ABS_Y],
+            _ => return Vec::new(),
+        };
+
+        let mut bits = vec![0u8; *codes.iter().max().unwrap() as usize / 8 + 1];
+        for code in codes {
+            bits[code as usize / 8] |= 1 << (code % 8);
+        }
+        bits
+    }
+
+    /// Whether the device reports the event, EV_SYN being reported by
+    /// both.
+    pub fn reports(self, event: &InputEvent) -> bool {
+        let button = event.code >= BTN_MISC && event.code <= BTN_TRIGGER_HAPPY40;
+        match (self, event.type_) {
+            (_, EV_SYN) => true,
+            (InputKind::Keyboard, EV_KEY) => !button,
+            (InputKind::Tablet, EV_KEY) => button,
+            (InputKind::Tablet, EV_REL) | (InputKind::Tablet, EV_ABS) => true,
+            _ => false,
+        }
+    }
+}
+
+// The configuration the driver selected, `size` bytes of it being valid.
+fn input_config(kind: InputKind, select: u8, subsel: u8) -> Vec<u8> {
+    match (select, subsel) {
+        (VIRTIO_INPUT_CFG_ID_NAME, 0) => kind.name().as_bytes().to_vec(),
+        (VIRTIO_INPUT_CFG_ID_SERIAL, 0) => Vec::new(),
+        (VIRTIO_INPUT_CFG_ID_DEVIDS, 0) => VirtioInputDevIds {
+            bustype: BUS_VIRTUAL,
+            vendor: INPUT_VENDOR,
+            product: kind.product(),
+            version: INPUT_VERSION,
+        }
+        .as_slice()
+        .to_vec(),
+        (VIRTIO_INPUT_CFG_EV_BITS, type_) => kind.event_bits(u16::from(type_)),
+        (VIRTIO_INPUT_CFG_ABS_INFO, axis)
+            if kind == InputKind::Tablet
+                && (u16::from(axis) == ABS_X || u16::from(axis) == ABS_Y) =>
+        {
+            VirtioInputAbsInfo {
+                max: TABLET_MAX,
+                ..Default::default()
+            }
+            .as_slice()
+            .to_vec()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Events pending for the guest, queued by the VMM.
+pub struct InputEvents {
+    input_evt: EventFd,
+    pending: Mutex<VecDeque<InputEvent>>,
+}
+
+impl InputEvents {
+    /// Queues events for the guest, usually ended by an EV_SYN one. The
+    /// events are dropped altogether if the driver doesn't take them fast
+    /// enough.
+    pub fn queue_events(&self, events: &[InputEvent]) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() + events.len() > MAX_PENDING_EVENTS {
+            warn!("Dropping input events the guest doesn't take");
+            return;
+        }
+        pending.extend(events);
+        let _ = self.input_evt.write(1);
+    }
+}
+
+struct InputEpollHandler {
+    queues: Vec<Queue>,
+    mem: Arc<RwLock<GuestMemoryMmap>>,
+    events: Arc<InputEvents>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    queue_evts: Vec<EventFd>,
+    kill_evt: EventFd,
+    pause: PauseWorker,
+}
+
+impl InputEpollHandler {
+    // Hands the pending events over to the driver, one per buffer.
+    fn process_event_queue(&mut self) -> bool {
+        let mut pending = self.events.pending.lock().unwrap();
+        if pending.is_empty() {
+            return false;
+        }
+
+        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.read().unwrap();
+        for avail_desc in self.queues[0].iter(&mem) {
+            let mut len = 0;
+            let event = pending.pop_front().unwrap();
+            if !avail_desc.is_write_only()
+                || (avail_desc.len as usize) < std::mem::size_of::<InputEvent>()
+            {
+                error!("Invalid virtio-input event buffer");
+            } else if let Err(e) = mem.write_obj(event, avail_desc.addr) {
+                error!("Failed to write input event: {:?}", e);
+            } else {
+                len = std::mem::size_of::<InputEvent>() as u32;
+            }
+
+            used_desc_heads[used_count] = (avail_desc.index, len);
+            used_count += 1;
+            if pending.is_empty() {
+                break;
+            }
+        }
+
+        for &(desc_index, len) in &used_desc_heads[..used_count] {
+            self.queues[0].add_used(&mem, desc_index, len);
+        }
+        used_count > 0
+    }
+
+    // The LED and sound events of the driver are only consumed.
+    fn process_status_queue(&mut self) -> bool {
+        let mut used_desc_heads = [0; QUEUE_SIZE as usize];
+        let mut used_count = 0;
+        let mem = self.mem.read().unwrap();
+        for avail_desc in self.queues[1].iter(&mem) {
+            used_desc_heads[used_count] = avail_desc.index;
+            used_count += 1;
+        }
+
+        for &desc_index in &used_desc_heads[..used_count] {
+            self.queues[1].add_used(&mem, desc_index, 0);
+        }
+        used_count > 0
+    }
+
+    fn signal_used_queue(&self, queue: &Queue) -> result::Result<(), DeviceError> {
+        (self.interrupt_cb)(&VirtioInterruptType::Queue, Some(queue)).map_err(|e| {
+            error!("Failed to signal used queue: {:?}", e);
+            DeviceError::FailedSignalingUsedQueue(e)
+        })
+    }
+
+    fn run(&mut self) -> result::Result<(), DeviceError> {
+        // Create the epoll file descriptor
+        let epoll_fd = epoll::create(true).map_err(DeviceError::EpollCreateFd)?;
+
+        // Add events
+        for (fd, event) in [
+            (self.queue_evts[0].as_raw_fd(), EVENT_QUEUE_EVENT),
+            (self.queue_evts[1].as_raw_fd(), STATUS_QUEUE_EVENT),
+            (self.events.input_evt.as_raw_fd(), INPUT_EVENT),
+            (self.kill_evt.as_raw_fd(), KILL_EVENT),
+            (self.pause.evt().as_raw_fd(), PAUSE_EVENT),
+        ]
+        .iter()
+        {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                *fd,
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(*event)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+
+        const EPOLL_EVENTS_LEN: usize = 100;
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+
+        'epoll: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(DeviceError::EpollWait(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let ev_type = event.data as u16;
+
+                match ev_type {
+                    EVENT_QUEUE_EVENT | INPUT_EVENT => {
+                        let evt = if ev_type == INPUT_EVENT {
+                            &self.events.input_evt
+                        } else {
+                            &self.queue_evts[0]
+                        };
+                        if let Err(e) = evt.read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_event_queue() {
+                            if let Err(e) = self.signal_used_queue(&self.queues[0]) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    STATUS_QUEUE_EVENT => {
+                        if let Err(e) = self.queue_evts[1].read() {
+                            error!("Failed to get queue event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_status_queue() {
+                            if let Err(e) = self.signal_used_queue(&self.queues[1]) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    KILL_EVENT => {
+                        debug!("KILL_EVENT received, stopping epoll loop");
+                        break 'epoll;
+                    }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing epoll loop");
+                        self.pause.wait_resumed();
+                    }
+                    _ => {
+                        error!("Unknown event for virtio-input");
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Virtio device for the keyboard and pointer events of the host.
+pub struct Input {
+    kind: InputKind,
+    kill_evt: Option<EventFd>,
+    avail_features: u64,
+    acked_features: u64,
+    config: VirtioInputConfig,
+    events: Arc<InputEvents>,
+    queue_evts: Option<Vec<EventFd>>,
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    pause: PauseControl,
+}
+
+impl Input {
+    /// Create a new virtio-input device of the given kind, along with the
+    /// events pending for it.
+    pub fn new(kind: InputKind, iommu: bool) -> io::Result<(Input, Arc<InputEvents>)> {
+        let mut avail_features = 1u64 << VIRTIO_F_VERSION_1;
+
+        if iommu {
+            avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
+        }
+
+        let events = Arc::new(InputEvents {
+            input_evt: EventFd::new(EFD_NONBLOCK)?,
+            pending: Mutex::new(VecDeque::new()),
+        });
+        Ok((
+            Input {
+                kind,
+                kill_evt: None,
+                avail_features,
+                acked_features: 0u64,
+                config: VirtioInputConfig::default(),
+                events: events.clone(),
+                queue_evts: None,
+                interrupt_cb: None,
+                pause: PauseControl::new()?,
+            },
+            events,
+        ))
+    }
+}
+
+impl Drop for Input {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+    }
+}
+
+impl Pausable for Input {
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
+impl VirtioDevice for Input {
+    fn device_type(&self) -> u32 {
+        VirtioDeviceType::TYPE_INPUT as u32
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            // Get the lower 32-bits of the features bitfield.
+            0 => self.avail_features as u32,
+            // Get the upper 32-bits of the features bitfield.
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!("Received request for unknown features page.");
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => u64::from(value),
+            1 => u64::from(value) << 32,
+            _ => {
+                warn!("Cannot acknowledge unknown features page.");
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!("Received acknowledge request for unknown feature.");
+
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_slice = self.config.as_slice();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            return;
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    // The driver selects the configuration it reads through `select` and
+    // `subsel`, the only writable fields.
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        match (offset, data.len()) {
+            (0, 1) => self.config.select = data[0],
+            (1, 1) => self.config.subsel = data[0],
+            (0, 2) => {
+                self.config.select = data[0];
+                self.config.subsel = data[1];
+            }
+            _ => {
+                warn!("Invalid virtio-input config write at offset {}", offset);
+                return;
+            }
+        }
+
+        let u = input_config(self.kind, self.config.select, self.config.subsel);
+        let size = cmp::min(u.len(), self.config.u.len());
+        self.config.size = size as u8;
+        self.config.u = [0; 128];
+        self.config.u[..size].copy_from_slice(&u[..size]);
+    }
+
+    fn activate(
+        &mut self,
+        mem: Arc<RwLock<GuestMemoryMmap>>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            return Err(ActivateError::BadActivate);
+        }
+
+        let (self_kill_evt, kill_evt) =
+            match EventFd::new(EFD_NONBLOCK).and_then(|e| Ok((e.try_clone()?, e))) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("failed creating kill EventFd pair: {}", e);
+                    return Err(ActivateError::BadActivate);
+                }
+            };
+        self.kill_evt = Some(self_kill_evt);
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
+            // but clone it to pass into the thread.
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // The events queued before the driver was ready are stale.
+        self.events.pending.lock().unwrap().clear();
+
+        let mut handler = InputEpollHandler {
+            queues,
+            mem,
+            events: self.events.clone(),
+            interrupt_cb,
+            queue_evts,
+            kill_evt,
+            pause: self.pause.worker(),
+        };
+
+        let worker_result = thread::Builder::new()
+            .name("virtio_input".to_string())
+            .spawn(move || handler.run());
+
+        if let Err(e) = worker_result {
+            error!("failed to spawn virtio_input worker: {}", e);
+            return Err(ActivateError::BadActivate);
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Option<(Arc<VirtioInterrupt>, Vec<EventFd>)> {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.write(1);
+        }
+
+        // Return the interrupt and queue EventFDs
+        Some((
+            self.interrupt_cb.take().unwrap(),
+            self.queue_evts.take().unwrap(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_config(input: &mut Input, select: u8, subsel: u8) -> Vec<u8> {
+        let mut input_config = VirtioInputConfig::default();
+        input.write_config(0, &[select]);
+        input.write_config(1, &[subsel]);
+        input.read_config(0, input_config.as_mut_slice());
+        input_config.u[..input_config.size as usize].to_vec()
+    }
+
+    #[test]
+    fn test_input_config() {
+        let (mut keyboard, _) = Input::new(InputKind::Keyboard, false).unwrap();
+        let (mut tablet, _) = Input::new(InputKind::Tablet, false).unwrap();
+
+        assert_eq!(
+            read_config(&mut keyboard, VIRTIO_INPUT_CFG_ID_NAME, 0),
+            b"Cloud Hypervisor Keyboard".to_vec()
+        );
+        let key_bits = read_config(&mut keyboard, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(key_bits.len(), 32);
+        assert_eq!(key_bits[0], 0xfe);
+        assert!(read_config(&mut keyboard, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8).is_empty());
+
+        let button_bits = read_config(&mut tablet, VIRTIO_INPUT_CFG_EV_BITS, EV_KEY as u8);
+        assert_eq!(button_bits.len(), 35);
+        assert_eq!(button_bits[34], 0x07);
+        assert_eq!(
+            read_config(&mut tablet, VIRTIO_INPUT_CFG_EV_BITS, EV_ABS as u8),
+            vec![0x03]
+        );
+        let abs_info = read_config(&mut tablet, VIRTIO_INPUT_CFG_ABS_INFO, ABS_Y as u8);
+        assert_eq!(abs_info.len(), 20);
+        assert_eq!(abs_info[4..8], TABLET_MAX.to_le_bytes());
+        assert!(read_config(&mut tablet, VIRTIO_INPUT_CFG_ABS_INFO, 2).is_empty());
+    }
+
+    #[test]
+    fn test_input_reports() {
+        let key = InputEvent {
+            type_: EV_KEY,
+            code: 30,
+            value: 1,
+        };
+        let button = InputEvent {
+            type_: EV_KEY,
+            code: BTN_LEFT,
+            value: 1,
+        };
+        let abs = InputEvent {
+            type_: EV_ABS,
+            code: ABS_X,
+            value: 100,
+        };
+        let syn = InputEvent::default();
+
+        assert!(InputKind::Keyboard.reports(&key));
+        assert!(!InputKind::Keyboard.reports(&button));
+        assert!(!InputKind::Keyboard.reports(&abs));
+        assert!(!InputKind::Tablet.reports(&key));
+        assert!(InputKind::Tablet.reports(&button));
+        assert!(InputKind::Tablet.reports(&abs));
+        assert!(InputKind::Keyboard.reports(&syn) && InputKind::Tablet.reports(&syn));
+    }
+}
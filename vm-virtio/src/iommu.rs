@@ -21,7 +21,7 @@ use super::{
     VirtioDeviceType, VIRTIO_F_VERSION_1,
 };
 use crate::{DmaRemapping, VirtioInterrupt, VirtioInterruptType};
-use vm_device::ExternalDmaMapping;
+use vm_device::{ExternalDmaMapping, MemoryListener, Pausable, PauseControl, PauseWorker};
 use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -40,6 +40,8 @@ const REQUEST_Q_EVENT: DeviceEventT = 0;
 const EVENT_Q_EVENT: DeviceEventT = 1;
 /// The device has been dropped.
 const KILL_EVENT: DeviceEventT = 2;
+/// The device is paused.
+const PAUSE_EVENT: DeviceEventT = 3;
 
 /// Virtio IOMMU features
 #[allow(unused)]
@@ -346,7 +348,6 @@ impl Request {
         mem: &GuestMemoryMmap,
         mapping: &Arc<IommuMapping>,
         ext_mapping: &BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
-        ext_domain_mapping: &mut BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
     ) -> result::Result<Request, Error> {
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
@@ -389,7 +390,11 @@ impl Request {
                 // external mapping, insert a new entry for the corresponding
                 // domain, with the same reference to the trait.
                 if let Some(map) = ext_mapping.get(&endpoint) {
-                    ext_domain_mapping.insert(domain, map.clone());
+                    mapping
+                        .ext_domain_mapping
+                        .write()
+                        .unwrap()
+                        .insert(domain, map.clone());
                 }
 
                 // Add new domain with no mapping if the entry didn't exist yet
@@ -418,7 +423,7 @@ impl Request {
                 // external mapping, remove the entry for the corresponding
                 // domain.
                 if ext_mapping.contains_key(&endpoint) {
-                    ext_domain_mapping.remove(&domain);
+                    mapping.ext_domain_mapping.write().unwrap().remove(&domain);
                 }
 
                 // Remove endpoint associated with specific domain
@@ -440,7 +445,7 @@ impl Request {
                 let domain = req.domain;
 
                 // Trigger external mapping if necessary.
-                if let Some(ext_map) = ext_domain_mapping.get(&domain) {
+                if let Some(ext_map) = mapping.ext_domain_mapping.read().unwrap().get(&domain) {
                     let size = req.virt_end - req.virt_start + 1;
                     ext_map
                         .map(req.virt_start, req.phys_start, size)
@@ -477,7 +482,7 @@ impl Request {
                 let virt_start = req.virt_start;
 
                 // Trigger external unmapping if necessary.
-                if let Some(ext_map) = ext_domain_mapping.get(&domain) {
+                if let Some(ext_map) = mapping.ext_domain_mapping.read().unwrap().get(&domain) {
                     let size = req.virt_end - virt_start + 1;
                     ext_map
                         .unmap(virt_start, size)
@@ -534,7 +539,7 @@ struct IommuEpollHandler {
     kill_evt: EventFd,
     mapping: Arc<IommuMapping>,
     ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
-    ext_domain_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
+    pause: PauseWorker,
 }
 
 impl IommuEpollHandler {
@@ -548,7 +553,6 @@ impl IommuEpollHandler {
                 &mem,
                 &self.mapping,
                 &self.ext_mapping,
-                &mut self.ext_domain_mapping,
             ) {
                 Ok(ref req) => {
                     let reply = VirtioIommuReqTail {
@@ -617,6 +621,13 @@ impl IommuEpollHandler {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause.evt().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
 
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -669,6 +680,10 @@ impl IommuEpollHandler {
                         debug!("kill_evt received, stopping epoll loop");
                         break 'epoll;
                     }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing epoll loop");
+                        self.pause.wait_resumed();
+                    }
                     _ => {
                         error!("Unknown event for virtio-iommu");
                         break 'epoll;
@@ -694,6 +709,9 @@ pub struct IommuMapping {
     endpoints: Arc<RwLock<BTreeMap<u32, u32>>>,
     // List of mappings per domain.
     mappings: Arc<RwLock<BTreeMap<u32, BTreeMap<u64, Mapping>>>>,
+    // External mapping per domain, for the devices not managed through
+    // virtio, such as VFIO ones.
+    ext_domain_mapping: Arc<RwLock<BTreeMap<u32, Arc<dyn ExternalDmaMapping>>>>,
 }
 
 impl DmaRemapping for IommuMapping {
@@ -721,6 +739,40 @@ impl DmaRemapping for IommuMapping {
     }
 }
 
+impl MemoryListener for IommuMapping {
+    fn region_added(&self, _gpa: u64, _size: u64, _host_addr: u64) -> io::Result<()> {
+        // The guest driver decides what gets mapped through the virtual
+        // IOMMU, there is nothing to do until it sends a map request.
+        Ok(())
+    }
+
+    fn region_removed(&self, gpa: u64, size: u64) -> io::Result<()> {
+        // Drop any IOVA mapping pointing into the removed region, so that no
+        // device can keep reaching it once it gets unplugged.
+        let ext_domain_mapping = self.ext_domain_mapping.read().unwrap();
+        for (domain, mappings) in self.mappings.write().unwrap().iter_mut() {
+            let stale: Vec<(u64, u64)> = mappings
+                .iter()
+                .filter(|(_, m)| m.gpa < gpa + size && gpa < m.gpa + m.size)
+                .map(|(&iova, m)| (iova, m.size))
+                .collect();
+
+            for (iova, iova_size) in stale {
+                debug!(
+                    "Removing stale mapping iova 0x{:x} from domain {}",
+                    iova, domain
+                );
+                if let Some(ext_map) = ext_domain_mapping.get(domain) {
+                    ext_map.unmap(iova, iova_size)?;
+                }
+                mappings.remove(&iova);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub struct Iommu {
     kill_evt: Option<EventFd>,
     avail_features: u64,
@@ -730,6 +782,7 @@ pub struct Iommu {
     ext_mapping: BTreeMap<u32, Arc<dyn ExternalDmaMapping>>,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    pause: PauseControl,
 }
 
 impl Iommu {
@@ -742,6 +795,7 @@ impl Iommu {
         let mapping = Arc::new(IommuMapping {
             endpoints: Arc::new(RwLock::new(BTreeMap::new())),
             mappings: Arc::new(RwLock::new(BTreeMap::new())),
+            ext_domain_mapping: Arc::new(RwLock::new(BTreeMap::new())),
         });
 
         Ok((
@@ -754,6 +808,7 @@ impl Iommu {
                 ext_mapping: BTreeMap::new(),
                 queue_evts: None,
                 interrupt_cb: None,
+                pause: PauseControl::new()?,
             },
             mapping,
         ))
@@ -773,6 +828,16 @@ impl Drop for Iommu {
     }
 }
 
+impl Pausable for Iommu {
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
 impl VirtioDevice for Iommu {
     fn device_type(&self) -> u32 {
         VirtioDeviceType::TYPE_IOMMU as u32
@@ -884,7 +949,7 @@ impl VirtioDevice for Iommu {
             kill_evt,
             mapping: self.mapping.clone(),
             ext_mapping: self.ext_mapping.clone(),
-            ext_domain_mapping: BTreeMap::new(),
+            pause: self.pause.worker(),
         };
 
         let worker_result = thread::Builder::new()
@@ -912,3 +977,36 @@ impl VirtioDevice for Iommu {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_region_removed_drops_stale_mappings() {
+        let (_iommu, mapping) = Iommu::new().unwrap();
+        mapping.endpoints.write().unwrap().insert(3, 1);
+
+        let mut domain_mappings = BTreeMap::new();
+        domain_mappings.insert(
+            0x1000,
+            Mapping {
+                gpa: 0x10_0000,
+                size: 0x1000,
+            },
+        );
+        domain_mappings.insert(
+            0x2000,
+            Mapping {
+                gpa: 0x20_0000,
+                size: 0x1000,
+            },
+        );
+        mapping.mappings.write().unwrap().insert(1, domain_mappings);
+
+        mapping.region_removed(0x10_0000, 0x10_0000).unwrap();
+
+        assert!(!mapping.mappings.read().unwrap()[&1].contains_key(&0x1000));
+        assert_eq!(mapping.translate(3, 0x2000).unwrap(), 0x20_0000);
+    }
+}
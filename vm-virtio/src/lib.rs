@@ -22,12 +22,16 @@ use std::fmt;
 use std::io;
 
 pub mod block;
+mod block_cache;
 mod console;
 mod device;
+mod gpu;
+mod input;
 mod iommu;
 pub mod net;
 mod pmem;
 mod queue;
+pub mod rate_limiter;
 mod rng;
 pub mod vsock;
 
@@ -35,12 +39,16 @@ pub mod transport;
 pub mod vhost_user;
 
 pub use self::block::*;
+pub use self::block_cache::*;
 pub use self::console::*;
 pub use self::device::*;
+pub use self::gpu::*;
+pub use self::input::*;
 pub use self::iommu::*;
 pub use self::net::*;
 pub use self::pmem::*;
 pub use self::queue::*;
+pub use self::rate_limiter::*;
 pub use self::rng::*;
 pub use self::vsock::*;
 
@@ -60,7 +68,7 @@ const VIRTIO_F_IN_ORDER: u32 = 35;
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
 #[repr(C)]
-enum VirtioDeviceType {
+pub enum VirtioDeviceType {
     TYPE_NET = 1,
     TYPE_BLOCK = 2,
     TYPE_CONSOLE = 3,
@@ -81,6 +89,7 @@ impl From<u32> for VirtioDeviceType {
         match t {
             1 => VirtioDeviceType::TYPE_NET,
             2 => VirtioDeviceType::TYPE_BLOCK,
+            3 => VirtioDeviceType::TYPE_CONSOLE,
             4 => VirtioDeviceType::TYPE_RNG,
             5 => VirtioDeviceType::TYPE_BALLOON,
             9 => VirtioDeviceType::TYPE_9P,
@@ -103,11 +112,14 @@ impl fmt::Display for VirtioDeviceType {
         let output = match *self {
             VirtioDeviceType::TYPE_NET => "net",
             VirtioDeviceType::TYPE_BLOCK => "block",
+            VirtioDeviceType::TYPE_CONSOLE => "console",
             VirtioDeviceType::TYPE_RNG => "rng",
             VirtioDeviceType::TYPE_BALLOON => "balloon",
             VirtioDeviceType::TYPE_GPU => "gpu",
+            VirtioDeviceType::TYPE_INPUT => "input",
             VirtioDeviceType::TYPE_9P => "9p",
             VirtioDeviceType::TYPE_VSOCK => "vsock",
+            VirtioDeviceType::TYPE_IOMMU => "iommu",
             VirtioDeviceType::TYPE_FS => "fs",
             VirtioDeviceType::TYPE_PMEM => "pmem",
             _ => return Err(std::fmt::Error),
@@ -12,7 +12,8 @@ use std::cmp;
 use std::io::Read;
 use std::io::{self, Write};
 use std::mem;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::raw::c_uint;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::{Arc, RwLock};
@@ -23,12 +24,13 @@ use net_gen;
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, DeviceEventT, Queue, VirtioDevice, VirtioDeviceType,
-    VirtioInterruptType,
+    ActivateError, ActivateResult, DeviceEventT, Queue, RateLimiter, TokenType, VirtioDevice,
+    VirtioDeviceType, VirtioInterruptType,
 };
 use crate::VirtioInterrupt;
 use net_util::{MacAddr, Tap, TapError, MAC_ADDR_LEN};
 use virtio_bindings::bindings::virtio_net::*;
+use vm_device::{Pausable, PauseControl, PauseWorker};
 use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -36,9 +38,10 @@ use vmm_sys_util::eventfd::EventFd;
 /// includes the 12-byte virtio net header.
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
-const QUEUE_SIZE: u16 = 256;
-const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
+// The RX and TX queues of a queue pair.
+const QUEUES_PER_PAIR: usize = 2;
+// Largest control command read from the guest, its header included.
+const MAX_CTRL_COMMAND_SIZE: usize = 64;
 
 // A frame is available for reading from the tap device to receive in the guest.
 const RX_TAP_EVENT: DeviceEventT = 0;
@@ -48,8 +51,16 @@ const RX_QUEUE_EVENT: DeviceEventT = 1;
 const TX_QUEUE_EVENT: DeviceEventT = 2;
 // The device has been dropped.
 pub const KILL_EVENT: DeviceEventT = 3;
+// The RX rate limiter refill timer expired.
+const RX_RATE_LIMITER_EVENT: DeviceEventT = 4;
+// The TX rate limiter refill timer expired.
+const TX_RATE_LIMITER_EVENT: DeviceEventT = 5;
+// The guest has made a control command available.
+const CTRL_QUEUE_EVENT: DeviceEventT = 6;
+// The device is paused.
+const PAUSE_EVENT: DeviceEventT = 7;
 // Number of DeviceEventT events supported by this implementation.
-pub const NET_EVENTS_COUNT: usize = 4;
+pub const NET_EVENTS_COUNT: usize = 8;
 
 #[derive(Debug)]
 pub enum Error {
@@ -59,16 +70,91 @@ pub enum Error {
     TapSetIp(TapError),
     /// Setting tap netmask failed.
     TapSetNetmask(TapError),
+    /// Setting tap IPv6 address failed.
+    TapSetIpv6(TapError),
     /// Setting tap interface offload flags failed.
     TapSetOffload(TapError),
     /// Setting vnet header size failed.
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Creating the pause EventFd failed.
+    PauseEventFd(io::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Offloads the device offers to the guest. The tap is set up for the ones
+/// the guest acknowledged once the device is activated, for it to only hand
+/// the device the frames the guest can receive.
+#[derive(Clone, Copy, Debug)]
+pub struct NetOffloads {
+    /// Checksum offload, which the other offloads rely on.
+    pub csum: bool,
+    /// TCP segmentation offload, over IPv4 and IPv6, with ECN.
+    pub tso: bool,
+    /// UDP fragmentation offload.
+    pub ufo: bool,
+}
+
+impl NetOffloads {
+    fn features(self) -> u64 {
+        let mut features = 0;
+        if self.csum {
+            features |= 1 << VIRTIO_NET_F_CSUM | 1 << VIRTIO_NET_F_GUEST_CSUM;
+            if self.tso {
+                features |= 1 << VIRTIO_NET_F_HOST_TSO4
+                    | 1 << VIRTIO_NET_F_HOST_TSO6
+                    | 1 << VIRTIO_NET_F_HOST_ECN
+                    | 1 << VIRTIO_NET_F_GUEST_TSO4
+                    | 1 << VIRTIO_NET_F_GUEST_TSO6
+                    | 1 << VIRTIO_NET_F_GUEST_ECN;
+            }
+            if self.ufo {
+                features |= 1 << VIRTIO_NET_F_HOST_UFO | 1 << VIRTIO_NET_F_GUEST_UFO;
+            }
+        }
+
+        features
+    }
+}
+
+impl Default for NetOffloads {
+    fn default() -> Self {
+        NetOffloads {
+            csum: true,
+            tso: true,
+            ufo: true,
+        }
+    }
+}
+
+/// The tap offload flags matching the guest offloads among `features`. The
+/// segmentation offloads only go along with the checksum one, and ECN with
+/// a segmentation offload, as the tap requires.
+pub fn virtio_features_to_tap_offload(features: u64) -> c_uint {
+    let has = |feature: u32| features & (1u64 << feature) != 0;
+    if !has(VIRTIO_NET_F_GUEST_CSUM) {
+        return 0;
+    }
+
+    let mut offloads = net_gen::TUN_F_CSUM;
+    if has(VIRTIO_NET_F_GUEST_TSO4) {
+        offloads |= net_gen::TUN_F_TSO4;
+    }
+    if has(VIRTIO_NET_F_GUEST_TSO6) {
+        offloads |= net_gen::TUN_F_TSO6;
+    }
+    if has(VIRTIO_NET_F_GUEST_ECN) && offloads & (net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6) != 0 {
+        offloads |= net_gen::TUN_F_TSO_ECN;
+    }
+    if has(VIRTIO_NET_F_GUEST_UFO) {
+        offloads |= net_gen::TUN_F_UFO;
+    }
+
+    offloads
+}
+
 struct TxVirtio {
     queue_evt: EventFd,
     queue: Queue,
@@ -92,6 +178,8 @@ struct RxVirtio {
     queue_evt: EventFd,
     deferred_frame: bool,
     deferred_irqs: bool,
+    // The deferred frame has not been accounted for by the rate limiter yet.
+    rate_limited: bool,
     queue: Queue,
     bytes_read: usize,
     frame_buf: [u8; MAX_BUFFER_SIZE],
@@ -103,6 +191,7 @@ impl RxVirtio {
             queue_evt,
             deferred_frame: false,
             deferred_irqs: false,
+            rate_limited: false,
             queue,
             bytes_read: 0,
             frame_buf: [0u8; MAX_BUFFER_SIZE],
@@ -110,19 +199,47 @@ impl RxVirtio {
     }
 }
 
+struct CtrlVirtio {
+    queue_evt: EventFd,
+    queue: Queue,
+}
+
 fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
 
+// The ack of a control command. The guest only sends the number of queue
+// pairs it uses, the device not offering the other classes of commands, and
+// the device keeps serving all the queue pairs.
+fn ctrl_command_ack(command: &[u8], queue_pairs: usize) -> u8 {
+    if command.len() >= 4
+        && u32::from(command[0]) == VIRTIO_NET_CTRL_MQ
+        && u32::from(command[1]) == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET
+    {
+        let pairs = usize::from(u16::from_le_bytes([command[2], command[3]]));
+        if pairs >= 1 && pairs <= queue_pairs {
+            return VIRTIO_NET_OK as u8;
+        }
+    }
+
+    VIRTIO_NET_ERR as u8
+}
+
 struct NetEpollHandler {
     mem: Arc<RwLock<GuestMemoryMmap>>,
     tap: Tap,
     rx: RxVirtio,
     tx: TxVirtio,
+    // The control queue, served by the handler of the first queue pair.
+    ctrl: Option<CtrlVirtio>,
+    queue_pairs: usize,
     interrupt_cb: Arc<VirtioInterrupt>,
     kill_evt: EventFd,
     epoll_fd: RawFd,
     rx_tap_listening: bool,
+    rx_rate_limiter: Option<RateLimiter>,
+    tx_rate_limiter: Option<RateLimiter>,
+    pause: PauseWorker,
 }
 
 impl NetEpollHandler {
@@ -194,12 +311,44 @@ impl NetEpollHandler {
         write_count >= self.rx.bytes_read
     }
 
+    // Accounts for the frame stored in `self.rx.frame_buf` against the RX rate
+    // limiter. Returns false if the frame must be held back, in which case we
+    // stop reading from the tap until the rate limiter gets unblocked.
+    fn rate_limit_rx(&mut self) -> bool {
+        let allowed = match &mut self.rx_rate_limiter {
+            Some(rate_limiter) => {
+                if !rate_limiter.consume(1, TokenType::Ops) {
+                    false
+                } else if !rate_limiter.consume(self.rx.bytes_read as u64, TokenType::Bytes) {
+                    // Give back the operation token taken above.
+                    rate_limiter.manual_replenish(1, TokenType::Ops);
+                    false
+                } else {
+                    true
+                }
+            }
+            None => true,
+        };
+
+        if !allowed && self.rx_tap_listening {
+            self.unregister_tap_rx_listener().unwrap();
+            self.rx_tap_listening = false;
+        }
+
+        allowed
+    }
+
     fn process_rx(&mut self) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
             match self.read_tap() {
                 Ok(count) => {
                     self.rx.bytes_read = count;
+                    if !self.rate_limit_rx() {
+                        self.rx.deferred_frame = true;
+                        self.rx.rate_limited = true;
+                        break;
+                    }
                     if !self.rx_single_frame() {
                         self.rx.deferred_frame = true;
                         break;
@@ -229,6 +378,15 @@ impl NetEpollHandler {
 
     fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
         if self.rx.deferred_frame {
+            // A frame held back by the rate limiter must be accounted for
+            // before being handed over to the guest.
+            if self.rx.rate_limited {
+                if !self.rate_limit_rx() {
+                    return Ok(());
+                }
+                self.rx.rate_limited = false;
+            }
+
             if self.rx_single_frame() {
                 self.rx.deferred_frame = false;
                 // process_rx() was interrupted possibly before consuming all
@@ -262,6 +420,21 @@ impl NetEpollHandler {
                 next_desc = desc.next_descriptor();
             }
 
+            if let Some(rate_limiter) = &mut self.tx_rate_limiter {
+                // If consume() fails, there is no budget left. Put the
+                // descriptor back and wait for the limiter to refill.
+                if !rate_limiter.consume(1, TokenType::Ops) {
+                    self.tx.queue.go_to_previous_position();
+                    break;
+                }
+                if !rate_limiter.consume(read_count as u64, TokenType::Bytes) {
+                    // Give back the operation token taken above.
+                    rate_limiter.manual_replenish(1, TokenType::Ops);
+                    self.tx.queue.go_to_previous_position();
+                    break;
+                }
+            }
+
             read_count = 0;
             // Copy buffer from across multiple descriptors.
             // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
@@ -299,6 +472,58 @@ impl NetEpollHandler {
         Ok(())
     }
 
+    fn process_ctrl(&mut self) -> result::Result<(), DeviceError> {
+        let mem = self.mem.read().unwrap();
+        let ctrl = match &mut self.ctrl {
+            Some(ctrl) => ctrl,
+            None => return Ok(()),
+        };
+
+        while let Some(avail_desc) = ctrl.queue.iter(&mem).next() {
+            let head_index = avail_desc.index;
+            let mut command = Vec::new();
+            let mut ack_addr = None;
+            let mut next_desc = Some(avail_desc);
+
+            // The command is read from the readable descriptors, the ack
+            // going to the writable one after them.
+            while let Some(desc) = next_desc {
+                if desc.is_write_only() {
+                    ack_addr = Some(desc.addr);
+                    break;
+                }
+                let start = command.len();
+                let end = cmp::min(start + desc.len as usize, MAX_CTRL_COMMAND_SIZE);
+                command.resize(end, 0);
+                if let Err(e) = mem.read_slice(&mut command[start..], desc.addr) {
+                    error!("Failed to read control command: {:?}", e);
+                }
+                next_desc = desc.next_descriptor();
+            }
+
+            let ack = ctrl_command_ack(&command, self.queue_pairs);
+            let len = match ack_addr {
+                Some(addr) => match mem.write_obj(ack, addr) {
+                    Ok(()) => 1,
+                    Err(e) => {
+                        error!("Failed to write control command ack: {:?}", e);
+                        0
+                    }
+                },
+                None => {
+                    warn!("Control command without a buffer for its ack");
+                    0
+                }
+            };
+            ctrl.queue.add_used(&mem, head_index, len);
+        }
+
+        match &self.ctrl {
+            Some(ctrl) => self.signal_used_queue(&ctrl.queue),
+            None => Ok(()),
+        }
+    }
+
     fn read_tap(&mut self) -> io::Result<usize> {
         self.tap.read(&mut self.rx.frame_buf)
     }
@@ -351,6 +576,40 @@ impl NetEpollHandler {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            self.epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause.evt().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        if let Some(rate_limiter) = &self.rx_rate_limiter {
+            epoll::ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                rate_limiter.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(RX_RATE_LIMITER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+        if let Some(rate_limiter) = &self.tx_rate_limiter {
+            epoll::ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                rate_limiter.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(TX_RATE_LIMITER_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
+        if let Some(ctrl) = &self.ctrl {
+            epoll::ctl(
+                self.epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                ctrl.queue_evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(CTRL_QUEUE_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
 
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -385,7 +644,7 @@ impl NetEpollHandler {
                         }
 
                         self.resume_rx().unwrap();
-                        if !self.rx_tap_listening {
+                        if !self.rx_tap_listening && !self.rx.rate_limited {
                             self.register_tap_rx_listener().unwrap();
                             self.rx_tap_listening = true;
                         }
@@ -405,21 +664,56 @@ impl NetEpollHandler {
                         // Process a deferred frame first if available. Don't read from tap again
                         // until we manage to receive this deferred frame.
                         {
-                            if self.rx_single_frame() {
-                                self.rx.deferred_frame = false;
-                                self.process_rx().unwrap();
-                            } else if self.rx.deferred_irqs {
-                                self.rx.deferred_irqs = false;
-                                self.signal_used_queue(&self.rx.queue).unwrap();
-                            }
+                            self.resume_rx().unwrap();
                         } else {
                             self.process_rx().unwrap();
                         }
                     }
+                    RX_RATE_LIMITER_EVENT => {
+                        debug!("RX_RATE_LIMITER_EVENT received");
+                        if let Some(rate_limiter) = &mut self.rx_rate_limiter {
+                            if let Err(e) = rate_limiter.event_handler() {
+                                error!("Failed to handle rx rate limiter event: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+
+                        self.resume_rx().unwrap();
+                        if !self.rx_tap_listening && !self.rx.deferred_frame {
+                            self.register_tap_rx_listener().unwrap();
+                            self.rx_tap_listening = true;
+                        }
+                    }
+                    TX_RATE_LIMITER_EVENT => {
+                        debug!("TX_RATE_LIMITER_EVENT received");
+                        if let Some(rate_limiter) = &mut self.tx_rate_limiter {
+                            if let Err(e) = rate_limiter.event_handler() {
+                                error!("Failed to handle tx rate limiter event: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+
+                        self.process_tx().unwrap();
+                    }
+                    CTRL_QUEUE_EVENT => {
+                        debug!("CTRL_QUEUE_EVENT received");
+                        if let Some(ctrl) = &self.ctrl {
+                            if let Err(e) = ctrl.queue_evt.read() {
+                                error!("Failed to get ctrl queue event: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+
+                        self.process_ctrl().unwrap();
+                    }
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
                         break 'epoll;
                     }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing epoll loop");
+                        self.pause.wait_resumed();
+                    }
                     _ => {
                         error!("Unknown event for virtio-net");
                     }
@@ -433,36 +727,47 @@ impl NetEpollHandler {
 
 pub struct Net {
     kill_evt: Option<EventFd>,
-    tap: Option<Tap>,
+    // The taps of the queue pairs, the queues of a multi-queue tap when
+    // there are several of them.
+    taps: Vec<Tap>,
     avail_features: u64,
     acked_features: u64,
     // The config space will only consist of the MAC address specified by the user,
-    // or nothing, if no such address if provided.
+    // or nothing, if no such address if provided, unless the device has several
+    // queue pairs.
     config_space: Vec<u8>,
+    queue_sizes: Vec<u16>,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    // The same limits are applied independently to the RX and TX directions,
+    // of each queue pair.
+    rate_limiter: Option<RateLimiter>,
+    pause: PauseControl,
 }
 
 impl Net {
-    /// Create a new virtio network device with the given TAP interface.
-    pub fn new_with_tap(tap: Tap, guest_mac: Option<&MacAddr>, iommu: bool) -> Result<Self> {
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+    /// Create a new virtio network device with the given TAP interfaces, one
+    /// per queue pair. The device gets a control queue for the guest to set
+    /// the number of queue pairs it uses when it has several of them.
+    pub fn new_with_taps(
+        taps: Vec<Tap>,
+        guest_mac: Option<&MacAddr>,
+        iommu: bool,
+        rate_limiter: Option<RateLimiter>,
+        queue_size: u16,
+        offloads: NetOffloads,
+    ) -> Result<Self> {
+        let mut avail_features = offloads.features() | 1 << VIRTIO_F_VERSION_1;
 
+        // Check the taps support the offloads offered, before they get the
+        // ones the guest acknowledged.
         let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
-
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_UFO
-            | 1 << VIRTIO_F_VERSION_1;
+        for tap in taps.iter() {
+            tap.set_offload(virtio_features_to_tap_offload(avail_features))
+                .map_err(Error::TapSetOffload)?;
+            tap.set_vnet_hdr_size(vnet_hdr_size)
+                .map_err(Error::TapSetVnetHdrSize)?;
+        }
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -481,31 +786,61 @@ impl Net {
             config_space = Vec::new();
         }
 
+        let queue_pairs = taps.len();
+        let mut num_queues = queue_pairs * QUEUES_PER_PAIR;
+        if queue_pairs > 1 {
+            // The number of queue pairs follows the MAC address and the
+            // status, which are there even without their features.
+            config_space.resize(MAC_ADDR_LEN + 2, 0);
+            config_space.extend_from_slice(&(queue_pairs as u16).to_le_bytes());
+            avail_features |= 1 << VIRTIO_NET_F_MQ | 1 << VIRTIO_NET_F_CTRL_VQ;
+            num_queues += 1;
+        }
+
         Ok(Net {
             kill_evt: None,
-            tap: Some(tap),
+            taps,
             avail_features,
             acked_features: 0u64,
             config_space,
+            queue_sizes: vec![queue_size; num_queues],
             queue_evts: None,
             interrupt_cb: None,
+            rate_limiter,
+            pause: PauseControl::new().map_err(Error::PauseEventFd)?,
         })
     }
 
     /// Create a new virtio network device with the given IP address and
-    /// netmask.
+    /// netmask, and the given IPv6 address and prefix length, if any.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         ip_addr: Ipv4Addr,
         netmask: Ipv4Addr,
+        ipv6: Option<(Ipv6Addr, u8)>,
         guest_mac: Option<&MacAddr>,
         iommu: bool,
+        rate_limiter: Option<RateLimiter>,
+        queue_size: u16,
+        offloads: NetOffloads,
     ) -> Result<Self> {
         let tap = Tap::new().map_err(Error::TapOpen)?;
         tap.set_ip_addr(ip_addr).map_err(Error::TapSetIp)?;
         tap.set_netmask(netmask).map_err(Error::TapSetNetmask)?;
+        if let Some((ip6_addr, prefix_len)) = ipv6 {
+            tap.set_ipv6_addr(ip6_addr, prefix_len)
+                .map_err(Error::TapSetIpv6)?;
+        }
         tap.enable().map_err(Error::TapEnable)?;
 
-        Self::new_with_tap(tap, guest_mac, iommu)
+        Self::new_with_taps(
+            vec![tap],
+            guest_mac,
+            iommu,
+            rate_limiter,
+            queue_size,
+            offloads,
+        )
     }
 }
 
@@ -518,13 +853,23 @@ impl Drop for Net {
     }
 }
 
+impl Pausable for Net {
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
 impl VirtioDevice for Net {
     fn device_type(&self) -> u32 {
         VirtioDeviceType::TYPE_NET as u32
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn features(&self, page: u32) -> u32 {
@@ -591,14 +936,18 @@ impl VirtioDevice for Net {
         mut queues: Vec<Queue>,
         mut queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        let num_queues = self.queue_sizes.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                num_queues,
                 queues.len()
             );
             return Err(ActivateError::BadActivate);
         }
+        if self.taps.is_empty() {
+            return Err(ActivateError::BadActivate);
+        }
 
         let (self_kill_evt, kill_evt) =
             match EventFd::new(EFD_NONBLOCK).and_then(|e| Ok((e.try_clone()?, e))) {
@@ -610,35 +959,80 @@ impl VirtioDevice for Net {
             };
         self.kill_evt = Some(self_kill_evt);
 
-        if let Some(tap) = self.tap.clone() {
-            // Save the interrupt EventFD as we need to return it on reset
+        // The taps only hand the device the frames the guest can receive,
+        // given the offloads it acknowledged.
+        let tap_offloads = virtio_features_to_tap_offload(self.acked_features);
+        for tap in self.taps.iter() {
+            tap.set_offload(tap_offloads).map_err(|e| {
+                error!("failed to set tap offload flags: {:?}", e);
+                ActivateError::BadActivate
+            })?;
+        }
+
+        // Save the interrupt EventFD as we need to return it on reset
+        // but clone it to pass into the thread.
+        self.interrupt_cb = Some(interrupt_cb.clone());
+
+        let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
+        for queue_evt in queue_evts.iter() {
+            // Save the queue EventFD as we need to return it on reset
             // but clone it to pass into the thread.
-            self.interrupt_cb = Some(interrupt_cb.clone());
-
-            let mut tmp_queue_evts: Vec<EventFd> = Vec::new();
-            for queue_evt in queue_evts.iter() {
-                // Save the queue EventFD as we need to return it on reset
-                // but clone it to pass into the thread.
-                tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
-                    error!("failed to clone queue EventFd: {}", e);
-                    ActivateError::BadActivate
-                })?);
-            }
-            self.queue_evts = Some(tmp_queue_evts);
+            tmp_queue_evts.push(queue_evt.try_clone().map_err(|e| {
+                error!("failed to clone queue EventFd: {}", e);
+                ActivateError::BadActivate
+            })?);
+        }
+        self.queue_evts = Some(tmp_queue_evts);
+
+        // The control queue comes after the queue pairs.
+        let queue_pairs = self.taps.len();
+        let mut ctrl = if queue_pairs > 1 {
+            Some(CtrlVirtio {
+                queue: queues.remove(queue_pairs * QUEUES_PER_PAIR),
+                queue_evt: queue_evts.remove(queue_pairs * QUEUES_PER_PAIR),
+            })
+        } else {
+            None
+        };
 
+        for tap in self.taps.iter() {
             let rx_queue = queues.remove(0);
             let tx_queue = queues.remove(0);
             let rx_queue_evt = queue_evts.remove(0);
             let tx_queue_evt = queue_evts.remove(0);
+
+            let (rx_rate_limiter, tx_rate_limiter) = match &self.rate_limiter {
+                Some(rate_limiter) => {
+                    let clone = || {
+                        rate_limiter.try_clone().map_err(|e| {
+                            error!("failed to clone rate limiter: {}", e);
+                            ActivateError::BadActivate
+                        })
+                    };
+                    (Some(clone()?), Some(clone()?))
+                }
+                None => (None, None),
+            };
+
+            let kill_evt = kill_evt.try_clone().map_err(|e| {
+                error!("failed to clone kill EventFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+
             let mut handler = NetEpollHandler {
-                mem,
-                tap,
+                mem: mem.clone(),
+                tap: tap.clone(),
                 rx: RxVirtio::new(rx_queue, rx_queue_evt),
                 tx: TxVirtio::new(tx_queue, tx_queue_evt),
-                interrupt_cb,
+                ctrl: ctrl.take(),
+                queue_pairs,
+                interrupt_cb: interrupt_cb.clone(),
                 kill_evt,
                 epoll_fd: 0,
                 rx_tap_listening: false,
+                rx_rate_limiter,
+                tx_rate_limiter,
+                pause: self.pause.worker(),
             };
 
             let worker_result = thread::Builder::new()
@@ -646,13 +1040,12 @@ impl VirtioDevice for Net {
                 .spawn(move || handler.run());
 
             if let Err(e) = worker_result {
-                error!("failed to spawn virtio_blk worker: {}", e);
+                error!("failed to spawn virtio_net worker: {}", e);
                 return Err(ActivateError::BadActivate);
             }
-
-            return Ok(());
         }
-        Err(ActivateError::BadActivate)
+
+        Ok(())
     }
 
     fn reset(&mut self) -> Option<(Arc<VirtioInterrupt>, Vec<EventFd>)> {
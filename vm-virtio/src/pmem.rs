@@ -24,6 +24,7 @@ use super::{
     VirtioDeviceType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::{VirtioInterrupt, VirtioInterruptType};
+use vm_device::{Pausable, PauseControl, PauseWorker};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap, GuestUsize,
 };
@@ -41,6 +42,8 @@ const VIRTIO_PMEM_RESP_TYPE_EIO: u32 = 1;
 const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
 // The device has been dropped.
 const KILL_EVENT: DeviceEventT = 1;
+// The device is paused.
+const PAUSE_EVENT: DeviceEventT = 2;
 
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
@@ -159,6 +162,7 @@ struct PmemEpollHandler {
     interrupt_cb: Arc<VirtioInterrupt>,
     queue_evt: EventFd,
     kill_evt: EventFd,
+    pause: PauseWorker,
 }
 
 impl PmemEpollHandler {
@@ -233,6 +237,13 @@ impl PmemEpollHandler {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause.evt().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
 
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -274,6 +285,10 @@ impl PmemEpollHandler {
                         debug!("kill_evt received, stopping epoll loop");
                         break 'epoll;
                     }
+                    PAUSE_EVENT => {
+                        debug!("PAUSE_EVENT received, pausing epoll loop");
+                        self.pause.wait_resumed();
+                    }
                     _ => {
                         error!("Unknown event for virtio-block");
                     }
@@ -293,6 +308,7 @@ pub struct Pmem {
     config: VirtioPmemConfig,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    pause: PauseControl,
 }
 
 impl Pmem {
@@ -316,6 +332,7 @@ impl Pmem {
             config,
             queue_evts: None,
             interrupt_cb: None,
+            pause: PauseControl::new()?,
         })
     }
 }
@@ -329,6 +346,16 @@ impl Drop for Pmem {
     }
 }
 
+impl Pausable for Pmem {
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
 impl VirtioDevice for Pmem {
     fn device_type(&self) -> u32 {
         VirtioDeviceType::TYPE_PMEM as u32
@@ -444,6 +471,7 @@ impl VirtioDevice for Pmem {
                 interrupt_cb,
                 queue_evt: queue_evts.remove(0),
                 kill_evt,
+                pause: self.pause.worker(),
             };
 
             let worker_result = thread::Builder::new()
@@ -21,6 +21,9 @@ use vm_memory::{
 pub(super) const VIRTQ_DESC_F_NEXT: u16 = 0x1;
 pub(super) const VIRTQ_DESC_F_WRITE: u16 = 0x2;
 
+/// The largest queue size the virtio spec allows a device to offer.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+
 // GuestMemoryMmap::read_obj() will be used to fetch the descriptor,
 // which has an explicit constraint that the entire descriptor doesn't
 // cross the page boundary. Otherwise the descriptor may be splitted into
@@ -314,6 +317,8 @@ impl Queue {
     pub fn reset(&mut self) {
         self.ready = false;
         self.size = self.max_size;
+        self.next_avail = Wrapping(0);
+        self.next_used = Wrapping(0);
     }
 
     pub fn is_valid(&self, mem: &GuestMemoryMmap) -> bool {
@@ -440,6 +445,33 @@ impl Queue {
     pub fn go_to_previous_position(&mut self) {
         self.next_avail -= Wrapping(1);
     }
+
+    /// Resumes the queue from its used ring index, for a device restarted
+    /// behind the back of the driver. The device completed, in order, the
+    /// descriptors it took up to that index, and takes the other ones again.
+    pub fn resume_from_used_ring(&mut self, mem: &GuestMemoryMmap) {
+        match mem.read_obj::<u16>(self.used_ring.unchecked_add(2)) {
+            Ok(used_idx) => {
+                self.next_avail = Wrapping(used_idx);
+                self.next_used = Wrapping(used_idx);
+            }
+            Err(e) => error!("Failed to read the used ring index: {:?}", e),
+        }
+    }
+
+    /// Indexes of the available and used rings, as the driver and the device
+    /// last wrote them: how many buffers the driver made available, and the
+    /// device used, wrapping at 2^16. None for a queue the driver didn't set
+    /// up.
+    pub fn ring_indexes(&self, mem: &GuestMemoryMmap) -> Option<(u16, u16)> {
+        if !self.ready {
+            return None;
+        }
+        let avail_idx = mem.read_obj::<u16>(self.avail_ring.unchecked_add(2)).ok()?;
+        let used_idx = mem.read_obj::<u16>(self.used_ring.unchecked_add(2)).ok()?;
+
+        Some((avail_idx, used_idx))
+    }
 }
 
 #[cfg(test)]
@@ -873,4 +905,42 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_resume_from_used_ring() {
+        let m = &GuestMemoryMmap::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        q.add_used(m, 1, 0x1000);
+        q.add_used(m, 2, 0x1000);
+
+        // A new copy of the queue, as the transport keeps it, starts over.
+        let mut q = vq.create_queue();
+        q.resume_from_used_ring(m);
+        assert_eq!(q.next_avail.0, 2);
+        assert_eq!(q.next_used.0, 2);
+
+        q.add_used(m, 3, 0x1000);
+        assert_eq!(vq.used.idx.get(), 3);
+        assert_eq!(vq.used.ring[2].get().id, 3);
+
+        q.reset();
+        assert_eq!(q.next_avail.0, 0);
+        assert_eq!(q.next_used.0, 0);
+    }
+
+    #[test]
+    fn test_ring_indexes() {
+        let m = &GuestMemoryMmap::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        vq.avail.idx.set(3);
+        q.add_used(m, 1, 0x1000);
+        assert_eq!(q.ring_indexes(m), Some((3, 1)));
+
+        q.ready = false;
+        assert_eq!(q.ring_indexes(m), None);
+    }
 }
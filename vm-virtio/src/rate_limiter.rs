@@ -0,0 +1,755 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+//
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Token bucket based rate limiting for virtio device I/O.
+//!
+//! A `RateLimiter` is made of up to two token buckets: one accounting for
+//! bandwidth (bytes) and one accounting for operations. Each bucket is
+//! described by its size, an optional one time burst allowing an initial
+//! amount of extra tokens, and a refill time in milliseconds, which is the
+//! time needed to refill a completely empty bucket.
+//!
+//! When a device runs out of tokens, the rate limiter arms a timer. The
+//! device is expected to add the rate limiter file descriptor to its epoll
+//! loop, and to resume processing its queues once the timer expired.
+//!
+//! A rate limiter can also hold a share of a `BandwidthGroup`, whose
+//! bandwidth is split between the devices of the group busy at the time, in
+//! proportion to their weights.
+//!
+//! The token buckets of a rate limiter and of its clones can be replaced
+//! while the device runs, through their `RateLimiterBuckets`.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Interval at which the refill timer will run when the limiter is blocked.
+const REFILL_TIMER_INTERVAL_MS: u64 = 100;
+
+const NANOSEC_IN_ONE_MILLISEC: u64 = 1_000_000;
+
+/// The kind of tokens a rate limiter can consume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenType {
+    /// Token type used for bandwidth limiting.
+    Bytes,
+    /// Token type used for operations/second limiting.
+    Ops,
+}
+
+/// Token bucket implementation.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    // Bucket defining traits.
+    size: u64,
+    // Initial burst size (number of free initial tokens, that can be consumed
+    // at most once).
+    one_time_burst: Option<u64>,
+    // Complete refill time in milliseconds.
+    refill_time: u64,
+
+    // Internal state descriptors.
+    budget: u64,
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a token bucket which holds up to `size` tokens and is
+    /// completely refilled in `refill_time_ms` milliseconds.
+    ///
+    /// Returns `None` if either the size or the refill time is zero, as such a
+    /// bucket would not limit anything.
+    pub fn new(size: u64, one_time_burst: Option<u64>, refill_time_ms: u64) -> Option<Self> {
+        if size == 0 || refill_time_ms == 0 {
+            return None;
+        }
+
+        Some(TokenBucket {
+            size,
+            one_time_burst,
+            refill_time: refill_time_ms,
+            // Start with a full bucket.
+            budget: size,
+            last_update: Instant::now(),
+        })
+    }
+
+    /// Attempts to consume `tokens` from the bucket, starting with the one
+    /// time burst budget. Returns `true` if the tokens were available, `false`
+    /// otherwise.
+    pub fn reduce(&mut self, mut tokens: u64) -> bool {
+        // First things first: consume the one-time-burst budget.
+        if let Some(otb) = self.one_time_burst.as_mut() {
+            if *otb > 0 {
+                if *otb >= tokens {
+                    *otb -= tokens;
+                    self.last_update = Instant::now();
+                    return true;
+                }
+                tokens -= *otb;
+                *otb = 0;
+            }
+        }
+
+        self.refill();
+
+        if tokens > self.budget {
+            // A request bigger than the bucket itself can never be fulfilled,
+            // so we let it go through once the bucket is full, leaving an
+            // empty bucket behind.
+            if tokens > self.size && self.budget == self.size {
+                self.budget = 0;
+                return true;
+            }
+            return false;
+        }
+
+        self.budget -= tokens;
+        true
+    }
+
+    /// Adds back `tokens` to the bucket, without going over its size.
+    pub fn replenish(&mut self, tokens: u64) {
+        self.budget = std::cmp::min(self.budget + tokens, self.size);
+    }
+
+    // Refills the bucket based on the time elapsed since the last update.
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_update);
+        let elapsed_ns = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+        let refill_time_ns = self.refill_time * NANOSEC_IN_ONE_MILLISEC;
+
+        // Compute the number of tokens generated over the elapsed period,
+        // using 128 bits arithmetic to avoid any overflow.
+        let tokens = (u128::from(elapsed_ns) * u128::from(self.size)) / u128::from(refill_time_ns);
+        if tokens > 0 {
+            self.budget = std::cmp::min(
+                self.size,
+                self.budget
+                    .saturating_add(tokens.min(u128::from(u64::max_value())) as u64),
+            );
+            self.last_update = now;
+        }
+    }
+
+    /// Returns the capacity of the token bucket.
+    pub fn capacity(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the remaining one time burst budget.
+    pub fn one_time_burst(&self) -> u64 {
+        self.one_time_burst.unwrap_or(0)
+    }
+
+    /// Returns the time in milliseconds required to completely fill the bucket.
+    pub fn refill_time_ms(&self) -> u64 {
+        self.refill_time
+    }
+
+    /// Returns the current budget (one time burst allowance notwithstanding).
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+}
+
+struct GroupMember {
+    weight: u32,
+    // Bytes left for the period, negative for a member which went over it.
+    budget: i64,
+    // Asked for bytes during the period.
+    busy: bool,
+}
+
+struct GroupState {
+    // Bytes per second.
+    bandwidth: u64,
+    period_start: Instant,
+    members: Vec<GroupMember>,
+}
+
+impl GroupState {
+    fn period_budget(&self) -> u64 {
+        self.bandwidth * REFILL_TIMER_INTERVAL_MS / 1000
+    }
+
+    // Splits the budget of the new period between the members busy during
+    // the last one. The bytes a member didn't use aren't carried over, the
+    // ones it went over by are. An idle member gets the share it would have
+    // if it was busy as well, for its first requests not to wait for the
+    // next period.
+    fn refill(&mut self) {
+        let period_budget = self.period_budget();
+        let busy_weights: u64 = self
+            .members
+            .iter()
+            .filter(|member| member.busy)
+            .map(|member| u64::from(member.weight))
+            .sum();
+
+        for member in self.members.iter_mut() {
+            let weight = u64::from(member.weight);
+            let weights = if member.busy {
+                busy_weights
+            } else {
+                busy_weights + weight
+            };
+            let share = period_budget * weight / weights;
+            member.budget = std::cmp::min(member.budget, 0) + share as i64;
+            member.busy = false;
+        }
+    }
+}
+
+/// Bandwidth, in bytes per second, shared by several devices in proportion
+/// to their weights. The budget of the group is split every refill timer
+/// interval between its members busy during the previous one, so that an
+/// idle member doesn't hold bandwidth the other ones could use.
+pub struct BandwidthGroup {
+    state: Mutex<GroupState>,
+}
+
+impl BandwidthGroup {
+    pub fn new(bandwidth: u64) -> Arc<Self> {
+        Arc::new(BandwidthGroup {
+            state: Mutex::new(GroupState {
+                bandwidth,
+                period_start: Instant::now(),
+                members: Vec::new(),
+            }),
+        })
+    }
+
+    /// Adds a member of weight `weight` to the group, returning its share.
+    pub fn join(group: &Arc<Self>, weight: u32) -> BandwidthShare {
+        let weight = std::cmp::max(weight, 1);
+        let mut state = group.state.lock().unwrap();
+        let weights: u64 = state
+            .members
+            .iter()
+            .map(|member| u64::from(member.weight))
+            .sum::<u64>()
+            + u64::from(weight);
+        let budget = state.period_budget() * u64::from(weight) / weights;
+        state.members.push(GroupMember {
+            weight,
+            budget: budget as i64,
+            busy: false,
+        });
+
+        BandwidthShare {
+            group: group.clone(),
+            member: state.members.len() - 1,
+        }
+    }
+}
+
+/// The share of a member of a bandwidth group.
+#[derive(Clone)]
+pub struct BandwidthShare {
+    group: Arc<BandwidthGroup>,
+    member: usize,
+}
+
+impl BandwidthShare {
+    pub fn weight(&self) -> u32 {
+        self.group.state.lock().unwrap().members[self.member].weight
+    }
+
+    /// Changes the weight of the member, from the next refill timer interval
+    /// on. A zero weight is taken as 1.
+    pub fn set_weight(&self, weight: u32) {
+        self.group.state.lock().unwrap().members[self.member].weight = std::cmp::max(weight, 1);
+    }
+
+    // Whether the member can transfer `bytes`. A member with a positive
+    // budget left can go over it, the bytes being taken from its next
+    // shares, for requests larger than a share to go through.
+    fn consume(&self, bytes: u64) -> bool {
+        let mut state = self.group.state.lock().unwrap();
+        state.members[self.member].busy = true;
+
+        let now = Instant::now();
+        if now.duration_since(state.period_start) >= Duration::from_millis(REFILL_TIMER_INTERVAL_MS)
+        {
+            state.refill();
+            state.members[self.member].busy = true;
+            state.period_start = now;
+        }
+
+        let member = &mut state.members[self.member];
+        if member.budget <= 0 {
+            return false;
+        }
+        member.budget -= bytes as i64;
+
+        true
+    }
+
+    fn replenish(&self, bytes: u64) {
+        self.group.state.lock().unwrap().members[self.member].budget += bytes as i64;
+    }
+}
+
+/// The token buckets of a rate limiter and of its clones, which another
+/// thread can replace, the rate limiters taking the new buckets before they
+/// next consume tokens.
+#[derive(Clone)]
+pub struct RateLimiterBuckets {
+    // Incremented each time the buckets are replaced.
+    version: Arc<AtomicU64>,
+    buckets: Arc<Mutex<(Option<TokenBucket>, Option<TokenBucket>)>>,
+}
+
+impl RateLimiterBuckets {
+    fn new(bandwidth: Option<TokenBucket>, ops: Option<TokenBucket>) -> Self {
+        RateLimiterBuckets {
+            version: Arc::new(AtomicU64::new(0)),
+            buckets: Arc::new(Mutex::new((bandwidth, ops))),
+        }
+    }
+
+    /// Replaces the token buckets, described as `RateLimiter::new()` takes
+    /// them. The new buckets start full.
+    pub fn set(
+        &self,
+        bytes_total_capacity: u64,
+        bytes_one_time_burst: Option<u64>,
+        bytes_complete_refill_time_ms: u64,
+        ops_total_capacity: u64,
+        ops_one_time_burst: Option<u64>,
+        ops_complete_refill_time_ms: u64,
+    ) {
+        *self.buckets.lock().unwrap() = (
+            TokenBucket::new(
+                bytes_total_capacity,
+                bytes_one_time_burst,
+                bytes_complete_refill_time_ms,
+            ),
+            TokenBucket::new(
+                ops_total_capacity,
+                ops_one_time_burst,
+                ops_complete_refill_time_ms,
+            ),
+        );
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Rate limiter accounting for bandwidth and/or operations.
+///
+/// A rate limiter with no token bucket configured, nor bandwidth share,
+/// never blocks.
+pub struct RateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    bandwidth_share: Option<BandwidthShare>,
+    buckets: RateLimiterBuckets,
+    // Version of the buckets the rate limiter took last.
+    buckets_version: u64,
+
+    timer_fd: RawFd,
+    // Internal flag that quickly determines timer state.
+    timer_active: bool,
+}
+
+impl RateLimiter {
+    /// Creates a new rate limiter. Each token bucket is described by its
+    /// size, optional one time burst and refill time in milliseconds. A zero
+    /// size or refill time disables the corresponding bucket.
+    pub fn new(
+        bytes_total_capacity: u64,
+        bytes_one_time_burst: Option<u64>,
+        bytes_complete_refill_time_ms: u64,
+        ops_total_capacity: u64,
+        ops_one_time_burst: Option<u64>,
+        ops_complete_refill_time_ms: u64,
+    ) -> io::Result<Self> {
+        let bandwidth = TokenBucket::new(
+            bytes_total_capacity,
+            bytes_one_time_burst,
+            bytes_complete_refill_time_ms,
+        );
+        let ops = TokenBucket::new(
+            ops_total_capacity,
+            ops_one_time_burst,
+            ops_complete_refill_time_ms,
+        );
+
+        let buckets = RateLimiterBuckets::new(bandwidth.clone(), ops.clone());
+
+        Ok(RateLimiter {
+            bandwidth,
+            ops,
+            bandwidth_share: None,
+            buckets,
+            buckets_version: 0,
+            timer_fd: Self::create_timer_fd()?,
+            timer_active: false,
+        })
+    }
+
+    /// Limits the bandwidth to the share of a bandwidth group as well.
+    pub fn with_bandwidth_share(self, bandwidth_share: BandwidthShare) -> Self {
+        RateLimiter {
+            bandwidth_share: Some(bandwidth_share),
+            ..self
+        }
+    }
+
+    /// Creates a new rate limiter with the same token buckets, but relying
+    /// on its own refill timer. This lets a device hand over a rate limiter
+    /// to its worker thread every time it gets activated.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(RateLimiter {
+            bandwidth: self.bandwidth.clone(),
+            ops: self.ops.clone(),
+            bandwidth_share: self.bandwidth_share.clone(),
+            buckets: self.buckets.clone(),
+            buckets_version: self.buckets_version,
+            timer_fd: Self::create_timer_fd()?,
+            timer_active: false,
+        })
+    }
+
+    /// The token buckets of the rate limiter, shared with its clones.
+    pub fn buckets(&self) -> RateLimiterBuckets {
+        self.buckets.clone()
+    }
+
+    // Takes the token buckets replaced since the rate limiter took them last.
+    fn update_buckets(&mut self) {
+        let version = self.buckets.version.load(Ordering::SeqCst);
+        if version != self.buckets_version {
+            let (bandwidth, ops) = self.buckets.buckets.lock().unwrap().clone();
+            self.bandwidth = bandwidth;
+            self.ops = ops;
+            self.buckets_version = version;
+        }
+    }
+
+    /// Attempts to consume `tokens` of type `token_type`. Returns `true` if
+    /// the operation can go through. Otherwise the refill timer is armed and
+    /// `false` is returned, meaning the caller must retry once the timer
+    /// expired.
+    pub fn consume(&mut self, tokens: u64, token_type: TokenType) -> bool {
+        // If the timer is active, we are already blocked.
+        if self.timer_active {
+            return false;
+        }
+        self.update_buckets();
+
+        let token_bucket = match token_type {
+            TokenType::Bytes => self.bandwidth.as_mut(),
+            TokenType::Ops => self.ops.as_mut(),
+        };
+
+        let mut blocked = false;
+        if let Some(bucket) = token_bucket {
+            blocked = !bucket.reduce(tokens);
+        }
+        if !blocked && token_type == TokenType::Bytes {
+            if let Some(share) = &self.bandwidth_share {
+                if !share.consume(tokens) {
+                    if let Some(bucket) = self.bandwidth.as_mut() {
+                        bucket.replenish(tokens);
+                    }
+                    blocked = true;
+                }
+            }
+        }
+
+        if blocked {
+            if let Err(e) = self.activate_timer(REFILL_TIMER_INTERVAL_MS) {
+                error!("Failed to arm rate limiter timer: {:?}", e);
+            }
+            return false;
+        }
+
+        true
+    }
+
+    /// Gives back `tokens` of type `token_type`. This is useful when an
+    /// operation consumed tokens of one type but got blocked on the other one.
+    pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
+        let token_bucket = match token_type {
+            TokenType::Bytes => self.bandwidth.as_mut(),
+            TokenType::Ops => self.ops.as_mut(),
+        };
+
+        if let Some(bucket) = token_bucket {
+            bucket.replenish(tokens);
+        }
+        if token_type == TokenType::Bytes {
+            if let Some(share) = &self.bandwidth_share {
+                share.replenish(tokens);
+            }
+        }
+    }
+
+    /// Returns `true` if the rate limiter is currently blocked.
+    pub fn is_blocked(&self) -> bool {
+        self.timer_active
+    }
+
+    /// Must be called when the rate limiter file descriptor becomes readable.
+    /// It consumes the timer expiration and unblocks the rate limiter.
+    pub fn event_handler(&mut self) -> io::Result<()> {
+        let mut expirations = [0u8; 8];
+        // Safe because we provide a valid buffer of the expected size and we
+        // check the return value.
+        let ret = unsafe {
+            libc::read(
+                self.timer_fd,
+                expirations.as_mut_ptr() as *mut libc::c_void,
+                expirations.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.timer_active = false;
+
+        Ok(())
+    }
+
+    fn create_timer_fd() -> io::Result<RawFd> {
+        // Safe because this doesn't modify any memory and we check the return
+        // value.
+        let timer_fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if timer_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(timer_fd)
+    }
+
+    fn activate_timer(&mut self, timeout_ms: u64) -> io::Result<()> {
+        let timeout = Duration::from_millis(timeout_ms);
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: timeout.as_secs() as libc::time_t,
+                tv_nsec: timeout.subsec_nanos() as libc::c_long,
+            },
+        };
+
+        // Safe because the timer fd is valid and we check the return value.
+        let ret = unsafe { libc::timerfd_settime(self.timer_fd, 0, &spec, std::ptr::null_mut()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.timer_active = true;
+
+        Ok(())
+    }
+}
+
+impl AsRawFd for RateLimiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_fd
+    }
+}
+
+impl Drop for RateLimiter {
+    fn drop(&mut self) {
+        // Safe because we own the timer fd.
+        unsafe {
+            libc::close(self.timer_fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_token_bucket_creation() {
+        assert!(TokenBucket::new(0, None, 100).is_none());
+        assert!(TokenBucket::new(100, None, 0).is_none());
+
+        let tb = TokenBucket::new(1000, Some(10), 100).unwrap();
+        assert_eq!(tb.capacity(), 1000);
+        assert_eq!(tb.one_time_burst(), 10);
+        assert_eq!(tb.refill_time_ms(), 100);
+        assert_eq!(tb.budget(), 1000);
+    }
+
+    #[test]
+    fn test_token_bucket_reduce() {
+        let mut tb = TokenBucket::new(1000, None, 1000).unwrap();
+        assert!(tb.reduce(600));
+        assert!(!tb.reduce(600));
+        thread::sleep(Duration::from_millis(300));
+        assert!(tb.reduce(600));
+
+        // Oversized requests only go through on a full bucket.
+        let mut tb = TokenBucket::new(100, None, 1000).unwrap();
+        assert!(tb.reduce(1000));
+        assert_eq!(tb.budget(), 0);
+        assert!(!tb.reduce(1000));
+    }
+
+    #[test]
+    fn test_token_bucket_one_time_burst() {
+        let mut tb = TokenBucket::new(100, Some(50), 1000).unwrap();
+        assert!(tb.reduce(40));
+        assert_eq!(tb.one_time_burst(), 10);
+        assert_eq!(tb.budget(), 100);
+        assert!(tb.reduce(60));
+        assert_eq!(tb.one_time_burst(), 0);
+        assert_eq!(tb.budget(), 50);
+    }
+
+    #[test]
+    fn test_rate_limiter_unlimited() {
+        let mut l = RateLimiter::new(0, None, 0, 0, None, 0).unwrap();
+        for _ in 0..100 {
+            assert!(l.consume(u64::max_value(), TokenType::Bytes));
+            assert!(l.consume(u64::max_value(), TokenType::Ops));
+        }
+        assert!(!l.is_blocked());
+    }
+
+    #[test]
+    fn test_rate_limiter_blocking() {
+        let mut l = RateLimiter::new(1000, None, 100, 10, None, 100).unwrap();
+        assert!(l.consume(1000, TokenType::Bytes));
+        assert!(!l.consume(100, TokenType::Bytes));
+        assert!(l.is_blocked());
+        // Everything is blocked until the timer fires.
+        assert!(!l.consume(1, TokenType::Ops));
+
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+        l.event_handler().unwrap();
+        assert!(!l.is_blocked());
+        assert!(l.consume(100, TokenType::Bytes));
+        assert!(l.consume(1, TokenType::Ops));
+    }
+
+    #[test]
+    fn test_rate_limiter_manual_replenish() {
+        let mut l = RateLimiter::new(1000, None, 1000, 0, None, 0).unwrap();
+        assert!(l.consume(1000, TokenType::Bytes));
+        l.manual_replenish(500, TokenType::Bytes);
+        assert!(l.consume(500, TokenType::Bytes));
+    }
+
+    #[test]
+    fn test_rate_limiter_try_clone() {
+        let mut l = RateLimiter::new(1000, None, 1000, 0, None, 0).unwrap();
+        assert!(l.consume(1000, TokenType::Bytes));
+        assert!(!l.consume(1, TokenType::Bytes));
+
+        let c = l.try_clone().unwrap();
+        assert_ne!(c.as_raw_fd(), l.as_raw_fd());
+        assert!(!c.is_blocked());
+    }
+
+    #[test]
+    fn test_rate_limiter_buckets() {
+        let mut l = RateLimiter::new(1000, None, 1000, 0, None, 0).unwrap();
+        let mut c = l.try_clone().unwrap();
+        assert!(l.consume(1000, TokenType::Bytes));
+        assert!(!l.consume(1, TokenType::Bytes));
+
+        // Both the rate limiter and its clone take the new buckets.
+        l.buckets().set(0, None, 0, 10, None, 1000);
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+        l.event_handler().unwrap();
+        assert!(l.consume(u64::max_value(), TokenType::Bytes));
+        assert!(l.consume(10, TokenType::Ops));
+        assert!(!l.consume(1, TokenType::Ops));
+        assert!(c.consume(u64::max_value(), TokenType::Bytes));
+        assert!(c.consume(10, TokenType::Ops));
+    }
+
+    #[test]
+    fn test_bandwidth_group_weights() {
+        // 1000 bytes per refill timer interval.
+        let group = BandwidthGroup::new(10_000);
+        let a = BandwidthGroup::join(&group, 100);
+        let b = BandwidthGroup::join(&group, 300);
+        assert_eq!(b.weight(), 300);
+
+        // Both members are busy during the first interval.
+        a.consume(1);
+        b.consume(1);
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+
+        assert!(a.consume(250));
+        assert!(!a.consume(1));
+        assert!(b.consume(750));
+        assert!(!b.consume(1));
+    }
+
+    #[test]
+    fn test_bandwidth_group_idle_member() {
+        let group = BandwidthGroup::new(10_000);
+        let a = BandwidthGroup::join(&group, 100);
+        let b = BandwidthGroup::join(&group, 100);
+
+        // Only the first member is busy, it gets the whole bandwidth.
+        a.consume(1);
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+        assert!(a.consume(1000));
+        assert!(!a.consume(1));
+
+        // The idle member can start right away with its share.
+        assert!(b.consume(500));
+        assert!(!b.consume(1));
+    }
+
+    #[test]
+    fn test_bandwidth_group_debt() {
+        let group = BandwidthGroup::new(10_000);
+        let a = BandwidthGroup::join(&group, 100);
+
+        // A request larger than the share goes through, and is paid back over
+        // the next intervals.
+        assert!(a.consume(2500));
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+        assert!(!a.consume(1));
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+        assert!(a.consume(1));
+    }
+
+    #[test]
+    fn test_rate_limiter_bandwidth_share() {
+        let group = BandwidthGroup::new(10_000);
+        let share = BandwidthGroup::join(&group, 100);
+        let mut l = RateLimiter::new(0, None, 0, 0, None, 0)
+            .unwrap()
+            .with_bandwidth_share(share.clone());
+        assert!(l.consume(1000, TokenType::Bytes));
+        assert!(!l.consume(1, TokenType::Bytes));
+        assert!(l.is_blocked());
+
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS + 10));
+        l.event_handler().unwrap();
+        share.set_weight(200);
+        assert_eq!(share.weight(), 200);
+        assert!(l.consume(1000, TokenType::Bytes));
+        // Operations are not shared.
+        assert!(l.consume(u64::max_value(), TokenType::Ops));
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Audit of the guest driver of a virtio device, telling why a driver and a
+//! device don't get along: the writes of the driver to the config space of
+//! the device, the features it acked, and the steps of the negotiation it
+//! got wrong.
+//!
+//! The transports record the audit as the driver goes, once it's enabled,
+//! the last `AUDIT_LOG_SIZE` records being kept.
+
+use crate::{
+    ActivateError, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
+    VIRTIO_F_VERSION_1,
+};
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Records kept by the audit of a device.
+pub const AUDIT_LOG_SIZE: usize = 256;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuditEvent {
+    /// The driver wrote `data` at `offset` of the device config space.
+    ConfigWrite { offset: u64, data: Vec<u8> },
+    /// The driver acked features the device doesn't offer, which the device
+    /// ignores.
+    UnofferedFeatures(u64),
+    /// The driver set FEATURES_OK without acking VIRTIO_F_VERSION_1, as a
+    /// legacy driver does.
+    MissingVersion1,
+    /// The driver set DRIVER_OK without FEATURES_OK.
+    DriverOkWithoutFeaturesOk,
+    /// The driver gave up on the device, setting FAILED.
+    DriverFailed,
+    /// The device could not be activated once the driver set it up.
+    ActivateFailed(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub event: AuditEvent,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DeviceAudit {
+    /// Features the device offers.
+    pub device_features: u64,
+    /// Features the driver acked, the ones the device doesn't offer included.
+    pub driver_features: u64,
+    /// Device status the driver last wrote.
+    pub driver_status: u32,
+    /// The records, oldest first.
+    pub records: VecDeque<AuditRecord>,
+    /// Records dropped for newer ones.
+    pub dropped: u64,
+}
+
+impl DeviceAudit {
+    pub fn new(device_features: u64) -> Self {
+        DeviceAudit {
+            device_features,
+            ..Default::default()
+        }
+    }
+
+    fn record(&mut self, event: AuditEvent) {
+        if self.records.len() == AUDIT_LOG_SIZE {
+            self.records.pop_front();
+            self.dropped += 1;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.records.push_back(AuditRecord { timestamp, event });
+    }
+
+    pub fn config_write(&mut self, offset: u64, data: &[u8]) {
+        self.record(AuditEvent::ConfigWrite {
+            offset,
+            data: data.to_vec(),
+        });
+    }
+
+    /// The driver acked the features of the page, 32 of them per page.
+    pub fn ack_features(&mut self, page: u32, value: u32) {
+        let shift = match page {
+            0 => 0,
+            1 => 32,
+            _ => return,
+        };
+        self.driver_features &= !(0xffff_ffff_u64 << shift);
+        self.driver_features |= u64::from(value) << shift;
+
+        let unoffered = (u64::from(value) << shift) & !self.device_features;
+        if unoffered != 0 {
+            self.record(AuditEvent::UnofferedFeatures(unoffered));
+        }
+    }
+
+    /// The driver wrote the device status.
+    pub fn set_status(&mut self, status: u32) {
+        let set = status & !self.driver_status;
+        self.driver_status = status;
+
+        if status == DEVICE_INIT {
+            self.driver_features = 0;
+            return;
+        }
+        if set & DEVICE_FEATURES_OK != 0 && self.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+            self.record(AuditEvent::MissingVersion1);
+        }
+        if set & DEVICE_DRIVER_OK != 0 && status & DEVICE_FEATURES_OK == 0 {
+            self.record(AuditEvent::DriverOkWithoutFeaturesOk);
+        }
+        if set & DEVICE_FAILED != 0 {
+            self.record(AuditEvent::DriverFailed);
+        }
+    }
+
+    pub fn activate_failed(&mut self, e: &ActivateError) {
+        self.record(AuditEvent::ActivateFailed(format!("{:?}", e)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DEVICE_ACKNOWLEDGE, DEVICE_DRIVER};
+
+    fn events(audit: &DeviceAudit) -> Vec<AuditEvent> {
+        audit.records.iter().map(|r| r.event.clone()).collect()
+    }
+
+    #[test]
+    fn test_feature_negotiation() {
+        let mut audit = DeviceAudit::new(1 << VIRTIO_F_VERSION_1 | 0x3);
+        let driver = DEVICE_ACKNOWLEDGE | DEVICE_DRIVER;
+
+        // A legacy driver, acking a feature the device doesn't offer.
+        audit.set_status(driver);
+        audit.ack_features(0, 0x5);
+        audit.set_status(driver | DEVICE_FEATURES_OK);
+        audit.set_status(driver | DEVICE_FEATURES_OK | DEVICE_FAILED);
+        assert_eq!(
+            events(&audit),
+            [
+                AuditEvent::UnofferedFeatures(0x4),
+                AuditEvent::MissingVersion1,
+                AuditEvent::DriverFailed
+            ]
+        );
+
+        // The reset clears the acked features, and a modern driver goes
+        // through.
+        audit.set_status(DEVICE_INIT);
+        assert_eq!(audit.driver_features, 0);
+        audit.set_status(driver);
+        audit.ack_features(0, 0x1);
+        audit.ack_features(1, 0x1);
+        audit.set_status(driver | DEVICE_FEATURES_OK);
+        audit.set_status(driver | DEVICE_FEATURES_OK | DEVICE_DRIVER_OK);
+        assert_eq!(audit.driver_features, 1 << VIRTIO_F_VERSION_1 | 0x1);
+        assert_eq!(audit.records.len(), 3);
+    }
+
+    #[test]
+    fn test_audit_log_size() {
+        let mut audit = DeviceAudit::new(0);
+        for offset in 0..AUDIT_LOG_SIZE as u64 + 2 {
+            audit.config_write(offset, &[0]);
+        }
+        assert_eq!(audit.records.len(), AUDIT_LOG_SIZE);
+        assert_eq!(audit.dropped, 2);
+        assert_eq!(
+            audit.records[0].event,
+            AuditEvent::ConfigWrite {
+                offset: 2,
+                data: vec![0]
+            }
+        );
+    }
+}
@@ -8,13 +8,17 @@ use std::sync::{Arc, RwLock};
 use byteorder::{ByteOrder, LittleEndian};
 use libc::EFD_NONBLOCK;
 
-use crate::transport::{VirtioTransport, NOTIFY_REG_OFFSET};
+use crate::transport::{
+    notify_queue, restart_device, ring_indexes, DeviceAudit, RestartError, VirtioTransport,
+    NOTIFY_REG_OFFSET,
+};
 use crate::{
     Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER,
     DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
     INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING,
 };
 use devices::{BusDevice, Interrupt};
+use vm_device::Pausable;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
 
@@ -51,6 +55,7 @@ pub struct MmioDevice {
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
     mem: Option<Arc<RwLock<GuestMemoryMmap>>>,
+    audit: Option<DeviceAudit>,
 }
 
 impl MmioDevice {
@@ -81,6 +86,7 @@ impl MmioDevice {
             queues,
             queue_evts,
             mem: Some(mem),
+            audit: None,
         })
     }
 
@@ -141,7 +147,48 @@ impl MmioDevice {
     }
 }
 
+impl Pausable for MmioDevice {
+    fn pause(&mut self) -> std::io::Result<()> {
+        self.device.pause()
+    }
+
+    fn resume(&mut self) -> std::io::Result<()> {
+        self.device.resume()
+    }
+}
+
 impl VirtioTransport for MmioDevice {
+    fn restart(&mut self) -> std::result::Result<(), RestartError> {
+        if !self.device_activated {
+            return Err(RestartError::NotActivated);
+        }
+        let mem = self.mem.as_ref().ok_or(RestartError::NotActivated)?;
+
+        let result = restart_device(self.device.as_mut(), mem, &mut self.queues);
+        if let Err(RestartError::Activate(_)) = result {
+            self.device_activated = false;
+        }
+        result
+    }
+
+    fn ring_indexes(&self) -> Vec<(u16, u16)> {
+        match self.mem.as_ref() {
+            Some(mem) if self.device_activated => ring_indexes(mem, &self.queues),
+            _ => Vec::new(),
+        }
+    }
+
+    fn enable_audit(&mut self) {
+        // VIRTIO_F_VERSION_1 is offered on top of the device features.
+        let device_features =
+            u64::from(self.device.features(0)) | (u64::from(self.device.features(1)) | 0x1) << 32;
+        self.audit = Some(DeviceAudit::new(device_features));
+    }
+
+    fn audit(&self) -> Option<DeviceAudit> {
+        self.audit.clone()
+    }
+
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)> {
         let notify_base = base_addr + u64::from(NOTIFY_REG_OFFSET);
         self.queue_evts()
@@ -202,16 +249,27 @@ impl BusDevice for MmioDevice {
                 let v = LittleEndian::read_u32(data);
                 match offset {
                     0x14 => self.features_select = v,
-                    0x20 => self.device.ack_features(self.acked_features_select, v),
+                    0x20 => {
+                        if let Some(audit) = self.audit.as_mut() {
+                            audit.ack_features(self.acked_features_select, v);
+                        }
+                        self.device.ack_features(self.acked_features_select, v);
+                    }
                     0x24 => self.acked_features_select = v,
                     0x30 => self.queue_select = v,
                     0x38 => mut_q = self.with_queue_mut(|q| q.size = v as u16),
                     0x44 => mut_q = self.with_queue_mut(|q| q.ready = v == 1),
+                    0x50 => notify_queue(&self.queue_evts, v as usize),
                     0x64 => {
                         self.interrupt_status
                             .fetch_and(!(v as usize), Ordering::SeqCst);
                     }
-                    0x70 => self.driver_status = v,
+                    0x70 => {
+                        if let Some(audit) = self.audit.as_mut() {
+                            audit.set_status(v);
+                        }
+                        self.driver_status = v;
+                    }
                     0x80 => mut_q = self.with_queue_mut(|q| lo(&mut q.desc_table, v)),
                     0x84 => mut_q = self.with_queue_mut(|q| hi(&mut q.desc_table, v)),
                     0x90 => mut_q = self.with_queue_mut(|q| lo(&mut q.avail_ring, v)),
@@ -224,7 +282,12 @@ impl BusDevice for MmioDevice {
                     }
                 }
             }
-            0x100..=0xfff => return self.device.write_config(offset - 0x100, data),
+            0x100..=0xfff => {
+                if let Some(audit) = self.audit.as_mut() {
+                    audit.config_write(offset - 0x100, data);
+                }
+                return self.device.write_config(offset - 0x100, data);
+            }
             _ => {
                 warn!(
                     "invalid virtio mmio write: 0x{:x}:0x{:x}",
@@ -243,15 +306,20 @@ impl BusDevice for MmioDevice {
             if let Some(interrupt_cb) = self.interrupt_cb.take() {
                 if self.mem.is_some() {
                     let mem = self.mem.as_ref().unwrap().clone();
-                    self.device
-                        .activate(
-                            mem,
-                            interrupt_cb,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
-                    self.device_activated = true;
+                    match self.device.activate(
+                        mem,
+                        interrupt_cb,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    ) {
+                        Ok(()) => self.device_activated = true,
+                        Err(e) => {
+                            error!("Failed to activate device: {:?}", e);
+                            if let Some(audit) = self.audit.as_mut() {
+                                audit.activate_failed(&e);
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -2,7 +2,14 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::{ActivateError, Queue, VirtioDevice, VirtioInterruptType};
+use std::io;
+use std::sync::{Arc, RwLock};
+use vm_device::Pausable;
+use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
+mod audit;
+pub use audit::{AuditEvent, AuditRecord, DeviceAudit, AUDIT_LOG_SIZE};
 #[cfg(feature = "pci_support")]
 mod pci_common_config;
 #[cfg(feature = "pci_support")]
@@ -19,6 +26,92 @@ pub use mmio::MmioDevice;
 #[cfg(feature = "mmio_support")]
 pub const NOTIFY_REG_OFFSET: u32 = 0x50;
 
-pub trait VirtioTransport {
+#[derive(Debug)]
+pub enum RestartError {
+    /// The guest driver didn't set the device up.
+    NotActivated,
+    /// The device can't be reset.
+    ResetUnsupported,
+    /// The device could not be activated again.
+    Activate(ActivateError),
+    /// The device or the driver could not be notified about the queues.
+    Notify(io::Error),
+}
+
+/// Transport of a virtio device, pausing the device it carries.
+pub trait VirtioTransport: Pausable {
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)>;
+
+    /// Resets the device and activates it again on the queues the driver set
+    /// up, without the driver knowing about it, e.g. to get a device whose
+    /// backend got stuck going again.
+    fn restart(&mut self) -> Result<(), RestartError>;
+
+    /// Indexes of the available and used rings of each of the queues the
+    /// driver set up, empty until it activated the device.
+    fn ring_indexes(&self) -> Vec<(u16, u16)>;
+
+    /// Starts auditing the guest driver of the device, from its next write
+    /// on.
+    fn enable_audit(&mut self);
+
+    /// The audit of the guest driver, None unless it was enabled.
+    fn audit(&self) -> Option<DeviceAudit>;
+}
+
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+fn ring_indexes(mem: &Arc<RwLock<GuestMemoryMmap>>, queues: &[Queue]) -> Vec<(u16, u16)> {
+    let mem = mem.read().unwrap();
+    queues
+        .iter()
+        .filter_map(|queue| queue.ring_indexes(&mem))
+        .collect()
+}
+
+// The guest notifications go to the ioeventfds of the queues, consumed by
+// the device threads without the vCPU exiting to the VMM. The ones reaching
+// the transport are the ones the hypervisor didn't take, when registering
+// an ioeventfd failed, and are passed on the same way.
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+fn notify_queue(queue_evts: &[EventFd], index: usize) {
+    match queue_evts.get(index) {
+        Some(queue_evt) => {
+            if let Err(e) = queue_evt.write(1) {
+                warn!("Failed to notify queue {}: {}", index, e);
+            }
+        }
+        None => warn!("Notification of a queue the device doesn't have: {}", index),
+    }
+}
+
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+fn restart_device(
+    device: &mut dyn VirtioDevice,
+    mem: &Arc<RwLock<GuestMemoryMmap>>,
+    queues: &mut [Queue],
+) -> Result<(), RestartError> {
+    let (interrupt_cb, queue_evts) = device.reset().ok_or(RestartError::ResetUnsupported)?;
+
+    for queue in queues.iter_mut() {
+        queue.resume_from_used_ring(&mem.read().unwrap());
+    }
+
+    // The device looks at the buffers made available while it was stuck,
+    // and the driver at the ones it may not have been notified about.
+    for queue_evt in queue_evts.iter() {
+        queue_evt.write(1).map_err(RestartError::Notify)?;
+    }
+    device
+        .activate(
+            mem.clone(),
+            interrupt_cb.clone(),
+            queues.to_vec(),
+            queue_evts,
+        )
+        .map_err(RestartError::Activate)?;
+    for queue in queues.iter() {
+        interrupt_cb(&VirtioInterruptType::Queue, Some(queue)).map_err(RestartError::Notify)?;
+    }
+
+    Ok(())
 }
@@ -255,6 +255,7 @@ mod tests {
     use crate::{ActivateResult, VirtioInterrupt};
 
     use std::sync::{Arc, RwLock};
+    use vm_device::Pausable;
     use vm_memory::GuestMemoryMmap;
     use vmm_sys_util::eventfd::EventFd;
 
@@ -262,6 +263,7 @@ mod tests {
     const QUEUE_SIZE: u16 = 256;
     const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE];
     const DUMMY_FEATURES: u64 = 0x5555_aaaa;
+    impl Pausable for DummyDevice {}
     impl VirtioDevice for DummyDevice {
         fn device_type(&self) -> u32 {
             return self.0;
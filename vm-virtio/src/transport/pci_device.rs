@@ -13,6 +13,7 @@ extern crate vm_allocator;
 extern crate vm_memory;
 extern crate vmm_sys_util;
 
+use byteorder::{ByteOrder, LittleEndian};
 use libc::EFD_NONBLOCK;
 use std::any::Any;
 use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
@@ -26,11 +27,14 @@ use pci::{
     PciMassStorageSubclass, PciNetworkControllerSubclass, PciSubclass,
 };
 use vm_allocator::SystemAllocator;
+use vm_device::{InterruptIndex, InterruptSourceGroup, Pausable};
 use vm_memory::{Address, ByteValued, GuestAddress, GuestMemoryMmap, GuestUsize, Le32};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
 
 use super::VirtioPciCommonConfig;
-use crate::transport::VirtioTransport;
+use crate::transport::{
+    notify_queue, restart_device, ring_indexes, DeviceAudit, RestartError, VirtioTransport,
+};
 use crate::{
     Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
     VirtioIommuRemapping, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED,
@@ -221,6 +225,9 @@ pub struct VirtioPciDevice {
     // MSI-X config
     msix_config: Option<Arc<Mutex<MsixConfig>>>,
 
+    // Interrupts of the MSI-X vectors
+    interrupt_group: Option<Arc<dyn InterruptSourceGroup>>,
+
     // Number of MSI-X vectors
     msix_num: u16,
 
@@ -244,6 +251,9 @@ pub struct VirtioPciDevice {
 
     // Whether to use 64-bit bar location or 32-bit
     use_64bit_bar: bool,
+
+    // Audit of the guest driver, once enabled
+    audit: Option<DeviceAudit>,
 }
 
 impl VirtioPciDevice {
@@ -323,6 +333,7 @@ impl VirtioPciDevice {
                 msix_config: Arc::new(AtomicU16::new(0)),
             },
             msix_config,
+            interrupt_group: None,
             msix_num,
             device,
             device_activated: false,
@@ -333,6 +344,7 @@ impl VirtioPciDevice {
             memory: Some(memory),
             settings_bar: 0,
             use_64bit_bar,
+            audit: None,
         })
     }
 
@@ -363,6 +375,14 @@ impl VirtioPciDevice {
         }
     }
 
+    /// Stops routing the MSI-X vectors, before the device is removed.
+    pub fn free_interrupts(&self) -> std::io::Result<()> {
+        match &self.interrupt_group {
+            Some(interrupt_group) => interrupt_group.free(),
+            None => Ok(()),
+        }
+    }
+
     pub fn config_bar_addr(&self) -> u64 {
         self.configuration.get_bar_addr(self.settings_bar as usize)
     }
@@ -438,7 +458,47 @@ impl VirtioPciDevice {
     }
 }
 
+impl Pausable for VirtioPciDevice {
+    fn pause(&mut self) -> std::io::Result<()> {
+        self.device.pause()
+    }
+
+    fn resume(&mut self) -> std::io::Result<()> {
+        self.device.resume()
+    }
+}
+
 impl VirtioTransport for VirtioPciDevice {
+    fn restart(&mut self) -> std::result::Result<(), RestartError> {
+        if !self.device_activated {
+            return Err(RestartError::NotActivated);
+        }
+        let mem = self.memory.as_ref().ok_or(RestartError::NotActivated)?;
+
+        let result = restart_device(self.device.as_mut(), mem, &mut self.queues);
+        if let Err(RestartError::Activate(_)) = result {
+            self.device_activated = false;
+        }
+        result
+    }
+
+    fn ring_indexes(&self) -> Vec<(u16, u16)> {
+        match self.memory.as_ref() {
+            Some(mem) if self.device_activated => ring_indexes(mem, &self.queues),
+            _ => Vec::new(),
+        }
+    }
+
+    fn enable_audit(&mut self) {
+        let device_features =
+            u64::from(self.device.features(0)) | u64::from(self.device.features(1)) << 32;
+        self.audit = Some(DeviceAudit::new(device_features));
+    }
+
+    fn audit(&self) -> Option<DeviceAudit> {
+        self.audit.clone()
+    }
+
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)> {
         let notify_base = base_addr + NOTIFICATION_BAR_OFFSET;
         self.queue_evts()
@@ -481,14 +541,16 @@ impl PciDevice for VirtioPciDevice {
         self.interrupt_cb = Some(cb);
     }
 
-    fn assign_msix(&mut self, msi_cb: Arc<InterruptDelivery>) {
+    fn assign_msix(&mut self, interrupt_group: Arc<dyn InterruptSourceGroup>) {
         if let Some(msix_config) = &self.msix_config {
             msix_config
                 .lock()
                 .unwrap()
-                .register_interrupt_cb(msi_cb.clone());
+                .register_interrupt_source_group(interrupt_group.clone());
+            self.interrupt_group = Some(interrupt_group.clone());
 
             let msix_config_clone = msix_config.clone();
+            let msix_num = self.msix_num;
 
             let common_config_msi_vector = self.common_config.msix_config.clone();
             let cb = Arc::new(Box::new(
@@ -506,8 +568,26 @@ impl PciDevice for VirtioPciDevice {
                         }
                     };
 
+                    // The driver assigned no vector to the queue, or to the
+                    // configuration changes.
+                    if vector >= msix_num {
+                        return Ok(());
+                    }
+                    let index = InterruptIndex::from(vector);
+
+                    // The vectors which aren't masked are raised through
+                    // their irqfd, without waiting for the vCPUs programming
+                    // the MSI-X table. A vector masked meanwhile may have
+                    // lost the interrupt along with its route, and gets it
+                    // pending as well.
+                    if !interrupt_group.masked(index) {
+                        interrupt_group.trigger(index)?;
+                        if !interrupt_group.masked(index) {
+                            return Ok(());
+                        }
+                    }
+
                     let config = &mut msix_config_clone.lock().unwrap();
-                    let entry = &config.table_entries[vector as usize];
 
                     // If MSI-X interrupts are not enabled for this device, then simply
                     // ignore the interrupt.
@@ -520,12 +600,13 @@ impl PciDevice for VirtioPciDevice {
                     // device should not inject the interrupt.
                     // Instead, the Pending Bit Array table is updated to reflect there
                     // is a pending interrupt for this specific vector.
-                    if config.masked() || entry.masked() {
+                    if config.masked() || config.table_entries[vector as usize].masked() {
                         config.set_pba_bit(vector, false);
                         return Ok(());
                     }
 
-                    (msi_cb)(InterruptParameters { msix: Some(entry) })
+                    // The vector was unmasked since.
+                    interrupt_group.trigger(index)
                 },
             ) as VirtioInterrupt);
 
@@ -616,6 +697,24 @@ impl PciDevice for VirtioPciDevice {
         Ok(ranges)
     }
 
+    // The shared memory BAR is left alone, its region being allocated along
+    // with the device rather than by allocate_bars().
+    fn free_bars(
+        &mut self,
+        allocator: &mut SystemAllocator,
+    ) -> Vec<(GuestAddress, GuestUsize, PciBarRegionType)> {
+        let addr = GuestAddress(self.config_bar_addr());
+        let region_type = if self.use_64bit_bar {
+            allocator.free_mmio_addresses(addr, CAPABILITY_BAR_SIZE);
+            PciBarRegionType::Memory64BitRegion
+        } else {
+            allocator.free_mmio_hole_addresses(addr, CAPABILITY_BAR_SIZE);
+            PciBarRegionType::Memory32BitRegion
+        };
+
+        vec![(addr, CAPABILITY_BAR_SIZE, region_type)]
+    }
+
     fn read_bar(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
         match offset {
             o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.read(
@@ -662,12 +761,14 @@ impl PciDevice for VirtioPciDevice {
 
     fn write_bar(&mut self, _base: u64, offset: u64, data: &[u8]) {
         match offset {
-            o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => self.common_config.write(
-                o - COMMON_CONFIG_BAR_OFFSET,
-                data,
-                &mut self.queues,
-                self.device.as_mut(),
-            ),
+            o if o < COMMON_CONFIG_BAR_OFFSET + COMMON_CONFIG_SIZE => {
+                let o = o - COMMON_CONFIG_BAR_OFFSET;
+                self.common_config
+                    .write(o, data, &mut self.queues, self.device.as_mut());
+                if let Some(audit) = self.audit.as_mut() {
+                    audit_common_config_write(audit, &self.common_config, o, data);
+                }
+            }
             o if ISR_CONFIG_BAR_OFFSET <= o && o < ISR_CONFIG_BAR_OFFSET + ISR_CONFIG_SIZE => {
                 if let Some(v) = data.get(0) {
                     self.interrupt_status
@@ -677,12 +778,18 @@ impl PciDevice for VirtioPciDevice {
             o if DEVICE_CONFIG_BAR_OFFSET <= o
                 && o < DEVICE_CONFIG_BAR_OFFSET + DEVICE_CONFIG_SIZE =>
             {
+                if let Some(audit) = self.audit.as_mut() {
+                    audit.config_write(o - DEVICE_CONFIG_BAR_OFFSET, data);
+                }
                 self.device.write_config(o - DEVICE_CONFIG_BAR_OFFSET, data);
             }
             o if NOTIFICATION_BAR_OFFSET <= o
                 && o < NOTIFICATION_BAR_OFFSET + NOTIFICATION_SIZE =>
             {
-                // Handled with ioeventfds.
+                // Handled with ioeventfds, unless the hypervisor didn't take
+                // the one of the queue.
+                let index = (o - NOTIFICATION_BAR_OFFSET) / u64::from(NOTIFY_OFF_MULTIPLIER);
+                notify_queue(&self.queue_evts, index as usize);
             }
             o if MSIX_TABLE_BAR_OFFSET <= o && o < MSIX_TABLE_BAR_OFFSET + MSIX_TABLE_SIZE => {
                 if let Some(msix_config) = &self.msix_config {
@@ -707,15 +814,20 @@ impl PciDevice for VirtioPciDevice {
             if let Some(interrupt_cb) = self.interrupt_cb.take() {
                 if self.memory.is_some() {
                     let mem = self.memory.as_ref().unwrap().clone();
-                    self.device
-                        .activate(
-                            mem,
-                            interrupt_cb,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
-                    self.device_activated = true;
+                    match self.device.activate(
+                        mem,
+                        interrupt_cb,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    ) {
+                        Ok(()) => self.device_activated = true,
+                        Err(e) => {
+                            error!("Failed to activate device: {:?}", e);
+                            if let Some(audit) = self.audit.as_mut() {
+                                audit.activate_failed(&e);
+                            }
+                        }
+                    }
                 }
             }
         }
@@ -745,6 +857,24 @@ impl PciDevice for VirtioPciDevice {
     }
 }
 
+// Audits the writes of the driver to the feature and status registers of the
+// common configuration.
+fn audit_common_config_write(
+    audit: &mut DeviceAudit,
+    common_config: &VirtioPciCommonConfig,
+    offset: u64,
+    data: &[u8],
+) {
+    match (offset, data.len()) {
+        (0x0c, 4) => audit.ack_features(
+            common_config.driver_feature_select,
+            LittleEndian::read_u32(data),
+        ),
+        (0x14, 1) => audit.set_status(u32::from(common_config.driver_status)),
+        _ => {}
+    }
+}
+
 impl BusDevice for VirtioPciDevice {
     fn read(&mut self, base: u64, offset: u64, data: &mut [u8]) {
         self.read_bar(base, offset, data)
@@ -12,6 +12,7 @@ use std::vec::Vec;
 
 use crate::VirtioInterrupt;
 
+use vm_device::{MemoryListener, Pausable};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -138,6 +139,10 @@ impl Drop for Blk {
     }
 }
 
+// The queues are processed by the vhost-user backend, which keeps running
+// while the VM is paused.
+impl Pausable for Blk {}
+
 impl VirtioDevice for Blk {
     fn device_type(&self) -> u32 {
         VirtioDeviceType::TYPE_BLOCK as u32
@@ -287,4 +292,14 @@ impl VirtioDevice for Blk {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn memory_listener(
+        &self,
+        mem: Arc<RwLock<GuestMemoryMmap>>,
+    ) -> Option<Arc<dyn MemoryListener>> {
+        Some(Arc::new(VhostUserMemoryListener::new(
+            self.vhost_user_blk.clone(),
+            mem,
+        )))
+    }
 }
@@ -1,7 +1,7 @@
 // Copyright 2019 Intel Corporation. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use super::vu_common_ctrl::{reset_vhost_user, setup_vhost_user};
+use super::vu_common_ctrl::{reset_vhost_user, setup_vhost_user, VhostUserMemoryListener};
 use super::{Error, Result};
 use crate::vhost_user::handler::{VhostUserEpollConfig, VhostUserEpollHandler};
 use crate::{
@@ -22,6 +22,7 @@ use vhost_rs::vhost_user::{
     HandlerResult, Master, MasterReqHandler, VhostUserMaster, VhostUserMasterReqHandler,
 };
 use vhost_rs::VhostBackend;
+use vm_device::{MemoryListener, Pausable};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -216,6 +217,10 @@ impl Drop for Fs {
     }
 }
 
+// The queues are processed by the vhost-user backend, which keeps running
+// while the VM is paused.
+impl Pausable for Fs {}
+
 impl VirtioDevice for Fs {
     fn device_type(&self) -> u32 {
         VirtioDeviceType::TYPE_FS as u32
@@ -405,4 +410,11 @@ impl VirtioDevice for Fs {
             None
         }
     }
+
+    fn memory_listener(
+        &self,
+        mem: Arc<RwLock<GuestMemoryMmap>>,
+    ) -> Option<Arc<dyn MemoryListener>> {
+        Some(Arc::new(VhostUserMemoryListener::new(self.vu.clone(), mem)))
+    }
 }
@@ -12,6 +12,7 @@ use std::vec::Vec;
 use crate::VirtioInterrupt;
 use net_util::{MacAddr, MAC_ADDR_LEN};
 
+use vm_device::{MemoryListener, Pausable};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 
@@ -130,6 +131,10 @@ impl Drop for Net {
     }
 }
 
+// The queues are processed by the vhost-user backend, which keeps running
+// while the VM is paused.
+impl Pausable for Net {}
+
 impl VirtioDevice for Net {
     fn device_type(&self) -> u32 {
         VirtioDeviceType::TYPE_NET as u32
@@ -272,4 +277,14 @@ impl VirtioDevice for Net {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn memory_listener(
+        &self,
+        mem: Arc<RwLock<GuestMemoryMmap>>,
+    ) -> Option<Arc<dyn MemoryListener>> {
+        Some(Arc::new(VhostUserMemoryListener::new(
+            self.vhost_user_net.clone(),
+            mem,
+        )))
+    }
 }
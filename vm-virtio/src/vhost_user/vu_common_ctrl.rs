@@ -3,7 +3,9 @@
 
 use libc;
 use libc::EFD_NONBLOCK;
+use std::io;
 use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex, RwLock};
 use std::vec::Vec;
 
 use vm_memory::{Address, Error as MmapError, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
@@ -13,6 +15,7 @@ use super::super::Queue;
 use super::{Error, Result};
 use vhost_rs::vhost_user::{Master, VhostUserMaster};
 use vhost_rs::{VhostBackend, VhostUserMemoryRegionInfo, VringConfigData};
+use vm_device::MemoryListener;
 
 #[derive(Debug, Clone)]
 pub struct VhostUserConfig {
@@ -21,12 +24,7 @@ pub struct VhostUserConfig {
     pub queue_size: u16,
 }
 
-pub fn setup_vhost_user_vring(
-    vu: &mut Master,
-    mem: &GuestMemoryMmap,
-    queues: Vec<Queue>,
-    queue_evts: Vec<EventFd>,
-) -> Result<Vec<(EventFd, Queue)>> {
+fn memory_regions(mem: &GuestMemoryMmap) -> Result<Vec<VhostUserMemoryRegionInfo>> {
     let mut regions: Vec<VhostUserMemoryRegionInfo> = Vec::new();
     mem.with_regions_mut(|_, region| {
         let (mmap_handle, mmap_offset) = match region.file_offset() {
@@ -48,6 +46,16 @@ pub fn setup_vhost_user_vring(
     })
     .map_err(Error::VhostUserMemoryRegion)?;
 
+    Ok(regions)
+}
+
+pub fn setup_vhost_user_vring(
+    vu: &mut Master,
+    mem: &GuestMemoryMmap,
+    queues: Vec<Queue>,
+    queue_evts: Vec<EventFd>,
+) -> Result<Vec<(EventFd, Queue)>> {
+    let regions = memory_regions(mem)?;
     vu.set_mem_table(regions.as_slice())
         .map_err(Error::VhostUserSetMemTable)?;
 
@@ -75,7 +83,7 @@ pub fn setup_vhost_user_vring(
 
         vu.set_vring_addr(queue_index, &config_data)
             .map_err(Error::VhostUserSetVringAddr)?;
-        vu.set_vring_base(queue_index, 0u16)
+        vu.set_vring_base(queue_index, queue.next_avail.0)
             .map_err(Error::VhostUserSetVringBase)?;
 
         let vhost_user_interrupt = EventFd::new(EFD_NONBLOCK).map_err(Error::VhostIrqCreate)?;
@@ -121,3 +129,45 @@ pub fn reset_vhost_user(vu: &mut Master, num_queues: usize) -> Result<()> {
     // Reset the owner.
     vu.reset_owner().map_err(Error::VhostUserResetOwner)
 }
+
+/// Sends the updated memory table to a vhost-user backend anytime the guest
+/// RAM layout changes.
+pub struct VhostUserMemoryListener {
+    vu: Mutex<Master>,
+    mem: Arc<RwLock<GuestMemoryMmap>>,
+}
+
+impl VhostUserMemoryListener {
+    pub fn new(vu: Master, mem: Arc<RwLock<GuestMemoryMmap>>) -> Self {
+        VhostUserMemoryListener {
+            vu: Mutex::new(vu),
+            mem,
+        }
+    }
+
+    fn update_mem_table(&self, removed: Option<u64>) -> Result<()> {
+        let mut regions = memory_regions(&self.mem.read().unwrap())?;
+        if let Some(gpa) = removed {
+            regions.retain(|r| r.guest_phys_addr != gpa);
+        }
+
+        self.vu
+            .lock()
+            .unwrap()
+            .set_mem_table(regions.as_slice())
+            .map_err(Error::VhostUserSetMemTable)
+    }
+}
+
+impl MemoryListener for VhostUserMemoryListener {
+    fn region_added(&self, _gpa: u64, _size: u64, _host_addr: u64) -> io::Result<()> {
+        self.update_mem_table(None)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+
+    fn region_removed(&self, gpa: u64, _size: u64) -> io::Result<()> {
+        // The region is still part of the guest memory at this point.
+        self.update_mem_table(Some(gpa))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    }
+}
@@ -44,12 +44,11 @@ use crate::{
     VirtioInterruptType, VIRTIO_F_IN_ORDER, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use byteorder::{ByteOrder, LittleEndian};
+use vm_device::{Pausable, PauseControl, PauseWorker};
 use vm_memory::GuestMemoryMmap;
 use vmm_sys_util::eventfd::EventFd;
 
-const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 3;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
 
 // New descriptors are pending on the rx queue.
 pub const RX_QUEUE_EVENT: DeviceEventT = 0;
@@ -61,7 +60,9 @@ pub const EVT_QUEUE_EVENT: DeviceEventT = 2;
 pub const BACKEND_EVENT: DeviceEventT = 3;
 // The device has been dropped.
 pub const KILL_EVENT: DeviceEventT = 4;
-pub const EVENTS_LEN: usize = 5;
+// The device is paused.
+pub const PAUSE_EVENT: DeviceEventT = 5;
+pub const EVENTS_LEN: usize = 6;
 
 /// The `VsockEpollHandler` implements the runtime logic of our vsock device:
 /// 1. Respond to TX queue events by wrapping virtio buffers into `VsockPacket`s, then sending those
@@ -88,6 +89,7 @@ pub struct VsockEpollHandler<B: VsockBackend> {
     pub kill_evt: EventFd,
     pub interrupt_cb: Arc<VirtioInterrupt>,
     pub backend: Arc<RwLock<B>>,
+    pub pause: PauseWorker,
 }
 
 impl<B> VsockEpollHandler<B>
@@ -112,7 +114,7 @@ where
     fn process_rx(&mut self) -> result::Result<(), DeviceError> {
         debug!("vsock: epoll_handler::process_rx()");
 
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_desc_heads = vec![(0, 0); self.queues[0].actual_size() as usize];
         let mut used_count = 0;
         let mem = self.mem.read().unwrap();
         for avail_desc in self.queues[0].iter(&mem) {
@@ -154,7 +156,7 @@ where
     fn process_tx(&mut self) -> result::Result<(), DeviceError> {
         debug!("vsock: epoll_handler::process_tx()");
 
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
+        let mut used_desc_heads = vec![(0, 0); self.queues[1].actual_size() as usize];
         let mut used_count = 0;
         let mem = self.mem.read().unwrap();
         for avail_desc in self.queues[1].iter(&mem) {
@@ -231,6 +233,13 @@ where
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(KILL_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.pause.evt().as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
 
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EVENTS_LEN];
 
@@ -336,6 +345,10 @@ where
                 debug!("KILL_EVENT received, stopping epoll loop");
                 return Ok(true);
             }
+            PAUSE_EVENT => {
+                debug!("PAUSE_EVENT received, pausing epoll loop");
+                self.pause.wait_resumed();
+            }
             other => {
                 error!("Unknown event for virtio-vsock");
                 return Err(DeviceError::UnknownEvent {
@@ -356,8 +369,10 @@ pub struct Vsock<B: VsockBackend> {
     kill_evt: Option<EventFd>,
     avail_features: u64,
     acked_features: u64,
+    queue_sizes: Vec<u16>,
     queue_evts: Option<Vec<EventFd>>,
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    pause: PauseControl,
 }
 
 impl<B> Vsock<B>
@@ -366,7 +381,7 @@ where
 {
     /// Create a new virtio-vsock device with the given VM CID and vsock
     /// backend.
-    pub fn new(cid: u64, backend: B, iommu: bool) -> io::Result<Vsock<B>> {
+    pub fn new(cid: u64, backend: B, iommu: bool, queue_size: u16) -> io::Result<Vsock<B>> {
         let mut avail_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_IN_ORDER;
 
         if iommu {
@@ -379,8 +394,10 @@ where
             kill_evt: None,
             avail_features,
             acked_features: 0u64,
+            queue_sizes: vec![queue_size; NUM_QUEUES],
             queue_evts: None,
             interrupt_cb: None,
+            pause: PauseControl::new()?,
         })
     }
 }
@@ -397,6 +414,19 @@ where
     }
 }
 
+impl<B> Pausable for Vsock<B>
+where
+    B: VsockBackend,
+{
+    fn pause(&mut self) -> io::Result<()> {
+        self.pause.pause()
+    }
+
+    fn resume(&mut self) -> io::Result<()> {
+        self.pause.resume()
+    }
+}
+
 impl<B> VirtioDevice for Vsock<B>
 where
     B: VsockBackend + Sync + 'static,
@@ -406,7 +436,7 @@ where
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn features(&self, page: u32) -> u32 {
@@ -514,6 +544,7 @@ where
             kill_evt,
             interrupt_cb,
             backend: self.backend.clone(),
+            pause: self.pause.worker(),
         };
 
         let worker_result = thread::Builder::new()
@@ -544,7 +575,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::super::tests::TestContext;
+    use super::super::tests::{TestContext, QUEUE_SIZE};
     use super::super::*;
     use super::*;
     use crate::vsock::device::{BACKEND_EVENT, EVT_QUEUE_EVENT, RX_QUEUE_EVENT, TX_QUEUE_EVENT};
@@ -567,7 +598,7 @@ mod tests {
             ctx.device.device_type(),
             VirtioDeviceType::TYPE_VSOCK as u32
         );
-        assert_eq!(ctx.device.queue_max_sizes(), QUEUE_SIZES);
+        assert_eq!(ctx.device.queue_max_sizes(), &[QUEUE_SIZE; NUM_QUEUES]);
         assert_eq!(ctx.device.features(0), device_pages[0]);
         assert_eq!(ctx.device.features(1), device_pages[1]);
         assert_eq!(ctx.device.features(2), 0);
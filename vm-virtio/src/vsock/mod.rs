@@ -166,6 +166,7 @@ mod tests {
 
     use std::os::unix::io::AsRawFd;
     use std::sync::{Arc, RwLock};
+    use vm_device::PauseControl;
     use vmm_sys_util::eventfd::EventFd;
 
     use crate::device::{VirtioInterrupt, VirtioInterruptType};
@@ -241,6 +242,8 @@ mod tests {
     }
     impl VsockBackend for TestBackend {}
 
+    pub const QUEUE_SIZE: u16 = 256;
+
     pub struct TestContext {
         pub cid: u64,
         pub mem: GuestMemoryMmap,
@@ -256,7 +259,7 @@ mod tests {
                 cid: CID,
                 mem: GuestMemoryMmap::new(&[(GuestAddress(0), MEM_SIZE)]).unwrap(),
                 mem_size: MEM_SIZE,
-                device: Vsock::new(CID, TestBackend::new(), false).unwrap(),
+                device: Vsock::new(CID, TestBackend::new(), false, QUEUE_SIZE).unwrap(),
             }
         }
 
@@ -308,6 +311,7 @@ mod tests {
                     kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
                     interrupt_cb,
                     backend: Arc::new(RwLock::new(TestBackend::new())),
+                    pause: PauseControl::new().unwrap().worker(),
                 },
             }
         }
@@ -0,0 +1,31 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+// The OpenAPI document of the HTTP API is converted to JSON, for the VMM to
+// serve it as the schema of the API.
+
+extern crate serde_json;
+extern crate serde_yaml;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const OPENAPI_PATH: &str = "src/api/openapi/cloud-hypervisor.yaml";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", OPENAPI_PATH);
+
+    let yaml = fs::read_to_string(OPENAPI_PATH).expect("Cannot read the OpenAPI document");
+    let schema: serde_json::Value =
+        serde_yaml::from_str(&yaml).expect("Cannot parse the OpenAPI document");
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is not set");
+    fs::write(
+        Path::new(&out_dir).join("cloud-hypervisor.json"),
+        schema.to_string(),
+    )
+    .expect("Cannot write the API schema");
+}
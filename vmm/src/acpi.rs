@@ -12,10 +12,26 @@ use vm_memory::{GuestAddress, GuestMemoryMmap};
 
 use vm_memory::{Address, ByteValued, Bytes};
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 
 use arch::layout;
 
+use crate::config::{AcpiOemTableConfig, CpuTopology, NumaConfig, SensorsConfig, VmConfig};
+use crate::cpu::CpuFrequency;
+use crate::device_manager::PciSegmentWindows;
+use crate::memory_manager::NumaMemoryRange;
+
+/// Signatures of the tables the VMM generates, that the user tables can't
+/// replace.
+pub const GENERATED_SIGNATURES: [&[u8; 4]; 16] = [
+    b"DSDT", b"FACP", b"FACS", b"APIC", b"MCFG", b"IORT", b"VIOT", b"PPTT", b"SRAT", b"SLIT",
+    b"TPM2", b"NFIT", b"WAET", b"RSDT", b"XSDT", b"OEMI",
+];
+
+/// The user tables share the EBDA with the generated ones.
+pub const MAX_USER_TABLES_SIZE: usize = 256 << 10;
+
 #[repr(packed)]
 struct LocalAPIC {
     pub r#type: u8,
@@ -25,6 +41,78 @@ struct LocalAPIC {
     pub flags: u32,
 }
 
+// The persistent memory range of an NVDIMM.
+#[repr(packed)]
+#[derive(Default)]
+struct NfitSpaRange {
+    pub type_: u16,
+    pub length: u16,
+    pub spa_index: u16,
+    pub flags: u16,
+    _reserved: u32,
+    pub proximity_domain: u32,
+    pub range_guid: [u8; 16],
+    pub base: u64,
+    pub size: u64,
+    pub memory_attributes: u64,
+}
+
+// The part of a persistent memory range an NVDIMM backs.
+#[repr(packed)]
+#[derive(Default)]
+struct NfitRegionMapping {
+    pub type_: u16,
+    pub length: u16,
+    pub handle: u32,
+    pub physical_id: u16,
+    pub region_id: u16,
+    pub spa_index: u16,
+    pub dcr_index: u16,
+    pub region_size: u64,
+    pub region_offset: u64,
+    pub region_dpa: u64,
+    pub interleave_index: u16,
+    pub interleave_ways: u16,
+    pub flags: u16,
+    _reserved: u16,
+}
+
+// The identity of an NVDIMM, without any block control window.
+#[repr(packed)]
+#[derive(Default)]
+struct NfitControlRegion {
+    pub type_: u16,
+    pub length: u16,
+    pub dcr_index: u16,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_device_id: u16,
+    pub subsystem_revision_id: u16,
+    pub valid_fields: u8,
+    pub manufacturing_location: u8,
+    pub manufacturing_date: u16,
+    _reserved1: u16,
+    pub serial_number: u32,
+    pub interface_code: u16,
+    pub num_block_windows: u16,
+    pub block_window_size: u64,
+    pub command_offset: u64,
+    pub command_size: u64,
+    pub status_offset: u64,
+    pub status_size: u64,
+    pub flags: u16,
+    _reserved2: [u8; 6],
+}
+
+// Byte addressable persistent memory, 66F0D379-B4F3-4074-AC43-0D3318B78CDB.
+const NFIT_PM_GUID: [u8; 16] = [
+    0x79, 0xd3, 0xf0, 0x66, 0xf3, 0xb4, 0x74, 0x40, 0xac, 0x43, 0x0d, 0x33, 0x18, 0xb7, 0x8c, 0xdb,
+];
+// EFI_MEMORY_WB | EFI_MEMORY_NV
+const NFIT_MEMORY_ATTRIBUTES: u64 = 0x8 | 0x8000;
+
 #[repr(packed)]
 #[derive(Default)]
 struct IOAPIC {
@@ -108,36 +196,363 @@ struct IortIdMapping {
     pub flags: u32,
 }
 
+#[repr(packed)]
+#[derive(Default)]
+struct ViotVirtioPciIommuNode {
+    pub type_: u8,
+    _reserved1: u8,
+    pub length: u16,
+    pub pci_segment: u16,
+    pub pci_bdf: u16,
+    _reserved2: [u8; 8],
+}
+
+#[repr(packed)]
+#[derive(Default)]
+struct ViotPciRangeNode {
+    pub type_: u8,
+    _reserved1: u8,
+    pub length: u16,
+    pub endpoint_start: u32,
+    pub pci_segment_start: u16,
+    pub pci_segment_end: u16,
+    pub pci_bdf_start: u16,
+    pub pci_bdf_end: u16,
+    pub output_node: u16,
+    _reserved2: [u8; 6],
+}
+
+// VIOT node types
+const VIOT_PCI_RANGE: u8 = 1;
+const VIOT_VIRTIO_PCI_IOMMU: u8 = 3;
+
+#[repr(packed)]
+#[derive(Default)]
+struct ProcessorHierarchyNode {
+    pub r#type: u8,
+    pub length: u8,
+    _reserved: u16,
+    pub flags: u32,
+    pub parent: u32,
+    pub acpi_processor_id: u32,
+    pub num_private_resources: u32,
+}
+
+// PPTT processor hierarchy node flags
+const PPTT_PHYSICAL_PACKAGE: u32 = 1 << 0;
+const PPTT_PROCESSOR_ID_VALID: u32 = 1 << 1;
+const PPTT_PROCESSOR_IS_THREAD: u32 = 1 << 2;
+const PPTT_NODE_IS_LEAF: u32 = 1 << 3;
+const PPTT_IDENTICAL_IMPLEMENTATION: u32 = 1 << 4;
+
+#[repr(packed)]
+#[derive(Default)]
+struct ProcessorLocalApicAffinity {
+    pub r#type: u8,
+    pub length: u8,
+    pub proximity_domain_lo: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+    pub local_sapic_eid: u8,
+    pub proximity_domain_hi: [u8; 3],
+    pub clock_domain: u32,
+}
+
+#[repr(packed)]
+#[derive(Default)]
+struct MemoryAffinity {
+    pub r#type: u8,
+    pub length: u8,
+    pub proximity_domain: u32,
+    _reserved1: u16,
+    pub base_addr_lo: u32,
+    pub base_addr_hi: u32,
+    pub length_lo: u32,
+    pub length_hi: u32,
+    _reserved2: u32,
+    pub flags: u32,
+    _reserved3: u64,
+}
+
+// SRAT affinity structures flags
+const SRAT_ENABLED: u32 = 1 << 0;
+
 struct CPU {
     cpu_id: u8,
     present: bool,
+    frequency: Option<CpuFrequency>,
+}
+
+// Continuous performance control revision 3 package, reporting the
+// frequencies as static performance levels, in MHz. None of the registers is
+// implemented, the guest can't change the frequency.
+struct CpcPackage {
+    frequency: CpuFrequency,
+}
+
+impl aml::Aml for CpcPackage {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let null_register = aml::ResourceTemplate::new(vec![&aml::Register::null()]);
+        let base = self.frequency.base;
+        let max = self.frequency.max;
+
+        aml::Package::new(vec![
+            &23u8,          // Number of entries
+            &3u8,           // Revision
+            &max,           // Highest performance
+            &base,          // Nominal performance
+            &base,          // Lowest nonlinear performance
+            &base,          // Lowest performance
+            &null_register, // Guaranteed performance register
+            &null_register, // Desired performance register
+            &null_register, // Minimum performance register
+            &null_register, // Maximum performance register
+            &null_register, // Performance reduction tolerance register
+            &null_register, // Time window register
+            &null_register, // Counter wraparound time
+            &null_register, // Reference performance counter register
+            &null_register, // Delivered performance counter register
+            &null_register, // Performance limited register
+            &null_register, // CPPC enable register
+            &aml::ZERO,     // Autonomous selection enable
+            &null_register, // Autonomous activity window register
+            &null_register, // Energy performance preference register
+            &base,          // Reference performance
+            &base,          // Lowest frequency
+            &base,          // Nominal frequency
+        ])
+        .to_aml_bytes()
+    }
 }
 
 impl aml::Aml for CPU {
     fn to_aml_bytes(&self) -> Vec<u8> {
+        let hid = aml::Name::new("_HID".into(), &"ACPI0007");
+        let uid = aml::Name::new("_UID".into(), &self.cpu_id);
+        /*
+        _STA return value:
+        Bit [0] – Set if the device is present.
+        Bit [1] – Set if the device is enabled and decoding its resources.
+        Bit [2] – Set if the device should be shown in the UI.
+        Bit [3] – Set if the device is functioning properly (cleared if device failed its diagnostics).
+        Bit [4] – Set if the battery is present.
+        Bits [31:5] – Reserved (must be cleared).
+        */
+        let sta = aml::Method::new(
+            "_STA".into(),
+            0,
+            false,
+            vec![&aml::Return::new(if self.present {
+                &0xfu8
+            } else {
+                &aml::ZERO
+            })],
+        );
+        let cpc = self
+            .frequency
+            .map(|frequency| aml::Name::new("_CPC".into(), &CpcPackage { frequency }));
+
+        let mut children: Vec<&dyn aml::Aml> = vec![&hid, &uid, &sta];
+        if let Some(cpc) = &cpc {
+            children.push(cpc);
+        }
+
+        aml::Device::new(format!("C{:03}", self.cpu_id).as_str().into(), children).to_aml_bytes()
+    }
+}
+
+// Both the battery and the thermal zone registers are exposed by the ACPI
+// sensors device, starting at this I/O port.
+const SENSORS_IO_PORT: usize = 0x3c8;
+
+// Critical temperature of the thermal zone, 100 degrees Celsius.
+const THERMAL_ZONE_CRITICAL_TEMPERATURE: u32 = 3732;
+
+// Design voltage and warning capacities of the battery, in mV and mWh.
+const BATTERY_DESIGN_VOLTAGE: u32 = 12_000;
+const BATTERY_WARNING_CAPACITY: u32 = devices::BATTERY_DESIGN_CAPACITY / 10;
+const BATTERY_LOW_CAPACITY: u32 = devices::BATTERY_DESIGN_CAPACITY / 20;
+
+fn create_battery_data() -> Vec<u8> {
+    let sta = aml::Method::new("_STA".into(), 0, false, vec![&aml::Return::new(&0x1fu8)]);
+
+    // Static battery information: capacities in mWh with a 1 mWh
+    // granularity, rechargeable.
+    let bif = aml::Name::new(
+        "_BIF".into(),
+        &aml::Package::new(vec![
+            &aml::ZERO,
+            &devices::BATTERY_DESIGN_CAPACITY,
+            &devices::BATTERY_DESIGN_CAPACITY,
+            &aml::ONE,
+            &BATTERY_DESIGN_VOLTAGE,
+            &BATTERY_WARNING_CAPACITY,
+            &BATTERY_LOW_CAPACITY,
+            &aml::ONE,
+            &aml::ONE,
+            &"CHBAT0",
+            &"0",
+            &"LION",
+            &"CLOUDH",
+        ]),
+    );
+
+    // The _BST package gets the state, present rate and remaining capacity
+    // read from the sensors device registers.
+    let bstp = aml::Name::new(
+        "BSTP".into(),
+        &aml::Package::new(vec![
+            &aml::ZERO,
+            &aml::ZERO,
+            &aml::ZERO,
+            &BATTERY_DESIGN_VOLTAGE,
+        ]),
+    );
+    let bst = aml::Method::new(
+        "_BST".into(),
+        0,
+        true,
+        vec![
+            &aml::Store::new(
+                &aml::Index::new(&aml::Path::new("BSTP"), &aml::ZERO),
+                &aml::Path::new("BSTA"),
+            ),
+            &aml::Store::new(
+                &aml::Index::new(&aml::Path::new("BSTP"), &aml::ONE),
+                &aml::Path::new("BRTE"),
+            ),
+            &aml::Store::new(
+                &aml::Index::new(&aml::Path::new("BSTP"), &2u8),
+                &aml::Path::new("BREM"),
+            ),
+            &aml::Return::new(&aml::Path::new("BSTP")),
+        ],
+    );
+
+    aml::Device::new(
+        "_SB_.BAT0".into(),
+        vec![
+            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C0A")),
+            &aml::Name::new("_UID".into(), &aml::ZERO),
+            &aml::OpRegion::new(
+                "BSTR".into(),
+                aml::OpRegionSpace::SystemIO,
+                SENSORS_IO_PORT,
+                0xc,
+            ),
+            &aml::Field::new(
+                "BSTR".into(),
+                aml::FieldAccessType::DWord,
+                aml::FieldUpdateRule::Preserve,
+                vec![
+                    aml::FieldEntry::Named(*b"BSTA", 32),
+                    aml::FieldEntry::Named(*b"BRTE", 32),
+                    aml::FieldEntry::Named(*b"BREM", 32),
+                ],
+            ),
+            &sta,
+            &bif,
+            &bstp,
+            &bst,
+        ],
+    )
+    .to_aml_bytes()
+}
+
+fn create_thermal_zone_data() -> Vec<u8> {
+    aml::ThermalZone::new(
+        "\\_TZ_.TZ00".into(),
+        vec![
+            &aml::OpRegion::new(
+                "TZRG".into(),
+                aml::OpRegionSpace::SystemIO,
+                SENSORS_IO_PORT + 0xc,
+                0x4,
+            ),
+            &aml::Field::new(
+                "TZRG".into(),
+                aml::FieldAccessType::DWord,
+                aml::FieldUpdateRule::Preserve,
+                vec![aml::FieldEntry::Named(*b"TMPR", 32)],
+            ),
+            &aml::Method::new(
+                "_TMP".into(),
+                0,
+                false,
+                vec![&aml::Return::new(&aml::Path::new("TMPR"))],
+            ),
+            &aml::Name::new("_CRT".into(), &THERMAL_ZONE_CRITICAL_TEMPERATURE),
+        ],
+    )
+    .to_aml_bytes()
+}
+
+// Notifies a device about a status change, when its event bit is set in the
+// pending GED events, which the _EVT method stores in Local0.
+struct GedNotification {
+    event: u8,
+    device: &'static str,
+}
+
+impl aml::Aml for GedNotification {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        aml::If::new(
+            &aml::Equal::new(
+                &aml::And::new(&aml::Local(1), &aml::Local(0), &self.event),
+                &self.event,
+            ),
+            vec![&aml::Notify::new(&aml::Path::new(self.device), &0x80u8)],
+        )
+        .to_aml_bytes()
+    }
+}
+
+// Calls a method when its event bit is set in the pending GED events, as
+// GedNotification.
+struct GedMethodCall {
+    event: u8,
+    method: &'static str,
+}
+
+impl aml::Aml for GedMethodCall {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        aml::If::new(
+            &aml::Equal::new(
+                &aml::And::new(&aml::Local(1), &aml::Local(0), &self.event),
+                &self.event,
+            ),
+            vec![&aml::MethodCall::new(self.method.into(), vec![])],
+        )
+        .to_aml_bytes()
+    }
+}
+
+// I/O port of the registers of the PCI hotplug device: the device numbers
+// added, the ones to remove, and the ones ejected, a bit for each.
+const PCI_HOTPLUG_IO_PORT: usize = 0xae00;
+
+// A slot of the PCI bus 0, which the guest ejects the device of through
+// its _EJ0 method.
+struct PciSlot {
+    device_id: u8,
+}
+
+impl aml::Aml for PciSlot {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let device_id = u32::from(self.device_id);
         aml::Device::new(
-            format!("C{:03}", self.cpu_id).as_str().into(),
+            format!("S{:03}", self.device_id).as_str().into(),
             vec![
-                &aml::Name::new("_HID".into(), &"ACPI0007"),
-                &aml::Name::new("_UID".into(), &self.cpu_id),
-                /*
-                _STA return value:
-                Bit [0] – Set if the device is present.
-                Bit [1] – Set if the device is enabled and decoding its resources.
-                Bit [2] – Set if the device should be shown in the UI.
-                Bit [3] – Set if the device is functioning properly (cleared if device failed its diagnostics).
-                Bit [4] – Set if the battery is present.
-                Bits [31:5] – Reserved (must be cleared).
-                */
+                &aml::Name::new("_ADR".into(), &(device_id << 16)),
+                &aml::Name::new("_SUN".into(), &device_id),
                 &aml::Method::new(
-                    "_STA".into(),
-                    0,
+                    "_EJ0".into(),
+                    1,
                     false,
-                    vec![&aml::Return::new(if self.present {
-                        &0xfu8
-                    } else {
-                        &aml::ZERO
-                    })],
+                    vec![&aml::Store::new(
+                        &aml::Path::new("B0EJ"),
+                        &(1u32 << device_id),
+                    )],
                 ),
             ],
         )
@@ -145,7 +560,36 @@ impl aml::Aml for CPU {
     }
 }
 
-fn create_cpu_data(num_cpus: u8) -> Vec<u8> {
+// Notifies a slot of the PCI bus 0 about its device being added, or to be
+// removed, when its bit is set in the devices added, which the PCNT method
+// stores in Local0, or in the ones to remove, in Local1.
+struct PciSlotNotification {
+    device_id: u8,
+}
+
+impl aml::Aml for PciSlotNotification {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        let slot = format!("S{:03}", self.device_id);
+        let mask = 1u32 << self.device_id;
+        let mut bytes = aml::If::new(
+            &aml::Equal::new(&aml::And::new(&aml::Local(2), &aml::Local(0), &mask), &mask),
+            // Device check
+            vec![&aml::Notify::new(&aml::Path::new(&slot), &1u8)],
+        )
+        .to_aml_bytes();
+        bytes.append(
+            &mut aml::If::new(
+                &aml::Equal::new(&aml::And::new(&aml::Local(2), &aml::Local(1), &mask), &mask),
+                // Eject request
+                vec![&aml::Notify::new(&aml::Path::new(&slot), &3u8)],
+            )
+            .to_aml_bytes(),
+        );
+        bytes
+    }
+}
+
+fn create_cpu_data(num_cpus: u8, frequency: Option<CpuFrequency>) -> Vec<u8> {
     let hid = aml::Name::new("_HID".into(), &"ACPI0010");
     let uid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A05"));
     let mut cpu_data_inner: Vec<&dyn aml::Aml> = vec![&hid, &uid];
@@ -155,6 +599,7 @@ fn create_cpu_data(num_cpus: u8) -> Vec<u8> {
         let cpu_device = CPU {
             cpu_id,
             present: true,
+            frequency,
         };
 
         cpu_devices.push(cpu_device);
@@ -167,115 +612,867 @@ fn create_cpu_data(num_cpus: u8) -> Vec<u8> {
     aml::Device::new("_SB_.CPUS".into(), cpu_data_inner).to_aml_bytes()
 }
 
+// COM2 to COM4, the additional UARTs, at the I/O port and IRQ they are
+// given.
+fn create_uart_data(index: usize, port: u16, irq: u8) -> Vec<u8> {
+    aml::Device::new(
+        format!("_SB_.COM{}", index + 2).as_str().into(),
+        vec![
+            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0501")),
+            &aml::Name::new("_UID".into(), &(index as u8 + 1)),
+            &aml::Name::new(
+                "_CRS".into(),
+                &aml::ResourceTemplate::new(vec![
+                    &aml::Interrupt::new(true, true, false, false, u32::from(irq)),
+                    &aml::IO::new(port, port, 0, 0x8),
+                ]),
+            ),
+        ],
+    )
+    .to_aml_bytes()
+}
+
+// NUMA node the host bridge of a PCI segment is local to, if any.
+fn pci_segment_proximity_domain(numa: &[NumaConfig], pci_segment: u16) -> Option<usize> {
+    for node in numa.iter() {
+        if node
+            .pci_segments
+            .iter()
+            .flatten()
+            .any(|&id| id == pci_segment)
+        {
+            return Some(node.id as usize);
+        }
+    }
+
+    None
+}
+
+// Host bridge of a PCI segment other than the segment 0, whose single bus
+// holds the devices placed on the segment.
+fn create_pci_segment_data(windows: &PciSegmentWindows, numa: &[NumaConfig]) -> Vec<u8> {
+    let io_start = windows.io.0.raw_value();
+    let mem32_start = windows.mem32.0.raw_value();
+    let mem64_start = windows.mem64.0.raw_value();
+    let id = usize::from(windows.id);
+
+    let hid = aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08"));
+    let cid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03"));
+    let adr = aml::Name::new("_ADR".into(), &aml::ZERO);
+    let seg = aml::Name::new("_SEG".into(), &id);
+    let uid = aml::Name::new("_UID".into(), &id);
+    let crs = aml::Name::new(
+        "_CRS".into(),
+        &aml::ResourceTemplate::new(vec![
+            &aml::AddressSpace::new_bus_number(0x0u16, 0x0u16),
+            &aml::AddressSpace::new_io(io_start as u16, (io_start + windows.io.1 - 1) as u16),
+            &aml::AddressSpace::new_memory(
+                aml::AddressSpaceCachable::NotCacheable,
+                true,
+                mem32_start as u32,
+                (mem32_start + windows.mem32.1 - 1) as u32,
+            ),
+            &aml::AddressSpace::new_memory(
+                aml::AddressSpaceCachable::NotCacheable,
+                true,
+                mem64_start,
+                mem64_start + windows.mem64.1 - 1,
+            ),
+        ]),
+    );
+    let mut pci_segment_inner: Vec<&dyn aml::Aml> = vec![&hid, &cid, &adr, &seg, &uid, &crs];
+
+    let pxm;
+    if let Some(proximity_domain) = pci_segment_proximity_domain(numa, windows.id) {
+        pxm = aml::Name::new("_PXM".into(), &proximity_domain);
+        pci_segment_inner.push(&pxm);
+    }
+
+    aml::Device::new(
+        format!("_SB_.PCI{:X}", windows.id).as_str().into(),
+        pci_segment_inner,
+    )
+    .to_aml_bytes()
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn create_dsdt_table(
     serial_enabled: bool,
+    uarts: &[(u16, u8)],
     start_of_device_area: GuestAddress,
     end_of_device_area: GuestAddress,
+    pci_segments: &[PciSegmentWindows],
+    numa: &[NumaConfig],
     num_cpus: u8,
+    ged_irq: Option<u32>,
+    sensors: &SensorsConfig,
+    frequency: Option<CpuFrequency>,
+    tpm: bool,
+    pvpanic: bool,
+    pci_hotplug: bool,
+    nvdimms: usize,
 ) -> SDT {
-    let pci_dsdt_data = aml::Device::new(
-        "_SB_.PCI0".into(),
+    // The windows of the other PCI segments are taken from the top of the
+    // ones of the segment 0.
+    let io_end = pci_segments
+        .iter()
+        .map(|windows| windows.io.0.raw_value() - 1)
+        .min()
+        .unwrap_or(0xffff);
+    let mem32_end = pci_segments
+        .iter()
+        .map(|windows| windows.mem32.0.raw_value() - 1)
+        .min()
+        .unwrap_or(layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE - 1);
+    let mem64_end = pci_segments
+        .iter()
+        .map(|windows| windows.mem64.0.raw_value() - 1)
+        .min()
+        .unwrap_or(end_of_device_area.0);
+
+    let hid = aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08"));
+    let cid = aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03"));
+    let adr = aml::Name::new("_ADR".into(), &aml::ZERO);
+    let seg = aml::Name::new("_SEG".into(), &aml::ZERO);
+    let uid = aml::Name::new("_UID".into(), &aml::ZERO);
+    let supp = aml::Name::new("SUPP".into(), &aml::ZERO);
+    let crs = aml::Name::new(
+        "_CRS".into(),
+        &aml::ResourceTemplate::new(vec![
+            &aml::AddressSpace::new_bus_number(0x0u16, 0xffu16),
+            &aml::IO::new(0xcf8, 0xcf8, 1, 0x8),
+            &aml::AddressSpace::new_io(0x0u16, 0xcf7u16),
+            &aml::AddressSpace::new_io(0xd00u16, io_end as u16),
+            &aml::AddressSpace::new_memory(
+                aml::AddressSpaceCachable::NotCacheable,
+                true,
+                layout::MEM_32BIT_DEVICES_START.0 as u32,
+                mem32_end as u32,
+            ),
+            &aml::AddressSpace::new_memory(
+                aml::AddressSpaceCachable::NotCacheable,
+                true,
+                start_of_device_area.0,
+                mem64_end,
+            ),
+        ]),
+    );
+    let mut pci_inner: Vec<&dyn aml::Aml> = vec![&hid, &cid, &adr, &seg, &uid, &supp, &crs];
+
+    let pxm;
+    if let Some(proximity_domain) = pci_segment_proximity_domain(numa, 0) {
+        pxm = aml::Name::new("_PXM".into(), &proximity_domain);
+        pci_inner.push(&pxm);
+    }
+
+    // The devices of the slots 1 to 31 can be added and removed, the guest
+    // being notified through the GED.
+    let pci_hotplug_region = aml::OpRegion::new(
+        "PCST".into(),
+        aml::OpRegionSpace::SystemIO,
+        PCI_HOTPLUG_IO_PORT,
+        0xc,
+    );
+    let pci_hotplug_fields = aml::Field::new(
+        "PCST".into(),
+        aml::FieldAccessType::DWord,
+        aml::FieldUpdateRule::WriteAsZeroes,
         vec![
-            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0A08")),
-            &aml::Name::new("_CID".into(), &aml::EISAName::new("PNP0A03")),
-            &aml::Name::new("_ADR".into(), &aml::ZERO),
-            &aml::Name::new("_SEG".into(), &aml::ZERO),
+            aml::FieldEntry::Named(*b"PCIU", 32),
+            aml::FieldEntry::Named(*b"PCID", 32),
+            aml::FieldEntry::Named(*b"B0EJ", 32),
+        ],
+    );
+    let pci_slots: Vec<PciSlot> = (1..32).map(|device_id| PciSlot { device_id }).collect();
+    let pci_slot_notifications: Vec<PciSlotNotification> = (1..32)
+        .map(|device_id| PciSlotNotification { device_id })
+        .collect();
+    let pcnt_store_added = aml::Store::new(&aml::Local(0), &aml::Path::new("PCIU"));
+    let pcnt_store_removing = aml::Store::new(&aml::Local(1), &aml::Path::new("PCID"));
+    let mut pcnt_children: Vec<&dyn aml::Aml> = vec![&pcnt_store_added, &pcnt_store_removing];
+    for notification in pci_slot_notifications.iter() {
+        pcnt_children.push(notification);
+    }
+    let pcnt = aml::Method::new("PCNT".into(), 0, true, pcnt_children);
+    if pci_hotplug {
+        pci_inner.push(&pci_hotplug_region);
+        pci_inner.push(&pci_hotplug_fields);
+        for slot in pci_slots.iter() {
+            pci_inner.push(slot);
+        }
+        pci_inner.push(&pcnt);
+    }
+
+    let pci_dsdt_data = aml::Device::new("_SB_.PCI0".into(), pci_inner).to_aml_bytes();
+
+    // The MMCONFIG areas of the other PCI segments follow the one of the
+    // segment 0.
+    let mmconfig = aml::Memory32Fixed::new(
+        true,
+        layout::PCI_MMCONFIG_START.0 as u32,
+        layout::PCI_MMCONFIG_SIZE as u32,
+    );
+    let segments_mmconfig = aml::Memory32Fixed::new(
+        true,
+        layout::PCI_SEGMENTS_MMCONFIG_START.0 as u32,
+        (pci_segments.len() as u64 * layout::PCI_SEGMENT_MMCONFIG_SIZE) as u32,
+    );
+    let mut mbrd_resources: Vec<&dyn aml::Aml> = vec![&mmconfig];
+    if !pci_segments.is_empty() {
+        mbrd_resources.push(&segments_mmconfig);
+    }
+
+    let mbrd_dsdt_data = aml::Device::new(
+        "_SB_.MBRD".into(),
+        vec![
+            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C02")),
+            &aml::Name::new("_UID".into(), &aml::ZERO),
+            &aml::Name::new("_CRS".into(), &aml::ResourceTemplate::new(mbrd_resources)),
+        ],
+    )
+    .to_aml_bytes();
+
+    let com1_dsdt_data = aml::Device::new(
+        "_SB_.COM1".into(),
+        vec![
+            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0501")),
             &aml::Name::new("_UID".into(), &aml::ZERO),
-            &aml::Name::new("SUPP".into(), &aml::ZERO),
             &aml::Name::new(
                 "_CRS".into(),
                 &aml::ResourceTemplate::new(vec![
-                    &aml::AddressSpace::new_bus_number(0x0u16, 0xffu16),
-                    &aml::IO::new(0xcf8, 0xcf8, 1, 0x8),
-                    &aml::AddressSpace::new_io(0x0u16, 0xcf7u16),
-                    &aml::AddressSpace::new_io(0xd00u16, 0xffffu16),
-                    &aml::AddressSpace::new_memory(
-                        aml::AddressSpaceCachable::NotCacheable,
-                        true,
-                        layout::MEM_32BIT_DEVICES_START.0 as u32,
-                        (layout::MEM_32BIT_DEVICES_START.0 + layout::MEM_32BIT_DEVICES_SIZE - 1)
-                            as u32,
-                    ),
-                    &aml::AddressSpace::new_memory(
-                        aml::AddressSpaceCachable::NotCacheable,
-                        true,
-                        start_of_device_area.0,
-                        end_of_device_area.0,
-                    ),
+                    &aml::Interrupt::new(true, true, false, false, 4),
+                    &aml::IO::new(0x3f8, 0x3f8, 0, 0x8),
                 ]),
             ),
         ],
     )
     .to_aml_bytes();
 
-    let mbrd_dsdt_data = aml::Device::new(
-        "_SB_.MBRD".into(),
+    let tpm_dsdt_data = aml::Device::new(
+        "_SB_.TPM2".into(),
         vec![
-            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C02")),
-            &aml::Name::new("_UID".into(), &aml::ZERO),
+            &aml::Name::new("_HID".into(), &"MSFT0101"),
+            &aml::Name::new("_STA".into(), &0xfu8),
             &aml::Name::new(
                 "_CRS".into(),
                 &aml::ResourceTemplate::new(vec![&aml::Memory32Fixed::new(
                     true,
-                    layout::PCI_MMCONFIG_START.0 as u32,
-                    layout::PCI_MMCONFIG_SIZE as u32,
+                    layout::TPM_START.0 as u32,
+                    layout::TPM_SIZE as u32,
                 )]),
             ),
         ],
     )
     .to_aml_bytes();
 
-    let com1_dsdt_data = aml::Device::new(
-        "_SB_.COM1".into(),
+    // The RTC of the CMOS, which the guests of a hardware-reduced ACPI
+    // platform only use once it is declared.
+    let rtc_dsdt_data = aml::Device::new(
+        "_SB_.RTC_".into(),
         vec![
-            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0501")),
-            &aml::Name::new("_UID".into(), &aml::ZERO),
+            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0B00")),
             &aml::Name::new(
                 "_CRS".into(),
-                &aml::ResourceTemplate::new(vec![
-                    &aml::Interrupt::new(true, true, false, false, 4),
-                    &aml::IO::new(0x3f8, 0x3f8, 0, 0x8),
-                ]),
+                &aml::ResourceTemplate::new(vec![&aml::IO::new(0x70, 0x70, 1, 0x2)]),
             ),
         ],
     )
     .to_aml_bytes();
 
+    // The pvpanic device of QEMU, which the guest looks up by its ID.
+    let pvpanic_dsdt_data = aml::Device::new(
+        "_SB_.PEVT".into(),
+        vec![
+            &aml::Name::new("_HID".into(), &"QEMU0001"),
+            &aml::Name::new("_STA".into(), &0xfu8),
+            &aml::Name::new(
+                "_CRS".into(),
+                &aml::ResourceTemplate::new(vec![&aml::IO::new(0x505, 0x505, 1, 0x1)]),
+            ),
+        ],
+    )
+    .to_aml_bytes();
+
+    let power_button_dsdt_data = aml::Device::new(
+        "_SB_.PWRB".into(),
+        vec![
+            &aml::Name::new("_HID".into(), &aml::EISAName::new("PNP0C0C")),
+            &aml::Name::new("_UID".into(), &aml::ZERO),
+        ],
+    )
+    .to_aml_bytes();
+
+    let mut ged_notifications = vec![GedNotification {
+        event: devices::GED_POWER_BUTTON,
+        device: "\\_SB_.PWRB",
+    }];
+    if sensors.battery {
+        ged_notifications.push(GedNotification {
+            event: devices::GED_BATTERY,
+            device: "\\_SB_.BAT0",
+        });
+    }
+    if sensors.thermal_zone {
+        ged_notifications.push(GedNotification {
+            event: devices::GED_THERMAL_ZONE,
+            device: "\\_TZ_.TZ00",
+        });
+    }
+
+    let gdat_store = aml::Store::new(&aml::Local(0), &aml::Path::new("GDAT"));
+    let mut ged_evt_children: Vec<&dyn aml::Aml> = vec![&gdat_store];
+    for notification in ged_notifications.iter() {
+        ged_evt_children.push(notification);
+    }
+    let pci_hotplug_call = GedMethodCall {
+        event: devices::GED_PCI_HOTPLUG,
+        method: "\\_SB_.PCI0.PCNT",
+    };
+    if pci_hotplug {
+        ged_evt_children.push(&pci_hotplug_call);
+    }
+
+    // The Generic Event Device reports the pending events through a single
+    // byte I/O port which is cleared when read.
+    let ged_dsdt_data = ged_irq.map(|irq| {
+        aml::Device::new(
+            "_SB_.GED_".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"ACPI0013"),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::Interrupt::new(
+                        true, true, false, false, irq,
+                    )]),
+                ),
+                &aml::OpRegion::new("GDST".into(), aml::OpRegionSpace::SystemIO, 0x3c4, 0x1),
+                &aml::Field::new(
+                    "GDST".into(),
+                    aml::FieldAccessType::Byte,
+                    aml::FieldUpdateRule::WriteAsZeroes,
+                    vec![aml::FieldEntry::Named(*b"GDAT", 8)],
+                ),
+                &aml::Method::new("_EVT".into(), 1, true, ged_evt_children.clone()),
+            ],
+        )
+        .to_aml_bytes()
+    });
+
     let s5_sleep_data =
         aml::Name::new("_S5_".into(), &aml::Package::new(vec![&5u8])).to_aml_bytes();
 
-    let cpu_data = create_cpu_data(num_cpus);
+    let cpu_data = create_cpu_data(num_cpus, frequency);
 
     // DSDT
     let mut dsdt = SDT::new(*b"DSDT", 36, 6, *b"CLOUDH", *b"CHDSDT  ", 1);
     dsdt.append_slice(pci_dsdt_data.as_slice());
+    for windows in pci_segments.iter() {
+        dsdt.append_slice(create_pci_segment_data(windows, numa).as_slice());
+    }
     dsdt.append_slice(mbrd_dsdt_data.as_slice());
     if serial_enabled {
         dsdt.append_slice(com1_dsdt_data.as_slice());
     }
+    for (index, (port, irq)) in uarts.iter().enumerate() {
+        dsdt.append_slice(create_uart_data(index, *port, *irq).as_slice());
+    }
+    if cfg!(feature = "cmos") {
+        dsdt.append_slice(rtc_dsdt_data.as_slice());
+    }
+    if tpm {
+        dsdt.append_slice(tpm_dsdt_data.as_slice());
+    }
+    if pvpanic {
+        dsdt.append_slice(pvpanic_dsdt_data.as_slice());
+    }
+    if nvdimms > 0 {
+        dsdt.append_slice(create_nvdimm_data(nvdimms).as_slice());
+    }
+    if let Some(ged_dsdt_data) = ged_dsdt_data {
+        dsdt.append_slice(power_button_dsdt_data.as_slice());
+        dsdt.append_slice(ged_dsdt_data.as_slice());
+    }
     dsdt.append_slice(s5_sleep_data.as_slice());
     dsdt.append_slice(cpu_data.as_slice());
+    if sensors.battery {
+        dsdt.append_slice(create_battery_data().as_slice());
+    }
+    if sensors.thermal_zone {
+        dsdt.append_slice(create_thermal_zone_data().as_slice());
+    }
 
     dsdt
 }
+fn append_pptt_node(pptt: &mut SDT, flags: u32, parent: u32, acpi_processor_id: u32) -> u32 {
+    let offset = pptt.len() as u32;
+    pptt.append(ProcessorHierarchyNode {
+        r#type: 0,
+        length: 20,
+        flags: flags | PPTT_IDENTICAL_IMPLEMENTATION,
+        parent,
+        acpi_processor_id,
+        ..Default::default()
+    });
+
+    offset
+}
+
+// The virtio-iommu, and each of the devices attached to it, are on PCI
+// segment 0, their endpoint ID being their b/d/f.
+fn create_viot_table(iommu_id: u32, dev_ids: &[u32]) -> SDT {
+    const NODES_OFFSET: u16 = 48;
+
+    let mut viot = SDT::new(*b"VIOT", 36, 0, *b"CLOUDH", *b"CHVIOT  ", 1);
+    // Number of nodes
+    viot.append(1 + dev_ids.len() as u16);
+    // Offset of the nodes
+    viot.append(NODES_OFFSET);
+    // VIOT reserved 8 bytes
+    viot.append(0u64);
+
+    viot.append(ViotVirtioPciIommuNode {
+        type_: VIOT_VIRTIO_PCI_IOMMU,
+        length: 16,
+        pci_segment: 0,
+        pci_bdf: iommu_id as u16,
+        ..Default::default()
+    });
+    for dev_id in dev_ids.iter() {
+        viot.append(ViotPciRangeNode {
+            type_: VIOT_PCI_RANGE,
+            length: 24,
+            endpoint_start: *dev_id,
+            pci_segment_start: 0,
+            pci_segment_end: 0,
+            pci_bdf_start: *dev_id as u16,
+            pci_bdf_end: *dev_id as u16,
+            output_node: NODES_OFFSET,
+            ..Default::default()
+        });
+    }
+
+    viot.update_checksum();
+    viot
+}
+
+// The vCPUs are numbered following the topology, the thread being the
+// fastest changing level, which matches the MADT processor IDs.
+fn create_pptt_table(topology: &CpuTopology) -> SDT {
+    let mut pptt = SDT::new(*b"PPTT", 36, 2, *b"CLOUDH", *b"CHPPTT  ", 1);
+    let mut cpu_id = 0;
+
+    for _ in 0..topology.packages {
+        let package = append_pptt_node(&mut pptt, PPTT_PHYSICAL_PACKAGE, 0, 0);
+
+        for _ in 0..topology.dies_per_package {
+            let die = if topology.dies_per_package > 1 {
+                append_pptt_node(&mut pptt, 0, package, 0)
+            } else {
+                package
+            };
+
+            for _ in 0..topology.cores_per_die {
+                if topology.threads_per_core == 1 {
+                    let flags = PPTT_PROCESSOR_ID_VALID | PPTT_NODE_IS_LEAF;
+                    append_pptt_node(&mut pptt, flags, die, cpu_id);
+                    cpu_id += 1;
+                    continue;
+                }
+
+                let core = append_pptt_node(&mut pptt, 0, die, 0);
+                for _ in 0..topology.threads_per_core {
+                    let flags =
+                        PPTT_PROCESSOR_ID_VALID | PPTT_PROCESSOR_IS_THREAD | PPTT_NODE_IS_LEAF;
+                    append_pptt_node(&mut pptt, flags, core, cpu_id);
+                    cpu_id += 1;
+                }
+            }
+        }
+    }
+
+    pptt
+}
+
+// The vCPUs which do not belong to any NUMA node are reported on node 0.
+fn create_srat_table(num_cpus: u8, numa: &[NumaConfig], numa_ranges: &[NumaMemoryRange]) -> SDT {
+    let mut srat = SDT::new(*b"SRAT", 36, 3, *b"CLOUDH", *b"CHSRAT  ", 1);
+    // SRAT reserved 4 bytes, set to 1 for backward compatibility
+    srat.append(1u32);
+    // SRAT reserved 8 bytes
+    srat.append(0u64);
+
+    for cpu in 0..num_cpus {
+        let node = numa
+            .iter()
+            .find(|node| node.cpus.iter().flatten().any(|&c| c == cpu))
+            .map_or(0, |node| node.id);
+        srat.append(ProcessorLocalApicAffinity {
+            r#type: 0,
+            length: 16,
+            proximity_domain_lo: node as u8,
+            apic_id: cpu,
+            flags: SRAT_ENABLED,
+            proximity_domain_hi: [(node >> 8) as u8, (node >> 16) as u8, (node >> 24) as u8],
+            ..Default::default()
+        });
+    }
+
+    for range in numa_ranges.iter() {
+        srat.append(MemoryAffinity {
+            r#type: 1,
+            length: 40,
+            proximity_domain: range.node,
+            base_addr_lo: range.start.raw_value() as u32,
+            base_addr_hi: (range.start.raw_value() >> 32) as u32,
+            length_lo: range.size as u32,
+            length_hi: (range.size >> 32) as u32,
+            flags: SRAT_ENABLED,
+            ..Default::default()
+        });
+    }
+
+    srat
+}
+
+// The NUMA node ids are numbered from 0, and index the distance matrix.
+fn create_slit_table(numa: &[NumaConfig]) -> SDT {
+    let mut slit = SDT::new(*b"SLIT", 36, 1, *b"CLOUDH", *b"CHSLIT  ", 1);
+    let mut nodes: Vec<&NumaConfig> = numa.iter().collect();
+    nodes.sort_by_key(|node| node.id);
+
+    // Number of system localities
+    slit.append(nodes.len() as u64);
+    for node in nodes.iter() {
+        for destination in nodes.iter() {
+            slit.append(node.distance(destination.id));
+        }
+    }
+
+    slit
+}
+
+// The TPM is reached through its CRB control area, the start method
+// needing no parameters.
+fn create_tpm2_table() -> SDT {
+    let mut tpm2 = SDT::new(*b"TPM2", 64, 4, *b"CLOUDH", *b"CHTPM2  ", 1);
+    // Platform class: client
+    tpm2.write(36, 0u16);
+    // Address of the control area
+    tpm2.write(
+        40,
+        layout::TPM_START.0 + devices::tpm::CRB_CONTROL_AREA_OFFSET,
+    );
+    // Start method: command response buffer
+    tpm2.write(48, 7u32);
+
+    tpm2.update_checksum();
+    tpm2
+}
+
+// The Windows ACPI Emulated devices Table: the RTC of the CMOS doesn't
+// need its status register C read after an interrupt, and the ACPI PM
+// timer reads are reliable, sparing Windows their workarounds.
+fn create_waet_table() -> SDT {
+    let mut waet = SDT::new(*b"WAET", 40, 1, *b"CLOUDH", *b"CHWAET  ", 1);
+    // RTC_GOOD and ACPI_PM_TIMER_GOOD
+    waet.write(36, 1u32 << 1 | 1u32);
+
+    waet.update_checksum();
+    waet
+}
+
+// The NVDIMMs are numbered from 0, their NFIT handle and physical ID
+// being their number plus one. Each range gets its own region mapping and
+// control region, as a single NVDIMM without interleaving.
+fn create_nfit_table(nvdimms: &[(GuestAddress, u64)]) -> SDT {
+    let mut nfit = SDT::new(*b"NFIT", 40, 1, *b"CLOUDH", *b"CHNFIT  ", 1);
+
+    for (index, (start, size)) in nvdimms.iter().enumerate() {
+        let handle = index as u32 + 1;
+        let spa_index = index as u16 * 2 + 1;
+        let dcr_index = index as u16 * 2 + 2;
+        nfit.append(NfitSpaRange {
+            type_: 0,
+            length: 56,
+            spa_index,
+            // Control region only for management during hot add
+            flags: 1,
+            range_guid: NFIT_PM_GUID,
+            base: start.raw_value(),
+            size: *size,
+            memory_attributes: NFIT_MEMORY_ATTRIBUTES,
+            ..Default::default()
+        });
+        nfit.append(NfitRegionMapping {
+            type_: 1,
+            length: 48,
+            handle,
+            physical_id: handle as u16,
+            spa_index,
+            dcr_index,
+            region_size: *size,
+            interleave_ways: 1,
+            ..Default::default()
+        });
+        nfit.append(NfitControlRegion {
+            type_: 4,
+            length: 80,
+            dcr_index,
+            vendor_id: 0x8086,
+            device_id: 1,
+            revision_id: 1,
+            serial_number: 0x0012_3456 + index as u32,
+            // Byte addressable, energy backed
+            interface_code: 0x301,
+            ..Default::default()
+        });
+    }
+
+    nfit
+}
+
+// An NVDIMM of the NFIT, by its handle. Its _DSM method calls the one of
+// the NVDIMM root device, which goes through the mailbox of the VMM.
+struct Nvdimm {
+    handle: u32,
+}
+
+impl aml::Aml for Nvdimm {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        aml::Device::new(
+            format!("NV{:02X}", self.handle - 1).as_str().into(),
+            vec![
+                &aml::Name::new("_ADR".into(), &self.handle),
+                &aml::Method::new(
+                    "_DSM".into(),
+                    4,
+                    true,
+                    vec![&aml::Return::new(&aml::MethodCall::new(
+                        "NCAL".into(),
+                        vec![
+                            &aml::Arg(0),
+                            &aml::Arg(1),
+                            &aml::Arg(2),
+                            &aml::Arg(3),
+                            &self.handle,
+                        ],
+                    ))],
+                ),
+            ],
+        )
+        .to_aml_bytes()
+    }
+}
+
+// Copies the arguments of the _DSM method of an NVDIMM, along with the
+// handle of the NVDIMM, to the mailbox, the function index last for the VMM
+// to run the function, and returns the output buffer of the function.
+struct NvdimmCall;
+
+impl aml::Aml for NvdimmCall {
+    fn to_aml_bytes(&self) -> Vec<u8> {
+        aml::Method::new(
+            "NCAL".into(),
+            5,
+            true,
+            vec![
+                &aml::Store::new(&aml::Path::new("HDLE"), &aml::Arg(4)),
+                &aml::Store::new(&aml::Path::new("REVN"), &aml::Arg(1)),
+                &aml::Store::new(&aml::Path::new("UUID"), &aml::Arg(0)),
+                // The query function has no input buffer.
+                &aml::If::new(
+                    &aml::Equal::new(&aml::SizeOf::new(&aml::Arg(3)), &aml::ONE),
+                    vec![&aml::Store::new(
+                        &aml::Path::new("INPB"),
+                        &aml::DerefOf::new(&aml::Index::new(&aml::Arg(3), &aml::ZERO)),
+                    )],
+                ),
+                &aml::Store::new(&aml::Path::new("FUNC"), &aml::Arg(2)),
+                &aml::Mid::new(
+                    &aml::Local(0),
+                    &aml::Path::new("ODAT"),
+                    &aml::ZERO,
+                    &aml::Path::new("OLEN"),
+                ),
+                &aml::Return::new(&aml::Local(0)),
+            ],
+        )
+        .to_aml_bytes()
+    }
+}
+
+// The NVDIMM root device, along with the mailbox and the NVDIMMs.
+fn create_nvdimm_data(count: usize) -> Vec<u8> {
+    use devices::nvdimm::{
+        DSM_FUNCTION, DSM_INPUT, DSM_INPUT_SIZE, DSM_OUTPUT_LENGTH, DSM_OUTPUT_SIZE, DSM_UUID,
+    };
+
+    let hid = aml::Name::new("_HID".into(), &"ACPI0012");
+    let mailbox = aml::OpRegion::new(
+        "NDSM".into(),
+        aml::OpRegionSpace::SystemMemory,
+        layout::NVDIMM_DSM_START.0 as usize,
+        layout::NVDIMM_DSM_SIZE as usize,
+    );
+    let fields = aml::Field::new(
+        "NDSM".into(),
+        aml::FieldAccessType::DWord,
+        aml::FieldUpdateRule::Preserve,
+        vec![
+            aml::FieldEntry::Named(*b"HDLE", 32),
+            aml::FieldEntry::Named(*b"REVN", 32),
+            aml::FieldEntry::Named(*b"FUNC", 32),
+            aml::FieldEntry::Reserved((DSM_UUID - DSM_FUNCTION - 4) * 8),
+            aml::FieldEntry::Named(*b"UUID", 128),
+            aml::FieldEntry::Named(*b"INPB", DSM_INPUT_SIZE * 8),
+            aml::FieldEntry::Reserved((DSM_OUTPUT_LENGTH - DSM_INPUT - DSM_INPUT_SIZE) * 8),
+            aml::FieldEntry::Named(*b"OLEN", 32),
+            aml::FieldEntry::Named(*b"ODAT", DSM_OUTPUT_SIZE * 8),
+        ],
+    );
+    let call = NvdimmCall;
+    let mut nvdimm_data_inner: Vec<&dyn aml::Aml> = vec![&hid, &mailbox, &fields, &call];
+
+    let nvdimms: Vec<Nvdimm> = (0..count)
+        .map(|index| Nvdimm {
+            handle: index as u32 + 1,
+        })
+        .collect();
+    for nvdimm in nvdimms.iter() {
+        nvdimm_data_inner.push(nvdimm);
+    }
+
+    aml::Device::new("_SB_.NVDR".into(), nvdimm_data_inner).to_aml_bytes()
+}
+
+/// Checks that a table read as is has a header, and that its length and
+/// checksum match its content.
+pub fn valid_table(data: &[u8]) -> bool {
+    if data.len() < 36 {
+        return false;
+    }
+
+    let mut length = [0u8; 4];
+    length.copy_from_slice(&data[4..8]);
+    u32::from_le_bytes(length) as usize == data.len()
+        && data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// OEM table whose content is its entries, as NUL terminated `key=value`
+/// strings. The signature must be 4 characters long.
+pub fn create_oem_table(config: &AcpiOemTableConfig) -> SDT {
+    let mut signature = [0u8; 4];
+    signature.copy_from_slice(config.signature.as_bytes());
+    let mut oem_table = *b"CH      ";
+    oem_table[2..6].copy_from_slice(&signature);
+
+    let mut table = SDT::new(signature, 36, 1, *b"CLOUDH", oem_table, 1);
+    for (key, value) in config.entries.iter() {
+        table.append_slice(format!("{}={}\0", key, value).as_bytes());
+    }
+
+    table
+}
+
+/// Reads back the tables create_acpi_tables() wrote from `rsdp_addr`, with
+/// their addresses, the DSDT first and the XSDT last, for the firmwares
+/// that don't find them in the guest memory.
+pub fn read_tables(
+    guest_mem: &GuestMemoryMmap,
+    rsdp_addr: GuestAddress,
+) -> Option<Vec<(GuestAddress, Vec<u8>)>> {
+    let read_table = |address: GuestAddress| {
+        let length: u32 = guest_mem.read_obj(address.checked_add(4)?).ok()?;
+        let mut table = vec![0u8; length as usize];
+        guest_mem.read_slice(&mut table, address).ok()?;
+        Some((address, table))
+    };
+
+    let xsdt_addr: u64 = guest_mem.read_obj(rsdp_addr.checked_add(24)?).ok()?;
+    let xsdt = read_table(GuestAddress(xsdt_addr))?;
+    let mut tables = Vec::new();
+    for entry in xsdt.1[36..].chunks_exact(8) {
+        let address = u64::from_le_bytes(entry.try_into().unwrap());
+        tables.push(read_table(GuestAddress(address))?);
+    }
+    // The FADT is the first table, pointing at the DSDT.
+    let dsdt_addr: u64 = guest_mem
+        .read_obj(tables.first()?.0.checked_add(140)?)
+        .ok()?;
+    tables.insert(0, read_table(GuestAddress(dsdt_addr))?);
+    tables.push(xsdt);
+
+    Some(tables)
+}
+
+/// OEM table with the `hostname` of the guest and its comma separated DNS
+/// `resolvers`, if it is given any of them.
+pub fn create_identity_table(config: &VmConfig) -> Option<SDT> {
+    let mut entries = BTreeMap::new();
+    if let Some(hostname) = &config.hostname {
+        entries.insert("hostname".to_string(), hostname.clone());
+    }
+    if let Some(resolvers) = &config.resolvers {
+        let resolvers: Vec<String> = resolvers.iter().map(|r| r.to_string()).collect();
+        entries.insert("resolvers".to_string(), resolvers.join(","));
+    }
+    if entries.is_empty() {
+        return None;
+    }
+
+    Some(create_oem_table(&AcpiOemTableConfig {
+        signature: "OEMI".to_string(),
+        entries,
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn create_acpi_tables(
     guest_mem: &GuestMemoryMmap,
     num_cpus: u8,
     serial_enabled: bool,
+    uarts: &[(u16, u8)],
     start_of_device_area: GuestAddress,
     end_of_device_area: GuestAddress,
+    pci_segments: &[PciSegmentWindows],
     virt_iommu: Option<(u32, &[u32])>,
+    ged_irq: Option<u32>,
+    topology: Option<&CpuTopology>,
+    numa: Option<(&[NumaConfig], &[NumaMemoryRange])>,
+    sensors: &SensorsConfig,
+    frequency: Option<CpuFrequency>,
+    tpm: bool,
+    pvpanic: bool,
+    pci_hotplug: bool,
+    nvdimms: &[(GuestAddress, u64)],
+    waet: bool,
+    user_tables: &[Vec<u8>],
 ) -> GuestAddress {
     // RSDP is at the EBDA
     let rsdp_offset = layout::RSDP_POINTER;
     let mut tables: Vec<u64> = Vec::new();
 
     // DSDT
+    let numa_nodes = match numa {
+        Some((numa_nodes, _)) => numa_nodes,
+        None => &[],
+    };
     let dsdt = create_dsdt_table(
         serial_enabled,
+        uarts,
         start_of_device_area,
         end_of_device_area,
+        pci_segments,
+        numa_nodes,
         num_cpus,
+        ged_irq,
+        sensors,
+        frequency,
+        tpm,
+        pvpanic,
+        pci_hotplug,
+        nvdimms.len(),
     );
     let dsdt_offset = rsdp_offset.checked_add(RSDP::len() as u64).unwrap();
     guest_mem
@@ -286,6 +1483,11 @@ pub fn create_acpi_tables(
     // Revision 6 of the ACPI FADT table is 276 bytes long
     let mut facp = SDT::new(*b"FACP", 276, 6, *b"CLOUDH", *b"CHFACP  ", 1);
 
+    // CENTURY, the CMOS register of the century
+    if cfg!(feature = "cmos") {
+        facp.write(108, 0x32u8);
+    }
+
     // HW_REDUCED_ACPI and RESET_REG_SUP
     let fadt_flags: u32 = 1 << 20 | 1 << 10;
     facp.write(112, fadt_flags);
@@ -366,6 +1568,17 @@ pub fn create_acpi_tables(
         ..Default::default()
     });
 
+    // The other segments only have their bus 0.
+    for windows in pci_segments.iter() {
+        mcfg.append(PCIRangeEntry {
+            base_address: windows.mmconfig.0.raw_value(),
+            segment: windows.id,
+            start: 0,
+            end: 0,
+            ..Default::default()
+        });
+    }
+
     let mcfg_offset = madt_offset.checked_add(madt.len() as u64).unwrap();
     guest_mem
         .write_slice(mcfg.as_slice(), mcfg_offset)
@@ -426,11 +1639,111 @@ pub fn create_acpi_tables(
             .expect("Error writing IORT table");
         tables.push(iort_offset.0);
 
-        (iort.len(), iort_offset)
+        // VIOT, which the guests look the virtio-iommu up in, IORT being
+        // for the ones predating it.
+        let viot = create_viot_table(*iommu_id, dev_ids);
+        let viot_offset = iort_offset.checked_add(iort.len() as u64).unwrap();
+        guest_mem
+            .write_slice(viot.as_slice(), viot_offset)
+            .expect("Error writing VIOT table");
+        tables.push(viot_offset.0);
+
+        (viot.len(), viot_offset)
     } else {
         (mcfg.len(), mcfg_offset)
     };
 
+    let (prev_tbl_len, prev_tbl_off) = if let Some(topology) = topology {
+        // PPTT
+        let pptt = create_pptt_table(topology);
+        let pptt_offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+        guest_mem
+            .write_slice(pptt.as_slice(), pptt_offset)
+            .expect("Error writing PPTT table");
+        tables.push(pptt_offset.0);
+
+        (pptt.len(), pptt_offset)
+    } else {
+        (prev_tbl_len, prev_tbl_off)
+    };
+
+    let (prev_tbl_len, prev_tbl_off) = if let Some((numa, numa_ranges)) = numa {
+        // SRAT
+        let srat = create_srat_table(num_cpus, numa, numa_ranges);
+        let srat_offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+        guest_mem
+            .write_slice(srat.as_slice(), srat_offset)
+            .expect("Error writing SRAT table");
+        tables.push(srat_offset.0);
+
+        // SLIT
+        let slit = create_slit_table(numa);
+        let slit_offset = srat_offset.checked_add(srat.len() as u64).unwrap();
+        guest_mem
+            .write_slice(slit.as_slice(), slit_offset)
+            .expect("Error writing SLIT table");
+        tables.push(slit_offset.0);
+
+        (slit.len(), slit_offset)
+    } else {
+        (prev_tbl_len, prev_tbl_off)
+    };
+
+    let (prev_tbl_len, prev_tbl_off) = if tpm {
+        // TPM2
+        let tpm2 = create_tpm2_table();
+        let tpm2_offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+        guest_mem
+            .write_slice(tpm2.as_slice(), tpm2_offset)
+            .expect("Error writing TPM2 table");
+        tables.push(tpm2_offset.0);
+
+        (tpm2.len(), tpm2_offset)
+    } else {
+        (prev_tbl_len, prev_tbl_off)
+    };
+
+    let (prev_tbl_len, prev_tbl_off) = if !nvdimms.is_empty() {
+        // NFIT
+        let nfit = create_nfit_table(nvdimms);
+        let nfit_offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+        guest_mem
+            .write_slice(nfit.as_slice(), nfit_offset)
+            .expect("Error writing NFIT table");
+        tables.push(nfit_offset.0);
+
+        (nfit.len(), nfit_offset)
+    } else {
+        (prev_tbl_len, prev_tbl_off)
+    };
+
+    let (prev_tbl_len, prev_tbl_off) = if waet {
+        // WAET
+        let waet = create_waet_table();
+        let waet_offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+        guest_mem
+            .write_slice(waet.as_slice(), waet_offset)
+            .expect("Error writing WAET table");
+        tables.push(waet_offset.0);
+
+        (waet.len(), waet_offset)
+    } else {
+        (prev_tbl_len, prev_tbl_off)
+    };
+
+    // User tables, as is
+    let (mut prev_tbl_len, mut prev_tbl_off) = (prev_tbl_len, prev_tbl_off);
+    for table in user_tables.iter() {
+        let table_offset = prev_tbl_off.checked_add(prev_tbl_len as u64).unwrap();
+        guest_mem
+            .write_slice(table, table_offset)
+            .expect("Error writing user table");
+        tables.push(table_offset.0);
+
+        prev_tbl_len = table.len();
+        prev_tbl_off = table_offset;
+    }
+
     // XSDT
     let mut xsdt = SDT::new(*b"XSDT", 36, 1, *b"CLOUDH", *b"CHXSDT  ", 1);
     for table in tables {
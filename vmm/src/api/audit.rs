@@ -0,0 +1,139 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Access log of the HTTP API, auditing the requests the management plane
+//! sent to the VMM.
+//!
+//! Each request is written as a JSON object on a line of its own, once it
+//! is answered: its method, path and query, the ID the client tagged it
+//! with, the size and BLAKE2b digest of its body, the status of the
+//! response and the time the VMM took to answer. The bodies themselves
+//! aren't logged, as they may hold secrets, only telling apart the requests
+//! with different ones.
+
+use crate::api::http_endpoint::status_code;
+use micro_http::{Method, Request, StatusCode};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Length of the digests of the request bodies, in bytes.
+const BODY_DIGEST_LENGTH: usize = 32;
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    /// Milliseconds since the UNIX epoch, when the request was received.
+    timestamp: u64,
+    method: String,
+    /// Path of the endpoint, along with the query of the request.
+    path: &'a str,
+    /// ID the client tagged the request with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+    /// Size of the body, in bytes.
+    body_size: usize,
+    /// BLAKE2b-256 digest of the body, in hexadecimal, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_digest: Option<String>,
+    status: u16,
+    /// Microseconds the VMM took to answer the request.
+    latency_us: u64,
+}
+
+/// Opens the access log, given as `path=<log_file>`, `fd=<file_descriptor>`,
+/// for a file descriptor the VMM inherited, or as a bare path. A file given
+/// by path is appended to, for the log to keep the requests to the previous
+/// VMM processes.
+pub fn open_audit_log(audit_log: &str) -> io::Result<File> {
+    if audit_log.starts_with("fd=") {
+        return crate::event_monitor::open_event_file(audit_log);
+    }
+
+    let path = if audit_log.starts_with("path=") {
+        &audit_log[5..]
+    } else {
+        audit_log
+    };
+
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// The access log of the API, written by the HTTP server thread.
+pub struct ApiAuditLog {
+    file: Mutex<File>,
+}
+
+/// A request the access log is to be written about once it is answered.
+pub struct AuditedRequest {
+    timestamp: u64,
+    start: Instant,
+}
+
+impl ApiAuditLog {
+    pub fn new(file: File) -> Self {
+        ApiAuditLog {
+            file: Mutex::new(file),
+        }
+    }
+
+    /// Starts timing a request the server just received.
+    pub fn begin(&self) -> AuditedRequest {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+
+        AuditedRequest {
+            timestamp,
+            start: Instant::now(),
+        }
+    }
+
+    /// Writes the entry of a request, given the status it was answered
+    /// with.
+    pub fn log(
+        &self,
+        audited: AuditedRequest,
+        request: &Request,
+        request_id: Option<&str>,
+        status: StatusCode,
+    ) -> io::Result<()> {
+        let latency = audited.start.elapsed();
+        let body = request.body.as_ref().map(|body| body.raw()).unwrap_or(&[]);
+        let body_digest = if body.is_empty() {
+            None
+        } else {
+            Some(
+                blake2b_simd::Params::new()
+                    .hash_length(BODY_DIGEST_LENGTH)
+                    .hash(body)
+                    .to_hex()
+                    .to_string(),
+            )
+        };
+        let entry = AuditEntry {
+            timestamp: audited.timestamp,
+            method: method_name(request.method()),
+            path: request.uri().get_abs_path(),
+            request_id,
+            body_size: body.len(),
+            body_digest,
+            status: status_code(status),
+            latency_us: latency.as_micros() as u64,
+        };
+
+        let mut line = serde_json::to_vec(&entry)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        line.push(b'\n');
+        // A single write, for the entries not to be interleaved with the
+        // ones of another process appending to the file.
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
+
+fn method_name(method: Method) -> String {
+    format!("{:?}", method).to_uppercase()
+}
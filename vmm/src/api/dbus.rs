@@ -0,0 +1,297 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! D-Bus frontend of the API, for the hosts managed through systemd and the
+//! desktop virtualization tools to drive the VMM without speaking HTTP over
+//! a UNIX socket.
+//!
+//! The VMM owns a service name on the session bus, or on the system bus,
+//! and serves the `org.cloudhypervisor.DBusApi1` interface at an object
+//! path. Each of the endpoints of the HTTP API is a method of the
+//! interface, e.g. `VmBoot` for `vm.boot`, the request and response bodies
+//! being the same JSON documents, as strings. A failed request is answered
+//! with an `org.freedesktop.DBus.Error.Failed` error, whose message is the
+//! cause of the error, or with an `InvalidArgs` one for a body which can't
+//! be deserialized.
+//!
+//! The VM lifecycle events are emitted as `Event` signals, the JSON object
+//! of the event monitor being their argument.
+
+use crate::api::{
+    vm_add_user_device, vm_add_vsock, vm_apply, vm_boot, vm_claim, vm_coredump, vm_create,
+    vm_delete, vm_device_audit, vm_dirty_rate, vm_info, vm_pause, vm_power_button, vm_quiesce,
+    vm_reboot, vm_remove_device, vm_reset_device, vm_resume, vm_screenshot, vm_set_disk_weight,
+    vm_set_sensors, vm_shutdown, vm_throttle, vm_update, vmm_capabilities, vmm_fds,
+    vmm_host_resources, vmm_pool, vmm_shutdown, ApiError, ApiResult, ApiSender, VmThrottleData,
+};
+use crate::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+use zbus::{dbus_interface, fdo};
+use zvariant::ObjectPath;
+
+/// Name of the interface the API is served through.
+pub const DBUS_INTERFACE: &str = "org.cloudhypervisor.DBusApi1";
+/// Service name the VMM owns on the bus, by default.
+pub const DEFAULT_DBUS_SERVICE_NAME: &str = "org.cloudhypervisor.DBusApi";
+/// Object path the API is served at, by default.
+pub const DEFAULT_DBUS_OBJECT_PATH: &str = "/org/cloudhypervisor/DBusApi";
+
+/// Where the D-Bus frontend of the API is served.
+pub struct DBusApiOptions {
+    pub service_name: String,
+    pub object_path: String,
+    /// The system bus, rather than the session bus.
+    pub system_bus: bool,
+}
+
+struct DBusApi {
+    api_notifier: EventFd,
+    api_sender: ApiSender,
+}
+
+fn failed(error: &ApiError) -> fdo::Error {
+    fdo::Error::Failed(format!("{:?}", error))
+}
+
+impl DBusApi {
+    // Sends the request to the VMM thread, with the JSON body it got, if
+    // any, and answers with the JSON body of the response.
+    fn request<T, R>(
+        &self,
+        request: fn(EventFd, ApiSender, Arc<T>) -> ApiResult<R>,
+        body: &str,
+    ) -> fdo::Result<String>
+    where
+        T: DeserializeOwned,
+        R: Serialize,
+    {
+        let data: T =
+            serde_json::from_str(body).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+
+        self.action(|api_notifier, api_sender| request(api_notifier, api_sender, Arc::new(data)))
+    }
+
+    fn action<R: Serialize>(
+        &self,
+        action: impl FnOnce(EventFd, ApiSender) -> ApiResult<R>,
+    ) -> fdo::Result<String> {
+        let api_notifier = self
+            .api_notifier
+            .try_clone()
+            .map_err(|e| fdo::Error::IOError(e.to_string()))?;
+        let response = action(api_notifier, self.api_sender.clone()).map_err(|e| failed(&e))?;
+
+        serde_json::to_string(&response).map_err(|e| fdo::Error::Failed(e.to_string()))
+    }
+}
+
+#[dbus_interface(name = "org.cloudhypervisor.DBusApi1")]
+impl DBusApi {
+    fn vm_create(&self, config: &str) -> fdo::Result<()> {
+        self.request(vm_create, config).map(|_| ())
+    }
+
+    fn vm_boot(&self) -> fdo::Result<()> {
+        self.action(vm_boot).map(|_| ())
+    }
+
+    fn vm_delete(&self) -> fdo::Result<()> {
+        self.action(vm_delete).map(|_| ())
+    }
+
+    fn vm_info(&self) -> fdo::Result<String> {
+        self.action(vm_info)
+    }
+
+    fn vm_pause(&self) -> fdo::Result<()> {
+        self.action(vm_pause).map(|_| ())
+    }
+
+    fn vm_resume(&self) -> fdo::Result<()> {
+        self.action(vm_resume).map(|_| ())
+    }
+
+    fn vm_shutdown(&self) -> fdo::Result<()> {
+        self.action(vm_shutdown).map(|_| ())
+    }
+
+    fn vm_reboot(&self) -> fdo::Result<()> {
+        self.action(vm_reboot).map(|_| ())
+    }
+
+    fn vm_power_button(&self) -> fdo::Result<()> {
+        self.action(vm_power_button).map(|_| ())
+    }
+
+    fn vm_quiesce(&self) -> fdo::Result<()> {
+        self.action(vm_quiesce).map(|_| ())
+    }
+
+    fn vm_set_sensors(&self, sensors: &str) -> fdo::Result<()> {
+        self.request(vm_set_sensors, sensors).map(|_| ())
+    }
+
+    fn vm_coredump(&self, data: &str) -> fdo::Result<()> {
+        self.request(vm_coredump, data).map(|_| ())
+    }
+
+    fn vm_reset_device(&self, data: &str) -> fdo::Result<()> {
+        self.request(vm_reset_device, data).map(|_| ())
+    }
+
+    fn vm_device_audit(&self) -> fdo::Result<String> {
+        self.action(vm_device_audit)
+    }
+
+    fn vm_set_disk_weight(&self, data: &str) -> fdo::Result<()> {
+        self.request(vm_set_disk_weight, data).map(|_| ())
+    }
+
+    fn vm_dirty_rate(&self, data: &str) -> fdo::Result<String> {
+        self.request(vm_dirty_rate, data)
+    }
+
+    fn vm_screenshot(&self, data: &str) -> fdo::Result<()> {
+        self.request(vm_screenshot, data).map(|_| ())
+    }
+
+    // An empty body only reports the throttling, as no body does over HTTP.
+    fn vm_throttle(&self, data: &str) -> fdo::Result<String> {
+        if data.is_empty() {
+            return self
+                .action(|api_notifier, api_sender| vm_throttle(api_notifier, api_sender, None));
+        }
+        let data: VmThrottleData =
+            serde_json::from_str(data).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+
+        self.action(|api_notifier, api_sender| {
+            vm_throttle(api_notifier, api_sender, Some(Arc::new(data)))
+        })
+    }
+
+    // The device the running VM hot-added, null when the VM doesn't run.
+    fn vm_add_vsock(&self, config: &str) -> fdo::Result<String> {
+        self.request(vm_add_vsock, config)
+    }
+
+    fn vm_add_user_device(&self, config: &str) -> fdo::Result<String> {
+        self.request(vm_add_user_device, config)
+    }
+
+    fn vm_remove_device(&self, data: &str) -> fdo::Result<()> {
+        self.request(vm_remove_device, data).map(|_| ())
+    }
+
+    fn vm_apply(&self, config: &str) -> fdo::Result<String> {
+        self.request(vm_apply, config)
+    }
+
+    fn vm_update(&self, update: &str) -> fdo::Result<()> {
+        self.request(vm_update, update).map(|_| ())
+    }
+
+    fn vm_claim(&self, data: &str) -> fdo::Result<()> {
+        self.request(vm_claim, data).map(|_| ())
+    }
+
+    fn vmm_capabilities(&self) -> fdo::Result<String> {
+        self.action(vmm_capabilities)
+    }
+
+    fn vmm_fds(&self) -> fdo::Result<String> {
+        self.action(vmm_fds)
+    }
+
+    fn vmm_host_resources(&self) -> fdo::Result<String> {
+        self.action(vmm_host_resources)
+    }
+
+    fn vmm_pool(&self, data: &str) -> fdo::Result<()> {
+        self.request(vmm_pool, data).map(|_| ())
+    }
+
+    fn vmm_shutdown(&self) -> fdo::Result<()> {
+        self.action(vmm_shutdown).map(|_| ())
+    }
+
+    /// A VM lifecycle event, as the JSON object of the event monitor.
+    #[dbus_interface(signal)]
+    fn event(&self, event: &str) -> zbus::Result<()>;
+}
+
+/// Serves the API on the bus, and emits the events received from the VMM
+/// thread as signals.
+pub fn start_dbus_thread(
+    options: DBusApiOptions,
+    api_notifier: EventFd,
+    api_sender: ApiSender,
+    events: Receiver<String>,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    let connection = if options.system_bus {
+        zbus::Connection::new_system()
+    } else {
+        zbus::Connection::new_session()
+    }
+    .map_err(Error::DBusApi)?;
+
+    ObjectPath::try_from(options.object_path.as_str())
+        .map_err(|e| Error::DBusApi(zbus::Error::from(e)))?;
+
+    let reply = fdo::DBusProxy::new(&connection)
+        .map_err(Error::DBusApi)?
+        .request_name(
+            &options.service_name,
+            fdo::RequestNameFlags::DoNotQueue.into(),
+        )
+        .map_err(Error::DBusApiName)?;
+    match reply {
+        fdo::RequestNameReply::PrimaryOwner | fdo::RequestNameReply::AlreadyOwner => {}
+        _ => return Err(Error::DBusApiNameOwned(options.service_name)),
+    }
+
+    let server_connection = connection.clone();
+    let object_path = options.object_path;
+    let api = DBusApi {
+        api_notifier,
+        api_sender,
+    };
+    let server_object_path = object_path.clone();
+    let server = thread::Builder::new()
+        .name("dbus-api".to_string())
+        .spawn(move || -> Result<()> {
+            let mut object_server = zbus::ObjectServer::new(&server_connection);
+            let path = ObjectPath::try_from(server_object_path.as_str())
+                .map_err(|e| Error::DBusApi(zbus::Error::from(e)))?;
+            object_server.at(&path, api).map_err(Error::DBusApi)?;
+
+            loop {
+                if let Err(e) = object_server.try_handle_next() {
+                    error!("D-Bus API error on handling a message: {}", e);
+                }
+            }
+        })
+        .map_err(Error::DBusThreadSpawn)?;
+
+    thread::Builder::new()
+        .name("dbus-events".to_string())
+        .spawn(move || {
+            for event in events.iter() {
+                if let Err(e) =
+                    connection.emit_signal(None, &object_path, DBUS_INTERFACE, "Event", &event)
+                {
+                    warn!("Cannot emit the D-Bus event signal: {}", e);
+                }
+            }
+        })
+        .map_err(Error::DBusThreadSpawn)?;
+
+    Ok(server)
+}
@@ -0,0 +1,131 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Socket the API clients hand already opened files to the VMM through,
+//! as SCM_RIGHTS ancillary data, so that the VMM doesn't need the rights to
+//! open the disk images, persistent memory files and tap interfaces of the
+//! VM on its own.
+//!
+//! Each message is the name of the file, ended by a newline, and carries
+//! its file descriptor. The VMM answers with `OK` or with `ERROR: <reason>`,
+//! on a line of its own. The VM configurations then refer to the file
+//! through its name, in the `fd` field of their disks, pmem devices and
+//! network interfaces. A name sent without a file descriptor forgets the
+//! file it was given to.
+//!
+//! The HTTP server doesn't give access to the ancillary data of the API
+//! socket, the files being passed through this socket of their own instead.
+
+use crate::{Error, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use vhost_rs::vhost_user::sock_ctrl_msg::ScmSocket;
+
+/// The files the API clients passed, by name.
+pub type PassedFds = Arc<Mutex<HashMap<String, File>>>;
+
+// Longest file name, newline included.
+const MAX_NAME_LEN: usize = 64;
+
+// The file descriptors of a message beyond the first one are closed, the
+// control buffer having room for a few of them.
+const MAX_MSG_FDS: usize = 4;
+
+// A client doesn't hold the socket for longer while sending a name.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Reads the messages of a client until it closes the connection, answering
+// each of them.
+fn handle_client(mut socket: UnixStream, passed_fds: &PassedFds) -> io::Result<()> {
+    socket.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut buf = [0u8; MAX_NAME_LEN];
+    let mut len = 0;
+    let mut files = Vec::new();
+    loop {
+        let (count, mut message_files) = socket
+            .recv_with_files(&mut buf[len..], MAX_MSG_FDS)
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        if count == 0 {
+            return Ok(());
+        }
+        len += count;
+        files.append(&mut message_files);
+
+        let end = match buf[..len].iter().position(|&b| b == b'\n') {
+            Some(end) => end,
+            None if len == buf.len() => {
+                socket.write_all(b"ERROR: name too long\n")?;
+                return Ok(());
+            }
+            None => continue,
+        };
+
+        let line = String::from_utf8_lossy(&buf[..end]).to_string();
+        let name = line.trim();
+        let reply = if !valid_name(name) {
+            format!("ERROR: invalid name {:?}\n", name)
+        } else if files.len() > 1 {
+            format!("ERROR: {} file descriptors for {:?}\n", files.len(), name)
+        } else {
+            let mut passed_fds = passed_fds.lock().unwrap();
+            match files.pop() {
+                Some(file) => {
+                    info!("File descriptor {} passed as {:?}", file.as_raw_fd(), name);
+                    passed_fds.insert(name.to_string(), file);
+                }
+                None => {
+                    passed_fds.remove(name);
+                }
+            }
+            "OK\n".to_string()
+        };
+        socket.write_all(reply.as_bytes())?;
+
+        // Dropping the extra files closes them.
+        files.clear();
+        buf.copy_within(end + 1..len, 0);
+        len -= end + 1;
+    }
+}
+
+pub fn start_fd_socket_thread(
+    path: &str,
+    passed_fds: PassedFds,
+) -> Result<thread::JoinHandle<Result<()>>> {
+    std::fs::remove_file(path).unwrap_or_default();
+    let listener = UnixListener::bind(path).map_err(Error::Bind)?;
+
+    thread::Builder::new()
+        .name("fd-socket".to_string())
+        .spawn(move || {
+            for socket in listener.incoming() {
+                match socket {
+                    Ok(socket) => {
+                        if let Err(e) = handle_client(socket, &passed_fds) {
+                            error!("File descriptor passing error: {}", e);
+                        }
+                    }
+                    Err(e) => error!("File descriptor socket error on accept: {}", e),
+                }
+            }
+
+            Ok(())
+        })
+        .map_err(Error::FdSocketThreadSpawn)
+}
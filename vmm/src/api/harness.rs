@@ -0,0 +1,492 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Drives the HTTP API through sequences of operations, valid or not, the
+//! VMM thread being stood in for by a model of the VM it manages, whose
+//! hypervisor fails to create the VM on demand.
+//!
+//! The model follows the VM state machine, `VmState::valid_transition`, as
+//! the VMM thread and the VM do. Each response is checked against the status
+//! a client expects from the operation in the state the VM is in, the state
+//! `vm.info` reports against the one of the model, and the VM is deleted at
+//! the end of each sequence, which leaves the VMM without any VM whatever
+//! the sequence did.
+//!
+//! The operations are read from arbitrary bytes, for `cargo fuzz` to explore
+//! the sequences guided by their coverage (see `fuzz/`), the tests running
+//! sequences of pseudo-random bytes.
+
+use crate::api::http::{handle_http_request, HTTP_ROOT};
+use crate::api::http_endpoint::status_code;
+use crate::api::{ApiError, ApiMessage, ApiRequest, ApiResponsePayload, ApiResult, ApiSender};
+use crate::config::VmConfig;
+use crate::vm::{Error as VmError, VmState};
+use micro_http::Request;
+use std::io;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+// Configuration of the VM the sequences create, which the model never runs.
+const VM_CONFIG: &str = r#"{"kernel":{"path":"/nonexistent/vmlinux"},"cmdline":{"args":""}}"#;
+
+// Whether a reboot creates a new VM, rather than shutting the VM down.
+const REBOOT_RECREATES: bool = cfg!(all(feature = "acpi", target_arch = "x86_64"));
+
+/// An operation on the API.
+#[derive(Clone, Copy, Debug)]
+pub enum Operation {
+    Create,
+    /// `vm.create` with a body which isn't a VM configuration.
+    CreateInvalid,
+    /// `vm.boot`, the hypervisor failing to create the VM when `fails`.
+    Boot {
+        fails: bool,
+    },
+    Pause,
+    Resume,
+    Shutdown,
+    /// `vm.reboot`, the hypervisor failing to create the new VM when `fails`.
+    Reboot {
+        fails: bool,
+    },
+    Delete,
+    Info,
+    /// `vm.boot` with a method the endpoint doesn't handle.
+    WrongMethod,
+    /// A path which isn't an endpoint of the API.
+    UnknownEndpoint,
+    /// `vm.pause` naming a VM by an invalid ID.
+    InvalidVmId,
+}
+
+const OPERATIONS: u8 = 12;
+
+impl Operation {
+    fn from_byte(byte: u8) -> Self {
+        let fails = byte & 0x80 != 0;
+        match (byte & 0x7f) % OPERATIONS {
+            0 => Operation::Create,
+            1 => Operation::CreateInvalid,
+            2 => Operation::Boot { fails },
+            3 => Operation::Pause,
+            4 => Operation::Resume,
+            5 => Operation::Shutdown,
+            6 => Operation::Reboot { fails },
+            7 => Operation::Delete,
+            8 => Operation::Info,
+            9 => Operation::WrongMethod,
+            10 => Operation::UnknownEndpoint,
+            _ => Operation::InvalidVmId,
+        }
+    }
+
+    fn request(self) -> Request {
+        match self {
+            Operation::Create => http_request("PUT", "vm.create", Some(VM_CONFIG)),
+            Operation::CreateInvalid => http_request("PUT", "vm.create", Some(r#"{"kernel":"#)),
+            Operation::Boot { .. } => http_request("PUT", "vm.boot", None),
+            Operation::Pause => http_request("PUT", "vm.pause", None),
+            Operation::Resume => http_request("PUT", "vm.resume", None),
+            Operation::Shutdown => http_request("PUT", "vm.shutdown", None),
+            Operation::Reboot { .. } => http_request("PUT", "vm.reboot", None),
+            Operation::Delete => http_request("PUT", "vm.delete", None),
+            Operation::Info => http_request("GET", "vm.info", None),
+            Operation::WrongMethod => http_request("GET", "vm.boot", None),
+            Operation::UnknownEndpoint => http_request("PUT", "vm.unknown", None),
+            Operation::InvalidVmId => http_request("PUT", "vm.pause?id=vm/1", None),
+        }
+    }
+}
+
+fn http_request(method: &str, endpoint: &str, body: Option<&str>) -> Request {
+    let mut raw = format!("{} {}/{} HTTP/1.1\r\n", method, HTTP_ROOT, endpoint);
+    match body {
+        Some(body) => raw.push_str(&format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )),
+        None => raw.push_str("\r\n"),
+    }
+
+    match Request::try_from(raw.as_bytes()) {
+        Ok(request) => request,
+        Err(_) => panic!("Invalid request {:?}", raw),
+    }
+}
+
+/// The VM of the VMM thread the model stands in for: the configuration the
+/// VM was created with, and the state of the VM once the hypervisor created
+/// it, which the VMM drops when shutting it down.
+#[derive(Default)]
+struct Model {
+    config: Option<Arc<VmConfig>>,
+    vm: Option<VmState>,
+    // The hypervisor fails to create the next VM.
+    hypervisor_fails: bool,
+}
+
+impl Model {
+    fn not_running(&self) -> VmError {
+        if self.config.is_none() {
+            VmError::VmNotCreated
+        } else {
+            VmError::VmNotRunning
+        }
+    }
+
+    fn create_vm(&mut self) -> Result<(), VmError> {
+        if self.hypervisor_fails {
+            return Err(VmError::VmCreate(io::Error::from_raw_os_error(
+                libc::ENOMEM,
+            )));
+        }
+        self.vm = Some(VmState::Created);
+
+        Ok(())
+    }
+
+    fn transition(&mut self, new_state: VmState) -> Result<(), VmError> {
+        match self.vm {
+            Some(state) => {
+                state.valid_transition(new_state)?;
+                self.vm = Some(new_state);
+                Ok(())
+            }
+            None => Err(self.not_running()),
+        }
+    }
+
+    fn start(&mut self) -> Result<(), VmError> {
+        if self.vm.is_none() {
+            self.create_vm()?;
+        }
+        self.transition(VmState::Running)
+    }
+
+    fn shutdown(&mut self) -> Result<(), VmError> {
+        match self.vm.take() {
+            Some(state) => state.valid_transition(VmState::Shutdown),
+            None => Err(self.not_running()),
+        }
+    }
+
+    fn reboot(&mut self) -> Result<(), VmError> {
+        if self.vm.is_none() {
+            return Err(self.not_running());
+        }
+        self.shutdown()?;
+        if REBOOT_RECREATES {
+            self.start()?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&mut self) -> Result<(), VmError> {
+        if self.config.is_none() {
+            return Ok(());
+        }
+        if self.vm.is_some() {
+            self.shutdown()?;
+        }
+        self.config = None;
+
+        Ok(())
+    }
+
+    fn info(&self) -> ApiResult<ApiResponsePayload> {
+        let config = self
+            .config
+            .as_ref()
+            .ok_or(ApiError::VmInfo(VmError::VmNotCreated))?;
+        let info = serde_json::json!({
+            "config": &**config,
+            "state": self.vm.unwrap_or(VmState::Created),
+        });
+
+        Ok(ApiResponsePayload::VmInfo(
+            serde_json::from_value(info).unwrap(),
+        ))
+    }
+
+    // Handles the request as the VMM thread does.
+    fn handle(&mut self, request: &ApiRequest) -> ApiResult<ApiResponsePayload> {
+        let result = match request {
+            ApiRequest::VmCreate(config, _) => {
+                if self.config.is_some() {
+                    return Err(ApiError::VmAlreadyCreated);
+                }
+                self.config = Some(config.clone());
+                Ok(())
+            }
+            ApiRequest::VmBoot(_) => {
+                if self.config.is_none() {
+                    return Err(ApiError::VmMissingConfig);
+                }
+                self.start().map_err(ApiError::VmBoot)
+            }
+            ApiRequest::VmPause(_) => self.transition(VmState::Paused).map_err(ApiError::VmPause),
+            ApiRequest::VmResume(_) => self
+                .transition(VmState::Running)
+                .map_err(ApiError::VmResume),
+            ApiRequest::VmShutdown(_) => self.shutdown().map_err(ApiError::VmShutdown),
+            ApiRequest::VmReboot(_) => self.reboot().map_err(ApiError::VmReboot),
+            ApiRequest::VmDelete(_) => self.delete().map_err(ApiError::VmDelete),
+            ApiRequest::VmInfo(_) => return self.info(),
+            _ => panic!("Request the harness doesn't send"),
+        };
+
+        result.map(|_| ApiResponsePayload::Empty)
+    }
+
+    // The status a client expects from the operation, in the current state
+    // of the VM.
+    fn expected_status(&self, operation: Operation) -> u16 {
+        let created = self.config.is_some();
+        match operation {
+            Operation::Create if created => 409,
+            Operation::Create => 204,
+            Operation::CreateInvalid | Operation::InvalidVmId => 400,
+            Operation::Info if created => 200,
+            Operation::Delete => 204,
+            Operation::WrongMethod => 405,
+            Operation::UnknownEndpoint => 404,
+            _ if !created => 404,
+            Operation::Boot { fails } => match self.vm {
+                Some(VmState::Running) => 409,
+                None if fails => 500,
+                _ => 204,
+            },
+            Operation::Pause => match self.vm {
+                Some(VmState::Running) => 204,
+                _ => 409,
+            },
+            Operation::Resume => match self.vm {
+                Some(VmState::Paused) => 204,
+                _ => 409,
+            },
+            Operation::Shutdown => match self.vm {
+                Some(_) => 204,
+                None => 409,
+            },
+            Operation::Reboot { fails } => match self.vm {
+                Some(_) if fails && REBOOT_RECREATES => 500,
+                Some(_) => 204,
+                None => 409,
+            },
+            Operation::Info => unreachable!(),
+        }
+    }
+
+    // The VM is only ever left created or running, a shutdown dropping it.
+    fn check(&self) {
+        match self.vm {
+            None | Some(VmState::Running) | Some(VmState::Paused) => {}
+            Some(state) => panic!("VM left {:?}", state),
+        }
+        assert!(self.vm.is_none() || self.config.is_some());
+    }
+}
+
+// Answers the requests as the VMM thread does, until the API is dropped.
+fn vmm_thread(receiver: Receiver<ApiMessage>, model: Arc<Mutex<Model>>) {
+    for message in receiver.iter() {
+        let response = model.lock().unwrap().handle(&message.request);
+        // The request isn't left waiting for its response.
+        if message.request.response_sender().send(response).is_err() {
+            panic!("Response not received");
+        }
+    }
+}
+
+struct Harness {
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    model: Arc<Mutex<Model>>,
+    vmm_thread: thread::JoinHandle<()>,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let (sender, receiver) = channel();
+        let model = Arc::new(Mutex::new(Model::default()));
+        let vmm_model = model.clone();
+        let vmm_thread = thread::Builder::new()
+            .name("vmm".to_string())
+            .spawn(move || vmm_thread(receiver, vmm_model))
+            .unwrap();
+
+        Harness {
+            api_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            api_sender: ApiSender::new(sender),
+            model,
+            vmm_thread,
+        }
+    }
+
+    // Sends the request of the operation, returning the status and the body
+    // of its response.
+    fn send(&self, operation: Operation) -> (u16, Option<serde_json::Value>) {
+        let request = operation.request();
+        let response = handle_http_request(&request, &self.api_evt, &self.api_sender);
+        // The API event is left unread, the VMM thread receiving the
+        // requests on its own.
+        let _ = self.api_evt.read();
+        let body = response.body().map(|body| {
+            serde_json::from_slice(body.raw())
+                .unwrap_or_else(|e| panic!("{:?}: invalid response body: {}", operation, e))
+        });
+
+        (status_code(response.status()), body)
+    }
+
+    fn run(&self, operation: Operation) {
+        let expected = {
+            let mut model = self.model.lock().unwrap();
+            model.hypervisor_fails = match operation {
+                Operation::Boot { fails } | Operation::Reboot { fails } => fails,
+                _ => false,
+            };
+            model.expected_status(operation)
+        };
+
+        let (status, body) = self.send(operation);
+        assert_eq!(status, expected, "{:?}: {:?}", operation, body);
+        self.model.lock().unwrap().check();
+
+        // The state the VMM reports is the state of the model.
+        let (status, body) = self.send(Operation::Info);
+        let model = self.model.lock().unwrap();
+        if model.config.is_none() {
+            assert_eq!(status, 404, "Info after {:?}", operation);
+            return;
+        }
+        assert_eq!(status, 200, "Info after {:?}", operation);
+        let state = model.vm.unwrap_or(VmState::Created);
+        assert_eq!(
+            body.as_ref().map(|body| &body["state"]),
+            Some(&serde_json::json!(state)),
+            "Info after {:?}",
+            operation
+        );
+    }
+
+    fn finish(self) {
+        // Whatever the sequence did, the VM can be deleted.
+        self.run(Operation::Delete);
+        {
+            let model = self.model.lock().unwrap();
+            assert!(model.config.is_none() && model.vm.is_none());
+        }
+
+        drop(self.api_sender);
+        self.vmm_thread.join().unwrap();
+    }
+}
+
+/// Runs the operations in order, panicking when a response or the state the
+/// VM ends in isn't the expected one.
+pub fn run_operations(operations: &[Operation]) {
+    let harness = Harness::new();
+    for operation in operations {
+        harness.run(*operation);
+    }
+    harness.finish();
+}
+
+/// Runs the operations the bytes give, one for each byte.
+pub fn run(data: &[u8]) {
+    let operations: Vec<Operation> = data.iter().map(|b| Operation::from_byte(*b)).collect();
+    run_operations(&operations);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pseudo-random bytes from a xorshift generator, for the sequences to
+    // be the same from a run to the next.
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_random_sequences() {
+        for seed in 1..=64 {
+            run(&random_bytes(seed, 256));
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        run_operations(&[
+            Operation::Create,
+            Operation::Boot { fails: false },
+            Operation::Pause,
+            Operation::Resume,
+            Operation::Reboot { fails: false },
+            Operation::Shutdown,
+            Operation::Boot { fails: false },
+        ]);
+    }
+
+    #[test]
+    fn test_invalid_transitions() {
+        run_operations(&[
+            Operation::Pause,
+            Operation::Boot { fails: false },
+            Operation::Create,
+            Operation::Resume,
+            Operation::Shutdown,
+            Operation::Reboot { fails: false },
+            Operation::Create,
+            Operation::Boot { fails: false },
+            Operation::Boot { fails: false },
+            Operation::Resume,
+        ]);
+    }
+
+    #[test]
+    fn test_hypervisor_failures() {
+        run_operations(&[
+            Operation::Create,
+            Operation::Boot { fails: true },
+            Operation::Pause,
+            Operation::Boot { fails: false },
+            Operation::Reboot { fails: true },
+            Operation::Boot { fails: false },
+        ]);
+    }
+
+    #[test]
+    fn test_delete_unbooted() {
+        run_operations(&[Operation::Create, Operation::Delete, Operation::Create]);
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        run_operations(&[
+            Operation::CreateInvalid,
+            Operation::WrongMethod,
+            Operation::UnknownEndpoint,
+            Operation::InvalidVmId,
+            Operation::Create,
+            Operation::InvalidVmId,
+            Operation::Boot { fails: false },
+            Operation::WrongMethod,
+        ]);
+    }
+}
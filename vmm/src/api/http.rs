@@ -3,31 +3,78 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::api::http_endpoint::{VmActionHandler, VmCreate, VmInfo, VmmShutdown};
-use crate::api::{ApiRequest, VmAction};
+use crate::api::audit::ApiAuditLog;
+use crate::api::http_endpoint::{
+    start_task, ApiDiscovery, ApiSchema, VmActionHandler, VmAddUserDevice, VmAddVsock, VmApply,
+    VmBatch, VmClaim, VmCoredump, VmCreate, VmDeviceAudit, VmDirtyRate, VmInfo, VmRemoveDevice,
+    VmResetDevice, VmScreenshot, VmSetDiskWeight, VmSetSensors, VmTaskStatus, VmThrottle, VmUpdate,
+    VmmCapabilities, VmmFds, VmmHostResources, VmmPool, VmmShutdown,
+};
+use crate::api::{ApiSender, VmAction};
 use crate::{Error, Result};
-use micro_http::{HttpServer, MediaType, Request, Response, StatusCode, Version};
+use micro_http::{Body, HttpServer, MediaType, Method, Request, Response, StatusCode, Version};
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::mpsc::Sender;
 use std::thread;
 use vmm_sys_util::eventfd::EventFd;
 
-const HTTP_ROOT: &str = "/api/v1";
+pub const HTTP_ROOT: &str = "/api/v1";
+
+/// Header the clients tag their requests with, for tracing them through the
+/// VMM logs and events.
+const REQUEST_ID_HEADER: &str = "X-Request-ID";
+const AUTHORIZATION_HEADER: &str = "Authorization";
+const BEARER_SCHEME: &str = "Bearer ";
+/// Header the clients ask for an asynchronous response with, as RFC 7240
+/// has it.
+const PREFER_HEADER: &str = "Prefer";
+const RESPOND_ASYNC: &str = "respond-async";
+
+/// Query parameter naming the VM a request is about, for a VMM process
+/// running several VMs.
+const VM_ID_PARAM: &str = "id";
+
+/// Endpoint whose ID parameter is the ID of a task, rather than of a VM.
+const TASK_STATUS_ENDPOINT: &str = "/vm.task-status";
 
 /// An HTTP endpoint handler interface
 pub trait EndpointHandler: Sync + Send {
-    /// Handles an HTTP request.
+    /// The methods the endpoint handles, the requests with other methods
+    /// being answered with 405 Method Not Allowed before reaching it.
+    fn methods(&self) -> &'static [Method];
+
+    /// Handles an HTTP request, given its method and body.
     /// After parsing the request, the handler could decide to send an
     /// associated API request down to the VMM API server to e.g. create
     /// or start a VM. The request will block waiting for an answer from the
     /// API server and translate that into an HTTP response.
     fn handle_request(
         &self,
-        req: &Request,
+        method: Method,
+        body: Option<&Body>,
         api_notifier: EventFd,
-        api_sender: Sender<ApiRequest>,
+        api_sender: ApiSender,
     ) -> Response;
+
+    /// Handles an HTTP request, given the query of its URI too, for the
+    /// endpoints taking parameters. The other ones ignore it.
+    fn handle_query_request(
+        &self,
+        method: Method,
+        _query: &str,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        self.handle_request(method, body, api_notifier, api_sender)
+    }
+
+    /// Whether the endpoint handles the requests asking for it in a task of
+    /// their own, answering them right away, for the long ones not to hold
+    /// the client up.
+    fn asynchronous(&self) -> bool {
+        false
+    }
 }
 
 /// An HTTP routes structure.
@@ -57,35 +104,158 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
         r.routes.insert(endpoint!("/vm.reboot"), Box::new(VmActionHandler::new(VmAction::Reboot)));
+        r.routes.insert(endpoint!("/vm.power-button"), Box::new(VmActionHandler::new(VmAction::PowerButton)));
+        r.routes.insert(endpoint!("/vm.quiesce"), Box::new(VmActionHandler::new(VmAction::Quiesce)));
+        r.routes.insert(endpoint!("/vm.sensors"), Box::new(VmSetSensors {}));
+        r.routes.insert(endpoint!("/vm.coredump"), Box::new(VmCoredump {}));
+        r.routes.insert(endpoint!("/vm.reset-device"), Box::new(VmResetDevice {}));
+        r.routes.insert(endpoint!("/vm.device-audit"), Box::new(VmDeviceAudit {}));
+        r.routes.insert(endpoint!("/vm.disk-weight"), Box::new(VmSetDiskWeight {}));
+        r.routes.insert(endpoint!("/vm.dirty-rate"), Box::new(VmDirtyRate {}));
+        r.routes.insert(endpoint!("/vm.screenshot"), Box::new(VmScreenshot {}));
+        r.routes.insert(endpoint!("/vm.throttle"), Box::new(VmThrottle {}));
+        r.routes.insert(endpoint!("/vm.add-vsock"), Box::new(VmAddVsock {}));
+        r.routes.insert(endpoint!("/vm.add-user-device"), Box::new(VmAddUserDevice {}));
+        r.routes.insert(endpoint!("/vm.remove-device"), Box::new(VmRemoveDevice {}));
+        r.routes.insert(endpoint!("/vm.apply"), Box::new(VmApply {}));
+        r.routes.insert(endpoint!("/vm.update"), Box::new(VmUpdate {}));
+        r.routes.insert(endpoint!("/vm.batch"), Box::new(VmBatch {}));
+        r.routes.insert(endpoint!("/vm.claim"), Box::new(VmClaim {}));
+        r.routes.insert(endpoint!(TASK_STATUS_ENDPOINT), Box::new(VmTaskStatus {}));
+        r.routes.insert(endpoint!("/vmm.capabilities"), Box::new(VmmCapabilities {}));
+        r.routes.insert(endpoint!("/vmm.fds"), Box::new(VmmFds {}));
+        r.routes.insert(endpoint!("/vmm.host-resources"), Box::new(VmmHostResources {}));
+        r.routes.insert(endpoint!("/vmm.pool"), Box::new(VmmPool {}));
         r.routes.insert(endpoint!("/vmm.shutdown"), Box::new(VmmShutdown {}));
+        r.routes.insert(endpoint!("/schema"), Box::new(ApiSchema {}));
+        r.routes.insert(endpoint!(""), Box::new(ApiDiscovery {}));
 
         r
     };
 }
 
-fn handle_http_request(
+// Header names are case insensitive.
+fn request_id(request: &Request) -> Option<String> {
+    request
+        .headers
+        .custom_entries()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(REQUEST_ID_HEADER))
+        .map(|(_, value)| value.clone())
+}
+
+// The owner token of the Authorization header, if any, empty for another
+// scheme than Bearer, for it to be rejected.
+fn owner_token(request: &Request) -> Option<String> {
+    request
+        .headers
+        .custom_entries()
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(AUTHORIZATION_HEADER))
+        .map(|(_, value)| {
+            let value = value.trim();
+            match value.get(..BEARER_SCHEME.len()) {
+                Some(scheme) if scheme.eq_ignore_ascii_case(BEARER_SCHEME) => {
+                    value[BEARER_SCHEME.len()..].trim().to_string()
+                }
+                _ => String::new(),
+            }
+        })
+}
+
+// Whether the Prefer header asks for an asynchronous response.
+fn respond_async(request: &Request) -> bool {
+    request
+        .headers
+        .custom_entries()
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(PREFER_HEADER))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case(RESPOND_ASYNC))
+}
+
+// The value of the VM ID parameter of the query, if any.
+fn vm_id(query: &str) -> Option<String> {
+    query
+        .split('&')
+        .find(|param| param.starts_with(VM_ID_PARAM) && param[VM_ID_PARAM.len()..].starts_with('='))
+        .map(|param| param[VM_ID_PARAM.len() + 1..].to_string())
+}
+
+/// Handles a request to the API, through the handler of its endpoint, as the
+/// HTTP server thread does.
+pub fn handle_http_request(
     request: &Request,
     api_notifier: &EventFd,
-    api_sender: &Sender<ApiRequest>,
+    api_sender: &ApiSender,
 ) -> Response {
-    let path = request.uri().get_abs_path().to_string();
-    let mut response = match HTTP_ROUTES.routes.get(&path) {
-        Some(route) => match api_notifier.try_clone() {
-            Ok(notifier) => route.handle_request(&request, notifier, api_sender.clone()),
+    let uri = request.uri().get_abs_path();
+    let (path, query) = match uri.find('?') {
+        Some(start) => (&uri[..start], &uri[start + 1..]),
+        None => (uri, ""),
+    };
+    // "/api/v1/" is the same endpoint as "/api/v1".
+    let path = path.trim_end_matches('/').to_string();
+    let request_id = request_id(request);
+    let api_sender = match request_id.clone() {
+        Some(request_id) => api_sender.clone().with_request_id(request_id),
+        None => Ok(api_sender.clone()),
+    };
+    let api_sender = match vm_id(query) {
+        Some(_) if path == endpoint!(TASK_STATUS_ENDPOINT) => api_sender,
+        Some(vm_id) => api_sender.and_then(|api_sender| api_sender.with_vm_id(vm_id)),
+        None => api_sender,
+    };
+    let api_sender =
+        api_sender.and_then(|api_sender| api_sender.with_http_client(owner_token(request)));
+    let mut response = match (HTTP_ROUTES.routes.get(&path), api_sender) {
+        (Some(route), _) if !route.methods().contains(&request.method()) => {
+            let mut response = Response::new(Version::Http11, StatusCode::MethodNotAllowed);
+            response.set_allow(route.methods().to_vec());
+            response
+        }
+        (Some(route), Ok(api_sender)) => match api_notifier.try_clone() {
+            Ok(notifier) if route.asynchronous() && respond_async(request) => start_task(
+                &**route,
+                &path,
+                request.method(),
+                request.body.as_ref(),
+                notifier,
+                api_sender,
+            ),
+            Ok(notifier) => route.handle_query_request(
+                request.method(),
+                query,
+                request.body.as_ref(),
+                notifier,
+                api_sender,
+            ),
             Err(_) => Response::new(Version::Http11, StatusCode::InternalServerError),
         },
-        None => Response::new(Version::Http11, StatusCode::NotFound),
+        (Some(_), Err(_)) => Response::new(Version::Http11, StatusCode::BadRequest),
+        (None, _) => Response::new(Version::Http11, StatusCode::NotFound),
     };
+    if let Some(request_id) = request_id {
+        info!(
+            "API request {:?} to {}: {:?}",
+            request_id,
+            path,
+            response.status()
+        );
+    }
 
     response.set_server("Cloud Hypervisor API");
     response.set_content_type(MediaType::ApplicationJson);
     response
 }
 
+/// Serves the API on the socket, writing the entry of each request to the
+/// access log, if any, once it is answered.
 pub fn start_http_thread(
     path: &str,
     api_notifier: EventFd,
-    api_sender: Sender<ApiRequest>,
+    api_sender: ApiSender,
+    audit_log: Option<ApiAuditLog>,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     std::fs::remove_file(path).unwrap_or_default();
     let socket_path = PathBuf::from(path);
@@ -101,7 +271,23 @@ pub fn start_http_thread(
                         for server_request in request_vec {
                             server
                                 .respond(server_request.process(|request| {
-                                    handle_http_request(request, &api_notifier, &api_sender)
+                                    let audited =
+                                        audit_log.as_ref().map(|audit_log| audit_log.begin());
+                                    let response =
+                                        handle_http_request(request, &api_notifier, &api_sender);
+                                    if let (Some(audit_log), Some(audited)) = (&audit_log, audited)
+                                    {
+                                        let request_id = request_id(request);
+                                        if let Err(e) = audit_log.log(
+                                            audited,
+                                            request,
+                                            request_id.as_ref().map(String::as_str),
+                                            response.status(),
+                                        ) {
+                                            error!("Failed writing the API access log: {}", e);
+                                        }
+                                    }
+                                    response
                                 }))
                                 .or_else(|e| {
                                     error!("HTTP server error on response: {}", e);
@@ -3,15 +3,27 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use crate::api::http::EndpointHandler;
+use crate::api::http::{EndpointHandler, HTTP_ROOT, HTTP_ROUTES};
 use crate::api::{
-    vm_boot, vm_create, vm_delete, vm_info, vm_pause, vm_reboot, vm_resume, vm_shutdown,
-    vmm_shutdown, ApiError, ApiRequest, ApiResult, VmAction, VmConfig,
+    vm_add_user_device, vm_add_vsock, vm_apply, vm_boot, vm_claim, vm_coredump, vm_create,
+    vm_delete, vm_device_audit, vm_dirty_rate, vm_info, vm_pause, vm_power_button, vm_quiesce,
+    vm_reboot, vm_remove_device, vm_reset_device, vm_resume, vm_screenshot, vm_set_disk_weight,
+    vm_set_sensors, vm_shutdown, vm_throttle, vm_update, vmm_capabilities, vmm_fds,
+    vmm_host_resources, vmm_pool, vmm_shutdown, ApiClient, ApiError, ApiResult, ApiSender,
+    PciDeviceInfo, VmAction, VmClaimData, VmConfig, VmCoredumpData, VmDirtyRateData,
+    VmDiskWeightData, VmRemoveDeviceData, VmResetDeviceData, VmScreenshotData, VmSensors,
+    VmThrottleData, VmmPoolData,
 };
-use micro_http::{Body, Method, Request, Response, StatusCode, Version};
+use crate::config::Error as ConfigError;
+use crate::config::{UserDeviceConfig, VmUpdateConfig, VsockConfig};
+use crate::device_manager::DeviceManagerError;
+use crate::vm::Error as VmError;
+use micro_http::{Body, Method, Response, StatusCode, Version};
 use serde_json::Error as SerdeError;
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use vmm_sys_util::eventfd::EventFd;
 
 /// Errors associated with VMM management
@@ -20,6 +32,18 @@ pub enum HttpError {
     /// API request receive error
     SerdeJsonDeserialize(SerdeError),
 
+    /// A step of the batch is not a request to an endpoint of the API
+    UnknownBatchEndpoint(String),
+
+    /// No task has the ID, or the task is another client's
+    UnknownTask(String),
+
+    /// Could not start the task of a request
+    StartTask(std::io::Error),
+
+    /// All the tasks kept are running
+    TooManyTasks,
+
     /// Could not create a VM
     VmCreate(ApiError),
 
@@ -41,16 +65,294 @@ pub enum HttpError {
     /// Could not reboot a VM
     VmReboot(ApiError),
 
+    /// Could not press the power button of a VM
+    VmPowerButton(ApiError),
+
+    /// Could not quiesce a VM
+    VmQuiesce(ApiError),
+
+    /// Could not set the sensors readings of a VM
+    VmSetSensors(ApiError),
+
+    /// Could not write the core dump of a VM
+    VmCoredump(ApiError),
+
+    /// Could not reset a device of a VM
+    VmResetDevice(ApiError),
+
+    /// Could not get the device audit of a VM
+    VmDeviceAudit(ApiError),
+
+    /// Could not change the weight of a disk of a VM
+    VmSetDiskWeight(ApiError),
+
+    /// Could not measure the dirty rate of a VM
+    VmDirtyRate(ApiError),
+
+    /// Could not write the display of a VM
+    VmScreenshot(ApiError),
+
+    /// Could not throttle the vCPUs of a VM
+    VmThrottle(ApiError),
+
+    /// Could not add a device to a VM
+    VmAddDevice(ApiError),
+
+    /// Could not remove a device from a VM
+    VmRemoveDevice(ApiError),
+
+    /// Could not apply a configuration to a VM
+    VmApply(ApiError),
+
+    /// Could not update the configuration of a VM
+    VmUpdate(ApiError),
+
+    /// Could not claim a VM of the pool
+    VmClaim(ApiError),
+
     /// Could not act on a VM
     VmAction(ApiError),
 
     /// Could not shut the VMM down
     VmmShutdown(ApiError),
+
+    /// Could not get the VMM capabilities
+    VmmCapabilities(ApiError),
+
+    /// Could not list the VMM file descriptors
+    VmmFds(ApiError),
+
+    /// Could not list the VMM host resources
+    VmmHostResources(ApiError),
+
+    /// Could not create the VM pool
+    VmmPool(ApiError),
+}
+
+impl HttpError {
+    // The name of the error, stable across versions for the clients to branch
+    // on, unlike the message giving its cause.
+    fn name(&self) -> &'static str {
+        match self {
+            HttpError::SerdeJsonDeserialize(_) => "SerdeJsonDeserialize",
+            HttpError::UnknownBatchEndpoint(_) => "UnknownBatchEndpoint",
+            HttpError::UnknownTask(_) => "UnknownTask",
+            HttpError::StartTask(_) => "StartTask",
+            HttpError::TooManyTasks => "TooManyTasks",
+            HttpError::VmCreate(_) => "VmCreate",
+            HttpError::VmBoot(_) => "VmBoot",
+            HttpError::VmInfo(_) => "VmInfo",
+            HttpError::VmPause(_) => "VmPause",
+            HttpError::VmResume(_) => "VmResume",
+            HttpError::VmShutdown(_) => "VmShutdown",
+            HttpError::VmReboot(_) => "VmReboot",
+            HttpError::VmPowerButton(_) => "VmPowerButton",
+            HttpError::VmQuiesce(_) => "VmQuiesce",
+            HttpError::VmSetSensors(_) => "VmSetSensors",
+            HttpError::VmCoredump(_) => "VmCoredump",
+            HttpError::VmResetDevice(_) => "VmResetDevice",
+            HttpError::VmDeviceAudit(_) => "VmDeviceAudit",
+            HttpError::VmSetDiskWeight(_) => "VmSetDiskWeight",
+            HttpError::VmDirtyRate(_) => "VmDirtyRate",
+            HttpError::VmScreenshot(_) => "VmScreenshot",
+            HttpError::VmThrottle(_) => "VmThrottle",
+            HttpError::VmAddDevice(_) => "VmAddDevice",
+            HttpError::VmRemoveDevice(_) => "VmRemoveDevice",
+            HttpError::VmApply(_) => "VmApply",
+            HttpError::VmUpdate(_) => "VmUpdate",
+            HttpError::VmClaim(_) => "VmClaim",
+            HttpError::VmAction(_) => "VmAction",
+            HttpError::VmmShutdown(_) => "VmmShutdown",
+            HttpError::VmmCapabilities(_) => "VmmCapabilities",
+            HttpError::VmmFds(_) => "VmmFds",
+            HttpError::VmmHostResources(_) => "VmmHostResources",
+            HttpError::VmmPool(_) => "VmmPool",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            HttpError::SerdeJsonDeserialize(e) => e.to_string(),
+            HttpError::UnknownBatchEndpoint(endpoint) => endpoint.clone(),
+            HttpError::UnknownTask(id) => id.clone(),
+            HttpError::StartTask(e) => e.to_string(),
+            HttpError::TooManyTasks => format!("{} tasks running", MAX_TASKS),
+            HttpError::VmCreate(e)
+            | HttpError::VmBoot(e)
+            | HttpError::VmInfo(e)
+            | HttpError::VmPause(e)
+            | HttpError::VmResume(e)
+            | HttpError::VmShutdown(e)
+            | HttpError::VmReboot(e)
+            | HttpError::VmPowerButton(e)
+            | HttpError::VmQuiesce(e)
+            | HttpError::VmSetSensors(e)
+            | HttpError::VmCoredump(e)
+            | HttpError::VmResetDevice(e)
+            | HttpError::VmDeviceAudit(e)
+            | HttpError::VmSetDiskWeight(e)
+            | HttpError::VmDirtyRate(e)
+            | HttpError::VmScreenshot(e)
+            | HttpError::VmThrottle(e)
+            | HttpError::VmAddDevice(e)
+            | HttpError::VmRemoveDevice(e)
+            | HttpError::VmApply(e)
+            | HttpError::VmUpdate(e)
+            | HttpError::VmClaim(e)
+            | HttpError::VmAction(e)
+            | HttpError::VmmShutdown(e)
+            | HttpError::VmmCapabilities(e)
+            | HttpError::VmmFds(e)
+            | HttpError::VmmHostResources(e)
+            | HttpError::VmmPool(e) => format!("{:?}", e),
+        }
+    }
+
+    // The status of the errors which aren't the request's or the VMM's own
+    // fault, but come from the state of the VM: 404 when there is no VM, no
+    // device audit, or no device to remove, to act on, 409 when the VM isn't
+    // in a state the request applies to. In multi-tenant mode, 401 when the
+    // request has no owner token, 403 when the VM is another owner's, or the
+    // request uses what only the admin may.
+    fn status(&self) -> Option<StatusCode> {
+        let error = match self {
+            HttpError::SerdeJsonDeserialize(_)
+            | HttpError::UnknownBatchEndpoint(_)
+            | HttpError::StartTask(_) => return None,
+            HttpError::UnknownTask(_) => return Some(StatusCode::NotFound),
+            HttpError::TooManyTasks => return Some(StatusCode::ServiceUnavailable),
+            HttpError::VmCreate(e)
+            | HttpError::VmBoot(e)
+            | HttpError::VmInfo(e)
+            | HttpError::VmPause(e)
+            | HttpError::VmResume(e)
+            | HttpError::VmShutdown(e)
+            | HttpError::VmReboot(e)
+            | HttpError::VmPowerButton(e)
+            | HttpError::VmQuiesce(e)
+            | HttpError::VmSetSensors(e)
+            | HttpError::VmCoredump(e)
+            | HttpError::VmResetDevice(e)
+            | HttpError::VmDeviceAudit(e)
+            | HttpError::VmSetDiskWeight(e)
+            | HttpError::VmDirtyRate(e)
+            | HttpError::VmScreenshot(e)
+            | HttpError::VmThrottle(e)
+            | HttpError::VmAddDevice(e)
+            | HttpError::VmRemoveDevice(e)
+            | HttpError::VmApply(e)
+            | HttpError::VmUpdate(e)
+            | HttpError::VmClaim(e)
+            | HttpError::VmAction(e)
+            | HttpError::VmmShutdown(e)
+            | HttpError::VmmCapabilities(e)
+            | HttpError::VmmFds(e)
+            | HttpError::VmmHostResources(e)
+            | HttpError::VmmPool(e) => e,
+        };
+
+        let vm_error = match error {
+            ApiError::VmNotCreated | ApiError::VmMissingConfig | ApiError::VmPoolEmpty => {
+                return Some(StatusCode::NotFound)
+            }
+            ApiError::OwnerTokenMissing => return Some(StatusCode::Unauthorized),
+            ApiError::VmNotOwned | ApiError::TenantField(_) | ApiError::TenantPath(_) => {
+                return Some(StatusCode::Forbidden)
+            }
+            ApiError::VmAlreadyCreated
+            | ApiError::VmNotBooted
+            | ApiError::VmNotPooled
+            | ApiError::VmPoolAlreadyCreated => return Some(StatusCode::Conflict),
+            ApiError::VmBoot(e)
+            | ApiError::VmCreate(e)
+            | ApiError::VmDelete(e)
+            | ApiError::VmInfo(e)
+            | ApiError::VmPause(e)
+            | ApiError::VmResume(e)
+            | ApiError::VmShutdown(e)
+            | ApiError::VmReboot(e)
+            | ApiError::VmPowerButton(e)
+            | ApiError::VmQuiesce(e)
+            | ApiError::VmSetSensors(e)
+            | ApiError::VmCoredump(e)
+            | ApiError::VmResetDevice(e)
+            | ApiError::VmDeviceAudit(e)
+            | ApiError::VmSetDiskWeight(e)
+            | ApiError::VmDirtyRate(e)
+            | ApiError::VmScreenshot(e)
+            | ApiError::VmThrottle(e)
+            | ApiError::VmAddDevice(e)
+            | ApiError::VmRemoveDevice(e)
+            | ApiError::VmApply(e)
+            | ApiError::VmUpdate(e)
+            | ApiError::VmClaim(e)
+            | ApiError::VmmPool(e)
+            | ApiError::VmmShutdown(e)
+            | ApiError::VmmCapabilities(e) => e,
+            _ => return None,
+        };
+
+        match vm_error {
+            VmError::VmNotCreated
+            | VmError::DeviceAuditDisabled
+            | VmError::GpuNotConfigured
+            | VmError::CgroupNotConfigured
+            | VmError::UnknownDevice(_)
+            | VmError::RemoveDevice(DeviceManagerError::UnknownRemovableDevice(_)) => {
+                Some(StatusCode::NotFound)
+            }
+            VmError::VmNotRunning
+            | VmError::VmNotPaused
+            | VmError::InvalidStateTransition(_, _)
+            | VmError::DisplayDisabled
+            | VmError::ConfigNotApplicable(_) => Some(StatusCode::Conflict),
+            _ => None,
+        }
+    }
+}
+
+// The body of an error response, a JSON object with the name of the error as
+// `error`, and its cause as `message`.
+fn error_body(error: &HttpError) -> serde_json::Value {
+    serde_json::json!({
+        "error": error.name(),
+        "message": error.message(),
+    })
 }
 
+// Responds with the error, with the status of its class if it has one, the
+// given one otherwise.
 fn error_response(error: HttpError, status: StatusCode) -> Response {
-    let mut response = Response::new(Version::Http11, status);
-    response.set_body(Body::new(format!("{:?}", error)));
+    let mut response = Response::new(Version::Http11, error.status().unwrap_or(status));
+    response.set_body(Body::new(error_body(&error).to_string()));
+
+    response
+}
+
+// The constraints of the VM configuration it doesn't meet, all of them, for
+// the client to fix them at once.
+fn invalid_config_response(error: &HttpError, errors: &[ConfigError]) -> Response {
+    let mut body = error_body(error);
+    body["errors"] = errors
+        .iter()
+        .map(|e| serde_json::Value::String(format!("{:?}", e)))
+        .collect();
+    let mut response = Response::new(Version::Http11, StatusCode::BadRequest);
+    response.set_body(Body::new(body));
+
+    response
+}
+
+// The fields of the VM configuration the VM can't take the changes of, all
+// of them, for the VM to be created anew.
+fn not_applicable_response(error: &HttpError, fields: &[String]) -> Response {
+    let mut body = error_body(error);
+    body["fields"] = fields
+        .iter()
+        .map(|field| serde_json::Value::String(field.clone()))
+        .collect();
+    let mut response = Response::new(Version::Http11, StatusCode::Conflict);
+    response.set_body(Body::new(body.to_string()));
 
     response
 }
@@ -59,15 +361,20 @@ fn error_response(error: HttpError, status: StatusCode) -> Response {
 pub struct VmCreate {}
 
 impl EndpointHandler for VmCreate {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
     fn handle_request(
         &self,
-        req: &Request,
+        method: Method,
+        body: Option<&Body>,
         api_notifier: EventFd,
-        api_sender: Sender<ApiRequest>,
+        api_sender: ApiSender,
     ) -> Response {
-        match req.method() {
+        match method {
             Method::Put => {
-                match &req.body {
+                match body {
                     Some(body) => {
                         // Deserialize into a VmConfig
                         let vm_config: VmConfig = match serde_json::from_slice(body.raw())
@@ -82,7 +389,12 @@ impl EndpointHandler for VmCreate {
                             .map_err(HttpError::VmCreate)
                         {
                             Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
-                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                            Err(e) => match &e {
+                                HttpError::VmCreate(ApiError::VmCreate(
+                                    VmError::InvalidConfig(errors),
+                                )) => invalid_config_response(&e, errors),
+                                _ => error_response(e, StatusCode::InternalServerError),
+                            },
                         }
                     }
 
@@ -93,17 +405,27 @@ impl EndpointHandler for VmCreate {
             _ => Response::new(Version::Http11, StatusCode::BadRequest),
         }
     }
+
+    fn asynchronous(&self) -> bool {
+        true
+    }
 }
 
 // Common handler for boot, shutdown and reboot
 pub struct VmActionHandler {
     action_fn: VmActionFn,
+    // Booting may take long, e.g. to prefault the guest RAM.
+    asynchronous: bool,
 }
 
-type VmActionFn = Box<dyn Fn(EventFd, Sender<ApiRequest>) -> ApiResult<()> + Send + Sync>;
+type VmActionFn = Box<dyn Fn(EventFd, ApiSender) -> ApiResult<()> + Send + Sync>;
 
 impl VmActionHandler {
     pub fn new(action: VmAction) -> Self {
+        let asynchronous = match action {
+            VmAction::Boot => true,
+            _ => false,
+        };
         let action_fn = Box::new(match action {
             VmAction::Boot => vm_boot,
             VmAction::Delete => vm_delete,
@@ -111,20 +433,30 @@ impl VmActionHandler {
             VmAction::Reboot => vm_reboot,
             VmAction::Pause => vm_pause,
             VmAction::Resume => vm_resume,
+            VmAction::PowerButton => vm_power_button,
+            VmAction::Quiesce => vm_quiesce,
         });
 
-        VmActionHandler { action_fn }
+        VmActionHandler {
+            action_fn,
+            asynchronous,
+        }
     }
 }
 
 impl EndpointHandler for VmActionHandler {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
     fn handle_request(
         &self,
-        req: &Request,
+        method: Method,
+        _body: Option<&Body>,
         api_notifier: EventFd,
-        api_sender: Sender<ApiRequest>,
+        api_sender: ApiSender,
     ) -> Response {
-        match req.method() {
+        match method {
             Method::Put => {
                 match (self.action_fn)(api_notifier, api_sender).map_err(|e| match e {
                     ApiError::VmBoot(_) => HttpError::VmBoot(e),
@@ -132,6 +464,8 @@ impl EndpointHandler for VmActionHandler {
                     ApiError::VmReboot(_) => HttpError::VmReboot(e),
                     ApiError::VmPause(_) => HttpError::VmPause(e),
                     ApiError::VmResume(_) => HttpError::VmResume(e),
+                    ApiError::VmPowerButton(_) => HttpError::VmPowerButton(e),
+                    ApiError::VmQuiesce(_) => HttpError::VmQuiesce(e),
                     _ => HttpError::VmAction(e),
                 }) {
                     Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
@@ -141,45 +475,1344 @@ impl EndpointHandler for VmActionHandler {
             _ => Response::new(Version::Http11, StatusCode::BadRequest),
         }
     }
+
+    fn asynchronous(&self) -> bool {
+        self.asynchronous
+    }
 }
 
-// /api/v1/vm.info handler
-pub struct VmInfo {}
+// /api/v1/vm.sensors handler
+pub struct VmSetSensors {}
+
+impl EndpointHandler for VmSetSensors {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
 
-impl EndpointHandler for VmInfo {
     fn handle_request(
         &self,
-        req: &Request,
+        method: Method,
+        body: Option<&Body>,
         api_notifier: EventFd,
-        api_sender: Sender<ApiRequest>,
+        api_sender: ApiSender,
     ) -> Response {
-        match req.method() {
-            Method::Get => match vm_info(api_notifier, api_sender).map_err(HttpError::VmInfo) {
-                Ok(info) => {
-                    let mut response = Response::new(Version::Http11, StatusCode::OK);
-                    let info_serialized = serde_json::to_string(&info).unwrap();
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmSensors
+                        let sensors: VmSensors = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(sensors) => sensors,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
 
-                    response.set_body(Body::new(info_serialized));
-                    response
+                        match vm_set_sensors(api_notifier, api_sender, Arc::new(sensors))
+                            .map_err(HttpError::VmSetSensors)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
                 }
-                Err(e) => error_response(e, StatusCode::InternalServerError),
-            },
+            }
+
             _ => Response::new(Version::Http11, StatusCode::BadRequest),
         }
     }
 }
 
-// /api/v1/vmm.shutdown handler
-pub struct VmmShutdown {}
+// /api/v1/vm.coredump handler
+pub struct VmCoredump {}
+
+impl EndpointHandler for VmCoredump {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmCoredumpData
+                        let data: VmCoredumpData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vm_coredump(api_notifier, api_sender, Arc::new(data))
+                            .map_err(HttpError::VmCoredump)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.screenshot handler
+pub struct VmScreenshot {}
+
+impl EndpointHandler for VmScreenshot {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmScreenshotData
+                        let data: VmScreenshotData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vm_screenshot(api_notifier, api_sender, Arc::new(data))
+                            .map_err(HttpError::VmScreenshot)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.throttle handler
+pub struct VmThrottle {}
+
+impl EndpointHandler for VmThrottle {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                // Deserialize into a VmThrottleData, the quota being left as
+                // it is without a body.
+                let data: Option<VmThrottleData> = match body {
+                    Some(body) => match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => Some(data),
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    },
+                    None => None,
+                };
+
+                match vm_throttle(api_notifier, api_sender, data.map(Arc::new))
+                    .map_err(HttpError::VmThrottle)
+                {
+                    Ok(throttle) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let throttle_serialized = serde_json::to_string(&throttle).unwrap();
+
+                        response.set_body(Body::new(throttle_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.reset-device handler
+pub struct VmResetDevice {}
+
+impl EndpointHandler for VmResetDevice {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmResetDeviceData
+                        let data: VmResetDeviceData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vm_reset_device(api_notifier, api_sender, Arc::new(data))
+                            .map_err(HttpError::VmResetDevice)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.disk-weight handler
+pub struct VmSetDiskWeight {}
+
+impl EndpointHandler for VmSetDiskWeight {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmDiskWeightData
+                        let data: VmDiskWeightData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vm_set_disk_weight(api_notifier, api_sender, Arc::new(data))
+                            .map_err(HttpError::VmSetDiskWeight)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.dirty-rate handler
+pub struct VmDirtyRate {}
+
+impl EndpointHandler for VmDirtyRate {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                // Deserialize into a VmDirtyRateData, the default sampling
+                // window being taken without a body.
+                let data: VmDirtyRateData = match body {
+                    Some(body) => match serde_json::from_slice(body.raw())
+                        .map_err(HttpError::SerdeJsonDeserialize)
+                    {
+                        Ok(data) => data,
+                        Err(e) => return error_response(e, StatusCode::BadRequest),
+                    },
+                    None => VmDirtyRateData::default(),
+                };
+
+                match vm_dirty_rate(api_notifier, api_sender, Arc::new(data))
+                    .map_err(HttpError::VmDirtyRate)
+                {
+                    Ok(dirty_rate) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let dirty_rate_serialized = serde_json::to_string(&dirty_rate).unwrap();
+
+                        response.set_body(Body::new(dirty_rate_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+
+    fn asynchronous(&self) -> bool {
+        true
+    }
+}
+
+// Responds with the device the running VM hot-added, or with no content
+// when the device was only added to the configuration of the VM.
+fn add_device_response(result: Result<Option<PciDeviceInfo>, HttpError>) -> Response {
+    match result {
+        Ok(Some(info)) => {
+            let mut response = Response::new(Version::Http11, StatusCode::OK);
+            let info_serialized = serde_json::to_string(&info).unwrap();
+
+            response.set_body(Body::new(info_serialized));
+            response
+        }
+        Ok(None) => Response::new(Version::Http11, StatusCode::NoContent),
+        Err(e) => match &e {
+            HttpError::VmAddDevice(ApiError::VmAddDevice(VmError::InvalidConfig(errors))) => {
+                invalid_config_response(&e, errors)
+            }
+            _ => error_response(e, StatusCode::InternalServerError),
+        },
+    }
+}
+
+// /api/v1/vm.add-vsock handler
+pub struct VmAddVsock {}
+
+impl EndpointHandler for VmAddVsock {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VsockConfig
+                        let data: VsockConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        add_device_response(
+                            vm_add_vsock(api_notifier, api_sender, Arc::new(data))
+                                .map_err(HttpError::VmAddDevice),
+                        )
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.add-user-device handler
+pub struct VmAddUserDevice {}
+
+impl EndpointHandler for VmAddUserDevice {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a UserDeviceConfig
+                        let data: UserDeviceConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        add_device_response(
+                            vm_add_user_device(api_notifier, api_sender, Arc::new(data))
+                                .map_err(HttpError::VmAddDevice),
+                        )
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.remove-device handler
+pub struct VmRemoveDevice {}
+
+impl EndpointHandler for VmRemoveDevice {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmRemoveDeviceData
+                        let data: VmRemoveDeviceData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vm_remove_device(api_notifier, api_sender, Arc::new(data))
+                            .map_err(HttpError::VmRemoveDevice)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.apply handler
+pub struct VmApply {}
+
+impl EndpointHandler for VmApply {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmConfig
+                        let vm_config: VmConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(config) => config,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vm_apply(api_notifier, api_sender, Arc::new(vm_config))
+                            .map_err(HttpError::VmApply)
+                        {
+                            Ok(result) => {
+                                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                                let result_serialized = serde_json::to_string(&result).unwrap();
+
+                                response.set_body(Body::new(result_serialized));
+                                response
+                            }
+                            Err(e) => match &e {
+                                HttpError::VmApply(ApiError::VmApply(VmError::InvalidConfig(
+                                    errors,
+                                ))) => invalid_config_response(&e, errors),
+                                HttpError::VmApply(ApiError::VmApply(
+                                    VmError::ConfigNotApplicable(fields),
+                                )) => not_applicable_response(&e, fields),
+                                _ => error_response(e, StatusCode::InternalServerError),
+                            },
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.update handler
+pub struct VmUpdate {}
+
+impl EndpointHandler for VmUpdate {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmUpdateConfig
+                        let update: VmUpdateConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(update) => update,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vm_update(api_notifier, api_sender, Arc::new(update))
+                            .map_err(HttpError::VmUpdate)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => match &e {
+                                HttpError::VmUpdate(ApiError::VmUpdate(
+                                    VmError::InvalidConfig(errors),
+                                )) => invalid_config_response(&e, errors),
+                                HttpError::VmUpdate(ApiError::VmUpdate(
+                                    VmError::ConfigNotApplicable(fields),
+                                )) => not_applicable_response(&e, fields),
+                                _ => error_response(e, StatusCode::InternalServerError),
+                            },
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.claim handler
+pub struct VmClaim {}
+
+impl EndpointHandler for VmClaim {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmClaimData
+                        let data: VmClaimData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vm_claim(api_notifier, api_sender, Arc::new(data))
+                            .map_err(HttpError::VmClaim)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => match &e {
+                                HttpError::VmClaim(ApiError::InvalidVmId) => {
+                                    error_response(e, StatusCode::BadRequest)
+                                }
+                                _ => error_response(e, StatusCode::InternalServerError),
+                            },
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+/// A step of a batch: the request to an endpoint, named by its path under
+/// /api/v1 such as "vm.create", along with its body, if any. The request is
+/// sent with the method of the endpoint.
+#[derive(Deserialize)]
+struct BatchStep {
+    endpoint: String,
+    #[serde(default)]
+    body: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct VmBatchData {
+    steps: Vec<BatchStep>,
+    /// Delete the VM the batch created when one of its steps fails.
+    #[serde(default = "default_batch_rollback")]
+    rollback: bool,
+}
+
+fn default_batch_rollback() -> bool {
+    true
+}
+
+/// The response to a step of a batch, its status and its JSON body, if any.
+#[derive(Serialize)]
+struct BatchStepResult {
+    endpoint: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+struct VmBatchResult {
+    /// All the steps succeeded.
+    completed: bool,
+    /// The VM the batch created was deleted, a step having failed.
+    rolled_back: bool,
+    /// The responses to the steps, up to the one which failed.
+    results: Vec<BatchStepResult>,
+}
+
+/// The code of the statuses the handlers respond with.
+pub fn status_code(status: StatusCode) -> u16 {
+    match status {
+        StatusCode::OK => 200,
+        StatusCode::Accepted => 202,
+        StatusCode::NoContent => 204,
+        StatusCode::BadRequest => 400,
+        StatusCode::Unauthorized => 401,
+        StatusCode::Forbidden => 403,
+        StatusCode::NotFound => 404,
+        StatusCode::MethodNotAllowed => 405,
+        StatusCode::Conflict => 409,
+        StatusCode::ServiceUnavailable => 503,
+        _ => 500,
+    }
+}
+
+// Sends the request to the endpoint, through its handler, as the HTTP
+// server does.
+fn batch_request(
+    handler: &dyn EndpointHandler,
+    endpoint: &str,
+    body: Option<&Body>,
+    api_notifier: &EventFd,
+    api_sender: &ApiSender,
+) -> BatchStepResult {
+    let response = match api_notifier.try_clone() {
+        Ok(notifier) => {
+            handler.handle_request(handler.methods()[0], body, notifier, api_sender.clone())
+        }
+        Err(_) => Response::new(Version::Http11, StatusCode::InternalServerError),
+    };
+
+    BatchStepResult {
+        endpoint: endpoint.to_string(),
+        status: status_code(response.status()),
+        body: response
+            .body()
+            .and_then(|body| serde_json::from_slice(body.raw()).ok()),
+    }
+}
+
+// Runs the steps in order, up to the first one which fails, and deletes the
+// VM the batch created then, if asked to.
+fn vm_batch(batch: VmBatchData, api_notifier: &EventFd, api_sender: &ApiSender) -> VmBatchResult {
+    let mut results = Vec::new();
+    let mut created = false;
+    let mut completed = true;
+
+    for step in batch.steps {
+        let handler = &HTTP_ROUTES.routes[&format!("{}/{}", HTTP_ROOT, step.endpoint)];
+        let body = step.body.map(|body| Body::new(body.to_string()));
+        let result = batch_request(
+            handler.as_ref(),
+            &step.endpoint,
+            body.as_ref(),
+            api_notifier,
+            api_sender,
+        );
+        let succeeded = result.status < 300;
+        results.push(result);
+
+        if !succeeded {
+            completed = false;
+            break;
+        }
+        match step.endpoint.as_str() {
+            "vm.create" => created = true,
+            "vm.delete" => created = false,
+            _ => {}
+        }
+    }
+
+    let mut rolled_back = false;
+    if !completed && created && batch.rollback {
+        let handler = &HTTP_ROUTES.routes[&format!("{}/vm.delete", HTTP_ROOT)];
+        let result = batch_request(
+            handler.as_ref(),
+            "vm.delete",
+            None,
+            api_notifier,
+            api_sender,
+        );
+        if result.status < 300 {
+            rolled_back = true;
+        } else {
+            error!(
+                "Cannot delete the VM of the failed batch: {:?}",
+                result.body
+            );
+        }
+    }
+
+    VmBatchResult {
+        completed,
+        rolled_back,
+        results,
+    }
+}
+
+// /api/v1/vm.batch handler
+pub struct VmBatch {}
+
+impl EndpointHandler for VmBatch {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmBatchData
+                        let batch: VmBatchData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(batch) => batch,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Check all the steps before running the first one, a
+                        // batch not nesting another one.
+                        if let Some(step) = batch.steps.iter().find(|step| {
+                            step.endpoint == "vm.batch"
+                                || !HTTP_ROUTES
+                                    .routes
+                                    .contains_key(&format!("{}/{}", HTTP_ROOT, step.endpoint))
+                        }) {
+                            return error_response(
+                                HttpError::UnknownBatchEndpoint(step.endpoint.clone()),
+                                StatusCode::BadRequest,
+                            );
+                        }
+
+                        let result = vm_batch(batch, &api_notifier, &api_sender);
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let result_serialized = serde_json::to_string(&result).unwrap();
+
+                        response.set_body(Body::new(result_serialized));
+                        response
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+/// Most tasks kept, the oldest completed ones being forgotten past it, and
+/// new ones refused while all of them run.
+const MAX_TASKS: usize = 64;
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum TaskState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// The status of a request handled in a task of its own, along with the
+/// status and JSON body of its response once it completed.
+#[derive(Serialize)]
+struct TaskStatus {
+    id: String,
+    endpoint: String,
+    state: TaskState,
+    /// Milliseconds the task runs for, or took.
+    elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<serde_json::Value>,
+}
+
+struct Task {
+    id: String,
+    endpoint: String,
+    // The client the task is reported to, the one which started it.
+    client: ApiClient,
+    started: Instant,
+    // How long the request took, along with the status and body of its
+    // response, once completed.
+    completion: Option<(Duration, u16, Option<serde_json::Value>)>,
+}
+
+impl Task {
+    fn status(&self) -> TaskStatus {
+        let (state, elapsed, status, body) = match &self.completion {
+            Some((elapsed, status, body)) if *status < 300 => {
+                (TaskState::Succeeded, *elapsed, Some(*status), body.clone())
+            }
+            Some((elapsed, status, body)) => {
+                (TaskState::Failed, *elapsed, Some(*status), body.clone())
+            }
+            None => (TaskState::Running, self.started.elapsed(), None, None),
+        };
+
+        TaskStatus {
+            id: self.id.clone(),
+            endpoint: self.endpoint.clone(),
+            state,
+            elapsed_ms: elapsed.as_millis() as u64,
+            status,
+            body,
+        }
+    }
+}
+
+// The tasks of the process, by start.
+#[derive(Default)]
+struct Tasks {
+    next_id: u64,
+    tasks: VecDeque<Task>,
+}
+
+impl Tasks {
+    // The ID of the task, None when all the tasks kept are running.
+    fn add(&mut self, endpoint: String, client: ApiClient) -> Option<String> {
+        if self.tasks.len() >= MAX_TASKS {
+            let index = self.tasks.iter().position(|t| t.completion.is_some())?;
+            self.tasks.remove(index);
+        }
+        let id = format!("task{}", self.next_id);
+        self.next_id += 1;
+
+        self.tasks.push_back(Task {
+            id: id.clone(),
+            endpoint,
+            client,
+            started: Instant::now(),
+            completion: None,
+        });
+
+        Some(id)
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.tasks.retain(|task| task.id != id);
+    }
+
+    fn complete(&mut self, id: &str, response: &Response) {
+        if let Some(task) = self.tasks.iter_mut().find(|task| task.id == id) {
+            let body = response
+                .body()
+                .and_then(|body| serde_json::from_slice(body.raw()).ok());
+            task.completion = Some((task.started.elapsed(), status_code(response.status()), body));
+        }
+    }
+}
+
+lazy_static! {
+    static ref HTTP_TASKS: Mutex<Tasks> = Mutex::new(Tasks::default());
+}
+
+/// Handles the request in a task of its own, through the handler of its
+/// endpoint, and responds right away with the ID of the task, which
+/// `vm.task-status` reports the response of once it completed, or with a
+/// `503 Service Unavailable` when all the tasks kept are running.
+pub fn start_task(
+    handler: &'static dyn EndpointHandler,
+    path: &str,
+    method: Method,
+    body: Option<&Body>,
+    api_notifier: EventFd,
+    api_sender: ApiSender,
+) -> Response {
+    let endpoint = path.trim_start_matches(HTTP_ROOT).trim_start_matches('/');
+    let id = match HTTP_TASKS
+        .lock()
+        .unwrap()
+        .add(endpoint.to_string(), api_sender.client().clone())
+    {
+        Some(id) => id,
+        None => {
+            return error_response(HttpError::TooManyTasks, StatusCode::ServiceUnavailable);
+        }
+    };
+    let body = body.map(|body| Body::new(body.raw().to_vec()));
+
+    let task_id = id.clone();
+    let spawned = thread::Builder::new()
+        .name(format!("http-{}", id))
+        .spawn(move || {
+            let response = handler.handle_request(method, body.as_ref(), api_notifier, api_sender);
+            HTTP_TASKS.lock().unwrap().complete(&task_id, &response);
+        });
+    if let Err(e) = spawned {
+        HTTP_TASKS.lock().unwrap().remove(&id);
+        return error_response(HttpError::StartTask(e), StatusCode::InternalServerError);
+    }
+
+    let mut response = Response::new(Version::Http11, StatusCode::Accepted);
+    response.set_body(Body::new(serde_json::json!({ "id": id }).to_string()));
+    response
+}
+
+// /api/v1/vm.task-status handler
+pub struct VmTaskStatus {}
+
+impl EndpointHandler for VmTaskStatus {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Get]
+    }
+
+    // The task is named by the query.
+    fn handle_request(
+        &self,
+        _method: Method,
+        _body: Option<&Body>,
+        _api_notifier: EventFd,
+        _api_sender: ApiSender,
+    ) -> Response {
+        Response::new(Version::Http11, StatusCode::BadRequest)
+    }
+
+    fn handle_query_request(
+        &self,
+        method: Method,
+        query: &str,
+        _body: Option<&Body>,
+        _api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Get => {
+                let id = match query.split('&').find(|param| param.starts_with("id=")) {
+                    Some(param) => &param[3..],
+                    None => return Response::new(Version::Http11, StatusCode::BadRequest),
+                };
+
+                let tasks = HTTP_TASKS.lock().unwrap();
+                match tasks
+                    .tasks
+                    .iter()
+                    .find(|task| task.id == id && &task.client == api_sender.client())
+                {
+                    Some(task) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let status_serialized = serde_json::to_string(&task.status()).unwrap();
+
+                        response.set_body(Body::new(status_serialized));
+                        response
+                    }
+                    None => {
+                        error_response(HttpError::UnknownTask(id.to_string()), StatusCode::NotFound)
+                    }
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.info handler
+pub struct VmInfo {}
+
+impl EndpointHandler for VmInfo {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Get]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        _body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Get => match vm_info(api_notifier, api_sender).map_err(HttpError::VmInfo) {
+                Ok(info) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let info_serialized = serde_json::to_string(&info).unwrap();
+
+                    response.set_body(Body::new(info_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vmm.capabilities handler
+pub struct VmmCapabilities {}
+
+impl EndpointHandler for VmmCapabilities {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Get]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        _body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Get => match vmm_capabilities(api_notifier, api_sender)
+                .map_err(HttpError::VmmCapabilities)
+            {
+                Ok(capabilities) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let capabilities_serialized = serde_json::to_string(&capabilities).unwrap();
+
+                    response.set_body(Body::new(capabilities_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.device-audit handler
+pub struct VmDeviceAudit {}
+
+impl EndpointHandler for VmDeviceAudit {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Get]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        _body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Get => {
+                match vm_device_audit(api_notifier, api_sender).map_err(HttpError::VmDeviceAudit) {
+                    Ok(audit) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let audit_serialized = serde_json::to_string(&audit).unwrap();
+
+                        response.set_body(Body::new(audit_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vmm.fds handler
+pub struct VmmFds {}
+
+impl EndpointHandler for VmmFds {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Get]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        _body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Get => match vmm_fds(api_notifier, api_sender).map_err(HttpError::VmmFds) {
+                Ok(fds) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let fds_serialized = serde_json::to_string(&fds).unwrap();
+
+                    response.set_body(Body::new(fds_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vmm.host-resources handler
+pub struct VmmHostResources {}
+
+impl EndpointHandler for VmmHostResources {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Get]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        _body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Get => match vmm_host_resources(api_notifier, api_sender)
+                .map_err(HttpError::VmmHostResources)
+            {
+                Ok(resources) => {
+                    let mut response = Response::new(Version::Http11, StatusCode::OK);
+                    let resources_serialized = serde_json::to_string(&resources).unwrap();
+
+                    response.set_body(Body::new(resources_serialized));
+                    response
+                }
+                Err(e) => error_response(e, StatusCode::InternalServerError),
+            },
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// The OpenAPI document of the API, converted to JSON when building the VMM.
+const API_SCHEMA: &str = include_str!(concat!(env!("OUT_DIR"), "/cloud-hypervisor.json"));
+
+// /api/v1/schema handler
+pub struct ApiSchema {}
+
+impl EndpointHandler for ApiSchema {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Get]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        _body: Option<&Body>,
+        _api_notifier: EventFd,
+        _api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Get => {
+                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                response.set_body(Body::new(API_SCHEMA));
+                response
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1 handler
+pub struct ApiDiscovery {}
+
+impl EndpointHandler for ApiDiscovery {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Get]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        _body: Option<&Body>,
+        _api_notifier: EventFd,
+        _api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Get => {
+                // The endpoints, sorted by path, along with their methods.
+                let endpoints: BTreeMap<&String, Vec<String>> = HTTP_ROUTES
+                    .routes
+                    .iter()
+                    .map(|(path, handler)| {
+                        let methods = handler
+                            .methods()
+                            .iter()
+                            .map(|method| format!("{:?}", method).to_uppercase())
+                            .collect();
+                        (path, methods)
+                    })
+                    .collect();
+                let discovery = serde_json::json!({
+                    "api_version": "v1",
+                    "endpoints": endpoints,
+                });
+
+                let mut response = Response::new(Version::Http11, StatusCode::OK);
+                response.set_body(Body::new(discovery.to_string()));
+                response
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vmm.pool handler
+pub struct VmmPool {}
+
+impl EndpointHandler for VmmPool {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
+
+    fn handle_request(
+        &self,
+        method: Method,
+        body: Option<&Body>,
+        api_notifier: EventFd,
+        api_sender: ApiSender,
+    ) -> Response {
+        match method {
+            Method::Put => {
+                match body {
+                    Some(body) => {
+                        // Deserialize into a VmmPoolData
+                        let data: VmmPoolData = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(data) => data,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        match vmm_pool(api_notifier, api_sender, Arc::new(data))
+                            .map_err(HttpError::VmmPool)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => match &e {
+                                HttpError::VmmPool(ApiError::VmmPool(VmError::InvalidConfig(
+                                    errors,
+                                ))) => invalid_config_response(&e, errors),
+                                _ => error_response(e, StatusCode::InternalServerError),
+                            },
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vmm.shutdown handler
+pub struct VmmShutdown {}
+
+impl EndpointHandler for VmmShutdown {
+    fn methods(&self) -> &'static [Method] {
+        &[Method::Put]
+    }
 
-impl EndpointHandler for VmmShutdown {
     fn handle_request(
         &self,
-        req: &Request,
+        method: Method,
+        _body: Option<&Body>,
         api_notifier: EventFd,
-        api_sender: Sender<ApiRequest>,
+        api_sender: ApiSender,
     ) -> Response {
-        match req.method() {
+        match method {
             Method::Put => {
                 match vmm_shutdown(api_notifier, api_sender).map_err(HttpError::VmmShutdown) {
                     Ok(_) => Response::new(Version::Http11, StatusCode::OK),
@@ -27,18 +27,45 @@
 //! 4. The thread reads the response back from the VMM API server, from the
 //!    response channel Receiver.
 //! 5. The thread handles the response and forwards potential errors.
+//!
+//! The requests are sent through an ApiSender, which tags them with the ID
+//! the API client gave its request, if any. The VMM thread carries it into
+//! its logs and into the events the request leads to.
+//!
+//! An ApiSender can also name the VM its requests are about, for a VMM
+//! process running several VMs. The VMM thread forwards the requests naming
+//! a VM to the VMM thread of that VM, the other ones being about the default
+//! VM of the process.
 
 extern crate micro_http;
 extern crate vmm_sys_util;
 
+pub use self::audit::ApiAuditLog;
+#[cfg(feature = "dbus_api")]
+pub use self::dbus::{start_dbus_thread, DBusApiOptions};
+pub use self::fd_socket::{start_fd_socket_thread, PassedFds};
 pub use self::http::start_http_thread;
 
+pub mod audit;
+#[cfg(feature = "dbus_api")]
+pub mod dbus;
+pub mod fd_socket;
+#[cfg(any(test, feature = "fuzzing"))]
+pub mod harness;
 pub mod http;
 pub mod http_endpoint;
 
-use crate::config::VmConfig;
+use crate::config::{PoolConfig, UserDeviceConfig, VmConfig, VmUpdateConfig, VsockConfig};
+use crate::cpu::VcpuFailure;
+use crate::diagnostics::DeviceAuditInfo;
+use crate::guest_os::GuestOsInfo;
+use crate::host_resources::HostResource;
+use crate::memory_manager::RamBacking;
+use crate::sriov::VfInfo;
 use crate::vm::{Error as VmError, VmState};
+use std::collections::BTreeMap;
 use std::io;
+use std::path::PathBuf;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::Arc;
 use vmm_sys_util::eventfd::EventFd;
@@ -58,6 +85,32 @@ pub enum ApiError {
     /// API response receive error
     ResponseRecv(RecvError),
 
+    /// The request ID is not made of up to 128 visible ASCII characters.
+    InvalidRequestId,
+
+    /// The VM ID is not made of up to 64 ASCII letters, digits, '-', '_' and
+    /// '.'.
+    InvalidVmId,
+
+    /// The owner token is not a Bearer token made of up to 256 visible ASCII
+    /// characters.
+    InvalidOwnerToken,
+
+    /// The request gives no owner token, which the multi-tenant VMM needs.
+    OwnerTokenMissing,
+
+    /// The owner token of the request is not the one of the VM, nor the
+    /// admin token.
+    VmNotOwned,
+
+    /// The VM configuration of a client other than the admin has a field
+    /// which only the admin may use.
+    TenantField(&'static str),
+
+    /// The request of a client other than the admin names a host file out
+    /// of the tenant directory.
+    TenantPath(PathBuf),
+
     /// The VM could not boot.
     VmBoot(VmError),
 
@@ -94,8 +147,75 @@ pub enum ApiError {
     /// The VM could not reboot.
     VmReboot(VmError),
 
+    /// The VM power button could not be pressed.
+    VmPowerButton(VmError),
+
+    /// The VM could not be quiesced.
+    VmQuiesce(VmError),
+
+    /// The VM sensors readings could not be set.
+    VmSetSensors(VmError),
+
+    /// The VM core dump could not be written.
+    VmCoredump(VmError),
+
+    /// The VM device could not be reset.
+    VmResetDevice(VmError),
+
+    /// The VM device audit is not available.
+    VmDeviceAudit(VmError),
+
+    /// The weight of the VM disk could not be changed.
+    VmSetDiskWeight(VmError),
+
+    /// The dirty rate of the VM could not be measured.
+    VmDirtyRate(VmError),
+
+    /// The VM display could not be written.
+    VmScreenshot(VmError),
+
+    /// The VM vCPUs could not be throttled.
+    VmThrottle(VmError),
+
+    /// The device could not be added to the VM.
+    VmAddDevice(VmError),
+
+    /// The device could not be removed from the VM.
+    VmRemoveDevice(VmError),
+
+    /// The desired configuration could not be applied to the VM.
+    VmApply(VmError),
+
+    /// The configuration of the VM could not be updated.
+    VmUpdate(VmError),
+
+    /// No VM of the pool is ready to be claimed, or the VMM has no pool.
+    VmPoolEmpty,
+
+    /// The VM to claim is not a VM of the pool, a VM being claimed through
+    /// the pool rather than by its ID.
+    VmNotPooled,
+
+    /// The VM pool could not be claimed from.
+    VmClaim(VmError),
+
+    /// The VM pool is already created.
+    VmPoolAlreadyCreated,
+
+    /// The VM pool could not be created.
+    VmmPool(VmError),
+
     /// The VMM could not shutdown.
     VmmShutdown(VmError),
+
+    /// The VMM capabilities could not be retrieved.
+    VmmCapabilities(VmError),
+
+    /// The VMM file descriptors could not be listed.
+    VmmFds(io::Error),
+
+    /// The VMM thread of the VM could not be started, or exited.
+    VmmThread(io::Error),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -103,6 +223,233 @@ pub type ApiResult<T> = std::result::Result<T, ApiError>;
 pub struct VmInfo {
     pub config: Arc<VmConfig>,
     pub state: VmState,
+    #[serde(default)]
+    pub serial_pty: Option<PathBuf>,
+    #[serde(default)]
+    pub console_pty: Option<PathBuf>,
+    /// Pseudo-terminals backing the additional UARTs, in their order.
+    #[serde(default)]
+    pub uart_ptys: Vec<Option<PathBuf>>,
+    /// The vCPUs that failed to run the guest, if any.
+    #[serde(default)]
+    pub vcpu_failures: Vec<VcpuFailure>,
+    /// All the vCPUs are halted for longer than the idle timeout.
+    #[serde(default)]
+    pub idle: bool,
+    /// The memory of the idle guest was paged out, and the guest wasn't busy
+    /// since.
+    #[serde(default)]
+    pub hibernated: bool,
+    /// Pages the guest RAM regions are backed by.
+    #[serde(default)]
+    pub memory_backing: Vec<RamBacking>,
+    /// Size of the guest RAM, once the VM is booted.
+    #[serde(default)]
+    pub memory_actual_size: u64,
+    /// PCI addresses of the devices, by ID, once the VM is booted.
+    #[serde(default)]
+    pub pci_devices: BTreeMap<String, String>,
+    /// The guest OS, as far as it was probed, when the VM is configured to
+    /// probe it.
+    #[serde(default)]
+    pub guest_os: Option<GuestOsInfo>,
+    /// Drift of the guest clock from the host clock last checked, in
+    /// milliseconds, when the VM is configured to check it.
+    #[serde(default)]
+    pub clock_drift_ms: Option<i64>,
+    /// SR-IOV virtual functions of the network devices passed through, by
+    /// ID, along with the MAC addresses and link states their physical
+    /// functions report.
+    #[serde(default)]
+    pub sriov_vfs: BTreeMap<String, VfInfo>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmmCapabilities {
+    /// Number of KVM memory slots supported by the host.
+    pub max_memory_slots: u32,
+    /// Number of KVM memory slots the current VM uses, if any.
+    #[serde(default)]
+    pub used_memory_slots: Option<u32>,
+}
+
+/// Readings reported by the emulated ACPI battery and thermal zone. The ones
+/// left out keep their current value.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSensors {
+    #[serde(default)]
+    pub battery: Option<BatteryState>,
+    /// Thermal zone temperature, in degrees Celsius.
+    #[serde(default)]
+    pub temperature: Option<u32>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmCoredumpData {
+    /// Path of the core dump file to write.
+    pub destination: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmResetDeviceData {
+    /// ID of the virtio device to reset, such as "block0".
+    pub id: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmRemoveDeviceData {
+    /// ID of the vsock, vfio-user or removable disk device to remove, such
+    /// as "vsock0".
+    pub id: String,
+}
+
+/// A device added to the PCI bus of the VM.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PciDeviceInfo {
+    pub id: String,
+    /// PCI address, such as "0000:00:05.0".
+    pub bdf: String,
+}
+
+/// The changes `vm.apply` made to the configuration of the VM.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct VmApplyResult {
+    /// IDs of the devices added.
+    pub added: Vec<String>,
+    /// The devices the running VM hot-added, and where.
+    pub pci_devices: Vec<PciDeviceInfo>,
+    /// IDs of the devices removed, or which the guest of the running VM is
+    /// asked to eject.
+    pub removed: Vec<String>,
+    /// IDs of the disks whose weight changed.
+    pub disk_weights: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmDiskWeightData {
+    /// ID of the virtio-blk disk, such as "block0".
+    pub id: String,
+    /// Weight of the disk in its disk group.
+    pub weight: u32,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmScreenshotData {
+    /// Path of the PPM image file to write.
+    pub destination: PathBuf,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct VmThrottleData {
+    /// CPU quota of the vCPUs, in percent of a host CPU, None lifting it.
+    #[serde(default)]
+    pub quota: Option<u32>,
+}
+
+/// The cap on the CPU time of the vCPUs of a VM, and how much they were held
+/// back by it, which the guest sees as steal time when it's given it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VmThrottle {
+    /// CPU quota of the vCPUs, in percent of a host CPU, if capped.
+    pub quota: Option<u32>,
+    /// CPU time the vCPUs used, in microseconds.
+    pub usage_usec: u64,
+    /// Periods of the quota elapsed, and the ones the vCPUs used it up in.
+    pub nr_periods: u64,
+    pub nr_throttled: u64,
+    /// Time the vCPUs were throttled for, in microseconds.
+    pub throttled_usec: u64,
+    /// Whether the guest is given the steal time MSR.
+    pub steal_time: bool,
+}
+
+/// Default length of the window the dirty rate of a VM is sampled over.
+pub const DEFAULT_DIRTY_RATE_DURATION_MS: u64 = 1000;
+
+fn default_dirty_rate_duration() -> u64 {
+    DEFAULT_DIRTY_RATE_DURATION_MS
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmDirtyRateData {
+    /// Length of the sampling window, in milliseconds.
+    #[serde(default = "default_dirty_rate_duration")]
+    pub duration_ms: u64,
+}
+
+impl Default for VmDirtyRateData {
+    fn default() -> Self {
+        VmDirtyRateData {
+            duration_ms: DEFAULT_DIRTY_RATE_DURATION_MS,
+        }
+    }
+}
+
+/// The pages of its RAM a guest wrote to over a sampling window, from which
+/// the time a live migration takes to converge can be told.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VmDirtyRate {
+    /// Length of the sampling window, in milliseconds.
+    pub duration_ms: u64,
+    /// Size of the pages, the host ones, in bytes.
+    pub page_size: u64,
+    /// Pages the guest wrote to during the window.
+    pub dirty_pages: u64,
+    pub dirty_pages_per_second: u64,
+    pub dirty_bytes_per_second: u64,
+}
+
+impl VmDirtyRate {
+    pub fn new(dirty_pages: u64, page_size: u64, duration_ms: u64) -> Self {
+        let dirty_pages_per_second = dirty_pages * 1000 / duration_ms.max(1);
+        VmDirtyRate {
+            duration_ms,
+            page_size,
+            dirty_pages,
+            dirty_pages_per_second,
+            dirty_bytes_per_second: dirty_pages_per_second * page_size,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmmPoolData {
+    /// Configuration the VMs of the pool are booted from.
+    pub config: VmConfig,
+    #[serde(flatten)]
+    pub pool: PoolConfig,
+}
+
+/// The claim of a VM of the pool, which the guest agent of the VM is given
+/// as is.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmClaimData {
+    /// ID the claimed VM goes by from now on.
+    pub id: String,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Anything else the guest agent customizes the guest from.
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BatteryState {
+    /// The battery is charging, as opposed to discharging.
+    pub charging: bool,
+    /// Remaining capacity, in percent.
+    pub level: u8,
+    /// Charging or discharging rate, in mW.
+    #[serde(default)]
+    pub rate: u32,
+}
+
+/// An open file descriptor of the VMM process, along with what it refers to,
+/// e.g. a path or "socket:[<inode>]".
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FdInfo {
+    pub fd: i32,
+    pub target: PathBuf,
 }
 
 pub enum ApiResponsePayload {
@@ -111,6 +458,30 @@ pub enum ApiResponsePayload {
 
     /// Virtual machine information
     VmInfo(VmInfo),
+
+    /// Audits of the guest drivers of the virtual machine devices
+    VmDeviceAudit(Vec<DeviceAuditInfo>),
+
+    /// Rate the virtual machine dirtied its RAM at
+    VmDirtyRate(VmDirtyRate),
+
+    /// Throttling of the virtual machine vCPUs
+    VmThrottle(VmThrottle),
+
+    /// Device added to the running virtual machine
+    PciDeviceInfo(PciDeviceInfo),
+
+    /// Changes made to the virtual machine configuration
+    VmApply(VmApplyResult),
+
+    /// Virtual Machine Monitor capabilities
+    VmmCapabilities(VmmCapabilities),
+
+    /// Virtual Machine Monitor open file descriptors
+    VmmFds(Vec<FdInfo>),
+
+    /// Host resources reserved by the Virtual Machine Monitor
+    VmmHostResources(Vec<HostResource>),
 }
 
 /// This is the response sent by the VMM API server through the mpsc channel.
@@ -154,17 +525,284 @@ pub enum ApiRequest {
     /// will send a VmReboot error back.
     VmReboot(Sender<ApiResponse>),
 
+    /// Press the ACPI power button of the previously booted virtual machine,
+    /// so that the guest OS can shut itself down gracefully.
+    /// If the VM was not previously booted, the VMM API server will send a
+    /// VmPowerButton error back.
+    VmPowerButton(Sender<ApiResponse>),
+
+    /// Pause the previously booted virtual machine and flush its disk images,
+    /// before the VMM process gets checkpointed. The VM is restarted with
+    /// VmResume.
+    VmQuiesce(Sender<ApiResponse>),
+
+    /// Set the readings of the emulated ACPI battery and thermal zone, and
+    /// notify the guest about them.
+    /// If the VM was not previously booted, or has no such sensors, the VMM
+    /// API server will send a VmSetSensors error back.
+    VmSetSensors(Arc<VmSensors>, Sender<ApiResponse>),
+
+    /// Write a core dump of the paused VM. If the VM is not paused, the API
+    /// server will send a VmCoredump error back.
+    VmCoredump(Arc<VmCoredumpData>, Sender<ApiResponse>),
+
+    /// Reset a virtio device of the VM and activate it again, the guest
+    /// driver keeping its queues. If the VM was not previously booted, or
+    /// has no such device, the API server will send a VmResetDevice error
+    /// back.
+    VmResetDevice(Arc<VmResetDeviceData>, Sender<ApiResponse>),
+
+    /// Request the audits of the guest drivers of the virtio devices of the
+    /// VM. If the VM was not previously booted, or isn't configured with the
+    /// device audit, the API server will send a VmDeviceAudit error back.
+    VmDeviceAudit(Sender<ApiResponse>),
+
+    /// Change the weight of a disk of the VM in its disk group. If the VM
+    /// was not previously booted, or has no such disk in a disk group, the
+    /// API server will send a VmSetDiskWeight error back.
+    VmSetDiskWeight(Arc<VmDiskWeightData>, Sender<ApiResponse>),
+
+    /// Measure the rate the running VM dirties its RAM at, over a sampling
+    /// window. The response is only sent once the window is over, the VMM
+    /// handling the other requests meanwhile. If the VM is not running, or
+    /// its dirty pages can't be logged, the API server will send a
+    /// VmDirtyRate error back.
+    VmDirtyRate(Arc<VmDirtyRateData>, Sender<ApiResponse>),
+
+    /// Write the display of the virtio-gpu device of the VM, as its guest
+    /// last flushed it. If the VM was not previously booted, has no
+    /// virtio-gpu device, or its guest didn't set up the display, the API
+    /// server will send a VmScreenshot error back.
+    VmScreenshot(Arc<VmScreenshotData>, Sender<ApiResponse>),
+
+    /// Change the CPU quota of the vCPUs of the VM, when given one, and
+    /// report how much they were throttled. If the VM was not previously
+    /// booted, or has no cgroup, the API server will send a VmThrottle error
+    /// back.
+    VmThrottle(Option<Arc<VmThrottleData>>, Sender<ApiResponse>),
+
+    /// Add a vsock device to the VM configuration, and hot-add it when the
+    /// VM runs. If the configuration is invalid with the device, or the VM
+    /// can't hot-add it, the API server will send a VmAddDevice error back.
+    VmAddVsock(Arc<VsockConfig>, Sender<ApiResponse>),
+
+    /// Add a vfio-user device, as VmAddVsock does.
+    VmAddUserDevice(Arc<UserDeviceConfig>, Sender<ApiResponse>),
+
+    /// Remove a vsock, vfio-user or removable disk device from the VM
+    /// configuration, asking the guest to eject it when the VM runs. If the
+    /// VM has no such device, the API server will send a VmRemoveDevice
+    /// error back.
+    VmRemoveDevice(Arc<VmRemoveDeviceData>, Sender<ApiResponse>),
+
+    /// Bring the VM configuration to the desired one, adding and removing
+    /// the devices and changing the disk weights the running VM can take.
+    /// If the VM can't take all the changes, the API server will send a
+    /// VmApply error back, having changed nothing.
+    VmApply(Arc<VmConfig>, Sender<ApiResponse>),
+
+    /// Change the fields of the VM configuration the running VM takes
+    /// without its devices being created anew: the file its serial port
+    /// writes to and the rate limiters of its devices. If the update changes
+    /// other fields, the API server will send a VmUpdate error back, having
+    /// changed nothing.
+    VmUpdate(Arc<VmUpdateConfig>, Sender<ApiResponse>),
+
+    /// Hand a VM of the pool out, under the ID of the claim, resuming it and
+    /// giving the claim to its guest agent. If no VM of the pool is ready,
+    /// the API server will send a VmPoolEmpty error back.
+    VmClaim(Arc<VmClaimData>, Sender<ApiResponse>),
+
+    /// Create the VM pool, and boot its VMs from the template. If the pool
+    /// is already created, the API server will send a VmPoolAlreadyCreated
+    /// error back.
+    VmmPool(Arc<VmmPoolData>, Sender<ApiResponse>),
+
+    /// Request the VMM capabilities.
+    VmmCapabilities(Sender<ApiResponse>),
+
+    /// Request the list of the VMM open file descriptors.
+    VmmFds(Sender<ApiResponse>),
+
+    /// Request the list of the host resources the VMM reserved, when it
+    /// coordinates them with the other VMM processes of the host.
+    VmmHostResources(Sender<ApiResponse>),
+
     /// Shut the VMM down.
     /// This will shutdown and delete the current VM, if any, and then exit the
     /// VMM process.
     VmmShutdown(Sender<ApiResponse>),
 }
 
-pub fn vm_create(
-    api_evt: EventFd,
-    api_sender: Sender<ApiRequest>,
-    config: Arc<VmConfig>,
-) -> ApiResult<()> {
+/// Longest request ID, the IDs going as is into the logs and the events.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Whether the ID a client tags its request with can be used as is in the
+/// logs and the events.
+pub fn valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+impl ApiRequest {
+    /// The channel the response to the request is sent to.
+    pub fn response_sender(&self) -> &Sender<ApiResponse> {
+        match self {
+            ApiRequest::VmCreate(_, sender)
+            | ApiRequest::VmSetSensors(_, sender)
+            | ApiRequest::VmCoredump(_, sender)
+            | ApiRequest::VmResetDevice(_, sender)
+            | ApiRequest::VmSetDiskWeight(_, sender)
+            | ApiRequest::VmDirtyRate(_, sender)
+            | ApiRequest::VmScreenshot(_, sender)
+            | ApiRequest::VmThrottle(_, sender)
+            | ApiRequest::VmAddVsock(_, sender)
+            | ApiRequest::VmAddUserDevice(_, sender)
+            | ApiRequest::VmRemoveDevice(_, sender)
+            | ApiRequest::VmApply(_, sender)
+            | ApiRequest::VmUpdate(_, sender)
+            | ApiRequest::VmClaim(_, sender)
+            | ApiRequest::VmmPool(_, sender)
+            | ApiRequest::VmBoot(sender)
+            | ApiRequest::VmDelete(sender)
+            | ApiRequest::VmInfo(sender)
+            | ApiRequest::VmDeviceAudit(sender)
+            | ApiRequest::VmPause(sender)
+            | ApiRequest::VmResume(sender)
+            | ApiRequest::VmShutdown(sender)
+            | ApiRequest::VmReboot(sender)
+            | ApiRequest::VmPowerButton(sender)
+            | ApiRequest::VmQuiesce(sender)
+            | ApiRequest::VmmCapabilities(sender)
+            | ApiRequest::VmmFds(sender)
+            | ApiRequest::VmmHostResources(sender)
+            | ApiRequest::VmmShutdown(sender) => sender,
+        }
+    }
+}
+
+/// Longest VM ID, the IDs going as is into the registry file names and the
+/// thread names.
+const MAX_VM_ID_LEN: usize = 64;
+
+/// Whether the ID of a VM can be used as is in file and thread names.
+pub fn valid_vm_id(vm_id: &str) -> bool {
+    !vm_id.is_empty()
+        && vm_id.len() <= MAX_VM_ID_LEN
+        && vm_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.')
+}
+
+/// Longest owner token.
+const MAX_OWNER_TOKEN_LEN: usize = 256;
+
+/// Whether an owner token can be compared and kept as is.
+pub fn valid_owner_token(token: &str) -> bool {
+    !token.is_empty()
+        && token.len() <= MAX_OWNER_TOKEN_LEN
+        && token.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Where an API request comes from, for a multi-tenant VMM to check it
+/// against the owner of the VM it is about.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApiClient {
+    /// The process itself, the VM of its command line, and the D-Bus API,
+    /// which the policy of the bus guards.
+    Local,
+    /// A client of the HTTP API, along with the owner token it gave, if any.
+    Http(Option<String>),
+}
+
+/// An API request, along with the ID its client tagged it with, if any, the
+/// ID of the VM it is about, if not the default one, and its client.
+pub struct ApiMessage {
+    pub request: ApiRequest,
+    pub request_id: Option<String>,
+    pub vm_id: Option<String>,
+    pub client: ApiClient,
+}
+
+/// Sends the API requests to the VMM thread, tagged with a request ID, a VM
+/// ID and their client.
+#[derive(Clone)]
+pub struct ApiSender {
+    sender: Sender<ApiMessage>,
+    request_id: Option<String>,
+    vm_id: Option<String>,
+    client: ApiClient,
+}
+
+impl ApiSender {
+    pub fn new(sender: Sender<ApiMessage>) -> Self {
+        ApiSender {
+            sender,
+            request_id: None,
+            vm_id: None,
+            client: ApiClient::Local,
+        }
+    }
+
+    /// Tags the requests sent from now on with `request_id`.
+    pub fn with_request_id(self, request_id: String) -> ApiResult<Self> {
+        if !valid_request_id(&request_id) {
+            return Err(ApiError::InvalidRequestId);
+        }
+
+        Ok(ApiSender {
+            request_id: Some(request_id),
+            ..self
+        })
+    }
+
+    /// Sends the requests from now on to the VM `vm_id`, rather than to the
+    /// default VM.
+    pub fn with_vm_id(self, vm_id: String) -> ApiResult<Self> {
+        if !valid_vm_id(&vm_id) {
+            return Err(ApiError::InvalidVmId);
+        }
+
+        Ok(ApiSender {
+            vm_id: Some(vm_id),
+            ..self
+        })
+    }
+
+    /// Sends the requests from now on on behalf of an HTTP client, which gave
+    /// `token` as its owner token, if any.
+    pub fn with_http_client(self, token: Option<String>) -> ApiResult<Self> {
+        if let Some(token) = &token {
+            if !valid_owner_token(token) {
+                return Err(ApiError::InvalidOwnerToken);
+            }
+        }
+
+        Ok(ApiSender {
+            client: ApiClient::Http(token),
+            ..self
+        })
+    }
+
+    /// The client the requests are sent on behalf of.
+    pub fn client(&self) -> &ApiClient {
+        &self.client
+    }
+
+    pub fn send(&self, request: ApiRequest) -> Result<(), SendError<ApiRequest>> {
+        self.sender
+            .send(ApiMessage {
+                request,
+                request_id: self.request_id.clone(),
+                vm_id: self.vm_id.clone(),
+                client: self.client.clone(),
+            })
+            .map_err(|e| SendError((e.0).request))
+    }
+}
+
+pub fn vm_create(api_evt: EventFd, api_sender: ApiSender, config: Arc<VmConfig>) -> ApiResult<()> {
     let (response_sender, response_receiver) = channel();
 
     // Send the VM creation request.
@@ -199,9 +837,15 @@ pub enum VmAction {
 
     /// Resume a VM
     Resume,
+
+    /// Press the power button of a VM
+    PowerButton,
+
+    /// Quiesce a VM
+    Quiesce,
 }
 
-fn vm_action(api_evt: EventFd, api_sender: Sender<ApiRequest>, action: VmAction) -> ApiResult<()> {
+fn vm_action(api_evt: EventFd, api_sender: ApiSender, action: VmAction) -> ApiResult<()> {
     let (response_sender, response_receiver) = channel();
 
     let request = match action {
@@ -211,6 +855,8 @@ fn vm_action(api_evt: EventFd, api_sender: Sender<ApiRequest>, action: VmAction)
         VmAction::Reboot => ApiRequest::VmReboot(response_sender),
         VmAction::Pause => ApiRequest::VmPause(response_sender),
         VmAction::Resume => ApiRequest::VmResume(response_sender),
+        VmAction::PowerButton => ApiRequest::VmPowerButton(response_sender),
+        VmAction::Quiesce => ApiRequest::VmQuiesce(response_sender),
     };
 
     // Send the VM request.
@@ -222,31 +868,35 @@ fn vm_action(api_evt: EventFd, api_sender: Sender<ApiRequest>, action: VmAction)
     Ok(())
 }
 
-pub fn vm_boot(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+pub fn vm_boot(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
     vm_action(api_evt, api_sender, VmAction::Boot)
 }
 
-pub fn vm_delete(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+pub fn vm_delete(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
     vm_action(api_evt, api_sender, VmAction::Delete)
 }
 
-pub fn vm_shutdown(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+pub fn vm_shutdown(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
     vm_action(api_evt, api_sender, VmAction::Shutdown)
 }
 
-pub fn vm_reboot(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+pub fn vm_reboot(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
     vm_action(api_evt, api_sender, VmAction::Reboot)
 }
 
-pub fn vm_pause(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+pub fn vm_pause(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
     vm_action(api_evt, api_sender, VmAction::Pause)
 }
 
-pub fn vm_resume(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+pub fn vm_resume(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
     vm_action(api_evt, api_sender, VmAction::Resume)
 }
 
-pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmInfo> {
+pub fn vm_power_button(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
+    vm_action(api_evt, api_sender, VmAction::PowerButton)
+}
+
+pub fn vm_info(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<VmInfo> {
     let (response_sender, response_receiver) = channel();
 
     // Send the VM request.
@@ -263,7 +913,335 @@ pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Vm
     }
 }
 
-pub fn vmm_shutdown(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<()> {
+pub fn vm_quiesce(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
+    vm_action(api_evt, api_sender, VmAction::Quiesce)
+}
+
+pub fn vm_set_sensors(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    sensors: Arc<VmSensors>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM sensors request.
+    api_sender
+        .send(ApiRequest::VmSetSensors(sensors, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_coredump(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VmCoredumpData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM core dump request.
+    api_sender
+        .send(ApiRequest::VmCoredump(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_reset_device(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VmResetDeviceData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM device reset request.
+    api_sender
+        .send(ApiRequest::VmResetDevice(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_device_audit(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<Vec<DeviceAuditInfo>> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM device audit request.
+    api_sender
+        .send(ApiRequest::VmDeviceAudit(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let audit = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match audit {
+        ApiResponsePayload::VmDeviceAudit(audit) => Ok(audit),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_set_disk_weight(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VmDiskWeightData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM disk weight request.
+    api_sender
+        .send(ApiRequest::VmSetDiskWeight(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_dirty_rate(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VmDirtyRateData>,
+) -> ApiResult<VmDirtyRate> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM dirty rate request.
+    api_sender
+        .send(ApiRequest::VmDirtyRate(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let dirty_rate = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match dirty_rate {
+        ApiResponsePayload::VmDirtyRate(dirty_rate) => Ok(dirty_rate),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_screenshot(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VmScreenshotData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM screenshot request.
+    api_sender
+        .send(ApiRequest::VmScreenshot(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_throttle(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Option<Arc<VmThrottleData>>,
+) -> ApiResult<VmThrottle> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM throttle request.
+    api_sender
+        .send(ApiRequest::VmThrottle(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let throttle = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match throttle {
+        ApiResponsePayload::VmThrottle(throttle) => Ok(throttle),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+// The device the running VM hot-added, None when the VM doesn't run.
+fn pci_device_info(response: ApiResponsePayload) -> ApiResult<Option<PciDeviceInfo>> {
+    match response {
+        ApiResponsePayload::PciDeviceInfo(info) => Ok(Some(info)),
+        ApiResponsePayload::Empty => Ok(None),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_add_vsock(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VsockConfig>,
+) -> ApiResult<Option<PciDeviceInfo>> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM add vsock request.
+    api_sender
+        .send(ApiRequest::VmAddVsock(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    pci_device_info(response_receiver.recv().map_err(ApiError::ResponseRecv)??)
+}
+
+pub fn vm_add_user_device(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<UserDeviceConfig>,
+) -> ApiResult<Option<PciDeviceInfo>> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM add user device request.
+    api_sender
+        .send(ApiRequest::VmAddUserDevice(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    pci_device_info(response_receiver.recv().map_err(ApiError::ResponseRecv)??)
+}
+
+pub fn vm_remove_device(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VmRemoveDeviceData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM remove device request.
+    api_sender
+        .send(ApiRequest::VmRemoveDevice(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_apply(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VmConfig>,
+) -> ApiResult<VmApplyResult> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM apply request.
+    api_sender
+        .send(ApiRequest::VmApply(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    match response_receiver.recv().map_err(ApiError::ResponseRecv)?? {
+        ApiResponsePayload::VmApply(result) => Ok(result),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vm_update(
+    api_evt: EventFd,
+    api_sender: ApiSender,
+    data: Arc<VmUpdateConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM update request.
+    api_sender
+        .send(ApiRequest::VmUpdate(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_claim(api_evt: EventFd, api_sender: ApiSender, data: Arc<VmClaimData>) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM claim request.
+    api_sender
+        .send(ApiRequest::VmClaim(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vmm_pool(api_evt: EventFd, api_sender: ApiSender, data: Arc<VmmPoolData>) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM pool request.
+    api_sender
+        .send(ApiRequest::VmmPool(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vmm_fds(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<Vec<FdInfo>> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VMM file descriptors request.
+    api_sender
+        .send(ApiRequest::VmmFds(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let fds = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match fds {
+        ApiResponsePayload::VmmFds(fds) => Ok(fds),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vmm_host_resources(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<Vec<HostResource>> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VMM host resources request.
+    api_sender
+        .send(ApiRequest::VmmHostResources(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let resources = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match resources {
+        ApiResponsePayload::VmmHostResources(resources) => Ok(resources),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vmm_capabilities(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<VmmCapabilities> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VMM capabilities request.
+    api_sender
+        .send(ApiRequest::VmmCapabilities(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let capabilities = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match capabilities {
+        ApiResponsePayload::VmmCapabilities(capabilities) => Ok(capabilities),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
+pub fn vmm_shutdown(api_evt: EventFd, api_sender: ApiSender) -> ApiResult<()> {
     let (response_sender, response_receiver) = channel();
 
     // Send the VMM shutdown request.
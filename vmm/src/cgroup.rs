@@ -0,0 +1,297 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Placement of the VMM into a cgroup v2 of its VM, for the host resources
+//! of the VM to be controlled without a wrapper moving the process around.
+//!
+//! The VMM process is moved into the cgroup, created if needed, which is
+//! given the io.weight of the VM, the io controller only applying to whole
+//! processes. The vCPU threads are moved into its `vcpus` threaded
+//! sub-group, and the threads they start into its `devices` one, each with
+//! its cpu.weight, cpu.max and cpuset.cpus. The device threads are the
+//! workers of the virtio devices, started from the vCPU thread whose write
+//! activates the device, in the `vcpus` sub-group then: the threads of that
+//! sub-group which aren't vCPU threads are moved every second.
+//!
+//! The cpu.max of the `vcpus` sub-group can be changed while the VM runs,
+//! throttling the guest, which sees the time its vCPUs were held back as
+//! steal time.
+
+use crate::config::{CgroupConfig, CgroupThreadsConfig};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Sub-groups of the vCPU threads, and of the threads they start.
+const VCPUS_CGROUP: &str = "vcpus";
+const DEVICES_CGROUP: &str = "devices";
+// How often the threads the vCPU threads started are moved to their
+// sub-group.
+const DEVICE_THREADS_PERIOD: Duration = Duration::from_secs(1);
+// Period of the cpu.max quotas, in microseconds.
+const CPU_MAX_PERIOD: u64 = 100_000;
+
+// A process being in a single cgroup, a single VM of the process can have
+// one.
+static PROCESS_CGROUP: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum Error {
+    /// Another VM of the process has a cgroup.
+    InUse,
+    /// Cannot create the cgroup, or one of its sub-groups.
+    Create(PathBuf, io::Error),
+    /// Cannot write a control file of the cgroup.
+    Write(PathBuf, io::Error),
+    /// Cannot read a control file of the cgroup.
+    Read(PathBuf, io::Error),
+    /// Cannot spawn the thread moving the device threads.
+    Thread(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// The CPU time the vCPU threads used, and how much they were throttled.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuStat {
+    /// CPU quota of the vCPU threads, in percent of a host CPU, if capped.
+    pub quota: Option<u32>,
+    /// CPU time used, in microseconds.
+    pub usage_usec: u64,
+    /// Periods of the quota elapsed, and the ones it was used up in.
+    pub nr_periods: u64,
+    pub nr_throttled: u64,
+    /// Time the threads were throttled for, in microseconds.
+    pub throttled_usec: u64,
+}
+
+pub struct VmCgroup {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl VmCgroup {
+    /// Creates the cgroup and its sub-groups, and moves the VMM process
+    /// into the cgroup.
+    pub fn new(config: &CgroupConfig) -> Result<Self> {
+        if PROCESS_CGROUP.swap(true, Ordering::SeqCst) {
+            return Err(Error::InUse);
+        }
+        // Dropped on error, releasing the process.
+        let cgroup = VmCgroup {
+            path: config.path.clone(),
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        };
+
+        create_cgroup(&cgroup.path)?;
+        if let Some(io_weight) = config.io_weight {
+            write_file(&cgroup.path, "io.weight", &format!("default {}", io_weight))?;
+        }
+
+        // The sub-groups are threaded before the controllers are enabled,
+        // for the cgroup to be the domain of their threads.
+        for name in [VCPUS_CGROUP, DEVICES_CGROUP].iter() {
+            let path = cgroup.path.join(name);
+            create_cgroup(&path)?;
+            write_file(&path, "cgroup.type", "threaded")?;
+        }
+        let threads = [&config.vcpus, &config.devices];
+        let mut controllers = Vec::new();
+        if threads
+            .iter()
+            .any(|threads| threads.weight.is_some() || threads.quota.is_some())
+        {
+            controllers.push("+cpu");
+        }
+        if threads.iter().any(|threads| threads.cpus.is_some()) {
+            controllers.push("+cpuset");
+        }
+        if !controllers.is_empty() {
+            write_file(
+                &cgroup.path,
+                "cgroup.subtree_control",
+                &controllers.join(" "),
+            )?;
+        }
+        set_limits(&cgroup.path.join(VCPUS_CGROUP), &config.vcpus)?;
+        set_limits(&cgroup.path.join(DEVICES_CGROUP), &config.devices)?;
+
+        write_file(
+            &cgroup.path,
+            "cgroup.procs",
+            &std::process::id().to_string(),
+        )?;
+
+        Ok(cgroup)
+    }
+
+    /// Moves the vCPU threads into their sub-group, and starts moving the
+    /// threads they start into the devices one.
+    pub fn start(&mut self, vcpu_tids: Vec<libc::pid_t>) -> Result<()> {
+        let vcpus_path = self.path.join(VCPUS_CGROUP);
+        let devices_path = self.path.join(DEVICES_CGROUP);
+        for tid in vcpu_tids.iter() {
+            write_file(&vcpus_path, "cgroup.threads", &tid.to_string())?;
+        }
+
+        let stop = self.stop.clone();
+        self.thread = Some(
+            thread::Builder::new()
+                .name("cgroup".to_string())
+                .spawn(move || loop {
+                    thread::park_timeout(DEVICE_THREADS_PERIOD);
+                    if stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let tids = match read_threads(&vcpus_path) {
+                        Ok(tids) => tids,
+                        Err(e) => {
+                            warn!("Cannot read the vCPUs cgroup threads: {}", e);
+                            continue;
+                        }
+                    };
+                    for tid in tids.iter().filter(|tid| !vcpu_tids.contains(tid)) {
+                        // The thread may have exited since.
+                        if let Err(e) =
+                            write_file(&devices_path, "cgroup.threads", &tid.to_string())
+                        {
+                            debug!("Cannot move the device thread {}: {:?}", tid, e);
+                        }
+                    }
+                })
+                .map_err(Error::Thread)?,
+        );
+
+        Ok(())
+    }
+
+    /// Caps the vCPU threads to the quota, in percent of a host CPU, or
+    /// lifts their cap.
+    pub fn set_vcpus_quota(&self, quota: Option<u32>) -> Result<()> {
+        // Enabling a controller already enabled is a no-op.
+        write_file(&self.path, "cgroup.subtree_control", "+cpu")?;
+        let max = match quota {
+            Some(quota) => (u64::from(quota) * CPU_MAX_PERIOD / 100).to_string(),
+            None => "max".to_string(),
+        };
+        write_file(
+            &self.path.join(VCPUS_CGROUP),
+            "cpu.max",
+            &format!("{} {}", max, CPU_MAX_PERIOD),
+        )
+    }
+
+    /// The quota of the vCPU threads, and the CPU time they used.
+    pub fn vcpus_cpu_stat(&self) -> Result<CpuStat> {
+        let vcpus_path = self.path.join(VCPUS_CGROUP);
+        let mut stat = CpuStat::default();
+
+        // The cpu.max file only exists once the cpu controller is enabled.
+        let max_path = vcpus_path.join("cpu.max");
+        if max_path.exists() {
+            let max = fs::read_to_string(&max_path).map_err(|e| Error::Read(max_path, e))?;
+            let mut fields = max.split_whitespace();
+            if let (Some(Ok(max)), Some(Ok(period))) = (
+                fields.next().map(str::parse::<u64>),
+                fields.next().map(str::parse::<u64>),
+            ) {
+                stat.quota = Some((max * 100 / period.max(1)) as u32);
+            }
+        }
+
+        let stat_path = vcpus_path.join("cpu.stat");
+        let lines = fs::read_to_string(&stat_path).map_err(|e| Error::Read(stat_path, e))?;
+        for line in lines.lines() {
+            let mut fields = line.split_whitespace();
+            let (name, value) = match (fields.next(), fields.next().map(str::parse)) {
+                (Some(name), Some(Ok(value))) => (name, value),
+                _ => continue,
+            };
+            match name {
+                "usage_usec" => stat.usage_usec = value,
+                "nr_periods" => stat.nr_periods = value,
+                "nr_throttled" => stat.nr_throttled = value,
+                "throttled_usec" => stat.throttled_usec = value,
+                _ => {}
+            }
+        }
+
+        Ok(stat)
+    }
+}
+
+impl Drop for VmCgroup {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            if thread.join().is_err() {
+                error!("The cgroup thread panicked");
+            }
+        }
+
+        // The threads still alive are moved back to the cgroup, for the
+        // sub-groups to be removed. The cgroup itself is left, the process
+        // being in it.
+        for name in [VCPUS_CGROUP, DEVICES_CGROUP].iter() {
+            let path = self.path.join(name);
+            if !path.exists() {
+                continue;
+            }
+            for tid in read_threads(&path).unwrap_or_default() {
+                let _ = write_file(&self.path, "cgroup.threads", &tid.to_string());
+            }
+            if let Err(e) = fs::remove_dir(&path) {
+                warn!("Cannot remove the cgroup {}: {}", path.display(), e);
+            }
+        }
+
+        PROCESS_CGROUP.store(false, Ordering::SeqCst);
+    }
+}
+
+fn create_cgroup(path: &Path) -> Result<()> {
+    match fs::create_dir(path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+            Err(Error::Create(path.to_path_buf(), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn write_file(cgroup: &Path, name: &str, value: &str) -> Result<()> {
+    let path = cgroup.join(name);
+    fs::write(&path, value).map_err(|e| Error::Write(path, e))
+}
+
+fn set_limits(cgroup: &Path, threads: &CgroupThreadsConfig) -> Result<()> {
+    if let Some(weight) = threads.weight {
+        write_file(cgroup, "cpu.weight", &weight.to_string())?;
+    }
+    if let Some(quota) = threads.quota {
+        let max = u64::from(quota) * CPU_MAX_PERIOD / 100;
+        write_file(cgroup, "cpu.max", &format!("{} {}", max, CPU_MAX_PERIOD))?;
+    }
+    if let Some(cpus) = &threads.cpus {
+        let cpus: Vec<String> = cpus.iter().map(|cpu| cpu.to_string()).collect();
+        write_file(cgroup, "cpuset.cpus", &cpus.join(","))?;
+    }
+
+    Ok(())
+}
+
+fn read_threads(cgroup: &Path) -> io::Result<Vec<libc::pid_t>> {
+    Ok(fs::read_to_string(cgroup.join("cgroup.threads"))?
+        .lines()
+        .filter_map(|tid| tid.parse().ok())
+        .collect())
+}
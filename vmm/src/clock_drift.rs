@@ -0,0 +1,136 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Check of the drift of the guest clock from the host clock, for the
+//! operators to hear about the guests keeping bad time before their
+//! applications do.
+//!
+//! The kvmclock the guest keeps its time from is read through the
+//! hypervisor every period, along with the `CLOCK_BOOTTIME` of the host,
+//! and the drift is how much more, or less, the kvmclock went forward than
+//! the host clock since the VM was created. The drift is reported once
+//! when it gets past the threshold, either way, and again only once it got
+//! back under it first.
+
+use crate::config::ClockDriftConfig;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+pub struct ClockDriftMonitor {
+    // The drift last checked, in milliseconds, positive when the guest
+    // clock is ahead.
+    drift: Arc<AtomicI64>,
+    alarm_evt: EventFd,
+    threshold: u64,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ClockDriftMonitor {
+    pub fn new(vm: Arc<dyn hypervisor::Vm>, config: &ClockDriftConfig) -> io::Result<Self> {
+        let drift = Arc::new(AtomicI64::new(0));
+        let alarm_evt = EventFd::new(EFD_NONBLOCK)?;
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_drift = drift.clone();
+        let thread_alarm_evt = alarm_evt.try_clone()?;
+        let thread_stop = stop.clone();
+        let threshold = config.threshold as i64;
+        let period = Duration::from_secs(config.period);
+        // The clocks the drift is measured from.
+        let guest_start = vm.get_clock()?;
+        let host_start = host_clock()?;
+
+        let thread = thread::Builder::new()
+            .name("clock_drift".to_string())
+            .spawn(move || {
+                let mut alarmed = false;
+                loop {
+                    thread::park_timeout(period);
+                    if thread_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+
+                    let (guest, host) = match (vm.get_clock(), host_clock()) {
+                        (Ok(guest), Ok(host)) => (guest, host),
+                        (Err(e), _) | (_, Err(e)) => {
+                            warn!("Cannot read the clocks, stopping the drift check: {}", e);
+                            return;
+                        }
+                    };
+                    let elapsed = |now: u64, start: u64| now.wrapping_sub(start) as i64;
+                    let drift =
+                        (elapsed(guest, guest_start) - elapsed(host, host_start)) / 1_000_000;
+                    thread_drift.store(drift, Ordering::SeqCst);
+
+                    if drift.abs() > threshold {
+                        if !alarmed {
+                            alarmed = true;
+                            if let Err(e) = thread_alarm_evt.write(1) {
+                                error!("Cannot report the guest clock drift: {}", e);
+                            }
+                        }
+                    } else if alarmed {
+                        info!("The guest clock drift is back to {} ms", drift);
+                        alarmed = false;
+                    }
+                }
+            })?;
+
+        Ok(ClockDriftMonitor {
+            drift,
+            alarm_evt,
+            threshold: config.threshold,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Event written when the drift gets past the threshold.
+    pub fn alarm_evt(&self) -> &EventFd {
+        &self.alarm_evt
+    }
+
+    /// The drift last checked, in milliseconds, positive when the guest
+    /// clock is ahead of the host clock.
+    pub fn drift(&self) -> i64 {
+        self.drift.load(Ordering::SeqCst)
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold
+    }
+}
+
+impl Drop for ClockDriftMonitor {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            if thread.join().is_err() {
+                error!("The clock drift thread panicked");
+            }
+        }
+    }
+}
+
+// The host clock, counting the time the host was suspended as the kvmclock
+// does, in nanoseconds.
+fn host_clock() -> io::Result<u64> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because the kernel only writes the timespec structure.
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
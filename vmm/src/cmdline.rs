@@ -0,0 +1,229 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Placeholders of the kernel command line, resolved when the kernel is
+//! loaded from the configuration of the VM devices, so that an image taking
+//! its parameters from the command line can be booted as is.
+//!
+//! A placeholder is a variable name between braces, such as `{mac0}` or
+//! `{disk0_part1_uuid}`, and `{{` stands for a literal brace.
+
+use crate::config::{NetConfig, VmConfig};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::result;
+
+// GPT header, at LBA 1 of the disks with 512 or 4096 bytes sectors, and the
+// fields of it and of its partition entries the UUIDs are found from.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_SECTOR_SIZES: [u64; 2] = [512, 4096];
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_DISK_GUID_OFFSET: usize = 56;
+const GPT_ENTRIES_LBA_OFFSET: usize = 72;
+const GPT_ENTRIES_COUNT_OFFSET: usize = 80;
+const GPT_ENTRY_SIZE_OFFSET: usize = 84;
+const GPT_ENTRY_GUID_OFFSET: u64 = 16;
+const GUID_SIZE: usize = 16;
+// The first three fields of a GUID are little endian.
+const GUID_BYTE_ORDER: [usize; GUID_SIZE] = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+
+#[derive(Debug)]
+pub enum Error {
+    /// A placeholder isn't closed.
+    UnterminatedVariable(String),
+    /// Unknown variable, or one of a device the VM doesn't have.
+    UnknownVariable(String),
+    /// Cannot read a disk image, for its UUIDs.
+    DiskImage(PathBuf, io::Error),
+    /// A disk image has no GPT partition table, or not that partition.
+    NoDiskUuid(PathBuf),
+    /// Cannot find out the host name.
+    Hostname(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+// Guest disk GUID, or partition unique GUID, in the form Linux shows them.
+fn guid_string(guid: &[u8]) -> String {
+    let mut uuid = String::new();
+    for (i, &byte) in GUID_BYTE_ORDER.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            uuid.push('-');
+        }
+        uuid.push_str(&format!("{:02x}", guid[byte]));
+    }
+
+    uuid
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// UUID of a raw disk image with a GPT partition table, or of one of its
+// partitions, numbered from 1.
+fn disk_uuid(path: &Path, partition: Option<u32>) -> Result<String> {
+    let image_error = |e| Error::DiskImage(path.to_path_buf(), e);
+    let mut file = File::open(path).map_err(image_error)?;
+
+    let mut header = [0u8; GPT_HEADER_SIZE];
+    for &sector_size in GPT_SECTOR_SIZES.iter() {
+        match read_at(&mut file, sector_size, &mut header) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(image_error(e)),
+        }
+        if &header[..GPT_SIGNATURE.len()] != GPT_SIGNATURE {
+            continue;
+        }
+
+        let partition = match partition {
+            Some(partition) => partition,
+            None => {
+                return Ok(guid_string(
+                    &header[GPT_DISK_GUID_OFFSET..GPT_DISK_GUID_OFFSET + GUID_SIZE],
+                ))
+            }
+        };
+        if partition == 0 || partition > le_u32(&header, GPT_ENTRIES_COUNT_OFFSET) {
+            break;
+        }
+        let entry_offset = le_u64(&header, GPT_ENTRIES_LBA_OFFSET) * sector_size
+            + u64::from(partition - 1) * u64::from(le_u32(&header, GPT_ENTRY_SIZE_OFFSET));
+        let mut guid = [0u8; GUID_SIZE];
+        read_at(&mut file, entry_offset + GPT_ENTRY_GUID_OFFSET, &mut guid).map_err(image_error)?;
+        // Unused entries have a null GUID.
+        if guid.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        return Ok(guid_string(&guid));
+    }
+
+    Err(Error::NoDiskUuid(path.to_path_buf()))
+}
+
+fn host_name() -> Result<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .map_err(Error::Hostname)
+}
+
+// Splits an indexed variable such as "disk0_part1_uuid" into its device
+// kind, index and attribute.
+fn indexed_variable(name: &str) -> Option<(&str, usize, &str)> {
+    let (device, attribute) = match name.find('_') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    let index = device.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let kind = &device[..device.len() - index.len()];
+
+    Some((kind, index.parse().ok()?, attribute))
+}
+
+fn resolve(name: &str, config: &VmConfig) -> Result<String> {
+    let unknown = || Error::UnknownVariable(name.to_string());
+
+    match name {
+        "hostname" => return host_name(),
+        "vm_hostname" => return config.hostname.clone().ok_or_else(unknown),
+        "cpus" => return Ok(config.cpus.cpu_count.to_string()),
+        _ => {}
+    }
+
+    let (kind, index, attribute) = indexed_variable(name).ok_or_else(unknown)?;
+    let net = |index| -> Result<&NetConfig> {
+        config
+            .net
+            .as_ref()
+            .and_then(|net| net.get(index))
+            .ok_or_else(unknown)
+    };
+    match (kind, attribute) {
+        ("mac", "") => Ok(net(index)?.mac.to_string()),
+        ("ip", "") => Ok(net(index)?.ip.to_string()),
+        ("mask", "") => Ok(net(index)?.mask.to_string()),
+        ("tap", "") => net(index)?.tap.clone().ok_or_else(unknown),
+        ("resolver", "") => config
+            .resolvers
+            .as_ref()
+            .and_then(|resolvers| resolvers.get(index))
+            .map(|resolver| resolver.to_string())
+            .ok_or_else(unknown),
+        ("disk", attribute) => {
+            let disk = config
+                .disks
+                .as_ref()
+                .and_then(|disks| disks.get(index))
+                .ok_or_else(unknown)?;
+            if attribute == "path" {
+                return Ok(disk.path.to_string_lossy().into_owned());
+            }
+            if attribute == "uuid" {
+                return disk_uuid(&disk.path, None);
+            }
+            if !attribute.starts_with("part") || !attribute.ends_with("_uuid") {
+                return Err(unknown());
+            }
+            let partition = attribute["part".len()..attribute.len() - "_uuid".len()]
+                .parse::<u32>()
+                .map_err(|_| unknown())?;
+            disk_uuid(&disk.path, Some(partition))
+        }
+        _ => Err(unknown()),
+    }
+}
+
+/// Kernel parameters giving the guest its hostname, through systemd, and
+/// its DNS resolvers, through the initramfs and systemd network generators.
+pub fn identity_parameters(config: &VmConfig) -> Vec<String> {
+    let mut parameters = Vec::new();
+    if let Some(hostname) = &config.hostname {
+        parameters.push(format!("systemd.hostname={}", hostname));
+    }
+    for resolver in config.resolvers.iter().flatten() {
+        parameters.push(format!("nameserver={}", resolver));
+    }
+
+    parameters
+}
+
+/// Replaces the placeholders of the kernel command line with their values.
+pub fn expand(template: &str, config: &VmConfig) -> Result<String> {
+    let mut expanded = String::with_capacity(template.len());
+
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if rest.starts_with("{{") {
+            expanded.push('{');
+            rest = &rest[2..];
+            continue;
+        }
+
+        let end = rest
+            .find('}')
+            .ok_or_else(|| Error::UnterminatedVariable(rest.to_string()))?;
+        expanded.push_str(&resolve(&rest[1..end], config)?);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
@@ -6,23 +6,110 @@
 extern crate vm_virtio;
 
 use net_util::MacAddr;
-use std::convert::From;
+use std::collections::BTreeMap;
+use std::convert::{From, TryFrom};
 use std::net::AddrParseError;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
 use std::result;
 
 pub const DEFAULT_VCPUS: u8 = 1;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 30;
+/// Longest KVM polls halted vCPUs for a wakeup, by default on x86 hosts.
+pub const DEFAULT_HALT_POLL_MAX_NS: u32 = 200_000;
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+pub const MDEV_SYSFS_PATH: &str = "/sys/bus/mdev/devices";
+const SGX_EPC_PAGE_SIZE: u64 = 0x1000;
+pub const NUMA_LOCAL_DISTANCE: u8 = 10;
+pub const NUMA_DEFAULT_REMOTE_DISTANCE: u8 = 20;
+/// I/O port and IRQ of COM2, COM3 and COM4, the serial port being COM1.
+pub const LEGACY_UARTS: [(u16, u8); 3] = [(0x2f8, 3), (0x3e8, 4), (0x2e8, 3)];
+pub const MAX_PCI_SEGMENTS: u16 = 16;
+/// vsock port the guest requests the host hooks on, by default.
+pub const DEFAULT_HOOK_PORT: u32 = 1025;
+/// vsock port the guest agent reports the guest OS on, by default.
+pub const DEFAULT_GUEST_OS_PORT: u32 = 1026;
+/// vsock port the guest agent of a pool VM waits for its claim on, by
+/// default.
+pub const DEFAULT_POOL_PORT: u32 = 1027;
+/// Weight of a disk in its disk group, by default.
+pub const DEFAULT_DISK_WEIGHT: u32 = 100;
+/// Capacity of the block cache shared by the VMs of the process, by default.
+pub const DEFAULT_BLOCK_CACHE_SIZE: u64 = 256 << 20;
+/// Prefix length of the host-side IPv6 address of a tap, by default.
+pub const DEFAULT_NET_IP6_PREFIX_LEN: u8 = 64;
+/// Drift of the guest clock from the host clock reported, in milliseconds,
+/// by default.
+pub const DEFAULT_CLOCK_DRIFT_THRESHOLD: u64 = 100;
+/// Seconds between two checks of the guest clock drift, by default.
+pub const DEFAULT_CLOCK_DRIFT_PERIOD: u64 = 10;
+/// Highest cpu.weight and io.weight of a cgroup.
+pub const MAX_CGROUP_WEIGHT: u16 = 10_000;
+/// Size of the display of the virtio-gpu device, by default.
+pub const DEFAULT_GPU_WIDTH: u32 = 1024;
+pub const DEFAULT_GPU_HEIGHT: u32 = 768;
+/// Largest width and height of the display of the virtio-gpu device.
+pub const MAX_GPU_RESOLUTION: u32 = 8192;
 
 /// Errors associated with VM configuration parameters.
 #[derive(Debug)]
 pub enum Error<'a> {
     /// Failed parsing cpus parameters.
     ParseCpusParams(std::num::ParseIntError),
+    /// Failed parsing cpu topology parameter.
+    ParseCpuTopologyParam(&'a str),
+    /// The cpu topology does not match the number of vCPUs.
+    ValidateCpuTopologyCount,
+    /// Failed parsing cpu affinity parameter.
+    ParseCpuAffinityParam(&'a str),
+    /// The cpu affinity refers to a vCPU that does not exist.
+    ValidateCpuAffinityVcpu(u8),
+    /// The cpu affinity refers to an invalid host CPU.
+    ValidateCpuAffinityHostCpu(usize),
+    /// Failed parsing cpu disabled features parameter.
+    ParseCpuFeatureParam(&'a str),
+    /// Failed parsing cpu features parameter.
+    ParseCpuEnabledFeatureParam(&'a str),
+    /// Failed parsing cpu KVM Hyper-V enlightenments parameter.
+    ParseCpuKvmHypervParam,
+    /// Failed parsing cpu frequency parameter.
+    ParseCpuFrequencyParam(std::num::ParseIntError),
+    /// The cpu frequency is zero.
+    ValidateCpuFrequency,
     /// Failed parsing memory file parameter.
     ParseMemoryFileParam,
+    /// Failed parsing memory zone file parameter.
+    ParseMemoryZoneFileParam,
+    /// Failed parsing memory zone shared parameter.
+    ParseMemoryZoneSharedParam,
+    /// Failed parsing memory zone hugepages parameter.
+    ParseMemoryZoneHugepagesParam,
+    /// A memory zone is empty.
+    ValidateMemoryZoneSize,
+    /// A file backed memory zone can only be shared.
+    ValidateMemoryZonePrivateFile,
+    /// Failed parsing memory hugepages parameter.
+    ParseMemoryHugepagesParam,
+    /// Failed parsing memory prefault parameter.
+    ParseMemoryPrefaultParam,
+    /// Failed parsing memory mergeable parameter.
+    ParseMemoryMergeableParam,
+    /// Failed parsing memory thp parameter.
+    ParseMemoryThpParam,
+    /// Only the private anonymous memory of regular pages can be merged.
+    ValidateMemoryMergeable,
+    /// Transparent huge pages can't be disabled for the zones falling back
+    /// to them.
+    ValidateMemoryThp,
+    /// The huge page size is not a power of two, or does not divide the
+    /// memory size.
+    ValidateHugepageSize(u64),
+    /// Failed parsing memory hugepages_fallback parameter.
+    ParseHugepagesFallbackParam,
+    /// A huge pages fallback is only for anonymous huge pages memory.
+    ValidateHugepagesFallback,
     /// Failed parsing kernel parameters.
     ParseKernelParams,
     /// Failed parsing kernel command line parameters.
@@ -35,8 +122,45 @@ pub enum Error<'a> {
     ParseNetIpParam(AddrParseError),
     /// Failed parsing network mask parameter.
     ParseNetMaskParam(AddrParseError),
+    /// Failed parsing network IPv6 address parameter.
+    ParseNetIp6Param(AddrParseError),
+    /// Failed parsing network IPv6 prefix length parameter, not up to 128.
+    ParseNetIp6PrefixLenParam,
     /// Failed parsing network mac parameter.
     ParseNetMacParam(&'a str),
+    /// Failed parsing network model parameter.
+    ParseNetModelParam,
+    /// Failed parsing network tap file descriptors parameter, not a
+    /// "[<fd>,...]" list.
+    ParseNetFdsParam(&'a str),
+    /// Failed parsing network offload parameters.
+    ParseNetOffloadParam,
+    /// The tap file descriptors of an interface are given along with its
+    /// tap or passed file descriptor, or several of them to an e1000
+    /// interface.
+    ValidateNetFds,
+    /// The segmentation or fragmentation offload of an interface is enabled
+    /// without its checksum offload.
+    ValidateNetOffload,
+    /// The macvtap interface of an interface is given along with its tap,
+    /// passed or tap file descriptors.
+    ValidateNetMacvtap,
+    /// Failed parsing disk model parameter.
+    ParseDiskModelParam,
+    /// Failed parsing disk weight parameter, not a positive integer.
+    ParseDiskWeightParam,
+    /// Failed parsing disk readonly parameter.
+    ParseDiskReadonlyParam,
+    /// Failed parsing disk removable parameter.
+    ParseDiskRemovableParam,
+    /// Failed parsing disk cache parameter.
+    ParseDiskCacheParam,
+    /// Failed parsing disk direct parameter.
+    ParseDiskDirectParam,
+    /// Disk group is missing its id.
+    ParseDiskGroupIdParam,
+    /// Failed parsing disk group bandwidth parameter, missing or zero.
+    ParseDiskGroupBandwidthParam,
     /// Failed parsing fs tag parameter.
     ParseFsTagParam,
     /// Failed parsing fs socket path parameter.
@@ -51,6 +175,14 @@ pub enum Error<'a> {
     InvalidCacheSizeWithDaxOff,
     /// Failed parsing persitent memory file parameter.
     ParsePmemFileParam,
+    /// Failed parsing NVDIMM file parameter.
+    ParseNvdimmFileParam,
+    /// The NVDIMM size, or the size of its label area, is not a multiple of
+    /// 4 KiB.
+    ValidateNvdimmSize(u64),
+    /// The NVDIMM label area is smaller than 128 KiB, or takes the whole
+    /// NVDIMM.
+    ValidateNvdimmLabelSize(u64),
     /// Failed parsing size parameter.
     ParseSizeParam(std::num::ParseIntError),
     /// Failed parsing console parameter.
@@ -77,25 +209,306 @@ pub enum Error<'a> {
     ValidateMissingKernelConfig,
     /// Failed parsing iommu parameter for the device.
     ParseDeviceIommu,
+    /// Failed parsing queue size parameter for the device.
+    ParseDeviceQueueSize(std::num::ParseIntError),
+    /// The device queue size is not a power of two within the virtio limit.
+    ValidateDeviceQueueSize(u16),
+    /// A device is given both a path and a mediated device UUID.
+    ParseDevicePathAndMdev,
+    /// The mediated device UUID is not a valid UUID.
+    ParseDeviceMdevParam(&'a str),
+    /// Failed parsing the MAC address parameter of the device.
+    ParseDeviceMacParam(&'a str),
+    /// Failed parsing profile parameter.
+    ParseProfileParam,
+    /// The unikernel profile only supports a single vCPU.
+    ValidateUnikernelCpus,
+    /// The unikernel profile requires virtio-mmio support.
+    ValidateUnikernelMmio,
+    /// The windows profile requires x86_64, with the ACPI and CMOS support.
+    ValidateWindowsDevices,
+    /// Failed parsing rate limiter parameters.
+    ParseRateLimiterParams(std::num::ParseIntError),
+    /// Rate limiter token bucket is missing its size or refill time.
+    InvalidRateLimiterParams,
+    /// Failed parsing NUMA node id parameter.
+    ParseNumaIdParam(std::num::ParseIntError),
+    /// Failed parsing NUMA node cpus parameter.
+    ParseNumaCpusParam(&'a str),
+    /// Failed parsing NUMA node distances parameter.
+    ParseNumaDistancesParam(&'a str),
+    /// Failed parsing NUMA node host_node parameter.
+    ParseNumaHostNodeParam(std::num::ParseIntError),
+    /// The NUMA node ids are not numbered from 0 without any gap.
+    ValidateNumaIds,
+    /// The NUMA nodes memory does not add up to the guest memory size.
+    ValidateNumaMemorySize,
+    /// A vCPU does not exist, or belongs to several NUMA nodes.
+    ValidateNumaCpu(u8),
+    /// A NUMA distance refers to an unknown node, or is not a remote distance.
+    ValidateNumaDistance(u32),
+    /// Failed parsing sensors battery parameter.
+    ParseSensorsBatteryParam,
+    /// Failed parsing sensors thermal_zone parameter.
+    ParseSensorsThermalZoneParam,
+    /// Failed parsing idle timeout parameter.
+    ParseIdleTimeoutParam(std::num::ParseIntError),
+    /// Failed parsing idle park parameter.
+    ParseIdleParkParam,
+    /// Failed parsing idle hibernate parameter.
+    ParseIdleHibernateParam(std::num::ParseIntError),
+    /// Failed parsing halt polling max_ns parameter.
+    ParseHaltPollMaxParam(std::num::ParseIntError),
+    /// Failed parsing halt polling adaptive parameter.
+    ParseHaltPollAdaptiveParam,
+    /// Failed parsing SGX EPC prefault parameter.
+    ParseSgxEpcPrefaultParam,
+    /// An SGX EPC section is empty, or not a whole number of pages.
+    ValidateSgxEpcSize(u64),
+    /// Failed parsing ACPI table path parameter.
+    ParseAcpiTablePathParam,
+    /// The ACPI OEM table signature is not 4 alphanumeric characters.
+    ParseAcpiOemTableSignatureParam(&'a str),
+    /// Failed parsing ACPI OEM table entry, not a key=value pair.
+    ParseAcpiOemTableEntryParam(&'a str),
+    /// Failed parsing platform parameter.
+    ParsePlatformParam,
+    /// A feature, or device, confidential guests don't support.
+    ValidateConfidentialFeature(&'static str),
+    /// The hostname is not made of labels of letters, digits and dashes.
+    ParseHostnameParam(&'a str),
+    /// Failed parsing a resolver IP address.
+    ParseResolverParam(&'a str, AddrParseError),
+    /// Failed parsing TPM socket path parameter.
+    ParseTpmSocketParam,
+    /// Failed parsing vfio-user device socket path parameter.
+    ParseUserDeviceSocketParam,
+    /// Failed parsing plugin name parameter, missing or not made of letters,
+    /// digits, dashes and underscores.
+    ParsePluginNameParam,
+    /// Failed parsing plugin path parameter.
+    ParsePluginPathParam,
+    /// Failed parsing plugin device plugin parameter.
+    ParsePluginDevicePluginParam,
+    /// Several plugins are given the same name.
+    ValidateDuplicatePlugin(String),
+    /// A plugin device is of a type no plugin is given for.
+    ValidatePluginDevice(String),
+    /// Failed parsing console port name parameter, missing or not made of
+    /// letters, digits, dots, dashes and underscores.
+    ParseConsolePortNameParam,
+    /// Failed parsing console port socket parameter.
+    ParseConsolePortSocketParam,
+    /// Several console ports are given the same name.
+    ValidateDuplicateConsolePort(String),
+    /// Console ports are given while the virtio-console is off.
+    ValidateConsolePorts,
+    /// Failed parsing PCI multifunction parameter.
+    ParsePciMultifunctionParam,
+    /// Failed parsing PCI segments count parameter.
+    ParsePciNumSegmentsParam(std::num::ParseIntError),
+    /// The PCI segments count is not between 1 and MAX_PCI_SEGMENTS.
+    ValidatePciNumSegments(u16),
+    /// Failed parsing device PCI segment parameter.
+    ParsePciSegmentParam(std::num::ParseIntError),
+    /// A device is on a PCI segment the VM doesn't have.
+    ValidatePciSegment(u16),
+    /// A device attached to the virtual IOMMU is not on the PCI segment 0.
+    ValidatePciSegmentIommu(u16),
+    /// Failed parsing PCI segment aperture parameters.
+    ParsePciSegmentApertureParam(&'a str),
+    /// The apertures of a PCI segment the VM doesn't have, of the segment 0,
+    /// or of a segment already given apertures.
+    ValidatePciSegmentAperture(u16),
+    /// Failed parsing NUMA PCI segments parameter.
+    ParseNumaPciSegmentsParam(&'a str),
+    /// A NUMA PCI segment the VM doesn't have, or which is already given to
+    /// another node.
+    ValidateNumaPciSegment(u16),
+    /// Failed parsing UART parameter, or a backend UARTs don't support.
+    ParseUartParam,
+    /// Failed parsing UART port parameter.
+    ParseUartPortParam(std::num::ParseIntError),
+    /// The UART IRQ is not one of the legacy COM port IRQs, 3 and 4.
+    ParseUartIrqParam(&'a str),
+    /// More UARTs than COM2, COM3 and COM4.
+    ValidateUartCount(usize),
+    /// Both SELinux and AppArmor labels are given.
+    ValidateSecurityModules,
+    /// Failed parsing GDB socket path parameter.
+    ParseGdbPathParam,
+    /// The diagnostics have neither a bundles directory nor the device
+    /// audit.
+    ParseDiagnosticsPathParam,
+    /// Failed parsing device audit parameter.
+    ParseDiagnosticsAuditParam,
+    /// Failed parsing guest OS agent vsock port parameter.
+    ParseGuestOsPortParam(std::num::ParseIntError),
+    /// Failed parsing Landlock rule path parameter.
+    ParseLandlockPathParam,
+    /// Failed parsing Landlock rule access parameter.
+    ParseLandlockAccessParam,
+    /// Failed parsing tracing format parameter.
+    ParseTracingFormatParam,
+    /// Failed parsing user ID parameter, missing or not a number.
+    ParseUserUidParam,
+    /// Failed parsing group ID parameter, missing or not a number.
+    ParseUserGidParam,
+    /// Failed parsing supplementary groups parameter.
+    ParseUserGroupsParam(std::num::ParseIntError),
+    /// Failed parsing clock drift threshold parameter.
+    ParseClockDriftThresholdParam(std::num::ParseIntError),
+    /// Failed parsing clock drift period parameter, not a positive number.
+    ParseClockDriftPeriodParam,
+    /// The guest OS agent reports on the vsock port of host hooks.
+    ValidateGuestOsPort(u32),
+    /// Failed parsing VM pool size parameter.
+    ParsePoolSizeParam(std::num::ParseIntError),
+    /// Failed parsing VM pool vsock port parameter.
+    ParsePoolPortParam(std::num::ParseIntError),
+    /// The VM pool is empty.
+    ValidatePoolSize,
+    /// The guest agent of the pool VMs waits for its claim on the vsock port
+    /// of host hooks, or of the guest OS agent.
+    ValidatePoolPort(u32),
+    /// Failed parsing host hook name parameter, missing or not made of
+    /// letters, digits, dashes, underscores and dots.
+    ParseHookNameParam,
+    /// Failed parsing host hook command parameter.
+    ParseHookCommandParam,
+    /// Failed parsing host hook vsock port parameter.
+    ParseHookPortParam(std::num::ParseIntError),
+    /// Host hooks are given without a vsock device for the guest to request
+    /// them through.
+    ValidateHooksVsock,
+    /// Several host hooks have the same name on the same vsock port.
+    ValidateHookName(String),
+    /// Failed parsing pvpanic action parameter.
+    ParsePvpanicActionParam,
+    /// Failed parsing latency profile parameter.
+    ParseLatencyProfileParam,
+    /// A feature the realtime latency profile conflicts with.
+    ValidateRealtimeFeature(&'static str),
+    /// vhost-user devices are given without the guest memory being shared
+    /// with their backend, backed by a file, shared, or by huge pages
+    /// without fallback.
+    ValidateVhostUserSharedMemory,
+    /// The queues of a vhost-user-net device don't make RX/TX pairs.
+    ValidateVhostUserNetQueues(usize),
+    /// A device has no queue, or more queues than vCPUs to service them.
+    ValidateDeviceQueueCount(usize),
+    /// Several devices are given the same disk image, VFIO device, socket,
+    /// MAC address, virtio-fs tag, tap file descriptor, macvtap interface or
+    /// ID.
+    ValidateDuplicateDevice(&'static str, String),
+    /// A device ID is empty, too long, or not made of letters, digits,
+    /// dashes and underscores.
+    ValidateDeviceId(String),
+    /// A disk refers to a disk group which doesn't exist, or isn't a
+    /// virtio-blk disk.
+    ValidateDiskGroup(String),
+    /// A disk is cached without being read-only, or isn't a virtio-blk
+    /// disk.
+    ValidateDiskCache(String),
+    /// A disk bypassing the page cache of the host is read through the
+    /// block cache.
+    ValidateDiskDirectCache(String),
+    /// A disk ignoring the flushes of the guest isn't a virtio-blk disk.
+    ValidateDiskCacheMode(String),
+    /// A removable disk isn't a virtio-blk disk, is in a disk group, or is
+    /// a function of a multifunction device.
+    ValidateDiskRemovable(String),
+    /// Several disk groups are given the same id.
+    ValidateDuplicateDiskGroup(String),
+    /// Failed parsing cgroup path parameter.
+    ParseCgroupPathParam,
+    /// Failed parsing cgroup weight parameter.
+    ParseCgroupWeightParam(std::num::ParseIntError),
+    /// Failed parsing cgroup quota parameter.
+    ParseCgroupQuotaParam(std::num::ParseIntError),
+    /// Failed parsing cgroup host CPUs parameter.
+    ParseCgroupCpusParam(&'a str),
+    /// The cgroup path is not absolute.
+    ValidateCgroupPath(String),
+    /// A cgroup weight is not between 1 and 10000.
+    ValidateCgroupWeight(u16),
+    /// A cgroup quota is zero.
+    ValidateCgroupQuota,
+    /// A cgroup is given an invalid host CPU.
+    ValidateCgroupHostCpu(usize),
+    /// Failed parsing gpu width parameter.
+    ParseGpuWidthParam(std::num::ParseIntError),
+    /// Failed parsing gpu height parameter.
+    ParseGpuHeightParam(std::num::ParseIntError),
+    /// The gpu display is empty, or larger than 8192 pixels in a dimension.
+    ValidateGpuResolution(u32, u32),
+    /// Failed parsing input socket parameter.
+    ParseInputSocketParam,
+    /// The configuration doesn't meet these constraints between its fields.
+    Validation(Vec<Error<'static>>),
+    /// Failed reading the VM configuration file.
+    ReadConfigFile(std::io::Error),
+    /// Failed parsing the VM configuration file as JSON.
+    ParseJsonConfigFile(serde_json::Error),
+    /// Failed parsing the VM configuration file as TOML.
+    ParseTomlConfigFile(toml::de::Error),
 }
 pub type Result<'a, T> = result::Result<T, Error<'a>>;
 
 pub struct VmParams<'a> {
     pub cpus: &'a str,
     pub memory: &'a str,
+    pub memory_zones: Option<Vec<&'a str>>,
     pub kernel: Option<&'a str>,
+    pub initramfs: Option<&'a str>,
     pub cmdline: Option<&'a str>,
     pub disks: Option<Vec<&'a str>>,
+    pub disk_groups: Option<Vec<&'a str>>,
     pub net: Option<Vec<&'a str>>,
     pub rng: &'a str,
     pub fs: Option<Vec<&'a str>>,
     pub pmem: Option<Vec<&'a str>>,
+    pub nvdimms: Option<Vec<&'a str>>,
     pub serial: &'a str,
     pub console: &'a str,
     pub devices: Option<Vec<&'a str>>,
+    pub user_devices: Option<Vec<&'a str>>,
+    pub plugins: Option<Vec<&'a str>>,
+    pub plugin_devices: Option<Vec<&'a str>>,
+    pub uarts: Option<Vec<&'a str>>,
+    pub console_ports: Option<Vec<&'a str>>,
     pub vhost_user_net: Option<Vec<&'a str>>,
     pub vhost_user_blk: Option<Vec<&'a str>>,
     pub vsock: Option<Vec<&'a str>>,
+    pub numa: Option<Vec<&'a str>>,
+    pub profile: &'a str,
+    pub confidential_guest: bool,
+    pub platform: &'a str,
+    pub latency_profile: &'a str,
+    pub sensors: Option<&'a str>,
+    pub idle: Option<&'a str>,
+    pub halt_poll: Option<&'a str>,
+    pub acpi_tables: Option<Vec<&'a str>>,
+    pub acpi_oem_tables: Option<Vec<&'a str>>,
+    pub sgx_epc: Option<Vec<&'a str>>,
+    pub hostname: Option<&'a str>,
+    pub resolvers: Option<&'a str>,
+    pub tpm: Option<&'a str>,
+    pub pci: Option<&'a str>,
+    pub pci_segments: Option<Vec<&'a str>>,
+    pub security: Option<&'a str>,
+    pub gdb: Option<&'a str>,
+    pub pvpanic: Option<&'a str>,
+    pub hooks: Option<Vec<&'a str>>,
+    pub diagnostics: Option<&'a str>,
+    pub guest_os: Option<&'a str>,
+    pub landlock: Option<Vec<&'a str>>,
+    pub user: Option<&'a str>,
+    pub clock_drift: Option<&'a str>,
+    pub ptp: bool,
+    pub cgroup: Option<&'a str>,
+    pub gpu: Option<&'a str>,
+    pub input: Option<&'a str>,
 }
 
 fn parse_size(size: &str) -> Result<u64> {
@@ -116,6 +529,56 @@ fn parse_size(size: &str) -> Result<u64> {
     Ok(res << shift)
 }
 
+// The huge page size is only meaningful along with huge pages, and
+// defaults to the host default huge page size.
+fn parse_hugepage_size(
+    hugepage_size: &str,
+    hugepages: bool,
+    memory_size: u64,
+) -> Result<Option<u64>> {
+    if hugepage_size.is_empty() {
+        return Ok(None);
+    }
+
+    let hugepage_size = parse_size(hugepage_size)?;
+    if !hugepages || !hugepage_size.is_power_of_two() || memory_size % hugepage_size != 0 {
+        return Err(Error::ValidateHugepageSize(hugepage_size));
+    }
+
+    Ok(Some(hugepage_size))
+}
+
+fn parse_hugepages_fallback(
+    hugepages_fallback: &str,
+    hugepages: bool,
+    backed: bool,
+) -> Result<HugepagesFallback> {
+    let hugepages_fallback = HugepagesFallback::parse(hugepages_fallback)?;
+    if hugepages_fallback != HugepagesFallback::None && (!hugepages || backed) {
+        return Err(Error::ValidateHugepagesFallback);
+    }
+
+    Ok(hugepages_fallback)
+}
+
+fn parse_device_id(id: &str) -> Option<String> {
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_string())
+    }
+}
+
+/// Whether the ID is a valid device ID: up to 64 letters, digits, dashes
+/// and underscores.
+pub fn valid_device_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 fn parse_iommu(iommu: &str) -> Result<bool> {
     if !iommu.is_empty() {
         let res = match iommu {
@@ -130,518 +593,3746 @@ fn parse_iommu(iommu: &str) -> Result<bool> {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct CpusConfig {
-    pub cpu_count: u8,
-}
+fn parse_queue_size(queue_size: &str) -> Result<u16> {
+    if queue_size.is_empty() {
+        return Ok(DEFAULT_QUEUE_SIZE);
+    }
 
-impl CpusConfig {
-    pub fn parse(cpus: &str) -> Result<Self> {
-        Ok(CpusConfig {
-            cpu_count: cpus.parse().map_err(Error::ParseCpusParams)?,
-        })
+    let queue_size: u16 = queue_size.parse().map_err(Error::ParseDeviceQueueSize)?;
+    if !queue_size.is_power_of_two() || queue_size > vm_virtio::MAX_QUEUE_SIZE {
+        return Err(Error::ValidateDeviceQueueSize(queue_size));
     }
+
+    Ok(queue_size)
 }
 
-impl Default for CpusConfig {
-    fn default() -> Self {
-        CpusConfig {
-            cpu_count: DEFAULT_VCPUS,
+fn default_queue_size() -> u16 {
+    DEFAULT_QUEUE_SIZE
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Profile {
+    Default,
+    /// Fast boot for unikernels: single vCPU, ELF kernel only, no ACPI
+    /// tables and virtio devices exposed through virtio-mmio.
+    Unikernel,
+    /// Windows guests: the Hyper-V enlightenments, and the WAET table
+    /// telling which emulated devices the guest can trust.
+    Windows,
+}
+
+impl Profile {
+    pub fn parse(profile: &str) -> Result<Self> {
+        match profile {
+            "" | "default" => Ok(Profile::Default),
+            "unikernel" => Ok(Profile::Unikernel),
+            "windows" => Ok(Profile::Windows),
+            _ => Err(Error::ParseProfileParam),
         }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct MemoryConfig {
-    pub size: u64,
-    pub file: Option<PathBuf>,
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::Default
+    }
 }
 
-impl MemoryConfig {
-    pub fn parse(memory: &str) -> Result<Self> {
-        // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = memory.split(',').collect();
-
-        let mut size_str: &str = "";
-        let mut file_str: &str = "";
-        let mut backed = false;
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum Platform {
+    Default,
+    /// Intel TDX guest, booted from a TDVF firmware.
+    Tdx,
+    /// AMD SEV-SNP guest, booted from an OVMF firmware.
+    SevSnp,
+}
 
-        for param in params_list.iter() {
-            if param.starts_with("size=") {
-                size_str = &param[5..];
-            } else if param.starts_with("file=") {
-                backed = true;
-                file_str = &param[5..];
-            }
+impl Platform {
+    pub fn parse(platform: &str) -> Result<Self> {
+        match platform {
+            "" | "default" => Ok(Platform::Default),
+            "tdx" => Ok(Platform::Tdx),
+            "sev-snp" => Ok(Platform::SevSnp),
+            _ => Err(Error::ParsePlatformParam),
         }
+    }
 
-        let file = if backed {
-            if file_str.is_empty() {
-                return Err(Error::ParseMemoryFileParam);
-            }
-
-            Some(PathBuf::from(file_str))
-        } else {
-            None
-        };
-
-        Ok(MemoryConfig {
-            size: parse_size(size_str)?,
-            file,
-        })
+    /// Whether the memory and the vCPUs state of the guest are encrypted,
+    /// out of reach of the host.
+    pub fn is_confidential(self) -> bool {
+        self != Platform::Default
     }
 }
 
-impl Default for MemoryConfig {
+impl Default for Platform {
     fn default() -> Self {
-        MemoryConfig {
-            size: DEFAULT_MEMORY_MB << 20,
-            file: None,
-        }
+        Platform::Default
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct KernelConfig {
-    pub path: PathBuf,
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum LatencyProfile {
+    Default,
+    /// Each vCPU runs on an isolated host CPU of its own, with a realtime
+    /// priority, HLT and PAUSE not exiting, and the VMM memory locked.
+    Realtime,
 }
 
-#[derive(Clone, Deserialize, Serialize)]
-pub struct CmdlineConfig {
-    pub args: String,
+impl LatencyProfile {
+    pub fn parse(latency_profile: &str) -> Result<Self> {
+        match latency_profile {
+            "" | "default" => Ok(LatencyProfile::Default),
+            "realtime" => Ok(LatencyProfile::Realtime),
+            _ => Err(Error::ParseLatencyProfileParam),
+        }
+    }
 }
 
-impl CmdlineConfig {
-    pub fn parse(cmdline: Option<&str>) -> Result<Self> {
-        let args = cmdline
-            .map(std::string::ToString::to_string)
-            .unwrap_or_else(String::new);
-
-        Ok(CmdlineConfig { args })
+impl Default for LatencyProfile {
+    fn default() -> Self {
+        LatencyProfile::Default
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct DiskConfig {
-    pub path: PathBuf,
-    #[serde(default)]
-    pub iommu: bool,
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CpuTopology {
+    pub threads_per_core: u8,
+    pub cores_per_die: u8,
+    pub dies_per_package: u8,
+    pub packages: u8,
 }
 
-impl DiskConfig {
-    pub fn parse(disk: &str) -> Result<Self> {
-        // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = disk.split(',').collect();
+impl CpuTopology {
+    pub fn parse<'a>(params_list: &[&'a str]) -> Result<'a, Self> {
+        let mut topology = CpuTopology::default();
 
-        let mut path_str: &str = "";
-        let mut iommu_str: &str = "";
+        for &param in params_list.iter() {
+            let (field, value) = if param.starts_with("threads:") {
+                (&mut topology.threads_per_core, &param[8..])
+            } else if param.starts_with("cores_per_die:") {
+                (&mut topology.cores_per_die, &param[14..])
+            } else if param.starts_with("dies:") {
+                (&mut topology.dies_per_package, &param[5..])
+            } else if param.starts_with("sockets:") {
+                (&mut topology.packages, &param[8..])
+            } else {
+                return Err(Error::ParseCpuTopologyParam(param));
+            };
 
-        for param in params_list.iter() {
-            if param.starts_with("path=") {
-                path_str = &param[5..];
-            } else if param.starts_with("iommu=") {
-                iommu_str = &param[6..];
+            *field = value
+                .parse()
+                .map_err(|_| Error::ParseCpuTopologyParam(param))?;
+            if *field == 0 {
+                return Err(Error::ParseCpuTopologyParam(param));
             }
         }
 
-        Ok(DiskConfig {
-            path: PathBuf::from(path_str),
-            iommu: parse_iommu(iommu_str)?,
-        })
+        Ok(topology)
+    }
+
+    pub fn cpu_count(&self) -> u32 {
+        u32::from(self.threads_per_core)
+            * u32::from(self.cores_per_die)
+            * u32::from(self.dies_per_package)
+            * u32::from(self.packages)
+    }
+}
+
+impl Default for CpuTopology {
+    fn default() -> Self {
+        CpuTopology {
+            threads_per_core: 1,
+            cores_per_die: 1,
+            dies_per_package: 1,
+            packages: 1,
+        }
     }
 }
 
+/// Host CPUs a vCPU thread gets pinned to.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct NetConfig {
-    pub tap: Option<String>,
-    pub ip: Ipv4Addr,
-    pub mask: Ipv4Addr,
-    pub mac: MacAddr,
-    #[serde(default)]
-    pub iommu: bool,
+pub struct CpuAffinity {
+    pub vcpu: u8,
+    pub host_cpus: Vec<usize>,
 }
 
-impl NetConfig {
-    pub fn parse(net: &str) -> Result<Self> {
-        // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = net.split(',').collect();
+impl CpuAffinity {
+    // Parse a "[<vcpu>@[<host_cpu>,...],...]" list.
+    pub fn parse_list(affinity: &str) -> Result<Vec<Self>> {
+        if !affinity.starts_with('[') || !affinity.ends_with(']') {
+            return Err(Error::ParseCpuAffinityParam(affinity));
+        }
 
-        let mut tap_str: &str = "";
-        let mut ip_str: &str = "";
-        let mut mask_str: &str = "";
-        let mut mac_str: &str = "";
-        let mut iommu_str: &str = "";
+        let mut affinity_list = Vec::new();
+        let entries = &affinity[1..affinity.len() - 1];
+        for entry in entries.split("],") {
+            let entry = entry.trim_end_matches(']');
+            let mut fields = entry.splitn(2, '@');
+            let vcpu = fields
+                .next()
+                .and_then(|vcpu| vcpu.parse().ok())
+                .ok_or(Error::ParseCpuAffinityParam(entry))?;
+            let host_cpus = match fields.next() {
+                Some(host_cpus) if host_cpus.starts_with('[') => &host_cpus[1..],
+                _ => return Err(Error::ParseCpuAffinityParam(entry)),
+            };
+            let host_cpus = host_cpus
+                .split(',')
+                .map(|host_cpu| host_cpu.parse())
+                .collect::<result::Result<Vec<usize>, _>>()
+                .map_err(|_| Error::ParseCpuAffinityParam(entry))?;
 
-        for param in params_list.iter() {
-            if param.starts_with("tap=") {
-                tap_str = &param[4..];
-            } else if param.starts_with("ip=") {
-                ip_str = &param[3..];
-            } else if param.starts_with("mask=") {
-                mask_str = &param[5..];
-            } else if param.starts_with("mac=") {
-                mac_str = &param[4..];
-            } else if param.starts_with("iommu=") {
-                iommu_str = &param[6..];
-            }
+            affinity_list.push(CpuAffinity { vcpu, host_cpus });
         }
 
-        let mut tap: Option<String> = None;
-        let mut ip: Ipv4Addr = Ipv4Addr::new(192, 168, 249, 1);
-        let mut mask: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
-        let mut mac: MacAddr = MacAddr::local_random();
-        let iommu = parse_iommu(iommu_str)?;
+        Ok(affinity_list)
+    }
+}
 
-        if !tap_str.is_empty() {
-            tap = Some(tap_str.to_string());
-        }
-        if !ip_str.is_empty() {
-            ip = ip_str.parse().map_err(Error::ParseNetIpParam)?;
-        }
-        if !mask_str.is_empty() {
-            mask = mask_str.parse().map_err(Error::ParseNetMaskParam)?;
+/// CPU feature that can be hidden from the guest, for instance so that it
+/// can migrate between hosts with different CPU generations.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum CpuFeature {
+    Aes,
+    Avx,
+    Avx2,
+    /// All the AVX-512 instruction sets, along with their XSAVE state.
+    Avx512,
+    Fma,
+    /// Memory Protection Extensions, along with their XSAVE state.
+    Mpx,
+    /// Protection keys, along with their XSAVE state.
+    Pku,
+    Rdrand,
+    Rdseed,
+    Sha,
+    /// Hardware lock elision and restricted transactional memory.
+    Tsx,
+}
+
+impl CpuFeature {
+    pub fn parse(feature: &str) -> Result<Self> {
+        match feature {
+            "aes" => Ok(CpuFeature::Aes),
+            "avx" => Ok(CpuFeature::Avx),
+            "avx2" => Ok(CpuFeature::Avx2),
+            "avx512" => Ok(CpuFeature::Avx512),
+            "fma" => Ok(CpuFeature::Fma),
+            "mpx" => Ok(CpuFeature::Mpx),
+            "pku" => Ok(CpuFeature::Pku),
+            "rdrand" => Ok(CpuFeature::Rdrand),
+            "rdseed" => Ok(CpuFeature::Rdseed),
+            "sha" => Ok(CpuFeature::Sha),
+            "tsx" => Ok(CpuFeature::Tsx),
+            _ => Err(Error::ParseCpuFeatureParam(feature)),
         }
-        if !mac_str.is_empty() {
-            mac = MacAddr::parse_str(mac_str).map_err(Error::ParseNetMacParam)?;
+    }
+
+    // Parse a "[<feature>,...]" list.
+    pub fn parse_list(features: &str) -> Result<Vec<Self>> {
+        if !features.starts_with('[') || !features.ends_with(']') {
+            return Err(Error::ParseCpuFeatureParam(features));
         }
 
-        Ok(NetConfig {
-            tap,
-            ip,
-            mask,
-            mac,
-            iommu,
-        })
+        features[1..features.len() - 1]
+            .split(',')
+            .map(CpuFeature::parse)
+            .collect()
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct RngConfig {
-    pub src: PathBuf,
-    #[serde(default)]
-    pub iommu: bool,
+/// Features of the host CPU hidden from the guest CPUID unless they are
+/// enabled: the virtualization extensions, for the guest to run its own
+/// hypervisor, and the state components the VMM process needs a permission
+/// to use.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum EnabledCpuFeature {
+    /// Intel VMX.
+    Vmx,
+    /// AMD SVM.
+    Svm,
+    /// Intel Advanced Matrix Extensions, along with their XSAVE state.
+    Amx,
 }
 
-impl RngConfig {
-    pub fn parse(rng: &str) -> Result<Self> {
-        // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = rng.split(',').collect();
+impl EnabledCpuFeature {
+    pub fn parse(feature: &str) -> Result<Self> {
+        match feature {
+            "vmx" => Ok(EnabledCpuFeature::Vmx),
+            "svm" => Ok(EnabledCpuFeature::Svm),
+            "amx" => Ok(EnabledCpuFeature::Amx),
+            _ => Err(Error::ParseCpuEnabledFeatureParam(feature)),
+        }
+    }
 
-        let mut src_str: &str = "";
-        let mut iommu_str: &str = "";
+    /// What the feature gives the guest, for the error messages.
+    pub fn description(self) -> &'static str {
+        match self {
+            EnabledCpuFeature::Vmx | EnabledCpuFeature::Svm => "nested virtualization",
+            EnabledCpuFeature::Amx => "AMX",
+        }
+    }
 
-        for param in params_list.iter() {
-            if param.starts_with("src=") {
-                src_str = &param[4..];
-            } else if param.starts_with("iommu=") {
-                iommu_str = &param[6..];
-            }
+    // Parse a single feature, or a "[<feature>,...]" list.
+    pub fn parse_list(features: &str) -> Result<Vec<Self>> {
+        if !features.starts_with('[') {
+            return Ok(vec![EnabledCpuFeature::parse(features)?]);
+        }
+        if !features.ends_with(']') {
+            return Err(Error::ParseCpuEnabledFeatureParam(features));
         }
 
-        Ok(RngConfig {
-            src: PathBuf::from(src_str),
-            iommu: parse_iommu(iommu_str)?,
-        })
+        features[1..features.len() - 1]
+            .split(',')
+            .map(EnabledCpuFeature::parse)
+            .collect()
     }
 }
 
-impl Default for RngConfig {
-    fn default() -> Self {
-        RngConfig {
-            src: PathBuf::from(DEFAULT_RNG_SOURCE),
-            iommu: false,
+// Take a "key=[...]" parameter out of the parameters fragments, as its value
+// contains commas of its own. The value ends with its matching bracket.
+fn take_list_param<'a>(params: &mut Vec<&'a str>, key: &str) -> Option<&'a str> {
+    let (index, start) = params
+        .iter()
+        .enumerate()
+        .find_map(|(index, fragment)| fragment.find(key).map(|start| (index, start)))?;
+
+    let fragment = params[index];
+    let value = &fragment[start + key.len()..];
+    let mut depth = 0;
+    let mut end = value.len();
+    for (i, c) in value.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ => continue,
+        }
+        if depth == 0 {
+            end = i + 1;
+            break;
         }
     }
+
+    params[index] = &fragment[..start];
+    params.insert(index + 1, &value[end..]);
+    Some(&value[..end])
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct FsConfig {
-    pub tag: String,
-    pub sock: PathBuf,
-    pub num_queues: usize,
-    pub queue_size: u16,
-    pub cache_size: Option<u64>,
+pub struct CpusConfig {
+    pub cpu_count: u8,
+    #[serde(default)]
+    pub topology: Option<CpuTopology>,
+    #[serde(default)]
+    pub affinity: Option<Vec<CpuAffinity>>,
+    /// Features hidden from the guest CPUID.
+    #[serde(default)]
+    pub disabled_features: Option<Vec<CpuFeature>>,
+    /// Features of the host CPU exposed to the guest, hidden otherwise.
+    #[serde(default)]
+    pub enabled_features: Option<Vec<EnabledCpuFeature>>,
+    /// Hyper-V enlightenments provided by KVM: relaxed timing, SynIC and
+    /// synthetic timers, meant for Windows guests.
+    #[serde(default)]
+    pub kvm_hyperv: bool,
+    /// Frequency, in MHz, reported to the guest instead of the host one.
+    #[serde(default)]
+    pub frequency: Option<u32>,
 }
 
-impl FsConfig {
-    pub fn parse(fs: &str) -> Result<Self> {
-        // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = fs.split(',').collect();
+impl CpusConfig {
+    pub fn parse(cpus: &str) -> Result<Self> {
+        // The affinity and disabled features values contain commas of their
+        // own, so they are taken out before splitting the other parameters.
+        let mut params = vec![cpus];
+        let affinity_str = take_list_param(&mut params, "affinity=");
+        let features_str = take_list_param(&mut params, "disable_features=");
+        // Once the disabled features are taken out, as their key ends with
+        // this one. A single feature is given without brackets.
+        let mut enabled_features_str = if params.iter().any(|p| p.contains("features=[")) {
+            take_list_param(&mut params, "features=")
+        } else {
+            None
+        };
 
-        let mut tag: &str = "";
-        let mut sock: &str = "";
-        let mut num_queues_str: &str = "";
-        let mut queue_size_str: &str = "";
-        let mut dax_str: &str = "";
-        let mut cache_size_str: &str = "";
+        // Split the parameters based on the comma delimiter. The topology
+        // value is itself a comma separated list of "key:value" pairs.
+        let params_list: Vec<&str> = params
+            .iter()
+            .flat_map(|params| params.split(','))
+            .filter(|param| !param.is_empty())
+            .collect();
+
+        let mut count_str: &str = "";
+        let mut kvm_hyperv_str: &str = "";
+        let mut frequency_str: &str = "";
+        let mut topology_params: Vec<&str> = Vec::new();
 
         for param in params_list.iter() {
-            if param.starts_with("tag=") {
-                tag = &param[4..];
-            } else if param.starts_with("sock=") {
-                sock = &param[5..];
-            } else if param.starts_with("num_queues=") {
-                num_queues_str = &param[11..];
-            } else if param.starts_with("queue_size=") {
-                queue_size_str = &param[11..];
-            } else if param.starts_with("dax=") {
-                dax_str = &param[4..];
-            } else if param.starts_with("cache_size=") {
-                cache_size_str = &param[11..];
+            if param.starts_with("topology=") {
+                topology_params.push(&param[9..]);
+            } else if param.starts_with("kvm_hyperv=") {
+                kvm_hyperv_str = &param[11..];
+            } else if param.starts_with("frequency=") {
+                frequency_str = &param[10..];
+            } else if param.starts_with("features=") {
+                enabled_features_str = Some(&param[9..]);
+            } else if param.contains(':') {
+                topology_params.push(*param);
+            } else {
+                count_str = param;
             }
         }
 
-        let mut num_queues: usize = 1;
-        let mut queue_size: u16 = 1024;
-        let mut dax: bool = true;
-        // Default cache size set to 8Gib.
-        let mut cache_size: Option<u64> = Some(0x0002_0000_0000);
+        let topology = if topology_params.is_empty() {
+            None
+        } else {
+            Some(CpuTopology::parse(&topology_params)?)
+        };
 
-        if tag.is_empty() {
-            return Err(Error::ParseFsTagParam);
-        }
-        if sock.is_empty() {
-            return Err(Error::ParseFsSockParam);
+        let cpu_count = match &topology {
+            Some(topology) if count_str.is_empty() => {
+                u8::try_from(topology.cpu_count()).map_err(|_| Error::ValidateCpuTopologyCount)?
+            }
+            _ => count_str.parse().map_err(Error::ParseCpusParams)?,
+        };
+
+        if let Some(topology) = &topology {
+            if topology.cpu_count() != u32::from(cpu_count) {
+                return Err(Error::ValidateCpuTopologyCount);
+            }
         }
-        if !num_queues_str.is_empty() {
-            num_queues = num_queues_str
-                .parse()
-                .map_err(Error::ParseFsNumQueuesParam)?;
+
+        let affinity = match affinity_str {
+            Some(affinity_str) => Some(CpuAffinity::parse_list(affinity_str)?),
+            None => None,
+        };
+
+        if let Some(affinity) = &affinity {
+            for entry in affinity.iter() {
+                if entry.vcpu >= cpu_count {
+                    return Err(Error::ValidateCpuAffinityVcpu(entry.vcpu));
+                }
+                if let Some(&host_cpu) = entry
+                    .host_cpus
+                    .iter()
+                    .find(|&&host_cpu| host_cpu >= libc::CPU_SETSIZE as usize)
+                {
+                    return Err(Error::ValidateCpuAffinityHostCpu(host_cpu));
+                }
+            }
         }
-        if !queue_size_str.is_empty() {
-            queue_size = queue_size_str
+
+        let disabled_features = match features_str {
+            Some(features_str) => Some(CpuFeature::parse_list(features_str)?),
+            None => None,
+        };
+
+        let enabled_features = match enabled_features_str {
+            Some(enabled_features_str) => {
+                Some(EnabledCpuFeature::parse_list(enabled_features_str)?)
+            }
+            None => None,
+        };
+
+        let kvm_hyperv = match kvm_hyperv_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseCpuKvmHypervParam),
+        };
+
+        let frequency = if frequency_str.is_empty() {
+            None
+        } else {
+            let frequency: u32 = frequency_str
                 .parse()
-                .map_err(Error::ParseFsQueueSizeParam)?;
-        }
-        if !dax_str.is_empty() {
-            match dax_str {
-                "on" => dax = true,
-                "off" => dax = false,
-                _ => return Err(Error::ParseFsDax),
+                .map_err(Error::ParseCpuFrequencyParam)?;
+            if frequency == 0 {
+                return Err(Error::ValidateCpuFrequency);
             }
+            Some(frequency)
+        };
+
+        Ok(CpusConfig {
+            cpu_count,
+            topology,
+            affinity,
+            disabled_features,
+            enabled_features,
+            kvm_hyperv,
+            frequency,
+        })
+    }
+}
+
+impl Default for CpusConfig {
+    fn default() -> Self {
+        CpusConfig {
+            cpu_count: DEFAULT_VCPUS,
+            topology: None,
+            affinity: None,
+            disabled_features: None,
+            enabled_features: None,
+            kvm_hyperv: false,
+            frequency: None,
         }
+    }
+}
 
-        // Take appropriate decision about cache_size based on DAX being
-        // enabled or disabled.
-        if !dax {
-            if !cache_size_str.is_empty() {
-                return Err(Error::InvalidCacheSizeWithDaxOff);
-            }
-            cache_size = None;
-        } else if !cache_size_str.is_empty() {
-            cache_size = Some(parse_size(cache_size_str)?);
+/// Backing of anonymous huge pages memory when the host can't provide the
+/// huge pages. Smaller huge page sizes of the host are tried first.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum HugepagesFallback {
+    /// No fallback, the VM fails to start.
+    None,
+    /// Transparent huge pages.
+    Thp,
+    /// Regular pages.
+    Small,
+}
+
+impl HugepagesFallback {
+    pub fn parse(hugepages_fallback: &str) -> Result<Self> {
+        match hugepages_fallback {
+            "" | "none" => Ok(HugepagesFallback::None),
+            "thp" => Ok(HugepagesFallback::Thp),
+            "small" => Ok(HugepagesFallback::Small),
+            _ => Err(Error::ParseHugepagesFallbackParam),
         }
+    }
+}
 
-        Ok(FsConfig {
-            tag: tag.to_string(),
-            sock: PathBuf::from(sock),
-            num_queues,
-            queue_size,
-            cache_size,
-        })
+impl Default for HugepagesFallback {
+    fn default() -> Self {
+        HugepagesFallback::None
     }
 }
 
+/// A part of the guest RAM with its own backing. The zones are laid out in
+/// the guest address space in the order they are given.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct PmemConfig {
-    pub file: PathBuf,
+pub struct MemoryZoneConfig {
     pub size: u64,
+    /// Backing file, or directory to create a temporary file in.
     #[serde(default)]
-    pub iommu: bool,
+    pub file: Option<PathBuf>,
+    /// MAP_SHARED rather than MAP_PRIVATE mapping, a file backed zone is
+    /// always shared.
+    #[serde(default)]
+    pub shared: bool,
+    /// Memory backed by huge pages. A file backed zone needs its file to
+    /// live on a hugetlbfs mount.
+    #[serde(default)]
+    pub hugepages: bool,
+    #[serde(default)]
+    pub hugepage_size: Option<u64>,
+    #[serde(default)]
+    pub hugepages_fallback: HugepagesFallback,
 }
 
-impl PmemConfig {
-    pub fn parse(pmem: &str) -> Result<Self> {
+impl MemoryZoneConfig {
+    pub fn parse(memory_zone: &str) -> Result<Self> {
         // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = pmem.split(',').collect();
+        let params_list: Vec<&str> = memory_zone.split(',').collect();
 
-        let mut file_str: &str = "";
         let mut size_str: &str = "";
-        let mut iommu_str: &str = "";
+        let mut file_str: &str = "";
+        let mut shared_str: &str = "";
+        let mut hugepages_str: &str = "";
+        let mut hugepage_size_str: &str = "";
+        let mut hugepages_fallback_str: &str = "";
+        let mut backed = false;
 
         for param in params_list.iter() {
-            if param.starts_with("file=") {
-                file_str = &param[5..];
-            } else if param.starts_with("size=") {
+            if param.starts_with("size=") {
                 size_str = &param[5..];
-            } else if param.starts_with("iommu=") {
-                iommu_str = &param[6..];
+            } else if param.starts_with("file=") {
+                backed = true;
+                file_str = &param[5..];
+            } else if param.starts_with("shared=") {
+                shared_str = &param[7..];
+            } else if param.starts_with("hugepages=") {
+                hugepages_str = &param[10..];
+            } else if param.starts_with("hugepage_size=") {
+                hugepage_size_str = &param[14..];
+            } else if param.starts_with("hugepages_fallback=") {
+                hugepages_fallback_str = &param[19..];
             }
         }
 
-        if file_str.is_empty() {
-            return Err(Error::ParsePmemFileParam);
-        }
+        let file = if backed {
+            if file_str.is_empty() {
+                return Err(Error::ParseMemoryZoneFileParam);
+            }
 
-        Ok(PmemConfig {
-            file: PathBuf::from(file_str),
-            size: parse_size(size_str)?,
-            iommu: parse_iommu(iommu_str)?,
-        })
-    }
-}
+            Some(PathBuf::from(file_str))
+        } else {
+            None
+        };
 
-#[derive(Clone, PartialEq, Deserialize, Serialize)]
-pub enum ConsoleOutputMode {
-    Off,
-    Tty,
-    File,
-    Null,
-}
+        let shared = match shared_str {
+            "on" => true,
+            "off" => false,
+            "" => file.is_some(),
+            _ => return Err(Error::ParseMemoryZoneSharedParam),
+        };
+        if file.is_some() && !shared {
+            return Err(Error::ValidateMemoryZonePrivateFile);
+        }
+        let hugepages = match hugepages_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseMemoryZoneHugepagesParam),
+        };
 
-impl ConsoleOutputMode {
-    pub fn input_enabled(&self) -> bool {
-        match self {
-            ConsoleOutputMode::Tty => true,
-            _ => false,
+        let size = parse_size(size_str)?;
+        if size == 0 {
+            return Err(Error::ValidateMemoryZoneSize);
         }
+        let hugepage_size = parse_hugepage_size(hugepage_size_str, hugepages, size)?;
+        let hugepages_fallback =
+            parse_hugepages_fallback(hugepages_fallback_str, hugepages, backed)?;
+
+        Ok(MemoryZoneConfig {
+            size,
+            file,
+            shared,
+            hugepages,
+            hugepage_size,
+            hugepages_fallback,
+        })
     }
 }
 
-#[derive(Clone, Deserialize, Serialize)]
-pub struct ConsoleConfig {
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MemoryConfig {
+    pub size: u64,
     pub file: Option<PathBuf>,
-    pub mode: ConsoleOutputMode,
     #[serde(default)]
-    pub iommu: bool,
+    pub hugepages: bool,
+    #[serde(default)]
+    pub hugepage_size: Option<u64>,
+    #[serde(default)]
+    pub hugepages_fallback: HugepagesFallback,
+    /// When set, the guest RAM is made of these zones, and size is the sum
+    /// of their sizes.
+    #[serde(default)]
+    pub zones: Option<Vec<MemoryZoneConfig>>,
+    /// The pages of the guest RAM are allocated before the guest boots, by
+    /// several threads, rather than as it first touches them.
+    #[serde(default)]
+    pub prefault: bool,
+    /// The guest RAM is advised mergeable, for KSM to merge its pages with
+    /// the identical ones of other VMs.
+    #[serde(default)]
+    pub mergeable: bool,
+    /// When set, the guest RAM is advised to be backed by transparent huge
+    /// pages, or not to be, rather than following the host policy.
+    #[serde(default)]
+    pub thp: Option<bool>,
 }
 
-impl ConsoleConfig {
-    pub fn parse(console: &str) -> Result<Self> {
+impl MemoryConfig {
+    pub fn parse(memory: &str) -> Result<Self> {
         // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = console.split(',').collect();
+        let params_list: Vec<&str> = memory.split(',').collect();
 
-        let mut valid = false;
-        let mut file: Option<PathBuf> = None;
-        let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
-        let mut iommu_str: &str = "";
+        let mut size_str: &str = "";
+        let mut file_str: &str = "";
+        let mut hugepages_str: &str = "";
+        let mut hugepage_size_str: &str = "";
+        let mut hugepages_fallback_str: &str = "";
+        let mut prefault_str: &str = "";
+        let mut mergeable_str: &str = "";
+        let mut thp_str: &str = "";
+        let mut backed = false;
 
         for param in params_list.iter() {
-            if param.starts_with("iommu=") {
-                iommu_str = &param[6..];
-            } else {
-                if *param == "off" {
-                    mode = ConsoleOutputMode::Off;
-                    file = None;
-                } else if *param == "tty" {
-                    mode = ConsoleOutputMode::Tty;
-                    file = None;
-                } else if param.starts_with("file=") {
-                    mode = ConsoleOutputMode::File;
-                    file = Some(PathBuf::from(&param[5..]));
-                } else if param.starts_with("null") {
-                    mode = ConsoleOutputMode::Null;
-                    file = None;
-                } else {
-                    return Err(Error::ParseConsoleParam);
-                }
-                valid = true;
+            if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("file=") {
+                backed = true;
+                file_str = &param[5..];
+            } else if param.starts_with("hugepages=") {
+                hugepages_str = &param[10..];
+            } else if param.starts_with("hugepage_size=") {
+                hugepage_size_str = &param[14..];
+            } else if param.starts_with("hugepages_fallback=") {
+                hugepages_fallback_str = &param[19..];
+            } else if param.starts_with("prefault=") {
+                prefault_str = &param[9..];
+            } else if param.starts_with("mergeable=") {
+                mergeable_str = &param[10..];
+            } else if param.starts_with("thp=") {
+                thp_str = &param[4..];
             }
         }
 
-        if !valid {
-            return Err(Error::ParseConsoleParam);
-        }
+        let file = if backed {
+            if file_str.is_empty() {
+                return Err(Error::ParseMemoryFileParam);
+            }
 
-        Ok(Self {
-            mode,
+            Some(PathBuf::from(file_str))
+        } else {
+            None
+        };
+
+        let hugepages = match hugepages_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseMemoryHugepagesParam),
+        };
+        let prefault = match prefault_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseMemoryPrefaultParam),
+        };
+        let mergeable = match mergeable_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseMemoryMergeableParam),
+        };
+        let thp = match thp_str {
+            "on" => Some(true),
+            "off" => Some(false),
+            "" => None,
+            _ => return Err(Error::ParseMemoryThpParam),
+        };
+
+        let size = parse_size(size_str)?;
+        let hugepage_size = parse_hugepage_size(hugepage_size_str, hugepages, size)?;
+        let hugepages_fallback =
+            parse_hugepages_fallback(hugepages_fallback_str, hugepages, backed)?;
+
+        Ok(MemoryConfig {
+            size,
             file,
-            iommu: parse_iommu(iommu_str)?,
+            hugepages,
+            hugepage_size,
+            hugepages_fallback,
+            zones: None,
+            prefault,
+            mergeable,
+            thp,
         })
     }
 
-    pub fn default_serial() -> Self {
-        ConsoleConfig {
-            file: None,
-            mode: ConsoleOutputMode::Null,
-            iommu: false,
+    /// KSM only merges private anonymous pages, which aren't huge pages.
+    pub fn validate(&self) -> Result<()> {
+        let zones = self.zones();
+        if self.mergeable
+            && zones
+                .iter()
+                .any(|zone| zone.shared || zone.file.is_some() || zone.hugepages)
+        {
+            return Err(Error::ValidateMemoryMergeable);
         }
+        if self.thp == Some(false)
+            && zones
+                .iter()
+                .any(|zone| zone.hugepages_fallback == HugepagesFallback::Thp)
+        {
+            return Err(Error::ValidateMemoryThp);
+        }
+
+        Ok(())
     }
 
-    pub fn default_console() -> Self {
-        ConsoleConfig {
-            file: None,
-            mode: ConsoleOutputMode::Tty,
-            iommu: false,
+    fn base_zone(&self, size: u64) -> MemoryZoneConfig {
+        MemoryZoneConfig {
+            size,
+            file: self.file.clone(),
+            shared: self.file.is_some(),
+            hugepages: self.hugepages,
+            hugepage_size: self.hugepage_size,
+            hugepages_fallback: self.hugepages_fallback,
         }
     }
-}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct DeviceConfig {
-    pub path: PathBuf,
-    #[serde(default)]
-    pub iommu: bool,
-}
+    /// The zones making the guest RAM. Without any explicit zone, the whole
+    /// RAM is a single zone, shared when backed by a file.
+    pub fn zones(&self) -> Vec<MemoryZoneConfig> {
+        match &self.zones {
+            Some(zones) if !zones.is_empty() => zones.clone(),
+            _ => vec![self.base_zone(self.size)],
+        }
+    }
 
-impl DeviceConfig {
-    pub fn parse(device: &str) -> Result<Self> {
-        // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = device.split(',').collect();
+    /// Backing of the RAM regions hotplugged later on.
+    pub fn hotplug_zone(&self) -> MemoryZoneConfig {
+        self.base_zone(0)
+    }
 
-        let mut path_str: &str = "";
-        let mut iommu_str: &str = "";
+    /// Whether the guest RAM is mapped from files, so that it can be shared
+    /// with the vhost-user backends.
+    pub fn shared(&self) -> bool {
+        self.zones().iter().all(|zone| {
+            zone.file.is_some()
+                || zone.shared
+                || (zone.hugepages && zone.hugepages_fallback == HugepagesFallback::None)
+        })
+    }
+}
 
-        for param in params_list.iter() {
-            if param.starts_with("path=") {
-                path_str = &param[5..];
-            } else if param.starts_with("iommu=") {
-                iommu_str = &param[6..];
-            }
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        MemoryConfig {
+            size: DEFAULT_MEMORY_MB << 20,
+            file: None,
+            hugepages: false,
+            hugepage_size: None,
+            hugepages_fallback: HugepagesFallback::None,
+            zones: None,
+            prefault: false,
+            mergeable: false,
+            thp: None,
         }
-
-        Ok(DeviceConfig {
-            path: PathBuf::from(path_str),
-            iommu: parse_iommu(iommu_str)?,
-        })
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct VuConfig {
-    pub sock: String,
-    pub num_queues: usize,
-    pub queue_size: u16,
+pub struct KernelConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InitramfsConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CmdlineConfig {
+    pub args: String,
+}
+
+impl CmdlineConfig {
+    pub fn parse(cmdline: Option<&str>) -> Result<Self> {
+        let args = cmdline
+            .map(std::string::ToString::to_string)
+            .unwrap_or_else(String::new);
+
+        Ok(CmdlineConfig { args })
+    }
+}
+
+/// Whether the name is a valid guest hostname: dot separated labels of up
+/// to 63 letters, digits and dashes, not starting nor ending with a dash.
+pub fn valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= 253
+        && hostname.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+fn parse_resolvers(resolvers: &str) -> Result<Vec<IpAddr>> {
+    resolvers
+        .split(',')
+        .map(|resolver| {
+            resolver
+                .parse()
+                .map_err(|e| Error::ParseResolverParam(resolver, e))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TokenBucketConfig {
+    pub size: u64,
+    #[serde(default)]
+    pub one_time_burst: Option<u64>,
+    /// Time in milliseconds needed to refill a completely empty bucket.
+    pub refill_time: u64,
+}
+
+impl TokenBucketConfig {
+    fn parse<'a>(
+        size: &'a str,
+        one_time_burst: &'a str,
+        refill_time: &'a str,
+    ) -> Result<'a, Option<Self>> {
+        if size.is_empty() && one_time_burst.is_empty() && refill_time.is_empty() {
+            return Ok(None);
+        }
+        if size.is_empty() || refill_time.is_empty() {
+            return Err(Error::InvalidRateLimiterParams);
+        }
+
+        let one_time_burst = if one_time_burst.is_empty() {
+            None
+        } else {
+            Some(parse_size(one_time_burst)?)
+        };
+
+        Ok(Some(TokenBucketConfig {
+            size: parse_size(size)?,
+            one_time_burst,
+            refill_time: refill_time
+                .parse::<u64>()
+                .map_err(Error::ParseRateLimiterParams)?,
+        }))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RateLimiterConfig {
+    /// Bandwidth limit, expressed in bytes.
+    #[serde(default)]
+    pub bandwidth: Option<TokenBucketConfig>,
+    /// Operations limit, expressed in requests or frames.
+    #[serde(default)]
+    pub ops: Option<TokenBucketConfig>,
+}
+
+impl RateLimiterConfig {
+    // Looks for the rate limiter parameters among a device parameters list,
+    // returning None if no limit has been set.
+    fn parse<'a>(params_list: &[&'a str]) -> Result<'a, Option<Self>> {
+        let mut bw_size_str: &str = "";
+        let mut bw_one_time_burst_str: &str = "";
+        let mut bw_refill_time_str: &str = "";
+        let mut ops_size_str: &str = "";
+        let mut ops_one_time_burst_str: &str = "";
+        let mut ops_refill_time_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("bw_size=") {
+                bw_size_str = &param[8..];
+            } else if param.starts_with("bw_one_time_burst=") {
+                bw_one_time_burst_str = &param[18..];
+            } else if param.starts_with("bw_refill_time=") {
+                bw_refill_time_str = &param[15..];
+            } else if param.starts_with("ops_size=") {
+                ops_size_str = &param[9..];
+            } else if param.starts_with("ops_one_time_burst=") {
+                ops_one_time_burst_str = &param[19..];
+            } else if param.starts_with("ops_refill_time=") {
+                ops_refill_time_str = &param[16..];
+            }
+        }
+
+        let bandwidth =
+            TokenBucketConfig::parse(bw_size_str, bw_one_time_burst_str, bw_refill_time_str)?;
+        let ops =
+            TokenBucketConfig::parse(ops_size_str, ops_one_time_burst_str, ops_refill_time_str)?;
+
+        if bandwidth.is_none() && ops.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(RateLimiterConfig { bandwidth, ops }))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DiskModel {
+    Virtio,
+    /// SATA drive behind an emulated AHCI controller, for guests without
+    /// any virtio driver.
+    Ahci,
+}
+
+impl DiskModel {
+    pub fn parse(model: &str) -> Result<Self> {
+        match model {
+            "" | "virtio" => Ok(DiskModel::Virtio),
+            "ahci" => Ok(DiskModel::Ahci),
+            _ => Err(Error::ParseDiskModelParam),
+        }
+    }
+}
+
+impl Default for DiskModel {
+    fn default() -> Self {
+        DiskModel::Virtio
+    }
+}
+
+/// How the writes of the guest reach the disk image.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum DiskCacheMode {
+    /// The writes go through the page cache of the host, the guest
+    /// flushing them to the disk image.
+    Writeback,
+    /// The writes bypass the page cache of the host, as with direct I/O,
+    /// the guest still flushing them out of the cache of the storage.
+    None,
+    /// The flushes of the guest are ignored: the writes the host didn't
+    /// sync yet are lost if it crashes.
+    Unsafe,
+}
+
+impl DiskCacheMode {
+    pub fn parse(cache_mode: &str) -> Result<Self> {
+        match cache_mode {
+            "" | "writeback" => Ok(DiskCacheMode::Writeback),
+            "none" => Ok(DiskCacheMode::None),
+            "unsafe" => Ok(DiskCacheMode::Unsafe),
+            _ => Err(Error::ParseDiskCacheParam),
+        }
+    }
+}
+
+impl Default for DiskCacheMode {
+    fn default() -> Self {
+        DiskCacheMode::Writeback
+    }
+}
+
+/// With `fd`, the disk image is the file an API client passed under that
+/// name, which `path` then only names.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiskConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub fd: Option<String>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub model: DiskModel,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: u16,
+    /// The disk group the bandwidth of the disk is shared in, if any.
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default = "default_disk_weight")]
+    pub weight: u32,
+    /// The guest can't write to the disk.
+    #[serde(default)]
+    pub readonly: bool,
+    /// The disk can be removed from the running VM, the guest ejecting it
+    /// as the hot-added devices.
+    #[serde(default)]
+    pub removable: bool,
+    /// The reads of the read-only disk go through the block cache the VMs
+    /// of the process share.
+    #[serde(default)]
+    pub cache: bool,
+    /// The writes go through the page cache of the host, bypass it, or
+    /// ignore the flushes of the guest, given as `cache=writeback`,
+    /// `cache=none` and `cache=unsafe` on the command line.
+    #[serde(default)]
+    pub cache_mode: DiskCacheMode,
+    /// The disk image is opened with `O_DIRECT`, its reads and writes
+    /// bypassing the page cache of the host.
+    #[serde(default)]
+    pub direct: bool,
+    /// ID of the device, which the API requests about it name it by, one
+    /// being given to the devices without when the VM is created.
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+fn default_disk_weight() -> u32 {
+    DEFAULT_DISK_WEIGHT
+}
+
+impl DiskConfig {
+    pub fn parse(disk: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = disk.split(',').collect();
+
+        let mut path_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut model_str: &str = "";
+        let mut queue_size_str: &str = "";
+        let mut group_str: &str = "";
+        let mut weight_str: &str = "";
+        let mut readonly_str: &str = "";
+        let mut removable_str: &str = "";
+        let mut cache_str: &str = "";
+        let mut direct_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("model=") {
+                model_str = &param[6..];
+            } else if param.starts_with("queue_size=") {
+                queue_size_str = &param[11..];
+            } else if param.starts_with("group=") {
+                group_str = &param[6..];
+            } else if param.starts_with("weight=") {
+                weight_str = &param[7..];
+            } else if param.starts_with("readonly=") {
+                readonly_str = &param[9..];
+            } else if param.starts_with("removable=") {
+                removable_str = &param[10..];
+            } else if param.starts_with("cache=") {
+                cache_str = &param[6..];
+            } else if param.starts_with("direct=") {
+                direct_str = &param[7..];
+            }
+        }
+
+        let group = if group_str.is_empty() {
+            None
+        } else {
+            Some(group_str.to_string())
+        };
+        let weight = if weight_str.is_empty() {
+            DEFAULT_DISK_WEIGHT
+        } else {
+            match weight_str.parse::<u32>() {
+                Ok(weight) if weight > 0 => weight,
+                _ => return Err(Error::ParseDiskWeightParam),
+            }
+        };
+        let readonly = match readonly_str {
+            "on" | "true" => true,
+            "off" | "false" | "" => false,
+            _ => return Err(Error::ParseDiskReadonlyParam),
+        };
+        let removable = match removable_str {
+            "on" | "true" => true,
+            "off" | "false" | "" => false,
+            _ => return Err(Error::ParseDiskRemovableParam),
+        };
+        // The block cache is turned on and off with the same parameter the
+        // cache mode is given with.
+        let (cache, cache_mode) = match cache_str {
+            "on" => (true, DiskCacheMode::default()),
+            "off" => (false, DiskCacheMode::default()),
+            _ => (false, DiskCacheMode::parse(cache_str)?),
+        };
+        let direct = match direct_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseDiskDirectParam),
+        };
+
+        Ok(DiskConfig {
+            path: PathBuf::from(path_str),
+            fd: None,
+            iommu: parse_iommu(iommu_str)?,
+            rate_limiter_config: RateLimiterConfig::parse(&params_list)?,
+            model: DiskModel::parse(model_str)?,
+            queue_size: parse_queue_size(queue_size_str)?,
+            group,
+            weight,
+            readonly,
+            removable,
+            cache,
+            cache_mode,
+            direct,
+            id: parse_device_id(id_str),
+        })
+    }
+
+    /// The disk image is opened with `O_DIRECT`, which `cache=none` implies.
+    pub fn direct_io(&self) -> bool {
+        self.direct || self.cache_mode == DiskCacheMode::None
+    }
+}
+
+/// Bandwidth, in bytes per second, shared by the disks of the group in
+/// proportion to their weights, for one of them not to starve the others
+/// of their common backing device.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiskGroupConfig {
+    pub id: String,
+    pub bandwidth: u64,
+}
+
+impl DiskGroupConfig {
+    pub fn parse(disk_group: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = disk_group.split(',').collect();
+
+        let mut id_str: &str = "";
+        let mut bandwidth_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("bandwidth=") {
+                bandwidth_str = &param[10..];
+            }
+        }
+
+        if id_str.is_empty() {
+            return Err(Error::ParseDiskGroupIdParam);
+        }
+        let bandwidth = match parse_size(bandwidth_str) {
+            Ok(bandwidth) if bandwidth > 0 => bandwidth,
+            _ => return Err(Error::ParseDiskGroupBandwidthParam),
+        };
+
+        Ok(DiskGroupConfig {
+            id: id_str.to_string(),
+            bandwidth,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum NetModel {
+    Virtio,
+    /// Emulated Intel 82540EM, for guests without any virtio driver.
+    E1000,
+}
+
+impl NetModel {
+    pub fn parse(model: &str) -> Result<Self> {
+        match model {
+            "" | "virtio" => Ok(NetModel::Virtio),
+            "e1000" => Ok(NetModel::E1000),
+            _ => Err(Error::ParseNetModelParam),
+        }
+    }
+}
+
+impl Default for NetModel {
+    fn default() -> Self {
+        NetModel::Virtio
+    }
+}
+
+/// With `fd`, the interface is backed by the tap an API client passed under
+/// that name, instead of `tap`. With `fds`, it is backed by the file
+/// descriptors the process inherited, the queues of a multi-queue tap, and
+/// gets a queue pair for each of them. With `macvtap`, it is backed by that
+/// macvtap interface, the guest sitting on its link.
+///
+/// The guest gets the MAC address of the macvtap interface backing an
+/// interface, by name or file descriptor, rather than `mac`.
+///
+/// The `offload_*` fields let the guest offload the checksums, TCP
+/// segmentation and UDP fragmentation of its frames to the host, and the
+/// host hand it frames the same way.
+/// The tap the VMM creates gets `ip` and `mask` as its host-side address, and
+/// `ip6` along with `ip6_prefix_len` as well, for a dual-stack interface.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NetConfig {
+    pub tap: Option<String>,
+    #[serde(default)]
+    pub fd: Option<String>,
+    #[serde(default)]
+    pub fds: Option<Vec<i32>>,
+    #[serde(default)]
+    pub macvtap: Option<String>,
+    pub ip: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    #[serde(default)]
+    pub ip6: Option<Ipv6Addr>,
+    #[serde(default = "default_net_ip6_prefix_len")]
+    pub ip6_prefix_len: u8,
+    pub mac: MacAddr,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub model: NetModel,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: u16,
+    #[serde(default = "default_net_offload")]
+    pub offload_csum: bool,
+    #[serde(default = "default_net_offload")]
+    pub offload_tso: bool,
+    #[serde(default = "default_net_offload")]
+    pub offload_ufo: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+fn default_net_ip6_prefix_len() -> u8 {
+    DEFAULT_NET_IP6_PREFIX_LEN
+}
+
+fn default_net_offload() -> bool {
+    true
+}
+
+fn parse_net_offload(offload: &str) -> Result<bool> {
+    match offload {
+        "" | "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(Error::ParseNetOffloadParam),
+    }
+}
+
+impl NetConfig {
+    pub fn parse(net: &str) -> Result<Self> {
+        // The tap file descriptors value contains commas of its own, so it
+        // is taken out before splitting the other parameters.
+        let mut params = vec![net];
+        let fds_str = take_list_param(&mut params, "fds=");
+
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = params
+            .iter()
+            .flat_map(|params| params.split(','))
+            .filter(|param| !param.is_empty())
+            .collect();
+
+        let mut tap_str: &str = "";
+        let mut macvtap_str: &str = "";
+        let mut ip_str: &str = "";
+        let mut mask_str: &str = "";
+        let mut ip6_str: &str = "";
+        let mut ip6_prefix_len_str: &str = "";
+        let mut mac_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut model_str: &str = "";
+        let mut queue_size_str: &str = "";
+        let mut offload_csum_str: &str = "";
+        let mut offload_tso_str: &str = "";
+        let mut offload_ufo_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("tap=") {
+                tap_str = &param[4..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("macvtap=") {
+                macvtap_str = &param[8..];
+            } else if param.starts_with("ip=") {
+                ip_str = &param[3..];
+            } else if param.starts_with("mask=") {
+                mask_str = &param[5..];
+            } else if param.starts_with("ip6=") {
+                ip6_str = &param[4..];
+            } else if param.starts_with("ip6_prefix_len=") {
+                ip6_prefix_len_str = &param[15..];
+            } else if param.starts_with("mac=") {
+                mac_str = &param[4..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("model=") {
+                model_str = &param[6..];
+            } else if param.starts_with("queue_size=") {
+                queue_size_str = &param[11..];
+            } else if param.starts_with("offload_csum=") {
+                offload_csum_str = &param[13..];
+            } else if param.starts_with("offload_tso=") {
+                offload_tso_str = &param[12..];
+            } else if param.starts_with("offload_ufo=") {
+                offload_ufo_str = &param[12..];
+            }
+        }
+
+        let mut tap: Option<String> = None;
+        let mut ip: Ipv4Addr = Ipv4Addr::new(192, 168, 249, 1);
+        let mut mask: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+        let mut mac: MacAddr = MacAddr::local_random();
+        let iommu = parse_iommu(iommu_str)?;
+        let rate_limiter_config = RateLimiterConfig::parse(&params_list)?;
+        let model = NetModel::parse(model_str)?;
+        let queue_size = parse_queue_size(queue_size_str)?;
+
+        if !tap_str.is_empty() {
+            tap = Some(tap_str.to_string());
+        }
+        if !ip_str.is_empty() {
+            ip = ip_str.parse().map_err(Error::ParseNetIpParam)?;
+        }
+        if !mask_str.is_empty() {
+            mask = mask_str.parse().map_err(Error::ParseNetMaskParam)?;
+        }
+        let ip6 = if ip6_str.is_empty() {
+            None
+        } else {
+            Some(ip6_str.parse().map_err(Error::ParseNetIp6Param)?)
+        };
+        let ip6_prefix_len = if ip6_prefix_len_str.is_empty() {
+            DEFAULT_NET_IP6_PREFIX_LEN
+        } else {
+            match ip6_prefix_len_str.parse::<u8>() {
+                Ok(prefix_len) if prefix_len <= 128 => prefix_len,
+                _ => return Err(Error::ParseNetIp6PrefixLenParam),
+            }
+        };
+        if !mac_str.is_empty() {
+            mac = MacAddr::parse_str(mac_str).map_err(Error::ParseNetMacParam)?;
+        }
+        let fds = match fds_str {
+            Some(fds_str) if fds_str.starts_with('[') && fds_str.ends_with(']') => Some(
+                fds_str[1..fds_str.len() - 1]
+                    .split(',')
+                    .map(|fd| fd.parse())
+                    .collect::<result::Result<Vec<i32>, _>>()
+                    .map_err(|_| Error::ParseNetFdsParam(fds_str))?,
+            ),
+            Some(fds_str) => return Err(Error::ParseNetFdsParam(fds_str)),
+            None => None,
+        };
+
+        Ok(NetConfig {
+            tap,
+            fd: None,
+            fds,
+            macvtap: if macvtap_str.is_empty() {
+                None
+            } else {
+                Some(macvtap_str.to_string())
+            },
+            ip,
+            mask,
+            ip6,
+            ip6_prefix_len,
+            mac,
+            iommu,
+            rate_limiter_config,
+            model,
+            queue_size,
+            offload_csum: parse_net_offload(offload_csum_str)?,
+            offload_tso: parse_net_offload(offload_tso_str)?,
+            offload_ufo: parse_net_offload(offload_ufo_str)?,
+            id: parse_device_id(id_str),
+        })
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if let Some(fds) = &self.fds {
+            if fds.is_empty()
+                || self.tap.is_some()
+                || self.fd.is_some()
+                || (fds.len() > 1 && self.model != NetModel::Virtio)
+            {
+                return Err(Error::ValidateNetFds);
+            }
+        }
+        if self.macvtap.is_some() && (self.tap.is_some() || self.fd.is_some() || self.fds.is_some())
+        {
+            return Err(Error::ValidateNetMacvtap);
+        }
+        if (self.offload_tso || self.offload_ufo) && !self.offload_csum {
+            return Err(Error::ValidateNetOffload);
+        }
+
+        Ok(())
+    }
+
+    /// Number of RX/TX queue pairs of the interface.
+    pub fn queue_pairs(&self) -> usize {
+        self.fds
+            .as_ref()
+            .map_or(1, |fds| std::cmp::max(fds.len(), 1))
+    }
+
+    /// The host-side IPv6 address of the tap, and its prefix length.
+    pub fn ipv6(&self) -> Option<(Ipv6Addr, u8)> {
+        self.ip6.map(|ip6| (ip6, self.ip6_prefix_len))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RngConfig {
+    pub src: PathBuf,
+    #[serde(default)]
+    pub iommu: bool,
+}
+
+impl RngConfig {
+    pub fn parse(rng: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = rng.split(',').collect();
+
+        let mut src_str: &str = "";
+        let mut iommu_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("src=") {
+                src_str = &param[4..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            }
+        }
+
+        Ok(RngConfig {
+            src: PathBuf::from(src_str),
+            iommu: parse_iommu(iommu_str)?,
+        })
+    }
+}
+
+impl Default for RngConfig {
+    fn default() -> Self {
+        RngConfig {
+            src: PathBuf::from(DEFAULT_RNG_SOURCE),
+            iommu: false,
+        }
+    }
+}
+
+/// Synthetic ACPI battery and thermal zone, whose readings are set through
+/// the API.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SensorsConfig {
+    #[serde(default)]
+    pub battery: bool,
+    #[serde(default)]
+    pub thermal_zone: bool,
+}
+
+impl SensorsConfig {
+    pub fn parse(sensors: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = sensors.split(',').collect();
+
+        let mut battery_str: &str = "";
+        let mut thermal_zone_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("battery=") {
+                battery_str = &param[8..];
+            } else if param.starts_with("thermal_zone=") {
+                thermal_zone_str = &param[13..];
+            }
+        }
+
+        let battery = match battery_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseSensorsBatteryParam),
+        };
+        let thermal_zone = match thermal_zone_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseSensorsThermalZoneParam),
+        };
+
+        Ok(SensorsConfig {
+            battery,
+            thermal_zone,
+        })
+    }
+}
+
+/// SGX EPC section exposed to the guest, taken from the host EPC. The EPC
+/// pages are allocated as the guest uses them, unless `prefault` is set.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SgxEpcConfig {
+    pub size: u64,
+    #[serde(default)]
+    pub prefault: bool,
+}
+
+impl SgxEpcConfig {
+    pub fn parse(sgx_epc: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = sgx_epc.split(',').collect();
+
+        let mut size_str: &str = "";
+        let mut prefault_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("prefault=") {
+                prefault_str = &param[9..];
+            }
+        }
+
+        let size = parse_size(size_str)?;
+        if size == 0 || size % SGX_EPC_PAGE_SIZE != 0 {
+            return Err(Error::ValidateSgxEpcSize(size));
+        }
+        let prefault = match prefault_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseSgxEpcPrefaultParam),
+        };
+
+        Ok(SgxEpcConfig { size, prefault })
+    }
+}
+
+/// ACPI table added as is to the guest ACPI tables, such as an SSDT. The
+/// file holds the whole table, header included.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AcpiTableConfig {
+    pub path: PathBuf,
+}
+
+impl AcpiTableConfig {
+    pub fn parse(acpi_table: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = acpi_table.split(',').collect();
+
+        let mut path_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            }
+        }
+
+        if path_str.is_empty() {
+            return Err(Error::ParseAcpiTablePathParam);
+        }
+
+        Ok(AcpiTableConfig {
+            path: PathBuf::from(path_str),
+        })
+    }
+}
+
+/// ACPI table of key-value pairs, for guest software looking platform
+/// specific data up in the OEM tables.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AcpiOemTableConfig {
+    pub signature: String,
+    #[serde(default)]
+    pub entries: BTreeMap<String, String>,
+}
+
+impl AcpiOemTableConfig {
+    pub fn parse(acpi_oem_table: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = acpi_oem_table.split(',').collect();
+
+        let mut signature_str: &str = "";
+        let mut entries = BTreeMap::new();
+
+        for param in params_list.iter().filter(|param| !param.is_empty()) {
+            if param.starts_with("signature=") {
+                signature_str = &param[10..];
+            } else {
+                let mut entry = param.splitn(2, '=');
+                match (entry.next(), entry.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() => {
+                        entries.insert(key.to_string(), value.to_string());
+                    }
+                    _ => return Err(Error::ParseAcpiOemTableEntryParam(*param)),
+                }
+            }
+        }
+
+        if signature_str.len() != 4 || !signature_str.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::ParseAcpiOemTableSignatureParam(signature_str));
+        }
+
+        Ok(AcpiOemTableConfig {
+            signature: signature_str.to_string(),
+            entries,
+        })
+    }
+}
+
+/// TPM 2.0 of the guest, emulated by swtpm listening for its control
+/// channel on `socket`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TpmConfig {
+    pub socket: PathBuf,
+}
+
+impl TpmConfig {
+    pub fn parse(tpm: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = tpm.split(',').collect();
+
+        let mut socket_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("socket=") {
+                socket_str = &param[7..];
+            }
+        }
+
+        if socket_str.is_empty() {
+            return Err(Error::ParseTpmSocketParam);
+        }
+
+        Ok(TpmConfig {
+            socket: PathBuf::from(socket_str),
+        })
+    }
+}
+
+/// GDB remote serial protocol stub, listening for the debugger on the UNIX
+/// socket at `path`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GdbConfig {
+    pub path: PathBuf,
+}
+
+impl GdbConfig {
+    pub fn parse(gdb: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = gdb.split(',').collect();
+
+        let mut path_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            }
+        }
+
+        if path_str.is_empty() {
+            return Err(Error::ParseGdbPathParam);
+        }
+
+        Ok(GdbConfig {
+            path: PathBuf::from(path_str),
+        })
+    }
+}
+
+/// Directory the diagnostic bundles are written to, when the guest panics
+/// or triple faults, and whether the guest drivers of the virtio devices are
+/// audited.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DiagnosticsConfig {
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub audit: bool,
+}
+
+impl DiagnosticsConfig {
+    pub fn parse(diagnostics: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = diagnostics.split(',').collect();
+
+        let mut path_str: &str = "";
+        let mut audit_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            } else if param.starts_with("audit=") {
+                audit_str = &param[6..];
+            }
+        }
+
+        let audit = match audit_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseDiagnosticsAuditParam),
+        };
+        if path_str.is_empty() && !audit {
+            return Err(Error::ParseDiagnosticsPathParam);
+        }
+
+        Ok(DiagnosticsConfig {
+            path: if path_str.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(path_str))
+            },
+            audit,
+        })
+    }
+}
+
+fn default_guest_os_port() -> u32 {
+    DEFAULT_GUEST_OS_PORT
+}
+
+/// Probing of the guest OS, from the boot messages on the guest consoles,
+/// and from the report of a guest agent on the vsock `port`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuestOsConfig {
+    #[serde(default = "default_guest_os_port")]
+    pub port: u32,
+}
+
+impl GuestOsConfig {
+    pub fn parse(guest_os: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = guest_os.split(',').collect();
+
+        let mut port_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("port=") {
+                port_str = &param[5..];
+            }
+        }
+
+        let port = if port_str.is_empty() {
+            DEFAULT_GUEST_OS_PORT
+        } else {
+            port_str
+                .parse::<u32>()
+                .map_err(Error::ParseGuestOsPortParam)?
+        };
+
+        Ok(GuestOsConfig { port })
+    }
+}
+
+/// Access a Landlock rule gives to the files under its path.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum LandlockAccess {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl Default for LandlockAccess {
+    fn default() -> Self {
+        LandlockAccess::ReadOnly
+    }
+}
+
+/// Files the VMM keeps access to once confined by Landlock, on top of the
+/// ones of the VM configuration.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LandlockRuleConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub access: LandlockAccess,
+}
+
+impl LandlockRuleConfig {
+    pub fn parse(rule: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = rule.split(',').collect();
+
+        let mut path_str: &str = "";
+        let mut access_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            } else if param.starts_with("access=") {
+                access_str = &param[7..];
+            }
+        }
+
+        if path_str.is_empty() {
+            return Err(Error::ParseLandlockPathParam);
+        }
+        let access = match access_str {
+            "ro" | "" => LandlockAccess::ReadOnly,
+            "rw" => LandlockAccess::ReadWrite,
+            _ => return Err(Error::ParseLandlockAccessParam),
+        };
+
+        Ok(LandlockRuleConfig {
+            path: PathBuf::from(path_str),
+            access,
+        })
+    }
+}
+
+/// The VMM confines its process with Landlock once the files of the VM are
+/// opened, to these files and the ones of the `rules`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LandlockConfig {
+    #[serde(default)]
+    pub rules: Vec<LandlockRuleConfig>,
+}
+
+impl LandlockConfig {
+    pub fn parse(rules: &[&str]) -> Result<Self> {
+        let mut rule_config_list = Vec::new();
+        for item in rules.iter() {
+            rule_config_list.push(LandlockRuleConfig::parse(item)?);
+        }
+
+        Ok(LandlockConfig {
+            rules: rule_config_list,
+        })
+    }
+}
+
+/// User and groups the VMM process switches to once the files and devices
+/// of the VM are opened, before the guest runs.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct UserConfig {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups, none by default.
+    #[serde(default)]
+    pub groups: Vec<u32>,
+}
+
+impl UserConfig {
+    pub fn parse(user: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = user.split(',').collect();
+
+        let mut uid_str: &str = "";
+        let mut gid_str: &str = "";
+        let mut groups_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("uid=") {
+                uid_str = &param[4..];
+            } else if param.starts_with("gid=") {
+                gid_str = &param[4..];
+            } else if param.starts_with("groups=") {
+                groups_str = &param[7..];
+            }
+        }
+
+        let uid = uid_str
+            .parse::<u32>()
+            .map_err(|_| Error::ParseUserUidParam)?;
+        let gid = gid_str
+            .parse::<u32>()
+            .map_err(|_| Error::ParseUserGidParam)?;
+        let mut groups = Vec::new();
+        if !groups_str.is_empty() {
+            for group in groups_str.split(':') {
+                groups.push(group.parse::<u32>().map_err(Error::ParseUserGroupsParam)?);
+            }
+        }
+
+        Ok(UserConfig { uid, gid, groups })
+    }
+}
+
+/// Drift of the guest kvmclock from the host clock, checked every `period`
+/// seconds, and reported past `threshold` milliseconds either way.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ClockDriftConfig {
+    #[serde(default = "ClockDriftConfig::default_threshold")]
+    pub threshold: u64,
+    #[serde(default = "ClockDriftConfig::default_period")]
+    pub period: u64,
+}
+
+impl ClockDriftConfig {
+    fn default_threshold() -> u64 {
+        DEFAULT_CLOCK_DRIFT_THRESHOLD
+    }
+
+    fn default_period() -> u64 {
+        DEFAULT_CLOCK_DRIFT_PERIOD
+    }
+
+    pub fn parse(clock_drift: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = clock_drift.split(',').collect();
+
+        let mut threshold_str: &str = "";
+        let mut period_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("threshold=") {
+                threshold_str = &param[10..];
+            } else if param.starts_with("period=") {
+                period_str = &param[7..];
+            }
+        }
+
+        let threshold = if threshold_str.is_empty() {
+            DEFAULT_CLOCK_DRIFT_THRESHOLD
+        } else {
+            threshold_str
+                .parse::<u64>()
+                .map_err(Error::ParseClockDriftThresholdParam)?
+        };
+        let period = if period_str.is_empty() {
+            DEFAULT_CLOCK_DRIFT_PERIOD
+        } else {
+            match period_str.parse::<u64>() {
+                Ok(period) if period > 0 => period,
+                _ => return Err(Error::ParseClockDriftPeriodParam),
+            }
+        };
+
+        Ok(ClockDriftConfig { threshold, period })
+    }
+}
+
+/// Share of the host CPUs of a group of threads of the VM: its cpu.weight,
+/// its cpu.max quota, in percent of a host CPU, and the host CPUs of its
+/// cpuset.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct CgroupThreadsConfig {
+    #[serde(default)]
+    pub weight: Option<u16>,
+    #[serde(default)]
+    pub quota: Option<u32>,
+    #[serde(default)]
+    pub cpus: Option<Vec<usize>>,
+}
+
+impl CgroupThreadsConfig {
+    pub fn validate<'a>(&self) -> Result<'a, ()> {
+        if let Some(weight) = self.weight {
+            if weight == 0 || weight > MAX_CGROUP_WEIGHT {
+                return Err(Error::ValidateCgroupWeight(weight));
+            }
+        }
+        if self.quota == Some(0) {
+            return Err(Error::ValidateCgroupQuota);
+        }
+        if let Some(&host_cpu) = self
+            .cpus
+            .iter()
+            .flatten()
+            .find(|&&host_cpu| host_cpu >= libc::CPU_SETSIZE as usize)
+        {
+            return Err(Error::ValidateCgroupHostCpu(host_cpu));
+        }
+
+        Ok(())
+    }
+}
+
+/// cgroup v2 the VMM process is moved into, under the cgroup2 mount, with
+/// the io.weight of the VM. The vCPU threads, and the device threads they
+/// start, get threaded sub-groups of their own.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct CgroupConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub io_weight: Option<u16>,
+    #[serde(default)]
+    pub vcpus: CgroupThreadsConfig,
+    #[serde(default)]
+    pub devices: CgroupThreadsConfig,
+}
+
+impl CgroupConfig {
+    pub fn parse(cgroup: &str) -> Result<Self> {
+        // The host CPUs values contain commas of their own, so they are
+        // taken out before splitting the other parameters.
+        let mut params = vec![cgroup];
+        let vcpu_cpus_str = take_list_param(&mut params, "vcpu_cpus=");
+        let device_cpus_str = take_list_param(&mut params, "device_cpus=");
+
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = params
+            .iter()
+            .flat_map(|params| params.split(','))
+            .filter(|param| !param.is_empty())
+            .collect();
+
+        let mut path_str: &str = "";
+        let mut io_weight_str: &str = "";
+        let mut vcpu_weight_str: &str = "";
+        let mut vcpu_quota_str: &str = "";
+        let mut device_weight_str: &str = "";
+        let mut device_quota_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            } else if param.starts_with("io_weight=") {
+                io_weight_str = &param[10..];
+            } else if param.starts_with("vcpu_weight=") {
+                vcpu_weight_str = &param[12..];
+            } else if param.starts_with("vcpu_quota=") {
+                vcpu_quota_str = &param[11..];
+            } else if param.starts_with("device_weight=") {
+                device_weight_str = &param[14..];
+            } else if param.starts_with("device_quota=") {
+                device_quota_str = &param[13..];
+            }
+        }
+
+        if path_str.is_empty() {
+            return Err(Error::ParseCgroupPathParam);
+        }
+
+        fn weight(weight_str: &str) -> Result<Option<u16>> {
+            if weight_str.is_empty() {
+                return Ok(None);
+            }
+            weight_str
+                .parse()
+                .map(Some)
+                .map_err(Error::ParseCgroupWeightParam)
+        }
+        fn quota(quota_str: &str) -> Result<Option<u32>> {
+            if quota_str.is_empty() {
+                return Ok(None);
+            }
+            quota_str
+                .parse()
+                .map(Some)
+                .map_err(Error::ParseCgroupQuotaParam)
+        }
+        fn cpus(cpus_str: Option<&str>) -> Result<Option<Vec<usize>>> {
+            let cpus_str = match cpus_str {
+                Some(cpus_str) => cpus_str,
+                None => return Ok(None),
+            };
+            if !cpus_str.starts_with('[') || !cpus_str.ends_with(']') {
+                return Err(Error::ParseCgroupCpusParam(cpus_str));
+            }
+            cpus_str[1..cpus_str.len() - 1]
+                .split(',')
+                .map(|host_cpu| host_cpu.parse())
+                .collect::<result::Result<Vec<usize>, _>>()
+                .map(Some)
+                .map_err(|_| Error::ParseCgroupCpusParam(cpus_str))
+        }
+
+        let config = CgroupConfig {
+            path: PathBuf::from(path_str),
+            io_weight: weight(io_weight_str)?,
+            vcpus: CgroupThreadsConfig {
+                weight: weight(vcpu_weight_str)?,
+                quota: quota(vcpu_quota_str)?,
+                cpus: cpus(vcpu_cpus_str)?,
+            },
+            devices: CgroupThreadsConfig {
+                weight: weight(device_weight_str)?,
+                quota: quota(device_quota_str)?,
+                cpus: cpus(device_cpus_str)?,
+            },
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate<'a>(&self) -> Result<'a, ()> {
+        if !self.path.is_absolute() {
+            return Err(Error::ValidateCgroupPath(self.path.display().to_string()));
+        }
+        if let Some(io_weight) = self.io_weight {
+            if io_weight == 0 || io_weight > MAX_CGROUP_WEIGHT {
+                return Err(Error::ValidateCgroupWeight(io_weight));
+            }
+        }
+        self.vcpus.validate()?;
+        self.devices.validate()
+    }
+}
+
+/// virtio-gpu device, with a single display of `width` by `height` pixels.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct GpuConfig {
+    #[serde(default = "default_gpu_width")]
+    pub width: u32,
+    #[serde(default = "default_gpu_height")]
+    pub height: u32,
+    #[serde(default)]
+    pub iommu: bool,
+}
+
+fn default_gpu_width() -> u32 {
+    DEFAULT_GPU_WIDTH
+}
+
+fn default_gpu_height() -> u32 {
+    DEFAULT_GPU_HEIGHT
+}
+
+impl GpuConfig {
+    pub fn parse(gpu: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = gpu.split(',').collect();
+
+        let mut width_str: &str = "";
+        let mut height_str: &str = "";
+        let mut iommu_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("width=") {
+                width_str = &param[6..];
+            } else if param.starts_with("height=") {
+                height_str = &param[7..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            }
+        }
+
+        let mut width = DEFAULT_GPU_WIDTH;
+        if !width_str.is_empty() {
+            width = width_str.parse().map_err(Error::ParseGpuWidthParam)?;
+        }
+        let mut height = DEFAULT_GPU_HEIGHT;
+        if !height_str.is_empty() {
+            height = height_str.parse().map_err(Error::ParseGpuHeightParam)?;
+        }
+
+        let config = GpuConfig {
+            width,
+            height,
+            iommu: parse_iommu(iommu_str)?,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate<'a>(&self) -> Result<'a, ()> {
+        if self.width == 0
+            || self.height == 0
+            || self.width > MAX_GPU_RESOLUTION
+            || self.height > MAX_GPU_RESOLUTION
+        {
+            return Err(Error::ValidateGpuResolution(self.width, self.height));
+        }
+
+        Ok(())
+    }
+}
+
+/// virtio-input keyboard and tablet, fed with the events the clients of the
+/// UNIX socket at `socket` send.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct InputConfig {
+    pub socket: PathBuf,
+    #[serde(default)]
+    pub iommu: bool,
+}
+
+impl InputConfig {
+    pub fn parse(input: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = input.split(',').collect();
+
+        let mut socket_str: &str = "";
+        let mut iommu_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("socket=") {
+                socket_str = &param[7..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            }
+        }
+
+        if socket_str.is_empty() {
+            return Err(Error::ParseInputSocketParam);
+        }
+
+        Ok(InputConfig {
+            socket: PathBuf::from(socket_str),
+            iommu: parse_iommu(iommu_str)?,
+        })
+    }
+}
+
+/// Block cache the read-only disks of the VMs of the process are read
+/// through, when they are cached, holding up to `size` bytes of their
+/// blocks.
+pub struct BlockCacheConfig {
+    pub size: u64,
+}
+
+impl BlockCacheConfig {
+    pub fn parse(block_cache: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = block_cache.split(',').collect();
+
+        let mut size_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("size=") {
+                size_str = &param[5..];
+            }
+        }
+
+        let size = if size_str.is_empty() {
+            DEFAULT_BLOCK_CACHE_SIZE
+        } else {
+            parse_size(size_str)?
+        };
+
+        Ok(BlockCacheConfig { size })
+    }
+}
+
+/// Format the trace of the phases of the VMM is written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    /// The events of the phases, as JSON objects of their own.
+    Json,
+    /// The Trace Event Format chrome://tracing and Perfetto load.
+    Chrome,
+}
+
+/// Tracing of the phases of the VMM, the trace being written to `path`
+/// once the VM booted.
+#[derive(Clone, Debug)]
+pub struct TracingConfig {
+    pub path: PathBuf,
+    pub format: TraceFormat,
+}
+
+impl TracingConfig {
+    pub fn parse(tracing: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = tracing.split(',').collect();
+
+        let mut path_str: &str = "";
+        let mut format_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            } else if param.starts_with("format=") {
+                format_str = &param[7..];
+            }
+        }
+
+        let format = match format_str {
+            "json" | "" => TraceFormat::Json,
+            "chrome" => TraceFormat::Chrome,
+            _ => return Err(Error::ParseTracingFormatParam),
+        };
+        let path = if path_str.is_empty() {
+            PathBuf::from(format!("cloud-hypervisor-{}.trace", std::process::id()))
+        } else {
+            PathBuf::from(path_str)
+        };
+
+        Ok(TracingConfig { path, format })
+    }
+}
+
+/// Multi-tenant mode of the VMM, the VMs of the API clients being owned by
+/// the token they give. The file at `admin_token` holds the token of the
+/// operator, which reaches the process and all the VMs. The host files of
+/// the VMs of the other owners have to be under `tenant_dir`.
+#[derive(Clone, Debug, Default)]
+pub struct TenancyConfig {
+    pub admin_token: Option<PathBuf>,
+    pub tenant_dir: Option<PathBuf>,
+}
+
+impl TenancyConfig {
+    pub fn parse(tenancy: &str) -> Self {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = tenancy.split(',').collect();
+
+        let mut admin_token_str: &str = "";
+        let mut tenant_dir_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("admin_token=") {
+                admin_token_str = &param[12..];
+            } else if param.starts_with("tenant_dir=") {
+                tenant_dir_str = &param[11..];
+            }
+        }
+
+        let admin_token = if admin_token_str.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(admin_token_str))
+        };
+        let tenant_dir = if tenant_dir_str.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(tenant_dir_str))
+        };
+
+        TenancyConfig {
+            admin_token,
+            tenant_dir,
+        }
+    }
+}
+
+fn default_pool_port() -> u32 {
+    DEFAULT_POOL_PORT
+}
+
+/// Pool of `size` VMs the VMM boots ahead of time from a template, and hands
+/// out when they are claimed. The guest agent of a pool VM waits for its
+/// claim on the vsock `port`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PoolConfig {
+    pub size: usize,
+    #[serde(default = "default_pool_port")]
+    pub port: u32,
+}
+
+impl PoolConfig {
+    pub fn parse(pool: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = pool.split(',').collect();
+
+        let mut size_str: &str = "";
+        let mut port_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("port=") {
+                port_str = &param[5..];
+            }
+        }
+
+        let size = size_str
+            .parse::<usize>()
+            .map_err(Error::ParsePoolSizeParam)?;
+        let port = if port_str.is_empty() {
+            DEFAULT_POOL_PORT
+        } else {
+            port_str.parse::<u32>().map_err(Error::ParsePoolPortParam)?
+        };
+
+        Ok(PoolConfig { size, port })
+    }
+
+    /// Checks the pool against the template its VMs are booted from, along
+    /// with the template itself.
+    pub fn validate(&self, template: &VmConfig) -> result::Result<(), Vec<Error<'static>>> {
+        let mut errors = template.validate().err().unwrap_or_default();
+
+        if self.size == 0 {
+            errors.push(Error::ValidatePoolSize);
+        }
+        let hook_port = template.hooks.as_ref().map_or(false, |hooks| {
+            hooks.iter().any(|hook| hook.port == self.port)
+        });
+        let guest_os_port = template
+            .guest_os
+            .as_ref()
+            .map_or(false, |guest_os| guest_os.port == self.port);
+        if hook_port || guest_os_port {
+            errors.push(Error::ValidatePoolPort(self.port));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// What the VMM does with a VM whose guest panicked, besides reporting the
+/// panic.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum PanicAction {
+    /// The guest is left running, and reboots on its own, or not.
+    None,
+    /// The VM is paused, e.g. for a core dump to be written.
+    Pause,
+    /// The VM is rebooted.
+    Reboot,
+    /// The VM is shut down, and the VMM exits.
+    Shutdown,
+}
+
+impl PanicAction {
+    pub fn parse(action: &str) -> Result<Self> {
+        match action {
+            "" | "none" => Ok(PanicAction::None),
+            "pause" => Ok(PanicAction::Pause),
+            "reboot" => Ok(PanicAction::Reboot),
+            "shutdown" => Ok(PanicAction::Shutdown),
+            _ => Err(Error::ParsePvpanicActionParam),
+        }
+    }
+}
+
+impl Default for PanicAction {
+    fn default() -> Self {
+        PanicAction::None
+    }
+}
+
+/// pvpanic device, through which the guest reports its panics, and what is
+/// done with the VM when the guest panicked.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PvpanicConfig {
+    #[serde(default)]
+    pub action: PanicAction,
+}
+
+impl PvpanicConfig {
+    pub fn parse(pvpanic: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = pvpanic.split(',').collect();
+
+        let mut action_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("action=") {
+                action_str = &param[7..];
+            }
+        }
+
+        Ok(PvpanicConfig {
+            action: PanicAction::parse(action_str)?,
+        })
+    }
+}
+
+fn default_hook_port() -> u32 {
+    DEFAULT_HOOK_PORT
+}
+
+/// Host executable the guest can have run by requesting its `name` on the
+/// vsock `port`. The command is run without arguments, the guest only
+/// choosing which of the hooks of the VM is run.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HookConfig {
+    pub name: String,
+    pub command: PathBuf,
+    #[serde(default = "default_hook_port")]
+    pub port: u32,
+}
+
+impl HookConfig {
+    pub fn parse(hook: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = hook.split(',').collect();
+
+        let mut name_str: &str = "";
+        let mut command_str: &str = "";
+        let mut port_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("name=") {
+                name_str = &param[5..];
+            } else if param.starts_with("command=") {
+                command_str = &param[8..];
+            } else if param.starts_with("port=") {
+                port_str = &param[5..];
+            }
+        }
+
+        if name_str.is_empty()
+            || !name_str
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            return Err(Error::ParseHookNameParam);
+        }
+        if command_str.is_empty() {
+            return Err(Error::ParseHookCommandParam);
+        }
+        let port = if port_str.is_empty() {
+            DEFAULT_HOOK_PORT
+        } else {
+            port_str.parse::<u32>().map_err(Error::ParseHookPortParam)?
+        };
+
+        Ok(HookConfig {
+            name: name_str.to_string(),
+            command: PathBuf::from(command_str),
+            port,
+        })
+    }
+
+    fn validate<'a>(hooks: &[HookConfig], vsock: &Option<Vec<VsockConfig>>) -> Result<'a, ()> {
+        if !hooks.is_empty() && vsock.as_ref().map_or(true, |vsock| vsock.is_empty()) {
+            return Err(Error::ValidateHooksVsock);
+        }
+        for (index, hook) in hooks.iter().enumerate() {
+            if hooks[..index]
+                .iter()
+                .any(|other| other.name == hook.name && other.port == hook.port)
+            {
+                return Err(Error::ValidateHookName(hook.name.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Mandatory access control labels of the VM: the SELinux contexts the VMM
+/// and the files of the VM are given, or the AppArmor profile the VMM
+/// changes to.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SecurityConfig {
+    #[serde(default)]
+    pub selinux_process_label: Option<String>,
+    #[serde(default)]
+    pub selinux_file_label: Option<String>,
+    #[serde(default)]
+    pub apparmor_profile: Option<String>,
+}
+
+impl SecurityConfig {
+    pub fn parse(security: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter. The MCS
+        // categories of the SELinux contexts are separated by commas too,
+        // the pieces without a parameter name belonging to the previous
+        // parameter.
+        let mut params_list: Vec<String> = Vec::new();
+        for piece in security.split(',') {
+            match params_list.last_mut() {
+                Some(param) if !piece.contains('=') => {
+                    param.push(',');
+                    param.push_str(piece);
+                }
+                _ => params_list.push(piece.to_string()),
+            }
+        }
+
+        let mut selinux_process_label = None;
+        let mut selinux_file_label = None;
+        let mut apparmor_profile = None;
+
+        for param in params_list.iter() {
+            if param.starts_with("selinux_process_label=") {
+                selinux_process_label = Some(param[22..].to_string());
+            } else if param.starts_with("selinux_file_label=") {
+                selinux_file_label = Some(param[19..].to_string());
+            } else if param.starts_with("apparmor_profile=") {
+                apparmor_profile = Some(param[17..].to_string());
+            }
+        }
+
+        let config = SecurityConfig {
+            selinux_process_label,
+            selinux_file_label,
+            apparmor_profile,
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // A host has a single major security module.
+    pub fn validate<'a>(&self) -> Result<'a, ()> {
+        if (self.selinux_process_label.is_some() || self.selinux_file_label.is_some())
+            && self.apparmor_profile.is_some()
+        {
+            return Err(Error::ValidateSecurityModules);
+        }
+
+        Ok(())
+    }
+}
+
+/// Layout of the PCI bus. With `multifunction`, the virtio devices are
+/// packed 8 per device number, as the functions of multifunction devices.
+/// The guest gets `num_pci_segments` PCI segments, each with a host bridge
+/// of its own, the virtio devices sitting on the segment 0.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PciConfig {
+    #[serde(default)]
+    pub multifunction: bool,
+    #[serde(default = "PciConfig::default_num_pci_segments")]
+    pub num_pci_segments: u16,
+}
+
+impl PciConfig {
+    fn default_num_pci_segments() -> u16 {
+        1
+    }
+
+    pub fn parse(pci: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = pci.split(',').collect();
+
+        let mut multifunction_str: &str = "";
+        let mut num_pci_segments_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("multifunction=") {
+                multifunction_str = &param[14..];
+            } else if param.starts_with("num_pci_segments=") {
+                num_pci_segments_str = &param[17..];
+            }
+        }
+
+        let multifunction = match multifunction_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParsePciMultifunctionParam),
+        };
+
+        let mut num_pci_segments = PciConfig::default_num_pci_segments();
+        if !num_pci_segments_str.is_empty() {
+            num_pci_segments = num_pci_segments_str
+                .parse()
+                .map_err(Error::ParsePciNumSegmentsParam)?;
+        }
+        if num_pci_segments == 0 || num_pci_segments > MAX_PCI_SEGMENTS {
+            return Err(Error::ValidatePciNumSegments(num_pci_segments));
+        }
+
+        Ok(PciConfig {
+            multifunction,
+            num_pci_segments,
+        })
+    }
+
+    // The devices are to be on one of the segments of the VM, and only the
+    // segment 0 is behind the virtual IOMMU.
+    fn validate_segment<'a>(&self, pci_segment: u16, iommu: bool) -> Result<'a, ()> {
+        if pci_segment >= self.num_pci_segments {
+            return Err(Error::ValidatePciSegment(pci_segment));
+        }
+        if iommu && pci_segment != 0 {
+            return Err(Error::ValidatePciSegmentIommu(pci_segment));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for PciConfig {
+    fn default() -> Self {
+        PciConfig {
+            multifunction: false,
+            num_pci_segments: PciConfig::default_num_pci_segments(),
+        }
+    }
+}
+
+fn parse_pci_segment(pci_segment: &str) -> Result<u16> {
+    if pci_segment.is_empty() {
+        return Ok(0);
+    }
+
+    pci_segment.parse().map_err(Error::ParsePciSegmentParam)
+}
+
+/// Sizes of the MMIO apertures of a PCI segment other than the segment 0,
+/// out of which the BARs of its devices are allocated. The segment 0 gets
+/// what the other segments leave of the MMIO address space.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PciSegmentConfig {
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub mmio32_aperture_size: Option<u64>,
+    #[serde(default)]
+    pub mmio64_aperture_size: Option<u64>,
+}
+
+impl PciSegmentConfig {
+    pub fn parse(pci_segment: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = pci_segment.split(',').collect();
+
+        let mut pci_segment_str: &str = "";
+        let mut mmio32_aperture_size_str: &str = "";
+        let mut mmio64_aperture_size_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("pci_segment=") {
+                pci_segment_str = &param[12..];
+            } else if param.starts_with("mmio32_aperture_size=") {
+                mmio32_aperture_size_str = &param[21..];
+            } else if param.starts_with("mmio64_aperture_size=") {
+                mmio64_aperture_size_str = &param[21..];
+            }
+        }
+
+        if pci_segment_str.is_empty() {
+            return Err(Error::ParsePciSegmentApertureParam(pci_segment));
+        }
+
+        let mut mmio32_aperture_size = None;
+        if !mmio32_aperture_size_str.is_empty() {
+            mmio32_aperture_size = Some(parse_size(mmio32_aperture_size_str)?);
+        }
+        let mut mmio64_aperture_size = None;
+        if !mmio64_aperture_size_str.is_empty() {
+            mmio64_aperture_size = Some(parse_size(mmio64_aperture_size_str)?);
+        }
+
+        Ok(PciSegmentConfig {
+            pci_segment: parse_pci_segment(pci_segment_str)?,
+            mmio32_aperture_size,
+            mmio64_aperture_size,
+        })
+    }
+
+    fn validate<'a>(pci_segments: &[PciSegmentConfig], pci: &PciConfig) -> Result<'a, ()> {
+        let mut configured = Vec::new();
+        for pci_segment in pci_segments.iter().map(|config| config.pci_segment) {
+            if pci_segment == 0
+                || pci_segment >= pci.num_pci_segments
+                || configured.contains(&pci_segment)
+            {
+                return Err(Error::ValidatePciSegmentAperture(pci_segment));
+            }
+            configured.push(pci_segment);
+        }
+
+        Ok(())
+    }
+}
+
+/// Detection of the guest being idle, all its vCPUs halted, for at least
+/// `timeout` seconds. The vCPU threads of an idle guest are parked if `park`
+/// is set, and its memory is paged out once it has been idle for `hibernate`
+/// seconds, if set.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IdleConfig {
+    #[serde(default = "IdleConfig::default_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub park: bool,
+    #[serde(default)]
+    pub hibernate: Option<u64>,
+}
+
+impl IdleConfig {
+    fn default_timeout() -> u64 {
+        DEFAULT_IDLE_TIMEOUT
+    }
+
+    pub fn parse(idle: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = idle.split(',').collect();
+
+        let mut timeout_str: &str = "";
+        let mut park_str: &str = "";
+        let mut hibernate_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("timeout=") {
+                timeout_str = &param[8..];
+            } else if param.starts_with("park=") {
+                park_str = &param[5..];
+            } else if param.starts_with("hibernate=") {
+                hibernate_str = &param[10..];
+            }
+        }
+
+        let timeout = if timeout_str.is_empty() {
+            DEFAULT_IDLE_TIMEOUT
+        } else {
+            timeout_str
+                .parse::<u64>()
+                .map_err(Error::ParseIdleTimeoutParam)?
+        };
+        let park = match park_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseIdleParkParam),
+        };
+        let hibernate = if hibernate_str.is_empty() {
+            None
+        } else {
+            Some(
+                hibernate_str
+                    .parse::<u64>()
+                    .map_err(Error::ParseIdleHibernateParam)?,
+            )
+        };
+
+        Ok(IdleConfig {
+            timeout,
+            park,
+            hibernate,
+        })
+    }
+}
+
+/// How long the hypervisor polls the halted vCPUs for a wakeup at most,
+/// before blocking them, in nanoseconds. Polling cuts the wakeup latency of
+/// interactive guests, at the cost of host CPU time. With `adaptive`, the
+/// VMM lowers or raises the polling, up to `max_ns`, depending on how often
+/// the wakeups come while polling.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HaltPollConfig {
+    #[serde(default = "HaltPollConfig::default_max_ns")]
+    pub max_ns: u32,
+    #[serde(default)]
+    pub adaptive: bool,
+}
+
+impl HaltPollConfig {
+    fn default_max_ns() -> u32 {
+        DEFAULT_HALT_POLL_MAX_NS
+    }
+
+    pub fn parse(halt_poll: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = halt_poll.split(',').collect();
+
+        let mut max_ns_str: &str = "";
+        let mut adaptive_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("max_ns=") {
+                max_ns_str = &param[7..];
+            } else if param.starts_with("adaptive=") {
+                adaptive_str = &param[9..];
+            }
+        }
+
+        let max_ns = if max_ns_str.is_empty() {
+            DEFAULT_HALT_POLL_MAX_NS
+        } else {
+            max_ns_str
+                .parse::<u32>()
+                .map_err(Error::ParseHaltPollMaxParam)?
+        };
+        let adaptive = match adaptive_str {
+            "on" => true,
+            "off" | "" => false,
+            _ => return Err(Error::ParseHaltPollAdaptiveParam),
+        };
+
+        Ok(HaltPollConfig { max_ns, adaptive })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FsConfig {
+    pub tag: String,
+    pub sock: PathBuf,
+    pub num_queues: usize,
+    pub queue_size: u16,
+    pub cache_size: Option<u64>,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+impl FsConfig {
+    pub fn parse(fs: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = fs.split(',').collect();
+
+        let mut tag: &str = "";
+        let mut sock: &str = "";
+        let mut num_queues_str: &str = "";
+        let mut queue_size_str: &str = "";
+        let mut dax_str: &str = "";
+        let mut cache_size_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("tag=") {
+                tag = &param[4..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("sock=") {
+                sock = &param[5..];
+            } else if param.starts_with("num_queues=") {
+                num_queues_str = &param[11..];
+            } else if param.starts_with("queue_size=") {
+                queue_size_str = &param[11..];
+            } else if param.starts_with("dax=") {
+                dax_str = &param[4..];
+            } else if param.starts_with("cache_size=") {
+                cache_size_str = &param[11..];
+            }
+        }
+
+        let mut num_queues: usize = 1;
+        let mut queue_size: u16 = 1024;
+        let mut dax: bool = true;
+        // Default cache size set to 8Gib.
+        let mut cache_size: Option<u64> = Some(0x0002_0000_0000);
+
+        if tag.is_empty() {
+            return Err(Error::ParseFsTagParam);
+        }
+        if sock.is_empty() {
+            return Err(Error::ParseFsSockParam);
+        }
+        if !num_queues_str.is_empty() {
+            num_queues = num_queues_str
+                .parse()
+                .map_err(Error::ParseFsNumQueuesParam)?;
+        }
+        if !queue_size_str.is_empty() {
+            queue_size = queue_size_str
+                .parse()
+                .map_err(Error::ParseFsQueueSizeParam)?;
+        }
+        if !dax_str.is_empty() {
+            match dax_str {
+                "on" => dax = true,
+                "off" => dax = false,
+                _ => return Err(Error::ParseFsDax),
+            }
+        }
+
+        // Take appropriate decision about cache_size based on DAX being
+        // enabled or disabled.
+        if !dax {
+            if !cache_size_str.is_empty() {
+                return Err(Error::InvalidCacheSizeWithDaxOff);
+            }
+            cache_size = None;
+        } else if !cache_size_str.is_empty() {
+            cache_size = Some(parse_size(cache_size_str)?);
+        }
+
+        Ok(FsConfig {
+            tag: tag.to_string(),
+            sock: PathBuf::from(sock),
+            num_queues,
+            queue_size,
+            cache_size,
+            id: parse_device_id(id_str),
+        })
+    }
+}
+
+/// With `fd`, the backing file is the one an API client passed under that
+/// name, which `file` then only names.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PmemConfig {
+    pub file: PathBuf,
+    #[serde(default)]
+    pub fd: Option<String>,
+    pub size: u64,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+impl PmemConfig {
+    pub fn parse(pmem: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = pmem.split(',').collect();
+
+        let mut file_str: &str = "";
+        let mut size_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("file=") {
+                file_str = &param[5..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            }
+        }
+
+        if file_str.is_empty() {
+            return Err(Error::ParsePmemFileParam);
+        }
+
+        Ok(PmemConfig {
+            file: PathBuf::from(file_str),
+            fd: None,
+            size: parse_size(size_str)?,
+            iommu: parse_iommu(iommu_str)?,
+            id: parse_device_id(id_str),
+        })
+    }
+}
+
+/// Smallest label area of an NVDIMM, as the guest kernels expect at least
+/// 128 KiB of namespace labels.
+pub const MIN_NVDIMM_LABEL_SIZE: u64 = 128 << 10;
+
+/// ACPI NVDIMM backed by `file`, of `size` bytes. The last `label_size`
+/// bytes of the file hold the namespace labels of the NVDIMM, which the
+/// guest manages through the _DSM methods rather than maps, the guest
+/// getting an NVDIMM without labels when it is zero.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NvdimmConfig {
+    pub file: PathBuf,
+    pub size: u64,
+    #[serde(default)]
+    pub label_size: u64,
+}
+
+impl NvdimmConfig {
+    pub fn parse(nvdimm: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = nvdimm.split(',').collect();
+
+        let mut file_str: &str = "";
+        let mut size_str: &str = "";
+        let mut label_size_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("file=") {
+                file_str = &param[5..];
+            } else if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("label_size=") {
+                label_size_str = &param[11..];
+            }
+        }
+
+        if file_str.is_empty() {
+            return Err(Error::ParseNvdimmFileParam);
+        }
+
+        let label_size = if label_size_str.is_empty() {
+            0
+        } else {
+            parse_size(label_size_str)?
+        };
+
+        Ok(NvdimmConfig {
+            file: PathBuf::from(file_str),
+            size: parse_size(size_str)?,
+            label_size,
+        })
+    }
+
+    /// Size of the range the guest maps, the label area excluded.
+    pub fn mapped_size(&self) -> u64 {
+        self.size - self.label_size
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for &size in [self.size, self.label_size].iter() {
+            if size % 0x1000 != 0 {
+                return Err(Error::ValidateNvdimmSize(size));
+            }
+        }
+        if self.label_size != 0
+            && (self.label_size < MIN_NVDIMM_LABEL_SIZE || self.label_size >= self.size)
+        {
+            return Err(Error::ValidateNvdimmLabelSize(self.label_size));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub enum ConsoleOutputMode {
+    Off,
+    Tty,
+    File,
+    Null,
+    Pty,
+    Socket,
+}
+
+impl ConsoleOutputMode {
+    pub fn input_enabled(&self) -> bool {
+        match self {
+            ConsoleOutputMode::Tty => true,
+            _ => false,
+        }
+    }
+}
+
+// Parses the output a console or serial port is backed by, along with the
+// file or socket path it takes.
+fn parse_console_output(param: &str) -> Option<(ConsoleOutputMode, Option<PathBuf>)> {
+    if param == "off" {
+        Some((ConsoleOutputMode::Off, None))
+    } else if param == "tty" {
+        Some((ConsoleOutputMode::Tty, None))
+    } else if param.starts_with("file=") {
+        Some((ConsoleOutputMode::File, Some(PathBuf::from(&param[5..]))))
+    } else if param.starts_with("null") {
+        Some((ConsoleOutputMode::Null, None))
+    } else if param == "pty" {
+        Some((ConsoleOutputMode::Pty, None))
+    } else if param.starts_with("socket=") {
+        Some((ConsoleOutputMode::Socket, Some(PathBuf::from(&param[7..]))))
+    } else {
+        None
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ConsoleConfig {
+    pub file: Option<PathBuf>,
+    pub mode: ConsoleOutputMode,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: u16,
+}
+
+impl ConsoleConfig {
+    pub fn parse(console: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = console.split(',').collect();
+
+        let mut valid = false;
+        let mut file: Option<PathBuf> = None;
+        let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
+        let mut iommu_str: &str = "";
+        let mut queue_size_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("queue_size=") {
+                queue_size_str = &param[11..];
+            } else {
+                let (output_mode, output_file) =
+                    parse_console_output(param).ok_or(Error::ParseConsoleParam)?;
+                mode = output_mode;
+                file = output_file;
+                valid = true;
+            }
+        }
+
+        if !valid {
+            return Err(Error::ParseConsoleParam);
+        }
+
+        Ok(Self {
+            mode,
+            file,
+            iommu: parse_iommu(iommu_str)?,
+            queue_size: parse_queue_size(queue_size_str)?,
+        })
+    }
+
+    pub fn default_serial() -> Self {
+        ConsoleConfig {
+            file: None,
+            mode: ConsoleOutputMode::Null,
+            iommu: false,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+
+    pub fn default_console() -> Self {
+        ConsoleConfig {
+            file: None,
+            mode: ConsoleOutputMode::Tty,
+            iommu: false,
+            queue_size: DEFAULT_QUEUE_SIZE,
+        }
+    }
+}
+
+/// Additional 16550 UART, at the I/O port and IRQ of the next legacy COM
+/// port unless `port` and `irq` are set.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UartConfig {
+    pub file: Option<PathBuf>,
+    pub mode: ConsoleOutputMode,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub irq: Option<u8>,
+}
+
+impl UartConfig {
+    pub fn parse(uart: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = uart.split(',').collect();
+
+        let mut output: Option<(ConsoleOutputMode, Option<PathBuf>)> = None;
+        let mut port_str: &str = "";
+        let mut irq_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("port=") {
+                port_str = &param[5..];
+            } else if param.starts_with("irq=") {
+                irq_str = &param[4..];
+            } else {
+                output = Some(parse_console_output(param).ok_or(Error::ParseUartParam)?);
+            }
+        }
+
+        // The tty is left to the serial port and the virtio-console.
+        let (mode, file) = match output {
+            Some((ConsoleOutputMode::Off, _)) | Some((ConsoleOutputMode::Tty, _)) | None => {
+                return Err(Error::ParseUartParam)
+            }
+            Some(output) => output,
+        };
+
+        let port = if port_str.is_empty() {
+            None
+        } else if port_str.starts_with("0x") {
+            Some(u16::from_str_radix(&port_str[2..], 16).map_err(Error::ParseUartPortParam)?)
+        } else {
+            Some(port_str.parse().map_err(Error::ParseUartPortParam)?)
+        };
+
+        let irq = match irq_str {
+            "" => None,
+            "3" => Some(3),
+            "4" => Some(4),
+            _ => return Err(Error::ParseUartIrqParam(irq_str)),
+        };
+
+        Ok(UartConfig {
+            file,
+            mode,
+            port,
+            irq,
+        })
+    }
+
+    /// I/O port and IRQ of the UART, the `index`th one of the VM.
+    pub fn resources(&self, index: usize) -> (u16, u8) {
+        let (port, irq) = LEGACY_UARTS[index];
+        (self.port.unwrap_or(port), self.irq.unwrap_or(irq))
+    }
+}
+
+/// Additional port of the virtio-console, which the guest finds under
+/// `name`, backed by the UNIX socket `socket` one client at a time can
+/// connect to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ConsolePortConfig {
+    pub name: String,
+    pub socket: PathBuf,
+}
+
+impl ConsolePortConfig {
+    pub fn parse(console_port: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = console_port.split(',').collect();
+
+        let mut name_str: &str = "";
+        let mut socket_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("name=") {
+                name_str = &param[5..];
+            } else if param.starts_with("socket=") {
+                socket_str = &param[7..];
+            }
+        }
+
+        if name_str.is_empty()
+            || !name_str
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+        {
+            return Err(Error::ParseConsolePortNameParam);
+        }
+        if socket_str.is_empty() {
+            return Err(Error::ParseConsolePortSocketParam);
+        }
+
+        Ok(ConsolePortConfig {
+            name: name_str.to_string(),
+            socket: PathBuf::from(socket_str),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeviceConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub pci_segment: u16,
+    /// Option ROM the guest sees in place of the one of the device.
+    #[serde(default)]
+    pub romfile: Option<PathBuf>,
+    /// MAC address assigned to the device, an SR-IOV virtual function,
+    /// through its physical function.
+    #[serde(default)]
+    pub mac: Option<MacAddr>,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+impl DeviceConfig {
+    pub fn parse(device: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = device.split(',').collect();
+
+        let mut path_str: &str = "";
+        let mut mdev_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut pci_segment_str: &str = "";
+        let mut romfile_str: &str = "";
+        let mut mac_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("path=") {
+                path_str = &param[5..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("romfile=") {
+                romfile_str = &param[8..];
+            } else if param.starts_with("mac=") {
+                mac_str = &param[4..];
+            } else if param.starts_with("mdev=") {
+                mdev_str = &param[5..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("pci_segment=") {
+                pci_segment_str = &param[12..];
+            }
+        }
+
+        // Mediated devices are designated by their UUID.
+        let path = if mdev_str.is_empty() {
+            PathBuf::from(path_str)
+        } else if !path_str.is_empty() {
+            return Err(Error::ParseDevicePathAndMdev);
+        } else if !DeviceConfig::is_uuid(mdev_str) {
+            return Err(Error::ParseDeviceMdevParam(mdev_str));
+        } else {
+            PathBuf::from(MDEV_SYSFS_PATH).join(mdev_str)
+        };
+
+        let romfile = if romfile_str.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(romfile_str))
+        };
+
+        let mac = if mac_str.is_empty() {
+            None
+        } else {
+            Some(MacAddr::parse_str(mac_str).map_err(Error::ParseDeviceMacParam)?)
+        };
+
+        Ok(DeviceConfig {
+            path,
+            iommu: parse_iommu(iommu_str)?,
+            pci_segment: parse_pci_segment(pci_segment_str)?,
+            romfile,
+            mac,
+            id: parse_device_id(id_str),
+        })
+    }
+
+    // A UUID is written as 32 hexadecimal digits, in groups of 8, 4, 4, 4
+    // and 12 separated by dashes.
+    fn is_uuid(uuid: &str) -> bool {
+        let groups: Vec<&str> = uuid.split('-').collect();
+        groups.len() == 5
+            && groups
+                .iter()
+                .zip([8, 4, 4, 4, 12].iter())
+                .all(|(group, &len)| {
+                    group.len() == len && group.chars().all(|c| c.is_ascii_hexdigit())
+                })
+    }
+}
+
+/// PCI device emulated by another process, reached through the vfio-user
+/// server it runs on `socket`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserDeviceConfig {
+    pub socket: PathBuf,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+impl UserDeviceConfig {
+    pub fn parse(user_device: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = user_device.split(',').collect();
+
+        let mut socket_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut pci_segment_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("socket=") {
+                socket_str = &param[7..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("pci_segment=") {
+                pci_segment_str = &param[12..];
+            }
+        }
+
+        if socket_str.is_empty() {
+            return Err(Error::ParseUserDeviceSocketParam);
+        }
+
+        Ok(UserDeviceConfig {
+            socket: PathBuf::from(socket_str),
+            iommu: parse_iommu(iommu_str)?,
+            pci_segment: parse_pci_segment(pci_segment_str)?,
+            id: parse_device_id(id_str),
+        })
+    }
+}
+
+/// Device type out of the tree, which the executable at `path` emulates
+/// for the plugin devices of the `name` type, serving them over vfio-user
+/// on the socket it is handed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PluginConfig {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl PluginConfig {
+    pub fn parse(plugin: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = plugin.split(',').collect();
+
+        let mut name_str: &str = "";
+        let mut path_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("name=") {
+                name_str = &param[5..];
+            } else if param.starts_with("path=") {
+                path_str = &param[5..];
+            }
+        }
+
+        if name_str.is_empty()
+            || !name_str
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(Error::ParsePluginNameParam);
+        }
+        if path_str.is_empty() {
+            return Err(Error::ParsePluginPathParam);
+        }
+
+        Ok(PluginConfig {
+            name: name_str.to_string(),
+            path: PathBuf::from(path_str),
+        })
+    }
+
+    fn validate<'a>(
+        plugins: &[PluginConfig],
+        plugin_devices: &[PluginDeviceConfig],
+    ) -> Result<'a, ()> {
+        for (index, plugin) in plugins.iter().enumerate() {
+            if plugins[..index]
+                .iter()
+                .any(|other| other.name == plugin.name)
+            {
+                return Err(Error::ValidateDuplicatePlugin(plugin.name.clone()));
+            }
+        }
+        for plugin_device in plugin_devices.iter() {
+            if !plugins
+                .iter()
+                .any(|plugin| plugin.name == plugin_device.plugin)
+            {
+                return Err(Error::ValidatePluginDevice(plugin_device.plugin.clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// PCI device of the `plugin` type, its plugin being given `config` as is.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PluginDeviceConfig {
+    pub plugin: String,
+    #[serde(default)]
+    pub config: Option<String>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub pci_segment: u16,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+impl PluginDeviceConfig {
+    pub fn parse(plugin_device: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = plugin_device.split(',').collect();
+
+        let mut plugin_str: &str = "";
+        let mut config_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut pci_segment_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("plugin=") {
+                plugin_str = &param[7..];
+            } else if param.starts_with("config=") {
+                config_str = &param[7..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("pci_segment=") {
+                pci_segment_str = &param[12..];
+            }
+        }
+
+        if plugin_str.is_empty() {
+            return Err(Error::ParsePluginDevicePluginParam);
+        }
+        let config = if config_str.is_empty() {
+            None
+        } else {
+            Some(config_str.to_string())
+        };
+
+        Ok(PluginDeviceConfig {
+            plugin: plugin_str.to_string(),
+            config,
+            iommu: parse_iommu(iommu_str)?,
+            pci_segment: parse_pci_segment(pci_segment_str)?,
+            id: parse_device_id(id_str),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VuConfig {
+    pub sock: String,
+    pub num_queues: usize,
+    pub queue_size: u16,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct VhostUserNetConfig {
     pub mac: MacAddr,
     pub vu_cfg: VuConfig,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+impl VhostUserNetConfig {
+    pub fn parse(vhost_user_net: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = vhost_user_net.split(',').collect();
+
+        let mut mac_str: &str = "";
+        let mut sock: &str = "";
+        let mut num_queues_str: &str = "";
+        let mut queue_size_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("mac=") {
+                mac_str = &param[4..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("sock=") {
+                sock = &param[5..];
+            } else if param.starts_with("num_queues=") {
+                num_queues_str = &param[11..];
+            } else if param.starts_with("queue_size=") {
+                queue_size_str = &param[11..];
+            }
+        }
+
+        let mut mac: MacAddr = MacAddr::local_random();
+        let mut num_queues: usize = 2;
+        let mut queue_size: u16 = 256;
+
+        if !mac_str.is_empty() {
+            mac = MacAddr::parse_str(mac_str).map_err(Error::ParseVuNetMacParam)?;
+        }
+        if sock.is_empty() {
+            return Err(Error::ParseVuSockParam);
+        }
+        if !num_queues_str.is_empty() {
+            num_queues = num_queues_str
+                .parse()
+                .map_err(Error::ParseVuNumQueuesParam)?;
+        }
+        if !queue_size_str.is_empty() {
+            queue_size = queue_size_str
+                .parse()
+                .map_err(Error::ParseVuQueueSizeParam)?;
+        }
+
+        let vu_cfg = VuConfig {
+            sock: sock.to_string(),
+            num_queues,
+            queue_size,
+        };
+
+        Ok(VhostUserNetConfig {
+            mac,
+            vu_cfg,
+            id: parse_device_id(id_str),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VsockConfig {
+    pub cid: u64,
+    pub sock: PathBuf,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: u16,
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
+impl VsockConfig {
+    pub fn parse(vsock: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = vsock.split(',').collect();
+
+        let mut cid_str: &str = "";
+        let mut sock_str: &str = "";
+        let mut iommu_str: &str = "";
+        let mut queue_size_str: &str = "";
+        let mut id_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("cid=") {
+                cid_str = &param[4..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("sock=") {
+                sock_str = &param[5..];
+            } else if param.starts_with("iommu=") {
+                iommu_str = &param[6..];
+            } else if param.starts_with("queue_size=") {
+                queue_size_str = &param[11..];
+            }
+        }
+
+        if sock_str.is_empty() {
+            return Err(Error::ParseVsockSockParam);
+        }
+
+        Ok(VsockConfig {
+            cid: cid_str.parse::<u64>().map_err(Error::ParseVsockCidParam)?,
+            sock: PathBuf::from(sock_str),
+            iommu: parse_iommu(iommu_str)?,
+            queue_size: parse_queue_size(queue_size_str)?,
+            id: parse_device_id(id_str),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VhostUserBlkConfig {
+    pub wce: bool,
+    pub vu_cfg: VuConfig,
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
-impl VhostUserNetConfig {
-    pub fn parse(vhost_user_net: &str) -> Result<Self> {
+impl VhostUserBlkConfig {
+    pub fn parse(vhost_user_blk: &str) -> Result<Self> {
         // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = vhost_user_net.split(',').collect();
+        let params_list: Vec<&str> = vhost_user_blk.split(',').collect();
 
-        let mut mac_str: &str = "";
         let mut sock: &str = "";
         let mut num_queues_str: &str = "";
         let mut queue_size_str: &str = "";
+        let mut wce_str: &str = "";
+        let mut id_str: &str = "";
 
         for param in params_list.iter() {
-            if param.starts_with("mac=") {
-                mac_str = &param[4..];
-            } else if param.starts_with("sock=") {
+            if param.starts_with("sock=") {
                 sock = &param[5..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
             } else if param.starts_with("num_queues=") {
                 num_queues_str = &param[11..];
             } else if param.starts_with("queue_size=") {
                 queue_size_str = &param[11..];
+            } else if param.starts_with("wce=") {
+                wce_str = &param[4..];
             }
         }
 
-        let mut mac: MacAddr = MacAddr::local_random();
-        let mut num_queues: usize = 2;
-        let mut queue_size: u16 = 256;
+        let mut num_queues: usize = 1;
+        let mut queue_size: u16 = 128;
+        let mut wce: bool = true;
 
-        if !mac_str.is_empty() {
-            mac = MacAddr::parse_str(mac_str).map_err(Error::ParseVuNetMacParam)?;
-        }
-        if sock.is_empty() {
-            return Err(Error::ParseVuSockParam);
-        }
         if !num_queues_str.is_empty() {
             num_queues = num_queues_str
                 .parse()
@@ -652,6 +4343,9 @@ impl VhostUserNetConfig {
                 .parse()
                 .map_err(Error::ParseVuQueueSizeParam)?;
         }
+        if !wce_str.is_empty() {
+            wce = wce_str.parse().map_err(Error::ParseVuBlkWceParam)?;
+        }
 
         let vu_cfg = VuConfig {
             sock: sock.to_string(),
@@ -659,137 +4353,1153 @@ impl VhostUserNetConfig {
             queue_size,
         };
 
-        Ok(VhostUserNetConfig { mac, vu_cfg })
+        Ok(VhostUserBlkConfig {
+            wce,
+            vu_cfg,
+            id: parse_device_id(id_str),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NumaDistance {
+    pub destination: u32,
+    pub distance: u8,
+}
+
+/// A guest NUMA node, owning a zone of the guest RAM and some vCPUs. The
+/// memory zones are laid out in the order of the node ids.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NumaConfig {
+    pub id: u32,
+    pub size: u64,
+    #[serde(default)]
+    pub cpus: Option<Vec<u8>>,
+    #[serde(default)]
+    pub distances: Option<Vec<NumaDistance>>,
+    /// Host NUMA node the memory zone is bound to.
+    #[serde(default)]
+    pub host_node: Option<u32>,
+    /// PCI segments whose host bridges are local to the node.
+    #[serde(default)]
+    pub pci_segments: Option<Vec<u16>>,
+}
+
+impl NumaConfig {
+    pub fn parse(numa: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter. The list
+        // values are themselves colon separated.
+        let params_list: Vec<&str> = numa.split(',').collect();
+
+        let mut id_str: &str = "";
+        let mut size_str: &str = "";
+        let mut cpus_str: &str = "";
+        let mut distances_str: &str = "";
+        let mut host_node_str: &str = "";
+        let mut pci_segments_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("size=") {
+                size_str = &param[5..];
+            } else if param.starts_with("cpus=") {
+                cpus_str = &param[5..];
+            } else if param.starts_with("distances=") {
+                distances_str = &param[10..];
+            } else if param.starts_with("host_node=") {
+                host_node_str = &param[10..];
+            } else if param.starts_with("pci_segments=") {
+                pci_segments_str = &param[13..];
+            }
+        }
+
+        // "<first>-<last>" ranges and single vCPUs can be mixed.
+        let mut cpus = None;
+        if !cpus_str.is_empty() {
+            let mut cpus_list = Vec::new();
+            for item in cpus_str.split(':') {
+                let mut range = item.splitn(2, '-');
+                let first: u8 = range
+                    .next()
+                    .and_then(|first| first.parse().ok())
+                    .ok_or(Error::ParseNumaCpusParam(item))?;
+                let last: u8 = match range.next() {
+                    Some(last) => last.parse().map_err(|_| Error::ParseNumaCpusParam(item))?,
+                    None => first,
+                };
+                if last < first {
+                    return Err(Error::ParseNumaCpusParam(item));
+                }
+                cpus_list.extend(first..=last);
+            }
+            cpus = Some(cpus_list);
+        }
+
+        let mut distances = None;
+        if !distances_str.is_empty() {
+            let mut distances_list = Vec::new();
+            for item in distances_str.split(':') {
+                let mut fields = item.splitn(2, '@');
+                let destination = fields
+                    .next()
+                    .and_then(|destination| destination.parse().ok())
+                    .ok_or(Error::ParseNumaDistancesParam(item))?;
+                let distance = fields
+                    .next()
+                    .and_then(|distance| distance.parse().ok())
+                    .ok_or(Error::ParseNumaDistancesParam(item))?;
+                distances_list.push(NumaDistance {
+                    destination,
+                    distance,
+                });
+            }
+            distances = Some(distances_list);
+        }
+
+        let mut host_node = None;
+        if !host_node_str.is_empty() {
+            host_node = Some(
+                host_node_str
+                    .parse()
+                    .map_err(Error::ParseNumaHostNodeParam)?,
+            );
+        }
+
+        let mut pci_segments = None;
+        if !pci_segments_str.is_empty() {
+            let mut pci_segments_list = Vec::new();
+            for item in pci_segments_str.split(':') {
+                pci_segments_list.push(
+                    item.parse()
+                        .map_err(|_| Error::ParseNumaPciSegmentsParam(item))?,
+                );
+            }
+            pci_segments = Some(pci_segments_list);
+        }
+
+        Ok(NumaConfig {
+            id: id_str.parse().map_err(Error::ParseNumaIdParam)?,
+            size: parse_size(size_str)?,
+            cpus,
+            distances,
+            host_node,
+            pci_segments,
+        })
+    }
+
+    // The nodes are numbered from 0, so that they can be used as indexes
+    // into the ACPI SLIT matrix.
+    fn validate<'a>(
+        numa: &[NumaConfig],
+        cpus: &CpusConfig,
+        memory: &MemoryConfig,
+        pci: &PciConfig,
+    ) -> Result<'a, ()> {
+        let node_count = numa.len() as u32;
+        let mut ids: Vec<u32> = numa.iter().map(|node| node.id).collect();
+        ids.sort_unstable();
+        if ids != (0..node_count).collect::<Vec<u32>>() {
+            return Err(Error::ValidateNumaIds);
+        }
+
+        if numa.iter().map(|node| node.size).sum::<u64>() != memory.size {
+            return Err(Error::ValidateNumaMemorySize);
+        }
+
+        let mut assigned_cpus = Vec::new();
+        for &cpu in numa.iter().flat_map(|node| node.cpus.iter().flatten()) {
+            if cpu >= cpus.cpu_count || assigned_cpus.contains(&cpu) {
+                return Err(Error::ValidateNumaCpu(cpu));
+            }
+            assigned_cpus.push(cpu);
+        }
+
+        let mut assigned_pci_segments = Vec::new();
+        for &pci_segment in numa
+            .iter()
+            .flat_map(|node| node.pci_segments.iter().flatten())
+        {
+            if pci_segment >= pci.num_pci_segments || assigned_pci_segments.contains(&pci_segment) {
+                return Err(Error::ValidateNumaPciSegment(pci_segment));
+            }
+            assigned_pci_segments.push(pci_segment);
+        }
+
+        for node in numa.iter() {
+            for distance in node.distances.iter().flatten() {
+                if distance.destination >= node_count
+                    || distance.destination == node.id
+                    || distance.distance <= NUMA_LOCAL_DISTANCE
+                    || distance.distance == 0xff
+                {
+                    return Err(Error::ValidateNumaDistance(distance.destination));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Distance from this node to the destination one, as reported through
+    /// the ACPI SLIT.
+    pub fn distance(&self, destination: u32) -> u8 {
+        if destination == self.id {
+            return NUMA_LOCAL_DISTANCE;
+        }
+
+        self.distances
+            .iter()
+            .flatten()
+            .find(|distance| distance.destination == destination)
+            .map_or(NUMA_DEFAULT_REMOTE_DISTANCE, |distance| distance.distance)
+    }
+}
+
+/// Changes to the configuration of a running VM, see `VmConfig::changes()`.
+#[derive(Default)]
+pub struct ConfigChanges {
+    /// The vsock devices to hot-add.
+    pub vsock: Vec<VsockConfig>,
+    /// The vfio-user devices to hot-add.
+    pub user_devices: Vec<UserDeviceConfig>,
+    /// IDs of the devices to remove.
+    pub removed: Vec<String>,
+    /// New weights of the disks, by ID.
+    pub disk_weights: Vec<(String, u32)>,
+}
+
+impl ConfigChanges {
+    pub fn is_empty(&self) -> bool {
+        self.vsock.is_empty()
+            && self.user_devices.is_empty()
+            && self.removed.is_empty()
+            && self.disk_weights.is_empty()
+    }
+}
+
+/// New rate limiter of a virtio-blk or virtio-net device, by ID. The other
+/// fields of the device can't change while it runs.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RateLimiterUpdate {
+    pub id: String,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(flatten)]
+    pub immutable: BTreeMap<String, serde_json::Value>,
+}
+
+/// Fields of the configuration of a VM to change, which the VM takes without
+/// its devices being created anew, see `VmConfig::update()`. The other
+/// fields of the configuration can't change this way.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct VmUpdateConfig {
+    /// File the serial port writes to, or none.
+    #[serde(default)]
+    pub serial: Option<ConsoleConfig>,
+    #[serde(default)]
+    pub disks: Option<Vec<RateLimiterUpdate>>,
+    #[serde(default)]
+    pub net: Option<Vec<RateLimiterUpdate>>,
+    #[serde(flatten)]
+    pub immutable: BTreeMap<String, serde_json::Value>,
+}
+
+fn same_config<T: serde::Serialize>(a: &T, b: &T) -> bool {
+    serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+}
+
+// The devices of the desired list the current one doesn't have, by ID, are
+// added, the ones it only has removed, and the ones changed otherwise fixed.
+fn device_changes<T: Clone + serde::Serialize>(
+    field: &str,
+    current: &Option<Vec<T>>,
+    desired: &Option<Vec<T>>,
+    device_id: impl Fn(&T) -> &Option<String>,
+    added: &mut Vec<T>,
+    removed: &mut Vec<String>,
+    fixed: &mut Vec<String>,
+) {
+    let find = |devices: &Option<Vec<T>>, id: &Option<String>| -> Option<T> {
+        devices
+            .iter()
+            .flatten()
+            .find(|device| device_id(device) == id)
+            .cloned()
+    };
+
+    for device in current.iter().flatten() {
+        let id = device_id(device);
+        match find(desired, id) {
+            Some(desired_device) => {
+                if !same_config(device, &desired_device) {
+                    fixed.push(format!("{}[{}]", field, id.clone().unwrap_or_default()));
+                }
+            }
+            None => removed.push(id.clone().unwrap_or_default()),
+        }
+    }
+    for device in desired.iter().flatten() {
+        if find(current, device_id(device)).is_none() {
+            added.push(device.clone());
+        }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct VsockConfig {
-    pub cid: u64,
-    pub sock: PathBuf,
-    #[serde(default)]
-    pub iommu: bool,
-}
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmConfig {
+    #[serde(default)]
+    pub cpus: CpusConfig,
+    #[serde(default)]
+    pub memory: MemoryConfig,
+    pub kernel: Option<KernelConfig>,
+    pub initramfs: Option<InitramfsConfig>,
+    pub cmdline: CmdlineConfig,
+    pub disks: Option<Vec<DiskConfig>>,
+    #[serde(default)]
+    pub disk_groups: Option<Vec<DiskGroupConfig>>,
+    pub net: Option<Vec<NetConfig>>,
+    #[serde(default)]
+    pub rng: RngConfig,
+    pub fs: Option<Vec<FsConfig>>,
+    pub pmem: Option<Vec<PmemConfig>>,
+    #[serde(default)]
+    pub nvdimms: Option<Vec<NvdimmConfig>>,
+    #[serde(default = "ConsoleConfig::default_serial")]
+    pub serial: ConsoleConfig,
+    #[serde(default = "ConsoleConfig::default_console")]
+    pub console: ConsoleConfig,
+    pub devices: Option<Vec<DeviceConfig>>,
+    #[serde(default)]
+    pub user_devices: Option<Vec<UserDeviceConfig>>,
+    /// Device types out of the tree, and the devices of these types.
+    #[serde(default)]
+    pub plugins: Option<Vec<PluginConfig>>,
+    #[serde(default)]
+    pub plugin_devices: Option<Vec<PluginDeviceConfig>>,
+    #[serde(default)]
+    pub uarts: Option<Vec<UartConfig>>,
+    #[serde(default)]
+    pub console_ports: Option<Vec<ConsolePortConfig>>,
+    pub vhost_user_net: Option<Vec<VhostUserNetConfig>>,
+    pub vhost_user_blk: Option<Vec<VhostUserBlkConfig>>,
+    pub vsock: Option<Vec<VsockConfig>>,
+    pub numa: Option<Vec<NumaConfig>>,
+    #[serde(default)]
+    pub iommu: bool,
+    #[serde(default)]
+    pub profile: Profile,
+    /// The guest memory is encrypted (e.g. AMD SEV-ES/SNP), the virtio
+    /// devices can only access the buffers the guest explicitly shares.
+    #[serde(default)]
+    pub confidential_guest: bool,
+    /// Hardware the guest is isolated from the host with, if any.
+    #[serde(default)]
+    pub platform: Platform,
+    /// How the vCPUs are scheduled on the host.
+    #[serde(default)]
+    pub latency_profile: LatencyProfile,
+    #[serde(default)]
+    pub sensors: SensorsConfig,
+    pub idle: Option<IdleConfig>,
+    #[serde(default)]
+    pub halt_poll: Option<HaltPollConfig>,
+    pub acpi_tables: Option<Vec<AcpiTableConfig>>,
+    pub acpi_oem_tables: Option<Vec<AcpiOemTableConfig>>,
+    pub sgx_epc: Option<Vec<SgxEpcConfig>>,
+    /// Hostname of the guest, and DNS resolvers it uses, given to it on the
+    /// kernel command line and in an ACPI table.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub resolvers: Option<Vec<IpAddr>>,
+    #[serde(default)]
+    pub tpm: Option<TpmConfig>,
+    #[serde(default)]
+    pub pci: PciConfig,
+    #[serde(default)]
+    pub pci_segments: Option<Vec<PciSegmentConfig>>,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default)]
+    pub gdb: Option<GdbConfig>,
+    /// The guest gets a pvpanic device, through which it reports its panics
+    /// to the event monitor.
+    #[serde(default)]
+    pub pvpanic: Option<PvpanicConfig>,
+    /// Host executables the guest can have run through the first vsock
+    /// device.
+    #[serde(default)]
+    pub hooks: Option<Vec<HookConfig>>,
+    /// Diagnostic bundles are written to the directory when the guest
+    /// panics or triple faults.
+    #[serde(default)]
+    pub diagnostics: Option<DiagnosticsConfig>,
+    /// The guest OS is probed, and reported in the VM information.
+    #[serde(default)]
+    pub guest_os: Option<GuestOsConfig>,
+    /// The VMM confines its process with Landlock once the files of the VM
+    /// are opened.
+    #[serde(default)]
+    pub landlock: Option<LandlockConfig>,
+    /// The VMM drops its privileges to the user once the files of the VM are
+    /// opened.
+    #[serde(default)]
+    pub user: Option<UserConfig>,
+    /// The drift of the guest clock from the host clock is checked, and
+    /// reported to the event monitor past its threshold.
+    #[serde(default)]
+    pub clock_drift: Option<ClockDriftConfig>,
+    /// The guest reads the host clock through the PTP clock KVM provides,
+    /// which the host must support.
+    #[serde(default)]
+    pub ptp: bool,
+    /// The VMM process is moved into the cgroup, and its vCPU and device
+    /// threads into sub-groups of it.
+    #[serde(default)]
+    pub cgroup: Option<CgroupConfig>,
+    /// The guest gets a virtio-gpu device, whose display is read through
+    /// the API.
+    #[serde(default)]
+    pub gpu: Option<GpuConfig>,
+    /// The guest gets a virtio-input keyboard and tablet, fed through the
+    /// socket.
+    #[serde(default)]
+    pub input: Option<InputConfig>,
+}
+
+impl VmConfig {
+    pub fn valid(&self) -> bool {
+        self.kernel.is_some()
+    }
+
+    /// Whether the devices can only access the guest memory the guest
+    /// shares with them.
+    pub fn encrypted_memory(&self) -> bool {
+        self.confidential_guest || self.platform.is_confidential()
+    }
+
+    /// The first feature of the configuration a confidential guest doesn't
+    /// support, if any. Their memory can't be shared with devices mapping
+    /// it on their own, the virtio devices only accessing the buffers the
+    /// guest shares with them. On a confidential platform, it can't be given
+    /// to the guest later on either, and the firmware does the whole boot.
+    pub fn confidential_conflict(&self) -> Option<&'static str> {
+        if !self.encrypted_memory() {
+            return None;
+        }
+
+        // The vhost-user backends map the whole guest memory, and the
+        // virtio-pmem devices give the guest host memory of their own.
+        if self.pmem.is_some() {
+            return Some("virtio-pmem devices");
+        } else if self.fs.is_some() {
+            return Some("virtio-fs devices");
+        } else if self.vhost_user_net.is_some() {
+            return Some("vhost-user-net devices");
+        } else if self.vhost_user_blk.is_some() {
+            return Some("vhost-user-blk devices");
+        }
+        if !self.platform.is_confidential() {
+            return None;
+        }
+
+        if self.devices.is_some() {
+            Some("VFIO devices")
+        } else if self.user_devices.is_some() {
+            Some("vfio-user devices")
+        } else if self.plugin_devices.is_some() {
+            Some("plugin devices")
+        } else if self.nvdimms.is_some() {
+            Some("NVDIMMs")
+        } else if self.sgx_epc.is_some() {
+            Some("SGX EPC sections")
+        } else if self.initramfs.is_some() {
+            Some("initramfs")
+        } else if self.profile == Profile::Unikernel {
+            Some("unikernel profile")
+        } else if self.gdb.is_some() {
+            Some("GDB stub")
+        } else if let Some(feature) = self.cpus.enabled_features.iter().flatten().next() {
+            Some(feature.description())
+        } else if self.ptp {
+            Some("PTP clock")
+        } else if self.memory.prefault {
+            Some("memory prefault")
+        } else {
+            None
+        }
+    }
+
+    /// The first feature of the configuration the realtime latency profile
+    /// conflicts with, if any. Its vCPUs never halt in the hypervisor, so
+    /// they can't be seen idle, nor be polled when halted.
+    pub fn realtime_conflict(&self) -> Option<&'static str> {
+        if self.latency_profile != LatencyProfile::Realtime {
+            return None;
+        }
+
+        if self.idle.is_some() {
+            Some("idle detection")
+        } else if self.halt_poll.is_some() {
+            Some("halt polling")
+        } else {
+            None
+        }
+    }
+
+    /// The IDs of the devices which have one.
+    pub fn device_ids(&self) -> impl Iterator<Item = &String> {
+        let disks = self.disks.iter().flatten().map(|disk| &disk.id);
+        let net = self.net.iter().flatten().map(|net| &net.id);
+        let fs = self.fs.iter().flatten().map(|fs| &fs.id);
+        let pmem = self.pmem.iter().flatten().map(|pmem| &pmem.id);
+        let devices = self.devices.iter().flatten().map(|device| &device.id);
+        let user_devices = self
+            .user_devices
+            .iter()
+            .flatten()
+            .map(|user_device| &user_device.id);
+        let plugin_devices = self
+            .plugin_devices
+            .iter()
+            .flatten()
+            .map(|plugin_device| &plugin_device.id);
+        let vhost_user_net = self
+            .vhost_user_net
+            .iter()
+            .flatten()
+            .map(|vhost_user_net| &vhost_user_net.id);
+        let vhost_user_blk = self
+            .vhost_user_blk
+            .iter()
+            .flatten()
+            .map(|vhost_user_blk| &vhost_user_blk.id);
+        let vsock = self.vsock.iter().flatten().map(|vsock| &vsock.id);
+
+        disks
+            .chain(net)
+            .chain(fs)
+            .chain(pmem)
+            .chain(devices)
+            .chain(user_devices)
+            .chain(plugin_devices)
+            .chain(vhost_user_net)
+            .chain(vhost_user_blk)
+            .chain(vsock)
+            .filter_map(|id| id.as_ref())
+    }
+
+    /// Gives an ID to the devices without one: their type followed by the
+    /// first index no other device has, in the order the devices are
+    /// created, `block0` and `block1` for the first two virtio-blk disks for
+    /// instance. The IDs only depend on the configuration, and not on the
+    /// PCI slots of the devices, for the VM to keep them across reboots.
+    pub fn assign_device_ids(&mut self) {
+        let mut taken: Vec<String> = self.device_ids().cloned().collect();
+        let mut assign = |id: &mut Option<String>, prefix: &str| {
+            if id.is_none() {
+                let new_id = (0..)
+                    .map(|index| format!("{}{}", prefix, index))
+                    .find(|new_id| !taken.contains(new_id))
+                    .unwrap();
+                taken.push(new_id.clone());
+                *id = Some(new_id);
+            }
+        };
+
+        for disk in self.disks.iter_mut().flatten() {
+            if disk.model == DiskModel::Virtio {
+                assign(&mut disk.id, "block");
+            }
+        }
+        for net in self.net.iter_mut().flatten() {
+            if net.model == NetModel::Virtio {
+                assign(&mut net.id, "net");
+            }
+        }
+        for fs in self.fs.iter_mut().flatten() {
+            assign(&mut fs.id, "fs");
+        }
+        for pmem in self.pmem.iter_mut().flatten() {
+            assign(&mut pmem.id, "pmem");
+        }
+        for vhost_user_net in self.vhost_user_net.iter_mut().flatten() {
+            assign(&mut vhost_user_net.id, "net");
+        }
+        for vhost_user_blk in self.vhost_user_blk.iter_mut().flatten() {
+            assign(&mut vhost_user_blk.id, "block");
+        }
+        for vsock in self.vsock.iter_mut().flatten() {
+            assign(&mut vsock.id, "vsock");
+        }
+        for device in self.devices.iter_mut().flatten() {
+            assign(&mut device.id, "vfio");
+        }
+        for user_device in self.user_devices.iter_mut().flatten() {
+            assign(&mut user_device.id, "vfio_user");
+        }
+        for plugin_device in self.plugin_devices.iter_mut().flatten() {
+            assign(&mut plugin_device.id, &plugin_device.plugin);
+        }
+        // The e1000 interfaces and the AHCI disks are the ones left.
+        for net in self.net.iter_mut().flatten() {
+            assign(&mut net.id, "e1000_");
+        }
+        for disk in self.disks.iter_mut().flatten() {
+            assign(&mut disk.id, "sata");
+        }
+    }
+
+    /// Removes the vsock, vfio-user or removable disk device with the given
+    /// ID, the kinds of devices which can be hot-removed, returning whether
+    /// there was one.
+    pub fn remove_device(&mut self, id: &str) -> bool {
+        fn remove<T>(
+            devices: &mut Option<Vec<T>>,
+            id: &str,
+            device_id: impl Fn(&T) -> &Option<String>,
+        ) -> bool {
+            let list = match devices {
+                Some(list) => list,
+                None => return false,
+            };
+            let len = list.len();
+            list.retain(|device| device_id(device).as_ref().map_or(true, |other| other != id));
+            let removed = list.len() != len;
+            if list.is_empty() {
+                *devices = None;
+            }
+            removed
+        }
+
+        let removable_disk =
+            self.disks.iter().flatten().any(|disk| {
+                disk.removable && disk.id.as_ref().map_or(false, |disk_id| disk_id == id)
+            });
+
+        remove(&mut self.vsock, id, |vsock| &vsock.id)
+            || remove(&mut self.user_devices, id, |user_device| &user_device.id)
+            || (removable_disk && remove(&mut self.disks, id, |disk| &disk.id))
+    }
+
+    /// Changes the weight of the disk with the given ID.
+    pub fn set_disk_weight(&mut self, id: &str, weight: u32) {
+        for disk in self.disks.iter_mut().flatten() {
+            if disk.id.as_ref().map_or(false, |disk_id| disk_id == id) {
+                disk.weight = weight;
+            }
+        }
+    }
+
+    /// The changes from this configuration to the desired one, which the
+    /// running VM can take: vsock and vfio-user devices added or removed,
+    /// and disk weights. Returns the fields which changed otherwise, all of
+    /// them. The devices are told apart by their IDs, assigned in both.
+    pub fn changes(&self, desired: &VmConfig) -> result::Result<ConfigChanges, Vec<String>> {
+        let mut changes = ConfigChanges::default();
+        let mut fixed = Vec::new();
+
+        let current_fields = serde_json::to_value(self).unwrap_or(serde_json::Value::Null);
+        let desired_fields = serde_json::to_value(desired).unwrap_or(serde_json::Value::Null);
+        if let (Some(current_fields), Some(desired_fields)) =
+            (current_fields.as_object(), desired_fields.as_object())
+        {
+            for (field, value) in current_fields {
+                if !["disks", "vsock", "user_devices"].contains(&field.as_str())
+                    && desired_fields.get(field) != Some(value)
+                {
+                    fixed.push(field.clone());
+                }
+            }
+        }
+
+        let disks = |config: &VmConfig| -> BTreeMap<String, DiskConfig> {
+            config
+                .disks
+                .iter()
+                .flatten()
+                .map(|disk| (disk.id.clone().unwrap_or_default(), disk.clone()))
+                .collect()
+        };
+        let (current_disks, desired_disks) = (disks(self), disks(desired));
+        if current_disks.keys().ne(desired_disks.keys()) {
+            fixed.push("disks".to_string());
+        } else {
+            for (id, disk) in current_disks.iter() {
+                let mut desired_disk = desired_disks[id].clone();
+                let weight = desired_disk.weight;
+                desired_disk.weight = disk.weight;
+                if !same_config(disk, &desired_disk) {
+                    fixed.push(format!("disks[{}]", id));
+                } else if weight != disk.weight {
+                    changes.disk_weights.push((id.clone(), weight));
+                }
+            }
+        }
+
+        device_changes(
+            "vsock",
+            &self.vsock,
+            &desired.vsock,
+            |vsock| &vsock.id,
+            &mut changes.vsock,
+            &mut changes.removed,
+            &mut fixed,
+        );
+        device_changes(
+            "user_devices",
+            &self.user_devices,
+            &desired.user_devices,
+            |user_device| &user_device.id,
+            &mut changes.user_devices,
+            &mut changes.removed,
+            &mut fixed,
+        );
 
-impl VsockConfig {
-    pub fn parse(vsock: &str) -> Result<Self> {
-        // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = vsock.split(',').collect();
+        if fixed.is_empty() {
+            Ok(changes)
+        } else {
+            Err(fixed)
+        }
+    }
 
-        let mut cid_str: &str = "";
-        let mut sock_str: &str = "";
-        let mut iommu_str: &str = "";
+    /// The configuration with the update applied: the serial port switched
+    /// from a file, or from nowhere, to another file or to nowhere, and the
+    /// rate limiters of virtio-blk and virtio-net devices replaced. Returns
+    /// the fields of the update which can't change this way, all of them,
+    /// the devices which don't exist included.
+    pub fn update(&self, update: &VmUpdateConfig) -> result::Result<VmConfig, Vec<String>> {
+        let mut config = self.clone();
+        let mut fixed: Vec<String> = update.immutable.keys().cloned().collect();
 
-        for param in params_list.iter() {
-            if param.starts_with("cid=") {
-                cid_str = &param[4..];
-            } else if param.starts_with("sock=") {
-                sock_str = &param[5..];
-            } else if param.starts_with("iommu=") {
-                iommu_str = &param[6..];
+        if let Some(serial) = &update.serial {
+            let switchable = |mode: &ConsoleOutputMode| {
+                [ConsoleOutputMode::File, ConsoleOutputMode::Null].contains(mode)
+            };
+            if switchable(&self.serial.mode)
+                && switchable(&serial.mode)
+                && (serial.mode != ConsoleOutputMode::File || serial.file.is_some())
+                && serial.iommu == self.serial.iommu
+                && serial.queue_size == self.serial.queue_size
+            {
+                config.serial = serial.clone();
+            } else if !same_config(&self.serial, serial) {
+                fixed.push("serial".to_string());
             }
         }
 
-        if sock_str.is_empty() {
-            return Err(Error::ParseVsockSockParam);
+        for rate_limiter in update.disks.iter().flatten() {
+            let disk = config
+                .disks
+                .iter_mut()
+                .flatten()
+                .find(|disk| disk.id.as_ref() == Some(&rate_limiter.id));
+            match disk {
+                Some(disk) if rate_limiter.immutable.is_empty() => {
+                    disk.rate_limiter_config = rate_limiter.rate_limiter_config.clone();
+                }
+                _ => fixed.push(format!("disks[{}]", rate_limiter.id)),
+            }
+        }
+        for rate_limiter in update.net.iter().flatten() {
+            let net = config
+                .net
+                .iter_mut()
+                .flatten()
+                .find(|net| net.id.as_ref() == Some(&rate_limiter.id));
+            match net {
+                Some(net) if rate_limiter.immutable.is_empty() => {
+                    net.rate_limiter_config = rate_limiter.rate_limiter_config.clone();
+                }
+                _ => fixed.push(format!("net[{}]", rate_limiter.id)),
+            }
         }
 
-        Ok(VsockConfig {
-            cid: cid_str.parse::<u64>().map_err(Error::ParseVsockCidParam)?,
-            sock: PathBuf::from(sock_str),
-            iommu: parse_iommu(iommu_str)?,
-        })
+        if fixed.is_empty() {
+            Ok(config)
+        } else {
+            Err(fixed)
+        }
     }
-}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct VhostUserBlkConfig {
-    pub wce: bool,
-    pub vu_cfg: VuConfig,
-}
+    /// Checks the constraints between the fields of the configuration,
+    /// returning all the ones it doesn't meet. Parsing the command line
+    /// goes through it, and so does a configuration given through the API,
+    /// before the VM is created.
+    pub fn validate(&self) -> result::Result<(), Vec<Error<'static>>> {
+        let mut errors: Vec<Error<'static>> = Vec::new();
 
-impl VhostUserBlkConfig {
-    pub fn parse(vhost_user_blk: &str) -> Result<Self> {
-        // Split the parameters based on the comma delimiter
-        let params_list: Vec<&str> = vhost_user_blk.split(',').collect();
+        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
+        {
+            errors.push(Error::ParseTTYParam);
+        }
+        if let Some(console_ports) = &self.console_ports {
+            if self.console.mode == ConsoleOutputMode::Off {
+                errors.push(Error::ValidateConsolePorts);
+            }
+            for (index, port) in console_ports.iter().enumerate() {
+                if console_ports[..index]
+                    .iter()
+                    .any(|other| other.name == port.name)
+                {
+                    errors.push(Error::ValidateDuplicateConsolePort(port.name.clone()));
+                }
+            }
+        }
+        if let Some(uarts) = &self.uarts {
+            if uarts.len() > LEGACY_UARTS.len() {
+                errors.push(Error::ValidateUartCount(uarts.len()));
+            }
+        }
 
-        let mut sock: &str = "";
-        let mut num_queues_str: &str = "";
-        let mut queue_size_str: &str = "";
-        let mut wce_str: &str = "";
+        if let Some(pci_segments) = &self.pci_segments {
+            errors.extend(PciSegmentConfig::validate(pci_segments, &self.pci).err());
+        }
+        for device in self.devices.iter().flatten() {
+            errors.extend(
+                self.pci
+                    .validate_segment(device.pci_segment, device.iommu)
+                    .err(),
+            );
+        }
+        for user_device in self.user_devices.iter().flatten() {
+            errors.extend(
+                self.pci
+                    .validate_segment(user_device.pci_segment, user_device.iommu)
+                    .err(),
+            );
+        }
+        let plugins = self.plugins.as_ref().map_or(&[][..], Vec::as_slice);
+        let plugin_devices = self.plugin_devices.as_ref().map_or(&[][..], Vec::as_slice);
+        errors.extend(PluginConfig::validate(plugins, plugin_devices).err());
+        for plugin_device in plugin_devices.iter() {
+            errors.extend(
+                self.pci
+                    .validate_segment(plugin_device.pci_segment, plugin_device.iommu)
+                    .err(),
+            );
+        }
+        if let Some(numa) = &self.numa {
+            errors.extend(NumaConfig::validate(numa, &self.cpus, &self.memory, &self.pci).err());
+        }
+        errors.extend(self.memory.validate().err());
+        for nvdimm in self.nvdimms.iter().flatten() {
+            errors.extend(nvdimm.validate().err());
+        }
 
-        for param in params_list.iter() {
-            if param.starts_with("sock=") {
-                sock = &param[5..];
-            } else if param.starts_with("num_queues=") {
-                num_queues_str = &param[11..];
-            } else if param.starts_with("queue_size=") {
-                queue_size_str = &param[11..];
-            } else if param.starts_with("wce=") {
-                wce_str = &param[4..];
+        if self.profile == Profile::Unikernel {
+            if self.cpus.cpu_count != 1 {
+                errors.push(Error::ValidateUnikernelCpus);
+            }
+            if !cfg!(feature = "mmio_support") {
+                errors.push(Error::ValidateUnikernelMmio);
+            }
+        }
+        if self.profile == Profile::Windows
+            && !(cfg!(target_arch = "x86_64") && cfg!(feature = "acpi") && cfg!(feature = "cmos"))
+        {
+            errors.push(Error::ValidateWindowsDevices);
+        }
+        errors.extend(self.security.validate().err());
+        if let Some(cgroup) = &self.cgroup {
+            errors.extend(cgroup.validate().err());
+        }
+        if let Some(gpu) = &self.gpu {
+            errors.extend(gpu.validate().err());
+        }
+        if let Some(hooks) = &self.hooks {
+            errors.extend(HookConfig::validate(hooks, &self.vsock).err());
+            if let Some(guest_os) = &self.guest_os {
+                if hooks.iter().any(|hook| hook.port == guest_os.port) {
+                    errors.push(Error::ValidateGuestOsPort(guest_os.port));
+                }
             }
         }
 
-        let mut num_queues: usize = 1;
-        let mut queue_size: u16 = 128;
-        let mut wce: bool = true;
+        // The backends of the vhost-user devices map the guest memory.
+        let vhost_user =
+            self.vhost_user_net.is_some() || self.vhost_user_blk.is_some() || self.fs.is_some();
+        if vhost_user && !self.memory.shared() {
+            errors.push(Error::ValidateVhostUserSharedMemory);
+        }
+        let vcpus = usize::from(self.cpus.cpu_count);
+        for net in self.net.iter().flatten() {
+            errors.extend(net.validate().err());
+            if net.queue_pairs() > vcpus {
+                errors.push(Error::ValidateDeviceQueueCount(net.queue_pairs() * 2));
+            }
+        }
+        for vhost_user_net in self.vhost_user_net.iter().flatten() {
+            let num_queues = vhost_user_net.vu_cfg.num_queues;
+            if num_queues % 2 != 0 {
+                errors.push(Error::ValidateVhostUserNetQueues(num_queues));
+            } else if num_queues == 0 || num_queues / 2 > vcpus {
+                errors.push(Error::ValidateDeviceQueueCount(num_queues));
+            }
+        }
+        let num_queues = self
+            .vhost_user_blk
+            .iter()
+            .flatten()
+            .map(|vhost_user_blk| vhost_user_blk.vu_cfg.num_queues)
+            .chain(self.fs.iter().flatten().map(|fs| fs.num_queues));
+        for num_queues in num_queues {
+            if num_queues == 0 || num_queues > vcpus {
+                errors.push(Error::ValidateDeviceQueueCount(num_queues));
+            }
+        }
 
-        if !num_queues_str.is_empty() {
-            num_queues = num_queues_str
-                .parse()
-                .map_err(Error::ParseVuNumQueuesParam)?;
+        let disks = self
+            .disks
+            .iter()
+            .flatten()
+            .filter(|disk| disk.fd.is_none())
+            .map(|disk| disk.path.display().to_string());
+        let vfio_devices = self
+            .devices
+            .iter()
+            .flatten()
+            .map(|device| device.path.display().to_string());
+        let sockets = self
+            .user_devices
+            .iter()
+            .flatten()
+            .map(|user_device| user_device.socket.display().to_string())
+            .chain(
+                self.vhost_user_net
+                    .iter()
+                    .flatten()
+                    .map(|vhost_user_net| vhost_user_net.vu_cfg.sock.clone()),
+            )
+            .chain(
+                self.vhost_user_blk
+                    .iter()
+                    .flatten()
+                    .map(|vhost_user_blk| vhost_user_blk.vu_cfg.sock.clone()),
+            )
+            .chain(
+                self.fs
+                    .iter()
+                    .flatten()
+                    .map(|fs| fs.sock.display().to_string()),
+            )
+            .chain(
+                self.vsock
+                    .iter()
+                    .flatten()
+                    .map(|vsock| vsock.sock.display().to_string()),
+            )
+            .chain(
+                self.console_ports
+                    .iter()
+                    .flatten()
+                    .map(|port| port.socket.display().to_string()),
+            );
+        let macs = self
+            .net
+            .iter()
+            .flatten()
+            .map(|net| net.mac)
+            .chain(
+                self.vhost_user_net
+                    .iter()
+                    .flatten()
+                    .map(|vhost_user_net| vhost_user_net.mac),
+            )
+            .map(|mac| mac.to_string());
+        let fs_tags = self.fs.iter().flatten().map(|fs| fs.tag.clone());
+        let tap_fds = self
+            .net
+            .iter()
+            .flatten()
+            .flat_map(|net| net.fds.iter().flatten())
+            .map(|fd| fd.to_string());
+        let macvtaps = self
+            .net
+            .iter()
+            .flatten()
+            .filter_map(|net| net.macvtap.clone());
+        for id in self.device_ids() {
+            if !valid_device_id(id) {
+                errors.push(Error::ValidateDeviceId(id.clone()));
+            }
         }
-        if !queue_size_str.is_empty() {
-            queue_size = queue_size_str
-                .parse()
-                .map_err(Error::ParseVuQueueSizeParam)?;
+        let ids = self.device_ids().cloned();
+        for (kind, values) in [
+            ("disk image", disks.collect::<Vec<String>>()),
+            ("VFIO device", vfio_devices.collect()),
+            ("socket", sockets.collect()),
+            ("MAC address", macs.collect()),
+            ("virtio-fs tag", fs_tags.collect()),
+            ("tap file descriptor", tap_fds.collect()),
+            ("macvtap interface", macvtaps.collect()),
+            ("ID", ids.collect()),
+        ]
+        .iter()
+        {
+            for (index, value) in values.iter().enumerate() {
+                if values[..index].contains(value) {
+                    errors.push(Error::ValidateDuplicateDevice(*kind, value.clone()));
+                }
+            }
         }
-        if !wce_str.is_empty() {
-            wce = wce_str.parse().map_err(Error::ParseVuBlkWceParam)?;
+
+        let disk_groups: Vec<&str> = self
+            .disk_groups
+            .iter()
+            .flatten()
+            .map(|group| group.id.as_str())
+            .collect();
+        for (index, id) in disk_groups.iter().enumerate() {
+            if disk_groups[..index].contains(id) {
+                errors.push(Error::ValidateDuplicateDiskGroup(id.to_string()));
+            }
+        }
+        for disk in self.disks.iter().flatten() {
+            if let Some(group) = &disk.group {
+                if !disk_groups.contains(&group.as_str()) || disk.model != DiskModel::Virtio {
+                    errors.push(Error::ValidateDiskGroup(group.clone()));
+                }
+            }
+            if disk.cache && (!disk.readonly || disk.model != DiskModel::Virtio) {
+                errors.push(Error::ValidateDiskCache(
+                    disk.path.to_string_lossy().into_owned(),
+                ));
+            }
+            if disk.cache && disk.direct_io() {
+                errors.push(Error::ValidateDiskDirectCache(
+                    disk.path.to_string_lossy().into_owned(),
+                ));
+            }
+            if disk.cache_mode == DiskCacheMode::Unsafe && disk.model != DiskModel::Virtio {
+                errors.push(Error::ValidateDiskCacheMode(
+                    disk.path.to_string_lossy().into_owned(),
+                ));
+            }
+            if disk.removable
+                && (disk.model != DiskModel::Virtio
+                    || disk.group.is_some()
+                    || self.pci.multifunction)
+            {
+                errors.push(Error::ValidateDiskRemovable(
+                    disk.path.to_string_lossy().into_owned(),
+                ));
+            }
         }
 
-        let vu_cfg = VuConfig {
-            sock: sock.to_string(),
-            num_queues,
-            queue_size,
-        };
+        if let Some(feature) = self.confidential_conflict() {
+            errors.push(Error::ValidateConfidentialFeature(feature));
+        }
+        if let Some(feature) = self.realtime_conflict() {
+            errors.push(Error::ValidateRealtimeFeature(feature));
+        }
 
-        Ok(VhostUserBlkConfig { wce, vu_cfg })
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
-}
 
-#[derive(Clone, Deserialize, Serialize)]
-pub struct VmConfig {
-    #[serde(default)]
-    pub cpus: CpusConfig,
-    #[serde(default)]
-    pub memory: MemoryConfig,
-    pub kernel: Option<KernelConfig>,
-    pub cmdline: CmdlineConfig,
-    pub disks: Option<Vec<DiskConfig>>,
-    pub net: Option<Vec<NetConfig>>,
-    #[serde(default)]
-    pub rng: RngConfig,
-    pub fs: Option<Vec<FsConfig>>,
-    pub pmem: Option<Vec<PmemConfig>>,
-    #[serde(default = "ConsoleConfig::default_serial")]
-    pub serial: ConsoleConfig,
-    #[serde(default = "ConsoleConfig::default_console")]
-    pub console: ConsoleConfig,
-    pub devices: Option<Vec<DeviceConfig>>,
-    pub vhost_user_net: Option<Vec<VhostUserNetConfig>>,
-    pub vhost_user_blk: Option<Vec<VhostUserBlkConfig>>,
-    pub vsock: Option<Vec<VsockConfig>>,
-    #[serde(default)]
-    pub iommu: bool,
-}
+    pub fn parse(vm_params: VmParams) -> Result<Self> {
+        let config = VmConfig::parse_params(vm_params)?;
+        config.validate().map_err(Error::Validation)?;
 
-impl VmConfig {
-    pub fn valid(&self) -> bool {
-        self.kernel.is_some()
+        Ok(config)
     }
 
-    pub fn parse(vm_params: VmParams) -> Result<Self> {
+    /// Reads the configuration from the JSON or TOML file at `path`, TOML
+    /// being picked by the `.toml` extension, the options of the command
+    /// line `given` tells were set overriding the fields of the file.
+    pub fn parse_with_file<'a>(
+        vm_params: VmParams<'a>,
+        path: &Path,
+        given: &dyn Fn(&str) -> bool,
+    ) -> Result<'a, Self> {
+        let content = std::fs::read_to_string(path).map_err(Error::ReadConfigFile)?;
+        let mut config: VmConfig = if path.extension().map_or(false, |ext| ext == "toml") {
+            toml::from_str(&content).map_err(Error::ParseTomlConfigFile)?
+        } else {
+            serde_json::from_str(&content).map_err(Error::ParseJsonConfigFile)?
+        };
+
+        let cli = VmConfig::parse_params(vm_params)?;
+        macro_rules! override_fields {
+            ($($option:expr => $field:ident),* $(,)?) => {
+                $(
+                    if given($option) {
+                        config.$field = cli.$field;
+                    }
+                )*
+            };
+        }
+        override_fields!(
+            "cpus" => cpus,
+            "kernel" => kernel,
+            "initramfs" => initramfs,
+            "cmdline" => cmdline,
+            "disk" => disks,
+            "disk-group" => disk_groups,
+            "net" => net,
+            "rng" => rng,
+            "fs" => fs,
+            "pmem" => pmem,
+            "nvdimm" => nvdimms,
+            "serial" => serial,
+            "console" => console,
+            "device" => devices,
+            "user-device" => user_devices,
+            "plugin" => plugins,
+            "plugin-device" => plugin_devices,
+            "uart" => uarts,
+            "console-port" => console_ports,
+            "vhost-user-net" => vhost_user_net,
+            "vhost-user-blk" => vhost_user_blk,
+            "vsock" => vsock,
+            "numa" => numa,
+            "profile" => profile,
+            "confidential-guest" => confidential_guest,
+            "platform" => platform,
+            "latency-profile" => latency_profile,
+            "sensors" => sensors,
+            "idle" => idle,
+            "halt-poll" => halt_poll,
+            "acpi-table" => acpi_tables,
+            "acpi-oem-table" => acpi_oem_tables,
+            "sgx-epc" => sgx_epc,
+            "hostname" => hostname,
+            "resolvers" => resolvers,
+            "tpm" => tpm,
+            "pci" => pci,
+            "pci-segment" => pci_segments,
+            "security" => security,
+            "gdb" => gdb,
+            "pvpanic" => pvpanic,
+            "hook" => hooks,
+            "diagnostics" => diagnostics,
+            "guest-os" => guest_os,
+            "landlock" => landlock,
+            "user" => user,
+            "clock-drift" => clock_drift,
+            "ptp" => ptp,
+            "cgroup" => cgroup,
+            "gpu" => gpu,
+            "input" => input,
+        );
+        // The size of the guest RAM is the sum of the sizes of its zones,
+        // when given.
+        if given("memory") || given("memory-zone") {
+            config.memory = cli.memory;
+        }
+        config.iommu |= cli.iommu;
+
+        config.validate().map_err(Error::Validation)?;
+
+        Ok(config)
+    }
+
+    fn parse_params(vm_params: VmParams) -> Result<Self> {
         let mut iommu = false;
 
         let mut disks: Option<Vec<DiskConfig>> = None;
@@ -805,6 +5515,15 @@ impl VmConfig {
             disks = Some(disk_config_list);
         }
 
+        let mut disk_groups: Option<Vec<DiskGroupConfig>> = None;
+        if let Some(disk_group_list) = &vm_params.disk_groups {
+            let mut disk_group_config_list = Vec::new();
+            for item in disk_group_list.iter() {
+                disk_group_config_list.push(DiskGroupConfig::parse(item)?);
+            }
+            disk_groups = Some(disk_group_config_list);
+        }
+
         let mut net: Option<Vec<NetConfig>> = None;
         if let Some(net_list) = &vm_params.net {
             let mut net_config_list = Vec::new();
@@ -845,13 +5564,51 @@ impl VmConfig {
             pmem = Some(pmem_config_list);
         }
 
+        let mut nvdimms: Option<Vec<NvdimmConfig>> = None;
+        if let Some(nvdimm_list) = &vm_params.nvdimms {
+            let mut nvdimm_config_list = Vec::new();
+            for item in nvdimm_list.iter() {
+                nvdimm_config_list.push(NvdimmConfig::parse(item)?);
+            }
+            nvdimms = Some(nvdimm_config_list);
+        }
+
         let console = ConsoleConfig::parse(vm_params.console)?;
         if console.iommu {
             iommu = true;
         }
         let serial = ConsoleConfig::parse(vm_params.serial)?;
-        if console.mode == ConsoleOutputMode::Tty && serial.mode == ConsoleOutputMode::Tty {
-            return Err(Error::ParseTTYParam);
+
+        let mut uarts: Option<Vec<UartConfig>> = None;
+        if let Some(uart_list) = &vm_params.uarts {
+            let mut uart_config_list = Vec::new();
+            for item in uart_list.iter() {
+                uart_config_list.push(UartConfig::parse(item)?);
+            }
+            uarts = Some(uart_config_list);
+        }
+
+        let mut console_ports: Option<Vec<ConsolePortConfig>> = None;
+        if let Some(console_port_list) = &vm_params.console_ports {
+            let mut console_port_config_list = Vec::new();
+            for item in console_port_list.iter() {
+                console_port_config_list.push(ConsolePortConfig::parse(item)?);
+            }
+            console_ports = Some(console_port_config_list);
+        }
+
+        let pci = match vm_params.pci {
+            Some(pci) => PciConfig::parse(pci)?,
+            None => PciConfig::default(),
+        };
+
+        let mut pci_segments: Option<Vec<PciSegmentConfig>> = None;
+        if let Some(pci_segment_list) = &vm_params.pci_segments {
+            let mut pci_segment_config_list = Vec::new();
+            for item in pci_segment_list.iter() {
+                pci_segment_config_list.push(PciSegmentConfig::parse(item)?);
+            }
+            pci_segments = Some(pci_segment_config_list);
         }
 
         let mut devices: Option<Vec<DeviceConfig>> = None;
@@ -867,6 +5624,41 @@ impl VmConfig {
             devices = Some(device_config_list);
         }
 
+        let mut user_devices: Option<Vec<UserDeviceConfig>> = None;
+        if let Some(user_device_list) = &vm_params.user_devices {
+            let mut user_device_config_list = Vec::new();
+            for item in user_device_list.iter() {
+                let user_device_config = UserDeviceConfig::parse(item)?;
+                if user_device_config.iommu {
+                    iommu = true;
+                }
+                user_device_config_list.push(user_device_config);
+            }
+            user_devices = Some(user_device_config_list);
+        }
+
+        let mut plugins: Option<Vec<PluginConfig>> = None;
+        if let Some(plugin_list) = &vm_params.plugins {
+            let mut plugin_config_list = Vec::new();
+            for item in plugin_list.iter() {
+                plugin_config_list.push(PluginConfig::parse(item)?);
+            }
+            plugins = Some(plugin_config_list);
+        }
+
+        let mut plugin_devices: Option<Vec<PluginDeviceConfig>> = None;
+        if let Some(plugin_device_list) = &vm_params.plugin_devices {
+            let mut plugin_device_config_list = Vec::new();
+            for item in plugin_device_list.iter() {
+                let plugin_device_config = PluginDeviceConfig::parse(item)?;
+                if plugin_device_config.iommu {
+                    iommu = true;
+                }
+                plugin_device_config_list.push(plugin_device_config);
+            }
+            plugin_devices = Some(plugin_device_config_list);
+        }
+
         let mut vhost_user_net: Option<Vec<VhostUserNetConfig>> = None;
         if let Some(vhost_user_net_list) = &vm_params.vhost_user_net {
             let mut vhost_user_net_config_list = Vec::new();
@@ -905,23 +5697,223 @@ impl VmConfig {
             });
         }
 
-        Ok(VmConfig {
-            cpus: CpusConfig::parse(vm_params.cpus)?,
-            memory: MemoryConfig::parse(vm_params.memory)?,
+        let mut initramfs: Option<InitramfsConfig> = None;
+        if let Some(k) = vm_params.initramfs {
+            initramfs = Some(InitramfsConfig {
+                path: PathBuf::from(k),
+            });
+        }
+
+        let cpus = CpusConfig::parse(vm_params.cpus)?;
+        let mut memory = MemoryConfig::parse(vm_params.memory)?;
+        if let Some(memory_zone_list) = &vm_params.memory_zones {
+            let mut memory_zone_config_list = Vec::new();
+            for item in memory_zone_list.iter() {
+                memory_zone_config_list.push(MemoryZoneConfig::parse(item)?);
+            }
+            memory.size = memory_zone_config_list.iter().map(|zone| zone.size).sum();
+            memory.zones = Some(memory_zone_config_list);
+        }
+
+        let mut numa: Option<Vec<NumaConfig>> = None;
+        if let Some(numa_list) = &vm_params.numa {
+            let mut numa_config_list = Vec::new();
+            for item in numa_list.iter() {
+                numa_config_list.push(NumaConfig::parse(item)?);
+            }
+            numa = Some(numa_config_list);
+        }
+
+        let profile = Profile::parse(vm_params.profile)?;
+
+        let sensors = match vm_params.sensors {
+            Some(sensors) => SensorsConfig::parse(sensors)?,
+            None => SensorsConfig::default(),
+        };
+
+        let security = match vm_params.security {
+            Some(security) => SecurityConfig::parse(security)?,
+            None => SecurityConfig::default(),
+        };
+
+        let mut halt_poll: Option<HaltPollConfig> = None;
+        if let Some(halt_poll_params) = vm_params.halt_poll {
+            halt_poll = Some(HaltPollConfig::parse(halt_poll_params)?);
+        }
+
+        let mut idle: Option<IdleConfig> = None;
+        if let Some(idle_params) = vm_params.idle {
+            idle = Some(IdleConfig::parse(idle_params)?);
+        }
+
+        let mut acpi_tables: Option<Vec<AcpiTableConfig>> = None;
+        if let Some(acpi_table_list) = &vm_params.acpi_tables {
+            let mut acpi_table_config_list = Vec::new();
+            for item in acpi_table_list.iter() {
+                acpi_table_config_list.push(AcpiTableConfig::parse(item)?);
+            }
+            acpi_tables = Some(acpi_table_config_list);
+        }
+
+        let mut acpi_oem_tables: Option<Vec<AcpiOemTableConfig>> = None;
+        if let Some(acpi_oem_table_list) = &vm_params.acpi_oem_tables {
+            let mut acpi_oem_table_config_list = Vec::new();
+            for item in acpi_oem_table_list.iter() {
+                acpi_oem_table_config_list.push(AcpiOemTableConfig::parse(item)?);
+            }
+            acpi_oem_tables = Some(acpi_oem_table_config_list);
+        }
+
+        let mut sgx_epc: Option<Vec<SgxEpcConfig>> = None;
+        if let Some(sgx_epc_list) = &vm_params.sgx_epc {
+            let mut sgx_epc_config_list = Vec::new();
+            for item in sgx_epc_list.iter() {
+                sgx_epc_config_list.push(SgxEpcConfig::parse(item)?);
+            }
+            sgx_epc = Some(sgx_epc_config_list);
+        }
+
+        let mut hostname = None;
+        if let Some(hostname_param) = vm_params.hostname {
+            if !valid_hostname(hostname_param) {
+                return Err(Error::ParseHostnameParam(hostname_param));
+            }
+            hostname = Some(hostname_param.to_string());
+        }
+
+        let resolvers = match vm_params.resolvers {
+            Some(resolvers) => Some(parse_resolvers(resolvers)?),
+            None => None,
+        };
+
+        let mut tpm: Option<TpmConfig> = None;
+        if let Some(tpm_params) = vm_params.tpm {
+            tpm = Some(TpmConfig::parse(tpm_params)?);
+        }
+
+        let mut gdb: Option<GdbConfig> = None;
+        if let Some(gdb_params) = vm_params.gdb {
+            gdb = Some(GdbConfig::parse(gdb_params)?);
+        }
+
+        let mut pvpanic: Option<PvpanicConfig> = None;
+        if let Some(pvpanic_params) = vm_params.pvpanic {
+            pvpanic = Some(PvpanicConfig::parse(pvpanic_params)?);
+        }
+
+        let mut diagnostics: Option<DiagnosticsConfig> = None;
+        if let Some(diagnostics_params) = vm_params.diagnostics {
+            diagnostics = Some(DiagnosticsConfig::parse(diagnostics_params)?);
+        }
+
+        let mut guest_os: Option<GuestOsConfig> = None;
+        if let Some(guest_os_params) = vm_params.guest_os {
+            guest_os = Some(GuestOsConfig::parse(guest_os_params)?);
+        }
+
+        let mut hooks: Option<Vec<HookConfig>> = None;
+        if let Some(hook_list) = &vm_params.hooks {
+            let mut hook_config_list = Vec::new();
+            for item in hook_list.iter() {
+                hook_config_list.push(HookConfig::parse(item)?);
+            }
+            hooks = Some(hook_config_list);
+        }
+
+        let mut landlock: Option<LandlockConfig> = None;
+        if let Some(landlock_rules) = &vm_params.landlock {
+            landlock = Some(LandlockConfig::parse(landlock_rules)?);
+        }
+
+        let mut user: Option<UserConfig> = None;
+        if let Some(user_params) = vm_params.user {
+            user = Some(UserConfig::parse(user_params)?);
+        }
+
+        let mut clock_drift: Option<ClockDriftConfig> = None;
+        if let Some(clock_drift_params) = vm_params.clock_drift {
+            clock_drift = Some(ClockDriftConfig::parse(clock_drift_params)?);
+        }
+
+        let mut cgroup: Option<CgroupConfig> = None;
+        if let Some(cgroup_params) = vm_params.cgroup {
+            cgroup = Some(CgroupConfig::parse(cgroup_params)?);
+        }
+
+        let mut gpu: Option<GpuConfig> = None;
+        if let Some(gpu_params) = vm_params.gpu {
+            let gpu_config = GpuConfig::parse(gpu_params)?;
+            if gpu_config.iommu {
+                iommu = true;
+            }
+            gpu = Some(gpu_config);
+        }
+
+        let mut input: Option<InputConfig> = None;
+        if let Some(input_params) = vm_params.input {
+            let input_config = InputConfig::parse(input_params)?;
+            if input_config.iommu {
+                iommu = true;
+            }
+            input = Some(input_config);
+        }
+
+        let config = VmConfig {
+            cpus,
+            memory,
             kernel,
+            initramfs,
             cmdline: CmdlineConfig::parse(vm_params.cmdline)?,
             disks,
+            disk_groups,
             net,
             rng,
             fs,
             pmem,
+            nvdimms,
             serial,
             console,
             devices,
+            user_devices,
+            plugins,
+            plugin_devices,
+            uarts,
+            console_ports,
             vhost_user_net,
             vhost_user_blk,
             vsock,
+            numa,
             iommu,
-        })
+            profile,
+            confidential_guest: vm_params.confidential_guest,
+            platform: Platform::parse(vm_params.platform)?,
+            latency_profile: LatencyProfile::parse(vm_params.latency_profile)?,
+            sensors,
+            idle,
+            halt_poll,
+            acpi_tables,
+            acpi_oem_tables,
+            sgx_epc,
+            hostname,
+            resolvers,
+            tpm,
+            pci,
+            pci_segments,
+            security,
+            gdb,
+            pvpanic,
+            hooks,
+            diagnostics,
+            guest_os,
+            landlock,
+            user,
+            clock_drift,
+            ptp: vm_params.ptp,
+            cgroup,
+            gpu,
+            input,
+        };
+
+        Ok(config)
     }
 }
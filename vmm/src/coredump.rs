@@ -0,0 +1,279 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! ELF core dump of a paused VM, laid out like the dumps of physical
+//! machines that `crash` and `gdb` read: one `PT_LOAD` segment per guest RAM
+//! region, at its guest physical address, and one `NT_PRSTATUS` note per
+//! vCPU, holding its registers. As with the QEMU dumps, each vCPU also has a
+//! `QEMU` note holding its control registers and descriptor tables, which
+//! `crash` finds the kernel page tables and KASLR offset of the guest with.
+
+use hypervisor::x86_64::{SegmentRegister, SpecialRegisters, StandardRegisters};
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+use vm_memory::{Address, Bytes, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+// From <elf.h>
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 7;
+const NT_PRSTATUS: u32 = 1;
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+const NOTE_NAME_SIZE: u32 = 5;
+const QEMU_NOTE_NAME: &[u8; 8] = b"QEMU\0\0\0\0";
+const QEMU_NOTE_TYPE: u32 = 0;
+
+// Layout of the version 1 `QEMUCPUState` of QEMU, up to the control
+// registers.
+const QEMU_CPU_STATE_VERSION: u32 = 1;
+const QEMU_CPU_STATE_SIZE: usize = 432;
+
+// Layout of the x86_64 `struct elf_prstatus`, whose registers are a
+// `struct user_regs_struct`.
+const PRSTATUS_SIZE: usize = 336;
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+const SEGMENT_ALIGNMENT: u64 = 0x1000;
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn elf_header(buf: &mut Vec<u8>, phnum: u16) {
+    buf.extend_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    buf.resize(16, 0);
+    push_u16(buf, ET_CORE);
+    push_u16(buf, EM_X86_64);
+    push_u32(buf, u32::from(EV_CURRENT));
+    // No entry point, nor section headers.
+    push_u64(buf, 0);
+    push_u64(buf, ELF_HEADER_SIZE);
+    push_u64(buf, 0);
+    push_u32(buf, 0);
+    push_u16(buf, ELF_HEADER_SIZE as u16);
+    push_u16(buf, PROGRAM_HEADER_SIZE as u16);
+    push_u16(buf, phnum);
+    push_u16(buf, 0);
+    push_u16(buf, 0);
+    push_u16(buf, 0);
+}
+
+fn program_header(buf: &mut Vec<u8>, p_type: u32, offset: u64, addr: u64, size: u64) {
+    let (flags, align) = if p_type == PT_LOAD {
+        (PF_RWX, SEGMENT_ALIGNMENT)
+    } else {
+        (0, 1)
+    };
+
+    push_u32(buf, p_type);
+    push_u32(buf, flags);
+    push_u64(buf, offset);
+    // Virtual and physical addresses are both the guest physical one.
+    push_u64(buf, addr);
+    push_u64(buf, addr);
+    push_u64(buf, size);
+    push_u64(buf, size);
+    push_u64(buf, align);
+}
+
+fn prstatus_note(buf: &mut Vec<u8>, id: u8, regs: &StandardRegisters, sregs: &SpecialRegisters) {
+    push_u32(buf, NOTE_NAME_SIZE);
+    push_u32(buf, PRSTATUS_SIZE as u32);
+    push_u32(buf, NT_PRSTATUS);
+    buf.extend_from_slice(NOTE_NAME);
+
+    let mut prstatus = vec![0u8; PRSTATUS_SIZE];
+    // Debuggers number the threads after their pid, which can't be 0.
+    prstatus[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4]
+        .copy_from_slice(&(u32::from(id) + 1).to_le_bytes());
+
+    let user_regs = [
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        // orig_rax, not in a system call.
+        u64::max_value(),
+        regs.rip,
+        u64::from(sregs.cs.selector),
+        regs.rflags,
+        regs.rsp,
+        u64::from(sregs.ss.selector),
+        sregs.fs.base,
+        sregs.gs.base,
+        u64::from(sregs.ds.selector),
+        u64::from(sregs.es.selector),
+        u64::from(sregs.fs.selector),
+        u64::from(sregs.gs.selector),
+    ];
+    for (i, value) in user_regs.iter().enumerate() {
+        let offset = PRSTATUS_REGS_OFFSET + i * 8;
+        prstatus[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    buf.extend_from_slice(&prstatus);
+}
+
+// A `QEMUCPUSegment`, whose flags are the attributes of the segment
+// descriptor, as they are in its second double word.
+fn push_segment(buf: &mut Vec<u8>, selector: u16, limit: u32, flags: u32, base: u64) {
+    push_u32(buf, u32::from(selector));
+    push_u32(buf, limit);
+    push_u32(buf, flags);
+    push_u32(buf, 0);
+    push_u64(buf, base);
+}
+
+fn segment_flags(segment: &SegmentRegister) -> u32 {
+    (u32::from(segment.type_) << 8)
+        | (u32::from(segment.s) << 12)
+        | (u32::from(segment.dpl) << 13)
+        | (u32::from(segment.present) << 15)
+        | (u32::from(segment.avl) << 20)
+        | (u32::from(segment.l) << 21)
+        | (u32::from(segment.db) << 22)
+        | (u32::from(segment.g) << 23)
+}
+
+fn qemu_cpu_state_note(buf: &mut Vec<u8>, regs: &StandardRegisters, sregs: &SpecialRegisters) {
+    push_u32(buf, NOTE_NAME_SIZE);
+    push_u32(buf, QEMU_CPU_STATE_SIZE as u32);
+    push_u32(buf, QEMU_NOTE_TYPE);
+    buf.extend_from_slice(QEMU_NOTE_NAME);
+
+    let start = buf.len();
+    push_u32(buf, QEMU_CPU_STATE_VERSION);
+    push_u32(buf, QEMU_CPU_STATE_SIZE as u32);
+    for value in [
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        regs.rsp,
+        regs.rbp,
+        regs.r8,
+        regs.r9,
+        regs.r10,
+        regs.r11,
+        regs.r12,
+        regs.r13,
+        regs.r14,
+        regs.r15,
+        regs.rip,
+        regs.rflags,
+    ]
+    .iter()
+    {
+        push_u64(buf, *value);
+    }
+    for segment in [
+        &sregs.cs, &sregs.ds, &sregs.es, &sregs.fs, &sregs.gs, &sregs.ss, &sregs.ldt, &sregs.tr,
+    ]
+    .iter()
+    {
+        push_segment(
+            buf,
+            segment.selector,
+            segment.limit,
+            segment_flags(segment),
+            segment.base,
+        );
+    }
+    for table in [&sregs.gdt, &sregs.idt].iter() {
+        push_segment(buf, 0, u32::from(table.limit), 0, table.base);
+    }
+    for cr in [sregs.cr0, 0, sregs.cr2, sregs.cr3, sregs.cr4].iter() {
+        push_u64(buf, *cr);
+    }
+    debug_assert_eq!(buf.len() - start, QEMU_CPU_STATE_SIZE);
+}
+
+/// Writes the core dump of the guest memory and vCPUs to `path`. The vCPUs
+/// must not be running.
+pub fn write_coredump(
+    path: &Path,
+    memory: &GuestMemoryMmap,
+    vcpus: &[Arc<dyn hypervisor::Vcpu>],
+) -> io::Result<()> {
+    let mut states = Vec::new();
+    for vcpu in vcpus.iter() {
+        states.push((vcpu.get_regs()?, vcpu.get_sregs()?));
+    }
+
+    // The debuggers number the threads after the order of the NT_PRSTATUS
+    // notes, which all come first.
+    let mut notes = Vec::new();
+    for (id, (regs, sregs)) in states.iter().enumerate() {
+        prstatus_note(&mut notes, id as u8, regs, sregs);
+    }
+    for (regs, sregs) in states.iter() {
+        qemu_cpu_state_note(&mut notes, regs, sregs);
+    }
+
+    let mut regions = Vec::new();
+    memory.with_regions_mut(|_, region| {
+        regions.push((region.start_addr(), region.len()));
+        Ok::<(), io::Error>(())
+    })?;
+
+    let phnum = regions.len() as u64 + 1;
+    let notes_offset = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+    let mut offset = notes_offset + notes.len() as u64;
+
+    let mut headers = Vec::new();
+    elf_header(&mut headers, phnum as u16);
+    program_header(&mut headers, PT_NOTE, notes_offset, 0, notes.len() as u64);
+    let mut segments = Vec::new();
+    for &(addr, len) in regions.iter() {
+        offset = (offset + SEGMENT_ALIGNMENT - 1) & !(SEGMENT_ALIGNMENT - 1);
+        program_header(&mut headers, PT_LOAD, offset, addr.raw_value(), len);
+        segments.push((offset, addr, len));
+        offset += len;
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&headers)?;
+    file.write_all(&notes)?;
+    for (offset, addr, len) in segments {
+        file.seek(SeekFrom::Start(offset))?;
+        memory
+            .write_all_to(addr, &mut file, len as usize)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+    }
+
+    Ok(())
+}
@@ -9,28 +9,115 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
+use std::collections::BTreeMap;
 use std::os::unix::thread::JoinHandleExt;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Barrier, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::{fmt, io, result};
 
 use libc::{c_void, siginfo_t};
 
+use crate::config::{CpuAffinity, HaltPollConfig, IdleConfig};
+#[cfg(target_arch = "x86_64")]
+use crate::config::{CpuFeature, CpuTopology, EnabledCpuFeature, Platform};
 use crate::device_manager::DeviceManager;
+#[cfg(target_arch = "x86_64")]
+use crate::memory_manager::SgxEpcSection;
 
+#[cfg(target_arch = "x86_64")]
+use arch::x86_64::igvm::IgvmVpContext;
+#[cfg(target_arch = "x86_64")]
+use arch::BootProtocol;
+use arch::EntryPoint;
 use devices::ioapic;
-use kvm_ioctls::*;
+#[cfg(target_arch = "x86_64")]
+use hypervisor::x86_64::{CpuId, CpuIdEntry, SevSnpPageType};
+use hypervisor::{VmExit, VmmOps};
 
-use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::signal::{register_signal_handler, validate_signal_num};
 
 const VCPU_RTSIG_OFFSET: i32 = 0;
 
-// Debug I/O port
+// Period the CPU time of the vCPU threads is sampled at, to find out whether
+// the guest is idle.
+const IDLE_SAMPLE_PERIOD: Duration = Duration::from_millis(100);
+// Share of the time, in percent, under which a vCPU is considered halted.
+// KVM halt polling and the timer interrupts of a halted vCPU account for
+// some CPU time.
+const IDLE_CPU_THRESHOLD: u32 = 2;
+// How long the vCPU threads of an idle guest are parked for, before going
+// back to the guest for its pending interrupts.
+const IDLE_PARK_DURATION: Duration = Duration::from_millis(50);
+
+// Reclaims the pages of the range, writing them back to their file or to
+// swap, since Linux 5.4. Not defined by the libc crate yet.
+const MADV_PAGEOUT: libc::c_int = 21;
+
+// Period the halt polling statistics of the VM are sampled at, to adapt its
+// halt polling.
+const HALT_POLL_SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+// Polls over a period under which the statistics don't tell much.
+const HALT_POLL_MIN_ATTEMPTS: u64 = 100;
+// Shares of the polls, in percent, a wakeup came during, from which the
+// polling is raised, and under which it is lowered.
+const HALT_POLL_GROW_THRESHOLD: u64 = 50;
+const HALT_POLL_SHRINK_THRESHOLD: u64 = 10;
+// Shortest polling, under which it is disabled.
+const HALT_POLL_MIN_NS: u32 = 10_000;
+// Periods after which disabled polling is tried again, no statistics coming
+// in meanwhile.
+const HALT_POLL_PROBE_PERIODS: u32 = 30;
+
+// SCHED_FIFO priority of the realtime vCPU threads, the lowest one, above
+// every regular thread, and under the kernel threads with one.
+const REALTIME_VCPU_PRIORITY: i32 = 1;
+
+// First hypervisor CPUID leaf, and how far the KVM leaves are moved when the
+// Hyper-V ones take their place.
+#[cfg(target_arch = "x86_64")]
+const HYPERVISOR_CPUID_BASE: u32 = 0x4000_0000;
+#[cfg(target_arch = "x86_64")]
+const KVM_CPUID_OFFSET: u32 = 0x100;
+#[cfg(target_arch = "x86_64")]
+const HYPERV_CPUID_MAX: u32 = 0x4000_000a;
+
+// Processor frequency information leaf.
+#[cfg(target_arch = "x86_64")]
+const FREQUENCY_CPUID_LEAF: u32 = 0x16;
+// Bus frequency reported when the host doesn't tell it, in MHz.
+#[cfg(target_arch = "x86_64")]
+const DEFAULT_BUS_FREQUENCY: u32 = 100;
+
+// SGX leaf, enumerating the EPC sections from its sub-leaf 2, and the SGX
+// bit of the structured extended feature flags.
+#[cfg(target_arch = "x86_64")]
+const SGX_CPUID_LEAF: u32 = 0x12;
+#[cfg(target_arch = "x86_64")]
+const SGX_EPC_CPUID_SUBLEAF: u32 = 2;
+#[cfg(target_arch = "x86_64")]
+const SGX_EBX_BIT: u8 = 2;
+
+// KVM features leaf, and its bit advertising the kvmclock MSRs.
+#[cfg(target_arch = "x86_64")]
+const KVM_FEATURES_CPUID_LEAF: u32 = HYPERVISOR_CPUID_BASE + 1;
+#[cfg(target_arch = "x86_64")]
+const KVM_FEATURE_CLOCKSOURCE2_EAX_BIT: u8 = 3;
+// Bit of the KVM features leaf advertising the steal time MSR.
+#[cfg(target_arch = "x86_64")]
+const KVM_FEATURE_STEAL_TIME_EAX_BIT: u8 = 5;
+// Clock source the host keeps its time from.
 #[cfg(target_arch = "x86_64")]
+const HOST_CLOCKSOURCE: &str = "/sys/devices/system/clocksource/clocksource0/current_clocksource";
+// From <linux/kvm.h>
+#[cfg(target_arch = "x86_64")]
+const KVM_CPUID_FLAG_SIGNIFCANT_INDEX: u32 = 1;
+
+// Debug I/O port
 const DEBUG_IOPORT: u16 = 0x80;
 const DEBUG_IOPORT_PREFIX: &str = "Debug I/O port";
 
@@ -82,6 +169,9 @@ pub enum Error {
     /// Cannot spawn a new vCPU thread.
     VcpuSpawn(io::Error),
 
+    /// The vCPU affinity refers to an invalid host CPU.
+    InvalidHostCpu(usize),
+
     #[cfg(target_arch = "x86_64")]
     /// Error configuring the general purpose registers
     REGSConfiguration(arch::x86_64::regs::Error),
@@ -94,6 +184,18 @@ pub enum Error {
     /// Error configuring the floating point related registers
     FPUConfiguration(arch::x86_64::regs::Error),
 
+    #[cfg(target_arch = "aarch64")]
+    /// Error initializing the vCPU for the host CPU target
+    VcpuInit(arch::aarch64::regs::Error),
+
+    #[cfg(target_arch = "aarch64")]
+    /// Error configuring the core registers
+    REGSConfiguration(arch::aarch64::regs::Error),
+
+    #[cfg(target_arch = "aarch64")]
+    /// Cannot finalize the interrupt controller once the vCPUs are created.
+    GicFinalize(arch::aarch64::gic::Error),
+
     /// The call to KVM_SET_CPUID2 failed.
     SetSupportedCpusFailed(io::Error),
 
@@ -105,14 +207,29 @@ pub enum Error {
     /// Error configuring the MSR registers
     MSRSConfiguration(arch::x86_64::regs::Error),
 
-    /// Unexpected KVM_RUN exit reason
-    VcpuUnhandledKvmExit,
+    /// Unexpected vCPU run exit reason
+    VcpuUnhandledKvmExit(String),
 
     /// Failed to join on vCPU threads
     ThreadCleanup,
+
+    /// Cannot set the halt polling of the VM.
+    SetHaltPoll(io::Error),
+
+    /// Cannot enable the Hyper-V synthetic interrupt controller.
+    EnableHypervSynic(io::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot add the initial state of the confidential guest.
+    ConfidentialLaunch(io::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot set the breakpoints or the single-stepping of a vCPU.
+    SetGuestDebug(io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
+#[cfg(target_arch = "x86_64")]
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
 enum CpuidReg {
@@ -122,6 +239,7 @@ enum CpuidReg {
     EDX,
 }
 
+#[cfg(target_arch = "x86_64")]
 pub struct CpuidPatch {
     pub function: u32,
     pub index: u32,
@@ -132,6 +250,7 @@ pub struct CpuidPatch {
     pub edx_bit: Option<u8>,
 }
 
+#[cfg(target_arch = "x86_64")]
 impl CpuidPatch {
     fn set_cpuid_reg(
         cpuid: &mut CpuId,
@@ -189,16 +308,615 @@ impl CpuidPatch {
     }
 }
 
-/// A wrapper around creating and using a kvm-based VCPU.
-pub struct Vcpu {
-    fd: VcpuFd,
-    id: u8,
+/// Describes the vCPU topology to the guest through CPUID. Each topology
+/// level takes a power of two sized field of the x2APIC ID, which is the
+/// vCPU index, starting with the thread in the least significant bits.
+#[cfg(target_arch = "x86_64")]
+pub fn update_cpuid_topology(cpuid: &mut CpuId, topology: &CpuTopology) {
+    let threads_per_core = u32::from(topology.threads_per_core);
+    let cores_per_die = u32::from(topology.cores_per_die);
+    let dies_per_package = u32::from(topology.dies_per_package);
+
+    let thread_width = 32 - (threads_per_core - 1).leading_zeros();
+    let core_width = 32 - (cores_per_die - 1).leading_zeros() + thread_width;
+    let die_width = 32 - (dies_per_package - 1).leading_zeros() + core_width;
+
+    let threads_per_die = threads_per_core * cores_per_die;
+    let threads_per_package = threads_per_die * dies_per_package;
+
+    // Extended topology enumeration, which has no die level: the core level
+    // covers the whole package.
+    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(0), CpuidReg::EAX, thread_width);
+    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(0), CpuidReg::EBX, threads_per_core);
+    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(0), CpuidReg::ECX, 1 << 8);
+    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(1), CpuidReg::EAX, die_width);
+    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(1), CpuidReg::EBX, threads_per_package);
+    CpuidPatch::set_cpuid_reg(cpuid, 0xb, Some(1), CpuidReg::ECX, 2 << 8 | 1);
+
+    // V2 extended topology enumeration, only reported when the host
+    // supports it.
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(0), CpuidReg::EAX, thread_width);
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(0), CpuidReg::EBX, threads_per_core);
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(0), CpuidReg::ECX, 1 << 8);
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(1), CpuidReg::EAX, core_width);
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(1), CpuidReg::EBX, threads_per_die);
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(1), CpuidReg::ECX, 2 << 8 | 1);
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(2), CpuidReg::EAX, die_width);
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(2), CpuidReg::EBX, threads_per_package);
+    CpuidPatch::set_cpuid_reg(cpuid, 0x1f, Some(2), CpuidReg::ECX, 5 << 8 | 2);
+
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        match entry.function {
+            1 => {
+                // Addressable logical processors per package
+                let logical_count = std::cmp::min(1 << die_width, 0xff);
+                entry.ebx = (entry.ebx & !(0xff << 16)) | logical_count << 16;
+            }
+            4 => {
+                // Deterministic cache parameters: L1 and L2 caches are
+                // private to a core, the L3 cache is shared by the package.
+                let cache_level = (entry.eax >> 5) & 0x7;
+                let sharing_width = match cache_level {
+                    0 => continue,
+                    1 | 2 => thread_width,
+                    _ => die_width,
+                };
+                let cores_per_package = (1 << (die_width - thread_width)) - 1;
+                entry.eax &= !(0xfff << 14 | 0x3f << 26);
+                entry.eax |=
+                    (((1 << sharing_width) - 1) & 0xfff) << 14 | (cores_per_package & 0x3f) << 26;
+            }
+            _ => {}
+        }
+    }
+}
+
+// CPUID bits advertising a feature, as (function, index, register, bit).
+#[cfg(target_arch = "x86_64")]
+fn cpu_feature_bits(feature: CpuFeature) -> &'static [(u32, u32, CpuidReg, u8)] {
+    use CpuidReg::*;
+
+    match feature {
+        CpuFeature::Aes => &[(1, 0, ECX, 25)],
+        CpuFeature::Avx => &[(1, 0, ECX, 28)],
+        CpuFeature::Avx2 => &[(7, 0, EBX, 5)],
+        CpuFeature::Avx512 => &[
+            (7, 0, EBX, 16),
+            (7, 0, EBX, 17),
+            (7, 0, EBX, 21),
+            (7, 0, EBX, 26),
+            (7, 0, EBX, 27),
+            (7, 0, EBX, 28),
+            (7, 0, EBX, 30),
+            (7, 0, EBX, 31),
+            (7, 0, ECX, 1),
+            (7, 0, ECX, 6),
+            (7, 0, ECX, 11),
+            (7, 0, ECX, 12),
+            (7, 0, ECX, 14),
+            (7, 0, EDX, 2),
+            (7, 0, EDX, 3),
+            (7, 0, EDX, 8),
+            (7, 1, EAX, 5),
+            // Opmask, ZMM_Hi256 and Hi16_ZMM XSAVE state components
+            (0xd, 0, EAX, 5),
+            (0xd, 0, EAX, 6),
+            (0xd, 0, EAX, 7),
+        ],
+        CpuFeature::Fma => &[(1, 0, ECX, 12)],
+        // Including the BNDREGS and BNDCSR XSAVE state components
+        CpuFeature::Mpx => &[(7, 0, EBX, 14), (0xd, 0, EAX, 3), (0xd, 0, EAX, 4)],
+        // Including the PKRU XSAVE state component
+        CpuFeature::Pku => &[(7, 0, ECX, 3), (0xd, 0, EAX, 9)],
+        CpuFeature::Rdrand => &[(1, 0, ECX, 30)],
+        CpuFeature::Rdseed => &[(7, 0, EBX, 18)],
+        CpuFeature::Sha => &[(7, 0, EBX, 29)],
+        // HLE and RTM
+        CpuFeature::Tsx => &[(7, 0, EBX, 4), (7, 0, EBX, 11)],
+    }
+}
+
+/// Hides CPU features from the guest, so that it can be migrated to hosts
+/// lacking them.
+#[cfg(target_arch = "x86_64")]
+pub fn disable_cpuid_features(cpuid: &mut CpuId, features: &[CpuFeature]) {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        for &(function, index, reg, bit) in features.iter().flat_map(|f| cpu_feature_bits(*f)) {
+            if entry.function != function || entry.index != index {
+                continue;
+            }
+            let value = match reg {
+                CpuidReg::EAX => &mut entry.eax,
+                CpuidReg::EBX => &mut entry.ebx,
+                CpuidReg::ECX => &mut entry.ecx,
+                CpuidReg::EDX => &mut entry.edx,
+            };
+            *value &= !(1 << bit);
+        }
+    }
+}
+
+// CPUID bit advertising a virtualization extension, as (function, register
+// bit), in ECX, and the parameter of the KVM module enabling its nested
+// support.
+#[cfg(target_arch = "x86_64")]
+fn nested_feature(feature: EnabledCpuFeature) -> (u32, u8, &'static str) {
+    match feature {
+        EnabledCpuFeature::Vmx => (1, 5, "/sys/module/kvm_intel/parameters/nested"),
+        EnabledCpuFeature::Svm => (0x8000_0001, 2, "/sys/module/kvm_amd/parameters/nested"),
+    }
+}
+
+/// Exposes the virtualization extensions of the host CPU which are enabled
+/// to the guest, for it to run its own hypervisor, and hides the other
+/// ones. Fails with the first enabled extension KVM doesn't support nested,
+/// as its module parameter, or the CPUID it supports, says.
+#[cfg(target_arch = "x86_64")]
+pub fn update_cpuid_nested(
+    cpuid: &mut CpuId,
+    features: &[EnabledCpuFeature],
+) -> result::Result<(), EnabledCpuFeature> {
+    for &feature in [EnabledCpuFeature::Vmx, EnabledCpuFeature::Svm].iter() {
+        let (function, bit, nested_param) = nested_feature(feature);
+        let entry = cpuid
+            .as_mut_slice()
+            .iter_mut()
+            .find(|entry| entry.function == function && entry.index == 0);
+
+        if !features.contains(&feature) {
+            if let Some(entry) = entry {
+                entry.ecx &= !(1 << bit);
+            }
+            continue;
+        }
+
+        // The module parameter is "Y", or "1" on older kernels.
+        let nested = std::fs::read_to_string(nested_param)
+            .map(|nested| {
+                let nested = nested.trim();
+                nested == "Y" || nested == "1"
+            })
+            .unwrap_or(false);
+        if !nested || !entry.map_or(false, |entry| entry.ecx & 1 << bit != 0) {
+            return Err(feature);
+        }
+    }
+
+    Ok(())
+}
+
+// CPUID bits of AMX, as (function, index, register, bit): AMX-BF16,
+// AMX-TILE and AMX-INT8, then the XTILECFG and XTILEDATA XSAVE state
+// components.
+#[cfg(target_arch = "x86_64")]
+const AMX_CPUID_BITS: [(u32, u32, CpuidReg, u8); 5] = [
+    (7, 0, CpuidReg::EDX, 22),
+    (7, 0, CpuidReg::EDX, 24),
+    (7, 0, CpuidReg::EDX, 25),
+    (0xd, 0, CpuidReg::EAX, 17),
+    (0xd, 0, CpuidReg::EAX, 18),
+];
+
+/// Exposes AMX to the guest when it is enabled, and hides it otherwise.
+/// Returns false when AMX is enabled but KVM doesn't report the tile
+/// instructions and their state in the CPUID it supports, as it does once
+/// the VMM process is allowed to use the tile data state.
+#[cfg(target_arch = "x86_64")]
+pub fn update_cpuid_amx(cpuid: &mut CpuId, enabled: bool) -> bool {
+    let mut found = 0;
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        for &(function, index, reg, bit) in AMX_CPUID_BITS.iter() {
+            if entry.function != function || entry.index != index {
+                continue;
+            }
+            let value = match reg {
+                CpuidReg::EAX => &mut entry.eax,
+                CpuidReg::EBX => &mut entry.ebx,
+                CpuidReg::ECX => &mut entry.ecx,
+                CpuidReg::EDX => &mut entry.edx,
+            };
+            if !enabled {
+                *value &= !(1 << bit);
+            } else if *value & 1 << bit != 0 {
+                found += 1;
+            }
+        }
+    }
+
+    !enabled || found == AMX_CPUID_BITS.len()
+}
+
+/// Advertises the Hyper-V enlightenments KVM provides, at the CPUID leaves
+/// Windows guests look for. The KVM leaves are moved past them, where Linux
+/// guests still find them.
+#[cfg(target_arch = "x86_64")]
+pub fn update_cpuid_kvm_hyperv(cpuid: &mut CpuId) {
+    let mut entries: Vec<CpuIdEntry> = cpuid.as_slice().to_vec();
+
+    for entry in entries.iter_mut() {
+        if entry.function >= HYPERVISOR_CPUID_BASE
+            && entry.function < HYPERVISOR_CPUID_BASE + KVM_CPUID_OFFSET
+        {
+            entry.function += KVM_CPUID_OFFSET;
+            // The signature leaf reports the last KVM leaf.
+            if entry.function == HYPERVISOR_CPUID_BASE + KVM_CPUID_OFFSET {
+                entry.eax += KVM_CPUID_OFFSET;
+            }
+        }
+    }
+
+    let hyperv_leaf = |function, eax, ebx, ecx, edx| CpuIdEntry {
+        function,
+        index: 0,
+        flags: 0,
+        eax,
+        ebx,
+        ecx,
+        edx,
+        ..Default::default()
+    };
+
+    // "Linux KVM Hv" vendor signature
+    entries.push(hyperv_leaf(
+        HYPERVISOR_CPUID_BASE,
+        HYPERV_CPUID_MAX,
+        0x756e_694c,
+        0x564b_2078,
+        0x7648_204d,
+    ));
+    // "Hv#1" interface signature
+    entries.push(hyperv_leaf(HYPERVISOR_CPUID_BASE + 1, 0x3123_7648, 0, 0, 0));
+    // Hypervisor version 10.0
+    entries.push(hyperv_leaf(
+        HYPERVISOR_CPUID_BASE + 2,
+        0x3839,
+        0xa_0000,
+        0,
+        0,
+    ));
+    // Partition reference counter, SynIC, synthetic timers, hypercall, VP
+    // index and reference TSC page
+    entries.push(hyperv_leaf(
+        HYPERVISOR_CPUID_BASE + 3,
+        1 << 1 | 1 << 2 | 1 << 3 | 1 << 5 | 1 << 6 | 1 << 9,
+        0,
+        0,
+        0,
+    ));
+    // Relaxed timing, and never notify about long spinlocks
+    entries.push(hyperv_leaf(
+        HYPERVISOR_CPUID_BASE + 4,
+        1 << 5,
+        0xffff_ffff,
+        0,
+        0,
+    ));
+    for function in HYPERVISOR_CPUID_BASE + 5..=HYPERV_CPUID_MAX {
+        entries.push(hyperv_leaf(function, 0, 0, 0, 0));
+    }
+
+    let mut hyperv_cpuid = CpuId::new(entries.len());
+    hyperv_cpuid.as_mut_slice().copy_from_slice(&entries);
+    *cpuid = hyperv_cpuid;
+}
+
+/// Frequencies the guest CPUs are reported to run at, in MHz.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuFrequency {
+    /// Base, or nominal, frequency.
+    pub base: u32,
+    /// Maximum, or turbo, frequency.
+    pub max: u32,
+    /// Bus, or reference, frequency.
+    pub bus: u32,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl CpuFrequency {
+    /// A fixed frequency, without any turbo.
+    pub fn fixed(frequency: u32) -> Self {
+        CpuFrequency {
+            base: frequency,
+            max: frequency,
+            bus: DEFAULT_BUS_FREQUENCY,
+        }
+    }
+
+    /// Frequencies of the host CPUs, taken from the processor frequency
+    /// CPUID leaf, or from cpufreq when the host CPU doesn't report them.
+    pub fn host() -> Option<Self> {
+        use core::arch::x86_64;
+
+        let max_leaf = unsafe { x86_64::__cpuid(0) }.eax;
+        if max_leaf >= FREQUENCY_CPUID_LEAF {
+            let leaf = unsafe { x86_64::__cpuid(FREQUENCY_CPUID_LEAF) };
+            let base = leaf.eax & 0xffff;
+            if base != 0 {
+                let bus = leaf.ecx & 0xffff;
+                return Some(CpuFrequency {
+                    base,
+                    max: std::cmp::max(base, leaf.ebx & 0xffff),
+                    bus: if bus != 0 { bus } else { DEFAULT_BUS_FREQUENCY },
+                });
+            }
+        }
+
+        // cpufreq reports frequencies in kHz. Only some drivers, such as
+        // intel_pstate, know about the base frequency.
+        let cpufreq = |name: &str| -> Option<u32> {
+            let path = format!("/sys/devices/system/cpu/cpu0/cpufreq/{}", name);
+            let khz: u32 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+            Some(khz / 1000).filter(|&mhz| mhz != 0)
+        };
+        let max = cpufreq("cpuinfo_max_freq")?;
+        let base = cpufreq("base_frequency").unwrap_or(max);
+
+        Some(CpuFrequency {
+            base,
+            max: std::cmp::max(base, max),
+            bus: DEFAULT_BUS_FREQUENCY,
+        })
+    }
+}
+
+/// Reports the CPU frequencies through the processor frequency CPUID leaf,
+/// raising the highest basic leaf when the host CPU doesn't provide it.
+#[cfg(target_arch = "x86_64")]
+pub fn update_cpuid_frequency(cpuid: &mut CpuId, frequency: &CpuFrequency) {
+    let mut entries: Vec<CpuIdEntry> = cpuid
+        .as_slice()
+        .iter()
+        .filter(|entry| entry.function != FREQUENCY_CPUID_LEAF)
+        .cloned()
+        .collect();
+
+    for entry in entries.iter_mut() {
+        if entry.function == 0 && entry.eax < FREQUENCY_CPUID_LEAF {
+            entry.eax = FREQUENCY_CPUID_LEAF;
+        }
+    }
+
+    entries.push(CpuIdEntry {
+        function: FREQUENCY_CPUID_LEAF,
+        index: 0,
+        flags: 0,
+        eax: frequency.base & 0xffff,
+        ebx: frequency.max & 0xffff,
+        ecx: frequency.bus & 0xffff,
+        edx: 0,
+        ..Default::default()
+    });
+
+    let mut frequency_cpuid = CpuId::new(entries.len());
+    frequency_cpuid.as_mut_slice().copy_from_slice(&entries);
+    *cpuid = frequency_cpuid;
+}
+
+/// Whether the VM can run SGX enclaves: SGX is advertised, along with the
+/// SGX1 instructions.
+#[cfg(target_arch = "x86_64")]
+pub fn sgx_supported(cpuid: &CpuId) -> bool {
+    let entries = cpuid.as_slice();
+
+    entries
+        .iter()
+        .any(|entry| entry.function == 7 && entry.index == 0 && entry.ebx & 1 << SGX_EBX_BIT != 0)
+        && entries
+            .iter()
+            .any(|entry| entry.function == SGX_CPUID_LEAF && entry.index == 0 && entry.eax & 1 != 0)
+}
+
+/// Why the guest can't use the PTP clock of KVM, if it can't. The `ptp_kvm`
+/// driver of the guest reads the host clock along with the TSC through a
+/// hypercall, which KVM only serves to the guests using the kvmclock, when
+/// the host keeps its time from the TSC. The KVM leaves must not be moved
+/// yet.
+#[cfg(target_arch = "x86_64")]
+pub fn ptp_unsupported(cpuid: &CpuId) -> Option<&'static str> {
+    let kvmclock = cpuid.as_slice().iter().any(|entry| {
+        entry.function == KVM_FEATURES_CPUID_LEAF
+            && entry.eax & 1 << KVM_FEATURE_CLOCKSOURCE2_EAX_BIT != 0
+    });
+    if !kvmclock {
+        return Some("the hypervisor doesn't provide the kvmclock");
+    }
+
+    match std::fs::read_to_string(HOST_CLOCKSOURCE) {
+        Ok(clocksource) if clocksource.trim() == "tsc" => None,
+        _ => Some("the host clock source isn't the TSC"),
+    }
+}
+
+/// Whether the guest is given the steal time MSR, through which KVM reports
+/// the time its vCPU threads were ready to run but didn't, e.g. throttled by
+/// the cpu.max of their cgroup. The KVM leaves may have been moved.
+#[cfg(target_arch = "x86_64")]
+pub fn steal_time_supported(cpuid: &CpuId) -> bool {
+    let entries = cpuid.as_slice();
+    let features_leaf = if entries
+        .iter()
+        .any(|entry| entry.function == HYPERVISOR_CPUID_BASE + KVM_CPUID_OFFSET)
+    {
+        KVM_FEATURES_CPUID_LEAF + KVM_CPUID_OFFSET
+    } else {
+        KVM_FEATURES_CPUID_LEAF
+    };
+
+    entries.iter().any(|entry| {
+        entry.function == features_leaf && entry.eax & 1 << KVM_FEATURE_STEAL_TIME_EAX_BIT != 0
+    })
+}
+
+/// Enumerates the EPC sections through the SGX leaf, the list ending with
+/// an invalid section.
+#[cfg(target_arch = "x86_64")]
+pub fn update_cpuid_sgx(cpuid: &mut CpuId, sections: &[SgxEpcSection]) {
+    let mut entries: Vec<CpuIdEntry> = cpuid
+        .as_slice()
+        .iter()
+        .filter(|entry| entry.function != SGX_CPUID_LEAF || entry.index < SGX_EPC_CPUID_SUBLEAF)
+        .cloned()
+        .collect();
+
+    for (i, section) in sections.iter().enumerate() {
+        let start = section.start.raw_value();
+        // Sections with confidentiality, integrity and replay protection.
+        entries.push(CpuIdEntry {
+            function: SGX_CPUID_LEAF,
+            index: SGX_EPC_CPUID_SUBLEAF + i as u32,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            eax: (start as u32 & 0xffff_f000) | 1,
+            ebx: (start >> 32) as u32,
+            ecx: (section.size as u32 & 0xffff_f000) | 1,
+            edx: (section.size >> 32) as u32,
+            ..Default::default()
+        });
+    }
+    entries.push(CpuIdEntry {
+        function: SGX_CPUID_LEAF,
+        index: SGX_EPC_CPUID_SUBLEAF + sections.len() as u32,
+        flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+        ..Default::default()
+    });
+
+    let mut sgx_cpuid = CpuId::new(entries.len());
+    sgx_cpuid.as_mut_slice().copy_from_slice(&entries);
+    *cpuid = sgx_cpuid;
+}
+
+/// Architecture specific configuration shared by all the vCPUs.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone)]
+pub struct VcpuArchConfig {
+    /// The CPUID entries supported by the VM.
+    pub cpuid: CpuId,
+    /// Enables the Hyper-V synthetic interrupt controller.
+    pub kvm_hyperv: bool,
+    /// The guest may run VMX, which its firmware would otherwise lock out.
+    pub nested_vmx: bool,
+    /// Frequencies reported to the guest, if known.
+    pub frequency: Option<CpuFrequency>,
+    /// The registers of TDX vCPUs are the TDX module's business.
+    pub platform: Platform,
+}
+
+/// A range of the TDVF added to the private memory of a TD, from the memory
+/// it was loaded in.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug)]
+pub struct TdxRegion {
+    pub host_address: u64,
+    pub guest_address: u64,
+    pub size: u64,
+    /// Whether the content of the range extends the TD measurement.
+    pub measure: bool,
+}
+
+/// A range of the guest memory encrypted in place for a SEV-SNP guest.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Copy, Debug)]
+pub struct SevSnpRegion {
+    pub host_address: u64,
+    pub guest_address: u64,
+    pub size: u64,
+    pub page_type: SevSnpPageType,
+}
+
+/// What is left to do to launch a confidential guest, once its vCPUs exist.
+#[cfg(target_arch = "x86_64")]
+#[derive(Clone, Debug)]
+pub enum ConfidentialLaunch {
+    /// The vCPUs are given the address of the TD HOB, then the regions of
+    /// the TDVF are added to the TD.
+    Tdx {
+        hob_address: u64,
+        regions: Vec<TdxRegion>,
+    },
+    /// The regions are added to the guest, then the vCPUs states.
+    SevSnp { regions: Vec<SevSnpRegion> },
+}
+
+/// Architecture specific configuration shared by all the vCPUs.
+#[cfg(target_arch = "aarch64")]
+#[derive(Clone)]
+pub struct VcpuArchConfig {
+    /// The interrupt controller, finalized once all the vCPUs exist.
+    pub gic: Arc<arch::aarch64::gic::Gic>,
+}
+
+// Dispatches the vCPU I/O and MMIO exits to the device buses.
+struct VcpuVmmOps {
     io_bus: Arc<devices::Bus>,
     mmio_bus: Arc<devices::Bus>,
-    ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
     vm_ts: std::time::Instant,
 }
 
+impl VcpuVmmOps {
+    // Log debug io port codes.
+    fn log_debug_ioport(&self, code: u8) {
+        let ts = self.vm_ts.elapsed();
+
+        debug!(
+            "[{} code 0x{:x}] {}.{:>06} seconds",
+            DebugIoPortRange::from_u8(code),
+            code,
+            ts.as_secs(),
+            ts.as_micros()
+        );
+    }
+}
+
+impl VmmOps for VcpuVmmOps {
+    fn pio_read(&self, addr: u64, data: &mut [u8]) {
+        self.io_bus.read(addr, data);
+    }
+
+    fn pio_write(&self, addr: u64, data: &[u8]) {
+        if addr == u64::from(DEBUG_IOPORT) && data.len() == 1 {
+            self.log_debug_ioport(data[0]);
+        }
+        self.io_bus.write(addr, data);
+    }
+
+    fn mmio_read(&self, addr: u64, data: &mut [u8]) {
+        self.mmio_bus.read(addr, data);
+    }
+
+    fn mmio_write(&self, addr: u64, data: &[u8]) {
+        self.mmio_bus.write(addr, data);
+    }
+}
+
+/// A vCPU that stopped running the guest, the reason why, and its registers
+/// at the time.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VcpuFailure {
+    pub id: u8,
+    pub reason: String,
+    /// Registers of the vCPU, by name.
+    #[serde(default)]
+    pub registers: BTreeMap<String, u64>,
+}
+
+/// What the VCPU thread does once the VCPU exited.
+#[derive(Debug, PartialEq)]
+pub enum VcpuRun {
+    /// The guest keeps running.
+    Continue,
+    /// The guest asked for a reset, or triple faulted.
+    Reset,
+    /// The VCPU hit a breakpoint or stepped, and stops for the debugger.
+    DebugStop,
+}
+
+/// A wrapper around creating and using a hypervisor VCPU.
+pub struct Vcpu {
+    vcpu: Arc<dyn hypervisor::Vcpu>,
+    id: u8,
+    ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
+}
+
 impl Vcpu {
     /// Constructs a new VCPU for `vm`.
     ///
@@ -208,56 +926,113 @@ impl Vcpu {
     /// * `vm` - The virtual machine this vcpu will get attached to.
     pub fn new(
         id: u8,
-        fd: &Arc<VmFd>,
+        vm: &Arc<dyn hypervisor::Vm>,
         io_bus: Arc<devices::Bus>,
         mmio_bus: Arc<devices::Bus>,
         ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
         creation_ts: std::time::Instant,
     ) -> Result<Self> {
-        let kvm_vcpu = fd.create_vcpu(id).map_err(Error::VcpuFd)?;
-        // Initially the cpuid per vCPU is the one supported by this VM.
-        Ok(Vcpu {
-            fd: kvm_vcpu,
-            id,
+        let vmm_ops = Arc::new(VcpuVmmOps {
             io_bus,
             mmio_bus,
-            ioapic,
             vm_ts: creation_ts,
-        })
+        });
+        let vcpu = vm.create_vcpu(id, Some(vmm_ops)).map_err(Error::VcpuFd)?;
+        #[cfg(target_arch = "aarch64")]
+        arch::aarch64::regs::setup_vcpu_init(vm, &vcpu, id).map_err(Error::VcpuInit)?;
+        // Initially the cpuid per vCPU is the one supported by this VM.
+        Ok(Vcpu { vcpu, id, ioapic })
     }
 
     /// Configures a x86_64 specific vcpu and should be called once per vcpu from the vcpu's thread.
     ///
     /// # Arguments
     ///
-    /// * `machine_config` - Specifies necessary info used for the CPUID configuration.
-    /// * `kernel_start_addr` - Offset from `guest_mem` at which the kernel starts.
-    /// * `vm` - The virtual machine this vcpu will get attached to.
+    /// * `kernel_entry_point` - Entry point of the kernel, and the protocol to boot it
+    ///   with, if any. A firmware runs from the vcpu reset state instead.
+    /// * `vm_memory` - The memory of the virtual machine this vcpu belongs to.
+    /// * `arch_config` - Specifies necessary info used for the CPUID configuration.
+    #[cfg(target_arch = "x86_64")]
     pub fn configure(
         &mut self,
-        kernel_start_addr: GuestAddress,
+        kernel_entry_point: Option<EntryPoint>,
         vm_memory: &Arc<RwLock<GuestMemoryMmap>>,
-        cpuid: CpuId,
+        arch_config: &VcpuArchConfig,
     ) -> Result<()> {
-        let mut cpuid = cpuid;
+        let mut cpuid = arch_config.cpuid.clone();
         CpuidPatch::set_cpuid_reg(&mut cpuid, 0xb, None, CpuidReg::EDX, u32::from(self.id));
-        self.fd
+        CpuidPatch::set_cpuid_reg(&mut cpuid, 0x1f, None, CpuidReg::EDX, u32::from(self.id));
+        self.vcpu
             .set_cpuid2(&cpuid)
             .map_err(Error::SetSupportedCpusFailed)?;
 
-        arch::x86_64::regs::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
-        // Safe to unwrap because this method is called after the VM is configured
-        arch::x86_64::regs::setup_regs(
-            &self.fd,
-            kernel_start_addr.raw_value(),
-            arch::x86_64::layout::BOOT_STACK_POINTER.raw_value(),
-            arch::x86_64::layout::ZERO_PAGE_START.raw_value(),
-        )
-        .map_err(Error::REGSConfiguration)?;
-        arch::x86_64::regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
-        arch::x86_64::regs::setup_sregs(&vm_memory.read().unwrap(), &self.fd)
+        if arch_config.kvm_hyperv {
+            self.vcpu
+                .enable_hyperv_synic()
+                .map_err(Error::EnableHypervSynic)?;
+        }
+        // The TDX module sets the vCPUs up, the TDVF running from their
+        // reset vector.
+        if arch_config.platform == Platform::Tdx {
+            return Ok(());
+        }
+
+        arch::x86_64::regs::setup_msrs(&self.vcpu).map_err(Error::MSRSConfiguration)?;
+        if arch_config.nested_vmx {
+            arch::x86_64::regs::setup_vmx_msrs(&self.vcpu).map_err(Error::MSRSConfiguration)?;
+        }
+        if let Some(entry_point) = kernel_entry_point {
+            match entry_point.protocol {
+                BootProtocol::LinuxBoot => arch::x86_64::regs::setup_regs(
+                    &self.vcpu,
+                    entry_point.entry_addr.raw_value(),
+                    arch::x86_64::layout::BOOT_STACK_POINTER.raw_value(),
+                    arch::x86_64::layout::ZERO_PAGE_START.raw_value(),
+                ),
+                BootProtocol::PvhBoot => arch::x86_64::regs::setup_pvh_regs(
+                    &self.vcpu,
+                    entry_point.entry_addr.raw_value(),
+                    arch::x86_64::layout::PVH_INFO_START.raw_value(),
+                ),
+            }
+            .map_err(Error::REGSConfiguration)?;
+            arch::x86_64::regs::setup_fpu(&self.vcpu).map_err(Error::FPUConfiguration)?;
+            // Safe to unwrap because this method is called after the VM is configured
+            arch::x86_64::regs::setup_sregs(
+                &vm_memory.read().unwrap(),
+                &self.vcpu,
+                entry_point.protocol,
+            )
             .map_err(Error::SREGSConfiguration)?;
-        arch::x86_64::interrupts::set_lint(&self.fd).map_err(Error::LocalIntConfiguration)?;
+        }
+        arch::x86_64::interrupts::set_lint(&self.vcpu).map_err(Error::LocalIntConfiguration)?;
+        Ok(())
+    }
+
+    /// Configures an aarch64 specific vcpu and should be called once per vcpu from the vcpu's thread.
+    /// Only the boot vcpu has its registers set, the other ones are powered
+    /// on by the guest through PSCI.
+    ///
+    /// # Arguments
+    ///
+    /// * `kernel_start_addr` - Offset from `guest_mem` at which the kernel starts, if any.
+    /// * `vm_memory` - The memory of the virtual machine this vcpu belongs to.
+    #[cfg(target_arch = "aarch64")]
+    pub fn configure(
+        &mut self,
+        kernel_entry_point: Option<EntryPoint>,
+        vm_memory: &Arc<RwLock<GuestMemoryMmap>>,
+        _arch_config: &VcpuArchConfig,
+    ) -> Result<()> {
+        if let (0, Some(entry_point)) = (self.id, kernel_entry_point) {
+            let fdt_addr = arch::aarch64::get_fdt_addr(&vm_memory.read().unwrap());
+            arch::aarch64::regs::setup_regs(
+                &self.vcpu,
+                entry_point.entry_addr.raw_value(),
+                fdt_addr.raw_value(),
+            )
+            .map_err(Error::REGSConfiguration)?;
+        }
         Ok(())
     }
 
@@ -265,66 +1040,133 @@ impl Vcpu {
     ///
     /// Note that the state of the VCPU and associated VM must be setup first for this to do
     /// anything useful.
-    pub fn run(&self) -> Result<bool> {
-        match self.fd.run() {
+    pub fn run(&self) -> Result<VcpuRun> {
+        match self.vcpu.run() {
             Ok(run) => match run {
-                VcpuExit::IoIn(addr, data) => {
-                    self.io_bus.read(u64::from(addr), data);
-                    Ok(true)
-                }
-                VcpuExit::IoOut(addr, data) => {
-                    if addr == DEBUG_IOPORT && data.len() == 1 {
-                        self.log_debug_ioport(data[0]);
-                    }
-                    self.io_bus.write(u64::from(addr), data);
-                    Ok(true)
-                }
-                VcpuExit::MmioRead(addr, data) => {
-                    self.mmio_bus.read(addr as u64, data);
-                    Ok(true)
-                }
-                VcpuExit::MmioWrite(addr, data) => {
-                    self.mmio_bus.write(addr as u64, data);
-                    Ok(true)
-                }
-                VcpuExit::IoapicEoi(vector) => {
+                VmExit::Ignore => Ok(VcpuRun::Continue),
+                VmExit::IoapicEoi(vector) => {
                     if let Some(ioapic) = &self.ioapic {
                         ioapic.lock().unwrap().end_of_interrupt(vector);
                     }
-                    Ok(true)
+                    Ok(VcpuRun::Continue)
                 }
-                VcpuExit::Shutdown => {
+                VmExit::Shutdown => {
                     // Triple fault to trigger a reboot
-                    Ok(false)
-                }
-                r => {
-                    error!("Unexpected exit reason on vcpu run: {:?}", r);
-                    Err(Error::VcpuUnhandledKvmExit)
+                    Ok(VcpuRun::Reset)
                 }
+                VmExit::Debug => Ok(VcpuRun::DebugStop),
+                VmExit::Unhandled(reason) => Err(Error::VcpuUnhandledKvmExit(reason)),
             },
 
-            Err(ref e) => match e.raw_os_error().unwrap() {
-                libc::EAGAIN | libc::EINTR => Ok(true),
-                _ => {
-                    error!("VCPU {:?} error {:?}", self.id, e);
-                    Err(Error::VcpuUnhandledKvmExit)
-                }
+            Err(e) => match e.raw_os_error() {
+                Some(libc::EAGAIN) | Some(libc::EINTR) => Ok(VcpuRun::Continue),
+                _ => Err(Error::VcpuRun(e)),
             },
         }
     }
 
-    // Log debug io port codes.
-    fn log_debug_ioport(&self, code: u8) {
-        let ts = self.vm_ts.elapsed();
+    /// Returns the registers of the VCPU, by name, or none of them if they
+    /// can't be read.
+    pub fn registers(&self) -> BTreeMap<String, u64> {
+        vcpu_registers(&self.vcpu)
+    }
+}
 
-        debug!(
-            "[{} code 0x{:x}] {}.{:>06} seconds",
-            DebugIoPortRange::from_u8(code),
-            code,
-            ts.as_secs(),
-            ts.as_micros()
-        );
+// Registers of a hypervisor vCPU, by name, or none of them if they can't be
+// read.
+#[cfg(target_arch = "x86_64")]
+fn vcpu_registers(vcpu: &Arc<dyn hypervisor::Vcpu>) -> BTreeMap<String, u64> {
+    let (regs, sregs) = match (vcpu.get_regs(), vcpu.get_sregs()) {
+        (Ok(regs), Ok(sregs)) => (regs, sregs),
+        _ => return BTreeMap::new(),
+    };
+
+    [
+        ("rax", regs.rax),
+        ("rbx", regs.rbx),
+        ("rcx", regs.rcx),
+        ("rdx", regs.rdx),
+        ("rsi", regs.rsi),
+        ("rdi", regs.rdi),
+        ("rsp", regs.rsp),
+        ("rbp", regs.rbp),
+        ("r8", regs.r8),
+        ("r9", regs.r9),
+        ("r10", regs.r10),
+        ("r11", regs.r11),
+        ("r12", regs.r12),
+        ("r13", regs.r13),
+        ("r14", regs.r14),
+        ("r15", regs.r15),
+        ("rip", regs.rip),
+        ("rflags", regs.rflags),
+        ("cs", u64::from(sregs.cs.selector)),
+        ("ss", u64::from(sregs.ss.selector)),
+        ("cr0", sregs.cr0),
+        ("cr2", sregs.cr2),
+        ("cr3", sregs.cr3),
+        ("cr4", sregs.cr4),
+        ("efer", sregs.efer),
+    ]
+    .iter()
+    .map(|(name, value)| (name.to_string(), *value))
+    .collect()
+}
+
+// Registers of a hypervisor vCPU, by name, or none of them if they can't be
+// read.
+#[cfg(target_arch = "aarch64")]
+fn vcpu_registers(vcpu: &Arc<dyn hypervisor::Vcpu>) -> BTreeMap<String, u64> {
+    arch::aarch64::regs::core_registers(vcpu)
+        .map(|registers| registers.into_iter().collect())
+        .unwrap_or_default()
+}
+
+// CPU time a thread of this process ran for, read from its thread CPU clock,
+// whose ID is built the way pthread_getcpuclockid() does.
+fn thread_cpu_time(tid: libc::pid_t) -> io::Result<Duration> {
+    // CPUCLOCK_SCHED | CPUCLOCK_PERTHREAD_MASK
+    let clock_id = ((!tid) << 3) | 6;
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safe because time is a valid timespec, and we check the result.
+    if unsafe { libc::clock_gettime(clock_id, &mut time) } != 0 {
+        return Err(io::Error::last_os_error());
     }
+
+    Ok(Duration::new(time.tv_sec as u64, time.tv_nsec as u32))
+}
+
+// Pages out the guest RAM, returning the size of the regions the kernel
+// reclaimed. The pages the guest or the devices access again are faulted
+// back in, from their file or from swap.
+fn page_out_memory(memory: &GuestMemoryMmap) -> usize {
+    let mut size = 0;
+    let _ = memory.with_regions_mut(|_, region| {
+        // Safe because the range is a mapping of the guest RAM, which the
+        // memory lock keeps mapped, and the return value is checked.
+        let ret = unsafe {
+            libc::madvise(
+                region.as_ptr() as *mut c_void,
+                region.len() as usize,
+                MADV_PAGEOUT,
+            )
+        };
+        if ret == 0 {
+            size += region.len() as usize;
+        } else {
+            warn!(
+                "Cannot page out the guest RAM at 0x{:x}: {}",
+                region.start_addr().raw_value(),
+                io::Error::last_os_error()
+            );
+        }
+        Ok::<(), io::Error>(())
+    });
+
+    size
 }
 
 pub struct CpuManager {
@@ -333,22 +1175,58 @@ pub struct CpuManager {
     mmio_bus: Arc<devices::Bus>,
     ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
     vm_memory: Arc<RwLock<GuestMemoryMmap>>,
-    cpuid: CpuId,
-    fd: Arc<VmFd>,
+    arch_config: VcpuArchConfig,
+    vm: Arc<dyn hypervisor::Vm>,
     vcpus_kill_signalled: Arc<AtomicBool>,
     vcpus_pause_signalled: Arc<AtomicBool>,
+    // All the vCPUs are halted for longer than the idle timeout.
+    vcpus_idle: Arc<AtomicBool>,
+    // The memory of the idle guest was paged out, and the guest wasn't busy
+    // since.
+    vcpus_hibernated: Arc<AtomicBool>,
+    idle: Option<IdleConfig>,
+    idle_monitor: Option<thread::JoinHandle<()>>,
+    halt_poll: Option<HaltPollConfig>,
+    halt_poll_monitor: Option<thread::JoinHandle<()>>,
+    // The vCPU threads are scheduled with SCHED_FIFO, and don't poll.
+    realtime: bool,
     reset_evt: EventFd,
+    vcpu_failure_evt: EventFd,
+    vcpu_failures: Arc<Mutex<Vec<VcpuFailure>>>,
+    // Written when vCPUs stop for the debugger, whose IDs are pushed to
+    // debug_stops.
+    debug_evt: EventFd,
+    debug_stops: Arc<Mutex<Vec<u8>>>,
+    // The vCPU that triple faulted, before the VM reset.
+    #[cfg(target_arch = "x86_64")]
+    triple_fault: Arc<Mutex<Option<u8>>>,
     threads: Vec<thread::JoinHandle<()>>,
+    // Thread IDs of the vCPU threads, once started.
+    vcpu_tids: Vec<libc::pid_t>,
+    // Hypervisor vCPUs, whose state is read while the vCPU threads are paused.
+    vcpus: Vec<Arc<dyn hypervisor::Vcpu>>,
+    affinity: Vec<CpuAffinity>,
+    #[cfg(target_arch = "x86_64")]
+    confidential_launch: Option<ConfidentialLaunch>,
+    #[cfg(target_arch = "x86_64")]
+    boot_vp_context: Option<IgvmVpContext>,
 }
 
 impl CpuManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         boot_vcpus: u8,
         device_manager: &DeviceManager,
         guest_memory: Arc<RwLock<GuestMemoryMmap>>,
-        fd: Arc<VmFd>,
-        cpuid: CpuId,
+        vm: Arc<dyn hypervisor::Vm>,
+        arch_config: VcpuArchConfig,
         reset_evt: EventFd,
+        vcpu_failure_evt: EventFd,
+        debug_evt: EventFd,
+        affinity: Vec<CpuAffinity>,
+        idle: Option<IdleConfig>,
+        halt_poll: Option<HaltPollConfig>,
+        realtime: bool,
     ) -> CpuManager {
         CpuManager {
             boot_vcpus,
@@ -356,20 +1234,112 @@ impl CpuManager {
             mmio_bus: device_manager.mmio_bus().clone(),
             ioapic: device_manager.ioapic().clone(),
             vm_memory: guest_memory,
-            cpuid,
-            fd,
+            arch_config,
+            vm,
             vcpus_kill_signalled: Arc::new(AtomicBool::new(false)),
             vcpus_pause_signalled: Arc::new(AtomicBool::new(false)),
+            vcpus_idle: Arc::new(AtomicBool::new(false)),
+            vcpus_hibernated: Arc::new(AtomicBool::new(false)),
+            idle,
+            idle_monitor: None,
+            halt_poll,
+            halt_poll_monitor: None,
+            realtime,
             threads: Vec::with_capacity(boot_vcpus as usize),
+            vcpu_tids: Vec::with_capacity(boot_vcpus as usize),
+            vcpus: Vec::with_capacity(boot_vcpus as usize),
             reset_evt,
+            vcpu_failure_evt,
+            vcpu_failures: Arc::new(Mutex::new(Vec::new())),
+            debug_evt,
+            debug_stops: Arc::new(Mutex::new(Vec::new())),
+            #[cfg(target_arch = "x86_64")]
+            triple_fault: Arc::new(Mutex::new(None)),
+            affinity,
+            #[cfg(target_arch = "x86_64")]
+            confidential_launch: None,
+            #[cfg(target_arch = "x86_64")]
+            boot_vp_context: None,
         }
     }
 
+    /// Sets what is left to do to launch a confidential guest, when its
+    /// vCPUs get started.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_confidential_launch(&mut self, launch: ConfidentialLaunch) {
+        self.confidential_launch = Some(launch);
+    }
+
+    /// Sets the registers the boot vCPU starts with, from an IGVM image,
+    /// instead of the ones of a kernel entry point.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_boot_vp_context(&mut self, context: IgvmVpContext) {
+        self.boot_vp_context = Some(context);
+    }
+
+    // Adds the vCPUs states to a confidential guest, and the TDVF for a TD,
+    // every vCPU being configured, and none of them running.
+    #[cfg(target_arch = "x86_64")]
+    fn launch_confidential(&self) -> Result<()> {
+        match &self.confidential_launch {
+            Some(ConfidentialLaunch::Tdx { regions, .. }) => {
+                for region in regions.iter() {
+                    self.vcpus[0]
+                        .tdx_init_memory_region(
+                            region.host_address,
+                            region.guest_address,
+                            region.size,
+                            region.measure,
+                        )
+                        .map_err(Error::ConfidentialLaunch)?;
+                }
+                self.vm.tdx_finalize().map_err(Error::ConfidentialLaunch)
+            }
+            Some(ConfidentialLaunch::SevSnp { regions }) => {
+                for region in regions.iter() {
+                    self.vm
+                        .sev_snp_launch_update(
+                            region.host_address,
+                            region.guest_address,
+                            region.size,
+                            region.page_type,
+                        )
+                        .map_err(Error::ConfidentialLaunch)?;
+                }
+                self.vm
+                    .sev_snp_launch_finish()
+                    .map_err(Error::ConfidentialLaunch)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Host CPU set the vCPU thread gets pinned to, if any.
+    fn vcpu_cpuset(&self, cpu_id: u8) -> Result<Option<libc::cpu_set_t>> {
+        let host_cpus = match self.affinity.iter().find(|a| a.vcpu == cpu_id) {
+            Some(affinity) => &affinity.host_cpus,
+            None => return Ok(None),
+        };
+
+        // Safe because cpu_set_t is a plain bitmask.
+        let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &host_cpu in host_cpus.iter() {
+            if host_cpu >= libc::CPU_SETSIZE as usize {
+                return Err(Error::InvalidHostCpu(host_cpu));
+            }
+            unsafe { libc::CPU_SET(host_cpu, &mut cpuset) };
+        }
+
+        Ok(Some(cpuset))
+    }
+
     // Starts all the vCPUs that the VM is booting with. Blocks until all vCPUs are running.
-    pub fn start_boot_vcpus(&mut self, entry_addr: GuestAddress) -> Result<()> {
+    pub fn start_boot_vcpus(&mut self, entry_point: Option<EntryPoint>) -> Result<()> {
         let creation_ts = std::time::Instant::now();
 
         let vcpu_thread_barrier = Arc::new(Barrier::new((self.boot_vcpus + 1) as usize));
+        let vcpu_tids = Arc::new(Mutex::new(Vec::with_capacity(self.boot_vcpus as usize)));
+        let idle_park = self.idle.as_ref().map(|idle| idle.park).unwrap_or(false);
 
         for cpu_id in 0..self.boot_vcpus {
             let ioapic = if let Some(ioapic) = &self.ioapic {
@@ -380,23 +1350,92 @@ impl CpuManager {
 
             let mut vcpu = Vcpu::new(
                 cpu_id,
-                &self.fd,
+                &self.vm,
                 self.io_bus.clone(),
                 self.mmio_bus.clone(),
                 ioapic,
                 creation_ts,
             )?;
-            vcpu.configure(entry_addr, &self.vm_memory, self.cpuid.clone())?;
+            vcpu.configure(entry_point, &self.vm_memory, &self.arch_config)?;
+            #[cfg(target_arch = "x86_64")]
+            {
+                if let (0, Some(context)) = (cpu_id, &self.boot_vp_context) {
+                    arch::x86_64::regs::setup_igvm_regs(&vcpu.vcpu, context)
+                        .map_err(Error::REGSConfiguration)?;
+                }
+                if let Some(ConfidentialLaunch::Tdx { hob_address, .. }) = &self.confidential_launch
+                {
+                    vcpu.vcpu
+                        .tdx_init(*hob_address)
+                        .map_err(Error::ConfidentialLaunch)?;
+                }
+            }
+            let cpuset = self.vcpu_cpuset(cpu_id)?;
+            self.vcpus.push(vcpu.vcpu.clone());
 
             let vcpu_thread_barrier = vcpu_thread_barrier.clone();
 
             let reset_evt = self.reset_evt.try_clone().unwrap();
+            let vcpu_failure_evt = self.vcpu_failure_evt.try_clone().unwrap();
+            let vcpu_failures = self.vcpu_failures.clone();
+            let debug_evt = self.debug_evt.try_clone().unwrap();
+            let debug_stops = self.debug_stops.clone();
+            #[cfg(target_arch = "x86_64")]
+            let triple_fault = self.triple_fault.clone();
             let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
             let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
+            let vcpus_idle = self.vcpus_idle.clone();
+            let vcpu_tids = vcpu_tids.clone();
+            let realtime = self.realtime;
             self.threads.push(
                 thread::Builder::new()
                     .name(format!("vcpu{}", vcpu.id))
                     .spawn(move || {
+                        // Safe because gettid() can't fail.
+                        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+                        vcpu_tids.lock().unwrap().push(tid);
+
+                        if let Some(cpuset) = cpuset {
+                            // Safe because cpuset is a valid cpu_set_t, and 0
+                            // designates the calling thread.
+                            let ret = unsafe {
+                                libc::sched_setaffinity(
+                                    0,
+                                    std::mem::size_of::<libc::cpu_set_t>(),
+                                    &cpuset,
+                                )
+                            };
+                            if ret != 0 {
+                                error!(
+                                    "Cannot pin vCPU {} to its host CPUs: {}",
+                                    cpu_id,
+                                    io::Error::last_os_error()
+                                );
+                                // The other vCPUs are waiting for this one.
+                                vcpu_thread_barrier.wait();
+                                return;
+                            }
+                        }
+
+                        if realtime {
+                            let param = libc::sched_param {
+                                sched_priority: REALTIME_VCPU_PRIORITY,
+                            };
+                            // Safe because param is a valid sched_param, and
+                            // 0 designates the calling thread.
+                            let ret =
+                                unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) };
+                            if ret != 0 {
+                                error!(
+                                    "Cannot give vCPU {} a realtime priority: {}",
+                                    cpu_id,
+                                    io::Error::last_os_error()
+                                );
+                                vcpu_thread_barrier.wait();
+                                return;
+                            }
+                        }
+
                         unsafe {
                             extern "C" fn handle_signal(_: i32, _: *mut siginfo_t, _: *mut c_void) {
                             }
@@ -414,22 +1453,46 @@ impl CpuManager {
                         vcpu_thread_barrier.wait();
 
                         loop {
-                            // vcpu.run() returns false on a KVM_EXIT_SHUTDOWN (triple-fault) so trigger a reset
+                            // vcpu.run() returns VcpuRun::Reset on a KVM_EXIT_SHUTDOWN (triple-fault) so trigger a reset
                             match vcpu.run() {
                                 Err(e) => {
-                                    error!("VCPU generated error: {:?}", e);
-                                    break;
+                                    // The vCPU is left as it failed, and
+                                    // pauses along with the VM, so that its
+                                    // state can be looked at.
+                                    let failure = VcpuFailure {
+                                        id: vcpu.id,
+                                        reason: format!("{:?}", e),
+                                        registers: vcpu.registers(),
+                                    };
+                                    error!("vCPU {} failed: {}", failure.id, failure.reason);
+                                    vcpu_failures.lock().unwrap().push(failure);
+                                    vcpu_pause_signalled.store(true, Ordering::SeqCst);
+                                    vcpu_failure_evt.write(1).unwrap();
                                 }
-                                Ok(true) => {}
-                                Ok(false) => {
+                                Ok(VcpuRun::Continue) => {}
+                                Ok(VcpuRun::Reset) => {
+                                    // On x86_64, the guest resets through
+                                    // its devices, a vCPU only shutting down
+                                    // on a triple fault.
+                                    #[cfg(target_arch = "x86_64")]
+                                    triple_fault.lock().unwrap().get_or_insert(vcpu.id);
                                     reset_evt.write(1).unwrap();
                                     break;
                                 }
+                                Ok(VcpuRun::DebugStop) => {
+                                    // The other vCPUs are paused along
+                                    // with this one by the debugger stub.
+                                    debug_stops.lock().unwrap().push(vcpu.id);
+                                    vcpu_pause_signalled.store(true, Ordering::SeqCst);
+                                    debug_evt.write(1).unwrap();
+                                }
                             }
 
-                            // We've been told to terminate
-                            if vcpu_kill_signalled.load(Ordering::SeqCst) {
-                                break;
+                            // The threads of an idle guest are parked for a
+                            // while, rather than waiting in the hypervisor
+                            // for the next interrupt of the guest.
+                            if idle_park && vcpus_idle.load(Ordering::SeqCst) {
+                                thread::park_timeout(IDLE_PARK_DURATION);
                             }
 
                             // If we are being told to pause, we park the thread
@@ -438,18 +1501,249 @@ impl CpuManager {
                             // the boolean and unpark the thread.
                             // We enter a loop because park() could spuriously
                             // return. We will then park() again unless the
-                            // pause boolean has been toggled.
-                            while vcpu_pause_signalled.load(Ordering::SeqCst) {
+                            // pause boolean has been toggled, or we've been
+                            // told to terminate.
+                            while vcpu_pause_signalled.load(Ordering::SeqCst)
+                                && !vcpu_kill_signalled.load(Ordering::SeqCst)
+                            {
                                 thread::park();
                             }
+
+                            // We've been told to terminate
+                            if vcpu_kill_signalled.load(Ordering::SeqCst) {
+                                break;
+                            }
                         }
                     })
                     .map_err(Error::VcpuSpawn)?,
             );
         }
 
+        // The interrupt controller can only be finalized once all the vCPUs
+        // are created, and before any of them runs.
+        #[cfg(target_arch = "aarch64")]
+        self.arch_config
+            .gic
+            .finalize()
+            .map_err(Error::GicFinalize)?;
+
+        // Likewise for the initial state of a confidential guest.
+        #[cfg(target_arch = "x86_64")]
+        self.launch_confidential()?;
+
         // Unblock all CPU threads.
         vcpu_thread_barrier.wait();
+        self.vcpu_tids = vcpu_tids.lock().unwrap().clone();
+
+        if let Some(idle) = self.idle.clone() {
+            let tids = self.vcpu_tids.clone();
+            self.start_idle_monitor(idle, tids)?;
+        }
+
+        if let Some(halt_poll) = self.halt_poll.clone() {
+            self.vm
+                .set_halt_poll_ns(halt_poll.max_ns)
+                .map_err(Error::SetHaltPoll)?;
+            if halt_poll.adaptive {
+                self.start_halt_poll_monitor(halt_poll.max_ns)?;
+            }
+        }
+
+        // The realtime vCPUs mostly idle without exiting, and when they do
+        // halt, the host CPU is theirs anyway.
+        if self.realtime {
+            if let Err(e) = self.vm.set_halt_poll_ns(0) {
+                warn!("Cannot disable the halt polling of the realtime VM: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Lowers the halt polling of the VM when the wakeups seldom come while
+    // the vCPUs poll, which only burns host CPU time, and raises it back, up
+    // to `max_ns`, when they often do, which cuts the wakeup latency.
+    fn start_halt_poll_monitor(&mut self, max_ns: u32) -> Result<()> {
+        let vm = self.vm.clone();
+        let vcpus_kill_signalled = self.vcpus_kill_signalled.clone();
+
+        self.halt_poll_monitor = Some(
+            thread::Builder::new()
+                .name("vcpu_halt_poll".to_string())
+                .spawn(move || {
+                    let mut halt_poll_ns = max_ns;
+                    let mut disabled_periods = 0;
+                    let mut last_stats = hypervisor::HaltPollStats::default();
+
+                    while !vcpus_kill_signalled.load(Ordering::SeqCst) {
+                        let stats = match vm.halt_poll_stats() {
+                            Ok(stats) => stats,
+                            Err(e) => {
+                                warn!(
+                                    "Cannot read the halt polling statistics, \
+                                     stopping adaptive halt polling: {}",
+                                    e
+                                );
+                                return;
+                            }
+                        };
+                        let attempted = stats.attempted.saturating_sub(last_stats.attempted);
+                        let successful = stats.successful.saturating_sub(last_stats.successful);
+                        last_stats = stats;
+
+                        let new_ns = if attempted >= HALT_POLL_MIN_ATTEMPTS {
+                            let success = successful * 100 / attempted;
+                            if success >= HALT_POLL_GROW_THRESHOLD {
+                                std::cmp::min(
+                                    std::cmp::max(halt_poll_ns.saturating_mul(2), HALT_POLL_MIN_NS),
+                                    max_ns,
+                                )
+                            } else if success < HALT_POLL_SHRINK_THRESHOLD {
+                                if halt_poll_ns / 2 < HALT_POLL_MIN_NS {
+                                    0
+                                } else {
+                                    halt_poll_ns / 2
+                                }
+                            } else {
+                                halt_poll_ns
+                            }
+                        } else if halt_poll_ns == 0 {
+                            // Without polling, there are no statistics to
+                            // tell whether it would pay off.
+                            disabled_periods += 1;
+                            if disabled_periods >= HALT_POLL_PROBE_PERIODS {
+                                std::cmp::min(HALT_POLL_MIN_NS, max_ns)
+                            } else {
+                                0
+                            }
+                        } else {
+                            halt_poll_ns
+                        };
+
+                        if new_ns != halt_poll_ns {
+                            match vm.set_halt_poll_ns(new_ns) {
+                                Ok(()) => {
+                                    debug!("Halt polling set to {} ns", new_ns);
+                                    halt_poll_ns = new_ns;
+                                    disabled_periods = 0;
+                                }
+                                Err(e) => {
+                                    warn!(
+                                        "Cannot set the halt polling, \
+                                         stopping adaptive halt polling: {}",
+                                        e
+                                    );
+                                    return;
+                                }
+                            }
+                        }
+
+                        // Unparked when the vCPUs are shut down.
+                        thread::park_timeout(HALT_POLL_SAMPLE_PERIOD);
+                    }
+                })
+                .map_err(Error::VcpuSpawn)?,
+        );
+
+        Ok(())
+    }
+
+    // Samples the CPU time of the vCPU threads, to find out when the guest
+    // is idle, and kicks the vCPUs out of the guest to be parked if it is
+    // and they are to be. The memory of a guest idle for long enough is paged
+    // out, once per idle period.
+    fn start_idle_monitor(&mut self, idle: IdleConfig, tids: Vec<libc::pid_t>) -> Result<()> {
+        let threads: Vec<libc::pthread_t> = self
+            .threads
+            .iter()
+            .map(|thread| thread.as_pthread_t())
+            .collect();
+        let vcpus_idle = self.vcpus_idle.clone();
+        let vcpus_pause_signalled = self.vcpus_pause_signalled.clone();
+        let vcpus_kill_signalled = self.vcpus_kill_signalled.clone();
+        let vcpus_hibernated = self.vcpus_hibernated.clone();
+        let vm_memory = self.vm_memory.clone();
+        let timeout = Duration::from_secs(idle.timeout);
+        let hibernate = idle.hibernate.map(Duration::from_secs);
+
+        self.idle_monitor = Some(
+            thread::Builder::new()
+                .name("vcpu_idle".to_string())
+                .spawn(move || {
+                    let mut last_sample = Instant::now();
+                    let mut last_times = vec![Duration::default(); tids.len()];
+                    let mut idle_since = last_sample;
+
+                    while !vcpus_kill_signalled.load(Ordering::SeqCst) {
+                        thread::sleep(IDLE_SAMPLE_PERIOD);
+
+                        let now = Instant::now();
+                        let times = match tids
+                            .iter()
+                            .map(|&tid| thread_cpu_time(tid))
+                            .collect::<io::Result<Vec<Duration>>>()
+                        {
+                            Ok(times) => times,
+                            Err(e) => {
+                                warn!(
+                                    "Cannot read the vCPUs CPU time, stopping idle detection: {}",
+                                    e
+                                );
+                                vcpus_idle.store(false, Ordering::SeqCst);
+                                return;
+                            }
+                        };
+                        let threshold = (now - last_sample) * IDLE_CPU_THRESHOLD / 100;
+                        // A paused guest isn't idle, it doesn't run at all.
+                        let busy = vcpus_pause_signalled.load(Ordering::SeqCst)
+                            || times
+                                .iter()
+                                .zip(last_times.iter())
+                                .any(|(time, last_time)| *time - *last_time >= threshold);
+                        last_sample = now;
+                        last_times = times;
+
+                        if busy {
+                            idle_since = now;
+                            if vcpus_idle.swap(false, Ordering::SeqCst) {
+                                info!("The guest is not idle anymore");
+                            }
+                            if vcpus_hibernated.swap(false, Ordering::SeqCst) {
+                                info!("The guest is out of hibernation");
+                            }
+                        } else if now - idle_since >= timeout
+                            && !vcpus_idle.swap(true, Ordering::SeqCst)
+                        {
+                            info!("The guest is idle");
+                        }
+
+                        if let Some(hibernate) = hibernate {
+                            if now - idle_since >= hibernate
+                                && !vcpus_hibernated.swap(true, Ordering::SeqCst)
+                            {
+                                let size = page_out_memory(&vm_memory.read().unwrap());
+                                info!(
+                                    "The guest is hibernated, {} MiB of memory paged out",
+                                    size >> 20
+                                );
+                            }
+                        }
+
+                        if idle.park && vcpus_idle.load(Ordering::SeqCst) {
+                            let signum = validate_signal_num(VCPU_RTSIG_OFFSET, true).unwrap();
+                            for &thread in threads.iter() {
+                                // Safe because the vCPU threads are only
+                                // joined once this thread is.
+                                unsafe {
+                                    libc::pthread_kill(thread, signum);
+                                }
+                            }
+                        }
+                    }
+                })
+                .map_err(Error::VcpuSpawn)?,
+        );
+
         Ok(())
     }
 
@@ -457,14 +1751,26 @@ impl CpuManager {
         // Tell the vCPUs to stop themselves next time they go through the loop
         self.vcpus_kill_signalled.store(true, Ordering::SeqCst);
 
+        // The idle monitor signals the vCPU threads, it must be done before
+        // they are.
+        if let Some(idle_monitor) = self.idle_monitor.take() {
+            idle_monitor.join().map_err(|_| Error::ThreadCleanup)?;
+        }
+        if let Some(halt_poll_monitor) = self.halt_poll_monitor.take() {
+            halt_poll_monitor.thread().unpark();
+            halt_poll_monitor.join().map_err(|_| Error::ThreadCleanup)?;
+        }
+
         // Signal to the spawned threads (vCPUs and console signal handler). For the vCPU threads
         // this will interrupt the KVM_RUN ioctl() allowing the loop to check the boolean set
         // above.
+        // The paused ones are unparked, so that they see it as well.
         for thread in self.threads.iter() {
             let signum = validate_signal_num(VCPU_RTSIG_OFFSET, true).unwrap();
             unsafe {
                 libc::pthread_kill(thread.as_pthread_t(), signum);
             }
+            thread.thread().unpark();
         }
 
         // Wait for all the threads to finish
@@ -489,6 +1795,17 @@ impl CpuManager {
             }
         }
 
+        // The guest is told its vCPUs were stopped once they are out of the
+        // hypervisor, which the ioctl waits for. Its kvmclock keeps going
+        // meanwhile, its watchdogs seeing the time it was paused for once
+        // resumed.
+        #[cfg(target_arch = "x86_64")]
+        for (id, vcpu) in self.vcpus.iter().enumerate() {
+            if let Err(e) = vcpu.kvmclock_paused() {
+                debug!("Cannot tell the guest the vCPU {} is paused: {}", id, e);
+            }
+        }
+
         Ok(())
     }
 
@@ -505,4 +1822,79 @@ impl CpuManager {
         }
         Ok(())
     }
+
+    /// The vCPUs that failed to run the guest, in the order they did.
+    pub fn vcpu_failures(&self) -> Vec<VcpuFailure> {
+        self.vcpu_failures.lock().unwrap().clone()
+    }
+
+    /// Event written when vCPUs stop on a breakpoint or after a single
+    /// step.
+    pub fn debug_evt(&self) -> &EventFd {
+        &self.debug_evt
+    }
+
+    /// The vCPUs that stopped for the debugger since the last call, in the
+    /// order they did.
+    pub fn take_debug_stops(&self) -> Vec<u8> {
+        self.debug_stops.lock().unwrap().drain(..).collect()
+    }
+
+    /// Sets the hardware breakpoints of all the vCPUs, the one given
+    /// single-stepping as well. The vCPUs must be paused.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_guest_debug(&self, breakpoints: &[u64], single_step: Option<u8>) -> Result<()> {
+        for (id, vcpu) in self.vcpus.iter().enumerate() {
+            vcpu.set_guest_debug(breakpoints, single_step == Some(id as u8))
+                .map_err(Error::SetGuestDebug)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the guest is idle, all its vCPUs halted for longer than the
+    /// idle timeout.
+    pub fn idle(&self) -> bool {
+        self.vcpus_idle.load(Ordering::SeqCst)
+    }
+
+    /// Whether the memory of the idle guest was paged out, and the guest
+    /// wasn't busy since.
+    pub fn hibernated(&self) -> bool {
+        self.vcpus_hibernated.load(Ordering::SeqCst)
+    }
+
+    /// The hypervisor vCPUs the VM booted with.
+    pub fn vcpus(&self) -> &[Arc<dyn hypervisor::Vcpu>] {
+        &self.vcpus
+    }
+
+    /// Thread IDs of the vCPU threads, once started.
+    pub fn vcpu_tids(&self) -> &[libc::pid_t] {
+        &self.vcpu_tids
+    }
+
+    /// The registers of each of the vCPUs, by name. The vCPUs must be
+    /// paused, or stopped.
+    pub fn registers(&self) -> Vec<BTreeMap<String, u64>> {
+        self.vcpus.iter().map(vcpu_registers).collect()
+    }
+
+    /// The vCPU that triple faulted, resetting the VM, since the last call.
+    #[cfg(target_arch = "x86_64")]
+    pub fn take_triple_fault(&self) -> Option<u8> {
+        self.triple_fault.lock().unwrap().take()
+    }
+
+    /// Frequencies the guest CPUs are reported to run at, if known.
+    #[cfg(target_arch = "x86_64")]
+    pub fn frequency(&self) -> Option<CpuFrequency> {
+        self.arch_config.frequency
+    }
+
+    /// The CPUID entries supported by the VM.
+    #[cfg(target_arch = "x86_64")]
+    pub fn cpuid(&self) -> &CpuId {
+        &self.arch_config.cpuid
+    }
 }
@@ -9,42 +9,76 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 //
 
-use crate::config::ConsoleOutputMode;
+use crate::config::{
+    ConsoleConfig, ConsoleOutputMode, DiskCacheMode, DiskConfig, DiskModel, NetConfig, NetModel,
+    Profile, RateLimiterConfig, VsockConfig, LEGACY_UARTS,
+};
+#[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+use crate::config::{UserDeviceConfig, VmConfig};
+use crate::diagnostics::{ConsoleLog, ConsoleLogWriter};
+use crate::guest_os::{ConsoleProbeWriter, GuestOsProbe};
+#[cfg(feature = "pci_support")]
+use crate::interrupt::MsiInterruptManager;
+use crate::memory_manager::Error as MemoryManagerError;
+#[cfg(feature = "pci_support")]
+use crate::memory_manager::MemoryManager;
+#[cfg(feature = "pci_support")]
+use crate::plugin::{self, PluginProcess};
+#[cfg(feature = "pci_support")]
+use crate::sriov;
+use crate::sriov::{VfInfo, VirtualFunction};
 use crate::vm::VmInfo;
 
 use devices::ioapic;
-use kvm_bindings::kvm_userspace_memory_region;
-use kvm_ioctls::*;
+#[cfg(feature = "mmio_support")]
+use hypervisor::DataMatch;
+#[cfg(feature = "pci_support")]
+use hypervisor::Device;
+#[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+use hypervisor::IoEventAddress;
+use hypervisor::UserMemoryRegion;
 use libc::O_TMPFILE;
 use libc::{EFD_NONBLOCK, TIOCGWINSZ};
 
-use net_util::Tap;
+use net_util::{MacAddr, Tap};
+#[cfg(all(feature = "pci_support", target_arch = "x86_64"))]
+use pci::PciConfigIo;
 #[cfg(feature = "pci_support")]
 use pci::{
     DeviceRelocation, InterruptDelivery, InterruptParameters, PciBarRegionType, PciBus,
-    PciConfigIo, PciConfigMmio, PciDevice, PciInterruptPin, PciRoot,
+    PciConfigMmio, PciDevice, PciInterruptPin, PciRoot,
 };
 use qcow::{self, ImageType, QcowFile};
 
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::CStr;
 use std::fs::{File, OpenOptions};
-use std::io::{self, sink, stdout};
+use std::io::{self, sink, stdout, Read, Write};
 
+#[cfg(target_arch = "x86_64")]
 use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
 use std::os::unix::fs::OpenOptionsExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::ptr::null_mut;
 use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
 #[cfg(feature = "pci_support")]
 use std::sync::Weak;
 use std::sync::{Arc, Mutex, RwLock};
 #[cfg(feature = "pci_support")]
-use vfio::{VfioDevice, VfioDmaMapping, VfioPciDevice, VfioPciError};
+use vfio::{
+    VfioDevice, VfioDmaMapping, VfioPciDevice, VfioPciError, VfioUserDevice, VfioUserDmaMapping,
+};
 use vm_allocator::SystemAllocator;
+#[cfg(feature = "pci_support")]
+use vm_device::{InterruptManager, MemoryListener, Pausable};
 use vm_memory::GuestAddress;
 use vm_memory::{Address, GuestMemoryMmap, GuestUsize};
 #[cfg(feature = "pci_support")]
 use vm_virtio::transport::VirtioPciDevice;
-use vm_virtio::transport::VirtioTransport;
+use vm_virtio::transport::{DeviceAudit, VirtioTransport};
 use vm_virtio::vhost_user::VhostUserConfig;
 #[cfg(feature = "pci_support")]
 use vm_virtio::{DmaRemapping, IommuMapping, VirtioIommuRemapping};
@@ -54,6 +88,30 @@ use vmm_sys_util::eventfd::EventFd;
 #[cfg(feature = "mmio_support")]
 const MMIO_LEN: u64 = 0x1000;
 
+// The legacy serial port, a 16550A UART on x86_64 and a PL011 on aarch64.
+#[cfg(target_arch = "x86_64")]
+type SerialDevice = devices::legacy::Serial;
+#[cfg(target_arch = "aarch64")]
+type SerialDevice = devices::legacy::Pl011;
+
+// Amount of serial output kept around while no client is connected to the
+// serial socket.
+const SERIAL_SOCKET_BUFFER_SIZE: usize = 64 << 10;
+
+// The guest kernels add the NVDIMMs to their memory map by memory sections,
+// of 128 MiB.
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+const NVDIMM_ALIGNMENT: GuestUsize = 128 << 20;
+
+// I/O ports of each PCI segment other than the segment 0, and alignment of
+// their MMIO windows.
+#[cfg(all(feature = "pci_support", target_arch = "x86_64"))]
+const PCI_SEGMENT_IO_SIZE: GuestUsize = 0x800;
+#[cfg(all(feature = "pci_support", target_arch = "x86_64"))]
+const PCI_SEGMENT_MEM32_ALIGNMENT: GuestUsize = 1 << 20;
+#[cfg(all(feature = "pci_support", target_arch = "x86_64"))]
+const PCI_SEGMENT_MEM64_ALIGNMENT: GuestUsize = 1 << 30;
+
 /// Errors associated with device manager
 #[derive(Debug)]
 pub enum DeviceManagerError {
@@ -72,12 +130,60 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-net device
     CreateVirtioNet(vm_virtio::net::Error),
 
+    /// Cannot create e1000 device
+    #[cfg(feature = "e1000_support")]
+    CreateE1000(e1000::Error),
+
+    /// The e1000 device needs to be built in, and exposed through PCI.
+    E1000Unsupported,
+
+    /// Cannot create AHCI controller
+    #[cfg(feature = "ahci_support")]
+    CreateAhci(ahci::Error),
+
+    /// The AHCI controller needs to be built in, and exposed through PCI.
+    AhciUnsupported,
+
+    /// The ACPI sensors need ACPI support, which the unikernel profile
+    /// does without.
+    SensorsUnsupported,
+
+    /// Cannot connect to the TPM emulator
+    CreateTpm(devices::tpm::emulator::Error),
+
+    /// The TPM needs ACPI support, which the unikernel profile does
+    /// without.
+    TpmUnsupported,
+
+    /// The pvpanic device needs ACPI support, which the unikernel profile
+    /// does without.
+    PvpanicUnsupported,
+
+    /// The NVDIMMs need ACPI support, which the unikernel profile does
+    /// without.
+    NvdimmUnsupported,
+
+    /// Cannot create rate limiter
+    CreateRateLimiter(io::Error),
+
+    /// Cannot register a device for the guest RAM changes.
+    RegisterMemoryListener(MemoryManagerError),
+
+    /// Cannot allocate a KVM memory slot.
+    AllocateKvmSlot(MemoryManagerError),
+
     /// Cannot create virtio-console device
     CreateVirtioConsole(io::Error),
 
     /// Cannot create virtio-rng device
     CreateVirtioRng(io::Error),
 
+    /// Cannot create virtio-gpu device
+    CreateVirtioGpu(io::Error),
+
+    /// Cannot create virtio-input device
+    CreateVirtioInput(io::Error),
+
     /// Cannot create virtio-fs device
     CreateVirtioFs(vm_virtio::vhost_user::Error),
 
@@ -105,9 +211,27 @@ pub enum DeviceManagerError {
     /// Cannot open qcow disk path
     QcowDeviceCreate(qcow::Error),
 
+    /// Direct I/O is only supported on raw disk images
+    DirectIoImage(PathBuf),
+
+    /// Cannot open the disk image with O_DIRECT
+    DirectIo(io::Error),
+
+    /// Cannot compute the digest the disk image is cached by
+    BlockCache(io::Error),
+
     /// Cannot open tap interface
     OpenTap(net_util::TapError),
 
+    /// Cannot duplicate a tap file descriptor the process inherited
+    DupTapFd(io::Error),
+
+    /// No file was passed through the API under this name
+    PassedFdNotFound(String),
+
+    /// Cannot duplicate a file passed through the API
+    PassedFdClone(io::Error),
+
     /// Cannot allocate IRQ.
     AllocateIrq,
 
@@ -137,6 +261,18 @@ pub enum DeviceManagerError {
     /// Cannot find a memory range for persistent memory
     PmemRangeAllocation,
 
+    /// Cannot open the backing file of an NVDIMM
+    NvdimmFileOpen(io::Error),
+
+    /// Cannot set the size of the backing file of an NVDIMM
+    NvdimmFileSetLen(io::Error),
+
+    /// Cannot find a memory range for an NVDIMM
+    NvdimmRangeAllocation,
+
+    /// Cannot map the backing file of an NVDIMM
+    NvdimmMmap(io::Error),
+
     /// Cannot find a memory range for virtio-fs
     FsRangeAllocation,
 
@@ -146,6 +282,27 @@ pub enum DeviceManagerError {
     /// Error creating console output file
     ConsoleOutputFileOpen(io::Error),
 
+    /// Error creating serial pseudo-terminal
+    SerialPtyOpen(io::Error),
+
+    /// Error creating console pseudo-terminal
+    ConsolePtyOpen(io::Error),
+
+    /// Error creating serial UNIX socket
+    SerialSocketOpen(io::Error),
+
+    /// Error creating console UNIX socket
+    ConsoleSocketOpen(io::Error),
+
+    /// More UARTs than the legacy COM ports
+    TooManyUarts(usize),
+
+    /// UART IRQ other than the legacy COM port ones
+    InvalidUartIrq(u8),
+
+    /// UARTs are only supported on x86_64
+    UartUnsupported,
+
     /// Cannot create a VFIO device
     #[cfg(feature = "pci_support")]
     VfioCreate(vfio::VfioError),
@@ -154,10 +311,26 @@ pub enum DeviceManagerError {
     #[cfg(feature = "pci_support")]
     VfioPciCreate(vfio::VfioPciError),
 
+    /// Cannot read the option ROM file of a VFIO device
+    #[cfg(feature = "pci_support")]
+    ReadRomFile(io::Error),
+
+    /// Cannot assign the MAC address of an SR-IOV virtual function
+    #[cfg(feature = "pci_support")]
+    SriovMac(sriov::Error),
+
     /// Failed to map VFIO MMIO region.
     #[cfg(feature = "pci_support")]
     VfioMapRegion(VfioPciError),
 
+    /// Cannot connect to a vfio-user device
+    #[cfg(feature = "pci_support")]
+    VfioUserCreate(vfio::VfioUserError),
+
+    /// Cannot run the plugin of a plugin device
+    #[cfg(feature = "pci_support")]
+    SpawnPlugin(plugin::Error),
+
     /// Failed to create the KVM device.
     CreateKvmDevice(io::Error),
 
@@ -169,9 +342,97 @@ pub enum DeviceManagerError {
 
     /// Failed to allocate IO port
     AllocateIOPort,
+
+    /// More PCI segments than MAX_PCI_SEGMENTS
+    TooManyPciSegments(u16),
+
+    /// Cannot allocate the windows of a PCI segment
+    PciSegmentAllocation(u16),
+
+    /// Device on a PCI segment the VM doesn't have, or attached to the
+    /// virtual IOMMU while not on the segment 0
+    InvalidPciSegment(u16),
+
+    /// PCI segments other than the segment 0 are only supported on x86_64
+    PciSegmentsUnsupported,
+
+    /// No virtio device has the given ID.
+    UnknownVirtioDevice(String),
+
+    /// A device is given the ID of a device the VMM adds on its own.
+    DuplicateDeviceId(String),
+
+    /// No disk of a disk group has the given ID.
+    UnknownGroupDisk(String),
+
+    /// No virtio-blk or virtio-net device with a rate limiter has the given
+    /// ID.
+    UnknownRateLimitedDevice(String),
+
+    /// Failed to restart a virtio device.
+    RestartVirtioDevice(vm_virtio::transport::RestartError),
+
+    /// The devices are only hot-added and removed through ACPI, on the PCI
+    /// bus 0, for the VMs using MSI-X.
+    PciHotplugUnsupported,
+
+    /// The hot-added devices can't be attached to the virtual IOMMU.
+    HotplugIommuUnsupported,
+
+    /// No device which can be removed has the given ID.
+    UnknownRemovableDevice(String),
+
+    /// Cannot notify the guest about a device being added or removed.
+    PciHotplugNotify(io::Error),
+
+    /// Cannot unregister the ioeventfds of a removed device.
+    UnregisterIoevent(io::Error),
+
+    /// Cannot unmap the MMIO regions of a removed VFIO device.
+    VfioUnmapRegion(VfioPciError),
+
+    /// Cannot free the interrupts of a removed VFIO device.
+    VfioFreeInterrupts(VfioPciError),
+
+    /// Cannot create the MSI-X interrupts of a virtio device.
+    CreateInterruptGroup(io::Error),
+
+    /// Cannot free the interrupts of a removed virtio device.
+    FreeInterrupts(io::Error),
+
+    /// Cannot pause a device.
+    PauseDevice(io::Error),
+
+    /// Cannot resume a device.
+    ResumeDevice(io::Error),
 }
 pub type DeviceManagerResult<T> = result::Result<T, DeviceManagerError>;
 
+// A virtio device, whether it is attached to the virtual IOMMU, and the ID
+// of its configuration, if any.
+type VirtioDeviceEntry = (Box<dyn vm_virtio::VirtioDevice>, bool, Option<String>);
+
+// A device of the PCI bus 0 which the guest can be asked to eject.
+#[cfg(feature = "pci_support")]
+#[cfg_attr(not(all(feature = "acpi", target_arch = "x86_64")), allow(dead_code))]
+struct RemovablePciDevice {
+    device_id: u32,
+    // Listener of the guest RAM changes the device is registered with.
+    memory_listener: Option<Arc<dyn MemoryListener>>,
+}
+
+// Hotplug of the devices of the PCI bus 0: the guest is notified of the
+// devices added and removed through the GED, and ejects the removed ones
+// through their ACPI slot.
+#[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+struct PciHotplug {
+    bus: Arc<Mutex<PciBus>>,
+    device: Arc<Mutex<devices::AcpiPciHotplugDevice>>,
+    eject_evt: EventFd,
+    memory: Arc<RwLock<GuestMemoryMmap>>,
+    memory_manager: Arc<Mutex<MemoryManager>>,
+}
+
 struct InterruptInfo<'a> {
     _msi_capable: bool,
     ioapic: &'a Option<Arc<Mutex<ioapic::Ioapic>>>,
@@ -236,29 +497,392 @@ pub fn get_win_size() -> (u16, u16) {
     (ws.cols, ws.rows)
 }
 
+/// Pseudo-terminal backing the serial port or the virtio-console.
+pub struct PtyPair {
+    /// Main side of the terminal, used by the VMM.
+    pub main: File,
+    // The subordinate side is kept open for the lifetime of the VM so that
+    // clients can attach and detach without the main side being hung up.
+    _sub: File,
+    /// Path to the subordinate side, to be opened by clients.
+    pub path: PathBuf,
+}
+
+// Pauses a device, only warning about the worker threads which don't pause
+// in time.
+fn pause_device<T: Pausable + ?Sized>(id: &str, device: &mut T) -> DeviceManagerResult<()> {
+    match device.pause() {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+            warn!("Device {} didn't pause in time: {}", id, e);
+            Ok(())
+        }
+        result => result.map_err(DeviceManagerError::PauseDevice),
+    }
+}
+
+fn create_pty() -> io::Result<PtyPair> {
+    // Safe because we check the return value.
+    let main_fd = unsafe {
+        libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK | libc::O_CLOEXEC)
+    };
+    if main_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we just created this file descriptor and nothing else
+    // owns it.
+    let main = unsafe { File::from_raw_fd(main_fd) };
+
+    // Safe because main_fd is a valid pseudo-terminal and we check the
+    // return values.
+    if unsafe { libc::grantpt(main_fd) } < 0 || unsafe { libc::unlockpt(main_fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut name = [0 as libc::c_char; 64];
+    // Safe because the buffer is large enough and its size is provided.
+    let ret = unsafe { libc::ptsname_r(main_fd, name.as_mut_ptr(), name.len()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    // Safe because ptsname_r() succeeded, hence name is NUL terminated.
+    let path = PathBuf::from(
+        unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned(),
+    );
+
+    let sub = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&path)?;
+
+    // Put the terminal in raw mode, clients attaching to it can then apply
+    // their own settings.
+    // Safe because termios is only written by tcgetattr() and we check the
+    // return values.
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(sub.as_raw_fd(), &mut termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        if libc::tcsetattr(sub.as_raw_fd(), libc::TCSANOW, &termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(PtyPair {
+        main,
+        _sub: sub,
+        path,
+    })
+}
+
+struct SocketOutput {
+    client: Option<UnixStream>,
+    // Output the client didn't take yet, or produced while no client is
+    // connected.
+    buffer: VecDeque<u8>,
+    // Epoll set the client is polled from, with its file descriptor and the
+    // data of its event, for the client to be polled for EPOLLOUT as long as
+    // the buffer isn't empty.
+    epoll: Option<(RawFd, RawFd, u64)>,
+    polling_out: bool,
+}
+
+impl SocketOutput {
+    fn drop_client(&mut self) {
+        self.client = None;
+        self.epoll = None;
+        self.polling_out = false;
+    }
+
+    // Writes as much of the buffer as the client takes without blocking.
+    fn write_buffer(&mut self) {
+        while let Some(client) = self.client.as_mut() {
+            let (front, _) = self.buffer.as_slices();
+            if front.is_empty() {
+                break;
+            }
+            match client.write(front) {
+                Ok(count) if count > 0 => {
+                    self.buffer.drain(..count);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // The client went away, keep the output for the next one.
+                _ => self.drop_client(),
+            }
+        }
+
+        let polling_out = self.client.is_some() && !self.buffer.is_empty();
+        self.set_polling_out(polling_out);
+    }
+
+    fn set_polling_out(&mut self, polling_out: bool) {
+        if polling_out == self.polling_out {
+            return;
+        }
+        if let Some((epoll_fd, fd, data)) = self.epoll {
+            let mut events = epoll::Events::EPOLLIN;
+            if polling_out {
+                events |= epoll::Events::EPOLLOUT;
+            }
+            match epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_MOD,
+                fd,
+                epoll::Event::new(events, data),
+            ) {
+                Ok(_) => self.polling_out = polling_out,
+                Err(e) => warn!("Cannot poll the socket client for output: {}", e),
+            }
+        }
+    }
+}
+
+struct SocketWriter {
+    output: Arc<Mutex<SocketOutput>>,
+}
+
+impl Write for SocketWriter {
+    // Never blocks: the output the client doesn't take right away is kept
+    // in the buffer, and written once the client is writable again.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut output = self.output.lock().unwrap();
+
+        output.buffer.extend(buf);
+        let len = output.buffer.len();
+        if len > SERIAL_SOCKET_BUFFER_SIZE {
+            output.buffer.drain(..len - SERIAL_SOCKET_BUFFER_SIZE);
+        }
+        output.write_buffer();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// UNIX socket backing the serial port. One client at a time can connect to
+/// it, and the latest client always replaces the previous one.
+pub struct SerialSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    // Client side used for reading the serial input.
+    input: Mutex<Option<UnixStream>>,
+    output: Arc<Mutex<SocketOutput>>,
+}
+
+impl SerialSocket {
+    fn new(path: &Path) -> io::Result<Self> {
+        std::fs::remove_file(path).unwrap_or_default();
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(SerialSocket {
+            listener,
+            path: path.to_path_buf(),
+            input: Mutex::new(None),
+            output: Arc::new(Mutex::new(SocketOutput {
+                client: None,
+                buffer: VecDeque::new(),
+                epoll: None,
+                polling_out: false,
+            })),
+        })
+    }
+
+    fn writer(&self) -> SocketWriter {
+        SocketWriter {
+            output: self.output.clone(),
+        }
+    }
+
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// Accepts a pending client connection, replacing the current client.
+    /// Returns the file descriptor to poll for the serial input, which
+    /// `watch_output()` is to be given once it is polled.
+    pub fn accept(&self) -> io::Result<RawFd> {
+        let (stream, _) = self.listener.accept()?;
+
+        // Neither the vCPU writing to the serial port, nor the VMM thread,
+        // may block on a client which doesn't read its output.
+        stream.set_nonblocking(true)?;
+        let input = stream.try_clone()?;
+        let input_fd = input.as_raw_fd();
+
+        let mut output = self.output.lock().unwrap();
+        output.drop_client();
+        output.client = Some(stream);
+        drop(output);
+        *self.input.lock().unwrap() = Some(input);
+
+        Ok(input_fd)
+    }
+
+    /// Polls the client for EPOLLOUT from the epoll set it was added to,
+    /// with the data of its event, whenever it has output pending. The
+    /// output buffered while no client was connected is sent first.
+    pub fn watch_output(&self, epoll_fd: RawFd, fd: RawFd, data: u64) {
+        let mut output = self.output.lock().unwrap();
+        if output.client.is_some() {
+            output.epoll = Some((epoll_fd, fd, data));
+            output.write_buffer();
+        }
+    }
+
+    /// Writes the pending output the client takes, once epoll reported it
+    /// writable.
+    pub fn write_output(&self) {
+        self.output.lock().unwrap().write_buffer();
+    }
+
+    /// Reads the input sent by the current client. The client is dropped
+    /// once it closed its side of the connection.
+    pub fn read_input(&self, out: &mut [u8]) -> io::Result<usize> {
+        let mut input = self.input.lock().unwrap();
+        let result = match input.as_mut() {
+            Some(stream) => stream.read(out),
+            None => return Ok(0),
+        };
+
+        match result {
+            Ok(count) if count > 0 => Ok(count),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => result,
+            _ => {
+                // The client closed the connection or failed, drop it.
+                self.output.lock().unwrap().drop_client();
+                *input = None;
+                result
+            }
+        }
+    }
+}
+
+impl Drop for SerialSocket {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).unwrap_or_default();
+    }
+}
+
+/// Output of the serial port writing to a file or to nowhere, which can be
+/// switched to another file, or to nowhere, while the VM runs.
+#[derive(Clone, Default)]
+pub struct SerialOutput(Arc<Mutex<Option<Box<dyn io::Write + Send>>>>);
+
+impl SerialOutput {
+    fn set(&self, writer: Option<Box<dyn io::Write + Send>>) {
+        *self.0.lock().unwrap() = writer;
+    }
+}
+
+impl io::Write for SerialOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(writer) => writer.write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
 pub struct Console {
-    // Serial port on 0x3f8
-    serial: Option<Arc<Mutex<devices::legacy::Serial>>>,
+    // Serial port on 0x3f8, or the PL011
+    serial: Option<Arc<Mutex<SerialDevice>>>,
+    // Output of the serial port, when it goes to a file or to nowhere
+    serial_output: Option<SerialOutput>,
     console_input: Option<Arc<vm_virtio::ConsoleInput>>,
     input_enabled: bool,
+    serial_pty: Option<PtyPair>,
+    console_pty: Option<PtyPair>,
+    serial_socket: Option<SerialSocket>,
+    console_socket: Option<SerialSocket>,
+    // Sockets of the additional virtio-console ports
+    console_port_sockets: Vec<SerialSocket>,
+    // Additional 16550 UARTs
+    uarts: Vec<Uart>,
 }
 
 impl Console {
     pub fn queue_input_bytes(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
+        // Devices backed by a pseudo-terminal or a socket only get their
+        // input from it.
+        if self.serial_pty.is_none() && self.serial_socket.is_none() {
+            self.queue_serial_input_bytes(out)?;
+        }
+        if self.console_pty.is_none() && self.console_socket.is_none() {
+            self.queue_console_input_bytes(out);
+        }
+
+        Ok(())
+    }
+
+    pub fn queue_serial_input_bytes(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
         if self.serial.is_some() {
             self.serial
                 .as_ref()
                 .unwrap()
                 .lock()
-                .expect("Failed to process stdin event due to poisoned lock")
+                .expect("Failed to process input event due to poisoned lock")
                 .queue_input_bytes(out)?;
         }
 
+        Ok(())
+    }
+
+    pub fn queue_console_input_bytes(&self, out: &[u8]) {
         if self.console_input.is_some() {
             self.console_input.as_ref().unwrap().queue_input_bytes(out);
         }
+    }
 
-        Ok(())
+    pub fn serial_pty(&self) -> Option<&PtyPair> {
+        self.serial_pty.as_ref()
+    }
+
+    pub fn console_pty(&self) -> Option<&PtyPair> {
+        self.console_pty.as_ref()
+    }
+
+    pub fn serial_socket(&self) -> Option<&SerialSocket> {
+        self.serial_socket.as_ref()
+    }
+
+    pub fn console_socket(&self) -> Option<&SerialSocket> {
+        self.console_socket.as_ref()
+    }
+
+    /// Sockets of the additional virtio-console ports, in their order.
+    pub fn console_port_sockets(&self) -> &[SerialSocket] {
+        &self.console_port_sockets
+    }
+
+    /// Queues input for the `index`th additional virtio-console port.
+    pub fn queue_console_port_input_bytes(&self, index: usize, out: &[u8]) {
+        if let Some(console_input) = &self.console_input {
+            console_input.queue_port_input_bytes(index + 1, out);
+        }
+    }
+
+    /// Tells the guest whether a client is connected to the socket of the
+    /// `index`th additional virtio-console port.
+    pub fn set_console_port_connected(&self, index: usize, connected: bool) {
+        if let Some(console_input) = &self.console_input {
+            console_input.set_port_connected(index + 1, connected);
+        }
     }
 
     pub fn update_console_size(&self, cols: u16, rows: u16) {
@@ -273,6 +897,70 @@ impl Console {
     pub fn input_enabled(&self) -> bool {
         self.input_enabled
     }
+
+    pub fn uarts(&self) -> &[Uart] {
+        &self.uarts
+    }
+
+    /// Whether the output of the serial port can be switched to another
+    /// file, or to nowhere.
+    pub fn serial_output_switchable(&self) -> bool {
+        self.serial_output.is_some()
+    }
+}
+
+/// Additional 16550 UART, along with the pseudo-terminal or UNIX socket
+/// backing it, if any.
+pub struct Uart {
+    serial: Arc<Mutex<devices::legacy::Serial>>,
+    pty: Option<PtyPair>,
+    socket: Option<SerialSocket>,
+    port: u16,
+    irq: u8,
+}
+
+impl Uart {
+    pub fn queue_input_bytes(&self, out: &[u8]) -> vmm_sys_util::errno::Result<()> {
+        self.serial
+            .lock()
+            .expect("Failed to process input event due to poisoned lock")
+            .queue_input_bytes(out)
+    }
+
+    pub fn pty(&self) -> Option<&PtyPair> {
+        self.pty.as_ref()
+    }
+
+    pub fn socket(&self) -> Option<&SerialSocket> {
+        self.socket.as_ref()
+    }
+
+    /// I/O port and IRQ the UART is at.
+    pub fn resources(&self) -> (u16, u8) {
+        (self.port, self.irq)
+    }
+}
+
+/// Windows of a PCI segment other than the segment 0: the MMCONFIG area of
+/// its bus, and the ranges its BARs are placed in, which its host bridge
+/// describes to the guest.
+#[derive(Clone, Copy, Debug)]
+pub struct PciSegmentWindows {
+    pub id: u16,
+    pub mmconfig: (GuestAddress, GuestUsize),
+    pub io: (GuestAddress, GuestUsize),
+    pub mem32: (GuestAddress, GuestUsize),
+    pub mem64: (GuestAddress, GuestUsize),
+}
+
+// Bus of a PCI segment other than the segment 0, along with the address
+// manager allocating, and moving, the BARs of its devices within the
+// windows of the segment.
+#[cfg(feature = "pci_support")]
+struct PciSegment {
+    windows: PciSegmentWindows,
+    bus: PciBus,
+    address_manager: Arc<AddressManager>,
 }
 
 struct AddressManager {
@@ -280,7 +968,9 @@ struct AddressManager {
     io_bus: Arc<devices::Bus>,
     mmio_bus: Arc<devices::Bus>,
     #[cfg(feature = "pci_support")]
-    vm_fd: Arc<VmFd>,
+    vm: Arc<dyn hypervisor::Vm>,
+    #[cfg(feature = "pci_support")]
+    msi_interrupt_manager: Arc<dyn InterruptManager>,
 }
 
 #[cfg(feature = "pci_support")]
@@ -316,12 +1006,12 @@ impl DeviceRelocation for AddressManager {
             }
             PciBarRegionType::Memory32BitRegion | PciBarRegionType::Memory64BitRegion => {
                 // Update system allocator
-                self.allocator
-                    .lock()
-                    .unwrap()
-                    .free_mmio_addresses(GuestAddress(old_base), len as GuestUsize);
-
                 if region_type == PciBarRegionType::Memory32BitRegion {
+                    self.allocator
+                        .lock()
+                        .unwrap()
+                        .free_mmio_hole_addresses(GuestAddress(old_base), len as GuestUsize);
+
                     self.allocator
                         .lock()
                         .unwrap()
@@ -337,6 +1027,11 @@ impl DeviceRelocation for AddressManager {
                             )
                         })?;
                 } else {
+                    self.allocator
+                        .lock()
+                        .unwrap()
+                        .free_mmio_addresses(GuestAddress(old_base), len as GuestUsize);
+
                     self.allocator
                         .lock()
                         .unwrap()
@@ -366,11 +1061,11 @@ impl DeviceRelocation for AddressManager {
             if bar_addr == new_base {
                 for (event, addr) in virtio_pci_dev.ioeventfds(old_base) {
                     let io_addr = IoEventAddress::Mmio(addr);
-                    self.vm_fd.unregister_ioevent(event, &io_addr)?;
+                    self.vm.unregister_ioevent(event, &io_addr)?;
                 }
                 for (event, addr) in virtio_pci_dev.ioeventfds(new_base) {
                     let io_addr = IoEventAddress::Mmio(addr);
-                    self.vm_fd.register_ioevent(event, &io_addr, NoDatamatch)?;
+                    self.vm.register_ioevent(event, &io_addr, None)?;
                 }
             }
         }
@@ -398,6 +1093,83 @@ pub struct DeviceManager {
     // Virtual IOMMU ID along with the list of device IDs attached to the
     // virtual IOMMU. This is useful for filling the ACPI IORT table.
     virt_iommu: Option<(u32, Vec<u32>)>,
+
+    // Base address, size and IRQ of the virtio-mmio devices, which the
+    // aarch64 device tree describes.
+    virtio_mmio_devices: Vec<(GuestAddress, GuestUsize, u32)>,
+
+    // Transports of the virtio devices, along with the IDs the API refers to
+    // them with.
+    virtio_devices: Vec<(String, Arc<Mutex<dyn VirtioTransport>>)>,
+
+    // PCI addresses of the devices, by ID.
+    pci_devices: BTreeMap<String, String>,
+
+    // SR-IOV virtual functions passed through with VFIO, by ID.
+    sriov_vfs: BTreeMap<String, VirtualFunction>,
+
+    // Last output of the serial port and the virtio console, kept for the
+    // diagnostic bundles.
+    console_log: Option<Arc<ConsoleLog>>,
+
+    // Guest OS probed from the boot messages of the serial port and the
+    // virtio console, and from the guest agent.
+    guest_os_probe: Option<Arc<GuestOsProbe>>,
+
+    // Written when virtio-blk disk images run out of space, along with the
+    // flags of the devices, by ID.
+    out_of_space_evt: EventFd,
+    out_of_space_disks: Vec<(String, Arc<AtomicBool>)>,
+
+    // Shares of the bandwidth of their group of the virtio-blk disks in a
+    // disk group, by ID.
+    disk_bandwidth_shares: Vec<(String, vm_virtio::BandwidthShare)>,
+
+    // Token buckets of the virtio-blk and virtio-net devices having a rate
+    // limiter, by ID.
+    rate_limiter_buckets: Vec<(String, vm_virtio::RateLimiterBuckets)>,
+
+    // Display of the virtio-gpu device.
+    gpu_display: Option<vm_virtio::GpuDisplay>,
+
+    // Events pending for the virtio-input keyboard and tablet.
+    input_events: Vec<(vm_virtio::InputKind, Arc<vm_virtio::InputEvents>)>,
+
+    // Windows of the PCI segments other than the segment 0, along with the
+    // address managers their buses relocate the BARs through.
+    pci_segments: Vec<(PciSegmentWindows, Arc<AddressManager>)>,
+
+    // ACPI Generic Event Device along with its IRQ
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    ged_notification_device: Option<(Arc<Mutex<devices::AcpiGEDDevice>>, u32)>,
+
+    // Synthetic ACPI battery and thermal zone readings
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    acpi_sensors_device: Option<Arc<Mutex<devices::AcpiSensorsDevice>>>,
+
+    // pvpanic device along with the event it writes on guest panics
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pvpanic_device: Option<(Arc<Mutex<devices::legacy::Pvpanic>>, EventFd)>,
+
+    // Guest ranges of the ACPI NVDIMMs, by handle less one
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    nvdimm_ranges: Vec<(GuestAddress, u64)>,
+
+    // Devices of the PCI bus 0 which can be removed, by ID.
+    #[cfg(feature = "pci_support")]
+    removable_pci_devices: BTreeMap<String, RemovablePciDevice>,
+
+    // Processes of the plugin devices, killed along with the device manager.
+    #[cfg(feature = "pci_support")]
+    _plugin_processes: Vec<PluginProcess>,
+
+    // Hotplug of the devices of the PCI bus 0, when the VM supports it.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pci_hotplug: Option<PciHotplug>,
+
+    // The e1000 devices, paused along with the virtio ones, by ID.
+    #[cfg(feature = "e1000_support")]
+    e1000_devices: Vec<(String, Arc<Mutex<e1000::E1000>>)>,
 }
 
 impl DeviceManager {
@@ -405,18 +1177,18 @@ impl DeviceManager {
         vm_info: &VmInfo,
         mut allocator: SystemAllocator,
         _msi_capable: bool,
-        userspace_ioapic: bool,
-        mut mem_slots: u32,
+        _userspace_ioapic: bool,
         _exit_evt: &EventFd,
-        reset_evt: &EventFd,
+        _reset_evt: &EventFd,
     ) -> DeviceManagerResult<Self> {
         let io_bus = devices::Bus::new();
         let mmio_bus = devices::Bus::new();
 
-        let ioapic = if userspace_ioapic {
+        #[cfg(target_arch = "x86_64")]
+        let ioapic = if _userspace_ioapic {
             // Create IOAPIC
             let ioapic = Arc::new(Mutex::new(ioapic::Ioapic::new(
-                vm_info.vm_fd.clone(),
+                vm_info.vm.clone(),
                 APIC_START,
             )));
             mmio_bus
@@ -426,46 +1198,79 @@ impl DeviceManager {
         } else {
             None
         };
+        // The interrupt controller is always emulated by the hypervisor.
+        #[cfg(target_arch = "aarch64")]
+        let ioapic = None;
 
         let interrupt_info = InterruptInfo {
             _msi_capable,
             ioapic: &ioapic,
         };
 
-        let serial_writer: Option<Box<dyn io::Write + Send>> = match vm_info.vm_cfg.serial.mode {
-            ConsoleOutputMode::File => Some(Box::new(
-                File::create(vm_info.vm_cfg.serial.file.as_ref().unwrap())
-                    .map_err(DeviceManagerError::SerialOutputFileOpen)?,
-            )),
-            ConsoleOutputMode::Tty => Some(Box::new(stdout())),
-            ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
+        let console_log = vm_info
+            .vm_cfg
+            .diagnostics
+            .as_ref()
+            .filter(|diagnostics| diagnostics.path.is_some())
+            .map(|_| Arc::new(ConsoleLog::default()));
+        let guest_os_probe = vm_info
+            .vm_cfg
+            .guest_os
+            .as_ref()
+            .map(|_| Arc::new(GuestOsProbe::default()));
+
+        let (mut serial_writer, serial_pty, serial_socket) = DeviceManager::create_serial_backend(
+            &vm_info.vm_cfg.serial.mode,
+            &vm_info.vm_cfg.serial.file,
+        )?;
+        // The file the serial port writes to can be switched while the VM
+        // runs.
+        let serial_output = match vm_info.vm_cfg.serial.mode {
+            ConsoleOutputMode::File | ConsoleOutputMode::Null => {
+                let output = SerialOutput::default();
+                output.set(serial_writer);
+                serial_writer = Some(Box::new(output.clone()));
+                Some(output)
+            }
+            _ => None,
         };
+        if let Some(console_log) = &console_log {
+            let writer = serial_writer.unwrap_or_else(|| Box::new(sink()));
+            serial_writer = Some(Box::new(ConsoleLogWriter::new(console_log.clone(), writer)));
+        }
+        if let Some(probe) = &guest_os_probe {
+            let writer = serial_writer.unwrap_or_else(|| Box::new(sink()));
+            serial_writer = Some(Box::new(ConsoleProbeWriter::new(probe.clone(), writer)));
+        }
         let serial = if vm_info.vm_cfg.serial.mode != ConsoleOutputMode::Off {
             // Serial is tied to IRQ #4
+            #[cfg(target_arch = "x86_64")]
             let serial_irq = 4;
-            let interrupt: Box<dyn devices::Interrupt> = if let Some(ioapic) = &ioapic {
-                Box::new(UserIoapicIrq::new(ioapic.clone(), serial_irq))
-            } else {
-                let serial_evt = EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
-                vm_info
-                    .vm_fd
-                    .register_irqfd(&serial_evt, serial_irq as u32)
-                    .map_err(DeviceManagerError::Irq)?;
+            #[cfg(target_arch = "aarch64")]
+            let serial_irq = arch::layout::LEGACY_SERIAL_IRQ as usize;
+            let interrupt = DeviceManager::create_legacy_interrupt(vm_info, &ioapic, serial_irq)?;
 
-                Box::new(KernelIoapicIrq::new(serial_evt))
-            };
+            let serial = Arc::new(Mutex::new(SerialDevice::new(interrupt, serial_writer)));
 
-            let serial = Arc::new(Mutex::new(devices::legacy::Serial::new(
-                interrupt,
-                serial_writer,
-            )));
-
-            allocator
-                .allocate_io_addresses(Some(GuestAddress(0x3f8)), 0x8, None)
-                .ok_or(DeviceManagerError::AllocateIOPort)?;
+            #[cfg(target_arch = "x86_64")]
+            {
+                allocator
+                    .allocate_io_addresses(Some(GuestAddress(0x3f8)), 0x8, None)
+                    .ok_or(DeviceManagerError::AllocateIOPort)?;
 
-            io_bus
-                .insert(serial.clone(), 0x3f8, 0x8)
+                io_bus
+                    .insert(serial.clone(), 0x3f8, 0x8)
+                    .map_err(DeviceManagerError::BusError)?;
+            }
+            // Part of the platform devices range, which is never allocated
+            // from.
+            #[cfg(target_arch = "aarch64")]
+            mmio_bus
+                .insert(
+                    serial.clone(),
+                    arch::layout::LEGACY_SERIAL_MAPPED_IO_START.raw_value(),
+                    arch::layout::LEGACY_SERIAL_SIZE,
+                )
                 .map_err(DeviceManagerError::BusError)?;
 
             Some(serial)
@@ -473,70 +1278,302 @@ impl DeviceManager {
             None
         };
 
-        // Add a shutdown device (i8042)
-        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(
-            reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
-        )));
+        let uart_configs = match &vm_info.vm_cfg.uarts {
+            Some(uarts) => uarts.as_slice(),
+            None => &[],
+        };
+        if uart_configs.len() > LEGACY_UARTS.len() {
+            return Err(DeviceManagerError::TooManyUarts(uart_configs.len()));
+        }
+        // The aarch64 guests only get the PL011.
+        #[cfg(target_arch = "aarch64")]
+        {
+            if !uart_configs.is_empty() {
+                return Err(DeviceManagerError::UartUnsupported);
+            }
+        }
+        let mut uarts = Vec::new();
+        for (index, uart_config) in uart_configs.iter().enumerate() {
+            let (port, irq) = uart_config.resources(index);
+            // The IRQs from 5 are handed out to the other devices.
+            if irq != 3 && irq != 4 {
+                return Err(DeviceManagerError::InvalidUartIrq(irq));
+            }
 
-        allocator
-            .allocate_io_addresses(Some(GuestAddress(0x61)), 0x4, None)
-            .ok_or(DeviceManagerError::AllocateIOPort)?;
+            let (writer, pty, socket) =
+                DeviceManager::create_serial_backend(&uart_config.mode, &uart_config.file)?;
+            let interrupt = DeviceManager::create_legacy_interrupt(vm_info, &ioapic, irq as usize)?;
+            let serial = Arc::new(Mutex::new(devices::legacy::Serial::new(interrupt, writer)));
+
+            allocator
+                .allocate_io_addresses(Some(GuestAddress(u64::from(port))), 0x8, None)
+                .ok_or(DeviceManagerError::AllocateIOPort)?;
 
-        io_bus
-            .insert(i8042.clone(), 0x61, 0x4)
-            .map_err(DeviceManagerError::BusError)?;
-        #[cfg(feature = "cmos")]
-        {
-            use vm_memory::GuestMemory;
-            let mem_size = vm_info.memory.as_ref().read().unwrap().end_addr().0 + 1;
-            let mem_below_4g = std::cmp::min(arch::layout::MEM_32BIT_RESERVED_START.0, mem_size);
-            let mem_above_4g = mem_size.saturating_sub(arch::layout::RAM_64BIT_START.0);
-
-            let cmos = Arc::new(Mutex::new(devices::legacy::Cmos::new(
-                mem_below_4g,
-                mem_above_4g,
-            )));
             io_bus
-                .insert(cmos.clone(), 0x70, 0x2)
+                .insert(serial.clone(), u64::from(port), 0x8)
                 .map_err(DeviceManagerError::BusError)?;
+
+            uarts.push(Uart {
+                serial,
+                pty,
+                socket,
+                port,
+                irq,
+            });
         }
-        #[cfg(feature = "acpi")]
+
+        // Add a shutdown device (i8042). On aarch64, the guest resets
+        // through PSCI instead.
+        #[cfg(target_arch = "x86_64")]
         {
-            let acpi_device = Arc::new(Mutex::new(devices::AcpiShutdownDevice::new(
-                _exit_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
-                reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+            let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(
+                _reset_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?,
             )));
 
             allocator
-                .allocate_io_addresses(Some(GuestAddress(0x3c0)), 0x4, None)
+                .allocate_io_addresses(Some(GuestAddress(0x61)), 0x4, None)
                 .ok_or(DeviceManagerError::AllocateIOPort)?;
 
             io_bus
-                .insert(acpi_device.clone(), 0x3c0, 0x4)
+                .insert(i8042.clone(), 0x61, 0x4)
                 .map_err(DeviceManagerError::BusError)?;
         }
+        // The unikernel profile does without any optional legacy device.
+        let unikernel = vm_info.vm_cfg.profile == Profile::Unikernel;
+
+        #[cfg(all(feature = "cmos", target_arch = "x86_64"))]
+        {
+            if !unikernel {
+                use vm_memory::GuestMemory;
+                let mem_size = vm_info.memory.as_ref().read().unwrap().end_addr().0 + 1;
+                let mem_below_4g =
+                    std::cmp::min(arch::layout::MEM_32BIT_RESERVED_START.0, mem_size);
+                let mem_above_4g = mem_size.saturating_sub(arch::layout::RAM_64BIT_START.0);
+
+                let cmos = Arc::new(Mutex::new(devices::legacy::Cmos::new(
+                    mem_below_4g,
+                    mem_above_4g,
+                )));
+                io_bus
+                    .insert(cmos.clone(), 0x70, 0x2)
+                    .map_err(DeviceManagerError::BusError)?;
+            }
+        }
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        let ged_notification_device = {
+            if !unikernel {
+                let acpi_device = Arc::new(Mutex::new(devices::AcpiShutdownDevice::new(
+                    _exit_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+                    _reset_evt
+                        .try_clone()
+                        .map_err(DeviceManagerError::EventFd)?,
+                )));
+
+                allocator
+                    .allocate_io_addresses(Some(GuestAddress(0x3c0)), 0x4, None)
+                    .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+                io_bus
+                    .insert(acpi_device.clone(), 0x3c0, 0x4)
+                    .map_err(DeviceManagerError::BusError)?;
+
+                // Generic Event Device, used to notify the guest about
+                // events such as a power button press.
+                let ged_irq = allocator
+                    .allocate_irq()
+                    .ok_or(DeviceManagerError::AllocateIrq)?;
+                let interrupt: Box<dyn devices::Interrupt> = if let Some(ioapic) = &ioapic {
+                    Box::new(UserIoapicIrq::new(ioapic.clone(), ged_irq as usize))
+                } else {
+                    let ged_evt =
+                        EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
+                    vm_info
+                        .vm
+                        .register_irqfd(&ged_evt, ged_irq)
+                        .map_err(DeviceManagerError::Irq)?;
+
+                    Box::new(KernelIoapicIrq::new(ged_evt))
+                };
+
+                let ged_device = Arc::new(Mutex::new(devices::AcpiGEDDevice::new(interrupt)));
+
+                allocator
+                    .allocate_io_addresses(Some(GuestAddress(0x3c4)), 0x1, None)
+                    .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+                io_bus
+                    .insert(ged_device.clone(), 0x3c4, 0x1)
+                    .map_err(DeviceManagerError::BusError)?;
+
+                Some((ged_device, ged_irq))
+            } else {
+                None
+            }
+        };
+
+        let sensors = &vm_info.vm_cfg.sensors;
+        let sensors_requested = sensors.battery || sensors.thermal_zone;
+        let acpi_supported = cfg!(all(feature = "acpi", target_arch = "x86_64")) && !unikernel;
+        if sensors_requested && !acpi_supported {
+            return Err(DeviceManagerError::SensorsUnsupported);
+        }
+        if vm_info.vm_cfg.tpm.is_some() && !acpi_supported {
+            return Err(DeviceManagerError::TpmUnsupported);
+        }
+        if vm_info.vm_cfg.pvpanic.is_some() && !acpi_supported {
+            return Err(DeviceManagerError::PvpanicUnsupported);
+        }
+        if vm_info.vm_cfg.nvdimms.is_some() && !acpi_supported {
+            return Err(DeviceManagerError::NvdimmUnsupported);
+        }
+
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        let acpi_sensors_device = {
+            if sensors_requested {
+                let sensors_device = Arc::new(Mutex::new(devices::AcpiSensorsDevice::new()));
+
+                allocator
+                    .allocate_io_addresses(Some(GuestAddress(0x3c8)), 0x10, None)
+                    .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+                io_bus
+                    .insert(sensors_device.clone(), 0x3c8, 0x10)
+                    .map_err(DeviceManagerError::BusError)?;
+
+                Some(sensors_device)
+            } else {
+                None
+            }
+        };
+
+        // The guest finds the pvpanic device through the ACPI DSDT.
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        let pvpanic_device = {
+            if vm_info.vm_cfg.pvpanic.is_some() {
+                let panic_evt = EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
+                let pvpanic_device = Arc::new(Mutex::new(devices::legacy::Pvpanic::new(
+                    panic_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+                )));
+
+                allocator
+                    .allocate_io_addresses(Some(GuestAddress(0x505)), 0x1, None)
+                    .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+                io_bus
+                    .insert(pvpanic_device.clone(), 0x505, 0x1)
+                    .map_err(DeviceManagerError::BusError)?;
+
+                Some((pvpanic_device, panic_evt))
+            } else {
+                None
+            }
+        };
+
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        {
+            if let Some(tpm) = &vm_info.vm_cfg.tpm {
+                let emulator = devices::tpm::emulator::Emulator::new(
+                    &tpm.socket,
+                    devices::tpm::CRB_DATA_BUFFER_SIZE as u32,
+                )
+                .map_err(DeviceManagerError::CreateTpm)?;
+                let tpm_device = Arc::new(Mutex::new(devices::tpm::TpmCrb::new(
+                    Box::new(emulator),
+                    arch::layout::TPM_START.0,
+                )));
+
+                // Part of the 32-bit reserved area, above the range the
+                // devices are allocated from.
+                mmio_bus
+                    .insert(
+                        tpm_device,
+                        arch::layout::TPM_START.0,
+                        arch::layout::TPM_SIZE,
+                    )
+                    .map_err(DeviceManagerError::BusError)?;
+            }
+        }
 
-        let mut virtio_devices: Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)> = Vec::new();
+        let mut virtio_devices: Vec<VirtioDeviceEntry> = Vec::new();
 
         // Create serial and virtio-console
+        let console_pty = if vm_info.vm_cfg.console.mode == ConsoleOutputMode::Pty {
+            Some(create_pty().map_err(DeviceManagerError::ConsolePtyOpen)?)
+        } else {
+            None
+        };
+        let console_socket = if vm_info.vm_cfg.console.mode == ConsoleOutputMode::Socket {
+            Some(
+                SerialSocket::new(vm_info.vm_cfg.console.file.as_ref().unwrap())
+                    .map_err(DeviceManagerError::ConsoleSocketOpen)?,
+            )
+        } else {
+            None
+        };
         let console_writer: Option<Box<dyn io::Write + Send + Sync>> =
             match vm_info.vm_cfg.console.mode {
                 ConsoleOutputMode::File => Some(Box::new(
                     File::create(vm_info.vm_cfg.console.file.as_ref().unwrap())
                         .map_err(DeviceManagerError::ConsoleOutputFileOpen)?,
                 )),
+                ConsoleOutputMode::Pty => Some(Box::new(
+                    console_pty
+                        .as_ref()
+                        .unwrap()
+                        .main
+                        .try_clone()
+                        .map_err(DeviceManagerError::ConsolePtyOpen)?,
+                )),
+                ConsoleOutputMode::Socket => {
+                    Some(Box::new(console_socket.as_ref().unwrap().writer()))
+                }
                 ConsoleOutputMode::Tty => Some(Box::new(stdout())),
                 ConsoleOutputMode::Null => Some(Box::new(sink())),
                 ConsoleOutputMode::Off => None,
             };
+        let console_writer = match (console_writer, &console_log) {
+            (Some(writer), Some(console_log)) => {
+                Some(Box::new(ConsoleLogWriter::new(console_log.clone(), writer))
+                    as Box<dyn io::Write + Send + Sync>)
+            }
+            (writer, _) => writer,
+        };
+        let console_writer = match (console_writer, &guest_os_probe) {
+            (Some(writer), Some(probe)) => {
+                Some(Box::new(ConsoleProbeWriter::new(probe.clone(), writer))
+                    as Box<dyn io::Write + Send + Sync>)
+            }
+            (writer, _) => writer,
+        };
+        // Rejected when parsing the configuration with the console off.
+        let mut console_port_sockets = Vec::new();
+        let mut console_ports = Vec::new();
+        for port in vm_info.vm_cfg.console_ports.iter().flatten() {
+            let socket =
+                SerialSocket::new(&port.socket).map_err(DeviceManagerError::ConsoleSocketOpen)?;
+            console_ports.push(vm_virtio::ConsolePort {
+                name: port.name.clone(),
+                out: Box::new(socket.writer()),
+            });
+            console_port_sockets.push(socket);
+        }
         let (col, row) = get_win_size();
         let console_input = if let Some(writer) = console_writer {
-            let (virtio_console_device, console_input) =
-                vm_virtio::Console::new(writer, col, row, vm_info.vm_cfg.console.iommu)
-                    .map_err(DeviceManagerError::CreateVirtioConsole)?;
+            let (virtio_console_device, console_input) = vm_virtio::Console::new(
+                writer,
+                console_ports,
+                col,
+                row,
+                DeviceManager::access_platform(vm_info, vm_info.vm_cfg.console.iommu),
+                vm_info.vm_cfg.console.queue_size,
+            )
+            .map_err(DeviceManagerError::CreateVirtioConsole)?;
             virtio_devices.push((
                 Box::new(virtio_console_device) as Box<dyn vm_virtio::VirtioDevice>,
                 false,
+                None,
             ));
             Some(console_input)
         } else {
@@ -545,35 +1582,132 @@ impl DeviceManager {
 
         let console = Arc::new(Console {
             serial,
+            serial_output,
             console_input,
             input_enabled: vm_info.vm_cfg.serial.mode.input_enabled()
                 || vm_info.vm_cfg.console.mode.input_enabled(),
+            serial_pty,
+            console_pty,
+            serial_socket,
+            console_socket,
+            console_port_sockets,
+            uarts,
         });
 
         let mut mmap_regions = Vec::new();
 
+        // The guest finds the NVDIMMs through the ACPI NFIT and DSDT.
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        let nvdimm_ranges =
+            DeviceManager::make_nvdimms(vm_info, &mut allocator, &mmio_bus, &mut mmap_regions)?;
+
+        let out_of_space_evt = EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
+        let mut out_of_space_disks = Vec::new();
+        let mut disk_bandwidth_shares = Vec::new();
+        let mut rate_limiter_buckets = Vec::new();
+        let mut gpu_display = None;
+        let mut input_events = Vec::new();
+
         virtio_devices.append(&mut DeviceManager::make_virtio_devices(
             vm_info,
             &mut allocator,
-            &mut mem_slots,
             &mut mmap_regions,
+            &out_of_space_evt,
+            &mut out_of_space_disks,
+            &mut disk_bandwidth_shares,
+            &mut rate_limiter_buckets,
+            &mut gpu_display,
+            &mut input_events,
         )?);
 
+        // Devices keeping their own mappings of the guest RAM need to be
+        // notified when it gets hotplugged or unplugged.
+        for (device, _, _) in virtio_devices.iter() {
+            if let Some(listener) = device.memory_listener(vm_info.memory.clone()) {
+                vm_info
+                    .memory_manager
+                    .lock()
+                    .unwrap()
+                    .add_memory_listener(listener)
+                    .map_err(DeviceManagerError::RegisterMemoryListener)?;
+            }
+        }
+
         #[allow(unused_mut)]
         let mut cmdline_additions = Vec::new();
 
         #[allow(unused_mut)]
         let mut virt_iommu: Option<(u32, Vec<u32>)> = None;
 
+        #[allow(unused_mut)]
+        let mut virtio_mmio_devices = Vec::new();
+
+        #[allow(unused_mut)]
+        let mut virtio_transports = Vec::new();
+
+        #[allow(unused_mut)]
+        let mut pci_devices = BTreeMap::new();
+
+        #[allow(unused_mut)]
+        let mut sriov_vfs = BTreeMap::new();
+
+        #[allow(unused_mut)]
+        let mut pci_segment_windows = Vec::new();
+
+        #[cfg(feature = "pci_support")]
+        let mut removable_pci_devices = BTreeMap::new();
+
+        #[cfg(feature = "pci_support")]
+        let mut plugin_processes = Vec::new();
+
+        #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+        let mut pci_hotplug = None;
+
+        #[cfg(feature = "e1000_support")]
+        let mut e1000_devices = Vec::new();
+
+        let allocator = Arc::new(Mutex::new(allocator));
+        // The routes of the in-kernel IOAPIC are kept along with the MSI
+        // ones, the MSI routes otherwise replacing them.
+        #[cfg(feature = "pci_support")]
+        let msi_interrupt_manager: Arc<dyn InterruptManager> = Arc::new(MsiInterruptManager::new(
+            vm_info.vm.clone(),
+            allocator.clone(),
+            !_userspace_ioapic,
+        ));
         let address_manager = Arc::new(AddressManager {
-            allocator: Arc::new(Mutex::new(allocator)),
+            allocator,
             io_bus: Arc::new(io_bus),
             mmio_bus: Arc::new(mmio_bus),
             #[cfg(feature = "pci_support")]
-            vm_fd: vm_info.vm_fd.clone(),
+            vm: vm_info.vm.clone(),
+            #[cfg(feature = "pci_support")]
+            msi_interrupt_manager,
         });
 
-        if cfg!(feature = "pci_support") {
+        let e1000_requested = vm_info
+            .vm_cfg
+            .net
+            .iter()
+            .flatten()
+            .any(|net_cfg| net_cfg.model == NetModel::E1000);
+        if e1000_requested && (!cfg!(feature = "e1000_support") || unikernel) {
+            return Err(DeviceManagerError::E1000Unsupported);
+        }
+
+        let ahci_requested = vm_info
+            .vm_cfg
+            .disks
+            .iter()
+            .flatten()
+            .any(|disk_cfg| disk_cfg.model == DiskModel::Ahci);
+        if ahci_requested && (!cfg!(feature = "ahci_support") || unikernel) {
+            return Err(DeviceManagerError::AhciUnsupported);
+        }
+
+        // Virtio devices are always exposed through virtio-mmio with the
+        // unikernel profile.
+        if cfg!(feature = "pci_support") && !unikernel {
             #[cfg(feature = "pci_support")]
             {
                 let pci_root = PciRoot::new(None);
@@ -582,6 +1716,11 @@ impl DeviceManager {
                     Arc::downgrade(&address_manager) as Weak<dyn DeviceRelocation>,
                 );
 
+                // The windows of the other segments are reserved before any
+                // BAR of the segment 0 is allocated.
+                let mut pci_segments =
+                    DeviceManager::create_pci_segments(vm_info, &address_manager)?;
+
                 let (mut iommu_device, iommu_mapping) = if vm_info.vm_cfg.iommu {
                     let (device, mapping) =
                         vm_virtio::Iommu::new().map_err(DeviceManagerError::CreateVirtioIommu)?;
@@ -590,70 +1729,184 @@ impl DeviceManager {
                     (None, None)
                 };
 
-                let mut iommu_attached_devices = Vec::new();
+                if let Some(mapping) = &iommu_mapping {
+                    vm_info
+                        .memory_manager
+                        .lock()
+                        .unwrap()
+                        .add_memory_listener(mapping.clone())
+                        .map_err(DeviceManagerError::RegisterMemoryListener)?;
+                }
 
-                for (device, iommu_attached) in virtio_devices {
+                let mut iommu_attached_devices = Vec::new();
+                let multifunction = vm_info.vm_cfg.pci.multifunction;
+                let removable_disks: Vec<&String> = vm_info
+                    .vm_cfg
+                    .disks
+                    .iter()
+                    .flatten()
+                    .filter(|disk_cfg| disk_cfg.removable)
+                    .filter_map(|disk_cfg| disk_cfg.id.as_ref())
+                    .collect();
+
+                for (device, iommu_attached, id) in virtio_devices {
                     let mapping: &Option<Arc<IommuMapping>> = if iommu_attached {
                         &iommu_mapping
                     } else {
                         &None
                     };
 
+                    // The vsock devices and the removable disks can be
+                    // removed, along with the vfio-user devices, unless
+                    // they are functions of a multifunction device.
+                    let removable = (device.device_type()
+                        == vm_virtio::VirtioDeviceType::TYPE_VSOCK as u32
+                        || id
+                            .as_ref()
+                            .map_or(false, |id| removable_disks.contains(&id)))
+                        && !multifunction;
+                    let device_id = pci_bus.next_device_id();
+
                     let virtio_iommu_attach_dev = DeviceManager::add_virtio_pci_device(
                         device,
+                        id,
                         vm_info.memory,
                         &address_manager,
-                        vm_info.vm_fd,
+                        vm_info.vm,
                         &mut pci_bus,
                         &interrupt_info,
                         mapping,
+                        &mut virtio_transports,
+                        &mut pci_devices,
+                        multifunction,
                     )?;
 
                     if let Some(dev_id) = virtio_iommu_attach_dev {
                         iommu_attached_devices.push(dev_id);
                     }
+                    if removable {
+                        let (id, _) = virtio_transports.last().unwrap();
+                        removable_pci_devices.insert(
+                            id.clone(),
+                            RemovablePciDevice {
+                                device_id,
+                                memory_listener: None,
+                            },
+                        );
+                    }
                 }
 
                 let mut vfio_iommu_device_ids = DeviceManager::add_vfio_devices(
                     vm_info,
                     &address_manager,
                     &mut pci_bus,
-                    mem_slots,
+                    &mut pci_segments,
                     &mut iommu_device,
+                    &mut pci_devices,
+                    &mut sriov_vfs,
                 )?;
 
                 iommu_attached_devices.append(&mut vfio_iommu_device_ids);
 
+                let mut vfio_user_iommu_device_ids = DeviceManager::add_vfio_user_devices(
+                    vm_info,
+                    &address_manager,
+                    &mut pci_bus,
+                    &mut pci_segments,
+                    &mut iommu_device,
+                    &mut pci_devices,
+                    &mut removable_pci_devices,
+                )?;
+
+                iommu_attached_devices.append(&mut vfio_user_iommu_device_ids);
+
+                let mut plugin_iommu_device_ids = DeviceManager::add_plugin_devices(
+                    vm_info,
+                    &address_manager,
+                    &mut pci_bus,
+                    &mut pci_segments,
+                    &mut iommu_device,
+                    &mut pci_devices,
+                    &mut plugin_processes,
+                )?;
+
+                iommu_attached_devices.append(&mut plugin_iommu_device_ids);
+
+                #[cfg(feature = "e1000_support")]
+                DeviceManager::add_e1000_devices(
+                    vm_info,
+                    &address_manager,
+                    &mut pci_bus,
+                    &interrupt_info,
+                    &mut pci_devices,
+                    &mut e1000_devices,
+                )?;
+
+                #[cfg(feature = "ahci_support")]
+                DeviceManager::add_ahci_device(
+                    vm_info,
+                    &address_manager,
+                    &mut pci_bus,
+                    &interrupt_info,
+                    &mut pci_devices,
+                )?;
+
                 if let Some(iommu_device) = iommu_device {
                     // We need to shift the device id since the 3 first bits
-                    // are dedicated to the PCI function, and we know we don't
-                    // do multifunction. Also, because we only support one PCI
-                    // bus, the bus 0, we don't need to add anything to the
-                    // global device ID.
-                    let iommu_id = pci_bus.next_device_id() << 3;
+                    // are dedicated to the PCI function, unless the device is
+                    // packed onto a multifunction device. Also, because we
+                    // only support one PCI bus, the bus 0, we don't need to
+                    // add anything to the global device ID.
+                    let iommu_id = if multifunction {
+                        pci_bus.next_function_id()
+                    } else {
+                        pci_bus.next_device_id() << 3
+                    };
 
                     // Because we determined the virtio-iommu b/d/f, we have to
                     // add the device to the PCI topology now. Otherwise, the
                     // b/d/f won't match the virtio-iommu device as expected.
                     DeviceManager::add_virtio_pci_device(
                         Box::new(iommu_device),
+                        None,
                         vm_info.memory,
                         &address_manager,
-                        vm_info.vm_fd,
+                        vm_info.vm,
                         &mut pci_bus,
                         &interrupt_info,
                         &None,
+                        &mut virtio_transports,
+                        &mut pci_devices,
+                        multifunction,
                     )?;
 
                     virt_iommu = Some((iommu_id, iommu_attached_devices));
                 }
 
                 let pci_bus = Arc::new(Mutex::new(pci_bus));
-                let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(pci_bus.clone())));
-                address_manager
-                    .io_bus
-                    .insert(pci_config_io, 0xcf8, 0x8)
-                    .map_err(DeviceManagerError::BusError)?;
+
+                // The pin IRQs of the devices can't be given back, the
+                // devices are only hot-added and removed with MSI-X.
+                #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+                {
+                    if ged_notification_device.is_some() && _msi_capable {
+                        pci_hotplug = Some(DeviceManager::create_pci_hotplug(
+                            vm_info,
+                            &address_manager,
+                            &pci_bus,
+                        )?);
+                    }
+                }
+                // aarch64 guests only access the configuration space
+                // through MMCONFIG.
+                #[cfg(target_arch = "x86_64")]
+                {
+                    let pci_config_io = Arc::new(Mutex::new(PciConfigIo::new(pci_bus.clone())));
+                    address_manager
+                        .io_bus
+                        .insert(pci_config_io, 0xcf8, 0x8)
+                        .map_err(DeviceManagerError::BusError)?;
+                }
                 let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(pci_bus)));
                 address_manager
                     .mmio_bus
@@ -663,11 +1916,28 @@ impl DeviceManager {
                         arch::layout::PCI_MMCONFIG_SIZE,
                     )
                     .map_err(DeviceManagerError::BusError)?;
+
+                // The other segments are only reached through their own
+                // MMCONFIG area.
+                for segment in pci_segments {
+                    let pci_config_mmio = Arc::new(Mutex::new(PciConfigMmio::new(Arc::new(
+                        Mutex::new(segment.bus),
+                    ))));
+                    address_manager
+                        .mmio_bus
+                        .insert(
+                            pci_config_mmio,
+                            segment.windows.mmconfig.0.raw_value(),
+                            segment.windows.mmconfig.1,
+                        )
+                        .map_err(DeviceManagerError::BusError)?;
+                    pci_segment_windows.push((segment.windows, segment.address_manager));
+                }
             }
         } else if cfg!(feature = "mmio_support") {
             #[cfg(feature = "mmio_support")]
             {
-                for (device, _) in virtio_devices {
+                for (device, _, id) in virtio_devices {
                     let mmio_addr = address_manager
                         .allocator
                         .lock()
@@ -676,12 +1946,15 @@ impl DeviceManager {
                     if let Some(addr) = mmio_addr {
                         DeviceManager::add_virtio_mmio_device(
                             device,
+                            id,
                             vm_info.memory,
                             &address_manager,
-                            vm_info.vm_fd,
+                            vm_info.vm,
                             &interrupt_info,
                             addr,
                             &mut cmdline_additions,
+                            &mut virtio_mmio_devices,
+                            &mut virtio_transports,
                         )?;
                     } else {
                         error!("Unable to allocate MMIO address!");
@@ -690,6 +1963,15 @@ impl DeviceManager {
             }
         }
 
+        // The guest drivers are audited from the boot on.
+        if let Some(diagnostics) = &vm_info.vm_cfg.diagnostics {
+            if diagnostics.audit {
+                for (_, transport) in virtio_transports.iter() {
+                    transport.lock().unwrap().enable_audit();
+                }
+            }
+        }
+
         Ok(DeviceManager {
             address_manager,
             console,
@@ -697,27 +1979,69 @@ impl DeviceManager {
             mmap_regions,
             cmdline_additions,
             virt_iommu,
+            virtio_mmio_devices,
+            virtio_devices: virtio_transports,
+            pci_devices,
+            sriov_vfs,
+            console_log,
+            guest_os_probe,
+            out_of_space_evt,
+            out_of_space_disks,
+            disk_bandwidth_shares,
+            rate_limiter_buckets,
+            gpu_display,
+            input_events,
+            pci_segments: pci_segment_windows,
+            #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+            ged_notification_device,
+            #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+            acpi_sensors_device,
+            #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+            pvpanic_device,
+            #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+            nvdimm_ranges,
+            #[cfg(feature = "pci_support")]
+            removable_pci_devices,
+            #[cfg(feature = "pci_support")]
+            _plugin_processes: plugin_processes,
+            #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+            pci_hotplug,
+            #[cfg(feature = "e1000_support")]
+            e1000_devices,
         })
     }
 
     fn make_virtio_devices(
         vm_info: &VmInfo,
         allocator: &mut SystemAllocator,
-        mut mem_slots: &mut u32,
         mmap_regions: &mut Vec<(*mut libc::c_void, usize)>,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
-        let mut devices: Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)> = Vec::new();
+        out_of_space_evt: &EventFd,
+        out_of_space_disks: &mut Vec<(String, Arc<AtomicBool>)>,
+        disk_bandwidth_shares: &mut Vec<(String, vm_virtio::BandwidthShare)>,
+        rate_limiter_buckets: &mut Vec<(String, vm_virtio::RateLimiterBuckets)>,
+        gpu_display: &mut Option<vm_virtio::GpuDisplay>,
+        input_events: &mut Vec<(vm_virtio::InputKind, Arc<vm_virtio::InputEvents>)>,
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
+        let mut devices: Vec<VirtioDeviceEntry> = Vec::new();
 
         // Create "standard" virtio devices (net/block/rng)
-        devices.append(&mut DeviceManager::make_virtio_block_devices(vm_info)?);
-        devices.append(&mut DeviceManager::make_virtio_net_devices(vm_info)?);
+        devices.append(&mut DeviceManager::make_virtio_block_devices(
+            vm_info,
+            out_of_space_evt,
+            out_of_space_disks,
+            disk_bandwidth_shares,
+            rate_limiter_buckets,
+        )?);
+        devices.append(&mut DeviceManager::make_virtio_net_devices(
+            vm_info,
+            rate_limiter_buckets,
+        )?);
         devices.append(&mut DeviceManager::make_virtio_rng_devices(vm_info)?);
 
         // Add virtio-fs if required
         devices.append(&mut DeviceManager::make_virtio_fs_devices(
             vm_info,
             allocator,
-            &mut mem_slots,
             mmap_regions,
         )?);
 
@@ -725,7 +2049,6 @@ impl DeviceManager {
         devices.append(&mut DeviceManager::make_virtio_pmem_devices(
             vm_info,
             allocator,
-            &mut mem_slots,
             mmap_regions,
         )?);
 
@@ -742,78 +2065,382 @@ impl DeviceManager {
         // Add virtio-vsock if required
         devices.append(&mut DeviceManager::make_virtio_vsock_devices(vm_info)?);
 
+        // Add virtio-gpu if required
+        devices.append(&mut DeviceManager::make_virtio_gpu_devices(
+            vm_info,
+            gpu_display,
+        )?);
+
+        // Add virtio-input if required
+        devices.append(&mut DeviceManager::make_virtio_input_devices(
+            vm_info,
+            input_events,
+        )?);
+
         Ok(devices)
     }
 
+    // The files the API clients passed are duplicated, so that they outlive
+    // the devices of a VM being rebooted.
+    fn passed_fd(vm_info: &VmInfo, name: &str) -> DeviceManagerResult<File> {
+        vm_info
+            .passed_fds
+            .lock()
+            .unwrap()
+            .get(name)
+            .ok_or_else(|| DeviceManagerError::PassedFdNotFound(name.to_string()))?
+            .try_clone()
+            .map_err(DeviceManagerError::PassedFdClone)
+    }
+
+    fn open_disk(vm_info: &VmInfo, disk_cfg: &DiskConfig) -> DeviceManagerResult<File> {
+        match &disk_cfg.fd {
+            Some(name) => DeviceManager::passed_fd(vm_info, name),
+            None => OpenOptions::new()
+                .read(true)
+                .write(!disk_cfg.readonly)
+                .open(&disk_cfg.path)
+                .map_err(DeviceManagerError::Disk),
+        }
+    }
+
+    // The disk image bypasses the page cache of the host with direct I/O
+    // once its type is detected, its header being read through a buffer
+    // which isn't aligned. The qcow images aren't supported, for the same
+    // reason.
+    fn set_direct_io(
+        raw_img: &File,
+        disk_cfg: &DiskConfig,
+        image_type: &ImageType,
+    ) -> DeviceManagerResult<()> {
+        if !disk_cfg.direct_io() {
+            return Ok(());
+        }
+        if let ImageType::Qcow2 = image_type {
+            return Err(DeviceManagerError::DirectIoImage(disk_cfg.path.clone()));
+        }
+
+        let fd = raw_img.as_raw_fd();
+        // Safe because we check the return value.
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 {
+            return Err(DeviceManagerError::DirectIo(io::Error::last_os_error()));
+        }
+        // Safe because we check the return value.
+        if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT) } < 0 {
+            return Err(DeviceManagerError::DirectIo(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    // The interfaces without a tap are given one the device creates. The
+    // interfaces backed by inherited file descriptors get a tap per queue
+    // pair.
+    fn open_taps(vm_info: &VmInfo, net_cfg: &NetConfig) -> DeviceManagerResult<Vec<Tap>> {
+        if let Some(fds) = &net_cfg.fds {
+            return fds
+                .iter()
+                .map(|fd| DeviceManager::inherited_tap(*fd))
+                .collect();
+        }
+
+        if let Some(name) = &net_cfg.fd {
+            let tap_file = DeviceManager::passed_fd(vm_info, name)?;
+            return Tap::from_tap_file(tap_file)
+                .map(|tap| vec![tap])
+                .map_err(DeviceManagerError::OpenTap);
+        }
+
+        if let Some(macvtap) = &net_cfg.macvtap {
+            return Tap::open_macvtap(macvtap)
+                .map(|tap| vec![tap])
+                .map_err(DeviceManagerError::OpenTap);
+        }
+
+        match &net_cfg.tap {
+            Some(tap_if_name) => Tap::open_named(tap_if_name)
+                .map(|tap| vec![tap])
+                .map_err(DeviceManagerError::OpenTap),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    // A guest on a macvtap interface is only reached at the MAC address of
+    // the interface, which it gets instead of the one of the configuration.
+    fn guest_mac(net_cfg: &NetConfig, tap: Option<&Tap>) -> DeviceManagerResult<MacAddr> {
+        match tap {
+            Some(tap) if tap.is_macvtap() => tap.mac_addr().map_err(DeviceManagerError::OpenTap),
+            _ => Ok(net_cfg.mac),
+        }
+    }
+
+    // The inherited file descriptor is duplicated rather than taken over, for
+    // the VM to open the tap again when it reboots.
+    fn inherited_tap(fd: RawFd) -> DeviceManagerResult<Tap> {
+        // Safe because we check the return value.
+        let dup_fd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup_fd < 0 {
+            return Err(DeviceManagerError::DupTapFd(io::Error::last_os_error()));
+        }
+        // Safe because we just created this file descriptor and nothing else
+        // owns it.
+        let tap_file = unsafe { File::from_raw_fd(dup_fd) };
+
+        Tap::from_tap_file(tap_file).map_err(DeviceManagerError::OpenTap)
+    }
+
+    // Confidential guests can only share bounce buffers with the VMM. They
+    // rely on them for DMA as soon as VIRTIO_F_IOMMU_PLATFORM is negotiated,
+    // even if the device is not attached to the virtual IOMMU.
+    fn access_platform(vm_info: &VmInfo, iommu: bool) -> bool {
+        iommu || vm_info.vm_cfg.encrypted_memory()
+    }
+
+    // A device in a bandwidth group gets a rate limiter for its share, even
+    // without a rate limiter of its own.
+    fn make_rate_limiter(
+        rate_limiter_cfg: &Option<RateLimiterConfig>,
+        bandwidth_share: Option<vm_virtio::BandwidthShare>,
+    ) -> DeviceManagerResult<Option<vm_virtio::RateLimiter>> {
+        let rate_limiter = if let Some(cfg) = rate_limiter_cfg {
+            let (bw_size, bw_one_time_burst, bw_refill_time) = match &cfg.bandwidth {
+                Some(bw) => (bw.size, bw.one_time_burst, bw.refill_time),
+                None => (0, None, 0),
+            };
+            let (ops_size, ops_one_time_burst, ops_refill_time) = match &cfg.ops {
+                Some(ops) => (ops.size, ops.one_time_burst, ops.refill_time),
+                None => (0, None, 0),
+            };
+
+            vm_virtio::RateLimiter::new(
+                bw_size,
+                bw_one_time_burst,
+                bw_refill_time,
+                ops_size,
+                ops_one_time_burst,
+                ops_refill_time,
+            )
+            .map_err(DeviceManagerError::CreateRateLimiter)?
+        } else if bandwidth_share.is_some() {
+            vm_virtio::RateLimiter::new(0, None, 0, 0, None, 0)
+                .map_err(DeviceManagerError::CreateRateLimiter)?
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(match bandwidth_share {
+            Some(share) => rate_limiter.with_bandwidth_share(share),
+            None => rate_limiter,
+        }))
+    }
+
+    // The virtio-blk devices are the first block devices, the ones without
+    // an ID getting block0, block1 and so on, in their order.
     fn make_virtio_block_devices(
         vm_info: &VmInfo,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
+        out_of_space_evt: &EventFd,
+        out_of_space_disks: &mut Vec<(String, Arc<AtomicBool>)>,
+        disk_bandwidth_shares: &mut Vec<(String, vm_virtio::BandwidthShare)>,
+        rate_limiter_buckets: &mut Vec<(String, vm_virtio::RateLimiterBuckets)>,
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
         let mut devices = Vec::new();
 
+        let mut groups = BTreeMap::new();
+        if let Some(group_list_cfg) = &vm_info.vm_cfg.disk_groups {
+            for group_cfg in group_list_cfg.iter() {
+                groups.insert(
+                    group_cfg.id.as_str(),
+                    vm_virtio::BandwidthGroup::new(group_cfg.bandwidth),
+                );
+            }
+        }
+
         if let Some(disk_list_cfg) = &vm_info.vm_cfg.disks {
-            for disk_cfg in disk_list_cfg.iter() {
+            for (index, disk_cfg) in disk_list_cfg
+                .iter()
+                .filter(|disk_cfg| disk_cfg.model == DiskModel::Virtio)
+                .enumerate()
+            {
+                let id = disk_cfg
+                    .id
+                    .clone()
+                    .unwrap_or_else(|| format!("block{}", index));
+                let out_of_space_evt = out_of_space_evt
+                    .try_clone()
+                    .map_err(DeviceManagerError::EventFd)?;
+                let bandwidth_share = disk_cfg
+                    .group
+                    .as_ref()
+                    .and_then(|group| groups.get(group.as_str()))
+                    .map(|group| vm_virtio::BandwidthGroup::join(group, disk_cfg.weight));
+                if let Some(share) = &bandwidth_share {
+                    disk_bandwidth_shares.push((id.clone(), share.clone()));
+                }
+                let rate_limiter = DeviceManager::make_rate_limiter(
+                    &disk_cfg.rate_limiter_config,
+                    bandwidth_share,
+                )?;
+                if let Some(rate_limiter) = &rate_limiter {
+                    rate_limiter_buckets.push((id.clone(), rate_limiter.buckets()));
+                }
                 // Open block device path
-                let raw_img: File = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .open(&disk_cfg.path)
-                    .map_err(DeviceManagerError::Disk)?;
+                let raw_img = DeviceManager::open_disk(vm_info, disk_cfg)?;
+                // The digest of a cached image is remembered for as long as
+                // its file isn't changed.
+                let identity = if disk_cfg.cache {
+                    Some(
+                        vm_virtio::ImageIdentity::of(&raw_img)
+                            .map_err(DeviceManagerError::BlockCache)?,
+                    )
+                } else {
+                    None
+                };
 
                 let image_type = qcow::detect_image_type(&raw_img)
                     .map_err(DeviceManagerError::DetectImageType)?;
-                let block = match image_type {
-                    ImageType::Raw => {
-                        let raw_img = vm_virtio::RawFile::new(raw_img);
-                        let dev = vm_virtio::Block::new(
-                            raw_img,
-                            disk_cfg.path.clone(),
-                            false,
-                            disk_cfg.iommu,
-                        )
-                        .map_err(DeviceManagerError::CreateVirtioBlock)?;
-                        Box::new(dev) as Box<dyn vm_virtio::VirtioDevice>
-                    }
+                DeviceManager::set_direct_io(&raw_img, disk_cfg, &image_type)?;
+                let (block, out_of_space) = match image_type {
+                    ImageType::Raw => DeviceManager::make_virtio_block(
+                        vm_virtio::RawFile::new(raw_img),
+                        identity,
+                        vm_info,
+                        disk_cfg,
+                        rate_limiter,
+                        out_of_space_evt,
+                    )?,
                     ImageType::Qcow2 => {
                         let qcow_img = QcowFile::from(raw_img)
                             .map_err(DeviceManagerError::QcowDeviceCreate)?;
-                        let dev = vm_virtio::Block::new(
+                        DeviceManager::make_virtio_block(
                             qcow_img,
-                            disk_cfg.path.clone(),
-                            false,
-                            disk_cfg.iommu,
-                        )
-                        .map_err(DeviceManagerError::CreateVirtioBlock)?;
-                        Box::new(dev) as Box<dyn vm_virtio::VirtioDevice>
+                            identity,
+                            vm_info,
+                            disk_cfg,
+                            rate_limiter,
+                            out_of_space_evt,
+                        )?
                     }
                 };
+                out_of_space_disks.push((id.clone(), out_of_space));
 
-                devices.push((block, disk_cfg.iommu));
+                devices.push((block, disk_cfg.iommu, Some(id)));
             }
         }
 
         Ok(devices)
     }
 
+    // The virtio-blk device of the disk image, read through the block cache
+    // of the process when the disk is cached, along with the flag of the
+    // image having run out of space.
+    fn make_virtio_block<T: 'static + vm_virtio::DiskFile + Send>(
+        disk_image: T,
+        identity: Option<vm_virtio::ImageIdentity>,
+        vm_info: &VmInfo,
+        disk_cfg: &DiskConfig,
+        rate_limiter: Option<vm_virtio::RateLimiter>,
+        out_of_space_evt: EventFd,
+    ) -> DeviceManagerResult<(Box<dyn vm_virtio::VirtioDevice>, Arc<AtomicBool>)> {
+        let identity = match identity {
+            Some(identity) => identity,
+            None => {
+                return DeviceManager::make_block_device(
+                    disk_image,
+                    vm_info,
+                    disk_cfg,
+                    rate_limiter,
+                    out_of_space_evt,
+                )
+            }
+        };
+
+        let cached_image =
+            vm_virtio::CachedDisk::new(disk_image, vm_info.block_cache.clone(), identity)
+                .map_err(DeviceManagerError::BlockCache)?;
+        DeviceManager::make_block_device(
+            cached_image,
+            vm_info,
+            disk_cfg,
+            rate_limiter,
+            out_of_space_evt,
+        )
+    }
+
+    fn make_block_device<T: 'static + vm_virtio::DiskFile + Send>(
+        disk_image: T,
+        vm_info: &VmInfo,
+        disk_cfg: &DiskConfig,
+        rate_limiter: Option<vm_virtio::RateLimiter>,
+        out_of_space_evt: EventFd,
+    ) -> DeviceManagerResult<(Box<dyn vm_virtio::VirtioDevice>, Arc<AtomicBool>)> {
+        let mut dev = vm_virtio::Block::new(
+            disk_image,
+            disk_cfg.path.clone(),
+            disk_cfg.readonly,
+            DeviceManager::access_platform(vm_info, disk_cfg.iommu),
+            rate_limiter,
+            disk_cfg.queue_size,
+        )
+        .map_err(DeviceManagerError::CreateVirtioBlock)?;
+        dev.set_ignore_flush(disk_cfg.cache_mode == DiskCacheMode::Unsafe);
+        dev.set_out_of_space_evt(out_of_space_evt);
+        let out_of_space = dev.out_of_space();
+
+        Ok((Box::new(dev), out_of_space))
+    }
+
     fn make_virtio_net_devices(
         vm_info: &VmInfo,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
+        rate_limiter_buckets: &mut Vec<(String, vm_virtio::RateLimiterBuckets)>,
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
         let mut devices = Vec::new();
 
         // Add virtio-net if required
         if let Some(net_list_cfg) = &vm_info.vm_cfg.net {
-            for net_cfg in net_list_cfg.iter() {
-                let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
-                    let tap = Tap::open_named(tap_if_name).map_err(DeviceManagerError::OpenTap)?;
-                    vm_virtio::Net::new_with_tap(tap, Some(&net_cfg.mac), net_cfg.iommu)
-                        .map_err(DeviceManagerError::CreateVirtioNet)?
+            for net_cfg in net_list_cfg
+                .iter()
+                .filter(|net_cfg| net_cfg.model == NetModel::Virtio)
+            {
+                let rate_limiter =
+                    DeviceManager::make_rate_limiter(&net_cfg.rate_limiter_config, None)?;
+                if let (Some(rate_limiter), Some(id)) = (&rate_limiter, &net_cfg.id) {
+                    rate_limiter_buckets.push((id.clone(), rate_limiter.buckets()));
+                }
+                let offloads = vm_virtio::NetOffloads {
+                    csum: net_cfg.offload_csum,
+                    tso: net_cfg.offload_tso,
+                    ufo: net_cfg.offload_ufo,
+                };
+                let taps = DeviceManager::open_taps(vm_info, net_cfg)?;
+                let mac = DeviceManager::guest_mac(net_cfg, taps.first())?;
+                let virtio_net_device = if !taps.is_empty() {
+                    vm_virtio::Net::new_with_taps(
+                        taps,
+                        Some(&mac),
+                        DeviceManager::access_platform(vm_info, net_cfg.iommu),
+                        rate_limiter,
+                        net_cfg.queue_size,
+                        offloads,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?
                 } else {
-                    vm_virtio::Net::new(net_cfg.ip, net_cfg.mask, Some(&net_cfg.mac), net_cfg.iommu)
-                        .map_err(DeviceManagerError::CreateVirtioNet)?
+                    vm_virtio::Net::new(
+                        net_cfg.ip,
+                        net_cfg.mask,
+                        net_cfg.ipv6(),
+                        Some(&net_cfg.mac),
+                        DeviceManager::access_platform(vm_info, net_cfg.iommu),
+                        rate_limiter,
+                        net_cfg.queue_size,
+                        offloads,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?
                 };
 
                 devices.push((
                     Box::new(virtio_net_device) as Box<dyn vm_virtio::VirtioDevice>,
                     net_cfg.iommu,
+                    net_cfg.id.clone(),
                 ));
             }
         }
@@ -821,30 +2448,80 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_rng_devices(
-        vm_info: &VmInfo,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
+    fn make_virtio_rng_devices(vm_info: &VmInfo) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
         let mut devices = Vec::new();
 
         // Add virtio-rng if required
         if let Some(rng_path) = vm_info.vm_cfg.rng.src.to_str() {
-            let virtio_rng_device = vm_virtio::Rng::new(rng_path, vm_info.vm_cfg.rng.iommu)
-                .map_err(DeviceManagerError::CreateVirtioRng)?;
+            let virtio_rng_device = vm_virtio::Rng::new(
+                rng_path,
+                DeviceManager::access_platform(vm_info, vm_info.vm_cfg.rng.iommu),
+            )
+            .map_err(DeviceManagerError::CreateVirtioRng)?;
             devices.push((
                 Box::new(virtio_rng_device) as Box<dyn vm_virtio::VirtioDevice>,
                 false,
+                None,
             ));
         }
 
         Ok(devices)
     }
 
-    fn make_virtio_fs_devices(
+    fn make_virtio_gpu_devices(
         vm_info: &VmInfo,
-        allocator: &mut SystemAllocator,
-        mem_slots: &mut u32,
+        gpu_display: &mut Option<vm_virtio::GpuDisplay>,
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
+        let mut devices = Vec::new();
+
+        if let Some(gpu_cfg) = &vm_info.vm_cfg.gpu {
+            let (virtio_gpu_device, display) = vm_virtio::Gpu::new(
+                gpu_cfg.width,
+                gpu_cfg.height,
+                DeviceManager::access_platform(vm_info, gpu_cfg.iommu),
+            )
+            .map_err(DeviceManagerError::CreateVirtioGpu)?;
+            *gpu_display = Some(display);
+            devices.push((
+                Box::new(virtio_gpu_device) as Box<dyn vm_virtio::VirtioDevice>,
+                false,
+                None,
+            ));
+        }
+
+        Ok(devices)
+    }
+
+    fn make_virtio_input_devices(
+        vm_info: &VmInfo,
+        input_events: &mut Vec<(vm_virtio::InputKind, Arc<vm_virtio::InputEvents>)>,
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
+        let mut devices = Vec::new();
+
+        if let Some(input_cfg) = &vm_info.vm_cfg.input {
+            for kind in [vm_virtio::InputKind::Keyboard, vm_virtio::InputKind::Tablet].iter() {
+                let (virtio_input_device, events) = vm_virtio::Input::new(
+                    *kind,
+                    DeviceManager::access_platform(vm_info, input_cfg.iommu),
+                )
+                .map_err(DeviceManagerError::CreateVirtioInput)?;
+                input_events.push((*kind, events));
+                devices.push((
+                    Box::new(virtio_input_device) as Box<dyn vm_virtio::VirtioDevice>,
+                    false,
+                    None,
+                ));
+            }
+        }
+
+        Ok(devices)
+    }
+
+    fn make_virtio_fs_devices(
+        vm_info: &VmInfo,
+        allocator: &mut SystemAllocator,
         mmap_regions: &mut Vec<(*mut libc::c_void, usize)>,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
         let mut devices = Vec::new();
         // Add virtio-fs if required
         if let Some(fs_list_cfg) = &vm_info.vm_cfg.fs {
@@ -878,18 +2555,20 @@ impl DeviceManager {
 
                         mmap_regions.push((addr, fs_cache as usize));
 
-                        let mem_region = kvm_userspace_memory_region {
-                            slot: *mem_slots as u32,
+                        let mem_region = UserMemoryRegion {
+                            slot: vm_info
+                                .memory_manager
+                                .lock()
+                                .unwrap()
+                                .allocate_kvm_slot()
+                                .map_err(DeviceManagerError::AllocateKvmSlot)?,
                             guest_phys_addr: fs_guest_addr.raw_value(),
                             memory_size: fs_cache,
                             userspace_addr: addr as u64,
                             flags: 0,
                         };
                         // Safe because the guest regions are guaranteed not to overlap.
-                        let _ = unsafe { vm_info.vm_fd.set_user_memory_region(mem_region) };
-
-                        // Increment the KVM slot number
-                        *mem_slots += 1;
+                        let _ = unsafe { vm_info.vm.set_user_memory_region(mem_region) };
 
                         let mut region_list = Vec::new();
                         region_list.push(VirtioSharedMemory {
@@ -918,6 +2597,7 @@ impl DeviceManager {
                     devices.push((
                         Box::new(virtio_fs_device) as Box<dyn vm_virtio::VirtioDevice>,
                         false,
+                        fs_cfg.id.clone(),
                     ));
                 }
             }
@@ -929,9 +2609,8 @@ impl DeviceManager {
     fn make_virtio_pmem_devices(
         vm_info: &VmInfo,
         allocator: &mut SystemAllocator,
-        mem_slots: &mut u32,
         mmap_regions: &mut Vec<(*mut libc::c_void, usize)>,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
         let mut devices = Vec::new();
         // Add virtio-pmem if required
         if let Some(pmem_list_cfg) = &vm_info.vm_cfg.pmem {
@@ -944,23 +2623,29 @@ impl DeviceManager {
                     .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
                     .ok_or(DeviceManagerError::PmemRangeAllocation)?;
 
-                let (custom_flags, set_len) = if pmem_cfg.file.is_dir() {
-                    (O_TMPFILE, true)
+                let file = if let Some(name) = &pmem_cfg.fd {
+                    DeviceManager::passed_fd(vm_info, name)?
                 } else {
-                    (0, false)
-                };
+                    let (custom_flags, set_len) = if pmem_cfg.file.is_dir() {
+                        (O_TMPFILE, true)
+                    } else {
+                        (0, false)
+                    };
 
-                let file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .custom_flags(custom_flags)
-                    .open(&pmem_cfg.file)
-                    .map_err(DeviceManagerError::PmemFileOpen)?;
+                    let file = OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .custom_flags(custom_flags)
+                        .open(&pmem_cfg.file)
+                        .map_err(DeviceManagerError::PmemFileOpen)?;
 
-                if set_len {
-                    file.set_len(size)
-                        .map_err(DeviceManagerError::PmemFileSetLen)?;
-                }
+                    if set_len {
+                        file.set_len(size)
+                            .map_err(DeviceManagerError::PmemFileSetLen)?;
+                    }
+
+                    file
+                };
 
                 let addr = unsafe {
                     libc::mmap(
@@ -975,18 +2660,20 @@ impl DeviceManager {
 
                 mmap_regions.push((addr, size as usize));
 
-                let mem_region = kvm_userspace_memory_region {
-                    slot: *mem_slots as u32,
+                let mem_region = UserMemoryRegion {
+                    slot: vm_info
+                        .memory_manager
+                        .lock()
+                        .unwrap()
+                        .allocate_kvm_slot()
+                        .map_err(DeviceManagerError::AllocateKvmSlot)?,
                     guest_phys_addr: pmem_guest_addr.raw_value(),
                     memory_size: size,
                     userspace_addr: addr as u64,
                     flags: 0,
                 };
                 // Safe because the guest regions are guaranteed not to overlap.
-                let _ = unsafe { vm_info.vm_fd.set_user_memory_region(mem_region) };
-
-                // Increment the KVM slot number
-                *mem_slots += 1;
+                let _ = unsafe { vm_info.vm.set_user_memory_region(mem_region) };
 
                 let virtio_pmem_device =
                     vm_virtio::Pmem::new(file, pmem_guest_addr, size as GuestUsize, pmem_cfg.iommu)
@@ -995,6 +2682,7 @@ impl DeviceManager {
                 devices.push((
                     Box::new(virtio_pmem_device) as Box<dyn vm_virtio::VirtioDevice>,
                     false,
+                    pmem_cfg.id.clone(),
                 ));
             }
         }
@@ -1002,9 +2690,99 @@ impl DeviceManager {
         Ok(devices)
     }
 
+    // The NVDIMMs are mapped as the virtio-pmem devices are, aligned for the
+    // guest to add them to its memory map as device memory, their label area
+    // being left out of the mapping. The _DSM mailbox reaches the label areas
+    // through the backing files.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    fn make_nvdimms(
+        vm_info: &VmInfo,
+        allocator: &mut SystemAllocator,
+        mmio_bus: &devices::Bus,
+        mmap_regions: &mut Vec<(*mut libc::c_void, usize)>,
+    ) -> DeviceManagerResult<Vec<(GuestAddress, u64)>> {
+        let nvdimm_list_cfg = match &vm_info.vm_cfg.nvdimms {
+            Some(nvdimm_list_cfg) => nvdimm_list_cfg,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut ranges = Vec::new();
+        let mut label_areas = Vec::new();
+        for nvdimm_cfg in nvdimm_list_cfg.iter() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&nvdimm_cfg.file)
+                .map_err(DeviceManagerError::NvdimmFileOpen)?;
+            let len = file
+                .metadata()
+                .map_err(DeviceManagerError::NvdimmFileOpen)?
+                .len();
+            if len < nvdimm_cfg.size {
+                file.set_len(nvdimm_cfg.size)
+                    .map_err(DeviceManagerError::NvdimmFileSetLen)?;
+            }
+
+            let size = nvdimm_cfg.mapped_size();
+            let guest_addr = allocator
+                .allocate_mmio_addresses(None, size as GuestUsize, Some(NVDIMM_ALIGNMENT))
+                .ok_or(DeviceManagerError::NvdimmRangeAllocation)?;
+
+            let addr = unsafe {
+                libc::mmap(
+                    null_mut(),
+                    size as usize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_NORESERVE | libc::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0 as libc::off_t,
+                )
+            };
+            if addr == libc::MAP_FAILED {
+                return Err(DeviceManagerError::NvdimmMmap(io::Error::last_os_error()));
+            }
+            mmap_regions.push((addr, size as usize));
+
+            let mem_region = UserMemoryRegion {
+                slot: vm_info
+                    .memory_manager
+                    .lock()
+                    .unwrap()
+                    .allocate_kvm_slot()
+                    .map_err(DeviceManagerError::AllocateKvmSlot)?,
+                guest_phys_addr: guest_addr.raw_value(),
+                memory_size: size,
+                userspace_addr: addr as u64,
+                flags: 0,
+            };
+            // Safe because the guest regions are guaranteed not to overlap.
+            let _ = unsafe { vm_info.vm.set_user_memory_region(mem_region) };
+
+            label_areas.push(devices::nvdimm::LabelArea {
+                file,
+                offset: size,
+                size: nvdimm_cfg.label_size,
+            });
+            ranges.push((guest_addr, size));
+        }
+
+        // Part of the 32-bit reserved area, above the range the devices are
+        // allocated from.
+        let dsm = Arc::new(Mutex::new(devices::nvdimm::NvdimmDsm::new(label_areas)));
+        mmio_bus
+            .insert(
+                dsm,
+                arch::layout::NVDIMM_DSM_START.0,
+                arch::layout::NVDIMM_DSM_SIZE,
+            )
+            .map_err(DeviceManagerError::BusError)?;
+
+        Ok(ranges)
+    }
+
     fn make_virtio_vhost_user_net_devices(
         vm_info: &VmInfo,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
         let mut devices = Vec::new();
         // Add vhost-user-net if required
         if let Some(vhost_user_net_list_cfg) = &vm_info.vm_cfg.vhost_user_net {
@@ -1021,6 +2799,7 @@ impl DeviceManager {
                 devices.push((
                     Box::new(vhost_user_net_device) as Box<dyn vm_virtio::VirtioDevice>,
                     false,
+                    vhost_user_net_cfg.id.clone(),
                 ));
             }
         }
@@ -1030,7 +2809,7 @@ impl DeviceManager {
 
     fn make_virtio_vhost_user_blk_devices(
         vm_info: &VmInfo,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
+    ) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
         let mut devices = Vec::new();
         // Add vhost-user-blk if required
         if let Some(vhost_user_blk_list_cfg) = &vm_info.vm_cfg.vhost_user_blk {
@@ -1047,6 +2826,7 @@ impl DeviceManager {
                 devices.push((
                     Box::new(vhost_user_blk_device) as Box<dyn vm_virtio::VirtioDevice>,
                     false,
+                    vhost_user_blk_cfg.id.clone(),
                 ));
             }
         }
@@ -1054,63 +2834,79 @@ impl DeviceManager {
         Ok(devices)
     }
 
-    fn make_virtio_vsock_devices(
-        vm_info: &VmInfo,
-    ) -> DeviceManagerResult<Vec<(Box<dyn vm_virtio::VirtioDevice>, bool)>> {
+    fn make_virtio_vsock_devices(vm_info: &VmInfo) -> DeviceManagerResult<Vec<VirtioDeviceEntry>> {
         let mut devices = Vec::new();
         // Add vsock if required
         if let Some(vsock_list_cfg) = &vm_info.vm_cfg.vsock {
             for vsock_cfg in vsock_list_cfg.iter() {
-                let socket_path = vsock_cfg
-                    .sock
-                    .to_str()
-                    .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
-                let backend =
-                    vm_virtio::vsock::VsockUnixBackend::new(vsock_cfg.cid, socket_path.to_string())
-                        .map_err(DeviceManagerError::CreateVsockBackend)?;
-
-                let vsock_device = vm_virtio::Vsock::new(vsock_cfg.cid, backend, vsock_cfg.iommu)
-                    .map_err(DeviceManagerError::CreateVirtioVsock)?;
-
-                devices.push((
-                    Box::new(vsock_device) as Box<dyn vm_virtio::VirtioDevice>,
-                    false,
-                ));
+                devices.push(DeviceManager::make_virtio_vsock(
+                    vsock_cfg,
+                    DeviceManager::access_platform(vm_info, vsock_cfg.iommu),
+                )?);
             }
         }
 
         Ok(devices)
     }
 
-    #[cfg(feature = "pci_support")]
-    fn create_kvm_device(vm: &Arc<VmFd>) -> DeviceManagerResult<DeviceFd> {
-        let mut vfio_dev = kvm_bindings::kvm_create_device {
-            type_: kvm_bindings::kvm_device_type_KVM_DEV_TYPE_VFIO,
-            fd: 0,
-            flags: 0,
-        };
+    fn make_virtio_vsock(
+        vsock_cfg: &VsockConfig,
+        access_platform: bool,
+    ) -> DeviceManagerResult<VirtioDeviceEntry> {
+        let socket_path = vsock_cfg
+            .sock
+            .to_str()
+            .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
+        let backend =
+            vm_virtio::vsock::VsockUnixBackend::new(vsock_cfg.cid, socket_path.to_string())
+                .map_err(DeviceManagerError::CreateVsockBackend)?;
+
+        let vsock_device = vm_virtio::Vsock::new(
+            vsock_cfg.cid,
+            backend,
+            access_platform,
+            vsock_cfg.queue_size,
+        )
+        .map_err(DeviceManagerError::CreateVirtioVsock)?;
+
+        Ok((
+            Box::new(vsock_device) as Box<dyn vm_virtio::VirtioDevice>,
+            false,
+            vsock_cfg.id.clone(),
+        ))
+    }
 
-        vm.create_device(&mut vfio_dev)
+    #[cfg(feature = "pci_support")]
+    fn create_passthrough_device(
+        vm: &Arc<dyn hypervisor::Vm>,
+    ) -> DeviceManagerResult<Arc<dyn Device>> {
+        vm.create_passthrough_device()
             .map_err(DeviceManagerError::CreateKvmDevice)
     }
 
     #[cfg(feature = "pci_support")]
+    #[allow(clippy::too_many_arguments)]
     fn add_vfio_devices(
         vm_info: &VmInfo,
         address_manager: &Arc<AddressManager>,
         pci: &mut PciBus,
-        mem_slots: u32,
+        pci_segments: &mut [PciSegment],
         iommu_device: &mut Option<vm_virtio::Iommu>,
+        pci_devices: &mut BTreeMap<String, String>,
+        sriov_vfs: &mut BTreeMap<String, VirtualFunction>,
     ) -> DeviceManagerResult<Vec<u32>> {
-        let mut mem_slot = mem_slots;
         let mut iommu_attached_device_ids = Vec::new();
-        let mut allocator = address_manager.allocator.lock().unwrap();
         if let Some(device_list_cfg) = &vm_info.vm_cfg.devices {
-            // Create the KVM VFIO device
-            let device_fd = DeviceManager::create_kvm_device(vm_info.vm_fd)?;
-            let device_fd = Arc::new(device_fd);
+            // Create the hypervisor VFIO device
+            let passthrough_device = DeviceManager::create_passthrough_device(vm_info.vm)?;
+
+            for (index, device_cfg) in device_list_cfg.iter().enumerate() {
+                if device_cfg.iommu && device_cfg.pci_segment != 0 {
+                    return Err(DeviceManagerError::InvalidPciSegment(
+                        device_cfg.pci_segment,
+                    ));
+                }
 
-            for device_cfg in device_list_cfg.iter() {
                 // We need to shift the device id since the 3 first bits
                 // are dedicated to the PCI function, and we know we don't
                 // do multifunction. Also, because we only support one PCI
@@ -1118,66 +2914,496 @@ impl DeviceManager {
                 // global device ID.
                 let device_id = pci.next_device_id() << 3;
 
-                let vfio_device = VfioDevice::new(
-                    &device_cfg.path,
-                    device_fd.clone(),
-                    vm_info.memory.clone(),
-                    device_cfg.iommu,
-                )
-                .map_err(DeviceManagerError::VfioCreate)?;
+                // The MAC address of a virtual function is assigned before
+                // the guest driver reads it, when the device is reset.
+                let vf = match (
+                    VirtualFunction::from_sysfs(&device_cfg.path),
+                    &device_cfg.mac,
+                ) {
+                    (Ok(Some(vf)), Some(mac)) => {
+                        vf.set_mac(mac).map_err(DeviceManagerError::SriovMac)?;
+                        Some(vf)
+                    }
+                    (Ok(None), Some(_)) => {
+                        return Err(DeviceManagerError::SriovMac(
+                            sriov::Error::NotVirtualFunction(device_cfg.path.clone()),
+                        ))
+                    }
+                    (Err(e), Some(_)) => return Err(DeviceManagerError::SriovMac(e)),
+                    (Ok(vf), None) => vf,
+                    // The virtual functions of the devices other than the
+                    // network ones aren't reported.
+                    (Err(sriov::Error::NoPhysicalFunctionNetdev(_)), None) => None,
+                    (Err(e), None) => {
+                        warn!(
+                            "Cannot find the physical function of {:?}: {:?}",
+                            device_cfg.path, e
+                        );
+                        None
+                    }
+                };
+
+                let vfio_device = VfioDevice::new(&device_cfg.path, passthrough_device.clone())
+                    .map_err(DeviceManagerError::VfioCreate)?;
+
+                let vfio_mapping = Arc::new(VfioDmaMapping::new(
+                    vfio_device.get_container(),
+                    Arc::clone(vm_info.memory),
+                ));
 
                 if device_cfg.iommu {
                     if let Some(iommu) = iommu_device {
-                        let vfio_mapping = Arc::new(VfioDmaMapping::new(
-                            vfio_device.get_container(),
-                            Arc::clone(vm_info.memory),
-                        ));
-
                         iommu_attached_device_ids.push(device_id);
                         iommu.add_external_mapping(device_id, vfio_mapping);
                     }
+                } else {
+                    // Without the virtual IOMMU, the whole guest RAM is
+                    // identity mapped, and kept up to date by the memory
+                    // manager when RAM gets hotplugged.
+                    vm_info
+                        .memory_manager
+                        .lock()
+                        .unwrap()
+                        .add_memory_listener(vfio_mapping)
+                        .map_err(DeviceManagerError::RegisterMemoryListener)?;
                 }
 
-                let mut vfio_pci_device =
-                    VfioPciDevice::new(vm_info.vm_fd, &mut allocator, vfio_device)
-                        .map_err(DeviceManagerError::VfioPciCreate)?;
+                let rom = match &device_cfg.romfile {
+                    Some(romfile) => {
+                        Some(std::fs::read(romfile).map_err(DeviceManagerError::ReadRomFile)?)
+                    }
+                    None => None,
+                };
+                let vfio_pci_device = VfioPciDevice::new(
+                    vm_info.vm,
+                    &address_manager.msi_interrupt_manager,
+                    Arc::new(vfio_device),
+                    rom,
+                )
+                .map_err(DeviceManagerError::VfioPciCreate)?;
+
+                let devfn = DeviceManager::add_vfio_pci_device(
+                    vm_info.memory_manager,
+                    address_manager,
+                    &mut address_manager.allocator.lock().unwrap(),
+                    pci,
+                    pci_segments,
+                    device_cfg.pci_segment,
+                    vfio_pci_device,
+                )?;
+                let id = device_cfg
+                    .id
+                    .clone()
+                    .unwrap_or_else(|| format!("vfio{}", index));
+                pci_devices.insert(
+                    id.clone(),
+                    DeviceManager::pci_bdf(device_cfg.pci_segment, devfn),
+                );
+                if let Some(vf) = vf {
+                    sriov_vfs.insert(id, vf);
+                }
+            }
+        }
+        Ok(iommu_attached_device_ids)
+    }
 
-                let bars = vfio_pci_device
-                    .allocate_bars(&mut allocator)
-                    .map_err(DeviceManagerError::AllocateBars)?;
+    #[cfg(feature = "pci_support")]
+    #[allow(clippy::too_many_arguments)]
+    fn add_vfio_user_devices(
+        vm_info: &VmInfo,
+        address_manager: &Arc<AddressManager>,
+        pci: &mut PciBus,
+        pci_segments: &mut [PciSegment],
+        iommu_device: &mut Option<vm_virtio::Iommu>,
+        pci_devices: &mut BTreeMap<String, String>,
+        removable_pci_devices: &mut BTreeMap<String, RemovablePciDevice>,
+    ) -> DeviceManagerResult<Vec<u32>> {
+        let mut iommu_attached_device_ids = Vec::new();
+        if let Some(user_device_list_cfg) = &vm_info.vm_cfg.user_devices {
+            for (index, user_device_cfg) in user_device_list_cfg.iter().enumerate() {
+                let vfio_user_device = VfioUserDevice::new(&user_device_cfg.socket)
+                    .map_err(DeviceManagerError::VfioUserCreate)?;
+                let (devfn, memory_listener) = DeviceManager::add_vfio_user_device(
+                    vm_info,
+                    address_manager,
+                    pci,
+                    pci_segments,
+                    iommu_device,
+                    &mut iommu_attached_device_ids,
+                    vfio_user_device,
+                    user_device_cfg.iommu,
+                    user_device_cfg.pci_segment,
+                )?;
+                let id = user_device_cfg
+                    .id
+                    .clone()
+                    .unwrap_or_else(|| format!("vfio_user{}", index));
+                pci_devices.insert(
+                    id.clone(),
+                    DeviceManager::pci_bdf(user_device_cfg.pci_segment, devfn),
+                );
+                // The devices of the bus 0 outside of the virtual IOMMU
+                // can be removed.
+                if user_device_cfg.pci_segment == 0 && memory_listener.is_some() {
+                    removable_pci_devices.insert(
+                        id,
+                        RemovablePciDevice {
+                            device_id: devfn >> 3,
+                            memory_listener,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(iommu_attached_device_ids)
+    }
 
-                mem_slot = vfio_pci_device
-                    .map_mmio_regions(vm_info.vm_fd, mem_slot)
-                    .map_err(DeviceManagerError::VfioMapRegion)?;
+    // Adds the vfio-user device to a PCI bus, returning its device and
+    // function numbers, along with the memory listener mapping the guest
+    // RAM for it, unless it is attached to the virtual IOMMU.
+    #[cfg(feature = "pci_support")]
+    #[allow(clippy::too_many_arguments)]
+    fn add_vfio_user_device(
+        vm_info: &VmInfo,
+        address_manager: &Arc<AddressManager>,
+        pci: &mut PciBus,
+        pci_segments: &mut [PciSegment],
+        iommu_device: &mut Option<vm_virtio::Iommu>,
+        iommu_attached_device_ids: &mut Vec<u32>,
+        vfio_user_device: VfioUserDevice,
+        iommu: bool,
+        pci_segment: u16,
+    ) -> DeviceManagerResult<(u32, Option<Arc<dyn MemoryListener>>)> {
+        if iommu && pci_segment != 0 {
+            return Err(DeviceManagerError::InvalidPciSegment(pci_segment));
+        }
 
-                let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
+        // Same as the VFIO devices, we only do single function devices on
+        // the bus 0.
+        let device_id = pci.next_device_id() << 3;
 
-                pci.add_device(vfio_pci_device.clone())
-                    .map_err(DeviceManagerError::AddPciDevice)?;
+        // The device maps the guest RAM from the files backing it, which it
+        // is handed along with each DMA mapping.
+        let vfio_user_device = Arc::new(vfio_user_device);
+        let vfio_user_mapping = Arc::new(VfioUserDmaMapping::new(
+            vfio_user_device.clone(),
+            Arc::clone(vm_info.memory),
+        ));
 
-                pci.register_mapping(
-                    vfio_pci_device.clone(),
-                    address_manager.io_bus.as_ref(),
-                    address_manager.mmio_bus.as_ref(),
-                    bars,
-                )
-                .map_err(DeviceManagerError::AddPciDevice)?;
+        let mut memory_listener = None;
+        if iommu {
+            if let Some(iommu) = iommu_device {
+                iommu_attached_device_ids.push(device_id);
+                iommu.add_external_mapping(device_id, vfio_user_mapping);
+            }
+        } else {
+            let listener: Arc<dyn MemoryListener> = vfio_user_mapping;
+            vm_info
+                .memory_manager
+                .lock()
+                .unwrap()
+                .add_memory_listener(listener.clone())
+                .map_err(DeviceManagerError::RegisterMemoryListener)?;
+            memory_listener = Some(listener);
+        }
+
+        let vfio_pci_device = VfioPciDevice::new(
+            vm_info.vm,
+            &address_manager.msi_interrupt_manager,
+            vfio_user_device,
+            None,
+        )
+        .map_err(DeviceManagerError::VfioPciCreate)?;
+
+        let devfn = DeviceManager::add_vfio_pci_device(
+            vm_info.memory_manager,
+            address_manager,
+            &mut address_manager.allocator.lock().unwrap(),
+            pci,
+            pci_segments,
+            pci_segment,
+            vfio_pci_device,
+        )?;
+
+        Ok((devfn, memory_listener))
+    }
+
+    // The plugin devices are vfio-user devices, served by the processes of
+    // their plugins.
+    #[cfg(feature = "pci_support")]
+    #[allow(clippy::too_many_arguments)]
+    fn add_plugin_devices(
+        vm_info: &VmInfo,
+        address_manager: &Arc<AddressManager>,
+        pci: &mut PciBus,
+        pci_segments: &mut [PciSegment],
+        iommu_device: &mut Option<vm_virtio::Iommu>,
+        pci_devices: &mut BTreeMap<String, String>,
+        plugin_processes: &mut Vec<PluginProcess>,
+    ) -> DeviceManagerResult<Vec<u32>> {
+        let mut iommu_attached_device_ids = Vec::new();
+        let plugins = vm_info
+            .vm_cfg
+            .plugins
+            .as_ref()
+            .map_or(&[][..], Vec::as_slice);
+        if let Some(plugin_device_list_cfg) = &vm_info.vm_cfg.plugin_devices {
+            for (index, plugin_device_cfg) in plugin_device_list_cfg.iter().enumerate() {
+                let id = plugin_device_cfg
+                    .id
+                    .clone()
+                    .unwrap_or_else(|| format!("{}{}", plugin_device_cfg.plugin, index));
+                let (process, socket) = plugin::spawn_plugin(plugins, plugin_device_cfg, &id)
+                    .map_err(DeviceManagerError::SpawnPlugin)?;
+                plugin_processes.push(process);
+
+                let vfio_user_device = VfioUserDevice::from_stream(socket)
+                    .map_err(DeviceManagerError::VfioUserCreate)?;
+                let (devfn, _) = DeviceManager::add_vfio_user_device(
+                    vm_info,
+                    address_manager,
+                    pci,
+                    pci_segments,
+                    iommu_device,
+                    &mut iommu_attached_device_ids,
+                    vfio_user_device,
+                    plugin_device_cfg.iommu,
+                    plugin_device_cfg.pci_segment,
+                )?;
+                pci_devices.insert(
+                    id,
+                    DeviceManager::pci_bdf(plugin_device_cfg.pci_segment, devfn),
+                );
             }
         }
         Ok(iommu_attached_device_ids)
     }
 
+    // Reserves the windows of the PCI segments other than the segment 0 out
+    // of the topmost I/O ports and MMIO addresses, and creates their buses.
+    // The 32 bits device area is shared evenly between all the segments,
+    // each of the other ones getting 1/64 of the physical address space for
+    // its 64 bits BARs.
+    #[cfg(all(feature = "pci_support", target_arch = "x86_64"))]
+    fn create_pci_segments(
+        vm_info: &VmInfo,
+        address_manager: &Arc<AddressManager>,
+    ) -> DeviceManagerResult<Vec<PciSegment>> {
+        let num_pci_segments = vm_info.vm_cfg.pci.num_pci_segments;
+        if num_pci_segments > crate::config::MAX_PCI_SEGMENTS {
+            return Err(DeviceManagerError::TooManyPciSegments(num_pci_segments));
+        }
+        if num_pci_segments <= 1 {
+            return Ok(Vec::new());
+        }
+
+        let default_mem32_size = (arch::layout::MEM_32BIT_DEVICES_SIZE
+            / u64::from(num_pci_segments))
+            & !(PCI_SEGMENT_MEM32_ALIGNMENT - 1);
+        let default_mem64_size: GuestUsize = (1 << crate::vm::get_host_cpu_phys_bits()) >> 6;
+
+        let mut allocator = address_manager.allocator.lock().unwrap();
+        let mut pci_segments = Vec::new();
+        for id in 1..num_pci_segments {
+            let config = vm_info
+                .vm_cfg
+                .pci_segments
+                .iter()
+                .flatten()
+                .find(|config| config.pci_segment == id);
+
+            // The apertures are rounded up to their alignment, the 64 bits
+            // one being naturally aligned when its size is a power of two,
+            // so that a BAR as large as the aperture fits into it.
+            let mem32_size = config
+                .and_then(|config| config.mmio32_aperture_size)
+                .map_or(default_mem32_size, |size| {
+                    (size + PCI_SEGMENT_MEM32_ALIGNMENT - 1) & !(PCI_SEGMENT_MEM32_ALIGNMENT - 1)
+                });
+            let mem64_size = config
+                .and_then(|config| config.mmio64_aperture_size)
+                .map_or(default_mem64_size, |size| {
+                    (size + PCI_SEGMENT_MEM64_ALIGNMENT - 1) & !(PCI_SEGMENT_MEM64_ALIGNMENT - 1)
+                });
+            let mem64_alignment = if mem64_size.is_power_of_two() {
+                mem64_size
+            } else {
+                PCI_SEGMENT_MEM64_ALIGNMENT
+            };
+
+            let io = allocator
+                .allocate_io_addresses(None, PCI_SEGMENT_IO_SIZE, Some(PCI_SEGMENT_IO_SIZE))
+                .ok_or(DeviceManagerError::PciSegmentAllocation(id))?;
+            let mem32 = allocator
+                .allocate_mmio_hole_addresses(None, mem32_size, Some(PCI_SEGMENT_MEM32_ALIGNMENT))
+                .ok_or(DeviceManagerError::PciSegmentAllocation(id))?;
+            let mem64 = allocator
+                .allocate_mmio_addresses(None, mem64_size, Some(mem64_alignment))
+                .ok_or(DeviceManagerError::PciSegmentAllocation(id))?;
+
+            let windows = PciSegmentWindows {
+                id,
+                mmconfig: (
+                    arch::layout::PCI_SEGMENTS_MMCONFIG_START
+                        .unchecked_add(u64::from(id - 1) * arch::layout::PCI_SEGMENT_MMCONFIG_SIZE),
+                    arch::layout::PCI_SEGMENT_MMCONFIG_SIZE,
+                ),
+                io: (io, PCI_SEGMENT_IO_SIZE),
+                mem32: (mem32, mem32_size),
+                mem64: (mem64, mem64_size),
+            };
+
+            let segment_allocator = SystemAllocator::new(
+                io,
+                PCI_SEGMENT_IO_SIZE,
+                mem64,
+                mem64_size,
+                mem32,
+                mem32_size,
+                vec![],
+            )
+            .ok_or(DeviceManagerError::PciSegmentAllocation(id))?;
+            let segment_address_manager = Arc::new(AddressManager {
+                allocator: Arc::new(Mutex::new(segment_allocator)),
+                io_bus: address_manager.io_bus.clone(),
+                mmio_bus: address_manager.mmio_bus.clone(),
+                vm: vm_info.vm.clone(),
+                msi_interrupt_manager: address_manager.msi_interrupt_manager.clone(),
+            });
+
+            let bus = PciBus::new(
+                PciRoot::new(None),
+                Arc::downgrade(&segment_address_manager) as Weak<dyn DeviceRelocation>,
+            );
+
+            pci_segments.push(PciSegment {
+                windows,
+                bus,
+                address_manager: segment_address_manager,
+            });
+        }
+
+        Ok(pci_segments)
+    }
+
+    #[cfg(all(feature = "pci_support", target_arch = "aarch64"))]
+    fn create_pci_segments(
+        vm_info: &VmInfo,
+        _address_manager: &Arc<AddressManager>,
+    ) -> DeviceManagerResult<Vec<PciSegment>> {
+        if vm_info.vm_cfg.pci.num_pci_segments > 1 {
+            return Err(DeviceManagerError::PciSegmentsUnsupported);
+        }
+
+        Ok(Vec::new())
+    }
+
+    // Allocates the BARs of a VFIO PCI device, maps its regions into the
+    // guest, and adds it to the PCI bus of its segment. The devices on the
+    // segments other than the segment 0 get their BARs out of the windows
+    // of their segment.
+    #[cfg(feature = "pci_support")]
+    fn add_vfio_pci_device(
+        memory_manager: &Arc<Mutex<MemoryManager>>,
+        address_manager: &Arc<AddressManager>,
+        allocator: &mut SystemAllocator,
+        pci: &mut PciBus,
+        pci_segments: &mut [PciSegment],
+        pci_segment: u16,
+        mut vfio_pci_device: VfioPciDevice,
+    ) -> DeviceManagerResult<u32> {
+        if pci_segment != 0 {
+            let segment = pci_segments
+                .get_mut(usize::from(pci_segment) - 1)
+                .ok_or(DeviceManagerError::InvalidPciSegment(pci_segment))?;
+            return DeviceManager::add_vfio_pci_device(
+                memory_manager,
+                &segment.address_manager,
+                &mut segment.address_manager.allocator.lock().unwrap(),
+                &mut segment.bus,
+                &mut [],
+                0,
+                vfio_pci_device,
+            );
+        }
+
+        let bars = vfio_pci_device
+            .allocate_bars(allocator)
+            .map_err(DeviceManagerError::AllocateBars)?;
+
+        vfio_pci_device
+            .map_mmio_regions(&address_manager.vm, || {
+                memory_manager.lock().unwrap().allocate_kvm_slot().ok()
+            })
+            .map_err(DeviceManagerError::VfioMapRegion)?;
+
+        let vfio_pci_device = Arc::new(Mutex::new(vfio_pci_device));
+
+        let devfn = pci.next_device_id() << 3;
+        pci.add_device(vfio_pci_device.clone())
+            .map_err(DeviceManagerError::AddPciDevice)?;
+
+        pci.register_mapping(
+            vfio_pci_device,
+            address_manager.io_bus.as_ref(),
+            address_manager.mmio_bus.as_ref(),
+            bars,
+        )
+        .map_err(DeviceManagerError::AddPciDevice)?;
+
+        Ok(devfn)
+    }
+
+    // The I/O ports the guest reads the devices added and removed from, and
+    // writes the ones it ejects to.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    fn create_pci_hotplug(
+        vm_info: &VmInfo,
+        address_manager: &Arc<AddressManager>,
+        bus: &Arc<Mutex<PciBus>>,
+    ) -> DeviceManagerResult<PciHotplug> {
+        let eject_evt = EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
+        let device = Arc::new(Mutex::new(devices::AcpiPciHotplugDevice::new(
+            eject_evt.try_clone().map_err(DeviceManagerError::EventFd)?,
+        )));
+
+        address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_io_addresses(Some(GuestAddress(0xae00)), 0xc, None)
+            .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+        address_manager
+            .io_bus
+            .insert(device.clone(), 0xae00, 0xc)
+            .map_err(DeviceManagerError::BusError)?;
+
+        Ok(PciHotplug {
+            bus: bus.clone(),
+            device,
+            eject_evt,
+            memory: vm_info.memory.clone(),
+            memory_manager: vm_info.memory_manager.clone(),
+        })
+    }
+
     #[cfg(feature = "pci_support")]
     #[allow(clippy::too_many_arguments)]
     fn add_virtio_pci_device(
         virtio_device: Box<dyn vm_virtio::VirtioDevice>,
+        id: Option<String>,
         memory: &Arc<RwLock<GuestMemoryMmap>>,
         address_manager: &Arc<AddressManager>,
-        vm_fd: &Arc<VmFd>,
+        vm: &Arc<dyn hypervisor::Vm>,
         pci: &mut PciBus,
         interrupt_info: &InterruptInfo,
         iommu_mapping: &Option<Arc<IommuMapping>>,
+        virtio_transports: &mut Vec<(String, Arc<Mutex<dyn VirtioTransport>>)>,
+        pci_devices: &mut BTreeMap<String, String>,
+        multifunction: bool,
     ) -> DeviceManagerResult<Option<u32>> {
+        let id = DeviceManager::virtio_device_id(id, virtio_device.as_ref(), virtio_transports)?;
         let msix_num = if interrupt_info._msi_capable {
             // Allows support for one MSI-X vector per queue. It also adds 1
             // as we need to take into account the dedicated vector to notify
@@ -1188,10 +3414,15 @@ impl DeviceManager {
         };
 
         // We need to shift the device id since the 3 first bits are dedicated
-        // to the PCI function, and we know we don't do multifunction.
+        // to the PCI function, unless the device is packed onto a
+        // multifunction device, as one of its functions.
         // Also, because we only support one PCI bus, the bus 0, we don't need
         // to add anything to the global device ID.
-        let dev_id = pci.next_device_id() << 3;
+        let dev_id = if multifunction {
+            pci.next_function_id()
+        } else {
+            pci.next_device_id() << 3
+        };
 
         // Create the callback from the implementation of the DmaRemapping
         // trait. The point with the callback is to simplify the code as we
@@ -1204,8 +3435,11 @@ impl DeviceManager {
                         std::io::Error::new(
                             std::io::ErrorKind::Other,
                             format!(
-                                "failed to translate addr 0x{:x} for device 00:{:02x}.0 {}",
-                                addr, dev_id, e
+                                "failed to translate addr 0x{:x} for device 00:{:02x}.{} {}",
+                                addr,
+                                dev_id >> 3,
+                                dev_id & 0x7,
+                                e
                             ),
                         )
                     })
@@ -1218,6 +3452,15 @@ impl DeviceManager {
             VirtioPciDevice::new(memory.clone(), virtio_device, msix_num, iommu_mapping_cb)
                 .map_err(DeviceManagerError::VirtioDevice)?;
 
+        // Each vector is raised through its irqfd, from the device threads.
+        if interrupt_info._msi_capable {
+            let interrupt_group = address_manager
+                .msi_interrupt_manager
+                .create_group(Some(dev_id), u32::from(msix_num))
+                .map_err(DeviceManagerError::CreateInterruptGroup)?;
+            virtio_pci_device.assign_msix(interrupt_group);
+        }
+
         let mut allocator = address_manager.allocator.lock().unwrap();
 
         let bars = virtio_pci_device
@@ -1227,69 +3470,15 @@ impl DeviceManager {
         let bar_addr = virtio_pci_device.config_bar_addr();
         for (event, addr) in virtio_pci_device.ioeventfds(bar_addr) {
             let io_addr = IoEventAddress::Mmio(addr);
-            vm_fd
-                .register_ioevent(event, &io_addr, NoDatamatch)
+            vm.register_ioevent(event, &io_addr, None)
                 .map_err(DeviceManagerError::RegisterIoevent)?;
         }
 
-        if interrupt_info._msi_capable {
-            let vm_fd_clone = vm_fd.clone();
-
-            let msi_cb = Arc::new(Box::new(move |p: InterruptParameters| {
-                if let Some(entry) = p.msix {
-                    use kvm_bindings::kvm_msi;
-                    let msi_queue = kvm_msi {
-                        address_lo: entry.msg_addr_lo,
-                        address_hi: entry.msg_addr_hi,
-                        data: entry.msg_data,
-                        flags: 0u32,
-                        devid: 0u32,
-                        pad: [0u8; 12],
-                    };
-
-                    return vm_fd_clone.signal_msi(msi_queue).map(|ret| {
-                        if ret > 0 {
-                            debug!("MSI message successfully delivered");
-                        } else if ret == 0 {
-                            warn!("failed to deliver MSI message, blocked by guest");
-                        }
-                    });
-                }
-
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "missing MSI-X entry",
-                ))
-            }) as InterruptDelivery);
-
-            virtio_pci_device.assign_msix(msi_cb);
-        } else {
+        if !interrupt_info._msi_capable {
             let irq_num = allocator
                 .allocate_irq()
                 .ok_or(DeviceManagerError::AllocateIrq)?;
-
-            let irq_cb = if let Some(ioapic) = interrupt_info.ioapic {
-                let ioapic_clone = ioapic.clone();
-                Box::new(move |_p: InterruptParameters| {
-                    ioapic_clone
-                        .lock()
-                        .unwrap()
-                        .service_irq(irq_num as usize)
-                        .map_err(|e| {
-                            std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                format!("failed to inject IRQ #{}: {:?}", irq_num, e),
-                            )
-                        })
-                }) as InterruptDelivery
-            } else {
-                let irqfd = EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
-                vm_fd
-                    .register_irqfd(&irqfd, irq_num)
-                    .map_err(DeviceManagerError::Irq)?;
-
-                Box::new(move |_p: InterruptParameters| irqfd.write(1)) as InterruptDelivery
-            };
+            let irq_cb = DeviceManager::pin_irq_cb(irq_num, vm, interrupt_info)?;
 
             virtio_pci_device.assign_pin_irq(
                 Arc::new(irq_cb),
@@ -1300,8 +3489,12 @@ impl DeviceManager {
 
         let virtio_pci_device = Arc::new(Mutex::new(virtio_pci_device));
 
-        pci.add_device(virtio_pci_device.clone())
-            .map_err(DeviceManagerError::AddPciDevice)?;
+        if multifunction {
+            pci.add_function(virtio_pci_device.clone())
+        } else {
+            pci.add_device(virtio_pci_device.clone())
+        }
+        .map_err(DeviceManagerError::AddPciDevice)?;
 
         pci.register_mapping(
             virtio_pci_device.clone(),
@@ -1311,6 +3504,9 @@ impl DeviceManager {
         )
         .map_err(DeviceManagerError::AddPciDevice)?;
 
+        pci_devices.insert(id.clone(), DeviceManager::pci_bdf(0, dev_id));
+        virtio_transports.push((id, virtio_pci_device));
+
         let ret = if iommu_mapping.is_some() {
             Some(dev_id)
         } else {
@@ -1320,25 +3516,262 @@ impl DeviceManager {
         Ok(ret)
     }
 
-    #[allow(clippy::too_many_arguments)]
-    #[cfg(feature = "mmio_support")]
-    fn add_virtio_mmio_device(
-        virtio_device: Box<dyn vm_virtio::VirtioDevice>,
-        memory: &Arc<RwLock<GuestMemoryMmap>>,
-        address_manager: &Arc<AddressManager>,
-        vm_fd: &Arc<VmFd>,
-        interrupt_info: &InterruptInfo,
-        mmio_base: GuestAddress,
-        cmdline_additions: &mut Vec<String>,
-    ) -> DeviceManagerResult<()> {
-        let mut mmio_device = vm_virtio::transport::MmioDevice::new(memory.clone(), virtio_device)
-            .map_err(DeviceManagerError::VirtioDevice)?;
-
-        for (i, (event, addr)) in mmio_device.ioeventfds(mmio_base.0).iter().enumerate() {
-            let io_addr = IoEventAddress::Mmio(*addr);
-            vm_fd
-                .register_ioevent(event, &io_addr, i as u32)
-                .map_err(DeviceManagerError::RegisterIoevent)?;
+    // Output of a serial port, along with the pseudo-terminal or UNIX socket
+    // backing it, if any.
+    #[allow(clippy::type_complexity)]
+    fn create_serial_backend(
+        mode: &ConsoleOutputMode,
+        file: &Option<PathBuf>,
+    ) -> DeviceManagerResult<(
+        Option<Box<dyn io::Write + Send>>,
+        Option<PtyPair>,
+        Option<SerialSocket>,
+    )> {
+        let pty = if *mode == ConsoleOutputMode::Pty {
+            Some(create_pty().map_err(DeviceManagerError::SerialPtyOpen)?)
+        } else {
+            None
+        };
+        let socket = if *mode == ConsoleOutputMode::Socket {
+            Some(
+                SerialSocket::new(file.as_ref().unwrap())
+                    .map_err(DeviceManagerError::SerialSocketOpen)?,
+            )
+        } else {
+            None
+        };
+        let writer: Option<Box<dyn io::Write + Send>> = match mode {
+            ConsoleOutputMode::File => Some(Box::new(
+                File::create(file.as_ref().unwrap())
+                    .map_err(DeviceManagerError::SerialOutputFileOpen)?,
+            )),
+            ConsoleOutputMode::Pty => Some(Box::new(
+                pty.as_ref()
+                    .unwrap()
+                    .main
+                    .try_clone()
+                    .map_err(DeviceManagerError::SerialPtyOpen)?,
+            )),
+            ConsoleOutputMode::Socket => Some(Box::new(socket.as_ref().unwrap().writer())),
+            ConsoleOutputMode::Tty => Some(Box::new(stdout())),
+            ConsoleOutputMode::Off | ConsoleOutputMode::Null => None,
+        };
+
+        Ok((writer, pty, socket))
+    }
+
+    // Interrupt of a legacy device, on the IOAPIC `irq` pin.
+    fn create_legacy_interrupt(
+        vm_info: &VmInfo,
+        ioapic: &Option<Arc<Mutex<ioapic::Ioapic>>>,
+        irq: usize,
+    ) -> DeviceManagerResult<Box<dyn devices::Interrupt>> {
+        if let Some(ioapic) = ioapic {
+            return Ok(Box::new(UserIoapicIrq::new(ioapic.clone(), irq)));
+        }
+
+        let evt = EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
+        vm_info
+            .vm
+            .register_irqfd(&evt, irq as u32)
+            .map_err(DeviceManagerError::Irq)?;
+
+        Ok(Box::new(KernelIoapicIrq::new(evt)))
+    }
+
+    // Legacy PCI interrupt, for devices without MSI-X.
+    #[cfg(feature = "pci_support")]
+    fn pin_irq_cb(
+        irq_num: u32,
+        vm: &Arc<dyn hypervisor::Vm>,
+        interrupt_info: &InterruptInfo,
+    ) -> DeviceManagerResult<InterruptDelivery> {
+        let irq_cb = if let Some(ioapic) = interrupt_info.ioapic {
+            let ioapic_clone = ioapic.clone();
+            Box::new(move |_p: InterruptParameters| {
+                ioapic_clone
+                    .lock()
+                    .unwrap()
+                    .service_irq(irq_num as usize)
+                    .map_err(|e| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            format!("failed to inject IRQ #{}: {:?}", irq_num, e),
+                        )
+                    })
+            }) as InterruptDelivery
+        } else {
+            let irqfd = EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
+            vm.register_irqfd(&irqfd, irq_num)
+                .map_err(DeviceManagerError::Irq)?;
+
+            Box::new(move |_p: InterruptParameters| irqfd.write(1)) as InterruptDelivery
+        };
+
+        Ok(irq_cb)
+    }
+
+    #[cfg(feature = "e1000_support")]
+    fn add_e1000_devices(
+        vm_info: &VmInfo,
+        address_manager: &Arc<AddressManager>,
+        pci: &mut PciBus,
+        interrupt_info: &InterruptInfo,
+        pci_devices: &mut BTreeMap<String, String>,
+        e1000_devices: &mut Vec<(String, Arc<Mutex<e1000::E1000>>)>,
+    ) -> DeviceManagerResult<()> {
+        if let Some(net_list_cfg) = &vm_info.vm_cfg.net {
+            for (index, net_cfg) in net_list_cfg
+                .iter()
+                .filter(|net_cfg| net_cfg.model == NetModel::E1000)
+                .enumerate()
+            {
+                let tap = DeviceManager::open_taps(vm_info, net_cfg)?.pop();
+                let mac = DeviceManager::guest_mac(net_cfg, tap.as_ref())?;
+                let mut e1000_device = if let Some(tap) = tap {
+                    e1000::E1000::new_with_tap(tap, &mac, vm_info.memory.clone())
+                        .map_err(DeviceManagerError::CreateE1000)?
+                } else {
+                    e1000::E1000::new(
+                        net_cfg.ip,
+                        net_cfg.mask,
+                        net_cfg.ipv6(),
+                        &net_cfg.mac,
+                        vm_info.memory.clone(),
+                    )
+                    .map_err(DeviceManagerError::CreateE1000)?
+                };
+
+                let mut allocator = address_manager.allocator.lock().unwrap();
+                let bars = e1000_device
+                    .allocate_bars(&mut allocator)
+                    .map_err(DeviceManagerError::AllocateBars)?;
+
+                let irq_num = allocator
+                    .allocate_irq()
+                    .ok_or(DeviceManagerError::AllocateIrq)?;
+                let irq_cb = DeviceManager::pin_irq_cb(irq_num, vm_info.vm, interrupt_info)?;
+                e1000_device.assign_pin_irq(Arc::new(irq_cb), irq_num, PciInterruptPin::IntA);
+
+                let e1000_device = Arc::new(Mutex::new(e1000_device));
+
+                let devfn = pci.next_device_id() << 3;
+                pci.add_device(e1000_device.clone())
+                    .map_err(DeviceManagerError::AddPciDevice)?;
+                let id = net_cfg
+                    .id
+                    .clone()
+                    .unwrap_or_else(|| format!("e1000_{}", index));
+                pci_devices.insert(id.clone(), DeviceManager::pci_bdf(0, devfn));
+                e1000_devices.push((id, e1000_device.clone()));
+
+                pci.register_mapping(
+                    e1000_device,
+                    address_manager.io_bus.as_ref(),
+                    address_manager.mmio_bus.as_ref(),
+                    bars,
+                )
+                .map_err(DeviceManagerError::AddPciDevice)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "ahci_support")]
+    fn add_ahci_device(
+        vm_info: &VmInfo,
+        address_manager: &Arc<AddressManager>,
+        pci: &mut PciBus,
+        interrupt_info: &InterruptInfo,
+        pci_devices: &mut BTreeMap<String, String>,
+    ) -> DeviceManagerResult<()> {
+        let mut disks = Vec::new();
+
+        if let Some(disk_list_cfg) = &vm_info.vm_cfg.disks {
+            for disk_cfg in disk_list_cfg
+                .iter()
+                .filter(|disk_cfg| disk_cfg.model == DiskModel::Ahci)
+            {
+                let raw_img = DeviceManager::open_disk(vm_info, disk_cfg)?;
+
+                let image_type = qcow::detect_image_type(&raw_img)
+                    .map_err(DeviceManagerError::DetectImageType)?;
+                DeviceManager::set_direct_io(&raw_img, disk_cfg, &image_type)?;
+                let disk = match image_type {
+                    ImageType::Raw => {
+                        Box::new(vm_virtio::RawFile::new(raw_img)) as Box<dyn ahci::DiskFile>
+                    }
+                    ImageType::Qcow2 => Box::new(
+                        QcowFile::from(raw_img).map_err(DeviceManagerError::QcowDeviceCreate)?,
+                    ) as Box<dyn ahci::DiskFile>,
+                };
+
+                // Same serial number as the one a virtio-blk device would get.
+                disks.push((disk, vm_virtio::build_disk_image_id(&disk_cfg.path)));
+            }
+        }
+
+        // A single controller drives all the AHCI disks, and is only created
+        // if there is at least one of them.
+        if disks.is_empty() {
+            return Ok(());
+        }
+
+        let mut ahci_device = ahci::Ahci::new(disks, vm_info.memory.clone())
+            .map_err(DeviceManagerError::CreateAhci)?;
+
+        let mut allocator = address_manager.allocator.lock().unwrap();
+        let bars = ahci_device
+            .allocate_bars(&mut allocator)
+            .map_err(DeviceManagerError::AllocateBars)?;
+
+        let irq_num = allocator
+            .allocate_irq()
+            .ok_or(DeviceManagerError::AllocateIrq)?;
+        let irq_cb = DeviceManager::pin_irq_cb(irq_num, vm_info.vm, interrupt_info)?;
+        ahci_device.assign_pin_irq(Arc::new(irq_cb), irq_num, PciInterruptPin::IntA);
+
+        let ahci_device = Arc::new(Mutex::new(ahci_device));
+
+        let devfn = pci.next_device_id() << 3;
+        pci.add_device(ahci_device.clone())
+            .map_err(DeviceManagerError::AddPciDevice)?;
+        pci_devices.insert("ahci0".to_string(), DeviceManager::pci_bdf(0, devfn));
+
+        pci.register_mapping(
+            ahci_device,
+            address_manager.io_bus.as_ref(),
+            address_manager.mmio_bus.as_ref(),
+            bars,
+        )
+        .map_err(DeviceManagerError::AddPciDevice)?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[cfg(feature = "mmio_support")]
+    fn add_virtio_mmio_device(
+        virtio_device: Box<dyn vm_virtio::VirtioDevice>,
+        id: Option<String>,
+        memory: &Arc<RwLock<GuestMemoryMmap>>,
+        address_manager: &Arc<AddressManager>,
+        vm: &Arc<dyn hypervisor::Vm>,
+        interrupt_info: &InterruptInfo,
+        mmio_base: GuestAddress,
+        cmdline_additions: &mut Vec<String>,
+        virtio_mmio_devices: &mut Vec<(GuestAddress, GuestUsize, u32)>,
+        virtio_transports: &mut Vec<(String, Arc<Mutex<dyn VirtioTransport>>)>,
+    ) -> DeviceManagerResult<()> {
+        let id = DeviceManager::virtio_device_id(id, virtio_device.as_ref(), virtio_transports)?;
+        let mut mmio_device = vm_virtio::transport::MmioDevice::new(memory.clone(), virtio_device)
+            .map_err(DeviceManagerError::VirtioDevice)?;
+
+        for (i, (event, addr)) in mmio_device.ioeventfds(mmio_base.0).iter().enumerate() {
+            let io_addr = IoEventAddress::Mmio(*addr);
+            vm.register_ioevent(event, &io_addr, Some(DataMatch::DataMatch32(i as u32)))
+                .map_err(DeviceManagerError::RegisterIoevent)?;
         }
 
         let irq_num = address_manager
@@ -1353,8 +3786,7 @@ impl DeviceManager {
         } else {
             let irqfd = EventFd::new(EFD_NONBLOCK).map_err(DeviceManagerError::EventFd)?;
 
-            vm_fd
-                .register_irqfd(&irqfd, irq_num as u32)
+            vm.register_irqfd(&irqfd, irq_num as u32)
                 .map_err(DeviceManagerError::Irq)?;
 
             Box::new(KernelIoapicIrq::new(irqfd))
@@ -1362,21 +3794,64 @@ impl DeviceManager {
 
         mmio_device.assign_interrupt(interrupt);
 
+        let mmio_device = Arc::new(Mutex::new(mmio_device));
         address_manager
             .mmio_bus
-            .insert(Arc::new(Mutex::new(mmio_device)), mmio_base.0, MMIO_LEN)
+            .insert(mmio_device.clone(), mmio_base.0, MMIO_LEN)
             .map_err(DeviceManagerError::BusError)?;
+        virtio_transports.push((id, mmio_device));
 
+        // The kernel can't tell the GIC interrupt from the command line, the
+        // device tree describes the device instead.
+        #[cfg(target_arch = "x86_64")]
         cmdline_additions.push(format!(
             "virtio_mmio.device={}K@0x{:08x}:{}",
             MMIO_LEN / 1024,
             mmio_base.0,
             irq_num
         ));
+        #[cfg(target_arch = "aarch64")]
+        let _ = cmdline_additions;
+        virtio_mmio_devices.push((mmio_base, MMIO_LEN, irq_num));
 
         Ok(())
     }
 
+    // The ID of a virtio device is the one of its configuration, if any.
+    // The devices the VMM adds on its own get their type followed by the
+    // first index no other device has, such as "console0" or "rng0".
+    #[cfg(any(feature = "pci_support", feature = "mmio_support"))]
+    fn virtio_device_id(
+        id: Option<String>,
+        virtio_device: &dyn vm_virtio::VirtioDevice,
+        virtio_transports: &[(String, Arc<Mutex<dyn VirtioTransport>>)],
+    ) -> DeviceManagerResult<String> {
+        let taken = |id: &str| virtio_transports.iter().any(|(other, _)| other == id);
+        if let Some(id) = id {
+            if taken(id.as_str()) {
+                return Err(DeviceManagerError::DuplicateDeviceId(id));
+            }
+            return Ok(id);
+        }
+
+        let name = match vm_virtio::VirtioDeviceType::from(virtio_device.device_type()) {
+            vm_virtio::VirtioDeviceType::TYPE_UNKNOWN => "virtio".to_string(),
+            device_type => device_type.to_string(),
+        };
+
+        Ok((0..)
+            .map(|index| format!("{}{}", name, index))
+            .find(|id| !taken(id.as_str()))
+            .unwrap())
+    }
+
+    // PCI address of a device on the bus 0 of a segment, in the
+    // "<segment>:<bus>:<device>.<function>" form of lspci.
+    #[cfg(feature = "pci_support")]
+    fn pci_bdf(pci_segment: u16, devfn: u32) -> String {
+        format!("{:04x}:00:{:02x}.{}", pci_segment, devfn >> 3, devfn & 0x7)
+    }
+
     pub fn io_bus(&self) -> &Arc<devices::Bus> {
         &self.address_manager.io_bus
     }
@@ -1397,6 +3872,17 @@ impl DeviceManager {
         self.cmdline_additions.as_slice()
     }
 
+    pub fn virtio_mmio_devices(&self) -> &[(GuestAddress, GuestUsize, u32)] {
+        self.virtio_mmio_devices.as_slice()
+    }
+
+    pub fn pci_segments(&self) -> Vec<PciSegmentWindows> {
+        self.pci_segments
+            .iter()
+            .map(|(windows, _)| *windows)
+            .collect()
+    }
+
     pub fn virt_iommu(&self) -> Option<(u32, &[u32])> {
         if let Some((iommu_id, dev_ids)) = self.virt_iommu.as_ref() {
             Some((*iommu_id, dev_ids.as_slice()))
@@ -1404,6 +3890,538 @@ impl DeviceManager {
             None
         }
     }
+
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn ged_notification_device(&self) -> Option<&Arc<Mutex<devices::AcpiGEDDevice>>> {
+        self.ged_notification_device.as_ref().map(|(ged, _)| ged)
+    }
+
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn ged_irq(&self) -> Option<u32> {
+        self.ged_notification_device.as_ref().map(|(_, irq)| *irq)
+    }
+
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn nvdimm_ranges(&self) -> &[(GuestAddress, u64)] {
+        &self.nvdimm_ranges
+    }
+
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn acpi_sensors_device(&self) -> Option<&Arc<Mutex<devices::AcpiSensorsDevice>>> {
+        self.acpi_sensors_device.as_ref()
+    }
+
+    /// Event written when the guest reports a panic through its pvpanic
+    /// device, if it has one.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn pvpanic_evt(&self) -> Option<&EventFd> {
+        self.pvpanic_device.as_ref().map(|(_, panic_evt)| panic_evt)
+    }
+
+    /// The pvpanic events the guest reported since the last call.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn take_pvpanic_events(&self) -> u8 {
+        match &self.pvpanic_device {
+            Some((pvpanic_device, _)) => pvpanic_device.lock().unwrap().take_events(),
+            None => 0,
+        }
+    }
+
+    /// PCI addresses of the devices, by ID: the one of their configuration,
+    /// such as "block0", or the one the VMM gives to its own devices, such
+    /// as "rng0" and the "ahci0" controller.
+    pub fn pci_devices(&self) -> &BTreeMap<String, String> {
+        &self.pci_devices
+    }
+
+    /// The MAC addresses and link states of the SR-IOV virtual functions of
+    /// the network devices passed through with VFIO, by ID, as their
+    /// physical functions report them.
+    pub fn sriov_vfs(&self) -> BTreeMap<String, VfInfo> {
+        self.sriov_vfs
+            .iter()
+            .map(|(id, vf)| (id.clone(), vf.info()))
+            .collect()
+    }
+
+    /// The last output of the guest consoles, when the VM is configured
+    /// with diagnostic bundles.
+    pub fn console_log(&self) -> Option<&Arc<ConsoleLog>> {
+        self.console_log.as_ref()
+    }
+
+    /// The guest OS probe, when the VM is configured to probe the guest OS.
+    pub fn guest_os_probe(&self) -> Option<&Arc<GuestOsProbe>> {
+        self.guest_os_probe.as_ref()
+    }
+
+    /// The display of the virtio-gpu device, when the VM has one.
+    pub fn gpu_display(&self) -> Option<&vm_virtio::GpuDisplay> {
+        self.gpu_display.as_ref()
+    }
+
+    /// The events pending for the virtio-input devices, along with their
+    /// kind.
+    pub fn input_events(&self) -> &[(vm_virtio::InputKind, Arc<vm_virtio::InputEvents>)] {
+        &self.input_events
+    }
+
+    /// Indexes of the available and used rings of the queues of each of the
+    /// virtio devices, by ID.
+    pub fn virtio_ring_indexes(&self) -> Vec<(String, Vec<(u16, u16)>)> {
+        self.virtio_devices
+            .iter()
+            .map(|(id, device)| (id.clone(), device.lock().unwrap().ring_indexes()))
+            .collect()
+    }
+
+    /// The audits of the guest drivers of the virtio devices, by ID, when
+    /// the VM is configured with the device audit.
+    pub fn virtio_audits(&self) -> Vec<(String, DeviceAudit)> {
+        self.virtio_devices
+            .iter()
+            .filter_map(|(id, device)| Some((id.clone(), device.lock().unwrap().audit()?)))
+            .collect()
+    }
+
+    /// Event written when virtio-blk disk images run out of space.
+    pub fn out_of_space_evt(&self) -> &EventFd {
+        &self.out_of_space_evt
+    }
+
+    /// The IDs of the virtio-blk devices whose disk image ran out of space
+    /// since the last call, and which wait for a reset to retry.
+    pub fn take_out_of_space_disks(&self) -> Vec<String> {
+        self.out_of_space_disks
+            .iter()
+            .filter(|(_, out_of_space)| out_of_space.swap(false, Ordering::SeqCst))
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Resets the virtio device with the given ID, and activates it again
+    /// from the state of its queues, the guest staying unaware of it.
+    pub fn reset_virtio_device(&self, id: &str) -> DeviceManagerResult<()> {
+        let (_, transport) = self
+            .virtio_devices
+            .iter()
+            .find(|(device_id, _)| device_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownVirtioDevice(id.to_string()))?;
+
+        transport
+            .lock()
+            .unwrap()
+            .restart()
+            .map_err(DeviceManagerError::RestartVirtioDevice)
+    }
+
+    /// Pauses the worker threads of the virtio and e1000 devices, the
+    /// virtio-blk ones flushing their disk images. The devices which don't
+    /// pause in time are only warned about, their worker threads pausing
+    /// once done with the requests they were handling.
+    pub fn pause(&self) -> DeviceManagerResult<()> {
+        for (id, transport) in self.virtio_devices.iter() {
+            pause_device(id, &mut *transport.lock().unwrap())?;
+        }
+        #[cfg(feature = "e1000_support")]
+        for (id, e1000_device) in self.e1000_devices.iter() {
+            pause_device(id, &mut *e1000_device.lock().unwrap())?;
+        }
+
+        Ok(())
+    }
+
+    /// Resumes the worker threads of the devices.
+    pub fn resume(&self) -> DeviceManagerResult<()> {
+        for (_, transport) in self.virtio_devices.iter() {
+            transport
+                .lock()
+                .unwrap()
+                .resume()
+                .map_err(DeviceManagerError::ResumeDevice)?;
+        }
+        #[cfg(feature = "e1000_support")]
+        for (_, e1000_device) in self.e1000_devices.iter() {
+            e1000_device
+                .lock()
+                .unwrap()
+                .resume()
+                .map_err(DeviceManagerError::ResumeDevice)?;
+        }
+
+        Ok(())
+    }
+
+    /// Changes the weight of the virtio-blk disk with the given ID in its
+    /// disk group.
+    pub fn set_disk_weight(&self, id: &str, weight: u32) -> DeviceManagerResult<()> {
+        let (_, share) = self
+            .disk_bandwidth_shares
+            .iter()
+            .find(|(disk_id, _)| disk_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownGroupDisk(id.to_string()))?;
+        share.set_weight(weight);
+
+        Ok(())
+    }
+
+    /// Replaces the token buckets of the rate limiter of the virtio-blk or
+    /// virtio-net device with the given ID, a device without a rate limiter
+    /// not getting one. No configuration leaves the device unlimited.
+    pub fn set_rate_limiter(
+        &self,
+        id: &str,
+        rate_limiter_cfg: &Option<RateLimiterConfig>,
+    ) -> DeviceManagerResult<()> {
+        let (_, buckets) = self
+            .rate_limiter_buckets
+            .iter()
+            .find(|(device_id, _)| device_id == id)
+            .ok_or_else(|| DeviceManagerError::UnknownRateLimitedDevice(id.to_string()))?;
+        let (bw_size, bw_one_time_burst, bw_refill_time) = match rate_limiter_cfg
+            .as_ref()
+            .and_then(|cfg| cfg.bandwidth.as_ref())
+        {
+            Some(bw) => (bw.size, bw.one_time_burst, bw.refill_time),
+            None => (0, None, 0),
+        };
+        let (ops_size, ops_one_time_burst, ops_refill_time) =
+            match rate_limiter_cfg.as_ref().and_then(|cfg| cfg.ops.as_ref()) {
+                Some(ops) => (ops.size, ops.one_time_burst, ops.refill_time),
+                None => (0, None, 0),
+            };
+        buckets.set(
+            bw_size,
+            bw_one_time_burst,
+            bw_refill_time,
+            ops_size,
+            ops_one_time_burst,
+            ops_refill_time,
+        );
+
+        Ok(())
+    }
+
+    /// Whether the virtio-blk or virtio-net device with the given ID has a
+    /// rate limiter, whose token buckets can be replaced.
+    pub fn has_rate_limiter(&self, id: &str) -> bool {
+        self.rate_limiter_buckets
+            .iter()
+            .any(|(device_id, _)| device_id == id)
+    }
+
+    /// Switches the output of the serial port to the file, or to nowhere,
+    /// of the configuration, when the serial port writes to a file or to
+    /// nowhere.
+    pub fn set_serial_output(&self, serial_cfg: &ConsoleConfig) -> DeviceManagerResult<()> {
+        if let Some(output) = &self.console.serial_output {
+            let (writer, _, _) =
+                DeviceManager::create_serial_backend(&serial_cfg.mode, &serial_cfg.file)?;
+            output.set(writer);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn pci_hotplug_enabled(&self) -> bool {
+        self.pci_hotplug.is_some()
+    }
+
+    #[cfg(all(feature = "acpi", not(feature = "pci_support"), target_arch = "x86_64"))]
+    pub fn pci_hotplug_enabled(&self) -> bool {
+        false
+    }
+
+    /// Event written when the guest ejects devices of the PCI bus 0.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn pci_eject_evt(&self) -> Option<&EventFd> {
+        self.pci_hotplug
+            .as_ref()
+            .map(|pci_hotplug| &pci_hotplug.eject_evt)
+    }
+
+    /// Hot-adds a vsock device to the PCI bus 0, returning its PCI address.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn hotplug_vsock(
+        &mut self,
+        vm_cfg: &VmConfig,
+        vsock_cfg: &VsockConfig,
+    ) -> DeviceManagerResult<String> {
+        let pci_hotplug = self
+            .pci_hotplug
+            .as_ref()
+            .ok_or(DeviceManagerError::PciHotplugUnsupported)?;
+        if vsock_cfg.iommu {
+            return Err(DeviceManagerError::HotplugIommuUnsupported);
+        }
+        let mut pci = pci_hotplug.bus.lock().unwrap();
+        if !pci.has_free_device_id() {
+            return Err(DeviceManagerError::AddPciDevice(
+                pci::PciRootError::NoPciDeviceSlotAvailable,
+            ));
+        }
+
+        let (device, _, id) =
+            DeviceManager::make_virtio_vsock(vsock_cfg, vm_cfg.encrypted_memory())?;
+        let memory_listener = device.memory_listener(pci_hotplug.memory.clone());
+        if let Some(listener) = &memory_listener {
+            pci_hotplug
+                .memory_manager
+                .lock()
+                .unwrap()
+                .add_memory_listener(listener.clone())
+                .map_err(DeviceManagerError::RegisterMemoryListener)?;
+        }
+
+        let device_id = pci.next_device_id();
+        let interrupt_info = InterruptInfo {
+            _msi_capable: true,
+            ioapic: &self.ioapic,
+        };
+        DeviceManager::add_virtio_pci_device(
+            device,
+            id,
+            &pci_hotplug.memory,
+            &self.address_manager,
+            &self.address_manager.vm,
+            &mut pci,
+            &interrupt_info,
+            &None,
+            &mut self.virtio_devices,
+            &mut self.pci_devices,
+            false,
+        )?;
+        drop(pci);
+
+        let (id, transport) = self.virtio_devices.last().unwrap();
+        if let Some(diagnostics) = &vm_cfg.diagnostics {
+            if diagnostics.audit {
+                transport.lock().unwrap().enable_audit();
+            }
+        }
+        let id = id.clone();
+        self.pci_hotplug_added(id, device_id, memory_listener)
+    }
+
+    /// Hot-adds a vfio-user device to the PCI bus 0, returning its PCI
+    /// address.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn hotplug_user_device(
+        &mut self,
+        user_device_cfg: &UserDeviceConfig,
+    ) -> DeviceManagerResult<String> {
+        let pci_hotplug = self
+            .pci_hotplug
+            .as_ref()
+            .ok_or(DeviceManagerError::PciHotplugUnsupported)?;
+        if user_device_cfg.iommu {
+            return Err(DeviceManagerError::HotplugIommuUnsupported);
+        }
+        if user_device_cfg.pci_segment != 0 {
+            return Err(DeviceManagerError::InvalidPciSegment(
+                user_device_cfg.pci_segment,
+            ));
+        }
+        let mut pci = pci_hotplug.bus.lock().unwrap();
+        if !pci.has_free_device_id() {
+            return Err(DeviceManagerError::AddPciDevice(
+                pci::PciRootError::NoPciDeviceSlotAvailable,
+            ));
+        }
+
+        let vfio_user_device = Arc::new(
+            VfioUserDevice::new(&user_device_cfg.socket)
+                .map_err(DeviceManagerError::VfioUserCreate)?,
+        );
+        let memory_listener: Arc<dyn MemoryListener> = Arc::new(VfioUserDmaMapping::new(
+            vfio_user_device.clone(),
+            pci_hotplug.memory.clone(),
+        ));
+        pci_hotplug
+            .memory_manager
+            .lock()
+            .unwrap()
+            .add_memory_listener(memory_listener.clone())
+            .map_err(DeviceManagerError::RegisterMemoryListener)?;
+
+        let vfio_pci_device = VfioPciDevice::new(
+            &self.address_manager.vm,
+            &self.address_manager.msi_interrupt_manager,
+            vfio_user_device,
+            None,
+        )
+        .map_err(DeviceManagerError::VfioPciCreate)?;
+        let mut allocator = self.address_manager.allocator.lock().unwrap();
+        let devfn = DeviceManager::add_vfio_pci_device(
+            &pci_hotplug.memory_manager,
+            &self.address_manager,
+            &mut allocator,
+            &mut pci,
+            &mut [],
+            0,
+            vfio_pci_device,
+        )?;
+        drop(allocator);
+        drop(pci);
+
+        // The configuration IDs are assigned before the device is added.
+        let id = user_device_cfg.id.clone().unwrap_or_default();
+        self.pci_devices
+            .insert(id.clone(), DeviceManager::pci_bdf(0, devfn));
+        self.pci_hotplug_added(id, devfn >> 3, Some(memory_listener))
+    }
+
+    // Records a hot-added device as removable, and notifies the guest about
+    // it.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    fn pci_hotplug_added(
+        &mut self,
+        id: String,
+        device_id: u32,
+        memory_listener: Option<Arc<dyn MemoryListener>>,
+    ) -> DeviceManagerResult<String> {
+        self.removable_pci_devices.insert(
+            id.clone(),
+            RemovablePciDevice {
+                device_id,
+                memory_listener,
+            },
+        );
+        if let Some(pci_hotplug) = &self.pci_hotplug {
+            pci_hotplug.device.lock().unwrap().add_device(device_id);
+        }
+        self.notify_pci_hotplug()?;
+
+        Ok(self.pci_devices[&id].clone())
+    }
+
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    fn notify_pci_hotplug(&self) -> DeviceManagerResult<()> {
+        match &self.ged_notification_device {
+            Some((ged, _)) => ged
+                .lock()
+                .unwrap()
+                .notify(devices::GED_PCI_HOTPLUG)
+                .map_err(DeviceManagerError::PciHotplugNotify),
+            None => Err(DeviceManagerError::PciHotplugUnsupported),
+        }
+    }
+
+    /// Asks the guest to eject the device with the given ID, which stays
+    /// until the guest ejects it.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn unplug_pci_device(&self, id: &str) -> DeviceManagerResult<()> {
+        let pci_hotplug = self
+            .pci_hotplug
+            .as_ref()
+            .ok_or(DeviceManagerError::PciHotplugUnsupported)?;
+        let removable = self
+            .removable_pci_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownRemovableDevice(id.to_string()))?;
+
+        pci_hotplug
+            .device
+            .lock()
+            .unwrap()
+            .remove_device(removable.device_id);
+        self.notify_pci_hotplug()
+    }
+
+    /// Removes the devices the guest ejected since the last call from the
+    /// PCI bus 0, along with their resources, returning their IDs.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn eject_pci_devices(&mut self) -> DeviceManagerResult<Vec<String>> {
+        let ejected = match &self.pci_hotplug {
+            Some(pci_hotplug) => pci_hotplug.device.lock().unwrap().take_ejected(),
+            None => return Ok(Vec::new()),
+        };
+
+        let ids: Vec<String> = self
+            .removable_pci_devices
+            .iter()
+            .filter(|(_, removable)| ejected & (1 << removable.device_id) != 0)
+            .map(|(id, _)| id.clone())
+            .collect();
+        if ids.len() != ejected.count_ones() as usize {
+            warn!(
+                "The guest ejected devices which can't be removed: {:#x}",
+                ejected
+            );
+        }
+        for id in ids.iter() {
+            self.remove_pci_device(id)?;
+        }
+
+        Ok(ids)
+    }
+
+    // Takes a device off the PCI bus 0, and gives its BARs, memory slots,
+    // interrupts and ioeventfds back. Dropping the device stops its threads.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    fn remove_pci_device(&mut self, id: &str) -> DeviceManagerResult<()> {
+        let pci_hotplug = self.pci_hotplug.as_ref().unwrap();
+        let removable = self.removable_pci_devices.remove(id).unwrap();
+        let pci_device = pci_hotplug
+            .bus
+            .lock()
+            .unwrap()
+            .remove_device(removable.device_id)
+            .ok_or_else(|| DeviceManagerError::UnknownRemovableDevice(id.to_string()))?;
+        let mut pci_device = pci_device.lock().unwrap();
+
+        {
+            let any_device = pci_device.as_any();
+            if let Some(virtio_pci_device) = any_device.downcast_ref::<VirtioPciDevice>() {
+                let bar_addr = virtio_pci_device.config_bar_addr();
+                for (event, addr) in virtio_pci_device.ioeventfds(bar_addr) {
+                    self.address_manager
+                        .vm
+                        .unregister_ioevent(event, &IoEventAddress::Mmio(addr))
+                        .map_err(DeviceManagerError::UnregisterIoevent)?;
+                }
+                virtio_pci_device
+                    .free_interrupts()
+                    .map_err(DeviceManagerError::FreeInterrupts)?;
+            } else if let Some(vfio_pci_device) = any_device.downcast_mut::<VfioPciDevice>() {
+                let memory_manager = &pci_hotplug.memory_manager;
+                vfio_pci_device
+                    .unmap_mmio_regions(|slot| memory_manager.lock().unwrap().free_kvm_slot(slot))
+                    .map_err(DeviceManagerError::VfioUnmapRegion)?;
+                vfio_pci_device
+                    .free_interrupt_routes()
+                    .map_err(DeviceManagerError::VfioFreeInterrupts)?;
+            }
+        }
+
+        // Freeing the interrupts gives their GSIs back to the allocator,
+        // which is only locked past it.
+        let mut allocator = self.address_manager.allocator.lock().unwrap();
+
+        for (addr, size, region_type) in pci_device.free_bars(&mut allocator) {
+            let bus = if region_type == PciBarRegionType::IORegion {
+                &self.address_manager.io_bus
+            } else {
+                &self.address_manager.mmio_bus
+            };
+            bus.remove(addr.raw_value(), size)
+                .map_err(DeviceManagerError::BusError)?;
+        }
+
+        if let Some(listener) = &removable.memory_listener {
+            pci_hotplug
+                .memory_manager
+                .lock()
+                .unwrap()
+                .remove_memory_listener(listener);
+        }
+        self.virtio_devices.retain(|(device_id, _)| device_id != id);
+        self.out_of_space_disks.retain(|(disk_id, _)| disk_id != id);
+        self.pci_devices.remove(id);
+
+        Ok(())
+    }
 }
 
 impl Drop for DeviceManager {
@@ -0,0 +1,255 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Diagnostic bundles, written when the guest panics or triple faults for
+//! support engineers to get the state of the VM in a single file: the
+//! registers of the vCPUs, the last output of the guest consoles, and the
+//! indexes of the rings of the virtio queues, telling how far the drivers
+//! and the devices got.
+//!
+//! A bundle is a JSON object, in a file of its own in the directory of the
+//! diagnostics configuration, which the event reporting the panic or the
+//! triple fault gives the path of.
+//!
+//! The audits of the guest drivers of the virtio devices, when enabled, are
+//! part of the bundles, and are given by the API at any time.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use vm_virtio::transport::{AuditEvent, AuditRecord, DeviceAudit};
+
+/// Console output kept for the bundles, the serial port and the virtio
+/// console together.
+pub const CONSOLE_LOG_SIZE: usize = 64 << 10;
+
+/// The last output of the guest consoles, up to `CONSOLE_LOG_SIZE` bytes.
+#[derive(Default)]
+pub struct ConsoleLog {
+    bytes: Mutex<VecDeque<u8>>,
+}
+
+impl ConsoleLog {
+    fn append(&self, buf: &[u8]) {
+        let mut bytes = self.bytes.lock().unwrap();
+        let buf = &buf[buf.len().saturating_sub(CONSOLE_LOG_SIZE)..];
+        let overflow = (bytes.len() + buf.len()).saturating_sub(CONSOLE_LOG_SIZE);
+        bytes.drain(..overflow);
+        bytes.extend(buf.iter());
+    }
+
+    /// The output kept, oldest first.
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Output of a guest console, which the console log gets a copy of.
+pub struct ConsoleLogWriter<W: io::Write> {
+    log: Arc<ConsoleLog>,
+    writer: W,
+}
+
+impl<W: io::Write> ConsoleLogWriter<W> {
+    pub fn new(log: Arc<ConsoleLog>, writer: W) -> Self {
+        ConsoleLogWriter { log, writer }
+    }
+}
+
+impl<W: io::Write> io::Write for ConsoleLogWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.log.append(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[derive(Serialize)]
+pub struct VcpuState {
+    pub id: u8,
+    /// Registers of the vCPU, by name.
+    pub registers: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+pub struct QueueState {
+    /// Buffers the driver made available, wrapping at 2^16.
+    pub avail: u16,
+    /// Buffers the device used, wrapping at 2^16.
+    pub used: u16,
+}
+
+#[derive(Serialize)]
+pub struct AuditRecordState {
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// What the driver did, or what failed: "config-write",
+    /// "unoffered-features", "missing-version-1",
+    /// "driver-ok-without-features-ok", "driver-failed" or "activate-failed".
+    pub event: String,
+    /// Offset of the config space write.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+    /// Bytes of the config space write, in hexadecimal.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    /// The features acked which the device doesn't offer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<String>,
+    /// Why the device could not be activated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<&AuditRecord> for AuditRecordState {
+    fn from(record: &AuditRecord) -> Self {
+        let mut state = AuditRecordState {
+            timestamp: record.timestamp,
+            event: String::new(),
+            offset: None,
+            data: None,
+            features: None,
+            error: None,
+        };
+        let event = match &record.event {
+            AuditEvent::ConfigWrite { offset, data } => {
+                state.offset = Some(*offset);
+                state.data = Some(data.iter().map(|b| format!("{:02x}", b)).collect());
+                "config-write"
+            }
+            AuditEvent::UnofferedFeatures(features) => {
+                state.features = Some(features_hex(*features));
+                "unoffered-features"
+            }
+            AuditEvent::MissingVersion1 => "missing-version-1",
+            AuditEvent::DriverOkWithoutFeaturesOk => "driver-ok-without-features-ok",
+            AuditEvent::DriverFailed => "driver-failed",
+            AuditEvent::ActivateFailed(error) => {
+                state.error = Some(error.clone());
+                "activate-failed"
+            }
+        };
+        state.event = event.to_string();
+
+        state
+    }
+}
+
+// Feature bits as a hexadecimal string, JSON numbers not being exact past
+// 2^53.
+fn features_hex(features: u64) -> String {
+    format!("{:#018x}", features)
+}
+
+#[derive(Serialize)]
+pub struct DeviceAuditState {
+    /// Features the device offers, in hexadecimal.
+    pub device_features: String,
+    /// Features the driver acked, in hexadecimal.
+    pub driver_features: String,
+    /// Device status the driver last wrote.
+    pub driver_status: u32,
+    /// The last records, oldest first.
+    pub records: Vec<AuditRecordState>,
+    /// Records dropped for newer ones.
+    pub dropped: u64,
+}
+
+impl From<&DeviceAudit> for DeviceAuditState {
+    fn from(audit: &DeviceAudit) -> Self {
+        DeviceAuditState {
+            device_features: features_hex(audit.device_features),
+            driver_features: features_hex(audit.driver_features),
+            driver_status: audit.driver_status,
+            records: audit.records.iter().map(AuditRecordState::from).collect(),
+            dropped: audit.dropped,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeviceState {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pci_address: Option<String>,
+    /// The queues the driver set up, empty until it activated the device.
+    pub queues: Vec<QueueState>,
+    /// The audit of the guest driver, when enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit: Option<DeviceAuditState>,
+}
+
+/// The audit of the guest driver of a virtio device, as the API gives it.
+#[derive(Serialize)]
+pub struct DeviceAuditInfo {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pci_address: Option<String>,
+    #[serde(flatten)]
+    pub audit: DeviceAuditState,
+}
+
+#[derive(Serialize)]
+pub struct Bundle {
+    /// What the bundle was collected for, e.g. "guest-panic".
+    pub reason: String,
+    /// Milliseconds since the UNIX epoch.
+    pub timestamp: u64,
+    /// Empty for a confidential guest, whose registers are encrypted.
+    pub vcpus: Vec<VcpuState>,
+    pub devices: Vec<DeviceState>,
+    /// The last output of the guest consoles, invalid UTF-8 replaced.
+    pub console: String,
+}
+
+impl Bundle {
+    pub fn new(
+        reason: &str,
+        vcpus: Vec<VcpuState>,
+        devices: Vec<DeviceState>,
+        console: Option<&ConsoleLog>,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let console = console
+            .map(|log| String::from_utf8_lossy(&log.contents()).into_owned())
+            .unwrap_or_default();
+
+        Bundle {
+            reason: reason.to_string(),
+            timestamp,
+            vcpus,
+            devices,
+            console,
+        }
+    }
+}
+
+/// Writes the bundle to a file of its own in the directory, returning its
+/// path.
+pub fn write_bundle(directory: &Path, bundle: &Bundle) -> io::Result<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let path = directory.join(format!(
+        "cloud-hypervisor-{}-{}-{}.json",
+        std::process::id(),
+        bundle.reason,
+        bundle.timestamp
+    ));
+
+    let contents =
+        serde_json::to_vec_pretty(bundle).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    fs::write(&path, contents)?;
+
+    Ok(path)
+}
@@ -0,0 +1,168 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// What triggered a VM lifecycle event.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum EventSource {
+    /// The guest itself, e.g. through ACPI.
+    Guest,
+    /// A VMM API request.
+    Api,
+}
+
+/// VM lifecycle events.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum EventType {
+    Booted,
+    Paused,
+    Resumed,
+    Shutdown,
+    Reboot,
+    /// A vCPU failed to run the guest, and the VM got paused.
+    VcpuFailed,
+    /// The disk image of a virtio-blk device ran out of space, and the
+    /// device waits for a reset to retry.
+    DiskOutOfSpace,
+    /// The guest reported a panic through its pvpanic device.
+    GuestPanic,
+    /// A vCPU triple faulted, and the VM gets rebooted.
+    GuestTripleFault,
+    /// The guest clock drifted from the host clock past the threshold.
+    ClockDrift,
+    /// The guest ejected a hot-removable device, which got removed.
+    DeviceRemoved,
+    /// A VM of the pool was claimed, and goes by the ID of the claim from
+    /// now on.
+    Claimed,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Event {
+    /// Milliseconds since the UNIX epoch.
+    timestamp: u64,
+    source: EventSource,
+    event: EventType,
+    /// Diagnostics about the event, e.g. the state of a failed vCPU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+    /// ID the client tagged the API request leading to the event with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    /// ID of the VM, for the VMs other than the default one of the process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vm_id: Option<String>,
+}
+
+/// Opens the file to report the events to, given as `path=<event_file>`,
+/// `fd=<file_descriptor>`, for a file descriptor the VMM inherited, or as
+/// a bare path. A file given by path is truncated.
+pub fn open_event_file(event_monitor: &str) -> io::Result<File> {
+    if event_monitor.starts_with("fd=") {
+        let fd: RawFd = event_monitor[3..]
+            .parse()
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // Safe because we only check the file descriptor is open.
+        if fd < 0 || unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+            return Err(io::Error::from_raw_os_error(libc::EBADF));
+        }
+
+        // Safe because the file descriptor is open, and the event monitor
+        // is the only one to use it from now on.
+        return Ok(unsafe { File::from_raw_fd(fd) });
+    }
+
+    let path = if event_monitor.starts_with("path=") {
+        &event_monitor[5..]
+    } else {
+        event_monitor
+    };
+
+    File::create(path)
+}
+
+/// Reports VM lifecycle events as JSON objects, one per line, so that a
+/// management agent knows why a VM stopped even after the VMM exited. The
+/// events are also sent to an API frontend, for it to notify its clients of
+/// them.
+pub struct EventMonitor {
+    file: Option<File>,
+    sender: Option<Sender<String>>,
+    vm_id: Option<String>,
+}
+
+impl EventMonitor {
+    pub fn new(file: Option<File>, sender: Option<Sender<String>>) -> Self {
+        EventMonitor {
+            file,
+            sender,
+            vm_id: None,
+        }
+    }
+
+    /// An event monitor reporting the events of another VM of the process to
+    /// the same file and frontend.
+    pub fn for_vm(&self, vm_id: &str) -> io::Result<Self> {
+        let file = match &self.file {
+            Some(file) => Some(file.try_clone()?),
+            None => None,
+        };
+
+        Ok(EventMonitor {
+            file,
+            sender: self.sender.clone(),
+            vm_id: Some(vm_id.to_string()),
+        })
+    }
+
+    /// Reports the events from now on under `vm_id`, for a VM of the pool
+    /// once claimed.
+    pub fn set_vm_id(&mut self, vm_id: &str) {
+        self.vm_id = Some(vm_id.to_string());
+    }
+
+    pub fn report(
+        &mut self,
+        source: EventSource,
+        event: EventType,
+        details: Option<serde_json::Value>,
+        request_id: Option<String>,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        let event = Event {
+            timestamp,
+            source,
+            event,
+            details,
+            request_id,
+            vm_id: self.vm_id.clone(),
+        };
+
+        match serde_json::to_string(&event) {
+            Ok(line) => {
+                if let Some(file) = &mut self.file {
+                    // A single write, the VMs of the process sharing the file.
+                    if let Err(e) = file.write_all(format!("{}\n", line).as_bytes()) {
+                        warn!("Cannot report event: {}", e);
+                    }
+                }
+                if let Some(sender) = &self.sender {
+                    // The frontend is gone if its thread failed.
+                    let _ = sender.send(line);
+                }
+            }
+            Err(e) => warn!("Cannot serialize event: {}", e),
+        }
+    }
+}
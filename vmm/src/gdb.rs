@@ -0,0 +1,534 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! GDB remote serial protocol stub, for debugging the guest kernel with
+//! `target remote` on a UNIX socket, as with the QEMU one.
+//!
+//! The stub stops the whole guest when the debugger connects, interrupts
+//! it, or when a vCPU hits a breakpoint or stepped, and resumes it when the
+//! debugger continues or steps. The breakpoints are the hardware ones of
+//! the vCPUs, set through the hypervisor, and the memory is accessed at the
+//! guest virtual addresses the debugger gives, translated by the page
+//! tables of the selected vCPU.
+
+use crate::cpu::{self, CpuManager};
+use hypervisor::x86_64::{SpecialRegisters, StandardRegisters};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::result;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+// Packets the debugger may send, hex encoded memory writes included.
+const PACKET_SIZE: usize = 0x1000;
+
+// Largest memory read, whose reply is hex encoded.
+const MAX_MEMORY_READ: usize = PACKET_SIZE / 2;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+// The vCPUs have 4 debug address registers.
+const MAX_BREAKPOINTS: usize = 4;
+
+// Byte the debugger sends, outside of any packet, to interrupt the guest.
+const INTERRUPT: u8 = 0x03;
+
+// Stop signals of the stop replies.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot bind the GDB socket.
+    Bind(io::Error),
+    /// Cannot accept a debugger connection.
+    Accept(io::Error),
+    /// Cannot read from the debugger.
+    Read(io::Error),
+    /// Cannot write to the debugger.
+    Write(io::Error),
+    /// Cannot pause, resume or set the breakpoints of the vCPUs.
+    CpuManager(cpu::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    hex.chunks(2)
+        .map(|pair| Some(hex_value(pair[0])? << 4 | hex_value(pair[1])?))
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_u64(hex: &str) -> Option<u64> {
+    u64::from_str_radix(hex, 16).ok()
+}
+
+// The `<addr>,<length>` of the memory and breakpoint packets.
+fn parse_range(range: &str) -> Option<(u64, usize)> {
+    let mut fields = range.splitn(2, ',');
+    let address = parse_u64(fields.next()?)?;
+    let length = parse_u64(fields.next()?)? as usize;
+
+    Some((address, length))
+}
+
+// The registers of the `g` packet of i386:x86-64, the general purpose ones
+// and rip on 8 bytes, then eflags and the segment selectors on 4 bytes.
+fn encode_registers(regs: &StandardRegisters, sregs: &SpecialRegisters) -> String {
+    let mut bytes = Vec::new();
+    for value in [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ]
+    .iter()
+    {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.extend_from_slice(&(regs.rflags as u32).to_le_bytes());
+    for segment in [sregs.cs, sregs.ss, sregs.ds, sregs.es, sregs.fs, sregs.gs].iter() {
+        bytes.extend_from_slice(&u32::from(segment.selector).to_le_bytes());
+    }
+
+    encode_hex(&bytes)
+}
+
+// Sets the general purpose registers, rip and eflags from a `G` packet,
+// the segment selectors being left alone, as their descriptors would be.
+fn decode_registers(bytes: &[u8], regs: &mut StandardRegisters) -> Option<()> {
+    let mut values = bytes.chunks(8).map(|chunk| {
+        let mut value = [0u8; 8];
+        value[..chunk.len()].copy_from_slice(chunk);
+        u64::from_le_bytes(value)
+    });
+    for reg in [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+        &mut regs.rip,
+    ]
+    .iter_mut()
+    {
+        **reg = values.next()?;
+    }
+    if bytes.len() >= 17 * 8 + 4 {
+        let mut rflags = [0u8; 4];
+        rflags.copy_from_slice(&bytes[17 * 8..17 * 8 + 4]);
+        regs.rflags = u64::from(u32::from_le_bytes(rflags));
+    }
+
+    Some(())
+}
+
+pub struct GdbStub {
+    listener: UnixListener,
+    path: PathBuf,
+    client: Option<UnixStream>,
+    // Bytes received from the debugger, not making a whole packet yet.
+    input: Vec<u8>,
+    breakpoints: Vec<u64>,
+    // vCPU the registers and memory accesses are for.
+    vcpu: u8,
+    // Whether the guest is stopped for the debugger.
+    stopped: bool,
+}
+
+impl GdbStub {
+    pub fn new(path: &Path) -> Result<Self> {
+        std::fs::remove_file(path).unwrap_or_default();
+        let listener = UnixListener::bind(path).map_err(Error::Bind)?;
+        listener.set_nonblocking(true).map_err(Error::Bind)?;
+
+        Ok(GdbStub {
+            listener,
+            path: path.to_path_buf(),
+            client: None,
+            input: Vec::new(),
+            breakpoints: Vec::new(),
+            vcpu: 0,
+            stopped: false,
+        })
+    }
+
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+
+    /// Accepts a pending debugger connection, replacing the current one,
+    /// and stops the guest for it. Returns the file descriptor to poll for
+    /// the packets of the debugger.
+    pub fn accept(&mut self, cpus: &CpuManager) -> Result<RawFd> {
+        let (stream, _) = self.listener.accept().map_err(Error::Accept)?;
+        let fd = stream.as_raw_fd();
+        self.client = Some(stream);
+        self.input.clear();
+        self.stop(cpus)?;
+        info!("GDB connected");
+
+        Ok(fd)
+    }
+
+    /// Handles the packets the debugger sent. The debugger is dropped once
+    /// it closed its side of the connection, the guest running on without
+    /// any breakpoint.
+    pub fn handle_input(&mut self, cpus: &CpuManager, memory: &GuestMemoryMmap) -> Result<()> {
+        let mut buf = [0u8; PACKET_SIZE];
+        let count = match self.client.as_mut() {
+            Some(client) => client.read(&mut buf).map_err(Error::Read)?,
+            None => return Ok(()),
+        };
+        if count == 0 {
+            info!("GDB disconnected");
+            return self.detach(cpus);
+        }
+        self.input.extend_from_slice(&buf[..count]);
+
+        while let Some(packet) = self.next_packet()? {
+            if let Some(reply) = self.handle_packet(&packet, cpus, memory)? {
+                self.send_packet(&reply)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops the guest after vCPUs hit a breakpoint or stepped, telling the
+    /// debugger about the first of them.
+    pub fn handle_debug_stop(&mut self, cpus: &CpuManager) -> Result<()> {
+        // Consume the event.
+        cpus.debug_evt().read().map_err(Error::Read)?;
+        let stops = cpus.take_debug_stops();
+        let vcpu = match stops.first() {
+            Some(vcpu) => *vcpu,
+            None => return Ok(()),
+        };
+
+        // The vCPU stopping paused the other ones, which still have to be
+        // kicked out of the guest.
+        cpus.pause().map_err(Error::CpuManager)?;
+        // Only the vCPU that stepped was single-stepping.
+        cpus.set_guest_debug(&self.breakpoints, None)
+            .map_err(Error::CpuManager)?;
+        if self.client.is_none() {
+            self.stopped = false;
+            return cpus.resume().map_err(Error::CpuManager);
+        }
+
+        self.stopped = true;
+        self.vcpu = vcpu;
+        self.send_packet(&format!(
+            "T{:02x}thread:{:02x};",
+            SIGTRAP,
+            u32::from(vcpu) + 1
+        ))
+    }
+
+    fn stop(&mut self, cpus: &CpuManager) -> Result<()> {
+        if !self.stopped {
+            cpus.pause().map_err(Error::CpuManager)?;
+            self.stopped = true;
+        }
+
+        Ok(())
+    }
+
+    fn resume(&mut self, cpus: &CpuManager, single_step: Option<u8>) -> Result<()> {
+        cpus.set_guest_debug(&self.breakpoints, single_step)
+            .map_err(Error::CpuManager)?;
+        self.stopped = false;
+        cpus.resume().map_err(Error::CpuManager)
+    }
+
+    fn detach(&mut self, cpus: &CpuManager) -> Result<()> {
+        self.client = None;
+        self.input.clear();
+        self.breakpoints.clear();
+        // The vCPUs can only be changed while they are paused.
+        self.stop(cpus)?;
+        self.resume(cpus, None)
+    }
+
+    // Takes the next whole packet out of the input, acknowledging it, an
+    // interrupt request making a packet of its own.
+    fn next_packet(&mut self) -> Result<Option<String>> {
+        loop {
+            match self.input.first() {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(&INTERRUPT) => {
+                    self.input.remove(0);
+                    return Ok(Some((INTERRUPT as char).to_string()));
+                }
+                // Acknowledgments, and the bytes in between packets.
+                Some(_) => {
+                    self.input.remove(0);
+                    continue;
+                }
+            }
+
+            // The checksum follows the end of packet character.
+            let end = match self.input.iter().position(|&b| b == b'#') {
+                Some(end) if self.input.len() >= end + 3 => end,
+                _ if self.input.len() > PACKET_SIZE => {
+                    self.input.clear();
+                    return Ok(None);
+                }
+                _ => return Ok(None),
+            };
+            let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+            let data = &packet[1..end];
+            let checksum = decode_hex(&packet[end + 1..]).and_then(|c| c.first().cloned());
+            let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if checksum != Some(sum) {
+                self.write(b"-")?;
+                continue;
+            }
+            self.write(b"+")?;
+
+            return Ok(Some(String::from_utf8_lossy(data).to_string()));
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        match self.client.as_mut() {
+            Some(client) => client.write_all(bytes).map_err(Error::Write),
+            None => Ok(()),
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<()> {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.write(format!("${}#{:02x}", data, sum).as_bytes())
+    }
+
+    // Handles a packet, returning the reply to send right away, if any. An
+    // empty reply tells the debugger the packet isn't supported.
+    fn handle_packet(
+        &mut self,
+        packet: &str,
+        cpus: &CpuManager,
+        memory: &GuestMemoryMmap,
+    ) -> Result<Option<String>> {
+        let vcpu_count = cpus.vcpus().len();
+        if packet.is_empty() {
+            return Ok(Some(String::new()));
+        }
+        let (command, args) = packet.split_at(1);
+
+        if command.as_bytes()[0] == INTERRUPT {
+            if self.stopped {
+                return Ok(None);
+            }
+            self.stop(cpus)?;
+            return Ok(Some(format!(
+                "T{:02x}thread:{:02x};",
+                SIGINT,
+                u32::from(self.vcpu) + 1
+            )));
+        }
+
+        // All the other packets are only handled on a stopped guest, the
+        // debugger only sending them once it got a stop reply.
+        if !self.stopped && command != "?" {
+            return Ok(Some("E01".to_string()));
+        }
+
+        let reply = match command {
+            "?" => format!("T{:02x}thread:{:02x};", SIGTRAP, u32::from(self.vcpu) + 1),
+            "q" => {
+                if args.starts_with("Supported") {
+                    format!("PacketSize={:x};hwbreak+", PACKET_SIZE)
+                } else if args.starts_with("Attached") {
+                    "1".to_string()
+                } else if args == "C" {
+                    format!("QC{:02x}", u32::from(self.vcpu) + 1)
+                } else if args == "fThreadInfo" {
+                    let threads: Vec<String> =
+                        (1..=vcpu_count).map(|id| format!("{:02x}", id)).collect();
+                    format!("m{}", threads.join(","))
+                } else if args == "sThreadInfo" {
+                    "l".to_string()
+                } else {
+                    String::new()
+                }
+            }
+            // The thread IDs are the vCPU IDs plus one, 0 and -1 meaning
+            // any thread.
+            "H" if args.len() > 1 => match &args[1..] {
+                "0" | "-1" => "OK".to_string(),
+                id => match parse_u64(id) {
+                    Some(id) if id >= 1 && id <= vcpu_count as u64 => {
+                        self.vcpu = (id - 1) as u8;
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                },
+            },
+            "T" => match parse_u64(args) {
+                Some(id) if id >= 1 && id <= vcpu_count as u64 => "OK".to_string(),
+                _ => "E01".to_string(),
+            },
+            "g" => {
+                let vcpu = &cpus.vcpus()[self.vcpu as usize];
+                match (vcpu.get_regs(), vcpu.get_sregs()) {
+                    (Ok(regs), Ok(sregs)) => encode_registers(&regs, &sregs),
+                    _ => "E01".to_string(),
+                }
+            }
+            "G" => {
+                let vcpu = &cpus.vcpus()[self.vcpu as usize];
+                let mut regs = match vcpu.get_regs() {
+                    Ok(regs) => regs,
+                    Err(_) => return Ok(Some("E01".to_string())),
+                };
+                match decode_hex(args.as_bytes())
+                    .and_then(|bytes| decode_registers(&bytes, &mut regs))
+                {
+                    Some(()) if vcpu.set_regs(&regs).is_ok() => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_range(args) {
+                Some((address, length)) => {
+                    let mut bytes = vec![0u8; length.min(MAX_MEMORY_READ)];
+                    match self.access_memory(cpus, memory, address, &mut bytes, false) {
+                        Ok(()) => encode_hex(&bytes),
+                        Err(()) => "E14".to_string(),
+                    }
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut fields = args.splitn(2, ':');
+                let range = fields.next().and_then(parse_range);
+                let data = fields.next().and_then(|data| decode_hex(data.as_bytes()));
+                match (range, data) {
+                    (Some((address, length)), Some(mut bytes)) if bytes.len() == length => {
+                        match self.access_memory(cpus, memory, address, &mut bytes, true) {
+                            Ok(()) => "OK".to_string(),
+                            Err(()) => "E14".to_string(),
+                        }
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            // Software breakpoints are set as hardware ones, rather than
+            // writing to the guest memory.
+            "Z" | "z" if args.starts_with('0') || args.starts_with('1') => {
+                let address = args
+                    .get(2..)
+                    .and_then(|range| range.split(',').next())
+                    .and_then(parse_u64);
+                match address {
+                    Some(address) if command == "Z" => {
+                        if self.breakpoints.contains(&address) {
+                            "OK".to_string()
+                        } else if self.breakpoints.len() < MAX_BREAKPOINTS {
+                            self.breakpoints.push(address);
+                            "OK".to_string()
+                        } else {
+                            "E22".to_string()
+                        }
+                    }
+                    Some(address) => {
+                        self.breakpoints.retain(|&a| a != address);
+                        "OK".to_string()
+                    }
+                    None => "E01".to_string(),
+                }
+            }
+            // The stop reply is sent once the guest stopped again.
+            "c" => {
+                self.resume(cpus, None)?;
+                return Ok(None);
+            }
+            "s" => {
+                let vcpu = self.vcpu;
+                self.resume(cpus, Some(vcpu))?;
+                return Ok(None);
+            }
+            "D" => {
+                self.send_packet("OK")?;
+                self.detach(cpus)?;
+                return Ok(None);
+            }
+            // The guest isn't killed, only left running.
+            "k" => {
+                self.detach(cpus)?;
+                return Ok(None);
+            }
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    // Reads or writes the guest memory at a virtual address, one page at a
+    // time, as contiguous virtual pages aren't contiguous guest physical
+    // ones.
+    fn access_memory(
+        &self,
+        cpus: &CpuManager,
+        memory: &GuestMemoryMmap,
+        address: u64,
+        bytes: &mut [u8],
+        write: bool,
+    ) -> result::Result<(), ()> {
+        let vcpu = &cpus.vcpus()[self.vcpu as usize];
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let gva = address.wrapping_add(offset as u64);
+            let chunk = ((PAGE_SIZE - gva % PAGE_SIZE) as usize).min(bytes.len() - offset);
+            let gpa = match vcpu.translate_gva(gva) {
+                Ok(Some(gpa)) => GuestAddress(gpa),
+                _ => return Err(()),
+            };
+            let slice = &mut bytes[offset..offset + chunk];
+            if write {
+                memory.write_slice(slice, gpa).map_err(|_| ())?;
+            } else {
+                memory.read_slice(slice, gpa).map_err(|_| ())?;
+            }
+            offset += chunk;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for GdbStub {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).unwrap_or_default();
+    }
+}
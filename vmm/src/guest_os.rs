@@ -0,0 +1,323 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Probing of the OS the guest booted, for inventory systems to get it from
+//! the VM information rather than guessing it from the name of the image.
+//!
+//! The boot messages of the guest consoles give the version of a Linux
+//! kernel, `Linux version <release>`, and the name of the distribution
+//! systemd greets with, `Welcome to <name>!`. A guest agent gives the OS
+//! more precisely, by writing the `os-release` file of the guest to the
+//! vsock port of the configuration, which the vsock device forwards to the
+//! UNIX socket `<sock>_<port>` the VMM listens on. The report of the agent
+//! takes precedence over the boot messages.
+//!
+//! Both come from the guest, which can tell anything about itself: the OS
+//! is reported as is, for information only.
+
+use crate::config::{GuestOsConfig, VsockConfig};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Longest report of the agent, an os-release file being well under it.
+const MAX_REPORT_LEN: u64 = 4096;
+
+// The agent doesn't hold the port for longer while sending its report.
+const REPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Longest console line looked at, the boot messages being well under it.
+const MAX_LINE_LEN: usize = 256;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot bind the UNIX socket the guest agent reports on.
+    Bind(PathBuf, io::Error),
+    /// Cannot spawn the thread the reports of the agent are read from.
+    ThreadSpawn(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// Where the guest OS was probed from.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum GuestOsSource {
+    /// The boot messages of the guest consoles.
+    Console,
+    /// The report of a guest agent.
+    Agent,
+}
+
+/// The OS of the guest, as far as it was probed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GuestOsInfo {
+    /// e.g. "linux".
+    pub family: String,
+    /// e.g. "Ubuntu 20.04.1 LTS".
+    #[serde(default)]
+    pub name: Option<String>,
+    /// e.g. "20.04", only reported by the agent.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Release of the kernel, e.g. "5.4.0-42-generic".
+    #[serde(default)]
+    pub kernel: Option<String>,
+    pub source: GuestOsSource,
+}
+
+/// The guest OS probed so far, shared by the guest consoles and the agent.
+#[derive(Default)]
+pub struct GuestOsProbe {
+    info: Mutex<Option<GuestOsInfo>>,
+}
+
+impl GuestOsProbe {
+    pub fn info(&self) -> Option<GuestOsInfo> {
+        self.info.lock().unwrap().clone()
+    }
+
+    // Whether the boot messages can't tell more than what is known already.
+    fn probed(&self) -> bool {
+        match &*self.info.lock().unwrap() {
+            Some(info) => {
+                info.kernel.is_some()
+                    && (info.source == GuestOsSource::Agent || info.name.is_some())
+            }
+            None => false,
+        }
+    }
+
+    fn console_line(&self, line: &str) {
+        let line = strip_escape_sequences(line);
+        let mut info = self.info.lock().unwrap();
+
+        if let Some(start) = line.find("Linux version ") {
+            let kernel = line[start + 14..].split_whitespace().next();
+            if let Some(kernel) = kernel {
+                let info = info.get_or_insert_with(console_info);
+                if info.kernel.is_none() {
+                    info!("Guest kernel is Linux {}", kernel);
+                    info.kernel = Some(kernel.to_string());
+                }
+            }
+        } else if let Some(start) = line.find("Welcome to ") {
+            let name = line[start + 11..].trim_end();
+            if name.ends_with('!') && name.len() > 1 {
+                let name = &name[..name.len() - 1];
+                let info = info.get_or_insert_with(console_info);
+                if info.source == GuestOsSource::Console && info.name.is_none() {
+                    info!("Guest OS is {}", name);
+                    info.name = Some(name.to_string());
+                }
+            }
+        }
+    }
+
+    fn agent_report(&self, report: &str) -> bool {
+        let fields = parse_os_release(report);
+        let name = fields
+            .get("PRETTY_NAME")
+            .or_else(|| fields.get("NAME"))
+            .or_else(|| fields.get("ID"));
+        let name = match name {
+            Some(name) => name.clone(),
+            None => return false,
+        };
+        info!("Guest agent reported the guest OS {}", name);
+
+        let mut info = self.info.lock().unwrap();
+        // The kernel the boot messages gave is kept, the agent not giving it.
+        let kernel = info.take().and_then(|info| info.kernel);
+        *info = Some(GuestOsInfo {
+            family: fields
+                .get("FAMILY")
+                .cloned()
+                .unwrap_or_else(|| "linux".to_string()),
+            name: Some(name),
+            version: fields.get("VERSION_ID").cloned(),
+            kernel,
+            source: GuestOsSource::Agent,
+        });
+
+        true
+    }
+}
+
+// The boot messages looked at are the ones of Linux guests.
+fn console_info() -> GuestOsInfo {
+    GuestOsInfo {
+        family: "linux".to_string(),
+        name: None,
+        version: None,
+        kernel: None,
+        source: GuestOsSource::Console,
+    }
+}
+
+// Drops the ANSI escape sequences systemd colors its messages with.
+fn strip_escape_sequences(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Up to the final byte of the sequence, a letter.
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+// The KEY=value lines of an os-release file, the values unquoted.
+fn parse_os_release(report: &str) -> BTreeMap<String, String> {
+    let mut fields = BTreeMap::new();
+    for line in report.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(equal) = line.find('=') {
+            let value = line[equal + 1..].trim_matches(|c| c == '"' || c == '\'');
+            if !value.is_empty() {
+                fields.insert(line[..equal].to_string(), value.to_string());
+            }
+        }
+    }
+
+    fields
+}
+
+/// Output of a guest console, whose boot messages the guest OS is probed
+/// from.
+pub struct ConsoleProbeWriter<W: io::Write> {
+    probe: Arc<GuestOsProbe>,
+    line: Vec<u8>,
+    writer: W,
+}
+
+impl<W: io::Write> ConsoleProbeWriter<W> {
+    pub fn new(probe: Arc<GuestOsProbe>, writer: W) -> Self {
+        ConsoleProbeWriter {
+            probe,
+            line: Vec::new(),
+            writer,
+        }
+    }
+}
+
+impl<W: io::Write> io::Write for ConsoleProbeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        if self.probe.probed() {
+            return Ok(len);
+        }
+
+        for &byte in buf[..len].iter() {
+            if byte == b'\n' {
+                self.probe
+                    .console_line(&String::from_utf8_lossy(&self.line));
+                self.line.clear();
+            } else if self.line.len() < MAX_LINE_LEN {
+                self.line.push(byte);
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The guest agent reports, read from a thread of their own as long as this
+/// is kept.
+pub struct GuestOsAgent {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+}
+
+impl GuestOsAgent {
+    fn new(path: PathBuf, probe: Arc<GuestOsProbe>) -> Result<Self> {
+        std::fs::remove_file(&path).unwrap_or_default();
+        let listener = UnixListener::bind(&path).map_err(|e| Error::Bind(path.clone(), e))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+
+        thread::Builder::new()
+            .name("guest-os".to_string())
+            .spawn(move || {
+                for socket in listener.incoming() {
+                    if thread_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match socket {
+                        Ok(socket) => {
+                            if let Err(e) = handle_report(socket, &probe) {
+                                warn!("Guest OS report error: {}", e);
+                            }
+                        }
+                        Err(e) => error!("Guest OS socket error on accept: {}", e),
+                    }
+                }
+            })
+            .map_err(Error::ThreadSpawn)?;
+
+        Ok(GuestOsAgent { path, stop })
+    }
+}
+
+impl Drop for GuestOsAgent {
+    fn drop(&mut self) {
+        // Connecting wakes the thread up, for it to see it is to stop.
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = UnixStream::connect(&self.path);
+        std::fs::remove_file(&self.path).unwrap_or_default();
+    }
+}
+
+/// Listens for the reports of the guest agent on the port of the
+/// configuration, through the first vsock device. Without a vsock device,
+/// the guest OS is only probed from the boot messages.
+pub fn start_guest_os_agent(
+    config: &GuestOsConfig,
+    vsock: Option<&VsockConfig>,
+    probe: Arc<GuestOsProbe>,
+) -> Result<Option<GuestOsAgent>> {
+    match vsock {
+        Some(vsock) => {
+            let path = PathBuf::from(format!("{}_{}", vsock.sock.display(), config.port));
+            GuestOsAgent::new(path, probe).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+// Reads the os-release file the agent writes, until it closes its side of
+// the connection, and answers whether it was understood.
+fn handle_report(mut socket: UnixStream, probe: &GuestOsProbe) -> io::Result<()> {
+    socket.set_read_timeout(Some(REPORT_TIMEOUT))?;
+
+    let mut report = Vec::new();
+    (&socket).take(MAX_REPORT_LEN).read_to_end(&mut report)?;
+    let reply = if probe.agent_report(&String::from_utf8_lossy(&report)) {
+        "OK\n"
+    } else {
+        "ERROR: invalid report\n"
+    };
+
+    socket.write_all(reply.as_bytes())
+}
@@ -0,0 +1,171 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host executables of the VM configuration the guest has the VMM run, by
+//! requesting them over vsock, for the guest workflows to hand over to the
+//! host automation, e.g. to have a certificate renewed.
+//!
+//! The vsock device forwards the connections the guest opens to the port
+//! `<port>` of the host to the UNIX socket `<sock>_<port>`, which the VMM
+//! listens on for each of the ports of the hooks. The guest writes the name
+//! of a hook, ended by a newline, and the VMM answers once the command of
+//! the hook exited, with `OK <exit_code>`, or with `ERROR: <reason>`, on a
+//! line of its own.
+//!
+//! The guest only picks which of the allow-listed commands is run: the
+//! command gets neither arguments nor input from it, only the name of the
+//! hook and the CID of the guest in its environment. The requests of a port
+//! are handled one at a time.
+
+use crate::config::{HookConfig, VsockConfig};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Longest request, newline included.
+const MAX_REQUEST_LEN: u64 = 64;
+
+// The guest doesn't hold the port for longer while sending a name.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The hooks need a vsock device for the guest to request them through.
+    NoVsock,
+    /// Cannot bind the UNIX socket the guest requests the hooks on.
+    Bind(PathBuf, io::Error),
+    /// Cannot spawn the thread the hooks are run from.
+    ThreadSpawn(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// The hooks requested on one vsock port, run from a thread of their own as
+/// long as this is kept.
+pub struct HostHooks {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+}
+
+impl HostHooks {
+    fn new(path: PathBuf, cid: u64, hooks: Vec<HookConfig>) -> Result<Self> {
+        std::fs::remove_file(&path).unwrap_or_default();
+        let listener = UnixListener::bind(&path).map_err(|e| Error::Bind(path.clone(), e))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+
+        thread::Builder::new()
+            .name("host-hooks".to_string())
+            .spawn(move || {
+                for socket in listener.incoming() {
+                    if thread_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match socket {
+                        Ok(socket) => {
+                            if let Err(e) = handle_request(socket, cid, &hooks) {
+                                warn!("Host hook request error: {}", e);
+                            }
+                        }
+                        Err(e) => error!("Host hooks socket error on accept: {}", e),
+                    }
+                }
+            })
+            .map_err(Error::ThreadSpawn)?;
+
+        Ok(HostHooks { path, stop })
+    }
+}
+
+impl Drop for HostHooks {
+    fn drop(&mut self) {
+        // Connecting wakes the thread up, for it to see it is to stop.
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = UnixStream::connect(&self.path);
+        std::fs::remove_file(&self.path).unwrap_or_default();
+    }
+}
+
+/// Listens for the requests of the guest on the ports of the hooks, through
+/// the vsock device.
+pub fn start_host_hooks(
+    hooks: &[HookConfig],
+    vsock: Option<&VsockConfig>,
+) -> Result<Vec<HostHooks>> {
+    if hooks.is_empty() {
+        return Ok(Vec::new());
+    }
+    let vsock = vsock.ok_or(Error::NoVsock)?;
+
+    let mut ports: BTreeMap<u32, Vec<HookConfig>> = BTreeMap::new();
+    for hook in hooks.iter() {
+        ports.entry(hook.port).or_default().push(hook.clone());
+    }
+
+    let mut host_hooks = Vec::new();
+    for (port, hooks) in ports {
+        let path = PathBuf::from(format!("{}_{}", vsock.sock.display(), port));
+        host_hooks.push(HostHooks::new(path, vsock.cid, hooks)?);
+    }
+
+    Ok(host_hooks)
+}
+
+// Reads the name of the hook the guest requests, runs its command and
+// answers with its exit code.
+fn handle_request(mut socket: UnixStream, cid: u64, hooks: &[HookConfig]) -> io::Result<()> {
+    socket.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut line = String::new();
+    let reply = match BufReader::new((&socket).take(MAX_REQUEST_LEN)).read_line(&mut line) {
+        Ok(_) if line.ends_with('\n') => {
+            let name = line.trim();
+            match hooks.iter().find(|hook| hook.name == name) {
+                Some(hook) => run_hook(hook, cid),
+                None => format!("ERROR: unknown hook {:?}\n", name),
+            }
+        }
+        Ok(_) | Err(_) => "ERROR: invalid request\n".to_string(),
+    };
+
+    socket.write_all(reply.as_bytes())
+}
+
+fn run_hook(hook: &HookConfig, cid: u64) -> String {
+    info!("Guest requested the host hook {:?}", hook.name);
+
+    let status = Command::new(&hook.command)
+        .env("CH_HOOK_NAME", &hook.name)
+        .env("CH_GUEST_CID", cid.to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status();
+
+    match status {
+        Ok(status) => match status.code() {
+            Some(code) => {
+                info!("Host hook {:?} exited with {}", hook.name, code);
+                format!("OK {}\n", code)
+            }
+            None => {
+                warn!("Host hook {:?} killed by a signal", hook.name);
+                "ERROR: hook killed by a signal\n".to_string()
+            }
+        },
+        Err(e) => {
+            error!(
+                "Cannot run the host hook {:?} command {:?}: {}",
+                hook.name, hook.command, e
+            );
+            "ERROR: cannot run the hook\n".to_string()
+        }
+    }
+}
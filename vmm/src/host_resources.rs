@@ -0,0 +1,651 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Registry of the host resources reserved by the VMM processes of a host,
+//! so that they don't hand the same TAP interface, VFIO device or huge pages
+//! to several VMs.
+//!
+//! Each reservation is a file of the registry directory, locked by the
+//! process holding the resource for as long as it holds it. The lock goes
+//! away with the process, a reservation file nobody holds the lock of is
+//! stale, and is taken over by the next process asking for the resource.
+//! All the reservations and releases are made with the registry lock held.
+//!
+//! Each VM of a process also keeps a manifest of the registry locked, which
+//! lists its reservations along with the files and UNIX sockets it created.
+//! The manifest of a process that died is stale as well, and tells what it
+//! left behind for `cleanup_stale` to reclaim.
+
+use crate::config::{ConsoleOutputMode, HugepagesFallback, LatencyProfile, VmConfig};
+use net_util::{Tap, TapError};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::result;
+
+/// Default registry directory.
+pub const DEFAULT_HOST_RESOURCES_PATH: &str = "/run/cloud-hypervisor/resources";
+
+const REGISTRY_LOCK: &str = "lock";
+const MANIFEST_PREFIX: &str = "manifest-";
+const HUGEPAGES_SYSFS_PATH: &str = "/sys/kernel/mm/hugepages";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot create the registry directory.
+    CreateRegistry(io::Error),
+    /// Cannot open or lock a registry file.
+    Lock(io::Error),
+    /// Cannot read or write a reservation file.
+    Reservation(io::Error),
+    /// The resource is held by another VM, the PID of its process, followed
+    /// by the VM ID for a VM other than the default one of its process.
+    Busy(HostResource, String),
+    /// Cannot find out the default huge page size of the host.
+    DefaultHugepageSize(io::Error),
+    /// Cannot find out how many huge pages of a size the host has.
+    HostHugepages(u64, io::Error),
+    /// The host doesn't have enough huge pages of a size left: size,
+    /// requested count and count left.
+    NotEnoughHugepages(u64, u64, u64),
+    /// Cannot read or write a manifest.
+    Manifest(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// A host resource reserved by the VMM process.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum HostResource {
+    /// TAP network interface, by name.
+    Tap(String),
+    /// VFIO device, by PCI address or mediated device UUID.
+    Vfio(String),
+    /// Huge pages of a size, in bytes.
+    Hugepages { size: u64, count: u64 },
+    /// Host CPU a realtime vCPU has to itself.
+    Cpu(usize),
+}
+
+impl HostResource {
+    fn file_name(&self, owner: &str) -> String {
+        match self {
+            HostResource::Tap(name) => format!("tap-{}", name),
+            HostResource::Vfio(name) => format!("vfio-{}", name),
+            HostResource::Cpu(cpu) => format!("cpu-{}", cpu),
+            // Huge pages are shared, every holder has its own count.
+            HostResource::Hugepages { size, .. } => format!("hugepages-{}-{}", size, owner),
+        }
+    }
+}
+
+/// Something a dead VMM process left behind.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub enum Leftover {
+    /// Persistent TAP interface, by name.
+    Tap(String),
+    /// File, such as a temporary file backing the guest memory.
+    File(PathBuf),
+    /// UNIX socket the VMM listened on.
+    Socket(PathBuf),
+    /// Reservation file of the registry, by name.
+    Reservation(String),
+}
+
+// What a VM of a process holds and created, as written to its manifest.
+#[derive(Default, Deserialize, Serialize)]
+struct Manifest {
+    owner: String,
+    reserved: Vec<HostResource>,
+    created: Vec<Leftover>,
+}
+
+// Tries to take the lock of a reservation file, without waiting for it.
+fn try_lock(file: &File) -> io::Result<bool> {
+    // Safe because the file descriptor is valid, and we check the result.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        Ok(false)
+    } else {
+        Err(e)
+    }
+}
+
+// Takes the registry lock, released when the returned file is dropped.
+fn lock_registry(path: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path.join(REGISTRY_LOCK))
+        .map_err(Error::Lock)?;
+    // Safe because the file descriptor is valid, and we check the result.
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+        return Err(Error::Lock(io::Error::last_os_error()));
+    }
+
+    Ok(lock)
+}
+
+fn read_content(file: &mut File) -> io::Result<String> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut content)?;
+    Ok(content.trim().to_string())
+}
+
+fn write_content(file: &mut File, content: &str) -> io::Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(content.as_bytes())
+}
+
+/// Default huge page size of the host, from /proc/meminfo.
+pub fn default_hugepage_size() -> io::Result<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo")?;
+    meminfo
+        .lines()
+        .find(|line| line.starts_with("Hugepagesize:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb << 10)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no huge pages support"))
+}
+
+fn hugepages_counter(size: u64, counter: &str) -> io::Result<u64> {
+    let path = format!(
+        "{}/hugepages-{}kB/{}",
+        HUGEPAGES_SYSFS_PATH,
+        size >> 10,
+        counter
+    );
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+// Number of huge pages of a size the host has.
+fn host_hugepages(size: u64) -> io::Result<u64> {
+    hugepages_counter(size, "nr_hugepages")
+}
+
+/// Number of huge pages of a size the host can still hand out: its free
+/// ones no mapping has reserved yet, and the surplus ones it may still
+/// allocate on top of them.
+pub fn available_hugepages(size: u64) -> io::Result<u64> {
+    let free = hugepages_counter(size, "free_hugepages")?;
+    let reserved = hugepages_counter(size, "resv_hugepages")?;
+    let overcommit = hugepages_counter(size, "nr_overcommit_hugepages")?;
+    let surplus = hugepages_counter(size, "surplus_hugepages")?;
+
+    Ok(free.saturating_sub(reserved) + overcommit.saturating_sub(surplus))
+}
+
+/// Huge page sizes the host supports, in bytes, from the largest one.
+pub fn host_hugepage_sizes() -> Vec<u64> {
+    let mut sizes: Vec<u64> = fs::read_dir(HUGEPAGES_SYSFS_PATH)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    name.trim_start_matches("hugepages-")
+                        .trim_end_matches("kB")
+                        .parse::<u64>()
+                        .ok()
+                })
+                .map(|kb| kb << 10)
+                .collect()
+        })
+        .unwrap_or_default();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+
+    sizes
+}
+
+/// Lists the host resources a VM needs for itself: its named TAP interfaces,
+/// VFIO devices, huge pages and the host CPUs of its realtime vCPUs. The TAP
+/// interfaces the VMM creates get a name of their own, and aren't part of
+/// it.
+pub fn vm_host_resources(config: &VmConfig) -> Result<Vec<HostResource>> {
+    let mut resources = Vec::new();
+
+    if let Some(net) = &config.net {
+        for tap in net.iter().filter_map(|net| net.tap.as_ref()) {
+            resources.push(HostResource::Tap(tap.clone()));
+        }
+    }
+
+    if let Some(devices) = &config.devices {
+        for device in devices.iter() {
+            // The sysfs path of a device may go through symbolic links, its
+            // last component is its address.
+            let path = fs::canonicalize(&device.path).unwrap_or_else(|_| device.path.clone());
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.to_string_lossy().into_owned());
+            resources.push(HostResource::Vfio(name));
+        }
+    }
+
+    let mut hugepages: BTreeMap<u64, u64> = BTreeMap::new();
+    // Huge pages with a fallback are only a preference, the VM doesn't
+    // reserve them.
+    for zone in config
+        .memory
+        .zones()
+        .iter()
+        .filter(|zone| zone.hugepages && zone.hugepages_fallback == HugepagesFallback::None)
+    {
+        let size = match zone.hugepage_size {
+            Some(size) => size,
+            None => default_hugepage_size().map_err(Error::DefaultHugepageSize)?,
+        };
+        *hugepages.entry(size).or_insert(0) += (zone.size + size - 1) / size;
+    }
+    for (size, count) in hugepages {
+        resources.push(HostResource::Hugepages { size, count });
+    }
+
+    // The host CPUs the realtime vCPUs are pinned to by default aren't
+    // known before the VM is created.
+    if config.latency_profile == LatencyProfile::Realtime {
+        for affinity in config.cpus.affinity.iter().flatten() {
+            for &cpu in affinity.host_cpus.iter() {
+                if !resources.contains(&HostResource::Cpu(cpu)) {
+                    resources.push(HostResource::Cpu(cpu));
+                }
+            }
+        }
+    }
+
+    Ok(resources)
+}
+
+/// Lists the UNIX sockets a VM listens on: the ones backing its serial port,
+/// console, console ports and UARTs, its vsock devices, the ones of the first vsock device
+/// its hooks and guest OS probe listen on, and its GDB one.
+pub fn vm_sockets(config: &VmConfig) -> Vec<PathBuf> {
+    let mut sockets = Vec::new();
+
+    let consoles = [
+        (&config.serial.mode, &config.serial.file),
+        (&config.console.mode, &config.console.file),
+    ];
+    let uarts = config.uarts.iter().flatten().map(|u| (&u.mode, &u.file));
+    for (mode, file) in consoles.iter().cloned().chain(uarts) {
+        if let (ConsoleOutputMode::Socket, Some(path)) = (mode, file) {
+            sockets.push(path.clone());
+        }
+    }
+    sockets.extend(
+        config
+            .console_ports
+            .iter()
+            .flatten()
+            .map(|port| port.socket.clone()),
+    );
+
+    if let Some(vsock) = &config.vsock {
+        sockets.extend(vsock.iter().map(|vsock| vsock.sock.clone()));
+        if let Some(first) = vsock.first() {
+            let mut ports: Vec<u32> = config.hooks.iter().flatten().map(|h| h.port).collect();
+            ports.extend(config.guest_os.iter().map(|guest_os| guest_os.port));
+            ports.sort_unstable();
+            ports.dedup();
+            for port in ports {
+                sockets.push(PathBuf::from(format!("{}_{}", first.sock.display(), port)));
+            }
+        }
+    }
+
+    if let Some(gdb) = &config.gdb {
+        sockets.push(gdb.path.clone());
+    }
+
+    sockets
+}
+
+/// The host resources held by a VM of this process, and the registry they
+/// are reserved in.
+pub struct HostResources {
+    path: PathBuf,
+    // The PID, followed by the ID of the VM for the VMs other than the
+    // default one.
+    owner: String,
+    held: Vec<(HostResource, File)>,
+    // The manifest, locked for as long as this is kept.
+    manifest: File,
+    // What the process created, which outlives the VMs, e.g. its API socket.
+    process_leftovers: Vec<Leftover>,
+    vm_leftovers: Vec<Leftover>,
+}
+
+impl HostResources {
+    pub fn new(path: &Path, vm_id: Option<&str>) -> Result<Self> {
+        fs::create_dir_all(path).map_err(Error::CreateRegistry)?;
+        let owner = match vm_id {
+            Some(vm_id) => format!("{}-{}", std::process::id(), vm_id),
+            None => std::process::id().to_string(),
+        };
+
+        let _lock = lock_registry(path)?;
+        let mut manifest = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path.join(format!("{}{}", MANIFEST_PREFIX, owner)))
+            .map_err(Error::Manifest)?;
+        if !try_lock(&manifest).map_err(Error::Lock)? {
+            return Err(Error::Lock(io::Error::from(io::ErrorKind::WouldBlock)));
+        }
+        // A dead process had the same PID, what it left behind is reclaimed
+        // before its manifest is taken over.
+        let content = read_content(&mut manifest).map_err(Error::Manifest)?;
+        if !content.is_empty() {
+            for (owner, leftover) in reclaim_manifest(&content) {
+                info!("Reclaimed {:?} of the VMM process {}", leftover, owner);
+            }
+        }
+
+        let mut host_resources = HostResources {
+            path: path.to_path_buf(),
+            owner,
+            held: Vec::new(),
+            manifest,
+            process_leftovers: Vec::new(),
+            vm_leftovers: Vec::new(),
+        };
+        host_resources.write_manifest()?;
+
+        Ok(host_resources)
+    }
+
+    /// The resources this process holds.
+    pub fn held(&self) -> Vec<HostResource> {
+        self.held
+            .iter()
+            .map(|(resource, _)| resource.clone())
+            .collect()
+    }
+
+    fn write_manifest(&mut self) -> Result<()> {
+        let manifest = Manifest {
+            owner: self.owner.clone(),
+            reserved: self.held(),
+            created: self
+                .process_leftovers
+                .iter()
+                .chain(self.vm_leftovers.iter())
+                .cloned()
+                .collect(),
+        };
+        let content = serde_json::to_string(&manifest).map_err(|e| Error::Manifest(e.into()))?;
+
+        write_content(&mut self.manifest, &content).map_err(Error::Manifest)
+    }
+
+    /// Records what the process created for all its VMs, e.g. its API
+    /// socket, in the manifest.
+    pub fn set_process_leftovers(&mut self, leftovers: Vec<Leftover>) -> Result<()> {
+        self.process_leftovers = leftovers;
+        self.write_manifest()
+    }
+
+    /// Records what the VM created in the manifest, in place of what it
+    /// created before.
+    pub fn set_vm_leftovers(&mut self, leftovers: Vec<Leftover>) -> Result<()> {
+        self.vm_leftovers = leftovers;
+        self.write_manifest()
+    }
+
+    /// Reserves all the resources, or none of them if one of them is busy.
+    pub fn reserve(&mut self, resources: &[HostResource]) -> Result<()> {
+        let _lock = lock_registry(&self.path)?;
+
+        let mut reserved = Vec::new();
+        for resource in resources.iter() {
+            let result = match resource {
+                HostResource::Hugepages { size, count } => self.reserve_hugepages(*size, *count),
+                _ => self.reserve_exclusive(resource),
+            };
+            match result {
+                Ok(file) => reserved.push((resource.clone(), file)),
+                Err(e) => {
+                    for (resource, _) in reserved {
+                        let _ = fs::remove_file(self.path.join(resource.file_name(&self.owner)));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        self.held.append(&mut reserved);
+
+        self.write_manifest()
+    }
+
+    fn reserve_exclusive(&self, resource: &HostResource) -> Result<File> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(self.path.join(resource.file_name(&self.owner)))
+            .map_err(Error::Lock)?;
+        if !try_lock(&file).map_err(Error::Lock)? {
+            let owner = read_content(&mut file).map_err(Error::Reservation)?;
+            return Err(Error::Busy(resource.clone(), owner));
+        }
+        write_content(&mut file, &self.owner).map_err(Error::Reservation)?;
+
+        Ok(file)
+    }
+
+    // Huge pages are reserved as long as the host has enough of them for
+    // all the processes holding some.
+    fn reserve_hugepages(&self, size: u64, count: u64) -> Result<File> {
+        let available = host_hugepages(size).map_err(|e| Error::HostHugepages(size, e))?;
+        let prefix = format!("hugepages-{}-", size);
+
+        let mut reserved = 0;
+        for entry in fs::read_dir(&self.path).map_err(Error::Reservation)? {
+            let entry = entry.map_err(Error::Reservation)?;
+            if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                continue;
+            }
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(entry.path())
+                .map_err(Error::Lock)?;
+            // A reservation is stale if its lock can be taken.
+            if try_lock(&file).map_err(Error::Lock)? {
+                let _ = fs::remove_file(entry.path());
+                continue;
+            }
+            reserved += read_content(&mut file)
+                .map_err(Error::Reservation)?
+                .parse::<u64>()
+                .unwrap_or(0);
+        }
+
+        let left = available.saturating_sub(reserved);
+        if count > left {
+            return Err(Error::NotEnoughHugepages(size, count, left));
+        }
+
+        let resource = HostResource::Hugepages { size, count };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.join(resource.file_name(&self.owner)))
+            .map_err(Error::Lock)?;
+        try_lock(&file).map_err(Error::Lock)?;
+        write_content(&mut file, &count.to_string()).map_err(Error::Reservation)?;
+
+        Ok(file)
+    }
+
+    /// Releases all the resources this process holds.
+    pub fn release(&mut self) -> Result<()> {
+        let _lock = lock_registry(&self.path)?;
+
+        for (resource, _file) in self.held.drain(..) {
+            // The file is removed before its lock goes away, when dropped.
+            if let Err(e) = fs::remove_file(self.path.join(resource.file_name(&self.owner))) {
+                warn!("Cannot remove the reservation of {:?}: {}", resource, e);
+            }
+        }
+
+        self.write_manifest()
+    }
+}
+
+impl Drop for HostResources {
+    fn drop(&mut self) {
+        // The reservations are gone along with their files, the manifest
+        // only stays behind if the process dies.
+        let _lock = lock_registry(&self.path);
+        let path = self.path.join(format!("{}{}", MANIFEST_PREFIX, self.owner));
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Cannot remove the host resources manifest: {}", e);
+        }
+    }
+}
+
+// Removes a leftover, telling whether it was still there.
+fn reclaim(leftover: &Leftover) -> io::Result<bool> {
+    let result = match leftover {
+        Leftover::File(path) => fs::remove_file(path),
+        // The path may have been taken by something else since.
+        Leftover::Socket(path) => match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+            Ok(_) => return Ok(false),
+            Err(e) => Err(e),
+        },
+        Leftover::Tap(name) => {
+            if !Path::new(&format!("/sys/class/net/{}/tun_flags", name)).exists() {
+                return Ok(false);
+            }
+            // Attaching to the interface fails if it is open, and it goes
+            // away once no longer persistent.
+            match Tap::open_named(name) {
+                Ok(tap) => tap
+                    .set_persist(false)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e))),
+                Err(TapError::CreateTap(ref e)) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    return Ok(false)
+                }
+                Err(e) => Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", e))),
+            }
+        }
+        // The reservations are reclaimed from the registry, not from the
+        // manifests.
+        Leftover::Reservation(_) => return Ok(false),
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Reclaims what a stale manifest lists, its reservations aside, along with
+// the TAP interfaces it reserved.
+fn reclaim_manifest(content: &str) -> Vec<(String, Leftover)> {
+    let manifest: Manifest = match serde_json::from_str(content) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            warn!("Invalid host resources manifest: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let taps = manifest
+        .reserved
+        .iter()
+        .filter_map(|resource| match resource {
+            HostResource::Tap(name) => Some(Leftover::Tap(name.clone())),
+            _ => None,
+        });
+    let mut reclaimed = Vec::new();
+    for leftover in manifest.created.iter().cloned().chain(taps) {
+        match reclaim(&leftover) {
+            Ok(true) => reclaimed.push((manifest.owner.clone(), leftover)),
+            Ok(false) => (),
+            Err(e) => warn!("Cannot reclaim {:?}: {}", leftover, e),
+        }
+    }
+
+    reclaimed
+}
+
+/// Reclaims what the dead VMM processes left behind in the registry: the
+/// files and UNIX sockets their manifests list, the persistent TAP
+/// interfaces they reserved which nobody has open, and the reservations
+/// nobody holds. Returns what was reclaimed, along with its owner.
+pub fn cleanup_stale(path: &Path) -> Result<Vec<(String, Leftover)>> {
+    let mut reclaimed = Vec::new();
+    if !path.exists() {
+        return Ok(reclaimed);
+    }
+    let _lock = lock_registry(path)?;
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(path).map_err(Error::Reservation)? {
+        let name = entry.map_err(Error::Reservation)?.file_name();
+        names.push(name.to_string_lossy().into_owned());
+    }
+    // The manifests first, the reservations they list being stale as well.
+    let (manifests, reservations): (Vec<String>, Vec<String>) = names
+        .into_iter()
+        .filter(|name| name != REGISTRY_LOCK)
+        .partition(|name| name.starts_with(MANIFEST_PREFIX));
+
+    for name in manifests {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.join(&name))
+            .map_err(Error::Manifest)?;
+        if !try_lock(&file).map_err(Error::Lock)? {
+            continue;
+        }
+        let content = read_content(&mut file).map_err(Error::Manifest)?;
+        reclaimed.append(&mut reclaim_manifest(&content));
+        fs::remove_file(path.join(&name)).map_err(Error::Manifest)?;
+    }
+
+    for name in reservations {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.join(&name))
+            .map_err(Error::Lock)?;
+        if !try_lock(&file).map_err(Error::Lock)? {
+            continue;
+        }
+        // The huge pages reservations are named after their owner, the
+        // other ones hold it.
+        let owner = if name.starts_with("hugepages-") {
+            name.splitn(3, '-').nth(2).unwrap_or_default().to_string()
+        } else {
+            read_content(&mut file).map_err(Error::Reservation)?
+        };
+        fs::remove_file(path.join(&name)).map_err(Error::Reservation)?;
+        reclaimed.push((owner, Leftover::Reservation(name)));
+    }
+
+    Ok(reclaimed)
+}
@@ -0,0 +1,162 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! UNIX socket the keyboard and pointer events of the host are sent to, for
+//! the virtio-input devices of the VM to report them to the guest, e.g. from
+//! a VNC server.
+//!
+//! The clients write evdev events, 8 bytes each: their type and code, as
+//! 16-bit little-endian integers, and their value, as a 32-bit little-endian
+//! integer. The events are grouped into frames, ended by an EV_SYN event,
+//! and each frame is split between the devices reporting its events: the
+//! keys go to the keyboard, the buttons, relative and absolute axes to the
+//! tablet, whose positions range from 0 to 32767. Nothing is written back.
+
+use std::convert::TryInto;
+use std::io::{self, Read};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use vm_virtio::{InputEvent, InputEvents, InputKind, EV_SYN};
+
+// Events of a frame, at most. The frames past it are dropped.
+const MAX_FRAME_EVENTS: usize = 64;
+
+// How often the clients check whether the socket is still open.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const EVENT_SIZE: usize = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot bind the UNIX socket the events are sent to.
+    Bind(PathBuf, io::Error),
+    /// Cannot spawn the thread the clients are accepted from.
+    ThreadSpawn(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// The socket the input events are sent to, whose clients are served from
+/// threads of their own as long as this is kept.
+pub struct InputSocket {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+}
+
+impl InputSocket {
+    pub fn new(path: PathBuf, devices: Vec<(InputKind, Arc<InputEvents>)>) -> Result<Self> {
+        std::fs::remove_file(&path).unwrap_or_default();
+        let listener = UnixListener::bind(&path).map_err(|e| Error::Bind(path.clone(), e))?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let devices = Arc::new(devices);
+
+        thread::Builder::new()
+            .name("input-socket".to_string())
+            .spawn(move || {
+                for socket in listener.incoming() {
+                    if thread_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let socket = match socket {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            error!("Input socket error on accept: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let client_stop = thread_stop.clone();
+                    let client_devices = devices.clone();
+                    if let Err(e) = thread::Builder::new()
+                        .name("input-client".to_string())
+                        .spawn(move || {
+                            if let Err(e) = handle_client(socket, &client_stop, &client_devices) {
+                                warn!("Input socket client error: {}", e);
+                            }
+                        })
+                    {
+                        error!("Cannot spawn the input socket client thread: {}", e);
+                    }
+                }
+            })
+            .map_err(Error::ThreadSpawn)?;
+
+        Ok(InputSocket { path, stop })
+    }
+}
+
+impl Drop for InputSocket {
+    fn drop(&mut self) {
+        // Connecting wakes the thread up, for it to see it is to stop. The
+        // clients see it within CLIENT_TIMEOUT.
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = UnixStream::connect(&self.path);
+        std::fs::remove_file(&self.path).unwrap_or_default();
+    }
+}
+
+// Reads the events of the client until it disconnects, queuing each frame
+// for the devices reporting its events.
+fn handle_client(
+    mut socket: UnixStream,
+    stop: &AtomicBool,
+    devices: &[(InputKind, Arc<InputEvents>)],
+) -> io::Result<()> {
+    socket.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut frames: Vec<Vec<InputEvent>> = vec![Vec::new(); devices.len()];
+    let mut buf = [0u8; EVENT_SIZE];
+    let mut filled = 0;
+    while !stop.load(Ordering::SeqCst) {
+        match socket.read(&mut buf[filled..]) {
+            Ok(0) => return Ok(()),
+            Ok(count) => filled += count,
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+        if filled < EVENT_SIZE {
+            continue;
+        }
+        filled = 0;
+
+        let event = InputEvent {
+            type_: u16::from_le_bytes(buf[0..2].try_into().unwrap()),
+            code: u16::from_le_bytes(buf[2..4].try_into().unwrap()),
+            value: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+        };
+        for ((kind, events), frame) in devices.iter().zip(frames.iter_mut()) {
+            if !kind.reports(&event) {
+                continue;
+            }
+            if event.type_ != EV_SYN {
+                frame.push(event);
+                if frame.len() >= MAX_FRAME_EVENTS {
+                    warn!(
+                        "Dropping an input frame of more than {} events",
+                        MAX_FRAME_EVENTS
+                    );
+                    frame.clear();
+                }
+            } else if !frame.is_empty() {
+                frame.push(event);
+                events.queue_events(frame);
+                frame.clear();
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,297 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! MSI and MSI-X interrupts of the PCI devices, raised through irqfds which
+//! the hypervisor delivers to the guest on its own, for an interrupt to take
+//! no more than an EventFd write from the device threads.
+//!
+//! Each vector gets its GSI and irqfd, and a route in the GSI routing table
+//! of the VM while it isn't masked. The hypervisor takes the table whole, so
+//! the routes of all the devices are kept together, along with the routes of
+//! the pins of the in-kernel interrupt controllers, and the table is set
+//! anew whenever one of them changes.
+
+use hypervisor::{IrqRoutingEntry, KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use vm_allocator::SystemAllocator;
+use vm_device::{InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqSourceConfig};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+#[derive(Clone, Copy, PartialEq)]
+struct MsiRoute {
+    config: MsiIrqSourceConfig,
+    devid: Option<u32>,
+}
+
+struct GsiRoutes {
+    vm: Arc<dyn hypervisor::Vm>,
+    // Routes of the pins of the in-kernel interrupt controllers, the legacy
+    // interrupts being raised through.
+    irqchip: Vec<IrqRoutingEntry>,
+    // Routes of the vectors which aren't masked, by GSI.
+    msi: Mutex<BTreeMap<u32, MsiRoute>>,
+}
+
+impl GsiRoutes {
+    // Routes the GSI to the message, or stops routing it, setting the table
+    // anew when the route changed.
+    fn set(&self, gsi: u32, route: Option<MsiRoute>) -> io::Result<()> {
+        let mut msi = self.msi.lock().unwrap();
+        let changed = match route {
+            Some(route) => msi.insert(gsi, route) != Some(route),
+            None => msi.remove(&gsi).is_some(),
+        };
+        if !changed {
+            return Ok(());
+        }
+
+        let mut entries = self.irqchip.clone();
+        for (gsi, route) in msi.iter() {
+            let mut entry = IrqRoutingEntry {
+                gsi: *gsi,
+                type_: KVM_IRQ_ROUTING_MSI,
+                ..Default::default()
+            };
+            entry.u.msi.address_lo = route.config.low_addr;
+            entry.u.msi.address_hi = route.config.high_addr;
+            entry.u.msi.data = route.config.data;
+            set_devid(&mut entry, route.devid);
+            entries.push(entry);
+        }
+
+        self.vm.set_gsi_routing(&entries)
+    }
+}
+
+// The ITS tells the devices apart by their requester ID.
+#[cfg(target_arch = "aarch64")]
+fn set_devid(entry: &mut IrqRoutingEntry, devid: Option<u32>) {
+    if let Some(devid) = devid {
+        entry.flags = hypervisor::aarch64::KVM_MSI_VALID_DEVID;
+        entry.u.msi.__bindgen_anon_1.devid = devid;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn set_devid(_entry: &mut IrqRoutingEntry, _devid: Option<u32>) {}
+
+fn irqchip_routing_entry(gsi: u32, irqchip: u32, pin: u32) -> IrqRoutingEntry {
+    let mut entry = IrqRoutingEntry {
+        gsi,
+        type_: KVM_IRQ_ROUTING_IRQCHIP,
+        ..Default::default()
+    };
+    entry.u.irqchip.irqchip = irqchip;
+    entry.u.irqchip.pin = pin;
+
+    entry
+}
+
+// The routes the hypervisor sets by default for the pins of the in-kernel
+// PICs and IOAPIC.
+#[cfg(target_arch = "x86_64")]
+fn irqchip_routes() -> Vec<IrqRoutingEntry> {
+    use hypervisor::x86_64::{KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE};
+
+    let mut routes = Vec::new();
+    for pin in 0..devices::ioapic::NUM_IOAPIC_PINS as u32 {
+        if pin < 8 {
+            routes.push(irqchip_routing_entry(pin, KVM_IRQCHIP_PIC_MASTER, pin));
+        } else if pin < 16 {
+            routes.push(irqchip_routing_entry(pin, KVM_IRQCHIP_PIC_SLAVE, pin - 8));
+        }
+        routes.push(irqchip_routing_entry(pin, KVM_IRQCHIP_IOAPIC, pin));
+    }
+
+    routes
+}
+
+// The routes the hypervisor sets by default for the shared peripheral
+// interrupts of the GIC.
+#[cfg(target_arch = "aarch64")]
+fn irqchip_routes() -> Vec<IrqRoutingEntry> {
+    (0..arch::aarch64::gic::GIC_NR_IRQS - arch::layout::IRQ_BASE)
+        .map(|pin| irqchip_routing_entry(pin, 0, pin))
+        .collect()
+}
+
+struct MsiVector {
+    gsi: u32,
+    irq_fd: EventFd,
+    masked: AtomicBool,
+}
+
+struct MsiInterruptGroup {
+    allocator: Arc<Mutex<SystemAllocator>>,
+    routes: Arc<GsiRoutes>,
+    devid: Option<u32>,
+    vectors: Vec<MsiVector>,
+    freed: AtomicBool,
+}
+
+impl MsiInterruptGroup {
+    fn vector(&self, index: InterruptIndex) -> io::Result<&MsiVector> {
+        self.vectors.get(index as usize).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no interrupt {}", index),
+            )
+        })
+    }
+}
+
+impl InterruptSourceGroup for MsiInterruptGroup {
+    fn trigger(&self, index: InterruptIndex) -> io::Result<()> {
+        self.vector(index)?.irq_fd.write(1)
+    }
+
+    fn notifier(&self, index: InterruptIndex) -> Option<&EventFd> {
+        self.vectors
+            .get(index as usize)
+            .map(|vector| &vector.irq_fd)
+    }
+
+    fn update(
+        &self,
+        index: InterruptIndex,
+        config: MsiIrqSourceConfig,
+        masked: bool,
+    ) -> io::Result<()> {
+        let vector = self.vector(index)?;
+        if self.freed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        // The vector is masked before its route goes away, and unmasked
+        // once it's routed, for the devices checking it before raising it.
+        if masked {
+            vector.masked.store(true, Ordering::SeqCst);
+            self.routes.set(vector.gsi, None)
+        } else {
+            let route = MsiRoute {
+                config,
+                devid: self.devid,
+            };
+            self.routes.set(vector.gsi, Some(route))?;
+            vector.masked.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn masked(&self, index: InterruptIndex) -> bool {
+        self.vectors
+            .get(index as usize)
+            .map_or(true, |vector| vector.masked.load(Ordering::SeqCst))
+    }
+
+    fn free(&self) -> io::Result<()> {
+        if self.freed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let mut allocator = self.allocator.lock().unwrap();
+        for vector in self.vectors.iter() {
+            vector.masked.store(true, Ordering::SeqCst);
+            self.routes.set(vector.gsi, None)?;
+            self.routes
+                .vm
+                .unregister_irqfd(&vector.irq_fd, vector.gsi)?;
+            allocator.free_gsi(vector.gsi);
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates the interrupt groups of the PCI devices of a VM, their GSIs
+/// being taken from its allocator.
+pub struct MsiInterruptManager {
+    allocator: Arc<Mutex<SystemAllocator>>,
+    routes: Arc<GsiRoutes>,
+}
+
+impl MsiInterruptManager {
+    // Gives the group one more vector, with its GSI and irqfd.
+    fn add_vector(&self, group: &mut MsiInterruptGroup) -> io::Result<()> {
+        let gsi = self
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_gsi()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "no GSI available"))?;
+        let irq_fd = EventFd::new(EFD_NONBLOCK).and_then(|irq_fd| {
+            self.routes.vm.register_irqfd(&irq_fd, gsi)?;
+            Ok(irq_fd)
+        });
+
+        match irq_fd {
+            Ok(irq_fd) => {
+                group.vectors.push(MsiVector {
+                    gsi,
+                    irq_fd,
+                    masked: AtomicBool::new(true),
+                });
+                Ok(())
+            }
+            Err(e) => {
+                self.allocator.lock().unwrap().free_gsi(gsi);
+                Err(e)
+            }
+        }
+    }
+
+    /// The routes of the in-kernel interrupt controller are kept in the
+    /// table along with the MSI ones, when there is one.
+    pub fn new(
+        vm: Arc<dyn hypervisor::Vm>,
+        allocator: Arc<Mutex<SystemAllocator>>,
+        irqchip: bool,
+    ) -> Self {
+        let irqchip = if irqchip {
+            irqchip_routes()
+        } else {
+            Vec::new()
+        };
+
+        MsiInterruptManager {
+            allocator,
+            routes: Arc::new(GsiRoutes {
+                vm,
+                irqchip,
+                msi: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+}
+
+impl InterruptManager for MsiInterruptManager {
+    fn create_group(
+        &self,
+        devid: Option<u32>,
+        count: InterruptIndex,
+    ) -> io::Result<Arc<dyn InterruptSourceGroup>> {
+        let mut group = MsiInterruptGroup {
+            allocator: self.allocator.clone(),
+            routes: self.routes.clone(),
+            devid,
+            vectors: Vec::new(),
+            freed: AtomicBool::new(false),
+        };
+
+        for _ in 0..count {
+            if let Err(e) = self.add_vector(&mut group) {
+                if let Err(e) = group.free() {
+                    warn!("Cannot free the interrupts: {}", e);
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(Arc::new(group))
+    }
+}
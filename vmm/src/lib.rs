@@ -12,27 +12,73 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+#[macro_use]
 extern crate vmm_sys_util;
 
-use crate::api::{ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo};
-use crate::config::VmConfig;
+use crate::api::{
+    ApiAuditLog, ApiClient, ApiError, ApiMessage, ApiRequest, ApiResponse, ApiResponsePayload,
+    ApiSender, FdInfo, PassedFds, PciDeviceInfo, VmApplyResult, VmClaimData, VmDirtyRate, VmInfo,
+    VmSensors, VmThrottle, VmThrottleData, VmmCapabilities, VmmPoolData,
+};
+use crate::config::{
+    PanicAction, PoolConfig, UserConfig, UserDeviceConfig, VmConfig, VmUpdateConfig, VsockConfig,
+};
+use crate::diagnostics::DeviceAuditInfo;
+use crate::event_monitor::{EventMonitor, EventSource, EventType};
+use crate::host_resources::{HostResource, HostResources, Leftover};
+use crate::pool::ClaimAgent;
+use crate::tenancy::Tenancy;
 use crate::vm::{Error as VmError, Vm, VmState};
 use libc::EFD_NONBLOCK;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvError, SendError, Sender};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{result, thread};
+use vm_virtio::BlockCache;
 use vmm_sys_util::eventfd::EventFd;
 
 pub mod api;
+mod cgroup;
+#[cfg(target_arch = "x86_64")]
+mod clock_drift;
+mod cmdline;
 pub mod config;
 pub mod cpu;
 pub mod device_manager;
+mod diagnostics;
+pub mod event_monitor;
+pub mod guest_os;
+mod hooks;
+pub mod host_resources;
+mod input;
+pub mod memory_manager;
+mod pool;
+mod realtime;
+pub mod security;
+pub mod sriov;
+pub mod tenancy;
+pub mod tracer;
 pub mod vm;
 
-#[cfg(feature = "acpi")]
+#[cfg(all(feature = "acpi", target_arch = "x86_64"))]
 mod acpi;
+#[cfg(target_arch = "x86_64")]
+mod coredump;
+#[cfg(target_arch = "x86_64")]
+mod gdb;
+#[cfg(feature = "pci_support")]
+mod interrupt;
+#[cfg(feature = "pci_support")]
+mod plugin;
+
+/// Longest window the dirty rate of a VM is sampled over, for the
+/// measurement not to slow the guest down for long.
+const MAX_DIRTY_RATE_DURATION_MS: u64 = 60_000;
 
 /// Errors associated with VMM management
 #[derive(Debug)]
@@ -53,6 +99,9 @@ pub enum Error {
     /// Cannot create EventFd.
     EventFdCreate(io::Error),
 
+    /// No supported hypervisor could be opened.
+    HypervisorCreate(io::Error),
+
     /// Cannot read from EventFd.
     EventFdRead(io::Error),
 
@@ -62,20 +111,51 @@ pub enum Error {
     /// Cannot create HTTP thread
     HttpThreadSpawn(io::Error),
 
+    /// Cannot create the file descriptor passing thread
+    FdSocketThreadSpawn(io::Error),
+
     /// Cannot handle the VM STDIN stream
     Stdin(VmError),
 
+    /// Cannot handle the VM pseudo-terminal input
+    Pty(VmError),
+
     /// Cannot reboot the VM
     VmReboot(VmError),
 
     /// Cannot shut a VM down
     VmShutdown(VmError),
 
+    /// Cannot boot a VM of the pool
+    VmPool(VmError),
+
+    /// Cannot listen for the guest agent of a VM of the pool
+    PoolAgent(pool::Error),
+
     /// Cannot create VMM thread
     VmmThreadSpawn(io::Error),
 
     /// Cannot shut the VMM down
     VmmShutdown(VmError),
+
+    /// Cannot open the host resources registry
+    HostResources(host_resources::Error),
+
+    /// Cannot serve the API on D-Bus
+    #[cfg(feature = "dbus_api")]
+    DBusApi(zbus::Error),
+
+    /// Cannot request the D-Bus service name
+    #[cfg(feature = "dbus_api")]
+    DBusApiName(zbus::fdo::Error),
+
+    /// The D-Bus service name is owned by another connection
+    #[cfg(feature = "dbus_api")]
+    DBusApiNameOwned(String),
+
+    /// Cannot create the D-Bus API threads
+    #[cfg(feature = "dbus_api")]
+    DBusThreadSpawn(io::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -83,8 +163,28 @@ pub type Result<T> = result::Result<T, Error>;
 pub enum EpollDispatch {
     Exit,
     Reset,
+    VcpuFailure,
     Stdin,
     Api,
+    SerialPty,
+    ConsolePty,
+    SerialSocketListener,
+    SerialSocket,
+    ConsoleSocketListener,
+    ConsoleSocket,
+    ConsolePortSocketListener(usize),
+    ConsolePortSocket(usize),
+    UartPty(usize),
+    UartSocketListener(usize),
+    UartSocket(usize),
+    GdbListener,
+    Gdb,
+    DebugStop,
+    DiskOutOfSpace,
+    GuestPanic,
+    ClockDrift,
+    PciEject,
+    PoolAgent,
 }
 
 pub struct EpollContext {
@@ -99,9 +199,12 @@ impl EpollContext {
         // Initial capacity needs to be large enough to hold:
         // * 1 exit event
         // * 1 reset event
+        // * 1 vCPU failure event
         // * 1 stdin event
         // * 1 API event
-        let mut dispatch_table = Vec::with_capacity(5);
+        // * 2 pseudo-terminal events
+        // * 2 serial socket events
+        let mut dispatch_table = Vec::with_capacity(10);
         dispatch_table.push(None);
 
         Ok(EpollContext {
@@ -139,6 +242,34 @@ impl EpollContext {
 
         Ok(())
     }
+
+    // Some file descriptors come and go with the VM, or with the clients
+    // connecting to it. The closed file descriptors are removed from the
+    // epoll set by the kernel, and the dispatch table entry is reused by the
+    // next file descriptor for the same event. Returns the data of the event,
+    // for its events to be modified.
+    fn add_vm_event(&mut self, fd: RawFd, token: EpollDispatch) -> result::Result<u64, io::Error> {
+        let dispatch_index = match self
+            .dispatch_table
+            .iter()
+            .position(|&dispatch| dispatch == Some(token))
+        {
+            Some(index) => index,
+            None => {
+                self.dispatch_table.push(Some(token));
+                self.dispatch_table.len() - 1
+            }
+        };
+
+        epoll::ctl(
+            self.raw_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            epoll::Event::new(epoll::Events::EPOLLIN, dispatch_index as u64),
+        )?;
+
+        Ok(dispatch_index as u64)
+    }
 }
 
 impl AsRawFd for EpollContext {
@@ -147,45 +278,186 @@ impl AsRawFd for EpollContext {
     }
 }
 
+// Whether a socket client, polled for its output as well, has input, or is
+// gone.
+fn socket_input(events: epoll::Events) -> bool {
+    events.intersects(epoll::Events::EPOLLIN | epoll::Events::EPOLLHUP | epoll::Events::EPOLLERR)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn start_vmm_thread(
     http_path: &str,
     api_event: EventFd,
-    api_sender: Sender<ApiRequest>,
-    api_receiver: Receiver<ApiRequest>,
+    api_sender: Sender<ApiMessage>,
+    api_receiver: Receiver<ApiMessage>,
+    event_monitor: Option<File>,
+    event_sender: Option<Sender<String>>,
+    host_resources: Option<PathBuf>,
+    fd_socket_path: Option<&str>,
+    block_cache_size: u64,
+    tenancy: Option<Tenancy>,
+    api_audit_log: Option<File>,
 ) -> Result<thread::JoinHandle<Result<()>>> {
     let http_api_event = api_event.try_clone().map_err(Error::EventFdClone)?;
+    let passed_fds = PassedFds::default();
+    let vmm_passed_fds = passed_fds.clone();
+    let sockets: Vec<Leftover> = Some(http_path)
+        .into_iter()
+        .chain(fd_socket_path)
+        .map(|path| Leftover::Socket(PathBuf::from(path)))
+        .collect();
 
     let thread = thread::Builder::new()
         .name("vmm".to_string())
         .spawn(move || {
-            let mut vmm = Vmm::new(api_event)?;
+            let event_monitor = if event_monitor.is_some() || event_sender.is_some() {
+                Some(EventMonitor::new(event_monitor, event_sender))
+            } else {
+                None
+            };
+            let hypervisor = hypervisor::new().map_err(Error::HypervisorCreate)?;
+            let mut vmm = Vmm::new(
+                api_event,
+                event_monitor,
+                host_resources,
+                vmm_passed_fds,
+                BlockCache::new(block_cache_size),
+                hypervisor,
+                None,
+            )?;
+            if let Some(host_resources) = &mut vmm.host_resources {
+                host_resources
+                    .set_process_leftovers(sockets)
+                    .map_err(Error::HostResources)?;
+            }
+            vmm.tenancy = tenancy;
 
             vmm.control_loop(Arc::new(api_receiver))
         })
         .map_err(Error::VmmThreadSpawn)?;
 
     // The VMM thread is started, we can start serving HTTP requests
-    api::start_http_thread(http_path, http_api_event, api_sender)?;
+    api::start_http_thread(
+        http_path,
+        http_api_event,
+        ApiSender::new(api_sender),
+        api_audit_log.map(ApiAuditLog::new),
+    )?;
+    if let Some(fd_socket_path) = fd_socket_path {
+        api::start_fd_socket_thread(fd_socket_path, passed_fds)?;
+    }
 
     Ok(thread)
 }
 
+// The VMs booted ahead of time from a template, and the ones of them ready
+// to be claimed, in the order they were started.
+struct VmPool {
+    template: Arc<VmConfig>,
+    config: PoolConfig,
+    ready: VecDeque<String>,
+    // The VMs of the pool are named "pool-<index>".
+    next_index: usize,
+}
+
+// The guest agent of a VM of the pool, and the event of it waiting for its
+// claim.
+struct PoolAgent {
+    agent: ClaimAgent,
+    ready_evt: EventFd,
+}
+
+// The VMM thread of a VM of the process other than the default one.
+struct VmThread {
+    api_evt: EventFd,
+    sender: Sender<ApiMessage>,
+    // Detached once the VM is shut down through vmm.shutdown.
+    thread: thread::JoinHandle<()>,
+    // Token of the client which created or claimed the VM, in multi-tenant
+    // mode.
+    owner: Option<String>,
+}
+
+impl VmThread {
+    // Gives the message back if the thread exited.
+    fn send(&self, message: ApiMessage) -> result::Result<(), ApiMessage> {
+        self.sender.send(message).map_err(|e| e.0)?;
+        if let Err(e) = self.api_evt.write(1) {
+            error!("Cannot notify the VMM thread of an API request: {}", e);
+        }
+
+        Ok(())
+    }
+}
+
 pub struct Vmm {
     epoll: EpollContext,
     exit_evt: EventFd,
     reset_evt: EventFd,
+    vcpu_failure_evt: EventFd,
     api_evt: EventFd,
     vm: Option<Vm>,
     vm_config: Option<Arc<VmConfig>>,
+    event_monitor: Option<EventMonitor>,
+    hypervisor: Arc<dyn hypervisor::Hypervisor>,
+    // Registry shared with the other VMM processes of the host, when the
+    // host resources are coordinated.
+    host_resources: Option<HostResources>,
+    host_resources_path: Option<PathBuf>,
+    // Files the API clients passed, which the VM configurations refer to.
+    passed_fds: PassedFds,
+    // The blocks of the cached disks, shared by the VMs of the process.
+    block_cache: Arc<BlockCache>,
+    // ID of the API request being handled, if its client gave one.
+    request_id: Option<String>,
+    // ID of the VM, None for the default VM of the process.
+    vm_id: Option<String>,
+    // The VMM threads of the other VMs of the process, by ID, which only the
+    // VMM thread of the default VM has.
+    vms: BTreeMap<String, VmThread>,
+    // The VM pool, which only the VMM thread of the default VM has.
+    pool: Option<VmPool>,
+    // Whether the VM is a VM of the pool not claimed yet.
+    pooled: bool,
+    pool_agent: Option<PoolAgent>,
+    // Whether the VMM thread confined the process with Landlock, which it
+    // can't undo.
+    landlocked: bool,
+    // Whether confining the process with Landlock failed once the VMM
+    // thread was confined, which leaves the process unfit to run a VM.
+    partly_confined: bool,
+    // The user the VMM thread switched the process to, which it can't undo
+    // either.
+    user: Option<UserConfig>,
+    // The owners of the VMs in multi-tenant mode, which only the VMM thread
+    // of the default VM checks.
+    tenancy: Option<Tenancy>,
 }
 
 impl Vmm {
-    fn new(api_evt: EventFd) -> Result<Self> {
+    fn new(
+        api_evt: EventFd,
+        event_monitor: Option<EventMonitor>,
+        host_resources_path: Option<PathBuf>,
+        passed_fds: PassedFds,
+        block_cache: Arc<BlockCache>,
+        hypervisor: Arc<dyn hypervisor::Hypervisor>,
+        vm_id: Option<String>,
+    ) -> Result<Self> {
         let mut epoll = EpollContext::new().map_err(Error::Epoll)?;
         let exit_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let reset_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
-
-        if unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
+        let vcpu_failure_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+        let host_resources = match &host_resources_path {
+            Some(path) => Some(
+                HostResources::new(path, vm_id.as_ref().map(|id| id.as_str()))
+                    .map_err(Error::HostResources)?,
+            ),
+            None => None,
+        };
+
+        // The terminal input goes to the default VM only.
+        if vm_id.is_none() && unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0 {
             epoll.add_stdin().map_err(Error::Epoll)?;
         }
 
@@ -197,6 +469,10 @@ impl Vmm {
             .add_event(&reset_evt, EpollDispatch::Reset)
             .map_err(Error::Epoll)?;
 
+        epoll
+            .add_event(&vcpu_failure_evt, EpollDispatch::VcpuFailure)
+            .map_err(Error::Epoll)?;
+
         epoll
             .add_event(&api_evt, EpollDispatch::Api)
             .map_err(Error::Epoll)?;
@@ -205,195 +481,1788 @@ impl Vmm {
             epoll,
             exit_evt,
             reset_evt,
+            vcpu_failure_evt,
             api_evt,
             vm: None,
             vm_config: None,
+            event_monitor,
+            hypervisor,
+            host_resources,
+            host_resources_path,
+            passed_fds,
+            block_cache,
+            request_id: None,
+            vm_id,
+            vms: BTreeMap::new(),
+            pool: None,
+            pooled: false,
+            pool_agent: None,
+            landlocked: false,
+            partly_confined: false,
+            user: None,
+            tenancy: None,
         })
     }
 
-    fn vm_boot(&mut self) -> result::Result<(), VmError> {
-        // Create a new VM is we don't have one yet.
-        if self.vm.is_none() {
-            let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
-            let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+    // Whether the VM of this VMM thread is the only one of the process, the
+    // default one without other VMs.
+    fn runs_alone(&self) -> bool {
+        self.vm_id.is_none() && self.vms.is_empty() && self.pool.is_none()
+    }
 
-            if let Some(ref vm_config) = self.vm_config {
-                let vm = Vm::new(Arc::clone(vm_config), exit_evt, reset_evt)?;
-                self.vm = Some(vm);
+    // Starts the VMM thread of another VM of the process, sharing the
+    // hypervisor, the passed files, the block cache, the host resources
+    // registry and the event monitor of this one. A VM of the pool is booted from the
+    // template by its VMM thread, its guest agent waiting for its claim on
+    // the vsock port given along.
+    fn start_vm_thread(
+        &self,
+        vm_id: &str,
+        pool: Option<(Arc<VmConfig>, u32)>,
+    ) -> io::Result<VmThread> {
+        // The other VMs couldn't open their files.
+        if self.landlocked || self.user.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the process is confined to the default VM",
+            ));
+        }
+        let api_evt = EventFd::new(EFD_NONBLOCK)?;
+        let vmm_api_evt = api_evt.try_clone()?;
+        let (sender, receiver) = channel();
+        let event_monitor = match &self.event_monitor {
+            Some(event_monitor) => Some(event_monitor.for_vm(vm_id)?),
+            None => None,
+        };
+        let host_resources_path = self.host_resources_path.clone();
+        let passed_fds = self.passed_fds.clone();
+        let block_cache = self.block_cache.clone();
+        let hypervisor = self.hypervisor.clone();
+        let thread_vm_id = vm_id.to_string();
+
+        let thread = thread::Builder::new()
+            .name(format!("vmm-{}", vm_id))
+            .spawn(move || {
+                let result = Vmm::new(
+                    vmm_api_evt,
+                    event_monitor,
+                    host_resources_path,
+                    passed_fds,
+                    block_cache,
+                    hypervisor,
+                    Some(thread_vm_id.clone()),
+                )
+                .and_then(|mut vmm| {
+                    if let Some((template, port)) = pool {
+                        vmm.join_pool(&template, port)?;
+                    }
+                    vmm.control_loop(Arc::new(receiver))
+                });
+                if let Err(e) = result {
+                    error!("The VMM thread of VM {} failed: {:?}", thread_vm_id, e);
+                }
+            })?;
+
+        Ok(VmThread {
+            api_evt,
+            sender,
+            thread,
+            owner: None,
+        })
+    }
+
+    // Forwards a request naming a VM to the VMM thread of the VM, which the
+    // request creating the VM starts. The thread is kept once the VM is
+    // deleted, for the ID to be reused, until the VM is shut down through
+    // vmm.shutdown, or the VMM is. In multi-tenant mode, the requests of
+    // clients which don't own the VM don't reach it, and the thread started
+    // by the request creating the VM is owned by its client.
+    fn forward_request(
+        &mut self,
+        vm_id: String,
+        mut message: ApiMessage,
+        client: &ApiClient,
+    ) -> Result<()> {
+        let owner = match &self.tenancy {
+            Some(tenancy) => {
+                let owner = match self.vms.get(&vm_id) {
+                    Some(vm_thread) => tenancy
+                        .authorize(client, vm_thread.owner.as_ref().map(|o| o.as_str()))
+                        .map(|_| vm_thread.owner.clone()),
+                    None => tenancy.owner(client),
+                }
+                .and_then(|owner| {
+                    tenancy
+                        .check_request(client, &message.request)
+                        .map(|_| owner)
+                });
+                match owner {
+                    Ok(owner) => owner,
+                    Err(e) => {
+                        return message
+                            .request
+                            .response_sender()
+                            .send(Err(e))
+                            .map_err(Error::ApiResponseSend)
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let (creates, shuts_down) = match message.request {
+            ApiRequest::VmCreate(..) => (true, false),
+            ApiRequest::VmmShutdown(_) => (false, true),
+            // A VM of the pool is claimed through the pool, for it to be
+            // known by the ID of the claim.
+            ApiRequest::VmClaim(..) => {
+                return message
+                    .request
+                    .response_sender()
+                    .send(Err(ApiError::VmNotPooled))
+                    .map_err(Error::ApiResponseSend);
+            }
+            _ => (false, false),
+        };
+
+        if let Some(vm_thread) = self.vms.remove(&vm_id) {
+            match vm_thread.send(message) {
+                Ok(()) => {
+                    // No request reaches the thread after the one shutting
+                    // it down.
+                    if !shuts_down {
+                        self.vms.insert(vm_id, vm_thread);
+                    }
+                    return Ok(());
+                }
+                // The thread failed, and logged why.
+                Err(returned) => message = returned,
             }
         }
 
-        // Now we can boot the VM.
-        if let Some(ref mut vm) = self.vm {
-            vm.boot()
+        let response = if creates {
+            match self.start_vm_thread(&vm_id, None) {
+                Ok(mut vm_thread) => {
+                    vm_thread.owner = owner;
+                    match vm_thread.send(message) {
+                        Ok(()) => {
+                            self.vms.insert(vm_id, vm_thread);
+                            return Ok(());
+                        }
+                        Err(returned) => {
+                            message = returned;
+                            let e = io::Error::new(io::ErrorKind::Other, "VMM thread exited");
+                            Err(ApiError::VmmThread(e))
+                        }
+                    }
+                }
+                Err(e) => Err(ApiError::VmmThread(e)),
+            }
+        } else if shuts_down {
+            // There is nothing to shut down.
+            Ok(ApiResponsePayload::Empty)
         } else {
-            Err(VmError::VmNotCreated)
+            Err(ApiError::VmNotCreated)
+        };
+
+        message
+            .request
+            .response_sender()
+            .send(response)
+            .map_err(Error::ApiResponseSend)
+    }
+
+    // Shuts the other VMs of the process down, waiting for their VMM threads
+    // to exit.
+    fn shutdown_vms(&mut self) {
+        for (vm_id, vm_thread) in std::mem::replace(&mut self.vms, BTreeMap::new()) {
+            Vmm::shutdown_vm_thread(&vm_id, vm_thread);
         }
+        self.pool = None;
     }
 
-    fn vm_pause(&mut self) -> result::Result<(), VmError> {
-        if let Some(ref mut vm) = self.vm {
-            vm.pause()
-        } else {
-            Err(VmError::VmNotRunning)
+    fn shutdown_vm_thread(vm_id: &str, vm_thread: VmThread) {
+        let (sender, receiver) = channel();
+        let message = ApiMessage {
+            request: ApiRequest::VmmShutdown(sender),
+            request_id: None,
+            vm_id: None,
+            client: ApiClient::Local,
+        };
+        if vm_thread.send(message).is_ok() {
+            if let Ok(Err(e)) = receiver.recv() {
+                error!("Cannot shut VM {} down: {:?}", vm_id, e);
+            }
+        }
+        if vm_thread.thread.join().is_err() {
+            error!("The VMM thread of VM {} panicked", vm_id);
         }
     }
 
-    fn vm_resume(&mut self) -> result::Result<(), VmError> {
-        if let Some(ref mut vm) = self.vm {
-            vm.resume()
-        } else {
-            Err(VmError::VmNotRunning)
+    // Creates the VM pool, and starts the VMM threads of its VMs, which boot
+    // them on their own.
+    fn vmm_pool(&mut self, data: &VmmPoolData) -> result::Result<(), ApiError> {
+        if self.pool.is_some() {
+            return Err(ApiError::VmPoolAlreadyCreated);
         }
+        data.pool
+            .validate(&data.config)
+            .map_err(|e| ApiError::VmmPool(VmError::InvalidConfig(e)))?;
+
+        info!(
+            "Booting a pool of {} VMs, their guest agent waiting on vsock port {}",
+            data.pool.size, data.pool.port
+        );
+        self.pool = Some(VmPool {
+            template: Arc::new(data.config.clone()),
+            config: data.pool.clone(),
+            ready: VecDeque::new(),
+            next_index: 0,
+        });
+
+        self.fill_pool().map_err(ApiError::VmmThread)
     }
 
-    fn vm_shutdown(&mut self) -> result::Result<(), VmError> {
-        if let Some(ref mut vm) = self.vm.take() {
-            vm.shutdown()
-        } else {
-            Err(VmError::VmNotRunning)
+    // Starts VMs of the pool until it has as many VMs ready to be claimed as
+    // its size.
+    fn fill_pool(&mut self) -> io::Result<()> {
+        loop {
+            let (vm_id, template, port) = match &mut self.pool {
+                Some(pool) if pool.ready.len() < pool.config.size => {
+                    pool.next_index += 1;
+                    let vm_id = format!("pool-{}", pool.next_index - 1);
+                    (vm_id, pool.template.clone(), pool.config.port)
+                }
+                _ => return Ok(()),
+            };
+            // The ID may have been given to a VM created through the API.
+            if self.vms.contains_key(&vm_id) {
+                continue;
+            }
+
+            let vm_thread = self.start_vm_thread(&vm_id, Some((template, port)))?;
+            self.vms.insert(vm_id.clone(), vm_thread);
+            if let Some(pool) = &mut self.pool {
+                pool.ready.push_back(vm_id);
+            }
         }
     }
 
-    fn vm_reboot(&mut self) -> result::Result<(), VmError> {
-        // Without ACPI, a reset is equivalent to a shutdown
-        #[cfg(not(feature = "acpi"))]
-        {
-            if self.vm.is_some() {
-                return self.vm_shutdown();
+    // Hands the VM of the pool started first out, under the ID of the claim,
+    // and starts another VM in its place. A claim waits for the VM to be
+    // booted. The VMs which can't be claimed, e.g. whose guest shut down, are
+    // shut down and replaced as well.
+    // The claimed VM is owned by `owner`, in multi-tenant mode.
+    fn vm_claim(
+        &mut self,
+        claim: Arc<VmClaimData>,
+        owner: Option<String>,
+    ) -> result::Result<(), ApiError> {
+        if !api::valid_vm_id(&claim.id) {
+            return Err(ApiError::InvalidVmId);
+        }
+        if self.vms.contains_key(&claim.id) {
+            return Err(ApiError::VmAlreadyCreated);
+        }
+
+        let result = loop {
+            let pool_vm_id = match self.pool.as_mut().and_then(|pool| pool.ready.pop_front()) {
+                Some(vm_id) => vm_id,
+                None => break Err(ApiError::VmPoolEmpty),
+            };
+            let mut vm_thread = match self.vms.remove(&pool_vm_id) {
+                Some(vm_thread) => vm_thread,
+                None => continue,
+            };
+
+            let (sender, receiver) = channel();
+            let message = ApiMessage {
+                request: ApiRequest::VmClaim(claim.clone(), sender),
+                request_id: self.request_id.clone(),
+                vm_id: None,
+                client: ApiClient::Local,
+            };
+            let response = match vm_thread.send(message) {
+                Ok(()) => receiver.recv().ok(),
+                Err(_) => None,
+            };
+            match response {
+                Some(Ok(_)) => {
+                    info!("VM {} of the pool claimed as {}", pool_vm_id, claim.id);
+                    vm_thread.owner = owner;
+                    self.vms.insert(claim.id.clone(), vm_thread);
+                    break Ok(());
+                }
+                Some(Err(e)) => {
+                    warn!("Cannot claim VM {} of the pool: {:?}", pool_vm_id, e);
+                    Vmm::shutdown_vm_thread(&pool_vm_id, vm_thread);
+                }
+                // The thread failed, and logged why.
+                None => {
+                    let _ = vm_thread.thread.join();
+                }
             }
+        };
+
+        if let Err(e) = self.fill_pool() {
+            error!("Cannot start a VM of the pool: {}", e);
         }
 
-        // First we stop the current VM and create a new one.
-        if let Some(ref mut vm) = self.vm {
-            let config = vm.get_config();
-            self.vm_shutdown()?;
+        result
+    }
 
-            let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
-            let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+    // Boots the VM of the pool from the template. The VM is paused once its
+    // guest agent waits for its claim, or right away if the template has no
+    // vsock device for the agent to wait on.
+    fn join_pool(&mut self, template: &VmConfig, port: u32) -> Result<()> {
+        let vm_id = self.vm_id.clone().unwrap_or_default();
+        let mut config = template.clone();
+        // The vsock devices of the VMs of the pool can't listen on the same
+        // UNIX socket.
+        for vsock in config.vsock.iter_mut().flatten() {
+            vsock.sock = PathBuf::from(format!("{}.{}", vsock.sock.display(), vm_id));
+        }
 
-            self.vm = Some(Vm::new(config, exit_evt, reset_evt)?);
+        if let Some(vsock) = config.vsock.as_ref().and_then(|vsock| vsock.first()) {
+            let ready_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
+            let path = PathBuf::from(format!("{}_{}", vsock.sock.display(), port));
+            let agent = ClaimAgent::new(path, ready_evt.try_clone().map_err(Error::EventFdClone)?)
+                .map_err(Error::PoolAgent)?;
+            self.epoll
+                .add_event(&ready_evt, EpollDispatch::PoolAgent)
+                .map_err(Error::Epoll)?;
+            self.pool_agent = Some(PoolAgent { agent, ready_evt });
+        }
+        self.pooled = true;
+
+        self.vm_create(Arc::new(config)).map_err(Error::VmPool)?;
+        self.vm_boot().map_err(Error::VmPool)?;
+        self.report_event(EventSource::Api, EventType::Booted);
+        if self.pool_agent.is_none() {
+            self.vm_pause().map_err(Error::VmPool)?;
+            self.report_event(EventSource::Api, EventType::Paused);
         }
 
-        // Then we start the new VM.
+        Ok(())
+    }
+
+    // The guest agent of the VM of the pool waits for its claim, the VM is
+    // paused until it is claimed.
+    fn vm_pool_ready(&mut self) -> result::Result<(), VmError> {
+        if !self.pooled {
+            return Ok(());
+        }
         if let Some(ref mut vm) = self.vm {
-            vm.boot()?;
-        } else {
-            return Err(VmError::VmNotCreated);
+            // The VM may have been paused in the meantime.
+            if vm.get_state()? == VmState::Running {
+                vm.pause()?;
+                self.report_event(EventSource::Guest, EventType::Paused);
+            }
         }
 
         Ok(())
     }
 
-    fn vm_info(&self) -> result::Result<VmInfo, VmError> {
-        match &self.vm_config {
-            Some(config) => {
-                let state = match &self.vm {
-                    Some(vm) => vm.get_state()?,
-                    None => VmState::Created,
-                };
+    // Renames the VM of the pool after the claim, gives the claim to its
+    // guest agent, and resumes it.
+    fn vm_claim_pooled(&mut self, claim: &VmClaimData) -> result::Result<(), ApiError> {
+        if !self.pooled {
+            return Err(ApiError::VmNotPooled);
+        }
+        let state = match &self.vm {
+            Some(vm) => vm.get_state().map_err(ApiError::VmClaim)?,
+            None => return Err(ApiError::VmClaim(self.vm_not_running())),
+        };
+        self.pooled = false;
+
+        let pool_vm_id = self.vm_id.replace(claim.id.clone());
+        if let Some(ref mut event_monitor) = self.event_monitor {
+            event_monitor.set_vm_id(&claim.id);
+            event_monitor.report(
+                EventSource::Api,
+                EventType::Claimed,
+                Some(serde_json::json!({ "pool_vm_id": pool_vm_id })),
+                self.request_id.clone(),
+            );
+        }
 
-                Ok(VmInfo {
-                    config: Arc::clone(config),
-                    state,
-                })
+        if let Some(pool_agent) = &self.pool_agent {
+            let document = serde_json::to_string(claim)
+                .map(|document| format!("{}\n", document))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            if let Err(e) =
+                document.and_then(|document| pool_agent.agent.claim(document.as_bytes()))
+            {
+                warn!("Cannot give the claim to the guest agent: {}", e);
             }
-            None => Err(VmError::VmNotCreated),
         }
+
+        if state == VmState::Paused {
+            self.vm_resume().map_err(ApiError::VmClaim)?;
+            self.report_event(EventSource::Api, EventType::Resumed);
+        }
+
+        Ok(())
     }
 
-    fn vm_delete(&mut self) -> result::Result<(), VmError> {
-        if self.vm_config.is_none() {
+    // The events an API request leads to carry its ID.
+    fn report_event(&mut self, source: EventSource, event: EventType) {
+        let request_id = match source {
+            EventSource::Api => self.request_id.clone(),
+            EventSource::Guest => None,
+        };
+        if let Some(ref mut event_monitor) = self.event_monitor {
+            event_monitor.report(source, event, None, request_id);
+        }
+    }
+
+    // Pauses the VM once some of its vCPUs failed, so that its state can be
+    // looked at, and reports the `count` latest failures.
+    fn vm_vcpu_failure(&mut self, count: usize) -> result::Result<(), VmError> {
+        let failures = match self.vm {
+            Some(ref mut vm) => vm.vcpu_failures(),
+            None => return Ok(()),
+        };
+        if failures.is_empty() {
+            // The failures were those of a VM since rebooted.
             return Ok(());
         }
 
-        // First we try to shut the current VM down.
-        self.vm_shutdown()?;
+        if let Some(ref mut vm) = self.vm {
+            // The VM may have been paused in the meantime.
+            if vm.get_state()? == VmState::Running {
+                vm.pause()?;
+            }
+        }
 
-        self.vm_config = None;
+        if let Some(ref mut event_monitor) = self.event_monitor {
+            for failure in failures.iter().skip(failures.len().saturating_sub(count)) {
+                event_monitor.report(
+                    EventSource::Guest,
+                    EventType::VcpuFailed,
+                    serde_json::to_value(failure).ok(),
+                    None,
+                );
+            }
+        }
 
         Ok(())
     }
 
-    fn vmm_shutdown(&mut self) -> result::Result<(), VmError> {
-        self.vm_delete()
+    // The error of an action on a VM which isn't running: whether there is no
+    // VM at all, or one which isn't booted.
+    fn vm_not_running(&self) -> VmError {
+        if self.vm_config.is_none() {
+            VmError::VmNotCreated
+        } else {
+            VmError::VmNotRunning
+        }
     }
 
-    fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
-        const EPOLL_EVENTS_LEN: usize = 100;
+    fn vm_coredump(&self, destination: &Path) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.coredump(destination)
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
 
-        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
-        let epoll_fd = self.epoll.as_raw_fd();
+    fn vm_screenshot(&self, destination: &Path) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.screenshot(destination)
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
 
-        'outer: loop {
-            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
-                Ok(res) => res,
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::Interrupted {
-                        // It's well defined from the epoll_wait() syscall
-                        // documentation that the epoll loop can be interrupted
-                        // before any of the requested events occurred or the
-                        // timeout expired. In both those cases, epoll_wait()
-                        // returns an error of type EINTR, but this should not
-                        // be considered as a regular error. Instead it is more
-                        // appropriate to retry, by calling into epoll_wait().
-                        continue;
-                    }
-                    return Err(Error::Epoll(e));
+    fn vm_reset_device(&self, id: &str) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.reset_device(id)
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_device_audit(&self) -> result::Result<Vec<DeviceAuditInfo>, VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.device_audit()
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_set_disk_weight(&self, id: &str, weight: u32) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.set_disk_weight(id, weight)
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_throttle(&self, data: Option<&VmThrottleData>) -> result::Result<VmThrottle, VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.throttle(data)
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    // Starts measuring the rate the guest dirties its RAM at. A thread of
+    // its own counts the dirty pages once the sampling window is over and
+    // answers the request, the VMM thread handling the other events
+    // meanwhile.
+    fn vm_dirty_rate(
+        &self,
+        duration_ms: u64,
+        sender: Sender<ApiResponse>,
+    ) -> result::Result<(), VmError> {
+        if duration_ms == 0 || duration_ms > MAX_DIRTY_RATE_DURATION_MS {
+            return Err(VmError::InvalidDirtyRateDuration(duration_ms));
+        }
+        let memory_manager = match self.vm {
+            Some(ref vm) => vm.start_dirty_log()?,
+            None => return Err(self.vm_not_running()),
+        };
+
+        let thread_memory_manager = memory_manager.clone();
+        let start = Instant::now();
+        let thread = thread::Builder::new()
+            .name("dirty-rate".to_string())
+            .spawn(move || {
+                thread::sleep(Duration::from_millis(duration_ms));
+                let mut memory_manager = thread_memory_manager.lock().unwrap();
+                let dirty_pages = memory_manager.dirty_pages();
+                let duration_ms = start.elapsed().as_millis() as u64;
+                if let Err(e) = memory_manager.stop_dirty_log() {
+                    warn!("Cannot stop logging the dirty pages: {:?}", e);
                 }
-            };
+                drop(memory_manager);
+
+                // Safe because sysconf() only reads a system constant.
+                let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+                let response = dirty_pages
+                    .map(|dirty_pages| {
+                        ApiResponsePayload::VmDirtyRate(VmDirtyRate::new(
+                            dirty_pages,
+                            page_size,
+                            duration_ms,
+                        ))
+                    })
+                    .map_err(|e| ApiError::VmDirtyRate(VmError::DirtyLog(e)));
+                // The client may have given up on the response.
+                let _ = sender.send(response);
+            });
+        if let Err(e) = thread {
+            let _ = memory_manager.lock().unwrap().stop_dirty_log();
+            return Err(VmError::DirtyRateThread(e));
+        }
 
-            for event in events.iter().take(num_events) {
-                let dispatch_idx = event.data as usize;
+        Ok(())
+    }
 
-                if let Some(dispatch_type) = self.epoll.dispatch_table[dispatch_idx] {
-                    match dispatch_type {
-                        EpollDispatch::Exit => {
-                            // Consume the event.
-                            self.exit_evt.read().map_err(Error::EventFdRead)?;
-                            self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+    // Adds a device to the configuration of the VM, the IDs being assigned
+    // and the configuration validated with it. The running VM hot-adds it,
+    // returning where.
+    fn vm_add_device<F, G>(
+        &mut self,
+        add: F,
+        hotplug: G,
+    ) -> result::Result<Option<PciDeviceInfo>, VmError>
+    where
+        F: FnOnce(&mut VmConfig),
+        G: FnOnce(&mut Vm, &VmConfig) -> result::Result<PciDeviceInfo, VmError>,
+    {
+        let mut config = match &self.vm_config {
+            Some(config) => VmConfig::clone(config),
+            None => return Err(VmError::VmNotCreated),
+        };
+        add(&mut config);
+        config.assign_device_ids();
+        config.validate().map_err(VmError::InvalidConfig)?;
+
+        let info = match self.vm {
+            Some(ref mut vm) => {
+                let info = hotplug(vm, &config)?;
+                self.vm_config = Some(vm.get_config());
+                Some(info)
+            }
+            None => {
+                self.vm_config = Some(Arc::new(config));
+                None
+            }
+        };
+        self.track_leftovers();
 
-                            break 'outer;
-                        }
-                        EpollDispatch::Reset => {
-                            // Consume the event.
-                            self.reset_evt.read().map_err(Error::EventFdRead)?;
-                            self.vm_reboot().map_err(Error::VmReboot)?;
-                        }
-                        EpollDispatch::Stdin => {
-                            if let Some(ref vm) = self.vm {
-                                vm.handle_stdin().map_err(Error::Stdin)?;
-                            }
-                        }
-                        EpollDispatch::Api => {
-                            // Consume the event.
-                            self.api_evt.read().map_err(Error::EventFdRead)?;
+        Ok(info)
+    }
 
-                            // Read from the API receiver channel
-                            let api_request = api_receiver.recv().map_err(Error::ApiRequestRecv)?;
+    fn vm_add_vsock(
+        &mut self,
+        vsock_cfg: &VsockConfig,
+    ) -> result::Result<Option<PciDeviceInfo>, VmError> {
+        self.vm_add_device(
+            |config| {
+                config
+                    .vsock
+                    .get_or_insert_with(Vec::new)
+                    .push(vsock_cfg.clone())
+            },
+            |vm, config| vm.add_vsock(config.vsock.iter().flatten().last().unwrap().clone()),
+        )
+    }
 
-                            match api_request {
-                                ApiRequest::VmCreate(config, sender) => {
-                                    // We only store the passed VM config.
-                                    // The VM will be created when being asked to boot it.
-                                    let response = if self.vm_config.is_none() {
-                                        self.vm_config = Some(config);
-                                        Ok(ApiResponsePayload::Empty)
-                                    } else {
-                                        Err(ApiError::VmAlreadyCreated)
-                                    };
+    fn vm_add_user_device(
+        &mut self,
+        user_device_cfg: &UserDeviceConfig,
+    ) -> result::Result<Option<PciDeviceInfo>, VmError> {
+        self.vm_add_device(
+            |config| {
+                config
+                    .user_devices
+                    .get_or_insert_with(Vec::new)
+                    .push(user_device_cfg.clone())
+            },
+            |vm, config| {
+                vm.add_user_device(config.user_devices.iter().flatten().last().unwrap().clone())
+            },
+        )
+    }
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmDelete(sender) => {
-                                    let response = self
-                                        .vm_delete()
-                                        .map_err(ApiError::VmDelete)
-                                        .map(|_| ApiResponsePayload::Empty);
+    // Asks the guest of the running VM to eject the device, which is removed
+    // from the configuration once ejected, or removes it from the
+    // configuration of the VM which doesn't run.
+    fn vm_remove_device(&mut self, id: &str) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            return vm.remove_device(id);
+        }
 
-                                    sender.send(response).map_err(Error::ApiResponseSend)?;
-                                }
-                                ApiRequest::VmBoot(sender) => {
+        match &mut self.vm_config {
+            Some(config) => {
+                if Arc::make_mut(config).remove_device(id) {
+                    Ok(())
+                } else {
+                    Err(VmError::UnknownDevice(id.to_string()))
+                }
+            }
+            None => Err(VmError::VmNotCreated),
+        }
+    }
+
+    // Brings the configuration of the VM to the desired one, once checked it
+    // only asks for changes the running VM can take, the running VM
+    // hot-adding and removing the devices. A change failing leaves the ones
+    // made before it.
+    fn vm_apply(&mut self, desired: &VmConfig) -> result::Result<VmApplyResult, VmError> {
+        let current = match &self.vm_config {
+            Some(config) => Arc::clone(config),
+            None => return Err(VmError::VmNotCreated),
+        };
+        let mut desired = desired.clone();
+        desired.assign_device_ids();
+        desired.validate().map_err(VmError::InvalidConfig)?;
+        let changes = current
+            .changes(&desired)
+            .map_err(VmError::ConfigNotApplicable)?;
+
+        let mut result = VmApplyResult::default();
+        if changes.is_empty() {
+            return Ok(result);
+        }
+        if let Some(ref vm) = self.vm {
+            // The guest is only asked to eject devices while it runs.
+            if !changes.removed.is_empty() && vm.get_state()? != VmState::Running {
+                return Err(VmError::VmNotRunning);
+            }
+        }
+
+        if !changes.disk_weights.is_empty() {
+            match self.vm {
+                Some(ref mut vm) => {
+                    vm.apply_disk_weights(&changes.disk_weights)?;
+                    self.vm_config = Some(vm.get_config());
+                }
+                None => {
+                    if let Some(config) = &mut self.vm_config {
+                        let config = Arc::make_mut(config);
+                        for (id, weight) in changes.disk_weights.iter() {
+                            config.set_disk_weight(id, *weight);
+                        }
+                    }
+                }
+            }
+            result.disk_weights = changes.disk_weights.into_iter().map(|(id, _)| id).collect();
+        }
+
+        for id in changes.removed {
+            self.vm_remove_device(&id)?;
+            result.removed.push(id);
+        }
+
+        for vsock_cfg in changes.vsock {
+            let info = self.vm_add_vsock(&vsock_cfg)?;
+            result.added.push(vsock_cfg.id.unwrap_or_default());
+            result.pci_devices.extend(info);
+        }
+        for user_device_cfg in changes.user_devices {
+            let info = self.vm_add_user_device(&user_device_cfg)?;
+            result.added.push(user_device_cfg.id.unwrap_or_default());
+            result.pci_devices.extend(info);
+        }
+
+        Ok(result)
+    }
+
+    // Changes the fields of the configuration of the VM which the running VM
+    // takes without its devices being created anew, or the configuration of
+    // the VM which doesn't run.
+    fn vm_update(&mut self, update: &VmUpdateConfig) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.update(update)?;
+            self.vm_config = Some(vm.get_config());
+            return Ok(());
+        }
+
+        match &mut self.vm_config {
+            Some(config) => {
+                let updated = config
+                    .update(update)
+                    .map_err(VmError::ConfigNotApplicable)?;
+                updated.validate().map_err(VmError::InvalidConfig)?;
+                *config = Arc::new(updated);
+                Ok(())
+            }
+            None => Err(VmError::VmNotCreated),
+        }
+    }
+
+    fn add_pci_eject_event(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(eject_evt) = vm.pci_eject_evt() {
+                self.epoll
+                    .add_vm_event(eject_evt.as_raw_fd(), EpollDispatch::PciEject)
+                    .map_err(VmError::PciEjectEpoll)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Removes the devices the guest ejected, from the configuration as well.
+    fn vm_eject_devices(&mut self) -> result::Result<(), VmError> {
+        let ids = match self.vm {
+            Some(ref mut vm) => {
+                let ids = vm.eject_devices()?;
+                self.vm_config = Some(vm.get_config());
+                ids
+            }
+            None => return Ok(()),
+        };
+        self.track_leftovers();
+
+        for id in ids {
+            info!("The guest ejected the device {}", id);
+            if let Some(ref mut event_monitor) = self.event_monitor {
+                event_monitor.report(
+                    EventSource::Guest,
+                    EventType::DeviceRemoved,
+                    Some(serde_json::json!({ "id": id })),
+                    None,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn vm_boot(&mut self) -> result::Result<(), VmError> {
+        // Create a new VM is we don't have one yet.
+        if self.vm.is_none() {
+            let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let vcpu_failure_evt = self
+                .vcpu_failure_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+
+            if let Some(ref vm_config) = self.vm_config {
+                let config = Arc::clone(vm_config);
+                let registry_path = self.host_resources_path.clone();
+                let registry = registry_path.as_ref().map(|p| p.as_path());
+                // The process runs as the user of the first VM it booted for
+                // good, and the files the user can't open again, when the VM
+                // reboots, are told before the VM is created.
+                if self.user.is_some() && self.user != config.user {
+                    return Err(VmError::Security(security::Error::UserChanged));
+                }
+                if self.partly_confined {
+                    return Err(VmError::Security(security::Error::PartlyConfined));
+                }
+                // Landlock confines the whole process to the files of the
+                // VM, and the whole process switches to its user, the VM
+                // having to be the only one of the process.
+                if (config.landlock.is_some() || config.user.is_some()) && !self.runs_alone() {
+                    return Err(VmError::Security(security::Error::SharedProcess));
+                }
+                security::check_user(&config, registry).map_err(VmError::Security)?;
+
+                let vm = Vm::new(
+                    Arc::clone(vm_config),
+                    self.hypervisor.clone(),
+                    exit_evt,
+                    reset_evt,
+                    vcpu_failure_evt,
+                    &self.passed_fds,
+                    &self.block_cache,
+                )?;
+
+                // The files of the VM are opened, the process is confined to
+                // them from now on, for the VMs created later as well. The
+                // VM is only kept once confined, or dropped along with the
+                // error.
+                if !self.landlocked && config.landlock.is_some() {
+                    if let Err(e) = security::landlock(&config, registry) {
+                        if let security::Error::LandlockThreads(_) = e {
+                            self.partly_confined = true;
+                        }
+                        return Err(VmError::Security(e));
+                    }
+                    self.landlocked = true;
+                }
+                // The privileges are not needed anymore, the guest not having
+                // run yet. The VM is dropped as well if the switch fails.
+                match &config.user {
+                    Some(user) if self.user.is_none() => {
+                        security::set_user(user).map_err(VmError::Security)?;
+                        self.user = Some(user.clone());
+                    }
+                    _ => (),
+                }
+
+                self.vm = Some(vm);
+                if let Err(e) = self.add_vm_events() {
+                    self.vm = None;
+                    return Err(e);
+                }
+            }
+        }
+
+        // Now we can boot the VM, which creates the rest of its files.
+        let result = match self.vm {
+            Some(ref mut vm) => vm.boot(),
+            None => Err(VmError::VmNotCreated),
+        };
+        self.track_leftovers();
+
+        result
+    }
+
+    // Lists what the VM created in the manifest of its host resources, for
+    // it to be reclaimed if the process dies.
+    fn track_leftovers(&mut self) {
+        if let Some(host_resources) = &mut self.host_resources {
+            let mut leftovers = self.vm.as_ref().map(Vm::leftovers).unwrap_or_default();
+            if let Some(pool_agent) = &self.pool_agent {
+                leftovers.push(Leftover::Socket(pool_agent.agent.path().to_path_buf()));
+            }
+            if let Err(e) = host_resources.set_vm_leftovers(leftovers) {
+                warn!("Cannot list the leftovers of the VM: {:?}", e);
+            }
+        }
+    }
+
+    fn add_vm_events(&mut self) -> result::Result<(), VmError> {
+        self.add_console_events()?;
+        self.add_gdb_events()?;
+        self.add_out_of_space_event()?;
+        self.add_pvpanic_event()?;
+        self.add_clock_drift_event()?;
+        self.add_pci_eject_event()
+    }
+
+    fn add_console_events(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(pty) = vm.serial_pty() {
+                self.epoll
+                    .add_vm_event(pty.main.as_raw_fd(), EpollDispatch::SerialPty)
+                    .map_err(VmError::PtyEpoll)?;
+            }
+            if let Some(pty) = vm.console_pty() {
+                self.epoll
+                    .add_vm_event(pty.main.as_raw_fd(), EpollDispatch::ConsolePty)
+                    .map_err(VmError::PtyEpoll)?;
+            }
+            if let Some(socket) = vm.serial_socket() {
+                self.epoll
+                    .add_vm_event(
+                        socket.listener().as_raw_fd(),
+                        EpollDispatch::SerialSocketListener,
+                    )
+                    .map_err(VmError::SerialSocketEpoll)?;
+            }
+            if let Some(socket) = vm.console_socket() {
+                self.epoll
+                    .add_vm_event(
+                        socket.listener().as_raw_fd(),
+                        EpollDispatch::ConsoleSocketListener,
+                    )
+                    .map_err(VmError::SerialSocketEpoll)?;
+            }
+            for (index, socket) in vm.console_port_sockets().iter().enumerate() {
+                self.epoll
+                    .add_vm_event(
+                        socket.listener().as_raw_fd(),
+                        EpollDispatch::ConsolePortSocketListener(index),
+                    )
+                    .map_err(VmError::SerialSocketEpoll)?;
+            }
+            for (index, uart) in vm.uarts().iter().enumerate() {
+                if let Some(pty) = uart.pty() {
+                    self.epoll
+                        .add_vm_event(pty.main.as_raw_fd(), EpollDispatch::UartPty(index))
+                        .map_err(VmError::PtyEpoll)?;
+                }
+                if let Some(socket) = uart.socket() {
+                    self.epoll
+                        .add_vm_event(
+                            socket.listener().as_raw_fd(),
+                            EpollDispatch::UartSocketListener(index),
+                        )
+                        .map_err(VmError::SerialSocketEpoll)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_out_of_space_event(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            self.epoll
+                .add_vm_event(
+                    vm.out_of_space_evt().as_raw_fd(),
+                    EpollDispatch::DiskOutOfSpace,
+                )
+                .map_err(VmError::OutOfSpaceEpoll)?;
+        }
+
+        Ok(())
+    }
+
+    // Reports the disks whose image ran out of space, their devices waiting
+    // for the operator to free space and reset them.
+    fn vm_disks_out_of_space(&mut self) -> result::Result<(), VmError> {
+        let disks = match self.vm {
+            Some(ref vm) => vm.take_out_of_space_disks()?,
+            None => return Ok(()),
+        };
+
+        for id in disks {
+            warn!("The disk image of {} is out of space", id);
+            if let Some(ref mut event_monitor) = self.event_monitor {
+                event_monitor.report(
+                    EventSource::Guest,
+                    EventType::DiskOutOfSpace,
+                    Some(serde_json::json!({ "id": id })),
+                    None,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_pvpanic_event(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(panic_evt) = vm.pvpanic_evt() {
+                self.epoll
+                    .add_vm_event(panic_evt.as_raw_fd(), EpollDispatch::GuestPanic)
+                    .map_err(VmError::PvpanicEpoll)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reports the panics the guest reported through its pvpanic device, and
+    // applies the panic action of the VM. A guest about to run the crash
+    // kernel it loaded is left to it.
+    fn vm_guest_panic(&mut self) -> result::Result<(), VmError> {
+        let events = match self.vm {
+            Some(ref vm) => vm.take_pvpanic_events()?,
+            None => return Ok(()),
+        };
+        if events == 0 {
+            return Ok(());
+        }
+
+        let crash_loaded = events & devices::legacy::PVPANIC_CRASH_LOADED != 0;
+        let pvpanic = self
+            .vm_config
+            .as_ref()
+            .and_then(|config| config.pvpanic.as_ref());
+        let action = match pvpanic {
+            Some(pvpanic) if !crash_loaded => pvpanic.action,
+            _ => PanicAction::None,
+        };
+        warn!("The guest panicked, action: {:?}", action);
+        let mut details = serde_json::json!({ "crash_loaded": crash_loaded, "action": action });
+        if let Some(bundle) = self.diagnostic_bundle("guest-panic") {
+            details["bundle"] = serde_json::json!(bundle);
+        }
+        if let Some(ref mut event_monitor) = self.event_monitor {
+            event_monitor.report(
+                EventSource::Guest,
+                EventType::GuestPanic,
+                Some(details),
+                None,
+            );
+        }
+
+        match action {
+            PanicAction::None => {}
+            PanicAction::Pause => {
+                if let Some(ref mut vm) = self.vm {
+                    // The VM may have been paused in the meantime.
+                    if vm.get_state()? == VmState::Running {
+                        vm.pause()?;
+                        self.report_event(EventSource::Guest, EventType::Paused);
+                    }
+                }
+            }
+            PanicAction::Reboot => {
+                self.vm_reboot()?;
+                self.report_event(EventSource::Guest, EventType::Reboot);
+            }
+            // As if the guest shut itself down, for the VMM to exit.
+            PanicAction::Shutdown => self.exit_evt.write(1).map_err(VmError::EventFdWrite)?,
+        }
+
+        Ok(())
+    }
+
+    fn add_clock_drift_event(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(drift_evt) = vm.clock_drift_evt() {
+                self.epoll
+                    .add_vm_event(drift_evt.as_raw_fd(), EpollDispatch::ClockDrift)
+                    .map_err(VmError::ClockDriftEpoll)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Reports the guest clock drifting from the host clock past the
+    // threshold.
+    fn vm_clock_drift(&mut self) -> result::Result<(), VmError> {
+        let (drift, threshold) = match self.vm {
+            Some(ref vm) => match vm.take_clock_drift()? {
+                Some(clock_drift) => clock_drift,
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        warn!(
+            "The guest clock drifted {} ms from the host clock, past {} ms",
+            drift, threshold
+        );
+        if let Some(ref mut event_monitor) = self.event_monitor {
+            event_monitor.report(
+                EventSource::Guest,
+                EventType::ClockDrift,
+                Some(serde_json::json!({ "drift_ms": drift, "threshold_ms": threshold })),
+                None,
+            );
+        }
+
+        Ok(())
+    }
+
+    // A vCPU triple faulted, and the VM is about to reset.
+    fn vm_triple_fault(&mut self, vcpu: u8) {
+        warn!("vCPU {} triple faulted, rebooting the VM", vcpu);
+        let mut details = serde_json::json!({ "vcpu": vcpu });
+        if let Some(bundle) = self.diagnostic_bundle("triple-fault") {
+            details["bundle"] = serde_json::json!(bundle);
+        }
+        if let Some(ref mut event_monitor) = self.event_monitor {
+            event_monitor.report(
+                EventSource::Guest,
+                EventType::GuestTripleFault,
+                Some(details),
+                None,
+            );
+        }
+    }
+
+    // Writes a diagnostic bundle of the VM, returning its path, when the VM
+    // is configured with them. A bundle that can't be written must not
+    // bring the VMM down.
+    fn diagnostic_bundle(&self, reason: &str) -> Option<PathBuf> {
+        match self.vm.as_ref()?.diagnostic_bundle(reason) {
+            Ok(bundle) => bundle,
+            Err(e) => {
+                error!("Cannot write the diagnostic bundle: {:?}", e);
+                None
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn add_gdb_events(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(listener) = vm.gdb_listener() {
+                self.epoll
+                    .add_vm_event(listener.as_raw_fd(), EpollDispatch::GdbListener)
+                    .map_err(VmError::GdbEpoll)?;
+                self.epoll
+                    .add_vm_event(vm.debug_evt().as_raw_fd(), EpollDispatch::DebugStop)
+                    .map_err(VmError::GdbEpoll)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn add_gdb_events(&mut self) -> result::Result<(), VmError> {
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn accept_gdb(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Some(fd) = vm.accept_gdb()? {
+                self.epoll
+                    .add_vm_event(fd, EpollDispatch::Gdb)
+                    .map_err(VmError::GdbEpoll)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept_uart_socket(&mut self, index: usize) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(socket) = vm.uarts().get(index).and_then(|uart| uart.socket()) {
+                let fd = socket.accept().map_err(VmError::SerialSocketAccept)?;
+                let data = self
+                    .epoll
+                    .add_vm_event(fd, EpollDispatch::UartSocket(index))
+                    .map_err(VmError::SerialSocketEpoll)?;
+                socket.watch_output(self.epoll.as_raw_fd(), fd, data);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept_serial_socket(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(socket) = vm.serial_socket() {
+                let fd = socket.accept().map_err(VmError::SerialSocketAccept)?;
+                let data = self
+                    .epoll
+                    .add_vm_event(fd, EpollDispatch::SerialSocket)
+                    .map_err(VmError::SerialSocketEpoll)?;
+                socket.watch_output(self.epoll.as_raw_fd(), fd, data);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept_console_socket(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(socket) = vm.console_socket() {
+                let fd = socket.accept().map_err(VmError::SerialSocketAccept)?;
+                let data = self
+                    .epoll
+                    .add_vm_event(fd, EpollDispatch::ConsoleSocket)
+                    .map_err(VmError::SerialSocketEpoll)?;
+                socket.watch_output(self.epoll.as_raw_fd(), fd, data);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept_console_port_socket(&mut self, index: usize) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            if let Some(socket) = vm.console_port_sockets().get(index) {
+                let fd = socket.accept().map_err(VmError::SerialSocketAccept)?;
+                let data = self
+                    .epoll
+                    .add_vm_event(fd, EpollDispatch::ConsolePortSocket(index))
+                    .map_err(VmError::SerialSocketEpoll)?;
+                socket.watch_output(self.epoll.as_raw_fd(), fd, data);
+                vm.set_console_port_connected(index, true);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn vm_pause(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.pause()
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_resume(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.resume()
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_shutdown(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm.take() {
+            let result = vm.shutdown();
+            self.track_leftovers();
+            result
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_reboot(&mut self) -> result::Result<(), VmError> {
+        if self.vm.is_none() {
+            return Err(self.vm_not_running());
+        }
+
+        // Without ACPI, a reset is equivalent to a shutdown
+        #[cfg(not(all(feature = "acpi", target_arch = "x86_64")))]
+        {
+            if self.vm.is_some() {
+                return self.vm_shutdown();
+            }
+        }
+
+        // First we stop the current VM and create a new one.
+        if let Some(ref mut vm) = self.vm {
+            let config = vm.get_config();
+            self.vm_shutdown()?;
+
+            let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+            let vcpu_failure_evt = self
+                .vcpu_failure_evt
+                .try_clone()
+                .map_err(VmError::EventFdClone)?;
+
+            self.vm = Some(Vm::new(
+                config,
+                self.hypervisor.clone(),
+                exit_evt,
+                reset_evt,
+                vcpu_failure_evt,
+                &self.passed_fds,
+                &self.block_cache,
+            )?);
+            self.add_vm_events()?;
+        }
+
+        // Then we start the new VM.
+        if let Some(ref mut vm) = self.vm {
+            vm.boot()?;
+        } else {
+            return Err(VmError::VmNotCreated);
+        }
+        self.track_leftovers();
+
+        Ok(())
+    }
+
+    fn vm_power_button(&self) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.power_button()
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_set_sensors(&self, sensors: &VmSensors) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            vm.set_sensors(sensors)
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_quiesce(&mut self) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            vm.quiesce()
+        } else {
+            Err(self.vm_not_running())
+        }
+    }
+
+    fn vm_info(&self) -> result::Result<VmInfo, VmError> {
+        match &self.vm_config {
+            Some(config) => {
+                let state = match &self.vm {
+                    Some(vm) => vm.get_state()?,
+                    None => VmState::Created,
+                };
+                let serial_pty = self
+                    .vm
+                    .as_ref()
+                    .and_then(|vm| vm.serial_pty())
+                    .map(|pty| pty.path.clone());
+                let console_pty = self
+                    .vm
+                    .as_ref()
+                    .and_then(|vm| vm.console_pty())
+                    .map(|pty| pty.path.clone());
+                let uart_ptys = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| {
+                        vm.uarts()
+                            .iter()
+                            .map(|uart| uart.pty().map(|pty| pty.path.clone()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                let vcpu_failures = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.vcpu_failures())
+                    .unwrap_or_default();
+                let idle = self.vm.as_ref().map(|vm| vm.idle()).unwrap_or(false);
+                let hibernated = self.vm.as_ref().map(|vm| vm.hibernated()).unwrap_or(false);
+                let memory_backing = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.memory_manager().lock().unwrap().ram_backing())
+                    .unwrap_or_default();
+                let memory_actual_size = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.memory_manager().lock().unwrap().ram_size())
+                    .unwrap_or(0);
+                let pci_devices = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.pci_devices().clone())
+                    .unwrap_or_default();
+                let guest_os = self.vm.as_ref().and_then(|vm| vm.guest_os());
+                let clock_drift_ms = self.vm.as_ref().and_then(|vm| vm.clock_drift());
+                let sriov_vfs = self
+                    .vm
+                    .as_ref()
+                    .map(|vm| vm.sriov_vfs())
+                    .unwrap_or_default();
+
+                Ok(VmInfo {
+                    config: Arc::clone(config),
+                    state,
+                    serial_pty,
+                    console_pty,
+                    uart_ptys,
+                    vcpu_failures,
+                    idle,
+                    hibernated,
+                    memory_backing,
+                    memory_actual_size,
+                    pci_devices,
+                    guest_os,
+                    clock_drift_ms,
+                    sriov_vfs,
+                })
+            }
+            None => Err(VmError::VmNotCreated),
+        }
+    }
+
+    fn vmm_capabilities(&self) -> result::Result<VmmCapabilities, VmError> {
+        let used_memory_slots = self
+            .vm
+            .as_ref()
+            .map(|vm| vm.memory_manager().lock().unwrap().used_kvm_slots());
+
+        Ok(VmmCapabilities {
+            max_memory_slots: self.hypervisor.get_max_memory_slots(),
+            used_memory_slots,
+        })
+    }
+
+    fn vmm_fds(&self) -> io::Result<Vec<FdInfo>> {
+        let mut fds = Vec::new();
+        for entry in std::fs::read_dir("/proc/self/fd")? {
+            let entry = entry?;
+            let fd = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(fd) => fd,
+                None => continue,
+            };
+            // The descriptor used by read_dir() is gone by now.
+            if let Ok(target) = std::fs::read_link(entry.path()) {
+                fds.push(FdInfo { fd, target });
+            }
+        }
+        fds.sort_by_key(|f| f.fd);
+
+        Ok(fds)
+    }
+
+    fn vmm_host_resources(&self) -> Vec<HostResource> {
+        match &self.host_resources {
+            Some(host_resources) => host_resources.held(),
+            None => Vec::new(),
+        }
+    }
+
+    // The host resources are held from the VM creation to its deletion, the
+    // VM keeps them across reboots. The files of the VM are labeled before
+    // the VMM thread is confined, which may take the rights to relabel them
+    // away from it.
+    fn vm_create(&mut self, mut config: Arc<VmConfig>) -> result::Result<(), VmError> {
+        // A configuration given through the API doesn't go through parsing.
+        config.validate().map_err(VmError::InvalidConfig)?;
+        Arc::make_mut(&mut config).assign_device_ids();
+
+        if let Some(registry) = &mut self.host_resources {
+            let resources =
+                host_resources::vm_host_resources(&config).map_err(VmError::HostResources)?;
+            registry
+                .reserve(&resources)
+                .map_err(VmError::HostResources)?;
+        }
+
+        if let Err(e) = security::label_files(&config, &self.passed_fds)
+            .and_then(|_| security::confine(&config))
+        {
+            if let Some(registry) = &mut self.host_resources {
+                registry.release().map_err(VmError::HostResources)?;
+            }
+            return Err(VmError::Security(e));
+        }
+
+        self.vm_config = Some(config);
+
+        Ok(())
+    }
+
+    fn vm_delete(&mut self) -> result::Result<(), VmError> {
+        if self.vm_config.is_none() {
+            return Ok(());
+        }
+
+        // First we try to shut the current VM down, if it was booted.
+        if self.vm.is_some() {
+            self.vm_shutdown()?;
+        }
+
+        self.vm_config = None;
+
+        if let Some(host_resources) = &mut self.host_resources {
+            host_resources.release().map_err(VmError::HostResources)?;
+        }
+
+        Ok(())
+    }
+
+    fn vmm_shutdown(&mut self) -> result::Result<(), VmError> {
+        self.shutdown_vms();
+        self.vm_delete()
+    }
+
+    fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiMessage>>) -> Result<()> {
+        const EPOLL_EVENTS_LEN: usize = 100;
+
+        let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
+        let epoll_fd = self.epoll.as_raw_fd();
+
+        'outer: loop {
+            let num_events = match epoll::wait(epoll_fd, -1, &mut events[..]) {
+                Ok(res) => res,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        // It's well defined from the epoll_wait() syscall
+                        // documentation that the epoll loop can be interrupted
+                        // before any of the requested events occurred or the
+                        // timeout expired. In both those cases, epoll_wait()
+                        // returns an error of type EINTR, but this should not
+                        // be considered as a regular error. Instead it is more
+                        // appropriate to retry, by calling into epoll_wait().
+                        continue;
+                    }
+                    return Err(Error::Epoll(e));
+                }
+            };
+
+            for event in events.iter().take(num_events) {
+                let dispatch_idx = event.data as usize;
+                let event_set = epoll::Events::from_bits_truncate(event.events);
+
+                if let Some(dispatch_type) = self.epoll.dispatch_table[dispatch_idx] {
+                    match dispatch_type {
+                        EpollDispatch::Exit => {
+                            // Consume the event.
+                            self.exit_evt.read().map_err(Error::EventFdRead)?;
+                            // Only the default VM takes the process down with it,
+                            // the other ones are deleted.
+                            if self.vm_id.is_some() {
+                                self.vm_delete().map_err(Error::VmShutdown)?;
+                                self.report_event(EventSource::Guest, EventType::Shutdown);
+                                continue;
+                            }
+                            self.vmm_shutdown().map_err(Error::VmmShutdown)?;
+                            self.report_event(EventSource::Guest, EventType::Shutdown);
+
+                            break 'outer;
+                        }
+                        EpollDispatch::Reset => {
+                            // Consume the event.
+                            self.reset_evt.read().map_err(Error::EventFdRead)?;
+                            if let Some(vcpu) = self.vm.as_ref().and_then(Vm::take_triple_fault) {
+                                self.vm_triple_fault(vcpu);
+                            }
+                            self.vm_reboot().map_err(Error::VmReboot)?;
+                            self.report_event(EventSource::Guest, EventType::Reboot);
+                        }
+                        EpollDispatch::VcpuFailure => {
+                            // Consume the event, which counts the failures.
+                            let count = self.vcpu_failure_evt.read().map_err(Error::EventFdRead)?;
+                            // A failing guest must not bring the VMM down.
+                            if let Err(e) = self.vm_vcpu_failure(count as usize) {
+                                error!("Cannot pause the VM after a vCPU failure: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::Stdin => {
+                            if let Some(ref vm) = self.vm {
+                                vm.handle_stdin().map_err(Error::Stdin)?;
+                            }
+                        }
+                        EpollDispatch::SerialPty => {
+                            if let Some(ref vm) = self.vm {
+                                vm.handle_serial_pty().map_err(Error::Pty)?;
+                            }
+                        }
+                        EpollDispatch::ConsolePty => {
+                            if let Some(ref vm) = self.vm {
+                                vm.handle_console_pty().map_err(Error::Pty)?;
+                            }
+                        }
+                        EpollDispatch::SerialSocketListener => {
+                            // A failing client must not bring the VMM down.
+                            if let Err(e) = self.accept_serial_socket() {
+                                warn!("Cannot accept serial socket connection: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::SerialSocket => {
+                            if let Some(ref vm) = self.vm {
+                                if event_set.contains(epoll::Events::EPOLLOUT) {
+                                    if let Some(socket) = vm.serial_socket() {
+                                        socket.write_output();
+                                    }
+                                }
+                                if socket_input(event_set) {
+                                    if let Err(e) = vm.handle_serial_socket() {
+                                        warn!("Cannot handle serial socket input: {:?}", e);
+                                    }
+                                }
+                            }
+                        }
+                        EpollDispatch::ConsoleSocketListener => {
+                            // A failing client must not bring the VMM down.
+                            if let Err(e) = self.accept_console_socket() {
+                                warn!("Cannot accept console socket connection: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::ConsoleSocket => {
+                            if let Some(ref vm) = self.vm {
+                                if event_set.contains(epoll::Events::EPOLLOUT) {
+                                    if let Some(socket) = vm.console_socket() {
+                                        socket.write_output();
+                                    }
+                                }
+                                if socket_input(event_set) {
+                                    if let Err(e) = vm.handle_console_socket() {
+                                        warn!("Cannot handle console socket input: {:?}", e);
+                                    }
+                                }
+                            }
+                        }
+                        EpollDispatch::ConsolePortSocketListener(index) => {
+                            // A failing client must not bring the VMM down.
+                            if let Err(e) = self.accept_console_port_socket(index) {
+                                warn!("Cannot accept console port socket connection: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::ConsolePortSocket(index) => {
+                            if let Some(ref vm) = self.vm {
+                                if event_set.contains(epoll::Events::EPOLLOUT) {
+                                    if let Some(socket) = vm.console_port_sockets().get(index) {
+                                        socket.write_output();
+                                    }
+                                }
+                                if socket_input(event_set) {
+                                    if let Err(e) = vm.handle_console_port_socket(index) {
+                                        warn!("Cannot handle console port socket input: {:?}", e);
+                                    }
+                                }
+                            }
+                        }
+                        EpollDispatch::UartPty(index) => {
+                            if let Some(ref vm) = self.vm {
+                                vm.handle_uart_pty(index).map_err(Error::Pty)?;
+                            }
+                        }
+                        EpollDispatch::UartSocketListener(index) => {
+                            // A failing client must not bring the VMM down.
+                            if let Err(e) = self.accept_uart_socket(index) {
+                                warn!("Cannot accept UART socket connection: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::UartSocket(index) => {
+                            if let Some(ref vm) = self.vm {
+                                if event_set.contains(epoll::Events::EPOLLOUT) {
+                                    if let Some(socket) =
+                                        vm.uarts().get(index).and_then(|uart| uart.socket())
+                                    {
+                                        socket.write_output();
+                                    }
+                                }
+                                if socket_input(event_set) {
+                                    if let Err(e) = vm.handle_uart_socket(index) {
+                                        warn!("Cannot handle UART socket input: {:?}", e);
+                                    }
+                                }
+                            }
+                        }
+                        #[cfg(target_arch = "x86_64")]
+                        EpollDispatch::GdbListener => {
+                            // A failing debugger must not bring the VMM down.
+                            if let Err(e) = self.accept_gdb() {
+                                warn!("Cannot accept GDB connection: {:?}", e);
+                            }
+                        }
+                        #[cfg(target_arch = "x86_64")]
+                        EpollDispatch::Gdb => {
+                            if let Some(ref mut vm) = self.vm {
+                                if let Err(e) = vm.handle_gdb() {
+                                    warn!("Cannot handle GDB packets: {:?}", e);
+                                }
+                            }
+                        }
+                        #[cfg(target_arch = "x86_64")]
+                        EpollDispatch::DebugStop => {
+                            if let Some(ref mut vm) = self.vm {
+                                if let Err(e) = vm.handle_debug_stop() {
+                                    warn!("Cannot stop the VM for GDB: {:?}", e);
+                                }
+                            }
+                        }
+                        EpollDispatch::DiskOutOfSpace => {
+                            // A full disk must not bring the VMM down.
+                            if let Err(e) = self.vm_disks_out_of_space() {
+                                error!("Cannot handle the disks out of space: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::GuestPanic => {
+                            if let Err(e) = self.vm_guest_panic() {
+                                error!("Cannot handle the guest panic: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::ClockDrift => {
+                            if let Err(e) = self.vm_clock_drift() {
+                                error!("Cannot handle the guest clock drift: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::PciEject => {
+                            if let Err(e) = self.vm_eject_devices() {
+                                error!("Cannot remove the devices the guest ejected: {:?}", e);
+                            }
+                        }
+                        EpollDispatch::PoolAgent => {
+                            // Consume the event.
+                            if let Some(pool_agent) = &self.pool_agent {
+                                pool_agent.ready_evt.read().map_err(Error::EventFdRead)?;
+                            }
+                            if let Err(e) = self.vm_pool_ready() {
+                                error!("Cannot pause the VM of the pool: {:?}", e);
+                            }
+                        }
+                        #[cfg(target_arch = "aarch64")]
+                        EpollDispatch::GdbListener
+                        | EpollDispatch::Gdb
+                        | EpollDispatch::DebugStop => {}
+                        EpollDispatch::Api => {
+                            // Consume the event.
+                            self.api_evt.read().map_err(Error::EventFdRead)?;
+
+                            // Read from the API receiver channel
+                            let ApiMessage {
+                                request,
+                                request_id,
+                                vm_id,
+                                client,
+                            } = api_receiver.recv().map_err(Error::ApiRequestRecv)?;
+                            if let Some(vm_id) = vm_id {
+                                let message = ApiMessage {
+                                    request,
+                                    request_id,
+                                    vm_id: None,
+                                    client: ApiClient::Local,
+                                };
+                                self.forward_request(vm_id, message, &client)?;
+                                continue;
+                            }
+                            // In multi-tenant mode, any owner claims a VM of
+                            // the pool, the other requests about the process
+                            // being the admin's.
+                            let owner = match (&self.tenancy, &request) {
+                                (Some(tenancy), ApiRequest::VmClaim(..)) => tenancy.owner(&client),
+                                (Some(tenancy), _) => {
+                                    tenancy.authorize(&client, None).map(|_| None)
+                                }
+                                (None, _) => Ok(None),
+                            };
+                            let owner = match owner {
+                                Ok(owner) => owner,
+                                Err(e) => {
+                                    request
+                                        .response_sender()
+                                        .send(Err(e))
+                                        .map_err(Error::ApiResponseSend)?;
+                                    continue;
+                                }
+                            };
+                            if let Some(ref request_id) = request_id {
+                                debug!("Handling API request {:?}", request_id);
+                            }
+                            self.request_id = request_id;
+
+                            match request {
+                                ApiRequest::VmCreate(config, sender) => {
+                                    // We only store the passed VM config.
+                                    // The VM will be created when being asked to boot it.
+                                    let response = if self.vm_config.is_none() {
+                                        self.vm_create(config)
+                                            .map_err(ApiError::VmCreate)
+                                            .map(|_| ApiResponsePayload::Empty)
+                                    } else {
+                                        Err(ApiError::VmAlreadyCreated)
+                                    };
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDelete(sender) => {
+                                    let response = self
+                                        .vm_delete()
+                                        .map_err(ApiError::VmDelete)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmBoot(sender) => {
                                     // If we don't have a config, we can not boot a VM.
                                     if self.vm_config.is_none() {
                                         sender
@@ -406,6 +2275,9 @@ impl Vmm {
                                         .vm_boot()
                                         .map_err(ApiError::VmBoot)
                                         .map(|_| ApiResponsePayload::Empty);
+                                    if response.is_ok() {
+                                        self.report_event(EventSource::Api, EventType::Booted);
+                                    }
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
@@ -414,6 +2286,9 @@ impl Vmm {
                                         .vm_shutdown()
                                         .map_err(ApiError::VmShutdown)
                                         .map(|_| ApiResponsePayload::Empty);
+                                    if response.is_ok() {
+                                        self.report_event(EventSource::Api, EventType::Shutdown);
+                                    }
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
@@ -422,6 +2297,163 @@ impl Vmm {
                                         .vm_reboot()
                                         .map_err(ApiError::VmReboot)
                                         .map(|_| ApiResponsePayload::Empty);
+                                    if response.is_ok() {
+                                        self.report_event(EventSource::Api, EventType::Reboot);
+                                    }
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmPowerButton(sender) => {
+                                    let response = self
+                                        .vm_power_button()
+                                        .map_err(ApiError::VmPowerButton)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmQuiesce(sender) => {
+                                    let response = self
+                                        .vm_quiesce()
+                                        .map_err(ApiError::VmQuiesce)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    if response.is_ok() {
+                                        self.report_event(EventSource::Api, EventType::Paused);
+                                    }
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetSensors(sensors, sender) => {
+                                    let response = self
+                                        .vm_set_sensors(&sensors)
+                                        .map_err(ApiError::VmSetSensors)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmCoredump(data, sender) => {
+                                    let response = self
+                                        .vm_coredump(&data.destination)
+                                        .map_err(ApiError::VmCoredump)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmResetDevice(data, sender) => {
+                                    let response = self
+                                        .vm_reset_device(&data.id)
+                                        .map_err(ApiError::VmResetDevice)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDeviceAudit(sender) => {
+                                    let response = self
+                                        .vm_device_audit()
+                                        .map_err(ApiError::VmDeviceAudit)
+                                        .map(ApiResponsePayload::VmDeviceAudit);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetDiskWeight(data, sender) => {
+                                    let response = self
+                                        .vm_set_disk_weight(&data.id, data.weight)
+                                        .map_err(ApiError::VmSetDiskWeight)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDirtyRate(data, sender) => {
+                                    // Answered by the thread measuring it,
+                                    // unless it can't be started.
+                                    if let Err(e) =
+                                        self.vm_dirty_rate(data.duration_ms, sender.clone())
+                                    {
+                                        sender
+                                            .send(Err(ApiError::VmDirtyRate(e)))
+                                            .map_err(Error::ApiResponseSend)?;
+                                    }
+                                }
+                                ApiRequest::VmScreenshot(data, sender) => {
+                                    let response = self
+                                        .vm_screenshot(&data.destination)
+                                        .map_err(ApiError::VmScreenshot)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmThrottle(data, sender) => {
+                                    let response = self
+                                        .vm_throttle(data.as_deref())
+                                        .map_err(ApiError::VmThrottle)
+                                        .map(ApiResponsePayload::VmThrottle);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddVsock(vsock_cfg, sender) => {
+                                    let response = self
+                                        .vm_add_vsock(&vsock_cfg)
+                                        .map_err(ApiError::VmAddDevice)
+                                        .map(|info| match info {
+                                            Some(info) => ApiResponsePayload::PciDeviceInfo(info),
+                                            None => ApiResponsePayload::Empty,
+                                        });
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddUserDevice(user_device_cfg, sender) => {
+                                    let response = self
+                                        .vm_add_user_device(&user_device_cfg)
+                                        .map_err(ApiError::VmAddDevice)
+                                        .map(|info| match info {
+                                            Some(info) => ApiResponsePayload::PciDeviceInfo(info),
+                                            None => ApiResponsePayload::Empty,
+                                        });
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmRemoveDevice(data, sender) => {
+                                    let response = self
+                                        .vm_remove_device(&data.id)
+                                        .map_err(ApiError::VmRemoveDevice)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmApply(config, sender) => {
+                                    let response = self
+                                        .vm_apply(&config)
+                                        .map_err(ApiError::VmApply)
+                                        .map(ApiResponsePayload::VmApply);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmUpdate(update, sender) => {
+                                    let response = self
+                                        .vm_update(&update)
+                                        .map_err(ApiError::VmUpdate)
+                                        .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmClaim(claim, sender) => {
+                                    // The default VM hands a VM of the pool
+                                    // out, which gets claimed in its thread.
+                                    let response = if self.vm_id.is_none() {
+                                        self.vm_claim(claim, owner)
+                                    } else {
+                                        self.vm_claim_pooled(&claim)
+                                    }
+                                    .map(|_| ApiResponsePayload::Empty);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmmPool(data, sender) => {
+                                    let response = if self.vm_id.is_none() {
+                                        self.vmm_pool(&data)
+                                    } else {
+                                        Err(ApiError::VmNotPooled)
+                                    }
+                                    .map(|_| ApiResponsePayload::Empty);
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
@@ -438,6 +2470,9 @@ impl Vmm {
                                         .vm_pause()
                                         .map_err(ApiError::VmPause)
                                         .map(|_| ApiResponsePayload::Empty);
+                                    if response.is_ok() {
+                                        self.report_event(EventSource::Api, EventType::Paused);
+                                    }
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
@@ -446,6 +2481,32 @@ impl Vmm {
                                         .vm_resume()
                                         .map_err(ApiError::VmResume)
                                         .map(|_| ApiResponsePayload::Empty);
+                                    if response.is_ok() {
+                                        self.report_event(EventSource::Api, EventType::Resumed);
+                                    }
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmmCapabilities(sender) => {
+                                    let response = self
+                                        .vmm_capabilities()
+                                        .map_err(ApiError::VmmCapabilities)
+                                        .map(ApiResponsePayload::VmmCapabilities);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmmFds(sender) => {
+                                    let response = self
+                                        .vmm_fds()
+                                        .map_err(ApiError::VmmFds)
+                                        .map(ApiResponsePayload::VmmFds);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmmHostResources(sender) => {
+                                    let response = Ok(ApiResponsePayload::VmmHostResources(
+                                        self.vmm_host_resources(),
+                                    ));
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
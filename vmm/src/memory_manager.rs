@@ -0,0 +1,1214 @@
+// Copyright © 2019 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use crate::config::{HugepagesFallback, MemoryConfig, MemoryZoneConfig, NumaConfig, SgxEpcConfig};
+use crate::host_resources;
+use hypervisor::{UserMemoryRegion, KVM_MEM_LOG_DIRTY_PAGES};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Instant;
+use vm_device::MemoryListener;
+use vm_memory::guest_memory::FileOffset;
+use vm_memory::{
+    Address, Bytes, Error as MmapError, GuestAddress, GuestMemoryError, GuestMemoryMmap,
+    GuestMemoryRegion, GuestRegionMmap, MemoryRegionAddress, MmapRegion,
+};
+
+/// Errors associated with guest memory management
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create shared file.
+    SharedFileCreate(io::Error),
+
+    /// Failed to set shared file length.
+    SharedFileSetLen(io::Error),
+
+    /// Cannot mmap a guest RAM region.
+    MmapRegion(io::Error),
+
+    /// Mmap backed guest memory error
+    GuestMemory(MmapError),
+
+    /// Cannot register a memory region with KVM.
+    SetUserMemoryRegion(io::Error),
+
+    /// The RAM region overlaps an existing one.
+    RegionOverlap(GuestAddress),
+
+    /// No RAM region starts at this guest address.
+    RegionNotFound(GuestAddress),
+
+    /// A memory listener failed to handle a new RAM region.
+    ListenerRegionAdded(io::Error),
+
+    /// A memory listener failed to handle a RAM region removal.
+    ListenerRegionRemoved(io::Error),
+
+    /// All the KVM memory slots are in use.
+    KvmSlotsExhausted(u32),
+
+    /// The NUMA nodes memory does not add up to the guest RAM size.
+    NumaMemorySize,
+
+    /// Cannot bind a NUMA node memory to its host node.
+    NumaBind(io::Error),
+
+    /// The memory zones do not add up to the guest RAM size.
+    MemoryZonesSize,
+
+    /// A file backed memory zone can only be shared.
+    PrivateFileBacking,
+
+    /// Cannot create an anonymous shared or huge pages memory file.
+    MemfdCreate(io::Error),
+
+    /// The backing file of a huge pages zone is not on hugetlbfs.
+    NotHugetlbfs(PathBuf),
+
+    /// Cannot find out the default huge page size of the host.
+    DefaultHugepageSize(io::Error),
+
+    /// Cannot find out how many huge pages of a size the host has left.
+    HostHugepages(u64, io::Error),
+
+    /// The host doesn't have enough huge pages of a size left: size,
+    /// needed count and count left.
+    NotEnoughHugepages(u64, u64, u64),
+
+    /// The firmware image, of this size, does not fit in the firmware region.
+    FirmwareTooLarge(usize),
+
+    /// Cannot copy the firmware image to the firmware region.
+    FirmwareLoad(GuestMemoryError),
+
+    /// Cannot open the SGX virtual EPC device.
+    SgxVepcOpen(io::Error),
+
+    /// The SGX EPC sections are already set up.
+    SgxEpcAlreadySetUp,
+
+    /// RAM can't be hotplugged into a guest with private memory.
+    PrivateMemoryHotplug,
+
+    /// Cannot spawn a thread prefaulting the guest RAM.
+    PrefaultThreadSpawn(io::Error),
+
+    /// Cannot advise the guest RAM mergeable, e.g. the host having no KSM.
+    Mergeable(io::Error),
+
+    /// The pages the guest dirties are already being logged.
+    DirtyLogActive,
+
+    /// The pages the guest dirties are not being logged.
+    DirtyLogInactive,
+
+    /// The writes of a guest to its private memory can't be logged.
+    PrivateMemoryDirtyLog,
+
+    /// Cannot read the pages the guest dirtied, e.g. the hypervisor not
+    /// logging them.
+    GetDirtyLog(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+// From <linux/mempolicy.h>
+const MPOL_BIND: u64 = 2;
+const MPOL_MF_MOVE: u64 = 1 << 1;
+
+// From <linux/memfd.h>
+const MFD_CLOEXEC: u32 = 1 << 0;
+const MFD_HUGETLB: u32 = 1 << 2;
+const MFD_HUGE_SHIFT: u32 = 26;
+
+// From <linux/magic.h>
+const HUGETLBFS_MAGIC: i64 = 0x9584_58f6;
+
+const THP_SYSFS_PATH: &str = "/sys/kernel/mm/transparent_hugepage";
+
+const SGX_VEPC_PATH: &str = "/dev/sgx_vepc";
+const SGX_EPC_PAGE_SIZE: usize = 0x1000;
+
+// The guest RAM is prefaulted in chunks of this size, which each thread
+// takes the next one of, so that the threads finish at about the same time
+// whatever the sizes of the regions.
+const PREFAULT_CHUNK_SIZE: usize = 1 << 30;
+// Past this many threads, the page faults contend on the memory of the VMM
+// process rather than going faster.
+const MAX_PREFAULT_THREADS: usize = 32;
+
+/// SGX EPC section of the guest, backed by host EPC pages.
+#[derive(Clone, Copy, Debug)]
+pub struct SgxEpcSection {
+    pub start: GuestAddress,
+    pub size: u64,
+}
+
+/// Pages backing a guest RAM region.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum PageBacking {
+    /// Pages of the host page size.
+    Small,
+    /// Transparent huge pages, that the host uses whenever it can.
+    TransparentHugepages,
+    /// Huge pages of this size, in bytes.
+    Hugepages(u64),
+}
+
+/// Backing a guest RAM region got from the host.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RamBacking {
+    pub start: u64,
+    pub size: u64,
+    pub backing: PageBacking,
+    /// The huge pages of the region zone fell back to this backing.
+    pub fallback: bool,
+}
+
+/// Guest RAM range belonging to a NUMA node.
+pub struct NumaMemoryRange {
+    pub node: u32,
+    pub start: GuestAddress,
+    pub size: u64,
+    host_addr: u64,
+}
+
+struct RamRegion {
+    region: Arc<GuestRegionMmap>,
+    slot: u32,
+    // Temporary file created to back this region, removed along with it.
+    temp_file: Option<PathBuf>,
+    backing: PageBacking,
+    fallback: bool,
+}
+
+/// The MemoryManager is the single place where guest RAM gets registered.
+/// KVM memory slots and the registered memory listeners are kept in sync
+/// with the guest memory each time a RAM region is added or removed.
+pub struct MemoryManager {
+    guest_memory: Arc<RwLock<GuestMemoryMmap>>,
+    vm: Arc<dyn hypervisor::Vm>,
+    // Backing of the hotplugged RAM regions.
+    hotplug_zone: MemoryZoneConfig,
+    // Guest RAM regions, indexed by their guest physical start address.
+    ram_regions: BTreeMap<u64, RamRegion>,
+    // Region the firmware image is mapped in, if the guest boots one.
+    firmware_region: Option<RamRegion>,
+    // SGX EPC sections, which are not guest RAM either.
+    sgx_epc_regions: Vec<RamRegion>,
+    // KVM memory slots in use, out of the max_kvm_slots KVM supports.
+    kvm_slots: BTreeSet<u32>,
+    max_kvm_slots: u32,
+    listeners: Vec<Arc<dyn MemoryListener>>,
+    numa_ranges: Vec<NumaMemoryRange>,
+    // The regions are private to a confidential guest, their mappings only
+    // backing the pages it shares with the host.
+    private_memory: bool,
+    // The host allocates the pages of the RAM regions as they are created,
+    // rather than as the guest first touches them.
+    prefault: bool,
+    // The RAM regions are advised mergeable, for KSM to merge their pages.
+    mergeable: bool,
+    // The RAM regions are advised to be backed by transparent huge pages,
+    // or not to be, rather than following the host policy.
+    thp: Option<bool>,
+    // The hypervisor logs the pages of the RAM regions the guest writes to.
+    dirty_log: bool,
+}
+
+impl MemoryManager {
+    pub fn new(
+        vm: Arc<dyn hypervisor::Vm>,
+        max_kvm_slots: u32,
+        config: &MemoryConfig,
+        ram_regions: &[(GuestAddress, usize)],
+        numa: &[NumaConfig],
+        private_memory: bool,
+    ) -> Result<Self> {
+        let mut regions = BTreeMap::new();
+        if let Err(e) = MemoryManager::create_zones_regions(
+            &config.zones(),
+            ram_regions,
+            max_kvm_slots,
+            &mut regions,
+        )
+        .and_then(|_| {
+            regions.values_mut().try_for_each(|ram_region| {
+                MemoryManager::advise_ram_region(ram_region, config.mergeable, config.thp)
+            })
+        }) {
+            for ram_region in regions.values() {
+                MemoryManager::remove_temp_file(&ram_region.temp_file);
+            }
+            return Err(e);
+        }
+        let slots = regions.len() as u32;
+
+        let guest_memory = GuestMemoryMmap::from_arc_regions(
+            regions.values().map(|r| r.region.clone()).collect(),
+        )
+        .map_err(Error::GuestMemory)?;
+
+        let numa_ranges = MemoryManager::split_numa_ranges(&regions, numa)?;
+        for range in numa_ranges.iter() {
+            let node = numa.iter().find(|node| node.id == range.node).unwrap();
+            if let Some(host_node) = node.host_node {
+                MemoryManager::bind_to_host_node(range, host_node)?;
+            }
+        }
+
+        let memory_manager = MemoryManager {
+            guest_memory: Arc::new(RwLock::new(guest_memory)),
+            vm,
+            hotplug_zone: config.hotplug_zone(),
+            ram_regions: regions,
+            firmware_region: None,
+            sgx_epc_regions: Vec::new(),
+            kvm_slots: (0..slots).collect(),
+            max_kvm_slots,
+            listeners: Vec::new(),
+            numa_ranges,
+            private_memory,
+            prefault: config.prefault,
+            mergeable: config.mergeable,
+            thp: config.thp,
+            dirty_log: false,
+        };
+
+        // Once the NUMA nodes are bound, for the pages to be allocated on
+        // their host nodes.
+        if memory_manager.prefault {
+            let regions: Vec<&RamRegion> = memory_manager.ram_regions.values().collect();
+            MemoryManager::prefault_regions(&regions)?;
+        }
+
+        for ram_region in memory_manager.ram_regions.values() {
+            memory_manager.set_kvm_region(ram_region, false)?;
+        }
+
+        Ok(memory_manager)
+    }
+
+    // Lay the memory zones out over the guest RAM ranges, in order. A zone
+    // gets one RAM region per range it overlaps, e.g. when it crosses the
+    // 32-bit memory hole.
+    fn create_zones_regions(
+        zones: &[MemoryZoneConfig],
+        ram_regions: &[(GuestAddress, usize)],
+        max_kvm_slots: u32,
+        regions: &mut BTreeMap<u64, RamRegion>,
+    ) -> Result<()> {
+        let mut zones = zones.iter().filter(|zone| zone.size > 0);
+        let mut zone = zones.next();
+        let mut zone_offset = 0;
+        for &(range_start, range_size) in ram_regions.iter() {
+            let mut range_offset = 0;
+            while range_offset < range_size as u64 {
+                let current = zone.ok_or(Error::MemoryZonesSize)?;
+                let slot = regions.len() as u32;
+                if slot >= max_kvm_slots {
+                    return Err(Error::KvmSlotsExhausted(max_kvm_slots));
+                }
+
+                let size =
+                    std::cmp::min(current.size - zone_offset, range_size as u64 - range_offset);
+                let start = range_start.unchecked_add(range_offset);
+                let ram_region = MemoryManager::create_ram_region(
+                    current,
+                    zone_offset,
+                    start,
+                    size as usize,
+                    slot,
+                )?;
+                regions.insert(start.raw_value(), ram_region);
+
+                range_offset += size;
+                zone_offset += size;
+                if zone_offset == current.size {
+                    zone = zones.next();
+                    zone_offset = 0;
+                }
+            }
+        }
+
+        if zone.is_some() {
+            return Err(Error::MemoryZonesSize);
+        }
+
+        Ok(())
+    }
+
+    // Anonymous shared or huge pages memory is backed by a memfd, as
+    // MmapRegion only maps files as shared.
+    // Without an explicit huge page size, the host default one is used.
+    fn create_memfd(hugepages: bool, hugepage_size: Option<u64>) -> Result<File> {
+        let name = std::ffi::CString::new("ch_ram").unwrap();
+        let mut flags = MFD_CLOEXEC;
+        if hugepages {
+            flags |= MFD_HUGETLB;
+            if let Some(hugepage_size) = hugepage_size {
+                flags |= hugepage_size.trailing_zeros() << MFD_HUGE_SHIFT;
+            }
+        }
+
+        // Safe because the name is a valid C string, and the returned fd is
+        // checked.
+        let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(Error::MemfdCreate(io::Error::last_os_error()));
+        }
+
+        // Safe because the fd was just created and is owned by no one else.
+        Ok(unsafe { File::from_raw_fd(fd as i32) })
+    }
+
+    // When backed by a directory, a temporary file is created for the
+    // region. It is only removed along with the region, as a shared mapping
+    // of an unlinked file could not be restored by checkpoint/restore tools
+    // such as CRIU. A regular backing file is mapped at the region offset
+    // within its zone.
+    fn create_ram_region(
+        zone: &MemoryZoneConfig,
+        zone_offset: u64,
+        start: GuestAddress,
+        size: usize,
+        slot: u32,
+    ) -> Result<RamRegion> {
+        if zone.file.is_some() && !zone.shared {
+            return Err(Error::PrivateFileBacking);
+        }
+
+        let mut temp_file = None;
+        let mut backing = PageBacking::Small;
+        let mut fallback = false;
+        let mmap_region = match zone.file {
+            Some(ref file) => {
+                let f = if file.is_dir() {
+                    let fs_str = format!("{}{}", file.display(), "/tmpfile_XXXXXX");
+                    let fs = std::ffi::CString::new(fs_str).unwrap();
+                    let mut path = fs.as_bytes_with_nul().to_owned();
+                    let path_ptr = path.as_mut_ptr() as *mut _;
+                    let fd = unsafe { libc::mkstemp(path_ptr) };
+                    if fd < 0 {
+                        return Err(Error::SharedFileCreate(io::Error::last_os_error()));
+                    }
+                    temp_file = Some(PathBuf::from(OsStr::from_bytes(&path[..path.len() - 1])));
+
+                    unsafe { File::from_raw_fd(fd) }
+                } else {
+                    OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(file)
+                        .map_err(Error::SharedFileCreate)?
+                };
+
+                let hugepage_size = MemoryManager::hugetlbfs_page_size(&f);
+                if zone.hugepages && hugepage_size.is_none() {
+                    MemoryManager::remove_temp_file(&temp_file);
+                    return Err(Error::NotHugetlbfs(file.clone()));
+                }
+                backing = hugepage_size.map_or(PageBacking::Small, PageBacking::Hugepages);
+
+                let offset = if temp_file.is_some() { 0 } else { zone_offset };
+                let len = f.metadata().map(|m| m.len()).unwrap_or(0);
+                if len < offset + size as u64 {
+                    if let Err(e) = f.set_len(offset + size as u64) {
+                        MemoryManager::remove_temp_file(&temp_file);
+                        return Err(Error::SharedFileSetLen(e));
+                    }
+                }
+
+                MmapRegion::from_fd(FileOffset::new(f, offset), size)
+            }
+            None if zone.hugepages && zone.hugepages_fallback != HugepagesFallback::None => {
+                let (mmap_region, hugepages_backing, hugepages_fallback) =
+                    MemoryManager::create_hugepages_fallback_mapping(zone, size)?;
+                backing = hugepages_backing;
+                fallback = hugepages_fallback;
+
+                Ok(mmap_region)
+            }
+            None if zone.shared || zone.hugepages => {
+                let f = MemoryManager::create_memfd(zone.hugepages, zone.hugepage_size)?;
+                f.set_len(size as u64).map_err(Error::SharedFileSetLen)?;
+                backing = MemoryManager::hugetlbfs_page_size(&f)
+                    .map_or(PageBacking::Small, PageBacking::Hugepages);
+
+                MmapRegion::from_fd(FileOffset::new(f, 0), size)
+            }
+            None => MmapRegion::new(size),
+        };
+
+        match mmap_region {
+            Ok(mmap_region) => Ok(RamRegion {
+                region: Arc::new(GuestRegionMmap::new(mmap_region, start)),
+                slot,
+                temp_file,
+                backing,
+                fallback,
+            }),
+            Err(e) => {
+                MemoryManager::remove_temp_file(&temp_file);
+                Err(Error::MmapRegion(e))
+            }
+        }
+    }
+
+    // Huge pages are only taken from the host pool when the guest touches
+    // them, so that a host short of them would kill the VM with a SIGBUS
+    // later on. The pool is checked for each huge page size tried, from
+    // the requested one down to the smaller ones the host supports, before
+    // falling back to the zone fallback policy.
+    fn create_hugepages_fallback_mapping(
+        zone: &MemoryZoneConfig,
+        size: usize,
+    ) -> Result<(MmapRegion, PageBacking, bool)> {
+        let (requested, mut error) = match zone.hugepage_size {
+            Some(hugepage_size) => (Some(hugepage_size), None),
+            None => match host_resources::default_hugepage_size() {
+                Ok(hugepage_size) => (Some(hugepage_size), None),
+                Err(e) => (None, Some(Error::DefaultHugepageSize(e))),
+            },
+        };
+        let mut hugepage_sizes: Vec<u64> = requested.into_iter().collect();
+        let max_size = requested.unwrap_or_else(u64::max_value);
+        hugepage_sizes.extend(
+            host_resources::host_hugepage_sizes()
+                .into_iter()
+                .filter(|hugepage_size| *hugepage_size < max_size),
+        );
+
+        for hugepage_size in hugepage_sizes {
+            match MemoryManager::create_hugepages_mapping(hugepage_size, size) {
+                Ok(mmap_region) => {
+                    let fallback = requested != Some(hugepage_size);
+                    if fallback {
+                        warn!(
+                            "Guest RAM backed by huge pages of {} bytes: {:?}",
+                            hugepage_size, error
+                        );
+                    }
+                    return Ok((mmap_region, PageBacking::Hugepages(hugepage_size), fallback));
+                }
+                Err(e) => {
+                    if error.is_none() {
+                        error = Some(e);
+                    }
+                }
+            }
+        }
+
+        let mmap_region = if zone.shared {
+            let f = MemoryManager::create_memfd(false, None)?;
+            f.set_len(size as u64).map_err(Error::SharedFileSetLen)?;
+            MmapRegion::from_fd(FileOffset::new(f, 0), size)
+        } else {
+            MmapRegion::new(size)
+        }
+        .map_err(Error::MmapRegion)?;
+
+        let backing = if zone.hugepages_fallback == HugepagesFallback::Thp
+            && MemoryManager::advise_hugepages(&mmap_region, zone.shared)
+        {
+            PageBacking::TransparentHugepages
+        } else {
+            PageBacking::Small
+        };
+        warn!("Guest RAM backed by {:?} pages: {:?}", backing, error);
+
+        Ok((mmap_region, backing, true))
+    }
+
+    fn create_hugepages_mapping(hugepage_size: u64, size: usize) -> Result<MmapRegion> {
+        let count = (size as u64 + hugepage_size - 1) / hugepage_size;
+        let available = host_resources::available_hugepages(hugepage_size)
+            .map_err(|e| Error::HostHugepages(hugepage_size, e))?;
+        if count > available {
+            return Err(Error::NotEnoughHugepages(hugepage_size, count, available));
+        }
+
+        let f = MemoryManager::create_memfd(true, Some(hugepage_size))?;
+        f.set_len(size as u64).map_err(Error::SharedFileSetLen)?;
+
+        MmapRegion::from_fd(FileOffset::new(f, 0), size).map_err(Error::MmapRegion)
+    }
+
+    // Transparent huge pages are used on the region, unless the host
+    // disabled them altogether.
+    fn advise_hugepages(mmap_region: &MmapRegion, shared: bool) -> bool {
+        let (setting, disabled): (&str, &[&str]) = if shared {
+            ("shmem_enabled", &["[never]", "[deny]"])
+        } else {
+            ("enabled", &["[never]"])
+        };
+        let enabled = match std::fs::read_to_string(format!("{}/{}", THP_SYSFS_PATH, setting)) {
+            Ok(value) => !disabled.iter().any(|never| value.contains(never)),
+            Err(_) => false,
+        };
+        if !enabled {
+            return false;
+        }
+
+        // Safe because the range is a mapping owned by the memory manager,
+        // and the return value is checked.
+        let ret = unsafe {
+            libc::madvise(
+                mmap_region.as_ptr() as *mut libc::c_void,
+                mmap_region.size(),
+                libc::MADV_HUGEPAGE,
+            )
+        };
+
+        ret == 0
+    }
+
+    // Advises the host on the pages of a RAM region, before the guest or
+    // the prefault touches them. The huge pages regions are left alone,
+    // neither KSM nor transparent huge pages applying to them.
+    fn advise_ram_region(
+        ram_region: &mut RamRegion,
+        mergeable: bool,
+        thp: Option<bool>,
+    ) -> Result<()> {
+        if let PageBacking::Hugepages(_) = ram_region.backing {
+            return Ok(());
+        }
+
+        let region = &ram_region.region;
+        let shared = region.file_offset().is_some();
+        match thp {
+            Some(true) if ram_region.backing == PageBacking::Small => {
+                if MemoryManager::advise_hugepages(region, shared) {
+                    ram_region.backing = PageBacking::TransparentHugepages;
+                } else {
+                    warn!(
+                        "Guest RAM at 0x{:x} backed by small pages, the host having no \
+                         transparent huge pages",
+                        region.start_addr().raw_value()
+                    );
+                }
+            }
+            Some(false) => {
+                // Safe because the range is a mapping owned by the memory
+                // manager. A host without transparent huge pages fails, and
+                // has none to disable anyway.
+                unsafe {
+                    libc::madvise(
+                        region.as_ptr() as *mut libc::c_void,
+                        region.len() as usize,
+                        libc::MADV_NOHUGEPAGE,
+                    )
+                };
+            }
+            _ => {}
+        }
+
+        if mergeable {
+            // Safe because the range is a mapping owned by the memory
+            // manager, and the return value is checked.
+            let ret = unsafe {
+                libc::madvise(
+                    region.as_ptr() as *mut libc::c_void,
+                    region.len() as usize,
+                    libc::MADV_MERGEABLE,
+                )
+            };
+            if ret != 0 {
+                return Err(Error::Mergeable(io::Error::last_os_error()));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Huge page size of the hugetlbfs mount a file lives on, None when the
+    // file is not on hugetlbfs.
+    fn hugetlbfs_page_size(f: &File) -> Option<u64> {
+        // Safe because statfs is only written by the kernel, and the return
+        // value is checked.
+        let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::fstatfs(f.as_raw_fd(), &mut statfs) };
+
+        if ret == 0 && statfs.f_type as i64 == HUGETLBFS_MAGIC {
+            Some(statfs.f_bsize as u64)
+        } else {
+            None
+        }
+    }
+
+    fn remove_temp_file(temp_file: &Option<PathBuf>) {
+        if let Some(path) = temp_file {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Cannot remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    // Split the guest RAM into the NUMA nodes memory zones, laid out in the
+    // order of the node ids. A zone may span several RAM regions, e.g. when
+    // it crosses the 32-bit memory hole.
+    fn split_numa_ranges(
+        ram_regions: &BTreeMap<u64, RamRegion>,
+        numa: &[NumaConfig],
+    ) -> Result<Vec<NumaMemoryRange>> {
+        let mut ranges = Vec::new();
+        if numa.is_empty() {
+            return Ok(ranges);
+        }
+
+        let mut nodes: Vec<&NumaConfig> = numa.iter().collect();
+        nodes.sort_by_key(|node| node.id);
+
+        let mut regions = ram_regions.values().map(|r| &r.region);
+        let mut current = regions.next();
+        let mut offset = 0;
+        for node in nodes {
+            let mut remaining = node.size;
+            while remaining > 0 {
+                let region = current.ok_or(Error::NumaMemorySize)?;
+                let size = std::cmp::min(remaining, region.len() - offset);
+                ranges.push(NumaMemoryRange {
+                    node: node.id,
+                    start: region.start_addr().unchecked_add(offset),
+                    size,
+                    host_addr: region.as_ptr() as u64 + offset,
+                });
+
+                remaining -= size;
+                offset += size;
+                if offset == region.len() {
+                    current = regions.next();
+                    offset = 0;
+                }
+            }
+        }
+
+        if current.is_some() {
+            return Err(Error::NumaMemorySize);
+        }
+
+        Ok(ranges)
+    }
+
+    // The guest RAM has not been touched yet, so that the pages are
+    // allocated on the host node as soon as the guest uses them.
+    fn bind_to_host_node(range: &NumaMemoryRange, host_node: u32) -> Result<()> {
+        let mut nodemask = vec![0u64; host_node as usize / 64 + 1];
+        nodemask[host_node as usize / 64] |= 1 << (host_node % 64);
+
+        // Safe because the range is a mapping owned by the memory manager,
+        // and the node mask outlives the call. The kernel only reads
+        // maxnode - 1 bits from the mask.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                range.host_addr,
+                range.size,
+                MPOL_BIND,
+                nodemask.as_ptr(),
+                nodemask.len() as u64 * 64 + 1,
+                MPOL_MF_MOVE,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::NumaBind(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    // Touches every page of the RAM regions, from as many threads as the
+    // host has CPUs. A page is written back the byte it holds, so that the
+    // content of the file backing it, if any, is kept.
+    fn prefault_regions(ram_regions: &[&RamRegion]) -> Result<()> {
+        let started = Instant::now();
+
+        // Safe because sysconf() has no side effect.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut chunks = Vec::new();
+        for ram_region in ram_regions.iter() {
+            let stride = match ram_region.backing {
+                PageBacking::Hugepages(hugepage_size) => hugepage_size as usize,
+                _ => page_size,
+            };
+            let host_addr = ram_region.region.as_ptr() as usize;
+            let size = ram_region.region.len() as usize;
+            for offset in (0..size).step_by(PREFAULT_CHUNK_SIZE) {
+                let len = std::cmp::min(PREFAULT_CHUNK_SIZE, size - offset);
+                chunks.push((host_addr + offset, len, stride));
+            }
+        }
+        let total: usize = chunks.iter().map(|&(_, len, _)| len).sum();
+
+        // Safe because sysconf() has no side effect.
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        let threads = std::cmp::min(
+            std::cmp::min(std::cmp::max(cpus, 1) as usize, MAX_PREFAULT_THREADS),
+            std::cmp::max(chunks.len(), 1),
+        );
+
+        let chunks = Arc::new(chunks);
+        let next_chunk = Arc::new(AtomicUsize::new(0));
+        let mut handles = Vec::new();
+        let mut spawn_error = None;
+        for _ in 0..threads {
+            let chunks = chunks.clone();
+            let next_chunk = next_chunk.clone();
+            let handle = thread::Builder::new()
+                .name("prefault".to_string())
+                .spawn(move || loop {
+                    let &(host_addr, len, stride) =
+                        match chunks.get(next_chunk.fetch_add(1, Ordering::SeqCst)) {
+                            Some(chunk) => chunk,
+                            None => break,
+                        };
+                    for offset in (0..len).step_by(stride) {
+                        let page = (host_addr + offset) as *mut u8;
+                        // Safe because the page is within a mapping of the
+                        // memory manager, which outlives the thread, it
+                        // being joined before returning, and the guest
+                        // isn't running yet.
+                        unsafe { std::ptr::write_volatile(page, std::ptr::read_volatile(page)) };
+                    }
+                });
+            match handle {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    spawn_error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        // The threads still touching the regions are joined whatever
+        // happened, the regions not being theirs past this point.
+        let spawned = handles.len();
+        for handle in handles {
+            if handle.join().is_err() {
+                error!("Guest RAM prefault thread panicked");
+            }
+        }
+        if let Some(e) = spawn_error {
+            return Err(Error::PrefaultThreadSpawn(e));
+        }
+
+        info!(
+            "Prefaulted {} MiB of guest RAM in {:?}, with {} threads",
+            total >> 20,
+            started.elapsed(),
+            spawned
+        );
+
+        Ok(())
+    }
+
+    fn set_kvm_region(&self, ram_region: &RamRegion, remove: bool) -> Result<()> {
+        let region = &ram_region.region;
+        let mem_region = UserMemoryRegion {
+            slot: ram_region.slot,
+            guest_phys_addr: region.start_addr().raw_value(),
+            memory_size: if remove { 0 } else { region.len() as u64 },
+            userspace_addr: region.as_ptr() as u64,
+            flags: if self.dirty_log {
+                KVM_MEM_LOG_DIRTY_PAGES
+            } else {
+                0
+            },
+        };
+
+        // Safe because the guest regions are guaranteed not to overlap.
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.private_memory {
+                return unsafe { self.vm.set_private_memory_region(mem_region) }
+                    .map_err(Error::SetUserMemoryRegion);
+            }
+        }
+        unsafe { self.vm.set_user_memory_region(mem_region) }
+            .map_err(Error::SetUserMemoryRegion)
+    }
+
+    fn update_guest_memory(&self) -> Result<()> {
+        let guest_memory = GuestMemoryMmap::from_arc_regions(
+            self.ram_regions.values().map(|r| r.region.clone()).collect(),
+        )
+        .map_err(Error::GuestMemory)?;
+
+        *self.guest_memory.write().unwrap() = guest_memory;
+
+        Ok(())
+    }
+
+    pub fn guest_memory(&self) -> Arc<RwLock<GuestMemoryMmap>> {
+        self.guest_memory.clone()
+    }
+
+    /// Reserve a KVM memory slot for a region that is not guest RAM, such
+    /// as a virtio-pmem backing file or a VFIO BAR. The lowest free slot is
+    /// always picked, so that released slots get reused first.
+    pub fn allocate_kvm_slot(&mut self) -> Result<u32> {
+        let slot = (0..self.max_kvm_slots)
+            .find(|slot| !self.kvm_slots.contains(slot))
+            .ok_or(Error::KvmSlotsExhausted(self.max_kvm_slots))?;
+        self.kvm_slots.insert(slot);
+
+        Ok(slot)
+    }
+
+    /// Give back a KVM memory slot once its region has been removed.
+    pub fn free_kvm_slot(&mut self, slot: u32) {
+        self.kvm_slots.remove(&slot);
+    }
+
+    /// Guest RAM ranges of the NUMA nodes, in guest address order.
+    pub fn numa_ranges(&self) -> &[NumaMemoryRange] {
+        &self.numa_ranges
+    }
+
+    /// Backing of the guest RAM regions, in guest address order.
+    pub fn ram_backing(&self) -> Vec<RamBacking> {
+        self.ram_regions
+            .values()
+            .map(|r| RamBacking {
+                start: r.region.start_addr().raw_value(),
+                size: r.region.len(),
+                backing: r.backing,
+                fallback: r.fallback,
+            })
+            .collect()
+    }
+
+    /// Temporary files backing the guest memory, left behind if the process
+    /// dies before removing them.
+    pub fn temp_files(&self) -> Vec<PathBuf> {
+        self.ram_regions
+            .values()
+            .chain(self.firmware_region.iter())
+            .chain(self.sgx_epc_regions.iter())
+            .filter_map(|r| r.temp_file.clone())
+            .collect()
+    }
+
+    /// Size of the guest RAM, hotplugged regions included.
+    pub fn ram_size(&self) -> u64 {
+        self.ram_regions.values().map(|r| r.region.len()).sum()
+    }
+
+    /// Number of KVM memory slots in use.
+    pub fn used_kvm_slots(&self) -> u32 {
+        self.kvm_slots.len() as u32
+    }
+
+    /// Number of KVM memory slots supported by KVM.
+    pub fn max_kvm_slots(&self) -> u32 {
+        self.max_kvm_slots
+    }
+
+    /// Register a listener for the guest RAM changes. All current RAM
+    /// regions are reported to it right away.
+    pub fn add_memory_listener(&mut self, listener: Arc<dyn MemoryListener>) -> Result<()> {
+        for ram_region in self.ram_regions.values() {
+            let region = &ram_region.region;
+            listener
+                .region_added(
+                    region.start_addr().raw_value(),
+                    region.len() as u64,
+                    region.as_ptr() as u64,
+                )
+                .map_err(Error::ListenerRegionAdded)?;
+        }
+
+        self.listeners.push(listener);
+
+        Ok(())
+    }
+
+    /// Unregister a listener, for a device going away. The listener is not
+    /// told about the RAM regions anymore, nor about their removal.
+    pub fn remove_memory_listener(&mut self, listener: &Arc<dyn MemoryListener>) {
+        // Told apart by their address, as the vtables of a same type may
+        // differ.
+        let address = |listener: &Arc<dyn MemoryListener>| {
+            &**listener as *const dyn MemoryListener as *const u8
+        };
+        let listener = address(listener);
+        self.listeners.retain(|other| address(other) != listener);
+    }
+
+    /// Hotplug a RAM region. It is registered with KVM and exposed through
+    /// the guest memory before the listeners are notified, so that no device
+    /// can be handed a range the guest cannot access yet.
+    pub fn add_ram_region(&mut self, start: GuestAddress, size: usize) -> Result<()> {
+        // The guest couldn't accept the new memory.
+        if self.private_memory {
+            return Err(Error::PrivateMemoryHotplug);
+        }
+
+        let end = start.raw_value() + size as u64;
+        let overlaps = self.ram_regions.values().any(|r| {
+            let r_start = r.region.start_addr().raw_value();
+            start.raw_value() < r_start + r.region.len() as u64 && r_start < end
+        });
+        if overlaps {
+            return Err(Error::RegionOverlap(start));
+        }
+
+        // Reserve the slot first, so that running out of slots does not
+        // cost a useless mapping.
+        let slot = self.allocate_kvm_slot()?;
+        let mut ram_region =
+            match MemoryManager::create_ram_region(&self.hotplug_zone, 0, start, size, slot) {
+                Ok(ram_region) => ram_region,
+                Err(e) => {
+                    self.free_kvm_slot(slot);
+                    return Err(e);
+                }
+            };
+        if let Err(e) = MemoryManager::advise_ram_region(&mut ram_region, self.mergeable, self.thp)
+        {
+            self.free_kvm_slot(slot);
+            MemoryManager::remove_temp_file(&ram_region.temp_file);
+            return Err(e);
+        }
+        let host_addr = ram_region.region.as_ptr() as u64;
+
+        let prefaulted = if self.prefault {
+            MemoryManager::prefault_regions(&[&ram_region])
+        } else {
+            Ok(())
+        };
+        if let Err(e) = prefaulted.and_then(|_| self.set_kvm_region(&ram_region, false)) {
+            self.free_kvm_slot(slot);
+            MemoryManager::remove_temp_file(&ram_region.temp_file);
+            return Err(e);
+        }
+        self.ram_regions.insert(start.raw_value(), ram_region);
+        self.update_guest_memory()?;
+
+        for listener in self.listeners.iter() {
+            listener
+                .region_added(start.raw_value(), size as u64, host_addr)
+                .map_err(Error::ListenerRegionAdded)?;
+        }
+
+        Ok(())
+    }
+
+    /// Map a firmware image, so that it ends where the region does. The
+    /// region is registered with KVM but is not guest RAM: it is not part of
+    /// the guest memory, and the listeners are not told about it.
+    pub fn add_firmware_region(
+        &mut self,
+        start: GuestAddress,
+        size: usize,
+        image: &[u8],
+    ) -> Result<()> {
+        if self.firmware_region.is_some() {
+            return Err(Error::RegionOverlap(start));
+        }
+        if image.len() > size {
+            return Err(Error::FirmwareTooLarge(image.len()));
+        }
+
+        let mmap_region = MmapRegion::new(size).map_err(Error::MmapRegion)?;
+        let region = Arc::new(GuestRegionMmap::new(mmap_region, start));
+        region
+            .write_slice(image, MemoryRegionAddress((size - image.len()) as u64))
+            .map_err(Error::FirmwareLoad)?;
+
+        let slot = self.allocate_kvm_slot()?;
+        let firmware_region = RamRegion {
+            region,
+            slot,
+            temp_file: None,
+            backing: PageBacking::Small,
+            fallback: false,
+        };
+        if let Err(e) = self.set_kvm_region(&firmware_region, false) {
+            self.free_kvm_slot(slot);
+            return Err(e);
+        }
+        self.firmware_region = Some(firmware_region);
+
+        Ok(())
+    }
+
+    /// Map the SGX EPC sections next to each other from `start`, each one
+    /// backed by its own virtual EPC instance. The EPC pages are allocated
+    /// by the host as they get touched, which a prefaulted section does
+    /// right away, reading them from outside of an enclave.
+    pub fn add_sgx_epc_sections(
+        &mut self,
+        start: GuestAddress,
+        sections: &[SgxEpcConfig],
+    ) -> Result<Vec<SgxEpcSection>> {
+        if !self.sgx_epc_regions.is_empty() {
+            return Err(Error::SgxEpcAlreadySetUp);
+        }
+
+        let mut start = start;
+        for section in sections.iter() {
+            let size = section.size as usize;
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(SGX_VEPC_PATH)
+                .map_err(Error::SgxVepcOpen)?;
+            let mmap_region =
+                MmapRegion::from_fd(FileOffset::new(f, 0), size).map_err(Error::MmapRegion)?;
+            if section.prefault {
+                for offset in (0..size).step_by(SGX_EPC_PAGE_SIZE) {
+                    // Safe because the offset is within the mapping.
+                    let _ = unsafe { std::ptr::read_volatile(mmap_region.as_ptr().add(offset)) };
+                }
+            }
+
+            let slot = self.allocate_kvm_slot()?;
+            let epc_region = RamRegion {
+                region: Arc::new(GuestRegionMmap::new(mmap_region, start)),
+                slot,
+                temp_file: None,
+                backing: PageBacking::Small,
+                fallback: false,
+            };
+            if let Err(e) = self.set_kvm_region(&epc_region, false) {
+                self.free_kvm_slot(slot);
+                return Err(e);
+            }
+            self.sgx_epc_regions.push(epc_region);
+
+            start = start.unchecked_add(section.size);
+        }
+
+        Ok(self.sgx_epc_sections())
+    }
+
+    /// The SGX EPC sections, in guest address order.
+    pub fn sgx_epc_sections(&self) -> Vec<SgxEpcSection> {
+        self.sgx_epc_regions
+            .iter()
+            .map(|r| SgxEpcSection {
+                start: r.region.start_addr(),
+                size: r.region.len(),
+            })
+            .collect()
+    }
+
+    /// Host address a guest range is mapped at, in a RAM region or in the
+    /// firmware region, if the range is within one of them.
+    pub fn host_address(&self, start: GuestAddress, size: u64) -> Option<u64> {
+        self.ram_regions
+            .values()
+            .chain(self.firmware_region.iter())
+            .find_map(|r| {
+                let offset = start
+                    .raw_value()
+                    .checked_sub(r.region.start_addr().raw_value())?;
+                if offset + size > r.region.len() as u64 {
+                    return None;
+                }
+                Some(r.region.as_ptr() as u64 + offset)
+            })
+    }
+
+    /// Start logging the pages of the guest RAM the guest writes to, the
+    /// hotplugged RAM regions being logged as well.
+    pub fn start_dirty_log(&mut self) -> Result<()> {
+        if self.dirty_log {
+            return Err(Error::DirtyLogActive);
+        }
+        if self.private_memory {
+            return Err(Error::PrivateMemoryDirtyLog);
+        }
+
+        self.set_dirty_log(true)?;
+        // Reading the logs first clears them, and finds out whether the
+        // hypervisor logs the pages at all.
+        if let Err(e) = self.dirty_pages() {
+            self.set_dirty_log(false)?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Stop logging the pages of the guest RAM the guest writes to.
+    pub fn stop_dirty_log(&mut self) -> Result<()> {
+        if !self.dirty_log {
+            return Err(Error::DirtyLogInactive);
+        }
+
+        self.set_dirty_log(false)
+    }
+
+    /// Count the pages of the guest RAM the guest wrote to since the last
+    /// count, or since the logging started, in host pages.
+    pub fn dirty_pages(&self) -> Result<u64> {
+        if !self.dirty_log {
+            return Err(Error::DirtyLogInactive);
+        }
+
+        let mut count = 0;
+        for ram_region in self.ram_regions.values() {
+            let bitmap = self
+                .vm
+                .get_dirty_log(ram_region.slot, ram_region.region.len() as u64)
+                .map_err(Error::GetDirtyLog)?;
+            count += bitmap.iter().map(|word| u64::from(word.count_ones())).sum::<u64>();
+        }
+
+        Ok(count)
+    }
+
+    // Registers the RAM regions again, with or without dirty logging.
+    fn set_dirty_log(&mut self, dirty_log: bool) -> Result<()> {
+        self.dirty_log = dirty_log;
+        for ram_region in self.ram_regions.values() {
+            self.set_kvm_region(ram_region, false)?;
+        }
+
+        Ok(())
+    }
+
+    /// Unplug a RAM region. The listeners drop their mappings of it first,
+    /// then it is removed from the guest memory and from KVM.
+    pub fn remove_ram_region(&mut self, start: GuestAddress) -> Result<()> {
+        let size = match self.ram_regions.get(&start.raw_value()) {
+            Some(ram_region) => ram_region.region.len() as u64,
+            None => return Err(Error::RegionNotFound(start)),
+        };
+
+        for listener in self.listeners.iter() {
+            listener
+                .region_removed(start.raw_value(), size)
+                .map_err(Error::ListenerRegionRemoved)?;
+        }
+
+        let ram_region = self.ram_regions.remove(&start.raw_value()).unwrap();
+        self.update_guest_memory()?;
+        self.set_kvm_region(&ram_region, true)?;
+        self.free_kvm_slot(ram_region.slot);
+        MemoryManager::remove_temp_file(&ram_region.temp_file);
+
+        Ok(())
+    }
+}
+
+impl Drop for MemoryManager {
+    fn drop(&mut self) {
+        for ram_region in self.ram_regions.values() {
+            MemoryManager::remove_temp_file(&ram_region.temp_file);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Device types out of the tree, emulated by plugin executables which the
+//! VMM runs, one process for each device of their type.
+//!
+//! The VMM and the plugins talk the vfio-user protocol: the plugin process
+//! is handed one end of a connected UNIX socket as its file descriptor 3,
+//! and serves a PCI device on it, as a vfio-user server. The VMM adds the
+//! device to the guest as it does the other vfio-user devices. The plugin
+//! finds in its environment:
+//!
+//! - `CH_PLUGIN_FD`: the file descriptor of the socket, 3.
+//! - `CH_PLUGIN_DEVICE_TYPE`: the name of its device type.
+//! - `CH_PLUGIN_DEVICE_ID`: the ID of the device in the VM.
+//! - `CH_PLUGIN_CONFIG`: the configuration of the device, an opaque string
+//!   the VMM hands over as is, when one is given.
+//!
+//! The plugin process is killed along with the device manager of the VM.
+
+use crate::config::{PluginConfig, PluginDeviceConfig};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::result;
+use std::time::Duration;
+
+// File descriptor the plugins are given their socket on.
+const PLUGIN_FD: i32 = 3;
+
+// Longest a plugin takes to answer a request, past which its device is
+// considered broken rather than blocking the VMM or the vCPU for good.
+const PLUGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum Error {
+    /// No plugin is given for the device type.
+    UnknownPlugin(String),
+    /// Cannot create the socket the plugin serves its device on.
+    Socket(io::Error),
+    /// Cannot run the plugin executable.
+    Spawn(PathBuf, io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// The process of a plugin device, killed when this is dropped.
+pub struct PluginProcess {
+    id: String,
+    child: Child,
+}
+
+impl Drop for PluginProcess {
+    fn drop(&mut self) {
+        // The plugin may have exited already.
+        let _ = self.child.kill();
+        if let Err(e) = self.child.wait() {
+            warn!(
+                "Cannot wait for the plugin of the device {}: {}",
+                self.id, e
+            );
+        }
+    }
+}
+
+/// Runs the plugin of the device `id`, returning its process along with the
+/// socket it serves the device on.
+pub fn spawn_plugin(
+    plugins: &[PluginConfig],
+    plugin_device: &PluginDeviceConfig,
+    id: &str,
+) -> Result<(PluginProcess, UnixStream)> {
+    let plugin = plugins
+        .iter()
+        .find(|plugin| plugin.name == plugin_device.plugin)
+        .ok_or_else(|| Error::UnknownPlugin(plugin_device.plugin.clone()))?;
+
+    let (socket, plugin_socket) = UnixStream::pair().map_err(Error::Socket)?;
+    socket
+        .set_read_timeout(Some(PLUGIN_TIMEOUT))
+        .map_err(Error::Socket)?;
+    let plugin_fd = plugin_socket.as_raw_fd();
+
+    let mut command = Command::new(&plugin.path);
+    command
+        .env("CH_PLUGIN_FD", PLUGIN_FD.to_string())
+        .env("CH_PLUGIN_DEVICE_TYPE", &plugin.name)
+        .env("CH_PLUGIN_DEVICE_ID", id)
+        .stdin(Stdio::null());
+    if let Some(config) = &plugin_device.config {
+        command.env("CH_PLUGIN_CONFIG", config);
+    }
+    // Safe because only async-signal-safe functions are called between the
+    // fork and the exec, on a file descriptor the parent keeps open until
+    // the plugin is spawned.
+    unsafe {
+        command.pre_exec(move || {
+            // The socket is created close-on-exec, which dup2() clears on
+            // the duplicate, unless it already is the file descriptor.
+            let ret = if plugin_fd == PLUGIN_FD {
+                libc::fcntl(plugin_fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(plugin_fd, PLUGIN_FD)
+            };
+            if ret < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command
+        .spawn()
+        .map_err(|e| Error::Spawn(plugin.path.clone(), e))?;
+    info!(
+        "Plugin {:?} runs the device {} as process {}",
+        plugin.name,
+        id,
+        child.id()
+    );
+
+    Ok((
+        PluginProcess {
+            id: id.to_string(),
+            child,
+        },
+        socket,
+    ))
+}
@@ -0,0 +1,129 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Pool of VMs booted ahead of time from a template, for a VM to be handed
+//! out without waiting for its guest to boot.
+//!
+//! The guest agent of a pool VM connects to the vsock port of the pool
+//! configuration once the guest is booted, which the vsock device forwards
+//! to the UNIX socket `<sock>_<port>` the VMM listens on, and waits. The VMM
+//! pauses the VM as soon as the agent is connected. Claiming the VM resumes
+//! it and writes the claim, a JSON object, to the agent, which customizes
+//! the guest from it. A VM claimed before its agent connected gets its claim
+//! once it does, and isn't paused.
+
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use vmm_sys_util::eventfd::EventFd;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot bind the UNIX socket the guest agent waits for its claim on.
+    Bind(PathBuf, io::Error),
+    /// Cannot spawn the thread the guest agent is accepted from.
+    ThreadSpawn(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+enum AgentState {
+    // The guest agent didn't connect yet.
+    Waiting,
+    // The guest agent waits for its claim.
+    Connected(UnixStream),
+    // The VM was claimed, and the claim is given to the agent connecting.
+    Claimed(Vec<u8>),
+}
+
+/// The guest agent of a pool VM, accepted from a thread of its own as long
+/// as this is kept.
+pub struct ClaimAgent {
+    path: PathBuf,
+    state: Arc<Mutex<AgentState>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ClaimAgent {
+    /// Listens for the guest agent on `path`, `ready_evt` being written to
+    /// once it is connected and waits for its claim.
+    pub fn new(path: PathBuf, ready_evt: EventFd) -> Result<Self> {
+        std::fs::remove_file(&path).unwrap_or_default();
+        let listener = UnixListener::bind(&path).map_err(|e| Error::Bind(path.clone(), e))?;
+        let state = Arc::new(Mutex::new(AgentState::Waiting));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_state = state.clone();
+        let thread_stop = stop.clone();
+
+        thread::Builder::new()
+            .name("pool-agent".to_string())
+            .spawn(move || {
+                for socket in listener.incoming() {
+                    if thread_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match socket {
+                        Ok(socket) => {
+                            if let Err(e) = handle_agent(socket, &thread_state, &ready_evt) {
+                                warn!("Pool agent error: {}", e);
+                            }
+                        }
+                        Err(e) => error!("Pool agent socket error on accept: {}", e),
+                    }
+                }
+            })
+            .map_err(Error::ThreadSpawn)?;
+
+        Ok(ClaimAgent { path, state, stop })
+    }
+
+    /// The UNIX socket the guest agent connects to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Gives the claim to the guest agent, now if it is connected, or once it
+    /// connects.
+    pub fn claim(&self, claim: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let previous = mem::replace(&mut *state, AgentState::Claimed(claim.to_vec()));
+        if let AgentState::Connected(mut socket) = previous {
+            socket.write_all(claim)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for ClaimAgent {
+    fn drop(&mut self) {
+        // Connecting wakes the thread up, for it to see it is to stop.
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = UnixStream::connect(&self.path);
+        std::fs::remove_file(&self.path).unwrap_or_default();
+    }
+}
+
+// Keeps the connection of the agent until the VM is claimed, an agent
+// connecting again, e.g. after the guest rebooted, taking the place of the
+// previous one.
+fn handle_agent(
+    mut socket: UnixStream,
+    state: &Mutex<AgentState>,
+    ready_evt: &EventFd,
+) -> io::Result<()> {
+    let mut state = state.lock().unwrap();
+    match &*state {
+        AgentState::Claimed(claim) => socket.write_all(claim),
+        AgentState::Waiting | AgentState::Connected(_) => {
+            *state = AgentState::Connected(socket);
+            ready_evt.write(1)
+        }
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Host side of the realtime latency profile: the host CPUs the vCPUs are
+//! pinned to, checked against the ones the host isolates from its scheduler
+//! with `isolcpus`, and the locking of the VMM memory.
+//!
+//! Without an affinity in the configuration, the vCPUs are pinned to the
+//! isolated CPUs in order, vCPU 0 to the first one. With one, each vCPU must
+//! be given a single isolated CPU, no other vCPU runs on.
+
+use crate::config::CpuAffinity;
+use std::fs;
+use std::io;
+use std::result;
+
+// CPUs the kernel keeps out of the scheduler domains, from isolcpus=.
+const ISOLATED_CPUS_PATH: &str = "/sys/devices/system/cpu/isolated";
+// CPUs the host interrupts are steered to by default, from irqaffinity=.
+const DEFAULT_IRQ_AFFINITY_PATH: &str = "/proc/irq/default_smp_affinity";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot read the CPUs the host isolates.
+    ReadIsolatedCpus(io::Error),
+    /// The host doesn't isolate a CPU for each vCPU, only these many.
+    NotEnoughIsolatedCpus(usize),
+    /// A vCPU isn't pinned to a single host CPU.
+    VcpuAffinity(u8),
+    /// A vCPU is pinned to a host CPU the host doesn't isolate.
+    HostCpuNotIsolated(u8, usize),
+    /// A vCPU is pinned to the host CPU of another vCPU.
+    HostCpuShared(u8, usize),
+    /// Cannot lock the VMM memory, RLIMIT_MEMLOCK being too low.
+    LockMemory(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// The host CPU of each of the vCPUs.
+pub fn vcpu_affinity(vcpus: u8, affinity: &[CpuAffinity]) -> Result<Vec<CpuAffinity>> {
+    let list = fs::read_to_string(ISOLATED_CPUS_PATH).map_err(Error::ReadIsolatedCpus)?;
+    let isolated = parse_cpu_list(list.trim())
+        .ok_or_else(|| Error::ReadIsolatedCpus(io::Error::from(io::ErrorKind::InvalidData)))?;
+
+    let affinity = if affinity.is_empty() {
+        if isolated.len() < vcpus as usize {
+            return Err(Error::NotEnoughIsolatedCpus(isolated.len()));
+        }
+        (0..vcpus)
+            .map(|vcpu| CpuAffinity {
+                vcpu,
+                host_cpus: vec![isolated[vcpu as usize]],
+            })
+            .collect()
+    } else {
+        affinity.to_vec()
+    };
+
+    let mut host_cpus: Vec<usize> = Vec::new();
+    for vcpu in 0..vcpus {
+        let host_cpu = match affinity.iter().find(|a| a.vcpu == vcpu) {
+            Some(a) if a.host_cpus.len() == 1 => a.host_cpus[0],
+            _ => return Err(Error::VcpuAffinity(vcpu)),
+        };
+        if !isolated.contains(&host_cpu) {
+            return Err(Error::HostCpuNotIsolated(vcpu, host_cpu));
+        }
+        if host_cpus.contains(&host_cpu) {
+            return Err(Error::HostCpuShared(vcpu, host_cpu));
+        }
+        host_cpus.push(host_cpu);
+    }
+
+    check_irq_affinity(&host_cpus);
+
+    Ok(affinity)
+}
+
+// The host interrupts landing on a vCPU host CPU preempt the vCPU, which is
+// only worth a warning, the devices of the host being set up on their own.
+fn check_irq_affinity(host_cpus: &[usize]) {
+    let mask = match fs::read_to_string(DEFAULT_IRQ_AFFINITY_PATH) {
+        Ok(mask) => mask,
+        Err(e) => {
+            warn!("Cannot read the default host interrupts affinity: {}", e);
+            return;
+        }
+    };
+
+    // Hexadecimal mask, in groups of 32 bits separated by commas, the
+    // lowest CPUs last.
+    let digits: Vec<u32> = mask
+        .trim()
+        .chars()
+        .rev()
+        .filter(|c| *c != ',')
+        .filter_map(|c| c.to_digit(16))
+        .collect();
+    for &host_cpu in host_cpus.iter() {
+        let steered = digits
+            .get(host_cpu / 4)
+            .map(|digit| digit & (1 << (host_cpu % 4)) != 0)
+            .unwrap_or(false);
+        if steered {
+            warn!(
+                "Host interrupts are steered to the host CPU {} of a vCPU, \
+                 irqaffinity= should leave it out",
+                host_cpu
+            );
+        }
+    }
+}
+
+// Parses a CPU list such as "2-5,8", as found in sysfs. Empty for no CPU.
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        let mut bounds = range.splitn(2, '-');
+        let first: usize = bounds.next()?.parse().ok()?;
+        let last: usize = match bounds.next() {
+            Some(last) => last.parse().ok()?,
+            None => first,
+        };
+        cpus.extend(first..=last);
+    }
+
+    Some(cpus)
+}
+
+/// Locks the memory of the VMM, the guest memory included, in RAM, for the
+/// guest to never wait for the host to page it in. The memory mapped later
+/// on is locked as well.
+pub fn lock_memory() -> Result<()> {
+    // Safe because mlockall() doesn't touch the memory of the process.
+    let ret = unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) };
+    if ret != 0 {
+        return Err(Error::LockMemory(io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
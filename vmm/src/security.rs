@@ -0,0 +1,895 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Mandatory access control labels of the VMM and of the files of its VM,
+//! so that the SELinux or AppArmor policy of a host running several VMs
+//! keeps them apart, as sVirt does.
+//!
+//! With SELinux, the disk images and persistent memory files of the VM are
+//! given the file label of its configuration, typically with MCS categories
+//! of its own, and the VMM thread then moves to the process label, with the
+//! same categories, which only gives it access to the files of its VM. With
+//! AppArmor, the VMM thread changes to the profile of the VM, whose rules
+//! name the files it may access.
+//!
+//! The labels are applied when the VM is created, before any of its files
+//! is opened. The threads the VMM thread creates afterwards, running the
+//! vCPUs and the devices, inherit its label or profile.
+//!
+//! Without a policy of the host, the VMM can confine its whole process with
+//! Landlock once the VM files are opened, right before the VM is booted,
+//! when the VM is the only one of the process. It keeps access to the files
+//! the VM configuration names, which it opens again when the VM reboots,
+//! and loses it to the rest of the filesystem.
+//!
+//! The VMM can also drop the privileges of its whole process to an
+//! unprivileged user at the same point, once the files and devices that
+//! need them, such as the TAP interfaces or the VFIO groups, are opened,
+//! before the guest runs, when the VM is the only one of the process.
+
+use crate::api::PassedFds;
+use crate::config::{ConsoleOutputMode, LandlockAccess, UserConfig, VmConfig};
+use crate::host_resources;
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use vmm_sys_util::signal::{register_signal_handler, validate_signal_num, SignalHandler};
+
+const SELINUX_XATTR: &[u8] = b"security.selinux\0";
+
+// Landlock, from the kernel UAPI, which the libc crate doesn't have. The
+// system call numbers are the same for all the architectures.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+const LANDLOCK_ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const LANDLOCK_ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const LANDLOCK_ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const LANDLOCK_ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const LANDLOCK_ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const LANDLOCK_ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const LANDLOCK_ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const LANDLOCK_ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+// All the access rights of the first Landlock ABI, from EXECUTE to
+// MAKE_SYM, which the ruleset denies but for its rules.
+const LANDLOCK_HANDLED_ACCESS_FS: u64 = (1 << 13) - 1;
+// The access rights which apply to a file rather than to a directory.
+const LANDLOCK_ACCESS_FILE: u64 =
+    LANDLOCK_ACCESS_FS_EXECUTE | LANDLOCK_ACCESS_FS_WRITE_FILE | LANDLOCK_ACCESS_FS_READ_FILE;
+
+const LANDLOCK_READ: u64 = LANDLOCK_ACCESS_FS_READ_FILE | LANDLOCK_ACCESS_FS_READ_DIR;
+const LANDLOCK_READ_WRITE: u64 = LANDLOCK_READ
+    | LANDLOCK_ACCESS_FS_WRITE_FILE
+    | LANDLOCK_ACCESS_FS_REMOVE_FILE
+    | LANDLOCK_ACCESS_FS_MAKE_DIR
+    | LANDLOCK_ACCESS_FS_MAKE_REG;
+// A UNIX socket is removed and bound again in its directory.
+const LANDLOCK_SOCKET_DIR: u64 = LANDLOCK_ACCESS_FS_REMOVE_FILE | LANDLOCK_ACCESS_FS_MAKE_SOCK;
+
+// Real-time signal, after the vCPU one, on which the other threads of the
+// process confine themselves with the Landlock ruleset, and how long they
+// have to.
+const LANDLOCK_RTSIG_OFFSET: i32 = 1;
+const LANDLOCK_THREADS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The ruleset the threads confine themselves with from the signal handler,
+// how many of them still have to, and whether any of them failed to.
+static LANDLOCK_RULESET: AtomicI32 = AtomicI32::new(-1);
+static LANDLOCK_PENDING: AtomicUsize = AtomicUsize::new(0);
+static LANDLOCK_FAILED: AtomicBool = AtomicBool::new(false);
+
+// Permission bits of the file modes, for the user the VMM process switches
+// to.
+const USER_READ: u32 = 0o4;
+const USER_WRITE: u32 = 0o2;
+const USER_SEARCH: u32 = 0o1;
+
+#[repr(C)]
+struct LandlockRulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct LandlockPathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot set the SELinux label of a file of the VM.
+    SetFileLabel(PathBuf, io::Error),
+    /// Cannot set the SELinux label of a file passed through the API.
+    SetPassedFileLabel(String, io::Error),
+    /// Cannot move the VMM thread to the SELinux process label.
+    SetProcessLabel(io::Error),
+    /// Cannot change the VMM thread to the AppArmor profile.
+    ChangeProfile(io::Error),
+    /// The kernel doesn't support Landlock, or has it disabled.
+    LandlockUnsupported(io::Error),
+    /// Cannot create the Landlock ruleset.
+    LandlockRuleset(io::Error),
+    /// Cannot add the Landlock rule of a path.
+    LandlockRule(PathBuf, io::Error),
+    /// Cannot confine the process with the Landlock ruleset.
+    LandlockRestrict(io::Error),
+    /// Cannot confine the other threads of the process with the Landlock
+    /// ruleset, the VMM thread being confined for good.
+    LandlockThreads(io::Error),
+    /// Confining the process failed midway, which left it unfit to run a VM.
+    PartlyConfined,
+    /// The process runs other VMs, which confining the whole process to
+    /// the files of the VM, or switching it to its user, would break.
+    SharedProcess,
+    /// Cannot check the access of the user to a file of the VM.
+    UserAccess(PathBuf, io::Error),
+    /// The user can't open these files of the VM, which the VMM opens again
+    /// when the VM reboots.
+    UserInaccessible(Vec<PathBuf>),
+    /// The user can't open the TAP interface, which the VMM creates, or
+    /// which isn't a persistent interface of the user, None when the VMM
+    /// names it.
+    UserTap(Option<String>),
+    /// The process already switched to the user of another VM, which it
+    /// can't undo.
+    UserChanged,
+    /// Cannot switch the process to the groups and user.
+    SetUser(io::Error),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+fn set_file_label(path: &Path, label: &str) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    // Safe because the path and the attribute name are null terminated,
+    // the value size is given, and we check the result.
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            SELINUX_XATTR.as_ptr() as *const libc::c_char,
+            label.as_ptr() as *const libc::c_void,
+            label.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn set_fd_label(file: &impl AsRawFd, label: &str) -> io::Result<()> {
+    // Safe because the file descriptor is valid, the attribute name is null
+    // terminated, the value size is given, and we check the result.
+    let ret = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            SELINUX_XATTR.as_ptr() as *const libc::c_char,
+            label.as_ptr() as *const libc::c_void,
+            label.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// The attributes of the calling thread only, the other threads of the
+// process, such as the HTTP server one, keeping theirs.
+fn write_thread_attr(attr: &str, value: &str) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(format!("/proc/thread-self/attr/{}", attr))?
+        .write_all(value.as_bytes())
+}
+
+/// Gives the disk images and persistent memory files of the VM the SELinux
+/// file label of its configuration, these files being opened by path or
+/// passed through the API.
+pub fn label_files(config: &VmConfig, passed_fds: &PassedFds) -> Result<()> {
+    let label = match &config.security.selinux_file_label {
+        Some(label) => label,
+        None => return Ok(()),
+    };
+
+    let mut paths = Vec::new();
+    let mut fd_names = Vec::new();
+    for disk in config.disks.iter().flatten() {
+        match &disk.fd {
+            Some(name) => fd_names.push(name),
+            None => paths.push(&disk.path),
+        }
+    }
+    for pmem in config.pmem.iter().flatten() {
+        match &pmem.fd {
+            Some(name) => fd_names.push(name),
+            // The directories get a new file of their own, labeled after
+            // the directory.
+            None if pmem.file.is_dir() => {}
+            None => paths.push(&pmem.file),
+        }
+    }
+
+    for path in paths {
+        set_file_label(path, label).map_err(|e| Error::SetFileLabel(path.clone(), e))?;
+    }
+
+    let passed_fds = passed_fds.lock().unwrap();
+    for name in fd_names {
+        // A missing file is reported when the VM is booted.
+        if let Some(file) = passed_fds.get(name) {
+            set_fd_label(file, label).map_err(|e| Error::SetPassedFileLabel(name.clone(), e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the calling thread, meant to be the VMM one, to the SELinux process
+/// label or to the AppArmor profile of the VM.
+pub fn confine(config: &VmConfig) -> Result<()> {
+    if let Some(label) = &config.security.selinux_process_label {
+        write_thread_attr("current", label).map_err(Error::SetProcessLabel)?;
+        info!("VMM thread moved to the SELinux label {}", label);
+    }
+
+    if let Some(profile) = &config.security.apparmor_profile {
+        let command = format!("changeprofile {}", profile);
+        // Recent kernels have an AppArmor directory of their own, the older
+        // ones only the attributes shared by all the security modules.
+        write_thread_attr("apparmor/current", &command)
+            .or_else(|_| write_thread_attr("current", &command))
+            .map_err(Error::ChangeProfile)?;
+        info!("VMM thread changed to the AppArmor profile {}", profile);
+    }
+
+    Ok(())
+}
+
+// The paths the VM needs once its files are opened, along with the access
+// it needs to them.
+fn landlock_paths(config: &VmConfig, registry: Option<&Path>) -> Vec<(PathBuf, u64)> {
+    let mut paths = Vec::new();
+    let mut read = |path: &Path| paths.push((path.to_path_buf(), LANDLOCK_READ));
+    for path in ["/proc", "/sys"].iter() {
+        read(Path::new(path));
+    }
+    for path in config.kernel.iter().map(|kernel| &kernel.path) {
+        read(path);
+    }
+    for path in config.initramfs.iter().map(|initramfs| &initramfs.path) {
+        read(path);
+    }
+    for path in config.acpi_tables.iter().flatten().map(|table| &table.path) {
+        read(path);
+    }
+    read(&config.rng.src);
+    for path in config
+        .devices
+        .iter()
+        .flatten()
+        .filter_map(|d| d.romfile.as_ref())
+    {
+        read(path);
+    }
+    // The plugins are run again when the VM reboots.
+    for plugin in config.plugins.iter().flatten() {
+        paths.push((
+            plugin.path.clone(),
+            LANDLOCK_READ | LANDLOCK_ACCESS_FS_EXECUTE,
+        ));
+    }
+    // The host hooks run from the process, confined as well.
+    for hook in config.hooks.iter().flatten() {
+        paths.push((
+            hook.command.clone(),
+            LANDLOCK_READ | LANDLOCK_ACCESS_FS_EXECUTE,
+        ));
+    }
+
+    for disk in config
+        .disks
+        .iter()
+        .flatten()
+        .filter(|disk| disk.fd.is_none())
+    {
+        let access = if disk.readonly {
+            LANDLOCK_READ
+        } else {
+            LANDLOCK_READ_WRITE
+        };
+        paths.push((disk.path.clone(), access));
+    }
+
+    // The directories the files backing the memory are created in, and the
+    // devices the VMM opens.
+    let mut read_write = Vec::new();
+    read_write.extend(
+        config
+            .memory
+            .zones()
+            .into_iter()
+            .filter_map(|zone| zone.file),
+    );
+    for pmem in config
+        .pmem
+        .iter()
+        .flatten()
+        .filter(|pmem| pmem.fd.is_none())
+    {
+        read_write.push(pmem.file.clone());
+    }
+    read_write.extend(
+        config
+            .nvdimms
+            .iter()
+            .flatten()
+            .map(|nvdimm| nvdimm.file.clone()),
+    );
+    read_write.extend(config.diagnostics.iter().filter_map(|d| d.path.clone()));
+    read_write.extend(config.cgroup.iter().map(|cgroup| cgroup.path.clone()));
+
+    let consoles = [
+        (&config.serial.mode, &config.serial.file),
+        (&config.console.mode, &config.console.file),
+    ];
+    let uarts = config.uarts.iter().flatten().map(|u| (&u.mode, &u.file));
+    for (mode, file) in consoles.iter().cloned().chain(uarts) {
+        match (mode, file) {
+            (ConsoleOutputMode::File, Some(path)) => read_write.push(path.clone()),
+            (ConsoleOutputMode::Null, _) => read_write.push(PathBuf::from("/dev/null")),
+            (ConsoleOutputMode::Pty, _) => {
+                read_write.push(PathBuf::from("/dev/ptmx"));
+                read_write.push(PathBuf::from("/dev/pts"));
+            }
+            _ => (),
+        }
+    }
+
+    for net in config
+        .net
+        .iter()
+        .flatten()
+        .filter(|net| net.fd.is_none() && net.fds.is_none())
+    {
+        match &net.macvtap {
+            // The character device of a macvtap interface is named after
+            // its index.
+            Some(macvtap) => {
+                if let Ok(ifindex) =
+                    fs::read_to_string(format!("/sys/class/net/{}/ifindex", macvtap))
+                {
+                    read_write.push(PathBuf::from(format!("/dev/tap{}", ifindex.trim())));
+                }
+            }
+            None => read_write.push(PathBuf::from("/dev/net/tun")),
+        }
+    }
+    if let Some(devices) = &config.devices {
+        read_write.push(PathBuf::from("/dev/vfio"));
+        read_write.extend(devices.iter().map(|device| device.path.clone()));
+    }
+    if config.sgx_epc.is_some() {
+        read_write.push(PathBuf::from("/dev/sgx_vepc"));
+    }
+    read_write.push(PathBuf::from("/dev/kvm"));
+    read_write.extend(registry.map(Path::to_path_buf));
+    paths.extend(
+        read_write
+            .into_iter()
+            .map(|path| (path, LANDLOCK_READ_WRITE)),
+    );
+
+    for socket in host_resources::vm_sockets(config) {
+        if let Some(dir) = socket.parent() {
+            paths.push((dir.to_path_buf(), LANDLOCK_SOCKET_DIR));
+        }
+    }
+
+    paths
+}
+
+// The paths the VMM opens again when the VM reboots, along with the
+// permission bits the user needs on them. A directory written to is one
+// files are created in.
+fn user_paths(config: &VmConfig, registry: Option<&Path>) -> Vec<(PathBuf, u32)> {
+    let mut paths = Vec::new();
+    let mut read = |path: &Path| paths.push((path.to_path_buf(), USER_READ));
+    for path in config.kernel.iter().map(|kernel| &kernel.path) {
+        read(path);
+    }
+    for path in config.initramfs.iter().map(|initramfs| &initramfs.path) {
+        read(path);
+    }
+    for path in config.acpi_tables.iter().flatten().map(|table| &table.path) {
+        read(path);
+    }
+    read(&config.rng.src);
+    for path in config
+        .devices
+        .iter()
+        .flatten()
+        .filter_map(|d| d.romfile.as_ref())
+    {
+        read(path);
+    }
+    for plugin in config.plugins.iter().flatten() {
+        paths.push((plugin.path.clone(), USER_READ | USER_SEARCH));
+    }
+
+    for disk in config
+        .disks
+        .iter()
+        .flatten()
+        .filter(|disk| disk.fd.is_none())
+    {
+        let bits = if disk.readonly {
+            USER_READ
+        } else {
+            USER_READ | USER_WRITE
+        };
+        paths.push((disk.path.clone(), bits));
+    }
+
+    let mut read_write = Vec::new();
+    read_write.extend(
+        config
+            .memory
+            .zones()
+            .into_iter()
+            .filter_map(|zone| zone.file),
+    );
+    for pmem in config
+        .pmem
+        .iter()
+        .flatten()
+        .filter(|pmem| pmem.fd.is_none())
+    {
+        read_write.push(pmem.file.clone());
+    }
+    read_write.extend(
+        config
+            .nvdimms
+            .iter()
+            .flatten()
+            .map(|nvdimm| nvdimm.file.clone()),
+    );
+    read_write.extend(config.diagnostics.iter().filter_map(|d| d.path.clone()));
+
+    let consoles = [
+        (&config.serial.mode, &config.serial.file),
+        (&config.console.mode, &config.console.file),
+    ];
+    let uarts = config.uarts.iter().flatten().map(|u| (&u.mode, &u.file));
+    for (mode, file) in consoles.iter().cloned().chain(uarts) {
+        match (mode, file) {
+            (ConsoleOutputMode::File, Some(path)) => read_write.push(path.clone()),
+            (ConsoleOutputMode::Null, _) => read_write.push(PathBuf::from("/dev/null")),
+            (ConsoleOutputMode::Pty, _) => read_write.push(PathBuf::from("/dev/ptmx")),
+            _ => (),
+        }
+    }
+
+    for net in config
+        .net
+        .iter()
+        .flatten()
+        .filter(|net| net.fd.is_none() && net.fds.is_none())
+    {
+        match &net.macvtap {
+            Some(macvtap) => {
+                if let Ok(ifindex) =
+                    fs::read_to_string(format!("/sys/class/net/{}/ifindex", macvtap))
+                {
+                    read_write.push(PathBuf::from(format!("/dev/tap{}", ifindex.trim())));
+                }
+            }
+            None => read_write.push(PathBuf::from("/dev/net/tun")),
+        }
+    }
+    if let Some(devices) = &config.devices {
+        read_write.push(PathBuf::from("/dev/vfio/vfio"));
+        for device in devices.iter() {
+            if let Ok(group) = fs::read_link(device.path.join("iommu_group")) {
+                read_write.extend(group.file_name().map(|g| Path::new("/dev/vfio").join(g)));
+            }
+        }
+    }
+    if config.sgx_epc.is_some() {
+        read_write.push(PathBuf::from("/dev/sgx_vepc"));
+    }
+    read_write.push(PathBuf::from("/dev/kvm"));
+    read_write.extend(registry.map(Path::to_path_buf));
+    // The UNIX sockets are removed and bound again in their directory.
+    for socket in host_resources::vm_sockets(config) {
+        read_write.extend(socket.parent().map(Path::to_path_buf));
+    }
+    paths.extend(
+        read_write
+            .into_iter()
+            .map(|path| (path, USER_READ | USER_WRITE)),
+    );
+
+    paths
+}
+
+// Whether the mode of the file gives the user the permission bits, through
+// the owner, the group, or the other bits.
+fn mode_allows(user: &UserConfig, metadata: &fs::Metadata, bits: u32) -> bool {
+    let mode = if metadata.uid() == user.uid {
+        metadata.mode() >> 6
+    } else if metadata.gid() == user.gid || user.groups.contains(&metadata.gid()) {
+        metadata.mode() >> 3
+    } else {
+        metadata.mode()
+    };
+
+    mode & bits == bits
+}
+
+// Whether the user may reach the path, searching the directories up to it,
+// and access it with the permission bits, a directory being written to by
+// creating files in it. The ACLs aren't accounted for.
+fn user_may(user: &UserConfig, path: &Path, bits: u32) -> io::Result<bool> {
+    if user.uid == 0 {
+        return Ok(true);
+    }
+    for dir in path
+        .ancestors()
+        .skip(1)
+        .filter(|dir| !dir.as_os_str().is_empty())
+    {
+        if !mode_allows(user, &fs::metadata(dir)?, USER_SEARCH) {
+            return Ok(false);
+        }
+    }
+
+    let metadata = fs::metadata(path)?;
+    let bits = if metadata.is_dir() && bits & USER_WRITE != 0 {
+        bits | USER_SEARCH
+    } else {
+        bits
+    };
+    Ok(mode_allows(user, &metadata, bits))
+}
+
+// Whether the user may open the TAP interface without privileges, it being
+// a persistent interface whose owner and group, when it has them, are the
+// user's.
+fn user_owns_tap(user: &UserConfig, tap: &str) -> bool {
+    let attr = |name: &str| {
+        fs::read_to_string(format!("/sys/class/net/{}/{}", tap, name))
+            .ok()
+            .and_then(|value| value.trim().parse::<i64>().ok())
+    };
+    let (owner, group) = match (attr("tun_flags"), attr("owner"), attr("group")) {
+        (Some(_), Some(owner), Some(group)) => (owner, group),
+        _ => return false,
+    };
+
+    (owner == -1 || owner == i64::from(user.uid))
+        && (group == -1
+            || group == i64::from(user.gid)
+            || user.groups.iter().any(|&g| i64::from(g) == group))
+}
+
+/// Checks that the user the VM configuration switches the VMM process to
+/// can open the files and devices of the VM once the process runs as the
+/// user, as the VMM does when the VM reboots, listing all the files it
+/// can't open at once.
+pub fn check_user(config: &VmConfig, registry: Option<&Path>) -> Result<()> {
+    let user = match &config.user {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    let mut inaccessible = Vec::new();
+    for (path, bits) in user_paths(config, registry) {
+        let accessible = match user_may(user, &path, bits) {
+            // A missing file is created in its directory.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && bits & USER_WRITE != 0 => {
+                match path.parent() {
+                    Some(dir) if dir.as_os_str().is_empty() => {
+                        user_may(user, Path::new("."), USER_WRITE)
+                    }
+                    Some(dir) => user_may(user, dir, USER_WRITE),
+                    None => Ok(true),
+                }
+            }
+            result => result,
+        };
+        match accessible {
+            Ok(true) => (),
+            Ok(false) => inaccessible.push(path),
+            // The missing files are reported when the VM opens them.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(Error::UserAccess(path, e)),
+        }
+    }
+    if !inaccessible.is_empty() {
+        return Err(Error::UserInaccessible(inaccessible));
+    }
+
+    for net in config
+        .net
+        .iter()
+        .flatten()
+        .filter(|net| net.fd.is_none() && net.fds.is_none() && net.macvtap.is_none())
+    {
+        match &net.tap {
+            Some(tap) if user.uid == 0 || user_owns_tap(user, tap) => (),
+            tap => return Err(Error::UserTap(tap.clone())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Switches the whole process to the user and groups for good, losing its
+/// capabilities, the C library switching all its threads. The VM has to be
+/// the only one of the process.
+pub fn set_user(user: &UserConfig) -> Result<()> {
+    // The groups go first, the process needing its privileges to set them.
+    // Safe because the groups are valid for their length, and we check the
+    // result.
+    if unsafe { libc::setgroups(user.groups.len(), user.groups.as_ptr()) } != 0 {
+        return Err(Error::SetUser(io::Error::last_os_error()));
+    }
+    // Safe because we check the result.
+    if unsafe { libc::setresgid(user.gid, user.gid, user.gid) } != 0 {
+        return Err(Error::SetUser(io::Error::last_os_error()));
+    }
+    // Safe because we check the result.
+    if unsafe { libc::setresuid(user.uid, user.uid, user.uid) } != 0 {
+        return Err(Error::SetUser(io::Error::last_os_error()));
+    }
+    info!(
+        "VMM process switched to the user {} and the group {}",
+        user.uid, user.gid
+    );
+
+    Ok(())
+}
+
+// Lets the ruleset give the access to the files under the path, only the
+// access rights of a file applying to a file.
+fn add_landlock_rule(ruleset: &File, path: &Path, access: u64) -> io::Result<()> {
+    let parent = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+        .open(path)?;
+    let allowed_access = if parent.metadata()?.is_dir() {
+        access
+    } else {
+        access & LANDLOCK_ACCESS_FILE
+    };
+    let attr = LandlockPathBeneathAttr {
+        allowed_access,
+        parent_fd: parent.as_raw_fd(),
+    };
+
+    // Safe because the attribute is valid for its type, and we check the
+    // result.
+    let ret = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const LandlockPathBeneathAttr,
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Confines the calling thread with the Landlock ruleset, which can't be done
+// without giving up on gaining privileges through the executables it runs.
+// Only runs async signal safe system calls, for the signal handler to call
+// it.
+fn restrict_self(ruleset: RawFd) -> bool {
+    // Safe because the system calls only take integers, and we check their
+    // result.
+    unsafe {
+        libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == 0
+            && libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0) == 0
+    }
+}
+
+extern "C" fn handle_landlock_signal(_: i32, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    // Safe because errno is thread local, and restored for the code the
+    // signal interrupted.
+    unsafe {
+        let errno = *libc::__errno_location();
+        if !restrict_self(LANDLOCK_RULESET.load(Ordering::SeqCst)) {
+            LANDLOCK_FAILED.store(true, Ordering::SeqCst);
+        }
+        *libc::__errno_location() = errno;
+    }
+    LANDLOCK_PENDING.fetch_sub(1, Ordering::SeqCst);
+}
+
+// The IDs of the threads of the process.
+fn process_threads() -> io::Result<Vec<libc::pid_t>> {
+    let mut threads = Vec::new();
+    for entry in fs::read_dir("/proc/self/task")? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+            threads.push(tid);
+        }
+    }
+
+    Ok(threads)
+}
+
+// Confines the other threads of the process with the ruleset, as Landlock
+// only confines the calling thread, each of them from the handler of a
+// signal sent to it, the way the C library switches the user of all the
+// threads. The threads the ones not confined yet start meanwhile are found
+// on the next pass over the threads of the process.
+fn restrict_other_threads(ruleset: &File) -> Result<()> {
+    let errno_error = |e: vmm_sys_util::errno::Error| {
+        Error::LandlockThreads(io::Error::from_raw_os_error(e.errno()))
+    };
+    // Safe because the handler only runs async signal safe code. The system
+    // calls the signal interrupts are restarted, where they can be.
+    unsafe {
+        register_signal_handler(
+            LANDLOCK_RTSIG_OFFSET,
+            SignalHandler::Siginfo(handle_landlock_signal),
+            true,
+            libc::SA_RESTART,
+        )
+    }
+    .map_err(errno_error)?;
+    let signum = validate_signal_num(LANDLOCK_RTSIG_OFFSET, true).map_err(errno_error)?;
+    LANDLOCK_RULESET.store(ruleset.as_raw_fd(), Ordering::SeqCst);
+    LANDLOCK_FAILED.store(false, Ordering::SeqCst);
+
+    // Safe because these system calls can't fail.
+    let pid = unsafe { libc::getpid() };
+    let mut confined = HashSet::new();
+    confined.insert(unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t);
+    loop {
+        let threads: Vec<libc::pid_t> = process_threads()
+            .map_err(Error::LandlockThreads)?
+            .into_iter()
+            .filter(|tid| !confined.contains(tid))
+            .collect();
+        if threads.is_empty() {
+            break;
+        }
+
+        LANDLOCK_PENDING.store(threads.len(), Ordering::SeqCst);
+        for &tid in threads.iter() {
+            // Safe because we check the result.
+            if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signum) } != 0 {
+                let e = io::Error::last_os_error();
+                // The thread exited.
+                if e.raw_os_error() != Some(libc::ESRCH) {
+                    return Err(Error::LandlockThreads(e));
+                }
+                LANDLOCK_PENDING.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        // A thread blocking the signal would stay unconfined.
+        let deadline = Instant::now() + LANDLOCK_THREADS_TIMEOUT;
+        while LANDLOCK_PENDING.load(Ordering::SeqCst) != 0 {
+            if Instant::now() > deadline {
+                return Err(Error::LandlockThreads(io::Error::from(
+                    io::ErrorKind::TimedOut,
+                )));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        if LANDLOCK_FAILED.load(Ordering::SeqCst) {
+            return Err(Error::LandlockThreads(io::Error::new(
+                io::ErrorKind::Other,
+                "a thread of the process failed to confine itself",
+            )));
+        }
+        confined.extend(threads);
+    }
+
+    Ok(())
+}
+
+/// Confines the process with Landlock, from its VMM thread on, to the
+/// files of the VM, the ones of the rules of its Landlock configuration and
+/// the host resources registry, if any. The paths of the VM which don't
+/// exist are left out, the ones of the rules have to. The VM has to be the
+/// only one of the process, whose other threads, such as the HTTP server
+/// one, are confined as well.
+pub fn landlock(config: &VmConfig, registry: Option<&Path>) -> Result<()> {
+    let landlock = match &config.landlock {
+        Some(landlock) => landlock,
+        None => return Ok(()),
+    };
+
+    // Safe because no attribute is given along with the version flag, and
+    // we check the result.
+    let abi = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<LandlockRulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(Error::LandlockUnsupported(io::Error::last_os_error()));
+    }
+
+    let attr = LandlockRulesetAttr {
+        handled_access_fs: LANDLOCK_HANDLED_ACCESS_FS,
+    };
+    // Safe because the attribute is valid for its size, and we check the
+    // result.
+    let fd = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const LandlockRulesetAttr,
+            mem::size_of::<LandlockRulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::LandlockRuleset(io::Error::last_os_error()));
+    }
+    // Safe because the file descriptor was just created, and nothing else
+    // owns it.
+    let ruleset = unsafe { File::from_raw_fd(fd as RawFd) };
+
+    for (path, access) in landlock_paths(config, registry) {
+        match add_landlock_rule(&ruleset, &path, access) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!(
+                    "No Landlock rule for {}, which doesn't exist",
+                    path.display()
+                );
+            }
+            Err(e) => return Err(Error::LandlockRule(path, e)),
+        }
+    }
+    for rule in landlock.rules.iter() {
+        let access = match rule.access {
+            LandlockAccess::ReadOnly => LANDLOCK_READ,
+            LandlockAccess::ReadWrite => LANDLOCK_READ_WRITE | LANDLOCK_SOCKET_DIR,
+        };
+        add_landlock_rule(&ruleset, &rule.path, access)
+            .map_err(|e| Error::LandlockRule(rule.path.clone(), e))?;
+    }
+
+    // The process is confined from this thread on, the threads it creates
+    // afterwards inheriting the confinement.
+    if !restrict_self(ruleset.as_raw_fd()) {
+        return Err(Error::LandlockRestrict(io::Error::last_os_error()));
+    }
+    restrict_other_threads(&ruleset)?;
+    info!("VMM process confined with the Landlock ABI {}", abi);
+
+    Ok(())
+}
@@ -0,0 +1,407 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! SR-IOV virtual functions passed through with VFIO, which the VMM
+//! programs the MAC address of, and reports the link state of, through the
+//! netlink interface of their physical function.
+//!
+//! The physical function keeps the network interface on the host, the
+//! administrator setting the MAC address and the link state of each of its
+//! virtual functions, as `ip link set <pf> vf <n> ...` does: the guest driver
+//! of a virtual function gets the MAC address assigned this way, and can't
+//! change it. The VMM finds the physical function, and the index of the
+//! virtual function, through sysfs.
+
+use net_util::MacAddr;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::result;
+
+// Netlink messages, from <linux/netlink.h> and <linux/rtnetlink.h>.
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const RTM_NEWLINK: u16 = 16;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+// Link attributes, from <linux/if_link.h>.
+const IFLA_VFINFO_LIST: u16 = 22;
+const IFLA_EXT_MASK: u16 = 29;
+const IFLA_VF_INFO: u16 = 1;
+const IFLA_VF_MAC: u16 = 1;
+const IFLA_VF_LINK_STATE: u16 = 5;
+const RTEXT_FILTER_VF: u32 = 1;
+
+const NLMSG_HDR_LEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const RTATTR_HDR_LEN: usize = 4;
+
+// Large enough for the link of a physical function along with the
+// attributes of all its virtual functions.
+const NETLINK_RECV_LEN: usize = 64 << 10;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The device is given a MAC address but isn't a virtual function.
+    NotVirtualFunction(PathBuf),
+    /// Cannot read the sysfs entries of the device.
+    Sysfs(PathBuf, io::Error),
+    /// The physical function of the device has no network interface.
+    NoPhysicalFunctionNetdev(PathBuf),
+    /// The device isn't among the virtual functions of its physical
+    /// function.
+    VirtualFunctionIndex(PathBuf),
+    /// The netlink request on the physical function failed.
+    Netlink(io::Error),
+    /// The physical function doesn't report the virtual function.
+    MissingVirtualFunction(u32),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+/// Link state the administrator set for a virtual function.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum VfLinkState {
+    /// The link follows the one of the physical function.
+    Auto,
+    /// The link is up whatever the one of the physical function.
+    Enable,
+    /// The link is down.
+    Disable,
+}
+
+/// A virtual function, as `vm.info` reports it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct VfInfo {
+    /// Network interface of the physical function.
+    pub pf: String,
+    /// Index of the virtual function on its physical function.
+    pub vf: u32,
+    /// MAC address the administrator assigned to the virtual function,
+    /// if the physical function could be asked.
+    #[serde(default)]
+    pub mac: Option<MacAddr>,
+    #[serde(default)]
+    pub link_state: Option<VfLinkState>,
+    /// Operational state of the physical function, such as "up", which the
+    /// link of the virtual function follows in the `Auto` state.
+    #[serde(default)]
+    pub pf_operstate: Option<String>,
+}
+
+/// A virtual function and the network interface of its physical function.
+pub struct VirtualFunction {
+    pf_name: String,
+    pf_index: i32,
+    vf: u32,
+}
+
+impl VirtualFunction {
+    /// The virtual function at the sysfs `path` of a PCI device, or `None`
+    /// if the device isn't a virtual function.
+    pub fn from_sysfs(path: &Path) -> Result<Option<Self>> {
+        let physfn = path.join("physfn");
+        if !physfn.exists() {
+            return Ok(None);
+        }
+        let pf = fs::canonicalize(&physfn).map_err(|e| Error::Sysfs(physfn.clone(), e))?;
+        let device = fs::canonicalize(path).map_err(|e| Error::Sysfs(path.to_path_buf(), e))?;
+
+        let mut vf = None;
+        for entry in fs::read_dir(&pf).map_err(|e| Error::Sysfs(pf.clone(), e))? {
+            let entry = entry.map_err(|e| Error::Sysfs(pf.clone(), e))?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with("virtfn") {
+                continue;
+            }
+            let index = match name[6..].parse::<u32>() {
+                Ok(index) => index,
+                Err(_) => continue,
+            };
+            if fs::canonicalize(entry.path())
+                .map(|virtfn| virtfn == device)
+                .unwrap_or(false)
+            {
+                vf = Some(index);
+                break;
+            }
+        }
+        let vf = vf.ok_or_else(|| Error::VirtualFunctionIndex(path.to_path_buf()))?;
+
+        let net = pf.join("net");
+        let pf_name = fs::read_dir(&net)
+            .ok()
+            .and_then(|mut entries| entries.next())
+            .and_then(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .ok_or_else(|| Error::NoPhysicalFunctionNetdev(pf.clone()))?;
+        let ifindex = net.join(&pf_name).join("ifindex");
+        let pf_index = fs::read_to_string(&ifindex)
+            .map_err(|e| Error::Sysfs(ifindex.clone(), e))?
+            .trim()
+            .parse()
+            .map_err(|_| {
+                Error::Sysfs(
+                    ifindex.clone(),
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid interface index"),
+                )
+            })?;
+
+        Ok(Some(VirtualFunction {
+            pf_name,
+            pf_index,
+            vf,
+        }))
+    }
+
+    /// Assigns `mac` to the virtual function, as the administrator.
+    pub fn set_mac(&self, mac: &MacAddr) -> Result<()> {
+        let mut vf_mac = [0u8; 36];
+        vf_mac[..4].copy_from_slice(&self.vf.to_ne_bytes());
+        vf_mac[4..10].copy_from_slice(mac.get_bytes());
+
+        let mut msg = link_message(RTM_SETLINK, NLM_F_REQUEST | NLM_F_ACK, self.pf_index);
+        nest_attr(&mut msg, IFLA_VFINFO_LIST, |msg| {
+            nest_attr(msg, IFLA_VF_INFO, |msg| {
+                push_attr(msg, IFLA_VF_MAC, &vf_mac)
+            })
+        });
+
+        let socket = NetlinkSocket::new().map_err(Error::Netlink)?;
+        socket.request(&mut msg).map_err(Error::Netlink)?;
+        info!(
+            "Assigned {} to the virtual function {} of {}",
+            mac, self.vf, self.pf_name
+        );
+        Ok(())
+    }
+
+    /// The MAC address and the link state of the virtual function, along
+    /// with the state of its physical function.
+    pub fn info(&self) -> VfInfo {
+        let pf_operstate = fs::read_to_string(format!("/sys/class/net/{}/operstate", self.pf_name))
+            .ok()
+            .map(|state| state.trim().to_string());
+        let (mac, link_state) = match self.query() {
+            Ok((mac, link_state)) => (Some(mac), link_state),
+            Err(e) => {
+                warn!(
+                    "Cannot get the virtual function {} of {}: {:?}",
+                    self.vf, self.pf_name, e
+                );
+                (None, None)
+            }
+        };
+
+        VfInfo {
+            pf: self.pf_name.clone(),
+            vf: self.vf,
+            mac,
+            link_state,
+            pf_operstate,
+        }
+    }
+
+    // Asks the physical function for the attributes of its virtual
+    // functions, returning the ones of this virtual function.
+    fn query(&self) -> Result<(MacAddr, Option<VfLinkState>)> {
+        let mut msg = link_message(RTM_GETLINK, NLM_F_REQUEST, self.pf_index);
+        push_attr(&mut msg, IFLA_EXT_MASK, &RTEXT_FILTER_VF.to_ne_bytes());
+
+        let socket = NetlinkSocket::new().map_err(Error::Netlink)?;
+        let reply = socket.request(&mut msg).map_err(Error::Netlink)?;
+
+        for (kind, vfinfo_list) in attrs(&reply[NLMSG_HDR_LEN + IFINFOMSG_LEN..]) {
+            if kind != IFLA_VFINFO_LIST {
+                continue;
+            }
+            for (_, vf_info) in attrs(vfinfo_list)
+                .into_iter()
+                .filter(|(kind, _)| *kind == IFLA_VF_INFO)
+            {
+                let mut mac = None;
+                let mut link_state = None;
+                for (kind, payload) in attrs(vf_info) {
+                    // Both attributes start with the index of the virtual
+                    // function.
+                    if payload.len() < 8 || read_u32(payload, 0) != self.vf {
+                        continue;
+                    }
+                    match kind {
+                        IFLA_VF_MAC if payload.len() >= 10 => {
+                            mac = Some(MacAddr::from_bytes_unchecked(&payload[4..10]))
+                        }
+                        IFLA_VF_LINK_STATE => {
+                            link_state = match read_u32(payload, 4) {
+                                0 => Some(VfLinkState::Auto),
+                                1 => Some(VfLinkState::Enable),
+                                2 => Some(VfLinkState::Disable),
+                                _ => None,
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(mac) = mac {
+                    return Ok((mac, link_state));
+                }
+            }
+        }
+
+        Err(Error::MissingVirtualFunction(self.vf))
+    }
+}
+
+// A route netlink socket, closed when this is dropped.
+struct NetlinkSocket(RawFd);
+
+impl NetlinkSocket {
+    fn new() -> io::Result<Self> {
+        // Safe because the arguments are valid and the return value is
+        // checked.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(NetlinkSocket(fd))
+    }
+
+    // Sends the request `msg`, filling its length in, and returns the reply
+    // of the kernel, failing on an error message.
+    fn request(&self, msg: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+        let len = msg.len() as u32;
+        msg[..4].copy_from_slice(&len.to_ne_bytes());
+
+        // Safe because the address is a zeroed sockaddr_nl, which is valid,
+        // addressing the kernel.
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        // Safe because the buffer and the address are valid for the lengths
+        // given, and the return value is checked.
+        let ret = unsafe {
+            libc::sendto(
+                self.0,
+                msg.as_ptr() as *const libc::c_void,
+                msg.len(),
+                0,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut reply = vec![0u8; NETLINK_RECV_LEN];
+        // Safe because the buffer is valid for its length, and the return
+        // value is checked.
+        let ret = unsafe {
+            libc::recv(
+                self.0,
+                reply.as_mut_ptr() as *mut libc::c_void,
+                reply.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        reply.truncate(ret as usize);
+        if reply.len() < NLMSG_HDR_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated netlink reply",
+            ));
+        }
+
+        match read_u16(&reply, 4) {
+            NLMSG_ERROR if reply.len() >= NLMSG_HDR_LEN + 4 => {
+                let errno = read_u32(&reply, NLMSG_HDR_LEN) as i32;
+                if errno == 0 {
+                    Ok(reply)
+                } else {
+                    Err(io::Error::from_raw_os_error(-errno))
+                }
+            }
+            RTM_NEWLINK if reply.len() >= NLMSG_HDR_LEN + IFINFOMSG_LEN => Ok(reply),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected netlink reply",
+            )),
+        }
+    }
+}
+
+impl Drop for NetlinkSocket {
+    fn drop(&mut self) {
+        // Safe because the file descriptor is owned by this socket.
+        unsafe { libc::close(self.0) };
+    }
+}
+
+// A link message on the interface `index`, its length left to fill in.
+fn link_message(kind: u16, flags: u16, index: i32) -> Vec<u8> {
+    let mut msg = vec![0u8; NLMSG_HDR_LEN + IFINFOMSG_LEN];
+    msg[4..6].copy_from_slice(&kind.to_ne_bytes());
+    msg[6..8].copy_from_slice(&flags.to_ne_bytes());
+    msg[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    // The family of the ifinfomsg is AF_UNSPEC, 0.
+    msg[NLMSG_HDR_LEN + 4..NLMSG_HDR_LEN + 8].copy_from_slice(&index.to_ne_bytes());
+    msg
+}
+
+fn push_attr(msg: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    nest_attr(msg, kind, |msg| msg.extend_from_slice(payload));
+}
+
+// Appends the attribute `kind` with the payload `fill` appends, padded to
+// the 4 bytes alignment.
+fn nest_attr<F: FnOnce(&mut Vec<u8>)>(msg: &mut Vec<u8>, kind: u16, fill: F) {
+    let start = msg.len();
+    msg.extend_from_slice(&[0u8; RTATTR_HDR_LEN]);
+    fill(msg);
+    let len = (msg.len() - start) as u16;
+    msg[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+    msg[start + 2..start + 4].copy_from_slice(&kind.to_ne_bytes());
+    while msg.len() % 4 != 0 {
+        msg.push(0);
+    }
+}
+
+// The attributes of `buf`, as their types and payloads.
+fn attrs(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while buf.len() >= RTATTR_HDR_LEN {
+        let len = read_u16(buf, 0) as usize;
+        if len < RTATTR_HDR_LEN || len > buf.len() {
+            break;
+        }
+        attrs.push((read_u16(buf, 2) & NLA_TYPE_MASK, &buf[RTATTR_HDR_LEN..len]));
+        buf = &buf[std::cmp::min((len + 3) & !3, buf.len())..];
+    }
+    attrs
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    let mut bytes = [0u8; 2];
+    bytes.copy_from_slice(&buf[offset..offset + 2]);
+    u16::from_ne_bytes(bytes)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
@@ -0,0 +1,283 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Ownership of the VMs of a VMM process serving several tenants, for a
+//! single VMM daemon of a host to run the VMs of all of them.
+//!
+//! The HTTP clients give their owner token along with their requests, as an
+//! `Authorization: Bearer <token>` header. A VM is owned by the token of the
+//! request creating it, or claiming it from the pool, and only the requests
+//! giving that token reach it. The requests about the process, and about
+//! its default VM or the VMs of the pool, need the admin token, which
+//! reaches all the VMs as well. The requests of the process itself, and of
+//! the D-Bus API, aren't checked.
+//!
+//! The VMs of the other owners don't run host executables nor get host
+//! devices, and the host files they open, create or connect to have to be
+//! under the tenant directory the admin configured.
+
+use crate::api::{self, ApiClient, ApiError, ApiRequest, ApiResult};
+use crate::config::{
+    ConsoleOutputMode, LatencyProfile, TenancyConfig, VmConfig, DEFAULT_RNG_SOURCE,
+};
+use crate::host_resources;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub struct Tenancy {
+    admin_token: Option<String>,
+    // Resolved, for the paths of the VMs to be compared to it.
+    tenant_dir: Option<PathBuf>,
+}
+
+// The first field of the VM configuration only the admin may use, which has
+// the VMM run host executables, gives the guest host devices or the files
+// of the process, or hands it host CPUs, NUMA nodes or network interfaces,
+// if any.
+fn admin_field(config: &VmConfig) -> Option<&'static str> {
+    if config.hooks.is_some() {
+        return Some("hooks");
+    }
+    if config.plugins.is_some() || config.plugin_devices.is_some() {
+        return Some("plugins");
+    }
+    if config.devices.is_some() {
+        return Some("devices");
+    }
+    if config.user_devices.is_some() {
+        return Some("user_devices");
+    }
+    if config.net.iter().flatten().any(|net| net.fds.is_some()) {
+        return Some("net.fds");
+    }
+    if config.net.iter().flatten().any(|net| net.tap.is_some()) {
+        return Some("net.tap");
+    }
+    if config.net.iter().flatten().any(|net| net.macvtap.is_some()) {
+        return Some("net.macvtap");
+    }
+    if config.sgx_epc.is_some() {
+        return Some("sgx_epc");
+    }
+    if config.latency_profile == LatencyProfile::Realtime {
+        return Some("latency_profile");
+    }
+    if config.cpus.affinity.is_some() {
+        return Some("cpus.affinity");
+    }
+    if config
+        .numa
+        .iter()
+        .flatten()
+        .any(|numa| numa.host_node.is_some())
+    {
+        return Some("numa.host_node");
+    }
+    if config.cgroup.is_some() {
+        return Some("cgroup");
+    }
+    let security = &config.security;
+    if security.selinux_process_label.is_some()
+        || security.selinux_file_label.is_some()
+        || security.apparmor_profile.is_some()
+    {
+        return Some("security");
+    }
+
+    None
+}
+
+// The host files the VM opens, creates or connects to, the devices of the
+// host other than its random source aside.
+fn host_paths(config: &VmConfig) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    paths.extend(config.kernel.iter().map(|kernel| kernel.path.clone()));
+    paths.extend(config.initramfs.iter().map(|i| i.path.clone()));
+    paths.extend(config.acpi_tables.iter().flatten().map(|t| t.path.clone()));
+    if config.rng.src != Path::new(DEFAULT_RNG_SOURCE) {
+        paths.push(config.rng.src.clone());
+    }
+    paths.extend(
+        config
+            .disks
+            .iter()
+            .flatten()
+            .filter(|disk| disk.fd.is_none())
+            .map(|disk| disk.path.clone()),
+    );
+    paths.extend(
+        config
+            .pmem
+            .iter()
+            .flatten()
+            .filter(|pmem| pmem.fd.is_none())
+            .map(|pmem| pmem.file.clone()),
+    );
+    paths.extend(config.nvdimms.iter().flatten().map(|n| n.file.clone()));
+    paths.extend(
+        config
+            .memory
+            .zones()
+            .into_iter()
+            .filter_map(|zone| zone.file),
+    );
+    paths.extend(config.diagnostics.iter().filter_map(|d| d.path.clone()));
+
+    let consoles = [
+        (&config.serial.mode, &config.serial.file),
+        (&config.console.mode, &config.console.file),
+    ];
+    let uarts = config.uarts.iter().flatten().map(|u| (&u.mode, &u.file));
+    for (mode, file) in consoles.iter().cloned().chain(uarts) {
+        if let (ConsoleOutputMode::File, Some(path)) = (mode, file) {
+            paths.push(path.clone());
+        }
+    }
+    paths.extend(host_resources::vm_sockets(config));
+    paths.extend(config.fs.iter().flatten().map(|fs| fs.sock.clone()));
+    paths.extend(
+        config
+            .vhost_user_net
+            .iter()
+            .flatten()
+            .map(|net| PathBuf::from(&net.vu_cfg.sock)),
+    );
+    paths.extend(
+        config
+            .vhost_user_blk
+            .iter()
+            .flatten()
+            .map(|blk| PathBuf::from(&blk.vu_cfg.sock)),
+    );
+    paths.extend(config.tpm.iter().map(|tpm| tpm.socket.clone()));
+    paths.extend(config.input.iter().map(|input| input.socket.clone()));
+
+    paths
+}
+
+// Resolves the symbolic links of the path, the ones of its directory for a
+// file to be created.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    if let Ok(path) = fs::canonicalize(path) {
+        return Some(path);
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Some(fs::canonicalize(dir).ok()?.join(path.file_name()?))
+}
+
+impl Tenancy {
+    /// Reads the admin token from its file, the VMM having no admin without
+    /// one. The VMs of the other owners can't open host files without a
+    /// tenant directory.
+    pub fn new(config: &TenancyConfig) -> io::Result<Self> {
+        let admin_token = match &config.admin_token {
+            Some(path) => {
+                let token = fs::read_to_string(path)?.trim().to_string();
+                if !api::valid_owner_token(&token) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid admin token",
+                    ));
+                }
+                Some(token)
+            }
+            None => None,
+        };
+
+        let tenant_dir = match &config.tenant_dir {
+            Some(dir) => Some(fs::canonicalize(dir)?),
+            None => None,
+        };
+
+        Ok(Tenancy {
+            admin_token,
+            tenant_dir,
+        })
+    }
+
+    fn admin(&self, token: &str) -> bool {
+        self.admin_token
+            .as_ref()
+            .map_or(false, |admin_token| same_token(admin_token, token))
+    }
+
+    /// The owner of the VMs the client creates or claims, the token it gave,
+    /// None for the process itself.
+    pub fn owner(&self, client: &ApiClient) -> ApiResult<Option<String>> {
+        match client {
+            ApiClient::Local => Ok(None),
+            ApiClient::Http(Some(token)) => Ok(Some(token.clone())),
+            ApiClient::Http(None) => Err(ApiError::OwnerTokenMissing),
+        }
+    }
+
+    /// Checks that the client may operate on a VM of `owner`, None standing
+    /// for the process, its default VM, and the other VMs nobody owns.
+    pub fn authorize(&self, client: &ApiClient, owner: Option<&str>) -> ApiResult<()> {
+        let token = match self.owner(client)? {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+
+        if self.admin(&token) || owner.map_or(false, |owner| same_token(owner, &token)) {
+            Ok(())
+        } else {
+            Err(ApiError::VmNotOwned)
+        }
+    }
+
+    /// Checks that the request of a client other than the admin doesn't
+    /// have the VMM run host executables, nor give the guest host devices,
+    /// and that the host files it names are under the tenant directory.
+    pub fn check_request(&self, client: &ApiClient, request: &ApiRequest) -> ApiResult<()> {
+        match self.owner(client)? {
+            Some(token) if !self.admin(&token) => (),
+            _ => return Ok(()),
+        }
+
+        let paths = match request {
+            ApiRequest::VmCreate(config, _) | ApiRequest::VmApply(config, _) => {
+                if let Some(field) = admin_field(config) {
+                    return Err(ApiError::TenantField(field));
+                }
+                host_paths(config)
+            }
+            ApiRequest::VmAddUserDevice(..) => return Err(ApiError::TenantField("user_devices")),
+            ApiRequest::VmAddVsock(vsock, _) => vec![vsock.sock.clone()],
+            ApiRequest::VmCoredump(coredump, _) => vec![coredump.destination.clone()],
+            ApiRequest::VmScreenshot(screenshot, _) => vec![screenshot.destination.clone()],
+            ApiRequest::VmUpdate(update, _) => update
+                .serial
+                .iter()
+                .filter_map(|serial| serial.file.clone())
+                .collect(),
+            _ => Vec::new(),
+        };
+        let outside = paths
+            .into_iter()
+            .find(|path| match (&self.tenant_dir, resolve(path)) {
+                (Some(dir), Some(path)) => !path.starts_with(dir),
+                _ => true,
+            });
+        match outside {
+            Some(path) => Err(ApiError::TenantPath(path)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Compares the tokens in a time which doesn't depend on where they differ.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,178 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Lightweight tracing of the phases of the VMM, such as the setup of the
+//! guest memory, the creation of the devices, the loading of the kernel and
+//! the start of the vCPUs, for chasing boot time regressions.
+//!
+//! A phase is timed by the scope of a `ScopedTrace` guard, which costs an
+//! atomic load when tracing isn't started. The trace holds the phases since
+//! tracing was started, and is written once the VM booted, as JSON or in
+//! the Trace Event Format chrome://tracing and Perfetto load.
+
+use crate::config::{TraceFormat, TracingConfig};
+use std::cell::Cell;
+use std::fs::File;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref TRACER: Mutex<Option<Tracer>> = Mutex::new(None);
+}
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    // Number of phases the thread is in, the nested ones being deeper.
+    static DEPTH: Cell<u32> = Cell::new(0);
+}
+
+struct TraceEvent {
+    name: &'static str,
+    thread_name: String,
+    tid: i64,
+    start: Duration,
+    duration: Duration,
+    depth: u32,
+}
+
+struct Tracer {
+    config: TracingConfig,
+    start: Instant,
+    events: Vec<TraceEvent>,
+}
+
+/// Starts tracing the phases of the process.
+pub fn start(config: TracingConfig) {
+    *TRACER.lock().unwrap() = Some(Tracer {
+        config,
+        start: Instant::now(),
+        events: Vec::new(),
+    });
+    TRACING.store(true, Ordering::Release);
+}
+
+/// Times a phase, from its creation until it is dropped.
+pub struct ScopedTrace {
+    name: &'static str,
+    start: Option<Instant>,
+}
+
+impl ScopedTrace {
+    pub fn new(name: &'static str) -> Self {
+        if !TRACING.load(Ordering::Acquire) {
+            return ScopedTrace { name, start: None };
+        }
+
+        DEPTH.with(|depth| depth.set(depth.get() + 1));
+        ScopedTrace {
+            name,
+            start: Some(Instant::now()),
+        }
+    }
+}
+
+impl Drop for ScopedTrace {
+    fn drop(&mut self) {
+        let start = match self.start {
+            Some(start) => start,
+            None => return,
+        };
+        let duration = start.elapsed();
+        let depth = DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            depth.get()
+        });
+
+        if let Some(tracer) = TRACER.lock().unwrap().as_mut() {
+            if start < tracer.start {
+                return;
+            }
+            tracer.events.push(TraceEvent {
+                name: self.name,
+                thread_name: thread::current().name().unwrap_or("").to_string(),
+                // Safe because the system call has no side effect.
+                tid: unsafe { libc::syscall(libc::SYS_gettid) },
+                start: start.duration_since(tracer.start),
+                duration,
+                depth,
+            });
+        }
+    }
+}
+
+/// Writes the trace to its file, if tracing was started, replacing the
+/// trace written before.
+pub fn dump() -> io::Result<()> {
+    let tracer = TRACER.lock().unwrap();
+    let tracer = match tracer.as_ref() {
+        Some(tracer) => tracer,
+        None => return Ok(()),
+    };
+
+    let trace = match tracer.config.format {
+        TraceFormat::Json => json_trace(&tracer.events),
+        TraceFormat::Chrome => chrome_trace(&tracer.events),
+    };
+    let file = File::create(&tracer.config.path)?;
+    serde_json::to_writer(file, &trace).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+fn json_trace(events: &[TraceEvent]) -> serde_json::Value {
+    let events: Vec<serde_json::Value> = events
+        .iter()
+        .map(|event| {
+            serde_json::json!({
+                "name": event.name,
+                "thread": event.thread_name,
+                "depth": event.depth,
+                "start_us": event.start.as_micros() as u64,
+                "duration_us": event.duration.as_micros() as u64,
+            })
+        })
+        .collect();
+
+    serde_json::json!({ "events": events })
+}
+
+// Complete events, and the names of their threads as metadata events.
+fn chrome_trace(events: &[TraceEvent]) -> serde_json::Value {
+    let pid = std::process::id();
+    let mut trace_events: Vec<serde_json::Value> = events
+        .iter()
+        .map(|event| {
+            serde_json::json!({
+                "name": event.name,
+                "cat": "vmm",
+                "ph": "X",
+                "pid": pid,
+                "tid": event.tid,
+                "ts": event.start.as_micros() as u64,
+                "dur": event.duration.as_micros() as u64,
+            })
+        })
+        .collect();
+
+    let mut threads: Vec<(i64, &str)> = events
+        .iter()
+        .map(|event| (event.tid, event.thread_name.as_str()))
+        .collect();
+    threads.sort();
+    threads.dedup();
+    for (tid, thread_name) in threads {
+        trace_events.push(serde_json::json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": pid,
+            "tid": tid,
+            "args": { "name": thread_name },
+        }));
+    }
+
+    serde_json::json!({ "traceEvents": trace_events, "displayTimeUnit": "ms" })
+}
@@ -12,7 +12,7 @@
 extern crate arch;
 extern crate devices;
 extern crate epoll;
-extern crate kvm_ioctls;
+extern crate hypervisor;
 extern crate libc;
 extern crate linux_loader;
 extern crate net_util;
@@ -23,53 +23,118 @@ extern crate vm_allocator;
 extern crate vm_memory;
 extern crate vm_virtio;
 
-use crate::config::VmConfig;
+use crate::api::{PassedFds, PciDeviceInfo, VmSensors, VmThrottle, VmThrottleData};
+use crate::cgroup::{self, VmCgroup};
+#[cfg(target_arch = "x86_64")]
+use crate::clock_drift::ClockDriftMonitor;
+#[cfg(target_arch = "x86_64")]
+use crate::config::{EnabledCpuFeature, Platform};
+use crate::config::{
+    LatencyProfile, Profile, RateLimiterUpdate, UserDeviceConfig, VmConfig, VmUpdateConfig,
+    VsockConfig,
+};
 use crate::cpu;
-use crate::device_manager::{get_win_size, Console, DeviceManager, DeviceManagerError};
-use arch::RegionType;
-use devices::ioapic;
-use kvm_bindings::{
-    kvm_enable_cap, kvm_pit_config, kvm_userspace_memory_region, KVM_CAP_SPLIT_IRQCHIP,
-    KVM_PIT_SPEAKER_DUMMY,
+use crate::device_manager::{
+    get_win_size, Console, DeviceManager, DeviceManagerError, PtyPair, SerialSocket, Uart,
 };
-use kvm_ioctls::*;
+use crate::diagnostics::{
+    self, Bundle, DeviceAuditInfo, DeviceAuditState, DeviceState, QueueState, VcpuState,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::gdb::{self, GdbStub};
+use crate::guest_os::{self, GuestOsAgent, GuestOsInfo};
+use crate::hooks::{self, HostHooks};
+use crate::host_resources::{self, Leftover};
+use crate::input::{self, InputSocket};
+use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
+use crate::realtime;
+use crate::sriov::VfInfo;
+use crate::tracer::{self, ScopedTrace};
+use arch::{BootProtocol, EntryPoint, RegionType};
+#[cfg(target_arch = "x86_64")]
+use devices::ioapic;
+#[cfg(target_arch = "x86_64")]
+use hypervisor::x86_64::{ConfidentialVm, SevSnpPageType};
+use hypervisor::Capability;
+#[cfg(target_arch = "x86_64")]
+use hypervisor::{UserMemoryRegion, VmExit, VmmOps};
 
 use linux_loader::cmdline::Cmdline;
+#[cfg(target_arch = "x86_64")]
 use linux_loader::loader::KernelLoader;
 use signal_hook::{iterator::Signals, SIGWINCH};
+use std::collections::BTreeMap;
 use std::ffi::CString;
-use std::fs::{File, OpenOptions};
-use std::io;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::ops::Deref;
-use std::os::unix::io::FromRawFd;
+#[cfg(target_arch = "x86_64")]
+use std::os::unix::io::RawFd;
+#[cfg(target_arch = "x86_64")]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::{result, str, thread};
 use vm_allocator::{GsiApic, SystemAllocator};
-use vm_memory::guest_memory::FileOffset;
-use vm_memory::{
-    Address, Bytes, Error as MmapError, GuestAddress, GuestMemory, GuestMemoryMmap,
-    GuestMemoryRegion, GuestUsize,
-};
-use vmm_sys_util::eventfd::EventFd;
+use vm_memory::{Address, Bytes, Error as MmapError, GuestAddress, GuestMemoryMmap, GuestUsize};
+#[cfg(target_arch = "x86_64")]
+use vm_memory::{GuestMemory, GuestMemoryRegion};
+use vm_virtio::BlockCache;
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 use vmm_sys_util::terminal::Terminal;
 
+#[cfg(target_arch = "x86_64")]
 const X86_64_IRQ_BASE: u32 = 5;
 
 // CPUID feature bits
+#[cfg(target_arch = "x86_64")]
 const TSC_DEADLINE_TIMER_ECX_BIT: u8 = 24; // tsc deadline timer ecx bit.
+#[cfg(target_arch = "x86_64")]
 const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
+#[cfg(target_arch = "x86_64")]
+const HTT_EDX_BIT: u8 = 28; // Hyper-Threading Technology edx bit.
 
 // 64 bit direct boot entry offset for bzImage
+#[cfg(target_arch = "x86_64")]
 const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
 
+// bzImage setup header magic, and its offset in the image
+#[cfg(target_arch = "x86_64")]
+const BZIMAGE_HEADER_MAGIC: &[u8; 4] = b"HdrS";
+#[cfg(target_arch = "x86_64")]
+const BZIMAGE_HEADER_MAGIC_OFFSET: u64 = 0x202;
+
+// ELF note of the PVH entry point, from xen/include/public/elfnote.h
+#[cfg(target_arch = "x86_64")]
+const XEN_ELFNOTE_PHYS32_ENTRY: u64 = 18;
+#[cfg(target_arch = "x86_64")]
+const XEN_ELFNOTE_NAME: &[u8; 4] = b"Xen\0";
+
+// Layout of the ELF64 headers, from <elf.h>
+#[cfg(target_arch = "x86_64")]
+const ELF_HEADER_SIZE: usize = 64;
+#[cfg(target_arch = "x86_64")]
+const ELF_PROGRAM_HEADER_SIZE: usize = 56;
+#[cfg(target_arch = "x86_64")]
+const ELF_NOTE_HEADER_SIZE: usize = 12;
+#[cfg(target_arch = "x86_64")]
+const ELF_PT_NOTE: u64 = 4;
+
+// SEV-SNP guest policy: SMT allowed, and the reserved bit that must be set.
+#[cfg(target_arch = "x86_64")]
+const SEV_SNP_POLICY: u64 = 0x3_0000;
+
+#[cfg(target_arch = "x86_64")]
+const PAGE_SIZE: u64 = 0x1000;
+
 /// Errors associated with VM management
 #[derive(Debug)]
 pub enum Error {
     /// Cannot open the VM file descriptor.
     VmFd(io::Error),
 
-    /// Cannot create the KVM instance
+    /// Cannot create the hypervisor VM
     VmCreate(io::Error),
 
     /// Cannot set the VM up
@@ -78,6 +143,12 @@ pub enum Error {
     /// Cannot open the kernel image
     KernelFile(io::Error),
 
+    /// Cannot open or read the initramfs image
+    InitramfsFile(io::Error),
+
+    /// Cannot load the initramfs in memory
+    InitramfsLoad,
+
     /// Mmap backed guest memory error
     GuestMemory(MmapError),
 
@@ -95,6 +166,36 @@ pub enum Error {
     /// Write to the console failed.
     Console(vmm_sys_util::errno::Error),
 
+    /// Cannot read from a pseudo-terminal.
+    PtyRead(io::Error),
+
+    /// Cannot add a pseudo-terminal to the VMM epoll context.
+    PtyEpoll(io::Error),
+
+    /// Cannot accept a serial socket connection.
+    SerialSocketAccept(io::Error),
+
+    /// Cannot read from the serial socket.
+    SerialSocketRead(io::Error),
+
+    /// Cannot add the serial socket to the VMM epoll context.
+    SerialSocketEpoll(io::Error),
+
+    /// Cannot add the GDB stub socket or events to the VMM epoll context.
+    GdbEpoll(io::Error),
+
+    /// Cannot add the out of space event to the VMM epoll context.
+    OutOfSpaceEpoll(io::Error),
+
+    /// Cannot add the guest panic event to the VMM epoll context.
+    PvpanicEpoll(io::Error),
+
+    /// Cannot add the clock drift event to the VMM epoll context.
+    ClockDriftEpoll(io::Error),
+
+    /// Cannot add the PCI eject event to the VMM epoll context.
+    PciEjectEpoll(io::Error),
+
     /// Cannot setup terminal in raw mode.
     SetTerminalRaw(vmm_sys_util::errno::Error),
 
@@ -110,11 +211,8 @@ pub enum Error {
     /// Memory is overflow
     MemOverflow,
 
-    /// Failed to create shared file.
-    SharedFileCreate(io::Error),
-
-    /// Failed to set shared file length.
-    SharedFileSetLen(io::Error),
+    /// Cannot create the guest memory.
+    MemoryManager(MemoryManagerError),
 
     /// Failed to allocate a memory range.
     MemoryRangeAllocation,
@@ -128,9 +226,6 @@ pub enum Error {
     /// Failed to join on vCPU threads
     ThreadCleanup,
 
-    /// Failed to create a new KVM instance
-    KvmNew(io::Error),
-
     /// VM is not created
     VmNotCreated,
 
@@ -140,18 +235,275 @@ pub enum Error {
     /// Cannot clone EventFd.
     EventFdClone(io::Error),
 
+    /// Cannot create EventFd.
+    EventFdCreate(io::Error),
+
+    /// Cannot read from EventFd.
+    EventFdRead(io::Error),
+
+    /// Cannot write to EventFd.
+    EventFdWrite(io::Error),
+
     /// Invalid VM state transition
     InvalidStateTransition(VmState, VmState),
 
     /// Error from CPU handling
     CpuManager(cpu::Error),
+
+    /// Cannot notify the guest about a power button press
+    PowerButton(io::Error),
+
+    /// The VM has no ACPI power button
+    PowerButtonNotSupported,
+
+    /// Cannot notify the guest about new sensors readings
+    SensorsNotify(io::Error),
+
+    /// The VM has no ACPI battery nor thermal zone
+    SensorsNotSupported,
+
+    /// Cannot flush a disk or persistent memory image
+    ImageSync(io::Error),
+
+    /// Cannot reserve or release the host resources of the VM
+    HostResources(crate::host_resources::Error),
+
+    /// Cannot apply the security labels of the VM
+    Security(crate::security::Error),
+
+    /// VM is not paused
+    VmNotPaused,
+
+    /// Cannot write the core dump of the VM
+    Coredump(io::Error),
+
+    /// Core dumps are not supported on this architecture
+    CoredumpNotSupported,
+
+    /// Cannot write a diagnostic bundle
+    DiagnosticBundle(io::Error),
+
+    /// The VM isn't configured with the device audit
+    DeviceAuditDisabled,
+
+    /// The VM has no virtio-gpu device
+    GpuNotConfigured,
+
+    /// The guest didn't set up the display of the virtio-gpu device
+    DisplayDisabled,
+
+    /// Cannot write the display of the virtio-gpu device
+    Screenshot(io::Error),
+
+    /// Cannot reset a virtio device
+    ResetDevice(DeviceManagerError),
+
+    /// Cannot pause the devices
+    PauseDevices(DeviceManagerError),
+
+    /// Cannot resume the devices
+    ResumeDevices(DeviceManagerError),
+
+    /// Cannot change the weight of a disk in its disk group
+    SetDiskWeight(DeviceManagerError),
+
+    /// Cannot switch the serial port output, or replace a rate limiter
+    UpdateDevice(DeviceManagerError),
+
+    /// The weight of a disk in its disk group can't be zero
+    InvalidDiskWeight,
+
+    /// Cannot log the pages the guest dirties
+    DirtyLog(MemoryManagerError),
+
+    /// The window the dirty rate is sampled over is zero or longer than a
+    /// minute
+    InvalidDirtyRateDuration(u64),
+
+    /// Cannot spawn the thread measuring the dirty rate
+    DirtyRateThread(io::Error),
+
+    /// Cannot hot-add a device
+    AddDevice(DeviceManagerError),
+
+    /// Cannot ask the guest to eject a device
+    RemoveDevice(DeviceManagerError),
+
+    /// The VM configuration has no vsock, vfio-user or removable disk
+    /// device with the given ID
+    UnknownDevice(String),
+
+    /// Cannot remove the devices the guest ejected
+    EjectDevices(DeviceManagerError),
+
+    /// The VM can't take the changes of these fields of its configuration
+    /// without being created anew
+    ConfigNotApplicable(Vec<String>),
+
+    /// The devices are only hot-added and removed through ACPI on x86_64,
+    /// on the PCI bus
+    PciHotplugNotSupported,
+
+    #[cfg(target_arch = "x86_64")]
+    /// GDB stub error
+    Gdb(gdb::Error),
+
+    /// Cannot listen for the host hook requests of the guest
+    HostHooks(hooks::Error),
+
+    /// Cannot listen for the guest OS reports of the guest agent
+    GuestOsAgent(guest_os::Error),
+
+    /// Cannot listen for the input events of the host
+    InputSocket(input::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot start checking the guest clock drift
+    ClockDrift(io::Error),
+
+    #[cfg(target_arch = "aarch64")]
+    /// There is no GDB stub on this architecture
+    GdbNotSupported,
+
+    #[cfg(target_arch = "aarch64")]
+    /// The guest clock drift can't be checked on this architecture
+    ClockDriftNotSupported,
+
+    #[cfg(target_arch = "aarch64")]
+    /// Cannot create the interrupt controller
+    CreateGic(arch::aarch64::gic::Error),
+
+    /// Cannot load the kernel, or write its boot parameters, the device tree
+    /// on AArch64, in memory
+    ConfigureSystem(arch::Error),
+
+    /// Cannot read a user ACPI table
+    AcpiTableFile(PathBuf, io::Error),
+
+    /// A user ACPI table doesn't have a valid header
+    InvalidAcpiTable(PathBuf),
+
+    /// A user ACPI table signature is invalid, or one the VMM generates
+    AcpiTableSignature(String),
+
+    /// The user ACPI tables, of this total size, don't fit in memory
+    AcpiTablesTooLarge(usize),
+
+    /// SGX EPC sections are configured, but the VM can't run enclaves
+    SgxNotSupported,
+
+    /// A virtualization extension is enabled, but KVM doesn't support it
+    /// nested
+    #[cfg(target_arch = "x86_64")]
+    NestedNotSupported(EnabledCpuFeature),
+
+    /// AMX is enabled, but the VMM process isn't allowed to give its guests
+    /// the tile data state
+    #[cfg(target_arch = "x86_64")]
+    AmxPermission(io::Error),
+
+    /// AMX is enabled, but KVM doesn't support it
+    #[cfg(target_arch = "x86_64")]
+    AmxNotSupported,
+
+    /// The PTP clock is configured, but the guest can't use it, for the
+    /// reason given
+    #[cfg(target_arch = "x86_64")]
+    PtpNotSupported(&'static str),
+
+    /// Cannot resolve a placeholder of the kernel command line
+    CmdlineVariable(crate::cmdline::Error),
+
+    /// The feature isn't supported by confidential guests
+    ConfidentialFeature(&'static str),
+
+    /// The guest hostname is invalid
+    InvalidHostname(String),
+
+    /// The feature isn't supported by realtime VMs
+    RealtimeFeature(&'static str),
+
+    /// The VM configuration doesn't meet these constraints between its
+    /// fields
+    InvalidConfig(Vec<crate::config::Error<'static>>),
+
+    /// Cannot pin the realtime vCPUs to isolated host CPUs, or lock the
+    /// VMM memory
+    Realtime(realtime::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot have the vCPUs run HLT and PAUSE without exiting
+    DisableIdleExits(io::Error),
+
+    #[cfg(target_arch = "aarch64")]
+    /// There are no confidential guests on this architecture
+    ConfidentialNotSupported,
+
+    #[cfg(target_arch = "x86_64")]
+    /// TDX guests need a split irqchip, and MSI support
+    TdxIrqchip,
+
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot set the confidential guest up
+    ConfidentialVmSetup(io::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Invalid TDVF firmware image
+    Tdvf(arch::x86_64::tdx::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// The TD HOB, of this size, doesn't fit in its TDVF section
+    TdHobTooLarge(usize),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Invalid SEV metadata in the firmware image
+    SevMetadata(arch::x86_64::sev::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// A range the confidential guest starts with is out of its memory
+    ConfidentialRange(u64),
+
+    #[cfg(target_arch = "x86_64")]
+    /// Cannot read back the ACPI tables, for the firmware
+    AcpiTablesRead,
+
+    #[cfg(target_arch = "x86_64")]
+    /// Invalid IGVM image
+    Igvm(arch::x86_64::igvm::Error),
+
+    #[cfg(target_arch = "x86_64")]
+    /// The IGVM image needs RAM at this address
+    IgvmRequiredMemory(u64),
+
+    #[cfg(target_arch = "x86_64")]
+    /// The parameters don't fit in the IGVM parameter area at this address
+    IgvmParameterArea(u64),
+
+    #[cfg(target_arch = "x86_64")]
+    /// An IGVM image is booted without an initramfs
+    IgvmInitramfs,
+
+    /// Cannot place the VMM into the cgroup of the VM
+    Cgroup(cgroup::Error),
+
+    /// The VM has no cgroup to throttle its vCPUs with
+    CgroupNotConfigured,
+
+    /// The CPU quota of the vCPUs can't be zero
+    InvalidThrottleQuota,
+
+    /// Cannot change the CPU quota of the vCPUs, or read their CPU time
+    Throttle(cgroup::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
 pub struct VmInfo<'a> {
     pub memory: &'a Arc<RwLock<GuestMemoryMmap>>,
-    pub vm_fd: &'a Arc<VmFd>,
+    pub memory_manager: &'a Arc<Mutex<MemoryManager>>,
+    pub vm: &'a Arc<dyn hypervisor::Vm>,
     pub vm_cfg: &'a VmConfig,
+    pub passed_fds: &'a PassedFds,
+    pub block_cache: &'a Arc<BlockCache>,
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
@@ -163,7 +515,7 @@ pub enum VmState {
 }
 
 impl VmState {
-    fn valid_transition(self, new_state: VmState) -> Result<()> {
+    pub fn valid_transition(self, new_state: VmState) -> Result<()> {
         match self {
             VmState::Created => match new_state {
                 VmState::Created | VmState::Shutdown | VmState::Paused => {
@@ -198,7 +550,9 @@ impl VmState {
 
 pub struct Vm {
     kernel: File,
+    initramfs: Option<File>,
     memory: Arc<RwLock<GuestMemoryMmap>>,
+    memory_manager: Arc<Mutex<MemoryManager>>,
     threads: Vec<thread::JoinHandle<()>>,
     devices: DeviceManager,
     config: Arc<VmConfig>,
@@ -206,9 +560,24 @@ pub struct Vm {
     signals: Option<Signals>,
     state: RwLock<VmState>,
     cpu_manager: cpu::CpuManager,
+    #[cfg(target_arch = "x86_64")]
+    gdb: Option<GdbStub>,
+    #[cfg(target_arch = "x86_64")]
+    clock_drift: Option<ClockDriftMonitor>,
+    // Kept for the guest to request the host hooks as long as the VM lives.
+    _host_hooks: Vec<HostHooks>,
+    // Kept for the guest agent to report the guest OS as long as the VM
+    // lives.
+    _guest_os_agent: Option<GuestOsAgent>,
+    // Kept for the host to send input events as long as the VM lives.
+    _input_socket: Option<InputSocket>,
+    // Last, for its sub-groups to be removed once the other threads of the
+    // VM are gone.
+    cgroup: Option<VmCgroup>,
 }
 
-fn get_host_cpu_phys_bits() -> u8 {
+#[cfg(target_arch = "x86_64")]
+pub fn get_host_cpu_phys_bits() -> u8 {
     use core::arch::x86_64;
     unsafe {
         let leaf = x86_64::__cpuid(0x8000_0000);
@@ -222,13 +591,72 @@ fn get_host_cpu_phys_bits() -> u8 {
     }
 }
 
+#[cfg(target_arch = "aarch64")]
+pub fn get_host_cpu_phys_bits() -> u8 {
+    // The smallest IPA size KVM supports, without asking for a larger one
+    // when creating the VM.
+    40
+}
+
 impl Vm {
-    pub fn new(config: Arc<VmConfig>, exit_evt: EventFd, reset_evt: EventFd) -> Result<Self> {
-        let kvm = Kvm::new().map_err(Error::KvmNew)?;
+    pub fn new(
+        config: Arc<VmConfig>,
+        hypervisor: Arc<dyn hypervisor::Hypervisor>,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        vcpu_failure_evt: EventFd,
+        passed_fds: &PassedFds,
+        block_cache: &Arc<BlockCache>,
+    ) -> Result<Self> {
+        let _trace = ScopedTrace::new("vm_create");
         let kernel =
             File::open(&config.kernel.as_ref().unwrap().path).map_err(Error::KernelFile)?;
-        let fd = kvm.create_vm().map_err(Error::VmCreate)?;
-        let fd = Arc::new(fd);
+        let initramfs = match &config.initramfs {
+            Some(initramfs) => Some(File::open(&initramfs.path).map_err(Error::InitramfsFile)?),
+            None => None,
+        };
+        // The configurations from the API aren't parsed.
+        if let Some(feature) = config.confidential_conflict() {
+            return Err(Error::ConfidentialFeature(feature));
+        }
+        if let Some(feature) = config.realtime_conflict() {
+            return Err(Error::RealtimeFeature(feature));
+        }
+        if let Some(hostname) = &config.hostname {
+            if !crate::config::valid_hostname(hostname) {
+                return Err(Error::InvalidHostname(hostname.clone()));
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            if config.gdb.is_some() {
+                return Err(Error::GdbNotSupported);
+            }
+            if config.clock_drift.is_some() {
+                return Err(Error::ClockDriftNotSupported);
+            }
+        }
+        #[cfg(target_arch = "x86_64")]
+        let vm = match config.platform {
+            Platform::Default => hypervisor.create_vm(),
+            Platform::Tdx => hypervisor.create_confidential_vm(ConfidentialVm::Tdx),
+            Platform::SevSnp => hypervisor.create_confidential_vm(ConfidentialVm::SevSnp),
+        }
+        .map_err(Error::VmCreate)?;
+        #[cfg(target_arch = "aarch64")]
+        let vm = {
+            if config.platform.is_confidential() {
+                return Err(Error::ConfidentialNotSupported);
+            }
+            hypervisor.create_vm().map_err(Error::VmCreate)?
+        };
+        #[cfg(target_arch = "x86_64")]
+        {
+            if config.platform == Platform::SevSnp {
+                vm.sev_snp_init(SEV_SNP_POLICY)
+                    .map_err(Error::ConfidentialVmSetup)?;
+            }
+        }
 
         // Init guest memory
         let arch_mem_regions = arch::arch_memory_regions(config.memory.size);
@@ -254,127 +682,208 @@ impl Vm {
             }
         }
 
-        let guest_memory = match config.memory.file {
-            Some(ref file) => {
-                let mut mem_regions = Vec::<(GuestAddress, usize, Option<FileOffset>)>::new();
-                for region in ram_regions.iter() {
-                    if file.is_file() {
-                        let file = OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(file)
-                            .map_err(Error::SharedFileCreate)?;
-
-                        file.set_len(region.1 as u64)
-                            .map_err(Error::SharedFileSetLen)?;
-
-                        mem_regions.push((region.0, region.1, Some(FileOffset::new(file, 0))));
-                    } else if file.is_dir() {
-                        let fs_str = format!("{}{}", file.display(), "/tmpfile_XXXXXX");
-                        let fs = std::ffi::CString::new(fs_str).unwrap();
-                        let mut path = fs.as_bytes_with_nul().to_owned();
-                        let path_ptr = path.as_mut_ptr() as *mut _;
-                        let fd = unsafe { libc::mkstemp(path_ptr) };
-                        unsafe { libc::unlink(path_ptr) };
-
-                        let f = unsafe { File::from_raw_fd(fd) };
-                        f.set_len(region.1 as u64)
-                            .map_err(Error::SharedFileSetLen)?;
-
-                        mem_regions.push((region.0, region.1, Some(FileOffset::new(f, 0))));
-                    }
-                }
-
-                GuestMemoryMmap::with_files(&mem_regions).map_err(Error::GuestMemory)?
+        let memory_manager = {
+            let _trace = ScopedTrace::new("memory_setup");
+            Arc::new(Mutex::new(
+                MemoryManager::new(
+                    vm.clone(),
+                    hypervisor.get_max_memory_slots(),
+                    &config.memory,
+                    &ram_regions,
+                    config.numa.as_ref().map(Vec::as_slice).unwrap_or(&[]),
+                    config.platform.is_confidential(),
+                )
+                .map_err(Error::MemoryManager)?,
+            ))
+        };
+        let guest_memory = memory_manager.lock().unwrap().guest_memory();
+
+        // The SGX EPC sections are placed right above the RAM, out of it.
+        #[cfg(target_arch = "x86_64")]
+        let sgx_epc_sections = match &config.sgx_epc {
+            Some(sections) => {
+                let mem_end = guest_memory.read().unwrap().end_addr();
+                let start = if mem_end < arch::layout::MEM_32BIT_RESERVED_START {
+                    arch::layout::RAM_64BIT_START
+                } else {
+                    mem_end.unchecked_add(1)
+                };
+                memory_manager
+                    .lock()
+                    .unwrap()
+                    .add_sgx_epc_sections(start, sections)
+                    .map_err(Error::MemoryManager)?
             }
-            None => GuestMemoryMmap::new(&ram_regions).map_err(Error::GuestMemory)?,
+            None => Vec::new(),
         };
 
-        guest_memory
-            .with_regions(|index, region| {
-                let mem_region = kvm_userspace_memory_region {
-                    slot: index as u32,
-                    guest_phys_addr: region.start_addr().raw_value(),
-                    memory_size: region.len() as u64,
-                    userspace_addr: region.as_ptr() as u64,
-                    flags: 0,
-                };
+        let msi_capable = hypervisor.check_capability(Capability::SignalMsi);
+
+        #[cfg(target_arch = "x86_64")]
+        let (arch_config, userspace_ioapic) = {
+            // Set TSS
+            vm.set_tss_address(arch::x86_64::layout::KVM_TSS_ADDRESS.raw_value() as usize)
+                .map_err(Error::VmSetup)?;
+
+            // The TDX module only emulates the local APIC.
+            if config.platform == Platform::Tdx
+                && !(hypervisor.check_capability(Capability::TscDeadlineTimer)
+                    && hypervisor.check_capability(Capability::SplitIrqchip)
+                    && msi_capable)
+            {
+                return Err(Error::TdxIrqchip);
+            }
 
-                // Safe because the guest regions are guaranteed not to overlap.
-                unsafe { fd.set_user_memory_region(mem_region) }
-            })
-            .map_err(|_| Error::GuestMemory(MmapError::NoMemoryRegion))?;
-
-        // Set TSS
-        fd.set_tss_address(arch::x86_64::layout::KVM_TSS_ADDRESS.raw_value() as usize)
-            .map_err(Error::VmSetup)?;
-
-        let msi_capable = kvm.check_extension(Cap::SignalMsi);
-
-        let mut cpuid_patches = Vec::new();
-        let mut userspace_ioapic = false;
-        if kvm.check_extension(Cap::TscDeadlineTimer) {
-            if kvm.check_extension(Cap::SplitIrqchip) && msi_capable {
-                // Create split irqchip
-                // Only the local APIC is emulated in kernel, both PICs and IOAPIC
-                // are not.
-                let mut cap: kvm_enable_cap = Default::default();
-                cap.cap = KVM_CAP_SPLIT_IRQCHIP;
-                cap.args[0] = ioapic::NUM_IOAPIC_PINS as u64;
-                fd.enable_cap(&cap).map_err(Error::VmSetup)?;
-
-                // Because of the split irqchip, we need a userspace IOAPIC.
-                userspace_ioapic = true;
+            let mut cpuid_patches = Vec::new();
+            let mut userspace_ioapic = false;
+            if hypervisor.check_capability(Capability::TscDeadlineTimer) {
+                if hypervisor.check_capability(Capability::SplitIrqchip) && msi_capable {
+                    // Create split irqchip
+                    // Only the local APIC is emulated in kernel, both PICs and IOAPIC
+                    // are not.
+                    vm.enable_split_irq(ioapic::NUM_IOAPIC_PINS as u32)
+                        .map_err(Error::VmSetup)?;
+
+                    // Because of the split irqchip, we need a userspace IOAPIC.
+                    userspace_ioapic = true;
+                } else {
+                    // Create irqchip
+                    // A local APIC, 2 PICs and an IOAPIC are emulated in kernel.
+                    vm.create_irq_chip().map_err(Error::VmSetup)?;
+                }
+
+                // Patch tsc deadline timer bit
+                cpuid_patches.push(cpu::CpuidPatch {
+                    function: 1,
+                    index: 0,
+                    flags_bit: None,
+                    eax_bit: None,
+                    ebx_bit: None,
+                    ecx_bit: Some(TSC_DEADLINE_TIMER_ECX_BIT),
+                    edx_bit: None,
+                });
             } else {
                 // Create irqchip
                 // A local APIC, 2 PICs and an IOAPIC are emulated in kernel.
-                fd.create_irq_chip().map_err(Error::VmSetup)?;
+                vm.create_irq_chip().map_err(Error::VmSetup)?;
+                // Creates an in-kernel device model for the PIT.
+                vm.create_pit().map_err(Error::VmSetup)?;
             }
 
-            // Patch tsc deadline timer bit
+            // Patch hypervisor bit
             cpuid_patches.push(cpu::CpuidPatch {
                 function: 1,
                 index: 0,
                 flags_bit: None,
                 eax_bit: None,
                 ebx_bit: None,
-                ecx_bit: Some(TSC_DEADLINE_TIMER_ECX_BIT),
+                ecx_bit: Some(HYPERVISOR_ECX_BIT),
                 edx_bit: None,
             });
-        } else {
-            // Create irqchip
-            // A local APIC, 2 PICs and an IOAPIC are emulated in kernel.
-            fd.create_irq_chip().map_err(Error::VmSetup)?;
-            // Creates an in-kernel device model for the PIT.
-            let mut pit_config = kvm_pit_config::default();
-            // We need to enable the emulation of a dummy speaker port stub so that writing to port 0x61
-            // (i.e. KVM_SPEAKER_BASE_ADDRESS) does not trigger an exit to user space.
-            pit_config.flags = KVM_PIT_SPEAKER_DUMMY;
-            fd.create_pit2(pit_config).map_err(Error::VmSetup)?;
-        }
-
-        // Patch hypervisor bit
-        cpuid_patches.push(cpu::CpuidPatch {
-            function: 1,
-            index: 0,
-            flags_bit: None,
-            eax_bit: None,
-            ebx_bit: None,
-            ecx_bit: Some(HYPERVISOR_ECX_BIT),
-            edx_bit: None,
-        });
-
-        // Supported CPUID
-        let mut cpuid = kvm
-            .get_supported_cpuid(MAX_KVM_CPUID_ENTRIES)
-            .map_err(Error::VmSetup)?;
-
-        cpu::CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
 
+            // Patch HTT bit, telling the guest there is more than one logical
+            // processor per package.
+            if let Some(topology) = &config.cpus.topology {
+                if topology.cpu_count() > 1 {
+                    cpuid_patches.push(cpu::CpuidPatch {
+                        function: 1,
+                        index: 0,
+                        flags_bit: None,
+                        eax_bit: None,
+                        ebx_bit: None,
+                        ecx_bit: None,
+                        edx_bit: Some(HTT_EDX_BIT),
+                    });
+                }
+            }
+
+            let enabled_features = config
+                .cpus
+                .enabled_features
+                .as_ref()
+                .map(|features| features.as_slice())
+                .unwrap_or(&[]);
+            // The AMX state is only part of the supported CPUID once the
+            // process has the permission to use it.
+            let amx = enabled_features.contains(&EnabledCpuFeature::Amx);
+            if amx {
+                hypervisor
+                    .enable_amx_state_components()
+                    .map_err(Error::AmxPermission)?;
+            }
+
+            // Supported CPUID
+            let mut cpuid = hypervisor.get_cpuid().map_err(Error::VmSetup)?;
+            if config.ptp {
+                if let Some(reason) = cpu::ptp_unsupported(&cpuid) {
+                    return Err(Error::PtpNotSupported(reason));
+                }
+            }
+
+            cpu::CpuidPatch::patch_cpuid(&mut cpuid, cpuid_patches);
+            if let Some(topology) = &config.cpus.topology {
+                cpu::update_cpuid_topology(&mut cpuid, topology);
+            }
+            if let Some(features) = &config.cpus.disabled_features {
+                cpu::disable_cpuid_features(&mut cpuid, features);
+            }
+            cpu::update_cpuid_nested(&mut cpuid, enabled_features)
+                .map_err(Error::NestedNotSupported)?;
+            if !cpu::update_cpuid_amx(&mut cpuid, amx) {
+                return Err(Error::AmxNotSupported);
+            }
+            // The guest is told the host frequencies, unless a fixed one is
+            // configured.
+            let frequency = match config.cpus.frequency {
+                Some(frequency) => Some(cpu::CpuFrequency::fixed(frequency)),
+                None => cpu::CpuFrequency::host(),
+            };
+            if let Some(frequency) = &frequency {
+                cpu::update_cpuid_frequency(&mut cpuid, frequency);
+            }
+            // Windows guests always get the Hyper-V enlightenments.
+            let kvm_hyperv = config.cpus.kvm_hyperv || config.profile == Profile::Windows;
+            if kvm_hyperv {
+                cpu::update_cpuid_kvm_hyperv(&mut cpuid);
+            }
+            if !sgx_epc_sections.is_empty() {
+                if !cpu::sgx_supported(&cpuid) {
+                    return Err(Error::SgxNotSupported);
+                }
+                cpu::update_cpuid_sgx(&mut cpuid, &sgx_epc_sections);
+            }
+            if config.platform == Platform::Tdx {
+                vm.tdx_init(&cpuid).map_err(Error::ConfidentialVmSetup)?;
+            }
+
+            (
+                cpu::VcpuArchConfig {
+                    cpuid,
+                    kvm_hyperv,
+                    nested_vmx: enabled_features.contains(&EnabledCpuFeature::Vmx),
+                    frequency,
+                    platform: config.platform,
+                },
+                userspace_ioapic,
+            )
+        };
+
+        // The GIC is created before the vCPUs, and finalized once they all
+        // exist. There is no IOAPIC on aarch64.
+        #[cfg(target_arch = "aarch64")]
+        let (arch_config, userspace_ioapic) = {
+            let gic = arch::aarch64::gic::Gic::new(&vm, config.cpus.cpu_count)
+                .map_err(Error::CreateGic)?;
+            (cpu::VcpuArchConfig { gic: Arc::new(gic) }, false)
+        };
+
+        #[cfg(target_arch = "x86_64")]
         let ioapic = GsiApic::new(
             X86_64_IRQ_BASE,
             ioapic::NUM_IOAPIC_PINS as u32 - X86_64_IRQ_BASE,
         );
+        #[cfg(target_arch = "aarch64")]
+        let ioapic = GsiApic::new(arch::layout::IRQ_BASE, arch::layout::IRQ_NUM);
 
         // Let's allocate 64 GiB of addressable MMIO space, starting at 0.
         let mut allocator = SystemAllocator::new(
@@ -394,45 +903,118 @@ impl Vm {
                 .allocate_mmio_addresses(Some(region.0), region.1 as GuestUsize, None)
                 .ok_or(Error::MemoryRangeAllocation)?;
         }
-
-        // Convert the guest memory into an Arc. The point being able to use it
-        // anywhere in the code, no matter which thread might use it.
-        // Add the RwLock aspect to guest memory as we might want to perform
-        // additions to the memory during runtime.
-        let guest_memory = Arc::new(RwLock::new(guest_memory));
+        #[cfg(target_arch = "x86_64")]
+        for section in sgx_epc_sections.iter() {
+            allocator
+                .allocate_mmio_addresses(Some(section.start), section.size, None)
+                .ok_or(Error::MemoryRangeAllocation)?;
+        }
 
         let vm_info = VmInfo {
             memory: &guest_memory,
-            vm_fd: &fd,
+            memory_manager: &memory_manager,
+            vm: &vm,
             vm_cfg: &config,
+            passed_fds,
+            block_cache,
         };
 
-        let device_manager = DeviceManager::new(
-            &vm_info,
-            allocator,
-            msi_capable,
-            userspace_ioapic,
-            ram_regions.len() as u32,
-            &exit_evt,
-            &reset_evt,
-        )
-        .map_err(Error::DeviceManager)?;
+        let device_manager = {
+            let _trace = ScopedTrace::new("device_creation");
+            DeviceManager::new(
+                &vm_info,
+                allocator,
+                msi_capable,
+                userspace_ioapic,
+                &exit_evt,
+                &reset_evt,
+            )
+            .map_err(Error::DeviceManager)?
+        };
 
         let on_tty = unsafe { libc::isatty(libc::STDIN_FILENO as i32) } != 0;
 
+        let debug_evt = EventFd::new(EFD_NONBLOCK).map_err(Error::EventFdCreate)?;
         let boot_vcpus = config.cpus.cpu_count;
+        let realtime = config.latency_profile == LatencyProfile::Realtime;
+        let affinity = config.cpus.affinity.clone().unwrap_or_default();
+        let affinity = if realtime {
+            let affinity =
+                realtime::vcpu_affinity(boot_vcpus, &affinity).map_err(Error::Realtime)?;
+            // The vCPUs having host CPUs of their own, they idle on them.
+            #[cfg(target_arch = "x86_64")]
+            {
+                vm.disable_idle_exits().map_err(Error::DisableIdleExits)?;
+            }
+            realtime::lock_memory().map_err(Error::Realtime)?;
+            affinity
+        } else {
+            affinity
+        };
+        #[cfg(target_arch = "x86_64")]
+        let clock_drift = match &config.clock_drift {
+            Some(clock_drift) => {
+                Some(ClockDriftMonitor::new(vm.clone(), clock_drift).map_err(Error::ClockDrift)?)
+            }
+            None => None,
+        };
+        let cgroup = match &config.cgroup {
+            Some(cgroup) => Some(VmCgroup::new(cgroup).map_err(Error::Cgroup)?),
+            None => None,
+        };
         let cpu_manager = cpu::CpuManager::new(
             boot_vcpus,
             &device_manager,
             guest_memory.clone(),
-            fd,
-            cpuid,
+            vm,
+            arch_config,
             reset_evt,
+            vcpu_failure_evt,
+            debug_evt,
+            affinity,
+            config.idle.clone(),
+            config.halt_poll.clone(),
+            realtime,
         );
 
+        #[cfg(target_arch = "x86_64")]
+        let gdb = match &config.gdb {
+            Some(gdb) => Some(GdbStub::new(&gdb.path).map_err(Error::Gdb)?),
+            None => None,
+        };
+
+        let host_hooks = match &config.hooks {
+            Some(hooks) => hooks::start_host_hooks(
+                hooks,
+                config.vsock.as_ref().and_then(|vsock| vsock.first()),
+            )
+            .map_err(Error::HostHooks)?,
+            None => Vec::new(),
+        };
+
+        let guest_os_agent = match (&config.guest_os, device_manager.guest_os_probe()) {
+            (Some(guest_os), Some(probe)) => guest_os::start_guest_os_agent(
+                guest_os,
+                config.vsock.as_ref().and_then(|vsock| vsock.first()),
+                probe.clone(),
+            )
+            .map_err(Error::GuestOsAgent)?,
+            _ => None,
+        };
+
+        let input_socket = match &config.input {
+            Some(input) => Some(
+                InputSocket::new(input.socket.clone(), device_manager.input_events().to_vec())
+                    .map_err(Error::InputSocket)?,
+            ),
+            None => None,
+        };
+
         Ok(Vm {
             kernel,
+            initramfs,
             memory: guest_memory,
+            memory_manager,
             devices: device_manager,
             config,
             on_tty,
@@ -440,148 +1022,793 @@ impl Vm {
             signals: None,
             state: RwLock::new(VmState::Created),
             cpu_manager,
+            #[cfg(target_arch = "x86_64")]
+            gdb,
+            #[cfg(target_arch = "x86_64")]
+            clock_drift,
+            _host_hooks: host_hooks,
+            _guest_os_agent: guest_os_agent,
+            _input_socket: input_socket,
+            cgroup,
         })
     }
 
-    fn load_kernel(&mut self) -> Result<GuestAddress> {
-        let mut cmdline = Cmdline::new(arch::CMDLINE_MAX_SIZE);
-        cmdline
-            .insert_str(self.config.cmdline.args.clone())
-            .map_err(|_| Error::CmdLine)?;
-        for entry in self.devices.cmdline_additions() {
-            cmdline.insert_str(entry).map_err(|_| Error::CmdLine)?;
-        }
+    // Loads the initramfs image at the end of the guest memory the kernel
+    // leaves to it.
+    fn load_initramfs(
+        initramfs: &mut File,
+        guest_mem: &GuestMemoryMmap,
+    ) -> Result<arch::InitramfsConfig> {
+        let size = initramfs
+            .seek(SeekFrom::End(0))
+            .map_err(Error::InitramfsFile)? as usize;
+        initramfs
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::InitramfsFile)?;
+
+        let address = arch::initramfs_load_addr(guest_mem, size).map_err(Error::ConfigureSystem)?;
+        guest_mem
+            .read_exact_from(address, initramfs, size)
+            .map_err(|_| Error::InitramfsLoad)?;
+
+        Ok(arch::InitramfsConfig { address, size })
+    }
 
-        let cmdline_cstring = CString::new(cmdline).map_err(|_| Error::CmdLine)?;
-        let mem = self.memory.read().unwrap();
-        let entry_addr = match linux_loader::loader::Elf::load(
-            mem.deref(),
-            None,
-            &mut self.kernel,
-            Some(arch::layout::HIGH_RAM_START),
-        ) {
-            Ok(entry_addr) => entry_addr,
-            Err(linux_loader::loader::Error::InvalidElfMagicNumber) => {
-                linux_loader::loader::BzImage::load(
-                    mem.deref(),
-                    None,
-                    &mut self.kernel,
-                    Some(arch::layout::HIGH_RAM_START),
-                )
-                .map_err(Error::KernelLoad)?
+    // The user tables come in the order they are given, the OEM ones last.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    fn load_acpi_tables(config: &VmConfig) -> Result<Vec<Vec<u8>>> {
+        let mut tables = Vec::new();
+        for table in config.acpi_tables.iter().flatten() {
+            let data = std::fs::read(&table.path)
+                .map_err(|e| Error::AcpiTableFile(table.path.clone(), e))?;
+            if !crate::acpi::valid_table(&data) {
+                return Err(Error::InvalidAcpiTable(table.path.clone()));
             }
-            _ => panic!("Invalid elf file"),
-        };
+            tables.push(data);
+        }
+        for table in config.acpi_oem_tables.iter().flatten() {
+            if table.signature.len() != 4 {
+                return Err(Error::AcpiTableSignature(table.signature.clone()));
+            }
+            tables.push(crate::acpi::create_oem_table(table).as_slice().to_vec());
+        }
 
-        linux_loader::loader::load_cmdline(
-            mem.deref(),
-            arch::layout::CMDLINE_START,
-            &cmdline_cstring,
-        )
-        .map_err(|_| Error::CmdLine)?;
-        let vcpu_count = self.config.cpus.cpu_count;
+        for table in tables.iter() {
+            if crate::acpi::GENERATED_SIGNATURES
+                .iter()
+                .any(|signature| &table[..4] == *signature)
+            {
+                return Err(Error::AcpiTableSignature(
+                    String::from_utf8_lossy(&table[..4]).into_owned(),
+                ));
+            }
+        }
+        if let Some(table) = crate::acpi::create_identity_table(config) {
+            tables.push(table.as_slice().to_vec());
+        }
+        let size = tables.iter().map(|table| table.len()).sum();
+        if size > crate::acpi::MAX_USER_TABLES_SIZE {
+            return Err(Error::AcpiTablesTooLarge(size));
+        }
 
-        #[allow(unused_mut, unused_assignments)]
-        let mut rsdp_addr: Option<GuestAddress> = None;
+        Ok(tables)
+    }
 
-        #[cfg(feature = "acpi")]
-        {
-            rsdp_addr = Some({
-                let end_of_range = GuestAddress((1 << get_host_cpu_phys_bits()) - 1);
+    // Whether the kernel image has a bzImage setup header.
+    #[cfg(target_arch = "x86_64")]
+    fn is_bzimage(kernel: &mut File) -> Result<bool> {
+        let mut magic = [0u8; 4];
+        kernel
+            .seek(SeekFrom::Start(BZIMAGE_HEADER_MAGIC_OFFSET))
+            .map_err(Error::KernelFile)?;
+        match kernel.read_exact(&mut magic) {
+            Ok(()) => Ok(&magic == BZIMAGE_HEADER_MAGIC),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(Error::KernelFile(e)),
+        }
+    }
 
-                let mem_end = mem.end_addr();
-                let start_of_device_area = if mem_end < arch::layout::MEM_32BIT_RESERVED_START {
-                    arch::layout::RAM_64BIT_START
-                } else {
-                    mem_end.unchecked_add(1)
-                };
+    // Whether the kernel image is an IGVM image.
+    #[cfg(target_arch = "x86_64")]
+    fn is_igvm(kernel: &mut File) -> Result<bool> {
+        let mut magic = [0u8; 4];
+        kernel.seek(SeekFrom::Start(0)).map_err(Error::KernelFile)?;
+        match kernel.read_exact(&mut magic) {
+            Ok(()) => Ok(arch::x86_64::igvm::is_igvm(&magic)),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(Error::KernelFile(e)),
+        }
+    }
 
-                use crate::config::ConsoleOutputMode;
-                crate::acpi::create_acpi_tables(
-                    &mem,
-                    vcpu_count,
-                    self.config.serial.mode != ConsoleOutputMode::Off,
-                    start_of_device_area,
-                    end_of_range,
-                    self.devices.virt_iommu(),
-                )
-            });
+    // The 32-bit entry point of an ELF kernel image supporting the PVH boot
+    // protocol, advertised through its XEN_ELFNOTE_PHYS32_ENTRY note.
+    #[cfg(target_arch = "x86_64")]
+    fn pvh_entry_point(kernel: &mut File) -> Result<Option<GuestAddress>> {
+        // The ELF fields are little endian, as the x86_64 ELF images are.
+        fn field(bytes: &[u8]) -> u64 {
+            bytes.iter().rev().fold(0, |v, &b| (v << 8) | u64::from(b))
+        }
+        fn align4(size: usize) -> usize {
+            (size + 3) & !3
         }
 
-        match entry_addr.setup_header {
-            Some(hdr) => {
-                arch::configure_system(
-                    &mem,
-                    arch::layout::CMDLINE_START,
-                    cmdline_cstring.to_bytes().len() + 1,
-                    vcpu_count,
-                    Some(hdr),
-                    rsdp_addr,
-                )
-                .map_err(|_| Error::CmdLine)?;
-
-                let load_addr = entry_addr
-                    .kernel_load
-                    .raw_value()
-                    .checked_add(KERNEL_64BIT_ENTRY_OFFSET)
-                    .ok_or(Error::MemOverflow)?;
-
-                Ok(GuestAddress(load_addr))
-            }
-            None => {
-                arch::configure_system(
-                    &mem,
-                    arch::layout::CMDLINE_START,
-                    cmdline_cstring.to_bytes().len() + 1,
-                    vcpu_count,
-                    None,
-                    rsdp_addr,
-                )
-                .map_err(|_| Error::CmdLine)?;
+        let mut ehdr = [0u8; ELF_HEADER_SIZE];
+        kernel.seek(SeekFrom::Start(0)).map_err(Error::KernelFile)?;
+        kernel.read_exact(&mut ehdr).map_err(Error::KernelFile)?;
+        let phoff = field(&ehdr[32..40]);
+        let phentsize = field(&ehdr[54..56]) as usize;
+        let phnum = field(&ehdr[56..58]);
+        if phentsize < ELF_PROGRAM_HEADER_SIZE {
+            return Ok(None);
+        }
 
-                Ok(entry_addr.kernel_load)
+        let mut phdr = vec![0u8; phentsize];
+        for i in 0..phnum {
+            kernel
+                .seek(SeekFrom::Start(phoff + i * phentsize as u64))
+                .map_err(Error::KernelFile)?;
+            kernel.read_exact(&mut phdr).map_err(Error::KernelFile)?;
+            if field(&phdr[0..4]) != ELF_PT_NOTE {
+                continue;
             }
-        }
-    }
 
-    pub fn shutdown(&mut self) -> Result<()> {
-        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
-        let new_state = VmState::Shutdown;
+            let mut notes = vec![0u8; field(&phdr[32..40]) as usize];
+            kernel
+                .seek(SeekFrom::Start(field(&phdr[8..16])))
+                .map_err(Error::KernelFile)?;
+            kernel.read_exact(&mut notes).map_err(Error::KernelFile)?;
+
+            let mut notes = &notes[..];
+            while notes.len() >= ELF_NOTE_HEADER_SIZE {
+                let name_size = field(&notes[0..4]) as usize;
+                let desc_size = field(&notes[4..8]) as usize;
+                let desc_start = ELF_NOTE_HEADER_SIZE + align4(name_size);
+                let next = desc_start + align4(desc_size);
+                if next > notes.len() {
+                    break;
+                }
 
-        state.valid_transition(new_state)?;
+                // The entry point is a 32-bit address, the note of 64-bit
+                // kernels has it in a 64-bit field.
+                if field(&notes[8..12]) == XEN_ELFNOTE_PHYS32_ENTRY
+                    && &notes[ELF_NOTE_HEADER_SIZE..ELF_NOTE_HEADER_SIZE + name_size]
+                        == XEN_ELFNOTE_NAME
+                    && desc_size >= 4
+                {
+                    let desc = &notes[desc_start..desc_start + desc_size.min(8)];
+                    return Ok(Some(GuestAddress(field(desc))));
+                }
 
-        if self.on_tty {
-            // Don't forget to set the terminal in canonical mode
-            // before to exit.
-            io::stdin()
-                .lock()
-                .set_canon_mode()
-                .map_err(Error::SetTerminalCanon)?;
+                notes = &notes[next..];
+            }
         }
 
-        // Trigger the termination of the signal_handler thread
-        if let Some(signals) = self.signals.take() {
-            signals.close();
-        }
+        Ok(None)
+    }
 
-        self.cpu_manager.shutdown().map_err(Error::CpuManager)?;
+    // Maps the firmware image so that it ends at 4GiB, where the vCPUs
+    // reset vector is, and returns it.
+    #[cfg(target_arch = "x86_64")]
+    fn load_firmware(
+        firmware: &mut File,
+        memory_manager: &Arc<Mutex<MemoryManager>>,
+    ) -> Result<Vec<u8>> {
+        let mut image = Vec::new();
+        firmware
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::KernelFile)?;
+        firmware
+            .read_to_end(&mut image)
+            .map_err(Error::KernelFile)?;
+
+        memory_manager
+            .lock()
+            .unwrap()
+            .add_firmware_region(
+                arch::layout::FIRMWARE_START,
+                arch::layout::FIRMWARE_SIZE as usize,
+                &image,
+            )
+            .map_err(Error::MemoryManager)?;
+
+        Ok(image)
+    }
 
-        // Wait for all the threads to finish
-        for thread in self.threads.drain(..) {
-            thread.join().map_err(|_| Error::ThreadCleanup)?
+    // Guest address the firmware image starts at, once it is loaded.
+    #[cfg(target_arch = "x86_64")]
+    fn firmware_address(firmware: &[u8]) -> u64 {
+        arch::layout::FIRMWARE_START.raw_value() + arch::layout::FIRMWARE_SIZE
+            - firmware.len() as u64
+    }
+
+    // The ACPI tables written from `rsdp_addr`, if any, for the firmwares
+    // of the confidential guests.
+    #[cfg(target_arch = "x86_64")]
+    #[allow(unused_variables)]
+    fn acpi_tables(
+        mem: &GuestMemoryMmap,
+        rsdp_addr: Option<GuestAddress>,
+    ) -> Result<Vec<(GuestAddress, Vec<u8>)>> {
+        #[cfg(feature = "acpi")]
+        {
+            if let Some(rsdp_addr) = rsdp_addr {
+                return crate::acpi::read_tables(mem, rsdp_addr).ok_or(Error::AcpiTablesRead);
+            }
         }
-        *state = new_state;
 
-        Ok(())
+        Ok(Vec::new())
     }
 
-    pub fn pause(&mut self) -> Result<()> {
-        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
-        let new_state = VmState::Paused;
-
-        state.valid_transition(new_state)?;
+    // Builds the TD HOB, describing the guest memory and carrying the ACPI
+    // tables, and tells the CPU manager which TDVF sections the TD starts
+    // with. The other ones are accepted by the firmware, as the RAM is.
+    #[cfg(target_arch = "x86_64")]
+    fn load_tdvf(&mut self, firmware: &[u8], rsdp_addr: Option<GuestAddress>) -> Result<()> {
+        use arch::x86_64::tdx::{self, TdHob, TdvfSectionType};
 
-        self.cpu_manager.pause().map_err(Error::CpuManager)?;
+        let sections = tdx::tdvf_sections(firmware).map_err(Error::Tdvf)?;
+        let mem = self.memory.read().unwrap();
+        let memory_manager = self.memory_manager.lock().unwrap();
+
+        let mut hob_section = None;
+        let mut accepted = Vec::new();
+        let mut regions = Vec::new();
+        for section in sections.iter().filter(|s| s.is_initial()) {
+            match section.type_ {
+                // The firmware volumes are in the image, as it is mapped.
+                TdvfSectionType::Bfv | TdvfSectionType::Cfv => {
+                    if section.address
+                        != Vm::firmware_address(firmware) + u64::from(section.data_offset)
+                    {
+                        return Err(Error::ConfidentialRange(section.address));
+                    }
+                }
+                TdvfSectionType::TdHob => {
+                    hob_section = Some(*section);
+                    accepted.push((section.address, section.size));
+                }
+                _ => accepted.push((section.address, section.size)),
+            }
+            let host_address = memory_manager
+                .host_address(GuestAddress(section.address), section.size)
+                .ok_or(Error::ConfidentialRange(section.address))?;
+            regions.push(cpu::TdxRegion {
+                host_address,
+                guest_address: section.address,
+                size: section.size,
+                measure: section.measure,
+            });
+        }
+        let hob_section = hob_section.ok_or(Error::Tdvf(tdx::Error::InvalidMetadata))?;
+
+        let mut ram = Vec::new();
+        mem.with_regions_mut(|_, region| {
+            ram.push((region.start_addr().raw_value(), region.len() as u64));
+            Ok::<(), Error>(())
+        })?;
+        let mut hob = TdHob::new(hob_section.address);
+        for (start, size, accepted) in tdx::memory_resources(&ram, &accepted) {
+            hob.add_memory(start, size, accepted);
+        }
+        // The device areas, below 4GiB and above the RAM.
+        hob.add_mmio(
+            arch::layout::MEM_32BIT_DEVICES_START.raw_value(),
+            arch::layout::MEM_32BIT_DEVICES_SIZE,
+        );
+        let mem_end = mem.end_addr();
+        let start_of_device_area = if mem_end < arch::layout::MEM_32BIT_RESERVED_START {
+            arch::layout::RAM_64BIT_START
+        } else {
+            mem_end.unchecked_add(1)
+        };
+        hob.add_mmio(
+            start_of_device_area.raw_value(),
+            (1 << get_host_cpu_phys_bits()) - start_of_device_area.raw_value(),
+        );
+        // The firmware has its own RSDP and XSDT.
+        for (_, table) in Vm::acpi_tables(&mem, rsdp_addr)?.iter() {
+            if &table[..4] != b"XSDT" {
+                hob.add_acpi_table(table).map_err(Error::Tdvf)?;
+            }
+        }
+
+        let hob = hob.finish();
+        if hob.len() as u64 > hob_section.size {
+            return Err(Error::TdHobTooLarge(hob.len()));
+        }
+        mem.write_slice(&hob, GuestAddress(hob_section.address))
+            .map_err(Error::GuestMemory)?;
+
+        self.cpu_manager
+            .set_confidential_launch(cpu::ConfidentialLaunch::Tdx {
+                hob_address: hob_section.address,
+                regions,
+            });
+
+        Ok(())
+    }
+
+    // Tells the CPU manager which ranges the SEV-SNP guest starts with: the
+    // firmware, the pages its SEV metadata asks for, the PVH start info the
+    // firmware finds the memory map in, and the ACPI tables.
+    #[cfg(target_arch = "x86_64")]
+    fn load_sev_snp(&mut self, firmware: &[u8], rsdp_addr: Option<GuestAddress>) -> Result<()> {
+        use arch::x86_64::sev::{self, SevSectionType};
+
+        let sections = sev::sev_sections(firmware).map_err(Error::SevMetadata)?;
+        let mem = self.memory.read().unwrap();
+        let memory_manager = self.memory_manager.lock().unwrap();
+
+        let firmware_size = (firmware.len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let mut ranges = vec![
+            (
+                Vm::firmware_address(firmware) & !(PAGE_SIZE - 1),
+                firmware_size,
+                SevSnpPageType::Normal,
+            ),
+            (
+                arch::layout::PVH_INFO_START.raw_value(),
+                PAGE_SIZE,
+                SevSnpPageType::Normal,
+            ),
+        ];
+        for section in sections.iter() {
+            let page_type = match section.type_ {
+                SevSectionType::SnpSecrets => SevSnpPageType::Secrets,
+                SevSectionType::Cpuid => {
+                    let page =
+                        sev::cpuid_page(self.cpu_manager.cpuid()).map_err(Error::SevMetadata)?;
+                    mem.write_slice(&page, GuestAddress(section.address))
+                        .map_err(Error::GuestMemory)?;
+                    SevSnpPageType::Cpuid
+                }
+                // There are no kernel hashes, the firmware boots on its own.
+                SevSectionType::SnpSecMem
+                | SevSectionType::SvsmCaa
+                | SevSectionType::KernelHashes => SevSnpPageType::Zero,
+            };
+            ranges.push((section.address, section.size, page_type));
+        }
+        let acpi_tables = Vm::acpi_tables(&mem, rsdp_addr)?;
+        if let Some(rsdp_addr) = rsdp_addr {
+            let end = acpi_tables
+                .iter()
+                .map(|(address, table)| address.raw_value() + table.len() as u64)
+                .max()
+                .unwrap_or_else(|| rsdp_addr.raw_value());
+            let start = rsdp_addr.raw_value() & !(PAGE_SIZE - 1);
+            let size = ((end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)) - start;
+            ranges.push((start, size, SevSnpPageType::Normal));
+        }
+
+        let mut regions = Vec::new();
+        for (guest_address, size, page_type) in ranges {
+            let host_address = memory_manager
+                .host_address(GuestAddress(guest_address), size)
+                .ok_or(Error::ConfidentialRange(guest_address))?;
+            regions.push(cpu::SevSnpRegion {
+                host_address,
+                guest_address,
+                size,
+                page_type,
+            });
+        }
+
+        self.cpu_manager
+            .set_confidential_launch(cpu::ConfidentialLaunch::SevSnp { regions });
+
+        Ok(())
+    }
+
+    // Loads the directives of an IGVM image applying to the platform of the
+    // guest: its pages, and its parameter areas the memory map, the number
+    // of vCPUs and the command line are written to. The boot vCPU starts
+    // from the context of the image. A SEV-SNP guest starts with these
+    // pages and areas only.
+    #[cfg(target_arch = "x86_64")]
+    fn load_igvm(&mut self, cmdline: &CString) -> Result<()> {
+        use arch::x86_64::igvm::{self, IgvmPageType, IgvmParameter, IgvmPlatform};
+        use arch::x86_64::sev;
+
+        if self.initramfs.is_some() {
+            return Err(Error::IgvmInitramfs);
+        }
+        let platform = match self.config.platform {
+            Platform::Default => IgvmPlatform::Native,
+            Platform::SevSnp => IgvmPlatform::SevSnp,
+            Platform::Tdx => return Err(Error::Igvm(igvm::Error::UnsupportedPlatform)),
+        };
+        let mut image = Vec::new();
+        self.kernel
+            .seek(SeekFrom::Start(0))
+            .map_err(Error::KernelFile)?;
+        self.kernel
+            .read_to_end(&mut image)
+            .map_err(Error::KernelFile)?;
+
+        let mem = self.memory.read().unwrap();
+        let mut ram = Vec::new();
+        mem.with_regions_mut(|_, region| {
+            ram.push((region.start_addr().raw_value(), region.len() as u64));
+            Ok::<(), Error>(())
+        })?;
+        let ram_size = ram.iter().map(|&(_, size)| size).sum();
+        let igvm = igvm::igvm_image(&image, platform, ram_size).map_err(Error::Igvm)?;
+        for &(start, size) in igvm.required_memory.iter() {
+            let end = start
+                .checked_add(size)
+                .ok_or(Error::IgvmRequiredMemory(start))?;
+            if !ram
+                .iter()
+                .any(|&(base, len)| start >= base && end <= base + len)
+            {
+                return Err(Error::IgvmRequiredMemory(start));
+            }
+        }
+
+        let mut ranges = Vec::new();
+        for page in igvm.pages.iter() {
+            if let Some(data) = &page.data {
+                mem.write_slice(data, GuestAddress(page.address))
+                    .map_err(Error::GuestMemory)?;
+            }
+            let page_type = match (page.type_, &page.data) {
+                (IgvmPageType::Cpuid, _) if platform == IgvmPlatform::SevSnp => {
+                    let cpuid_page =
+                        sev::cpuid_page(self.cpu_manager.cpuid()).map_err(Error::SevMetadata)?;
+                    mem.write_slice(&cpuid_page, GuestAddress(page.address))
+                        .map_err(Error::GuestMemory)?;
+                    SevSnpPageType::Cpuid
+                }
+                (IgvmPageType::Secrets, _) => SevSnpPageType::Secrets,
+                (_, Some(_)) if page.measured => SevSnpPageType::Normal,
+                (_, None) if page.measured => SevSnpPageType::Zero,
+                _ => SevSnpPageType::Unmeasured,
+            };
+            ranges.push((page.address, page.size, page_type));
+        }
+
+        let memory_map = igvm::memory_map(&ram);
+        for area in igvm.parameter_areas.iter() {
+            let mut data = area.data.clone();
+            for &(parameter, offset) in area.parameters.iter() {
+                let value = match parameter {
+                    IgvmParameter::VpCount => {
+                        u32::from(self.config.cpus.cpu_count).to_le_bytes().to_vec()
+                    }
+                    IgvmParameter::MemoryMap => memory_map.clone(),
+                    IgvmParameter::CommandLine => cmdline.as_bytes_with_nul().to_vec(),
+                };
+                data.get_mut(offset..offset + value.len())
+                    .ok_or(Error::IgvmParameterArea(area.address))?
+                    .copy_from_slice(&value);
+            }
+            mem.write_slice(&data, GuestAddress(area.address))
+                .map_err(Error::GuestMemory)?;
+            let size = (data.len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            ranges.push((area.address, size, SevSnpPageType::Unmeasured));
+        }
+
+        if let Some(context) = igvm.vp_context {
+            self.cpu_manager.set_boot_vp_context(context);
+        }
+        if platform == IgvmPlatform::SevSnp {
+            let memory_manager = self.memory_manager.lock().unwrap();
+            let mut regions = Vec::new();
+            for (guest_address, size, page_type) in ranges {
+                let host_address = memory_manager
+                    .host_address(GuestAddress(guest_address), size)
+                    .ok_or(Error::ConfidentialRange(guest_address))?;
+                regions.push(cpu::SevSnpRegion {
+                    host_address,
+                    guest_address,
+                    size,
+                    page_type,
+                });
+            }
+            self.cpu_manager
+                .set_confidential_launch(cpu::ConfidentialLaunch::SevSnp { regions });
+        }
+
+        Ok(())
+    }
+
+    // Loads the kernel, and returns the address the vCPUs start at. A
+    // firmware image has no entry point, the vCPUs start from their reset
+    // state instead, as does an IGVM image, from the context it gives the
+    // boot vCPU.
+    #[cfg(target_arch = "x86_64")]
+    fn load_kernel(&mut self) -> Result<Option<EntryPoint>> {
+        let mut cmdline = Cmdline::new(arch::CMDLINE_MAX_SIZE);
+        let args = crate::cmdline::expand(&self.config.cmdline.args, &self.config)
+            .map_err(Error::CmdlineVariable)?;
+        cmdline.insert_str(args).map_err(|_| Error::CmdLine)?;
+        for entry in self.devices.cmdline_additions() {
+            cmdline.insert_str(entry).map_err(|_| Error::CmdLine)?;
+        }
+        for parameter in crate::cmdline::identity_parameters(&self.config) {
+            cmdline.insert_str(parameter).map_err(|_| Error::CmdLine)?;
+        }
+
+        let cmdline_cstring = CString::new(cmdline).map_err(|_| Error::CmdLine)?;
+        if Vm::is_igvm(&mut self.kernel)? {
+            self.load_igvm(&cmdline_cstring)?;
+            return Ok(None);
+        }
+
+        let mem = self.memory.read().unwrap();
+        // Confidential guests only boot from a firmware, or an IGVM image,
+        // added to their encrypted memory as they are launched.
+        let mut firmware = None;
+        let entry_addr = if self.config.platform.is_confidential() {
+            firmware = Some(Vm::load_firmware(&mut self.kernel, &self.memory_manager)?);
+            None
+        } else {
+            match linux_loader::loader::Elf::load(
+                mem.deref(),
+                None,
+                &mut self.kernel,
+                Some(arch::layout::HIGH_RAM_START),
+            ) {
+                Ok(entry_addr) => Some(entry_addr),
+                Err(linux_loader::loader::Error::InvalidElfMagicNumber) => {
+                    // The unikernel profile only boots ELF binaries.
+                    if self.config.profile == Profile::Unikernel {
+                        return Err(Error::KernelLoad(
+                            linux_loader::loader::Error::InvalidElfMagicNumber,
+                        ));
+                    }
+                    if Vm::is_bzimage(&mut self.kernel)? {
+                        Some(
+                            linux_loader::loader::BzImage::load(
+                                mem.deref(),
+                                None,
+                                &mut self.kernel,
+                                Some(arch::layout::HIGH_RAM_START),
+                            )
+                            .map_err(Error::KernelLoad)?,
+                        )
+                    } else {
+                        // Neither an ELF binary nor a bzImage, this is a firmware.
+                        Vm::load_firmware(&mut self.kernel, &self.memory_manager)?;
+                        None
+                    }
+                }
+                _ => panic!("Invalid elf file"),
+            }
+        };
+
+        linux_loader::loader::load_cmdline(
+            mem.deref(),
+            arch::layout::CMDLINE_START,
+            &cmdline_cstring,
+        )
+        .map_err(|_| Error::CmdLine)?;
+        let vcpu_count = self.config.cpus.cpu_count;
+
+        // The SGX EPC sections are next to each other.
+        let sgx_epc_sections = self.memory_manager.lock().unwrap().sgx_epc_sections();
+        let sgx_epc_region = match (sgx_epc_sections.first(), sgx_epc_sections.last()) {
+            (Some(first), Some(last)) => Some((
+                first.start,
+                last.start
+                    .unchecked_add(last.size)
+                    .unchecked_offset_from(first.start),
+            )),
+            _ => None,
+        };
+
+        #[allow(unused_mut, unused_assignments)]
+        let mut rsdp_addr: Option<GuestAddress> = None;
+
+        #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+        {
+            if self.config.profile != Profile::Unikernel {
+                let user_tables = Vm::load_acpi_tables(&self.config)?;
+                rsdp_addr = Some({
+                    let end_of_range = GuestAddress((1 << get_host_cpu_phys_bits()) - 1);
+
+                    let mem_end = mem.end_addr();
+                    let mut start_of_device_area =
+                        if mem_end < arch::layout::MEM_32BIT_RESERVED_START {
+                            arch::layout::RAM_64BIT_START
+                        } else {
+                            mem_end.unchecked_add(1)
+                        };
+                    if let Some((start, size)) = sgx_epc_region {
+                        start_of_device_area = start.unchecked_add(size);
+                    }
+
+                    let memory_manager = self.memory_manager.lock().unwrap();
+                    let numa = self
+                        .config
+                        .numa
+                        .as_ref()
+                        .map(|numa| (numa.as_slice(), memory_manager.numa_ranges()));
+
+                    let uarts: Vec<(u16, u8)> = self
+                        .devices
+                        .console()
+                        .uarts()
+                        .iter()
+                        .map(|uart| uart.resources())
+                        .collect();
+
+                    use crate::config::ConsoleOutputMode;
+                    crate::acpi::create_acpi_tables(
+                        &mem,
+                        vcpu_count,
+                        self.config.serial.mode != ConsoleOutputMode::Off,
+                        &uarts,
+                        start_of_device_area,
+                        end_of_range,
+                        &self.devices.pci_segments(),
+                        self.devices.virt_iommu(),
+                        self.devices.ged_irq(),
+                        self.config.cpus.topology.as_ref(),
+                        numa,
+                        &self.config.sensors,
+                        self.cpu_manager.frequency(),
+                        self.config.tpm.is_some(),
+                        self.config.pvpanic.is_some(),
+                        self.devices.pci_hotplug_enabled(),
+                        self.devices.nvdimm_ranges(),
+                        self.config.profile == Profile::Windows,
+                        &user_tables,
+                    )
+                });
+            }
+        }
+
+        // ELF kernels advertising a PVH entry point are booted through it,
+        // the PVH start info is always set up by configure_system().
+        let (setup_header, entry_point) = match entry_addr {
+            Some(entry_addr) => match entry_addr.setup_header {
+                Some(hdr) => {
+                    let load_addr = entry_addr
+                        .kernel_load
+                        .raw_value()
+                        .checked_add(KERNEL_64BIT_ENTRY_OFFSET)
+                        .ok_or(Error::MemOverflow)?;
+
+                    let entry_point = EntryPoint {
+                        entry_addr: GuestAddress(load_addr),
+                        protocol: BootProtocol::LinuxBoot,
+                    };
+                    (Some(hdr), Some(entry_point))
+                }
+                None => {
+                    let entry_point = match Vm::pvh_entry_point(&mut self.kernel)? {
+                        Some(pvh_entry_addr) => EntryPoint {
+                            entry_addr: pvh_entry_addr,
+                            protocol: BootProtocol::PvhBoot,
+                        },
+                        None => EntryPoint {
+                            entry_addr: entry_addr.kernel_load,
+                            protocol: BootProtocol::LinuxBoot,
+                        },
+                    };
+                    (None, Some(entry_point))
+                }
+            },
+            None => (None, None),
+        };
+
+        let initramfs = match &mut self.initramfs {
+            Some(initramfs) => Some(Vm::load_initramfs(initramfs, mem.deref())?),
+            None => None,
+        };
+
+        arch::configure_system(
+            &mem,
+            arch::layout::CMDLINE_START,
+            cmdline_cstring.to_bytes().len() + 1,
+            &initramfs,
+            vcpu_count,
+            setup_header,
+            rsdp_addr,
+            sgx_epc_region,
+        )
+        .map_err(Error::ConfigureSystem)?;
+
+        drop(mem);
+        match (self.config.platform, firmware) {
+            (Platform::Tdx, Some(firmware)) => self.load_tdvf(&firmware, rsdp_addr)?,
+            (Platform::SevSnp, Some(firmware)) => self.load_sev_snp(&firmware, rsdp_addr)?,
+            _ => {}
+        }
+
+        Ok(entry_point)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn load_kernel(&mut self) -> Result<Option<EntryPoint>> {
+        let mut cmdline = Cmdline::new(arch::CMDLINE_MAX_SIZE);
+        let args = crate::cmdline::expand(&self.config.cmdline.args, &self.config)
+            .map_err(Error::CmdlineVariable)?;
+        cmdline.insert_str(args).map_err(|_| Error::CmdLine)?;
+        for entry in self.devices.cmdline_additions() {
+            cmdline.insert_str(entry).map_err(|_| Error::CmdLine)?;
+        }
+        for parameter in crate::cmdline::identity_parameters(&self.config) {
+            cmdline.insert_str(parameter).map_err(|_| Error::CmdLine)?;
+        }
+
+        let cmdline_cstring = CString::new(cmdline).map_err(|_| Error::CmdLine)?;
+        let mem = self.memory.read().unwrap();
+        let entry_addr =
+            arch::aarch64::load_kernel(&mem, &mut self.kernel).map_err(Error::ConfigureSystem)?;
+        let initramfs = match &mut self.initramfs {
+            Some(initramfs) => Some(Vm::load_initramfs(initramfs, &mem)?),
+            None => None,
+        };
+
+        // The command line, and the devices the guest can't probe, are
+        // described through the device tree.
+        let pci_enabled =
+            cfg!(feature = "pci_support") && self.config.profile != Profile::Unikernel;
+        arch::configure_system(
+            &mem,
+            &cmdline_cstring,
+            &initramfs,
+            self.config.cpus.cpu_count,
+            pci_enabled,
+            self.devices.virtio_mmio_devices(),
+        )
+        .map_err(Error::ConfigureSystem)?;
+
+        Ok(Some(EntryPoint {
+            entry_addr,
+            protocol: BootProtocol::LinuxBoot,
+        }))
+    }
+
+    pub fn shutdown(&mut self) -> Result<()> {
+        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
+        let new_state = VmState::Shutdown;
+
+        state.valid_transition(new_state)?;
+
+        if self.on_tty {
+            // Don't forget to set the terminal in canonical mode
+            // before to exit.
+            io::stdin()
+                .lock()
+                .set_canon_mode()
+                .map_err(Error::SetTerminalCanon)?;
+        }
+
+        // Trigger the termination of the signal_handler thread
+        if let Some(signals) = self.signals.take() {
+            signals.close();
+        }
+
+        self.cpu_manager.shutdown().map_err(Error::CpuManager)?;
+
+        // Wait for all the threads to finish
+        for thread in self.threads.drain(..) {
+            thread.join().map_err(|_| Error::ThreadCleanup)?
+        }
+        *state = new_state;
+
+        Ok(())
+    }
+
+    pub fn pause(&mut self) -> Result<()> {
+        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
+        let new_state = VmState::Paused;
+
+        state.valid_transition(new_state)?;
+
+        self.cpu_manager.pause().map_err(Error::CpuManager)?;
+        // The devices are paused once the vCPUs stopped queuing requests.
+        if let Err(e) = self.devices.pause() {
+            // Ignore the results because there is nothing we can do about
+            // them, the VM going on running.
+            let _ = self.devices.resume();
+            let _ = self.cpu_manager.resume();
+            return Err(Error::PauseDevices(e));
+        }
 
         *state = new_state;
 
@@ -594,6 +1821,7 @@ impl Vm {
 
         state.valid_transition(new_state)?;
 
+        self.devices.resume().map_err(Error::ResumeDevices)?;
         self.cpu_manager.resume().map_err(Error::CpuManager)?;
 
         // And we're back to the Running state.
@@ -602,6 +1830,652 @@ impl Vm {
         Ok(())
     }
 
+    /// The vCPUs that failed to run the guest, in the order they did.
+    pub fn vcpu_failures(&self) -> Vec<cpu::VcpuFailure> {
+        self.cpu_manager.vcpu_failures()
+    }
+
+    /// Whether the guest is idle, see the idle configuration.
+    pub fn idle(&self) -> bool {
+        self.cpu_manager.idle()
+    }
+
+    /// Whether the memory of the idle guest was paged out, see the idle
+    /// configuration.
+    pub fn hibernated(&self) -> bool {
+        self.cpu_manager.hibernated()
+    }
+
+    /// Write an ELF core dump of the guest memory and vCPUs, for offline
+    /// debugging. The VM must be paused, so that its state doesn't change
+    /// while it is written.
+    #[cfg(target_arch = "x86_64")]
+    pub fn coredump(&self, destination: &Path) -> Result<()> {
+        // The memory and registers of a confidential guest are encrypted.
+        if self.config.platform.is_confidential() {
+            return Err(Error::CoredumpNotSupported);
+        }
+        if self.get_state()? != VmState::Paused {
+            return Err(Error::VmNotPaused);
+        }
+
+        crate::coredump::write_coredump(
+            destination,
+            &self.memory.read().unwrap(),
+            self.cpu_manager.vcpus(),
+        )
+        .map_err(Error::Coredump)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn coredump(&self, _destination: &Path) -> Result<()> {
+        Err(Error::CoredumpNotSupported)
+    }
+
+    /// Writes a diagnostic bundle, collected for the given reason, to the
+    /// directory of the diagnostics configuration, returning its path. None
+    /// when the VM isn't configured with diagnostic bundles.
+    pub fn diagnostic_bundle(&self, reason: &str) -> Result<Option<PathBuf>> {
+        let directory = match self
+            .config
+            .diagnostics
+            .as_ref()
+            .and_then(|diagnostics| diagnostics.path.as_ref())
+        {
+            Some(directory) => directory,
+            None => return Ok(None),
+        };
+
+        // The registers of a confidential guest are encrypted. The vCPUs of
+        // a running VM are paused for theirs to be read, and resumed right
+        // after.
+        let vcpus = if self.config.platform.is_confidential() {
+            Vec::new()
+        } else {
+            let running = self.get_state()? == VmState::Running;
+            if running {
+                self.cpu_manager.pause().map_err(Error::CpuManager)?;
+            }
+            let registers = self.cpu_manager.registers();
+            if running {
+                self.cpu_manager.resume().map_err(Error::CpuManager)?;
+            }
+
+            registers
+                .into_iter()
+                .enumerate()
+                .map(|(id, registers)| VcpuState {
+                    id: id as u8,
+                    registers,
+                })
+                .collect()
+        };
+
+        let audits = self.devices.virtio_audits();
+        let devices = self
+            .devices
+            .virtio_ring_indexes()
+            .into_iter()
+            .map(|(id, queues)| DeviceState {
+                pci_address: self.devices.pci_devices().get(&id).cloned(),
+                audit: audits
+                    .iter()
+                    .find(|(audit_id, _)| *audit_id == id)
+                    .map(|(_, audit)| DeviceAuditState::from(audit)),
+                id,
+                queues: queues
+                    .into_iter()
+                    .map(|(avail, used)| QueueState { avail, used })
+                    .collect(),
+            })
+            .collect();
+
+        let bundle = Bundle::new(
+            reason,
+            vcpus,
+            devices,
+            self.devices.console_log().map(|log| log.as_ref()),
+        );
+        diagnostics::write_bundle(directory, &bundle)
+            .map(Some)
+            .map_err(Error::DiagnosticBundle)
+    }
+
+    /// Writes the display of the virtio-gpu device, as the guest last
+    /// flushed it, to the destination as a PPM image.
+    pub fn screenshot(&self, destination: &Path) -> Result<()> {
+        let framebuffer = self
+            .devices
+            .gpu_display()
+            .ok_or(Error::GpuNotConfigured)?
+            .framebuffer()
+            .ok_or(Error::DisplayDisabled)?;
+
+        let mut file = io::BufWriter::new(File::create(destination).map_err(Error::Screenshot)?);
+        framebuffer
+            .write_ppm(&mut file)
+            .and_then(|_| file.flush())
+            .map_err(Error::Screenshot)
+    }
+
+    /// The audits of the guest drivers of the virtio devices: their writes to
+    /// the device config spaces, and the failures of their feature
+    /// negotiations.
+    pub fn device_audit(&self) -> Result<Vec<DeviceAuditInfo>> {
+        match &self.config.diagnostics {
+            Some(diagnostics) if diagnostics.audit => {}
+            _ => return Err(Error::DeviceAuditDisabled),
+        }
+
+        Ok(self
+            .devices
+            .virtio_audits()
+            .into_iter()
+            .map(|(id, audit)| DeviceAuditInfo {
+                pci_address: self.devices.pci_devices().get(&id).cloned(),
+                id,
+                audit: DeviceAuditState::from(&audit),
+            })
+            .collect())
+    }
+
+    /// The vCPU that triple faulted, resetting the VM, if any.
+    #[cfg(target_arch = "x86_64")]
+    pub fn take_triple_fault(&self) -> Option<u8> {
+        self.cpu_manager.take_triple_fault()
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn take_triple_fault(&self) -> Option<u8> {
+        None
+    }
+
+    /// Event written when virtio-blk disk images run out of space.
+    pub fn out_of_space_evt(&self) -> &EventFd {
+        self.devices.out_of_space_evt()
+    }
+
+    /// The IDs of the disks whose image ran out of space, since the last
+    /// call, and which wait for a device reset to retry. Consumes the out
+    /// of space event.
+    pub fn take_out_of_space_disks(&self) -> Result<Vec<String>> {
+        self.devices
+            .out_of_space_evt()
+            .read()
+            .map_err(Error::EventFdRead)?;
+
+        Ok(self.devices.take_out_of_space_disks())
+    }
+
+    /// Event written when the guest reports a panic through its pvpanic
+    /// device, if it has one.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn pvpanic_evt(&self) -> Option<&EventFd> {
+        self.devices.pvpanic_evt()
+    }
+
+    #[cfg(not(all(feature = "acpi", target_arch = "x86_64")))]
+    pub fn pvpanic_evt(&self) -> Option<&EventFd> {
+        None
+    }
+
+    /// The pvpanic events the guest reported since the last call. Consumes
+    /// the guest panic event.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn take_pvpanic_events(&self) -> Result<u8> {
+        if let Some(panic_evt) = self.devices.pvpanic_evt() {
+            panic_evt.read().map_err(Error::EventFdRead)?;
+        }
+
+        Ok(self.devices.take_pvpanic_events())
+    }
+
+    #[cfg(not(all(feature = "acpi", target_arch = "x86_64")))]
+    pub fn take_pvpanic_events(&self) -> Result<u8> {
+        Ok(0)
+    }
+
+    /// Event written when the guest clock drift gets past its threshold, if
+    /// it is checked.
+    #[cfg(target_arch = "x86_64")]
+    pub fn clock_drift_evt(&self) -> Option<&EventFd> {
+        self.clock_drift
+            .as_ref()
+            .map(|clock_drift| clock_drift.alarm_evt())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn clock_drift_evt(&self) -> Option<&EventFd> {
+        None
+    }
+
+    /// The guest clock drift last checked and its threshold, in
+    /// milliseconds, consuming the clock drift event.
+    #[cfg(target_arch = "x86_64")]
+    pub fn take_clock_drift(&self) -> Result<Option<(i64, u64)>> {
+        match &self.clock_drift {
+            Some(clock_drift) => {
+                clock_drift.alarm_evt().read().map_err(Error::EventFdRead)?;
+                Ok(Some((clock_drift.drift(), clock_drift.threshold())))
+            }
+            None => Ok(None),
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn take_clock_drift(&self) -> Result<Option<(i64, u64)>> {
+        Ok(None)
+    }
+
+    /// The guest clock drift last checked, in milliseconds, if it is
+    /// checked.
+    #[cfg(target_arch = "x86_64")]
+    pub fn clock_drift(&self) -> Option<i64> {
+        self.clock_drift
+            .as_ref()
+            .map(|clock_drift| clock_drift.drift())
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    pub fn clock_drift(&self) -> Option<i64> {
+        None
+    }
+
+    /// Socket the GDB stub listens for the debugger on, if any.
+    #[cfg(target_arch = "x86_64")]
+    pub fn gdb_listener(&self) -> Option<&UnixListener> {
+        self.gdb.as_ref().map(|gdb| gdb.listener())
+    }
+
+    /// Event written when vCPUs stop for the debugger.
+    pub fn debug_evt(&self) -> &EventFd {
+        self.cpu_manager.debug_evt()
+    }
+
+    /// Accepts a debugger connection, stopping the guest for it. Returns
+    /// the file descriptor to poll for the packets of the debugger.
+    #[cfg(target_arch = "x86_64")]
+    pub fn accept_gdb(&mut self) -> Result<Option<RawFd>> {
+        match self.gdb.as_mut() {
+            Some(gdb) => gdb.accept(&self.cpu_manager).map(Some).map_err(Error::Gdb),
+            None => Ok(None),
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn handle_gdb(&mut self) -> Result<()> {
+        if let Some(gdb) = self.gdb.as_mut() {
+            gdb.handle_input(&self.cpu_manager, &self.memory.read().unwrap())
+                .map_err(Error::Gdb)?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub fn handle_debug_stop(&mut self) -> Result<()> {
+        if let Some(gdb) = self.gdb.as_mut() {
+            gdb.handle_debug_stop(&self.cpu_manager)
+                .map_err(Error::Gdb)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reset a single virtio device and activate it again, to get it out of
+    /// the queues a backend failure left it stuck on, without the guest
+    /// rebooting.
+    pub fn reset_device(&self, id: &str) -> Result<()> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        self.devices
+            .reset_virtio_device(id)
+            .map_err(Error::ResetDevice)
+    }
+
+    /// Change the weight of a virtio-blk disk in its disk group, the share
+    /// of the bandwidth of the group it gets following from the next refill
+    /// of the group on.
+    pub fn set_disk_weight(&self, id: &str, weight: u32) -> Result<()> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+        if weight == 0 {
+            return Err(Error::InvalidDiskWeight);
+        }
+
+        self.devices
+            .set_disk_weight(id, weight)
+            .map_err(Error::SetDiskWeight)
+    }
+
+    /// Change the CPU quota of the vCPUs through the cpu.max of their
+    /// cgroup, when given one, and report how much they were throttled. The
+    /// quota isn't kept across reboots.
+    pub fn throttle(&self, data: Option<&VmThrottleData>) -> Result<VmThrottle> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+        let cgroup = self.cgroup.as_ref().ok_or(Error::CgroupNotConfigured)?;
+
+        if let Some(data) = data {
+            if data.quota == Some(0) {
+                return Err(Error::InvalidThrottleQuota);
+            }
+            cgroup
+                .set_vcpus_quota(data.quota)
+                .map_err(Error::Throttle)?;
+        }
+        let stat = cgroup.vcpus_cpu_stat().map_err(Error::Throttle)?;
+
+        #[cfg(target_arch = "x86_64")]
+        let steal_time = cpu::steal_time_supported(self.cpu_manager.cpuid());
+        #[cfg(target_arch = "aarch64")]
+        let steal_time = false;
+
+        Ok(VmThrottle {
+            quota: stat.quota,
+            usage_usec: stat.usage_usec,
+            nr_periods: stat.nr_periods,
+            nr_throttled: stat.nr_throttled,
+            throttled_usec: stat.throttled_usec,
+            steal_time,
+        })
+    }
+
+    /// Start logging the pages of its RAM the running guest writes to, for
+    /// measuring its dirty rate, returning the memory manager counting them.
+    pub fn start_dirty_log(&self) -> Result<Arc<Mutex<MemoryManager>>> {
+        match self.get_state()? {
+            VmState::Running => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .start_dirty_log()
+            .map_err(Error::DirtyLog)?;
+
+        Ok(self.memory_manager.clone())
+    }
+
+    /// Change the weights of disks in their disk groups, keeping them in the
+    /// configuration of the VM for it to keep them across reboots.
+    pub fn apply_disk_weights(&mut self, weights: &[(String, u32)]) -> Result<()> {
+        for (id, weight) in weights.iter() {
+            self.set_disk_weight(id, *weight)?;
+            Arc::make_mut(&mut self.config).set_disk_weight(id, *weight);
+        }
+
+        Ok(())
+    }
+
+    /// Apply the update to the configuration of the VM, switching the output
+    /// of its serial port and replacing the rate limiters of its devices,
+    /// and keep it in the configuration for the VM to keep it across
+    /// reboots. Devices created without a rate limiter don't get one.
+    pub fn update(&mut self, update: &VmUpdateConfig) -> Result<()> {
+        let config = self
+            .config
+            .update(update)
+            .map_err(Error::ConfigNotApplicable)?;
+        config.validate().map_err(Error::InvalidConfig)?;
+
+        let rate_limiters: Vec<(&str, &RateLimiterUpdate)> = update
+            .disks
+            .iter()
+            .flatten()
+            .map(|rate_limiter| ("disks", rate_limiter))
+            .chain(
+                update
+                    .net
+                    .iter()
+                    .flatten()
+                    .map(|rate_limiter| ("net", rate_limiter)),
+            )
+            .collect();
+        let fixed: Vec<String> = rate_limiters
+            .iter()
+            .filter(|(_, rate_limiter)| {
+                rate_limiter.rate_limiter_config.is_some()
+                    && !self.devices.has_rate_limiter(&rate_limiter.id)
+            })
+            .map(|(field, rate_limiter)| format!("{}[{}]", field, rate_limiter.id))
+            .collect();
+        if !fixed.is_empty() {
+            return Err(Error::ConfigNotApplicable(fixed));
+        }
+
+        if update.serial.is_some() {
+            self.devices
+                .set_serial_output(&config.serial)
+                .map_err(Error::UpdateDevice)?;
+        }
+        for (_, rate_limiter) in rate_limiters {
+            if self.devices.has_rate_limiter(&rate_limiter.id) {
+                self.devices
+                    .set_rate_limiter(&rate_limiter.id, &rate_limiter.rate_limiter_config)
+                    .map_err(Error::UpdateDevice)?;
+            }
+        }
+        self.config = Arc::new(config);
+
+        Ok(())
+    }
+
+    /// Hot-add a vsock device, with the ID of its configuration assigned,
+    /// and add it to the configuration of the VM for it to keep it across
+    /// reboots.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn add_vsock(&mut self, vsock_cfg: VsockConfig) -> Result<PciDeviceInfo> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        let bdf = self
+            .devices
+            .hotplug_vsock(&self.config, &vsock_cfg)
+            .map_err(Error::AddDevice)?;
+        let info = PciDeviceInfo {
+            id: vsock_cfg.id.clone().unwrap_or_default(),
+            bdf,
+        };
+        Arc::make_mut(&mut self.config)
+            .vsock
+            .get_or_insert_with(Vec::new)
+            .push(vsock_cfg);
+
+        Ok(info)
+    }
+
+    #[cfg(not(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64")))]
+    pub fn add_vsock(&mut self, _vsock_cfg: VsockConfig) -> Result<PciDeviceInfo> {
+        Err(Error::PciHotplugNotSupported)
+    }
+
+    /// Hot-add a vfio-user device, as `add_vsock()` does.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn add_user_device(&mut self, user_device_cfg: UserDeviceConfig) -> Result<PciDeviceInfo> {
+        match self.get_state()? {
+            VmState::Running | VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        let bdf = self
+            .devices
+            .hotplug_user_device(&user_device_cfg)
+            .map_err(Error::AddDevice)?;
+        let info = PciDeviceInfo {
+            id: user_device_cfg.id.clone().unwrap_or_default(),
+            bdf,
+        };
+        Arc::make_mut(&mut self.config)
+            .user_devices
+            .get_or_insert_with(Vec::new)
+            .push(user_device_cfg);
+
+        Ok(info)
+    }
+
+    #[cfg(not(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64")))]
+    pub fn add_user_device(&mut self, _user_device_cfg: UserDeviceConfig) -> Result<PciDeviceInfo> {
+        Err(Error::PciHotplugNotSupported)
+    }
+
+    /// Ask the guest to eject a vsock, vfio-user or removable disk device.
+    /// The device is removed once the guest ejected it.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn remove_device(&self, id: &str) -> Result<()> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        self.devices
+            .unplug_pci_device(id)
+            .map_err(Error::RemoveDevice)
+    }
+
+    #[cfg(not(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64")))]
+    pub fn remove_device(&self, _id: &str) -> Result<()> {
+        Err(Error::PciHotplugNotSupported)
+    }
+
+    /// Event written when the guest ejects devices of the PCI bus.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn pci_eject_evt(&self) -> Option<&EventFd> {
+        self.devices.pci_eject_evt()
+    }
+
+    #[cfg(not(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64")))]
+    pub fn pci_eject_evt(&self) -> Option<&EventFd> {
+        None
+    }
+
+    /// Remove the devices the guest ejected, from the configuration of the
+    /// VM as well, returning their IDs. Consumes the PCI eject event.
+    #[cfg(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64"))]
+    pub fn eject_devices(&mut self) -> Result<Vec<String>> {
+        if let Some(eject_evt) = self.devices.pci_eject_evt() {
+            eject_evt.read().map_err(Error::EventFdRead)?;
+        }
+
+        let ids = self
+            .devices
+            .eject_pci_devices()
+            .map_err(Error::EjectDevices)?;
+        if !ids.is_empty() {
+            let config = Arc::make_mut(&mut self.config);
+            for id in ids.iter() {
+                config.remove_device(id);
+            }
+        }
+
+        Ok(ids)
+    }
+
+    #[cfg(not(all(feature = "acpi", feature = "pci_support", target_arch = "x86_64")))]
+    pub fn eject_devices(&mut self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Press the ACPI power button, letting the guest OS shut itself down.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn power_button(&self) -> Result<()> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        match self.devices.ged_notification_device() {
+            Some(ged) => ged
+                .lock()
+                .unwrap()
+                .notify(devices::GED_POWER_BUTTON)
+                .map_err(Error::PowerButton),
+            None => Err(Error::PowerButtonNotSupported),
+        }
+    }
+
+    #[cfg(not(all(feature = "acpi", target_arch = "x86_64")))]
+    pub fn power_button(&self) -> Result<()> {
+        Err(Error::PowerButtonNotSupported)
+    }
+
+    /// Update the ACPI battery and thermal zone readings, and notify the
+    /// guest about the ones which changed.
+    #[cfg(all(feature = "acpi", target_arch = "x86_64"))]
+    pub fn set_sensors(&self, sensors: &VmSensors) -> Result<()> {
+        if self.get_state()? != VmState::Running {
+            return Err(Error::VmNotRunning);
+        }
+
+        let sensors_device = self
+            .devices
+            .acpi_sensors_device()
+            .ok_or(Error::SensorsNotSupported)?;
+
+        let mut notification = 0;
+        if let Some(battery) = &sensors.battery {
+            if !self.config.sensors.battery {
+                return Err(Error::SensorsNotSupported);
+            }
+            sensors_device.lock().unwrap().set_battery(
+                battery.charging,
+                battery.level,
+                battery.rate,
+            );
+            notification |= devices::GED_BATTERY;
+        }
+        if let Some(temperature) = sensors.temperature {
+            if !self.config.sensors.thermal_zone {
+                return Err(Error::SensorsNotSupported);
+            }
+            sensors_device.lock().unwrap().set_temperature(temperature);
+            notification |= devices::GED_THERMAL_ZONE;
+        }
+
+        match self.devices.ged_notification_device() {
+            Some(ged) if notification != 0 => ged
+                .lock()
+                .unwrap()
+                .notify(notification)
+                .map_err(Error::SensorsNotify),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(all(feature = "acpi", target_arch = "x86_64")))]
+    pub fn set_sensors(&self, _sensors: &VmSensors) -> Result<()> {
+        Err(Error::SensorsNotSupported)
+    }
+
+    /// Pause the vCPUs and flush the disk and persistent memory images, so
+    /// that the whole VMM process can be checkpointed by tools such as CRIU.
+    /// The VM is restarted with resume().
+    pub fn quiesce(&mut self) -> Result<()> {
+        match self.get_state()? {
+            VmState::Running => self.pause()?,
+            VmState::Paused => {}
+            _ => return Err(Error::VmNotRunning),
+        }
+
+        let disks = self.config.disks.iter().flatten().map(|disk| &disk.path);
+        let pmems = self.config.pmem.iter().flatten().map(|pmem| &pmem.file);
+        for path in disks.chain(pmems) {
+            File::open(path)
+                .and_then(|f| f.sync_all())
+                .map_err(Error::ImageSync)?;
+        }
+
+        Ok(())
+    }
+
     fn os_signal_handler(signals: Signals, console_input_clone: Arc<Console>) {
         for signal in signals.forever() {
             if signal == SIGWINCH {
@@ -620,11 +2494,24 @@ impl Vm {
         let new_state = VmState::Running;
         current_state.valid_transition(new_state)?;
 
-        let entry_addr = self.load_kernel()?;
+        let trace = ScopedTrace::new("vm_boot");
+        let entry_point = {
+            let _trace = ScopedTrace::new("kernel_load");
+            self.load_kernel()?
+        };
 
-        self.cpu_manager
-            .start_boot_vcpus(entry_addr)
-            .map_err(Error::CpuManager)?;
+        {
+            let _trace = ScopedTrace::new("vcpu_start");
+            self.cpu_manager
+                .start_boot_vcpus(entry_point)
+                .map_err(Error::CpuManager)?;
+        }
+
+        if let Some(cgroup) = &mut self.cgroup {
+            cgroup
+                .start(self.cpu_manager.vcpu_tids().to_vec())
+                .map_err(Error::Cgroup)?;
+        }
 
         if self.devices.console().input_enabled() {
             let console = self.devices.console().clone();
@@ -654,6 +2541,11 @@ impl Vm {
         let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
         *state = new_state;
 
+        drop(trace);
+        if let Err(e) = tracer::dump() {
+            warn!("Failed writing the trace: {}", e);
+        }
+
         Ok(())
     }
 
@@ -662,6 +2554,12 @@ impl Vm {
         self.memory.clone()
     }
 
+    /// Gets an Arc to the memory manager, through which guest RAM can be
+    /// hotplugged or unplugged.
+    pub fn memory_manager(&self) -> Arc<Mutex<MemoryManager>> {
+        self.memory_manager.clone()
+    }
+
     pub fn handle_stdin(&self) -> Result<()> {
         let mut out = [0u8; 64];
         let count = io::stdin()
@@ -679,6 +2577,183 @@ impl Vm {
         Ok(())
     }
 
+    // Reads what is available from the main side of a pseudo-terminal.
+    fn read_pty(pty: &PtyPair, out: &mut [u8]) -> Result<usize> {
+        match (&pty.main).read(out) {
+            Ok(count) => Ok(count),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(Error::PtyRead(e)),
+        }
+    }
+
+    pub fn handle_serial_pty(&self) -> Result<()> {
+        let console = self.devices.console();
+        if let Some(pty) = console.serial_pty() {
+            let mut out = [0u8; 64];
+            let count = Vm::read_pty(pty, &mut out)?;
+            console
+                .queue_serial_input_bytes(&out[..count])
+                .map_err(Error::Console)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_console_pty(&self) -> Result<()> {
+        let console = self.devices.console();
+        if let Some(pty) = console.console_pty() {
+            let mut out = [0u8; 64];
+            let count = Vm::read_pty(pty, &mut out)?;
+            console.queue_console_input_bytes(&out[..count]);
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_serial_socket(&self) -> Result<()> {
+        let console = self.devices.console();
+        if let Some(socket) = console.serial_socket() {
+            let mut out = [0u8; 64];
+            let count = socket
+                .read_input(&mut out)
+                .map_err(Error::SerialSocketRead)?;
+            console
+                .queue_serial_input_bytes(&out[..count])
+                .map_err(Error::Console)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_console_socket(&self) -> Result<()> {
+        let console = self.devices.console();
+        if let Some(socket) = console.console_socket() {
+            let mut out = [0u8; 64];
+            let count = socket
+                .read_input(&mut out)
+                .map_err(Error::SerialSocketRead)?;
+            console.queue_console_input_bytes(&out[..count]);
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_console_port_socket(&self, index: usize) -> Result<()> {
+        let console = self.devices.console();
+        if let Some(socket) = console.console_port_sockets().get(index) {
+            let mut out = [0u8; 64];
+            let result = socket.read_input(&mut out);
+            match result {
+                Ok(count) if count > 0 => {
+                    console.queue_console_port_input_bytes(index, &out[..count])
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // The client is gone, which the guest is told about.
+                _ => console.set_console_port_connected(index, false),
+            }
+            result.map_err(Error::SerialSocketRead)?;
+        }
+
+        Ok(())
+    }
+
+    /// Tells the guest whether a client is connected to the socket of the
+    /// `index`th additional virtio-console port.
+    pub fn set_console_port_connected(&self, index: usize, connected: bool) {
+        self.devices
+            .console()
+            .set_console_port_connected(index, connected)
+    }
+
+    pub fn handle_uart_pty(&self, index: usize) -> Result<()> {
+        if let Some(uart) = self.devices.console().uarts().get(index) {
+            if let Some(pty) = uart.pty() {
+                let mut out = [0u8; 64];
+                let count = Vm::read_pty(pty, &mut out)?;
+                uart.queue_input_bytes(&out[..count])
+                    .map_err(Error::Console)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_uart_socket(&self, index: usize) -> Result<()> {
+        if let Some(uart) = self.devices.console().uarts().get(index) {
+            if let Some(socket) = uart.socket() {
+                let mut out = [0u8; 64];
+                let count = socket
+                    .read_input(&mut out)
+                    .map_err(Error::SerialSocketRead)?;
+                uart.queue_input_bytes(&out[..count])
+                    .map_err(Error::Console)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// PCI addresses of the devices, by ID.
+    pub fn pci_devices(&self) -> &BTreeMap<String, String> {
+        self.devices.pci_devices()
+    }
+
+    /// SR-IOV virtual functions passed through, by ID, along with their
+    /// link state.
+    pub fn sriov_vfs(&self) -> BTreeMap<String, VfInfo> {
+        self.devices.sriov_vfs()
+    }
+
+    /// The guest OS, as far as it was probed, when the VM is configured to
+    /// probe it.
+    pub fn guest_os(&self) -> Option<GuestOsInfo> {
+        self.devices.guest_os_probe().and_then(|probe| probe.info())
+    }
+
+    /// The files and UNIX sockets of the VM, which the process leaves behind
+    /// if it dies.
+    pub fn leftovers(&self) -> Vec<Leftover> {
+        let temp_files = self.memory_manager.lock().unwrap().temp_files();
+        let sockets = host_resources::vm_sockets(&self.config);
+
+        temp_files
+            .into_iter()
+            .map(Leftover::File)
+            .chain(sockets.into_iter().map(Leftover::Socket))
+            .collect()
+    }
+
+    /// Additional UARTs, in the order of the VM configuration.
+    pub fn uarts(&self) -> &[Uart] {
+        self.devices.console().uarts()
+    }
+
+    /// UNIX socket backing the serial port, if any.
+    pub fn serial_socket(&self) -> Option<&SerialSocket> {
+        self.devices.console().serial_socket()
+    }
+
+    /// UNIX socket backing the virtio-console, if any.
+    pub fn console_socket(&self) -> Option<&SerialSocket> {
+        self.devices.console().console_socket()
+    }
+
+    /// UNIX sockets of the additional virtio-console ports, in the order of
+    /// the VM configuration.
+    pub fn console_port_sockets(&self) -> &[SerialSocket] {
+        self.devices.console().console_port_sockets()
+    }
+
+    /// Pseudo-terminal backing the serial port, if any.
+    pub fn serial_pty(&self) -> Option<&PtyPair> {
+        self.devices.console().serial_pty()
+    }
+
+    /// Pseudo-terminal backing the virtio-console, if any.
+    pub fn console_pty(&self) -> Option<&PtyPair> {
+        self.devices.console().console_pty()
+    }
+
     /// Gets a thread-safe reference counted pointer to the VM configuration.
     pub fn get_config(&self) -> Arc<VmConfig> {
         Arc::clone(&self.config)
@@ -752,6 +2827,34 @@ mod tests {
     }
 }
 
+#[cfg(target_arch = "x86_64")]
+#[allow(unused)]
+struct TestVmmOps;
+
+#[cfg(target_arch = "x86_64")]
+impl VmmOps for TestVmmOps {
+    fn pio_read(&self, addr: u64, data: &mut [u8]) {
+        println!(
+            "IO in -- addr: {:#x} data [{:?}]",
+            addr,
+            str::from_utf8(data).unwrap()
+        );
+    }
+
+    fn pio_write(&self, addr: u64, data: &[u8]) {
+        println!(
+            "IO out -- addr: {:#x} data [{:?}]",
+            addr,
+            str::from_utf8(data).unwrap()
+        );
+    }
+
+    fn mmio_read(&self, _addr: u64, _data: &mut [u8]) {}
+
+    fn mmio_write(&self, _addr: u64, _data: &[u8]) {}
+}
+
+#[cfg(target_arch = "x86_64")]
 #[allow(unused)]
 pub fn test_vm() {
     // This example based on https://lwn.net/Articles/658511/
@@ -769,11 +2872,11 @@ pub fn test_vm() {
     let load_addr = GuestAddress(0x1000);
     let mem = GuestMemoryMmap::new(&[(load_addr, mem_size)]).unwrap();
 
-    let kvm = Kvm::new().expect("new KVM instance creation failed");
-    let vm_fd = kvm.create_vm().expect("new VM fd creation failed");
+    let hypervisor = hypervisor::new().expect("new hypervisor creation failed");
+    let vm = hypervisor.create_vm().expect("new VM creation failed");
 
     mem.with_regions(|index, region| {
-        let mem_region = kvm_userspace_memory_region {
+        let mem_region = UserMemoryRegion {
             slot: index as u32,
             guest_phys_addr: region.start_addr().raw_value(),
             memory_size: region.len() as u64,
@@ -782,73 +2885,35 @@ pub fn test_vm() {
         };
 
         // Safe because the guest regions are guaranteed not to overlap.
-        unsafe { vm_fd.set_user_memory_region(mem_region) }
+        unsafe { vm.set_user_memory_region(mem_region) }
     })
     .expect("Cannot configure guest memory");
     mem.write_slice(&code, load_addr)
         .expect("Writing code to memory failed");
 
-    let vcpu_fd = vm_fd.create_vcpu(0).expect("new VcpuFd failed");
+    let vcpu = vm
+        .create_vcpu(0, Some(Arc::new(TestVmmOps)))
+        .expect("new vCPU creation failed");
 
-    let mut vcpu_sregs = vcpu_fd.get_sregs().expect("get sregs failed");
+    let mut vcpu_sregs = vcpu.get_sregs().expect("get sregs failed");
     vcpu_sregs.cs.base = 0;
     vcpu_sregs.cs.selector = 0;
-    vcpu_fd.set_sregs(&vcpu_sregs).expect("set sregs failed");
+    vcpu.set_sregs(&vcpu_sregs).expect("set sregs failed");
 
-    let mut vcpu_regs = vcpu_fd.get_regs().expect("get regs failed");
+    let mut vcpu_regs = vcpu.get_regs().expect("get regs failed");
     vcpu_regs.rip = 0x1000;
     vcpu_regs.rax = 2;
     vcpu_regs.rbx = 3;
     vcpu_regs.rflags = 2;
-    vcpu_fd.set_regs(&vcpu_regs).expect("set regs failed");
+    vcpu.set_regs(&vcpu_regs).expect("set regs failed");
 
     loop {
-        match vcpu_fd.run().expect("run failed") {
-            VcpuExit::IoIn(addr, data) => {
-                println!(
-                    "IO in -- addr: {:#x} data [{:?}]",
-                    addr,
-                    str::from_utf8(&data).unwrap()
-                );
-            }
-            VcpuExit::IoOut(addr, data) => {
-                println!(
-                    "IO out -- addr: {:#x} data [{:?}]",
-                    addr,
-                    str::from_utf8(&data).unwrap()
-                );
-            }
-            VcpuExit::MmioRead(_addr, _data) => {}
-            VcpuExit::MmioWrite(_addr, _data) => {}
-            VcpuExit::Unknown => {}
-            VcpuExit::Exception => {}
-            VcpuExit::Hypercall => {}
-            VcpuExit::Debug => {}
-            VcpuExit::Hlt => {
-                println!("HLT");
-            }
-            VcpuExit::IrqWindowOpen => {}
-            VcpuExit::Shutdown => {}
-            VcpuExit::FailEntry => {}
-            VcpuExit::Intr => {}
-            VcpuExit::SetTpr => {}
-            VcpuExit::TprAccess => {}
-            VcpuExit::S390Sieic => {}
-            VcpuExit::S390Reset => {}
-            VcpuExit::Dcr => {}
-            VcpuExit::Nmi => {}
-            VcpuExit::InternalError => {}
-            VcpuExit::Osi => {}
-            VcpuExit::PaprHcall => {}
-            VcpuExit::S390Ucontrol => {}
-            VcpuExit::Watchdog => {}
-            VcpuExit::S390Tsch => {}
-            VcpuExit::Epr => {}
-            VcpuExit::SystemEvent => {}
-            VcpuExit::S390Stsi => {}
-            VcpuExit::IoapicEoi(_vector) => {}
-            VcpuExit::Hyperv => {}
-        }
-        //        r => panic!("unexpected exit reason: {:?}", r),
+        match vcpu.run().expect("run failed") {
+            VmExit::Ignore => {}
+            r => {
+                println!("exit reason: {:?}", r);
+                break;
+            }
+        }
     }
 }